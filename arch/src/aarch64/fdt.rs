@@ -22,7 +22,7 @@ use super::super::GuestMemoryMmap;
 use super::super::InitramfsConfig;
 use super::layout::{
     IRQ_BASE, MEM_32BIT_DEVICES_SIZE, MEM_32BIT_DEVICES_START, MEM_PCI_IO_SIZE, MEM_PCI_IO_START,
-    PCI_HIGH_BASE, PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+    PCI_HIGH_BASE,
 };
 use std::fs;
 use std::path::Path;
@@ -219,7 +219,7 @@ pub fn create_fdt<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::BuildHash
     guest_mem: &GuestMemoryMmap,
     cmdline: &str,
     vcpu_mpidr: Vec<u64>,
-    vcpu_topology: Option<(u8, u8, u8)>,
+    vcpu_topology: Option<(u8, u8, u8, u8)>,
     device_info: &HashMap<(DeviceType, String), T, S>,
     gic_device: &Arc<Mutex<dyn Vgic>>,
     initrd: &Option<InitramfsConfig>,
@@ -281,7 +281,7 @@ pub fn write_fdt_to_memory(fdt_final: Vec<u8>, guest_mem: &GuestMemoryMmap) -> R
 fn create_cpu_nodes(
     fdt: &mut FdtWriter,
     vcpu_mpidr: &[u64],
-    vcpu_topology: Option<(u8, u8, u8)>,
+    vcpu_topology: Option<(u8, u8, u8, u8)>,
     numa_nodes: &NumaNodes,
 ) -> FdtWriterResult<()> {
     // See https://github.com/torvalds/linux/blob/master/Documentation/devicetree/bindings/arm/cpus.yaml.
@@ -290,8 +290,10 @@ fn create_cpu_nodes(
     fdt.property_u32("#size-cells", 0x0)?;
 
     let num_cpus = vcpu_mpidr.len();
-    let (threads_per_core, cores_per_package, packages) = vcpu_topology.unwrap_or((1, 1, 1));
-    let max_cpus: u32 = (threads_per_core * cores_per_package * packages).into();
+    let (threads_per_core, cores_per_cluster, clusters_per_package, packages) =
+        vcpu_topology.unwrap_or((1, 1, 1, 1));
+    let max_cpus: u32 =
+        (threads_per_core * cores_per_cluster * clusters_per_package * packages).into();
 
     // Add cache info.
     // L1 Data Cache Info.
@@ -463,7 +465,7 @@ fn create_cpu_nodes(
     }
 
     if let Some(topology) = vcpu_topology {
-        let (threads_per_core, cores_per_package, packages) = topology;
+        let (threads_per_core, cores_per_cluster, clusters_per_package, packages) = topology;
         let cpu_map_node = fdt.begin_node("cpu-map")?;
 
         // Create device tree nodes with regard of above mapping.
@@ -472,26 +474,30 @@ fn create_cpu_nodes(
             let package_node = fdt.begin_node(&package_name)?;
 
             // Cluster is the container of cores, and it is mandatory in the CPU topology.
-            // Add a default "cluster0" in each socket/package.
-            let cluster_node = fdt.begin_node("cluster0")?;
-
-            for core_idx in 0..cores_per_package {
-                let core_name = format!("core{core_idx:x}");
-                let core_node = fdt.begin_node(&core_name)?;
-
-                for thread_idx in 0..threads_per_core {
-                    let thread_name = format!("thread{thread_idx:x}");
-                    let thread_node = fdt.begin_node(&thread_name)?;
-                    let cpu_idx = threads_per_core * cores_per_package * package_idx
-                        + threads_per_core * core_idx
-                        + thread_idx;
-                    fdt.property_u32("cpu", cpu_idx as u32 + FIRST_VCPU_PHANDLE)?;
-                    fdt.end_node(thread_node)?;
+            for cluster_idx in 0..clusters_per_package {
+                let cluster_name = format!("cluster{cluster_idx:x}");
+                let cluster_node = fdt.begin_node(&cluster_name)?;
+
+                for core_idx in 0..cores_per_cluster {
+                    let core_name = format!("core{core_idx:x}");
+                    let core_node = fdt.begin_node(&core_name)?;
+
+                    for thread_idx in 0..threads_per_core {
+                        let thread_name = format!("thread{thread_idx:x}");
+                        let thread_node = fdt.begin_node(&thread_name)?;
+                        let cpu_idx = threads_per_core
+                            * cores_per_cluster
+                            * (clusters_per_package * package_idx + cluster_idx)
+                            + threads_per_core * core_idx
+                            + thread_idx;
+                        fdt.property_u32("cpu", cpu_idx as u32 + FIRST_VCPU_PHANDLE)?;
+                        fdt.end_node(thread_node)?;
+                    }
+
+                    fdt.end_node(core_node)?;
                 }
-
-                fdt.end_node(core_node)?;
+                fdt.end_node(cluster_node)?;
             }
-            fdt.end_node(cluster_node)?;
             fdt.end_node(package_node)?;
         }
         fdt.end_node(cpu_map_node)?;
@@ -742,7 +748,11 @@ fn create_virtio_node<T: DeviceInfoForFdt + Clone + Debug>(
     dev_info: &T,
 ) -> FdtWriterResult<()> {
     let device_reg_prop = [dev_info.addr(), dev_info.length()];
-    let irq = [GIC_FDT_IRQ_TYPE_SPI, dev_info.irq(), IRQ_TYPE_EDGE_RISING];
+    let irq = [
+        GIC_FDT_IRQ_TYPE_SPI,
+        dev_info.irq() - IRQ_BASE,
+        IRQ_TYPE_EDGE_RISING,
+    ];
 
     let virtio_node = fdt.begin_node(&format!("virtio_mmio@{:x}", dev_info.addr()))?;
     fdt.property_string("compatible", "virtio,mmio")?;
@@ -800,6 +810,21 @@ fn create_rtc_node<T: DeviceInfoForFdt + Clone + Debug>(
     Ok(())
 }
 
+fn create_debug_console_node<T: DeviceInfoForFdt + Clone + Debug>(
+    fdt: &mut FdtWriter,
+    dev_info: &T,
+) -> FdtWriterResult<()> {
+    // No interrupt, the device being output only
+    let debug_console_reg_prop = [dev_info.addr(), dev_info.length()];
+
+    let debug_console_node = fdt.begin_node(&format!("debug-console@{:x}", dev_info.addr()))?;
+    fdt.property_string("compatible", "cloud-hypervisor,debug-console")?;
+    fdt.property_array_u64("reg", &debug_console_reg_prop)?;
+    fdt.end_node(debug_console_node)?;
+
+    Ok(())
+}
+
 fn create_gpio_node<T: DeviceInfoForFdt + Clone + Debug>(
     fdt: &mut FdtWriter,
     dev_info: &T,
@@ -849,6 +874,7 @@ fn create_devices_node<T: DeviceInfoForFdt + Clone + Debug, S: ::std::hash::Buil
 
     for ((device_type, _device_id), info) in dev_info {
         match device_type {
+            DeviceType::DebugConsole => create_debug_console_node(fdt, info)?,
             DeviceType::Gpio => create_gpio_node(fdt, info)?,
             DeviceType::Rtc => create_rtc_node(fdt, info)?,
             DeviceType::Serial => create_serial_node(fdt, info)?,
@@ -944,15 +970,18 @@ fn create_pci_nodes(
             (pci_device_size_64bit >> 32) as u32, // size
             pci_device_size_64bit as u32,
         ];
-        let bus_range = [0, 0]; // Only bus 0
+        let bus_range = [
+            pci_device_info_elem.start_bus as u32,
+            pci_device_info_elem.end_bus as u32,
+        ];
         let reg = [
             pci_device_info_elem.mmio_config_address,
-            PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+            pci_device_info_elem.mmio_config_size,
         ];
         // See kernel document Documentation/devicetree/bindings/pci/pci-msi.txt
         let msi_map = [
             // rid-base: A single cell describing the first RID matched by the entry.
-            0x0,
+            (pci_device_info_elem.start_bus as u32) << 8,
             // msi-controller: A single phandle to an MSI controller.
             MSI_PHANDLE,
             // msi-base: An msi-specifier describing the msi-specifier produced for the
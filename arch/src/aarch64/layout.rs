@@ -76,6 +76,7 @@ pub const GIC_V3_ITS_SIZE: u64 = 0x02_0000;
 pub const LEGACY_SERIAL_MAPPED_IO_START: GuestAddress = MAPPED_IO_START;
 pub const LEGACY_RTC_MAPPED_IO_START: GuestAddress = GuestAddress(0x0901_0000);
 pub const LEGACY_GPIO_MAPPED_IO_START: GuestAddress = GuestAddress(0x0902_0000);
+pub const LEGACY_DEBUG_CONSOLE_MAPPED_IO_START: GuestAddress = GuestAddress(0x0903_0000);
 
 /// Space 0x0905_0000 ~ 0x0906_0000 is reserved for pcie io address
 pub const MEM_PCI_IO_START: GuestAddress = GuestAddress(0x0905_0000);
@@ -120,6 +121,11 @@ pub const ACPI_START: GuestAddress = GuestAddress(RAM_START.0 + FDT_MAX_SIZE);
 pub const ACPI_MAX_SIZE: u64 = 0x20_0000;
 pub const RSDP_POINTER: GuestAddress = ACPI_START;
 
+/// TPM event log, described by the ACPI TPM2 table, at the end of the ACPI
+/// area
+pub const TPM_LOG_SIZE: u64 = 0x1_0000;
+pub const TPM_LOG_START: GuestAddress = GuestAddress(ACPI_START.0 + ACPI_MAX_SIZE - TPM_LOG_SIZE);
+
 /// Kernel start after FDT and ACPI
 pub const KERNEL_START: GuestAddress = GuestAddress(ACPI_START.0 + ACPI_MAX_SIZE);
 
@@ -90,9 +90,10 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, configure_vcpu, generate_common_cpuid,
-    get_host_cpu_phys_bits, initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE,
-    layout::CMDLINE_START, regs, CpuidConfig, CpuidFeatureEntry, EntryPoint, _NSIG,
+    arch_memory_regions, configure_system, configure_vcpu, configure_vcpu_wakeup,
+    generate_common_cpuid, get_host_cpu_phys_bits, initramfs_load_addr, layout,
+    layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START, regs, CpuidConfig, CpuidFeatureEntry,
+    EntryPoint, _NSIG,
 };
 
 /// Safe wrapper for `sysconf(_SC_PAGESIZE)`.
@@ -139,6 +140,9 @@ pub enum DeviceType {
     /// Device Type: GPIO.
     #[cfg(target_arch = "aarch64")]
     Gpio,
+    /// Device Type: Debug console.
+    #[cfg(target_arch = "aarch64")]
+    DebugConsole,
 }
 
 /// Default (smallest) memory page size for the supported architectures.
@@ -165,6 +169,9 @@ pub struct MmioDeviceInfo {
 pub struct PciSpaceInfo {
     pub pci_segment_id: u16,
     pub mmio_config_address: u64,
+    pub mmio_config_size: u64,
+    pub start_bus: u8,
+    pub end_bus: u8,
     pub pci_device_space_start: u64,
     pub pci_device_space_size: u64,
 }
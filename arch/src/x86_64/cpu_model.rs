@@ -0,0 +1,556 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Named CPU models and CPUID feature masking.
+//!
+//! A CPU model is a set of CPUID features the guest can rely on, whatever the
+//! host it runs onto. Exposing the same model on all the hosts of a
+//! heterogeneous cluster lets VMs migrate safely between them. The features
+//! from [`CPUID_FEATURES`] which are not part of the model are hidden from the
+//! guest, as well as any unknown bit of the [`FEATURE_REGISTERS`], and the
+//! family, model, stepping and brand string of the host are replaced with the
+//! ones of the model.
+
+use super::{CpuidPatch, CpuidReg, Error};
+use hypervisor::arch::x86::CpuIdEntry;
+
+pub struct CpuidFeature {
+    pub name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u8,
+}
+
+const fn feature(
+    name: &'static str,
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    bit: u8,
+) -> CpuidFeature {
+    CpuidFeature {
+        name,
+        function,
+        index,
+        reg,
+        bit,
+    }
+}
+
+/// CPUID features which can be masked, named after their Linux
+/// `/proc/cpuinfo` flag.
+pub const CPUID_FEATURES: &[CpuidFeature] = &[
+    // Leaf 0x1, ECX
+    feature("sse3", 0x1, 0, CpuidReg::ECX, 0),
+    feature("pclmulqdq", 0x1, 0, CpuidReg::ECX, 1),
+    feature("ssse3", 0x1, 0, CpuidReg::ECX, 9),
+    feature("fma", 0x1, 0, CpuidReg::ECX, 12),
+    feature("cx16", 0x1, 0, CpuidReg::ECX, 13),
+    feature("pcid", 0x1, 0, CpuidReg::ECX, 17),
+    feature("sse4_1", 0x1, 0, CpuidReg::ECX, 19),
+    feature("sse4_2", 0x1, 0, CpuidReg::ECX, 20),
+    feature("x2apic", 0x1, 0, CpuidReg::ECX, 21),
+    feature("movbe", 0x1, 0, CpuidReg::ECX, 22),
+    feature("popcnt", 0x1, 0, CpuidReg::ECX, 23),
+    feature("aes", 0x1, 0, CpuidReg::ECX, 25),
+    feature("xsave", 0x1, 0, CpuidReg::ECX, 26),
+    feature("avx", 0x1, 0, CpuidReg::ECX, 28),
+    feature("f16c", 0x1, 0, CpuidReg::ECX, 29),
+    feature("rdrand", 0x1, 0, CpuidReg::ECX, 30),
+    // Leaf 0x7, EBX
+    feature("fsgsbase", 0x7, 0, CpuidReg::EBX, 0),
+    feature("bmi1", 0x7, 0, CpuidReg::EBX, 3),
+    feature("hle", 0x7, 0, CpuidReg::EBX, 4),
+    feature("avx2", 0x7, 0, CpuidReg::EBX, 5),
+    feature("smep", 0x7, 0, CpuidReg::EBX, 7),
+    feature("bmi2", 0x7, 0, CpuidReg::EBX, 8),
+    feature("erms", 0x7, 0, CpuidReg::EBX, 9),
+    feature("invpcid", 0x7, 0, CpuidReg::EBX, 10),
+    feature("rtm", 0x7, 0, CpuidReg::EBX, 11),
+    feature("avx512f", 0x7, 0, CpuidReg::EBX, 16),
+    feature("avx512dq", 0x7, 0, CpuidReg::EBX, 17),
+    feature("rdseed", 0x7, 0, CpuidReg::EBX, 18),
+    feature("adx", 0x7, 0, CpuidReg::EBX, 19),
+    feature("smap", 0x7, 0, CpuidReg::EBX, 20),
+    feature("avx512ifma", 0x7, 0, CpuidReg::EBX, 21),
+    feature("clflushopt", 0x7, 0, CpuidReg::EBX, 23),
+    feature("clwb", 0x7, 0, CpuidReg::EBX, 24),
+    feature("avx512cd", 0x7, 0, CpuidReg::EBX, 28),
+    feature("sha_ni", 0x7, 0, CpuidReg::EBX, 29),
+    feature("avx512bw", 0x7, 0, CpuidReg::EBX, 30),
+    feature("avx512vl", 0x7, 0, CpuidReg::EBX, 31),
+    // Leaf 0x7, ECX
+    feature("avx512vbmi", 0x7, 0, CpuidReg::ECX, 1),
+    feature("umip", 0x7, 0, CpuidReg::ECX, 2),
+    feature("pku", 0x7, 0, CpuidReg::ECX, 3),
+    feature("waitpkg", 0x7, 0, CpuidReg::ECX, 5),
+    feature("avx512_vbmi2", 0x7, 0, CpuidReg::ECX, 6),
+    feature("gfni", 0x7, 0, CpuidReg::ECX, 8),
+    feature("vaes", 0x7, 0, CpuidReg::ECX, 9),
+    feature("vpclmulqdq", 0x7, 0, CpuidReg::ECX, 10),
+    feature("avx512_vnni", 0x7, 0, CpuidReg::ECX, 11),
+    feature("avx512_bitalg", 0x7, 0, CpuidReg::ECX, 12),
+    feature("avx512_vpopcntdq", 0x7, 0, CpuidReg::ECX, 14),
+    feature("rdpid", 0x7, 0, CpuidReg::ECX, 22),
+    feature("movdiri", 0x7, 0, CpuidReg::ECX, 27),
+    feature("movdir64b", 0x7, 0, CpuidReg::ECX, 28),
+    // Leaf 0x7, EDX
+    feature("fsrm", 0x7, 0, CpuidReg::EDX, 4),
+    feature("md_clear", 0x7, 0, CpuidReg::EDX, 10),
+    feature("serialize", 0x7, 0, CpuidReg::EDX, 14),
+    feature("avx512_fp16", 0x7, 0, CpuidReg::EDX, 23),
+    // Leaf 0x7, sub-leaf 1, EAX
+    feature("avx_vnni", 0x7, 1, CpuidReg::EAX, 4),
+    feature("avx512_bf16", 0x7, 1, CpuidReg::EAX, 5),
+    // Leaf 0xd, sub-leaf 1, EAX
+    feature("xsaveopt", 0xd, 1, CpuidReg::EAX, 0),
+    feature("xsavec", 0xd, 1, CpuidReg::EAX, 1),
+    feature("xgetbv1", 0xd, 1, CpuidReg::EAX, 2),
+    feature("xsaves", 0xd, 1, CpuidReg::EAX, 3),
+    // Leaf 0x8000_0001, ECX
+    feature("lahf_lm", 0x8000_0001, 0, CpuidReg::ECX, 0),
+    feature("abm", 0x8000_0001, 0, CpuidReg::ECX, 5),
+    feature("3dnowprefetch", 0x8000_0001, 0, CpuidReg::ECX, 8),
+    // Leaf 0x8000_0001, EDX
+    feature("pdpe1gb", 0x8000_0001, 0, CpuidReg::EDX, 26),
+    feature("rdtscp", 0x8000_0001, 0, CpuidReg::EDX, 27),
+];
+
+/// CPUID register holding feature bits, fully masked when a CPU model is
+/// used, except for the bits of `keep`.
+pub struct FeatureRegister {
+    function: u32,
+    index: u32,
+    reg: CpuidReg,
+    keep: u32,
+}
+
+/// Feature registers restricted by the CPU models. The bits left untouched
+/// are either emulated by the hypervisor, reflecting the guest state, managed
+/// through other options, or the speculation control bits the guest relies on
+/// to apply the appropriate mitigations. The legacy features of leaf 0x1 EDX
+/// are part of every model and are not restricted.
+pub const FEATURE_REGISTERS: &[FeatureRegister] = &[
+    FeatureRegister {
+        function: 0x1,
+        index: 0,
+        reg: CpuidReg::ECX,
+        // vmx, x2apic, tsc_deadline_timer, osxsave, hypervisor
+        keep: 1 << 5 | 1 << 21 | 1 << 24 | 1 << 27 | 1 << 31,
+    },
+    FeatureRegister {
+        function: 0x7,
+        index: 0,
+        reg: CpuidReg::EBX,
+        // sgx
+        keep: 1 << 2,
+    },
+    FeatureRegister {
+        function: 0x7,
+        index: 0,
+        reg: CpuidReg::ECX,
+        // ospke, sgx_lc
+        keep: 1 << 4 | 1 << 30,
+    },
+    FeatureRegister {
+        function: 0x7,
+        index: 0,
+        reg: CpuidReg::EDX,
+        // hybrid, amx_bf16, amx_tile, amx_int8, spec_ctrl, intel_stibp,
+        // flush_l1d, arch_capabilities, core_capabilities, spec_ctrl_ssbd
+        keep: 1 << 15 | 1 << 22 | 1 << 24 | 1 << 25 | 0xfc00_0000,
+    },
+    FeatureRegister {
+        function: 0x7,
+        index: 1,
+        reg: CpuidReg::EAX,
+        keep: 0,
+    },
+    FeatureRegister {
+        function: 0xd,
+        index: 1,
+        reg: CpuidReg::EAX,
+        keep: 0,
+    },
+    FeatureRegister {
+        function: 0x8000_0001,
+        index: 0,
+        reg: CpuidReg::ECX,
+        // svm
+        keep: 1 << 2,
+    },
+    FeatureRegister {
+        function: 0x8000_0001,
+        index: 0,
+        reg: CpuidReg::EDX,
+        // syscall, nx, lm, and the legacy features AMD mirrors from leaf 0x1
+        // EDX
+        keep: 1 << 11 | 1 << 20 | 1 << 29 | 0x0183_f3ff,
+    },
+];
+
+// Levels of the x86-64 psABI
+const X86_64_V2: &[&str] = &[
+    "cx16", "lahf_lm", "popcnt", "sse3", "sse4_1", "sse4_2", "ssse3",
+];
+const X86_64_V3: &[&str] = &[
+    "abm", "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "movbe", "xsave",
+];
+const X86_64_V4: &[&str] = &["avx512bw", "avx512cd", "avx512dq", "avx512f", "avx512vl"];
+
+// Intel Xeon generations, on top of the x86-64-v4 level
+const SKYLAKE_SERVER: &[&str] = &[
+    "3dnowprefetch",
+    "adx",
+    "aes",
+    "clflushopt",
+    "clwb",
+    "erms",
+    "fsgsbase",
+    "invpcid",
+    "pcid",
+    "pclmulqdq",
+    "pdpe1gb",
+    "pku",
+    "rdrand",
+    "rdseed",
+    "rdtscp",
+    "smap",
+    "smep",
+    "x2apic",
+    "xgetbv1",
+    "xsavec",
+    "xsaveopt",
+];
+const TSX: &[&str] = &["hle", "rtm"];
+const CASCADELAKE_SERVER: &[&str] = &["avx512_vnni"];
+const ICELAKE_SERVER: &[&str] = &[
+    "avx512_bitalg",
+    "avx512_vbmi2",
+    "avx512_vpopcntdq",
+    "avx512vbmi",
+    "fsrm",
+    "gfni",
+    "md_clear",
+    "rdpid",
+    "umip",
+    "vaes",
+    "vpclmulqdq",
+];
+
+pub struct CpuModel {
+    pub name: &'static str,
+    // Family, model and stepping reported to the guest
+    signature: (u32, u32, u32),
+    features: &'static [&'static [&'static str]],
+}
+
+impl CpuModel {
+    fn has_feature(&self, name: &str) -> bool {
+        self.features.iter().any(|f| f.contains(&name))
+    }
+}
+
+pub const CPU_MODELS: &[CpuModel] = &[
+    CpuModel {
+        name: "x86-64-v1",
+        signature: (15, 6, 1),
+        features: &[],
+    },
+    CpuModel {
+        name: "x86-64-v2",
+        signature: (6, 26, 3),
+        features: &[X86_64_V2],
+    },
+    CpuModel {
+        name: "x86-64-v3",
+        signature: (6, 60, 4),
+        features: &[X86_64_V2, X86_64_V3],
+    },
+    CpuModel {
+        name: "x86-64-v4",
+        signature: (6, 85, 4),
+        features: &[X86_64_V2, X86_64_V3, X86_64_V4],
+    },
+    CpuModel {
+        name: "SkylakeServer",
+        signature: (6, 85, 4),
+        features: &[X86_64_V2, X86_64_V3, X86_64_V4, SKYLAKE_SERVER, TSX],
+    },
+    CpuModel {
+        name: "CascadelakeServer",
+        signature: (6, 85, 6),
+        features: &[
+            X86_64_V2,
+            X86_64_V3,
+            X86_64_V4,
+            SKYLAKE_SERVER,
+            TSX,
+            CASCADELAKE_SERVER,
+        ],
+    },
+    CpuModel {
+        name: "IcelakeServer",
+        signature: (6, 106, 6),
+        features: &[
+            X86_64_V2,
+            X86_64_V3,
+            X86_64_V4,
+            SKYLAKE_SERVER,
+            CASCADELAKE_SERVER,
+            ICELAKE_SERVER,
+        ],
+    },
+];
+
+pub fn find_cpu_model(name: &str) -> Option<&'static CpuModel> {
+    CPU_MODELS.iter().find(|m| m.name == name)
+}
+
+pub fn find_cpuid_feature(name: &str) -> Option<&'static CpuidFeature> {
+    CPUID_FEATURES.iter().find(|f| f.name == name)
+}
+
+fn cpuid_reg(entry: &mut CpuIdEntry, reg: CpuidReg) -> &mut u32 {
+    match reg {
+        CpuidReg::EAX => &mut entry.eax,
+        CpuidReg::EBX => &mut entry.ebx,
+        CpuidReg::ECX => &mut entry.ecx,
+        CpuidReg::EDX => &mut entry.edx,
+    }
+}
+
+// Encode the family, model and stepping the way leaf 0x1 EAX reports them.
+fn cpuid_signature((family, model, stepping): (u32, u32, u32)) -> u32 {
+    let mut eax = stepping & 0xf | (model & 0xf) << 4;
+    if family >= 0xf {
+        eax |= 0xf << 8 | (family - 0xf) << 20;
+    } else {
+        eax |= family << 8;
+    }
+    if family == 0x6 || family >= 0xf {
+        eax |= (model >> 4) << 16;
+    }
+    eax
+}
+
+fn update_cpuid_identification(cpuid: &mut [CpuIdEntry], model: &CpuModel) {
+    let signature = cpuid_signature(model.signature);
+    let mut brand = [0u8; 48];
+    let name = format!("{} CPU", model.name);
+    brand[..name.len()].copy_from_slice(name.as_bytes());
+
+    for entry in cpuid.iter_mut() {
+        match entry.function {
+            0x1 | 0x8000_0001 => entry.eax = signature,
+            0x8000_0002..=0x8000_0004 => {
+                let offset = (entry.function - 0x8000_0002) as usize * 16;
+                let regs = [CpuidReg::EAX, CpuidReg::EBX, CpuidReg::ECX, CpuidReg::EDX];
+                for (i, reg) in regs.into_iter().enumerate() {
+                    let bytes = &brand[offset + i * 4..offset + (i + 1) * 4];
+                    *cpuid_reg(entry, reg) = u32::from_le_bytes(bytes.try_into().unwrap());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Restrict the CPUID features to the ones of the CPU model, if any, then
+/// apply the explicit additions and removals, the latter taking precedence.
+/// The host must support every feature exposed this way.
+pub fn apply_cpu_model(
+    cpuid: &mut [CpuIdEntry],
+    model: Option<&str>,
+    add: &[String],
+    remove: &[String],
+) -> Result<(), Error> {
+    let model = model
+        .map(|m| find_cpu_model(m).ok_or_else(|| Error::UnknownCpuModel(m.to_string())))
+        .transpose()?;
+    for name in add.iter().chain(remove.iter()) {
+        find_cpuid_feature(name).ok_or_else(|| Error::UnknownCpuidFeature(name.clone()))?;
+    }
+
+    for feature in CPUID_FEATURES {
+        let name = feature.name.to_string();
+        let enabled = if remove.contains(&name) {
+            false
+        } else if add.contains(&name) {
+            true
+        } else if let Some(model) = model {
+            model.has_feature(feature.name)
+        } else {
+            continue;
+        };
+
+        if enabled {
+            if !CpuidPatch::is_feature_enabled(
+                cpuid,
+                feature.function,
+                feature.index,
+                feature.reg,
+                feature.bit as usize,
+            ) {
+                return Err(Error::CpuidFeatureUnsupported(name));
+            }
+            continue;
+        }
+
+        for entry in cpuid.iter_mut() {
+            if entry.function == feature.function && entry.index == feature.index {
+                *cpuid_reg(entry, feature.reg) &= !(1 << feature.bit);
+            }
+        }
+    }
+
+    if let Some(model) = model {
+        // Hide the features unknown to the models, which the guest could
+        // otherwise find on one host and not on another.
+        for register in FEATURE_REGISTERS {
+            let known = CPUID_FEATURES
+                .iter()
+                .filter(|f| {
+                    f.function == register.function
+                        && f.index == register.index
+                        && f.reg == register.reg
+                })
+                .fold(0u32, |mask, f| mask | 1 << f.bit);
+            for entry in cpuid.iter_mut() {
+                if entry.function == register.function && entry.index == register.index {
+                    *cpuid_reg(entry, register.reg) &= known | register.keep;
+                }
+            }
+        }
+
+        update_cpuid_identification(cpuid, model);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_cpuid() -> Vec<CpuIdEntry> {
+        vec![
+            CpuIdEntry {
+                function: 0x1,
+                // sse3, ssse3, sse4_1, sse4_2, popcnt, avx
+                ecx: 1 | 1 << 9 | 1 << 19 | 1 << 20 | 1 << 23 | 1 << 28,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x7,
+                // avx2, rtm
+                ebx: 1 << 5 | 1 << 11,
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_cpu_model_names() {
+        for model in CPU_MODELS {
+            for features in model.features {
+                for name in features.iter() {
+                    assert!(find_cpuid_feature(name).is_some(), "{name}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_cpu_model() {
+        // Without any model, only the explicit removals are applied.
+        let mut cpuid = host_cpuid();
+        apply_cpu_model(&mut cpuid, None, &[], &["rtm".to_string()]).unwrap();
+        assert_eq!(cpuid[0].ecx, host_cpuid()[0].ecx);
+        assert_eq!(cpuid[1].ebx, 1 << 5);
+
+        // The host is missing some x86-64-v2 features.
+        let mut cpuid = host_cpuid();
+        assert!(matches!(
+            apply_cpu_model(&mut cpuid, Some("x86-64-v2"), &[], &[]),
+            Err(Error::CpuidFeatureUnsupported(_))
+        ));
+
+        let mut cpuid = host_cpuid();
+        apply_cpu_model(
+            &mut cpuid,
+            Some("x86-64-v1"),
+            &["sse3".to_string(), "rtm".to_string()],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(cpuid[0].ecx, 1);
+        assert_eq!(cpuid[1].ebx, 1 << 11);
+
+        let mut cpuid = host_cpuid();
+        assert!(matches!(
+            apply_cpu_model(&mut cpuid, Some("Pentium"), &[], &[]),
+            Err(Error::UnknownCpuModel(_))
+        ));
+        assert!(matches!(
+            apply_cpu_model(&mut cpuid, None, &["avx9".to_string()], &[]),
+            Err(Error::UnknownCpuidFeature(_))
+        ));
+    }
+
+    #[test]
+    fn test_cpu_model_unknown_features() {
+        let mut host = host_cpuid();
+        // Unknown bits: sdbg (leaf 0x1 ECX 11) and la57 (leaf 0x7 ECX 16),
+        // next to the hypervisor bit, which is kept.
+        host[0].ecx |= 1 << 11 | 1 << 31;
+        host[1].ecx = 1 << 16;
+
+        // Without any model, unknown bits are left untouched.
+        let mut cpuid = host.clone();
+        apply_cpu_model(&mut cpuid, None, &[], &[]).unwrap();
+        assert_eq!(cpuid[0].ecx, host[0].ecx);
+        assert_eq!(cpuid[1].ecx, host[1].ecx);
+
+        let mut cpuid = host;
+        apply_cpu_model(&mut cpuid, Some("x86-64-v1"), &["avx".to_string()], &[]).unwrap();
+        assert_eq!(cpuid[0].ecx, 1 << 28 | 1 << 31);
+        assert_eq!(cpuid[1].ebx, 0);
+        assert_eq!(cpuid[1].ecx, 0);
+    }
+
+    #[test]
+    fn test_cpu_model_identification() {
+        assert_eq!(cpuid_signature((6, 85, 4)), 0x0005_0654);
+        assert_eq!(cpuid_signature((6, 106, 6)), 0x0006_06a6);
+        assert_eq!(cpuid_signature((15, 6, 1)), 0x0000_0f61);
+        assert_eq!(cpuid_signature((0x19, 0x11, 1)), 0x00a1_0f11);
+
+        let mut cpuid = host_cpuid();
+        cpuid[0].eax = 0x000a_06a7;
+        for function in 0x8000_0002..=0x8000_0004 {
+            cpuid.push(CpuIdEntry {
+                function,
+                eax: u32::MAX,
+                ebx: u32::MAX,
+                ecx: u32::MAX,
+                edx: u32::MAX,
+                ..Default::default()
+            });
+        }
+        apply_cpu_model(&mut cpuid, Some("x86-64-v1"), &[], &[]).unwrap();
+        assert_eq!(cpuid[0].eax, 0x0000_0f61);
+
+        let mut brand = Vec::new();
+        for entry in &cpuid[2..] {
+            for reg in [entry.eax, entry.ebx, entry.ecx, entry.edx] {
+                brand.extend_from_slice(&reg.to_le_bytes());
+            }
+        }
+        assert_eq!(&brand[..13], b"x86-64-v1 CPU");
+        assert!(brand[13..].iter().all(|b| *b == 0));
+    }
+}
@@ -73,6 +73,15 @@ pub const EBDA_START: GuestAddress = GuestAddress(0xa0000);
 // ACPI RSDP table
 pub const RSDP_POINTER: GuestAddress = EBDA_START;
 
+// ACPI FACS table, holding the S3 waking vector. 64 bytes aligned, right
+// after the RSDP.
+pub const FACS_START: GuestAddress = GuestAddress(0xa0040);
+
+// TPM event log, described by the ACPI TPM2 table, right below the SMBIOS
+// tables.
+pub const TPM_LOG_START: GuestAddress = GuestAddress(0xe0000);
+pub const TPM_LOG_SIZE: u64 = 0x10000;
+
 pub const SMBIOS_START: u64 = 0xf0000; // First possible location per the spec.
 
 // == End of "EBDA" range ==
@@ -112,6 +121,10 @@ pub const KVM_IDENTITY_MAP_SIZE: u64 = 4 << 10;
 pub const TPM_START: GuestAddress = GuestAddress(0xfed4_0000);
 pub const TPM_SIZE: u64 = 0x1000;
 
+/// Register set of the interrupt remapping unit, where QEMU places the one
+/// of its emulated VT-d
+pub const INTERRUPT_REMAPPING_START: GuestAddress = GuestAddress(0xfed9_0000);
+
 // IOAPIC
 pub const IOAPIC_START: GuestAddress = GuestAddress(0xfec0_0000);
 pub const IOAPIC_SIZE: u64 = 0x20;
@@ -119,6 +132,11 @@ pub const IOAPIC_SIZE: u64 = 0x20;
 // APIC
 pub const APIC_START: GuestAddress = GuestAddress(0xfee0_0000);
 
+/// UEFI variable store, in the last 4 MiB below 4 GiB where EDK2 expects
+/// the flash of its variables
+pub const UEFI_VARS_START: GuestAddress = GuestAddress(0xffc0_0000);
+pub const UEFI_VARS_SIZE: u64 = 0x40_0000;
+
 // == End of "32-bit reserved" range. ==
 
 // ** 64-bit RAM start (start: 4GiB, length: varies) **
@@ -7,6 +7,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 use std::sync::Arc;
+pub mod cpu_model;
 pub mod interrupts;
 pub mod layout;
 mod mpspec;
@@ -37,9 +38,12 @@ const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 const MTRR_EDX_BIT: u8 = 12; // Hypervisor ecx bit.
 const INVARIANT_TSC_EDX_BIT: u8 = 8; // Invariant TSC bit on 0x8000_0007 EDX
+const HYBRID_EDX_BIT: u8 = 15; // Hybrid part bit on 0x7 EDX
 const AMX_BF16: u8 = 22; // AMX tile computation on bfloat16 numbers
 const AMX_TILE: u8 = 24; // AMX tile load/store instructions
 const AMX_INT8: u8 = 25; // AMX tile computation on 8-bit integers
+const VMX_ECX_BIT: u8 = 5; // VMX bit on 0x1 ECX
+const SVM_ECX_BIT: u8 = 2; // SVM bit on 0x8000_0001 ECX
 
 // KVM feature bits
 #[cfg(feature = "tdx")]
@@ -148,13 +152,28 @@ struct BootParamsWrapper(boot_params);
 // SAFETY: BootParamsWrap is a wrapper over `boot_params` (a series of ints).
 unsafe impl ByteValued for BootParamsWrapper {}
 
+/// Hyper-V enlightenments exposed to the guest along with the KVM Hyper-V
+/// emulation.
+#[derive(Copy, Clone, Debug)]
+pub struct HypervFeatures {
+    pub synic: bool,
+    pub stimer: bool,
+    pub reenlightenment: bool,
+    pub tlbflush: bool,
+}
+
 pub struct CpuidConfig {
     pub sgx_epc_sections: Option<Vec<SgxEpcSection>>,
     pub phys_bits: u8,
     pub kvm_hyperv: bool,
+    pub kvm_hyperv_features: HypervFeatures,
     #[cfg(feature = "tdx")]
     pub tdx: bool,
     pub amx: bool,
+    pub nested: bool,
+    pub model: Option<String>,
+    pub cpuid_add: Option<Vec<String>>,
+    pub cpuid_remove: Option<Vec<String>>,
 }
 
 #[derive(Debug)]
@@ -204,6 +223,15 @@ pub enum Error {
     /// Error checking CPUID compatibility
     CpuidCheckCompatibility,
 
+    /// Unknown CPU model
+    UnknownCpuModel(String),
+
+    /// Unknown CPUID feature
+    UnknownCpuidFeature(String),
+
+    /// CPUID feature required by the CPU configuration is not supported by the host
+    CpuidFeatureUnsupported(String),
+
     // Error writing EBDA address
     EbdaSetup(vm_memory::GuestMemoryError),
 
@@ -221,7 +249,7 @@ impl From<Error> for super::Error {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CpuidReg {
     EAX,
     EBX,
@@ -229,6 +257,24 @@ pub enum CpuidReg {
     EDX,
 }
 
+/// Type of a core on a hybrid platform, as reported through the hybrid
+/// information CPUID leaf 0x1a.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoreType {
+    Efficiency,
+    Performance,
+}
+
+impl CoreType {
+    // Value of the core type field, bits 31:24 of 0x1a EAX
+    fn cpuid_value(&self) -> u32 {
+        match self {
+            CoreType::Efficiency => 0x20,
+            CoreType::Performance => 0x40,
+        }
+    }
+}
+
 pub struct CpuidPatch {
     pub function: u32,
     pub index: u32,
@@ -626,6 +672,8 @@ pub fn generate_common_cpuid(
 
     CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
+    update_cpuid_nested(&mut cpuid, config.nested);
+
     if let Some(sgx_epc_sections) = &config.sgx_epc_sections {
         update_cpuid_sgx(&mut cpuid, sgx_epc_sections)?;
     }
@@ -718,6 +766,13 @@ pub fn generate_common_cpuid(
         });
     }
 
+    cpu_model::apply_cpu_model(
+        &mut cpuid,
+        config.model.as_deref(),
+        config.cpuid_add.as_deref().unwrap_or_default(),
+        config.cpuid_remove.as_deref().unwrap_or_default(),
+    )?;
+
     if config.kvm_hyperv {
         // Remove conflicting entries
         cpuid.retain(|c| c.function != 0x4000_0000);
@@ -743,18 +798,34 @@ pub fn generate_common_cpuid(
             ebx: 0xa0000, // "Version"
             ..Default::default()
         });
+        let features = &config.kvm_hyperv_features;
+        let mut privileges = 1 << 1 // AccessPartitionReferenceCounter
+                   | 1 << 9; // AccessPartitionReferenceTsc
+        let mut recommendations = 1 << 5; // Recommend relaxed timing
+        if features.synic {
+            privileges |= 1 << 2; // AccessSynicRegs
+        }
+        if features.stimer {
+            privileges |= 1 << 3; // AccessSyntheticTimerRegs
+        }
+        if features.reenlightenment {
+            privileges |= 1 << 13; // AccessReenlightenmentControls
+        }
+        if features.tlbflush {
+            privileges |= 1 << 5 // AccessHypercallMsrs
+                   | 1 << 6; // AccessVpIndex
+            recommendations |= 1 << 2 // Recommend remote TLB flush hypercalls
+                   | 1 << 11; // Recommend extended processor masks
+        }
         cpuid.push(CpuIdEntry {
             function: 0x4000_0003,
-            eax: 1 << 1 // AccessPartitionReferenceCounter
-                   | 1 << 2 // AccessSynicRegs
-                   | 1 << 3 // AccessSyntheticTimerRegs
-                   | 1 << 9, // AccessPartitionReferenceTsc
+            eax: privileges,
             edx: 1 << 3, // CPU dynamic partitioning
             ..Default::default()
         });
         cpuid.push(CpuIdEntry {
             function: 0x4000_0004,
-            eax: 1 << 5, // Recommend relaxed timing
+            eax: recommendations,
             ..Default::default()
         });
         for i in 0x4000_0005..=0x4000_000a {
@@ -768,6 +839,7 @@ pub fn generate_common_cpuid(
     Ok(cpuid)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn configure_vcpu(
     vcpu: &Arc<dyn hypervisor::Vcpu>,
     id: u8,
@@ -776,6 +848,7 @@ pub fn configure_vcpu(
     kvm_hyperv: bool,
     cpu_vendor: CpuVendor,
     topology: Option<(u8, u8, u8)>,
+    core_type: Option<CoreType>,
 ) -> super::Result<()> {
     // Per vCPU CPUID changes; common are handled via generate_common_cpuid()
     let mut cpuid = cpuid;
@@ -795,6 +868,10 @@ pub fn configure_vcpu(
         update_cpuid_topology(&mut cpuid, t.0, t.1, t.2, cpu_vendor, id);
     }
 
+    if let Some(core_type) = core_type {
+        update_cpuid_core_type(&mut cpuid, core_type);
+    }
+
     // Set ApicId in cpuid for each vcpu
     // SAFETY: get host cpuid when eax=1
     let mut cpu_ebx = unsafe { core::arch::x86_64::__cpuid(1) }.ebx;
@@ -833,7 +910,8 @@ pub fn configure_vcpu(
     vcpu.set_cpuid2(&cpuid)
         .map_err(|e| Error::SetSupportedCpusFailed(e.into()))?;
 
-    if kvm_hyperv {
+    // Only emulate the SynIC when it is exposed to the guest
+    if kvm_hyperv && CpuidPatch::is_feature_enabled(&cpuid, 0x4000_0003, 0, CpuidReg::EAX, 2) {
         vcpu.enable_hyperv_synic().unwrap();
     }
 
@@ -848,6 +926,16 @@ pub fn configure_vcpu(
     Ok(())
 }
 
+/// Configures the boot vCPU for resuming from the S3 sleep state at
+/// `waking_vector`. The other vCPUs are brought up again by the OS.
+pub fn configure_vcpu_wakeup(
+    vcpu: &Arc<dyn hypervisor::Vcpu>,
+    waking_vector: u32,
+) -> super::Result<()> {
+    regs::setup_wakeup_regs(vcpu, waking_vector).map_err(Error::RegsConfiguration)?;
+    Ok(())
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
@@ -893,6 +981,8 @@ pub fn configure_system(
     _num_cpus: u8,
     rsdp_addr: Option<GuestAddress>,
     sgx_epc_region: Option<SgxEpcRegion>,
+    manufacturer: Option<&str>,
+    product: Option<&str>,
     serial_number: Option<&str>,
     uuid: Option<&str>,
     oem_strings: Option<&[&str]>,
@@ -902,8 +992,15 @@ pub fn configure_system(
         .write_obj((layout::EBDA_START.0 >> 4) as u16, layout::EBDA_POINTER)
         .map_err(Error::EbdaSetup)?;
 
-    let size = smbios::setup_smbios(guest_mem, serial_number, uuid, oem_strings)
-        .map_err(Error::SmbiosSetup)?;
+    let size = smbios::setup_smbios(
+        guest_mem,
+        manufacturer,
+        product,
+        serial_number,
+        uuid,
+        oem_strings,
+    )
+    .map_err(Error::SmbiosSetup)?;
 
     // Place the MP table after the SMIOS table aligned to 16 bytes
     let offset = GuestAddress(layout::SMBIOS_START).unchecked_add(size);
@@ -1183,6 +1280,40 @@ pub fn get_host_cpu_phys_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>) -> u
     }
 }
 
+// Advertise a hybrid part and report the type of the core, raising the
+// maximum basic leaf for the guest to read the hybrid information leaf.
+fn update_cpuid_core_type(cpuid: &mut Vec<CpuIdEntry>, core_type: CoreType) {
+    const HYBRID_INFO_LEAF: u32 = 0x1a;
+
+    let max_leaf = cpuid
+        .iter()
+        .find(|entry| entry.function == 0)
+        .map_or(0, |entry| entry.eax);
+    if max_leaf < HYBRID_INFO_LEAF {
+        CpuidPatch::set_cpuid_reg(cpuid, 0, None, CpuidReg::EAX, HYBRID_INFO_LEAF);
+    }
+
+    CpuidPatch::patch_cpuid(
+        cpuid,
+        vec![CpuidPatch {
+            function: 7,
+            index: 0,
+            flags_bit: None,
+            eax_bit: None,
+            ebx_bit: None,
+            ecx_bit: None,
+            edx_bit: Some(HYBRID_EDX_BIT),
+        }],
+    );
+    CpuidPatch::set_cpuid_reg(
+        cpuid,
+        HYBRID_INFO_LEAF,
+        Some(0),
+        CpuidReg::EAX,
+        core_type.cpuid_value() << 24,
+    );
+}
+
 fn update_cpuid_topology(
     cpuid: &mut Vec<CpuIdEntry>,
     threads_per_core: u8,
@@ -1307,6 +1438,36 @@ fn update_cpuid_topology(
     }
 }
 
+// VMX and SVM are only reported by KVM when nested virtualization is enabled
+// on the host, and are hidden from the guest unless nested virtualization is
+// requested.
+fn update_cpuid_nested(cpuid: &mut Vec<CpuIdEntry>, nested: bool) {
+    if nested {
+        if !CpuidPatch::is_feature_enabled(cpuid, 0x1, 0, CpuidReg::ECX, VMX_ECX_BIT as usize)
+            && !CpuidPatch::is_feature_enabled(
+                cpuid,
+                0x8000_0001,
+                0,
+                CpuidReg::ECX,
+                SVM_ECX_BIT as usize,
+            )
+        {
+            info!("Nested virtualization is not supported by the host");
+        }
+        return;
+    }
+
+    for entry in cpuid.iter_mut() {
+        match entry.function {
+            0x1 => entry.ecx &= !(1 << VMX_ECX_BIT),
+            0x8000_0001 => entry.ecx &= !(1 << SVM_ECX_BIT),
+            _ => {}
+        }
+    }
+    // Drop the SVM features leaf
+    cpuid.retain(|c| c.function != 0x8000_000a);
+}
+
 // The goal is to update the CPUID sub-leaves to reflect the number of EPC
 // sections exposed to the guest.
 fn update_cpuid_sgx(
@@ -1383,6 +1544,8 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
         );
         assert!(config_err.is_err());
 
@@ -1405,6 +1568,8 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -1432,6 +1597,8 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
         )
         .unwrap();
 
@@ -1445,6 +1612,8 @@ mod tests {
             None,
             None,
             None,
+            None,
+            None,
         )
         .unwrap();
     }
@@ -1473,4 +1642,72 @@ mod tests {
 
         assert_eq!(format!("{memmap:?}"), format!("{expected_memmap:?}"));
     }
+
+    #[test]
+    fn test_update_cpuid_core_type() {
+        let leaf = |cpuid: &[CpuIdEntry], function: u32| {
+            *cpuid
+                .iter()
+                .find(|entry| entry.function == function)
+                .unwrap()
+        };
+        let mut cpuid = vec![
+            CpuIdEntry {
+                function: 0,
+                eax: 0x16,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 7,
+                flags: CPUID_FLAG_VALID_INDEX,
+                ..Default::default()
+            },
+        ];
+
+        update_cpuid_core_type(&mut cpuid, CoreType::Efficiency);
+        assert_eq!(leaf(&cpuid, 0).eax, 0x1a);
+        assert_eq!(leaf(&cpuid, 7).edx, 1 << HYBRID_EDX_BIT);
+        assert_eq!(leaf(&cpuid, 0x1a).eax, 0x20 << 24);
+
+        // A higher maximum leaf is kept
+        let mut cpuid = vec![CpuIdEntry {
+            function: 0,
+            eax: 0x20,
+            ..Default::default()
+        }];
+        update_cpuid_core_type(&mut cpuid, CoreType::Performance);
+        assert_eq!(leaf(&cpuid, 0).eax, 0x20);
+        assert_eq!(leaf(&cpuid, 0x1a).eax, 0x40 << 24);
+    }
+
+    #[test]
+    fn test_update_cpuid_nested() {
+        let host_cpuid = vec![
+            CpuIdEntry {
+                function: 0x1,
+                ecx: 1 << VMX_ECX_BIT | 1 << 31,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x8000_0001,
+                ecx: 1 << SVM_ECX_BIT | 1,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: 0x8000_000a,
+                eax: 1,
+                ..Default::default()
+            },
+        ];
+
+        let mut cpuid = host_cpuid.clone();
+        update_cpuid_nested(&mut cpuid, true);
+        assert_eq!(cpuid, host_cpuid);
+
+        let mut cpuid = host_cpuid;
+        update_cpuid_nested(&mut cpuid, false);
+        assert_eq!(cpuid.len(), 2);
+        assert_eq!(cpuid[0].ecx, 1 << 31);
+        assert_eq!(cpuid[1].ecx, 1);
+    }
 }
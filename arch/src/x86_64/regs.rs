@@ -9,8 +9,10 @@
 use crate::layout::{BOOT_GDT_START, BOOT_IDT_START, PVH_INFO_START};
 use crate::GuestMemoryMmap;
 use hypervisor::arch::x86::gdt::{gdt_entry, segment_from_gdt};
-use hypervisor::arch::x86::regs::CR0_PE;
-use hypervisor::arch::x86::{FpuState, SpecialRegisters, StandardRegisters};
+use hypervisor::arch::x86::regs::{CR0_ET, CR0_PE};
+use hypervisor::arch::x86::{
+    DescriptorTable, FpuState, SegmentRegister, SpecialRegisters, StandardRegisters,
+};
 use std::sync::Arc;
 use std::{mem, result};
 use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryError};
@@ -100,6 +102,77 @@ pub fn setup_sregs(mem: &GuestMemoryMmap, vcpu: &Arc<dyn hypervisor::Vcpu>) -> R
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
 
+/// Configures the registers of a CPU woken up from the S3 sleep state, for it
+/// to start executing the OS waking code in real mode.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `waking_vector` - Real mode address of the waking code, from the FACS.
+pub fn setup_wakeup_regs(vcpu: &Arc<dyn hypervisor::Vcpu>, waking_vector: u32) -> Result<()> {
+    let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+    configure_wakeup_sregs(&mut sregs, waking_vector);
+    vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)?;
+
+    let regs = StandardRegisters {
+        rflags: 0x0000000000000002u64,
+        rip: u64::from(waking_vector & 0xf),
+        ..Default::default()
+    };
+    vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
+}
+
+fn configure_wakeup_sregs(sregs: &mut SpecialRegisters, waking_vector: u32) {
+    // The ACPI specification has the waking code entered at
+    // (waking_vector >> 4):(waking_vector & 0xf)
+    let code_seg = SegmentRegister {
+        base: u64::from(waking_vector & !0xf),
+        limit: 0xffff,
+        selector: (waking_vector >> 4) as u16,
+        type_: 0xb,
+        present: 1,
+        s: 1,
+        ..Default::default()
+    };
+    let data_seg = SegmentRegister {
+        limit: 0xffff,
+        type_: 0x3,
+        present: 1,
+        s: 1,
+        ..Default::default()
+    };
+
+    sregs.cs = code_seg;
+    sregs.ds = data_seg;
+    sregs.es = data_seg;
+    sregs.fs = data_seg;
+    sregs.gs = data_seg;
+    sregs.ss = data_seg;
+    sregs.tr = SegmentRegister {
+        limit: 0xffff,
+        type_: 0xb,
+        present: 1,
+        ..Default::default()
+    };
+    sregs.ldt = SegmentRegister {
+        limit: 0xffff,
+        type_: 0x2,
+        present: 1,
+        ..Default::default()
+    };
+    sregs.gdt = DescriptorTable {
+        base: 0,
+        limit: 0xffff,
+    };
+    sregs.idt = sregs.gdt;
+
+    sregs.cr0 = CR0_ET;
+    sregs.cr2 = 0;
+    sregs.cr3 = 0;
+    sregs.cr4 = 0;
+    sregs.efer = 0;
+}
+
 const BOOT_GDT_MAX: usize = 4;
 
 fn write_gdt_table(table: &[u64], guest_mem: &GuestMemoryMmap) -> Result<()> {
@@ -209,4 +282,18 @@ mod tests {
         assert_eq!(CR0_PE, sregs.cr0);
         assert_eq!(0, sregs.cr4);
     }
+
+    #[test]
+    fn wakeup_sregs() {
+        let mut sregs: SpecialRegisters = Default::default();
+        configure_wakeup_sregs(&mut sregs, 0x9a123);
+
+        assert_eq!(0x9a12, sregs.cs.selector);
+        assert_eq!(0x9a120, sregs.cs.base);
+        assert_eq!(0xffff, sregs.cs.limit);
+        assert_eq!(0, sregs.ds.base);
+        assert_eq!(0, sregs.ss.selector);
+        assert_eq!(0, sregs.cr0 & CR0_PE);
+        assert_eq!(0, sregs.efer);
+    }
 }
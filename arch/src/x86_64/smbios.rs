@@ -182,6 +182,8 @@ fn write_string(
 
 pub fn setup_smbios(
     mem: &GuestMemoryMmap,
+    manufacturer: Option<&str>,
+    product: Option<&str>,
     serial_number: Option<&str>,
     uuid: Option<&str>,
     oem_strings: Option<&[&str]>,
@@ -229,8 +231,8 @@ pub fn setup_smbios(
             ..Default::default()
         };
         curptr = write_and_incr(mem, smbios_sysinfo, curptr)?;
-        curptr = write_string(mem, "Cloud Hypervisor", curptr)?;
-        curptr = write_string(mem, "cloud-hypervisor", curptr)?;
+        curptr = write_string(mem, manufacturer.unwrap_or("Cloud Hypervisor"), curptr)?;
+        curptr = write_string(mem, product.unwrap_or("cloud-hypervisor"), curptr)?;
         if let Some(serial_number) = serial_number {
             curptr = write_string(mem, serial_number, curptr)?;
         }
@@ -316,10 +318,35 @@ mod tests {
     fn entrypoint_checksum() {
         let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
 
-        setup_smbios(&mem, None, None, None).unwrap();
+        setup_smbios(&mem, None, None, None, None, None).unwrap();
 
         let smbios_ep: Smbios30Entrypoint = mem.read_obj(GuestAddress(SMBIOS_START)).unwrap();
 
         assert_eq!(compute_checksum(&smbios_ep), 0);
     }
+
+    #[test]
+    fn system_information_strings() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(SMBIOS_START), 4096)]).unwrap();
+
+        setup_smbios(&mem, Some("ACME"), Some("Rocket"), Some("1234"), None, None).unwrap();
+
+        let smbios_ep: Smbios30Entrypoint = mem.read_obj(GuestAddress(SMBIOS_START)).unwrap();
+        let mut addr = GuestAddress(smbios_ep.physptr);
+
+        // Skip the BIOS information structure and its strings
+        let bios_info: SmbiosBiosInfo = mem.read_obj(addr).unwrap();
+        addr = addr.unchecked_add(bios_info.length as u64);
+        addr = addr.unchecked_add(b"cloud-hypervisor\00\0\0".len() as u64);
+
+        let sys_info: SmbiosSysInfo = mem.read_obj(addr).unwrap();
+        assert_eq!(sys_info.r#type, SYSTEM_INFORMATION);
+        assert_eq!(sys_info.serial_number, 3);
+        addr = addr.unchecked_add(sys_info.length as u64);
+
+        let expected = b"ACME\0Rocket\01234\0\0";
+        let mut strings = [0u8; 18];
+        mem.read_slice(&mut strings, addr).unwrap();
+        assert_eq!(&strings, expected);
+    }
 }
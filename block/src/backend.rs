@@ -0,0 +1,348 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disk backends the virtio-block devices are created from.
+//!
+//! A backend turns the path of a disk into a [`DiskFile`], which the device
+//! relies on for all its I/O. The raw, QCOW2, VHDX and fixed VHD images, as
+//! well as the NBD exports given by URI, are handled by built-in backends,
+//! picked from the path and the image format unless the disk selects a
+//! backend by name. Additional backends, for instance reaching other network
+//! storage, can be registered with [`register_disk_backend()`] before the
+//! disks get created, without any change to the device itself.
+
+use crate::async_io::DiskFile;
+#[cfg(feature = "io_uring")]
+use crate::fixed_vhd_async::FixedVhdDiskAsync;
+use crate::fixed_vhd_sync::FixedVhdDiskSync;
+use crate::nbd::{is_nbd_uri, NbdDisk};
+use crate::qcow;
+use crate::qcow_sync::QcowDiskSync;
+#[cfg(feature = "io_uring")]
+use crate::raw_async::RawFileDisk;
+use crate::raw_async_aio::RawFileDiskAio;
+use crate::raw_sync::RawFileDiskSync;
+use crate::vhdx::VhdxError;
+use crate::vhdx_sync::VhdxDiskSync;
+use crate::{detect_image_type, ImageType};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Name of the built-in backend for raw images.
+pub const RAW_DISK_BACKEND: &str = "raw";
+/// Name of the built-in backend for QCOW2 images.
+pub const QCOW2_DISK_BACKEND: &str = "qcow2";
+/// Name of the built-in backend for VHDX images.
+pub const VHDX_DISK_BACKEND: &str = "vhdx";
+/// Name of the built-in backend for fixed VHD images.
+pub const FIXED_VHD_DISK_BACKEND: &str = "vhd";
+/// Name of the built-in backend for NBD exports.
+pub const NBD_DISK_BACKEND: &str = "nbd";
+
+#[derive(Error, Debug)]
+pub enum DiskBackendError {
+    /// No backend registered with this name.
+    #[error("Unknown disk backend: {0}")]
+    UnknownBackend(String),
+    /// Failed opening the disk image.
+    #[error("Failed opening the disk image: {0}")]
+    Open(#[source] io::Error),
+    /// Failed detecting the format of the disk image.
+    #[error("Failed detecting the disk image type: {0}")]
+    DetectImageType(#[source] io::Error),
+    /// Failed creating a fixed VHD disk.
+    #[error("Failed creating the fixed VHD disk: {0}")]
+    FixedVhd(#[source] io::Error),
+    /// Failed creating a QCOW2 disk.
+    #[error("Failed creating the QCOW2 disk: {0}")]
+    Qcow(qcow::Error),
+    /// Failed creating a VHDX disk.
+    #[error("Failed creating the VHDX disk: {0}")]
+    Vhdx(#[source] VhdxError),
+    /// Failed connecting to an NBD export.
+    #[error("Failed connecting to the NBD export: {0}")]
+    Nbd(#[source] io::Error),
+    /// Failed creating the disk from an external backend.
+    #[error("Failed creating the disk: {0}")]
+    Backend(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+pub type DiskBackendResult<T> = std::result::Result<T, DiskBackendError>;
+
+/// How the disk is accessed, and which I/O interfaces the backend can use.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskBackendOptions {
+    pub readonly: bool,
+    /// Bypass the host page cache (`O_DIRECT`).
+    pub direct: bool,
+    /// io_uring is supported on the host and not disabled for the disk.
+    pub io_uring: bool,
+    /// aio is supported on the host and not disabled for the disk.
+    pub aio: bool,
+}
+
+impl DiskBackendOptions {
+    /// Open the disk image as a regular file or block device.
+    pub fn open_file(&self, path: &Path) -> DiskBackendResult<File> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        options.write(!self.readonly);
+        if self.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        options.open(path).map_err(DiskBackendError::Open)
+    }
+}
+
+pub trait DiskBackend: Send + Sync {
+    /// Name the backend is selected with.
+    fn name(&self) -> &str;
+    /// Create the disk found at `path`, which does not have to be a file for
+    /// backends reaching remote storage.
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>>;
+}
+
+struct RawDiskBackend;
+
+impl DiskBackend for RawDiskBackend {
+    fn name(&self) -> &str {
+        RAW_DISK_BACKEND
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>> {
+        let file = options.open_file(path)?;
+
+        #[cfg(feature = "io_uring")]
+        if options.io_uring {
+            info!("Using asynchronous RAW disk file (io_uring)");
+            return Ok(Box::new(RawFileDisk::new(file)));
+        }
+
+        if options.aio {
+            info!("Using asynchronous RAW disk file (aio)");
+            Ok(Box::new(RawFileDiskAio::new(file)))
+        } else {
+            info!("Using synchronous RAW disk file");
+            Ok(Box::new(RawFileDiskSync::new(file)))
+        }
+    }
+}
+
+struct QcowDiskBackend;
+
+impl DiskBackend for QcowDiskBackend {
+    fn name(&self) -> &str {
+        QCOW2_DISK_BACKEND
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>> {
+        let file = options.open_file(path)?;
+
+        info!("Using synchronous QCOW disk file");
+        Ok(Box::new(
+            QcowDiskSync::new(file, options.direct).map_err(DiskBackendError::Qcow)?,
+        ))
+    }
+}
+
+struct VhdxDiskBackend;
+
+impl DiskBackend for VhdxDiskBackend {
+    fn name(&self) -> &str {
+        VHDX_DISK_BACKEND
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>> {
+        let file = options.open_file(path)?;
+
+        info!("Using synchronous VHDX disk file");
+        Ok(Box::new(
+            VhdxDiskSync::new(file).map_err(DiskBackendError::Vhdx)?,
+        ))
+    }
+}
+
+struct FixedVhdDiskBackend;
+
+impl DiskBackend for FixedVhdDiskBackend {
+    fn name(&self) -> &str {
+        FIXED_VHD_DISK_BACKEND
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>> {
+        let file = options.open_file(path)?;
+
+        #[cfg(feature = "io_uring")]
+        if options.io_uring {
+            info!("Using asynchronous fixed VHD disk file (io_uring)");
+            return Ok(Box::new(
+                FixedVhdDiskAsync::new(file).map_err(DiskBackendError::FixedVhd)?,
+            ));
+        }
+
+        info!("Using synchronous fixed VHD disk file");
+        Ok(Box::new(
+            FixedVhdDiskSync::new(file).map_err(DiskBackendError::FixedVhd)?,
+        ))
+    }
+}
+
+struct NbdDiskBackend;
+
+impl DiskBackend for NbdDiskBackend {
+    fn name(&self) -> &str {
+        NBD_DISK_BACKEND
+    }
+
+    fn open(
+        &self,
+        path: &Path,
+        options: &DiskBackendOptions,
+    ) -> DiskBackendResult<Box<dyn DiskFile>> {
+        info!("Using NBD disk");
+        Ok(Box::new(
+            NbdDisk::new(path, options.readonly).map_err(DiskBackendError::Nbd)?,
+        ))
+    }
+}
+
+// Backends registered on top of the built-in ones.
+static DISK_BACKENDS: Mutex<Vec<Arc<dyn DiskBackend>>> = Mutex::new(Vec::new());
+
+/// Register a disk backend, for disks to select it by its name. A backend
+/// registered with the name of a built-in one, or of a backend registered
+/// earlier, replaces it.
+pub fn register_disk_backend(backend: Arc<dyn DiskBackend>) {
+    let mut backends = DISK_BACKENDS.lock().unwrap();
+    backends.retain(|b| b.name() != backend.name());
+    backends.push(backend);
+}
+
+/// Find the disk backend registered with `name`.
+pub fn disk_backend(name: &str) -> Option<Arc<dyn DiskBackend>> {
+    if let Some(backend) = DISK_BACKENDS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|b| b.name() == name)
+    {
+        return Some(backend.clone());
+    }
+
+    match name {
+        RAW_DISK_BACKEND => Some(Arc::new(RawDiskBackend)),
+        QCOW2_DISK_BACKEND => Some(Arc::new(QcowDiskBackend)),
+        VHDX_DISK_BACKEND => Some(Arc::new(VhdxDiskBackend)),
+        FIXED_VHD_DISK_BACKEND => Some(Arc::new(FixedVhdDiskBackend)),
+        NBD_DISK_BACKEND => Some(Arc::new(NbdDiskBackend)),
+        _ => None,
+    }
+}
+
+/// Create the disk found at `path` through the backend named `backend`, or
+/// the one handling the NBD URI or the format of the image when none is
+/// named.
+pub fn open_disk(
+    path: &Path,
+    backend: Option<&str>,
+    options: &DiskBackendOptions,
+) -> DiskBackendResult<Box<dyn DiskFile>> {
+    let name = match backend {
+        Some(name) => name,
+        None if is_nbd_uri(path) => NBD_DISK_BACKEND,
+        None => {
+            let mut file = options.open_file(path)?;
+            match detect_image_type(&mut file).map_err(DiskBackendError::DetectImageType)? {
+                ImageType::FixedVhd => FIXED_VHD_DISK_BACKEND,
+                ImageType::Qcow2 => QCOW2_DISK_BACKEND,
+                ImageType::Raw => RAW_DISK_BACKEND,
+                ImageType::Vhdx => VHDX_DISK_BACKEND,
+            }
+        }
+    };
+
+    disk_backend(name)
+        .ok_or_else(|| DiskBackendError::UnknownBackend(name.to_owned()))?
+        .open(path, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_io::{AsyncIo, DiskFileResult};
+    use vmm_sys_util::tempfile::TempFile;
+
+    struct NullDisk;
+
+    impl DiskFile for NullDisk {
+        fn size(&mut self) -> DiskFileResult<u64> {
+            Ok(0x1000)
+        }
+
+        fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+            unimplemented!()
+        }
+    }
+
+    struct NullDiskBackend;
+
+    impl DiskBackend for NullDiskBackend {
+        fn name(&self) -> &str {
+            "null"
+        }
+
+        fn open(
+            &self,
+            _path: &Path,
+            _options: &DiskBackendOptions,
+        ) -> DiskBackendResult<Box<dyn DiskFile>> {
+            Ok(Box::new(NullDisk))
+        }
+    }
+
+    #[test]
+    fn test_disk_backends() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x2000).unwrap();
+        let options = DiskBackendOptions::default();
+
+        // Detected as a raw image
+        let mut disk = open_disk(file.as_path(), None, &options).unwrap();
+        assert_eq!(disk.size().unwrap(), 0x2000);
+
+        assert!(matches!(
+            open_disk(Path::new("nbd://localhost/export"), Some("null"), &options),
+            Err(DiskBackendError::UnknownBackend(_))
+        ));
+
+        register_disk_backend(Arc::new(NullDiskBackend));
+        let mut disk =
+            open_disk(Path::new("nbd://localhost/export"), Some("null"), &options).unwrap();
+        assert_eq!(disk.size().unwrap(), 0x1000);
+    }
+}
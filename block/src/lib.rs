@@ -12,11 +12,13 @@
 extern crate log;
 
 pub mod async_io;
+pub mod backend;
 pub mod fixed_vhd;
 #[cfg(feature = "io_uring")]
 /// Enabled with the `"io_uring"` feature
 pub mod fixed_vhd_async;
 pub mod fixed_vhd_sync;
+pub mod nbd;
 pub mod qcow;
 pub mod qcow_sync;
 #[cfg(feature = "io_uring")]
@@ -0,0 +1,798 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Disks exported by a Network Block Device server.
+//!
+//! The export is given by an `nbd://host[:port][/export]` or
+//! `nbd+unix:///[export]?socket=<path>` URI, and reached by the VMM itself
+//! rather than through the nbd driver of the host kernel. The requests of all
+//! the queues of the disk share a single connection, sent one at a time from
+//! a thread of their own and answered with simple replies. A request the
+//! server doesn't answer in time, or failing for any other reason than an
+//! error replied by the server, completes with EIO, the connection being
+//! established again for the following ones.
+
+use crate::async_io::{
+    AsyncIo, AsyncIoError, AsyncIoResult, DiskFile, DiskFileError, DiskFileResult,
+};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+const NBD_DEFAULT_PORT: u16 = 10809;
+
+// Time the server is given to answer, or to take in a request.
+const NBD_TIMEOUT: Duration = Duration::from_secs(30);
+// Delay before trying to connect again after a failed attempt
+const NBD_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// Longest option reply accepted, the export information and error messages
+// being much shorter.
+const NBD_MAX_OPTION_REPLY: usize = 64 << 10;
+
+// Handshake
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const NBD_REP_MAGIC: u64 = 0x3_e889_0455_65a9;
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_INFO_EXPORT: u16 = 0;
+
+// Transmission
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+
+/// Whether `path` is the URI of an NBD export rather than a file.
+pub fn is_nbd_uri(path: &Path) -> bool {
+    path.to_str().map_or(false, |p| {
+        p.starts_with("nbd://") || p.starts_with("nbd+unix://")
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum NbdAddress {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct NbdUri {
+    address: NbdAddress,
+    export: String,
+}
+
+fn invalid_uri(uri: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid NBD URI: {uri}"),
+    )
+}
+
+fn parse_uri(uri: &str) -> io::Result<NbdUri> {
+    if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+        // The socket is given as a query, the authority staying empty.
+        let (path, query) = rest.split_once('?').ok_or_else(|| invalid_uri(uri))?;
+        let export = path.strip_prefix('/').unwrap_or(path);
+        let socket = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("socket="))
+            .ok_or_else(|| invalid_uri(uri))?;
+
+        return Ok(NbdUri {
+            address: NbdAddress::Unix(PathBuf::from(socket)),
+            export: export.to_owned(),
+        });
+    }
+
+    let rest = uri.strip_prefix("nbd://").ok_or_else(|| invalid_uri(uri))?;
+    let (authority, export) = rest.split_once('/').unwrap_or((rest, ""));
+    let (host, port) = if let Some(ipv6) = authority.strip_prefix('[') {
+        let (host, port) = ipv6.split_once(']').ok_or_else(|| invalid_uri(uri))?;
+        (host, port.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid_uri(uri));
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid_uri(uri))?,
+        None => NBD_DEFAULT_PORT,
+    };
+
+    Ok(NbdUri {
+        address: NbdAddress::Tcp(host.to_owned(), port),
+        export: export.to_owned(),
+    })
+}
+
+trait NbdStream: Read + Write + Send {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl NbdStream for TcpStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+impl NbdStream for UnixStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("NBD: {msg}"))
+}
+
+struct NbdConnection {
+    stream: Box<dyn NbdStream>,
+    size: u64,
+    flags: u16,
+    handle: u64,
+    // Set once a request failed other than by the server replying with an
+    // error, the replies being out of sync from then.
+    failed: bool,
+}
+
+impl NbdConnection {
+    fn connect(uri: &NbdUri, timeout: Duration) -> io::Result<Self> {
+        let stream: Box<dyn NbdStream> = match &uri.address {
+            NbdAddress::Tcp(host, port) => {
+                let stream = TcpStream::connect((host.as_str(), *port))?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            NbdAddress::Unix(path) => Box::new(UnixStream::connect(path)?),
+        };
+        stream.set_timeout(timeout)?;
+
+        Self::handshake(stream, &uri.export)
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0u8; 2];
+        self.stream.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0u8; 4];
+        self.stream.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u32(&buf))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.stream.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u64(&buf))
+    }
+
+    // Negotiate the export following the fixed newstyle handshake.
+    fn handshake(stream: Box<dyn NbdStream>, export: &str) -> io::Result<Self> {
+        let mut conn = NbdConnection {
+            stream,
+            size: 0,
+            flags: 0,
+            handle: 0,
+            failed: false,
+        };
+
+        if conn.read_u64()? != NBDMAGIC || conn.read_u64()? != IHAVEOPT {
+            return Err(protocol_error("server not speaking the newstyle protocol"));
+        }
+        let handshake_flags = conn.read_u16()?;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(protocol_error(
+                "server not speaking the fixed newstyle protocol",
+            ));
+        }
+        let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+        if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+
+        let mut buf = Vec::with_capacity(26 + export.len());
+        buf.extend_from_slice(&client_flags.to_be_bytes());
+        buf.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&NBD_OPT_GO.to_be_bytes());
+        buf.extend_from_slice(&(export.len() as u32 + 6).to_be_bytes());
+        buf.extend_from_slice(&(export.len() as u32).to_be_bytes());
+        buf.extend_from_slice(export.as_bytes());
+        // No information requested besides the size and flags of the export
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        conn.stream.write_all(&buf)?;
+
+        let mut export_info = false;
+        loop {
+            if conn.read_u64()? != NBD_REP_MAGIC || conn.read_u32()? != NBD_OPT_GO {
+                return Err(protocol_error("unexpected option reply"));
+            }
+            let reply = conn.read_u32()?;
+            let len = conn.read_u32()? as usize;
+            if len > NBD_MAX_OPTION_REPLY {
+                return Err(protocol_error("option reply too long"));
+            }
+            let mut data = vec![0u8; len];
+            conn.stream.read_exact(&mut data)?;
+
+            match reply {
+                NBD_REP_ACK => break,
+                NBD_REP_INFO if len >= 12 && BigEndian::read_u16(&data) == NBD_INFO_EXPORT => {
+                    conn.size = BigEndian::read_u64(&data[2..]);
+                    conn.flags = BigEndian::read_u16(&data[10..]);
+                    export_info = true;
+                }
+                // Information not asked for
+                NBD_REP_INFO => {}
+                reply if reply & NBD_REP_FLAG_ERROR != 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "NBD: export {:?} refused ({:#x}): {}",
+                            export,
+                            reply,
+                            String::from_utf8_lossy(&data)
+                        ),
+                    ));
+                }
+                _ => return Err(protocol_error("unexpected option reply")),
+            }
+        }
+        if !export_info {
+            return Err(protocol_error("size of the export not reported"));
+        }
+
+        Ok(conn)
+    }
+
+    // Send a request and wait for its reply, the data being taken from or
+    // read to the buffers described by `iovecs`. Returns the number of bytes
+    // transferred, or the negated errno the server replied with, any other
+    // failure leaving the connection out of sync.
+    fn request(&mut self, command: u16, offset: u64, iovecs: &[libc::iovec]) -> io::Result<i32> {
+        let result = self.send_request(command, offset, iovecs);
+        if result.is_err() {
+            self.failed = true;
+        }
+
+        result
+    }
+
+    fn send_request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        iovecs: &[libc::iovec],
+    ) -> io::Result<i32> {
+        let len: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        self.handle = self.handle.wrapping_add(1);
+
+        let mut header = [0u8; 28];
+        BigEndian::write_u32(&mut header[0..4], NBD_REQUEST_MAGIC);
+        BigEndian::write_u16(&mut header[6..8], command);
+        BigEndian::write_u64(&mut header[8..16], self.handle);
+        BigEndian::write_u64(&mut header[16..24], offset);
+        BigEndian::write_u32(&mut header[24..28], len as u32);
+        self.stream.write_all(&header)?;
+
+        if command == NBD_CMD_WRITE {
+            for iovec in iovecs {
+                // SAFETY: the iovecs describe guest buffers mapped for the
+                // duration of the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                };
+                self.stream.write_all(buf)?;
+            }
+        }
+
+        if self.read_u32()? != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(protocol_error("unexpected reply"));
+        }
+        let error = self.read_u32()?;
+        if self.read_u64()? != self.handle {
+            return Err(protocol_error("reply to an unknown request"));
+        }
+        if error != 0 {
+            return Ok(-(error as i32));
+        }
+
+        if command == NBD_CMD_READ {
+            for iovec in iovecs {
+                // SAFETY: the iovecs describe guest buffers mapped for the
+                // duration of the request.
+                let buf = unsafe {
+                    std::slice::from_raw_parts_mut(iovec.iov_base as *mut u8, iovec.iov_len)
+                };
+                self.stream.read_exact(buf)?;
+            }
+        }
+
+        Ok(len as i32)
+    }
+}
+
+impl Drop for NbdConnection {
+    fn drop(&mut self) {
+        // The server might not be answering, nor be reading a disconnection
+        // request either.
+        if self.failed {
+            return;
+        }
+
+        let mut header = [0u8; 28];
+        BigEndian::write_u32(&mut header[0..4], NBD_REQUEST_MAGIC);
+        BigEndian::write_u16(&mut header[6..8], NBD_CMD_DISC);
+        // Nothing to do if the server is gone already.
+        let _ = self.stream.write_all(&header);
+    }
+}
+
+struct NbdCompletions {
+    list: Mutex<VecDeque<(u64, i32)>>,
+    eventfd: EventFd,
+}
+
+impl NbdCompletions {
+    fn complete(&self, user_data: u64, result: i32) {
+        self.list.lock().unwrap().push_back((user_data, result));
+        let _ = self.eventfd.write(1);
+    }
+}
+
+enum NbdCompletion {
+    // Completed through the completion list and the eventfd of a queue
+    Queue(Arc<NbdCompletions>, u64),
+    // Waited for by the submitter
+    Wait(Sender<i32>),
+}
+
+struct NbdRequest {
+    command: u16,
+    offset: u64,
+    iovecs: Vec<libc::iovec>,
+    completion: NbdCompletion,
+}
+
+// SAFETY: the iovecs describe guest buffers, which remain mapped until the
+// request completes, whichever thread it is sent from.
+unsafe impl Send for NbdRequest {}
+
+// Thread sending the requests of all the queues of a disk, so that the queues
+// aren't held while the server answers.
+struct NbdWorker {
+    uri: NbdUri,
+    readonly: bool,
+    timeout: Duration,
+    size: u64,
+    connection: Option<NbdConnection>,
+    // Earliest time the connection can be established again
+    next_connect: Instant,
+}
+
+impl NbdWorker {
+    // The connection, established again after a failure, as long as the
+    // export didn't change.
+    fn connection(&mut self) -> Option<&mut NbdConnection> {
+        if self.connection.is_none() && Instant::now() >= self.next_connect {
+            match NbdConnection::connect(&self.uri, self.timeout) {
+                Ok(connection) if connection.size != self.size => {
+                    error!("NBD export resized, not reconnecting");
+                    self.next_connect = Instant::now() + NBD_RECONNECT_DELAY;
+                }
+                Ok(connection) if !self.readonly && connection.flags & NBD_FLAG_READ_ONLY != 0 => {
+                    error!("NBD export now read-only, not reconnecting");
+                    self.next_connect = Instant::now() + NBD_RECONNECT_DELAY;
+                }
+                Ok(connection) => {
+                    info!("Reconnected to NBD export {:?}", self.uri.export);
+                    self.connection = Some(connection);
+                }
+                Err(e) => {
+                    error!("Failed reconnecting to NBD export: {}", e);
+                    self.next_connect = Instant::now() + NBD_RECONNECT_DELAY;
+                }
+            }
+        }
+
+        self.connection.as_mut()
+    }
+
+    fn execute(&mut self, request: &NbdRequest) -> i32 {
+        let Some(connection) = self.connection() else {
+            return -libc::EIO;
+        };
+
+        match connection.request(request.command, request.offset, &request.iovecs) {
+            Ok(result) => result,
+            Err(e) => {
+                error!("NBD request failed, reconnecting: {}", e);
+                self.connection = None;
+                -libc::EIO
+            }
+        }
+    }
+
+    fn run(mut self, requests: Receiver<NbdRequest>) {
+        for request in requests {
+            let result = self.execute(&request);
+            match request.completion {
+                NbdCompletion::Queue(completions, user_data) => {
+                    completions.complete(user_data, result)
+                }
+                NbdCompletion::Wait(sender) => {
+                    let _ = sender.send(result);
+                }
+            }
+        }
+    }
+}
+
+pub struct NbdDisk {
+    size: u64,
+    flags: u16,
+    requests: Sender<NbdRequest>,
+}
+
+impl NbdDisk {
+    /// Connect to the export found at `uri`, failing if it can't be written
+    /// to while the disk is not read-only.
+    pub fn new(uri: &Path, readonly: bool) -> io::Result<Self> {
+        Self::with_timeout(uri, readonly, NBD_TIMEOUT)
+    }
+
+    fn with_timeout(uri: &Path, readonly: bool, timeout: Duration) -> io::Result<Self> {
+        let uri_str = uri
+            .to_str()
+            .ok_or_else(|| invalid_uri(&uri.to_string_lossy()))?;
+        let uri = parse_uri(uri_str)?;
+        let connection = NbdConnection::connect(&uri, timeout)?;
+        if !readonly && connection.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("NBD export {uri_str} is read-only"),
+            ));
+        }
+
+        info!(
+            "Connected to NBD export {} ({} bytes)",
+            uri_str, connection.size
+        );
+
+        let size = connection.size;
+        let flags = connection.flags;
+        let worker = NbdWorker {
+            uri,
+            readonly,
+            timeout,
+            size,
+            connection: Some(connection),
+            next_connect: Instant::now(),
+        };
+        let (requests, receiver) = channel();
+        // The worker exits once the disk and all its queues are gone.
+        thread::Builder::new()
+            .name("nbd".to_string())
+            .spawn(move || worker.run(receiver))?;
+
+        Ok(NbdDisk {
+            size,
+            flags,
+            requests,
+        })
+    }
+}
+
+impl DiskFile for NbdDisk {
+    fn size(&mut self) -> DiskFileResult<u64> {
+        Ok(self.size)
+    }
+
+    fn new_async_io(&self, _ring_depth: u32) -> DiskFileResult<Box<dyn AsyncIo>> {
+        Ok(Box::new(NbdAsyncIo {
+            requests: self.requests.clone(),
+            flags: self.flags,
+            completions: Arc::new(NbdCompletions {
+                list: Mutex::new(VecDeque::new()),
+                eventfd: EventFd::new(libc::EFD_NONBLOCK).map_err(DiskFileError::NewAsyncIo)?,
+            }),
+        }) as Box<dyn AsyncIo>)
+    }
+}
+
+pub struct NbdAsyncIo {
+    requests: Sender<NbdRequest>,
+    flags: u16,
+    completions: Arc<NbdCompletions>,
+}
+
+impl NbdAsyncIo {
+    // Hand the request to the worker, which completes it with the number of
+    // bytes transferred, or with an error.
+    fn request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> io::Result<()> {
+        let request = NbdRequest {
+            command,
+            offset,
+            iovecs: iovecs.to_vec(),
+            completion: NbdCompletion::Queue(self.completions.clone(), user_data),
+        };
+
+        self.requests
+            .send(request)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EIO))
+    }
+}
+
+impl AsyncIo for NbdAsyncIo {
+    fn notifier(&self) -> &EventFd {
+        &self.completions.eventfd
+    }
+
+    fn read_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.request(NBD_CMD_READ, offset as u64, iovecs, user_data)
+            .map_err(AsyncIoError::ReadVectored)
+    }
+
+    fn write_vectored(
+        &mut self,
+        offset: libc::off_t,
+        iovecs: &[libc::iovec],
+        user_data: u64,
+    ) -> AsyncIoResult<()> {
+        self.request(NBD_CMD_WRITE, offset as u64, iovecs, user_data)
+            .map_err(AsyncIoError::WriteVectored)
+    }
+
+    fn fsync(&mut self, user_data: Option<u64>) -> AsyncIoResult<()> {
+        // Writes are durable once acknowledged without flush support.
+        let flush = self.flags & NBD_FLAG_SEND_FLUSH != 0;
+
+        match user_data {
+            Some(user_data) if flush => self
+                .request(NBD_CMD_FLUSH, 0, &[], user_data)
+                .map_err(AsyncIoError::Fsync),
+            Some(user_data) => {
+                self.completions.complete(user_data, 0);
+                Ok(())
+            }
+            None if flush => {
+                let (sender, receiver) = channel();
+                let request = NbdRequest {
+                    command: NBD_CMD_FLUSH,
+                    offset: 0,
+                    iovecs: Vec::new(),
+                    completion: NbdCompletion::Wait(sender),
+                };
+                let result = self
+                    .requests
+                    .send(request)
+                    .ok()
+                    .and_then(|_| receiver.recv().ok())
+                    .unwrap_or(-libc::EIO);
+                if result < 0 {
+                    return Err(AsyncIoError::Fsync(io::Error::from_raw_os_error(-result)));
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn next_completed_request(&mut self) -> Option<(u64, i32)> {
+        self.completions.list.lock().unwrap().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_parse_nbd_uri() {
+        assert_eq!(
+            parse_uri("nbd://localhost/disk0").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("localhost".to_owned(), NBD_DEFAULT_PORT),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert_eq!(
+            parse_uri("nbd://[::1]:10810").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("::1".to_owned(), 10810),
+                export: String::new(),
+            }
+        );
+        assert_eq!(
+            parse_uri("nbd+unix:///disk0?socket=/run/nbd.sock").unwrap(),
+            NbdUri {
+                address: NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert!(parse_uri("nbd://:10809/disk0").is_err());
+        assert!(parse_uri("nbd://localhost:port/disk0").is_err());
+        assert!(parse_uri("nbd+unix:///disk0").is_err());
+
+        assert!(is_nbd_uri(Path::new("nbd://localhost/disk0")));
+        assert!(!is_nbd_uri(Path::new("/path/to/nbd://disk0")));
+    }
+
+    // Negotiate a single export of 4 KiB.
+    fn serve_handshake(stream: &mut UnixStream) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBDMAGIC.to_be_bytes());
+        buf.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        buf.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&buf).unwrap();
+
+        let mut option = [0u8; 20];
+        stream.read_exact(&mut option).unwrap();
+        let mut data = vec![0u8; BigEndian::read_u32(&option[16..]) as usize];
+        stream.read_exact(&mut data).unwrap();
+
+        let mut buf = Vec::new();
+        for (reply, data) in [
+            (NBD_REP_INFO, &[0u8, 0, 0, 0, 0, 0, 0, 0x10, 0, 0, 0, 3][..]),
+            (NBD_REP_ACK, &[][..]),
+        ] {
+            buf.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+            buf.extend_from_slice(&NBD_OPT_GO.to_be_bytes());
+            buf.extend_from_slice(&reply.to_be_bytes());
+            buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buf.extend_from_slice(data);
+        }
+        stream.write_all(&buf).unwrap();
+    }
+
+    // Serve a single export of 4 KiB holding 0xaa bytes, answering a read
+    // request before the disconnection.
+    fn serve(mut stream: UnixStream, handshake: bool) {
+        if handshake {
+            serve_handshake(&mut stream);
+        }
+
+        let mut request = [0u8; 28];
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(BigEndian::read_u16(&request[6..]), NBD_CMD_READ);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&0u32.to_be_bytes());
+        buf.extend_from_slice(&request[8..16]);
+        buf.extend(std::iter::repeat(0xaa).take(BigEndian::read_u32(&request[24..]) as usize));
+        stream.write_all(&buf).unwrap();
+
+        stream.read_exact(&mut request).unwrap();
+        assert_eq!(BigEndian::read_u16(&request[6..]), NBD_CMD_DISC);
+    }
+
+    fn wait_completion(async_io: &mut dyn AsyncIo) -> (u64, i32) {
+        for _ in 0..1000 {
+            if let Some(completion) = async_io.next_completed_request() {
+                return completion;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("NBD request not completed");
+    }
+
+    #[test]
+    fn test_nbd_disk() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || serve(listener.accept().unwrap().0, true));
+
+        let uri = format!("nbd+unix:///disk0?socket={}", socket.display());
+        // The export is read-only
+        let mut disk = NbdDisk::new(Path::new(&uri), true).unwrap();
+        assert_eq!(disk.size().unwrap(), 0x1000);
+
+        let mut async_io = disk.new_async_io(1).unwrap();
+        let mut buf = [0u8; 0x200];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        async_io.read_vectored(0x200, &iovecs, 7).unwrap();
+        assert_eq!(wait_completion(async_io.as_mut()), (7, 0x200));
+        assert!(buf.iter().all(|b| *b == 0xaa));
+
+        drop(async_io);
+        drop(disk);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_nbd_reconnect() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            serve_handshake(&mut stream);
+            // Take the request in without ever answering it
+            let mut request = [0u8; 28];
+            stream.read_exact(&mut request).unwrap();
+
+            // The client connects again, and gets an error replied first
+            let mut stream = listener.accept().unwrap().0;
+            serve_handshake(&mut stream);
+            stream.read_exact(&mut request).unwrap();
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            buf.extend_from_slice(&(libc::ENOSPC as u32).to_be_bytes());
+            buf.extend_from_slice(&request[8..16]);
+            stream.write_all(&buf).unwrap();
+            serve(stream, false);
+            receiver.recv().unwrap();
+        });
+
+        let uri = format!("nbd+unix:///disk0?socket={}", socket.display());
+        let disk =
+            NbdDisk::with_timeout(Path::new(&uri), true, Duration::from_millis(100)).unwrap();
+
+        let mut async_io = disk.new_async_io(1).unwrap();
+        let mut buf = [0u8; 0x200];
+        let iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        // The request times out without holding the queue
+        async_io.read_vectored(0, &iovecs, 7).unwrap();
+        assert_eq!(wait_completion(async_io.as_mut()), (7, -libc::EIO));
+        // The error replied by the server fails the request only
+        async_io.read_vectored(0, &iovecs, 8).unwrap();
+        assert_eq!(wait_completion(async_io.as_mut()), (8, -libc::ENOSPC));
+        async_io.read_vectored(0, &iovecs, 9).unwrap();
+        assert_eq!(wait_completion(async_io.as_mut()), (9, 0x200));
+        assert!(buf.iter().all(|b| *b == 0xaa));
+
+        drop(async_io);
+        drop(disk);
+        sender.send(()).unwrap();
+        server.join().unwrap();
+    }
+}
@@ -16,10 +16,12 @@ use vmm_sys_util::eventfd::EventFd;
 
 pub const GED_DEVICE_ACPI_SIZE: usize = 0x1;
 
-/// A device for handling ACPI shutdown and reboot
+/// A device for handling ACPI shutdown, reboot, suspend and hibernation
 pub struct AcpiShutdownDevice {
     exit_evt: EventFd,
     reset_evt: EventFd,
+    suspend_evt: EventFd,
+    hibernate_evt: EventFd,
     vcpus_kill_signalled: Arc<AtomicBool>,
 }
 
@@ -28,11 +30,15 @@ impl AcpiShutdownDevice {
     pub fn new(
         exit_evt: EventFd,
         reset_evt: EventFd,
+        suspend_evt: EventFd,
+        hibernate_evt: EventFd,
         vcpus_kill_signalled: Arc<AtomicBool>,
     ) -> AcpiShutdownDevice {
         AcpiShutdownDevice {
             exit_evt,
             reset_evt,
+            suspend_evt,
+            hibernate_evt,
             vcpus_kill_signalled,
         }
     }
@@ -59,10 +65,36 @@ impl BusDevice for AcpiShutdownDevice {
                 thread::sleep(std::time::Duration::from_millis(1));
             }
         }
-        // The ACPI DSDT table specifies the S5 sleep state (shutdown) as value 5
+        // The ACPI DSDT table specifies the S3 sleep state (suspend) as value 3,
+        // the S4 sleep state (hibernation) as value 4 and the S5 sleep state
+        // (shutdown) as value 5
+        const S3_SLEEP_VALUE: u8 = 3;
+        const S4_SLEEP_VALUE: u8 = 4;
         const S5_SLEEP_VALUE: u8 = 5;
         const SLEEP_STATUS_EN_BIT: u8 = 5;
         const SLEEP_VALUE_BIT: u8 = 2;
+        if data[0] == (S3_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            info!("ACPI Suspend signalled");
+            // No need to wait for the vCPUs to be paused, the guest polls the
+            // sleep status register until it is woken up.
+            if let Err(e) = self.suspend_evt.write(1) {
+                error!("Error triggering ACPI suspend event: {}", e);
+            }
+        }
+        if data[0] == (S4_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
+            info!("ACPI Hibernate signalled");
+            if let Err(e) = self.hibernate_evt.write(1) {
+                error!("Error triggering ACPI hibernate event: {}", e);
+            }
+            // Whatever the hibernation policy, the VM gets torn down. Spin
+            // until we return from the KVM_RUN to exit rather than re-enter
+            // the guest.
+            while !self.vcpus_kill_signalled.load(Ordering::SeqCst) {
+                // This is more effective than thread::yield_now() at
+                // avoiding a priority inversion with the VMM thread
+                thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
         if data[0] == (S5_SLEEP_VALUE << SLEEP_VALUE_BIT) | (1 << SLEEP_STATUS_EN_BIT) {
             info!("ACPI Shutdown signalled");
             if let Err(e) = self.exit_evt.write(1) {
@@ -86,6 +118,7 @@ pub struct AcpiGedDevice {
     notification_type: AcpiNotificationFlags,
     ged_irq: u32,
     address: GuestAddress,
+    hardware_error: bool,
 }
 
 impl AcpiGedDevice {
@@ -99,9 +132,16 @@ impl AcpiGedDevice {
             notification_type: AcpiNotificationFlags::NO_DEVICES_CHANGED,
             ged_irq,
             address,
+            hardware_error: false,
         }
     }
 
+    /// Forward the hardware error notifications to the Hardware Error
+    /// Device, which must then be part of the DSDT.
+    pub fn enable_hardware_error_notification(&mut self) {
+        self.hardware_error = true;
+    }
+
     pub fn notify(
         &mut self,
         notification_type: AcpiNotificationFlags,
@@ -126,6 +166,9 @@ impl BusDevice for AcpiGedDevice {
 
 impl Aml for AcpiGedDevice {
     fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        // The Hardware Error Device only exists when the errors are reported.
+        let hed_path = aml::Path::new("\\_SB_.HED_");
+        let hed_notify = aml::Notify::new(&hed_path, &0x80usize);
         aml::Device::new(
             "_SB_.GEC_".into(),
             vec![
@@ -183,6 +226,15 @@ impl Aml for AcpiGedDevice {
                                 &0x80usize,
                             )],
                         ),
+                        &aml::And::new(&aml::Local(1), &aml::Local(0), &16usize),
+                        &aml::If::new(
+                            &aml::Equal::new(&aml::Local(1), &16usize),
+                            if self.hardware_error {
+                                vec![&hed_notify]
+                            } else {
+                                vec![]
+                            },
+                        ),
                     ],
                 ),
             ],
@@ -0,0 +1,338 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reporting of hardware errors to the guest through ACPI APEI.
+//!
+//! The device backs the Generic Hardware Error Source (GHES) described in the
+//! HEST table. Its first register holds the address of the error status block
+//! found further in the same MMIO region, where the errors are written as
+//! Common Platform Error Records (CPER). The guest is then notified through
+//! the Hardware Error Device (HED), and it acknowledges each error by clearing
+//! the status of the block, allowing the next one to be reported.
+//!
+//! PCIe errors carry the content of the AER capability of the device, which
+//! lets the guest run the error recovery of the device driver, as if it had
+//! been notified by the AER driver of a root port.
+
+use acpi_tables::{aml, Aml, AmlSink};
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+use vm_memory::{ByteValued, GuestAddress};
+
+pub const GHES_DEVICE_SIZE: u64 = 0x1000;
+// Offset of the error status block within the MMIO region
+const ERROR_STATUS_BLOCK_OFFSET: usize = 0x100;
+pub const ERROR_STATUS_BLOCK_LENGTH: usize = 0x400;
+
+// Generic Error Status Block
+const BLOCK_STATUS_UNCORRECTABLE: u32 = 1;
+const BLOCK_STATUS_CORRECTABLE: u32 = 1 << 1;
+const BLOCK_STATUS_ENTRY_COUNT_SHIFT: u32 = 4;
+
+// CPER error severities
+const CPER_SEV_RECOVERABLE: u32 = 0;
+const CPER_SEV_FATAL: u32 = 1;
+const CPER_SEV_CORRECTED: u32 = 2;
+
+// CPER PCI Express error section: D995E954-BBC1-430F-AD91-B44DCB3C6F35
+const CPER_SEC_PCIE: [u8; 16] = [
+    0x54, 0xe9, 0x95, 0xd9, 0xc1, 0xbb, 0x0f, 0x43, 0xad, 0x91, 0xb4, 0x4d, 0xcb, 0x3c, 0x6f, 0x35,
+];
+const CPER_PCIE_VALID_PORT_TYPE: u64 = 1;
+const CPER_PCIE_VALID_VERSION: u64 = 1 << 1;
+const CPER_PCIE_VALID_COMMAND_STATUS: u64 = 1 << 2;
+const CPER_PCIE_VALID_DEVICE_ID: u64 = 1 << 3;
+const CPER_PCIE_VALID_AER_INFO: u64 = 1 << 7;
+const PCIE_PORT_TYPE_ENDPOINT: u32 = 0;
+
+// Registers of the AER capability, in dwords
+const AER_UNCOR_STATUS: usize = 1;
+const AER_UNCOR_MASK: usize = 2;
+const AER_UNCOR_SEVERITY: usize = 3;
+const AER_COR_STATUS: usize = 4;
+const AER_COR_MASK: usize = 5;
+pub const AER_CAPABILITY_DWORDS: usize = 24;
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct GenericErrorStatus {
+    block_status: u32,
+    raw_data_offset: u32,
+    raw_data_length: u32,
+    data_length: u32,
+    error_severity: u32,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for GenericErrorStatus {}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct GenericErrorData {
+    section_type: [u8; 16],
+    error_severity: u32,
+    revision: u16,
+    validation_bits: u8,
+    flags: u8,
+    error_data_length: u32,
+    fru_id: [u8; 16],
+    fru_text: [u8; 20],
+    timestamp: u64,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for GenericErrorData {}
+
+#[repr(packed)]
+#[derive(Clone, Copy)]
+struct CperSecPcie {
+    validation_bits: u64,
+    port_type: u32,
+    version: u32,
+    command: u16,
+    status: u16,
+    reserved: u32,
+    vendor_id: u16,
+    device_id: u16,
+    class_code: [u8; 3],
+    function: u8,
+    device: u8,
+    segment: u16,
+    bus: u8,
+    secondary_bus: u8,
+    slot: u16,
+    reserved_id: u8,
+    serial_number: u64,
+    bridge: u32,
+    capability: [u8; 60],
+    aer_info: [u32; AER_CAPABILITY_DWORDS],
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for CperSecPcie {}
+
+/// Error reported by a PCIe device.
+#[derive(Clone, Debug, Default)]
+pub struct PcieError {
+    pub segment: u16,
+    pub bus: u8,
+    pub devfn: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: [u8; 3],
+    pub command: u16,
+    pub status: u16,
+    /// Content of the AER capability, if the device has one.
+    pub aer: Option<[u32; AER_CAPABILITY_DWORDS]>,
+}
+
+impl PcieError {
+    /// Severity of the error, as found from the AER capability. The errors
+    /// without any unmasked status bit left are considered uncorrectable but
+    /// recoverable, as they are only notified for uncorrectable errors.
+    fn severity(&self) -> u32 {
+        let aer = match &self.aer {
+            Some(aer) => aer,
+            None => return CPER_SEV_RECOVERABLE,
+        };
+
+        let uncor = aer[AER_UNCOR_STATUS] & !aer[AER_UNCOR_MASK];
+        if uncor & aer[AER_UNCOR_SEVERITY] != 0 {
+            CPER_SEV_FATAL
+        } else if uncor != 0 {
+            CPER_SEV_RECOVERABLE
+        } else if aer[AER_COR_STATUS] & !aer[AER_COR_MASK] != 0 {
+            CPER_SEV_CORRECTED
+        } else {
+            CPER_SEV_RECOVERABLE
+        }
+    }
+
+    fn section(&self) -> CperSecPcie {
+        let mut validation_bits = CPER_PCIE_VALID_PORT_TYPE
+            | CPER_PCIE_VALID_VERSION
+            | CPER_PCIE_VALID_COMMAND_STATUS
+            | CPER_PCIE_VALID_DEVICE_ID;
+        if self.aer.is_some() {
+            validation_bits |= CPER_PCIE_VALID_AER_INFO;
+        }
+
+        CperSecPcie {
+            validation_bits,
+            port_type: PCIE_PORT_TYPE_ENDPOINT,
+            // PCI Express Base Specification 1.1
+            version: 0x0101,
+            command: self.command,
+            status: self.status,
+            reserved: 0,
+            vendor_id: self.vendor_id,
+            device_id: self.device_id,
+            class_code: self.class_code,
+            function: self.devfn & 0x7,
+            device: self.devfn >> 3,
+            segment: self.segment,
+            bus: self.bus,
+            secondary_bus: 0,
+            slot: (self.devfn as u16 >> 3) << 3,
+            reserved_id: 0,
+            serial_number: 0,
+            bridge: 0,
+            capability: [0; 60],
+            aer_info: self.aer.unwrap_or([0; AER_CAPABILITY_DWORDS]),
+        }
+    }
+}
+
+/// Generic Hardware Error Source exposing the errors to the guest.
+pub struct GhesDevice {
+    address: GuestAddress,
+    block: Vec<u8>,
+}
+
+impl GhesDevice {
+    pub fn new(address: GuestAddress) -> Self {
+        GhesDevice {
+            address,
+            block: vec![0; ERROR_STATUS_BLOCK_LENGTH],
+        }
+    }
+
+    /// Address of the register pointing to the error status block.
+    pub fn error_status_address(&self) -> GuestAddress {
+        self.address
+    }
+
+    /// Write a PCIe error to the error status block, returning false if the
+    /// guest hasn't acknowledged the previous error yet.
+    pub fn report_pcie_error(&mut self, error: &PcieError) -> bool {
+        let status = GenericErrorStatus::from_slice(
+            &self.block[..std::mem::size_of::<GenericErrorStatus>()],
+        )
+        .unwrap();
+        if status.block_status != 0 {
+            return false;
+        }
+
+        let severity = error.severity();
+        let section = error.section();
+        let data = GenericErrorData {
+            section_type: CPER_SEC_PCIE,
+            error_severity: severity,
+            revision: 0x300,
+            error_data_length: std::mem::size_of::<CperSecPcie>() as u32,
+            ..Default::default()
+        };
+        let status = GenericErrorStatus {
+            block_status: if severity == CPER_SEV_CORRECTED {
+                BLOCK_STATUS_CORRECTABLE
+            } else {
+                BLOCK_STATUS_UNCORRECTABLE
+            } | (1 << BLOCK_STATUS_ENTRY_COUNT_SHIFT),
+            data_length: (data.as_slice().len() + section.as_slice().len()) as u32,
+            error_severity: severity,
+            ..Default::default()
+        };
+
+        self.block.fill(0);
+        let mut offset = 0;
+        for bytes in [status.as_slice(), data.as_slice(), section.as_slice()] {
+            self.block[offset..offset + bytes.len()].copy_from_slice(bytes);
+            offset += bytes.len();
+        }
+
+        true
+    }
+}
+
+impl BusDevice for GhesDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let offset = offset as usize;
+        let block_address = (self.address.0 + ERROR_STATUS_BLOCK_OFFSET as u64).to_le_bytes();
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i;
+            *byte = if offset < block_address.len() {
+                block_address[offset]
+            } else if offset >= ERROR_STATUS_BLOCK_OFFSET {
+                self.block
+                    .get(offset - ERROR_STATUS_BLOCK_OFFSET)
+                    .copied()
+                    .unwrap_or(0)
+            } else {
+                0
+            };
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        // The guest acknowledges the error by clearing the block status.
+        let offset = offset as usize;
+        for (i, byte) in data.iter().enumerate() {
+            if let Some(b) = (offset + i)
+                .checked_sub(ERROR_STATUS_BLOCK_OFFSET)
+                .and_then(|o| self.block.get_mut(o))
+            {
+                *b = *byte;
+            }
+        }
+
+        None
+    }
+}
+
+impl Aml for GhesDevice {
+    fn to_aml_bytes(&self, sink: &mut dyn AmlSink) {
+        aml::Device::new(
+            "_SB_.HED_".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C33")),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_pcie_error() {
+        let mut ghes = GhesDevice::new(GuestAddress(0xfe00_0000));
+        assert_eq!(std::mem::size_of::<GenericErrorData>(), 72);
+        assert_eq!(std::mem::size_of::<CperSecPcie>(), 208);
+
+        let mut data = [0u8; 8];
+        ghes.read(0, 0, &mut data);
+        assert_eq!(u64::from_le_bytes(data), 0xfe00_0100);
+
+        let mut aer = [0u32; AER_CAPABILITY_DWORDS];
+        aer[AER_UNCOR_STATUS] = 1 << 18;
+        aer[AER_UNCOR_SEVERITY] = 1 << 18;
+        let error = PcieError {
+            devfn: 3 << 3,
+            aer: Some(aer),
+            ..Default::default()
+        };
+        assert_eq!(error.severity(), CPER_SEV_FATAL);
+        assert!(ghes.report_pcie_error(&error));
+
+        let mut status = [0u8; 4];
+        ghes.read(0, ERROR_STATUS_BLOCK_OFFSET as u64, &mut status);
+        assert_eq!(u32::from_le_bytes(status), 0x11);
+        // The previous error must be acknowledged first
+        assert!(!ghes.report_pcie_error(&error));
+        ghes.write(0, ERROR_STATUS_BLOCK_OFFSET as u64, &[0; 4]);
+
+        aer[AER_UNCOR_SEVERITY] = 0;
+        aer[AER_UNCOR_MASK] = 1 << 18;
+        aer[AER_COR_STATUS] = 1;
+        let error = PcieError {
+            aer: Some(aer),
+            ..error
+        };
+        assert_eq!(error.severity(), CPER_SEV_CORRECTED);
+        assert!(ghes.report_pcie_error(&error));
+        ghes.read(0, ERROR_STATUS_BLOCK_OFFSET as u64, &mut status);
+        assert_eq!(u32::from_le_bytes(status), 0x12);
+    }
+}
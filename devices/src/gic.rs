@@ -158,7 +158,9 @@ impl Snapshottable for Gic {
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
         let vgic = self.vgic.as_ref().unwrap().clone();
-        let state = vgic.lock().unwrap().state().unwrap();
+        let state = vgic.lock().unwrap().state().map_err(|e| {
+            MigratableError::Snapshot(anyhow!("Could not save GICv3ITS state {:?}", e))
+        })?;
         Snapshot::new_from_state(&state)
     }
 }
@@ -35,7 +35,9 @@ type Result<T> = result::Result<T, Error>;
 // split between two 32 bits registers as follow:
 //
 // 63-56: Destination Field - R/W
-// 55-17: Reserved
+// 55-49: Reserved
+// 48:    Interrupt Format - R/W
+// 47-17: Reserved
 // 16:    Interrupt Mask - R/W
 // 15:    Trigger Mode - R/W
 // 14:    Remote IRR - RO
@@ -72,6 +74,15 @@ fn destination_field(entry: RedirectionTableEntry) -> u8 {
     // retrieve the destination field based on bits 56-63.
     ((entry >> 56) & 0xffu64) as u8
 }
+// In the remappable format, used along with interrupt remapping, bits 63-49
+// and bit 11 hold the index of the interrupt remapping table entry describing
+// the interrupt, instead of the destination.
+fn remappable_format(entry: RedirectionTableEntry) -> bool {
+    (entry >> 48) & 0x1u64 == 1
+}
+fn interrupt_index(entry: RedirectionTableEntry) -> u32 {
+    ((entry >> 49) & 0x7fffu64) as u32 | u32::from(destination_mode(entry)) << 15
+}
 fn set_delivery_status(entry: &mut RedirectionTableEntry, val: u8) {
     // Clear bit 12
     *entry &= 0xffff_ffff_ffff_efff;
@@ -346,11 +357,17 @@ impl Ioapic {
         // interrupt.
         let redirection_hint: u8 = 1;
 
-        // Generate MSI message address
-        let low_addr: u32 = self.apic_address.0 as u32
-            | u32::from(destination_id) << 12
-            | u32::from(redirection_hint) << 3
-            | u32::from(destination_mode) << 2;
+        // Generate MSI message address, which is a remappable format one
+        // forwarding the interrupt index if the entry is in this format.
+        let low_addr: u32 = if remappable_format(entry) {
+            let index = interrupt_index(entry);
+            self.apic_address.0 as u32 | (index & 0x7fff) << 5 | 1 << 4 | (index >> 15) << 2
+        } else {
+            self.apic_address.0 as u32
+                | u32::from(destination_id) << 12
+                | u32::from(redirection_hint) << 3
+                | u32::from(destination_mode) << 2
+        };
 
         // Validate Trigger Mode value
         let trigger_mode = trigger_mode(entry);
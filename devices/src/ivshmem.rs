@@ -0,0 +1,1021 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! ivshmem PCI device, sharing memory of the host with the guest.
+//!
+//! The shared memory is exposed through BAR2, mapped as is in the guest
+//! address space, and the registers through BAR0. Sharing a file, the device
+//! behaves as an ivshmem-plain device. Connected to an ivshmem-server, it
+//! behaves as an ivshmem-doorbell device: the server hands out the shared
+//! memory along with the eventfds of the peers, the guest ringing the doorbell
+//! of a peer through the Doorbell register and being interrupted through MSI-X
+//! when a peer rings its own.
+
+use anyhow::anyhow;
+use pci::{
+    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarPrefetchable,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciSubclass, PCI_CONFIGURATION_ID,
+};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
+};
+use vm_device::{BusDevice, Resource};
+use vm_memory::{Address, GuestAddress, MmapRegion};
+use vm_migration::{Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+const IVSHMEM_VENDOR_ID: u16 = 0x1af4;
+const IVSHMEM_DEVICE_ID: u16 = 0x1110;
+
+// Registers, in BAR0
+const IVSHMEM_REG_BAR_INDEX: usize = 0;
+const IVSHMEM_REG_BAR_SIZE: u64 = 0x100;
+const IVSHMEM_REG_INTR_MASK: u64 = 0x0;
+const IVSHMEM_REG_INTR_STATUS: u64 = 0x4;
+const IVSHMEM_REG_IV_POSITION: u64 = 0x8;
+const IVSHMEM_REG_DOORBELL: u64 = 0xc;
+
+// MSI-X table and PBA, in BAR1
+const IVSHMEM_MSIX_BAR_INDEX: usize = 1;
+const IVSHMEM_MSIX_BAR_SIZE: u64 = 0x1000;
+const IVSHMEM_MSIX_TABLE_OFFSET: u32 = 0;
+const IVSHMEM_MSIX_PBA_OFFSET: u64 = 0x800;
+
+// Shared memory, in BAR2
+const IVSHMEM_SHM_BAR_INDEX: usize = 2;
+
+/// Maximum number of interrupt vectors of a doorbell device, the MSI-X table
+/// having to fit before the PBA.
+pub const IVSHMEM_MAX_VECTORS: u16 = 64;
+
+const IVSHMEM_PROTOCOL_VERSION: i64 = 0;
+
+// Epoll events of the doorbell thread, the vectors following
+const KILL_EVENT: u64 = 0;
+const SERVER_EVENT: u64 = 1;
+const VECTOR_EVENT_BASE: u64 = 2;
+
+#[derive(Debug, Error)]
+pub enum IvshmemError {
+    #[error("Failed creating IvshmemDevice: {0}")]
+    CreateIvshmemDevice(#[source] anyhow::Error),
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Cannot connect to the ivshmem server: {0}")]
+    ConnectServer(#[source] io::Error),
+    #[error("Cannot receive a message from the ivshmem server: {0}")]
+    ReceiveServerMessage(#[source] io::Error),
+    #[error("Unsupported ivshmem server protocol version {0}")]
+    UnsupportedProtocolVersion(i64),
+    #[error("Invalid peer identifier {0} from the ivshmem server")]
+    InvalidPeerId(i64),
+    #[error("The ivshmem server did not send the shared memory")]
+    MissingSharedMemory,
+}
+
+#[derive(Copy, Clone)]
+enum IvshmemSubclass {
+    Ram = 0x00,
+}
+
+impl PciSubclass for IvshmemSubclass {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// Eventfds of the vectors of each peer, including the device itself
+type Peers = Arc<Mutex<BTreeMap<u16, Vec<File>>>>;
+
+// Receive a message from the ivshmem server, a little endian 64 bits integer
+// optionally carrying a file descriptor.
+fn recv_server_message(socket: &UnixStream) -> io::Result<(i64, Option<File>)> {
+    let mut buf = [0u8; 8];
+    let (len, file) = socket.recv_with_fd(&mut buf[..]).map_err(io::Error::from)?;
+    match len {
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        8 => Ok((i64::from_le_bytes(buf), file)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Truncated message of {len} bytes"),
+        )),
+    }
+}
+
+fn peer_id(id: i64) -> Result<u16, IvshmemError> {
+    u16::try_from(id).map_err(|_| IvshmemError::InvalidPeerId(id))
+}
+
+/// Connection to an ivshmem-server.
+pub struct IvshmemServer {
+    socket: UnixStream,
+    id: u16,
+}
+
+impl IvshmemServer {
+    /// Connect to the server listening on `path`, returning the connection
+    /// along with the shared memory.
+    pub fn connect(path: &Path) -> Result<(Self, File), IvshmemError> {
+        let socket = UnixStream::connect(path).map_err(IvshmemError::ConnectServer)?;
+
+        let (version, _) =
+            recv_server_message(&socket).map_err(IvshmemError::ReceiveServerMessage)?;
+        if version != IVSHMEM_PROTOCOL_VERSION {
+            return Err(IvshmemError::UnsupportedProtocolVersion(version));
+        }
+
+        let (id, _) = recv_server_message(&socket).map_err(IvshmemError::ReceiveServerMessage)?;
+        let id = peer_id(id)?;
+
+        let (shm_id, shm) =
+            recv_server_message(&socket).map_err(IvshmemError::ReceiveServerMessage)?;
+        let shm = match (shm_id, shm) {
+            (-1, Some(shm)) => shm,
+            _ => return Err(IvshmemError::MissingSharedMemory),
+        };
+
+        Ok((IvshmemServer { socket, id }, shm))
+    }
+}
+
+struct Doorbell {
+    id: u16,
+    peers: Peers,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    // Taken by the handler of the doorbell
+    server: Option<UnixStream>,
+    kill_evt: EventFd,
+}
+
+/// Handler of the doorbell of an ivshmem device, tracking the peers announced
+/// by the ivshmem server and interrupting the guest when its doorbell rings.
+pub struct IvshmemDoorbellHandler {
+    id: u16,
+    vectors: u16,
+    peers: Peers,
+    msix_config: Arc<Mutex<MsixConfig>>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    server: UnixStream,
+    kill_evt: EventFd,
+    // Eventfds of the vectors of the device
+    vector_evts: Vec<File>,
+}
+
+impl IvshmemDoorbellHandler {
+    fn handle_server_message(&mut self, epoll_fd: i32) -> io::Result<()> {
+        let (id, fd) = recv_server_message(&self.server)?;
+        let Ok(id) = u16::try_from(id) else {
+            warn!("Ignoring invalid ivshmem peer identifier {}", id);
+            return Ok(());
+        };
+
+        let Some(fd) = fd else {
+            info!("ivshmem peer {} disconnected", id);
+            self.peers.lock().unwrap().remove(&id);
+            return Ok(());
+        };
+
+        let mut peers = self.peers.lock().unwrap();
+        let vectors = peers.entry(id).or_default();
+        if id == self.id && self.vector_evts.len() < self.vectors as usize {
+            let vector = self.vector_evts.len() as u64;
+            let vector_evt = fd.try_clone()?;
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                vector_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, VECTOR_EVENT_BASE + vector),
+            )?;
+            self.vector_evts.push(vector_evt);
+        }
+        vectors.push(fd);
+
+        Ok(())
+    }
+
+    fn trigger(&self, vector: u16) -> io::Result<()> {
+        let mut config = self.msix_config.lock().unwrap();
+        // A masked vector only sets its pending bit, the interrupt being
+        // delivered once unmasked.
+        if config.masked() || config.table_entries[vector as usize].masked() {
+            config.set_pba_bit(vector, false);
+            return Ok(());
+        }
+
+        self.interrupt_source_group
+            .trigger(vector as InterruptIndex)
+    }
+
+    /// Run the handler until the device is dropped.
+    pub fn run(&mut self) -> io::Result<()> {
+        let epoll_fd = epoll::create(true)?;
+        // SAFETY: the file descriptor was just created, and is owned by the
+        // file from now on.
+        let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, KILL_EVENT),
+        )?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.server.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, SERVER_EVENT),
+        )?;
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 8];
+        loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(num_events) => num_events,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for event in events.iter().take(num_events) {
+                let data = event.data;
+                match data {
+                    KILL_EVENT => return Ok(()),
+                    SERVER_EVENT => {
+                        if let Err(e) = self.handle_server_message(epoll_fd) {
+                            // The peers can't change anymore, the doorbells
+                            // still ring.
+                            error!("Lost the connection to the ivshmem server: {}", e);
+                            epoll::ctl(
+                                epoll_fd,
+                                epoll::ControlOptions::EPOLL_CTL_DEL,
+                                self.server.as_raw_fd(),
+                                epoll::Event::new(epoll::Events::empty(), 0),
+                            )?;
+                        }
+                    }
+                    _ => {
+                        let vector = (data - VECTOR_EVENT_BASE) as u16;
+                        let mut count = [0u8; 8];
+                        self.vector_evts[vector as usize].read_exact(&mut count)?;
+                        if let Err(e) = self.trigger(vector) {
+                            error!("Failed to trigger ivshmem vector {}: {}", vector, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An ivshmem device sharing memory of the host with the guest
+pub struct IvshmemDevice {
+    id: String,
+    // Shared memory, mapped in the guest address space through BAR2
+    shm_region: MmapRegion,
+    shm_mem_slot: Option<u32>,
+    doorbell: Option<Doorbell>,
+
+    // PCI configuration registers.
+    configuration: PciConfiguration,
+    bar_regions: Vec<PciBarConfiguration>,
+}
+
+impl IvshmemDevice {
+    pub fn new(
+        id: String,
+        shm_region: MmapRegion,
+        server: Option<IvshmemServer>,
+        vectors: u16,
+        interrupt_manager: &dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>,
+        pci_device_bdf: u32,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, IvshmemError> {
+        let pci_configuration_state =
+            vm_migration::versioned_state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID)
+                .map_err(|e| {
+                    IvshmemError::RetrievePciConfigurationState(anyhow!(
+                        "Failed to get PciConfigurationState from Snapshot: {}",
+                        e
+                    ))
+                })?;
+
+        let doorbell = if let Some(server) = server {
+            let interrupt_source_group = interrupt_manager
+                .create_group(MsiIrqGroupConfig {
+                    base: 0,
+                    count: vectors as InterruptIndex,
+                })
+                .map_err(|e| {
+                    IvshmemError::CreateIvshmemDevice(anyhow!(
+                        "Failed creating MSI interrupt group: {}",
+                        e
+                    ))
+                })?;
+
+            let msix_state =
+                vm_migration::versioned_state_from_id(snapshot.as_ref(), pci::MSIX_CONFIG_ID)
+                    .map_err(|e| {
+                        IvshmemError::CreateIvshmemDevice(anyhow!(
+                            "Failed to get MsixConfigState from Snapshot: {}",
+                            e
+                        ))
+                    })?;
+            let msix_config = MsixConfig::new(
+                vectors,
+                interrupt_source_group.clone(),
+                pci_device_bdf,
+                msix_state,
+            )
+            .map_err(|e| {
+                IvshmemError::CreateIvshmemDevice(anyhow!(
+                    "Failed creating MSI-X configuration: {:?}",
+                    e
+                ))
+            })?;
+
+            Some(Doorbell {
+                id: server.id,
+                peers: Arc::new(Mutex::new(BTreeMap::new())),
+                msix_config: Arc::new(Mutex::new(msix_config)),
+                interrupt_source_group,
+                server: Some(server.socket),
+                kill_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(|e| {
+                    IvshmemError::CreateIvshmemDevice(anyhow!(
+                        "Failed creating kill eventfd: {}",
+                        e
+                    ))
+                })?,
+            })
+        } else {
+            None
+        };
+
+        let mut configuration = PciConfiguration::new(
+            IVSHMEM_VENDOR_ID,
+            IVSHMEM_DEVICE_ID,
+            0x1,
+            PciClassCode::MemoryController,
+            &IvshmemSubclass::Ram,
+            None,
+            PciHeaderType::Device,
+            0,
+            0,
+            doorbell
+                .as_ref()
+                .map(|doorbell| doorbell.msix_config.clone()),
+            pci_configuration_state,
+        );
+
+        let command: [u8; 2] = [0x03, 0x01];
+        configuration.write_config_register(1, 0, &command);
+
+        Ok(IvshmemDevice {
+            id,
+            shm_region,
+            shm_mem_slot: None,
+            doorbell,
+            configuration,
+            bar_regions: vec![],
+        })
+    }
+
+    pub fn shm_size(&self) -> u64 {
+        self.shm_region.size() as u64
+    }
+
+    pub fn shm_host_addr(&self) -> u64 {
+        self.shm_region.as_ptr() as u64
+    }
+
+    /// Guest address of the shared memory, once the BARs are allocated
+    pub fn shm_bar_addr(&self) -> Option<u64> {
+        self.bar_regions
+            .iter()
+            .find(|bar| bar.idx() == IVSHMEM_SHM_BAR_INDEX)
+            .map(|bar| bar.addr())
+    }
+
+    /// Memory slot mapping the shared memory in the guest
+    pub fn shm_mem_slot(&self) -> Option<u32> {
+        self.shm_mem_slot
+    }
+
+    pub fn set_shm_mem_slot(&mut self, mem_slot: u32) {
+        self.shm_mem_slot = Some(mem_slot);
+    }
+
+    /// Handler of the doorbell, to be run by a thread of its own. This
+    /// returns `None` for an ivshmem-plain device, or once taken.
+    pub fn doorbell_handler(&mut self) -> Option<IvshmemDoorbellHandler> {
+        let doorbell = self.doorbell.as_mut()?;
+        let server = doorbell.server.take()?;
+        let kill_evt = match doorbell.kill_evt.try_clone() {
+            Ok(kill_evt) => kill_evt,
+            Err(e) => {
+                error!("Failed cloning the ivshmem kill eventfd: {}", e);
+                return None;
+            }
+        };
+
+        Some(IvshmemDoorbellHandler {
+            id: doorbell.id,
+            vectors: doorbell.msix_config.lock().unwrap().table_entries.len() as u16,
+            peers: doorbell.peers.clone(),
+            msix_config: doorbell.msix_config.clone(),
+            interrupt_source_group: doorbell.interrupt_source_group.clone(),
+            server,
+            kill_evt,
+            vector_evts: Vec::new(),
+        })
+    }
+
+    fn ring_doorbell(&self, value: u32) {
+        let Some(doorbell) = &self.doorbell else {
+            return;
+        };
+
+        let peer = (value >> 16) as u16;
+        let vector = (value & 0xffff) as usize;
+        let peers = doorbell.peers.lock().unwrap();
+        let Some(mut evt) = peers.get(&peer).and_then(|evts| evts.get(vector)) else {
+            debug!(
+                "ivshmem doorbell of unknown peer {} vector {}",
+                peer, vector
+            );
+            return;
+        };
+
+        if let Err(e) = evt.write_all(&1u64.to_ne_bytes()) {
+            warn!(
+                "Failed to ring the ivshmem doorbell of peer {} vector {}: {}",
+                peer, vector, e
+            );
+        }
+    }
+
+    fn bar_index(&self, base: u64) -> Option<usize> {
+        self.bar_regions
+            .iter()
+            .find(|bar| bar.addr() == base)
+            .map(|bar| bar.idx())
+    }
+
+    fn read_registers(&self, offset: u64, data: &mut [u8]) {
+        let value: u32 = match offset {
+            // Only meant for legacy interrupts, which aren't supported
+            IVSHMEM_REG_INTR_MASK | IVSHMEM_REG_INTR_STATUS => 0,
+            // Reads as -1 without a server
+            IVSHMEM_REG_IV_POSITION => self
+                .doorbell
+                .as_ref()
+                .map_or(u32::MAX, |doorbell| u32::from(doorbell.id)),
+            _ => 0,
+        };
+
+        if let Some(bytes) = value.to_le_bytes().get(..data.len()) {
+            data.copy_from_slice(bytes);
+        }
+    }
+}
+
+impl Drop for IvshmemDevice {
+    fn drop(&mut self) {
+        if let Some(doorbell) = &self.doorbell {
+            let _ = doorbell.kill_evt.write(1);
+        }
+    }
+}
+
+impl BusDevice for IvshmemDevice {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for IvshmemDevice {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &Arc<Mutex<SystemAllocator>>,
+        mmio_allocator: &mut AddressAllocator,
+        resources: Option<Vec<Resource>>,
+    ) -> std::result::Result<Vec<PciBarConfiguration>, PciDeviceError> {
+        let restoring = resources.is_some();
+        let mut bar_addrs = BTreeMap::new();
+        for resource in resources.into_iter().flatten() {
+            if let Resource::PciBar { index, base, .. } = resource {
+                bar_addrs.insert(index, GuestAddress(base));
+            }
+        }
+
+        let mut bars = vec![(
+            IVSHMEM_REG_BAR_INDEX,
+            IVSHMEM_REG_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+        )];
+        if self.doorbell.is_some() {
+            bars.push((
+                IVSHMEM_MSIX_BAR_INDEX,
+                IVSHMEM_MSIX_BAR_SIZE,
+                PciBarRegionType::Memory32BitRegion,
+            ));
+        }
+        bars.push((
+            IVSHMEM_SHM_BAR_INDEX,
+            self.shm_size(),
+            PciBarRegionType::Memory64BitRegion,
+        ));
+
+        for (bar_id, region_size, region_type) in bars {
+            let bar_addr = bar_addrs.get(&bar_id).copied();
+            if restoring && bar_addr.is_none() {
+                return Err(PciDeviceError::MissingResource);
+            }
+
+            let (bar_addr, prefetchable) = if region_type == PciBarRegionType::Memory64BitRegion {
+                let addr = mmio_allocator
+                    .allocate(bar_addr, region_size, Some(region_size))
+                    .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+                (addr, PciBarPrefetchable::Prefetchable)
+            } else {
+                let addr = allocator
+                    .lock()
+                    .unwrap()
+                    .allocate_mmio_hole_addresses(bar_addr, region_size, Some(region_size))
+                    .ok_or(PciDeviceError::IoAllocationFailed(region_size))?;
+                (addr, PciBarPrefetchable::NotPrefetchable)
+            };
+
+            let bar = PciBarConfiguration::default()
+                .set_index(bar_id)
+                .set_address(bar_addr.raw_value())
+                .set_size(region_size)
+                .set_region_type(region_type)
+                .set_prefetchable(prefetchable);
+
+            debug!("ivshmem bar {} address 0x{:x}", bar_id, bar_addr.0);
+            if !restoring {
+                self.configuration
+                    .add_pci_bar(&bar)
+                    .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+            }
+
+            self.bar_regions.push(bar);
+        }
+
+        if !restoring && self.doorbell.is_some() {
+            let vectors = self
+                .doorbell
+                .as_ref()
+                .unwrap()
+                .msix_config
+                .lock()
+                .unwrap()
+                .table_entries
+                .len() as u16;
+            let msix_cap = MsixCap::new(
+                IVSHMEM_MSIX_BAR_INDEX as u8,
+                vectors,
+                IVSHMEM_MSIX_TABLE_OFFSET,
+                IVSHMEM_MSIX_BAR_INDEX as u8,
+                IVSHMEM_MSIX_PBA_OFFSET as u32,
+            );
+            self.configuration
+                .add_capability(&msix_cap)
+                .map_err(PciDeviceError::CapabilitiesSetup)?;
+        }
+
+        Ok(self.bar_regions.clone())
+    }
+
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+        mmio_allocator: &mut AddressAllocator,
+    ) -> std::result::Result<(), PciDeviceError> {
+        for bar in self.bar_regions.drain(..) {
+            match bar.region_type() {
+                PciBarRegionType::Memory32BitRegion => {
+                    allocator.free_mmio_hole_addresses(GuestAddress(bar.addr()), bar.size());
+                }
+                PciBarRegionType::Memory64BitRegion => {
+                    mmio_allocator.free(GuestAddress(bar.addr()), bar.size());
+                }
+                _ => error!("Unexpected PCI bar type"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> result::Result<(), std::io::Error> {
+        // The shared memory is remapped by the DeviceManager
+        for bar in self.bar_regions.iter_mut() {
+            if bar.addr() == old_base {
+                *bar = bar.set_address(new_base);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        match self.bar_index(base) {
+            Some(IVSHMEM_REG_BAR_INDEX) => self.read_registers(offset, data),
+            Some(IVSHMEM_MSIX_BAR_INDEX) => {
+                let Some(doorbell) = &self.doorbell else {
+                    return;
+                };
+                let mut msix_config = doorbell.msix_config.lock().unwrap();
+                if offset < IVSHMEM_MSIX_PBA_OFFSET {
+                    msix_config.read_table(offset, data);
+                } else {
+                    msix_config.read_pba(offset - IVSHMEM_MSIX_PBA_OFFSET, data);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        match self.bar_index(base) {
+            Some(IVSHMEM_REG_BAR_INDEX) => {
+                if offset == IVSHMEM_REG_DOORBELL && data.len() == 4 {
+                    self.ring_doorbell(u32::from_le_bytes(data.try_into().unwrap()));
+                }
+            }
+            Some(IVSHMEM_MSIX_BAR_INDEX) => {
+                let doorbell = self.doorbell.as_ref()?;
+                let mut msix_config = doorbell.msix_config.lock().unwrap();
+                if offset < IVSHMEM_MSIX_PBA_OFFSET {
+                    msix_config.write_table(offset, data);
+                } else {
+                    msix_config.write_pba(offset - IVSHMEM_MSIX_PBA_OFFSET, data);
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for IvshmemDevice {}
+
+impl Snapshottable for IvshmemDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    // The shared memory isn't part of the snapshot, it lives on in the file
+    // or the ivshmem server.
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::default();
+
+        // Snapshot PciConfiguration
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+
+        // Snapshot MSI-X
+        if let Some(doorbell) = &self.doorbell {
+            let mut msix_config = doorbell.msix_config.lock().unwrap();
+            snapshot.add_snapshot(msix_config.id(), msix_config.snapshot()?);
+        }
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for IvshmemDevice {}
+impl Migratable for IvshmemDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vm_device::interrupt::InterruptSourceConfig;
+
+    #[derive(Default)]
+    struct TestInterrupt {
+        triggered: AtomicUsize,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> io::Result<()> {
+            self.triggered.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct TestInterruptManager {
+        interrupt: Arc<TestInterrupt>,
+    }
+
+    impl InterruptManager for TestInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: MsiIrqGroupConfig,
+        ) -> io::Result<Arc<dyn InterruptSourceGroup>> {
+            Ok(self.interrupt.clone())
+        }
+
+        fn destroy_group(&self, _group: Arc<dyn InterruptSourceGroup>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const SHM_SIZE: usize = 0x10_0000;
+
+    fn new_device(
+        server: Option<IvshmemServer>,
+        interrupt_manager: &TestInterruptManager,
+    ) -> (IvshmemDevice, Vec<PciBarConfiguration>) {
+        let mut device = IvshmemDevice::new(
+            "_ivshmem0".to_string(),
+            MmapRegion::new(SHM_SIZE).unwrap(),
+            server,
+            2,
+            interrupt_manager,
+            0,
+            None,
+        )
+        .unwrap();
+
+        let allocator = Arc::new(Mutex::new(
+            SystemAllocator::new(
+                #[cfg(target_arch = "x86_64")]
+                GuestAddress(0),
+                #[cfg(target_arch = "x86_64")]
+                0x10000,
+                GuestAddress(0x1_0000_0000),
+                0x1000_0000,
+                GuestAddress(0xc000_0000),
+                0x1000_0000,
+                #[cfg(target_arch = "x86_64")]
+                vec![],
+            )
+            .unwrap(),
+        ));
+        let mut mmio_allocator =
+            AddressAllocator::new(GuestAddress(0x2_0000_0000), 0x1_0000_0000).unwrap();
+        let bars = device
+            .allocate_bars(&allocator, &mut mmio_allocator, None)
+            .unwrap();
+
+        (device, bars)
+    }
+
+    fn bar(bars: &[PciBarConfiguration], index: usize) -> &PciBarConfiguration {
+        bars.iter().find(|bar| bar.idx() == index).unwrap()
+    }
+
+    fn read_u32(device: &mut IvshmemDevice, base: u64, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read_bar(base, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_ivshmem_plain() {
+        let interrupt_manager = TestInterruptManager::default();
+        let (mut device, bars) = new_device(None, &interrupt_manager);
+
+        assert_eq!(bars.len(), 2);
+        let reg_bar = bar(&bars, IVSHMEM_REG_BAR_INDEX);
+        assert_eq!(reg_bar.size(), IVSHMEM_REG_BAR_SIZE);
+        assert_eq!(reg_bar.region_type(), PciBarRegionType::Memory32BitRegion);
+        let shm_bar = bar(&bars, IVSHMEM_SHM_BAR_INDEX);
+        assert_eq!(shm_bar.size(), SHM_SIZE as u64);
+        assert_eq!(shm_bar.region_type(), PciBarRegionType::Memory64BitRegion);
+        assert_eq!(device.shm_bar_addr(), Some(shm_bar.addr()));
+        assert_eq!(
+            device.read_config_register(0),
+            (u32::from(IVSHMEM_DEVICE_ID) << 16) | u32::from(IVSHMEM_VENDOR_ID)
+        );
+
+        let reg_base = reg_bar.addr();
+        assert_eq!(
+            read_u32(&mut device, reg_base, IVSHMEM_REG_IV_POSITION),
+            u32::MAX
+        );
+        assert_eq!(read_u32(&mut device, reg_base, IVSHMEM_REG_INTR_STATUS), 0);
+        // Ringing the doorbell without a server is a no-op
+        device.write_bar(reg_base, IVSHMEM_REG_DOORBELL, &1u32.to_le_bytes());
+        assert!(device.doorbell_handler().is_none());
+
+        // Restoring the BARs at their addresses
+        let resources = bars
+            .iter()
+            .map(|bar| Resource::PciBar {
+                index: bar.idx(),
+                base: bar.addr(),
+                size: bar.size(),
+                type_: bar.region_type().into(),
+                prefetchable: bar.prefetchable().into(),
+            })
+            .collect::<Vec<_>>();
+        let allocator = Arc::new(Mutex::new(
+            SystemAllocator::new(
+                #[cfg(target_arch = "x86_64")]
+                GuestAddress(0),
+                #[cfg(target_arch = "x86_64")]
+                0x10000,
+                GuestAddress(0x1_0000_0000),
+                0x1000_0000,
+                GuestAddress(0xc000_0000),
+                0x1000_0000,
+                #[cfg(target_arch = "x86_64")]
+                vec![],
+            )
+            .unwrap(),
+        ));
+        let mut mmio_allocator =
+            AddressAllocator::new(GuestAddress(0x2_0000_0000), 0x1_0000_0000).unwrap();
+        let mut restored = IvshmemDevice::new(
+            "_ivshmem0".to_string(),
+            MmapRegion::new(SHM_SIZE).unwrap(),
+            None,
+            2,
+            &interrupt_manager,
+            0,
+            None,
+        )
+        .unwrap();
+        let restored_bars = restored
+            .allocate_bars(&allocator, &mut mmio_allocator, Some(resources))
+            .unwrap();
+        assert_eq!(
+            restored_bars
+                .iter()
+                .map(|bar| bar.addr())
+                .collect::<Vec<_>>(),
+            bars.iter().map(|bar| bar.addr()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_ivshmem_doorbell() {
+        let interrupt_manager = TestInterruptManager::default();
+        let (socket, server_socket) = UnixStream::pair().unwrap();
+        let server = IvshmemServer { socket, id: 3 };
+        let (mut device, bars) = new_device(Some(server), &interrupt_manager);
+
+        assert_eq!(bars.len(), 3);
+        let msix_bar = bar(&bars, IVSHMEM_MSIX_BAR_INDEX);
+        assert_eq!(msix_bar.size(), IVSHMEM_MSIX_BAR_SIZE);
+        let reg_base = bar(&bars, IVSHMEM_REG_BAR_INDEX).addr();
+        assert_eq!(read_u32(&mut device, reg_base, IVSHMEM_REG_IV_POSITION), 3);
+
+        let mut handler = device.doorbell_handler().unwrap();
+        assert!(device.doorbell_handler().is_none());
+        let epoll_fd = epoll::create(true).unwrap();
+        // SAFETY: the file descriptor was just created, and is owned by the
+        // file from now on.
+        let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+
+        // The server announces a peer along with the eventfd of its vector
+        let peer_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        server_socket
+            .send_with_fd(&5i64.to_le_bytes()[..], peer_evt.as_raw_fd())
+            .unwrap();
+        handler.handle_server_message(epoll_fd).unwrap();
+
+        device.write_bar(reg_base, IVSHMEM_REG_DOORBELL, &(5u32 << 16).to_le_bytes());
+        assert_eq!(peer_evt.read().unwrap(), 1);
+        // Unknown vectors and peers are ignored
+        device.write_bar(
+            reg_base,
+            IVSHMEM_REG_DOORBELL,
+            &((5u32 << 16) | 1).to_le_bytes(),
+        );
+        device.write_bar(reg_base, IVSHMEM_REG_DOORBELL, &(6u32 << 16).to_le_bytes());
+        assert!(peer_evt.read().is_err());
+
+        // And the disconnection of the peer
+        (&server_socket).write_all(&5i64.to_le_bytes()).unwrap();
+        handler.handle_server_message(epoll_fd).unwrap();
+        device.write_bar(reg_base, IVSHMEM_REG_DOORBELL, &(5u32 << 16).to_le_bytes());
+        assert!(peer_evt.read().is_err());
+
+        // The vectors of the device itself are waited for
+        let own_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        server_socket
+            .send_with_fd(&3i64.to_le_bytes()[..], own_evt.as_raw_fd())
+            .unwrap();
+        handler.handle_server_message(epoll_fd).unwrap();
+        assert_eq!(handler.vector_evts.len(), 1);
+    }
+
+    #[test]
+    fn test_ivshmem_msix() {
+        let interrupt_manager = TestInterruptManager::default();
+        let (socket, _server_socket) = UnixStream::pair().unwrap();
+        let server = IvshmemServer { socket, id: 0 };
+        let (mut device, bars) = new_device(Some(server), &interrupt_manager);
+        let msix_base = bar(&bars, IVSHMEM_MSIX_BAR_INDEX).addr();
+        let handler = device.doorbell_handler().unwrap();
+        let triggered = || interrupt_manager.interrupt.triggered.load(Ordering::SeqCst);
+
+        // MSI-X starts disabled and masked, the interrupt is left pending
+        handler.trigger(0).unwrap();
+        assert_eq!(triggered(), 0);
+        assert_eq!(
+            read_u32(&mut device, msix_base, IVSHMEM_MSIX_PBA_OFFSET),
+            0x1
+        );
+
+        // Still pending while the vector is masked
+        handler.msix_config.lock().unwrap().set_msg_ctl(1 << 15);
+        assert_eq!(triggered(), 0);
+        assert_eq!(
+            read_u32(&mut device, msix_base, IVSHMEM_MSIX_PBA_OFFSET),
+            0x1
+        );
+
+        // Delivered once the vector is unmasked
+        device.write_bar(msix_base, 0xc, &0u32.to_le_bytes());
+        assert_eq!(read_u32(&mut device, msix_base, 0xc), 0);
+        assert_eq!(triggered(), 1);
+        assert_eq!(read_u32(&mut device, msix_base, IVSHMEM_MSIX_PBA_OFFSET), 0);
+
+        handler.trigger(0).unwrap();
+        assert_eq!(triggered(), 2);
+
+        // Masking the vector again
+        device.write_bar(msix_base, 0xc, &1u32.to_le_bytes());
+        handler.trigger(0).unwrap();
+        assert_eq!(triggered(), 2);
+        assert_eq!(
+            read_u32(&mut device, msix_base, IVSHMEM_MSIX_PBA_OFFSET),
+            0x1
+        );
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+use std::sync::{Arc, Barrier, Condvar, Mutex};
+use std::thread;
+use vm_device::BusDevice;
+
+/// Value read from the port, telling the guest the debug console is present.
+pub const DEBUG_CONSOLE_MAGIC: u8 = 0xe9;
+
+// Most bytes waiting for the output. The guest isn't slowed down by a
+// stalled output, what it writes beyond this being dropped.
+const MAX_PENDING_BYTES: usize = 1 << 16;
+
+#[derive(Default)]
+struct Pending {
+    data: Vec<u8>,
+    dropped: bool,
+    closed: bool,
+}
+
+/// Debug console, as found on Bochs and QEMU, where each byte written to the
+/// port is sent as is to the output. It sits on the I/O bus at 0xe9 on x86_64,
+/// and on the MMIO bus on aarch64, the data register being at offset 0.
+///
+/// The bytes are written to the output by a thread of the device, the vCPU
+/// only queuing them.
+pub struct DebugConsole {
+    pending: Arc<(Mutex<Pending>, Condvar)>,
+}
+
+impl DebugConsole {
+    pub fn new(out: Box<dyn io::Write + Send>) -> io::Result<Self> {
+        let pending = Arc::new((Mutex::new(Pending::default()), Condvar::new()));
+        let output_pending = pending.clone();
+        thread::Builder::new()
+            .name("debug_console".to_string())
+            .spawn(move || Self::output(out, &output_pending))?;
+
+        Ok(Self { pending })
+    }
+
+    fn output(mut out: Box<dyn io::Write + Send>, pending: &(Mutex<Pending>, Condvar)) {
+        let (lock, cvar) = pending;
+        loop {
+            let data = {
+                let mut pending = lock.lock().unwrap();
+                while pending.data.is_empty() && !pending.closed {
+                    pending = cvar.wait(pending).unwrap();
+                }
+                if pending.data.is_empty() {
+                    return;
+                }
+                if pending.dropped {
+                    warn!("Debug console output too slow, bytes were dropped");
+                    pending.dropped = false;
+                }
+                std::mem::take(&mut pending.data)
+            };
+
+            if let Err(e) = out.write_all(&data).and_then(|_| out.flush()) {
+                error!("Error writing to the debug console output: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for DebugConsole {
+    fn drop(&mut self) {
+        // The output thread exits once it wrote the pending bytes.
+        let (lock, cvar) = &*self.pending;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_one();
+    }
+}
+
+impl BusDevice for DebugConsole {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        data.fill(0);
+        if offset == 0 {
+            data[0] = DEBUG_CONSOLE_MAGIC;
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        // Only the low byte of wider accesses is a character
+        if offset != 0 || data.is_empty() {
+            return None;
+        }
+
+        let (lock, cvar) = &*self.pending;
+        let mut pending = lock.lock().unwrap();
+        if pending.data.len() < MAX_PENDING_BYTES {
+            pending.data.push(data[0]);
+            cvar.notify_one();
+        } else {
+            pending.dropped = true;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_debug_console() {
+        let buffer = SharedBuffer::default();
+        let mut debug_console = DebugConsole::new(Box::new(buffer.clone())).unwrap();
+
+        let mut data = [0u8; 1];
+        debug_console.read(0, 0, &mut data);
+        assert_eq!(data[0], DEBUG_CONSOLE_MAGIC);
+
+        for c in b"ok\n" {
+            debug_console.write(0, 0, &[*c]);
+        }
+        // Only the low byte of a word access is written
+        debug_console.write(0, 0, &[b'!', 0, 0, 0]);
+        debug_console.write(0, 4, &[b'?']);
+
+        // The pending bytes are written once the device is gone
+        drop(debug_console);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while buffer.0.lock().unwrap().len() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(buffer.0.lock().unwrap().as_slice(), b"ok\n!");
+    }
+}
@@ -6,6 +6,7 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 mod cmos;
+mod debug_console;
 #[cfg(target_arch = "x86_64")]
 mod debug_port;
 #[cfg(target_arch = "x86_64")]
@@ -13,6 +14,7 @@ mod fwdebug;
 #[cfg(target_arch = "aarch64")]
 mod gpio_pl061;
 mod i8042;
+mod pflash;
 #[cfg(target_arch = "aarch64")]
 mod rtc_pl031;
 mod serial;
@@ -20,11 +22,13 @@ mod serial;
 mod uart_pl011;
 
 pub use self::cmos::Cmos;
+pub use self::debug_console::DebugConsole;
 #[cfg(target_arch = "x86_64")]
 pub use self::debug_port::DebugPort;
 #[cfg(target_arch = "x86_64")]
 pub use self::fwdebug::FwDebugDevice;
 pub use self::i8042::I8042Device;
+pub use self::pflash::{Pflash, PFLASH_BLOCK_SIZE};
 pub use self::serial::Serial;
 
 #[cfg(target_arch = "aarch64")]
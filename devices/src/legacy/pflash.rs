@@ -0,0 +1,212 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! CFI parallel flash backing the UEFI variable store with a file.
+//!
+//! Only the commands the EDK2 flash driver relies on are implemented: byte
+//! program, block erase, and the status register reads. Every program and
+//! erase is written through to the file, for the variables to survive the
+//! VMM.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Barrier};
+use vm_device::BusDevice;
+
+/// Size of the blocks erased at once.
+pub const PFLASH_BLOCK_SIZE: u64 = 0x1000;
+
+const ERASED: u8 = 0xff;
+
+// Commands, see the Intel "Common Flash Interface" and "3 Volt Synchronous
+// StrataFlash" specifications.
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_READ_ARRAY_ALT: u8 = 0x00;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+const CMD_WRITE_BYTE: u8 = 0x10;
+const CMD_WRITE_BYTE_ALT: u8 = 0x40;
+const CMD_BLOCK_ERASE: u8 = 0x20;
+const CMD_BLOCK_ERASE_CONFIRM: u8 = 0xd0;
+
+// Status register bits.
+const STATUS_READY: u8 = 1 << 7;
+const STATUS_ERASE_ERROR: u8 = 1 << 5;
+const STATUS_PROGRAM_ERROR: u8 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    ReadArray,
+    ReadStatus,
+    // Waiting for the byte to program
+    WriteByte,
+    // Waiting for the erase confirmation
+    BlockErase,
+}
+
+pub struct Pflash {
+    file: File,
+    data: Vec<u8>,
+    mode: Mode,
+    status: u8,
+}
+
+impl Pflash {
+    /// Create the flash from the content of `file`. An empty file is first
+    /// extended to `default_size` and erased, the firmware formatting the
+    /// variable store when it finds it blank.
+    pub fn new(file: File, default_size: u64) -> io::Result<Self> {
+        let mut size = file.metadata()?.len();
+        if size == 0 {
+            file.write_all_at(&vec![ERASED; default_size as usize], 0)?;
+            size = default_size;
+        }
+        if size % PFLASH_BLOCK_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("size {size:#x} not a multiple of the {PFLASH_BLOCK_SIZE:#x} block size"),
+            ));
+        }
+
+        let mut data = vec![0; size as usize];
+        file.read_exact_at(&mut data, 0)?;
+
+        Ok(Pflash {
+            file,
+            data,
+            mode: Mode::ReadArray,
+            status: STATUS_READY,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn program(&mut self, offset: u64, values: &[u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let bytes = self
+            .data
+            .get_mut(start..start + values.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        bytes.copy_from_slice(values);
+        self.file.write_all_at(bytes, offset)
+    }
+
+    fn erase(&mut self, offset: u64) -> io::Result<()> {
+        let start = offset & !(PFLASH_BLOCK_SIZE - 1);
+        let block = self
+            .data
+            .get_mut(start as usize..(start + PFLASH_BLOCK_SIZE) as usize)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        block.fill(ERASED);
+        self.file.write_all_at(block, start)
+    }
+}
+
+impl BusDevice for Pflash {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        match self.mode {
+            Mode::ReadArray => {
+                let start = offset as usize;
+                match self.data.get(start..start + data.len()) {
+                    Some(bytes) => data.copy_from_slice(bytes),
+                    None => data.fill(ERASED),
+                }
+            }
+            // The status register is read back until the array read mode is
+            // restored.
+            _ => data.fill(self.status),
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        self.mode = match (self.mode, data[0]) {
+            (Mode::WriteByte, _) => {
+                if let Err(e) = self.program(offset, data) {
+                    error!("Failed programming the flash at {offset:#x}: {e}");
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                Mode::ReadStatus
+            }
+            (Mode::BlockErase, CMD_BLOCK_ERASE_CONFIRM) => {
+                if let Err(e) = self.erase(offset) {
+                    error!("Failed erasing the flash block at {offset:#x}: {e}");
+                    self.status |= STATUS_ERASE_ERROR;
+                }
+                Mode::ReadStatus
+            }
+            (Mode::BlockErase, _) => {
+                // Erasing without confirmation is a command sequence error
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                Mode::ReadStatus
+            }
+            (_, CMD_READ_ARRAY | CMD_READ_ARRAY_ALT) => Mode::ReadArray,
+            (_, CMD_READ_STATUS) => Mode::ReadStatus,
+            (_, CMD_CLEAR_STATUS) => {
+                self.status = STATUS_READY;
+                Mode::ReadArray
+            }
+            (_, CMD_WRITE_BYTE | CMD_WRITE_BYTE_ALT) => Mode::WriteByte,
+            (_, CMD_BLOCK_ERASE) => Mode::BlockErase,
+            (_, command) => {
+                warn!("Unsupported flash command {command:#x}");
+                Mode::ReadArray
+            }
+        };
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_pflash() {
+        let file = TempFile::new().unwrap();
+        let mut pflash = Pflash::new(file.as_file().try_clone().unwrap(), 0x2000).unwrap();
+        assert_eq!(pflash.size(), 0x2000);
+
+        let mut data = [0u8; 4];
+        pflash.read(0, 0x10, &mut data);
+        assert_eq!(data, [ERASED; 4]);
+
+        // Probed as flash by EDK2: the status is read back instead of the
+        // command written.
+        pflash.write(0, 0, &[CMD_READ_STATUS]);
+        pflash.read(0, 0, &mut data[..1]);
+        assert_eq!(data[0], STATUS_READY);
+
+        pflash.write(0, 0x1010, &[CMD_WRITE_BYTE]);
+        pflash.write(0, 0x1010, &[0x42]);
+        pflash.write(0, 0, &[CMD_READ_ARRAY]);
+        pflash.read(0, 0x1010, &mut data[..1]);
+        assert_eq!(data[0], 0x42);
+
+        pflash.write(0, 0x1000, &[CMD_BLOCK_ERASE]);
+        pflash.write(0, 0x1000, &[CMD_BLOCK_ERASE_CONFIRM]);
+        pflash.write(0, 0x10, &[CMD_WRITE_BYTE]);
+        pflash.write(0, 0x10, &[0x24]);
+        pflash.write(0, 0, &[CMD_READ_ARRAY]);
+        pflash.read(0, 0x1010, &mut data[..1]);
+        assert_eq!(data[0], ERASED);
+
+        // Written through to the file
+        let mut content = Vec::new();
+        file.as_file().read_to_end(&mut content).unwrap();
+        assert_eq!(content.len(), 0x2000);
+        assert_eq!(content[0x10], 0x24);
+        assert_eq!(content[0x1010], ERASED);
+
+        // A file not made of whole blocks is rejected
+        file.as_file().set_len(0x2001).unwrap();
+        assert!(Pflash::new(file.as_file().try_clone().unwrap(), 0x2000).is_err());
+    }
+}
@@ -15,16 +15,20 @@ extern crate event_monitor;
 extern crate log;
 
 pub mod acpi;
+pub mod ghes;
 #[cfg(target_arch = "aarch64")]
 pub mod gic;
 pub mod interrupt_controller;
 #[cfg(target_arch = "x86_64")]
 pub mod ioapic;
+pub mod ivshmem;
 pub mod legacy;
 pub mod pvpanic;
 pub mod tpm;
 
 pub use self::acpi::{AcpiGedDevice, AcpiPmTimerDevice, AcpiShutdownDevice};
+pub use self::ghes::{GhesDevice, PcieError, GHES_DEVICE_SIZE};
+pub use self::ivshmem::{IvshmemDevice, IvshmemDoorbellHandler, IvshmemServer};
 pub use self::pvpanic::{PvPanicDevice, PVPANIC_DEVICE_MMIO_SIZE};
 
 bitflags! {
@@ -34,6 +38,7 @@ bitflags! {
         const MEMORY_DEVICES_CHANGED = 0b10;
         const PCI_DEVICES_CHANGED = 0b100;
         const POWER_BUTTON_CHANGED = 0b1000;
+        const HARDWARE_ERROR = 0b10000;
     }
 }
 
@@ -21,6 +21,7 @@ use vm_memory::{Address, GuestAddress};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
 };
+use vmm_sys_util::eventfd::EventFd;
 
 const PVPANIC_VENDOR_ID: u16 = 0x1b36;
 const PVPANIC_DEVICE_ID: u16 = 0x0011;
@@ -53,6 +54,8 @@ impl PciSubclass for PvPanicSubclass {
 pub struct PvPanicDevice {
     id: String,
     events: u8,
+    // Signaled when the guest reports a panic, for the VMM to apply its policy
+    panic_evt: EventFd,
 
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -67,7 +70,11 @@ pub struct PvPanicDeviceState {
 impl VersionMapped for PvPanicDeviceState {}
 
 impl PvPanicDevice {
-    pub fn new(id: String, snapshot: Option<Snapshot>) -> Result<Self, PvPanicError> {
+    pub fn new(
+        id: String,
+        panic_evt: EventFd,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, PvPanicError> {
         let pci_configuration_state =
             vm_migration::versioned_state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID)
                 .map_err(|e| {
@@ -113,6 +120,7 @@ impl PvPanicDevice {
         let pvpanic_device = PvPanicDevice {
             id,
             events,
+            panic_evt,
             configuration,
             bar_regions: vec![],
         };
@@ -150,6 +158,14 @@ impl BusDevice for PvPanicDevice {
         let event = self.event_to_string(data[0]);
         info!("pvpanic got guest event {}", event);
         event!("guest", "panic", "event", &event);
+
+        // A crash kernel being loaded means the guest handles the crash by
+        // itself, only an actual panic triggers the VMM policy.
+        if data[0] & PVPANIC_PANICKED != 0 {
+            if let Err(e) = self.panic_evt.write(1) {
+                error!("Failed to signal guest panic: {}", e);
+            }
+        }
         None
     }
 }
@@ -3,17 +3,21 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::Serialize;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 static MONITOR: OnceCell<MonitorHandle> = OnceCell::new();
+static HISTORY: Lazy<Mutex<EventHistory>> = Lazy::new(Mutex::default);
+
+/// Number of events kept for the clients polling them.
+pub const EVENT_HISTORY_SIZE: usize = 1024;
 
 #[derive(Serialize)]
 struct Event<'a> {
@@ -45,6 +49,38 @@ impl Monitor {
     }
 }
 
+// The events are numbered in the order they are reported, the oldest ones
+// being dropped once the history is full.
+#[derive(Default)]
+struct EventHistory {
+    first: u64,
+    events: VecDeque<Arc<String>>,
+}
+
+/// Keep an event for the clients polling them.
+pub fn record_history(event: Arc<String>) {
+    let mut history = HISTORY.lock().unwrap();
+    if history.events.len() == EVENT_HISTORY_SIZE {
+        history.events.pop_front();
+        history.first += 1;
+    }
+    history.events.push_back(event);
+}
+
+/// Events kept from the event numbered `since`, along with the number of the
+/// next event to be reported. The events dropped from the history are
+/// skipped.
+pub fn history_since(since: u64) -> (Vec<Arc<String>>, u64) {
+    let history = HISTORY.lock().unwrap();
+    let next = history.first + history.events.len() as u64;
+    let skip = since.clamp(history.first, next) - history.first;
+
+    (
+        history.events.iter().skip(skip as usize).cloned().collect(),
+        next,
+    )
+}
+
 struct MonitorHandle {
     tx: flume::Sender<String>,
     start: Instant,
@@ -129,3 +165,28 @@ macro_rules! event {
         }
      };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_since() {
+        for i in 0..EVENT_HISTORY_SIZE + 2 {
+            record_history(Arc::new(i.to_string()));
+        }
+
+        let next = EVENT_HISTORY_SIZE as u64 + 2;
+        assert_eq!(history_since(next), (vec![], next));
+        assert_eq!(history_since(u64::MAX), (vec![], next));
+        assert_eq!(
+            history_since(next - 1),
+            (vec![Arc::new((EVENT_HISTORY_SIZE + 1).to_string())], next)
+        );
+
+        // The first two events were dropped
+        let (events, _) = history_since(0);
+        assert_eq!(events.len(), EVENT_HISTORY_SIZE);
+        assert_eq!(*events[0], "2");
+    }
+}
@@ -17,7 +17,9 @@ use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::sync::Arc;
-use virtio_devices::{Block, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{
+    Block, NotificationConfig, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+};
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
@@ -60,6 +62,8 @@ fuzz_target!(|bytes| {
         None,
         SeccompAction::Allow,
         None,
+        None,
+        NotificationConfig::default(),
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
     )
@@ -73,6 +73,7 @@ fuzz_target!(|bytes| {
         endpoint,
         None,  // resize_pipe
         false, // iommu
+        1,     // max_ports
         SeccompAction::Allow,
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
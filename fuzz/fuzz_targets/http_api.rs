@@ -120,6 +120,9 @@ fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receive
                         ApiRequest::VmmPing(sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
+                        ApiRequest::VmmResources(sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
                         ApiRequest::VmPause(sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
@@ -141,9 +144,30 @@ fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receive
                         ApiRequest::VmResizeZone(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
+                        ApiRequest::VmBindZone(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmResizeFs(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmRemoveVcpu(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmSetCpuAffinity(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmSetCpuBandwidth(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
                         ApiRequest::VmAddDevice(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
+                        ApiRequest::VmAddVf(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmAddMdev(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
                         ApiRequest::VmAddUserDevice(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
@@ -168,7 +192,17 @@ fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receive
                         ApiRequest::VmAddVsock(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
-                        ApiRequest::VmCounters(sender) => {
+                        #[cfg(target_arch = "x86_64")]
+                        ApiRequest::VmAddSgxEpc(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmAddConsolePort(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmRemoveConsolePort(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmCounters(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
                         ApiRequest::VmReceiveMigration(_, sender) => {
@@ -177,9 +211,39 @@ fn http_receiver_stub(exit_evt: EventFd, api_evt: EventFd, api_receiver: Receive
                         ApiRequest::VmSendMigration(_, sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
+                        ApiRequest::VmMigrationPrecheck(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
                         ApiRequest::VmPowerButton(sender) => {
                             sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
                         }
+                        ApiRequest::VmGuestExec(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmGuestFsFreeze(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmMemoryRead(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmMemoryWrite(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmVirtqueueState(_, sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmGuestInfo(sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmBootTimings(sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmHeteroBalloon(sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
+                        ApiRequest::VmReportFreePages(sender) => {
+                            sender.send(Ok(ApiResponsePayload::Empty)).unwrap();
+                        }
                     }
                 }
             }
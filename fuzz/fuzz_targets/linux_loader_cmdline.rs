@@ -23,6 +23,9 @@ fuzz_target!(|bytes| {
         kernel: None,
         cmdline: Some(String::from_utf8_lossy(&bytes).to_string()),
         initramfs: None,
+        firmware_fd: None,
+        kernel_fd: None,
+        initramfs_fd: None,
     };
     let kernel_cmdline = match vmm::vm::Vm::generate_cmdline(&payload_config) {
         Ok(cmdline) => cmdline,
@@ -8,7 +8,10 @@ use libfuzzer_sys::fuzz_target;
 use seccompiler::SeccompAction;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::sync::{Arc, Mutex};
-use virtio_devices::{BlocksState, Mem, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{
+    BlocksState, Mem, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
+};
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
@@ -141,7 +144,10 @@ fn create_dummy_virtio_mem(bytes: &[u8; VIRTIO_MEM_DATA_SIZE]) -> (Mem, Arc<Gues
     )
     .unwrap();
 
-    let blocks_state = Arc::new(Mutex::new(BlocksState::new(region.size() as u64)));
+    let blocks_state = Arc::new(Mutex::new(BlocksState::new(
+        region.size() as u64,
+        VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
+    )));
 
     (
         Mem::new(
@@ -151,6 +157,7 @@ fn create_dummy_virtio_mem(bytes: &[u8; VIRTIO_MEM_DATA_SIZE]) -> (Mem, Arc<Gues
             numa_id.map(|i| i as u16),
             0,
             false,
+            VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
             EventFd::new(EFD_NONBLOCK).unwrap(),
             blocks_state.clone(),
             None,
@@ -10,7 +10,7 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::sync::Arc;
-use virtio_devices::{VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{NotificationConfig, VirtioDevice, VirtioInterrupt, VirtioInterruptType};
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm::EpollContext;
@@ -79,6 +79,7 @@ fuzz_target!(|bytes| {
         true,
         true,
         true,
+        NotificationConfig::default(),
     )
     .unwrap();
 
@@ -125,6 +125,7 @@ fn create_dummy_pmem() -> Pmem {
         dummy_user_mapping,
         dummy_mmap_region,
         false,
+        false,
         SeccompAction::Allow,
         EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
@@ -8,7 +8,9 @@ use libfuzzer_sys::fuzz_target;
 use seccompiler::SeccompAction;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::sync::Arc;
-use virtio_devices::{VirtioDevice, VirtioInterrupt, VirtioInterruptType};
+use virtio_devices::{
+    VirtioDevice, VirtioInterrupt, VirtioInterruptType, WatchdogAction, WatchdogCounters,
+};
 use virtio_queue::{Queue, QueueT};
 use vm_memory::{bitmap::AtomicBitmap, Bytes, GuestAddress, GuestMemoryAtomic};
 use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
@@ -36,8 +38,13 @@ fuzz_target!(|bytes| {
     let mut watchdog = virtio_devices::Watchdog::new(
         "fuzzer_watchdog".to_owned(),
         EventFd::new(EFD_NONBLOCK).unwrap(),
+        EventFd::new(EFD_NONBLOCK).unwrap(),
+        WatchdogAction::Reset,
+        None,
+        Arc::new(WatchdogCounters::default()),
         SeccompAction::Allow,
         EventFd::new(EFD_NONBLOCK).unwrap(),
+        EventFd::new(EFD_NONBLOCK).unwrap(),
         None,
     )
     .unwrap();
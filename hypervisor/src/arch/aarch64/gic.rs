@@ -1,6 +1,7 @@
 // Copyright 2022 Arm Limited (or its affiliates). All rights reserved.
 
 use crate::{CpuState, GicState, HypervisorDeviceError, HypervisorVmError};
+use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::result;
 
@@ -13,6 +14,10 @@ pub enum Error {
     SetDeviceAttribute(HypervisorDeviceError),
     /// Error while getting device attributes for the GIC.
     GetDeviceAttribute(HypervisorDeviceError),
+    /// The saved state does not match the GIC it is restored to.
+    InvalidState(String),
+    /// The GIC does not support the capabilities of the saved one.
+    IncompatibleCapabilities(String),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -28,6 +33,37 @@ pub struct VgicConfig {
     pub nr_irqs: u32,
 }
 
+/// Capabilities of a virtualized GIC the state can only be restored with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VgicCapabilities {
+    /// Number of interrupts handled by the distributor.
+    pub nr_irqs: u32,
+    /// Revision of the layout of the ITS tables saved into guest RAM.
+    pub its_revision: u8,
+}
+
+impl VgicCapabilities {
+    /// Check the GIC with these capabilities can restore the state of the
+    /// one with `src` capabilities.
+    pub fn check_compatibility(&self, src: &VgicCapabilities) -> Result<()> {
+        if src.nr_irqs != self.nr_irqs {
+            return Err(Error::IncompatibleCapabilities(format!(
+                "{} interrupts handled instead of {}",
+                self.nr_irqs, src.nr_irqs
+            )));
+        }
+
+        if src.its_revision > self.its_revision {
+            return Err(Error::IncompatibleCapabilities(format!(
+                "ITS tables revision {} not supported, up to {}",
+                src.its_revision, self.its_revision
+            )));
+        }
+
+        Ok(())
+    }
+}
+
 /// Hypervisor agnostic interface for a virtualized GIC
 pub trait Vgic: Send + Sync {
     /// Returns the fdt compatibility property of the device
@@ -65,4 +101,40 @@ pub trait Vgic: Send + Sync {
 
     /// Saves GIC internal data tables into RAM.
     fn save_data_tables(&self) -> Result<()>;
+
+    /// Returns the capabilities the state of the GIC depends on.
+    fn capabilities(&self) -> Result<VgicCapabilities>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vgic_capabilities_compatibility() {
+        let dest = VgicCapabilities {
+            nr_irqs: 256,
+            its_revision: 1,
+        };
+
+        assert!(dest.check_compatibility(&dest).is_ok());
+        assert!(dest
+            .check_compatibility(&VgicCapabilities {
+                its_revision: 0,
+                ..dest
+            })
+            .is_ok());
+        assert!(dest
+            .check_compatibility(&VgicCapabilities {
+                its_revision: 2,
+                ..dest
+            })
+            .is_err());
+        assert!(dest
+            .check_compatibility(&VgicCapabilities {
+                nr_irqs: 512,
+                ..dest
+            })
+            .is_err());
+    }
 }
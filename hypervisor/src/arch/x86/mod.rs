@@ -311,3 +311,14 @@ pub struct MsrEntry {
     pub index: u32,
     pub data: u64,
 }
+
+/// Outcome of a guest access to a filtered MSR.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MsrFilterAction {
+    /// The access is handled as usual.
+    Allow,
+    /// A #GP is injected into the guest.
+    Deny,
+    /// Reads return zero while writes are ignored.
+    Zero,
+}
@@ -10,6 +10,7 @@ pub const EFER_LME: u64 = 0x100;
 
 // CR0 bits
 pub const CR0_PE: u64 = 0x1;
+pub const CR0_ET: u64 = 0x10;
 pub const CR0_PG: u64 = 0x80000000;
 
 // CR4 bits
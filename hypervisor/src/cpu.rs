@@ -18,6 +18,8 @@ use crate::arch::x86::{
 use crate::kvm::{TdxExitDetails, TdxExitStatus};
 use crate::CpuState;
 use crate::MpState;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use thiserror::Error;
 use vm_memory::GuestAddress;
 
@@ -246,6 +248,12 @@ pub enum HypervisorCpuError {
     ///
     #[error("Failed to initialize PMU")]
     InitializePmu,
+    #[cfg(target_arch = "aarch64")]
+    ///
+    /// Failed to set the PMU event filter
+    ///
+    #[error("Failed to set the PMU event filter")]
+    SetPmuEventFilter,
     #[cfg(target_arch = "x86_64")]
     ///
     /// Error getting TSC frequency
@@ -267,6 +275,43 @@ pub enum HypervisorCpuError {
     ///
     #[error("Failed to write to GPA: {0}")]
     GpaWrite(#[source] anyhow::Error),
+    ///
+    /// Error setting the SEV control register
+    ///
+    #[cfg(feature = "sev_snp")]
+    #[error("Failed to set the SEV control register: {0}")]
+    SetSevControlRegister(#[source] anyhow::Error),
+}
+
+/// Accesses triggering a hardware watchpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    Read,
+    ReadWrite,
+}
+
+/// Hardware watchpoint on a range of guest virtual addresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: GuestAddress,
+    pub len: u64,
+    pub kind: WatchpointKind,
+}
+
+/// Cause of a debug exit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebugExit {
+    SingleStep,
+    HwBreakpoint,
+    /// The watchpoint is identified by its index among the ones passed to
+    /// `set_guest_debug()`, or by the accessed address, depending on what the
+    /// architecture reports.
+    HwWatchpoint {
+        index: Option<usize>,
+        addr: Option<u64>,
+    },
+    Unknown,
 }
 
 #[derive(Debug)]
@@ -282,11 +327,96 @@ pub enum VmExit<'a> {
     Ignore,
     Reset,
     Shutdown,
+    #[cfg(target_arch = "x86_64")]
+    TripleFault,
     Hyperv,
     #[cfg(feature = "tdx")]
     Tdx,
     #[cfg(feature = "kvm")]
-    Debug,
+    Debug(DebugExit),
+}
+
+///
+/// Reason of an exit of a vCPU, as counted by [`VcpuExitCounters`].
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuExitReason {
+    Mmio,
+    Pio,
+    Msr,
+    Halt,
+    EptViolation,
+}
+
+///
+/// Exits of a vCPU counted by the hypervisor itself, for those it handles
+/// without returning to the VMM.
+///
+pub trait VcpuExitStats: Send + Sync + std::fmt::Debug {
+    ///
+    /// Returns the number of exits for the reason, if the hypervisor counts them
+    ///
+    fn get(&self, reason: VcpuExitReason) -> Option<u64>;
+}
+
+///
+/// Number of exits of a vCPU by reason, shared with the VMM so that they can
+/// be read while the vCPU runs. The exits which the hypervisor returns to the
+/// VMM are counted here, the ones it handles by itself, such as the EPT
+/// violations with KVM, being read from its statistics when available.
+///
+#[derive(Debug, Default)]
+pub struct VcpuExitCounters {
+    mmio: AtomicU64,
+    pio: AtomicU64,
+    msr: AtomicU64,
+    halt: AtomicU64,
+    ept_violation: AtomicU64,
+    stats: Option<Box<dyn VcpuExitStats>>,
+}
+
+impl VcpuExitCounters {
+    pub fn with_stats(stats: Box<dyn VcpuExitStats>) -> Self {
+        VcpuExitCounters {
+            stats: Some(stats),
+            ..Default::default()
+        }
+    }
+
+    fn counter(&self, reason: VcpuExitReason) -> &AtomicU64 {
+        match reason {
+            VcpuExitReason::Mmio => &self.mmio,
+            VcpuExitReason::Pio => &self.pio,
+            VcpuExitReason::Msr => &self.msr,
+            VcpuExitReason::Halt => &self.halt,
+            VcpuExitReason::EptViolation => &self.ept_violation,
+        }
+    }
+
+    pub fn record(&self, reason: VcpuExitReason) {
+        self.counter(reason).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, reason: VcpuExitReason) -> u64 {
+        self.stats
+            .as_ref()
+            .and_then(|stats| stats.get(reason))
+            .unwrap_or_else(|| self.counter(reason).load(Ordering::Relaxed))
+    }
+
+    /// Name and value of each counter.
+    pub fn counters(&self) -> [(&'static str, u64); 5] {
+        [
+            ("exits_mmio", self.get(VcpuExitReason::Mmio)),
+            ("exits_pio", self.get(VcpuExitReason::Pio)),
+            ("exits_msr", self.get(VcpuExitReason::Msr)),
+            ("exits_halt", self.get(VcpuExitReason::Halt)),
+            (
+                "exits_ept_violation",
+                self.get(VcpuExitReason::EptViolation),
+            ),
+        ]
+    }
 }
 
 ///
@@ -377,9 +507,15 @@ pub trait Vcpu: Send + Sync {
         Ok(())
     }
     ///
-    /// Sets debug registers to set hardware breakpoints and/or enable single step.
+    /// Sets debug registers to set hardware breakpoints, watchpoints and/or
+    /// enable single step.
     ///
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[Watchpoint],
+        _singlestep: bool,
+    ) -> Result<()> {
         Err(HypervisorCpuError::SetDebugRegs(anyhow!("unimplemented")))
     }
     ///
@@ -414,6 +550,11 @@ pub trait Vcpu: Send + Sync {
     #[cfg(target_arch = "aarch64")]
     fn init_pmu(&self, irq: u32) -> Result<()>;
     ///
+    /// Restrict the PMU events exposed to the guest to the given list
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_pmu_event_filter(&self, events: &[u16]) -> Result<()>;
+    ///
     /// Retrieve the vCPU state.
     /// This function is necessary to snapshot the VM
     ///
@@ -457,6 +598,15 @@ pub trait Vcpu: Send + Sync {
     fn set_tdx_status(&mut self, _status: TdxExitStatus) {
         unimplemented!()
     }
+    #[cfg(feature = "sev_snp")]
+    ///
+    /// Point the SEV control register to the VMSA page the vCPU starts from
+    ///
+    fn set_sev_control_register(&self, _vmsa_pfn: u64) -> Result<()> {
+        Err(HypervisorCpuError::SetSevControlRegister(anyhow::anyhow!(
+            "not supported by the hypervisor"
+        )))
+    }
     #[cfg(target_arch = "x86_64")]
     ///
     /// Return the list of initial MSR entries for a VCPU
@@ -477,4 +627,8 @@ pub trait Vcpu: Send + Sync {
     fn set_tsc_khz(&self, _freq: u32) -> Result<()> {
         Ok(())
     }
+    ///
+    /// Returns the counters of the exits of the vCPU
+    ///
+    fn exit_counters(&self) -> Arc<VcpuExitCounters>;
 }
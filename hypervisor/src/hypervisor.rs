@@ -147,6 +147,12 @@ pub trait Hypervisor: Send + Sync {
     fn get_guest_debug_hw_bps(&self) -> usize {
         unimplemented!()
     }
+    ///
+    /// Get the number of supported hardware watchpoints
+    ///
+    fn get_guest_debug_hw_wps(&self) -> usize {
+        unimplemented!()
+    }
 
     /// Get maximum number of vCPUs
     fn get_max_vcpus(&self) -> u32;
@@ -108,7 +108,8 @@ pub fn write_ctlr(gic: &DeviceFd, val: u32) -> Result<()> {
     dist_attr_access(gic, GICD_CTLR, &val, true)
 }
 
-fn get_interrupts_num(gic: &DeviceFd) -> Result<u32> {
+/// Get the number of interrupts handled by the distributor.
+pub fn get_interrupts_num(gic: &DeviceFd) -> Result<u32> {
     let num_irq = 0;
 
     let mut nr_irqs_attr = kvm_device_attr {
@@ -151,6 +152,19 @@ fn compute_reg_len(gic: &DeviceFd, reg: &DistReg, base: u32) -> Result<u32> {
 
 /// Set distributor registers of the GIC.
 pub fn set_dist_regs(gic: &DeviceFd, state: &[u32]) -> Result<()> {
+    let mut len = 0;
+    for dreg in VGIC_DIST_REGS {
+        let base = dreg.base + REG_SIZE as u32 * dreg.bpi as u32;
+        len += ((compute_reg_len(gic, dreg, base)? - base) / REG_SIZE as u32) as usize;
+    }
+    if state.len() != len {
+        return Err(Error::InvalidState(format!(
+            "{} distributor registers saved instead of {}",
+            state.len(),
+            len
+        )));
+    }
+
     let mut idx = 0;
 
     for dreg in VGIC_DIST_REGS {
@@ -4,11 +4,11 @@ mod dist_regs;
 mod icc_regs;
 mod redist_regs;
 
-use crate::arch::aarch64::gic::{Error, Result, Vgic, VgicConfig};
+use crate::arch::aarch64::gic::{Error, Result, Vgic, VgicCapabilities, VgicConfig};
 use crate::device::HypervisorDeviceError;
 use crate::kvm::{kvm_bindings, KvmVm};
 use crate::{CpuState, Vm};
-use dist_regs::{get_dist_regs, read_ctlr, set_dist_regs, write_ctlr};
+use dist_regs::{get_dist_regs, get_interrupts_num, read_ctlr, set_dist_regs, write_ctlr};
 use icc_regs::{get_icc_regs, set_icc_regs};
 use kvm_ioctls::DeviceFd;
 use redist_regs::{construct_gicr_typers, get_redist_regs, set_redist_regs};
@@ -23,6 +23,9 @@ const GITS_CWRITER: u32 = 0x0088;
 const GITS_CREADR: u32 = 0x0090;
 const GITS_BASER: u32 = 0x0100;
 
+const GITS_IIDR_REV_SHIFT: u64 = 12;
+const GITS_IIDR_REV_MASK: u64 = 0xf;
+
 /// Access an ITS device attribute.
 ///
 /// This is a helper function to get/set the ITS device attribute depending
@@ -394,6 +397,12 @@ impl Vgic for KvmGicV3Its {
     }
 
     /// Restore the state of GICv3ITS.
+    ///
+    /// The redistributors are restored before the ITS, for the LPIs mapped
+    /// by the ITS tables to pick their configuration and pending state from
+    /// the tables GICR_PROPBASER and GICR_PENDBASER point to. The ITS
+    /// registers are then restored in the order required by KVM, GITS_CTLR
+    /// last once the ITS tables have been restored from guest RAM.
     fn set_state(&mut self, state: &Gicv3ItsState) -> Result<()> {
         let gicr_typers = self.gicr_typers.clone();
 
@@ -414,6 +423,16 @@ impl Vgic for KvmGicV3Its {
             true,
         )?;
 
+        for i in 0..8 {
+            gicv3_its_attr_access(
+                self.its_device.as_ref().unwrap(),
+                kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ITS_REGS,
+                GITS_BASER + i * 8,
+                &state.its_baser[i as usize],
+                true,
+            )?;
+        }
+
         gicv3_its_attr_access(
             self.its_device.as_ref().unwrap(),
             kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ITS_REGS,
@@ -438,16 +457,6 @@ impl Vgic for KvmGicV3Its {
             true,
         )?;
 
-        for i in 0..8 {
-            gicv3_its_attr_access(
-                self.its_device.as_ref().unwrap(),
-                kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ITS_REGS,
-                GITS_BASER + i * 8,
-                &state.its_baser[i as usize],
-                true,
-            )?;
-        }
-
         // Restore ITS tables
         gicv3_its_tables_access(self.its_device.as_ref().unwrap(), false)?;
 
@@ -477,6 +486,22 @@ impl Vgic for KvmGicV3Its {
         // Flush ITS tables to guest RAM.
         gicv3_its_tables_access(self.its_device.as_ref().unwrap(), true)
     }
+
+    fn capabilities(&self) -> Result<VgicCapabilities> {
+        let its_iidr: u64 = 0;
+        gicv3_its_attr_access(
+            self.its_device.as_ref().unwrap(),
+            kvm_bindings::KVM_DEV_ARM_VGIC_GRP_ITS_REGS,
+            GITS_IIDR,
+            &its_iidr,
+            false,
+        )?;
+
+        Ok(VgicCapabilities {
+            nr_irqs: get_interrupts_num(&self.device)?,
+            its_revision: ((its_iidr >> GITS_IIDR_REV_SHIFT) & GITS_IIDR_REV_MASK) as u8,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -571,4 +596,17 @@ mod tests {
 
         assert!(gic.lock().unwrap().save_data_tables().is_ok());
     }
+
+    #[test]
+    fn test_capabilities() {
+        let hv = crate::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        let gic = vm
+            .create_vgic(create_test_vgic_config())
+            .expect("Cannot create gic");
+
+        let capabilities = gic.lock().unwrap().capabilities().unwrap();
+        assert_eq!(capabilities.nr_irqs, 256);
+        assert!(capabilities.check_compatibility(&capabilities).is_ok());
+    }
 }
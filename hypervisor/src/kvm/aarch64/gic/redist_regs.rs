@@ -164,6 +164,20 @@ pub fn get_redist_regs(gic: &DeviceFd, gicr_typer: &[u64]) -> Result<Vec<u32>> {
 
 /// Set redistributor registers.
 pub fn set_redist_regs(gic: &DeviceFd, gicr_typer: &[u64], state: &[u32]) -> Result<()> {
+    let len = gicr_typer.len()
+        * VGIC_RDIST_REGS
+            .iter()
+            .chain(VGIC_SGI_REGS)
+            .map(|r| (r.length / REG_SIZE) as usize)
+            .sum::<usize>();
+    if state.len() != len {
+        return Err(Error::InvalidState(format!(
+            "{} redistributor registers saved instead of {}",
+            state.len(),
+            len
+        )));
+    }
+
     let mut idx: usize = 0;
     let mut mut_state = state.to_owned();
     access_redists_aux(
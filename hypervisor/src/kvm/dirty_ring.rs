@@ -0,0 +1,302 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Dirty pages tracking through the KVM dirty rings.
+//!
+//! When the kernel supports it, each vCPU shares a ring with KVM, onto which
+//! the guest frame numbers of the pages it dirties are pushed. Unlike the
+//! dirty bitmaps, the cost of collecting the dirty pages only depends on the
+//! number of pages actually dirtied, and not on the size of the guest memory,
+//! which matters when migrating very large VMs.
+//!
+//! The rings are harvested into per memory slot bitmaps, handed out through
+//! `get_dirty_log()`, so that the users don't have to care about which
+//! tracking method is being used.
+
+use crate::vm;
+use kvm_bindings::{kvm_enable_cap, KVMIO};
+use kvm_ioctls::{VcpuFd, VmFd};
+use std::collections::HashMap;
+use std::os::raw::c_ulong;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+const KVM_CAP_DIRTY_LOG_RING: u32 = 192;
+pub const KVM_EXIT_DIRTY_RING_FULL: u32 = 31;
+
+// Offset of the ring in the vCPU file, in pages
+const KVM_DIRTY_LOG_PAGE_OFFSET: i64 = 64;
+
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1;
+const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+// Number of entries of each ring, if allowed by KVM. A vCPU filling its ring
+// exits to userspace until the rings are harvested.
+const DIRTY_RING_ENTRIES: usize = 16384;
+
+const PAGE_SHIFT: u64 = 12;
+
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_io_nr!(KVM_RESET_DIRTY_RINGS, KVMIO, 0xc7);
+
+#[repr(C)]
+struct KvmDirtyGfn {
+    flags: u32,
+    slot: u32,
+    offset: u64,
+}
+
+struct DirtyRing {
+    gfns: *mut KvmDirtyGfn,
+    // Index of the next entry to harvest
+    fetch_index: usize,
+}
+
+// SAFETY: The ring is only accessed while holding the DirtyRings lock.
+unsafe impl Send for DirtyRing {}
+
+impl DirtyRing {
+    /// Move the dirty entries of the ring, made of `entries` entries, to the
+    /// per slot bitmaps, flagging them for KVM to reset. Returns whether any
+    /// entry was harvested.
+    fn harvest(&mut self, entries: usize, bitmaps: &mut HashMap<u32, Vec<u64>>) -> bool {
+        let mut harvested = false;
+        loop {
+            // SAFETY: The index is within the mapping.
+            let gfn = unsafe { self.gfns.add(self.fetch_index % entries) };
+            // SAFETY: The flags are shared with KVM, which updates them
+            // concurrently, hence the atomic accesses.
+            let flags = unsafe { &*(std::ptr::addr_of!((*gfn).flags) as *const AtomicU32) };
+            if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                break;
+            }
+
+            // SAFETY: KVM doesn't modify a dirty entry.
+            let (slot, offset) = unsafe { ((*gfn).slot & 0xffff, (*gfn).offset) };
+            let bitmap = bitmaps.entry(slot).or_default();
+            let index = (offset / 64) as usize;
+            if bitmap.len() <= index {
+                bitmap.resize(index + 1, 0);
+            }
+            bitmap[index] |= 1u64 << (offset % 64);
+
+            flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+            self.fetch_index = self.fetch_index.wrapping_add(1);
+            harvested = true;
+        }
+
+        harvested
+    }
+}
+
+struct DirtyRingsState {
+    rings: Vec<DirtyRing>,
+    // Pages dirtied since the last call to get_dirty_log(), per slot
+    bitmaps: HashMap<u32, Vec<u64>>,
+}
+
+pub struct DirtyRings {
+    fd: Arc<VmFd>,
+    entries: usize,
+    state: Mutex<DirtyRingsState>,
+}
+
+impl DirtyRings {
+    /// Enable the dirty rings on a VM without any vCPU yet, returning None
+    /// when the kernel doesn't support them.
+    pub fn new(fd: Arc<VmFd>) -> Option<Self> {
+        // SAFETY: FFI call with a valid fd, the ioctl doesn't access memory.
+        let max_size = unsafe {
+            ioctl_with_val(
+                fd.as_ref(),
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_DIRTY_LOG_RING as c_ulong,
+            )
+        };
+        if max_size <= 0 {
+            return None;
+        }
+
+        // Both sizes are powers of two, as required by KVM.
+        let size = (DIRTY_RING_ENTRIES * std::mem::size_of::<KvmDirtyGfn>()).min(max_size as usize);
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_DIRTY_LOG_RING,
+            ..Default::default()
+        };
+        cap.args[0] = size as u64;
+        if let Err(e) = fd.enable_cap(&cap) {
+            warn!("Cannot enable the KVM dirty rings: {}", e);
+            return None;
+        }
+        info!("Using KVM dirty rings of {} bytes", size);
+
+        Some(DirtyRings {
+            fd,
+            entries: size / std::mem::size_of::<KvmDirtyGfn>(),
+            state: Mutex::new(DirtyRingsState {
+                rings: Vec::new(),
+                bitmaps: HashMap::new(),
+            }),
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.entries * std::mem::size_of::<KvmDirtyGfn>()
+    }
+
+    /// Map the ring of a newly created vCPU.
+    pub fn add_vcpu(&self, vcpu: &VcpuFd) -> vm::Result<()> {
+        // SAFETY: FFI call with valid arguments, the result is checked.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                self.size(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                KVM_DIRTY_LOG_PAGE_OFFSET * libc::sysconf(libc::_SC_PAGESIZE),
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(vm::HypervisorVmError::CreateVcpu(
+                std::io::Error::last_os_error().into(),
+            ));
+        }
+
+        self.state.lock().unwrap().rings.push(DirtyRing {
+            gfns: addr as *mut KvmDirtyGfn,
+            fetch_index: 0,
+        });
+
+        Ok(())
+    }
+
+    /// Move the entries of all the rings to the dirty bitmaps, and give the
+    /// rings back to KVM.
+    pub fn harvest(&self) -> vm::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let DirtyRingsState { rings, bitmaps } = &mut *state;
+
+        let mut harvested = false;
+        for ring in rings.iter_mut() {
+            harvested |= ring.harvest(self.entries, bitmaps);
+        }
+
+        if harvested {
+            // SAFETY: FFI call with a valid fd, the ioctl doesn't access memory.
+            let ret = unsafe { ioctl(self.fd.as_ref(), KVM_RESET_DIRTY_RINGS()) };
+            if ret < 0 {
+                return Err(vm::HypervisorVmError::GetDirtyLog(
+                    std::io::Error::last_os_error().into(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the pages of a slot dirtied since the previous call, one bit per
+    /// page.
+    pub fn get_dirty_log(&self, slot: u32, memory_size: u64) -> vm::Result<Vec<u64>> {
+        self.harvest()?;
+
+        let mut bitmap = self
+            .state
+            .lock()
+            .unwrap()
+            .bitmaps
+            .remove(&slot)
+            .unwrap_or_default();
+        let pages = memory_size >> PAGE_SHIFT;
+        bitmap.resize(((pages + 63) / 64) as usize, 0);
+
+        Ok(bitmap)
+    }
+
+    /// Drop the dirty pages harvested so far.
+    pub fn clear(&self) -> vm::Result<()> {
+        self.harvest()?;
+        self.state.lock().unwrap().bitmaps.clear();
+
+        Ok(())
+    }
+}
+
+impl Drop for DirtyRings {
+    fn drop(&mut self) {
+        let size = self.size();
+        for ring in self.state.get_mut().unwrap().rings.iter() {
+            // SAFETY: FFI call unmapping a ring mapped by add_vcpu().
+            unsafe { libc::munmap(ring.gfns as *mut libc::c_void, size) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirty_gfn(slot: u32, offset: u64) -> KvmDirtyGfn {
+        KvmDirtyGfn {
+            flags: KVM_DIRTY_GFN_F_DIRTY,
+            slot,
+            offset,
+        }
+    }
+
+    #[test]
+    fn test_dirty_ring_harvest() {
+        let mut gfns = vec![
+            dirty_gfn(0, 3),
+            dirty_gfn(1, 70),
+            dirty_gfn(0, 3),
+            KvmDirtyGfn {
+                flags: 0,
+                slot: 0,
+                offset: 5,
+            },
+        ];
+        let entries = gfns.len();
+        let mut ring = DirtyRing {
+            gfns: gfns.as_mut_ptr(),
+            fetch_index: 0,
+        };
+        let mut bitmaps = HashMap::new();
+
+        // The harvest stops at the first entry KVM didn't publish, flagging
+        // the harvested ones for reset.
+        assert!(ring.harvest(entries, &mut bitmaps));
+        assert_eq!(ring.fetch_index, 3);
+        assert_eq!(bitmaps[&0], vec![1 << 3]);
+        assert_eq!(bitmaps[&1], vec![0, 1 << 6]);
+        for gfn in &gfns[..3] {
+            assert_eq!(gfn.flags, KVM_DIRTY_GFN_F_RESET);
+        }
+        assert_eq!(gfns[3].flags, 0);
+
+        // Nothing new was published.
+        let mut ring = DirtyRing {
+            gfns: gfns.as_mut_ptr(),
+            fetch_index: 3,
+        };
+        assert!(!ring.harvest(entries, &mut bitmaps));
+
+        // Once reset by KVM, the entries are reused from the start of the
+        // ring, and the address space id of the slot is ignored.
+        gfns[3] = dirty_gfn(0, 5);
+        gfns[0] = dirty_gfn(1 << 16, 0);
+        gfns[1].flags = 0;
+        let mut ring = DirtyRing {
+            gfns: gfns.as_mut_ptr(),
+            fetch_index: 3,
+        };
+        assert!(ring.harvest(entries, &mut bitmaps));
+        assert_eq!(ring.fetch_index, 5);
+        assert_eq!(bitmaps[&0], vec![1 | 1 << 3 | 1 << 5]);
+    }
+}
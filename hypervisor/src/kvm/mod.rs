@@ -42,13 +42,20 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::sync::{Arc, RwLock};
 use vmm_sys_util::eventfd::EventFd;
+
+mod stats;
+
 // x86_64 dependencies
 #[cfg(target_arch = "x86_64")]
+mod dirty_ring;
+#[cfg(target_arch = "x86_64")]
+mod msr_filter;
+#[cfg(target_arch = "x86_64")]
 pub mod x86_64;
 #[cfg(target_arch = "x86_64")]
 use crate::arch::x86::{
-    CpuIdEntry, FpuState, LapicState, MsrEntry, SpecialRegisters, StandardRegisters,
-    NUM_IOAPIC_PINS,
+    CpuIdEntry, FpuState, LapicState, MsrEntry, MsrFilterAction, SpecialRegisters,
+    StandardRegisters, NUM_IOAPIC_PINS,
 };
 #[cfg(target_arch = "x86_64")]
 use crate::ClockData;
@@ -59,11 +66,18 @@ use crate::{
 #[cfg(target_arch = "aarch64")]
 use aarch64::{RegList, Register, StandardRegisters};
 #[cfg(target_arch = "x86_64")]
+use dirty_ring::{DirtyRings, KVM_EXIT_DIRTY_RING_FULL};
+use kvm_bindings::kvm_debug_exit_arch;
+#[cfg(target_arch = "x86_64")]
 use kvm_bindings::{
     kvm_enable_cap, kvm_msr_entry, MsrList, KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP,
     KVM_GUESTDBG_USE_HW_BP,
 };
 #[cfg(target_arch = "x86_64")]
+use msr_filter::{KVM_EXIT_X86_RDMSR, KVM_EXIT_X86_WRMSR};
+#[cfg(target_arch = "x86_64")]
+use std::collections::HashSet;
+#[cfg(target_arch = "x86_64")]
 use x86_64::check_required_kvm_extensions;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{CpuId, ExtendedControlRegisters, MsrEntries, VcpuKvmState, Xsave};
@@ -105,6 +119,20 @@ pub use {
 #[cfg(target_arch = "x86_64")]
 const KVM_CAP_SGX_ATTRIBUTE: u32 = 196;
 
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_VCPU_PMU_V3_FILTER: u64 = 2;
+#[cfg(target_arch = "aarch64")]
+const KVM_PMU_EVENT_ALLOW: u8 = 0;
+
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+struct KvmPmuEventFilter {
+    base_event: u16,
+    nevents: u16,
+    action: u8,
+    pad: [u8; 3],
+}
+
 #[cfg(feature = "tdx")]
 const KVM_EXIT_TDX: u32 = 50;
 #[cfg(feature = "tdx")]
@@ -131,7 +159,12 @@ enum TdxCommand {
 
 #[cfg(feature = "tdx")]
 pub enum TdxExitDetails {
-    GetQuote,
+    /// Guest physical address and size of the shared buffer holding the
+    /// GetQuote request.
+    GetQuote {
+        gpa: u64,
+        size: u64,
+    },
     SetupEventNotifyInterrupt,
 }
 
@@ -315,6 +348,11 @@ pub struct KvmVm {
     #[cfg(target_arch = "x86_64")]
     msrs: Vec<MsrEntry>,
     dirty_log_slots: Arc<RwLock<HashMap<u32, KvmDirtyLogSlot>>>,
+    #[cfg(target_arch = "x86_64")]
+    dirty_rings: Option<Arc<DirtyRings>>,
+    // MSRs emulated as zero when the guest accesses them
+    #[cfg(target_arch = "x86_64")]
+    zero_msrs: Arc<RwLock<HashSet<u32>>>,
 }
 
 impl KvmVm {
@@ -401,6 +439,19 @@ impl vm::Vm for KvmVm {
             .fd
             .create_vcpu(id as u64)
             .map_err(|e| vm::HypervisorVmError::CreateVcpu(e.into()))?;
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = &self.dirty_rings {
+            dirty_rings.add_vcpu(&vc)?;
+        }
+        // The kernels without binary statistics only let the exits to the
+        // VMM be counted.
+        let exit_counters = match stats::KvmVcpuStats::new(&vc) {
+            Ok(stats) => cpu::VcpuExitCounters::with_stats(Box::new(stats)),
+            Err(e) => {
+                debug!("Cannot get the statistics of vCPU {}: {}", id, e);
+                cpu::VcpuExitCounters::default()
+            }
+        };
         let vcpu = KvmVcpu {
             fd: vc,
             #[cfg(target_arch = "x86_64")]
@@ -408,6 +459,11 @@ impl vm::Vm for KvmVm {
             vm_ops,
             #[cfg(target_arch = "x86_64")]
             hyperv_synic: AtomicBool::new(false),
+            #[cfg(target_arch = "x86_64")]
+            dirty_rings: self.dirty_rings.clone(),
+            #[cfg(target_arch = "x86_64")]
+            zero_msrs: self.zero_msrs.clone(),
+            exit_counters: Arc::new(exit_counters),
         };
         Ok(Arc::new(vcpu))
     }
@@ -657,6 +713,21 @@ impl vm::Vm for KvmVm {
             .map_err(|e| vm::HypervisorVmError::EnableSgxAttribute(e.into()))?;
         Ok(())
     }
+    /// Filter the guest accesses to the listed MSRs.
+    #[cfg(target_arch = "x86_64")]
+    fn set_msr_filter(
+        &self,
+        default_action: MsrFilterAction,
+        msrs: &[(u32, MsrFilterAction)],
+    ) -> vm::Result<()> {
+        msr_filter::set_msr_filter(&self.fd, default_action, msrs)?;
+        *self.zero_msrs.write().unwrap() = msrs
+            .iter()
+            .filter(|(_, action)| *action == MsrFilterAction::Zero)
+            .map(|(index, _)| *index)
+            .collect();
+        Ok(())
+    }
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> vm::Result<ClockData> {
@@ -689,6 +760,12 @@ impl vm::Vm for KvmVm {
     /// Start logging dirty pages
     ///
     fn start_dirty_log(&self) -> vm::Result<()> {
+        // Forget about the pages dirtied during a previous tracking
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = &self.dirty_rings {
+            dirty_rings.clear()?;
+        }
+
         let dirty_log_slots = self.dirty_log_slots.read().unwrap();
         for (_, s) in dirty_log_slots.iter() {
             let region = kvm_userspace_memory_region {
@@ -737,6 +814,11 @@ impl vm::Vm for KvmVm {
     /// Get dirty pages bitmap (one bit per page)
     ///
     fn get_dirty_log(&self, slot: u32, _base_gpa: u64, memory_size: u64) -> vm::Result<Vec<u64>> {
+        #[cfg(target_arch = "x86_64")]
+        if let Some(dirty_rings) = &self.dirty_rings {
+            return dirty_rings.get_dirty_log(slot, memory_size);
+        }
+
         self.fd
             .get_dirty_log(slot, memory_size as usize)
             .map_err(|e| vm::HypervisorVmError::GetDirtyLog(e.into()))
@@ -981,10 +1063,20 @@ impl hypervisor::Hypervisor for KvmHypervisor {
                 msrs[pos].index = *index;
             }
 
+            // The dirty rings can't be used along with the guest private
+            // memory of the confidential VM types.
+            let dirty_rings = if vm_type == 0 {
+                DirtyRings::new(vm_fd.clone()).map(Arc::new)
+            } else {
+                None
+            };
+
             Ok(Arc::new(KvmVm {
                 fd: vm_fd,
                 msrs,
                 dirty_log_slots: Arc::new(RwLock::new(HashMap::new())),
+                dirty_rings,
+                zero_msrs: Arc::new(RwLock::new(HashSet::new())),
             }))
         }
 
@@ -1085,11 +1177,74 @@ impl hypervisor::Hypervisor for KvmHypervisor {
         }
     }
 
+    ///
+    /// Get the number of supported hardware watchpoints. On x86_64, the debug
+    /// registers are shared with the hardware breakpoints.
+    ///
+    fn get_guest_debug_hw_wps(&self) -> usize {
+        #[cfg(target_arch = "x86_64")]
+        {
+            4
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            self.kvm.get_guest_debug_hw_wps() as usize
+        }
+    }
+
     /// Get maximum number of vCPUs
     fn get_max_vcpus(&self) -> u32 {
         self.kvm.get_max_vcpus().min(u32::MAX as usize) as u32
     }
 }
+
+// Cause of a debug exit, as found from DR6 and DR7
+#[cfg(target_arch = "x86_64")]
+fn debug_exit(debug: &kvm_debug_exit_arch) -> cpu::DebugExit {
+    // DR6.BS: single step
+    if debug.dr6 & (1 << 14) != 0 {
+        return cpu::DebugExit::SingleStep;
+    }
+    // DR6.B0-B3: the debug register which was hit
+    let slot = match (0..4).find(|i| debug.dr6 & (1 << i) != 0) {
+        Some(slot) => slot,
+        None => return cpu::DebugExit::Unknown,
+    };
+    // DR7.RW: instruction execution breakpoints are 0
+    let is_breakpoint = |slot: u64| (debug.dr7 >> (16 + slot * 4)) & 0b11 == 0;
+    if is_breakpoint(slot) {
+        return cpu::DebugExit::HwBreakpoint;
+    }
+
+    // The watchpoints follow the breakpoints in the debug registers
+    let breakpoints = (0..4)
+        .filter(|i| debug.dr7 & (2 << (i * 2)) != 0 && is_breakpoint(*i))
+        .count();
+    cpu::DebugExit::HwWatchpoint {
+        index: (slot as usize).checked_sub(breakpoints),
+        addr: None,
+    }
+}
+
+// Cause of a debug exit, as found from the exception class of the syndrome
+#[cfg(target_arch = "aarch64")]
+fn debug_exit(debug: &kvm_debug_exit_arch) -> cpu::DebugExit {
+    const ESR_ELX_EC_SHIFT: u32 = 26;
+    const ESR_ELX_EC_BREAKPT_LOW: u32 = 0x30;
+    const ESR_ELX_EC_SOFTSTP_LOW: u32 = 0x32;
+    const ESR_ELX_EC_WATCHPT_LOW: u32 = 0x34;
+
+    match debug.hsr >> ESR_ELX_EC_SHIFT {
+        ESR_ELX_EC_BREAKPT_LOW => cpu::DebugExit::HwBreakpoint,
+        ESR_ELX_EC_SOFTSTP_LOW => cpu::DebugExit::SingleStep,
+        ESR_ELX_EC_WATCHPT_LOW => cpu::DebugExit::HwWatchpoint {
+            index: None,
+            addr: Some(debug.far),
+        },
+        _ => cpu::DebugExit::Unknown,
+    }
+}
+
 /// Vcpu struct for KVM
 pub struct KvmVcpu {
     fd: VcpuFd,
@@ -1098,6 +1253,11 @@ pub struct KvmVcpu {
     vm_ops: Option<Arc<dyn vm::VmOps>>,
     #[cfg(target_arch = "x86_64")]
     hyperv_synic: AtomicBool,
+    #[cfg(target_arch = "x86_64")]
+    dirty_rings: Option<Arc<DirtyRings>>,
+    #[cfg(target_arch = "x86_64")]
+    zero_msrs: Arc<RwLock<HashSet<u32>>>,
+    exit_counters: Arc<cpu::VcpuExitCounters>,
 }
 /// Implementation of Vcpu trait for KVM
 ///
@@ -1531,6 +1691,7 @@ impl cpu::Vcpu for KvmVcpu {
             Ok(run) => match run {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoIn(addr, data) => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Pio);
                     if let Some(vm_ops) = &self.vm_ops {
                         return vm_ops
                             .pio_read(addr.into(), data)
@@ -1542,6 +1703,7 @@ impl cpu::Vcpu for KvmVcpu {
                 }
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoOut(addr, data) => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Pio);
                     if let Some(vm_ops) = &self.vm_ops {
                         return vm_ops
                             .pio_write(addr.into(), data)
@@ -1554,7 +1716,12 @@ impl cpu::Vcpu for KvmVcpu {
                 #[cfg(target_arch = "x86_64")]
                 VcpuExit::IoapicEoi(vector) => Ok(cpu::VmExit::IoapicEoi(vector)),
                 #[cfg(target_arch = "x86_64")]
-                VcpuExit::Shutdown | VcpuExit::Hlt => Ok(cpu::VmExit::Reset),
+                VcpuExit::Shutdown => Ok(cpu::VmExit::TripleFault),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Hlt => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Halt);
+                    Ok(cpu::VmExit::Reset)
+                }
 
                 #[cfg(target_arch = "aarch64")]
                 VcpuExit::SystemEvent(event_type, flags) => {
@@ -1575,6 +1742,7 @@ impl cpu::Vcpu for KvmVcpu {
                 }
 
                 VcpuExit::MmioRead(addr, data) => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Mmio);
                     if let Some(vm_ops) = &self.vm_ops {
                         return vm_ops
                             .mmio_read(addr, data)
@@ -1585,6 +1753,7 @@ impl cpu::Vcpu for KvmVcpu {
                     Ok(cpu::VmExit::MmioRead(addr, data))
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Mmio);
                     if let Some(vm_ops) = &self.vm_ops {
                         return vm_ops
                             .mmio_write(addr, data)
@@ -1595,9 +1764,36 @@ impl cpu::Vcpu for KvmVcpu {
                     Ok(cpu::VmExit::MmioWrite(addr, data))
                 }
                 VcpuExit::Hyperv => Ok(cpu::VmExit::Hyperv),
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Unsupported(KVM_EXIT_DIRTY_RING_FULL) => {
+                    // The vCPU can't run again until its ring is harvested
+                    if let Some(dirty_rings) = &self.dirty_rings {
+                        dirty_rings
+                            .harvest()
+                            .map_err(|e| cpu::HypervisorCpuError::RunVcpu(e.into()))?;
+                    }
+                    Ok(cpu::VmExit::Ignore)
+                }
+                #[cfg(target_arch = "x86_64")]
+                VcpuExit::Unsupported(KVM_EXIT_X86_RDMSR | KVM_EXIT_X86_WRMSR) => {
+                    // Only the accesses to the filtered MSRs exit to userspace
+                    self.exit_counters.record(cpu::VcpuExitReason::Msr);
+                    let kvm_run = self.fd.get_kvm_run();
+                    // SAFETY: accessing a union field in a valid structure
+                    let msr = unsafe { &mut kvm_run.__bindgen_anon_1.msr };
+                    if self.zero_msrs.read().unwrap().contains(&msr.index) {
+                        // Reads return zero while writes are ignored
+                        msr.data = 0;
+                        msr.error = 0;
+                    } else {
+                        // Let KVM inject a #GP
+                        msr.error = 1;
+                    }
+                    Ok(cpu::VmExit::Ignore)
+                }
                 #[cfg(feature = "tdx")]
                 VcpuExit::Unsupported(KVM_EXIT_TDX) => Ok(cpu::VmExit::Tdx),
-                VcpuExit::Debug(_) => Ok(cpu::VmExit::Debug),
+                VcpuExit::Debug(debug) => Ok(cpu::VmExit::Debug(debug_exit(&debug))),
 
                 r => Err(cpu::HypervisorCpuError::RunVcpu(anyhow!(
                     "Unexpected exit reason on vcpu run: {:?}",
@@ -1632,11 +1828,13 @@ impl cpu::Vcpu for KvmVcpu {
         Ok(())
     }
     ///
-    /// Sets debug registers to set hardware breakpoints and/or enable single step.
+    /// Sets debug registers to set hardware breakpoints, watchpoints and/or
+    /// enable single step.
     ///
     fn set_guest_debug(
         &self,
         addrs: &[vm_memory::GuestAddress],
+        watchpoints: &[cpu::Watchpoint],
         singlestep: bool,
     ) -> cpu::Result<()> {
         let mut dbg = kvm_guest_debug {
@@ -1651,8 +1849,10 @@ impl cpu::Vcpu for KvmVcpu {
         }
 
         // Set the debug registers.
-        // Here we assume that the number of addresses do not exceed what
-        // `Hypervisor::get_guest_debug_hw_bps()` specifies.
+        // Here we assume that the number of addresses and watchpoints do not
+        // exceed what `Hypervisor::get_guest_debug_hw_bps()` and
+        // `Hypervisor::get_guest_debug_hw_wps()` specify, and that the
+        // watchpoints have a length and alignment the hardware supports.
         #[cfg(target_arch = "x86_64")]
         {
             // Set bits 9 and 10.
@@ -1665,6 +1865,26 @@ impl cpu::Vcpu for KvmVcpu {
                 // Set global breakpoint enable flag
                 dbg.arch.debugreg[7] |= 2 << (i * 2);
             }
+            // The watchpoints use the registers left by the breakpoints
+            for (i, watchpoint) in watchpoints.iter().enumerate() {
+                let slot = addrs.len() + i;
+                dbg.arch.debugreg[slot] = watchpoint.addr.0;
+                dbg.arch.debugreg[7] |= 2 << (slot * 2);
+                // RW bits: 0b01 for writes, 0b11 for reads and writes, as
+                // reads alone can't be watched.
+                let rw: u64 = match watchpoint.kind {
+                    cpu::WatchpointKind::Write => 0b01,
+                    cpu::WatchpointKind::Read | cpu::WatchpointKind::ReadWrite => 0b11,
+                };
+                // LEN bits
+                let len: u64 = match watchpoint.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                dbg.arch.debugreg[7] |= (rw | (len << 2)) << (16 + slot * 4);
+            }
         }
         #[cfg(target_arch = "aarch64")]
         {
@@ -1679,6 +1899,24 @@ impl cpu::Vcpu for KvmVcpu {
                 // bit 2~52: VA[2:52]
                 dbg.arch.dbg_bvr[i] = (!0u64 >> 11) & addr.0;
             }
+            for (i, watchpoint) in watchpoints.iter().enumerate() {
+                // DBGWCR_EL1 (Debug Watchpoint Control Registers, D13.3.11):
+                // bit 0: 1 (Enabled)
+                // bit 1~2: 0b11 (PAC = EL1/EL0)
+                // bit 3~4: LSC (0b01 = load, 0b10 = store, 0b11 = both)
+                // bit 5~12: BAS, the watched bytes of the double word
+                // others: 0
+                let lsc: u64 = match watchpoint.kind {
+                    cpu::WatchpointKind::Read => 0b01,
+                    cpu::WatchpointKind::Write => 0b10,
+                    cpu::WatchpointKind::ReadWrite => 0b11,
+                };
+                let bas = ((1u64 << watchpoint.len) - 1) << (watchpoint.addr.0 & 0x7);
+                dbg.arch.dbg_wcr[i] = 0b1u64 | 0b110u64 | (lsc << 3) | ((bas & 0xff) << 5);
+                // DBGWVR_EL1 (Debug Watchpoint Value Registers, D13.3.12):
+                // bit 3~52: VA[3:52]
+                dbg.arch.dbg_wvr[i] = (!0u64 >> 11) & watchpoint.addr.0 & !0x7;
+            }
         }
         self.fd
             .set_guest_debug(&dbg)
@@ -2097,7 +2335,10 @@ impl cpu::Vcpu for KvmVcpu {
         }
 
         match tdx_vmcall.subfunction {
-            TDG_VP_VMCALL_GET_QUOTE => Ok(TdxExitDetails::GetQuote),
+            TDG_VP_VMCALL_GET_QUOTE => Ok(TdxExitDetails::GetQuote {
+                gpa: tdx_vmcall.in_r12,
+                size: tdx_vmcall.in_r13,
+            }),
             TDG_VP_VMCALL_SETUP_EVENT_NOTIFY_INTERRUPT => {
                 Ok(TdxExitDetails::SetupEventNotifyInterrupt)
             }
@@ -2175,6 +2416,50 @@ impl cpu::Vcpu for KvmVcpu {
             .set_device_attr(&cpu_attr)
             .map_err(|_| cpu::HypervisorCpuError::InitializePmu)
     }
+    ///
+    /// Allow the given PMU events, which implicitly denies all the other
+    /// ones. The filter is shared by all the vCPUs and must be set before the
+    /// PMU is initialized.
+    ///
+    #[cfg(target_arch = "aarch64")]
+    fn set_pmu_event_filter(&self, events: &[u16]) -> cpu::Result<()> {
+        let mut events = events.to_vec();
+        events.sort_unstable();
+        events.dedup();
+
+        // Coalesce consecutive events into ranges.
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for event in events {
+            match ranges.last_mut() {
+                Some((base, nevents))
+                    if u32::from(*base) + u32::from(*nevents) == u32::from(event) =>
+                {
+                    *nevents += 1
+                }
+                _ => ranges.push((event, 1)),
+            }
+        }
+
+        for (base_event, nevents) in ranges {
+            let filter = KvmPmuEventFilter {
+                base_event,
+                nevents,
+                action: KVM_PMU_EVENT_ALLOW,
+                pad: [0; 3],
+            };
+            let cpu_attr = kvm_bindings::kvm_device_attr {
+                group: kvm_bindings::KVM_ARM_VCPU_PMU_V3_CTRL,
+                attr: KVM_ARM_VCPU_PMU_V3_FILTER,
+                addr: &filter as *const KvmPmuEventFilter as u64,
+                flags: 0,
+            };
+            self.fd
+                .set_device_attr(&cpu_attr)
+                .map_err(|_| cpu::HypervisorCpuError::SetPmuEventFilter)?;
+        }
+
+        Ok(())
+    }
 
     #[cfg(target_arch = "x86_64")]
     ///
@@ -2209,6 +2494,12 @@ impl cpu::Vcpu for KvmVcpu {
             Ok(_) => Ok(()),
         }
     }
+    ///
+    /// Returns the counters of the exits of the vCPU
+    ///
+    fn exit_counters(&self) -> Arc<cpu::VcpuExitCounters> {
+        self.exit_counters.clone()
+    }
 }
 
 impl KvmVcpu {
@@ -0,0 +1,217 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Filtering of the guest MSR accesses through KVM_X86_SET_MSR_FILTER.
+//!
+//! KVM checks each guest access to an MSR against up to 16 ranges, each one
+//! holding a bitmap of the MSRs it covers, a set bit allowing the access. The
+//! MSRs which aren't covered by any range get the default action. A denied
+//! access results in a #GP being injected into the guest, unless the exits to
+//! userspace for filtered MSRs are enabled, in which case the VMM decides
+//! what happens. This is what allows some MSRs to be emulated as zero.
+
+use crate::arch::x86::MsrFilterAction;
+use crate::vm;
+use kvm_bindings::{kvm_enable_cap, KVMIO};
+use kvm_ioctls::VmFd;
+use std::os::raw::c_ulong;
+use vmm_sys_util::ioctl::{ioctl_with_ref, ioctl_with_val};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr, ioctl_iow_nr};
+
+const KVM_CAP_X86_USER_SPACE_MSR: u32 = 188;
+const KVM_CAP_X86_MSR_FILTER: u32 = 189;
+pub const KVM_EXIT_X86_RDMSR: u32 = 29;
+pub const KVM_EXIT_X86_WRMSR: u32 = 30;
+
+const KVM_MSR_EXIT_REASON_FILTER: u64 = 1 << 2;
+
+const KVM_MSR_FILTER_MAX_RANGES: usize = 16;
+const KVM_MSR_FILTER_MAX_BITMAP_SIZE: usize = 0x600;
+const KVM_MSR_FILTER_READ: u32 = 1;
+const KVM_MSR_FILTER_WRITE: u32 = 1 << 1;
+const KVM_MSR_FILTER_DEFAULT_ALLOW: u32 = 0;
+const KVM_MSR_FILTER_DEFAULT_DENY: u32 = 1;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct KvmMsrFilterRange {
+    flags: u32,
+    nmsrs: u32,
+    base: u32,
+    bitmap: *const u8,
+}
+
+#[repr(C)]
+struct KvmMsrFilter {
+    flags: u32,
+    ranges: [KvmMsrFilterRange; KVM_MSR_FILTER_MAX_RANGES],
+}
+
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_iow_nr!(KVM_X86_SET_MSR_FILTER, KVMIO, 0xc6, KvmMsrFilter);
+
+#[derive(Debug, PartialEq, Eq)]
+struct MsrRange {
+    base: u32,
+    // One bit per MSR, starting from the base one
+    bitmap: Vec<u8>,
+}
+
+// Group the MSRs into as few ranges as possible, every MSR of a range which
+// isn't listed getting the default action.
+fn build_ranges(
+    default_action: MsrFilterAction,
+    msrs: &[(u32, MsrFilterAction)],
+) -> vm::Result<Vec<MsrRange>> {
+    let default_byte = if default_action == MsrFilterAction::Allow {
+        0xff
+    } else {
+        0
+    };
+
+    let mut msrs = msrs.to_vec();
+    msrs.sort_by_key(|(index, _)| *index);
+
+    let mut ranges: Vec<MsrRange> = Vec::new();
+    for (index, action) in msrs {
+        let range = match ranges.last_mut() {
+            Some(r) if ((index - r.base) as usize) < KVM_MSR_FILTER_MAX_BITMAP_SIZE * 8 => r,
+            _ => {
+                ranges.push(MsrRange {
+                    base: index,
+                    bitmap: Vec::new(),
+                });
+                ranges.last_mut().unwrap()
+            }
+        };
+
+        let bit = (index - range.base) as usize;
+        if range.bitmap.len() <= bit / 8 {
+            range.bitmap.resize(bit / 8 + 1, default_byte);
+        }
+        if action == MsrFilterAction::Allow {
+            range.bitmap[bit / 8] |= 1 << (bit % 8);
+        } else {
+            range.bitmap[bit / 8] &= !(1 << (bit % 8));
+        }
+    }
+
+    if ranges.len() > KVM_MSR_FILTER_MAX_RANGES {
+        return Err(vm::HypervisorVmError::SetMsrFilter(anyhow!(
+            "Too many MSR ranges: {} (max {})",
+            ranges.len(),
+            KVM_MSR_FILTER_MAX_RANGES
+        )));
+    }
+
+    Ok(ranges)
+}
+
+/// Install the MSR filter on the VM, enabling the exits to userspace when
+/// some MSRs must be emulated as zero.
+pub fn set_msr_filter(
+    fd: &VmFd,
+    default_action: MsrFilterAction,
+    msrs: &[(u32, MsrFilterAction)],
+) -> vm::Result<()> {
+    // SAFETY: FFI call with a valid fd, the ioctl doesn't access memory.
+    let supported =
+        unsafe { ioctl_with_val(fd, KVM_CHECK_EXTENSION(), KVM_CAP_X86_MSR_FILTER as c_ulong) };
+    if supported <= 0 {
+        return Err(vm::HypervisorVmError::SetMsrFilter(anyhow!(
+            "KVM_CAP_X86_MSR_FILTER is not supported"
+        )));
+    }
+
+    if msrs.iter().any(|(_, a)| *a == MsrFilterAction::Zero) {
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_USER_SPACE_MSR,
+            ..Default::default()
+        };
+        cap.args[0] = KVM_MSR_EXIT_REASON_FILTER;
+        fd.enable_cap(&cap)
+            .map_err(|e| vm::HypervisorVmError::SetMsrFilter(e.into()))?;
+    }
+
+    let ranges = build_ranges(default_action, msrs)?;
+    let mut filter = KvmMsrFilter {
+        flags: if default_action == MsrFilterAction::Allow {
+            KVM_MSR_FILTER_DEFAULT_ALLOW
+        } else {
+            KVM_MSR_FILTER_DEFAULT_DENY
+        },
+        ranges: [KvmMsrFilterRange {
+            flags: 0,
+            nmsrs: 0,
+            base: 0,
+            bitmap: std::ptr::null(),
+        }; KVM_MSR_FILTER_MAX_RANGES],
+    };
+    for (i, range) in ranges.iter().enumerate() {
+        filter.ranges[i] = KvmMsrFilterRange {
+            flags: KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE,
+            nmsrs: (range.bitmap.len() * 8) as u32,
+            base: range.base,
+            bitmap: range.bitmap.as_ptr(),
+        };
+    }
+
+    // SAFETY: FFI call with a valid fd and filter, KVM copies the bitmaps
+    // which outlive the call.
+    let ret = unsafe { ioctl_with_ref(fd, KVM_X86_SET_MSR_FILTER(), &filter) };
+    if ret < 0 {
+        return Err(vm::HypervisorVmError::SetMsrFilter(
+            std::io::Error::last_os_error().into(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_ranges() {
+        let ranges = build_ranges(
+            MsrFilterAction::Allow,
+            &[
+                (0xc001_0131, MsrFilterAction::Deny),
+                (0x10, MsrFilterAction::Zero),
+                (0x1a, MsrFilterAction::Deny),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                MsrRange {
+                    base: 0x10,
+                    bitmap: vec![0xfe, 0xfb],
+                },
+                MsrRange {
+                    base: 0xc001_0131,
+                    bitmap: vec![0xfe],
+                },
+            ]
+        );
+
+        let ranges =
+            build_ranges(MsrFilterAction::Deny, &[(0x3a, MsrFilterAction::Allow)]).unwrap();
+        assert_eq!(
+            ranges,
+            vec![MsrRange {
+                base: 0x3a,
+                bitmap: vec![0x01],
+            }]
+        );
+
+        let msrs: Vec<(u32, MsrFilterAction)> = (0..=KVM_MSR_FILTER_MAX_RANGES as u32)
+            .map(|i| (i * 0x10000, MsrFilterAction::Deny))
+            .collect();
+        assert!(build_ranges(MsrFilterAction::Allow, &msrs).is_err());
+    }
+}
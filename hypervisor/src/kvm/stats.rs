@@ -0,0 +1,156 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Statistics of a vCPU, as exported by KVM through its binary stats file.
+//!
+//! The exits KVM handles by itself never reach the VMM, so that the halts and
+//! the EPT violations can only be counted by reading the statistics KVM keeps
+//! for each vCPU. The file starts with a header giving the offsets of the
+//! descriptors of the statistics and of their values, the descriptors naming
+//! each statistic and giving the offset of its value.
+
+use crate::cpu::{VcpuExitReason, VcpuExitStats};
+use kvm_bindings::KVMIO;
+use kvm_ioctls::VcpuFd;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use vmm_sys_util::ioctl::ioctl;
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr};
+
+ioctl_io_nr!(KVM_GET_STATS_FD, KVMIO, 0xce);
+
+// struct kvm_stats_header
+const HEADER_SIZE: usize = 24;
+const HEADER_NAME_SIZE: usize = 4;
+const HEADER_NUM_DESC: usize = 8;
+const HEADER_DESC_OFFSET: usize = 16;
+const HEADER_DATA_OFFSET: usize = 20;
+
+// struct kvm_stats_desc, followed by the name of the statistic
+const DESC_OFFSET: usize = 8;
+const DESC_NAME: usize = 16;
+
+// Statistics counting the exits, by order of preference, the older kernels
+// not counting the page faults taken.
+const HALT_STATS: &[&str] = &["halt_exits", "wfi_exit_stat"];
+const EPT_VIOLATION_STATS: &[&str] = &["pf_taken", "pf_fixed"];
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Debug)]
+pub struct KvmVcpuStats {
+    file: File,
+    // Offsets in the file of the values of the statistics
+    halt: Option<u64>,
+    ept_violation: Option<u64>,
+}
+
+impl KvmVcpuStats {
+    pub fn new(fd: &VcpuFd) -> io::Result<Self> {
+        // SAFETY: FFI call with a valid vCPU fd
+        let ret = unsafe { ioctl(fd, KVM_GET_STATS_FD()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the fd was just returned by KVM, nothing else owns it
+        Self::from_file(unsafe { File::from_raw_fd(ret) })
+    }
+
+    fn from_file(file: File) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)?;
+        let name_size = read_u32(&header, HEADER_NAME_SIZE) as usize;
+        let num_desc = read_u32(&header, HEADER_NUM_DESC) as usize;
+        let desc_offset = read_u32(&header, HEADER_DESC_OFFSET) as u64;
+        let data_offset = read_u32(&header, HEADER_DATA_OFFSET) as u64;
+
+        let desc_size = DESC_NAME + name_size;
+        let mut descs = vec![0u8; desc_size * num_desc];
+        file.read_exact_at(&mut descs, desc_offset)?;
+
+        let offsets: HashMap<&[u8], u64> = descs
+            .chunks_exact(desc_size)
+            .map(|desc| {
+                let name = desc[DESC_NAME..].split(|c| *c == 0).next().unwrap();
+                (name, data_offset + read_u32(desc, DESC_OFFSET) as u64)
+            })
+            .collect();
+        let offset = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| offsets.get(name.as_bytes()).copied())
+        };
+
+        Ok(KvmVcpuStats {
+            halt: offset(HALT_STATS),
+            ept_violation: offset(EPT_VIOLATION_STATS),
+            file,
+        })
+    }
+}
+
+impl VcpuExitStats for KvmVcpuStats {
+    fn get(&self, reason: VcpuExitReason) -> Option<u64> {
+        let offset = match reason {
+            VcpuExitReason::Halt => self.halt?,
+            VcpuExitReason::EptViolation => self.ept_violation?,
+            _ => return None,
+        };
+
+        let mut value = [0u8; 8];
+        self.file.read_exact_at(&mut value, offset).ok()?;
+        Some(u64::from_ne_bytes(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use vmm_sys_util::tempfile::TempFile;
+
+    fn desc(name: &str, offset: u32, name_size: usize) -> Vec<u8> {
+        let mut desc = vec![0u8; DESC_NAME + name_size];
+        desc[DESC_OFFSET..DESC_OFFSET + 4].copy_from_slice(&offset.to_ne_bytes());
+        desc[DESC_NAME..DESC_NAME + name.len()].copy_from_slice(name.as_bytes());
+        desc
+    }
+
+    #[test]
+    fn test_kvm_vcpu_stats() {
+        let name_size = 48;
+        let desc_offset = (HEADER_SIZE + name_size) as u32;
+        let data_offset = desc_offset + 3 * (DESC_NAME + name_size) as u32;
+
+        let mut stats = vec![0u8; HEADER_SIZE + name_size];
+        stats[HEADER_NAME_SIZE..HEADER_NAME_SIZE + 4]
+            .copy_from_slice(&(name_size as u32).to_ne_bytes());
+        stats[HEADER_NUM_DESC..HEADER_NUM_DESC + 4].copy_from_slice(&3u32.to_ne_bytes());
+        stats[HEADER_DESC_OFFSET..HEADER_DESC_OFFSET + 4]
+            .copy_from_slice(&desc_offset.to_ne_bytes());
+        stats[HEADER_DATA_OFFSET..HEADER_DATA_OFFSET + 4]
+            .copy_from_slice(&data_offset.to_ne_bytes());
+        stats.extend(desc("pf_fixed", 0, name_size));
+        stats.extend(desc("halt_exits", 8, name_size));
+        stats.extend(desc("pf_taken", 16, name_size));
+        for value in [3u64, 5, 7] {
+            stats.extend(value.to_ne_bytes());
+        }
+
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().write_all(&stats).unwrap();
+        let stats = KvmVcpuStats::from_file(temp_file.as_file().try_clone().unwrap()).unwrap();
+
+        assert_eq!(stats.get(VcpuExitReason::Halt), Some(5));
+        // The page faults taken are preferred when the kernel counts them
+        assert_eq!(stats.get(VcpuExitReason::EptViolation), Some(7));
+        assert_eq!(stats.get(VcpuExitReason::Mmio), None);
+    }
+}
@@ -51,7 +51,10 @@ mod device;
 pub use crate::hypervisor::{Hypervisor, HypervisorError};
 #[cfg(target_arch = "x86_64")]
 pub use cpu::CpuVendor;
-pub use cpu::{HypervisorCpuError, Vcpu, VmExit};
+pub use cpu::{
+    DebugExit, HypervisorCpuError, Vcpu, VcpuExitCounters, VcpuExitReason, VcpuExitStats, VmExit,
+    Watchpoint, WatchpointKind,
+};
 pub use device::HypervisorDeviceError;
 #[cfg(all(feature = "kvm", target_arch = "aarch64"))]
 pub use kvm::{aarch64, GicState};
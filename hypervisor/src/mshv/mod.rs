@@ -46,7 +46,7 @@ use std::fs::File;
 use std::os::unix::io::AsRawFd;
 
 #[cfg(target_arch = "x86_64")]
-use crate::arch::x86::{CpuIdEntry, FpuState, MsrEntry};
+use crate::arch::x86::{CpuIdEntry, FpuState, MsrEntry, MsrFilterAction};
 
 const DIRTY_BITMAP_CLEAR_DIRTY: u64 = 0x4;
 const DIRTY_BITMAP_SET_DIRTY: u64 = 0x8;
@@ -338,6 +338,7 @@ pub struct MshvVcpu {
     cpuid: Vec<CpuIdEntry>,
     msrs: Vec<MsrEntry>,
     vm_ops: Option<Arc<dyn vm::VmOps>>,
+    exit_counters: Arc<cpu::VcpuExitCounters>,
 }
 
 /// Implementation of Vcpu trait for Microsoft Hypervisor
@@ -466,6 +467,7 @@ impl cpu::Vcpu for MshvVcpu {
             Ok(x) => match x.header.message_type {
                 hv_message_type_HVMSG_X64_HALT => {
                     debug!("HALT");
+                    self.exit_counters.record(cpu::VcpuExitReason::Halt);
                     Ok(cpu::VmExit::Reset)
                 }
                 hv_message_type_HVMSG_UNRECOVERABLE_EXCEPTION => {
@@ -473,6 +475,7 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Shutdown)
                 }
                 hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Pio);
                     let info = x.to_ioport_info().unwrap();
                     let access_info = info.access_info;
                     // SAFETY: access_info is valid, otherwise we won't be here
@@ -563,6 +566,8 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_UNMAPPED_GPA => {
+                    // The MMIO accesses are counted by the emulator context
+                    self.exit_counters.record(cpu::VcpuExitReason::EptViolation);
                     let info = x.to_memory_info().unwrap();
                     let insn_len = info.instruction_byte_count as usize;
                     assert!(insn_len > 0 && insn_len <= 16);
@@ -593,6 +598,7 @@ impl cpu::Vcpu for MshvVcpu {
                     Ok(cpu::VmExit::Ignore)
                 }
                 hv_message_type_HVMSG_X64_MSR_INTERCEPT => {
+                    self.exit_counters.record(cpu::VcpuExitReason::Msr);
                     let info = x.to_msr_info().unwrap();
                     if info.header.intercept_access_type == 0 {
                         debug!("msr read: {:x}", { info.msr_number });
@@ -1152,6 +1158,12 @@ impl cpu::Vcpu for MshvVcpu {
         ]
         .to_vec()
     }
+    ///
+    /// Returns the counters of the exits of the vCPU
+    ///
+    fn exit_counters(&self) -> Arc<cpu::VcpuExitCounters> {
+        self.exit_counters.clone()
+    }
 }
 
 impl MshvVcpu {
@@ -1211,6 +1223,20 @@ impl MshvVcpu {
             .set_vcpu_events(events)
             .map_err(|e| cpu::HypervisorCpuError::SetVcpuEvents(e.into()))
     }
+    #[cfg(feature = "sev_snp")]
+    ///
+    /// Enable the encrypted state of the vCPU, starting from the given VMSA
+    ///
+    fn set_sev_control_register(&self, vmsa_pfn: u64) -> cpu::Result<()> {
+        // Bit 0 enables the encrypted state, the VMSA page number starting
+        // at bit 12.
+        let reg_names = [(
+            hv_register_name_HV_X64_REGISTER_SEV_CONTROL,
+            (vmsa_pfn << PAGE_SHIFT) | 1,
+        )];
+        set_registers_64!(self.fd, reg_names)
+            .map_err(|e| cpu::HypervisorCpuError::SetSevControlRegister(e.into()))
+    }
 }
 
 struct MshvEmulatorContext<'a> {
@@ -1256,6 +1282,7 @@ impl<'a> PlatformEmulator for MshvEmulatorContext<'a> {
 
         if let Some(vm_ops) = &self.vcpu.vm_ops {
             if vm_ops.guest_mem_read(gpa, data).is_err() {
+                self.vcpu.exit_counters.record(cpu::VcpuExitReason::Mmio);
                 vm_ops
                     .mmio_read(gpa, data)
                     .map_err(|e| PlatformError::MemoryReadFailure(e.into()))?;
@@ -1276,6 +1303,7 @@ impl<'a> PlatformEmulator for MshvEmulatorContext<'a> {
 
         if let Some(vm_ops) = &self.vcpu.vm_ops {
             if vm_ops.guest_mem_write(gpa, data).is_err() {
+                self.vcpu.exit_counters.record(cpu::VcpuExitReason::Mmio);
                 vm_ops
                     .mmio_write(gpa, data)
                     .map_err(|e| PlatformError::MemoryWriteFailure(e.into()))?;
@@ -1435,6 +1463,7 @@ impl vm::Vm for MshvVm {
             cpuid: Vec::new(),
             msrs: self.msrs.clone(),
             vm_ops,
+            exit_counters: Arc::new(cpu::VcpuExitCounters::default()),
         };
         Ok(Arc::new(vcpu))
     }
@@ -1446,6 +1475,16 @@ impl vm::Vm for MshvVm {
     fn enable_sgx_attribute(&self, _file: File) -> vm::Result<()> {
         Ok(())
     }
+    #[cfg(target_arch = "x86_64")]
+    fn set_msr_filter(
+        &self,
+        _default_action: MsrFilterAction,
+        _msrs: &[(u32, MsrFilterAction)],
+    ) -> vm::Result<()> {
+        Err(vm::HypervisorVmError::SetMsrFilter(anyhow!(
+            "MSR filtering is not supported"
+        )))
+    }
     fn register_ioevent(
         &self,
         fd: &EventFd,
@@ -14,6 +14,8 @@ use crate::aarch64::VcpuInit;
 use crate::arch::aarch64::gic::{Vgic, VgicConfig};
 #[cfg(feature = "tdx")]
 use crate::arch::x86::CpuIdEntry;
+#[cfg(target_arch = "x86_64")]
+use crate::arch::x86::MsrFilterAction;
 use crate::cpu::Vcpu;
 #[cfg(target_arch = "x86_64")]
 use crate::ClockData;
@@ -128,6 +130,11 @@ pub enum HypervisorVmError {
     #[error("Failed to enable SGX attribute: {0}")]
     EnableSgxAttribute(#[source] anyhow::Error),
     ///
+    /// Set MSR filter error
+    ///
+    #[error("Failed to set the MSR filter: {0}")]
+    SetMsrFilter(#[source] anyhow::Error),
+    ///
     /// Get clock error
     ///
     #[error("Failed to get clock: {0}")]
@@ -328,6 +335,14 @@ pub trait Vm: Send + Sync + Any {
     fn enable_split_irq(&self) -> Result<()>;
     #[cfg(target_arch = "x86_64")]
     fn enable_sgx_attribute(&self, file: File) -> Result<()>;
+    /// Filter the guest accesses to the listed MSRs, the accesses to the
+    /// other ones getting the default action.
+    #[cfg(target_arch = "x86_64")]
+    fn set_msr_filter(
+        &self,
+        default_action: MsrFilterAction,
+        msrs: &[(u32, MsrFilterAction)],
+    ) -> Result<()>;
     /// Retrieve guest clock.
     #[cfg(target_arch = "x86_64")]
     fn get_clock(&self) -> Result<ClockData>;
@@ -13,6 +13,7 @@ mod mac;
 mod open_tap;
 mod queue_pair;
 mod tap;
+mod xdp;
 
 use std::io::Error as IoError;
 use std::os::raw::c_uint;
@@ -33,8 +34,11 @@ type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
 pub use ctrl_queue::{CtrlQueue, Error as CtrlQueueError};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use open_tap::{open_tap, Error as OpenTapError};
-pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
+pub use queue_pair::{
+    NetCounters, NetQueueBackend, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio,
+};
 pub use tap::{Error as TapError, Tap};
+pub use xdp::{interface_mtu, open_xdp, Error as XdpError, XdpSocket, XDP_FRAME_SIZE, XDP_MAX_MTU};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -2,19 +2,91 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
+use super::{register_listener, unregister_listener, vnet_hdr_len, Tap, XdpSocket};
 use crate::GuestMemoryMmap;
 use rate_limiter::{RateLimiter, TokenType};
+use std::fs::File;
 use std::io;
 use std::num::Wrapping;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::{Bytes, GuestMemory};
 use vm_virtio::{AccessPlatform, Translatable};
 
+/// Host side of a queue pair, which the frames of the guest are exchanged
+/// through.
+#[derive(Clone)]
+pub enum NetQueueBackend {
+    Tap(Tap),
+    Xdp(Arc<Mutex<XdpSocket>>),
+}
+
+impl NetQueueBackend {
+    fn readv(&self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        match self {
+            NetQueueBackend::Tap(tap) => {
+                // SAFETY: FFI call with correct arguments
+                let result = unsafe {
+                    libc::readv(
+                        tap.as_raw_fd() as libc::c_int,
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                    )
+                };
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(result as usize)
+            }
+            NetQueueBackend::Xdp(socket) => socket.lock().unwrap().read_frame(iovecs),
+        }
+    }
+
+    fn writev(&self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        match self {
+            NetQueueBackend::Tap(tap) => {
+                // SAFETY: FFI call with correct arguments
+                let result = unsafe {
+                    libc::writev(
+                        tap.as_raw_fd() as libc::c_int,
+                        iovecs.as_ptr(),
+                        iovecs.len() as libc::c_int,
+                    )
+                };
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(result as usize)
+            }
+            NetQueueBackend::Xdp(socket) => socket.lock().unwrap().write_frame(iovecs),
+        }
+    }
+
+    /// Duplicate the file descriptor of the backend, so that the events it
+    /// reports can be registered twice with the same epoll instance.
+    pub fn try_clone_fd(&self) -> io::Result<File> {
+        // SAFETY: FFI call to dup. Trivially safe.
+        let fd = unsafe { libc::dup(self.as_raw_fd()) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just duplicated and nothing else owns it.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+}
+
+impl AsRawFd for NetQueueBackend {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NetQueueBackend::Tap(tap) => tap.as_raw_fd(),
+            NetQueueBackend::Xdp(socket) => socket.lock().unwrap().as_raw_fd(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub counter_bytes: Wrapping<u64>,
@@ -38,7 +110,7 @@ impl TxVirtio {
     pub fn process_desc_chain(
         &mut self,
         mem: &GuestMemoryMmap,
-        tap: &Tap,
+        backend: &NetQueueBackend,
         queue: &mut Queue,
         rate_limiter: &mut Option<RateLimiter>,
         access_platform: Option<&Arc<dyn AccessPlatform>>,
@@ -83,29 +155,21 @@ impl TxVirtio {
             }
 
             let len = if !iovecs.is_empty() {
-                // SAFETY: FFI call with correct arguments
-                let result = unsafe {
-                    libc::writev(
-                        tap.as_raw_fd() as libc::c_int,
-                        iovecs.as_ptr(),
-                        iovecs.len() as libc::c_int,
-                    )
-                };
-
-                if result < 0 {
-                    let e = std::io::Error::last_os_error();
-
+                let result = match backend.writev(&iovecs) {
+                    Ok(result) => result,
                     /* EAGAIN */
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         queue.go_to_previous_position();
                         retry_write = true;
                         break;
                     }
-                    error!("net: tx: failed writing to tap: {}", e);
-                    return Err(NetQueuePairError::WriteTap(e));
-                }
+                    Err(e) => {
+                        error!("net: tx: failed writing to tap: {}", e);
+                        return Err(NetQueuePairError::WriteTap(e));
+                    }
+                };
 
-                if (result as usize) < vnet_hdr_len() {
+                if result < vnet_hdr_len() {
                     return Err(NetQueuePairError::InvalidVirtioNetHeader);
                 }
 
@@ -164,7 +228,7 @@ impl RxVirtio {
     pub fn process_desc_chain(
         &mut self,
         mem: &GuestMemoryMmap,
-        tap: &Tap,
+        backend: &NetQueueBackend,
         queue: &mut Queue,
         rate_limiter: &mut Option<RateLimiter>,
         access_platform: Option<&Arc<dyn AccessPlatform>>,
@@ -222,29 +286,23 @@ impl RxVirtio {
             }
 
             let len = if !iovecs.is_empty() {
-                // SAFETY: FFI call with correct arguments
-                let result = unsafe {
-                    libc::readv(
-                        tap.as_raw_fd() as libc::c_int,
-                        iovecs.as_ptr(),
-                        iovecs.len() as libc::c_int,
-                    )
-                };
-                if result < 0 {
-                    let e = std::io::Error::last_os_error();
-                    exhausted_descs = false;
-                    queue.go_to_previous_position();
+                let result = match backend.readv(&iovecs) {
+                    Ok(result) => result,
+                    Err(e) => {
+                        exhausted_descs = false;
+                        queue.go_to_previous_position();
 
-                    /* EAGAIN */
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        break;
-                    }
+                        /* EAGAIN */
+                        if e.kind() == std::io::ErrorKind::WouldBlock {
+                            break;
+                        }
 
-                    error!("net: rx: failed reading from tap: {}", e);
-                    return Err(NetQueuePairError::ReadTap(e));
-                }
+                        error!("net: rx: failed reading from tap: {}", e);
+                        return Err(NetQueuePairError::ReadTap(e));
+                    }
+                };
 
-                if (result as usize) < vnet_hdr_len() {
+                if result < vnet_hdr_len() {
                     return Err(NetQueuePairError::InvalidVirtioNetHeader);
                 }
 
@@ -329,12 +387,12 @@ pub enum NetQueuePairError {
 }
 
 pub struct NetQueuePair {
-    pub tap: Tap,
+    pub backend: NetQueueBackend,
     // With epoll each FD must be unique. So in order to filter the
     // events we need to get a second FD responding to the original
     // device so that we can send EPOLLOUT and EPOLLIN to separate
     // events.
-    pub tap_for_write_epoll: Tap,
+    pub backend_for_write_epoll: File,
     pub rx: RxVirtio,
     pub tx: TxVirtio,
     pub epoll_fd: Option<RawFd>,
@@ -357,7 +415,7 @@ impl NetQueuePair {
     ) -> Result<bool, NetQueuePairError> {
         let tx_tap_retry = self.tx.process_desc_chain(
             mem,
-            &self.tap,
+            &self.backend,
             queue,
             &mut self.tx_rate_limiter,
             self.access_platform.as_ref(),
@@ -367,7 +425,7 @@ impl NetQueuePair {
         if tx_tap_retry && !self.tx_tap_listening {
             register_listener(
                 self.epoll_fd.unwrap(),
-                self.tap_for_write_epoll.as_raw_fd(),
+                self.backend_for_write_epoll.as_raw_fd(),
                 epoll::Events::EPOLLOUT,
                 u64::from(self.tap_tx_event_id),
             )
@@ -377,7 +435,7 @@ impl NetQueuePair {
         } else if !tx_tap_retry && self.tx_tap_listening {
             unregister_listener(
                 self.epoll_fd.unwrap(),
-                self.tap_for_write_epoll.as_raw_fd(),
+                self.backend_for_write_epoll.as_raw_fd(),
                 epoll::Events::EPOLLOUT,
                 u64::from(self.tap_tx_event_id),
             )
@@ -407,7 +465,7 @@ impl NetQueuePair {
     ) -> Result<bool, NetQueuePairError> {
         self.rx_desc_avail = !self.rx.process_desc_chain(
             mem,
-            &self.tap,
+            &self.backend,
             queue,
             &mut self.rx_rate_limiter,
             self.access_platform.as_ref(),
@@ -423,7 +481,7 @@ impl NetQueuePair {
         if self.rx_tap_listening && (!self.rx_desc_avail || rate_limit_reached) {
             unregister_listener(
                 self.epoll_fd.unwrap(),
-                self.tap.as_raw_fd(),
+                self.backend.as_raw_fd(),
                 epoll::Events::EPOLLIN,
                 u64::from(self.tap_rx_event_id),
             )
@@ -0,0 +1,1043 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! AF_XDP sockets the virtio-net devices can exchange frames through, as an
+//! alternative to TAP interfaces.
+//!
+//! Each queue pair of a device gets a socket bound to the queue of the same
+//! index of a host interface. An XDP program attached to the interface
+//! redirects the frames received on these queues for the MAC address of the
+//! guest, along with the broadcast and multicast ones, to the sockets. Any
+//! other frame reaches the host network stack as usual. The interface is
+//! made promiscuous for as long as the program is attached, as it would
+//! otherwise drop the frames for the guest before they reach the program.
+//! Frames are copied between the guest buffers and a memory region shared
+//! with the kernel (the UMEM), which the NIC accesses directly when its
+//! driver supports the zero-copy mode.
+
+use super::{create_unix_socket, vnet_hdr_len, Error as NetUtilError, MacAddr};
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid interface name")]
+    InvalidIfname,
+    #[error("Unknown interface {0}: {1}")]
+    UnknownInterface(String, #[source] io::Error),
+    #[error("Failed to retrieve the MTU of the interface: {0}")]
+    Mtu(#[source] io::Error),
+    #[error("Failed to make the interface promiscuous: {0}")]
+    Promiscuous(#[source] io::Error),
+    #[error("Failed to create a socket: {0}")]
+    NetUtil(NetUtilError),
+    #[error("Failed to create the AF_XDP socket: {0}")]
+    CreateSocket(#[source] io::Error),
+    #[error("Failed to set up the UMEM: {0}")]
+    Umem(#[source] io::Error),
+    #[error("Failed to set up the rings: {0}")]
+    Rings(#[source] io::Error),
+    #[error("Failed to bind the socket to queue {0}: {1}")]
+    Bind(u32, #[source] io::Error),
+    #[error("Failed to create the XSKMAP: {0}")]
+    CreateMap(#[source] io::Error),
+    #[error("Failed to load the XDP program: {0}")]
+    LoadProgram(#[source] io::Error),
+    #[error("Failed to attach the XDP program: {0}")]
+    AttachProgram(#[source] io::Error),
+    #[error("Failed to redirect queue {0} to the socket: {1}")]
+    UpdateMap(u32, #[source] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const SOL_XDP: libc::c_int = 283;
+
+// Socket options
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+// Bind flags
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+
+// Offsets the rings are mapped at
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+// Headroom the kernel reserves in front of the received frames.
+const XDP_PACKET_HEADROOM: usize = 256;
+
+/// Size of each frame of the UMEM.
+pub const XDP_FRAME_SIZE: usize = 4096;
+/// Largest MTU the frames of the UMEM can hold.
+pub const XDP_MAX_MTU: usize = XDP_FRAME_SIZE - XDP_PACKET_HEADROOM - 14;
+
+// Entries of each ring, the UMEM holding as many frames for receiving as for
+// transmitting.
+const RING_SIZE: u32 = 1024;
+const NUM_FRAMES: usize = 2 * RING_SIZE as usize;
+
+// bpf() commands
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct BpfMapUpdateElemAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct BpfProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Default)]
+struct BpfLinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct BpfInsn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: dst | src << 4,
+            off,
+            imm,
+        }
+    }
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &T) -> io::Result<libc::c_long> {
+    // SAFETY: the attributes match the command, and the kernel reads no more
+    // than their size.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret)
+}
+
+fn bpf_fd<T>(cmd: libc::c_int, attr: &T) -> io::Result<File> {
+    let fd = bpf(cmd, attr)?;
+    // SAFETY: the command returned a new file descriptor we own.
+    Ok(unsafe { File::from_raw_fd(fd as RawFd) })
+}
+
+fn setsockopt<T>(fd: &File, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: the option value is valid for its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Memory mapping unmapped when dropped.
+struct MmapRegion {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl MmapRegion {
+    fn new(len: usize, fd: Option<&File>, offset: libc::off_t) -> io::Result<Self> {
+        let (flags, fd) = match fd {
+            Some(fd) => (libc::MAP_SHARED | libc::MAP_POPULATE, fd.as_raw_fd()),
+            None => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1),
+        };
+        // SAFETY: FFI call creating a new mapping, the result being checked.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MmapRegion { addr, len })
+    }
+}
+
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        // SAFETY: the region was mapped with this length, and nothing refers
+        // to it anymore.
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+// Ring shared with the kernel, either producing or consuming its entries.
+struct Ring<T> {
+    _region: MmapRegion,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    entries: *mut T,
+    _entry: PhantomData<T>,
+}
+
+impl<T: Copy> Ring<T> {
+    fn new(fd: &File, offsets: &XdpRingOffset, pgoff: libc::off_t) -> io::Result<Self> {
+        let len = offsets.desc as usize + RING_SIZE as usize * std::mem::size_of::<T>();
+        let region = MmapRegion::new(len, Some(fd), pgoff)?;
+        let base = region.addr as *mut u8;
+
+        // SAFETY: the kernel reported the offsets within the mapping.
+        unsafe {
+            Ok(Ring {
+                producer: base.add(offsets.producer as usize) as *const AtomicU32,
+                consumer: base.add(offsets.consumer as usize) as *const AtomicU32,
+                entries: base.add(offsets.desc as usize) as *mut T,
+                _region: region,
+                _entry: PhantomData,
+            })
+        }
+    }
+
+    // Take the next entry the kernel produced.
+    fn pop(&mut self) -> Option<T> {
+        // SAFETY: the pointers refer to the mapping owned by the ring, and the
+        // entry read is one the kernel produced.
+        unsafe {
+            let consumer = (*self.consumer).load(Ordering::Relaxed);
+            if consumer == (*self.producer).load(Ordering::Acquire) {
+                return None;
+            }
+            let entry = *self.entries.add((consumer & (RING_SIZE - 1)) as usize);
+            (*self.consumer).store(consumer.wrapping_add(1), Ordering::Release);
+
+            Some(entry)
+        }
+    }
+
+    // Hand an entry to the kernel, returning false if the ring is full.
+    fn push(&mut self, entry: T) -> bool {
+        // SAFETY: the pointers refer to the mapping owned by the ring, and the
+        // entry written is one the kernel consumed already.
+        unsafe {
+            let producer = (*self.producer).load(Ordering::Relaxed);
+            if producer.wrapping_sub((*self.consumer).load(Ordering::Acquire)) == RING_SIZE {
+                return false;
+            }
+            *self.entries.add((producer & (RING_SIZE - 1)) as usize) = entry;
+            (*self.producer).store(producer.wrapping_add(1), Ordering::Release);
+
+            true
+        }
+    }
+}
+
+// Copy `data` to the buffers described by `iovecs`, starting `offset` bytes
+// into them, returning the number of bytes copied.
+fn copy_to_iovecs(iovecs: &[libc::iovec], mut offset: usize, mut data: &[u8]) -> usize {
+    let mut copied = 0;
+    for iovec in iovecs {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= iovec.iov_len {
+            offset -= iovec.iov_len;
+            continue;
+        }
+        let len = std::cmp::min(iovec.iov_len - offset, data.len());
+        // SAFETY: the iovecs describe guest buffers mapped for the duration of
+        // the call, which don't overlap with `data`.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (iovec.iov_base as *mut u8).add(offset),
+                len,
+            )
+        };
+        data = &data[len..];
+        copied += len;
+        offset = 0;
+    }
+
+    copied
+}
+
+// Copy the buffers described by `iovecs` to `data`, starting `offset` bytes
+// into them, returning the number of bytes copied.
+fn copy_from_iovecs(iovecs: &[libc::iovec], mut offset: usize, mut data: &mut [u8]) -> usize {
+    let mut copied = 0;
+    for iovec in iovecs {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= iovec.iov_len {
+            offset -= iovec.iov_len;
+            continue;
+        }
+        let len = std::cmp::min(iovec.iov_len - offset, data.len());
+        // SAFETY: the iovecs describe guest buffers mapped for the duration of
+        // the call, which don't overlap with `data`.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (iovec.iov_base as *const u8).add(offset),
+                data.as_mut_ptr(),
+                len,
+            )
+        };
+        data = &mut std::mem::take(&mut data)[len..];
+        copied += len;
+        offset = 0;
+    }
+
+    copied
+}
+
+// Instructions of the XDP program, redirecting the frames for `mac`, along
+// with the broadcast and multicast ones, to the socket the XSKMAP `map_fd`
+// holds for their queue. Any other frame is passed to the host network
+// stack.
+fn redirect_program(map_fd: RawFd, mac: &MacAddr) -> Vec<BpfInsn> {
+    // Indexes of the instructions jumped to
+    const REDIRECT: i16 = 20;
+    const PASS: i16 = 26;
+
+    let mut insns = vec![
+        // r6 = r1, the context
+        BpfInsn::new(0xbf, 6, 1, 0, 0),
+        // r2 = ((struct xdp_md *)r6)->data
+        BpfInsn::new(0x61, 2, 6, 0, 0),
+        // r3 = ((struct xdp_md *)r6)->data_end
+        BpfInsn::new(0x61, 3, 6, 4, 0),
+        // if r2 + 6 > r3 goto pass, the frame holding no destination
+        BpfInsn::new(0xbf, 4, 2, 0, 0),
+        BpfInsn::new(0x07, 4, 0, 0, 6),
+        BpfInsn::new(0x2d, 4, 3, PASS - 6, 0),
+        // if (*(u8 *)r2 & 1) goto redirect, for broadcast and multicast
+        BpfInsn::new(0x71, 4, 2, 0, 0),
+        BpfInsn::new(0x45, 4, 0, REDIRECT - 8, 1),
+    ];
+    // if (*(u8 *)(r2 + i) != mac[i]) goto pass
+    for (i, byte) in mac.get_bytes().iter().enumerate() {
+        let index = insns.len() as i16;
+        insns.push(BpfInsn::new(0x71, 4, 2, i as i16, 0));
+        insns.push(BpfInsn::new(0x55, 4, 0, PASS - index - 2, i32::from(*byte)));
+    }
+    insns.extend([
+        // r2 = ((struct xdp_md *)r6)->rx_queue_index
+        BpfInsn::new(0x61, 2, 6, 16, 0),
+        // r1 = map
+        BpfInsn::new(0x18, 1, 1, 0, map_fd),
+        BpfInsn::new(0, 0, 0, 0, 0),
+        // r3 = XDP_PASS, when no socket is bound to the queue
+        BpfInsn::new(0xb7, 3, 0, 0, 2),
+        // r0 = bpf_redirect_map(r1, r2, r3)
+        BpfInsn::new(0x85, 0, 0, 0, 51),
+        // return r0
+        BpfInsn::new(0x95, 0, 0, 0, 0),
+        // pass: return XDP_PASS
+        BpfInsn::new(0xb7, 0, 0, 0, 2),
+        BpfInsn::new(0x95, 0, 0, 0, 0),
+    ]);
+
+    insns
+}
+
+fn interface_ifreq(if_name: &str) -> Result<net_gen::ifreq> {
+    if if_name.len() >= net_gen::iff::IFNAMSIZ as usize {
+        return Err(Error::InvalidIfname);
+    }
+
+    let mut ifreq: net_gen::ifreq = Default::default();
+    // SAFETY: access a union field, the name fitting in it.
+    unsafe {
+        ifreq.ifr_ifrn.ifrn_name.as_mut()[..if_name.len()].copy_from_slice(if_name.as_bytes());
+    }
+
+    Ok(ifreq)
+}
+
+// Promiscuous mode of an interface, turned off once dropped unless it was
+// on already.
+struct PromiscuousMode {
+    ifreq: net_gen::ifreq,
+}
+
+impl PromiscuousMode {
+    fn set(ifreq: &mut net_gen::ifreq, on: bool) -> Result<bool> {
+        let sock = create_unix_socket().map_err(Error::NetUtil)?;
+        // SAFETY: ioctl is safe. Called with a valid sock fd, and we check the return.
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCGIFFLAGS as c_ulong, ifreq) };
+        if ret < 0 {
+            return Err(Error::Promiscuous(io::Error::last_os_error()));
+        }
+
+        let promisc = net_gen::iff::net_device_flags_IFF_PROMISC as libc::c_short;
+        // SAFETY: access a union field
+        let flags = unsafe { ifreq.ifr_ifru.ifru_flags };
+        if ((flags & promisc) != 0) == on {
+            return Ok(false);
+        }
+        ifreq.ifr_ifru.ifru_flags = if on {
+            flags | promisc
+        } else {
+            flags & !promisc
+        };
+
+        // SAFETY: ioctl is safe. Called with a valid sock fd, and we check the return.
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFFLAGS as c_ulong, ifreq) };
+        if ret < 0 {
+            return Err(Error::Promiscuous(io::Error::last_os_error()));
+        }
+
+        Ok(true)
+    }
+
+    fn enable(if_name: &str) -> Result<Option<Self>> {
+        let mut ifreq = interface_ifreq(if_name)?;
+        if !Self::set(&mut ifreq, true)? {
+            return Ok(None);
+        }
+
+        Ok(Some(PromiscuousMode {
+            ifreq: interface_ifreq(if_name)?,
+        }))
+    }
+}
+
+impl Drop for PromiscuousMode {
+    fn drop(&mut self) {
+        if let Err(e) = Self::set(&mut self.ifreq, false) {
+            warn!("Failed to turn the promiscuous mode off: {}", e);
+        }
+    }
+}
+
+// XDP program redirecting the frames received on the queues of an interface
+// to the sockets bound to them, detached once dropped.
+struct XdpProgram {
+    map: File,
+    _link: File,
+    // Dropped after the program is detached
+    _promiscuous: Option<PromiscuousMode>,
+}
+
+impl XdpProgram {
+    fn attach(if_name: &str, ifindex: u32, num_queues: u32, mac: &MacAddr) -> Result<Self> {
+        let map = bpf_fd(
+            BPF_MAP_CREATE,
+            &BpfMapCreateAttr {
+                map_type: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries: num_queues,
+                ..Default::default()
+            },
+        )
+        .map_err(Error::CreateMap)?;
+
+        let insns = redirect_program(map.as_raw_fd(), mac);
+        let license = b"GPL\0";
+        let mut prog_name = [0u8; 16];
+        prog_name[..12].copy_from_slice(b"xsk_redirect");
+
+        let prog = bpf_fd(
+            BPF_PROG_LOAD,
+            &BpfProgLoadAttr {
+                prog_type: BPF_PROG_TYPE_XDP,
+                insn_cnt: insns.len() as u32,
+                insns: insns.as_ptr() as u64,
+                license: license.as_ptr() as u64,
+                prog_name,
+                ..Default::default()
+            },
+        )
+        .map_err(Error::LoadProgram)?;
+
+        let link = bpf_fd(
+            BPF_LINK_CREATE,
+            &BpfLinkCreateAttr {
+                prog_fd: prog.as_raw_fd() as u32,
+                target_ifindex: ifindex,
+                attach_type: BPF_XDP,
+                flags: 0,
+            },
+        )
+        .map_err(Error::AttachProgram)?;
+
+        let promiscuous = PromiscuousMode::enable(if_name)?;
+
+        Ok(XdpProgram {
+            map,
+            _link: link,
+            _promiscuous: promiscuous,
+        })
+    }
+
+    fn redirect(&self, queue_id: u32, socket: &File) -> Result<()> {
+        let fd = socket.as_raw_fd() as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &BpfMapUpdateElemAttr {
+                map_fd: self.map.as_raw_fd() as u32,
+                key: &queue_id as *const u32 as u64,
+                value: &fd as *const u32 as u64,
+                ..Default::default()
+            },
+        )
+        .map_err(|e| Error::UpdateMap(queue_id, e))?;
+
+        Ok(())
+    }
+}
+
+/// AF_XDP socket bound to a queue of a host interface.
+pub struct XdpSocket {
+    rx: Ring<XdpDesc>,
+    tx: Ring<XdpDesc>,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    // Frames of the UMEM available for transmitting.
+    free_frames: Vec<u64>,
+    fd: File,
+    umem: MmapRegion,
+    _program: Arc<XdpProgram>,
+}
+
+// SAFETY: the rings and the UMEM are only accessed through the socket, which
+// owns their mappings.
+unsafe impl Send for XdpSocket {}
+
+impl XdpSocket {
+    fn new(ifindex: u32, queue_id: u32, zero_copy: bool, program: Arc<XdpProgram>) -> Result<Self> {
+        // SAFETY: FFI call, the result being checked.
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::CreateSocket(io::Error::last_os_error()));
+        }
+        // SAFETY: the socket was just created and nothing else owns it.
+        let fd = unsafe { File::from_raw_fd(fd) };
+
+        let umem = MmapRegion::new(NUM_FRAMES * XDP_FRAME_SIZE, None, 0).map_err(Error::Umem)?;
+        setsockopt(
+            &fd,
+            XDP_UMEM_REG,
+            &XdpUmemReg {
+                addr: umem.addr as u64,
+                len: umem.len as u64,
+                chunk_size: XDP_FRAME_SIZE as u32,
+                ..Default::default()
+            },
+        )
+        .map_err(Error::Umem)?;
+
+        for ring in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            setsockopt(&fd, ring, &RING_SIZE).map_err(Error::Rings)?;
+        }
+
+        let mut offsets = XdpMmapOffsets::default();
+        let mut len = std::mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        // SAFETY: the option value is valid for its size.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut XdpMmapOffsets as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Rings(io::Error::last_os_error()));
+        }
+
+        let mut socket = XdpSocket {
+            rx: Ring::new(&fd, &offsets.rx, XDP_PGOFF_RX_RING).map_err(Error::Rings)?,
+            tx: Ring::new(&fd, &offsets.tx, XDP_PGOFF_TX_RING).map_err(Error::Rings)?,
+            fill: Ring::new(&fd, &offsets.fr, XDP_UMEM_PGOFF_FILL_RING).map_err(Error::Rings)?,
+            completion: Ring::new(&fd, &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING)
+                .map_err(Error::Rings)?,
+            free_frames: (RING_SIZE as usize..NUM_FRAMES)
+                .map(|frame| (frame * XDP_FRAME_SIZE) as u64)
+                .collect(),
+            fd,
+            umem,
+            _program: program.clone(),
+        };
+
+        if zero_copy {
+            match socket.bind(ifindex, queue_id, XDP_ZEROCOPY) {
+                Ok(()) => info!(
+                    "AF_XDP socket bound to queue {} in zero-copy mode",
+                    queue_id
+                ),
+                Err(e) => {
+                    warn!(
+                        "Zero-copy mode unavailable for queue {}, falling back to copy mode: {}",
+                        queue_id, e
+                    );
+                    socket.bind(ifindex, queue_id, XDP_COPY)?;
+                }
+            }
+        } else {
+            socket.bind(ifindex, queue_id, XDP_COPY)?;
+        }
+
+        for frame in 0..RING_SIZE as usize {
+            socket.fill.push((frame * XDP_FRAME_SIZE) as u64);
+        }
+
+        program.redirect(queue_id, &socket.fd)?;
+
+        Ok(socket)
+    }
+
+    fn bind(&self, ifindex: u32, queue_id: u32, flags: u16) -> Result<()> {
+        let addr = SockaddrXdp {
+            family: libc::AF_XDP as u16,
+            flags,
+            ifindex,
+            queue_id,
+            shared_umem_fd: 0,
+        };
+        // SAFETY: the address is valid for its size.
+        let ret = unsafe {
+            libc::bind(
+                self.fd.as_raw_fd(),
+                &addr as *const SockaddrXdp as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::Bind(queue_id, io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    fn frame(&mut self, addr: u64, len: usize) -> io::Result<&mut [u8]> {
+        let addr = addr as usize;
+        if addr + len > self.umem.len {
+            return Err(io::Error::from_raw_os_error(libc::EFAULT));
+        }
+        // SAFETY: the range was checked to be within the UMEM, which the
+        // kernel doesn't access while the frame is owned by the socket.
+        Ok(unsafe { std::slice::from_raw_parts_mut((self.umem.addr as *mut u8).add(addr), len) })
+    }
+
+    // Ask the kernel to transmit the frames of the TX ring.
+    fn kick(&self) -> io::Result<()> {
+        // SAFETY: FFI call without any buffer, the result being checked.
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                null_mut(),
+                0,
+                libc::MSG_DONTWAIT,
+                null_mut(),
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            // The frames are transmitted on the next attempt.
+            if !matches!(
+                e.raw_os_error(),
+                Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS | libc::ENETDOWN)
+            ) {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive a frame into the buffers described by `iovecs`, behind a
+    /// virtio-net header without any offload. Returns the number of bytes
+    /// written, or an error of kind `WouldBlock` when no frame is pending.
+    pub fn read_frame(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        let desc = self
+            .rx
+            .pop()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::WouldBlock))?;
+
+        let hdr_len = vnet_hdr_len();
+        let mut len = copy_to_iovecs(iovecs, 0, &[0u8; 32][..hdr_len]);
+        if let Ok(frame) = self.frame(desc.addr, desc.len as usize) {
+            len += copy_to_iovecs(iovecs, hdr_len, frame);
+        }
+
+        // Hand the frame back to the kernel, the fill ring having room for
+        // all the frames used for receiving.
+        self.fill.push(desc.addr & !(XDP_FRAME_SIZE as u64 - 1));
+
+        Ok(len)
+    }
+
+    /// Transmit the frame found in the buffers described by `iovecs`, behind
+    /// a virtio-net header. Returns the number of bytes consumed, or an error
+    /// of kind `WouldBlock` when no room is left for the frame. A frame not
+    /// fitting in a frame of the UMEM is dropped.
+    pub fn write_frame(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        let total: usize = iovecs.iter().map(|iovec| iovec.iov_len).sum();
+        let hdr_len = vnet_hdr_len();
+        let Some(len) = tx_frame_len(total, hdr_len) else {
+            debug!("Dropping a frame of {} bytes", total);
+            return Ok(total);
+        };
+
+        while let Some(addr) = self.completion.pop() {
+            self.free_frames.push(addr);
+        }
+        let addr = match self.free_frames.pop() {
+            Some(addr) => addr,
+            None => {
+                self.kick()?;
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+        };
+
+        copy_from_iovecs(iovecs, hdr_len, self.frame(addr, len)?);
+        if !self.tx.push(XdpDesc {
+            addr,
+            len: len as u32,
+            options: 0,
+        }) {
+            self.free_frames.push(addr);
+            self.kick()?;
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        self.kick()?;
+
+        Ok(total)
+    }
+}
+
+impl AsRawFd for XdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+// Length of the frame held by `total` bytes behind a virtio-net header of
+// `hdr_len` bytes, if it can be transmitted.
+fn tx_frame_len(total: usize, hdr_len: usize) -> Option<usize> {
+    total
+        .checked_sub(hdr_len)
+        .filter(|len| *len <= XDP_FRAME_SIZE)
+}
+
+fn interface_index(if_name: &str) -> Result<u32> {
+    let name = CString::new(if_name).map_err(|_| Error::InvalidIfname)?;
+    // SAFETY: FFI call with a nul-terminated string, the result being checked.
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(Error::UnknownInterface(
+            if_name.to_owned(),
+            io::Error::last_os_error(),
+        ));
+    }
+
+    Ok(ifindex)
+}
+
+/// Retrieve the MTU of a host interface.
+pub fn interface_mtu(if_name: &str) -> Result<i32> {
+    let ifreq = interface_ifreq(if_name)?;
+    let sock = create_unix_socket().map_err(Error::NetUtil)?;
+
+    // SAFETY: ioctl is safe. Called with a valid sock fd, and we check the return.
+    let ret = unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCGIFMTU as c_ulong, &ifreq) };
+    if ret < 0 {
+        return Err(Error::Mtu(io::Error::last_os_error()));
+    }
+
+    // SAFETY: access a union field
+    Ok(unsafe { ifreq.ifr_ifru.ifru_mtu })
+}
+
+/// Create an AF_XDP socket for each of the first `num_queue_pairs` queues of
+/// the host interface `if_name`, and attach the XDP program redirecting
+/// their frames for `mac` to the sockets. The program stays attached, and
+/// the interface promiscuous, for as long as any of the sockets is.
+pub fn open_xdp(
+    if_name: &str,
+    num_queue_pairs: usize,
+    zero_copy: bool,
+    mac: &MacAddr,
+) -> Result<Vec<XdpSocket>> {
+    let ifindex = interface_index(if_name)?;
+    let program = Arc::new(XdpProgram::attach(
+        if_name,
+        ifindex,
+        num_queue_pairs as u32,
+        mac,
+    )?);
+
+    (0..num_queue_pairs as u32)
+        .map(|queue_id| XdpSocket::new(ifindex, queue_id, zero_copy, program.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_iovecs() {
+        let mut buf0 = [0u8; 4];
+        let mut buf1 = [0u8; 8];
+        let iovecs = [
+            libc::iovec {
+                iov_base: buf0.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf0.len(),
+            },
+            libc::iovec {
+                iov_base: buf1.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf1.len(),
+            },
+        ];
+
+        assert_eq!(copy_to_iovecs(&iovecs, 2, &[1, 2, 3, 4, 5, 6]), 6);
+        let mut data = [0u8; 8];
+        assert_eq!(copy_from_iovecs(&iovecs, 1, &mut data), 8);
+        assert_eq!(data, [0, 1, 2, 3, 4, 5, 6, 0]);
+
+        // Truncated to the size of the buffers
+        assert_eq!(copy_to_iovecs(&iovecs, 10, &[7, 8, 9]), 2);
+        assert_eq!(buf1[6..], [7, 8]);
+    }
+
+    #[test]
+    fn test_tx_frame_len() {
+        assert_eq!(tx_frame_len(12, 12), Some(0));
+        assert_eq!(tx_frame_len(12 + 1514, 12), Some(1514));
+        assert_eq!(tx_frame_len(12 + XDP_FRAME_SIZE, 12), Some(XDP_FRAME_SIZE));
+        // Dropped rather than failing the queue
+        assert_eq!(tx_frame_len(12 + XDP_FRAME_SIZE + 1, 12), None);
+        assert_eq!(tx_frame_len(11, 12), None);
+    }
+
+    const XDP_PASS: u64 = 2;
+    const XDP_REDIRECT: u64 = 4;
+
+    // Run the program on a frame received on the given queue, interpreting
+    // the few instructions it is made of.
+    fn run_program(insns: &[BpfInsn], frame: &[u8], queue: u32) -> u64 {
+        // Addresses the context and the frame are found at
+        const CTX: u64 = 0x1000;
+        const DATA: u64 = 0x2000;
+
+        let load = |addr: u64, size: u64| -> u64 {
+            match addr {
+                CTX => DATA,
+                a if a == CTX + 4 => DATA + frame.len() as u64,
+                a if a == CTX + 16 => u64::from(queue),
+                a if (DATA..DATA + frame.len() as u64).contains(&a) && size == 1 => {
+                    u64::from(frame[(a - DATA) as usize])
+                }
+                _ => panic!("Invalid load of {size} bytes at {addr:#x}"),
+            }
+        };
+
+        let mut regs = [0u64; 11];
+        regs[1] = CTX;
+        let mut pc = 0;
+        loop {
+            let insn = &insns[pc];
+            let (dst, src) = ((insn.regs & 0xf) as usize, (insn.regs >> 4) as usize);
+            let target = (pc as i64 + 1 + i64::from(insn.off)) as usize;
+            pc += 1;
+            match insn.code {
+                0xbf => regs[dst] = regs[src],
+                0xb7 => regs[dst] = insn.imm as u64,
+                0x07 => regs[dst] = regs[dst].wrapping_add(insn.imm as u64),
+                0x61 => regs[dst] = load(regs[src] + insn.off as u64, 4),
+                0x71 => regs[dst] = load(regs[src] + insn.off as u64, 1),
+                0x2d if regs[dst] > regs[src] => pc = target,
+                0x45 if regs[dst] & insn.imm as u64 != 0 => pc = target,
+                0x55 if regs[dst] != insn.imm as u64 => pc = target,
+                0x2d | 0x45 | 0x55 => {}
+                0x18 => {
+                    regs[dst] = insn.imm as u64;
+                    pc += 1;
+                }
+                // bpf_redirect_map(), with a socket bound to queue 0 only
+                0x85 => {
+                    assert_eq!(insn.imm, 51);
+                    regs[0] = if regs[2] == 0 { XDP_REDIRECT } else { regs[3] };
+                }
+                0x95 => return regs[0],
+                code => panic!("Unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_redirect_program() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let insns = redirect_program(42, &mac);
+
+        let frame = |dst: [u8; 6]| {
+            let mut frame = dst.to_vec();
+            frame.extend_from_slice(&[0xfe, 0, 0, 0, 0, 1, 0x08, 0x00]);
+            frame
+        };
+
+        // Frames for the guest
+        let unicast = frame([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        assert_eq!(run_program(&insns, &unicast, 0), XDP_REDIRECT);
+        assert_eq!(run_program(&insns, &frame([0xff; 6]), 0), XDP_REDIRECT);
+        let multicast = frame([0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]);
+        assert_eq!(run_program(&insns, &multicast, 0), XDP_REDIRECT);
+
+        // Frames for the host
+        let other = frame([0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd]);
+        assert_eq!(run_program(&insns, &other, 0), XDP_PASS);
+        let other = frame([0x02, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        assert_eq!(run_program(&insns, &other, 0), XDP_PASS);
+        assert_eq!(run_program(&insns, &unicast[..5], 0), XDP_PASS);
+
+        // Queues without any socket
+        assert_eq!(run_program(&insns, &unicast, 1), XDP_PASS);
+    }
+}
@@ -319,6 +319,27 @@ impl FromStr for IntegerList {
     }
 }
 
+/// Integer written in hexadecimal, with or without the `0x` prefix.
+pub struct Hex<T>(pub T);
+
+#[derive(Debug)]
+pub enum HexParseError {
+    InvalidValue(String),
+}
+
+impl<T: TryFrom<u64>> FromStr for Hex<T> {
+    type Err = HexParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let v = u64::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16)
+            .map_err(|_| HexParseError::InvalidValue(s.to_owned()))?;
+        T::try_from(v)
+            .map(Hex)
+            .map_err(|_| HexParseError::InvalidValue(s.to_owned()))
+    }
+}
+
 pub trait TupleValue {
     fn parse_value(input: &str) -> Result<Self, TupleError>
     where
@@ -342,6 +363,12 @@ impl TupleValue for Vec<u8> {
     }
 }
 
+impl TupleValue for String {
+    fn parse_value(input: &str) -> Result<Self, TupleError> {
+        Ok(input.to_string())
+    }
+}
+
 impl TupleValue for Vec<u64> {
     fn parse_value(input: &str) -> Result<Self, TupleError> {
         Ok(IntegerList::from_str(input)
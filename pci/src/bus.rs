@@ -6,7 +6,7 @@ use crate::configuration::{
     PciBarRegionType, PciBridgeSubclass, PciClassCode, PciConfiguration, PciHeaderType,
 };
 use crate::device::{DeviceRelocation, Error as PciDeviceError, PciDevice};
-use crate::PciBarConfiguration;
+use crate::{PciBarConfiguration, PciRootPort};
 use byteorder::{ByteOrder, LittleEndian};
 use std::any::Any;
 use std::collections::HashMap;
@@ -99,12 +99,20 @@ pub struct PciBus {
     /// Devices attached to this bus.
     /// Device 0 is host bridge.
     devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>>,
+    /// Root ports attached to this bus, each one leading to its own bus.
+    root_ports: HashMap<u32, Arc<Mutex<PciRootPort>>>,
     device_reloc: Arc<dyn DeviceRelocation>,
     device_ids: Vec<bool>,
+    /// Number of this bus, as seen by the guest.
+    number: u8,
+    /// Other root buses of the segment, taking the devices once this one is
+    /// full. The devices of an expansion bus are identified by the ids
+    /// following the ones of the previous bus.
+    expansion_buses: Vec<u8>,
 }
 
 impl PciBus {
-    pub fn new(pci_root: PciRoot, device_reloc: Arc<dyn DeviceRelocation>) -> Self {
+    pub fn new(pci_root: PciRoot, device_reloc: Arc<dyn DeviceRelocation>, number: u8) -> Self {
         let mut devices: HashMap<u32, Arc<Mutex<dyn PciDevice>>> = HashMap::new();
         let mut device_ids: Vec<bool> = vec![false; NUM_DEVICE_IDS];
 
@@ -113,11 +121,42 @@ impl PciBus {
 
         PciBus {
             devices,
+            root_ports: HashMap::new(),
             device_reloc,
             device_ids,
+            number,
+            expansion_buses: Vec::new(),
         }
     }
 
+    /// Add a root bus taking the devices once the previous ones are full.
+    pub fn add_expansion_bus(&mut self, number: u8) {
+        self.expansion_buses.push(number);
+        self.device_ids.extend([false; NUM_DEVICE_IDS]);
+    }
+
+    /// Bus and slot of the device with the given id.
+    pub fn device_address(&self, id: u32) -> (u8, u8) {
+        let index = id as usize / NUM_DEVICE_IDS;
+        let bus = if index == 0 {
+            self.number
+        } else {
+            self.expansion_buses[index - 1]
+        };
+        (bus, (id as usize % NUM_DEVICE_IDS) as u8)
+    }
+
+    /// Id of the device found at the given slot of this bus or of one of its
+    /// expansion buses.
+    pub fn device_id(&self, bus: u8, device: u8) -> Option<u32> {
+        let index = if bus == self.number {
+            0
+        } else {
+            self.expansion_buses.iter().position(|b| *b == bus)? + 1
+        };
+        Some((index * NUM_DEVICE_IDS + device as usize) as u32)
+    }
+
     pub fn register_mapping(
         &self,
         dev: Arc<Mutex<dyn BusDevice>>,
@@ -150,13 +189,56 @@ impl PciBus {
         Ok(())
     }
 
+    pub fn add_root_port(
+        &mut self,
+        device_id: u32,
+        root_port: Arc<Mutex<PciRootPort>>,
+    ) -> Result<()> {
+        self.devices.insert(device_id, root_port.clone());
+        self.root_ports.insert(device_id, root_port);
+        Ok(())
+    }
+
     pub fn remove_by_device(&mut self, device: &Arc<Mutex<dyn PciDevice>>) -> Result<()> {
         self.devices.retain(|_, dev| !Arc::ptr_eq(dev, device));
+        for root_port in self.root_ports.values() {
+            root_port.lock().unwrap().detach_device(device);
+        }
         Ok(())
     }
 
-    pub fn next_device_id(&mut self) -> Result<u32> {
-        for (idx, device_id) in self.device_ids.iter_mut().enumerate() {
+    // Device found at the given address, the devices behind the root ports
+    // being the only ones on the buses of these ports.
+    fn device(&self, bus: usize, device: usize) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        if bus > u8::MAX as usize || device >= NUM_DEVICE_IDS {
+            return None;
+        }
+        if let Some(id) = self.device_id(bus as u8, device as u8) {
+            return self.devices.get(&id).cloned();
+        }
+        if device != 0 {
+            return None;
+        }
+
+        self.root_ports.values().find_map(|p| {
+            let root_port = p.lock().unwrap();
+            if root_port.secondary_bus() as usize == bus && root_port.powered() {
+                root_port.device()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Allocate the id of a device, on the expansion buses as well if asked
+    /// to once this bus is full.
+    pub fn next_device_id(&mut self, expand: bool) -> Result<u32> {
+        let num_ids = if expand {
+            self.device_ids.len()
+        } else {
+            NUM_DEVICE_IDS
+        };
+        for (idx, device_id) in self.device_ids.iter_mut().take(num_ids).enumerate() {
             if !(*device_id) {
                 *device_id = true;
                 return Ok(idx as u32);
@@ -167,7 +249,7 @@ impl PciBus {
     }
 
     pub fn get_device_id(&mut self, id: usize) -> Result<()> {
-        if id < NUM_DEVICE_IDS {
+        if id < self.device_ids.len() {
             if !self.device_ids[id] {
                 self.device_ids[id] = true;
                 Ok(())
@@ -180,7 +262,7 @@ impl PciBus {
     }
 
     pub fn put_device_id(&mut self, id: usize) -> Result<()> {
-        if id < NUM_DEVICE_IDS {
+        if id < self.device_ids.len() {
             self.device_ids[id] = false;
             Ok(())
         } else {
@@ -212,11 +294,6 @@ impl PciConfigIo {
         let (bus, device, function, register) =
             parse_io_config_address(self.config_address & !0x8000_0000);
 
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
-
         // Don't support multi-function devices.
         if function > 0 {
             return 0xffff_ffff;
@@ -226,8 +303,7 @@ impl PciConfigIo {
             .as_ref()
             .lock()
             .unwrap()
-            .devices
-            .get(&(device as u32))
+            .device(bus, device)
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -246,13 +322,8 @@ impl PciConfigIo {
         let (bus, device, _function, register) =
             parse_io_config_address(self.config_address & !0x8000_0000);
 
-        // Only support one bus.
-        if bus != 0 {
-            return None;
-        }
-
         let pci_bus = self.pci_bus.as_ref().lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
+        if let Some(d) = pci_bus.device(bus, device) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -272,6 +343,22 @@ impl PciConfigIo {
                 }
             }
 
+            // Same thing if one of the BARs is being resized.
+            if let Some(params) = device.detect_bar_resizing(register, data) {
+                if let Err(e) = pci_bus.device_reloc.resize_bar(
+                    params.base,
+                    params.old_len,
+                    params.new_len,
+                    device.deref_mut(),
+                    params.region_type,
+                ) {
+                    error!(
+                        "Failed resizing device BAR: {}: 0x{:x}(0x{:x}->0x{:x})",
+                        e, params.base, params.old_len, params.new_len
+                    );
+                }
+            }
+
             // Update the register value
             device.write_config_register(register, offset, data)
         } else {
@@ -348,16 +435,10 @@ impl PciConfigMmio {
     fn config_space_read(&self, config_address: u32) -> u32 {
         let (bus, device, _function, register) = parse_mmio_config_address(config_address);
 
-        // Only support one bus.
-        if bus != 0 {
-            return 0xffff_ffff;
-        }
-
-        self.pci_bus
-            .lock()
-            .unwrap()
-            .devices
-            .get(&(device as u32))
+        // The configuration space starts with the bus of the segment.
+        let pci_bus = self.pci_bus.lock().unwrap();
+        pci_bus
+            .device(pci_bus.number as usize + bus, device)
             .map_or(0xffff_ffff, |d| {
                 d.lock().unwrap().read_config_register(register)
             })
@@ -370,13 +451,9 @@ impl PciConfigMmio {
 
         let (bus, device, _function, register) = parse_mmio_config_address(config_address);
 
-        // Only support one bus.
-        if bus != 0 {
-            return;
-        }
-
+        // The configuration space starts with the bus of the segment.
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(&(device as u32)) {
+        if let Some(d) = pci_bus.device(pci_bus.number as usize + bus, device) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -396,6 +473,22 @@ impl PciConfigMmio {
                 }
             }
 
+            // Same thing if one of the BARs is being resized.
+            if let Some(params) = device.detect_bar_resizing(register, data) {
+                if let Err(e) = pci_bus.device_reloc.resize_bar(
+                    params.base,
+                    params.old_len,
+                    params.new_len,
+                    device.deref_mut(),
+                    params.region_type,
+                ) {
+                    error!(
+                        "Failed resizing device BAR: {}: 0x{:x}(0x{:x}->0x{:x})",
+                        e, params.base, params.old_len, params.new_len
+                    );
+                }
+            }
+
             // Update the register value
             device.write_config_register(register, offset, data);
         }
@@ -473,3 +566,68 @@ fn parse_io_config_address(config_address: u32) -> (usize, usize, usize, usize)
         shift_and_mask(config_address, REGISTER_NUMBER_OFFSET, REGISTER_NUMBER_MASK),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    struct NoRelocation;
+
+    impl DeviceRelocation for NoRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+
+        fn resize_bar(
+            &self,
+            _base: u64,
+            _old_len: u64,
+            _new_len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_expansion_buses() {
+        let mut bus = PciBus::new(PciRoot::new(None), Arc::new(NoRelocation), 0x10);
+        bus.add_expansion_bus(0x12);
+        bus.add_expansion_bus(0x13);
+
+        // The devices only go to the expansion buses once asked to, after
+        // the first bus is full.
+        for id in 1..NUM_DEVICE_IDS as u32 {
+            assert_eq!(bus.next_device_id(true).unwrap(), id);
+        }
+        assert!(bus.next_device_id(false).is_err());
+        assert_eq!(bus.next_device_id(true).unwrap(), 32);
+        assert_eq!(bus.next_device_id(true).unwrap(), 33);
+
+        assert_eq!(bus.device_address(5), (0x10, 5));
+        assert_eq!(bus.device_address(33), (0x12, 1));
+        assert_eq!(bus.device_address(66), (0x13, 2));
+        assert_eq!(bus.device_id(0x10, 5), Some(5));
+        assert_eq!(bus.device_id(0x12, 1), Some(33));
+        assert_eq!(bus.device_id(0x13, 2), Some(66));
+        assert_eq!(bus.device_id(0x11, 0), None);
+
+        // The host bridge is only found on the first bus
+        assert!(bus.device(0x10, 0).is_some());
+        assert!(bus.device(0x12, 0).is_none());
+
+        bus.put_device_id(32).unwrap();
+        bus.get_device_id(32).unwrap();
+        assert!(bus.get_device_id(32).is_err());
+        assert!(bus.get_device_id(96).is_err());
+    }
+}
@@ -598,7 +598,12 @@ impl PciConfiguration {
                 }
                 PciHeaderType::Bridge => {
                     registers[3] = 0x0001_0000; // Header type 1 (bridge)
+                    writable_bits[6] = 0x00ff_ffff; // Primary, secondary and subordinate bus
                     writable_bits[9] = 0xfff0_fff0; // Memory base and limit
+                    registers[10] = 0x0001_0001; // 64-bit prefetchable memory base and limit
+                    writable_bits[10] = 0xfff0_fff0;
+                    writable_bits[11] = 0xffff_ffff; // Prefetchable base upper 32 bits
+                    writable_bits[12] = 0xffff_ffff; // Prefetchable limit upper 32 bits
                     writable_bits[15] = 0xffff_00ff; // Bridge control (r/w), interrupt line (r/w)
                 }
             };
@@ -797,6 +802,46 @@ impl PciConfiguration {
         Ok(())
     }
 
+    /// Changes the size of a memory BAR previously added, which is moved to
+    /// the given address at the same time.
+    pub fn resize_bar(&mut self, bar_idx: usize, addr: u64, size: u64) -> Result<()> {
+        if bar_idx >= NUM_BAR_REGS || !self.bars[bar_idx].used {
+            return Err(Error::BarInvalid(bar_idx));
+        }
+
+        if size.count_ones() != 1 {
+            return Err(Error::BarSizeInvalid(size));
+        }
+
+        let reg_idx = BAR0_REG + bar_idx;
+        match self.bars[bar_idx].r#type {
+            Some(PciBarRegionType::Memory32BitRegion) => {
+                if addr.checked_add(size - 1).unwrap_or(u64::MAX) > u64::from(u32::max_value()) {
+                    return Err(Error::BarAddressInvalid(addr, size));
+                }
+
+                self.bars[bar_idx].size =
+                    encode_32_bits_bar_size(size as u32).ok_or(Error::Encode32BarSize)?;
+            }
+            Some(PciBarRegionType::Memory64BitRegion) => {
+                let (bar_size_hi, bar_size_lo) =
+                    encode_64_bits_bar_size(size).ok_or(Error::Encode64BarSize)?;
+
+                self.registers[reg_idx + 1] = (addr >> 32) as u32;
+                self.bars[bar_idx + 1].addr = self.registers[reg_idx + 1];
+                self.bars[bar_idx].size = bar_size_lo;
+                self.bars[bar_idx + 1].size = bar_size_hi;
+            }
+            _ => return Err(Error::BarInvalid(bar_idx)),
+        }
+
+        self.registers[reg_idx] =
+            (self.registers[reg_idx] & !BAR_MEM_ADDR_MASK) | ((addr as u32) & BAR_MEM_ADDR_MASK);
+        self.bars[bar_idx].addr = self.registers[reg_idx];
+
+        Ok(())
+    }
+
     /// Adds rom expansion BAR.
     pub fn add_pci_rom_bar(&mut self, config: &PciBarConfiguration, active: u32) -> Result<()> {
         let bar_idx = config.idx;
@@ -901,6 +946,45 @@ impl PciConfiguration {
         Ok(cap_offset)
     }
 
+    /// Returns the offsets of the capabilities identified by `id`, in the
+    /// order they appear in the capability list.
+    pub fn capability_offsets(&self, id: PciCapabilityId) -> Vec<usize> {
+        let read_byte = |offset: usize| (self.read_reg(offset / 4) >> ((offset % 4) * 8)) as u8;
+
+        let mut offsets = Vec::new();
+        let mut cap_offset = read_byte(CAPABILITY_LIST_HEAD_OFFSET) as usize;
+        // Bound the walk in case the list is corrupted and loops.
+        while cap_offset >= FIRST_CAPABILITY_OFFSET
+            && cap_offset < CAPABILITY_MAX_OFFSET
+            && offsets.len() < CAPABILITY_MAX_OFFSET / 4
+        {
+            if read_byte(cap_offset) == id as u8 {
+                offsets.push(cap_offset);
+            }
+            cap_offset = read_byte(cap_offset + 1) as usize;
+        }
+
+        offsets
+    }
+
+    /// Overwrites the content of the capability found at `cap_offset` with
+    /// `cap_data`, leaving the two-byte header (type, next) untouched.
+    pub fn update_capability(
+        &mut self,
+        cap_offset: usize,
+        cap_data: &dyn PciCapability,
+    ) -> Result<()> {
+        let total_len = cap_data.bytes().len();
+        if cap_offset + total_len + 2 > CAPABILITY_MAX_OFFSET {
+            return Err(Error::CapabilitySpaceFull(total_len));
+        }
+        for (i, byte) in cap_data.bytes().iter().enumerate() {
+            self.write_byte_internal(cap_offset + i + 2, *byte, false);
+        }
+
+        Ok(())
+    }
+
     // Find the next aligned offset after the one given.
     fn next_dword(offset: usize, len: usize) -> usize {
         let next = offset + len;
@@ -1180,6 +1264,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn resize_bar() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+            None,
+        );
+        let bar = PciBarConfiguration::default()
+            .set_index(0)
+            .set_address(0x1_0000_0000)
+            .set_size(0x1000_0000)
+            .set_region_type(PciBarRegionType::Memory64BitRegion)
+            .set_prefetchable(PciBarPrefetchable::Prefetchable);
+        cfg.add_pci_bar(&bar).unwrap();
+
+        cfg.resize_bar(0, 0x4_0000_0000, 0x4_0000_0000).unwrap();
+        assert_eq!(cfg.get_bar_addr(0), 0x4_0000_0000);
+        // The flags are kept, while the new size is reported to the guest
+        assert_eq!(cfg.read_reg(BAR0_REG), 0xc);
+        cfg.write_reg(BAR0_REG, 0xffff_ffff);
+        cfg.write_reg(BAR0_REG + 1, 0xffff_ffff);
+        assert_eq!(cfg.read_reg(BAR0_REG), 0xc);
+        assert_eq!(cfg.read_reg(BAR0_REG + 1), 0xffff_fffc);
+
+        assert!(cfg.resize_bar(0, 0x4_0000_0000, 0x3000).is_err());
+        assert!(cfg.resize_bar(2, 0x4_0000_0000, 0x1000).is_err());
+    }
+
     #[test]
     fn add_capability() {
         let mut cfg = PciConfiguration::new(
@@ -1226,6 +1346,50 @@ mod tests {
         assert_eq!((cap2_data >> 24) & 0xFF, 0x55); // cap2.foo
     }
 
+    #[test]
+    fn update_capability() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            0x1,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+            None,
+        );
+        assert!(cfg
+            .capability_offsets(PciCapabilityId::VendorSpecific)
+            .is_empty());
+
+        let cap1_offset = cfg.add_capability(&TestCap { len: 4, foo: 0xAA }).unwrap();
+        let cap2_offset = cfg.add_capability(&TestCap { len: 4, foo: 0x55 }).unwrap();
+        assert_eq!(
+            cfg.capability_offsets(PciCapabilityId::VendorSpecific),
+            vec![cap1_offset, cap2_offset]
+        );
+        assert!(cfg.capability_offsets(PciCapabilityId::MsiX).is_empty());
+
+        cfg.update_capability(cap2_offset, &TestCap { len: 4, foo: 0x66 })
+            .unwrap();
+
+        // The header of the capability and its neighbours are preserved.
+        let cap1_data = cfg.read_reg(cap1_offset / 4);
+        assert_eq!((cap1_data >> 8) & 0xFF, cap2_offset as u32);
+        assert_eq!((cap1_data >> 24) & 0xFF, 0xAA);
+        let cap2_data = cfg.read_reg(cap2_offset / 4);
+        assert_eq!(cap2_data & 0xFF, 0x09);
+        assert_eq!((cap2_data >> 8) & 0xFF, 0x00);
+        assert_eq!((cap2_data >> 24) & 0xFF, 0x66);
+
+        assert!(cfg
+            .update_capability(CAPABILITY_MAX_OFFSET - 2, &TestCap { len: 4, foo: 0 })
+            .is_err());
+    }
+
     #[derive(Copy, Clone)]
     enum TestPi {
         Test = 0x5a,
@@ -52,6 +52,14 @@ pub struct BarReprogrammingParams {
     pub region_type: PciBarRegionType,
 }
 
+#[derive(Clone, Copy)]
+pub struct BarResizingParams {
+    pub base: u64,
+    pub old_len: u64,
+    pub new_len: u64,
+    pub region_type: PciBarRegionType,
+}
+
 pub trait PciDevice: BusDevice {
     /// Allocates the needed PCI BARs space using the `allocate` function which takes a size and
     /// returns an address. Returns a Vec of (GuestAddress, GuestUsize) tuples.
@@ -93,6 +101,10 @@ pub trait PciDevice: BusDevice {
     ) -> Option<BarReprogrammingParams> {
         None
     }
+    /// Detects if a BAR is being resized through the Resizable BAR capability.
+    fn detect_bar_resizing(&mut self, _reg_idx: usize, _data: &[u8]) -> Option<BarResizingParams> {
+        None
+    }
     /// Reads from a BAR region mapped in to the device.
     /// * `addr` - The guest address inside the BAR.
     /// * `data` - Filled with the data from `addr`.
@@ -107,6 +119,18 @@ pub trait PciDevice: BusDevice {
     fn move_bar(&mut self, _old_base: u64, _new_base: u64) -> result::Result<(), io::Error> {
         Ok(())
     }
+    /// Changes the size of the BAR, which is relocated at the same time.
+    fn resize_bar(
+        &mut self,
+        _old_base: u64,
+        _new_base: u64,
+        _new_len: u64,
+    ) -> result::Result<(), io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "BAR resizing is not supported",
+        ))
+    }
     /// Provides a mutable reference to the Any trait. This is useful to let
     /// the caller have access to the underlying type behind the trait.
     fn as_any(&mut self) -> &mut dyn Any;
@@ -128,4 +152,15 @@ pub trait DeviceRelocation: Send + Sync {
         pci_dev: &mut dyn PciDevice,
         region_type: PciBarRegionType,
     ) -> result::Result<(), io::Error>;
+
+    /// The size of the BAR has been changed by the guest, meaning it needs to
+    /// be allocated again, possibly at a different location.
+    fn resize_bar(
+        &self,
+        base: u64,
+        old_len: u64,
+        new_len: u64,
+        pci_dev: &mut dyn PciDevice,
+        region_type: PciBarRegionType,
+    ) -> result::Result<(), io::Error>;
 }
@@ -11,8 +11,10 @@ mod configuration;
 mod device;
 mod msi;
 mod msix;
+mod root_port;
 mod vfio;
 mod vfio_user;
+mod vfio_user_server;
 
 pub use self::bus::{PciBus, PciConfigIo, PciConfigMmio, PciRoot, PciRootError};
 pub use self::configuration::{
@@ -22,12 +24,16 @@ pub use self::configuration::{
     PCI_CONFIGURATION_ID,
 };
 pub use self::device::{
-    BarReprogrammingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
+    BarReprogrammingParams, BarResizingParams, DeviceRelocation, Error as PciDeviceError, PciDevice,
 };
 pub use self::msi::{msi_num_enabled_vectors, MsiCap, MsiConfig};
 pub use self::msix::{MsixCap, MsixConfig, MsixTableEntry, MSIX_CONFIG_ID, MSIX_TABLE_ENTRY_SIZE};
-pub use self::vfio::{VfioPciDevice, VfioPciError};
+pub use self::root_port::{PciErrorSeverity, PciRootPort, PciRootPortError};
+pub use self::vfio::{VfioPciDevice, VfioPciError, PCI_AER_CAPABILITY_DWORDS};
 pub use self::vfio_user::{VfioUserDmaMapping, VfioUserPciDevice, VfioUserPciDeviceError};
+pub use self::vfio_user_server::{
+    VfioUserPciServer, VfioUserPciServerError, VfioUserServerInterrupts,
+};
 use serde::de::Visitor;
 use std::fmt::{self, Display};
 use std::num::ParseIntError;
@@ -201,10 +201,19 @@ impl MsixConfig {
         // pending MSI-X message to inject, given that the vector is not
         // masked.
         if old_masked && !self.masked {
-            for (index, entry) in self.table_entries.clone().iter().enumerate() {
-                if !entry.masked() && self.get_pba_bit(index as u16) == 1 {
-                    self.inject_msix_and_clear_pba(index);
-                }
+            self.inject_pending();
+        }
+    }
+
+    /// Inject the pending MSI-X messages whose vector is not masked.
+    pub fn inject_pending(&mut self) {
+        if self.masked {
+            return;
+        }
+
+        for (index, entry) in self.table_entries.clone().iter().enumerate() {
+            if !entry.masked() && self.get_pba_bit(index as u16) == 1 {
+                self.inject_msix_and_clear_pba(index);
             }
         }
     }
@@ -0,0 +1,1010 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulation of a PCI Express root port.
+//!
+//! The root port is a PCI-to-PCI bridge sitting on the root bus of a segment,
+//! leading to a secondary bus holding a single device. The bus numbers and the
+//! memory windows of the bridge are programmed from the resources of this
+//! device, but the guest is free to assign them again, the configuration
+//! accesses being routed from the secondary bus number the bridge holds.
+//!
+//! The port exposes an Access Control Services (ACS) capability, which lets
+//! the guest isolate the device behind it from the other ones, as IOMMU
+//! groups are built from the ACS support of the ports.
+//!
+//! Its slot is hot-plug capable, with an attention button, a power controller
+//! and both indicators, so that the pciehp driver of the guest manages it. The
+//! hot-plug events are notified through a single MSI vector.
+//!
+//! It also exposes an Advanced Error Reporting (AER) capability, as the root
+//! port receiving the error messages of the device, which are notified
+//! through the same vector. The details of the errors are found by the guest
+//! in the AER capability of the device itself.
+
+use crate::configuration::{PciBridgeSubclass, PciCapability, PciCapabilityId};
+use crate::msi::{MsiConfigState, MSI_CONFIG_ID};
+use crate::{
+    MsiConfig, PciBarConfiguration, PciBarRegionType, PciClassCode, PciConfiguration, PciDevice,
+    PciHeaderType, PCI_CONFIGURATION_ID,
+};
+use anyhow::anyhow;
+use std::any::Any;
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::BusDevice;
+use vm_memory::ByteValued;
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
+};
+
+// Generic PCI Express root port
+const ROOT_PORT_VENDOR_ID: u16 = 0x1b36;
+const ROOT_PORT_DEVICE_ID: u16 = 0x000c;
+
+const BUS_NUMBERS_REG: usize = 6;
+const MEMORY_WINDOW_REG: usize = 9;
+const PREF_MEMORY_WINDOW_REG: usize = 10;
+const PREF_MEMORY_BASE_UPPER_REG: usize = 11;
+const PREF_MEMORY_LIMIT_UPPER_REG: usize = 12;
+// Granularity of the memory windows
+const WINDOW_ALIGNMENT: u64 = 1 << 20;
+
+// PCI Express capability
+const PCI_EXP_FLAGS_VERSION: u16 = 2;
+const PCI_EXP_FLAGS_TYPE_ROOT_PORT: u16 = 4 << 4;
+const PCI_EXP_FLAGS_SLOT: u16 = 1 << 8;
+const PCI_EXP_DEVCAP_RBER: u32 = 1 << 15;
+// 16 GT/s x16 link
+const PCI_EXP_LNK_SPEED: u32 = 4;
+const PCI_EXP_LNK_WIDTH: u32 = 16 << 4;
+const PCI_EXP_LNKCAP_DLLLARC: u32 = 1 << 20;
+const PCI_EXP_LNKCAP_PN_SHIFT: u32 = 24;
+const PCI_EXP_LNKSTA_DLLLA: u32 = 1 << 13;
+// Attention button, power controller, attention and power indicators,
+// hot-plug capable, no command completed support
+const PCI_EXP_SLTCAP_HOTPLUG: u32 = 0x1 | 0x2 | 0x8 | 0x10 | 0x40 | 1 << 18;
+const PCI_EXP_SLTCAP_PSN_SHIFT: u32 = 19;
+const PCI_EXP_SLTCTL_ABPE: u16 = 1 << 0;
+const PCI_EXP_SLTCTL_PDCE: u16 = 1 << 3;
+const PCI_EXP_SLTCTL_HPIE: u16 = 1 << 5;
+const PCI_EXP_SLTCTL_AIC: u16 = 3 << 6;
+const PCI_EXP_SLTCTL_ATTN_IND_OFF: u16 = 3 << 6;
+const PCI_EXP_SLTCTL_PIC: u16 = 3 << 8;
+const PCI_EXP_SLTCTL_PWR_IND_ON: u16 = 1 << 8;
+const PCI_EXP_SLTCTL_PCC: u16 = 1 << 10;
+const PCI_EXP_SLTCTL_DLLSCE: u16 = 1 << 12;
+const PCI_EXP_SLTCTL_WRITABLE: u16 = PCI_EXP_SLTCTL_ABPE
+    | PCI_EXP_SLTCTL_PDCE
+    | PCI_EXP_SLTCTL_HPIE
+    | PCI_EXP_SLTCTL_AIC
+    | PCI_EXP_SLTCTL_PIC
+    | PCI_EXP_SLTCTL_PCC
+    | PCI_EXP_SLTCTL_DLLSCE;
+const PCI_EXP_SLTSTA_ABP: u16 = 1 << 0;
+const PCI_EXP_SLTSTA_PDC: u16 = 1 << 3;
+const PCI_EXP_SLTSTA_PDS: u16 = 1 << 6;
+const PCI_EXP_SLTSTA_DLLSC: u16 = 1 << 8;
+// Hot-plug events, along with the control bit enabling each of them
+const HOTPLUG_EVENTS: [(u16, u16); 3] = [
+    (PCI_EXP_SLTCTL_ABPE, PCI_EXP_SLTSTA_ABP),
+    (PCI_EXP_SLTCTL_PDCE, PCI_EXP_SLTSTA_PDC),
+    (PCI_EXP_SLTCTL_DLLSCE, PCI_EXP_SLTSTA_DLLSC),
+];
+const PCI_EXP_LNKCAP2_SLS: u32 = 0x1e;
+const PCI_EXP_LNKSTA: usize = 0x10;
+const PCI_EXP_SLTCTL: usize = 0x18;
+
+// MSI capability, a single vector with a 64-bit address
+const MSI_CTL_64_BITS: u16 = 0x80;
+
+// Access Control Services extended capability
+const ACS_CAP_REG: usize = 0x100 / 4;
+const ACS_CAP_HEADER: u32 = 0x000d | (1 << 16) | (AER_CAP_REG as u32 * 4) << 20;
+// Source validation, translation blocking, P2P request and completion
+// redirection, upstream forwarding
+const ACS_SUPPORTED: u16 = 0x1f;
+
+// Advanced Error Reporting extended capability, the last one
+const AER_CAP_REG: usize = 0x140 / 4;
+const AER_CAP_HEADER: u32 = 0x0001 | (2 << 16);
+const AER_CAP_DWORDS: usize = 14;
+// Registers of the capability, the layout being shared with the AER
+// capability of the devices
+const AER_UNCOR_STATUS: usize = 1;
+const AER_UNCOR_MASK: usize = 2;
+const AER_UNCOR_SEVERITY: usize = 3;
+const AER_COR_MASK: usize = 5;
+const AER_ROOT_COMMAND: usize = 11;
+const AER_ROOT_STATUS: usize = 12;
+const AER_ERROR_SOURCE: usize = 13;
+const AER_UNCOR_ERRORS: u32 = 0x03ff_f030;
+const AER_UNCOR_SEVERITY_DEFAULT: u32 = 0x0046_2030;
+const AER_COR_ERRORS: u32 = 0xf1c1;
+const AER_COR_MASK_DEFAULT: u32 = 0x2000;
+// Correctable, non-fatal and fatal error reporting
+const AER_ROOT_COMMAND_ENABLES: u32 = 0x7;
+const AER_ROOT_STATUS_COR: u32 = 1 << 0;
+const AER_ROOT_STATUS_MULTI_COR: u32 = 1 << 1;
+const AER_ROOT_STATUS_UNCOR: u32 = 1 << 2;
+const AER_ROOT_STATUS_MULTI_UNCOR: u32 = 1 << 3;
+const AER_ROOT_STATUS_FIRST_FATAL: u32 = 1 << 4;
+const AER_ROOT_STATUS_NONFATAL: u32 = 1 << 5;
+const AER_ROOT_STATUS_FATAL: u32 = 1 << 6;
+const AER_ROOT_STATUS_EVENTS: u32 = 0x7f;
+
+#[derive(Debug, Error)]
+pub enum PciRootPortError {
+    #[error("Failed to retrieve PciConfigurationState: {0}")]
+    RetrievePciConfigurationState(#[source] anyhow::Error),
+    #[error("Failed to retrieve PciRootPortState: {0}")]
+    RetrieveState(#[source] anyhow::Error),
+    #[error("Failed to retrieve MsiConfigState: {0}")]
+    RetrieveMsiConfigState(#[source] anyhow::Error),
+    #[error("Failed to add the PCI Express capability: {0}")]
+    AddCapability(#[source] crate::configuration::Error),
+    #[error("Failed to create the MSI configuration: {0}")]
+    CreateMsiConfig(#[source] crate::msi::Error),
+    #[error("BAR {0} can't be forwarded by the port")]
+    UnforwardableBar(usize),
+}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct PcieCap {
+    pcie_cap: u16,
+    dev_cap: u32,
+    dev_ctl: u16,
+    dev_sts: u16,
+    link_cap: u32,
+    link_ctl: u16,
+    link_sts: u16,
+    slot_cap: u32,
+    slot_ctl: u16,
+    slot_sts: u16,
+    root_ctl: u16,
+    root_cap: u16,
+    root_sts: u32,
+    dev_cap2: u32,
+    dev_ctl2: u16,
+    dev_sts2: u16,
+    link_cap2: u32,
+    link_ctl2: u16,
+    link_sts2: u16,
+    slot_cap2: u32,
+    slot_ctl2: u16,
+    slot_sts2: u16,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for PcieCap {}
+
+impl PciCapability for PcieCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PciExpress
+    }
+}
+
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct MsiCap {
+    msg_ctl: u16,
+    msg_addr_lo: u32,
+    msg_addr_hi: u32,
+    msg_data: u16,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for MsiCap {}
+
+impl PciCapability for MsiCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::MessageSignalledInterrupts
+    }
+}
+
+/// Severity of an error signaled by the device behind a root port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PciErrorSeverity {
+    Correctable,
+    NonFatal,
+    Fatal,
+}
+
+impl PciErrorSeverity {
+    /// Severity of an uncorrectable error, from the registers of the AER
+    /// capability of the device if it has one.
+    pub fn uncorrectable(aer: Option<&[u32]>) -> Self {
+        match aer {
+            Some(aer)
+                if aer[AER_UNCOR_STATUS] & !aer[AER_UNCOR_MASK] & aer[AER_UNCOR_SEVERITY] != 0 =>
+            {
+                PciErrorSeverity::Fatal
+            }
+            _ => PciErrorSeverity::NonFatal,
+        }
+    }
+}
+
+#[derive(Versionize)]
+pub struct PciRootPortState {
+    acs_control: u16,
+    slot_control: u16,
+    slot_status: u16,
+    aer_uncor_mask: u32,
+    aer_uncor_severity: u32,
+    aer_cor_mask: u32,
+    aer_root_command: u32,
+    aer_root_status: u32,
+    aer_error_source: u32,
+}
+
+impl VersionMapped for PciRootPortState {}
+
+/// PCI Express root port, leading to a single device.
+pub struct PciRootPort {
+    id: String,
+    configuration: PciConfiguration,
+    // Offset of the PCI Express capability
+    pcie_cap_offset: usize,
+    // Offset of the MSI capability
+    msi_cap_offset: usize,
+    msi_config: MsiConfig,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    acs_control: u16,
+    slot_control: u16,
+    // Pending hot-plug events, the presence of the device being reported
+    // from its attachment
+    slot_status: u16,
+    // Registers of the AER capability, the port not detecting any error
+    // itself
+    aer_uncor_mask: u32,
+    aer_uncor_severity: u32,
+    aer_cor_mask: u32,
+    aer_root_command: u32,
+    aer_root_status: u32,
+    aer_error_source: u32,
+    device: Option<Arc<Mutex<dyn PciDevice>>>,
+}
+
+impl PciRootPort {
+    /// Create the root port with the given port number, its secondary bus
+    /// being the only bus below it. The hot-plug events are notified through
+    /// the first interrupt of the group.
+    pub fn new(
+        id: String,
+        port_number: u8,
+        primary_bus: u8,
+        secondary_bus: u8,
+        interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self, PciRootPortError> {
+        let pci_configuration_state =
+            vm_migration::versioned_state_from_id(snapshot.as_ref(), PCI_CONFIGURATION_ID)
+                .map_err(|e| {
+                    PciRootPortError::RetrievePciConfigurationState(anyhow!(
+                        "Failed to get PciConfigurationState from Snapshot: {}",
+                        e
+                    ))
+                })?;
+        let state: Option<PciRootPortState> = snapshot
+            .as_ref()
+            .map(|s| s.to_versioned_state())
+            .transpose()
+            .map_err(|e| {
+                PciRootPortError::RetrieveState(anyhow!(
+                    "Failed to get PciRootPortState from Snapshot: {}",
+                    e
+                ))
+            })?;
+        let msi_state: Option<MsiConfigState> =
+            vm_migration::versioned_state_from_id(snapshot.as_ref(), MSI_CONFIG_ID).map_err(
+                |e| {
+                    PciRootPortError::RetrieveMsiConfigState(anyhow!(
+                        "Failed to get MsiConfigState from Snapshot: {}",
+                        e
+                    ))
+                },
+            )?;
+        let restoring = pci_configuration_state.is_some();
+
+        let mut configuration = PciConfiguration::new(
+            ROOT_PORT_VENDOR_ID,
+            ROOT_PORT_DEVICE_ID,
+            0,
+            PciClassCode::BridgeDevice,
+            &PciBridgeSubclass::PciToPciBridge,
+            None,
+            PciHeaderType::Bridge,
+            0,
+            0,
+            None,
+            pci_configuration_state,
+        );
+
+        // The capabilities are part of the restored configuration.
+        let (pcie_cap_offset, msi_cap_offset) = if restoring {
+            let pcie_cap_offset = configuration.read_reg(0x34 / 4) as u8 as usize;
+            let msi_cap_offset = (configuration.read_reg(pcie_cap_offset / 4) >> 8) as u8 as usize;
+            (pcie_cap_offset, msi_cap_offset)
+        } else {
+            let link = PCI_EXP_LNK_SPEED | PCI_EXP_LNK_WIDTH;
+            let cap = PcieCap {
+                pcie_cap: PCI_EXP_FLAGS_VERSION | PCI_EXP_FLAGS_TYPE_ROOT_PORT | PCI_EXP_FLAGS_SLOT,
+                dev_cap: PCI_EXP_DEVCAP_RBER,
+                link_cap: link
+                    | PCI_EXP_LNKCAP_DLLLARC
+                    | (port_number as u32) << PCI_EXP_LNKCAP_PN_SHIFT,
+                link_sts: link as u16,
+                slot_cap: PCI_EXP_SLTCAP_HOTPLUG | (port_number as u32) << PCI_EXP_SLTCAP_PSN_SHIFT,
+                link_cap2: PCI_EXP_LNKCAP2_SLS,
+                link_ctl2: PCI_EXP_LNK_SPEED as u16,
+                ..Default::default()
+            };
+            let pcie_cap_offset = configuration
+                .add_capability(&cap)
+                .map_err(PciRootPortError::AddCapability)?;
+            let msi_cap_offset = configuration
+                .add_capability(&MsiCap {
+                    msg_ctl: MSI_CTL_64_BITS,
+                    ..Default::default()
+                })
+                .map_err(PciRootPortError::AddCapability)?;
+
+            configuration.write_reg(
+                BUS_NUMBERS_REG,
+                primary_bus as u32 | (secondary_bus as u32) << 8 | (secondary_bus as u32) << 16,
+            );
+            // No window is open until a device is attached.
+            configuration.write_reg(MEMORY_WINDOW_REG, 0x0000_fff0);
+            configuration.write_reg(PREF_MEMORY_WINDOW_REG, 0x0000_fff0);
+
+            (pcie_cap_offset, msi_cap_offset)
+        };
+
+        let msi_config = MsiConfig::new(MSI_CTL_64_BITS, interrupt_source_group.clone(), msi_state)
+            .map_err(PciRootPortError::CreateMsiConfig)?;
+
+        // The slot is powered, its attention indicator off.
+        let state = state.unwrap_or(PciRootPortState {
+            acs_control: 0,
+            slot_control: PCI_EXP_SLTCTL_ATTN_IND_OFF | PCI_EXP_SLTCTL_PWR_IND_ON,
+            slot_status: 0,
+            aer_uncor_mask: 0,
+            aer_uncor_severity: AER_UNCOR_SEVERITY_DEFAULT,
+            aer_cor_mask: AER_COR_MASK_DEFAULT,
+            aer_root_command: 0,
+            aer_root_status: 0,
+            aer_error_source: 0,
+        });
+
+        Ok(PciRootPort {
+            id,
+            configuration,
+            pcie_cap_offset,
+            msi_cap_offset,
+            msi_config,
+            interrupt_source_group,
+            acs_control: state.acs_control,
+            slot_control: state.slot_control,
+            slot_status: state.slot_status,
+            aer_uncor_mask: state.aer_uncor_mask,
+            aer_uncor_severity: state.aer_uncor_severity,
+            aer_cor_mask: state.aer_cor_mask,
+            aer_root_command: state.aer_root_command,
+            aer_root_status: state.aer_root_status,
+            aer_error_source: state.aer_error_source,
+            device: None,
+        })
+    }
+
+    /// Bus the device behind the port is attached to.
+    pub fn secondary_bus(&self) -> u8 {
+        (self.configuration.read_reg(BUS_NUMBERS_REG) >> 8) as u8
+    }
+
+    /// Device attached to the port, if any.
+    pub fn device(&self) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        self.device.clone()
+    }
+
+    /// Whether the slot is powered, the guest being able to turn it off. The
+    /// device behind a slot turned off can't be reached.
+    pub fn powered(&self) -> bool {
+        self.slot_control & PCI_EXP_SLTCTL_PCC == 0
+    }
+
+    /// Attach a device to the port, opening the memory windows of the port
+    /// over its BARs. The non-prefetchable memory window only spans the
+    /// first 4 GiB, the non-prefetchable BARs above it can't be forwarded.
+    pub fn attach_device(
+        &mut self,
+        device: Arc<Mutex<dyn PciDevice>>,
+        bars: &[PciBarConfiguration],
+    ) -> Result<(), PciRootPortError> {
+        let memory_bars = bars
+            .iter()
+            .filter(|b| b.region_type() != PciBarRegionType::IoRegion);
+        if let Some(bar) = memory_bars
+            .clone()
+            .find(|b| !bool::from(b.prefetchable()) && b.addr() + b.size() > 1 << 32)
+        {
+            return Err(PciRootPortError::UnforwardableBar(bar.idx()));
+        }
+
+        self.device = Some(device);
+
+        let window = |prefetchable: bool| {
+            let bars = memory_bars
+                .clone()
+                .filter(|b| bool::from(b.prefetchable()) == prefetchable);
+            let base = bars.clone().map(|b| b.addr()).min()?;
+            let end = bars.map(|b| b.addr() + b.size()).max()?;
+            Some((
+                base & !(WINDOW_ALIGNMENT - 1),
+                ((end + WINDOW_ALIGNMENT - 1) & !(WINDOW_ALIGNMENT - 1)) - 1,
+            ))
+        };
+
+        if let Some((base, limit)) = window(false) {
+            self.configuration.write_reg(
+                MEMORY_WINDOW_REG,
+                (base >> 16) as u32 & 0xfff0 | (limit as u32 & 0xfff0_0000),
+            );
+        }
+        if let Some((base, limit)) = window(true) {
+            self.configuration.write_reg(
+                PREF_MEMORY_WINDOW_REG,
+                (base >> 16) as u32 & 0xfff0 | (limit as u32 & 0xfff0_0000),
+            );
+            self.configuration
+                .write_reg(PREF_MEMORY_BASE_UPPER_REG, (base >> 32) as u32);
+            self.configuration
+                .write_reg(PREF_MEMORY_LIMIT_UPPER_REG, (limit >> 32) as u32);
+        }
+
+        Ok(())
+    }
+
+    /// Press the attention button of the slot, the guest being notified if
+    /// it enabled the event.
+    pub fn press_attention_button(&mut self) {
+        self.update_slot(self.slot_control, self.slot_status | PCI_EXP_SLTSTA_ABP);
+    }
+
+    /// Report an error signaled by the device behind the port, as if the
+    /// port received its error message. Returns whether the guest enabled
+    /// the reporting of such errors.
+    pub fn report_error(&mut self, severity: PciErrorSeverity) -> bool {
+        // The device is the only one on the secondary bus.
+        let requester_id = (self.secondary_bus() as u32) << 8;
+        let enable = match severity {
+            PciErrorSeverity::Correctable => {
+                if self.aer_root_status & AER_ROOT_STATUS_COR != 0 {
+                    self.aer_root_status |= AER_ROOT_STATUS_MULTI_COR;
+                } else {
+                    self.aer_root_status |= AER_ROOT_STATUS_COR;
+                    self.aer_error_source = (self.aer_error_source & 0xffff_0000) | requester_id;
+                }
+                1 << 0
+            }
+            PciErrorSeverity::NonFatal | PciErrorSeverity::Fatal => {
+                let fatal = severity == PciErrorSeverity::Fatal;
+                if self.aer_root_status & AER_ROOT_STATUS_UNCOR != 0 {
+                    self.aer_root_status |= AER_ROOT_STATUS_MULTI_UNCOR;
+                } else {
+                    self.aer_root_status |= AER_ROOT_STATUS_UNCOR;
+                    if fatal {
+                        self.aer_root_status |= AER_ROOT_STATUS_FIRST_FATAL;
+                    }
+                    self.aer_error_source = (self.aer_error_source & 0xffff) | requester_id << 16;
+                }
+                if fatal {
+                    self.aer_root_status |= AER_ROOT_STATUS_FATAL;
+                    1 << 2
+                } else {
+                    self.aer_root_status |= AER_ROOT_STATUS_NONFATAL;
+                    1 << 1
+                }
+            }
+        };
+
+        if self.aer_root_command & enable == 0 {
+            return false;
+        }
+        if self.msi_config.enabled() {
+            if let Err(e) = self.interrupt_source_group.trigger(0) {
+                error!("Failed to notify the error: {}", e);
+            }
+        }
+
+        true
+    }
+
+    fn read_aer_register(&self, idx: usize) -> u32 {
+        match idx {
+            0 => AER_CAP_HEADER,
+            AER_UNCOR_MASK => self.aer_uncor_mask,
+            AER_UNCOR_SEVERITY => self.aer_uncor_severity,
+            AER_COR_MASK => self.aer_cor_mask,
+            AER_ROOT_COMMAND => self.aer_root_command,
+            AER_ROOT_STATUS => self.aer_root_status,
+            AER_ERROR_SOURCE => self.aer_error_source,
+            // The port detects no error itself, and doesn't log any header.
+            _ => 0,
+        }
+    }
+
+    fn write_aer_register(&mut self, idx: usize, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        let mut value = [0u8; 4];
+        let mut mask = [0u8; 4];
+        if let Some(bytes) = value.get_mut(offset..offset + data.len()) {
+            bytes.copy_from_slice(data);
+            mask[offset..offset + data.len()].fill(0xff);
+        }
+        let value = u32::from_le_bytes(value);
+        let mask = u32::from_le_bytes(mask);
+        let update = |register: u32, writable: u32| {
+            let writable = writable & mask;
+            (register & !writable) | (value & writable)
+        };
+
+        match idx {
+            AER_UNCOR_MASK => self.aer_uncor_mask = update(self.aer_uncor_mask, AER_UNCOR_ERRORS),
+            AER_UNCOR_SEVERITY => {
+                self.aer_uncor_severity = update(self.aer_uncor_severity, AER_UNCOR_ERRORS)
+            }
+            AER_COR_MASK => self.aer_cor_mask = update(self.aer_cor_mask, AER_COR_ERRORS),
+            AER_ROOT_COMMAND => {
+                self.aer_root_command = update(self.aer_root_command, AER_ROOT_COMMAND_ENABLES)
+            }
+            // The events are cleared by writing 1 to them.
+            AER_ROOT_STATUS => self.aer_root_status &= !(value & mask & AER_ROOT_STATUS_EVENTS),
+            _ => {}
+        }
+    }
+
+    fn hotplug_interrupt_pending(&self) -> bool {
+        self.slot_control & PCI_EXP_SLTCTL_HPIE != 0
+            && HOTPLUG_EVENTS.iter().any(|(enable, event)| {
+                self.slot_control & enable != 0 && self.slot_status & event != 0
+            })
+    }
+
+    // The hot-plug interrupt is sent when an enabled event becomes pending
+    // while none was.
+    fn update_slot(&mut self, control: u16, status: u16) {
+        let pending = self.hotplug_interrupt_pending();
+        self.slot_control = control;
+        self.slot_status = status;
+
+        if !pending && self.hotplug_interrupt_pending() && self.msi_config.enabled() {
+            if let Err(e) = self.interrupt_source_group.trigger(0) {
+                error!("Failed to notify the hot-plug event: {}", e);
+            }
+        }
+    }
+
+    fn write_slot_register(&mut self, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        let mut value = [0u8; 4];
+        let mut mask = [0u8; 4];
+        if let Some(bytes) = value.get_mut(offset..offset + data.len()) {
+            bytes.copy_from_slice(data);
+            mask[offset..offset + data.len()].fill(0xff);
+        }
+        let value = u32::from_le_bytes(value) & u32::from_le_bytes(mask);
+        let mask = u32::from_le_bytes(mask);
+
+        let writable = mask as u16 & PCI_EXP_SLTCTL_WRITABLE;
+        let control = (self.slot_control & !writable) | (value as u16 & writable);
+        // The events are cleared by writing 1 to them.
+        let mut status = self.slot_status & !(value >> 16) as u16;
+
+        // The link goes up or down along with the power of the slot.
+        if self.device.is_some() && (control ^ self.slot_control) & PCI_EXP_SLTCTL_PCC != 0 {
+            status |= PCI_EXP_SLTSTA_DLLSC;
+        }
+
+        self.update_slot(control, status);
+    }
+
+    fn msi_registers(&self) -> std::ops::Range<usize> {
+        self.msi_cap_offset / 4..(self.msi_cap_offset + self.msi_config.size() as usize + 3) / 4
+    }
+
+    /// Detach the device from the port, returning whether it was attached.
+    pub fn detach_device(&mut self, device: &Arc<Mutex<dyn PciDevice>>) -> bool {
+        if self
+            .device
+            .as_ref()
+            .map_or(false, |d| Arc::ptr_eq(d, device))
+        {
+            self.device = None;
+            return true;
+        }
+
+        false
+    }
+
+    fn state(&self) -> PciRootPortState {
+        PciRootPortState {
+            acs_control: self.acs_control,
+            slot_control: self.slot_control,
+            slot_status: self.slot_status,
+            aer_uncor_mask: self.aer_uncor_mask,
+            aer_uncor_severity: self.aer_uncor_severity,
+            aer_cor_mask: self.aer_cor_mask,
+            aer_root_command: self.aer_root_command,
+            aer_root_status: self.aer_root_status,
+            aer_error_source: self.aer_error_source,
+        }
+    }
+}
+
+impl BusDevice for PciRootPort {}
+
+impl PciDevice for PciRootPort {
+    fn write_config_register(
+        &mut self,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> Option<Arc<Barrier>> {
+        if reg_idx == ACS_CAP_REG + 1 {
+            let offset = offset as usize;
+            let mut value = self.read_config_register(reg_idx).to_le_bytes();
+            if let Some(bytes) = value.get_mut(offset..offset + data.len()) {
+                bytes.copy_from_slice(data);
+            }
+            self.acs_control = (u32::from_le_bytes(value) >> 16) as u16 & ACS_SUPPORTED;
+            return None;
+        }
+        if reg_idx == (self.pcie_cap_offset + PCI_EXP_SLTCTL) / 4 {
+            self.write_slot_register(offset, data);
+            return None;
+        }
+        if (AER_CAP_REG..AER_CAP_REG + AER_CAP_DWORDS).contains(&reg_idx) {
+            self.write_aer_register(reg_idx - AER_CAP_REG, offset, data);
+            return None;
+        }
+        if self.msi_registers().contains(&reg_idx) {
+            self.msi_config
+                .update((reg_idx * 4 - self.msi_cap_offset) as u64 + offset, data);
+            return None;
+        }
+
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+        None
+    }
+
+    fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+        let msi_registers = self.msi_registers();
+        match reg_idx {
+            ACS_CAP_REG => ACS_CAP_HEADER,
+            r if r == ACS_CAP_REG + 1 => ACS_SUPPORTED as u32 | (self.acs_control as u32) << 16,
+            r if (AER_CAP_REG..AER_CAP_REG + AER_CAP_DWORDS).contains(&r) => {
+                self.read_aer_register(r - AER_CAP_REG)
+            }
+            // The slot is occupied once a device is attached.
+            r if r == (self.pcie_cap_offset + PCI_EXP_SLTCTL) / 4 => {
+                let mut status = self.slot_status;
+                if self.device.is_some() {
+                    status |= PCI_EXP_SLTSTA_PDS;
+                }
+                self.slot_control as u32 | (status as u32) << 16
+            }
+            r if msi_registers.contains(&r) => {
+                let cap = &self.msi_config.cap;
+                match r - msi_registers.start {
+                    0 => self.configuration.read_reg(r) & 0xffff | (cap.msg_ctl as u32) << 16,
+                    1 => cap.msg_addr_lo,
+                    2 => cap.msg_addr_hi,
+                    _ => cap.msg_data as u32,
+                }
+            }
+            _ => {
+                let mut value = self.configuration.read_reg(reg_idx);
+                // The link is up while the slot of the attached device is
+                // powered.
+                if self.device.is_some()
+                    && self.powered()
+                    && reg_idx == (self.pcie_cap_offset + PCI_EXP_LNKSTA) / 4
+                {
+                    value |= PCI_EXP_LNKSTA_DLLLA << 16;
+                }
+                value
+            }
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn id(&self) -> Option<String> {
+        Some(self.id.clone())
+    }
+}
+
+impl Pausable for PciRootPort {}
+
+impl Snapshottable for PciRootPort {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new_from_versioned_state(&self.state())?;
+        snapshot.add_snapshot(self.configuration.id(), self.configuration.snapshot()?);
+        snapshot.add_snapshot(self.msi_config.id(), self.msi_config.snapshot()?);
+
+        Ok(snapshot)
+    }
+}
+
+impl Transportable for PciRootPort {}
+impl Migratable for PciRootPort {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PciBarPrefetchable;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[derive(Default)]
+    struct TestInterrupt {
+        triggered: AtomicUsize,
+    }
+
+    impl InterruptSourceGroup for TestInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            self.triggered.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_port(id: &str, port_number: u8) -> (PciRootPort, Arc<TestInterrupt>) {
+        let interrupt = Arc::new(TestInterrupt::default());
+        let port = PciRootPort::new(
+            id.to_string(),
+            port_number,
+            0,
+            port_number,
+            interrupt.clone(),
+            None,
+        )
+        .unwrap();
+        (port, interrupt)
+    }
+
+    #[test]
+    fn test_root_port() {
+        assert_eq!(std::mem::size_of::<PcieCap>(), 0x3a);
+
+        let (mut port, _) = new_port("_rp0", 1);
+        assert_eq!(port.secondary_bus(), 1);
+        assert_eq!(port.read_config_register(BUS_NUMBERS_REG), 0x0001_0100);
+
+        // The guest is free to assign the buses again
+        port.write_config_register(BUS_NUMBERS_REG, 0, &0x0004_0400u32.to_le_bytes());
+        assert_eq!(port.secondary_bus(), 4);
+
+        let link_status_reg = (port.pcie_cap_offset + PCI_EXP_LNKSTA) / 4;
+        assert_eq!(port.read_config_register(link_status_reg) >> 16, 0x104);
+
+        let bars = [
+            PciBarConfiguration::default()
+                .set_address(0xc010_0000)
+                .set_size(0x8_0000)
+                .set_region_type(PciBarRegionType::Memory32BitRegion),
+            PciBarConfiguration::default()
+                .set_index(2)
+                .set_address(0x1_0000_0000)
+                .set_size(0x1000_0000)
+                .set_region_type(PciBarRegionType::Memory64BitRegion)
+                .set_prefetchable(PciBarPrefetchable::Prefetchable),
+            PciBarConfiguration::default()
+                .set_index(4)
+                .set_address(0xc020_0000)
+                .set_size(0x4000)
+                .set_region_type(PciBarRegionType::Memory64BitRegion),
+        ];
+        let device: Arc<Mutex<dyn PciDevice>> = Arc::new(Mutex::new(new_port("_rp1", 2).0));
+
+        // The non-prefetchable BARs above 4 GiB can't be forwarded
+        let unforwardable = [PciBarConfiguration::default()
+            .set_address(0x2_0000_0000)
+            .set_size(0x4000)
+            .set_region_type(PciBarRegionType::Memory64BitRegion)];
+        assert!(matches!(
+            port.attach_device(device.clone(), &unforwardable),
+            Err(PciRootPortError::UnforwardableBar(0))
+        ));
+        assert!(port.device().is_none());
+
+        port.attach_device(device.clone(), &bars).unwrap();
+        assert_eq!(port.read_config_register(MEMORY_WINDOW_REG), 0xc020_c010);
+        assert_eq!(
+            port.read_config_register(PREF_MEMORY_WINDOW_REG),
+            0x0ff1_0001
+        );
+        assert_eq!(port.read_config_register(PREF_MEMORY_BASE_UPPER_REG), 1);
+        assert_eq!(port.read_config_register(PREF_MEMORY_LIMIT_UPPER_REG), 1);
+        assert_eq!(
+            port.read_config_register(link_status_reg) >> 16,
+            0x104 | PCI_EXP_LNKSTA_DLLLA
+        );
+
+        // Only the supported ACS controls can be enabled
+        assert_eq!(port.read_config_register(ACS_CAP_REG), ACS_CAP_HEADER);
+        port.write_config_register(ACS_CAP_REG + 1, 2, &[0xff, 0xff]);
+        assert_eq!(port.read_config_register(ACS_CAP_REG + 1), 0x001f_001f);
+
+        assert!(port.detach_device(&device));
+        assert!(port.device().is_none());
+    }
+
+    #[test]
+    fn test_root_port_hotplug() {
+        let (mut port, interrupt) = new_port("_rp0", 1);
+        let device: Arc<Mutex<dyn PciDevice>> = Arc::new(Mutex::new(new_port("_rp1", 2).0));
+        port.attach_device(device, &[]).unwrap();
+
+        let slot_reg = (port.pcie_cap_offset + PCI_EXP_SLTCTL) / 4;
+        let link_status_reg = (port.pcie_cap_offset + PCI_EXP_LNKSTA) / 4;
+        assert_eq!(
+            port.read_config_register(slot_reg - 1) & PCI_EXP_SLTCAP_HOTPLUG,
+            PCI_EXP_SLTCAP_HOTPLUG
+        );
+        assert_eq!(
+            port.read_config_register(slot_reg),
+            0x01c0 | (PCI_EXP_SLTSTA_PDS as u32) << 16
+        );
+
+        // No interrupt is sent until the guest enables MSI and the event
+        port.press_attention_button();
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 0);
+        port.write_config_register(slot_reg, 2, &PCI_EXP_SLTSTA_ABP.to_le_bytes());
+        assert_eq!(port.read_config_register(slot_reg) >> 16, 0x40);
+
+        let msi_reg = port.msi_cap_offset / 4;
+        assert_eq!(
+            port.read_config_register(msi_reg) >> 16,
+            MSI_CTL_64_BITS as u32
+        );
+        port.write_config_register(msi_reg, 2, &1u16.to_le_bytes());
+        assert!(port.msi_config.enabled());
+        let control = PCI_EXP_SLTCTL_HPIE | PCI_EXP_SLTCTL_ABPE | PCI_EXP_SLTCTL_DLLSCE | 0x01c0;
+        port.write_config_register(slot_reg, 0, &control.to_le_bytes());
+
+        port.press_attention_button();
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 1);
+        assert_eq!(port.read_config_register(slot_reg) >> 16, 0x41);
+
+        // Powering the slot off takes the link down
+        port.write_config_register(slot_reg, 2, &PCI_EXP_SLTSTA_ABP.to_le_bytes());
+        port.write_config_register(slot_reg, 0, &(control | PCI_EXP_SLTCTL_PCC).to_le_bytes());
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 2);
+        assert!(!port.powered());
+        assert_eq!(
+            port.read_config_register(slot_reg) >> 16,
+            (PCI_EXP_SLTSTA_DLLSC | PCI_EXP_SLTSTA_PDS) as u32
+        );
+        assert_eq!(
+            port.read_config_register(link_status_reg) >> 16 & PCI_EXP_LNKSTA_DLLLA,
+            0
+        );
+    }
+
+    #[test]
+    fn test_root_port_aer() {
+        let (mut port, interrupt) = new_port("_rp0", 1);
+        let device: Arc<Mutex<dyn PciDevice>> = Arc::new(Mutex::new(new_port("_rp1", 2).0));
+        port.attach_device(device, &[]).unwrap();
+
+        assert_eq!(port.read_config_register(ACS_CAP_REG) >> 20, 0x140);
+        assert_eq!(port.read_config_register(AER_CAP_REG), AER_CAP_HEADER);
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_UNCOR_SEVERITY),
+            AER_UNCOR_SEVERITY_DEFAULT
+        );
+
+        // The error is latched but not notified until the guest enables its
+        // reporting
+        assert!(!port.report_error(PciErrorSeverity::Correctable));
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ROOT_STATUS),
+            AER_ROOT_STATUS_COR
+        );
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ERROR_SOURCE),
+            0x100
+        );
+
+        let msi_reg = port.msi_cap_offset / 4;
+        port.write_config_register(msi_reg, 2, &1u16.to_le_bytes());
+        port.write_config_register(AER_CAP_REG + AER_ROOT_COMMAND, 0, &[0xff]);
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ROOT_COMMAND),
+            AER_ROOT_COMMAND_ENABLES
+        );
+
+        assert!(port.report_error(PciErrorSeverity::Correctable));
+        assert!(port.report_error(PciErrorSeverity::Fatal));
+        assert_eq!(interrupt.triggered.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ROOT_STATUS),
+            AER_ROOT_STATUS_COR
+                | AER_ROOT_STATUS_MULTI_COR
+                | AER_ROOT_STATUS_UNCOR
+                | AER_ROOT_STATUS_FIRST_FATAL
+                | AER_ROOT_STATUS_FATAL
+        );
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ERROR_SOURCE),
+            0x0100_0100
+        );
+
+        // The events are cleared by writing 1 to them
+        port.write_config_register(
+            AER_CAP_REG + AER_ROOT_STATUS,
+            0,
+            &(AER_ROOT_STATUS_COR | AER_ROOT_STATUS_MULTI_COR).to_le_bytes(),
+        );
+        assert_eq!(
+            port.read_config_register(AER_CAP_REG + AER_ROOT_STATUS),
+            AER_ROOT_STATUS_UNCOR | AER_ROOT_STATUS_FIRST_FATAL | AER_ROOT_STATUS_FATAL
+        );
+
+        let mut aer = [0u32; 24];
+        aer[AER_UNCOR_STATUS] = 1 << 18;
+        assert_eq!(
+            PciErrorSeverity::uncorrectable(Some(&aer)),
+            PciErrorSeverity::NonFatal
+        );
+        aer[AER_UNCOR_SEVERITY] = AER_UNCOR_SEVERITY_DEFAULT;
+        assert_eq!(
+            PciErrorSeverity::uncorrectable(Some(&aer)),
+            PciErrorSeverity::Fatal
+        );
+        aer[AER_UNCOR_MASK] = 1 << 18;
+        assert_eq!(
+            PciErrorSeverity::uncorrectable(Some(&aer)),
+            PciErrorSeverity::NonFatal
+        );
+    }
+}
@@ -6,8 +6,8 @@
 use crate::msi::{MsiConfigState, MSI_CONFIG_ID};
 use crate::msix::MsixConfigState;
 use crate::{
-    msi_num_enabled_vectors, BarReprogrammingParams, MsiCap, MsiConfig, MsixCap, MsixConfig,
-    PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciBdf, PciCapabilityId,
+    msi_num_enabled_vectors, BarReprogrammingParams, BarResizingParams, MsiCap, MsiConfig, MsixCap,
+    MsixConfig, PciBarConfiguration, PciBarPrefetchable, PciBarRegionType, PciBdf, PciCapabilityId,
     PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciExpressCapabilityId,
     PciHeaderType, PciSubclass, MSIX_CONFIG_ID, MSIX_TABLE_ENTRY_SIZE, PCI_CONFIGURATION_ID,
 };
@@ -411,6 +411,17 @@ pub(crate) struct ConfigPatch {
     patch: u32,
 }
 
+// BAR exposed through the Resizable BAR capability
+pub(crate) struct ResizableBar {
+    bar_index: u32,
+    // Index of the capability register, followed by the control register
+    reg_idx: usize,
+    // Sizes the BAR can take, bit n standing for 1 MiB << n
+    sizes: u32,
+    // Current size, encoded as in the control register
+    size: u32,
+}
+
 pub(crate) struct VfioCommon {
     pub(crate) configuration: PciConfiguration,
     pub(crate) mmio_regions: Vec<MmioRegion>,
@@ -419,6 +430,9 @@ pub(crate) struct VfioCommon {
     pub(crate) legacy_interrupt_group: Option<Arc<dyn InterruptSourceGroup>>,
     pub(crate) vfio_wrapper: Arc<dyn Vfio>,
     pub(crate) patches: HashMap<usize, ConfigPatch>,
+    pub(crate) resizable_bars: Vec<ResizableBar>,
+    // Whether the non-prefetchable 64-bit BARs are placed below 4 GiB
+    pub(crate) non_prefetchable_bars_below_4g: bool,
 }
 
 impl VfioCommon {
@@ -465,6 +479,8 @@ impl VfioCommon {
             legacy_interrupt_group,
             vfio_wrapper,
             patches: HashMap::new(),
+            resizable_bars: Vec::new(),
+            non_prefetchable_bars_below_4g: false,
         };
 
         let state: Option<VfioCommonState> = snapshot
@@ -556,13 +572,16 @@ impl VfioCommon {
                         base,
                         size,
                         type_,
-                        ..
+                        prefetchable: restored_prefetchable,
                     } = resource
                     {
                         if *index == bar_id as usize {
                             restored_bar_addr = Some(GuestAddress(*base));
                             region_size = *size;
                             region_type = PciBarRegionType::from(*type_);
+                            if *restored_prefetchable {
+                                prefetchable = PciBarPrefetchable::Prefetchable;
+                            }
                             break;
                         }
                     }
@@ -695,14 +714,29 @@ impl VfioCommon {
                     // We need do some fixup to keep MMIO RW region and msix cap region page size
                     // aligned.
                     region_size = self.fixup_msix_region(bar_id, region_size);
-                    mmio_allocator
-                        .allocate(
-                            restored_bar_addr,
-                            region_size,
-                            // SAFETY: FFI call. Trivially safe.
-                            Some(unsafe { sysconf(_SC_PAGESIZE) as GuestUsize }),
-                        )
-                        .ok_or(PciDeviceError::IoAllocationFailed(region_size))?
+                    // A resizable BAR is naturally aligned, so that the guest
+                    // doesn't have to move it for it to be resized.
+                    let alignment = if self.resizable_bars.iter().any(|b| b.bar_index == bar_id) {
+                        region_size
+                    } else {
+                        // SAFETY: FFI call. Trivially safe.
+                        unsafe { sysconf(_SC_PAGESIZE) as GuestUsize }
+                    };
+                    if self.non_prefetchable_bars_below_4g && !bool::from(prefetchable) {
+                        allocator
+                            .lock()
+                            .unwrap()
+                            .allocate_mmio_hole_addresses(
+                                restored_bar_addr,
+                                region_size,
+                                Some(alignment),
+                            )
+                            .ok_or(PciDeviceError::IoAllocationFailed(region_size))?
+                    } else {
+                        mmio_allocator
+                            .allocate(restored_bar_addr, region_size, Some(alignment))
+                            .ok_or(PciDeviceError::IoAllocationFailed(region_size))?
+                    }
                 }
             };
 
@@ -911,17 +945,12 @@ impl VfioCommon {
             let cap_next: u16 = ((ext_cap_hdr >> 20) & 0xfff) as u16;
 
             match PciExpressCapabilityId::from(cap_id) {
+                PciExpressCapabilityId::ResizeableBar
+                    if self.parse_resizable_bars(current_offset) => {}
                 PciExpressCapabilityId::AlternativeRoutingIdentificationInterpretation
                 | PciExpressCapabilityId::ResizeableBar
                 | PciExpressCapabilityId::SingleRootIoVirtualization => {
-                    let reg_idx = (current_offset / 4) as usize;
-                    self.patches.insert(
-                        reg_idx,
-                        ConfigPatch {
-                            mask: 0x0000_ffff,
-                            patch: PciExpressCapabilityId::NullCapability as u32,
-                        },
-                    );
+                    self.hide_extended_capability(current_offset);
                 }
                 _ => {}
             }
@@ -934,6 +963,131 @@ impl VfioCommon {
         }
     }
 
+    fn hide_extended_capability(&mut self, offset: u32) {
+        self.patches.insert(
+            (offset / 4) as usize,
+            ConfigPatch {
+                mask: 0x0000_ffff,
+                patch: PciExpressCapabilityId::NullCapability as u32,
+            },
+        );
+    }
+
+    // Find the BARs of the Resizable BAR capability, which is exposed to the
+    // guest unless one of these BARs holds the MSI-X tables. The BARs can't be
+    // made larger than they are on the host, as they couldn't be backed by the
+    // device otherwise.
+    fn parse_resizable_bars(&mut self, offset: u32) -> bool {
+        let ctrl = self.vfio_wrapper.read_config_dword(offset + PCI_REBAR_CTRL);
+        let nbars = (ctrl & PCI_REBAR_CTRL_NBAR_MASK) >> PCI_REBAR_CTRL_NBAR_SHIFT;
+
+        let mut resizable_bars = Vec::new();
+        for i in 0..nbars {
+            let entry = offset + i * PCI_REBAR_ENTRY_SIZE;
+            let cap = self.vfio_wrapper.read_config_dword(entry + PCI_REBAR_CAP);
+            let ctrl = self.vfio_wrapper.read_config_dword(entry + PCI_REBAR_CTRL);
+            let bar_index = ctrl & PCI_REBAR_CTRL_BAR_IDX;
+            let size = (ctrl & PCI_REBAR_CTRL_BAR_SIZE_MASK) >> PCI_REBAR_CTRL_BAR_SIZE_SHIFT;
+
+            if let Some(msix) = self.interrupt.msix.as_ref() {
+                if bar_index == msix.cap.table_bir() || bar_index == msix.cap.pba_bir() {
+                    return false;
+                }
+            }
+
+            let host_sizes = 1u32.checked_shl(size + 1).map_or(u32::MAX, |s| s - 1);
+            resizable_bars.push(ResizableBar {
+                bar_index,
+                reg_idx: ((entry + PCI_REBAR_CAP) / 4) as usize,
+                sizes: (cap >> PCI_REBAR_CAP_SIZES_SHIFT) & host_sizes,
+                size,
+            });
+        }
+
+        self.resizable_bars = resizable_bars;
+        !self.resizable_bars.is_empty()
+    }
+
+    /// Hide the Resizable BAR capability, for the devices which can't follow
+    /// the resizing of their BARs.
+    pub(crate) fn hide_resizable_bars(&mut self) {
+        if let Some(bar) = self.resizable_bars.first() {
+            let offset = (bar.reg_idx * 4) as u32 - PCI_REBAR_CAP;
+            self.hide_extended_capability(offset);
+        }
+        self.resizable_bars.clear();
+    }
+
+    fn read_resizable_bar_register(&self, reg_idx: usize) -> Option<u32> {
+        let nbars = self.resizable_bars.len() as u32;
+        self.resizable_bars.iter().enumerate().find_map(|(i, bar)| {
+            if reg_idx == bar.reg_idx {
+                Some(bar.sizes << PCI_REBAR_CAP_SIZES_SHIFT)
+            } else if reg_idx == bar.reg_idx + 1 {
+                // The number of BARs is only reported for the first one
+                let nbars = if i == 0 {
+                    nbars << PCI_REBAR_CTRL_NBAR_SHIFT
+                } else {
+                    0
+                };
+                Some(bar.bar_index | nbars | (bar.size << PCI_REBAR_CTRL_BAR_SIZE_SHIFT))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub(crate) fn detect_bar_resizing(
+        &self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarResizingParams> {
+        if data.len() != 4 {
+            return None;
+        }
+
+        let bar = self
+            .resizable_bars
+            .iter()
+            .find(|b| reg_idx == b.reg_idx + 1)?;
+        let value = LittleEndian::read_u32(data);
+        let size = (value & PCI_REBAR_CTRL_BAR_SIZE_MASK) >> PCI_REBAR_CTRL_BAR_SIZE_SHIFT;
+        // Only the sizes advertised to the guest can be selected
+        if size == bar.size || bar.sizes.checked_shr(size).unwrap_or(0) & 1 == 0 {
+            return None;
+        }
+
+        let region = self
+            .mmio_regions
+            .iter()
+            .find(|r| r.index == bar.bar_index)?;
+
+        Some(BarResizingParams {
+            base: region.start.raw_value(),
+            old_len: region.length,
+            new_len: 1 << (size + PCI_REBAR_MIN_SIZE_SHIFT),
+            region_type: region.type_,
+        })
+    }
+
+    // Update the size of a resizable BAR, once its new location has been
+    // found.
+    fn resize_bar(&mut self, bar_index: u32, addr: u64, size: u64) -> Result<(), io::Error> {
+        self.configuration
+            .resize_bar(bar_index as usize, addr, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        if let Some(bar) = self
+            .resizable_bars
+            .iter_mut()
+            .find(|b| b.bar_index == bar_index)
+        {
+            bar.size = size.trailing_zeros() - PCI_REBAR_MIN_SIZE_SHIFT;
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn enable_intx(&mut self) -> Result<(), VfioPciError> {
         if let Some(intx) = &mut self.interrupt.intx {
             if !intx.enabled {
@@ -1162,6 +1316,16 @@ impl VfioCommon {
             return None;
         }
 
+        // The Resizable BAR capability is emulated, the resizing itself being
+        // handled when detected (see detect_bar_resizing()).
+        if self
+            .resizable_bars
+            .iter()
+            .any(|b| reg_idx == b.reg_idx || reg_idx == b.reg_idx + 1)
+        {
+            return None;
+        }
+
         let reg = (reg_idx * PCI_CONFIG_REGISTER_SIZE) as u64;
 
         // If the MSI or MSI-X capabilities are accessed, we need to
@@ -1209,6 +1373,10 @@ impl VfioCommon {
             return self.configuration.read_reg(reg_idx);
         }
 
+        if let Some(value) = self.read_resizable_bar_register(reg_idx) {
+            return value;
+        }
+
         if let Some(id) = self.get_msix_cap_idx() {
             let msix = self.interrupt.msix.as_mut().unwrap();
             if reg_idx * 4 == id + 4 {
@@ -1331,7 +1499,13 @@ pub struct VfioPciDevice {
     container: Arc<VfioContainer>,
     common: VfioCommon,
     iommu_attached: bool,
+    // Containers of the peer devices allowed to reach the BARs
+    p2p_containers: Vec<Arc<VfioContainer>>,
     memory_slot: Arc<dyn Fn() -> u32 + Send + Sync>,
+    // Signaled by the host when an error is detected on the device
+    error_evt: Option<EventFd>,
+    // Whether the host signals the errors through the VFIO error interrupt
+    error_irq: bool,
 }
 
 impl VfioPciDevice {
@@ -1363,6 +1537,13 @@ impl VfioPciDevice {
             vm_migration::snapshot_from_id(snapshot.as_ref(), VFIO_COMMON_ID),
         )?;
 
+        let error_evt = EventFd::new(0)
+            .map_err(|e| warn!("Failed creating the error eventfd: {}", e))
+            .ok();
+        let error_irq = error_evt
+            .as_ref()
+            .map_or(false, |evt| Self::enable_error_irq(&device, evt));
+
         let vfio_pci_device = VfioPciDevice {
             id,
             vm: vm.clone(),
@@ -1370,12 +1551,95 @@ impl VfioPciDevice {
             container,
             common,
             iommu_attached,
+            p2p_containers: Vec::new(),
             memory_slot,
+            error_evt,
+            error_irq,
         };
 
         Ok(vfio_pci_device)
     }
 
+    fn enable_error_irq(device: &VfioDevice, error_evt: &EventFd) -> bool {
+        match device.get_irq_info(VFIO_PCI_ERR_IRQ_INDEX) {
+            Some(irq_info) if irq_info.count > 0 => {}
+            _ => return false,
+        }
+
+        if let Err(e) = device.enable_irq(VFIO_PCI_ERR_IRQ_INDEX, vec![error_evt]) {
+            warn!("Failed enabling the error interrupt: {}", e);
+            return false;
+        }
+
+        true
+    }
+
+    /// Place the non-prefetchable 64-bit BARs below 4 GiB rather than in the
+    /// 64-bit MMIO area, for them to be forwarded by the memory window of a
+    /// root port. Must be called before the BARs are allocated.
+    pub fn place_non_prefetchable_bars_below_4g(&mut self) {
+        self.common.non_prefetchable_bars_below_4g = true;
+    }
+
+    /// Eventfd signaled when the host detects an uncorrectable error on the
+    /// device, and when the device is dropped. The eventfd is blocking.
+    pub fn error_notifier(&self) -> Option<EventFd> {
+        self.error_evt.as_ref().and_then(|evt| evt.try_clone().ok())
+    }
+
+    fn aer_offset(&self) -> Option<u32> {
+        let mut offset = PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET;
+        loop {
+            let header = self.common.vfio_wrapper.read_config_dword(offset);
+            if header & 0xffff == PciExpressCapabilityId::AdvancedErrorReporting as u32 {
+                return Some(offset);
+            }
+
+            let next = (header >> 20) & 0xfff;
+            // The next capability can't be found before the current one, and
+            // the capabilities are dword aligned.
+            if next <= offset || next & 0x3 != 0 {
+                return None;
+            }
+            offset = next;
+        }
+    }
+
+    /// Content of the AER capability of the device, if it has one.
+    pub fn aer_registers(&self) -> Option<[u32; PCI_AER_CAPABILITY_DWORDS]> {
+        let offset = self.aer_offset()?;
+        let mut registers = [0u32; PCI_AER_CAPABILITY_DWORDS];
+        for (i, register) in registers.iter_mut().enumerate() {
+            *register = self
+                .common
+                .vfio_wrapper
+                .read_config_dword(offset + i as u32 * 4);
+        }
+
+        Some(registers)
+    }
+
+    /// Content of the AER capability of the device if an unmasked
+    /// correctable error is latched, optionally clearing the error. The host
+    /// doesn't signal the correctable errors, which have to be polled.
+    pub fn correctable_errors(&self, clear: bool) -> Option<[u32; PCI_AER_CAPABILITY_DWORDS]> {
+        let offset = self.aer_offset()?;
+        let wrapper = &self.common.vfio_wrapper;
+        let status = wrapper.read_config_dword(offset + PCI_AER_COR_STATUS)
+            & !wrapper.read_config_dword(offset + PCI_AER_COR_MASK);
+        if status == 0 {
+            return None;
+        }
+
+        let registers = self.aer_registers();
+        // The status bits are write 1 to clear.
+        if clear {
+            wrapper.write_config_dword(offset + PCI_AER_COR_STATUS, status);
+        }
+
+        registers
+    }
+
     pub fn iommu_attached(&self) -> bool {
         self.iommu_attached
     }
@@ -1476,6 +1740,11 @@ impl VfioPciDevice {
         let fd = self.device.as_raw_fd();
 
         for region in self.common.mmio_regions.iter_mut() {
+            // Skip the regions already mapped, when remapping a resized BAR
+            if !region.user_memory_regions.is_empty() {
+                continue;
+            }
+
             let region_flags = self.device.get_region_flags(region.index);
             if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
                 let mut prot = 0;
@@ -1504,16 +1773,29 @@ impl VfioPciDevice {
                     }
                 }
 
-                let mmap_size = self.device.get_region_size(region.index);
+                let mut mmap_size = self.device.get_region_size(region.index);
+                // A resizable BAR may be smaller than the region backing it
+                if self
+                    .common
+                    .resizable_bars
+                    .iter()
+                    .any(|b| b.bar_index == region.index)
+                {
+                    mmap_size = std::cmp::min(mmap_size, region.length);
+                }
                 let mmap_offset = self.device.get_region_offset(region.index);
 
-                let sparse_areas = Self::generate_sparse_areas(
+                let mut sparse_areas = Self::generate_sparse_areas(
                     &caps,
                     region.index,
                     region.start.0,
                     mmap_size,
                     self.common.interrupt.msix.as_ref(),
                 )?;
+                sparse_areas.retain(|area| area.offset < mmap_size);
+                for area in sparse_areas.iter_mut() {
+                    area.size = std::cmp::min(area.size, mmap_size - area.offset);
+                }
 
                 for area in sparse_areas.iter() {
                     // SAFETY: FFI call with correct arguments
@@ -1568,6 +1850,18 @@ impl VfioPciDevice {
                     self.vm
                         .create_user_memory_region(mem_region)
                         .map_err(VfioPciError::CreateUserMemoryRegion)?;
+
+                    // Let the peer devices reach the BAR through its guest
+                    // address.
+                    for container in self.p2p_containers.iter() {
+                        container
+                            .vfio_dma_map(
+                                user_memory_region.start,
+                                user_memory_region.size,
+                                user_memory_region.host_addr,
+                            )
+                            .map_err(VfioPciError::DmaMap)?;
+                    }
                 }
             }
         }
@@ -1575,38 +1869,96 @@ impl VfioPciDevice {
         Ok(())
     }
 
-    pub fn unmap_mmio_regions(&mut self) {
+    /// Container the device is attached to.
+    pub fn container(&self) -> &Arc<VfioContainer> {
+        &self.container
+    }
+
+    /// Map the BARs into the container of a peer device, allowing it to reach
+    /// them through their guest addresses, including after they're moved.
+    pub fn add_p2p_container(&mut self, container: Arc<VfioContainer>) -> Result<(), VfioPciError> {
         for region in self.common.mmio_regions.iter() {
             for user_memory_region in region.user_memory_regions.iter() {
-                // Remove region
-                let r = self.vm.make_user_memory_region(
-                    user_memory_region.slot,
-                    user_memory_region.start,
-                    user_memory_region.size,
-                    user_memory_region.host_addr,
-                    false,
-                    false,
-                );
+                container
+                    .vfio_dma_map(
+                        user_memory_region.start,
+                        user_memory_region.size,
+                        user_memory_region.host_addr,
+                    )
+                    .map_err(VfioPciError::DmaMap)?;
+            }
+        }
+
+        self.p2p_containers.push(container);
+        Ok(())
+    }
 
-                if let Err(e) = self.vm.remove_user_memory_region(r) {
-                    error!("Could not remove the userspace memory region: {}", e);
+    /// Unmap the BARs from the container of a peer device being removed.
+    pub fn remove_p2p_container(&mut self, container: &Arc<VfioContainer>) {
+        let Some(index) = self
+            .p2p_containers
+            .iter()
+            .position(|c| Arc::ptr_eq(c, container))
+        else {
+            return;
+        };
+
+        let container = self.p2p_containers.remove(index);
+        for region in self.common.mmio_regions.iter() {
+            for user_memory_region in region.user_memory_regions.iter() {
+                if let Err(e) =
+                    container.vfio_dma_unmap(user_memory_region.start, user_memory_region.size)
+                {
+                    error!("Could not DMA unmap the region {}: {}", region.index, e);
                 }
+            }
+        }
+    }
 
-                // SAFETY: FFI call with correct arguments
-                let ret = unsafe {
-                    libc::munmap(
-                        user_memory_region.host_addr as *mut libc::c_void,
-                        user_memory_region.size as usize,
-                    )
-                };
-                if ret != 0 {
-                    error!(
-                        "Could not unmap region {}, error:{}",
-                        region.index,
-                        io::Error::last_os_error()
-                    );
+    pub fn unmap_mmio_regions(&mut self) {
+        for region in self.common.mmio_regions.iter() {
+            self.unmap_mmio_region(region);
+        }
+    }
+
+    fn unmap_mmio_region(&self, region: &MmioRegion) {
+        for user_memory_region in region.user_memory_regions.iter() {
+            // Remove region
+            let r = self.vm.make_user_memory_region(
+                user_memory_region.slot,
+                user_memory_region.start,
+                user_memory_region.size,
+                user_memory_region.host_addr,
+                false,
+                false,
+            );
+
+            if let Err(e) = self.vm.remove_user_memory_region(r) {
+                error!("Could not remove the userspace memory region: {}", e);
+            }
+
+            for container in self.p2p_containers.iter() {
+                if let Err(e) =
+                    container.vfio_dma_unmap(user_memory_region.start, user_memory_region.size)
+                {
+                    error!("Could not DMA unmap the region {}: {}", region.index, e);
                 }
             }
+
+            // SAFETY: FFI call with correct arguments
+            let ret = unsafe {
+                libc::munmap(
+                    user_memory_region.host_addr as *mut libc::c_void,
+                    user_memory_region.size as usize,
+                )
+            };
+            if ret != 0 {
+                error!(
+                    "Could not unmap region {}, error:{}",
+                    region.index,
+                    io::Error::last_os_error()
+                );
+            }
         }
     }
 
@@ -1639,6 +1991,17 @@ impl Drop for VfioPciDevice {
     fn drop(&mut self) {
         self.unmap_mmio_regions();
 
+        if let Some(error_evt) = &self.error_evt {
+            if self.error_irq {
+                if let Err(e) = self.device.disable_irq(VFIO_PCI_ERR_IRQ_INDEX) {
+                    error!("Could not disable the error interrupt: {}", e);
+                }
+            }
+            // Wake up the thread waiting for the errors, so that it can
+            // find out the device is gone.
+            let _ = error_evt.write(1);
+        }
+
         if let Some(msix) = &self.common.interrupt.msix {
             if msix.bar.enabled() {
                 self.common.disable_msix();
@@ -1673,6 +2036,11 @@ const PCI_CONFIG_BAR_OFFSET: u32 = 0x10;
 const PCI_CONFIG_CAPABILITY_OFFSET: u32 = 0x34;
 // Extended capabilities register offset in the PCI config space.
 const PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET: u32 = 0x100;
+// Size of the AER capability, including the root port registers
+pub const PCI_AER_CAPABILITY_DWORDS: usize = 24;
+// Offsets of the correctable error registers in the AER capability
+const PCI_AER_COR_STATUS: u32 = 0x10;
+const PCI_AER_COR_MASK: u32 = 0x14;
 // IO BAR when first BAR bit is 1.
 const PCI_CONFIG_IO_BAR: u32 = 0x1;
 // 64-bit memory bar flag.
@@ -1689,6 +2057,22 @@ const PCI_HEADER_TYPE_REG_INDEX: usize = 3;
 const PCI_CONFIG_BAR0_INDEX: usize = 4;
 // PCI ROM expansion BAR register index
 const PCI_ROM_EXP_BAR_INDEX: usize = 12;
+// Offsets of the capability and control registers of the first BAR in the
+// Resizable BAR capability, each BAR taking 8 bytes.
+const PCI_REBAR_CAP: u32 = 0x4;
+const PCI_REBAR_CTRL: u32 = 0x8;
+const PCI_REBAR_ENTRY_SIZE: u32 = 0x8;
+// Supported sizes in the Resizable BAR capability register, bit n standing
+// for 1 MiB << n.
+const PCI_REBAR_CAP_SIZES_SHIFT: u32 = 4;
+// Fields of the Resizable BAR control register
+const PCI_REBAR_CTRL_BAR_IDX: u32 = 0x7;
+const PCI_REBAR_CTRL_NBAR_MASK: u32 = 0xe0;
+const PCI_REBAR_CTRL_NBAR_SHIFT: u32 = 5;
+const PCI_REBAR_CTRL_BAR_SIZE_MASK: u32 = 0x3f00;
+const PCI_REBAR_CTRL_BAR_SIZE_SHIFT: u32 = 8;
+// Smallest size of a resizable BAR (1 MiB)
+const PCI_REBAR_MIN_SIZE_SHIFT: u32 = 20;
 
 impl PciDevice for VfioPciDevice {
     fn allocate_bars(
@@ -1732,6 +2116,10 @@ impl PciDevice for VfioPciDevice {
             .detect_bar_reprogramming(reg_idx, data)
     }
 
+    fn detect_bar_resizing(&mut self, reg_idx: usize, data: &[u8]) -> Option<BarResizingParams> {
+        self.common.detect_bar_resizing(reg_idx, data)
+    }
+
     fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
         self.common.read_bar(base, offset, data)
     }
@@ -1760,6 +2148,12 @@ impl PciDevice for VfioPciDevice {
                         .remove_user_memory_region(old_mem_region)
                         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
+                    for container in self.p2p_containers.iter() {
+                        container
+                            .vfio_dma_unmap(user_memory_region.start, user_memory_region.size)
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    }
+
                     // Update the user memory region with the correct start address.
                     if new_base > old_base {
                         user_memory_region.start += new_base - old_base;
@@ -1780,6 +2174,16 @@ impl PciDevice for VfioPciDevice {
                     self.vm
                         .create_user_memory_region(new_mem_region)
                         .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+                    for container in self.p2p_containers.iter() {
+                        container
+                            .vfio_dma_map(
+                                user_memory_region.start,
+                                user_memory_region.size,
+                                user_memory_region.host_addr,
+                            )
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    }
                 }
             }
         }
@@ -1787,6 +2191,34 @@ impl PciDevice for VfioPciDevice {
         Ok(())
     }
 
+    fn resize_bar(&mut self, old_base: u64, new_base: u64, new_len: u64) -> Result<(), io::Error> {
+        let index = self
+            .common
+            .mmio_regions
+            .iter()
+            .position(|r| r.start.raw_value() == old_base)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no region at 0x{old_base:x}"),
+                )
+            })?;
+
+        // The mappings of the BAR are created again with its new size
+        let region = self.common.mmio_regions[index].clone();
+        self.unmap_mmio_region(&region);
+
+        let region = &mut self.common.mmio_regions[index];
+        region.start = GuestAddress(new_base);
+        region.length = new_len;
+        region.user_memory_regions.clear();
+        let bar_index = region.index;
+        self.common.resize_bar(bar_index, new_base, new_len)?;
+
+        self.map_mmio_regions()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
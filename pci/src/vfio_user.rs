@@ -87,7 +87,7 @@ impl VfioUserPciDevice {
             client: client.clone(),
         };
 
-        let common = VfioCommon::new(
+        let mut common = VfioCommon::new(
             msi_interrupt_manager,
             legacy_interrupt_group,
             Arc::new(vfio_wrapper) as Arc<dyn Vfio>,
@@ -96,6 +96,8 @@ impl VfioUserPciDevice {
             vm_migration::snapshot_from_id(snapshot.as_ref(), VFIO_COMMON_ID),
         )
         .map_err(VfioUserPciDeviceError::CreateVfioCommon)?;
+        // The BARs are only resized for the VFIO devices
+        common.hide_resizable_bars();
 
         Ok(Self {
             id,
@@ -0,0 +1,654 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Export of an emulated PCI device to another VMM through vfio-user.
+//!
+//! The device is exposed as a set of vfio-user regions, one per BAR plus the
+//! PCI configuration space, the accesses to these regions being forwarded to
+//! the device. The memory of the guest is shared by the client through DMA
+//! mappings, which are collected into the guest memory handed to the device,
+//! while the MSI-X vectors are delivered through the eventfds provided by the
+//! client.
+
+use crate::{
+    PciBarConfiguration, PciBarRegionType, PciCapabilityId, PciDevice, MSIX_TABLE_ENTRY_SIZE,
+};
+use std::fs::File;
+use std::io;
+use std::ops::DerefMut;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use vfio_bindings::bindings::vfio::*;
+use vfio_user::{DmaMapFlags, DmaUnmapFlags, IrqInfo, Server, ServerBackend, ServerRegion};
+use vm_device::interrupt::{
+    InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
+    MsiIrqGroupConfig,
+};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::mmap::MmapRegion;
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryAtomic, GuestRegionMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+// PCI Express configuration space
+const PCI_CONFIG_SPACE_SIZE: u64 = 0x1000;
+
+// Pointer to the first capability
+const PCI_CAPABILITY_LIST: usize = 0x34;
+// Offset of the vector control in an MSI-X table entry
+const MSIX_TABLE_ENTRY_VECTOR_CTL: u64 = 12;
+
+#[derive(Debug, Error)]
+pub enum VfioUserPciServerError {
+    #[error("Failed to create the vfio-user server: {0}")]
+    CreateServer(#[source] vfio_user::Error),
+    #[error("Failed to run the vfio-user server: {0}")]
+    RunServer(#[source] vfio_user::Error),
+}
+
+/// Interrupts of an exported device, triggered through the eventfds the
+/// client provides for the MSI-X vectors.
+#[derive(Default)]
+pub struct VfioUserServerInterrupts {
+    vectors: Arc<Mutex<Vec<Option<EventFd>>>>,
+}
+
+impl VfioUserServerInterrupts {
+    fn set_vectors(&self, start: u32, fds: Vec<File>) {
+        let mut vectors = self.vectors.lock().unwrap();
+        // Disabling the interrupts drops all the eventfds
+        if fds.is_empty() {
+            vectors.clear();
+            return;
+        }
+
+        for (i, fd) in fds.into_iter().enumerate() {
+            let vector = start as usize + i;
+            if vectors.len() <= vector {
+                vectors.resize_with(vector + 1, || None);
+            }
+            // SAFETY: the file is an eventfd sent by the client, which we own
+            vectors[vector] = Some(unsafe { EventFd::from_raw_fd(fd.into_raw_fd()) });
+        }
+    }
+}
+
+impl InterruptManager for VfioUserServerInterrupts {
+    type GroupConfig = MsiIrqGroupConfig;
+
+    fn create_group(&self, config: Self::GroupConfig) -> io::Result<Arc<dyn InterruptSourceGroup>> {
+        Ok(Arc::new(VfioUserServerIrqGroup {
+            vectors: self.vectors.clone(),
+            base: config.base,
+        }))
+    }
+
+    fn destroy_group(&self, _group: Arc<dyn InterruptSourceGroup>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct VfioUserServerIrqGroup {
+    vectors: Arc<Mutex<Vec<Option<EventFd>>>>,
+    base: InterruptIndex,
+}
+
+impl InterruptSourceGroup for VfioUserServerIrqGroup {
+    fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+        match self
+            .vectors
+            .lock()
+            .unwrap()
+            .get((self.base + index) as usize)
+        {
+            Some(Some(fd)) => fd.write(1),
+            // The client hasn't enabled this vector
+            _ => Ok(()),
+        }
+    }
+
+    fn notifier(&self, index: InterruptIndex) -> Option<EventFd> {
+        match self
+            .vectors
+            .lock()
+            .unwrap()
+            .get((self.base + index) as usize)
+        {
+            Some(Some(fd)) => fd.try_clone().ok(),
+            _ => None,
+        }
+    }
+
+    // The routing of the vectors is handled by the client
+    fn update(
+        &self,
+        _index: InterruptIndex,
+        _config: InterruptSourceConfig,
+        _masked: bool,
+        _set_gsi: bool,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_gsi(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Backend of the vfio-user server forwarding the requests of the client to
+/// an emulated PCI device.
+pub struct VfioUserPciServer {
+    device: Arc<Mutex<dyn PciDevice>>,
+    bars: Vec<PciBarConfiguration>,
+    interrupts: Arc<VfioUserServerInterrupts>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    msix_vectors: u32,
+    notifiers: Vec<(u32, u64, EventFd)>,
+    // DMA mappings without any file, which the device can't access
+    unmapped_dma: Vec<(u64, u64)>,
+}
+
+impl VfioUserPciServer {
+    /// Create the backend of a device, which must have been created with the
+    /// given interrupts and memory, and whose BARs have been allocated.
+    pub fn new(
+        device: Arc<Mutex<dyn PciDevice>>,
+        bars: Vec<PciBarConfiguration>,
+        interrupts: Arc<VfioUserServerInterrupts>,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        msix_vectors: u32,
+    ) -> Self {
+        VfioUserPciServer {
+            device,
+            bars,
+            interrupts,
+            memory,
+            msix_vectors,
+            notifiers: Vec::new(),
+            unmapped_dma: Vec::new(),
+        }
+    }
+
+    /// Signal the eventfd when the client writes to the given offset of a
+    /// BAR, in place of forwarding the write to the device, the same way an
+    /// ioeventfd would for a vCPU.
+    pub fn add_notifier(&mut self, region: u32, offset: u64, eventfd: EventFd) {
+        self.notifiers.push((region, offset, eventfd));
+    }
+
+    /// Serve the requests of the clients connecting to the socket.
+    pub fn run(&mut self, path: &Path) -> Result<(), VfioUserPciServerError> {
+        let server = Server::new(path, true, self.irqs(), self.regions())
+            .map_err(VfioUserPciServerError::CreateServer)?;
+        server.run(self).map_err(VfioUserPciServerError::RunServer)
+    }
+
+    fn regions(&self) -> Vec<ServerRegion> {
+        let mut regions: Vec<ServerRegion> = (0..VFIO_PCI_NUM_REGIONS)
+            .map(|_| ServerRegion {
+                flags: 0,
+                size: 0,
+                file_offset: None,
+            })
+            .collect();
+
+        for bar in self.bars.iter() {
+            regions[bar.idx()] = ServerRegion {
+                flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+                size: bar.size(),
+                file_offset: None,
+            };
+        }
+        regions[VFIO_PCI_CONFIG_REGION_INDEX as usize] = ServerRegion {
+            flags: VFIO_REGION_INFO_FLAG_READ | VFIO_REGION_INFO_FLAG_WRITE,
+            size: PCI_CONFIG_SPACE_SIZE,
+            file_offset: None,
+        };
+
+        regions
+    }
+
+    fn irqs(&self) -> Vec<IrqInfo> {
+        (0..VFIO_PCI_NUM_IRQS)
+            .map(|index| IrqInfo {
+                index,
+                flags: VFIO_IRQ_INFO_EVENTFD,
+                count: if index == VFIO_PCI_MSIX_IRQ_INDEX {
+                    self.msix_vectors
+                } else {
+                    0
+                },
+            })
+            .collect()
+    }
+
+    fn bar(&self, region: u32) -> io::Result<&PciBarConfiguration> {
+        self.bars
+            .iter()
+            .find(|b| b.idx() == region as usize && b.region_type() != PciBarRegionType::IoRegion)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid region"))
+    }
+
+    fn read_config_byte(device: &mut dyn PciDevice, offset: usize) -> u8 {
+        (device.read_config_register(offset / 4) >> ((offset % 4) * 8)) as u8
+    }
+
+    // Find the BAR and offset of the MSI-X table by walking the capabilities.
+    fn msix_table(&self, device: &mut dyn PciDevice) -> Option<(u64, u64)> {
+        let mut cap = Self::read_config_byte(device, PCI_CAPABILITY_LIST) as usize & !0x3;
+        // Bound the walk in case the list loops
+        for _ in 0..48 {
+            if cap == 0 {
+                break;
+            }
+            if Self::read_config_byte(device, cap) == PciCapabilityId::MsiX as u8 {
+                let table = device.read_config_register(cap / 4 + 1);
+                let bar = self.bar(table & 0x7).ok()?;
+                return Some((bar.addr(), u64::from(table & !0x7)));
+            }
+            cap = Self::read_config_byte(device, cap + 1) as usize & !0x3;
+        }
+
+        None
+    }
+
+    // The client emulates the MSI-X table, the writes of the guest never
+    // reaching the device, whose vectors would stay masked. They're unmasked
+    // as soon as the client provides their eventfds instead.
+    fn set_msix_masked(&self, vectors: std::ops::Range<u32>, masked: bool) {
+        let mut device = self.device.lock().unwrap();
+        let Some((base, table)) = self.msix_table(device.deref_mut()) else {
+            return;
+        };
+
+        let data = u32::from(masked).to_le_bytes();
+        for vector in vectors {
+            let offset = table
+                + u64::from(vector) * MSIX_TABLE_ENTRY_SIZE as u64
+                + MSIX_TABLE_ENTRY_VECTOR_CTL;
+            device.write_bar(base, offset, &data);
+        }
+    }
+}
+
+impl ServerBackend for VfioUserPciServer {
+    fn region_read(&mut self, region: u32, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        if region == VFIO_PCI_CONFIG_REGION_INDEX {
+            // The configuration space is accessed one register at a time
+            let mut device = self.device.lock().unwrap();
+            for (i, byte) in data.iter_mut().enumerate() {
+                let offset = offset as usize + i;
+                let value = device.read_config_register(offset / 4);
+                *byte = (value >> ((offset % 4) * 8)) as u8;
+            }
+            return Ok(());
+        }
+
+        let base = self.bar(region)?.addr();
+        self.device.lock().unwrap().read_bar(base, offset, data);
+        Ok(())
+    }
+
+    fn region_write(&mut self, region: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        if let Some((_, _, eventfd)) = self
+            .notifiers
+            .iter()
+            .find(|(r, o, _)| *r == region && *o == offset)
+        {
+            return eventfd.write(1);
+        }
+
+        let barrier = if region == VFIO_PCI_CONFIG_REGION_INDEX {
+            let reg_idx = offset as usize / 4;
+            let mut device = self.device.lock().unwrap();
+
+            // The client may move the BARs, which the device must follow so
+            // that the accesses keep being decoded at the right place.
+            if let Some(params) = device.detect_bar_reprogramming(reg_idx, data) {
+                device.move_bar(params.old_base, params.new_base)?;
+                if let Some(bar) = self.bars.iter_mut().find(|b| b.addr() == params.old_base) {
+                    *bar = bar.set_address(params.new_base);
+                }
+            }
+
+            device.write_config_register(reg_idx, offset % 4, data)
+        } else {
+            let base = self.bar(region)?.addr();
+            self.device.lock().unwrap().write_bar(base, offset, data)
+        };
+
+        // Wait for the device to be activated, as a vCPU would do
+        if let Some(barrier) = barrier {
+            barrier.wait();
+        }
+
+        Ok(())
+    }
+
+    fn dma_map(
+        &mut self,
+        _flags: DmaMapFlags,
+        offset: u64,
+        address: u64,
+        size: u64,
+        fd: Option<File>,
+    ) -> io::Result<()> {
+        // The client keeps the regions it doesn't share to itself, which is
+        // only a problem if the device is asked to access them.
+        let Some(fd) = fd else {
+            warn!(
+                "DMA mapping 0x{:x} (0x{:x}) without any file, not accessible to the device",
+                address, size
+            );
+            self.unmapped_dma.push((address, size));
+            return Ok(());
+        };
+
+        let mmap_region = MmapRegion::from_file(FileOffset::new(fd, offset), size as usize)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let region = GuestRegionMmap::new(mmap_region, GuestAddress(address))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        let mut memory = self.memory.lock().unwrap();
+        let new_memory = memory
+            .insert_region(Arc::new(region))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        memory.replace(new_memory);
+
+        Ok(())
+    }
+
+    fn dma_unmap(&mut self, _flags: DmaUnmapFlags, address: u64, size: u64) -> io::Result<()> {
+        if let Some(index) = self.unmapped_dma.iter().position(|r| *r == (address, size)) {
+            self.unmapped_dma.remove(index);
+            return Ok(());
+        }
+
+        let mut memory = self.memory.lock().unwrap();
+        let (new_memory, _) = memory
+            .remove_region(GuestAddress(address), size)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        memory.replace(new_memory);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.interrupts.set_vectors(0, Vec::new());
+        self.set_msix_masked(0..self.msix_vectors, true);
+        Ok(())
+    }
+
+    fn set_irqs(
+        &mut self,
+        index: u32,
+        _flags: u32,
+        start: u32,
+        _count: u32,
+        fds: Vec<File>,
+    ) -> io::Result<()> {
+        if index != VFIO_PCI_MSIX_IRQ_INDEX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Only MSI-X interrupts are supported",
+            ));
+        }
+
+        // The vectors are unmasked once their eventfds are known, for the
+        // pending messages to be delivered.
+        let vectors = if fds.is_empty() {
+            0..self.msix_vectors
+        } else {
+            start..start + fds.len() as u32
+        };
+        let masked = fds.is_empty();
+        self.interrupts.set_vectors(start, fds);
+        self.set_msix_masked(vectors, masked);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BarReprogrammingParams, PciBarPrefetchable};
+    use std::any::Any;
+    use std::sync::Barrier;
+    use vm_device::BusDevice;
+    use vm_memory::{GuestAddressSpace, GuestMemory};
+    use vmm_sys_util::tempfile::TempFile;
+
+    const BAR_ADDR: u64 = 0x1000_0000;
+    const BAR_SIZE: u64 = 0x4000;
+    const MSIX_CAP: usize = 0x40;
+    const MSIX_TABLE: u32 = 0x2000;
+
+    #[derive(Default)]
+    struct DummyDevice {
+        config: Vec<u32>,
+        bar_writes: Vec<(u64, u64, Vec<u8>)>,
+        moves: Vec<(u64, u64)>,
+    }
+
+    impl DummyDevice {
+        fn new() -> Self {
+            let mut config = vec![0u32; PCI_CONFIG_SPACE_SIZE as usize / 4];
+            config[4] = BAR_ADDR as u32;
+            config[PCI_CAPABILITY_LIST / 4] = MSIX_CAP as u32;
+            config[MSIX_CAP / 4] = PciCapabilityId::MsiX as u32;
+            // The MSI-X table lives in BAR 0
+            config[MSIX_CAP / 4 + 1] = MSIX_TABLE;
+            DummyDevice {
+                config,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl BusDevice for DummyDevice {}
+
+    impl PciDevice for DummyDevice {
+        fn write_config_register(
+            &mut self,
+            reg_idx: usize,
+            _offset: u64,
+            data: &[u8],
+        ) -> Option<Arc<Barrier>> {
+            if data.len() == 4 {
+                self.config[reg_idx] = u32::from_le_bytes(data.try_into().unwrap());
+            }
+            None
+        }
+
+        fn read_config_register(&mut self, reg_idx: usize) -> u32 {
+            self.config[reg_idx]
+        }
+
+        fn detect_bar_reprogramming(
+            &mut self,
+            reg_idx: usize,
+            data: &[u8],
+        ) -> Option<BarReprogrammingParams> {
+            let new_base = u64::from(u32::from_le_bytes(data.try_into().ok()?));
+            (reg_idx == 4 && new_base != u64::from(self.config[4])).then_some(
+                BarReprogrammingParams {
+                    old_base: u64::from(self.config[4]),
+                    new_base,
+                    len: BAR_SIZE,
+                    region_type: PciBarRegionType::Memory32BitRegion,
+                },
+            )
+        }
+
+        fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+            self.bar_writes.push((base, offset, data.to_vec()));
+            None
+        }
+
+        fn move_bar(&mut self, old_base: u64, new_base: u64) -> io::Result<()> {
+            self.moves.push((old_base, new_base));
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn id(&self) -> Option<String> {
+            None
+        }
+    }
+
+    fn server() -> (VfioUserPciServer, Arc<Mutex<DummyDevice>>) {
+        let device = Arc::new(Mutex::new(DummyDevice::new()));
+        let bar = PciBarConfiguration::new(
+            0,
+            BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+            PciBarPrefetchable::NotPrefetchable,
+        )
+        .set_address(BAR_ADDR);
+        let server = VfioUserPciServer::new(
+            device.clone(),
+            vec![bar],
+            Arc::new(VfioUserServerInterrupts::default()),
+            GuestMemoryAtomic::new(GuestMemoryMmap::new()),
+            2,
+        );
+
+        (server, device)
+    }
+
+    #[test]
+    fn test_vfio_user_server_regions() {
+        let (mut server, device) = server();
+
+        let mut data = [0u8; 4];
+        server
+            .region_read(VFIO_PCI_CONFIG_REGION_INDEX, MSIX_CAP as u64 + 4, &mut data)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), MSIX_TABLE);
+
+        server.region_write(0, 0x10, &[1, 2]).unwrap();
+        assert_eq!(
+            device.lock().unwrap().bar_writes,
+            vec![(BAR_ADDR, 0x10, vec![1, 2])]
+        );
+        // Only the BARs and the configuration space are exposed
+        assert!(server.region_write(1, 0, &[0]).is_err());
+
+        // The notifications don't reach the device
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        server.add_notifier(0, 0x3000, eventfd.try_clone().unwrap());
+        server.region_write(0, 0x3000, &[0, 0]).unwrap();
+        assert_eq!(eventfd.read().unwrap(), 1);
+        assert_eq!(device.lock().unwrap().bar_writes.len(), 1);
+    }
+
+    #[test]
+    fn test_vfio_user_server_bar_reprogramming() {
+        let (mut server, device) = server();
+
+        let new_base: u32 = 0x2000_0000;
+        server
+            .region_write(VFIO_PCI_CONFIG_REGION_INDEX, 0x10, &new_base.to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            device.lock().unwrap().moves,
+            vec![(BAR_ADDR, u64::from(new_base))]
+        );
+
+        // The accesses follow the BAR
+        server.region_write(0, 0x10, &[1]).unwrap();
+        assert_eq!(
+            device.lock().unwrap().bar_writes,
+            vec![(u64::from(new_base), 0x10, vec![1])]
+        );
+    }
+
+    #[test]
+    fn test_vfio_user_server_msix() {
+        let (mut server, device) = server();
+
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        // SAFETY: the file descriptor comes from a valid eventfd
+        let fd = unsafe { File::from_raw_fd(eventfd.try_clone().unwrap().into_raw_fd()) };
+        server
+            .set_irqs(VFIO_PCI_MSIX_IRQ_INDEX, 0, 1, 1, vec![fd])
+            .unwrap();
+        let ctl = u64::from(MSIX_TABLE) + MSIX_TABLE_ENTRY_SIZE as u64 + 12;
+        assert_eq!(
+            device.lock().unwrap().bar_writes,
+            vec![(BAR_ADDR, ctl, vec![0; 4])]
+        );
+
+        // Disabling the interrupts masks all the vectors back
+        device.lock().unwrap().bar_writes.clear();
+        server
+            .set_irqs(VFIO_PCI_MSIX_IRQ_INDEX, 0, 0, 0, Vec::new())
+            .unwrap();
+        let writes = &device.lock().unwrap().bar_writes;
+        assert_eq!(writes.len(), 2);
+        assert!(writes.iter().all(|(_, _, data)| *data == vec![1, 0, 0, 0]));
+
+        assert!(server
+            .set_irqs(VFIO_PCI_INTX_IRQ_INDEX, 0, 0, 0, Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_vfio_user_server_dma() {
+        let (mut server, _) = server();
+        let flags = DmaMapFlags::READ_WRITE;
+
+        // Without any file, the region is accepted but not accessible
+        server.dma_map(flags, 0, 0x10_0000, 0x1000, None).unwrap();
+        assert_eq!(server.memory.memory().num_regions(), 0);
+        server
+            .dma_unmap(DmaUnmapFlags::empty(), 0x10_0000, 0x1000)
+            .unwrap();
+
+        let file = TempFile::new().unwrap().into_file();
+        file.set_len(0x2000).unwrap();
+        server
+            .dma_map(flags, 0x1000, 0x20_0000, 0x1000, Some(file))
+            .unwrap();
+        assert!(server
+            .memory
+            .memory()
+            .find_region(GuestAddress(0x20_0800))
+            .is_some());
+        server
+            .dma_unmap(DmaUnmapFlags::empty(), 0x20_0000, 0x1000)
+            .unwrap();
+        assert_eq!(server.memory.memory().num_regions(), 0);
+    }
+
+    #[test]
+    fn test_vfio_user_server_interrupts() {
+        let interrupts = VfioUserServerInterrupts::default();
+        let group = interrupts
+            .create_group(MsiIrqGroupConfig { base: 0, count: 2 })
+            .unwrap();
+
+        // Triggering a vector the client hasn't enabled is a no-op
+        group.trigger(1).unwrap();
+        assert!(group.notifier(1).is_none());
+
+        let eventfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        // SAFETY: the file descriptor comes from a valid eventfd
+        let fd = unsafe { File::from_raw_fd(eventfd.try_clone().unwrap().into_raw_fd()) };
+        interrupts.set_vectors(1, vec![fd]);
+        group.trigger(1).unwrap();
+        assert_eq!(eventfd.read().unwrap(), 1);
+        assert!(group.notifier(0).is_none());
+
+        interrupts.set_vectors(0, Vec::new());
+        assert!(group.notifier(1).is_none());
+    }
+}
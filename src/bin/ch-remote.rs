@@ -6,14 +6,19 @@
 use api_client::simple_api_command;
 use api_client::simple_api_command_with_fds;
 use api_client::simple_api_full_command;
+use api_client::simple_api_full_command_and_response;
 use api_client::Error as ApiClientError;
 use clap::{Arg, ArgAction, ArgMatches, Command};
 use option_parser::{ByteSized, ByteSizedList, ByteSizedListParseError, ByteSizedParseError};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::Read;
 use std::marker::PhantomData;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 #[cfg(feature = "dbus_api")]
 use zbus::{dbus_proxy, zvariant::Optional};
 
@@ -25,9 +30,15 @@ enum Error {
     #[cfg(feature = "dbus_api")]
     DBusApiClient(zbus::Error),
     InvalidCpuCount(std::num::ParseIntError),
+    InvalidCpuId(std::num::ParseIntError),
     InvalidMemorySize(ByteSizedParseError),
+    InvalidNumaNode(std::num::ParseIntError),
     InvalidBalloonSize(ByteSizedListParseError),
+    InvalidMemoryAddress(std::num::ParseIntError),
+    InvalidMemoryLength(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
+    AddVfConfig(vmm::config::Error),
+    AddMdevConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
     AddPmemConfig(vmm::config::Error),
@@ -35,9 +46,17 @@ enum Error {
     AddUserDeviceConfig(vmm::config::Error),
     AddVdpaConfig(vmm::config::Error),
     AddVsockConfig(vmm::config::Error),
+    #[cfg(target_arch = "x86_64")]
+    AddSgxEpcConfig(vmm::config::Error),
+    AddConsolePortConfig(vmm::config::Error),
+    SetCpuAffinityConfig(vmm::config::Error),
+    SetCpuBandwidthConfig(vmm::config::Error),
     Restore(vmm::config::Error),
+    InvalidSnapshotFd(std::num::ParseIntError),
     ReadingStdin(std::io::Error),
     ReadingFile(std::io::Error),
+    InvalidResponse(serde_json::Error),
+    Reconnect(std::io::Error),
 }
 
 impl fmt::Display for Error {
@@ -48,9 +67,15 @@ impl fmt::Display for Error {
             #[cfg(feature = "dbus_api")]
             DBusApiClient(e) => write!(f, "Error D-Bus proxy: {e}"),
             InvalidCpuCount(e) => write!(f, "Error parsing CPU count: {e}"),
+            InvalidCpuId(e) => write!(f, "Error parsing CPU id: {e}"),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {e:?}"),
+            InvalidNumaNode(e) => write!(f, "Error parsing host NUMA node: {e}"),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {e:?}"),
+            InvalidMemoryAddress(e) => write!(f, "Error parsing guest memory address: {e}"),
+            InvalidMemoryLength(e) => write!(f, "Error parsing guest memory length: {e}"),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {e}"),
+            AddVfConfig(e) => write!(f, "Error parsing virtual function syntax: {e}"),
+            AddMdevConfig(e) => write!(f, "Error parsing mediated device syntax: {e}"),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {e}"),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {e}"),
             AddPmemConfig(e) => write!(f, "Error parsing persistent memory syntax: {e}"),
@@ -58,9 +83,17 @@ impl fmt::Display for Error {
             AddUserDeviceConfig(e) => write!(f, "Error parsing user device syntax: {e}"),
             AddVdpaConfig(e) => write!(f, "Error parsing vDPA device syntax: {e}"),
             AddVsockConfig(e) => write!(f, "Error parsing vsock syntax: {e}"),
+            #[cfg(target_arch = "x86_64")]
+            AddSgxEpcConfig(e) => write!(f, "Error parsing SGX EPC syntax: {e}"),
+            AddConsolePortConfig(e) => write!(f, "Error parsing console port syntax: {e}"),
+            SetCpuAffinityConfig(e) => write!(f, "Error parsing CPU affinity syntax: {e}"),
+            SetCpuBandwidthConfig(e) => write!(f, "Error parsing CPU bandwidth syntax: {e}"),
             Restore(e) => write!(f, "Error parsing restore syntax: {e}"),
+            InvalidSnapshotFd(e) => write!(f, "Error parsing snapshot file descriptor: {e}"),
             ReadingStdin(e) => write!(f, "Error reading from stdin: {e}"),
             ReadingFile(e) => write!(f, "Error reading from file: {e}"),
+            InvalidResponse(e) => write!(f, "Error parsing the API response: {e}"),
+            Reconnect(e) => write!(f, "Error reconnecting to the HTTP socket: {e}"),
         }
     }
 }
@@ -75,8 +108,11 @@ enum TargetApi<'a> {
 #[dbus_proxy(name = "org.cloudhypervisor.DBusApi1", assume_defaults = false)]
 trait DBusApi1 {
     fn vmm_ping(&self) -> zbus::Result<String>;
+    fn vmm_resources(&self) -> zbus::Result<String>;
     fn vmm_shutdown(&self) -> zbus::Result<()>;
     fn vm_add_device(&self, device_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_vf(&self, vf_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_mdev(&self, mdev_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_disk(&self, disk_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_fs(&self, fs_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_net(&self, net_config: &str) -> zbus::Result<Optional<String>>;
@@ -84,24 +120,45 @@ trait DBusApi1 {
     fn vm_add_user_device(&self, vm_add_user_device: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_vdpa(&self, vdpa_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_add_vsock(&self, vsock_config: &str) -> zbus::Result<Optional<String>>;
+    fn vm_add_sgx_epc(&self, sgx_epc_config: &str) -> zbus::Result<()>;
+    fn vm_add_console_port(&self, console_port_config: &str) -> zbus::Result<Optional<String>>;
     fn vm_boot(&self) -> zbus::Result<()>;
+    fn vm_boot_timings(&self) -> zbus::Result<Optional<String>>;
     fn vm_coredump(&self, vm_coredump_data: &str) -> zbus::Result<()>;
     fn vm_counters(&self) -> zbus::Result<Optional<String>>;
+    fn vm_counter_rates(&self) -> zbus::Result<Optional<String>>;
     fn vm_create(&self, vm_config: &str) -> zbus::Result<()>;
     fn vm_delete(&self) -> zbus::Result<()>;
+    fn vm_guest_exec(&self, vm_guest_exec: &str) -> zbus::Result<Optional<String>>;
+    fn vm_guest_fsfreeze(&self, vm_guest_fsfreeze: &str) -> zbus::Result<Optional<String>>;
+    fn vm_guest_info(&self) -> zbus::Result<Optional<String>>;
+    fn vm_hetero_balloon(&self) -> zbus::Result<Optional<String>>;
     fn vm_info(&self) -> zbus::Result<String>;
+    fn vm_memory_read(&self, vm_memory_read: &str) -> zbus::Result<Optional<String>>;
+    fn vm_memory_write(&self, vm_memory_write: &str) -> zbus::Result<()>;
+    fn vm_migration_precheck(&self, send_migration_data: &str) -> zbus::Result<Optional<String>>;
     fn vm_pause(&self) -> zbus::Result<()>;
     fn vm_power_button(&self) -> zbus::Result<()>;
     fn vm_reboot(&self) -> zbus::Result<()>;
+    fn vm_remove_console_port(&self, vm_remove_console_port: &str) -> zbus::Result<()>;
     fn vm_remove_device(&self, vm_remove_device: &str) -> zbus::Result<()>;
+    fn vm_report_free_pages(&self) -> zbus::Result<()>;
     fn vm_resize(&self, vm_resize: &str) -> zbus::Result<()>;
     fn vm_resize_zone(&self, vm_resize_zone: &str) -> zbus::Result<()>;
+    fn vm_bind_zone(&self, vm_bind_zone: &str) -> zbus::Result<()>;
+    fn vm_resize_fs(&self, vm_resize_fs: &str) -> zbus::Result<()>;
+    fn vm_remove_vcpu(&self, vm_remove_vcpu: &str) -> zbus::Result<()>;
+    fn vm_set_cpu_affinity(&self, vm_set_cpu_affinity: &str) -> zbus::Result<()>;
+    fn vm_set_cpu_bandwidth(&self, vm_set_cpu_bandwidth: &str) -> zbus::Result<()>;
     fn vm_restore(&self, restore_config: &str) -> zbus::Result<()>;
     fn vm_receive_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
     fn vm_send_migration(&self, receive_migration_data: &str) -> zbus::Result<()>;
     fn vm_resume(&self) -> zbus::Result<()>;
     fn vm_shutdown(&self) -> zbus::Result<()>;
     fn vm_snapshot(&self, vm_snapshot_config: &str) -> zbus::Result<()>;
+    fn vm_virtqueue_state(&self, vm_virtqueue_state: &str) -> zbus::Result<Optional<String>>;
+    #[dbus_proxy(signal)]
+    fn event(&self, event: String) -> zbus::Result<()>;
 }
 
 #[cfg(feature = "dbus_api")]
@@ -135,6 +192,12 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vmm_resources(&self) -> ApiResult {
+        self.vmm_resources()
+            .map(|resources| println!("{resources}"))
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vmm_shutdown(&self) -> ApiResult {
         self.vmm_shutdown().map_err(Error::DBusApiClient)
     }
@@ -143,6 +206,14 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.print_response(self.vm_add_device(device_config))
     }
 
+    fn api_vm_add_vf(&self, vf_config: &str) -> ApiResult {
+        self.print_response(self.vm_add_vf(vf_config))
+    }
+
+    fn api_vm_add_mdev(&self, mdev_config: &str) -> ApiResult {
+        self.print_response(self.vm_add_mdev(mdev_config))
+    }
+
     fn api_vm_add_disk(&self, disk_config: &str) -> ApiResult {
         self.print_response(self.vm_add_disk(disk_config))
     }
@@ -171,6 +242,16 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.print_response(self.vm_add_vsock(vsock_config))
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn api_vm_add_sgx_epc(&self, sgx_epc_config: &str) -> ApiResult {
+        self.vm_add_sgx_epc(sgx_epc_config)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_add_console_port(&self, console_port_config: &str) -> ApiResult {
+        self.print_response(self.vm_add_console_port(console_port_config))
+    }
+
     fn api_vm_boot(&self) -> ApiResult {
         self.vm_boot().map_err(Error::DBusApiClient)
     }
@@ -180,8 +261,29 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
-    fn api_vm_counters(&self) -> ApiResult {
-        self.print_response(self.vm_counters())
+    fn api_vm_boot_timings(&self) -> ApiResult {
+        self.print_response(self.vm_boot_timings())
+    }
+
+    fn api_vm_counters(&self, rates: bool) -> ApiResult {
+        if rates {
+            self.print_response(self.vm_counter_rates())
+        } else {
+            self.print_response(self.vm_counters())
+        }
+    }
+
+    // The events are received from the signal rather than polled
+    fn api_watch_events(&self) -> ApiResult {
+        for signal in self.receive_event().map_err(Error::DBusApiClient)? {
+            let args = signal.args().map_err(Error::DBusApiClient)?;
+            match serde_json::from_str::<serde_json::Value>(args.event()) {
+                Ok(event) => println!("{event}"),
+                Err(_) => println!("{}", args.event()),
+            }
+        }
+
+        Ok(())
     }
 
     fn api_vm_create(&self, vm_config: &str) -> ApiResult {
@@ -192,6 +294,31 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vm_delete().map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_guest_exec(&self, vm_guest_exec: &str) -> ApiResult {
+        self.print_response(self.vm_guest_exec(vm_guest_exec))
+    }
+
+    fn api_vm_guest_fsfreeze(&self, vm_guest_fsfreeze: &str) -> ApiResult {
+        self.print_response(self.vm_guest_fsfreeze(vm_guest_fsfreeze))
+    }
+
+    fn api_vm_guest_info(&self) -> ApiResult {
+        self.print_response(self.vm_guest_info())
+    }
+
+    fn api_vm_hetero_balloon(&self) -> ApiResult {
+        self.print_response(self.vm_hetero_balloon())
+    }
+
+    fn api_vm_memory_read(&self, vm_memory_read: &str) -> ApiResult {
+        self.print_response(self.vm_memory_read(vm_memory_read))
+    }
+
+    fn api_vm_memory_write(&self, vm_memory_write: &str) -> ApiResult {
+        self.vm_memory_write(vm_memory_write)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_info(&self) -> ApiResult {
         self.vm_info()
             .map(|info| println!("{info}"))
@@ -210,6 +337,15 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vm_reboot().map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_report_free_pages(&self) -> ApiResult {
+        self.vm_report_free_pages().map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_remove_console_port(&self, vm_remove_console_port: &str) -> ApiResult {
+        self.vm_remove_console_port(vm_remove_console_port)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_remove_device(&self, vm_remove_device: &str) -> ApiResult {
         self.vm_remove_device(vm_remove_device)
             .map_err(Error::DBusApiClient)
@@ -224,6 +360,31 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_bind_zone(&self, vm_bind_zone: &str) -> ApiResult {
+        self.vm_bind_zone(vm_bind_zone)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_resize_fs(&self, vm_resize_fs: &str) -> ApiResult {
+        self.vm_resize_fs(vm_resize_fs)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_remove_vcpu(&self, vm_remove_vcpu: &str) -> ApiResult {
+        self.vm_remove_vcpu(vm_remove_vcpu)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_set_cpu_affinity(&self, vm_set_cpu_affinity: &str) -> ApiResult {
+        self.vm_set_cpu_affinity(vm_set_cpu_affinity)
+            .map_err(Error::DBusApiClient)
+    }
+
+    fn api_vm_set_cpu_bandwidth(&self, vm_set_cpu_bandwidth: &str) -> ApiResult {
+        self.vm_set_cpu_bandwidth(vm_set_cpu_bandwidth)
+            .map_err(Error::DBusApiClient)
+    }
+
     fn api_vm_restore(&self, restore_config: &str) -> ApiResult {
         self.vm_restore(restore_config)
             .map_err(Error::DBusApiClient)
@@ -239,6 +400,10 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
             .map_err(Error::DBusApiClient)
     }
 
+    fn api_vm_migration_precheck(&self, send_migration_data: &str) -> ApiResult {
+        self.print_response(self.vm_migration_precheck(send_migration_data))
+    }
+
     fn api_vm_resume(&self) -> ApiResult {
         self.vm_resume().map_err(Error::DBusApiClient)
     }
@@ -251,6 +416,10 @@ impl<'a> DBusApi1ProxyBlocking<'a> {
         self.vm_snapshot(vm_snapshot_config)
             .map_err(Error::DBusApiClient)
     }
+
+    fn api_vm_virtqueue_state(&self, vm_virtqueue_state: &str) -> ApiResult {
+        self.print_response(self.vm_virtqueue_state(vm_virtqueue_state))
+    }
 }
 
 impl<'a> TargetApi<'a> {
@@ -279,6 +448,8 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
         Some("power-button") => {
             simple_api_command(socket, "PUT", "power-button", None).map_err(Error::HttpApiClient)
         }
+        Some("report-free-pages") => simple_api_command(socket, "PUT", "report-free-pages", None)
+            .map_err(Error::HttpApiClient),
         Some("reboot") => {
             simple_api_command(socket, "PUT", "reboot", None).map_err(Error::HttpApiClient)
         }
@@ -289,11 +460,74 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "GET", "info", None).map_err(Error::HttpApiClient)
         }
         Some("counters") => {
-            simple_api_command(socket, "GET", "counters", None).map_err(Error::HttpApiClient)
+            let counters_data = counters_data(
+                matches
+                    .subcommand_matches("counters")
+                    .unwrap()
+                    .get_flag("rates"),
+            );
+            simple_api_command(socket, "GET", "counters", counters_data.as_deref())
+                .map_err(Error::HttpApiClient)
+        }
+        Some("guest-info") => {
+            simple_api_command(socket, "GET", "guest-info", None).map_err(Error::HttpApiClient)
+        }
+        Some("boot-timings") => {
+            simple_api_command(socket, "GET", "boot-timings", None).map_err(Error::HttpApiClient)
+        }
+        Some("hetero-balloon") => {
+            simple_api_command(socket, "GET", "hetero-balloon", None).map_err(Error::HttpApiClient)
+        }
+        Some("guest-exec") => {
+            let guest_exec_data =
+                guest_exec_data(matches.subcommand_matches("guest-exec").unwrap());
+            simple_api_command(socket, "PUT", "guest-exec", Some(&guest_exec_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("memory-read") => {
+            let memory_read_data =
+                memory_read_data(matches.subcommand_matches("memory-read").unwrap())?;
+            simple_api_command(socket, "PUT", "memory-read", Some(&memory_read_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("memory-write") => {
+            let memory_write_data =
+                memory_write_data(matches.subcommand_matches("memory-write").unwrap())?;
+            simple_api_command(socket, "PUT", "memory-write", Some(&memory_write_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("virtqueue-state") => {
+            let virtqueue_state_data = virtqueue_state_data(
+                matches
+                    .subcommand_matches("virtqueue-state")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "virtqueue-state",
+                Some(&virtqueue_state_data),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("guest-fsfreeze") => {
+            let guest_fsfreeze_data = guest_fsfreeze_data(
+                matches
+                    .subcommand_matches("guest-fsfreeze")
+                    .unwrap()
+                    .get_one::<String>("action")
+                    .unwrap(),
+            );
+            simple_api_command(socket, "PUT", "guest-fsfreeze", Some(&guest_fsfreeze_data))
+                .map_err(Error::HttpApiClient)
         }
         Some("ping") => {
             simple_api_full_command(socket, "GET", "vmm.ping", None).map_err(Error::HttpApiClient)
         }
+        Some("resources") => simple_api_full_command(socket, "GET", "vmm.resources", None)
+            .map_err(Error::HttpApiClient),
         Some("shutdown") => {
             simple_api_command(socket, "PUT", "shutdown", None).map_err(Error::HttpApiClient)
         }
@@ -333,6 +567,38 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "resize-zone", Some(&resize_zone))
                 .map_err(Error::HttpApiClient)
         }
+        Some("bind-zone") => {
+            let bind_zone = bind_zone_config(
+                matches
+                    .subcommand_matches("bind-zone")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("bind-zone")
+                    .unwrap()
+                    .get_one::<String>("host_numa_node")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "bind-zone", Some(&bind_zone))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("resize-fs") => {
+            let resize_fs = resize_fs_config(
+                matches
+                    .subcommand_matches("resize-fs")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("resize-fs")
+                    .unwrap()
+                    .get_one::<String>("size")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "resize-fs", Some(&resize_fs))
+                .map_err(Error::HttpApiClient)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -344,6 +610,28 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "add-device", Some(&device_config))
                 .map_err(Error::HttpApiClient)
         }
+        Some("add-vf") => {
+            let vf_config = add_vf_config(
+                matches
+                    .subcommand_matches("add-vf")
+                    .unwrap()
+                    .get_one::<String>("vf_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "add-vf", Some(&vf_config))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("add-mdev") => {
+            let mdev_config = add_mdev_config(
+                matches
+                    .subcommand_matches("add-mdev")
+                    .unwrap()
+                    .get_one::<String>("mdev_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "add-mdev", Some(&mdev_config))
+                .map_err(Error::HttpApiClient)
+        }
         Some("remove-device") => {
             let remove_device_data = remove_device_config(
                 matches
@@ -355,6 +643,42 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "remove-device", Some(&remove_device_data))
                 .map_err(Error::HttpApiClient)
         }
+        Some("remove-vcpu") => {
+            let remove_vcpu_data = remove_vcpu_config(
+                matches
+                    .subcommand_matches("remove-vcpu")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "remove-vcpu", Some(&remove_vcpu_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("set-cpu-affinity") => {
+            let cpu_affinity_data = set_cpu_affinity_config(
+                matches
+                    .subcommand_matches("set-cpu-affinity")
+                    .unwrap()
+                    .get_one::<String>("affinity")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "set-cpu-affinity", Some(&cpu_affinity_data))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("set-cpu-bandwidth") => {
+            let set_cpu_bandwidth = matches.subcommand_matches("set-cpu-bandwidth").unwrap();
+            let cpu_bandwidth_data = set_cpu_bandwidth_config(
+                set_cpu_bandwidth.get_one::<String>("cpu-max"),
+                set_cpu_bandwidth.get_one::<String>("vcpu-max"),
+            )?;
+            simple_api_command(
+                socket,
+                "PUT",
+                "set-cpu-bandwidth",
+                Some(&cpu_bandwidth_data),
+            )
+            .map_err(Error::HttpApiClient)
+        }
         Some("add-disk") => {
             let disk_config = add_disk_config(
                 matches
@@ -432,26 +756,70 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "add-vsock", Some(&vsock_config))
                 .map_err(Error::HttpApiClient)
         }
+        #[cfg(target_arch = "x86_64")]
+        Some("add-sgx-epc") => {
+            let sgx_epc_config = add_sgx_epc_config(
+                matches
+                    .subcommand_matches("add-sgx-epc")
+                    .unwrap()
+                    .get_one::<String>("sgx_epc_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(socket, "PUT", "add-sgx-epc", Some(&sgx_epc_config))
+                .map_err(Error::HttpApiClient)
+        }
+        Some("add-console-port") => {
+            let console_port_config = add_console_port_config(
+                matches
+                    .subcommand_matches("add-console-port")
+                    .unwrap()
+                    .get_one::<String>("console_port_config")
+                    .unwrap(),
+            )?;
+            simple_api_command(
+                socket,
+                "PUT",
+                "add-console-port",
+                Some(&console_port_config),
+            )
+            .map_err(Error::HttpApiClient)
+        }
+        Some("remove-console-port") => {
+            let remove_console_port_data = remove_device_config(
+                matches
+                    .subcommand_matches("remove-console-port")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "remove-console-port",
+                Some(&remove_console_port_data),
+            )
+            .map_err(Error::HttpApiClient)
+        }
         Some("snapshot") => {
-            let snapshot_config = snapshot_config(
+            let (snapshot_config, fds) = snapshot_config(
                 matches
                     .subcommand_matches("snapshot")
                     .unwrap()
                     .get_one::<String>("snapshot_config")
                     .unwrap(),
-            );
-            simple_api_command(socket, "PUT", "snapshot", Some(&snapshot_config))
+            )?;
+            simple_api_command_with_fds(socket, "PUT", "snapshot", Some(&snapshot_config), fds)
                 .map_err(Error::HttpApiClient)
         }
         Some("restore") => {
-            let restore_config = restore_config(
+            let (restore_config, fds) = restore_config(
                 matches
                     .subcommand_matches("restore")
                     .unwrap()
                     .get_one::<String>("restore_config")
                     .unwrap(),
             )?;
-            simple_api_command(socket, "PUT", "restore", Some(&restore_config))
+            simple_api_command_with_fds(socket, "PUT", "restore", Some(&restore_config), fds)
                 .map_err(Error::HttpApiClient)
         }
         Some("coredump") => {
@@ -480,6 +848,26 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             simple_api_command(socket, "PUT", "send-migration", Some(&send_migration_data))
                 .map_err(Error::HttpApiClient)
         }
+        Some("migration-precheck") => {
+            let send_migration_data = send_migration_data(
+                matches
+                    .subcommand_matches("migration-precheck")
+                    .unwrap()
+                    .get_one::<String>("send_migration_config")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("migration-precheck")
+                    .unwrap()
+                    .get_flag("send_migration_local"),
+            );
+            simple_api_command(
+                socket,
+                "PUT",
+                "migration-precheck",
+                Some(&send_migration_data),
+            )
+            .map_err(Error::HttpApiClient)
+        }
         Some("receive-migration") => {
             let receive_migration_data = receive_migration_data(
                 matches
@@ -506,6 +894,18 @@ fn rest_api_do_command(matches: &ArgMatches, socket: &mut UnixStream) -> ApiResu
             )?;
             simple_api_command(socket, "PUT", "create", Some(&data)).map_err(Error::HttpApiClient)
         }
+        Some("watch") => {
+            let (what, interval) = watch_args(matches.subcommand_matches("watch").unwrap());
+            let path = rest_api_socket_path(socket)?;
+            match what {
+                "counters" => watch_counters(interval, || {
+                    let mut socket = UnixStream::connect(&path).map_err(Error::Reconnect)?;
+                    simple_api_full_command_and_response(&mut socket, "GET", "vm.counters", None)
+                        .map_err(Error::HttpApiClient)
+                }),
+                _ => rest_api_watch_events(&path, interval),
+            }
+        }
         _ => unreachable!(),
     }
 }
@@ -518,11 +918,56 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
         Some("shutdown-vmm") => proxy.api_vmm_shutdown(),
         Some("resume") => proxy.api_vm_resume(),
         Some("power-button") => proxy.api_vm_power_button(),
+        Some("report-free-pages") => proxy.api_vm_report_free_pages(),
         Some("reboot") => proxy.api_vm_reboot(),
         Some("pause") => proxy.api_vm_pause(),
         Some("info") => proxy.api_vm_info(),
-        Some("counters") => proxy.api_vm_counters(),
+        Some("counters") => proxy.api_vm_counters(
+            matches
+                .subcommand_matches("counters")
+                .unwrap()
+                .get_flag("rates"),
+        ),
+        Some("guest-info") => proxy.api_vm_guest_info(),
+        Some("boot-timings") => proxy.api_vm_boot_timings(),
+        Some("hetero-balloon") => proxy.api_vm_hetero_balloon(),
+        Some("guest-exec") => {
+            let guest_exec_data =
+                guest_exec_data(matches.subcommand_matches("guest-exec").unwrap());
+            proxy.api_vm_guest_exec(&guest_exec_data)
+        }
+        Some("memory-read") => {
+            let memory_read_data =
+                memory_read_data(matches.subcommand_matches("memory-read").unwrap())?;
+            proxy.api_vm_memory_read(&memory_read_data)
+        }
+        Some("memory-write") => {
+            let memory_write_data =
+                memory_write_data(matches.subcommand_matches("memory-write").unwrap())?;
+            proxy.api_vm_memory_write(&memory_write_data)
+        }
+        Some("virtqueue-state") => {
+            let virtqueue_state_data = virtqueue_state_data(
+                matches
+                    .subcommand_matches("virtqueue-state")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            proxy.api_vm_virtqueue_state(&virtqueue_state_data)
+        }
+        Some("guest-fsfreeze") => {
+            let guest_fsfreeze_data = guest_fsfreeze_data(
+                matches
+                    .subcommand_matches("guest-fsfreeze")
+                    .unwrap()
+                    .get_one::<String>("action")
+                    .unwrap(),
+            );
+            proxy.api_vm_guest_fsfreeze(&guest_fsfreeze_data)
+        }
         Some("ping") => proxy.api_vmm_ping(),
+        Some("resources") => proxy.api_vmm_resources(),
         Some("shutdown") => proxy.api_vm_shutdown(),
         Some("resize") => {
             let resize = resize_config(
@@ -559,6 +1004,36 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_resize_zone(&resize_zone)
         }
+        Some("bind-zone") => {
+            let bind_zone = bind_zone_config(
+                matches
+                    .subcommand_matches("bind-zone")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("bind-zone")
+                    .unwrap()
+                    .get_one::<String>("host_numa_node")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_bind_zone(&bind_zone)
+        }
+        Some("resize-fs") => {
+            let resize_fs = resize_fs_config(
+                matches
+                    .subcommand_matches("resize-fs")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("resize-fs")
+                    .unwrap()
+                    .get_one::<String>("size")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_resize_fs(&resize_fs)
+        }
         Some("add-device") => {
             let device_config = add_device_config(
                 matches
@@ -569,6 +1044,26 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_add_device(&device_config)
         }
+        Some("add-vf") => {
+            let vf_config = add_vf_config(
+                matches
+                    .subcommand_matches("add-vf")
+                    .unwrap()
+                    .get_one::<String>("vf_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_vf(&vf_config)
+        }
+        Some("add-mdev") => {
+            let mdev_config = add_mdev_config(
+                matches
+                    .subcommand_matches("add-mdev")
+                    .unwrap()
+                    .get_one::<String>("mdev_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_mdev(&mdev_config)
+        }
         Some("remove-device") => {
             let remove_device_data = remove_device_config(
                 matches
@@ -579,6 +1074,34 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             );
             proxy.api_vm_remove_device(&remove_device_data)
         }
+        Some("remove-vcpu") => {
+            let remove_vcpu_data = remove_vcpu_config(
+                matches
+                    .subcommand_matches("remove-vcpu")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_remove_vcpu(&remove_vcpu_data)
+        }
+        Some("set-cpu-affinity") => {
+            let cpu_affinity_data = set_cpu_affinity_config(
+                matches
+                    .subcommand_matches("set-cpu-affinity")
+                    .unwrap()
+                    .get_one::<String>("affinity")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_set_cpu_affinity(&cpu_affinity_data)
+        }
+        Some("set-cpu-bandwidth") => {
+            let set_cpu_bandwidth = matches.subcommand_matches("set-cpu-bandwidth").unwrap();
+            let cpu_bandwidth_data = set_cpu_bandwidth_config(
+                set_cpu_bandwidth.get_one::<String>("cpu-max"),
+                set_cpu_bandwidth.get_one::<String>("vcpu-max"),
+            )?;
+            proxy.api_vm_set_cpu_bandwidth(&cpu_bandwidth_data)
+        }
         Some("add-disk") => {
             let disk_config = add_disk_config(
                 matches
@@ -649,18 +1172,49 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_add_vsock(&vsock_config)
         }
+        #[cfg(target_arch = "x86_64")]
+        Some("add-sgx-epc") => {
+            let sgx_epc_config = add_sgx_epc_config(
+                matches
+                    .subcommand_matches("add-sgx-epc")
+                    .unwrap()
+                    .get_one::<String>("sgx_epc_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_sgx_epc(&sgx_epc_config)
+        }
+        Some("add-console-port") => {
+            let console_port_config = add_console_port_config(
+                matches
+                    .subcommand_matches("add-console-port")
+                    .unwrap()
+                    .get_one::<String>("console_port_config")
+                    .unwrap(),
+            )?;
+            proxy.api_vm_add_console_port(&console_port_config)
+        }
+        Some("remove-console-port") => {
+            let remove_console_port_data = remove_device_config(
+                matches
+                    .subcommand_matches("remove-console-port")
+                    .unwrap()
+                    .get_one::<String>("id")
+                    .unwrap(),
+            );
+            proxy.api_vm_remove_console_port(&remove_console_port_data)
+        }
         Some("snapshot") => {
-            let snapshot_config = snapshot_config(
+            let (snapshot_config, _fds) = snapshot_config(
                 matches
                     .subcommand_matches("snapshot")
                     .unwrap()
                     .get_one::<String>("snapshot_config")
                     .unwrap(),
-            );
+            )?;
             proxy.api_vm_snapshot(&snapshot_config)
         }
         Some("restore") => {
-            let restore_config = restore_config(
+            let (restore_config, _fds) = restore_config(
                 matches
                     .subcommand_matches("restore")
                     .unwrap()
@@ -693,6 +1247,20 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             );
             proxy.api_vm_send_migration(&send_migration_data)
         }
+        Some("migration-precheck") => {
+            let send_migration_data = send_migration_data(
+                matches
+                    .subcommand_matches("migration-precheck")
+                    .unwrap()
+                    .get_one::<String>("send_migration_config")
+                    .unwrap(),
+                matches
+                    .subcommand_matches("migration-precheck")
+                    .unwrap()
+                    .get_flag("send_migration_local"),
+            );
+            proxy.api_vm_migration_precheck(&send_migration_data)
+        }
         Some("receive-migration") => {
             let receive_migration_data = receive_migration_data(
                 matches
@@ -713,10 +1281,109 @@ fn dbus_api_do_command(matches: &ArgMatches, proxy: &DBusApi1ProxyBlocking<'_>)
             )?;
             proxy.api_vm_create(&data)
         }
+        Some("watch") => {
+            let (what, interval) = watch_args(matches.subcommand_matches("watch").unwrap());
+            match what {
+                "counters" => watch_counters(interval, || {
+                    proxy
+                        .vm_counters()
+                        .map(|counters| (*counters).clone())
+                        .map_err(Error::DBusApiClient)
+                }),
+                _ => proxy.api_watch_events(),
+            }
+        }
         _ => unreachable!(),
     }
 }
 
+type Counters = BTreeMap<String, BTreeMap<String, u64>>;
+
+fn watch_args(matches: &ArgMatches) -> (&str, Duration) {
+    (
+        matches.get_one::<String>("what").unwrap().as_str(),
+        Duration::from_secs(*matches.get_one::<u64>("interval").unwrap()),
+    )
+}
+
+// The counters are polled, only the ones that changed over the interval being
+// printed, along with their increase. The first poll only gives the values
+// the next ones are compared to.
+fn watch_counters(
+    interval: Duration,
+    mut get_counters: impl FnMut() -> Result<Option<String>, Error>,
+) -> ApiResult {
+    let start = Instant::now();
+    let mut previous: Option<Counters> = None;
+    loop {
+        let counters: Counters = match get_counters()? {
+            Some(counters) => serde_json::from_str(&counters).map_err(Error::InvalidResponse)?,
+            None => Counters::new(),
+        };
+
+        if let Some(previous) = &previous {
+            let elapsed = start.elapsed().as_secs_f64();
+            for (device, values) in &counters {
+                for (counter, value) in values {
+                    let old = previous
+                        .get(device)
+                        .and_then(|values| values.get(counter))
+                        .copied()
+                        .unwrap_or_default();
+                    if *value != old {
+                        println!(
+                            "[{elapsed:>10.3}] {device} {counter} +{} ({value})",
+                            value.wrapping_sub(old)
+                        );
+                    }
+                }
+            }
+        }
+
+        previous = Some(counters);
+        thread::sleep(interval);
+    }
+}
+
+// The server closes the connection once it answered a request, the polls of a
+// watch connecting again to the socket the command was given.
+fn rest_api_socket_path(socket: &UnixStream) -> Result<PathBuf, Error> {
+    let addr = socket.peer_addr().map_err(Error::Reconnect)?;
+    addr.as_pathname()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| Error::Reconnect(std::io::ErrorKind::AddrNotAvailable.into()))
+}
+
+// The events reported before the watch started are skipped.
+fn rest_api_watch_events(path: &Path, interval: Duration) -> ApiResult {
+    let mut since = None;
+    loop {
+        let mut socket = UnixStream::connect(path).map_err(Error::Reconnect)?;
+        let data = vmm::api::VmmEventsData {
+            since: since.unwrap_or_default(),
+        };
+        let response = simple_api_full_command_and_response(
+            &mut socket,
+            "GET",
+            "vmm.events",
+            Some(&serde_json::to_string(&data).unwrap()),
+        )
+        .map_err(Error::HttpApiClient)?
+        .unwrap_or_default();
+        let events: vmm::api::VmmEventsResponse =
+            serde_json::from_str(&response).map_err(Error::InvalidResponse)?;
+
+        if since.is_some() {
+            for event in events.events {
+                println!("{event}");
+            }
+        }
+
+        since = Some(events.next);
+        thread::sleep(interval);
+    }
+}
+
 fn resize_config(
     cpus: Option<&str>,
     memory: Option<&str>,
@@ -777,6 +1444,68 @@ fn resize_zone_config(id: &str, size: &str) -> Result<String, Error> {
     Ok(serde_json::to_string(&resize_zone).unwrap())
 }
 
+fn bind_zone_config(id: &str, host_numa_node: &str) -> Result<String, Error> {
+    let bind_zone = vmm::api::VmBindZoneData {
+        id: id.to_owned(),
+        host_numa_node: host_numa_node.parse().map_err(Error::InvalidNumaNode)?,
+    };
+
+    Ok(serde_json::to_string(&bind_zone).unwrap())
+}
+
+fn remove_vcpu_config(id: &str) -> Result<String, Error> {
+    let remove_vcpu_data = vmm::api::VmRemoveVcpuData {
+        id: id.parse().map_err(Error::InvalidCpuId)?,
+    };
+
+    Ok(serde_json::to_string(&remove_vcpu_data).unwrap())
+}
+
+fn set_cpu_affinity_config(affinity: &str) -> Result<String, Error> {
+    // Reuse the parser of the "affinity" parameter from --cpus
+    let affinity = vmm::config::CpusConfig::parse(&format!("affinity={affinity}"))
+        .map_err(Error::SetCpuAffinityConfig)?
+        .affinity
+        .unwrap_or_default();
+    let cpu_affinity_data = vmm::api::VmSetCpuAffinityData { affinity };
+
+    Ok(serde_json::to_string(&cpu_affinity_data).unwrap())
+}
+
+fn set_cpu_bandwidth_config(
+    cpu_max: Option<&String>,
+    vcpu_max: Option<&String>,
+) -> Result<String, Error> {
+    // Reuse the parser of the "cpu_max" and "vcpu_max" parameters from --cpus
+    let mut cpus = Vec::new();
+    if let Some(cpu_max) = cpu_max {
+        cpus.push(format!("cpu_max={cpu_max}"));
+    }
+    if let Some(vcpu_max) = vcpu_max {
+        cpus.push(format!("vcpu_max={vcpu_max}"));
+    }
+    let cpus =
+        vmm::config::CpusConfig::parse(&cpus.join(",")).map_err(Error::SetCpuBandwidthConfig)?;
+    let cpu_bandwidth_data = vmm::api::VmSetCpuBandwidthData {
+        cpu_max: cpus.cpu_max,
+        vcpu_max: cpus.vcpu_max,
+    };
+
+    Ok(serde_json::to_string(&cpu_bandwidth_data).unwrap())
+}
+
+fn resize_fs_config(id: &str, size: &str) -> Result<String, Error> {
+    let resize_fs = vmm::api::VmResizeFsData {
+        id: id.to_owned(),
+        desired_cache_size: size
+            .parse::<ByteSized>()
+            .map_err(Error::InvalidMemorySize)?
+            .0,
+    };
+
+    Ok(serde_json::to_string(&resize_fs).unwrap())
+}
+
 fn add_device_config(config: &str) -> Result<String, Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
     let device_config = serde_json::to_string(&device_config).unwrap();
@@ -784,6 +1513,20 @@ fn add_device_config(config: &str) -> Result<String, Error> {
     Ok(device_config)
 }
 
+fn add_vf_config(config: &str) -> Result<String, Error> {
+    let vf_config = vmm::config::VfConfig::parse(config).map_err(Error::AddVfConfig)?;
+    let vf_config = serde_json::to_string(&vf_config).unwrap();
+
+    Ok(vf_config)
+}
+
+fn add_mdev_config(config: &str) -> Result<String, Error> {
+    let mdev_config = vmm::config::MdevConfig::parse(config).map_err(Error::AddMdevConfig)?;
+    let mdev_config = serde_json::to_string(&mdev_config).unwrap();
+
+    Ok(mdev_config)
+}
+
 fn add_user_device_config(config: &str) -> Result<String, Error> {
     let device_config =
         vmm::config::UserDeviceConfig::parse(config).map_err(Error::AddUserDeviceConfig)?;
@@ -846,19 +1589,120 @@ fn add_vsock_config(config: &str) -> Result<String, Error> {
     Ok(vsock_config)
 }
 
-fn snapshot_config(url: &str) -> String {
-    let snapshot_config = vmm::api::VmSnapshotConfig {
-        destination_url: String::from(url),
+#[cfg(target_arch = "x86_64")]
+fn add_sgx_epc_config(config: &str) -> Result<String, Error> {
+    let sgx_epc_config =
+        vmm::config::SgxEpcConfig::parse(config).map_err(Error::AddSgxEpcConfig)?;
+    let sgx_epc_config = serde_json::to_string(&sgx_epc_config).unwrap();
+
+    Ok(sgx_epc_config)
+}
+
+fn add_console_port_config(config: &str) -> Result<String, Error> {
+    let console_port_config =
+        vmm::config::ConsolePortConfig::parse(config).map_err(Error::AddConsolePortConfig)?;
+    let console_port_config = serde_json::to_string(&console_port_config).unwrap();
+
+    Ok(console_port_config)
+}
+
+fn guest_exec_data(matches: &ArgMatches) -> String {
+    let guest_exec_data = vmm::api::VmGuestExecData {
+        path: matches.get_one::<String>("path").unwrap().to_owned(),
+        args: matches
+            .get_many::<String>("args")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+        env: matches
+            .get_many::<String>("env")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+        capture_output: matches.get_flag("capture-output"),
+        wait: matches.get_flag("wait"),
     };
 
-    serde_json::to_string(&snapshot_config).unwrap()
+    serde_json::to_string(&guest_exec_data).unwrap()
 }
 
-fn restore_config(config: &str) -> Result<String, Error> {
-    let restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)?;
+// Guest physical addresses are given in hex with a 0x prefix, or in decimal.
+fn parse_memory_address(address: &str) -> Result<u64, Error> {
+    match address.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(Error::InvalidMemoryAddress)
+}
+
+fn memory_read_data(matches: &ArgMatches) -> Result<String, Error> {
+    let memory_read_data = vmm::api::VmMemoryReadData {
+        address: parse_memory_address(matches.get_one::<String>("address").unwrap())?,
+        length: matches
+            .get_one::<String>("length")
+            .unwrap()
+            .parse()
+            .map_err(Error::InvalidMemoryLength)?,
+    };
+
+    Ok(serde_json::to_string(&memory_read_data).unwrap())
+}
+
+fn memory_write_data(matches: &ArgMatches) -> Result<String, Error> {
+    let memory_write_data = vmm::api::VmMemoryData {
+        address: parse_memory_address(matches.get_one::<String>("address").unwrap())?,
+        data: matches.get_one::<String>("data").unwrap().to_owned(),
+    };
+
+    Ok(serde_json::to_string(&memory_write_data).unwrap())
+}
+
+fn virtqueue_state_data(id: &str) -> String {
+    let virtqueue_state_data = vmm::api::VmVirtqueueStateData { id: id.to_owned() };
+
+    serde_json::to_string(&virtqueue_state_data).unwrap()
+}
+
+fn counters_data(rates: bool) -> Option<String> {
+    rates.then(|| serde_json::to_string(&vmm::api::VmCountersData { rates }).unwrap())
+}
+
+fn guest_fsfreeze_data(action: &str) -> String {
+    let action = match action {
+        "freeze" => vmm::api::VmGuestFsFreezeAction::Freeze,
+        "thaw" => vmm::api::VmGuestFsFreezeAction::Thaw,
+        _ => vmm::api::VmGuestFsFreezeAction::Status,
+    };
+    let guest_fsfreeze_data = vmm::api::VmGuestFsFreezeData { action };
+
+    serde_json::to_string(&guest_fsfreeze_data).unwrap()
+}
+
+// A snapshot streamed through a file descriptor of ch-remote, such as a pipe,
+// has it sent along with the request, the URL referring to it by its index.
+fn snapshot_stream_fds(url: &str) -> Result<(String, Vec<i32>), Error> {
+    match url.strip_prefix("fd://") {
+        Some(fd) => {
+            let fd = fd.parse().map_err(Error::InvalidSnapshotFd)?;
+            Ok((String::from("fd://0"), vec![fd]))
+        }
+        None => Ok((String::from(url), Vec::new())),
+    }
+}
+
+fn snapshot_config(url: &str) -> Result<(String, Vec<i32>), Error> {
+    let (destination_url, fds) = snapshot_stream_fds(url)?;
+    let snapshot_config = vmm::api::VmSnapshotConfig { destination_url };
+    let snapshot_config = serde_json::to_string(&snapshot_config).unwrap();
+
+    Ok((snapshot_config, fds))
+}
+
+fn restore_config(config: &str) -> Result<(String, Vec<i32>), Error> {
+    let mut restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::Restore)?;
+    let (source_url, fds) = snapshot_stream_fds(&restore_config.source_url.to_string_lossy())?;
+    restore_config.source_url = source_url.into();
     let restore_config = serde_json::to_string(&restore_config).unwrap();
 
-    Ok(restore_config)
+    Ok((restore_config, fds))
 }
 
 fn coredump_config(destination_url: &str) -> String {
@@ -933,6 +1777,24 @@ fn main() {
                     .help(vmm::config::DeviceConfig::SYNTAX),
             ),
         )
+        .subcommand(
+            Command::new("add-vf")
+                .about("Create an SR-IOV virtual function and add it as a VFIO device")
+                .arg(
+                    Arg::new("vf_config")
+                        .index(1)
+                        .help(vmm::config::VfConfig::SYNTAX),
+                ),
+        )
+        .subcommand(
+            Command::new("add-mdev")
+                .about("Create a mediated device and add it as a VFIO device")
+                .arg(
+                    Arg::new("mdev_config")
+                        .index(1)
+                        .help(vmm::config::MdevConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             Command::new("add-disk").about("Add block device").arg(
                 Arg::new("disk_config")
@@ -988,16 +1850,162 @@ fn main() {
                     .help(vmm::config::VsockConfig::SYNTAX),
             ),
         )
+        .subcommand(
+            Command::new("add-console-port")
+                .about("Add virtio-console port")
+                .arg(
+                    Arg::new("console_port_config")
+                        .index(1)
+                        .help(vmm::config::ConsolePortConfig::SYNTAX),
+                ),
+        )
         .subcommand(
             Command::new("remove-device")
                 .about("Remove VFIO device")
                 .arg(Arg::new("id").index(1).help("<device_id>")),
         )
+        .subcommand(
+            Command::new("remove-console-port")
+                .about("Remove virtio-console port")
+                .arg(Arg::new("id").index(1).help("<port_id>")),
+        )
+        .subcommand(
+            Command::new("remove-vcpu")
+                .about("Remove a specific vCPU")
+                .arg(Arg::new("id").index(1).help("<cpu_id>")),
+        )
+        .subcommand(
+            Command::new("set-cpu-affinity")
+                .about("Change the host CPUs vCPUs run onto")
+                .arg(
+                    Arg::new("affinity")
+                        .index(1)
+                        .help("[<vcpu>@[<host_cpus>],...]"),
+                ),
+        )
+        .subcommand(
+            Command::new("set-cpu-bandwidth")
+                .about("Change the CPU bandwidth limits, unlimited when omitted")
+                .arg(
+                    Arg::new("cpu-max")
+                        .long("cpu-max")
+                        .help("Limit of the whole VM, <quota_us>:<period_us>")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("vcpu-max")
+                        .long("vcpu-max")
+                        .help("Limit of each vCPU, <quota_us>:<period_us>")
+                        .num_args(1),
+                ),
+        )
         .subcommand(Command::new("info").about("Info on the VM"))
-        .subcommand(Command::new("counters").about("Counters from the VM"))
+        .subcommand(
+            Command::new("counters").about("Counters from the VM").arg(
+                Arg::new("rates")
+                    .long("rates")
+                    .help("Increase of the counters per second, over the last sampling interval")
+                    .num_args(0)
+                    .action(ArgAction::SetTrue),
+            ),
+        )
+        .subcommand(
+            Command::new("memory-read")
+                .about("Read guest memory, hex encoded")
+                .arg(
+                    Arg::new("address")
+                        .index(1)
+                        .required(true)
+                        .help("<guest_physical_address>"),
+                )
+                .arg(
+                    Arg::new("length")
+                        .index(2)
+                        .required(true)
+                        .help("<length_in_bytes>"),
+                ),
+        )
+        .subcommand(
+            Command::new("memory-write")
+                .about("Write guest memory while the VM is paused")
+                .arg(
+                    Arg::new("address")
+                        .index(1)
+                        .required(true)
+                        .help("<guest_physical_address>"),
+                )
+                .arg(
+                    Arg::new("data")
+                        .index(2)
+                        .required(true)
+                        .help("<hex_encoded_bytes>"),
+                ),
+        )
+        .subcommand(
+            Command::new("virtqueue-state")
+                .about("Dump the state of the virtqueues of a device")
+                .arg(Arg::new("id").index(1).required(true).help("<device_id>")),
+        )
+        .subcommand(
+            Command::new("guest-exec")
+                .about("Run a command in the guest through the guest agent")
+                .arg(
+                    Arg::new("path")
+                        .index(1)
+                        .required(true)
+                        .help("<binary_path>"),
+                )
+                .arg(
+                    Arg::new("args")
+                        .index(2)
+                        .num_args(0..)
+                        .trailing_var_arg(true)
+                        .help("Arguments passed to the binary"),
+                )
+                .arg(
+                    Arg::new("env")
+                        .long("env")
+                        .help("Environment variable of the command, NAME=value")
+                        .num_args(1)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("capture-output")
+                        .long("capture-output")
+                        .help("Capture the output of the command")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("wait")
+                        .long("wait")
+                        .help("Wait for the command to exit and print its status")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("guest-fsfreeze")
+                .about("Freeze or thaw the guest filesystems through the guest agent")
+                .arg(
+                    Arg::new("action")
+                        .index(1)
+                        .value_parser(["freeze", "thaw", "status"])
+                        .default_value("status"),
+                ),
+        )
+        .subcommand(Command::new("guest-info").about("Info on the guest agent"))
+        .subcommand(
+            Command::new("hetero-balloon")
+                .about("State of the heterogeneous memory balloon controller"),
+        )
         .subcommand(Command::new("pause").about("Pause the VM"))
         .subcommand(Command::new("reboot").about("Reboot the VM"))
         .subcommand(Command::new("power-button").about("Trigger a power button in the VM"))
+        .subcommand(
+            Command::new("report-free-pages")
+                .about("Ask the guest to report its free pages through the balloon"),
+        )
         .subcommand(
             Command::new("resize")
                 .about("Resize the VM")
@@ -1036,8 +2044,43 @@ fn main() {
                         .num_args(1),
                 ),
         )
+        .subcommand(
+            Command::new("bind-zone")
+                .about("Bind a memory zone to a host NUMA node")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("Memory zone identifier")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("host_numa_node")
+                        .long("host_numa_node")
+                        .help("Host NUMA node to move the memory zone to")
+                        .num_args(1),
+                ),
+        )
+        .subcommand(
+            Command::new("resize-fs")
+                .about("Resize the DAX window of a virtio-fs device")
+                .arg(
+                    Arg::new("id")
+                        .long("id")
+                        .help("virtio-fs device identifier")
+                        .num_args(1),
+                )
+                .arg(
+                    Arg::new("size")
+                        .long("size")
+                        .help("New DAX window size in bytes (supports K/M/G suffix)")
+                        .num_args(1),
+                ),
+        )
         .subcommand(Command::new("resume").about("Resume the VM"))
         .subcommand(Command::new("boot").about("Boot a created VM"))
+        .subcommand(
+            Command::new("boot-timings").about("Breakdown of the time spent booting the VM"),
+        )
         .subcommand(Command::new("delete").about("Delete a VM"))
         .subcommand(Command::new("shutdown").about("Shutdown the VM"))
         .subcommand(
@@ -1046,7 +2089,7 @@ fn main() {
                 .arg(
                     Arg::new("snapshot_config")
                         .index(1)
-                        .help("<destination_url>"),
+                        .help("<destination_url> (file:///<dir>, unix:<socket> or fd://<fd>)"),
                 ),
         )
         .subcommand(
@@ -1078,6 +2121,21 @@ fn main() {
                         .action(ArgAction::SetTrue),
                 ),
         )
+        .subcommand(
+            Command::new("migration-precheck")
+                .about("Check a VM migration against its destination")
+                .arg(
+                    Arg::new("send_migration_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                )
+                .arg(
+                    Arg::new("send_migration_local")
+                        .long("local")
+                        .num_args(0)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
         .subcommand(
             Command::new("receive-migration")
                 .about("Receive a VM migration")
@@ -1092,9 +2150,42 @@ fn main() {
                 .about("Create VM from a JSON configuration")
                 .arg(Arg::new("path").index(1).default_value("-")),
         )
+        .subcommand(
+            Command::new("watch")
+                .about("Print the counters as they change, or the events as they are reported")
+                .arg(
+                    Arg::new("what")
+                        .index(1)
+                        .value_parser(["counters", "events"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .help("Polling interval, in seconds")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1"),
+                ),
+        )
         .subcommand(Command::new("ping").about("Ping the VMM to check for API server availability"))
+        .subcommand(
+            Command::new("resources")
+                .about("Resources used by the VMM threads since the last call"),
+        )
         .subcommand(Command::new("shutdown-vmm").about("Shutdown the VMM"));
 
+    #[cfg(target_arch = "x86_64")]
+    let app = app.subcommand(
+        Command::new("add-sgx-epc")
+            .about("Add SGX EPC section, exposed to the guest on its next boot")
+            .arg(
+                Arg::new("sgx_epc_config")
+                    .index(1)
+                    .help(vmm::config::SgxEpcConfig::SYNTAX),
+            ),
+    );
+
     let matches = app.get_matches();
 
     let mut target_api = match (
@@ -9,12 +9,13 @@ extern crate event_monitor;
 use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use libc::EFD_NONBLOCK;
 use log::{warn, LevelFilter};
-use option_parser::{NanosecTimed, OptionParser};
+use option_parser::{ByteSized, NanosecTimed, OptionParser, StringList};
 use seccompiler::SeccompAction;
 use signal_hook::consts::SIGSYS;
 use std::env;
 use std::fs::File;
 use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use thiserror::Error;
@@ -72,6 +73,28 @@ enum Error {
     EventMonitorIo(std::io::Error),
     #[error("Event monitor thread failed: {0}")]
     EventMonitorThread(#[source] vmm::Error),
+    #[cfg(feature = "tls_api")]
+    #[error("Error parsing --api-tls: {0}")]
+    ParsingApiTls(option_parser::OptionParserError),
+    #[cfg(feature = "tls_api")]
+    #[error("Error parsing --api-tls: listen, cert, key and client_ca required")]
+    BareApiTls,
+    #[error("Error parsing --api-audit-log: {0}")]
+    ParsingApiAuditLog(option_parser::OptionParserError),
+    #[error("Error parsing --api-audit-log: path required")]
+    BareApiAuditLog,
+    #[error("Error opening the API audit log: {0}")]
+    ApiAuditLogIo(std::io::Error),
+    #[error("Error parsing --jail: {0}")]
+    ParsingJail(option_parser::OptionParserError),
+    #[error("Error parsing --jail: path required")]
+    BareJail,
+    #[error("Error entering the jail: {0}")]
+    EnterJail(#[source] vmm::jail::Error),
+    #[error("Error starting the virtio-fs launcher: {0}")]
+    VirtiofsLauncher(#[source] vmm::virtiofsd::Error),
+    #[error("Error setting up the VM lifecycle hooks: {0}")]
+    Hooks(#[source] vmm::hooks::Error),
     #[cfg(feature = "guest_debug")]
     #[error("Error parsing --gdb: {0}")]
     ParsingGdb(option_parser::OptionParserError),
@@ -169,8 +192,14 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .help(
                     "boot=<boot_vcpus>,max=<max_vcpus>,\
                     topology=<threads_per_core>:<cores_per_die>:<dies_per_package>:<packages>,\
-                    kvm_hyperv=on|off,max_phys_bits=<maximum_number_of_physical_bits>,\
+                    kvm_hyperv=on|off,kvm_hyperv_features=<list_of_hyperv_features>,\
+                    max_phys_bits=<maximum_number_of_physical_bits>,\
                     affinity=<list_of_vcpus_with_their_associated_cpuset>,\
+                    efficiency_cores=<list_of_efficiency_vcpus>,\
+                    pmu=on|off,pmu_events=<list_of_allowed_pmu_events>,nested=on|off,\
+                    model=<cpu_model>,cpuid_add=<list_of_cpuid_features>,\
+                    cpuid_remove=<list_of_cpuid_features>,\
+                    cpu_max=<quota_us>:<period_us>,vcpu_max=<quota_us>:<period_us>,\
                     features=<list_of_features_to_enable>",
                 )
                 .default_value(default_vcpus)
@@ -179,10 +208,24 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("platform")
                 .long("platform")
-                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>")
+                .help("num_pci_segments=<num_pci_segments>,iommu_segments=<list_of_segments>,manufacturer=<dmi_system_manufacturer>,product=<dmi_system_product_name>,serial_number=<dmi_device_serial_number>,uuid=<dmi_device_uuid>,oem_strings=<list_of_strings>,s3=on|off,s4=on|off,hibernate_action=exit|shutdown|reboot,interrupt_remapping=on|off")
                 .num_args(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("pci-segment")
+                .long("pci-segment")
+                .help(config::PciSegmentConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("pci-root-port")
+                .long("pci-root-port")
+                .help(config::PciRootPortConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("memory")
                 .long("memory")
@@ -209,7 +252,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                      host_numa_node=<node_id>,\
                      id=<zone_identifier>,hotplug_size=<hotpluggable_memory_size>,\
                      hotplugged_size=<hotplugged_memory_size>,\
-                     prefault=on|off\"",
+                     prefault=on|off,overcommit=on|off\"",
                 )
                 .num_args(1..)
                 .group("vm-config"),
@@ -262,9 +305,7 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
         .arg(
             Arg::new("rng")
                 .long("rng")
-                .help(
-                    "Random number generator parameters \"src=<entropy_source_path>,iommu=on|off\"",
-                )
+                .help(config::RngConfig::SYNTAX)
                 .default_value(default_rng)
                 .group("vm-config"),
         )
@@ -289,10 +330,17 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1..)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("ivshmem")
+                .long("ivshmem")
+                .help(config::IvshmemConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("serial")
                 .long("serial")
-                .help("Control serial port: off|null|pty|tty|file=</path/to/a/file>|socket=</path/to/a/file>")
+                .help("Control serial port: off|null|pty|tty|file=</path/to/a/file>|socket=</path/to/a/file>|socket_dir=</path/to/a/directory>|tcp=<address>:<port>,telnet=on|off")
                 .default_value("null")
                 .group("vm-config"),
         )
@@ -300,11 +348,25 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             Arg::new("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|pty|tty|file=</path/to/a/file>,iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=</path/to/a/file>,iommu=on|off,max_ports=<ports>,socket_dir=</path/to/a/directory>\"",
                 )
                 .default_value("tty")
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("console-port")
+                .long("console-port")
+                .help(config::ConsolePortConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("debug-console")
+                .long("debug-console")
+                .help(config::DebugConsoleConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("device")
                 .long("device")
@@ -333,6 +395,13 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("guest-agent")
+                .long("guest-agent")
+                .help(config::GuestAgentConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("pvpanic")
                 .long("pvpanic")
@@ -341,6 +410,20 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .action(ArgAction::SetTrue)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("pvpanic-policy")
+                .long("pvpanic-policy")
+                .help(config::PvPanicPolicyConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("on-crash")
+                .long("on-crash")
+                .help(config::OnCrashConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("numa")
                 .long("numa")
@@ -356,6 +439,20 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .action(ArgAction::SetTrue)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::new("watchdog-action")
+                .long("watchdog-action")
+                .help("Action on virtio-watchdog expiry: reset|poweroff|pause|event|panic")
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("watchdog-escalation")
+                .long("watchdog-escalation")
+                .help(config::WatchdogEscalationConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::new("v")
                 .short('v')
@@ -384,6 +481,41 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::new("api-audit-log")
+                .long("api-audit-log")
+                .help(
+                    "File to record the API requests to: \
+                     path=</path/to/a/file>,max_size=<rotation_size>,max_files=<rotated_files>",
+                )
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("api-memory-access")
+                .long("api-memory-access")
+                .help("Allow the guest memory to be read and written through the API")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("jail")
+                .long("jail")
+                .help(
+                    "Confine the VMM into the given root directory: \
+                     path=</path/to/jail>,uid=<user_id>,gid=<group_id>,bind=[<path>,...]",
+                )
+                .num_args(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("hook")
+                .long("hook")
+                .help(vmm::hooks::HookConfig::SYNTAX)
+                .num_args(1..)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::new("restore")
                 .long("restore")
@@ -404,6 +536,49 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .num_args(1)
                 .help(config::TpmConfig::SYNTAX)
                 .group("vmm-config"),
+        )
+        .arg(
+            Arg::new("acpi-table")
+                .long("acpi-table")
+                .help(config::AcpiTableConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("cgroup")
+                .long("cgroup")
+                .help(config::CgroupConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("process-limits")
+                .long("process-limits")
+                .help(config::ProcessLimitsConfig::SYNTAX)
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("seccomp-override")
+                .long("seccomp-override")
+                .help(config::SeccompOverrideConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("landlock")
+                .long("landlock")
+                .help("Restrict the VMM to the paths of the VM configuration with Landlock")
+                .num_args(0)
+                .action(ArgAction::SetTrue)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("landlock-rules")
+                .long("landlock-rules")
+                .help(config::LandlockConfig::SYNTAX)
+                .num_args(1..)
+                .group("vm-config"),
         );
 
     #[cfg(target_arch = "x86_64")]
@@ -415,6 +590,41 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
             .group("vm-config"),
     );
 
+    #[cfg(target_arch = "x86_64")]
+    let app = app.arg(
+        Arg::new("msr-filter")
+            .long("msr-filter")
+            .help(config::MsrFilterConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
+    );
+
+    #[cfg(target_arch = "x86_64")]
+    let app = app.arg(
+        Arg::new("uefi-vars")
+            .long("uefi-vars")
+            .help(config::UefiVarsConfig::SYNTAX)
+            .num_args(1)
+            .group("vm-config"),
+    );
+
+    #[cfg(feature = "sev_snp")]
+    let app = app
+        .arg(
+            Arg::new("igvm")
+                .long("igvm")
+                .help("Path to IGVM file to load")
+                .num_args(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::new("host-data")
+                .long("host-data")
+                .help("Host specific data to SEV-SNP guest, 32 bytes in hexadecimal")
+                .num_args(1)
+                .group("vm-config"),
+        );
+
     #[cfg(feature = "guest_debug")]
     let app = app.arg(
         Arg::new("gdb")
@@ -449,6 +659,19 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
                 .group("vmm-config"),
         );
 
+    #[cfg(feature = "tls_api")]
+    let app = app.arg(
+        Arg::new("api-tls")
+            .long("api-tls")
+            .help(
+                "Serve the HTTP API over TCP with TLS: \
+                 listen=<address:port>,cert=</path/to/cert.pem>,key=</path/to/key.pem>,\
+                 client_ca=</path/to/ca.pem>",
+            )
+            .num_args(1)
+            .group("vmm-config"),
+    );
+
     let app = app.arg(
         Arg::new("hmem")
             .long("hmem")
@@ -469,6 +692,16 @@ fn create_app(default_vcpus: String, default_memory: String, default_rng: String
     )
 }
 
+// Whether the command line describes a VM to create and boot.
+fn payload_present(cmd_arguments: &ArgMatches) -> bool {
+    let payload_present =
+        cmd_arguments.contains_id("kernel") || cmd_arguments.contains_id("firmware");
+    #[cfg(feature = "sev_snp")]
+    let payload_present = payload_present || cmd_arguments.contains_id("igvm");
+
+    payload_present
+}
+
 fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     let log_level = match cmd_arguments.get_count("v") {
         0 => LevelFilter::Warn,
@@ -612,6 +845,30 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
 
     let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateExitEventFd)?;
 
+    if let Some(audit_log_config) = cmd_arguments.get_one::<String>("api-audit-log") {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("max_size").add("max_files");
+        parser
+            .parse(audit_log_config)
+            .map_err(Error::ParsingApiAuditLog)?;
+
+        let path = parser.get("path").ok_or(Error::BareApiAuditLog)?;
+        let max_size = parser
+            .convert::<ByteSized>("max_size")
+            .map_err(Error::ParsingApiAuditLog)?
+            .map_or(vmm::api::audit::DEFAULT_AUDIT_LOG_SIZE, |s| s.0);
+        let max_files = parser
+            .convert("max_files")
+            .map_err(Error::ParsingApiAuditLog)?
+            .unwrap_or(vmm::api::audit::DEFAULT_AUDIT_LOG_FILES);
+        vmm::api::audit::set_audit_log(std::path::Path::new(&path), max_size, max_files)
+            .map_err(Error::ApiAuditLogIo)?;
+    }
+
+    if cmd_arguments.get_flag("api-memory-access") {
+        vmm::api::enable_memory_access();
+    }
+
     #[allow(unused_mut)]
     let mut event_monitor = cmd_arguments
         .get_one::<String>("event-monitor")
@@ -675,16 +932,128 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         (None, None) => Ok(None),
     }?;
 
-    if let Some(monitor) = event_monitor {
-        vmm::start_event_monitor_thread(
-            monitor,
-            &seccomp_action,
-            hypervisor.hypervisor_type(),
-            exit_evt.try_clone().unwrap(),
-        )
-        .map_err(Error::EventMonitorThread)?;
+    // The events are kept for the API clients polling them, even when they
+    // aren't reported to a file.
+    let event_monitor = match event_monitor {
+        Some(monitor) => monitor,
+        None => event_monitor::set_monitor(None).map_err(Error::EventMonitorIo)?,
+    };
+
+    #[cfg(feature = "tls_api")]
+    let tls_options = cmd_arguments
+        .get_one::<String>("api-tls")
+        .map(|tls_config| {
+            let mut parser = OptionParser::new();
+            parser.add("listen").add("cert").add("key").add("client_ca");
+            parser.parse(tls_config).map_err(Error::ParsingApiTls)?;
+
+            let listen = parser
+                .convert("listen")
+                .map_err(Error::ParsingApiTls)?
+                .ok_or(Error::BareApiTls)?;
+            let path = |option: &str| {
+                parser
+                    .get(option)
+                    .map(PathBuf::from)
+                    .ok_or(Error::BareApiTls)
+            };
+            Ok(vmm::api::TlsApiOptions {
+                listen,
+                cert: path("cert")?,
+                key: path("key")?,
+                client_ca: path("client_ca")?,
+            })
+        })
+        .transpose()?;
+
+    let payload_present = payload_present(&cmd_arguments);
+    let vm_config = if payload_present {
+        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+        Some(config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?)
+    } else {
+        None
+    };
+    let restore_config = if payload_present {
+        None
+    } else {
+        cmd_arguments
+            .get_one::<String>("restore")
+            .map(|restore_params| config::RestoreConfig::parse(restore_params))
+            .transpose()
+            .map_err(Error::ParsingRestore)?
+    };
+
+    // The daemons of the shared directories are spawned from outside of the
+    // jail, and without the seccomp filters of the VMM.
+    vmm::virtiofsd::start_virtiofs_launcher().map_err(Error::VirtiofsLauncher)?;
+
+    // The hooks are run from outside of the jail, and without the seccomp
+    // filters of the VMM.
+    let hooks = cmd_arguments
+        .get_many::<String>("hook")
+        .map(|hooks| {
+            hooks
+                .map(|hook| vmm::hooks::HookConfig::parse(hook))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .map_err(Error::Hooks)?
+        .unwrap_or_default();
+    vmm::hooks::start_hooks(hooks).map_err(Error::Hooks)?;
+
+    // The jail must be entered while the VMM is single threaded.
+    if let Some(jail_config) = cmd_arguments.get_one::<String>("jail") {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("uid").add("gid").add("bind");
+        parser.parse(jail_config).map_err(Error::ParsingJail)?;
+
+        let jail_config = vmm::jail::JailConfig {
+            path: parser
+                .get("path")
+                .map(PathBuf::from)
+                .ok_or(Error::BareJail)?,
+            uid: parser.convert("uid").map_err(Error::ParsingJail)?,
+            gid: parser.convert("gid").map_err(Error::ParsingJail)?,
+            bind: parser
+                .convert::<StringList>("bind")
+                .map_err(Error::ParsingJail)?
+                .map_or(Vec::new(), |list| {
+                    list.0.into_iter().map(PathBuf::from).collect()
+                }),
+        };
+
+        // The API socket is created once jailed, and a restored VM is read
+        // from the snapshot directory.
+        let mut extra_paths: Vec<PathBuf> = api_socket_path.iter().map(PathBuf::from).collect();
+        if let Some(restore_config) = &restore_config {
+            if let Ok(path) =
+                vmm::migration::url_to_path(&restore_config.source_url.to_string_lossy())
+            {
+                extra_paths.push(path);
+            }
+        }
+        // The certificates and the key are read once the VMM is jailed.
+        #[cfg(feature = "tls_api")]
+        if let Some(options) = &tls_options {
+            extra_paths.extend([
+                options.cert.clone(),
+                options.key.clone(),
+                options.client_ca.clone(),
+            ]);
+        }
+
+        vmm::jail::enter_jail(&jail_config, vm_config.as_ref(), &extra_paths)
+            .map_err(Error::EnterJail)?;
     }
 
+    vmm::start_event_monitor_thread(
+        event_monitor,
+        &seccomp_action,
+        hypervisor.hypervisor_type(),
+        exit_evt.try_clone().unwrap(),
+    )
+    .map_err(Error::EventMonitorThread)?;
+
     event!("vmm", "starting");
 
     let vmm_thread_handle = vmm::start_vmm_thread(
@@ -693,6 +1062,8 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         api_socket_fd,
         #[cfg(feature = "dbus_api")]
         dbus_options,
+        #[cfg(feature = "tls_api")]
+        tls_options,
         api_evt.try_clone().unwrap(),
         api_request_sender_clone,
         api_request_receiver,
@@ -709,13 +1080,7 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
     .map_err(Error::StartVmmThread)?;
 
     let r: Result<(), Error> = (|| {
-        let payload_present =
-            cmd_arguments.contains_id("kernel") || cmd_arguments.contains_id("firmware");
-
-        if payload_present {
-            let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-            let vm_config = config::VmConfig::parse(vm_params).map_err(Error::ParsingConfig)?;
-
+        if let Some(vm_config) = vm_config {
             // Create and boot the VM based off the VM config we just built.
             let sender = api_request_sender.clone();
             vmm::api::vm_create(
@@ -725,13 +1090,11 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
             )
             .map_err(Error::VmCreate)?;
             vmm::api::vm_boot(api_evt.try_clone().unwrap(), sender).map_err(Error::VmBoot)?;
-        } else if let Some(restore_params) = cmd_arguments.get_one::<String>("restore") {
+        } else if let Some(restore_config) = restore_config {
             vmm::api::vm_restore(
                 api_evt.try_clone().unwrap(),
                 api_request_sender.clone(),
-                Arc::new(
-                    config::RestoreConfig::parse(restore_params).map_err(Error::ParsingRestore)?,
-                ),
+                Arc::new(restore_config),
             )
             .map_err(Error::VmRestore)?;
         }
@@ -766,6 +1129,13 @@ fn start_vmm(cmd_arguments: ArgMatches) -> Result<Option<String>, Error> {
         dbus_api_graceful_shutdown(chs);
     }
 
+    #[cfg(feature = "tls_api")]
+    if let Some(handle) = vmm_thread_handle.https_api_handle {
+        if let Err(e) = handle.shutdown() {
+            warn!("Error shutting down the HTTPS API: {}", e);
+        }
+    }
+
     r.map(|_| api_socket_path)
 }
 
@@ -813,11 +1183,11 @@ fn main() {
 #[cfg(test)]
 mod unit_tests {
     use crate::config::HotplugMethod;
-    use crate::{create_app, prepare_default_values};
+    use crate::{create_app, payload_present, prepare_default_values};
     use std::path::PathBuf;
     use vmm::config::{
         ConsoleConfig, ConsoleOutputMode, CpuFeatures, CpusConfig, MemoryConfig, PayloadConfig,
-        RngConfig, VmConfig, VmParams,
+        RngConfig, VmConfig, VmParams, WatchdogAction,
     };
 
     fn get_vm_config_from_vec(args: &[&str]) -> VmConfig {
@@ -861,9 +1231,20 @@ mod unit_tests {
                 max_vcpus: 1,
                 topology: None,
                 kvm_hyperv: false,
+                kvm_hyperv_features: None,
                 max_phys_bits: 46,
                 affinity: None,
+                efficiency_cores: None,
+                pmu: true,
+                pmu_events: None,
+                nested: false,
+                model: None,
+                cpuid_add: None,
+                cpuid_remove: None,
+                cpu_max: None,
+                vcpu_max: None,
                 features: CpuFeatures::default(),
+                removed_vcpus: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -887,36 +1268,67 @@ mod unit_tests {
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
                 iommu: false,
+                rate_limiter_config: None,
+                mmio: false,
+                removed: false,
             },
             balloon: None,
             fs: None,
             pmem: None,
+            ivshmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
             },
+            console_ports: None,
+            debug_console: None,
             devices: None,
             user_devices: None,
             vdpa: None,
             vsock: None,
+            guest_agent: None,
             pvpanic: false,
+            pvpanic_policy: None,
+            on_crash: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: None,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars: None,
             numa: None,
             watchdog: false,
+            watchdog_action: WatchdogAction::Reset,
+            watchdog_escalation: None,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             platform: None,
+            pci_segments: None,
+            pci_root_ports: None,
             tpm: None,
+            acpi_tables: None,
+            cgroup: None,
+            process_limits: None,
+            seccomp_overrides: None,
+            landlock_enable: false,
+            landlock_rules: None,
             preserved_fds: None,
         };
 
@@ -1613,6 +2025,53 @@ mod unit_tests {
         });
     }
 
+    #[test]
+    fn test_valid_vm_config_console_ports() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--console",
+                    "tty,max_ports=3",
+                    "--console-port",
+                    "name=org.example.agent,socket=/path/to/agent.sock",
+                    "id=port1",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "console": {"mode": "Tty", "max_ports": 3},
+                    "console_ports": [
+                        {"name": "org.example.agent", "socket": "/path/to/agent.sock"},
+                        {"id": "port1"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--console",
+                    "tty,max_ports=3",
+                    "--console-port",
+                    "name=org.example.agent",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "console": {"mode": "Tty", "max_ports": 3}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_valid_vm_config_devices() {
@@ -1836,6 +2295,88 @@ mod unit_tests {
         });
     }
 
+    #[test]
+    fn test_valid_vm_config_guest_agent() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--vsock",
+                    "cid=123,socket=/path/to/sock/1",
+                    "--guest-agent",
+                    "port=4321",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "vsock": {"cid": 123, "socket": "/path/to/sock/1"},
+                    "guest_agent": {"port": 4321}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--vsock",
+                    "cid=123,socket=/path/to/sock/1",
+                    "--guest-agent",
+                    "port=4321,timeout=30",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "vsock": {"cid": 123, "socket": "/path/to/sock/1"},
+                    "guest_agent": {"port": 4321}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_debug_console() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--debug-console",
+                    "file=/tmp/debug.log",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "debug_console": {"mode": "File", "file": "/tmp/debug.log"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--debug-console",
+                    "socket=/tmp/debug.sock",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "debug_console": {"mode": "File", "file": "/tmp/debug.sock"}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
     #[test]
     fn test_valid_vm_config_tpm_socket() {
         [(
@@ -1857,4 +2398,302 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_valid_vm_config_uefi_vars() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--firmware",
+                    "/path/to/firmware",
+                    "--uefi-vars",
+                    "path=/path/to/vars.fd",
+                ],
+                r#"{
+                    "payload": {"firmware": "/path/to/firmware"},
+                    "uefi_vars": {"path": "/path/to/vars.fd"}
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--firmware",
+                    "/path/to/firmware",
+                    "--uefi-vars",
+                    "path=/path/to/vars.fd,pk=/path/to/pk.der,db=[/path/to/db.der]",
+                ],
+                r#"{
+                    "payload": {"firmware": "/path/to/firmware"},
+                    "uefi_vars": {
+                        "path": "/path/to/vars.fd",
+                        "pk": "/path/to/pk.der",
+                        "db": ["/path/to/db.der"]
+                    }
+                }"#,
+                true,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_acpi_tables() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--acpi-table",
+                "path=/path/to/ssdt.aml",
+                "path=/path/to/oem.aml",
+            ],
+            r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "acpi_tables": [
+                        {"path": "/path/to/ssdt.aml"},
+                        {"path": "/path/to/oem.aml"}
+                    ]
+                }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_cgroup() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--cgroup",
+                "path=/machine/vm0,memory_max=1G,io_max=[8:0 wbps=1048576],device_cpu_max=[_net0@20000:100000]",
+            ],
+            r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "cgroup": {
+                        "path": "/machine/vm0",
+                        "memory_max": 1073741824,
+                        "io_max": [{"major": 8, "minor": 0, "wbps": 1048576}],
+                        "device_cpu_max": [{"id": "_net0", "cpu_max": {"quota": 20000, "period": 100000}}]
+                    }
+                }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_process_limits() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--process-limits",
+                "nofile=65536,memlock=1G,oom_score_adj=-500",
+            ],
+            r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "process_limits": {"nofile": 65536, "memlock": 1073741824, "oom_score_adj": -500}
+                }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_seccomp_overrides() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--kernel",
+                "/path/to/kernel",
+                "--seccomp-override",
+                "id=__balloon,deny=[fallocate]",
+                "id=disk0,allow=[statx]",
+            ],
+            r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "seccomp_overrides": [
+                        {"id": "__balloon", "deny": ["fallocate"]},
+                        {"id": "disk0", "allow": ["statx"]}
+                    ]
+                }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_valid_vm_config_landlock() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--landlock",
+                    "--landlock-rules",
+                    "path=/path/to/snapshots,access=rw",
+                    "path=/path/to/images,access=r",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "landlock_enable": true,
+                    "landlock_rules": [
+                        {"path": "/path/to/snapshots", "access": "rw"},
+                        {"path": "/path/to/images", "access": "r"}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--landlock",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"}
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[cfg(feature = "sev_snp")]
+    #[test]
+    fn test_valid_vm_config_igvm() {
+        [(
+            vec![
+                "cloud-hypervisor",
+                "--igvm",
+                "/path/to/igvm",
+                "--host-data",
+                "243eb7dc1a21129caa91dcbb794922b933baecb5823a377eb431188673288c07",
+                "--platform",
+                "sev_snp=on",
+            ],
+            r#"{
+                "payload": {
+                    "igvm": "/path/to/igvm",
+                    "host_data": "243eb7dc1a21129caa91dcbb794922b933baecb5823a377eb431188673288c07"
+                },
+                "platform": {"sev_snp": true}
+            }"#,
+            true,
+        )]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
+
+    #[test]
+    fn test_payload_present() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+        let app = create_app(default_vcpus, default_memory, default_rng);
+
+        for (cli, present) in [
+            (
+                vec!["cloud-hypervisor", "--kernel", "/path/to/kernel"],
+                true,
+            ),
+            (vec!["cloud-hypervisor", "--firmware", "/path/to/fw"], true),
+            (vec!["cloud-hypervisor", "--api-socket", "/tmp/api"], false),
+            #[cfg(feature = "sev_snp")]
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--igvm",
+                    "/path/to/igvm",
+                    "--platform",
+                    "sev_snp=on",
+                ],
+                true,
+            ),
+        ] {
+            let cmd_arguments = app.clone().get_matches_from(&cli);
+            assert_eq!(payload_present(&cmd_arguments), present, "{cli:?}");
+        }
+    }
+
+    #[test]
+    fn test_valid_vm_config_watchdog() {
+        [
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--watchdog",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "watchdog": true,
+                    "watchdog_action": "Reset"
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--watchdog",
+                    "--watchdog-action",
+                    "pause",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "watchdog": true,
+                    "watchdog_action": "Pause"
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--kernel",
+                    "/path/to/kernel",
+                    "--watchdog",
+                    "--watchdog-action",
+                    "event",
+                ],
+                r#"{
+                    "payload": {"kernel": "/path/to/kernel"},
+                    "watchdog": true
+                }"#,
+                false,
+            ),
+        ]
+        .iter()
+        .for_each(|(cli, openapi, equal)| {
+            compare_vm_config_cli_vs_json(cli, openapi, *equal);
+        });
+    }
 }
@@ -12,6 +12,8 @@ mod tracer_noop;
 #[cfg(not(feature = "tracing"))]
 pub use tracer_noop::*;
 
+#[cfg(feature = "tracing")]
+mod otlp;
 #[cfg(feature = "tracing")]
 mod tracer;
 #[cfg(feature = "tracing")]
@@ -0,0 +1,233 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Export of the trace events to an OpenTelemetry collector.
+//!
+//! The collector is found from the standard `OTEL_EXPORTER_OTLP_ENDPOINT` and
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` environment variables. Each completed
+//! block is turned into a span, nested under the block it was opened from, and
+//! the spans are periodically sent with OTLP over HTTP, using the JSON
+//! encoding, all of them belonging to a single trace.
+
+use crate::tracer::TraceEvent;
+use serde_json::{json, Value};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+const OTLP_TRACES_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT";
+const OTLP_TRACES_PATH: &str = "/v1/traces";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+// Interval between two exports of the pending spans
+pub(crate) const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const SPAN_KIND_INTERNAL: u32 = 1;
+
+struct Span {
+    thread_name: String,
+    event: TraceEvent,
+}
+
+pub(crate) struct OtlpExporter {
+    // Host and port of the collector
+    authority: String,
+    path: String,
+    trace_id: [u8; 16],
+    // Time of the start of the tracer, in nanoseconds since the epoch
+    start_time: u64,
+    pending: Mutex<Vec<Span>>,
+}
+
+impl OtlpExporter {
+    /// Create the exporter if a collector is configured.
+    pub(crate) fn from_env(start_time: SystemTime) -> Option<Self> {
+        let endpoint = match env::var(OTLP_TRACES_ENDPOINT) {
+            Ok(endpoint) => endpoint,
+            Err(_) => format!(
+                "{}{}",
+                env::var(OTLP_ENDPOINT).ok()?.trim_end_matches('/'),
+                OTLP_TRACES_PATH
+            ),
+        };
+        let (authority, path) = match parse_endpoint(&endpoint) {
+            Some(endpoint) => endpoint,
+            None => {
+                warn!("Unsupported OTLP endpoint, only http:// is supported: {endpoint}");
+                return None;
+            }
+        };
+
+        Some(OtlpExporter {
+            authority,
+            path,
+            trace_id: trace_id(),
+            start_time: start_time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub(crate) fn push(&self, thread_name: &str, event: &TraceEvent) {
+        self.pending.lock().unwrap().push(Span {
+            thread_name: thread_name.to_string(),
+            event: event.clone(),
+        });
+    }
+
+    /// Send the spans completed since the previous export.
+    pub(crate) fn flush(&self) {
+        let spans: Vec<Span> = self.pending.lock().unwrap().drain(..).collect();
+        if spans.is_empty() {
+            return;
+        }
+
+        let body = self.request(&spans).to_string();
+        if let Err(e) = self.post(body.as_bytes()) {
+            warn!("Failed to export {} spans: {}", spans.len(), e);
+        }
+    }
+
+    // ExportTraceServiceRequest, as encoded in JSON
+    fn request(&self, spans: &[Span]) -> Value {
+        let spans: Vec<Value> = spans.iter().map(|s| self.span(s)).collect();
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        string_attribute("service.name", "cloud-hypervisor"),
+                        // SAFETY: FFI call
+                        string_attribute("process.pid", &unsafe { libc::getpid() }.to_string()),
+                    ]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "tracer" },
+                    "spans": spans,
+                }]
+            }]
+        })
+    }
+
+    fn span(&self, span: &Span) -> Value {
+        let event = &span.event;
+        let start = self.start_time + event.timestamp.as_nanos() as u64;
+        let end =
+            self.start_time + event.end_timestamp.unwrap_or(event.timestamp).as_nanos() as u64;
+
+        let mut value = json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&event.span_id.to_be_bytes()),
+            "name": event.event,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": start.to_string(),
+            "endTimeUnixNano": end.to_string(),
+            "attributes": [string_attribute("thread.name", &span.thread_name)],
+        });
+        if let Some(parent_span_id) = event.parent_span_id {
+            value["parentSpanId"] = Value::from(hex(&parent_span_id.to_be_bytes()));
+        }
+
+        value
+    }
+
+    fn post(&self, body: &[u8]) -> io::Result<()> {
+        let address = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))?;
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unexpected response: {}", status.trim()),
+            )),
+        }
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn trace_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    if File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut id))
+        .is_err()
+    {
+        // Unique enough for the traces of a single host
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        id[..8].copy_from_slice(&now.to_be_bytes());
+        // SAFETY: FFI call
+        id[8..12].copy_from_slice(&unsafe { libc::getpid() }.to_be_bytes());
+    }
+    id
+}
+
+// Split an http:// URL into the authority and the path
+fn parse_endpoint(endpoint: &str) -> Option<(String, String)> {
+    let endpoint = endpoint.strip_prefix("http://")?;
+    let (authority, path) = match endpoint.find('/') {
+        Some(i) => endpoint.split_at(i),
+        None => (endpoint, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    let authority = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    };
+
+    Some((authority, path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        assert_eq!(
+            parse_endpoint("http://127.0.0.1:4318/v1/traces"),
+            Some(("127.0.0.1:4318".to_string(), "/v1/traces".to_string()))
+        );
+        assert_eq!(
+            parse_endpoint("http://collector"),
+            Some(("collector:80".to_string(), "/".to_string()))
+        );
+        assert_eq!(parse_endpoint("https://collector:4318/v1/traces"), None);
+        assert_eq!(parse_endpoint("http:///v1/traces"), None);
+    }
+}
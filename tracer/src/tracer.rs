@@ -3,28 +3,49 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::otlp::{OtlpExporter, EXPORT_INTERVAL};
 use once_cell::unsync::OnceCell;
 use serde::Serialize;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+thread_local! {
+    // Blocks opened by the current thread, the innermost one being the last.
+    static OPEN_SPANS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+}
+
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
 
-#[derive(Debug)]
 struct Tracer {
     events: Arc<Mutex<HashMap<String, Vec<TraceEvent>>>>,
-    thread_depths: HashMap<String, Arc<AtomicU64>>,
     start: Instant,
+    exporter: Option<Arc<OtlpExporter>>,
 }
 
 impl Tracer {
     fn new() -> Self {
+        let exporter = OtlpExporter::from_env(SystemTime::now()).map(Arc::new);
+        if let Some(exporter) = exporter.clone() {
+            thread::Builder::new()
+                .name("tracer_otlp".to_string())
+                .spawn(move || loop {
+                    thread::sleep(EXPORT_INTERVAL);
+                    exporter.flush();
+                })
+                .map_err(|e| warn!("Failed to spawn the OTLP exporter thread: {}", e))
+                .ok();
+        }
+
         Self {
             events: Arc::new(Mutex::new(HashMap::default())),
             start: Instant::now(),
-            thread_depths: HashMap::default(),
+            exporter,
         }
     }
 
@@ -50,11 +71,18 @@ impl Tracer {
         file.flush().unwrap();
 
         warn!("Trace output: {}", path);
+
+        if let Some(exporter) = &self.exporter {
+            exporter.flush();
+        }
     }
 
-    fn add_event(&mut self, event: TraceEvent) {
+    fn add_event(&self, event: TraceEvent) {
         let current = std::thread::current();
         let thread_name = current.name().unwrap_or("");
+        if let Some(exporter) = &self.exporter {
+            exporter.push(thread_name, &event);
+        }
         let mut events = self.events.lock().unwrap();
         if let Some(thread_events) = events.get_mut(thread_name) {
             thread_events.push(event);
@@ -62,97 +90,87 @@ impl Tracer {
             events.insert(thread_name.to_string(), vec![event]);
         }
     }
-
-    fn increase_thread_depth(&mut self) {
-        let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        if let Some(depth) = self.thread_depths.get_mut(thread_name) {
-            depth.fetch_add(1, Ordering::SeqCst);
-        } else {
-            self.thread_depths
-                .insert(thread_name.to_string(), Arc::new(AtomicU64::new(0)));
-        }
-    }
-
-    fn decrease_thread_depth(&mut self) {
-        let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        if let Some(depth) = self.thread_depths.get_mut(thread_name) {
-            depth.fetch_sub(1, Ordering::SeqCst);
-        } else {
-            panic!("Unmatched decrease for thread: {thread_name}");
-        }
-    }
-
-    fn thread_depth(&self) -> u64 {
-        let current = std::thread::current();
-        let thread_name = current.name().unwrap_or("");
-        self.thread_depths
-            .get(thread_name)
-            .map(|v| v.load(Ordering::SeqCst))
-            .unwrap_or_default()
-    }
 }
 
 static mut TRACER: OnceCell<Tracer> = OnceCell::new();
 
+fn tracer() -> Option<&'static Tracer> {
+    // SAFETY: TRACER is only set by start(), before other threads start
+    unsafe { TRACER.get() }
+}
+
 #[derive(Clone, Debug, Serialize)]
-struct TraceEvent {
-    timestamp: Duration,
-    event: &'static str,
-    end_timestamp: Option<Duration>,
+pub(crate) struct TraceEvent {
+    pub(crate) timestamp: Duration,
+    pub(crate) event: &'static str,
+    pub(crate) end_timestamp: Option<Duration>,
     depth: u64,
+    #[serde(skip)]
+    pub(crate) span_id: u64,
+    #[serde(skip)]
+    pub(crate) parent_span_id: Option<u64>,
 }
 
 pub fn trace_point_log(event: &'static str) {
-    let trace_event = TraceEvent {
-        // SAFETY: start has been initialised as part of initialising the value of TRACER
-        timestamp: Instant::now().duration_since(unsafe { TRACER.get().unwrap().start }),
+    // Events happening before the start of the tracer are ignored
+    let Some(tracer) = tracer() else {
+        return;
+    };
+    let (depth, parent_span_id) =
+        OPEN_SPANS.with(|s| (s.borrow().len() as u64, s.borrow().last().copied()));
+    tracer.add_event(TraceEvent {
+        timestamp: Instant::now().duration_since(tracer.start),
         event,
         end_timestamp: None,
-        // SAFETY: thread_depth accesses current thread only specific data
-        depth: unsafe { TRACER.get().unwrap().thread_depth() },
-    };
-    // SAFETY: add_event accesses current thread only specific data
-    unsafe {
-        TRACER.get_mut().unwrap().add_event(trace_event);
-    }
+        depth,
+        span_id: NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed),
+        parent_span_id,
+    });
 }
 
 pub struct TraceBlock {
     start: Instant,
     event: &'static str,
+    span_id: u64,
+    parent_span_id: Option<u64>,
 }
 
 impl TraceBlock {
     pub fn new(event: &'static str) -> Self {
-        // SAFETY: increase_thread_depth accesses current thread only specific data
-        unsafe {
-            TRACER.get_mut().unwrap().increase_thread_depth();
-        }
+        let span_id = NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed);
+        let parent_span_id = OPEN_SPANS.with(|s| {
+            let mut spans = s.borrow_mut();
+            let parent_span_id = spans.last().copied();
+            spans.push(span_id);
+            parent_span_id
+        });
         Self {
             start: Instant::now(),
             event,
+            span_id,
+            parent_span_id,
         }
     }
 }
 
 impl Drop for TraceBlock {
     fn drop(&mut self) {
-        // SAFETY: start has been initialised as part of initialising the value of TRACER
-        let start = unsafe { TRACER.get().unwrap().start };
-        let trace_event = TraceEvent {
-            timestamp: self.start.duration_since(start),
-            event: self.event,
-            end_timestamp: Some(Instant::now().duration_since(start)),
-            // SAFETY: thread_depth() returns a number local to the current thread
-            depth: unsafe { TRACER.get().unwrap().thread_depth() },
+        let depth = OPEN_SPANS.with(|s| {
+            let mut spans = s.borrow_mut();
+            spans.pop();
+            spans.len() as u64
+        });
+        let Some(tracer) = tracer() else {
+            return;
         };
-        // SAFETY: add_event and decrease_thread_depth access current thread only specific data
-        unsafe {
-            TRACER.get_mut().unwrap().add_event(trace_event);
-            TRACER.get_mut().unwrap().decrease_thread_depth();
-        }
+        tracer.add_event(TraceEvent {
+            timestamp: self.start.saturating_duration_since(tracer.start),
+            event: self.event,
+            end_timestamp: Some(Instant::now().duration_since(tracer.start)),
+            depth,
+            span_id: self.span_id,
+            parent_span_id: self.parent_span_id,
+        });
     }
 }
 
@@ -171,11 +189,15 @@ macro_rules! trace_scoped {
 }
 
 pub fn end() {
-    // SAFETY: this is called after all other threads end
-    unsafe { TRACER.get().unwrap().end() }
+    if let Some(tracer) = tracer() {
+        tracer.end()
+    }
 }
 
 pub fn start() {
-    // SAFETY: this is called before other threads start
-    unsafe { TRACER.set(Tracer::new()).unwrap() }
+    // SAFETY: this is called before other threads start. The tracer is only
+    // started once, so that its spans can keep being exported.
+    unsafe {
+        TRACER.get_or_init(Tracer::new);
+    }
 }
@@ -0,0 +1,253 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Standalone vfio-user server exporting a virtio-block device backed by a
+//! raw disk image, for a VMM to attach through `--user-device`.
+
+use block::raw_sync::RawFileDiskSync;
+use log::*;
+use option_parser::{OptionParser, OptionParserError, Toggle};
+use pci::{PciDevice, VfioUserPciServer, VfioUserServerInterrupts};
+use seccompiler::SeccompAction;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::{error, fmt};
+use virtio_devices::transport::{VirtioPciDevice, VirtioPciDeviceActivator, VirtioTransport};
+use virtio_devices::{Block, NotificationConfig, PciIdsConfig, VirtioDevice};
+use vm_allocator::{AddressAllocator, SystemAllocator};
+use vm_device::interrupt::{InterruptManager, MsiIrqGroupConfig};
+use vm_memory::bitmap::AtomicBitmap;
+use vm_memory::{GuestAddress, GuestMemoryAtomic};
+use vmm_sys_util::eventfd::EventFd;
+
+type GuestMemoryMmap = vm_memory::GuestMemoryMmap<AtomicBitmap>;
+
+// The BARs are placed by the client, this area only giving them an initial
+// address.
+const BAR_AREA_START: u64 = 1 << 40;
+const BAR_AREA_SIZE: u64 = 1 << 32;
+
+pub const SYNTAX: &str = "vfio-user-block server parameters \
+ \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
+ queue_size=<size_of_each_queue>,readonly=true|false\"";
+
+#[derive(Debug)]
+enum Error {
+    /// Failed to parse configuration string
+    FailedConfigParse(OptionParserError),
+    /// No path provided
+    PathParameterMissing,
+    /// No socket provided
+    SocketParameterMissing,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vfio_user_block_error: {self:?}")
+    }
+}
+
+impl error::Error for Error {}
+
+type Result<T> = std::result::Result<T, Error>;
+
+struct VfioUserBlkServerConfig {
+    path: String,
+    socket: String,
+    num_queues: usize,
+    queue_size: u16,
+    readonly: bool,
+}
+
+impl VfioUserBlkServerConfig {
+    fn parse(server: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("readonly")
+            .add("num_queues")
+            .add("queue_size")
+            .add("socket");
+        parser.parse(server).map_err(Error::FailedConfigParse)?;
+
+        let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
+        let readonly = parser
+            .convert::<Toggle>("readonly")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(1);
+        let socket = parser.get("socket").ok_or(Error::SocketParameterMissing)?;
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(128);
+
+        Ok(VfioUserBlkServerConfig {
+            path,
+            socket,
+            num_queues,
+            queue_size,
+            readonly,
+        })
+    }
+}
+
+fn exit_on_error<T, E: fmt::Debug>(result: std::result::Result<T, E>, what: &str) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => {
+            error!("{}: {:?}", what, e);
+            process::exit(1);
+        }
+    }
+}
+
+pub fn start_block_server(server_command: &str) {
+    let server_config = match VfioUserBlkServerConfig::parse(server_command) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed parsing parameters {e:?}");
+            process::exit(1);
+        }
+    };
+
+    let file = exit_on_error(
+        OpenOptions::new()
+            .read(true)
+            .write(!server_config.readonly)
+            .open(&server_config.path),
+        "Failed opening the disk image",
+    );
+    let exit_evt = exit_on_error(EventFd::new(libc::EFD_NONBLOCK), "Failed creating eventfd");
+    let block = exit_on_error(
+        Block::new(
+            "vfio_user_block".to_string(),
+            Box::new(RawFileDiskSync::new(file)),
+            PathBuf::from(&server_config.path),
+            server_config.readonly,
+            false,
+            server_config.num_queues,
+            server_config.queue_size,
+            None,
+            SeccompAction::Allow,
+            None,
+            None,
+            NotificationConfig::default(),
+            exit_evt,
+            None,
+        ),
+        "Failed creating the virtio-block device",
+    );
+    let block: Arc<Mutex<dyn VirtioDevice>> = Arc::new(Mutex::new(block));
+
+    // The memory is filled with the DMA mappings of the client, and the
+    // interrupts are delivered through the eventfds it provides.
+    let memory = GuestMemoryAtomic::new(GuestMemoryMmap::new());
+    let interrupts = Arc::new(VfioUserServerInterrupts::default());
+    let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+        interrupts.clone();
+    let msix_num = (server_config.num_queues + 1) as u16;
+
+    let activate_evt = exit_on_error(EventFd::new(0), "Failed creating eventfd");
+    let pending_activations = Arc::new(Mutex::new(Vec::<VirtioPciDeviceActivator>::new()));
+    let mut virtio_pci_device = exit_on_error(
+        VirtioPciDevice::new(
+            "vfio_user_block".to_string(),
+            memory.clone(),
+            block,
+            msix_num,
+            None,
+            &interrupt_manager,
+            0,
+            exit_on_error(activate_evt.try_clone(), "Failed cloning eventfd"),
+            true,
+            None,
+            pending_activations.clone(),
+            PciIdsConfig::default(),
+            None,
+        ),
+        "Failed creating the virtio-pci device",
+    );
+
+    let allocator = Arc::new(Mutex::new(
+        SystemAllocator::new(
+            #[cfg(target_arch = "x86_64")]
+            GuestAddress(0),
+            #[cfg(target_arch = "x86_64")]
+            0x10000,
+            GuestAddress(BAR_AREA_START + BAR_AREA_SIZE),
+            BAR_AREA_SIZE,
+            GuestAddress(BAR_AREA_START + 2 * BAR_AREA_SIZE),
+            BAR_AREA_SIZE,
+            #[cfg(target_arch = "x86_64")]
+            vec![],
+        )
+        .unwrap(),
+    ));
+    let mut mmio_allocator =
+        AddressAllocator::new(GuestAddress(BAR_AREA_START), BAR_AREA_SIZE).unwrap();
+    let bars = exit_on_error(
+        virtio_pci_device.allocate_bars(&allocator, &mut mmio_allocator, None),
+        "Failed allocating the BARs",
+    );
+
+    // The queue notifications are written to the eventfds of the queues, as
+    // the VMM would register them as ioeventfds.
+    let config_bar_addr = virtio_pci_device.config_bar_addr();
+    let config_bar = bars
+        .iter()
+        .find(|b| b.addr() == config_bar_addr)
+        .unwrap()
+        .idx() as u32;
+    let notifiers: Vec<(u64, EventFd)> = virtio_pci_device
+        .ioeventfds(config_bar_addr)
+        .into_iter()
+        .map(|(event, addr)| {
+            (
+                addr - config_bar_addr,
+                exit_on_error(event.try_clone(), "Failed cloning eventfd"),
+            )
+        })
+        .collect();
+
+    // Activate the device from another thread, the server waiting for the
+    // activation as a vCPU would.
+    exit_on_error(
+        thread::Builder::new()
+            .name("vfio_user_block_activate".to_string())
+            .spawn(move || loop {
+                if let Err(e) = activate_evt.read() {
+                    error!("Failed reading the activation eventfd: {:?}", e);
+                    return;
+                }
+                for mut activator in pending_activations.lock().unwrap().drain(..) {
+                    if let Err(e) = activator.activate() {
+                        error!("Failed activating the device: {:?}", e);
+                    }
+                }
+            }),
+        "Failed spawning the activation thread",
+    );
+
+    let device: Arc<Mutex<dyn PciDevice>> = Arc::new(Mutex::new(virtio_pci_device));
+    let mut server = VfioUserPciServer::new(device, bars, interrupts, memory, u32::from(msix_num));
+    for (offset, event) in notifiers {
+        server.add_notifier(config_bar, offset, event);
+    }
+
+    debug!("vfio-user block server is created");
+
+    if let Err(e) = server.run(Path::new(&server_config.socket)) {
+        error!("Error from the vfio-user server: {:?}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+extern crate vfio_user_block;
+
+use clap::{Arg, Command};
+use vfio_user_block::start_block_server;
+
+fn main() {
+    env_logger::init();
+
+    let cmd_arguments = Command::new("vfio-user block server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Export a virtio-block device through vfio-user.")
+        .arg(
+            Arg::new("block-server")
+                .long("block-server")
+                .help(vfio_user_block::SYNTAX)
+                .num_args(1)
+                .required(true),
+        )
+        .get_matches();
+
+    let server_command = cmd_arguments.get_one::<String>("block-server").unwrap();
+    start_block_server(server_command);
+}
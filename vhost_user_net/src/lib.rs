@@ -9,9 +9,10 @@
 use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    open_tap, MacAddr, NetCounters, NetQueueBackend, NetQueuePair, OpenTapError, RxVirtio, Tap,
+    TapError, TxVirtio,
 };
-use option_parser::Toggle;
+use option_parser::{IntegerList, Toggle};
 use option_parser::{OptionParser, OptionParserError};
 use std::fmt;
 use std::io;
@@ -55,11 +56,20 @@ pub enum Error {
     NetQueuePair(net_util::NetQueuePairError),
     /// Failed to register the TAP listener.
     RegisterTapListener(io::Error),
+    /// Failed to use a TAP file descriptor.
+    TapFromFd(TapError),
+    /// Failed to duplicate the TAP file descriptor.
+    DuplicateTapFd(io::Error),
+    /// Failed to set the MTU of the TAP device.
+    SetTapMtu(TapError),
+    /// Number of queues not matching the number of TAP file descriptors.
+    InvalidNumQueuesForFds,
 }
 
 pub const SYNTAX: &str = "vhost-user-net backend parameters \
 \"ip=<ip_addr>,mask=<net_mask>,socket=<socket_path>,client=on|off,\
-num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"";
+num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+host_mac=<host_mac>,mtu=<mtu>,fd=<fd1,fd2...>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -83,11 +93,12 @@ struct VhostUserNetThread {
 impl VhostUserNetThread {
     /// Create a new virtio network device with the given TAP interface.
     fn new(tap: Tap) -> Result<Self> {
+        let backend = NetQueueBackend::Tap(tap);
         Ok(VhostUserNetThread {
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             net: NetQueuePair {
-                tap_for_write_epoll: tap.clone(),
-                tap,
+                backend_for_write_epoll: backend.try_clone_fd().map_err(Error::DuplicateTapFd)?,
+                backend,
                 rx: RxVirtio::new(),
                 tx: TxVirtio::new(),
                 rx_tap_listening: false,
@@ -127,18 +138,33 @@ impl VhostUserNetBackend {
         num_queues: usize,
         queue_size: u16,
         ifname: Option<&str>,
+        fds: Option<&[RawFd]>,
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
     ) -> Result<Self> {
-        let mut taps = open_tap(
-            ifname,
-            Some(ip_addr),
-            Some(netmask),
-            &mut Some(host_mac),
-            mtu,
-            num_queues / 2,
-            None,
-        )
-        .map_err(Error::OpenTap)?;
+        let mut taps = if let Some(fds) = fds {
+            // The TAP devices have been created and configured by a more
+            // privileged process, which lets this one run without being
+            // allowed to manage network interfaces.
+            let mut taps = Vec::new();
+            for fd in fds {
+                taps.push(Tap::from_tap_fd(*fd, fds.len()).map_err(Error::TapFromFd)?);
+            }
+            if let (Some(mtu), Some(tap)) = (mtu, taps.first()) {
+                tap.set_mtu(mtu as i32).map_err(Error::SetTapMtu)?;
+            }
+            taps
+        } else {
+            open_tap(
+                ifname,
+                Some(ip_addr),
+                Some(netmask),
+                &mut Some(host_mac),
+                mtu,
+                num_queues / 2,
+                None,
+            )
+            .map_err(Error::OpenTap)?
+        };
 
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
@@ -210,7 +236,7 @@ impl VhostUserBackendMut<VringRwLock<GuestMemoryAtomic<GuestMemoryMmap>>, Atomic
                 if !thread.net.rx_tap_listening {
                     net_util::register_listener(
                         thread.net.epoll_fd.unwrap(),
-                        thread.net.tap.as_raw_fd(),
+                        thread.net.backend.as_raw_fd(),
                         epoll::Events::EPOLLIN,
                         u64::from(thread.net.tap_rx_event_id),
                     )
@@ -280,6 +306,7 @@ pub struct VhostUserNetBackendConfig {
     pub num_queues: usize,
     pub queue_size: u16,
     pub tap: Option<String>,
+    pub fds: Option<Vec<RawFd>>,
     pub client: bool,
 }
 
@@ -296,7 +323,8 @@ impl VhostUserNetBackendConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
-            .add("client");
+            .add("client")
+            .add("fd");
 
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
@@ -318,10 +346,25 @@ impl VhostUserNetBackendConfig {
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(256);
-        let num_queues = parser
-            .convert("num_queues")
+        let fds: Option<Vec<RawFd>> = parser
+            .convert::<IntegerList>("fd")
             .map_err(Error::FailedConfigParse)?
-            .unwrap_or(2);
+            .map(|v| v.0.iter().map(|e| *e as RawFd).collect());
+        // Each TAP file descriptor backs one pair of RX/TX queues.
+        let num_queues = match (
+            parser
+                .convert("num_queues")
+                .map_err(Error::FailedConfigParse)?,
+            &fds,
+        ) {
+            (_, Some(fds)) if fds.is_empty() => return Err(Error::InvalidNumQueuesForFds),
+            (Some(num_queues), Some(fds)) if num_queues != fds.len() * 2 => {
+                return Err(Error::InvalidNumQueuesForFds)
+            }
+            (Some(num_queues), _) => num_queues,
+            (None, Some(fds)) => fds.len() * 2,
+            (None, None) => 2,
+        };
         let socket = parser.get("socket").ok_or(Error::SocketParameterMissing)?;
         let client = parser
             .convert::<Toggle>("client")
@@ -338,6 +381,7 @@ impl VhostUserNetBackendConfig {
             num_queues,
             queue_size,
             tap,
+            fds,
             client,
         })
     }
@@ -365,6 +409,7 @@ pub fn start_net_backend(backend_command: &str) {
             backend_config.num_queues,
             backend_config.queue_size,
             tap,
+            backend_config.fds.as_deref(),
             mem.clone(),
         )
         .unwrap(),
@@ -14,8 +14,8 @@
 
 use crate::{
     seccomp_filters::Thread, thread_helper::spawn_virtio_thread, ActivateError, ActivateResult,
-    EpollHelper, EpollHelperError, EpollHelperHandler, GuestMemoryMmap, VirtioCommon, VirtioDevice,
-    VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    EpollHelper, EpollHelperError, EpollHelperHandler, GuestMemoryMmap, QueueCursors, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
     VIRTIO_F_VERSION_1,
 };
 use anyhow::anyhow;
@@ -27,7 +27,7 @@ use std::num::Wrapping;
 use std::ops::Index;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{atomic::AtomicBool, Arc, Barrier};
 use std::time::Duration;
 use thiserror::Error;
@@ -63,6 +63,8 @@ const REPORTING_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 const HETERO_INFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
 // Heterogeneous deflate virtio queue event.
 const HETERO_DEFLATE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// Free page virtio queue event.
+const FREE_PAGE_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
 
 // Size of a PFN in the balloon interface.
 const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
@@ -71,6 +73,9 @@ const VIRTIO_BALLOON_PFN_SHIFT: u64 = 12;
 const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1;
 // Deflate balloon on OOM
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 2;
+// Enable an additional virtqueue to let the guest hint free pages when the
+// host asks for it.
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u64 = 3;
 // Enable an additional virtqueue to let the guest notify the host about free
 // pages.
 const VIRTIO_BALLOON_F_REPORTING: u64 = 5;
@@ -82,8 +87,7 @@ enum BalloonVq {
     Inflate,
     Deflate,
     Stats,
-    // Not supported currently
-    _FreePage,
+    FreePage,
     Reporting,
     HeteroInflate,
     HeteroDeflate,
@@ -117,8 +121,18 @@ pub enum Error {
     UnexpectedStatTag(u16),
     #[error("Failed to support memory statistics")]
     MemoryStatistic,
+    #[error("Free page hinting not enabled by the guest")]
+    FreePageHintNotEnabled,
 }
 
+// Free page hinting command identifiers, see include/uapi/linux/virtio_balloon.h.
+// The guest stops hinting free pages.
+const FREE_PAGE_HINT_CMD_ID_STOP: u32 = 0;
+// The guest gives the hinted free pages back to its allocator.
+const FREE_PAGE_HINT_CMD_ID_DONE: u32 = 1;
+// Any other identifier starts a new hinting. Same range as QEMU.
+const FREE_PAGE_HINT_CMD_ID_MIN: u32 = 0x8000_0000;
+
 // Got from include/uapi/linux/virtio_balloon.h
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default, Versionize)]
@@ -127,8 +141,8 @@ pub struct VirtioBalloonConfig {
     num_pages: u32,
     // Number of pages we've actually got in balloon.
     actual: u32,
-    // Free page hinting to speed up migration (this feature is not implemented).
-    // Caveat: should not be mixed with free page reporting
+    // Free page hinting command, for the host to reclaim the free pages of
+    // the guest on demand.
     hint_cmd_id: u32,
     // Deflated or reported free pages are initialized with this value (this feature is not implemented).
     poison_val: u32,
@@ -148,6 +162,8 @@ unsafe impl ByteValued for VirtioBalloonConfig {}
 struct BalloonEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queues: Vec<Queue>,
+    // Indexed as the queues.
+    queue_cursors: Vec<Arc<QueueCursors>>,
     // Fix the mismatch between index into queues and BalloonVq value when some queues are not present due to unsupported features
     queue_indices: HashMap<BalloonVq, usize>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
@@ -158,15 +174,26 @@ struct BalloonEpollHandler {
     stats_polling_interval: Option<Duration>,
     stats_queue_index: Option<usize>,
     reporting_queue_evt: Option<EventFd>,
+    free_page_queue_evt: Option<EventFd>,
     hetero_inflate_queue_evt: Option<EventFd>,
     hetero_deflate_queue_evt: Option<EventFd>,
     kill_evt: EventFd,
     pause_evt: EventFd,
     counters: Arc<BalloonCounters>,
+    hint_cmd_id: Arc<AtomicU32>,
 }
 
 impl BalloonEpollHandler {
     fn signal(&self, int_type: VirtioInterruptType) -> result::Result<(), Error> {
+        if let VirtioInterruptType::Queue(queue_index) = int_type {
+            let queue_index = queue_index as usize;
+            if let (Some(cursors), Some(queue)) = (
+                self.queue_cursors.get(queue_index),
+                self.queues.get(queue_index),
+            ) {
+                cursors.signalled(queue);
+            }
+        }
         self.interrupt_cb.trigger(int_type).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
             Error::FailedSignal(e)
@@ -381,6 +408,55 @@ impl BalloonEpollHandler {
         }
     }
 
+    fn process_free_page_queue(&mut self, queue: BalloonVq) -> result::Result<(), Error> {
+        let queue_index = self.queue_indices[&queue];
+        let mut used_descs = false;
+        let mut hinting_stopped = false;
+        while let Some(mut desc_chain) =
+            self.queues[queue_index].pop_descriptor_chain(self.mem.memory())
+        {
+            while let Some(desc) = desc_chain.next() {
+                if desc.is_write_only() {
+                    // Block of free pages the guest holds on to until the
+                    // hinting is done, so it can be released right away.
+                    Self::release_memory_range(
+                        desc_chain.memory(),
+                        desc.addr(),
+                        desc.len() as usize,
+                    )?;
+                } else if desc.len() as usize == size_of::<u32>() {
+                    let cmd_id: u32 = desc_chain
+                        .memory()
+                        .read_obj(desc.addr())
+                        .map_err(Error::GuestMemory)?;
+                    hinting_stopped = u32::from_le(cmd_id) == FREE_PAGE_HINT_CMD_ID_STOP;
+                } else {
+                    error!("the command size {} is not right", desc.len());
+                    return Err(Error::InvalidRequest);
+                }
+            }
+
+            self.queues[queue_index]
+                .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        if used_descs {
+            self.signal(VirtioInterruptType::Queue(queue_index as u16))?;
+        }
+
+        if hinting_stopped {
+            // All the free pages have been hinted, and released. Let the guest
+            // give them back to its allocator.
+            self.hint_cmd_id
+                .store(FREE_PAGE_HINT_CMD_ID_DONE, Ordering::Release);
+            self.signal(VirtioInterruptType::Config)?;
+        }
+
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -398,6 +474,9 @@ impl BalloonEpollHandler {
         if let Some(reporting_queue_evt) = self.reporting_queue_evt.as_ref() {
             helper.add_event(reporting_queue_evt.as_raw_fd(), REPORTING_QUEUE_EVENT)?;
         }
+        if let Some(free_page_queue_evt) = self.free_page_queue_evt.as_ref() {
+            helper.add_event(free_page_queue_evt.as_raw_fd(), FREE_PAGE_QUEUE_EVENT)?;
+        }
         if let Some(hetero_inflate_queue_evt) = self.hetero_inflate_queue_evt.as_ref() {
             helper.add_event(
                 hetero_inflate_queue_evt.as_raw_fd(),
@@ -508,6 +587,27 @@ impl EpollHelperHandler for BalloonEpollHandler {
                     )));
                 }
             }
+            FREE_PAGE_QUEUE_EVENT => {
+                if let Some(free_page_queue_evt) = self.free_page_queue_evt.as_ref() {
+                    free_page_queue_evt.read().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!(
+                            "Failed to get free page queue event: {:?}",
+                            e
+                        ))
+                    })?;
+                    self.process_free_page_queue(BalloonVq::FreePage)
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used free page queue: {:?}",
+                                e
+                            ))
+                        })?;
+                } else {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Invalid free page queue event as no eventfd registered"
+                    )));
+                }
+            }
             HETERO_INFLATE_QUEUE_EVENT => {
                 if let Some(hetero_inflate_queue_evt) = self.hetero_inflate_queue_evt.as_ref() {
                     hetero_inflate_queue_evt.read().map_err(|e| {
@@ -555,6 +655,10 @@ impl EpollHelperHandler for BalloonEpollHandler {
             }
         }
 
+        for (queue, cursors) in self.queues.iter().zip(self.queue_cursors.iter()) {
+            cursors.update(queue);
+        }
+
         Ok(())
     }
 }
@@ -578,6 +682,8 @@ pub struct Balloon {
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     counters: Arc<BalloonCounters>,
     stats_polling_interval: Option<Duration>,
+    hint_cmd_id: Arc<AtomicU32>,
+    next_hint_cmd_id: u32,
 }
 
 impl Balloon {
@@ -589,6 +695,7 @@ impl Balloon {
         stats_polling_interval: Option<Duration>,
         deflate_on_oom: bool,
         free_page_reporting: bool,
+        free_page_hinting: bool,
         heterogeneous_memory: bool,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
@@ -612,6 +719,9 @@ impl Balloon {
             if deflate_on_oom {
                 avail_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
             }
+            if free_page_hinting {
+                avail_features |= 1u64 << VIRTIO_BALLOON_F_FREE_PAGE_HINT;
+            }
             if free_page_reporting {
                 avail_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
             }
@@ -631,6 +741,9 @@ impl Balloon {
         if stats_polling_interval.is_some() {
             queue_sizes.push(STATS_QUEUE_SIZE);
         }
+        if free_page_hinting {
+            queue_sizes.push(QUEUE_SIZE);
+        }
         if free_page_reporting {
             queue_sizes.push(REPORTING_QUEUE_SIZE);
         }
@@ -656,6 +769,8 @@ impl Balloon {
             interrupt_cb: None,
             counters: Arc::new(BalloonCounters::default()),
             stats_polling_interval,
+            hint_cmd_id: Arc::new(AtomicU32::new(config.hint_cmd_id)),
+            next_hint_cmd_id: FREE_PAGE_HINT_CMD_ID_MIN,
         })
     }
 
@@ -672,6 +787,35 @@ impl Balloon {
         }
     }
 
+    // Ask the guest to hint its free pages right away, for the host to
+    // release them without waiting for the guest to report them.
+    pub fn report_free_pages(&mut self) -> Result<(), Error> {
+        if !self.common.feature_acked(VIRTIO_BALLOON_F_FREE_PAGE_HINT) {
+            return Err(Error::FreePageHintNotEnabled);
+        }
+
+        self.hint_cmd_id
+            .store(self.next_hint_cmd_id, Ordering::Release);
+        self.next_hint_cmd_id = self
+            .next_hint_cmd_id
+            .checked_add(1)
+            .unwrap_or(FREE_PAGE_HINT_CMD_ID_MIN);
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb
+                .trigger(VirtioInterruptType::Config)
+                .map_err(Error::FailedSignal)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn current_config(&self) -> VirtioBalloonConfig {
+        let mut config = self.config;
+        config.hint_cmd_id = self.hint_cmd_id.load(Ordering::Acquire);
+        config
+    }
+
     // Get the actual size of the virtio-balloon.
     pub fn get_actual(&self) -> u64 {
         (self.config.actual as u64) << VIRTIO_BALLOON_PFN_SHIFT
@@ -686,7 +830,7 @@ impl Balloon {
         BalloonState {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
-            config: self.config,
+            config: self.current_config(),
         }
     }
 
@@ -724,7 +868,7 @@ impl VirtioDevice for Balloon {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        self.read_config_from_slice(self.current_config().as_slice(), offset, data);
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
@@ -771,48 +915,65 @@ impl VirtioDevice for Balloon {
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
         let mut virtqueues = Vec::new();
+        let mut queue_cursors = Vec::new();
         let mut queue_indices = HashMap::new();
-        let (_, queue, queue_evt) = queues.remove(0);
+        let (index, queue, queue_evt) = queues.remove(0);
         queue_indices.insert(BalloonVq::Inflate, virtqueues.len());
         virtqueues.push(queue);
+        queue_cursors.push(self.common.queue_cursors(index));
         let inflate_queue_evt = queue_evt;
-        let (_, queue, queue_evt) = queues.remove(0);
+        let (index, queue, queue_evt) = queues.remove(0);
         queue_indices.insert(BalloonVq::Deflate, virtqueues.len());
         virtqueues.push(queue);
+        queue_cursors.push(self.common.queue_cursors(index));
         let deflate_queue_evt = queue_evt;
         let (stats_queue_evt, stats_timer_evt) =
             if self.common.feature_acked(VIRTIO_BALLOON_F_STATS_VQ) && !queues.is_empty() {
-                let (_, queue, queue_evt) = queues.remove(0);
+                let (index, queue, queue_evt) = queues.remove(0);
                 queue_indices.insert(BalloonVq::Stats, virtqueues.len());
                 virtqueues.push(queue);
+                queue_cursors.push(self.common.queue_cursors(index));
                 let timer_evt = TimerFd::new().map_err(|_| ActivateError::BadActivate)?;
                 (Some(queue_evt), Some(timer_evt))
             } else {
                 (None, None)
             };
+        let free_page_queue_evt =
+            if self.common.feature_acked(VIRTIO_BALLOON_F_FREE_PAGE_HINT) && !queues.is_empty() {
+                let (index, queue, queue_evt) = queues.remove(0);
+                queue_indices.insert(BalloonVq::FreePage, virtqueues.len());
+                virtqueues.push(queue);
+                queue_cursors.push(self.common.queue_cursors(index));
+                Some(queue_evt)
+            } else {
+                None
+            };
         let reporting_queue_evt =
             if self.common.feature_acked(VIRTIO_BALLOON_F_REPORTING) && !queues.is_empty() {
-                let (_, queue, queue_evt) = queues.remove(0);
+                let (index, queue, queue_evt) = queues.remove(0);
                 queue_indices.insert(BalloonVq::Reporting, virtqueues.len());
                 virtqueues.push(queue);
+                queue_cursors.push(self.common.queue_cursors(index));
                 Some(queue_evt)
             } else {
                 None
             };
         let hetero_inflate_queue_evt =
             if self.common.feature_acked(VIRTIO_BALLOON_F_HETERO_MEM) && !queues.is_empty() {
-                let (_, queue, queue_evt) = queues.remove(0);
+                let (index, queue, queue_evt) = queues.remove(0);
                 queue_indices.insert(BalloonVq::HeteroInflate, virtqueues.len());
                 virtqueues.push(queue);
+                queue_cursors.push(self.common.queue_cursors(index));
                 Some(queue_evt)
             } else {
                 None
             };
         let hetero_deflate_queue_evt =
             if self.common.feature_acked(VIRTIO_BALLOON_F_HETERO_MEM) && !queues.is_empty() {
-                let (_, queue, queue_evt) = queues.remove(0);
+                let (index, queue, queue_evt) = queues.remove(0);
                 queue_indices.insert(BalloonVq::HeteroDeflate, virtqueues.len());
                 virtqueues.push(queue);
+                queue_cursors.push(self.common.queue_cursors(index));
                 Some(queue_evt)
             } else {
                 None
@@ -828,6 +989,7 @@ impl VirtioDevice for Balloon {
         let mut handler = BalloonEpollHandler {
             mem,
             queues: virtqueues,
+            queue_cursors,
             queue_indices,
             interrupt_cb,
             inflate_queue_evt,
@@ -837,11 +999,13 @@ impl VirtioDevice for Balloon {
             stats_polling_interval: self.stats_polling_interval,
             stats_queue_index: None,
             reporting_queue_evt,
+            free_page_queue_evt,
             hetero_inflate_queue_evt,
             hetero_deflate_queue_evt,
             kill_evt,
             pause_evt,
             counters: self.counters.clone(),
+            hint_cmd_id: self.hint_cmd_id.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -868,6 +1032,10 @@ impl VirtioDevice for Balloon {
         result
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         let mut map: HashMap<_, _> = (0..16)
             .map(|i| {
@@ -978,3 +1146,68 @@ impl BalloonCounters {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balloon(free_page_reporting: bool, free_page_hinting: bool) -> Balloon {
+        Balloon::new(
+            "balloon".to_string(),
+            [0; 2],
+            None,
+            false,
+            free_page_reporting,
+            free_page_hinting,
+            false,
+            SeccompAction::Allow,
+            EventFd::new(libc::EFD_NONBLOCK).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_free_page_options() {
+        let b = balloon(false, false);
+        assert_eq!(b.features() & (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT), 0);
+        assert_eq!(b.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(b.queue_max_sizes(), &[QUEUE_SIZE; MIN_NUM_QUEUES]);
+
+        let b = balloon(true, false);
+        assert_eq!(b.features() & (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT), 0);
+        assert_ne!(b.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(
+            b.queue_max_sizes(),
+            &[QUEUE_SIZE, QUEUE_SIZE, REPORTING_QUEUE_SIZE]
+        );
+
+        let b = balloon(false, true);
+        assert_ne!(b.features() & (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT), 0);
+        assert_eq!(b.features() & (1 << VIRTIO_BALLOON_F_REPORTING), 0);
+        assert_eq!(b.queue_max_sizes(), &[QUEUE_SIZE; 3]);
+
+        // The free page hinting queue comes before the reporting one.
+        let b = balloon(true, true);
+        assert_eq!(
+            b.queue_max_sizes(),
+            &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, REPORTING_QUEUE_SIZE]
+        );
+    }
+
+    #[test]
+    fn test_report_free_pages_not_acked() {
+        let mut b = balloon(true, false);
+        assert!(matches!(
+            b.report_free_pages(),
+            Err(Error::FreePageHintNotEnabled)
+        ));
+
+        // Offered but not negotiated by the guest
+        let mut b = balloon(false, true);
+        assert!(matches!(
+            b.report_free_pages(),
+            Err(Error::FreePageHintNotEnabled)
+        ));
+    }
+}
@@ -10,10 +10,11 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
-    RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
-    EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, EventLoop,
+    NotificationCoalescer, NotificationConfig, QueueCursors, RateLimiterConfig, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
+use crate::histogram::LatencyHistogram;
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::GuestMemoryMmap;
@@ -40,6 +41,7 @@ use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_bindings::virtio_blk::*;
 use virtio_bindings::virtio_config::*;
+use virtio_bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use virtio_queue::{Queue, QueueOwnedT, QueueT};
 use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError};
 use vm_migration::VersionMapped;
@@ -56,6 +58,8 @@ const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const COMPLETION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // New 'wake up' event from the rate limiter
 const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// The notification held back on the queue is due
+const COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 // latency scale, for reduce precision loss in calculate.
 const LATENCY_SCALE: u64 = 10000;
@@ -78,6 +82,12 @@ pub enum Error {
     QueueAddUsed(virtio_queue::Error),
     #[error("Failed creating an iterator over the queue: {0}")]
     QueueIterator(virtio_queue::Error),
+    #[error("Failed enabling the notifications of the queue: {0}")]
+    QueueEnableNotification(virtio_queue::Error),
+    #[error("Failed checking whether the driver needs a notification: {0}")]
+    QueueNeedsNotification(virtio_queue::Error),
+    #[error("Failed coalescing the notifications of the queue: {0}")]
+    NotificationCoalescing(io::Error),
     #[error("Failed to update request status: {0}")]
     RequestStatus(GuestMemoryError),
 }
@@ -98,6 +108,8 @@ pub struct BlockCounters {
     write_latency_min: Arc<AtomicU64>,
     write_latency_max: Arc<AtomicU64>,
     write_latency_avg: Arc<AtomicU64>,
+    read_latency: LatencyHistogram,
+    write_latency: LatencyHistogram,
 }
 
 impl Default for BlockCounters {
@@ -113,6 +125,8 @@ impl Default for BlockCounters {
             write_latency_min: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_max: Arc::new(AtomicU64::new(u64::MAX)),
             write_latency_avg: Arc::new(AtomicU64::new(u64::MAX)),
+            read_latency: latency_histogram!("read_latency_us"),
+            write_latency: latency_histogram!("write_latency_us"),
         }
     }
 }
@@ -120,6 +134,7 @@ impl Default for BlockCounters {
 struct BlockEpollHandler {
     queue_index: u16,
     queue: Queue,
+    queue_cursors: Arc<QueueCursors>,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Box<dyn AsyncIo>,
     disk_nsectors: u64,
@@ -132,102 +147,139 @@ struct BlockEpollHandler {
     queue_evt: EventFd,
     inflight_requests: VecDeque<(u16, Request)>,
     rate_limiter: Option<RateLimiter>,
+    coalescer: Option<NotificationCoalescer>,
     access_platform: Option<Arc<dyn AccessPlatform>>,
     read_only: bool,
+    event_loop: EventLoop,
 }
 
 impl BlockEpollHandler {
     fn process_queue_submit(&mut self) -> Result<bool> {
+        let next_used = self.queue.next_used();
         let queue = &mut self.queue;
 
         let mut used_descs = false;
 
-        while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
-            let mut request = Request::parse(&mut desc_chain, self.access_platform.as_ref())
-                .map_err(Error::RequestParsing)?;
-
-            // For virtio spec compliance
-            // "A device MUST set the status byte to VIRTIO_BLK_S_IOERR for a write request
-            // if the VIRTIO_BLK_F_RO feature if offered, and MUST NOT write any data."
-            if self.read_only
-                && (request.request_type == RequestType::Out
-                    || request.request_type == RequestType::Flush)
-            {
-                desc_chain
-                    .memory()
-                    .write_obj(VIRTIO_BLK_S_IOERR, request.status_addr)
-                    .map_err(Error::RequestStatus)?;
-
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                queue
-                    .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
-                    .map_err(Error::QueueAddUsed)?;
-                used_descs = true;
-                continue;
-            }
-
-            if let Some(rate_limiter) = &mut self.rate_limiter {
-                // If limiter.consume() fails it means there is no more TokenType::Ops
-                // budget and rate limiting is in effect.
-                if !rate_limiter.consume(1, TokenType::Ops) {
-                    // Stop processing the queue and return this descriptor chain to the
-                    // avail ring, for later processing.
-                    queue.go_to_previous_position();
-                    break;
-                }
-                // Exercise the rate limiter only if this request is of data transfer type.
-                if request.request_type == RequestType::In
-                    || request.request_type == RequestType::Out
+        'submit: loop {
+            while let Some(mut desc_chain) = queue.pop_descriptor_chain(self.mem.memory()) {
+                let mut request = Request::parse(&mut desc_chain, self.access_platform.as_ref())
+                    .map_err(Error::RequestParsing)?;
+
+                // For virtio spec compliance
+                // "A device MUST set the status byte to VIRTIO_BLK_S_IOERR for a write request
+                // if the VIRTIO_BLK_F_RO feature if offered, and MUST NOT write any data."
+                if self.read_only
+                    && (request.request_type == RequestType::Out
+                        || request.request_type == RequestType::Flush)
                 {
-                    let mut bytes = Wrapping(0);
-                    for (_, data_len) in &request.data_descriptors {
-                        bytes += Wrapping(*data_len as u64);
-                    }
+                    desc_chain
+                        .memory()
+                        .write_obj(VIRTIO_BLK_S_IOERR, request.status_addr)
+                        .map_err(Error::RequestStatus)?;
+
+                    // If no asynchronous operation has been submitted, we can
+                    // simply return the used descriptor.
+                    queue
+                        .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                        .map_err(Error::QueueAddUsed)?;
+                    used_descs = true;
+                    continue;
+                }
 
-                    // If limiter.consume() fails it means there is no more TokenType::Bytes
+                if let Some(rate_limiter) = &mut self.rate_limiter {
+                    // If limiter.consume() fails it means there is no more TokenType::Ops
                     // budget and rate limiting is in effect.
-                    if !rate_limiter.consume(bytes.0, TokenType::Bytes) {
-                        // Revert the OPS consume().
-                        rate_limiter.manual_replenish(1, TokenType::Ops);
+                    if !rate_limiter.consume(1, TokenType::Ops) {
                         // Stop processing the queue and return this descriptor chain to the
                         // avail ring, for later processing.
                         queue.go_to_previous_position();
-                        break;
+                        break 'submit;
                     }
-                };
-            }
+                    // Exercise the rate limiter only if this request is of data transfer type.
+                    if request.request_type == RequestType::In
+                        || request.request_type == RequestType::Out
+                    {
+                        let mut bytes = Wrapping(0);
+                        for (_, data_len) in &request.data_descriptors {
+                            bytes += Wrapping(*data_len as u64);
+                        }
+
+                        // If limiter.consume() fails it means there is no more TokenType::Bytes
+                        // budget and rate limiting is in effect.
+                        if !rate_limiter.consume(bytes.0, TokenType::Bytes) {
+                            // Revert the OPS consume().
+                            rate_limiter.manual_replenish(1, TokenType::Ops);
+                            // Stop processing the queue and return this descriptor chain to the
+                            // avail ring, for later processing.
+                            queue.go_to_previous_position();
+                            break 'submit;
+                        }
+                    };
+                }
 
-            request.set_writeback(self.writeback.load(Ordering::Acquire));
+                request.set_writeback(self.writeback.load(Ordering::Acquire));
 
-            if request
-                .execute_async(
-                    desc_chain.memory(),
-                    self.disk_nsectors,
-                    self.disk_image.as_mut(),
-                    &self.serial,
-                    desc_chain.head_index() as u64,
-                )
-                .map_err(Error::RequestExecuting)?
+                if request
+                    .execute_async(
+                        desc_chain.memory(),
+                        self.disk_nsectors,
+                        self.disk_image.as_mut(),
+                        &self.serial,
+                        desc_chain.head_index() as u64,
+                    )
+                    .map_err(Error::RequestExecuting)?
+                {
+                    self.inflight_requests
+                        .push_back((desc_chain.head_index(), request));
+                } else {
+                    desc_chain
+                        .memory()
+                        .write_obj(VIRTIO_BLK_S_OK, request.status_addr)
+                        .map_err(Error::RequestStatus)?;
+
+                    // If no asynchronous operation has been submitted, we can
+                    // simply return the used descriptor.
+                    queue
+                        .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                        .map_err(Error::QueueAddUsed)?;
+                    used_descs = true;
+                }
+            }
+
+            // With VIRTIO_RING_F_EVENT_IDX, the driver is asked to notify the
+            // queue about the requests made from now on.
+            if !queue
+                .enable_notification(self.mem.memory().deref())
+                .map_err(Error::QueueEnableNotification)?
             {
-                self.inflight_requests
-                    .push_back((desc_chain.head_index(), request));
-            } else {
-                desc_chain
-                    .memory()
-                    .write_obj(VIRTIO_BLK_S_OK, request.status_addr)
-                    .map_err(Error::RequestStatus)?;
-
-                // If no asynchronous operation has been submitted, we can
-                // simply return the used descriptor.
-                queue
-                    .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
-                    .map_err(Error::QueueAddUsed)?;
-                used_descs = true;
+                break;
             }
         }
 
-        Ok(used_descs)
+        self.must_notify(next_used, used_descs)
+    }
+
+    // Whether the driver must be notified now about the buffers used since
+    // `next_used`, rather than coalescing the notification with the next
+    // ones.
+    fn must_notify(&mut self, next_used: u16, used_descs: bool) -> Result<bool> {
+        if !used_descs {
+            return Ok(false);
+        }
+
+        let needs_notification = self
+            .queue
+            .needs_notification(self.mem.memory().deref())
+            .map_err(Error::QueueNeedsNotification)?;
+        match self.coalescer.as_mut() {
+            Some(coalescer) => coalescer
+                .add_used(
+                    self.queue.next_used().wrapping_sub(next_used),
+                    needs_notification,
+                )
+                .map_err(Error::NotificationCoalescing),
+            None => Ok(needs_notification),
+        }
     }
 
     fn process_queue_submit_and_signal(&mut self) -> result::Result<(), EpollHelperError> {
@@ -266,6 +318,7 @@ impl BlockEpollHandler {
     }
 
     fn process_queue_complete(&mut self) -> Result<bool> {
+        let next_used = self.queue.next_used();
         let mut used_descs = false;
         let mem = self.mem.memory();
         let mut read_bytes = Wrapping(0);
@@ -294,6 +347,7 @@ impl BlockEpollHandler {
                             read_bytes += Wrapping(*data_len as u64);
                         }
                         read_ops += Wrapping(1);
+                        self.counters.read_latency.record(latency);
                         if latency < self.counters.read_latency_min.load(Ordering::Relaxed) {
                             self.counters
                                 .read_latency_min
@@ -326,6 +380,7 @@ impl BlockEpollHandler {
                             write_bytes += Wrapping(*data_len as u64);
                         }
                         write_ops += Wrapping(1);
+                        self.counters.write_latency.record(latency);
                         if latency < self.counters.write_latency_min.load(Ordering::Relaxed) {
                             self.counters
                                 .write_latency_min
@@ -396,10 +451,22 @@ impl BlockEpollHandler {
             .read_ops
             .fetch_add(read_ops.0, Ordering::AcqRel);
 
-        Ok(used_descs)
+        self.must_notify(next_used, used_descs)
+    }
+
+    // Send the notification held back on the queue, once its coalescing
+    // timer expires.
+    fn handle_coalescing_event(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(coalescer) = self.coalescer.as_mut() {
+            if coalescer.expired().map_err(DeviceError::IoError)? {
+                self.signal_used_queue()?;
+            }
+        }
+        Ok(())
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.queue_cursors.signalled(&self.queue);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(self.queue_index))
             .map_err(|e| {
@@ -413,12 +480,16 @@ impl BlockEpollHandler {
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
-        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        let mut helper =
+            EpollHelper::new_with_event_loop(&self.kill_evt, &self.pause_evt, self.event_loop)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
         helper.add_event(self.disk_image.notifier().as_raw_fd(), COMPLETION_EVENT)?;
         if let Some(rate_limiter) = &self.rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
         }
+        if let Some(coalescer) = &self.coalescer {
+            helper.add_event(coalescer.as_raw_fd(), COALESCING_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -485,6 +556,14 @@ impl EpollHelperHandler for BlockEpollHandler {
                     )));
                 }
             }
+            COALESCING_EVENT => {
+                self.handle_coalescing_event().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to send the queue notification: {:?}",
+                        e
+                    ))
+                })?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {}",
@@ -492,10 +571,33 @@ impl EpollHelperHandler for BlockEpollHandler {
                 )));
             }
         }
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
 
+// The I/O submitted by a queue thread, be it synchronously, through AIO or
+// through io_uring, is scheduled with the I/O priority of the thread.
+fn set_io_priority(io_priority: u16) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    // SAFETY: FFI call with valid arguments, a "who" of 0 being the calling
+    // thread.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            libc::c_int::from(io_priority),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
     common: VirtioCommon,
@@ -508,9 +610,12 @@ pub struct Block {
     counters: BlockCounters,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    io_priority: Option<u16>,
+    notification: NotificationConfig,
     exit_evt: EventFd,
     read_only: bool,
     serial: Vec<u8>,
+    event_loop: EventLoop,
 }
 
 #[derive(Versionize)]
@@ -538,6 +643,8 @@ impl Block {
         serial: Option<String>,
         seccomp_action: SeccompAction,
         rate_limiter_config: Option<RateLimiterConfig>,
+        io_priority: Option<u16>,
+        notification: NotificationConfig,
         exit_evt: EventFd,
         state: Option<BlockState>,
     ) -> io::Result<Self> {
@@ -572,6 +679,10 @@ impl Block {
                     | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
                     | (1u64 << VIRTIO_BLK_F_TOPOLOGY);
 
+                if notification.event_idx {
+                    avail_features |= 1u64 << VIRTIO_RING_F_EVENT_IDX;
+                }
+
                 if iommu {
                     avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
                 }
@@ -640,12 +751,20 @@ impl Block {
             counters: BlockCounters::default(),
             seccomp_action,
             rate_limiter_config,
+            io_priority,
+            notification,
             exit_evt,
             read_only,
             serial,
+            event_loop: EventLoop::default(),
         })
     }
 
+    /// Set how the queue threads wait for their events.
+    pub fn set_event_loop(&mut self, event_loop: EventLoop) {
+        self.event_loop = event_loop;
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.to_str().unwrap().to_owned(),
@@ -741,9 +860,11 @@ impl VirtioDevice for Block {
 
         self.update_writeback();
 
+        let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
         let mut epoll_threads = Vec::new();
         for i in 0..queues.len() {
-            let (_, queue, queue_evt) = queues.remove(0);
+            let (index, mut queue, queue_evt) = queues.remove(0);
+            queue.set_event_idx(event_idx);
             let queue_size = queue.size();
             let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
@@ -752,10 +873,13 @@ impl VirtioDevice for Block {
                 .map(RateLimiterConfig::try_into)
                 .transpose()
                 .map_err(ActivateError::CreateRateLimiter)?;
+            let coalescer = NotificationCoalescer::new(&self.notification)
+                .map_err(ActivateError::CreateNotificationCoalescer)?;
 
             let mut handler = BlockEpollHandler {
                 queue_index: i as u16,
                 queue,
+                queue_cursors: self.common.queue_cursors(index),
                 mem: mem.clone(),
                 disk_image: self
                     .disk_image
@@ -777,12 +901,16 @@ impl VirtioDevice for Block {
                 // compromising the cost of the reallocation or memory overhead
                 inflight_requests: VecDeque::with_capacity(64),
                 rate_limiter,
+                coalescer,
                 access_platform: self.common.access_platform.clone(),
                 read_only: self.read_only,
+                event_loop: self.event_loop,
             };
 
             let paused = self.common.paused.clone();
             let paused_sync = self.common.paused_sync.clone();
+            let io_priority = self.io_priority;
+            let id = self.id.clone();
 
             spawn_virtio_thread(
                 &format!("{}_q{}", self.id.clone(), i),
@@ -790,7 +918,19 @@ impl VirtioDevice for Block {
                 Thread::VirtioBlock,
                 &mut epoll_threads,
                 &self.exit_evt,
-                move || handler.run(paused, paused_sync.unwrap()),
+                move || {
+                    // The realtime class requires CAP_SYS_ADMIN, the queue
+                    // rather keeping the priority of the VMM than failing.
+                    if let Some(io_priority) = io_priority {
+                        if let Err(e) = set_io_priority(io_priority) {
+                            warn!(
+                                "Cannot set the I/O priority of {}, keeping the default one: {}",
+                                id, e
+                            );
+                        }
+                    }
+                    handler.run(paused, paused_sync.unwrap())
+                },
             )?;
         }
 
@@ -849,10 +989,16 @@ impl VirtioDevice for Block {
             "read_latency_avg",
             Wrapping(self.counters.read_latency_avg.load(Ordering::Acquire) / LATENCY_SCALE),
         );
+        self.counters.read_latency.add_counters(&mut counters);
+        self.counters.write_latency.add_counters(&mut counters);
 
         Some(counters)
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
@@ -0,0 +1,124 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Coalescing of the used buffer notifications.
+//!
+//! Rather than notifying the driver each time buffers are added to a used
+//! ring, the notification is held back until enough buffers have been used,
+//! or until a timer expires, trading some latency for fewer interrupts.
+
+use crate::NotificationConfig;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use vmm_sys_util::timerfd::TimerFd;
+
+pub struct NotificationCoalescer {
+    buffers: u32,
+    timeout: Duration,
+    // Buffers used since the first notification held back.
+    pending: u32,
+    // A notification is held back, the timer being armed.
+    owed: bool,
+    timer: TimerFd,
+}
+
+impl NotificationCoalescer {
+    /// Create the coalescer of a queue, or None if the notifications aren't
+    /// coalesced.
+    pub fn new(config: &NotificationConfig) -> io::Result<Option<Self>> {
+        if config.coalesce_buffers <= 1 {
+            return Ok(None);
+        }
+
+        Ok(Some(NotificationCoalescer {
+            buffers: u32::from(config.coalesce_buffers),
+            timeout: Duration::from_micros(config.coalesce_timeout),
+            pending: 0,
+            owed: false,
+            timer: TimerFd::new()?,
+        }))
+    }
+
+    /// Account for `used` buffers added to the used ring, `needs_notification`
+    /// telling whether the driver expects to be notified about them. Returns
+    /// whether the driver must be notified right away, the notification being
+    /// otherwise sent once the timer expires.
+    pub fn add_used(&mut self, used: u16, needs_notification: bool) -> io::Result<bool> {
+        if !needs_notification && !self.owed {
+            return Ok(false);
+        }
+
+        self.pending += u32::from(used);
+        if self.pending >= self.buffers {
+            if self.owed {
+                self.timer.clear()?;
+            }
+            self.pending = 0;
+            self.owed = false;
+            return Ok(true);
+        }
+
+        if !self.owed {
+            self.timer.reset(self.timeout, None)?;
+            self.owed = true;
+        }
+
+        Ok(false)
+    }
+
+    /// Handle the expiration of the timer, returning whether the notification
+    /// held back must be sent.
+    pub fn expired(&mut self) -> io::Result<bool> {
+        // The timer got cleared since the event was reported.
+        if !self.owed {
+            return Ok(false);
+        }
+
+        self.timer.wait()?;
+        self.pending = 0;
+        self.owed = false;
+
+        Ok(true)
+    }
+}
+
+impl AsRawFd for NotificationCoalescer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notification_coalescer() {
+        assert!(NotificationCoalescer::new(&NotificationConfig::default())
+            .unwrap()
+            .is_none());
+
+        let mut coalescer = NotificationCoalescer::new(&NotificationConfig {
+            coalesce_buffers: 4,
+            coalesce_timeout: 1000,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        // Nothing to hold back until the driver expects a notification
+        assert!(!coalescer.add_used(3, false).unwrap());
+        assert!(!coalescer.add_used(1, true).unwrap());
+        // Once held back, all the buffers used count
+        assert!(!coalescer.add_used(2, false).unwrap());
+        assert!(coalescer.add_used(1, false).unwrap());
+        assert!(!coalescer.expired().unwrap());
+
+        // Sent once the timer expires otherwise
+        assert!(!coalescer.add_used(1, true).unwrap());
+        assert!(coalescer.expired().unwrap());
+        assert!(!coalescer.add_used(1, false).unwrap());
+    }
+}
@@ -3,9 +3,9 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice,
-    VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
-    VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors,
+    VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -16,11 +16,13 @@ use libc::{EFD_NONBLOCK, TIOCGWINSZ};
 use seccompiler::SeccompAction;
 use serial_buffer::SerialBuffer;
 use std::cmp;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::io;
 use std::io::{Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixListener;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
@@ -36,7 +38,6 @@ use vmm_sys_util::eventfd::EventFd;
 
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
 // New descriptors are pending on the virtio queue.
 const INPUT_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
@@ -47,9 +48,42 @@ const CONFIG_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 const FILE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 // Console resized
 const RESIZE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
+// New descriptors are pending on the control queues.
+const CONTROL_RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+const CONTROL_TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// Additional ports have been plugged or unplugged
+const PORTS_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
+// Each additional port owns PORT_EVENT_COUNT consecutive events starting
+// from PORT_EVENT_BASE + (port - 1) * PORT_EVENT_COUNT.
+const PORT_EVENT_BASE: u16 = EPOLL_HELPER_EVENT_LAST + 9;
+const PORT_EVENT_COUNT: u16 = 4;
+const PORT_RX_QUEUE_EVENT: u16 = 0;
+const PORT_TX_QUEUE_EVENT: u16 = 1;
+const PORT_LISTENER_EVENT: u16 = 2;
+const PORT_INPUT_EVENT: u16 = 3;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+//Multiple ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+/// Maximum number of ports, including the console port, a virtio-console
+/// device can expose.
+pub const VIRTIO_CONSOLE_MAX_PORTS: u32 = 32;
+
+// Control message events
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Queue indexes of the control queues
+const CONTROL_RX_QUEUE_INDEX: u16 = 2;
+const CONTROL_TX_QUEUE_INDEX: u16 = 3;
 
 #[derive(Error, Debug)]
 enum Error {
@@ -63,6 +97,23 @@ enum Error {
     OutputFlush(io::Error),
     #[error("Failed to add used index: {0}")]
     QueueAddUsed(virtio_queue::Error),
+    #[error("Descriptor too small for control message")]
+    ControlDescriptorTooSmall,
+}
+
+/// Errors when plugging or unplugging console ports.
+#[derive(Error, Debug)]
+pub enum ConsolePortError {
+    #[error("Multiport is not enabled on the virtio-console device")]
+    MultiportDisabled,
+    #[error("No free port left on the virtio-console device")]
+    NoFreePort,
+    #[error("Console port identifier already in use: {0}")]
+    DuplicateId(String),
+    #[error("Unknown console port: {0}")]
+    UnknownPort(String),
+    #[error("Failed to notify the port change: {0}")]
+    Notify(io::Error),
 }
 
 #[derive(Copy, Clone, Debug, Versionize)]
@@ -88,10 +139,164 @@ impl Default for VirtioConsoleConfig {
 // SAFETY: it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+/// Host side of an additional console port.
+pub enum ConsolePortEndpoint {
+    /// PTY main and subsidiary sides. The subsidiary side is kept open so
+    /// the main side does not report a hang-up while nothing is attached.
+    Pty(File, File),
+    /// Listening UNIX socket, accepting one connection at a time.
+    Socket(UnixListener),
+}
+
+impl ConsolePortEndpoint {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Pty(main, sub) => Self::Pty(main.try_clone()?, sub.try_clone()?),
+            Self::Socket(listener) => Self::Socket(listener.try_clone()?),
+        })
+    }
+}
+
+/// Additional port plugged into a multiport virtio-console device.
+pub struct ConsolePort {
+    pub id: String,
+    pub name: Option<String>,
+    pub endpoint: ConsolePortEndpoint,
+}
+
+impl Drop for ConsolePort {
+    fn drop(&mut self) {
+        if let ConsolePortEndpoint::Socket(listener) = &self.endpoint {
+            if let Some(path) = listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|p| p.to_path_buf()))
+            {
+                std::fs::remove_file(path).ok();
+            }
+        }
+    }
+}
+
+fn port_rx_queue_index(port: u32) -> u16 {
+    if port == 0 {
+        0
+    } else {
+        (2 + 2 * port) as u16
+    }
+}
+
+fn port_tx_queue_index(port: u32) -> u16 {
+    port_rx_queue_index(port) + 1
+}
+
+fn port_event(port: u32, event: u16) -> u16 {
+    PORT_EVENT_BASE + (port as u16 - 1) * PORT_EVENT_COUNT + event
+}
+
+// Runtime state of an additional port slot.
+struct PortHandler {
+    rx_queue: Queue,
+    rx_queue_evt: EventFd,
+    tx_queue: Queue,
+    tx_queue_evt: EventFd,
+    // Set while a port is plugged into this slot.
+    id: Option<String>,
+    endpoint: Option<ConsolePortEndpoint>,
+    name: Option<String>,
+    // PTY main side or connected socket.
+    conn: Option<File>,
+    in_buffer: VecDeque<u8>,
+}
+
+impl PortHandler {
+    fn plug(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: u32,
+        plugged: &ConsolePort,
+    ) -> result::Result<(), EpollHelperError> {
+        let endpoint = plugged.endpoint.try_clone().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to clone port endpoint: {:?}", e))
+        })?;
+        match &endpoint {
+            ConsolePortEndpoint::Pty(main, _) => {
+                let conn = main.try_clone().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to clone port PTY: {:?}", e))
+                })?;
+                helper.add_event(conn.as_raw_fd(), port_event(port, PORT_INPUT_EVENT))?;
+                self.conn = Some(conn);
+            }
+            ConsolePortEndpoint::Socket(listener) => {
+                helper.add_event(listener.as_raw_fd(), port_event(port, PORT_LISTENER_EVENT))?;
+            }
+        }
+        self.id = Some(plugged.id.clone());
+        self.endpoint = Some(endpoint);
+        self.name = plugged.name.clone();
+
+        Ok(())
+    }
+
+    fn unplug(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: u32,
+    ) -> result::Result<(), EpollHelperError> {
+        if let Some(conn) = self.conn.take() {
+            helper.del_event_custom(
+                conn.as_raw_fd(),
+                port_event(port, PORT_INPUT_EVENT),
+                epoll::Events::EPOLLIN,
+            )?;
+        }
+        if let Some(ConsolePortEndpoint::Socket(listener)) = self.endpoint.as_ref() {
+            helper.del_event_custom(
+                listener.as_raw_fd(),
+                port_event(port, PORT_LISTENER_EVENT),
+                epoll::Events::EPOLLIN,
+            )?;
+        }
+        self.id = None;
+        self.endpoint = None;
+        self.name = None;
+        self.in_buffer.clear();
+
+        Ok(())
+    }
+}
+
+struct MultiportHandler {
+    control_rx_queue: Queue,
+    control_rx_queue_evt: EventFd,
+    control_tx_queue: Queue,
+    control_tx_queue_evt: EventFd,
+    ports: Arc<Mutex<BTreeMap<u32, ConsolePort>>>,
+    ports_evt: EventFd,
+    // Indexed by port number minus one.
+    port_handlers: Vec<PortHandler>,
+    driver_ready: Arc<AtomicBool>,
+    // Control messages waiting for a buffer on the control receive queue.
+    pending_control: VecDeque<Vec<u8>>,
+}
+
 struct ConsoleEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     input_queue: Queue,
     output_queue: Queue,
+    // Indexed by queue, following the layout of the device queues.
+    queue_cursors: Vec<Arc<QueueCursors>>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
     resizer: Arc<ConsoleResizer>,
@@ -106,6 +311,7 @@ struct ConsoleEpollHandler {
     out: Option<Box<dyn Write + Send>>,
     write_out: Option<Arc<AtomicBool>>,
     file_event_registered: bool,
+    multiport: Option<MultiportHandler>,
 }
 
 pub enum Endpoint {
@@ -160,6 +366,7 @@ impl ConsoleEpollHandler {
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
         input_queue: Queue,
         output_queue: Queue,
+        queue_cursors: Vec<Arc<QueueCursors>>,
         interrupt_cb: Arc<dyn VirtioInterrupt>,
         in_buffer: Arc<Mutex<VecDeque<u8>>>,
         resizer: Arc<ConsoleResizer>,
@@ -171,6 +378,7 @@ impl ConsoleEpollHandler {
         kill_evt: EventFd,
         pause_evt: EventFd,
         access_platform: Option<Arc<dyn AccessPlatform>>,
+        multiport: Option<MultiportHandler>,
     ) -> Self {
         let out_file = endpoint.out_file();
         let (out, write_out) = if let Some(out_file) = out_file {
@@ -191,6 +399,7 @@ impl ConsoleEpollHandler {
             mem,
             input_queue,
             output_queue,
+            queue_cursors,
             interrupt_cb,
             in_buffer,
             resizer,
@@ -205,6 +414,7 @@ impl ConsoleEpollHandler {
             out,
             write_out,
             file_event_registered: false,
+            multiport,
         }
     }
 
@@ -284,7 +494,42 @@ impl ConsoleEpollHandler {
         Ok(used_descs)
     }
 
+    // Queue at the given index, following the layout of the device queues.
+    fn queue(&self, index: usize) -> Option<&Queue> {
+        match index {
+            0 => Some(&self.input_queue),
+            1 => Some(&self.output_queue),
+            _ => {
+                let multiport = self.multiport.as_ref()?;
+                match index {
+                    2 => Some(&multiport.control_rx_queue),
+                    3 => Some(&multiport.control_tx_queue),
+                    _ => {
+                        let port_handler = multiport.port_handlers.get((index - 4) / 2)?;
+                        if index % 2 == 0 {
+                            Some(&port_handler.rx_queue)
+                        } else {
+                            Some(&port_handler.tx_queue)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn update_queue_cursors(&self) {
+        for (index, cursors) in self.queue_cursors.iter().enumerate() {
+            if let Some(queue) = self.queue(index) {
+                cursors.update(queue);
+            }
+        }
+    }
+
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        let index = queue_index as usize;
+        if let (Some(cursors), Some(queue)) = (self.queue_cursors.get(index), self.queue(index)) {
+            cursors.signalled(queue);
+        }
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(queue_index))
             .map_err(|e| {
@@ -293,6 +538,493 @@ impl ConsoleEpollHandler {
             })
     }
 
+    fn queue_control(&mut self, id: u32, event: u16, value: u16, data: &[u8]) {
+        if let Some(multiport) = self.multiport.as_mut() {
+            let mut msg = VirtioConsoleControl { id, event, value }
+                .as_slice()
+                .to_vec();
+            msg.extend_from_slice(data);
+            multiport.pending_control.push_back(msg);
+        }
+    }
+
+    /*
+     * The control receive queue carries messages from the device
+     * to the driver, such as port hotplug notifications. Here,
+     * we place the pending control messages into the empty
+     * buffers provided by the driver.
+     */
+    fn process_control_rx_queue(&mut self) -> Result<bool, Error> {
+        let multiport = match self.multiport.as_mut() {
+            Some(multiport) => multiport,
+            None => return Ok(false),
+        };
+        let mut used_descs = false;
+
+        while !multiport.pending_control.is_empty() {
+            let mut desc_chain = match multiport
+                .control_rx_queue
+                .pop_descriptor_chain(self.mem.memory())
+            {
+                Some(desc_chain) => desc_chain,
+                None => break,
+            };
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+            // Give back a buffer too small for the message without consuming
+            // it, the message waiting for the next one.
+            if (desc.len() as usize) < multiport.pending_control[0].len() {
+                warn!("{}", Error::ControlDescriptorTooSmall);
+                multiport
+                    .control_rx_queue
+                    .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                    .map_err(Error::QueueAddUsed)?;
+                used_descs = true;
+                continue;
+            }
+            let msg = multiport.pending_control.pop_front().unwrap();
+
+            desc_chain
+                .memory()
+                .write_slice(
+                    &msg,
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                )
+                .map_err(Error::GuestMemoryWrite)?;
+
+            multiport
+                .control_rx_queue
+                .add_used(
+                    desc_chain.memory(),
+                    desc_chain.head_index(),
+                    msg.len() as u32,
+                )
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    /*
+     * The control transmit queue carries messages from the
+     * driver to the device, such as the readiness of the driver
+     * and of each port. Here, we read and handle them.
+     */
+    fn process_control_tx_queue(&mut self) -> Result<bool, Error> {
+        let multiport = match self.multiport.as_mut() {
+            Some(multiport) => multiport,
+            None => return Ok(false),
+        };
+        let mut used_descs = false;
+        let mut msgs = Vec::new();
+
+        while let Some(mut desc_chain) = multiport
+            .control_tx_queue
+            .pop_descriptor_chain(self.mem.memory())
+        {
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+            // A message too short to be parsed is dropped, rather than
+            // bringing the whole device down.
+            if (desc.len() as usize) < size_of::<VirtioConsoleControl>() {
+                warn!("{}", Error::ControlDescriptorTooSmall);
+                multiport
+                    .control_tx_queue
+                    .add_used(desc_chain.memory(), desc_chain.head_index(), 0)
+                    .map_err(Error::QueueAddUsed)?;
+                used_descs = true;
+                continue;
+            }
+
+            let msg: VirtioConsoleControl = desc_chain
+                .memory()
+                .read_obj(
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                )
+                .map_err(Error::GuestMemoryRead)?;
+            msgs.push(msg);
+
+            multiport
+                .control_tx_queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), desc.len())
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        for msg in msgs {
+            self.handle_control_message(msg);
+        }
+
+        Ok(used_descs)
+    }
+
+    fn handle_control_message(&mut self, msg: VirtioConsoleControl) {
+        let (id, event, value) = (msg.id, msg.event, msg.value);
+
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value != 1 {
+                    error!("virtio-console driver failed to initialize");
+                    return;
+                }
+
+                let multiport = self.multiport.as_ref().unwrap();
+                multiport.driver_ready.store(true, Ordering::Release);
+                let ports: Vec<u32> = multiport
+                    .port_handlers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, handler)| handler.id.is_some())
+                    .map(|(idx, _)| idx as u32 + 1)
+                    .collect();
+
+                self.queue_control(0, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                for port in ports {
+                    self.queue_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                if value != 1 {
+                    warn!("virtio-console port {} failed to initialize", id);
+                    return;
+                }
+
+                if id == 0 {
+                    self.queue_control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                    self.queue_control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                    return;
+                }
+
+                let (name, connected) = match self
+                    .multiport
+                    .as_ref()
+                    .unwrap()
+                    .port_handlers
+                    .get(id as usize - 1)
+                {
+                    Some(handler) if handler.id.is_some() => {
+                        (handler.name.clone(), handler.conn.is_some())
+                    }
+                    _ => return,
+                };
+                if let Some(name) = name {
+                    self.queue_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                if connected {
+                    self.queue_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                debug!("virtio-console port {} guest open state: {}", id, value);
+            }
+            _ => {
+                warn!("Unexpected virtio-console control event {}", event);
+            }
+        }
+    }
+
+    fn flush_control_queue(&mut self) -> result::Result<(), EpollHelperError> {
+        let needs_notification = self.process_control_rx_queue().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!(
+                "Failed to process control receive queue : {:?}",
+                e
+            ))
+        })?;
+        if needs_notification {
+            self.signal_used_queue(CONTROL_RX_QUEUE_INDEX)
+                .map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    // With multiport, the driver expects console size changes through a
+    // control message rather than through the configuration space.
+    fn send_console_size(&mut self) -> result::Result<(), EpollHelperError> {
+        match self.multiport.as_ref() {
+            Some(multiport) if multiport.driver_ready.load(Ordering::Acquire) => {}
+            _ => return Ok(()),
+        }
+
+        let config = *self.resizer.config.lock().unwrap();
+        let (rows, cols) = (config.rows, config.cols);
+        let mut size = rows.to_le_bytes().to_vec();
+        size.extend_from_slice(&cols.to_le_bytes());
+        self.queue_control(0, VIRTIO_CONSOLE_RESIZE, 0, &size);
+
+        self.flush_control_queue()
+    }
+
+    // Reconcile the port slots with the ports currently plugged into the
+    // device, and let the driver know about the changes.
+    fn update_ports(&mut self, helper: &mut EpollHelper) -> result::Result<(), EpollHelperError> {
+        let multiport = match self.multiport.as_mut() {
+            Some(multiport) => multiport,
+            None => return Ok(()),
+        };
+        let driver_ready = multiport.driver_ready.load(Ordering::Acquire);
+        let ports = multiport.ports.clone();
+        let ports = ports.lock().unwrap();
+        let mut notifications = Vec::new();
+
+        for (idx, handler) in multiport.port_handlers.iter_mut().enumerate() {
+            let port = idx as u32 + 1;
+            let plugged = ports.get(&port);
+
+            if handler.id.is_some()
+                && plugged
+                    .map(|p| Some(&p.id) != handler.id.as_ref())
+                    .unwrap_or(true)
+            {
+                handler.unplug(helper, port)?;
+                if driver_ready {
+                    notifications.push((port, VIRTIO_CONSOLE_DEVICE_REMOVE));
+                }
+            }
+
+            if let Some(plugged) = plugged {
+                if handler.id.is_none() {
+                    handler.plug(helper, port, plugged)?;
+                    if driver_ready {
+                        notifications.push((port, VIRTIO_CONSOLE_DEVICE_ADD));
+                    }
+                }
+            }
+        }
+        drop(ports);
+
+        for (port, event) in notifications {
+            self.queue_control(port, event, 0, &[]);
+        }
+
+        self.flush_control_queue()
+    }
+
+    fn port_handler(&mut self, port: u32) -> &mut PortHandler {
+        &mut self.multiport.as_mut().unwrap().port_handlers[port as usize - 1]
+    }
+
+    fn process_port_rx_queue(&mut self, port: u32) -> Result<bool, Error> {
+        let handler = &mut self.multiport.as_mut().unwrap().port_handlers[port as usize - 1];
+        let mut used_descs = false;
+
+        if handler.in_buffer.is_empty() {
+            return Ok(false);
+        }
+
+        while let Some(mut desc_chain) = handler.rx_queue.pop_descriptor_chain(self.mem.memory()) {
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+            let len = cmp::min(desc.len(), handler.in_buffer.len() as u32);
+            let source_slice = handler.in_buffer.drain(..len as usize).collect::<Vec<u8>>();
+
+            desc_chain
+                .memory()
+                .write_slice(
+                    &source_slice[..],
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                )
+                .map_err(Error::GuestMemoryWrite)?;
+
+            handler
+                .rx_queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), len)
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+
+            if handler.in_buffer.is_empty() {
+                break;
+            }
+        }
+
+        Ok(used_descs)
+    }
+
+    fn process_port_tx_queue(&mut self, port: u32) -> Result<bool, Error> {
+        let handler = &mut self.multiport.as_mut().unwrap().port_handlers[port as usize - 1];
+        let mut used_descs = false;
+
+        while let Some(mut desc_chain) = handler.tx_queue.pop_descriptor_chain(self.mem.memory()) {
+            let desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
+            // Output is dropped while nothing is connected to the port.
+            if let Some(conn) = handler.conn.as_mut() {
+                if let Err(e) = desc_chain.memory().write_to(
+                    desc.addr()
+                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                    conn,
+                    desc.len() as usize,
+                ) {
+                    warn!("Failed to write to virtio-console port {}: {:?}", port, e);
+                }
+            }
+            handler
+                .tx_queue
+                .add_used(desc_chain.memory(), desc_chain.head_index(), desc.len())
+                .map_err(Error::QueueAddUsed)?;
+            used_descs = true;
+        }
+
+        Ok(used_descs)
+    }
+
+    fn accept_port_connection(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: u32,
+    ) -> result::Result<(), EpollHelperError> {
+        let handler = self.port_handler(port);
+        let stream = match handler.endpoint.as_ref() {
+            Some(ConsolePortEndpoint::Socket(listener)) => match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(
+                        "Failed to accept virtio-console port {} connection: {:?}",
+                        port, e
+                    );
+                    return Ok(());
+                }
+            },
+            _ => return Ok(()),
+        };
+
+        if handler.conn.is_some() {
+            warn!("virtio-console port {} is already connected", port);
+            return Ok(());
+        }
+
+        // SAFETY: the file descriptor is owned by the consumed stream.
+        let conn = unsafe { File::from_raw_fd(stream.into_raw_fd()) };
+        helper.add_event(conn.as_raw_fd(), port_event(port, PORT_INPUT_EVENT))?;
+        handler.conn = Some(conn);
+
+        if self
+            .multiport
+            .as_ref()
+            .unwrap()
+            .driver_ready
+            .load(Ordering::Acquire)
+        {
+            self.queue_control(port, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            self.flush_control_queue()?;
+        }
+
+        Ok(())
+    }
+
+    fn read_port_input(
+        &mut self,
+        helper: &mut EpollHelper,
+        port: u32,
+        events: u32,
+    ) -> result::Result<(), EpollHelperError> {
+        let handler = self.port_handler(port);
+        let mut disconnected = false;
+
+        if let Some(conn) = handler.conn.as_mut() {
+            if events & libc::EPOLLIN as u32 != 0 {
+                let mut input = [0u8; 64];
+                match conn.read(&mut input) {
+                    Ok(0) => disconnected = true,
+                    Ok(count) => handler.in_buffer.extend(&input[..count]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        warn!("Failed to read from virtio-console port {}: {:?}", port, e);
+                        disconnected = true;
+                    }
+                }
+            } else if events & (libc::EPOLLHUP | libc::EPOLLERR) as u32 != 0 {
+                disconnected = true;
+            }
+        }
+
+        if disconnected {
+            let conn = handler.conn.take().unwrap();
+            helper.del_event_custom(
+                conn.as_raw_fd(),
+                port_event(port, PORT_INPUT_EVENT),
+                epoll::Events::EPOLLIN,
+            )?;
+
+            if self
+                .multiport
+                .as_ref()
+                .unwrap()
+                .driver_ready
+                .load(Ordering::Acquire)
+            {
+                self.queue_control(port, VIRTIO_CONSOLE_PORT_OPEN, 0, &[]);
+                self.flush_control_queue()?;
+            }
+            return Ok(());
+        }
+
+        let needs_notification = self.process_port_rx_queue(port).map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process port queue : {:?}", e))
+        })?;
+        if needs_notification {
+            self.signal_used_queue(port_rx_queue_index(port))
+                .map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+                })?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_port_event(
+        &mut self,
+        helper: &mut EpollHelper,
+        ev_type: u16,
+        events: u32,
+    ) -> result::Result<(), EpollHelperError> {
+        let port = ((ev_type - PORT_EVENT_BASE) / PORT_EVENT_COUNT) as u32 + 1;
+
+        match (ev_type - PORT_EVENT_BASE) % PORT_EVENT_COUNT {
+            PORT_RX_QUEUE_EVENT => {
+                self.port_handler(port).rx_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_port_rx_queue(port).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process port queue : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue(port_rx_queue_index(port))
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used queue: {:?}",
+                                e
+                            ))
+                        })?;
+                }
+            }
+            PORT_TX_QUEUE_EVENT => {
+                self.port_handler(port).tx_queue_evt.read().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                })?;
+                let needs_notification = self.process_port_tx_queue(port).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!("Failed to process port queue : {:?}", e))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue(port_tx_queue_index(port))
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used queue: {:?}",
+                                e
+                            ))
+                        })?;
+                }
+            }
+            PORT_LISTENER_EVENT => self.accept_port_connection(helper, port)?,
+            PORT_INPUT_EVENT => self.read_port_input(helper, port, events)?,
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -313,6 +1045,29 @@ impl ConsoleEpollHandler {
             helper.add_event_custom(in_file.as_raw_fd(), FILE_EVENT, events)?;
             self.file_event_registered = true;
         }
+        if let Some(multiport) = self.multiport.as_ref() {
+            helper.add_event(
+                multiport.control_rx_queue_evt.as_raw_fd(),
+                CONTROL_RX_QUEUE_EVENT,
+            )?;
+            helper.add_event(
+                multiport.control_tx_queue_evt.as_raw_fd(),
+                CONTROL_TX_QUEUE_EVENT,
+            )?;
+            helper.add_event(multiport.ports_evt.as_raw_fd(), PORTS_EVENT)?;
+            for (idx, handler) in multiport.port_handlers.iter().enumerate() {
+                let port = idx as u32 + 1;
+                helper.add_event(
+                    handler.rx_queue_evt.as_raw_fd(),
+                    port_event(port, PORT_RX_QUEUE_EVENT),
+                )?;
+                helper.add_event(
+                    handler.tx_queue_evt.as_raw_fd(),
+                    port_event(port, PORT_TX_QUEUE_EVENT),
+                )?;
+            }
+        }
+        self.update_ports(&mut helper)?;
 
         // In case of PTY, we want to be able to detect a connection on the
         // other end of the PTY. This is done by detecting there's no event
@@ -426,6 +1181,7 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                             e
                         ))
                     })?;
+                self.send_console_size()?;
             }
             RESIZE_EVENT => {
                 self.resize_pipe
@@ -484,12 +1240,55 @@ impl EpollHelperHandler for ConsoleEpollHandler {
                     }
                 }
             }
+            CONTROL_RX_QUEUE_EVENT => {
+                if let Some(multiport) = self.multiport.as_ref() {
+                    multiport.control_rx_queue_evt.read().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                    })?;
+                }
+                self.flush_control_queue()?;
+            }
+            CONTROL_TX_QUEUE_EVENT => {
+                if let Some(multiport) = self.multiport.as_ref() {
+                    multiport.control_tx_queue_evt.read().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
+                    })?;
+                }
+                let needs_notification = self.process_control_tx_queue().map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Failed to process control transmit queue : {:?}",
+                        e
+                    ))
+                })?;
+                if needs_notification {
+                    self.signal_used_queue(CONTROL_TX_QUEUE_INDEX)
+                        .map_err(|e| {
+                            EpollHelperError::HandleEvent(anyhow!(
+                                "Failed to signal used queue: {:?}",
+                                e
+                            ))
+                        })?;
+                }
+                self.flush_control_queue()?;
+            }
+            PORTS_EVENT => {
+                if let Some(multiport) = self.multiport.as_ref() {
+                    multiport.ports_evt.read().map_err(|e| {
+                        EpollHelperError::HandleEvent(anyhow!("Failed to get ports event: {:?}", e))
+                    })?;
+                }
+                self.update_ports(helper)?;
+            }
+            ev_type if ev_type >= PORT_EVENT_BASE && self.multiport.is_some() => {
+                self.handle_port_event(helper, ev_type, event.events)?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unknown event for virtio-console"
                 )));
             }
         }
+        self.update_queue_cursors();
         Ok(())
     }
 
@@ -586,6 +1385,9 @@ pub struct Console {
     seccomp_action: SeccompAction,
     in_buffer: Arc<Mutex<VecDeque<u8>>>,
     exit_evt: EventFd,
+    ports: Arc<Mutex<BTreeMap<u32, ConsolePort>>>,
+    ports_evt: EventFd,
+    driver_ready: Arc<AtomicBool>,
 }
 
 #[derive(Versionize)]
@@ -594,6 +1396,7 @@ pub struct ConsoleState {
     acked_features: u64,
     config: VirtioConsoleConfig,
     in_buffer: Vec<u8>,
+    driver_ready: bool,
 }
 
 fn get_win_size(tty: &dyn AsRawFd) -> (u16, u16) {
@@ -619,38 +1422,52 @@ impl VersionMapped for ConsoleState {}
 
 impl Console {
     /// Create a new virtio console device
+    ///
+    /// When `max_ports` is greater than one, the multiport feature is
+    /// offered so that additional ports can be plugged at runtime.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         endpoint: Endpoint,
         resize_pipe: Option<File>,
         iommu: bool,
+        max_ports: u32,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         state: Option<ConsoleState>,
     ) -> io::Result<(Console, Arc<ConsoleResizer>)> {
-        let (avail_features, acked_features, config, in_buffer, paused) = if let Some(state) = state
-        {
-            info!("Restoring virtio-console {}", id);
-            (
-                state.avail_features,
-                state.acked_features,
-                state.config,
-                state.in_buffer.into(),
-                true,
-            )
+        let (avail_features, acked_features, config, in_buffer, driver_ready, paused) =
+            if let Some(state) = state {
+                info!("Restoring virtio-console {}", id);
+                (
+                    state.avail_features,
+                    state.acked_features,
+                    state.config,
+                    state.in_buffer.into(),
+                    state.driver_ready,
+                    true,
+                )
+            } else {
+                let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
+                if iommu {
+                    avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+                }
+
+                let mut config = VirtioConsoleConfig::default();
+                if max_ports > 1 {
+                    avail_features |= 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+                    config.max_nr_ports = max_ports;
+                }
+
+                (avail_features, 0, config, VecDeque::new(), false, false)
+            };
+
+        // Each port owns a receive and a transmit queue, and the control
+        // queues sit between the queues of the first and second ports.
+        let num_queues = if avail_features & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0 {
+            2 * (config.max_nr_ports as usize + 1)
         } else {
-            let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
-            if iommu {
-                avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
-            }
-
-            (
-                avail_features,
-                0,
-                VirtioConsoleConfig::default(),
-                VecDeque::new(),
-                false,
-            )
+            NUM_QUEUES
         };
 
         let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
@@ -668,7 +1485,7 @@ impl Console {
             Console {
                 common: VirtioCommon {
                     device_type: VirtioDeviceType::Console as u32,
-                    queue_sizes: QUEUE_SIZES.to_vec(),
+                    queue_sizes: vec![QUEUE_SIZE; num_queues],
                     avail_features,
                     acked_features,
                     paused_sync: Some(Arc::new(Barrier::new(2))),
@@ -684,6 +1501,9 @@ impl Console {
                 seccomp_action,
                 in_buffer: Arc::new(Mutex::new(in_buffer)),
                 exit_evt,
+                ports: Arc::new(Mutex::new(BTreeMap::new())),
+                ports_evt: EventFd::new(EFD_NONBLOCK)?,
+                driver_ready: Arc::new(AtomicBool::new(driver_ready)),
             },
             resizer,
         ))
@@ -695,7 +1515,54 @@ impl Console {
             acked_features: self.common.acked_features,
             config: *(self.config.lock().unwrap()),
             in_buffer: self.in_buffer.lock().unwrap().clone().into(),
+            driver_ready: self.driver_ready.load(Ordering::Acquire),
+        }
+    }
+
+    /// Plug an additional port, returning the port number assigned to it.
+    pub fn add_port(&self, port: ConsolePort) -> result::Result<u32, ConsolePortError> {
+        if self.common.avail_features & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT) == 0 {
+            return Err(ConsolePortError::MultiportDisabled);
         }
+
+        let max_ports = self.config.lock().unwrap().max_nr_ports;
+        let mut ports = self.ports.lock().unwrap();
+        if ports.values().any(|p| p.id == port.id) {
+            return Err(ConsolePortError::DuplicateId(port.id.clone()));
+        }
+        let port_number = (1..max_ports)
+            .find(|n| !ports.contains_key(n))
+            .ok_or(ConsolePortError::NoFreePort)?;
+        ports.insert(port_number, port);
+        drop(ports);
+
+        self.ports_evt.write(1).map_err(ConsolePortError::Notify)?;
+
+        Ok(port_number)
+    }
+
+    /// Identifiers of the additional ports, by port number.
+    pub fn port_ids(&self) -> BTreeMap<u32, String> {
+        self.ports
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(number, port)| (*number, port.id.clone()))
+            .collect()
+    }
+
+    /// Unplug the additional port identified by `id`.
+    pub fn remove_port(&self, id: &str) -> result::Result<(), ConsolePortError> {
+        let mut ports = self.ports.lock().unwrap();
+        let port_number = ports
+            .iter()
+            .find(|(_, port)| port.id == id)
+            .map(|(n, _)| *n)
+            .ok_or_else(|| ConsolePortError::UnknownPort(id.to_owned()))?;
+        ports.remove(&port_number);
+        drop(ports);
+
+        self.ports_evt.write(1).map_err(ConsolePortError::Notify)
     }
 
     #[cfg(fuzzing)]
@@ -754,13 +1621,59 @@ impl VirtioDevice for Console {
 
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
+        let queue_cursors = queues
+            .iter()
+            .map(|(index, _, _)| self.common.queue_cursors(*index))
+            .collect();
+
         let (_, input_queue, input_queue_evt) = queues.remove(0);
         let (_, output_queue, output_queue_evt) = queues.remove(0);
 
+        let multiport = if self.common.feature_acked(VIRTIO_CONSOLE_F_MULTIPORT) {
+            if queues.len() < 2 {
+                error!("Missing control queues for virtio-console multiport");
+                return Err(ActivateError::BadActivate);
+            }
+            let (_, control_rx_queue, control_rx_queue_evt) = queues.remove(0);
+            let (_, control_tx_queue, control_tx_queue_evt) = queues.remove(0);
+
+            let mut port_handlers = Vec::new();
+            while queues.len() >= 2 {
+                let (_, rx_queue, rx_queue_evt) = queues.remove(0);
+                let (_, tx_queue, tx_queue_evt) = queues.remove(0);
+                port_handlers.push(PortHandler {
+                    rx_queue,
+                    rx_queue_evt,
+                    tx_queue,
+                    tx_queue_evt,
+                    id: None,
+                    endpoint: None,
+                    name: None,
+                    conn: None,
+                    in_buffer: VecDeque::new(),
+                });
+            }
+
+            Some(MultiportHandler {
+                control_rx_queue,
+                control_rx_queue_evt,
+                control_tx_queue,
+                control_tx_queue_evt,
+                ports: self.ports.clone(),
+                ports_evt: self.ports_evt.try_clone().unwrap(),
+                port_handlers,
+                driver_ready: self.driver_ready.clone(),
+                pending_control: VecDeque::new(),
+            })
+        } else {
+            None
+        };
+
         let mut handler = ConsoleEpollHandler::new(
             mem,
             input_queue,
             output_queue,
+            queue_cursors,
             interrupt_cb,
             self.in_buffer.clone(),
             Arc::clone(&self.resizer),
@@ -772,6 +1685,7 @@ impl VirtioDevice for Console {
             kill_evt,
             pause_evt,
             self.common.access_platform.clone(),
+            multiport,
         );
 
         let paused = self.common.paused.clone();
@@ -795,10 +1709,15 @@ impl VirtioDevice for Console {
 
     fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         let result = self.common.reset();
+        self.driver_ready.store(false, Ordering::Release);
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
@@ -15,11 +15,11 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::num::Wrapping;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Barrier,
+    atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering},
+    Arc, Barrier, Mutex, Weak,
 };
 use std::thread;
-use virtio_queue::Queue;
+use virtio_queue::{Queue, QueueT};
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestUsize};
 use vm_migration::{MigratableError, Pausable};
 use vm_virtio::AccessPlatform;
@@ -152,6 +152,12 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Returns the cursors published by the threads processing the queues,
+    /// indexed by queue, for the devices processing them in the VMM.
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        Vec::new()
+    }
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
@@ -190,6 +196,111 @@ pub trait DmaRemapping {
     fn translate_gpa(&self, id: u32, addr: u64) -> std::result::Result<u64, std::io::Error>;
 }
 
+/// Trait providing the remapping of the MSI messages of a device the same way
+/// a physical interrupt remapping table would, so that a device can only
+/// signal interrupts the guest allowed through the IOMMU. The implementation
+/// should be provided by the code emulating the IOMMU for the guest.
+pub trait InterruptRemapping: Send + Sync {
+    /// Remap the MSI message programmed by the device `id`, given as its
+    /// address and data, into the message delivered to the interrupt
+    /// controller.
+    fn remap_msi(
+        &self,
+        id: u32,
+        addr: u64,
+        data: u32,
+    ) -> std::result::Result<(u64, u32), std::io::Error>;
+    /// Register a listener notified whenever the remapping changes, so that
+    /// the messages remapped before can be remapped again.
+    fn add_listener(&self, listener: Weak<dyn InterruptRemappingListener>);
+}
+
+/// Trait implemented by the users of an interrupt remapping, to be told about
+/// the changes of the remapping.
+pub trait InterruptRemappingListener: Send + Sync {
+    fn remapping_changed(&self);
+}
+
+/// Listeners of an interrupt remapping, which are dropped along with the
+/// interrupt groups they belong to.
+#[derive(Default)]
+pub struct InterruptRemappingListeners {
+    listeners: Mutex<Vec<Weak<dyn InterruptRemappingListener>>>,
+}
+
+impl InterruptRemappingListeners {
+    pub fn add(&self, listener: Weak<dyn InterruptRemappingListener>) {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.retain(|l| l.strong_count() > 0);
+        listeners.push(listener);
+    }
+
+    /// Notify the listeners, which must be done without holding the locks
+    /// used by the remapping as they remap their messages again.
+    pub fn notify(&self) {
+        let listeners: Vec<Arc<dyn InterruptRemappingListener>> = self
+            .listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for listener in listeners {
+            listener.remapping_changed();
+        }
+    }
+}
+
+impl std::fmt::Debug for InterruptRemappingListeners {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InterruptRemappingListeners")
+            .field("count", &self.listeners.lock().unwrap().len())
+            .finish()
+    }
+}
+
+/// Positions of a queue, published by the thread processing it so that they
+/// can be read without synchronizing with it.
+#[derive(Default)]
+pub struct QueueCursors {
+    next_avail: AtomicU16,
+    next_used: AtomicU16,
+    // Used index the driver was last notified at, plus one so that 0 means
+    // the driver wasn't notified yet.
+    signalled_used: AtomicU32,
+}
+
+impl QueueCursors {
+    /// Publish the positions of the queue, once done processing a kick or a
+    /// completion.
+    pub fn update(&self, queue: &Queue) {
+        self.next_avail.store(queue.next_avail(), Ordering::Release);
+        self.next_used.store(queue.next_used(), Ordering::Release);
+    }
+
+    /// Record the driver being notified of the descriptors used so far.
+    pub fn signalled(&self, queue: &Queue) {
+        self.update(queue);
+        self.signalled_used
+            .store(u32::from(queue.next_used()) + 1, Ordering::Release);
+    }
+
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.load(Ordering::Acquire)
+    }
+
+    pub fn next_used(&self) -> u16 {
+        self.next_used.load(Ordering::Acquire)
+    }
+
+    pub fn signalled_used(&self) -> Option<u16> {
+        match self.signalled_used.load(Ordering::Acquire) {
+            0 => None,
+            used => Some((used - 1) as u16),
+        }
+    }
+}
+
 /// Structure to handle device state common to all devices
 #[derive(Default)]
 pub struct VirtioCommon {
@@ -205,6 +316,7 @@ pub struct VirtioCommon {
     pub device_type: u32,
     pub min_queues: u16,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
+    pub queue_cursors: Vec<(usize, Arc<QueueCursors>)>,
 }
 
 impl VirtioCommon {
@@ -255,9 +367,19 @@ impl VirtioCommon {
         // but clone it to pass into the thread.
         self.interrupt_cb = Some(interrupt_cb.clone());
 
+        self.queue_cursors.clear();
+
         Ok(())
     }
 
+    /// Create the cursors the thread processing the queue `index` publishes
+    /// its progress through.
+    pub fn queue_cursors(&mut self, index: usize) -> Arc<QueueCursors> {
+        let cursors = Arc::new(QueueCursors::default());
+        self.queue_cursors.push((index, cursors.clone()));
+        cursors
+    }
+
     pub fn reset(&mut self) -> Option<Arc<dyn VirtioInterrupt>> {
         // We first must resume the virtio thread if it was paused.
         if self.pause_evt.take().is_some() {
@@ -277,6 +399,8 @@ impl VirtioCommon {
             }
         }
 
+        self.queue_cursors.clear();
+
         // Return the interrupt
         Some(self.interrupt_cb.take().unwrap())
     }
@@ -8,8 +8,12 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
+#[cfg(feature = "io_uring")]
+use crate::event_ring::EventRing;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
@@ -19,6 +23,37 @@ use vmm_sys_util::eventfd::EventFd;
 pub struct EpollHelper {
     pause_evt: EventFd,
     epoll_file: File,
+    #[cfg(feature = "io_uring")]
+    event_ring: Option<EventRing>,
+}
+
+/// Mechanism a device thread waits for its events with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum EventLoop {
+    /// Wait with epoll_wait().
+    #[default]
+    Epoll,
+    /// Wait with io_uring polls, re-armed and waited for with a single
+    /// io_uring_enter() per wakeup. Falls back to epoll when io_uring isn't
+    /// available.
+    IoUring,
+}
+
+#[derive(Debug)]
+pub enum ParseEventLoopError {
+    InvalidValue(String),
+}
+
+impl FromStr for EventLoop {
+    type Err = ParseEventLoopError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "epoll" => Ok(EventLoop::Epoll),
+            "io_uring" => Ok(EventLoop::IoUring),
+            _ => Err(ParseEventLoopError::InvalidValue(s.to_owned())),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -83,6 +118,14 @@ impl EpollHelper {
     pub fn new(
         kill_evt: &EventFd,
         pause_evt: &EventFd,
+    ) -> std::result::Result<Self, EpollHelperError> {
+        Self::new_with_event_loop(kill_evt, pause_evt, EventLoop::Epoll)
+    }
+
+    pub fn new_with_event_loop(
+        kill_evt: &EventFd,
+        pause_evt: &EventFd,
+        event_loop: EventLoop,
     ) -> std::result::Result<Self, EpollHelperError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(EpollHelperError::CreateFd)?;
@@ -90,9 +133,27 @@ impl EpollHelper {
         // SAFETY: epoll_fd is a valid fd
         let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
 
+        #[cfg(feature = "io_uring")]
+        let event_ring = match event_loop {
+            EventLoop::Epoll => None,
+            EventLoop::IoUring => match EventRing::new(epoll_fd) {
+                Ok(event_ring) => Some(event_ring),
+                Err(e) => {
+                    warn!("Cannot use io_uring to wait for events, using epoll: {}", e);
+                    None
+                }
+            },
+        };
+        #[cfg(not(feature = "io_uring"))]
+        if event_loop == EventLoop::IoUring {
+            warn!("io_uring is disabled by crate features, using epoll to wait for events");
+        }
+
         let mut helper = Self {
             pause_evt: pause_evt.try_clone().unwrap(),
             epoll_file,
+            #[cfg(feature = "io_uring")]
+            event_ring,
         };
 
         helper.add_event(kill_evt.as_raw_fd(), EPOLL_HELPER_EVENT_KILL)?;
@@ -110,6 +171,11 @@ impl EpollHelper {
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(event_ring) = &mut self.event_ring {
+            return event_ring.add(fd, id, evts).map_err(EpollHelperError::Ctl);
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_ADD,
@@ -125,6 +191,13 @@ impl EpollHelper {
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(event_ring) = &mut self.event_ring {
+            return event_ring
+                .modify(fd, id, evts)
+                .map_err(EpollHelperError::Ctl);
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_MOD,
@@ -140,6 +213,11 @@ impl EpollHelper {
         id: u16,
         evts: epoll::Events,
     ) -> std::result::Result<(), EpollHelperError> {
+        #[cfg(feature = "io_uring")]
+        if let Some(event_ring) = &mut self.event_ring {
+            return event_ring.delete(fd).map_err(EpollHelperError::Ctl);
+        }
+
         epoll::ctl(
             self.epoll_file.as_raw_fd(),
             epoll::ControlOptions::EPOLL_CTL_DEL,
@@ -149,6 +227,15 @@ impl EpollHelper {
         .map_err(EpollHelperError::Ctl)
     }
 
+    fn wait(&mut self, timeout: i32, events: &mut [epoll::Event]) -> std::io::Result<usize> {
+        #[cfg(feature = "io_uring")]
+        if let Some(event_ring) = &mut self.event_ring {
+            return event_ring.wait(timeout, events);
+        }
+
+        epoll::wait(self.epoll_file.as_raw_fd(), timeout, events)
+    }
+
     pub fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -179,23 +266,22 @@ impl EpollHelper {
         }
 
         loop {
-            let num_events =
-                match epoll::wait(self.epoll_file.as_raw_fd(), timeout, &mut events[..]) {
-                    Ok(res) => res,
-                    Err(e) => {
-                        if e.kind() == std::io::ErrorKind::Interrupted {
-                            // It's well defined from the epoll_wait() syscall
-                            // documentation that the epoll loop can be interrupted
-                            // before any of the requested events occurred or the
-                            // timeout expired. In both those cases, epoll_wait()
-                            // returns an error of type EINTR, but this should not
-                            // be considered as a regular error. Instead it is more
-                            // appropriate to retry, by calling into epoll_wait().
-                            continue;
-                        }
-                        return Err(EpollHelperError::Wait(e));
+            let num_events = match self.wait(timeout, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == std::io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
                     }
-                };
+                    return Err(EpollHelperError::Wait(e));
+                }
+            };
 
             if num_events == 0 {
                 // This case happens when the timeout is reached before any of
@@ -258,7 +344,7 @@ impl EpollHelper {
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
         loop {
-            let num_events = match epoll::wait(self.epoll_file.as_raw_fd(), 0, &mut events[..]) {
+            let num_events = match self.wait(0, &mut events[..]) {
                 Ok(res) => res,
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::Interrupted {
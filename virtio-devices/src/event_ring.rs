@@ -0,0 +1,394 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! io_uring based wait for the events of a device thread.
+//!
+//! Each registered file descriptor is polled through a one shot
+//! IORING_OP_POLL_ADD, re-armed once its event was handled so that, like
+//! epoll, a file descriptor still ready is reported again. The polls to re-arm
+//! are submitted along with the wait for the next events, a wakeup of the
+//! device thread costing a single io_uring_enter() however many events it
+//! handles.
+//!
+//! The file descriptors registered directly on the epoll file descriptor of
+//! the EpollHelper, as the net queue pairs do for their TAP, are still waited
+//! for, through a poll of the epoll file descriptor itself.
+//!
+//! The wait is bounded by a plain timeout, only waking the device thread up.
+//! It expires at the deadline of the wait it was armed for, and is left
+//! pending when the wait returns with events, for the next wait to reuse it
+//! unless it expires later than its own deadline.
+
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+// Enough for the events of any device thread
+const EVENT_RING_SIZE: u32 = 64;
+
+// User data of the entries not polling a registered file descriptor
+const TIMEOUT_DATA: u64 = u64::MAX;
+const REMOVE_DATA: u64 = u64::MAX - 1;
+const EPOLL_DATA: u64 = u64::MAX - 2;
+
+struct Registration {
+    id: u16,
+    events: epoll::Events,
+    // Tells the completions of the current poll apart from the ones of the
+    // polls removed since, when the file descriptor is modified.
+    generation: u32,
+}
+
+pub struct EventRing {
+    io_uring: IoUring,
+    epoll_fd: RawFd,
+    fds: HashMap<RawFd, Registration>,
+    generation: u32,
+    // Polls completed, re-armed on the next wait
+    rearm: Vec<(RawFd, u32)>,
+    epoll_armed: bool,
+    timeout: types::Timespec,
+    // Expiry of the pending timeout
+    timeout_expiry: Option<Instant>,
+}
+
+fn user_data(fd: RawFd, generation: u32) -> u64 {
+    (u64::from(generation) << 32) | u64::from(fd as u32)
+}
+
+fn poll_entry(fd: RawFd, events: epoll::Events, user_data: u64) -> squeue::Entry {
+    opcode::PollAdd::new(types::Fd(fd), events.bits())
+        .build()
+        .user_data(user_data)
+}
+
+impl EventRing {
+    pub fn new(epoll_fd: RawFd) -> io::Result<Self> {
+        Ok(EventRing {
+            io_uring: IoUring::new(EVENT_RING_SIZE)?,
+            epoll_fd,
+            fds: HashMap::new(),
+            generation: 0,
+            rearm: Vec::new(),
+            epoll_armed: false,
+            timeout: types::Timespec::new(),
+            timeout_expiry: None,
+        })
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: the only entries pointing to memory are the timeouts,
+            // pointing to the timespec of the ring, which the kernel reads
+            // when they are submitted, before the ring is dropped.
+            if unsafe { self.io_uring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+            // The submission queue is full
+            self.io_uring.submit()?;
+        }
+    }
+
+    // Make sure a timeout expires by the deadline, replacing the pending one
+    // if it expires later.
+    fn arm_timeout(&mut self, deadline: Instant) -> io::Result<()> {
+        if self
+            .timeout_expiry
+            .map_or(false, |expiry| expiry <= deadline)
+        {
+            return Ok(());
+        }
+
+        if self.timeout_expiry.is_some() {
+            // Removing a timeout which expired already fails, which is
+            // harmless
+            self.push(
+                opcode::TimeoutRemove::new(TIMEOUT_DATA)
+                    .build()
+                    .user_data(REMOVE_DATA),
+            )?;
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        self.timeout = types::Timespec::new()
+            .sec(remaining.as_secs())
+            .nsec(remaining.subsec_nanos());
+        // A count of 0 only completes the timeout once it expired, however
+        // many other entries completed.
+        let entry = opcode::Timeout::new(&self.timeout)
+            .count(0)
+            .build()
+            .user_data(TIMEOUT_DATA);
+        self.push(entry)?;
+        self.timeout_expiry = Some(deadline);
+
+        Ok(())
+    }
+
+    fn arm(&mut self, fd: RawFd, id: u16, events: epoll::Events) -> io::Result<()> {
+        self.generation = self.generation.wrapping_add(1);
+        let generation = self.generation;
+        self.fds.insert(
+            fd,
+            Registration {
+                id,
+                events,
+                generation,
+            },
+        );
+        self.push(poll_entry(fd, events, user_data(fd, generation)))
+    }
+
+    fn disarm(&mut self, fd: RawFd) -> io::Result<()> {
+        let registration = self
+            .fds
+            .remove(&fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        // Removing a poll which completed already fails, which is harmless
+        self.push(
+            opcode::PollRemove::new(user_data(fd, registration.generation))
+                .build()
+                .user_data(REMOVE_DATA),
+        )
+    }
+
+    pub fn add(&mut self, fd: RawFd, id: u16, events: epoll::Events) -> io::Result<()> {
+        if self.fds.contains_key(&fd) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST));
+        }
+        self.arm(fd, id, events)
+    }
+
+    pub fn modify(&mut self, fd: RawFd, id: u16, events: epoll::Events) -> io::Result<()> {
+        self.disarm(fd)?;
+        self.arm(fd, id, events)
+    }
+
+    pub fn delete(&mut self, fd: RawFd) -> io::Result<()> {
+        self.disarm(fd)
+    }
+
+    /// Wait for the events of the registered file descriptors, for at most
+    /// `timeout` milliseconds unless negative, returning the number of events
+    /// written to `events`.
+    pub fn wait(&mut self, timeout: i32, events: &mut [epoll::Event]) -> io::Result<usize> {
+        let deadline = u64::try_from(timeout)
+            .ok()
+            .map(|timeout| Instant::now() + Duration::from_millis(timeout));
+
+        for (fd, generation) in std::mem::take(&mut self.rearm) {
+            if let Some(registration) = self.fds.get(&fd) {
+                if registration.generation == generation {
+                    let entry = poll_entry(fd, registration.events, user_data(fd, generation));
+                    self.push(entry)?;
+                }
+            }
+        }
+
+        loop {
+            if !self.epoll_armed {
+                self.push(poll_entry(
+                    self.epoll_fd,
+                    epoll::Events::EPOLLIN,
+                    EPOLL_DATA,
+                ))?;
+                self.epoll_armed = true;
+            }
+
+            if let Some(deadline) = deadline {
+                self.arm_timeout(deadline)?;
+            }
+
+            self.io_uring.submit_and_wait(1)?;
+
+            let mut count = 0;
+            let mut epoll_ready = false;
+            // Completions left over when the events are full are reaped on
+            // the next wait, which then doesn't block.
+            let mut completion = self.io_uring.completion();
+            while count < events.len() {
+                let Some(entry) = completion.next() else {
+                    break;
+                };
+
+                match entry.user_data() {
+                    // The removed timeouts complete with -ECANCELED
+                    TIMEOUT_DATA if entry.result() == -libc::ETIME => {
+                        self.timeout_expiry = None;
+                    }
+                    TIMEOUT_DATA => {}
+                    REMOVE_DATA => {}
+                    EPOLL_DATA => {
+                        self.epoll_armed = false;
+                        epoll_ready = entry.result() >= 0;
+                    }
+                    data => {
+                        let fd = data as u32 as RawFd;
+                        let generation = (data >> 32) as u32;
+                        // Ignore the polls removed since
+                        let Some(registration) = self
+                            .fds
+                            .get(&fd)
+                            .filter(|registration| registration.generation == generation)
+                        else {
+                            continue;
+                        };
+
+                        if entry.result() < 0 {
+                            return Err(io::Error::from_raw_os_error(-entry.result()));
+                        }
+
+                        events[count] = epoll::Event::new(
+                            epoll::Events::from_bits_truncate(entry.result() as u32),
+                            registration.id.into(),
+                        );
+                        count += 1;
+                        self.rearm.push((fd, generation));
+                    }
+                }
+            }
+            drop(completion);
+
+            if epoll_ready && count < events.len() {
+                count += epoll::wait(self.epoll_fd, 0, &mut events[count..])?;
+            }
+
+            if count > 0 || deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::eventfd::EventFd;
+
+    // io_uring may not be available to the tests
+    fn event_ring() -> Option<(EventRing, RawFd)> {
+        let epoll_fd = epoll::create(true).unwrap();
+        match EventRing::new(epoll_fd) {
+            Ok(event_ring) => Some((event_ring, epoll_fd)),
+            Err(e) => {
+                // SAFETY: the epoll file descriptor was created above
+                unsafe { libc::close(epoll_fd) };
+                eprintln!("Skipping the test, io_uring isn't available: {e}");
+                None
+            }
+        }
+    }
+
+    fn wait(event_ring: &mut EventRing, timeout: i32) -> Vec<u64> {
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 8];
+        let count = event_ring.wait(timeout, &mut events).unwrap();
+        events[..count].iter().map(|event| event.data).collect()
+    }
+
+    #[test]
+    fn test_event_ring_ready() {
+        let Some((mut event_ring, epoll_fd)) = event_ring() else {
+            return;
+        };
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        event_ring
+            .add(evt.as_raw_fd(), 1, epoll::Events::EPOLLIN)
+            .unwrap();
+        assert!(matches!(
+            event_ring.add(evt.as_raw_fd(), 1, epoll::Events::EPOLLIN),
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST)
+        ));
+        assert!(wait(&mut event_ring, 10).is_empty());
+
+        evt.write(1).unwrap();
+        assert_eq!(wait(&mut event_ring, -1), vec![1]);
+        // Still ready, so reported again once re-armed
+        assert_eq!(wait(&mut event_ring, -1), vec![1]);
+        evt.read().unwrap();
+        assert!(wait(&mut event_ring, 10).is_empty());
+
+        // SAFETY: the epoll file descriptor was created by the test
+        unsafe { libc::close(epoll_fd) };
+    }
+
+    #[test]
+    fn test_event_ring_modify_delete() {
+        let Some((mut event_ring, epoll_fd)) = event_ring() else {
+            return;
+        };
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        event_ring
+            .add(evt.as_raw_fd(), 1, epoll::Events::EPOLLIN)
+            .unwrap();
+        event_ring
+            .modify(evt.as_raw_fd(), 2, epoll::Events::EPOLLIN)
+            .unwrap();
+        evt.write(1).unwrap();
+        // Only the current poll is reported
+        assert_eq!(wait(&mut event_ring, -1), vec![2]);
+
+        event_ring.delete(evt.as_raw_fd()).unwrap();
+        assert!(wait(&mut event_ring, 10).is_empty());
+        assert!(matches!(
+            event_ring.delete(evt.as_raw_fd()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT)
+        ));
+
+        // The file descriptors registered on the epoll file descriptor
+        let other_evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            other_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, 3),
+        )
+        .unwrap();
+        other_evt.write(1).unwrap();
+        assert_eq!(wait(&mut event_ring, -1), vec![3]);
+
+        // SAFETY: the epoll file descriptor was created by the test
+        unsafe { libc::close(epoll_fd) };
+    }
+
+    #[test]
+    fn test_event_ring_timeout() {
+        let Some((mut event_ring, epoll_fd)) = event_ring() else {
+            return;
+        };
+        let evt = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+
+        let start = Instant::now();
+        assert!(wait(&mut event_ring, 50).is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The timeout of a wait returning early doesn't delay the next one
+        event_ring
+            .add(evt.as_raw_fd(), 1, epoll::Events::EPOLLIN)
+            .unwrap();
+        evt.write(1).unwrap();
+        assert_eq!(wait(&mut event_ring, 10_000), vec![1]);
+        evt.read().unwrap();
+        let start = Instant::now();
+        assert!(wait(&mut event_ring, 50).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Nor do the other completions, such as the removed polls, push the
+        // timeout back
+        event_ring.delete(evt.as_raw_fd()).unwrap();
+        let start = Instant::now();
+        assert!(wait(&mut event_ring, 100).is_empty());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(5));
+
+        // SAFETY: the epoll file descriptor was created by the test
+        unsafe { libc::close(epoll_fd) };
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Latency histograms of the requests processed by the devices.
+//!
+//! The latencies are counted in a fixed set of buckets, cheap enough to be
+//! updated from the device threads, and reported with the counters of the
+//! device. Following the Prometheus histograms, each bucket counts all the
+//! latencies up to its bound, along with the sum and the count of all of them.
+
+use std::collections::HashMap;
+use std::num::Wrapping;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Upper bounds of the buckets, in microseconds, the last bucket counting all
+/// the latencies above them.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 100000,
+];
+const NUM_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+/// Names of the counters of a histogram.
+pub struct HistogramNames {
+    pub buckets: [&'static str; NUM_BUCKETS],
+    pub sum: &'static str,
+    pub count: &'static str,
+}
+
+/// Create a latency histogram whose counters are named after the given
+/// prefix, as `<prefix>_bucket_le_<bound>`, `<prefix>_sum` and
+/// `<prefix>_count`.
+macro_rules! latency_histogram {
+    ($prefix:literal) => {{
+        static NAMES: $crate::histogram::HistogramNames = $crate::histogram::HistogramNames {
+            buckets: [
+                concat!($prefix, "_bucket_le_10"),
+                concat!($prefix, "_bucket_le_25"),
+                concat!($prefix, "_bucket_le_50"),
+                concat!($prefix, "_bucket_le_100"),
+                concat!($prefix, "_bucket_le_250"),
+                concat!($prefix, "_bucket_le_500"),
+                concat!($prefix, "_bucket_le_1000"),
+                concat!($prefix, "_bucket_le_2500"),
+                concat!($prefix, "_bucket_le_5000"),
+                concat!($prefix, "_bucket_le_10000"),
+                concat!($prefix, "_bucket_le_25000"),
+                concat!($prefix, "_bucket_le_100000"),
+                concat!($prefix, "_bucket_le_inf"),
+            ],
+            sum: concat!($prefix, "_sum"),
+            count: concat!($prefix, "_count"),
+        };
+        $crate::histogram::LatencyHistogram::new(&NAMES)
+    }};
+}
+
+/// Histogram shared between a device and its handlers.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    names: &'static HistogramNames,
+    buckets: Arc<[AtomicU64; NUM_BUCKETS]>,
+    sum: Arc<AtomicU64>,
+}
+
+impl LatencyHistogram {
+    pub fn new(names: &'static HistogramNames) -> Self {
+        LatencyHistogram {
+            names,
+            buckets: Arc::new(Default::default()),
+            sum: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Count a latency, in microseconds.
+    pub fn record(&self, latency_us: u64) {
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| latency_us <= *bound)
+            .unwrap_or(NUM_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(latency_us, Ordering::Relaxed);
+    }
+
+    /// Add the counters of the histogram to the ones of the device.
+    pub fn add_counters(&self, counters: &mut HashMap<&'static str, Wrapping<u64>>) {
+        let mut count = 0;
+        for (bucket, name) in self.buckets.iter().zip(self.names.buckets) {
+            count += bucket.load(Ordering::Acquire);
+            counters.insert(name, Wrapping(count));
+        }
+        counters.insert(self.names.count, Wrapping(count));
+        counters.insert(self.names.sum, Wrapping(self.sum.load(Ordering::Acquire)));
+    }
+}
+
+/// Time spent on each pass over the RX and TX queues of a device, in
+/// microseconds.
+#[derive(Clone)]
+pub struct QueueLatencies {
+    pub rx: LatencyHistogram,
+    pub tx: LatencyHistogram,
+}
+
+impl Default for QueueLatencies {
+    fn default() -> Self {
+        QueueLatencies {
+            rx: latency_histogram!("rx_latency_us"),
+            tx: latency_histogram!("tx_latency_us"),
+        }
+    }
+}
+
+impl QueueLatencies {
+    pub fn add_counters(&self, counters: &mut HashMap<&'static str, Wrapping<u64>>) {
+        self.rx.add_counters(counters);
+        self.tx.add_counters(counters);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = latency_histogram!("read_latency_us");
+        for (name, bound) in histogram.names.buckets.iter().zip(LATENCY_BUCKETS_US) {
+            assert_eq!(*name, format!("read_latency_us_bucket_le_{bound}"));
+        }
+
+        for latency in [3, 10, 11, 700, 200000] {
+            histogram.record(latency);
+        }
+
+        let mut counters = HashMap::new();
+        histogram.add_counters(&mut counters);
+        assert_eq!(counters["read_latency_us_bucket_le_10"].0, 2);
+        assert_eq!(counters["read_latency_us_bucket_le_25"].0, 3);
+        assert_eq!(counters["read_latency_us_bucket_le_500"].0, 3);
+        assert_eq!(counters["read_latency_us_bucket_le_1000"].0, 4);
+        assert_eq!(counters["read_latency_us_bucket_le_100000"].0, 4);
+        assert_eq!(counters["read_latency_us_bucket_le_inf"].0, 5);
+        assert_eq!(counters["read_latency_us_count"].0, 5);
+        assert_eq!(counters["read_latency_us_sum"].0, 200724);
+    }
+}
@@ -4,13 +4,16 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon, VirtioDevice,
-    VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::GuestMemoryMmap;
-use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
+use crate::{
+    DmaRemapping, InterruptRemapping, InterruptRemappingListener, InterruptRemappingListeners,
+    VirtioInterrupt, VirtioInterruptType,
+};
 use anyhow::anyhow;
 use seccompiler::SeccompAction;
 use std::collections::BTreeMap;
@@ -20,7 +23,7 @@ use std::ops::Bound::Included;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier, Mutex, RwLock};
+use std::sync::{Arc, Barrier, Mutex, RwLock, Weak};
 use thiserror::Error;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -609,6 +612,20 @@ impl Request {
             Ok(())
         })();
 
+        // The MSIs remapped through the domains must be remapped again once
+        // the domains changed.
+        if result.is_ok()
+            && matches!(
+                req_head.type_,
+                VIRTIO_IOMMU_T_ATTACH
+                    | VIRTIO_IOMMU_T_DETACH
+                    | VIRTIO_IOMMU_T_MAP
+                    | VIRTIO_IOMMU_T_UNMAP
+            )
+        {
+            mapping.listeners.notify();
+        }
+
         let status_desc = desc_chain.next().ok_or(Error::DescriptorChainTooShort)?;
 
         // The status MUST always be writable
@@ -643,6 +660,7 @@ impl Request {
 struct IommuEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     request_queue: Queue,
+    request_queue_cursors: Arc<QueueCursors>,
     _event_queue: Queue,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     request_queue_evt: EventFd,
@@ -677,6 +695,9 @@ impl IommuEpollHandler {
     }
 
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        if queue_index == 0 {
+            self.request_queue_cursors.signalled(&self.request_queue);
+        }
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(queue_index))
             .map_err(|e| {
@@ -733,6 +754,7 @@ impl EpollHelperHandler for IommuEpollHandler {
                 )));
             }
         }
+        self.request_queue_cursors.update(&self.request_queue);
         Ok(())
     }
 }
@@ -758,6 +780,10 @@ pub struct IommuMapping {
     // Global flag indicating if endpoints that are not attached to any domain
     // are in bypass mode.
     bypass: AtomicBool,
+    // Range of the interrupt controller doorbells, reserved for MSIs.
+    msi_iova_space: (u64, u64),
+    // Interrupt groups whose MSIs are remapped through the domains.
+    listeners: InterruptRemappingListeners,
 }
 
 impl DmaRemapping for IommuMapping {
@@ -826,6 +852,48 @@ impl DmaRemapping for IommuMapping {
     }
 }
 
+impl InterruptRemapping for IommuMapping {
+    fn remap_msi(
+        &self,
+        id: u32,
+        addr: u64,
+        data: u32,
+    ) -> std::result::Result<(u64, u32), std::io::Error> {
+        debug!("Remap MSI addr 0x{:x}", addr);
+        let (msi_start, msi_end) = self.msi_iova_space;
+        // The MSI reserved region is identity mapped for all endpoints, while
+        // any other address must be translated through the domain of the
+        // endpoint, the message being dropped if it doesn't reach a doorbell
+        // once translated.
+        let new_addr = if (msi_start..=msi_end).contains(&addr) {
+            if self.endpoints.read().unwrap().get(&id).is_some()
+                || self.bypass.load(Ordering::Acquire)
+            {
+                Some(addr)
+            } else {
+                None
+            }
+        } else {
+            self.translate_gva(id, addr).ok()
+        };
+
+        match new_addr {
+            Some(new_addr) if (msi_start..=msi_end).contains(&new_addr) => {
+                debug!("Into doorbell addr 0x{:x}", new_addr);
+                Ok((new_addr, data))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to remap MSI addr 0x{addr:x}"),
+            )),
+        }
+    }
+
+    fn add_listener(&self, listener: Weak<dyn InterruptRemappingListener>) {
+        self.listeners.add(listener);
+    }
+}
+
 #[derive(Debug)]
 pub struct AccessPlatformMapping {
     id: u32,
@@ -920,6 +988,8 @@ impl Iommu {
             endpoints: Arc::new(RwLock::new(endpoints)),
             domains: Arc::new(RwLock::new(domains)),
             bypass: AtomicBool::new(true),
+            msi_iova_space,
+            listeners: InterruptRemappingListeners::default(),
         });
 
         Ok((
@@ -981,7 +1051,9 @@ impl Iommu {
 
         let bypass = self.config.bypass == 1;
         info!("Updating bypass mode to {}", bypass);
-        self.mapping.bypass.store(bypass, Ordering::Release);
+        if self.mapping.bypass.swap(bypass, Ordering::AcqRel) != bypass {
+            self.mapping.listeners.notify();
+        }
     }
 
     pub fn add_external_mapping(&mut self, device_id: u32, mapping: Arc<dyn ExternalDmaMapping>) {
@@ -1052,12 +1124,13 @@ impl VirtioDevice for Iommu {
         self.common.activate(&queues, &interrupt_cb)?;
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
-        let (_, request_queue, request_queue_evt) = queues.remove(0);
+        let (request_queue_index, request_queue, request_queue_evt) = queues.remove(0);
         let (_, _event_queue, _event_queue_evt) = queues.remove(0);
 
         let mut handler = IommuEpollHandler {
             mem,
             request_queue,
+            request_queue_cursors: self.common.queue_cursors(request_queue_index),
             _event_queue,
             interrupt_cb,
             request_queue_evt,
@@ -1092,6 +1165,10 @@ impl VirtioDevice for Iommu {
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
+
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
 }
 
 impl Pausable for Iommu {
@@ -22,10 +22,15 @@ use thiserror::Error;
 
 #[macro_use]
 mod device;
+#[macro_use]
+mod histogram;
 pub mod balloon;
 pub mod block;
+mod coalescing;
 mod console;
 pub mod epoll_helper;
+#[cfg(feature = "io_uring")]
+mod event_ring;
 mod iommu;
 pub mod mem;
 pub mod net;
@@ -41,22 +46,33 @@ pub mod watchdog;
 
 pub use self::balloon::Balloon;
 pub use self::block::{Block, BlockState};
-pub use self::console::{Console, ConsoleResizer, Endpoint};
+pub use self::coalescing::NotificationCoalescer;
+pub use self::console::{
+    Console, ConsolePort, ConsolePortEndpoint, ConsolePortError, ConsoleResizer, Endpoint,
+    VIRTIO_CONSOLE_MAX_PORTS,
+};
 pub use self::device::{
-    DmaRemapping, UserspaceMapping, VirtioCommon, VirtioDevice, VirtioInterrupt,
-    VirtioInterruptType, VirtioSharedMemoryList,
+    DmaRemapping, InterruptRemapping, InterruptRemappingListener, InterruptRemappingListeners,
+    QueueCursors, UserspaceMapping, VirtioCommon, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType, VirtioSharedMemory, VirtioSharedMemoryList,
 };
 pub use self::epoll_helper::{
-    EpollHelper, EpollHelperError, EpollHelperHandler, EPOLL_HELPER_EVENT_LAST,
+    EpollHelper, EpollHelperError, EpollHelperHandler, EventLoop, ParseEventLoopError,
+    EPOLL_HELPER_EVENT_LAST,
 };
 pub use self::iommu::{AccessPlatformMapping, Iommu, IommuMapping};
-pub use self::mem::{BlocksState, Mem, VirtioMemMappingSource, VIRTIO_MEM_ALIGN_SIZE};
+pub use self::mem::{
+    BlocksState, Mem, VirtioMemMappingSource, VirtioMemResizeInfo, VirtioMemResizeStatus,
+    VIRTIO_MEM_ALIGN_SIZE, VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
+};
 pub use self::net::{Net, NetCtrlEpollHandler};
 pub use self::pmem::Pmem;
 pub use self::rng::Rng;
 pub use self::vdpa::{Vdpa, VdpaDmaMapping};
 pub use self::vsock::Vsock;
-pub use self::watchdog::Watchdog;
+pub use self::watchdog::{
+    ParseWatchdogActionError, Watchdog, WatchdogAction, WatchdogCounters, WatchdogEscalation,
+};
 use vm_memory::{bitmap::AtomicBitmap, GuestAddress, GuestMemory};
 use vm_virtio::VirtioDeviceType;
 
@@ -97,6 +113,8 @@ pub enum ActivateError {
     CreateSeccompFilter(seccompiler::Error),
     #[error("Failed to create rate limiter: {0}")]
     CreateRateLimiter(std::io::Error),
+    #[error("Failed to create the notification coalescer: {0}")]
+    CreateNotificationCoalescer(std::io::Error),
     #[error("Failed to activate the vDPA device: {0}")]
     ActivateVdpa(vdpa::Error),
 }
@@ -139,6 +157,57 @@ pub struct RateLimiterConfig {
     pub ops: Option<TokenBucketConfig>,
 }
 
+/// Identifiers exposed by a virtio-pci device in place of the default ones.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PciIdsConfig {
+    pub subsystem_vendor_id: Option<u16>,
+    pub subsystem_id: Option<u16>,
+    pub revision_id: Option<u8>,
+}
+
+/// Default longest delay of a coalesced notification, in microseconds.
+pub const DEFAULT_COALESCE_TIMEOUT: u64 = 100;
+
+fn default_notificationconfig_event_idx() -> bool {
+    true
+}
+
+fn default_notificationconfig_coalesce_buffers() -> u16 {
+    1
+}
+
+fn default_notificationconfig_coalesce_timeout() -> u64 {
+    DEFAULT_COALESCE_TIMEOUT
+}
+
+/// How a virtio device notifies the driver about the buffers it used.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Offer VIRTIO_RING_F_EVENT_IDX, for the driver to suppress the
+    /// notifications it doesn't need.
+    #[serde(default = "default_notificationconfig_event_idx")]
+    pub event_idx: bool,
+    /// Buffers used before the driver gets notified, 1 notifying it right
+    /// away.
+    #[serde(default = "default_notificationconfig_coalesce_buffers")]
+    pub coalesce_buffers: u16,
+    /// Longest delay of a notification held back, in microseconds.
+    #[serde(default = "default_notificationconfig_coalesce_timeout")]
+    pub coalesce_timeout: u64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            event_idx: default_notificationconfig_event_idx(),
+            coalesce_buffers: default_notificationconfig_coalesce_buffers(),
+            coalesce_timeout: default_notificationconfig_coalesce_timeout(),
+        }
+    }
+}
+
 impl TryInto<rate_limiter::RateLimiter> for RateLimiterConfig {
     type Error = io::Error;
 
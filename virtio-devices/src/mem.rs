@@ -14,8 +14,8 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
-    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors,
+    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -23,6 +23,7 @@ use crate::{GuestMemoryMmap, GuestRegionMmap};
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccompiler::SeccompAction;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::io;
 use std::mem::size_of;
@@ -52,8 +53,9 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 // 128MiB is the standard memory block size in Linux. A virtio-mem region must
 // be aligned on this size, and the region size must be a multiple of it.
 pub const VIRTIO_MEM_ALIGN_SIZE: u64 = 128 << 20;
-// Use 2 MiB alignment so transparent hugepages can be used by KVM.
-const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 2 << 20;
+// Use 2 MiB alignment so transparent hugepages can be used by KVM. Regions
+// backed by bigger hugepages use blocks of the size of a hugepage.
+pub const VIRTIO_MEM_DEFAULT_BLOCK_SIZE: u64 = 2 << 20;
 
 // Request processed successfully, applicable for
 // - VIRTIO_MEM_REQ_PLUG
@@ -129,6 +131,8 @@ pub enum Error {
     ValidateError(anyhow::Error),
     #[error("Failed discarding memory range: {0}")]
     DiscardMemoryRange(std::io::Error),
+    #[error("Failed allocating memory range: {0}")]
+    AllocateMemoryRange(std::io::Error),
     #[error("Failed DMA mapping: {0}")]
     DmaMap(std::io::Error),
     #[error("Failed DMA unmapping: {0}")]
@@ -193,6 +197,51 @@ pub struct VirtioMemConfig {
 // SAFETY: it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioMemConfig {}
 
+/// Progress of the last resize of a virtio-mem device, the guest plugging or
+/// unplugging the memory blocks at its own pace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VirtioMemResizeStatus {
+    InProgress,
+    Completed,
+    /// The guest was refused some memory blocks, it may still get them on a
+    /// later attempt.
+    Failed,
+}
+
+impl VirtioMemResizeStatus {
+    fn from_config(config: &VirtioMemConfig) -> Self {
+        if config.plugged_size == config.requested_size {
+            VirtioMemResizeStatus::Completed
+        } else {
+            VirtioMemResizeStatus::InProgress
+        }
+    }
+
+    // Status once the guest handled a request changing the plugged size,
+    // `refused` telling whether the host failed plugging or unplugging the
+    // blocks. The guest may unplug memory again after completing a resize,
+    // for instance when its driver is reloaded.
+    fn update(self, config: &VirtioMemConfig, acked: bool, refused: bool) -> Self {
+        if config.plugged_size == config.requested_size {
+            VirtioMemResizeStatus::Completed
+        } else if refused {
+            VirtioMemResizeStatus::Failed
+        } else if acked || self == VirtioMemResizeStatus::Completed {
+            VirtioMemResizeStatus::InProgress
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtioMemResizeInfo {
+    pub requested_size: u64,
+    pub plugged_size: u64,
+    pub status: VirtioMemResizeStatus,
+}
+
 impl VirtioMemConfig {
     fn validate(&self) -> result::Result<(), Error> {
         if self.addr % self.block_size != 0 {
@@ -339,12 +388,14 @@ impl Request {
 #[derive(Clone, Versionize)]
 pub struct BlocksState {
     bitmap: Vec<bool>,
+    block_size: u64,
 }
 
 impl BlocksState {
-    pub fn new(region_size: u64) -> Self {
+    pub fn new(region_size: u64, block_size: u64) -> Self {
         BlocksState {
-            bitmap: vec![false; (region_size / VIRTIO_MEM_DEFAULT_BLOCK_SIZE) as usize],
+            bitmap: vec![false; (region_size / block_size) as usize],
+            block_size,
         }
     }
 
@@ -394,17 +445,21 @@ impl BlocksState {
             }
         }
 
-        MemoryRangeTable::from_bitmap(bitmap, start_addr, VIRTIO_MEM_DEFAULT_BLOCK_SIZE)
+        MemoryRangeTable::from_bitmap(bitmap, start_addr, self.block_size)
     }
 }
 
 struct MemEpollHandler {
+    id: String,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     host_addr: u64,
     host_fd: Option<RawFd>,
+    host_file_offset: u64,
     blocks_state: Arc<Mutex<BlocksState>>,
     config: Arc<Mutex<VirtioMemConfig>>,
+    resize_status: Arc<Mutex<VirtioMemResizeStatus>>,
     queue: Queue,
+    queue_cursors: Arc<QueueCursors>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -414,6 +469,31 @@ struct MemEpollHandler {
 }
 
 impl MemEpollHandler {
+    // Allocate the hugepages backing a memory range being plugged, as the
+    // region is mapped without reserving them. This lets the request fail
+    // when the host runs out of hugepages, instead of the guest getting a
+    // SIGBUS when accessing the memory.
+    fn allocate_memory_range(&self, offset: u64, size: u64) -> Result<(), Error> {
+        if let (Some(fd), true) = (self.host_fd, self.hugepages) {
+            // SAFETY: FFI call with valid arguments
+            let res = unsafe {
+                libc::fallocate64(
+                    fd,
+                    0,
+                    (self.host_file_offset + offset) as libc::off64_t,
+                    size as libc::off64_t,
+                )
+            };
+            if res != 0 {
+                let err = io::Error::last_os_error();
+                error!("Allocating file space failed: {}", err);
+                return Err(Error::AllocateMemoryRange(err));
+            }
+        }
+
+        Ok(())
+    }
+
     fn discard_memory_range(&self, offset: u64, size: u64) -> Result<(), Error> {
         // Use fallocate if the memory region is backed by a file.
         if let Some(fd) = self.host_fd {
@@ -422,7 +502,7 @@ impl MemEpollHandler {
                 libc::fallocate64(
                     fd,
                     libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
-                    offset as libc::off64_t,
+                    (self.host_file_offset + offset) as libc::off64_t,
                     size as libc::off64_t,
                 )
             };
@@ -477,11 +557,14 @@ impl MemEpollHandler {
             return VIRTIO_MEM_RESP_ERROR;
         }
 
-        if !plug {
-            if let Err(e) = self.discard_memory_range(offset, size) {
-                error!("failed discarding memory range: {:?}", e);
-                return VIRTIO_MEM_RESP_ERROR;
+        if plug {
+            if let Err(e) = self.allocate_memory_range(offset, size) {
+                error!("failed allocating memory range: {:?}", e);
+                return VIRTIO_MEM_RESP_NACK;
             }
+        } else if let Err(e) = self.discard_memory_range(offset, size) {
+            error!("failed discarding memory range: {:?}", e);
+            return VIRTIO_MEM_RESP_ERROR;
         }
 
         self.blocks_state
@@ -594,7 +677,45 @@ impl MemEpollHandler {
         (resp_type, resp_state)
     }
 
+    // Report the completion, or the failure, of the resize once the guest
+    // handled a request changing the plugged size.
+    fn update_resize_status(&self, req_type: u16, nb_blocks: u16, resp_type: u16) {
+        let config = self.config.lock().unwrap();
+        let mut status = self.resize_status.lock().unwrap();
+
+        // A plug request beyond the requested size is the guest racing with a
+        // resize shrinking it, not a failure of the host.
+        let exceeds_request = req_type == VIRTIO_MEM_REQ_PLUG
+            && config.plugged_size + nb_blocks as u64 * config.block_size > config.requested_size;
+        let acked = resp_type == VIRTIO_MEM_RESP_ACK;
+        let new_status = status.update(&config, acked, !acked && !exceeds_request);
+        if new_status == *status {
+            return;
+        }
+        *status = new_status;
+
+        let event = match new_status {
+            VirtioMemResizeStatus::Completed => "resize-completed",
+            VirtioMemResizeStatus::Failed => "resize-failed",
+            VirtioMemResizeStatus::InProgress => return,
+        };
+
+        event!(
+            "virtio-mem",
+            event,
+            "id",
+            &self.id,
+            "requested_size",
+            config.requested_size.to_string(),
+            "plugged_size",
+            config.plugged_size.to_string()
+        );
+    }
+
     fn signal(&self, int_type: VirtioInterruptType) -> result::Result<(), DeviceError> {
+        if let VirtioInterruptType::Queue(_) = int_type {
+            self.queue_cursors.signalled(&self.queue);
+        }
         self.interrupt_cb.trigger(int_type).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
             DeviceError::FailedSignalingUsedQueue(e)
@@ -621,6 +742,9 @@ impl MemEpollHandler {
                     return Err(Error::UnknownRequestType(r.req.req_type));
                 }
             };
+            if r.req.req_type != VIRTIO_MEM_REQ_STATE {
+                self.update_resize_status(r.req.req_type, r.req.nb_blocks, resp_type);
+            }
             let len = r.send_response(desc_chain.memory(), resp_type, resp_state)?;
             self.queue
                 .add_used(desc_chain.memory(), desc_chain.head_index(), len)
@@ -676,6 +800,7 @@ impl EpollHelperHandler for MemEpollHandler {
                 )));
             }
         }
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
@@ -701,7 +826,9 @@ pub struct Mem {
     id: String,
     host_addr: u64,
     host_fd: Option<RawFd>,
+    host_file_offset: u64,
     config: Arc<Mutex<VirtioMemConfig>>,
+    resize_status: Arc<Mutex<VirtioMemResizeStatus>>,
     seccomp_action: SeccompAction,
     hugepages: bool,
     dma_mapping_handlers: Arc<Mutex<BTreeMap<VirtioMemMappingSource, Arc<dyn ExternalDmaMapping>>>>,
@@ -720,6 +847,7 @@ impl Mem {
         numa_node_id: Option<u16>,
         initial_size: u64,
         hugepages: bool,
+        block_size: u64,
         exit_evt: EventFd,
         blocks_state: Arc<Mutex<BlocksState>>,
         state: Option<MemState>,
@@ -746,7 +874,7 @@ impl Mem {
             let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
 
             let mut config = VirtioMemConfig {
-                block_size: VIRTIO_MEM_DEFAULT_BLOCK_SIZE,
+                block_size,
                 addr: region.start_addr().raw_value(),
                 region_size: region.len(),
                 usable_region_size: region.len(),
@@ -786,6 +914,7 @@ impl Mem {
         let host_fd = region
             .file_offset()
             .map(|f_offset| f_offset.file().as_raw_fd());
+        let host_file_offset = region.file_offset().map_or(0, |f_offset| f_offset.start());
 
         Ok(Mem {
             common: VirtioCommon {
@@ -801,6 +930,8 @@ impl Mem {
             id,
             host_addr: region.as_ptr() as u64,
             host_fd,
+            host_file_offset,
+            resize_status: Arc::new(Mutex::new(VirtioMemResizeStatus::from_config(&config))),
             config: Arc::new(Mutex::new(config)),
             seccomp_action,
             hugepages,
@@ -817,6 +948,22 @@ impl Mem {
             Error::ResizeError(anyhow!("Failed to update virtio configuration: {:?}", e))
         })?;
 
+        // The resize completes once the guest plugged or unplugged the blocks
+        let status = VirtioMemResizeStatus::from_config(&config);
+        *self.resize_status.lock().unwrap() = status;
+        if status == VirtioMemResizeStatus::Completed {
+            event!(
+                "virtio-mem",
+                "resize-completed",
+                "id",
+                &self.id,
+                "requested_size",
+                config.requested_size.to_string(),
+                "plugged_size",
+                config.plugged_size.to_string()
+            );
+        }
+
         if let Some(interrupt_cb) = self.interrupt_cb.as_ref() {
             interrupt_cb
                 .trigger(VirtioInterruptType::Config)
@@ -881,6 +1028,15 @@ impl Mem {
         Ok(())
     }
 
+    pub fn resize_info(&self) -> VirtioMemResizeInfo {
+        let config = self.config.lock().unwrap();
+        VirtioMemResizeInfo {
+            requested_size: config.requested_size,
+            plugged_size: config.plugged_size,
+            status: *self.resize_status.lock().unwrap(),
+        }
+    }
+
     fn state(&self) -> MemState {
         MemState {
             avail_features: self.common.avail_features,
@@ -936,17 +1092,21 @@ impl VirtioDevice for Mem {
         self.common.activate(&queues, &interrupt_cb)?;
         let (kill_evt, pause_evt) = self.common.dup_eventfds();
 
-        let (_, queue, queue_evt) = queues.remove(0);
+        let (index, queue, queue_evt) = queues.remove(0);
 
         self.interrupt_cb = Some(interrupt_cb.clone());
 
         let mut handler = MemEpollHandler {
+            id: self.id.clone(),
             mem,
             host_addr: self.host_addr,
             host_fd: self.host_fd,
+            host_file_offset: self.host_file_offset,
             blocks_state: Arc::clone(&self.blocks_state),
             config: self.config.clone(),
+            resize_status: self.resize_status.clone(),
             queue,
+            queue_cursors: self.common.queue_cursors(index),
             interrupt_cb,
             queue_evt,
             kill_evt,
@@ -993,6 +1153,10 @@ impl VirtioDevice for Mem {
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
+
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
 }
 
 impl Pausable for Mem {
@@ -1016,3 +1180,49 @@ impl Snapshottable for Mem {
 }
 impl Transportable for Mem {}
 impl Migratable for Mem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resize_status() {
+        let mut config = VirtioMemConfig {
+            block_size: 0x20_0000,
+            region_size: 0x4000_0000,
+            usable_region_size: 0x4000_0000,
+            requested_size: 0x40_0000,
+            ..Default::default()
+        };
+        let status = VirtioMemResizeStatus::from_config(&config);
+        assert_eq!(status, VirtioMemResizeStatus::InProgress);
+
+        // The host failing to plug a block
+        let status = status.update(&config, false, true);
+        assert_eq!(status, VirtioMemResizeStatus::Failed);
+        // Until the guest plugs some memory again
+        let status = status.update(&config, false, false);
+        assert_eq!(status, VirtioMemResizeStatus::Failed);
+        config.plugged_size = 0x20_0000;
+        let status = status.update(&config, true, false);
+        assert_eq!(status, VirtioMemResizeStatus::InProgress);
+
+        config.plugged_size = 0x40_0000;
+        let status = status.update(&config, true, false);
+        assert_eq!(status, VirtioMemResizeStatus::Completed);
+
+        // The guest unplugging all the memory after the resize completed
+        config.plugged_size = 0;
+        let status = status.update(&config, true, false);
+        assert_eq!(status, VirtioMemResizeStatus::InProgress);
+        config.plugged_size = 0x40_0000;
+        let status = status.update(&config, true, false);
+        assert_eq!(status, VirtioMemResizeStatus::Completed);
+
+        // A guest request refused without failing the resize
+        config.requested_size = 0x20_0000;
+        let status = VirtioMemResizeStatus::from_config(&config);
+        let status = status.update(&config, false, false);
+        assert_eq!(status, VirtioMemResizeStatus::InProgress);
+    }
+}
@@ -7,10 +7,11 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
-    RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
-    EPOLL_HELPER_EVENT_LAST,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, EventLoop,
+    NotificationCoalescer, NotificationConfig, QueueCursors, RateLimiterConfig, VirtioCommon,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
+use crate::histogram::QueueLatencies;
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::GuestMemoryMmap;
@@ -18,9 +19,9 @@ use crate::VirtioInterrupt;
 use anyhow::anyhow;
 use net_util::CtrlQueue;
 use net_util::{
-    build_net_config_space, build_net_config_space_with_mq, open_tap,
-    virtio_features_to_tap_offload, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio,
-    Tap, TapError, TxVirtio, VirtioNetConfig,
+    build_net_config_space, build_net_config_space_with_mq, interface_mtu, open_tap, open_xdp,
+    virtio_features_to_tap_offload, MacAddr, NetCounters, NetQueueBackend, NetQueuePair,
+    OpenTapError, RxVirtio, Tap, TapError, TxVirtio, VirtioNetConfig, XdpError, XDP_MAX_MTU,
 };
 use seccompiler::SeccompAction;
 use std::net::Ipv4Addr;
@@ -29,8 +30,9 @@ use std::ops::Deref;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::Instant;
 use std::vec::Vec;
 use std::{collections::HashMap, convert::TryInto};
 use thiserror::Error;
@@ -60,6 +62,7 @@ pub struct NetCtrlEpollHandler {
     pub ctrl_q: CtrlQueue,
     pub queue_evt: EventFd,
     pub queue: Queue,
+    pub queue_cursors: Arc<QueueCursors>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub queue_index: u16,
@@ -67,6 +70,7 @@ pub struct NetCtrlEpollHandler {
 
 impl NetCtrlEpollHandler {
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        self.queue_cursors.signalled(&self.queue);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(queue_index))
             .map_err(|e| {
@@ -137,6 +141,7 @@ impl EpollHelperHandler for NetCtrlEpollHandler {
             }
         }
 
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
@@ -154,6 +159,10 @@ pub const TX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 pub const RX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 5;
 // New 'wake up' event from the tx rate limiter
 pub const TX_RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 6;
+// The notification held back on the rx queue is due
+pub const RX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 7;
+// The notification held back on the tx queue is due
+pub const TX_COALESCING_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 8;
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -163,28 +172,40 @@ pub enum Error {
     TapError(TapError),
     #[error("Error calling dup() on tap fd: {0}")]
     DuplicateTapFd(std::io::Error),
+    #[error("Failed to open AF_XDP sockets: {0}")]
+    Xdp(XdpError),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 struct NetEpollHandler {
     net: NetQueuePair,
+    latencies: QueueLatencies,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     kill_evt: EventFd,
     pause_evt: EventFd,
     queue_index_base: u16,
     queue_pair: (Queue, Queue),
+    queue_cursors_pair: (Arc<QueueCursors>, Arc<QueueCursors>),
     queue_evt_pair: (EventFd, EventFd),
+    rx_coalescer: Option<NotificationCoalescer>,
+    tx_coalescer: Option<NotificationCoalescer>,
     // Always generate interrupts until the driver has signalled to the device.
     // This mitigates a problem with interrupts from tap events being "lost" upon
     // a restore as the vCPU thread isn't ready to handle the interrupt. This causes
     // issues when combined with VIRTIO_RING_F_EVENT_IDX interrupt suppression.
     driver_awake: bool,
+    event_loop: EventLoop,
 }
 
 impl NetEpollHandler {
     fn signal_used_queue(&self, queue_index: u16) -> result::Result<(), DeviceError> {
+        if queue_index == self.queue_index_base {
+            self.queue_cursors_pair.0.signalled(&self.queue_pair.0);
+        } else {
+            self.queue_cursors_pair.1.signalled(&self.queue_pair.1);
+        }
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(queue_index))
             .map_err(|e| {
@@ -211,7 +232,7 @@ impl NetEpollHandler {
         if !self.net.rx_tap_listening && !rate_limit_reached {
             net_util::register_listener(
                 self.net.epoll_fd.unwrap(),
-                self.net.tap.as_raw_fd(),
+                self.net.backend.as_raw_fd(),
                 epoll::Events::EPOLLIN,
                 u64::from(self.net.tap_rx_event_id),
             )
@@ -222,13 +243,36 @@ impl NetEpollHandler {
         Ok(())
     }
 
+    // Whether the driver must be notified now about the `count` buffers just
+    // used, rather than coalescing the notification with the next ones.
+    fn must_notify(
+        coalescer: Option<&mut NotificationCoalescer>,
+        count: u16,
+        needs_notification: bool,
+        driver_awake: bool,
+    ) -> result::Result<bool, DeviceError> {
+        match coalescer {
+            Some(coalescer) if driver_awake => coalescer
+                .add_used(count, needs_notification)
+                .map_err(DeviceError::IoError),
+            _ => Ok(needs_notification || !driver_awake),
+        }
+    }
+
     fn process_tx(&mut self) -> result::Result<(), DeviceError> {
-        if self
+        let start = Instant::now();
+        let next_used = self.queue_pair.1.next_used();
+        let used = self
             .net
             .process_tx(&self.mem.memory(), &mut self.queue_pair.1)
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
+            .map_err(DeviceError::NetQueuePair)?;
+
+        // Only the passes processing some descriptors are measured.
+        let count = self.queue_pair.1.next_used().wrapping_sub(next_used);
+        if count > 0 {
+            self.latencies.tx.record(start.elapsed().as_micros() as u64);
+        }
+        if Self::must_notify(self.tx_coalescer.as_mut(), count, used, self.driver_awake)? {
             self.signal_used_queue(self.queue_index_base + 1)?;
             debug!("Signalling TX queue");
         } else {
@@ -252,12 +296,18 @@ impl NetEpollHandler {
     }
 
     fn handle_rx_tap_event(&mut self) -> result::Result<(), DeviceError> {
-        if self
+        let start = Instant::now();
+        let next_used = self.queue_pair.0.next_used();
+        let used = self
             .net
             .process_rx(&self.mem.memory(), &mut self.queue_pair.0)
-            .map_err(DeviceError::NetQueuePair)?
-            || !self.driver_awake
-        {
+            .map_err(DeviceError::NetQueuePair)?;
+
+        let count = self.queue_pair.0.next_used().wrapping_sub(next_used);
+        if count > 0 {
+            self.latencies.rx.record(start.elapsed().as_micros() as u64);
+        }
+        if Self::must_notify(self.rx_coalescer.as_mut(), count, used, self.driver_awake)? {
             self.signal_used_queue(self.queue_index_base)?;
             debug!("Signalling RX queue");
         } else {
@@ -266,12 +316,30 @@ impl NetEpollHandler {
         Ok(())
     }
 
+    // Send the notification held back on the RX or TX queue, once its
+    // coalescing timer expires.
+    fn handle_coalescing_event(&mut self, tx: bool) -> result::Result<(), DeviceError> {
+        let (coalescer, queue_index) = if tx {
+            (self.tx_coalescer.as_mut(), self.queue_index_base + 1)
+        } else {
+            (self.rx_coalescer.as_mut(), self.queue_index_base)
+        };
+
+        if let Some(coalescer) = coalescer {
+            if coalescer.expired().map_err(DeviceError::IoError)? {
+                self.signal_used_queue(queue_index)?;
+            }
+        }
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
         paused_sync: Arc<Barrier>,
     ) -> result::Result<(), EpollHelperError> {
-        let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
+        let mut helper =
+            EpollHelper::new_with_event_loop(&self.kill_evt, &self.pause_evt, self.event_loop)?;
         helper.add_event(self.queue_evt_pair.0.as_raw_fd(), RX_QUEUE_EVENT)?;
         helper.add_event(self.queue_evt_pair.1.as_raw_fd(), TX_QUEUE_EVENT)?;
         if let Some(rate_limiter) = &self.net.rx_rate_limiter {
@@ -280,6 +348,12 @@ impl NetEpollHandler {
         if let Some(rate_limiter) = &self.net.tx_rate_limiter {
             helper.add_event(rate_limiter.as_raw_fd(), TX_RATE_LIMITER_EVENT)?;
         }
+        if let Some(coalescer) = &self.rx_coalescer {
+            helper.add_event(coalescer.as_raw_fd(), RX_COALESCING_EVENT)?;
+        }
+        if let Some(coalescer) = &self.tx_coalescer {
+            helper.add_event(coalescer.as_raw_fd(), TX_COALESCING_EVENT)?;
+        }
 
         let mem = self.mem.memory();
         // If there are some already available descriptors on the RX queue,
@@ -295,7 +369,7 @@ impl NetEpollHandler {
                 .avail_idx(mem.deref(), Ordering::Acquire)
                 .map_err(EpollHelperError::QueueRingIndex)?
         {
-            helper.add_event(self.net.tap.as_raw_fd(), RX_TAP_EVENT)?;
+            helper.add_event(self.net.backend.as_raw_fd(), RX_TAP_EVENT)?;
             self.net.rx_tap_listening = true;
             info!("Listener registered at start");
         }
@@ -360,7 +434,7 @@ impl EpollHelperHandler for NetEpollHandler {
                     if !self.net.rx_tap_listening && self.net.rx_desc_avail {
                         net_util::register_listener(
                             self.net.epoll_fd.unwrap(),
-                            self.net.tap.as_raw_fd(),
+                            self.net.backend.as_raw_fd(),
                             epoll::Events::EPOLLIN,
                             u64::from(self.net.tap_rx_event_id),
                         )
@@ -400,6 +474,22 @@ impl EpollHelperHandler for NetEpollHandler {
                     )));
                 }
             }
+            RX_COALESCING_EVENT => {
+                self.handle_coalescing_event(false).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Error sending the RX queue notification: {:?}",
+                        e
+                    ))
+                })?;
+            }
+            TX_COALESCING_EVENT => {
+                self.handle_coalescing_event(true).map_err(|e| {
+                    EpollHelperError::HandleEvent(anyhow!(
+                        "Error sending the TX queue notification: {:?}",
+                        e
+                    ))
+                })?;
+            }
             _ => {
                 return Err(EpollHelperError::HandleEvent(anyhow!(
                     "Unexpected event: {}",
@@ -407,6 +497,8 @@ impl EpollHelperHandler for NetEpollHandler {
                 )));
             }
         }
+        self.queue_cursors_pair.0.update(&self.queue_pair.0);
+        self.queue_cursors_pair.1.update(&self.queue_pair.1);
         Ok(())
     }
 }
@@ -414,13 +506,16 @@ impl EpollHelperHandler for NetEpollHandler {
 pub struct Net {
     common: VirtioCommon,
     id: String,
-    taps: Vec<Tap>,
+    backends: Vec<NetQueueBackend>,
     config: VirtioNetConfig,
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<()>>,
     counters: NetCounters,
+    latencies: QueueLatencies,
     seccomp_action: SeccompAction,
     rate_limiter_config: Option<RateLimiterConfig>,
+    notification: NotificationConfig,
     exit_evt: EventFd,
+    event_loop: EventLoop,
 }
 
 #[derive(Versionize)]
@@ -450,24 +545,126 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        notification: NotificationConfig,
     ) -> Result<Self> {
         assert!(!taps.is_empty());
 
         let mtu = taps[0].mtu().map_err(Error::TapError)? as u16;
 
+        Self::new_with_backends(
+            id,
+            taps.into_iter().map(NetQueueBackend::Tap).collect(),
+            mtu,
+            guest_mac,
+            iommu,
+            num_queues,
+            queue_size,
+            seccomp_action,
+            rate_limiter_config,
+            exit_evt,
+            state,
+            offload_tso,
+            offload_ufo,
+            offload_csum,
+            notification,
+        )
+    }
+
+    /// Create a new virtio network device exchanging its frames through
+    /// AF_XDP sockets bound to the queues of the host interface `if_name`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_xdp(
+        id: String,
+        if_name: &str,
+        zero_copy: bool,
+        guest_mac: Option<MacAddr>,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        exit_evt: EventFd,
+        state: Option<NetState>,
+        notification: NotificationConfig,
+    ) -> Result<Self> {
+        // The frames must fit in the frames of the UMEM.
+        let mtu = std::cmp::min(
+            interface_mtu(if_name).map_err(Error::Xdp)? as usize,
+            XDP_MAX_MTU,
+        ) as u16;
+
+        // Only the frames for the MAC address of the guest are redirected to
+        // it, which must therefore be known.
+        let guest_mac = match &state {
+            Some(state) => MacAddr::from_bytes_unchecked(&state.config.mac),
+            None => guest_mac.unwrap_or_else(MacAddr::local_random),
+        };
+
+        let backends = open_xdp(if_name, num_queues / 2, zero_copy, &guest_mac)
+            .map_err(Error::Xdp)?
+            .into_iter()
+            .map(|socket| NetQueueBackend::Xdp(Arc::new(Mutex::new(socket))))
+            .collect();
+
+        // The frames reach the host interface as they are, hence no offload.
+        Self::new_with_backends(
+            id,
+            backends,
+            mtu,
+            Some(guest_mac),
+            iommu,
+            num_queues,
+            queue_size,
+            seccomp_action,
+            rate_limiter_config,
+            exit_evt,
+            state,
+            false,
+            false,
+            false,
+            notification,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new_with_backends(
+        id: String,
+        backends: Vec<NetQueueBackend>,
+        mtu: u16,
+        guest_mac: Option<MacAddr>,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+        seccomp_action: SeccompAction,
+        rate_limiter_config: Option<RateLimiterConfig>,
+        exit_evt: EventFd,
+        state: Option<NetState>,
+        offload_tso: bool,
+        offload_ufo: bool,
+        offload_csum: bool,
+        notification: NotificationConfig,
+    ) -> Result<Self> {
         let (avail_features, acked_features, config, queue_sizes, paused) =
             if let Some(state) = state {
                 info!("Restoring virtio-net {}", id);
+                let mut config = state.config;
+                // The MAC address changes when restoring a clone of the VM.
+                if let Some(mac) = guest_mac {
+                    config.mac.copy_from_slice(mac.get_bytes());
+                }
                 (
                     state.avail_features,
                     state.acked_features,
-                    state.config,
+                    config,
                     state.queue_size,
                     true,
                 )
             } else {
-                let mut avail_features =
-                    1 << VIRTIO_NET_F_MTU | 1 << VIRTIO_RING_F_EVENT_IDX | 1 << VIRTIO_F_VERSION_1;
+                let mut avail_features = 1 << VIRTIO_NET_F_MTU | 1 << VIRTIO_F_VERSION_1;
+
+                if notification.event_idx {
+                    avail_features |= 1 << VIRTIO_RING_F_EVENT_IDX;
+                }
 
                 if iommu {
                     avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -535,13 +732,16 @@ impl Net {
                 ..Default::default()
             },
             id,
-            taps,
+            backends,
             config,
             ctrl_queue_epoll_thread: None,
             counters: NetCounters::default(),
+            latencies: QueueLatencies::default(),
             seccomp_action,
             rate_limiter_config,
+            notification,
             exit_evt,
+            event_loop: EventLoop::default(),
         })
     }
 
@@ -566,6 +766,7 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        notification: NotificationConfig,
     ) -> Result<Self> {
         let taps = open_tap(
             if_name,
@@ -592,6 +793,7 @@ impl Net {
             offload_tso,
             offload_ufo,
             offload_csum,
+            notification,
         )
     }
 
@@ -610,6 +812,7 @@ impl Net {
         offload_tso: bool,
         offload_ufo: bool,
         offload_csum: bool,
+        notification: NotificationConfig,
     ) -> Result<Self> {
         let mut taps: Vec<Tap> = Vec::new();
         let num_queue_pairs = fds.len();
@@ -645,6 +848,7 @@ impl Net {
             offload_tso,
             offload_ufo,
             offload_csum,
+            notification,
         )
     }
 
@@ -657,6 +861,11 @@ impl Net {
         }
     }
 
+    /// Set how the queue pair threads wait for their events.
+    pub fn set_event_loop(&mut self, event_loop: EventLoop) {
+        self.event_loop = event_loop;
+    }
+
     #[cfg(fuzzing)]
     pub fn wait_for_epoll_threads(&mut self) {
         self.common.wait_for_epoll_threads();
@@ -712,7 +921,7 @@ impl VirtioDevice for Net {
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
         if self.common.feature_acked(VIRTIO_NET_F_CTRL_VQ.into()) && num_queues % 2 != 0 {
             let ctrl_queue_index = num_queues - 1;
-            let (_, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
+            let (index, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
 
             ctrl_queue.set_event_idx(event_idx);
 
@@ -721,8 +930,17 @@ impl VirtioDevice for Net {
                 mem: mem.clone(),
                 kill_evt,
                 pause_evt,
-                ctrl_q: CtrlQueue::new(self.taps.clone()),
+                ctrl_q: CtrlQueue::new(
+                    self.backends
+                        .iter()
+                        .filter_map(|backend| match backend {
+                            NetQueueBackend::Tap(tap) => Some(tap.clone()),
+                            NetQueueBackend::Xdp(_) => None,
+                        })
+                        .collect(),
+                ),
                 queue: ctrl_queue,
+                queue_cursors: self.common.queue_cursors(index),
                 queue_evt: ctrl_queue_evt,
                 access_platform: self.common.access_platform.clone(),
                 queue_index: ctrl_queue_index as u16,
@@ -733,7 +951,7 @@ impl VirtioDevice for Net {
             // Let's update the barrier as we need 1 for each RX/TX pair +
             // 1 for the control queue + 1 for the main thread signalling
             // the pause.
-            self.common.paused_sync = Some(Arc::new(Barrier::new(self.taps.len() + 2)));
+            self.common.paused_sync = Some(Arc::new(Barrier::new(self.backends.len() + 2)));
             let paused_sync = self.common.paused_sync.clone();
 
             let mut epoll_threads = Vec::new();
@@ -749,17 +967,21 @@ impl VirtioDevice for Net {
         }
 
         let mut epoll_threads = Vec::new();
-        let mut taps = self.taps.clone();
+        let mut backends = self.backends.clone();
         for i in 0..queues.len() / 2 {
             let rx = RxVirtio::new();
             let tx = TxVirtio::new();
             let rx_tap_listening = false;
 
-            let (_, queue_0, queue_evt_0) = queues.remove(0);
-            let (_, queue_1, queue_evt_1) = queues.remove(0);
+            let (index_0, queue_0, queue_evt_0) = queues.remove(0);
+            let (index_1, queue_1, queue_evt_1) = queues.remove(0);
             let mut queue_pair = (queue_0, queue_1);
             queue_pair.0.set_event_idx(event_idx);
             queue_pair.1.set_event_idx(event_idx);
+            let queue_cursors_pair = (
+                self.common.queue_cursors(index_0),
+                self.common.queue_cursors(index_1),
+            );
 
             let queue_evt_pair = (queue_evt_0, queue_evt_1);
 
@@ -777,18 +999,29 @@ impl VirtioDevice for Net {
                 .transpose()
                 .map_err(ActivateError::CreateRateLimiter)?;
 
-            let tap = taps.remove(0);
+            let rx_coalescer = NotificationCoalescer::new(&self.notification)
+                .map_err(ActivateError::CreateNotificationCoalescer)?;
+            let tx_coalescer = NotificationCoalescer::new(&self.notification)
+                .map_err(ActivateError::CreateNotificationCoalescer)?;
+
+            let backend = backends.remove(0);
             #[cfg(not(fuzzing))]
-            tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
-                .map_err(|e| {
-                    error!("Error programming tap offload: {:?}", e);
-                    ActivateError::BadActivate
-                })?;
+            if let NetQueueBackend::Tap(tap) = &backend {
+                tap.set_offload(virtio_features_to_tap_offload(self.common.acked_features))
+                    .map_err(|e| {
+                        error!("Error programming tap offload: {:?}", e);
+                        ActivateError::BadActivate
+                    })?;
+            }
+            let backend_for_write_epoll = backend.try_clone_fd().map_err(|e| {
+                error!("Error duplicating the backend fd: {:?}", e);
+                ActivateError::BadActivate
+            })?;
 
             let mut handler = NetEpollHandler {
                 net: NetQueuePair {
-                    tap_for_write_epoll: tap.clone(),
-                    tap,
+                    backend_for_write_epoll,
+                    backend,
                     rx,
                     tx,
                     epoll_fd: None,
@@ -802,14 +1035,19 @@ impl VirtioDevice for Net {
                     tx_rate_limiter,
                     access_platform: self.common.access_platform.clone(),
                 },
+                latencies: self.latencies.clone(),
                 mem: mem.clone(),
                 queue_index_base: (i * 2) as u16,
                 queue_pair,
+                queue_cursors_pair,
                 queue_evt_pair,
+                rx_coalescer,
+                tx_coalescer,
                 interrupt_cb: interrupt_cb.clone(),
                 kill_evt,
                 pause_evt,
                 driver_awake: false,
+                event_loop: self.event_loop,
             };
 
             let paused = self.common.paused.clone();
@@ -837,6 +1075,10 @@ impl VirtioDevice for Net {
         result
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -856,6 +1098,7 @@ impl VirtioDevice for Net {
             "tx_frames",
             Wrapping(self.counters.tx_frames.load(Ordering::Acquire)),
         );
+        self.latencies.add_counters(&mut counters);
 
         Some(counters)
     }
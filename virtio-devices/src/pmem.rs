@@ -8,7 +8,7 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors,
     UserspaceMapping, VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
     VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
@@ -45,6 +45,14 @@ const VIRTIO_PMEM_REQ_TYPE_FLUSH: u32 = 0;
 const VIRTIO_PMEM_RESP_TYPE_OK: u32 = 0;
 const VIRTIO_PMEM_RESP_TYPE_EIO: u32 = 1;
 
+// The DISCARD request, allowing the guest to punch holes into the backing
+// file, is not part of the virtio specification. Its feature bit is the last
+// device specific one, away from the ones the specification assigns from the
+// first one (VIRTIO_PMEM_F_SHMEM_REGION), and its request type is in the
+// upper half of the range, away from the FLUSH one.
+const VIRTIO_PMEM_F_DISCARD: u64 = 23;
+const VIRTIO_PMEM_REQ_TYPE_DISCARD: u32 = 0x8000_0000;
+
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
@@ -67,6 +75,18 @@ struct VirtioPmemReq {
 // SAFETY: it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioPmemReq {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioPmemDiscardReq {
+    type_: u32,
+    reserved: u32,
+    offset: u64,
+    len: u64,
+}
+
+// SAFETY: it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioPmemDiscardReq {}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct VirtioPmemResp {
@@ -92,11 +112,16 @@ enum Error {
     InvalidRequest,
     #[error("Failed adding used index: {0}")]
     QueueAddUsed(virtio_queue::Error),
+    #[error("Discard range out of bounds: offset {0:#x} length {1:#x}")]
+    DiscardOutOfRange(u64, u64),
+    #[error("Failed to discard range: {0}")]
+    Discard(io::Error),
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum RequestType {
     Flush,
+    Discard { offset: u64, len: u64 },
 }
 
 struct Request {
@@ -115,20 +140,34 @@ impl Request {
             return Err(Error::UnexpectedWriteOnlyDescriptor);
         }
 
-        if desc.len() as usize != size_of::<VirtioPmemReq>() {
+        if (desc.len() as usize) < size_of::<VirtioPmemReq>() {
             return Err(Error::InvalidRequest);
         }
 
+        let req_addr = desc
+            .addr()
+            .translate_gva(access_platform, desc.len() as usize);
         let request: VirtioPmemReq = desc_chain
             .memory()
-            .read_obj(
-                desc.addr()
-                    .translate_gva(access_platform, desc.len() as usize),
-            )
+            .read_obj(req_addr)
             .map_err(Error::GuestMemory)?;
 
         let request_type = match request.type_ {
-            VIRTIO_PMEM_REQ_TYPE_FLUSH => RequestType::Flush,
+            VIRTIO_PMEM_REQ_TYPE_FLUSH if desc.len() as usize == size_of::<VirtioPmemReq>() => {
+                RequestType::Flush
+            }
+            VIRTIO_PMEM_REQ_TYPE_DISCARD
+                if desc.len() as usize == size_of::<VirtioPmemDiscardReq>() =>
+            {
+                let request: VirtioPmemDiscardReq = desc_chain
+                    .memory()
+                    .read_obj(req_addr)
+                    .map_err(Error::GuestMemory)?;
+                RequestType::Discard {
+                    offset: request.offset,
+                    len: request.len,
+                }
+            }
             _ => return Err(Error::InvalidRequest),
         };
 
@@ -155,7 +194,10 @@ impl Request {
 struct PmemEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queue: Queue,
+    queue_cursors: Arc<QueueCursors>,
     disk: File,
+    size: u64,
+    discard: bool,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -164,15 +206,53 @@ struct PmemEpollHandler {
 }
 
 impl PmemEpollHandler {
+    fn discard_range(&self, offset: u64, len: u64) -> result::Result<(), Error> {
+        if offset.checked_add(len).map_or(true, |end| end > self.size) {
+            return Err(Error::DiscardOutOfRange(offset, len));
+        }
+
+        // Punching a hole into the backing file releases the host pages,
+        // and the shared mapping exposed to the guest reads back zeroes.
+        // SAFETY: FFI call with a valid file descriptor
+        let res = unsafe {
+            libc::fallocate64(
+                self.disk.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off64_t,
+                len as libc::off64_t,
+            )
+        };
+        if res != 0 {
+            return Err(Error::Discard(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     fn process_queue(&mut self) -> result::Result<bool, Error> {
         let mut used_descs = false;
         while let Some(mut desc_chain) = self.queue.pop_descriptor_chain(self.mem.memory()) {
             let len = match Request::parse(&mut desc_chain, self.access_platform.as_ref()) {
-                Ok(ref req) if (req.type_ == RequestType::Flush) => {
-                    let status_code = match self.disk.sync_all() {
-                        Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
-                        Err(e) => {
-                            error!("failed flushing disk image: {}", e);
+                Ok(ref req) => {
+                    let status_code = match req.type_ {
+                        RequestType::Flush => match self.disk.sync_all() {
+                            Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
+                            Err(e) => {
+                                error!("failed flushing disk image: {}", e);
+                                VIRTIO_PMEM_RESP_TYPE_EIO
+                            }
+                        },
+                        RequestType::Discard { offset, len } if self.discard => {
+                            match self.discard_range(offset, len) {
+                                Ok(()) => VIRTIO_PMEM_RESP_TYPE_OK,
+                                Err(e) => {
+                                    error!("failed discarding disk image range: {}", e);
+                                    VIRTIO_PMEM_RESP_TYPE_EIO
+                                }
+                            }
+                        }
+                        RequestType::Discard { .. } => {
+                            error!("Discard request received but not negotiated");
                             VIRTIO_PMEM_RESP_TYPE_EIO
                         }
                     };
@@ -186,11 +266,6 @@ impl PmemEpollHandler {
                         }
                     }
                 }
-                Ok(ref req) => {
-                    // Currently, there is only one virtio-pmem request, FLUSH.
-                    error!("Invalid virtio request type {:?}", req.type_);
-                    0
-                }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
                     0
@@ -207,6 +282,7 @@ impl PmemEpollHandler {
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.queue_cursors.signalled(&self.queue);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(0))
             .map_err(|e| {
@@ -261,6 +337,7 @@ impl EpollHelperHandler for PmemEpollHandler {
                 )));
             }
         }
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
@@ -297,6 +374,7 @@ impl Pmem {
         mapping: UserspaceMapping,
         _region: MmapRegion,
         iommu: bool,
+        discard: bool,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         state: Option<PmemState>,
@@ -320,6 +398,10 @@ impl Pmem {
             if iommu {
                 avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
             }
+
+            if discard {
+                avail_features |= 1u64 << VIRTIO_PMEM_F_DISCARD;
+            }
             (avail_features, 0, config, false)
         };
 
@@ -403,12 +485,15 @@ impl VirtioDevice for Pmem {
                 ActivateError::BadActivate
             })?;
 
-            let (_, queue, queue_evt) = queues.remove(0);
+            let (index, queue, queue_evt) = queues.remove(0);
 
             let mut handler = PmemEpollHandler {
                 mem,
                 queue,
+                queue_cursors: self.common.queue_cursors(index),
                 disk,
+                size: u64::from_le(self.config.size),
+                discard: self.common.feature_acked(VIRTIO_PMEM_F_DISCARD),
                 interrupt_cb,
                 queue_evt,
                 kill_evt,
@@ -443,6 +528,10 @@ impl VirtioDevice for Pmem {
         result
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
         vec![self.mapping.clone()]
     }
@@ -4,18 +4,21 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
-    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_IOMMU_PLATFORM,
-    VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors,
+    RateLimiterConfig, VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
 use crate::GuestMemoryMmap;
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
+use rate_limiter::{RateLimiter, TokenType};
 use seccompiler::SeccompAction;
-use std::fs::File;
+use std::cmp;
+use std::fs::{File, OpenOptions};
 use std::io;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::AtomicBool;
@@ -35,6 +38,10 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
+// The rate limiter budget has been replenished.
+const RATE_LIMITER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+// The entropy source has data available again.
+const SOURCE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
 
 #[derive(Error, Debug)]
 enum Error {
@@ -51,12 +58,22 @@ enum Error {
 struct RngEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queue: Queue,
+    queue_cursors: Arc<QueueCursors>,
     random_file: File,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
     pause_evt: EventFd,
     access_platform: Option<Arc<dyn AccessPlatform>>,
+    rate_limiter: Option<RateLimiter>,
+    // Size of the rate limiter bandwidth bucket, which caps the amount of
+    // data provided at once so that a large buffer can't exceed the budget.
+    bandwidth_size: Option<u64>,
+    // The source ran out of data and is registered to the epoll loop until it
+    // becomes readable again.
+    source_blocked: bool,
+    // The source reached its end, which only happens with a regular file.
+    source_exhausted: bool,
 }
 
 impl RngEpollHandler {
@@ -72,16 +89,62 @@ impl RngEpollHandler {
                 return Err(Error::InvalidDescriptor);
             }
 
+            // The device may provide less entropy than requested, which keeps
+            // buffers larger than the bucket from blowing the budget.
+            let request_len = self
+                .bandwidth_size
+                .map_or(desc.len() as u64, |size| cmp::min(desc.len() as u64, size));
+
+            if let Some(rate_limiter) = &mut self.rate_limiter {
+                // If limiter.consume() fails it means there is no more TokenType::Bytes
+                // budget and rate limiting is in effect.
+                if !rate_limiter.consume(request_len, TokenType::Bytes) {
+                    // Stop processing the queue and return this descriptor chain to the
+                    // avail ring, for later processing.
+                    queue.go_to_previous_position();
+                    break;
+                }
+            }
+
             // Fill the read with data from the random device on the host.
-            let len = desc_chain
-                .memory()
-                .read_from(
-                    desc.addr()
-                        .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
-                    &mut self.random_file,
-                    desc.len() as usize,
-                )
-                .map_err(Error::GuestMemoryWrite)?;
+            let len = match desc_chain.memory().read_from(
+                desc.addr()
+                    .translate_gva(self.access_platform.as_ref(), desc.len() as usize),
+                &mut self.random_file,
+                request_len as usize,
+            ) {
+                // Completing the request without any data would only make the
+                // guest driver submit it again right away. Leave it pending,
+                // as the file won't provide anything more.
+                Ok(0) => {
+                    if let Some(rate_limiter) = &mut self.rate_limiter {
+                        rate_limiter.manual_replenish(request_len, TokenType::Bytes);
+                    }
+                    queue.go_to_previous_position();
+                    warn!("virtio-rng: the entropy source reached its end");
+                    self.source_exhausted = true;
+                    break;
+                }
+                Ok(len) => len,
+                Err(vm_memory::GuestMemoryError::IOError(e))
+                    if e.kind() == io::ErrorKind::WouldBlock =>
+                {
+                    // Nothing to read from the source for now, give back the
+                    // budget and wait for the source to become readable.
+                    if let Some(rate_limiter) = &mut self.rate_limiter {
+                        rate_limiter.manual_replenish(request_len, TokenType::Bytes);
+                    }
+                    queue.go_to_previous_position();
+                    self.source_blocked = true;
+                    break;
+                }
+                Err(e) => return Err(Error::GuestMemoryWrite(e)),
+            };
+
+            if let Some(rate_limiter) = &mut self.rate_limiter {
+                // Only account for the bytes actually provided to the guest.
+                rate_limiter.manual_replenish(request_len - len as u64, TokenType::Bytes);
+            }
 
             queue
                 .add_used(desc_chain.memory(), desc_chain.head_index(), len as u32)
@@ -93,6 +156,7 @@ impl RngEpollHandler {
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.queue_cursors.signalled(&self.queue);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(0))
             .map_err(|e| {
@@ -101,6 +165,31 @@ impl RngEpollHandler {
             })
     }
 
+    fn process_queue_and_signal(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> result::Result<(), EpollHelperError> {
+        if self.source_exhausted {
+            return Ok(());
+        }
+
+        let needs_notification = self.process_queue().map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
+        })?;
+
+        if self.source_blocked {
+            helper.add_event(self.random_file.as_raw_fd(), SOURCE_EVENT)?;
+        }
+
+        if needs_notification {
+            self.signal_used_queue().map_err(|e| {
+                EpollHelperError::HandleEvent(anyhow!("Failed to signal used queue: {:?}", e))
+            })?;
+        }
+
+        Ok(())
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -108,6 +197,9 @@ impl RngEpollHandler {
     ) -> result::Result<(), EpollHelperError> {
         let mut helper = EpollHelper::new(&self.kill_evt, &self.pause_evt)?;
         helper.add_event(self.queue_evt.as_raw_fd(), QUEUE_AVAIL_EVENT)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            helper.add_event(rate_limiter.as_raw_fd(), RATE_LIMITER_EVENT)?;
+        }
         helper.run(paused, paused_sync, self)?;
 
         Ok(())
@@ -117,7 +209,7 @@ impl RngEpollHandler {
 impl EpollHelperHandler for RngEpollHandler {
     fn handle_event(
         &mut self,
-        _helper: &mut EpollHelper,
+        helper: &mut EpollHelper,
         event: &epoll::Event,
     ) -> result::Result<(), EpollHelperError> {
         let ev_type = event.data as u16;
@@ -126,16 +218,48 @@ impl EpollHelperHandler for RngEpollHandler {
                 self.queue_evt.read().map_err(|e| {
                     EpollHelperError::HandleEvent(anyhow!("Failed to get queue event: {:?}", e))
                 })?;
-                let needs_notification = self.process_queue().map_err(|e| {
-                    EpollHelperError::HandleEvent(anyhow!("Failed to process queue : {:?}", e))
-                })?;
-                if needs_notification {
-                    self.signal_used_queue().map_err(|e| {
+
+                let rate_limit_reached =
+                    self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
+
+                // Process the queue only when the rate limit is not reached
+                // and the source is not waiting for more data.
+                if !rate_limit_reached && !self.source_blocked {
+                    self.process_queue_and_signal(helper)?
+                }
+            }
+            RATE_LIMITER_EVENT => {
+                if let Some(rate_limiter) = &mut self.rate_limiter {
+                    // Upon rate limiter event, call the rate limiter handler
+                    // and restart processing the queue.
+                    rate_limiter.event_handler().map_err(|e| {
                         EpollHelperError::HandleEvent(anyhow!(
-                            "Failed to signal used queue: {:?}",
+                            "Failed to process rate limiter event: {:?}",
                             e
                         ))
                     })?;
+
+                    if !self.source_blocked {
+                        self.process_queue_and_signal(helper)?
+                    }
+                } else {
+                    return Err(EpollHelperError::HandleEvent(anyhow!(
+                        "Unexpected 'RATE_LIMITER_EVENT' when rate_limiter is not enabled."
+                    )));
+                }
+            }
+            SOURCE_EVENT => {
+                helper.del_event_custom(
+                    self.random_file.as_raw_fd(),
+                    SOURCE_EVENT,
+                    epoll::Events::EPOLLIN,
+                )?;
+                self.source_blocked = false;
+
+                let rate_limit_reached =
+                    self.rate_limiter.as_ref().map_or(false, |r| r.is_blocked());
+                if !rate_limit_reached {
+                    self.process_queue_and_signal(helper)?
                 }
             }
             _ => {
@@ -145,6 +269,7 @@ impl EpollHelperHandler for RngEpollHandler {
                 )));
             }
         }
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
@@ -156,6 +281,7 @@ pub struct Rng {
     random_file: Option<File>,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    rate_limiter_config: Option<RateLimiterConfig>,
 }
 
 #[derive(Versionize)]
@@ -167,7 +293,10 @@ pub struct RngState {
 impl VersionMapped for RngState {}
 
 impl Rng {
-    /// Create a new virtio rng device that gets random data from /dev/urandom.
+    /// Create a new virtio rng device that gets random data from `path`, which
+    /// can be a character device such as /dev/urandom or /dev/hwrng, a regular
+    /// file, or a named pipe.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         path: &str,
@@ -175,8 +304,18 @@ impl Rng {
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
         state: Option<RngState>,
+        rate_limiter_config: Option<RateLimiterConfig>,
     ) -> io::Result<Rng> {
-        let random_file = File::open(path)?;
+        // The source is read in non-blocking mode so that a slow or empty
+        // source doesn't stall the device thread. A named pipe is also opened
+        // for writing, which prevents it from reporting EOF whenever no
+        // producer is connected.
+        let is_fifo = std::fs::metadata(path)?.file_type().is_fifo();
+        let random_file = OpenOptions::new()
+            .read(true)
+            .write(is_fifo)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
 
         let (avail_features, acked_features, paused) = if let Some(state) = state {
             info!("Restoring virtio-rng {}", id);
@@ -206,6 +345,7 @@ impl Rng {
             random_file: Some(random_file),
             seccomp_action,
             exit_evt,
+            rate_limiter_config,
         })
     }
 
@@ -264,17 +404,32 @@ impl VirtioDevice for Rng {
                 ActivateError::BadActivate
             })?;
 
-            let (_, queue, queue_evt) = queues.remove(0);
+            let (index, queue, queue_evt) = queues.remove(0);
+
+            let rate_limiter: Option<RateLimiter> = self
+                .rate_limiter_config
+                .map(RateLimiterConfig::try_into)
+                .transpose()
+                .map_err(ActivateError::CreateRateLimiter)?;
 
             let mut handler = RngEpollHandler {
                 mem,
                 queue,
+                queue_cursors: self.common.queue_cursors(index),
                 random_file,
                 interrupt_cb,
                 queue_evt,
                 kill_evt,
                 pause_evt,
                 access_platform: self.common.access_platform.clone(),
+                bandwidth_size: self
+                    .rate_limiter_config
+                    .and_then(|config| config.bandwidth)
+                    .map(|bandwidth| bandwidth.size)
+                    .filter(|size| *size != 0),
+                rate_limiter,
+                source_blocked: false,
+                source_exhausted: false,
             };
 
             let paused = self.common.paused.clone();
@@ -303,6 +458,10 @@ impl VirtioDevice for Rng {
         result
     }
 
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
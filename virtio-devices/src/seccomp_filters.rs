@@ -8,7 +8,9 @@ use seccompiler::{
     BpfProgram, Error, SeccompAction, SeccompCmpArgLen as ArgLen, SeccompCmpOp::Eq,
     SeccompCondition as Cond, SeccompFilter, SeccompRule,
 };
+use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::sync::Mutex;
 
 pub enum Thread {
     VirtioBalloon,
@@ -46,6 +48,124 @@ macro_rules! or {
     ($($x:expr),*) => (vec![$($x),*])
 }
 
+/// Shorthand for listing syscalls along with their names.
+macro_rules! syscalls {
+    ($($x:ident),* $(,)?) => (&[$((stringify!($x), libc::$x)),*])
+}
+
+// Syscalls the seccomp filter of a device can be overridden with.
+const OVERRIDABLE_SYSCALLS: &[(&str, i64)] = syscalls![
+    SYS_accept4,
+    SYS_brk,
+    SYS_clock_gettime,
+    SYS_close,
+    SYS_connect,
+    SYS_copy_file_range,
+    SYS_dup,
+    SYS_epoll_create1,
+    SYS_epoll_ctl,
+    SYS_epoll_pwait,
+    SYS_eventfd2,
+    SYS_exit,
+    SYS_exit_group,
+    SYS_fallocate,
+    SYS_fcntl,
+    SYS_fdatasync,
+    SYS_fsync,
+    SYS_ftruncate,
+    SYS_futex,
+    SYS_getpid,
+    SYS_getrandom,
+    SYS_gettid,
+    SYS_io_destroy,
+    SYS_io_getevents,
+    SYS_io_setup,
+    SYS_io_submit,
+    SYS_io_uring_enter,
+    SYS_io_uring_register,
+    SYS_io_uring_setup,
+    SYS_ioctl,
+    SYS_lseek,
+    SYS_madvise,
+    SYS_mbind,
+    SYS_memfd_create,
+    SYS_mmap,
+    SYS_mprotect,
+    SYS_mremap,
+    SYS_munmap,
+    SYS_openat,
+    SYS_pipe2,
+    SYS_ppoll,
+    SYS_prctl,
+    SYS_pread64,
+    SYS_preadv,
+    SYS_pwrite64,
+    SYS_pwritev,
+    SYS_read,
+    SYS_readv,
+    SYS_recvfrom,
+    SYS_recvmsg,
+    SYS_rt_sigprocmask,
+    SYS_rt_sigreturn,
+    SYS_sched_getaffinity,
+    SYS_sched_yield,
+    SYS_sendmsg,
+    SYS_sendto,
+    SYS_set_robust_list,
+    SYS_sigaltstack,
+    SYS_socket,
+    SYS_statx,
+    SYS_tgkill,
+    SYS_timerfd_create,
+    SYS_timerfd_settime,
+    SYS_write,
+    SYS_writev,
+];
+
+/// Number of the syscall named `name`, such as "fallocate", if the seccomp
+/// filter of a device can be overridden with it.
+pub fn syscall_number(name: &str) -> Option<i64> {
+    OVERRIDABLE_SYSCALLS
+        .iter()
+        .find(|(syscall, _)| syscall.strip_prefix("SYS_") == Some(name))
+        .map(|(_, number)| *number)
+}
+
+/// Syscalls allowed, whatever their arguments, or denied on top of the
+/// seccomp filter of the threads of a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SeccompOverride {
+    pub allow: Vec<i64>,
+    pub deny: Vec<i64>,
+}
+
+// Overrides of the seccomp filters, by device identifier.
+static SECCOMP_OVERRIDES: Mutex<BTreeMap<String, SeccompOverride>> = Mutex::new(BTreeMap::new());
+
+/// Override the seccomp filters of the threads of the devices, by device
+/// identifier, replacing the previous overrides. They apply to the threads
+/// spawned from now on.
+pub fn set_seccomp_overrides(overrides: BTreeMap<String, SeccompOverride>) {
+    *SECCOMP_OVERRIDES.lock().unwrap() = overrides;
+}
+
+/// Find the override of the device a thread belongs to, from the name of the
+/// thread: the identifier of the device, followed by the queue for the
+/// devices with several threads.
+pub fn seccomp_override(thread_name: &str) -> Option<SeccompOverride> {
+    SECCOMP_OVERRIDES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| {
+            thread_name
+                .strip_prefix(id.as_str())
+                .map_or(false, |queue| queue.is_empty() || queue.starts_with('_'))
+        })
+        .max_by_key(|(id, _)| id.len())
+        .map(|(_, seccomp_override)| seccomp_override.clone())
+}
+
 // See include/uapi/asm-generic/ioctls.h in the kernel code.
 const TIOCGWINSZ: u64 = 0x5413;
 const FIONBIO: u64 = 0x5421;
@@ -57,6 +177,14 @@ const VFIO_IOMMU_UNMAP_DMA: u64 = 0x3b72;
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 
+// See include/uapi/linux/kvm.h in the kernel code.
+const KVM_SET_GSI_ROUTING: u64 = 0x4008_ae6a;
+const KVM_IRQFD: u64 = 0x4020_ae76;
+
+// See include/uapi/linux/mshv.h in the kernel code.
+const MSHV_IRQFD: u64 = 0x4010_b80e;
+const MSHV_SET_MSI_ROUTING: u64 = 0x4008_b811;
+
 fn create_virtio_console_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![and![Cond::new(1, ArgLen::Dword, Eq, TIOCGWINSZ).unwrap()]]
 }
@@ -65,6 +193,11 @@ fn create_virtio_iommu_ioctl_seccomp_rule() -> Vec<SeccompRule> {
     or![
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_MAP_DMA).unwrap()],
         and![Cond::new(1, ArgLen::Dword, Eq, VFIO_IOMMU_UNMAP_DMA).unwrap()],
+        // The MSIs of the devices are remapped again once the domains changed
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_GSI_ROUTING).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_IRQFD).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, MSHV_IRQFD).unwrap()],
+        and![Cond::new(1, ArgLen::Dword, Eq, MSHV_SET_MSI_ROUTING).unwrap()],
     ]
 }
 
@@ -93,12 +226,16 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_io_getevents, vec![]),
         (libc::SYS_io_submit, vec![]),
         (libc::SYS_io_uring_enter, vec![]),
+        (libc::SYS_io_uring_setup, vec![]),
+        (libc::SYS_ioprio_set, vec![]),
         (libc::SYS_lseek, vec![]),
         (libc::SYS_pread64, vec![]),
         (libc::SYS_preadv, vec![]),
         (libc::SYS_pwritev, vec![]),
         (libc::SYS_pwrite64, vec![]),
+        (libc::SYS_recvfrom, vec![]),
         (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_sendto, vec![]),
         (libc::SYS_set_robust_list, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
     ]
@@ -106,6 +243,7 @@ fn virtio_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 
 fn virtio_console_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_accept4, vec![]),
         (libc::SYS_ioctl, create_virtio_console_ioctl_seccomp_rule()),
         (libc::SYS_sched_getaffinity, vec![]),
         (libc::SYS_set_robust_list, vec![]),
@@ -127,7 +265,10 @@ fn virtio_mem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 
 fn virtio_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
+        (libc::SYS_io_uring_enter, vec![]),
+        (libc::SYS_io_uring_setup, vec![]),
         (libc::SYS_readv, vec![]),
+        (libc::SYS_sendto, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
         (libc::SYS_writev, vec![]),
     ]
@@ -142,13 +283,14 @@ fn virtio_net_ctl_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
 }
 
 fn virtio_pmem_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
-    vec![(libc::SYS_fsync, vec![])]
+    vec![(libc::SYS_fallocate, vec![]), (libc::SYS_fsync, vec![])]
 }
 
 fn virtio_rng_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_sched_getaffinity, vec![]),
         (libc::SYS_set_robust_list, vec![]),
+        (libc::SYS_timerfd_settime, vec![]),
     ]
 }
 
@@ -163,6 +305,8 @@ fn virtio_vhost_fs_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_sendto, vec![]),
         (libc::SYS_socket, vec![]),
+        (libc::SYS_timerfd_create, vec![]),
+        (libc::SYS_timerfd_settime, vec![]),
     ]
 }
 
@@ -170,6 +314,10 @@ fn virtio_vhost_net_ctl_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![]
 }
 
+fn create_virtio_vhost_net_ioctl_seccomp_rule() -> Vec<SeccompRule> {
+    or![and![Cond::new(1, ArgLen::Dword, Eq, FIONBIO).unwrap()]]
+}
+
 fn virtio_vhost_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
     vec![
         (libc::SYS_accept4, vec![]),
@@ -177,12 +325,18 @@ fn virtio_vhost_net_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_clock_nanosleep, vec![]),
         (libc::SYS_connect, vec![]),
         (libc::SYS_getcwd, vec![]),
+        (
+            libc::SYS_ioctl,
+            create_virtio_vhost_net_ioctl_seccomp_rule(),
+        ),
         (libc::SYS_listen, vec![]),
         (libc::SYS_nanosleep, vec![]),
         (libc::SYS_recvmsg, vec![]),
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_sendto, vec![]),
         (libc::SYS_socket, vec![]),
+        (libc::SYS_timerfd_create, vec![]),
+        (libc::SYS_timerfd_settime, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_unlink, vec![]),
         #[cfg(target_arch = "aarch64")]
@@ -198,6 +352,8 @@ fn virtio_vhost_block_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_recvmsg, vec![]),
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_socket, vec![]),
+        (libc::SYS_timerfd_create, vec![]),
+        (libc::SYS_timerfd_settime, vec![]),
     ]
 }
 
@@ -212,6 +368,7 @@ fn virtio_vsock_thread_rules() -> Vec<(i64, Vec<SeccompRule>)> {
         (libc::SYS_ioctl, create_vsock_ioctl_seccomp_rule()),
         (libc::SYS_recvfrom, vec![]),
         (libc::SYS_socket, vec![]),
+        (libc::SYS_timerfd_settime, vec![]),
     ]
 }
 
@@ -272,15 +429,28 @@ fn virtio_thread_common() -> Vec<(i64, Vec<SeccompRule>)> {
     ]
 }
 
-/// Generate a BPF program based on the seccomp_action value
+/// Generate a BPF program based on the seccomp_action value, with the
+/// syscalls of the override of the device allowed or denied
 pub fn get_seccomp_filter(
     seccomp_action: &SeccompAction,
     thread_type: Thread,
+    seccomp_override: Option<&SeccompOverride>,
 ) -> Result<BpfProgram, Error> {
+    let mut rules = get_seccomp_rules(thread_type);
+    if let Some(seccomp_override) = seccomp_override {
+        rules.retain(|(syscall, _)| !seccomp_override.deny.contains(syscall));
+        rules.extend(
+            seccomp_override
+                .allow
+                .iter()
+                .map(|syscall| (*syscall, vec![])),
+        );
+    }
+
     match seccomp_action {
         SeccompAction::Allow => Ok(vec![]),
         SeccompAction::Log => SeccompFilter::new(
-            get_seccomp_rules(thread_type).into_iter().collect(),
+            rules.into_iter().collect(),
             SeccompAction::Log,
             SeccompAction::Allow,
             std::env::consts::ARCH.try_into().unwrap(),
@@ -288,7 +458,7 @@ pub fn get_seccomp_filter(
         .and_then(|filter| filter.try_into())
         .map_err(Error::Backend),
         _ => SeccompFilter::new(
-            get_seccomp_rules(thread_type).into_iter().collect(),
+            rules.into_iter().collect(),
             SeccompAction::Trap,
             SeccompAction::Allow,
             std::env::consts::ARCH.try_into().unwrap(),
@@ -297,3 +467,39 @@ pub fn get_seccomp_filter(
         .map_err(Error::Backend),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seccomp_overrides() {
+        assert_eq!(syscall_number("fallocate"), Some(libc::SYS_fallocate));
+        assert_eq!(syscall_number("SYS_fallocate"), None);
+        assert_eq!(syscall_number("reboot"), None);
+
+        let balloon = SeccompOverride {
+            allow: vec![],
+            deny: vec![libc::SYS_fallocate],
+        };
+        let disk = SeccompOverride {
+            allow: vec![libc::SYS_statx],
+            deny: vec![],
+        };
+        set_seccomp_overrides(BTreeMap::from([
+            ("__balloon".to_owned(), balloon.clone()),
+            ("disk".to_owned(), disk.clone()),
+            ("disk_1".to_owned(), SeccompOverride::default()),
+        ]));
+
+        assert_eq!(seccomp_override("__balloon"), Some(balloon));
+        assert_eq!(seccomp_override("disk_q0"), Some(disk));
+        // The longest device identifier wins
+        assert_eq!(
+            seccomp_override("disk_1_q0"),
+            Some(SeccompOverride::default())
+        );
+        assert_eq!(seccomp_override("disk2_q0"), None);
+        assert_eq!(seccomp_override("_balloon"), None);
+    }
+}
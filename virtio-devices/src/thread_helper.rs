@@ -5,7 +5,7 @@
 
 use crate::{
     epoll_helper::EpollHelperError,
-    seccomp_filters::{get_seccomp_filter, Thread},
+    seccomp_filters::{get_seccomp_filter, seccomp_override, Thread},
     ActivateError,
 };
 use seccompiler::{apply_filter, SeccompAction};
@@ -27,8 +27,9 @@ where
     F: FnOnce() -> std::result::Result<(), EpollHelperError>,
     F: Send + 'static,
 {
-    let seccomp_filter = get_seccomp_filter(seccomp_action, thread_type)
-        .map_err(ActivateError::CreateSeccompFilter)?;
+    let seccomp_filter =
+        get_seccomp_filter(seccomp_action, thread_type, seccomp_override(name).as_ref())
+            .map_err(ActivateError::CreateSeccompFilter)?;
 
     let thread_exit_evt = exit_evt
         .try_clone()
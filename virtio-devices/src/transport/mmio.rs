@@ -0,0 +1,746 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-mmio transport, as described by the "Virtio Over MMIO" section of
+//! the virtio specification (version 2, non-legacy devices only).
+//!
+//! The devices are described to the guest through the device tree, or the
+//! kernel command line, rather than being discovered on a PCI bus. This lets
+//! minimal guests boot without any PCI support, at the cost of hotplug, the
+//! virtual IOMMU and MSI-X: every device raises a single legacy interrupt.
+
+use super::pci_device::{QueueState, VirtioPciDeviceActivator};
+use super::{virtqueue_state, VirtqueueState};
+use crate::GuestMemoryMmap;
+use crate::{
+    ActivateResult, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE,
+    DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
+};
+use anyhow::anyhow;
+use byteorder::{ByteOrder, LittleEndian};
+use libc::EFD_NONBLOCK;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use thiserror::Error;
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use virtio_queue::{Queue, QueueT};
+use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::BusDevice;
+use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the register window of a virtio-mmio device.
+pub const VIRTIO_MMIO_DEVICE_SIZE: u64 = 0x1000;
+
+// "virt" in little endian.
+const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const MMIO_VERSION: u32 = 2;
+const MMIO_VENDOR_ID: u32 = 0;
+
+// Registers, offsets from the base of the device.
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const SHM_LEN_LOW: u64 = 0x0b0;
+const SHM_LEN_HIGH: u64 = 0x0b4;
+const SHM_BASE_LOW: u64 = 0x0b8;
+const SHM_BASE_HIGH: u64 = 0x0bc;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+// Bits of the interrupt status register.
+const INTERRUPT_USED_RING: usize = 0x1;
+const INTERRUPT_CONFIG_CHANGED: usize = 0x2;
+
+#[derive(Error, Debug)]
+pub enum VirtioMmioDeviceError {
+    #[error("Failed creating VirtioMmioDevice: {0}")]
+    CreateVirtioMmioDevice(#[source] anyhow::Error),
+}
+pub type Result<T> = std::result::Result<T, VirtioMmioDeviceError>;
+
+#[derive(Versionize)]
+pub struct VirtioMmioDeviceState {
+    device_activated: bool,
+    interrupt_status: usize,
+    driver_status: u32,
+    config_generation: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+    queues: Vec<QueueState>,
+}
+
+impl VersionMapped for VirtioMmioDeviceState {}
+
+pub struct VirtioMmioDevice {
+    id: String,
+
+    // Virtio device reference and status
+    device: Arc<Mutex<dyn VirtioDevice>>,
+    device_activated: Arc<AtomicBool>,
+
+    // Interrupt status register, shared with the interrupt.
+    interrupt_status: Arc<AtomicUsize>,
+    virtio_interrupt: Option<Arc<dyn VirtioInterrupt>>,
+
+    // virtio queues
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+
+    // Guest memory
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+
+    // Registers
+    driver_status: u32,
+    config_generation: u32,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    queue_select: u32,
+
+    // EventFd to signal on to request activation
+    activate_evt: EventFd,
+
+    // Pending activations
+    pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+}
+
+impl VirtioMmioDevice {
+    /// Constructs a new MMIO transport for the given virtio device, raising
+    /// `interrupt` to notify the driver.
+    pub fn new(
+        id: String,
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        device: Arc<Mutex<dyn VirtioDevice>>,
+        interrupt: Arc<dyn InterruptSourceGroup>,
+        activate_evt: EventFd,
+        pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+        snapshot: Option<Snapshot>,
+    ) -> Result<Self> {
+        let locked_device = device.lock().unwrap();
+        let mut queue_evts = Vec::new();
+        for _ in locked_device.queue_max_sizes().iter() {
+            queue_evts.push(EventFd::new(EFD_NONBLOCK).map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed creating eventfd: {}",
+                    e
+                ))
+            })?)
+        }
+
+        let mut queues: Vec<Queue> = locked_device
+            .queue_max_sizes()
+            .iter()
+            .map(|&s| Queue::new(s).unwrap())
+            .collect();
+
+        // Dropping the MutexGuard to unlock the VirtioDevice, which might
+        // need to be activated below.
+        std::mem::drop(locked_device);
+
+        let state: Option<VirtioMmioDeviceState> = snapshot
+            .as_ref()
+            .map(|s| s.to_versioned_state())
+            .transpose()
+            .map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed to get VirtioMmioDeviceState from Snapshot: {}",
+                    e
+                ))
+            })?;
+
+        if let Some(state) = &state {
+            // Update virtqueues indexes for both available and used rings.
+            for (i, queue) in queues.iter_mut().enumerate() {
+                queue.set_size(state.queues[i].size);
+                queue.set_ready(state.queues[i].ready);
+                queue
+                    .try_set_desc_table_address(GuestAddress(state.queues[i].desc_table))
+                    .unwrap();
+                queue
+                    .try_set_avail_ring_address(GuestAddress(state.queues[i].avail_ring))
+                    .unwrap();
+                queue
+                    .try_set_used_ring_address(GuestAddress(state.queues[i].used_ring))
+                    .unwrap();
+                let used_idx = queue
+                    .used_idx(memory.memory().deref(), Ordering::Acquire)
+                    .unwrap()
+                    .0;
+                queue.set_next_avail(used_idx);
+                queue.set_next_used(used_idx);
+            }
+        }
+
+        let interrupt_status = Arc::new(AtomicUsize::new(
+            state.as_ref().map_or(0, |s| s.interrupt_status),
+        ));
+        let mut virtio_mmio_device = VirtioMmioDevice {
+            id,
+            device,
+            device_activated: Arc::new(AtomicBool::new(
+                state.as_ref().map_or(false, |s| s.device_activated),
+            )),
+            interrupt_status: interrupt_status.clone(),
+            virtio_interrupt: Some(Arc::new(VirtioInterruptIntx::new(
+                interrupt_status,
+                interrupt,
+            ))),
+            queues,
+            queue_evts,
+            memory,
+            driver_status: state.as_ref().map_or(DEVICE_INIT, |s| s.driver_status),
+            config_generation: state.as_ref().map_or(0, |s| s.config_generation),
+            device_feature_select: state.as_ref().map_or(0, |s| s.device_feature_select),
+            driver_feature_select: state.as_ref().map_or(0, |s| s.driver_feature_select),
+            queue_select: state.as_ref().map_or(0, |s| s.queue_select),
+            activate_evt,
+            pending_activations,
+        };
+
+        // In case of a restore, we can activate the device, as we know at
+        // this point the virtqueues are in the right state and the device is
+        // ready to be activated, which will spawn each virtio worker thread.
+        if virtio_mmio_device.device_activated.load(Ordering::SeqCst)
+            && virtio_mmio_device.is_driver_ready()
+        {
+            virtio_mmio_device.activate().map_err(|e| {
+                VirtioMmioDeviceError::CreateVirtioMmioDevice(anyhow!(
+                    "Failed activating the device: {}",
+                    e
+                ))
+            })?;
+        }
+
+        Ok(virtio_mmio_device)
+    }
+
+    fn state(&self) -> VirtioMmioDeviceState {
+        VirtioMmioDeviceState {
+            device_activated: self.device_activated.load(Ordering::Acquire),
+            interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation,
+            device_feature_select: self.device_feature_select,
+            driver_feature_select: self.driver_feature_select,
+            queue_select: self.queue_select,
+            queues: self
+                .queues
+                .iter()
+                .map(|q| QueueState {
+                    max_size: q.max_size(),
+                    size: q.size(),
+                    ready: q.ready(),
+                    desc_table: q.desc_table(),
+                    avail_ring: q.avail_ring(),
+                    used_ring: q.used_ring(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn virtio_device(&self) -> Arc<Mutex<dyn VirtioDevice>> {
+        self.device.clone()
+    }
+
+    /// State of the queues of the device, read from the rings the driver
+    /// shares with the threads processing them.
+    pub fn virtqueue_states(&self) -> std::result::Result<Vec<VirtqueueState>, GuestMemoryError> {
+        let memory = self.memory.memory();
+        let cursors = self.device.lock().unwrap().queue_cursors();
+        self.queues
+            .iter()
+            .enumerate()
+            .map(|(index, queue)| {
+                let cursors = cursors.iter().find(|(i, _)| *i == index);
+                virtqueue_state(index, queue, cursors.map(|(_, c)| c.as_ref()), &memory)
+            })
+            .collect()
+    }
+
+    /// Gets the queue events along with the address and value the driver
+    /// writes to notify each queue, all the queues sharing the same register.
+    pub fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64, u32)> {
+        self.queue_evts
+            .iter()
+            .enumerate()
+            .map(|(i, event)| (event, base_addr + QUEUE_NOTIFY, i as u32))
+            .collect()
+    }
+
+    fn is_driver_ready(&self) -> bool {
+        let ready_bits = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
+        self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED == 0
+    }
+
+    /// Determines if the driver has requested the device (re)init / reset itself
+    fn is_driver_init(&self) -> bool {
+        self.driver_status == DEVICE_INIT
+    }
+
+    fn prepare_activator(&mut self, barrier: Option<Arc<Barrier>>) -> VirtioPciDeviceActivator {
+        let mut queues = Vec::new();
+
+        for (queue_index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready() {
+                continue;
+            }
+
+            if !queue.is_valid(self.memory.memory().deref()) {
+                error!("Queue {} is not valid", queue_index);
+            }
+
+            queues.push((
+                queue_index,
+                vm_virtio::clone_queue(queue),
+                self.queue_evts[queue_index].try_clone().unwrap(),
+            ));
+        }
+
+        VirtioPciDeviceActivator {
+            interrupt: self.virtio_interrupt.take(),
+            memory: Some(self.memory.clone()),
+            device: self.device.clone(),
+            queues: Some(queues),
+            device_activated: self.device_activated.clone(),
+            barrier,
+            id: self.id.clone(),
+        }
+    }
+
+    fn activate(&mut self) -> ActivateResult {
+        self.prepare_activator(None).activate()
+    }
+
+    fn needs_activation(&self) -> bool {
+        !self.device_activated.load(Ordering::SeqCst) && self.is_driver_ready()
+    }
+
+    fn with_queue<U, F>(&self, f: F) -> Option<U>
+    where
+        F: FnOnce(&Queue) -> U,
+    {
+        self.queues.get(self.queue_select as usize).map(f)
+    }
+
+    fn with_queue_mut<F: FnOnce(&mut Queue)>(&mut self, f: F) {
+        if let Some(queue) = self.queues.get_mut(self.queue_select as usize) {
+            f(queue);
+        }
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        match offset {
+            MAGIC_VALUE => MMIO_MAGIC_VALUE,
+            VERSION => MMIO_VERSION,
+            DEVICE_ID => self.device.lock().unwrap().device_type(),
+            VENDOR_ID => MMIO_VENDOR_ID,
+            DEVICE_FEATURES => {
+                // Only 64 bits of features (2 pages) are defined for now, so
+                // limit the selector to avoid shifting by 64 or more bits.
+                if self.device_feature_select < 2 {
+                    (self.device.lock().unwrap().features() >> (self.device_feature_select * 32))
+                        as u32
+                } else {
+                    0
+                }
+            }
+            QUEUE_NUM_MAX => u32::from(self.with_queue(|q| q.max_size()).unwrap_or(0)),
+            QUEUE_READY => u32::from(self.with_queue(|q| q.ready()).unwrap_or(false)),
+            INTERRUPT_STATUS => self.interrupt_status.load(Ordering::Acquire) as u32,
+            STATUS => self.driver_status,
+            // No shared memory region, signaled with a length of all ones.
+            SHM_LEN_LOW | SHM_LEN_HIGH | SHM_BASE_LOW | SHM_BASE_HIGH => u32::MAX,
+            CONFIG_GENERATION => self.config_generation,
+            _ => {
+                warn!(
+                    "{}: invalid virtio-mmio register read: 0x{:x}",
+                    self.id, offset
+                );
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u32) {
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_feature_select = value,
+            DRIVER_FEATURES => {
+                if self.driver_feature_select < 2 {
+                    self.device
+                        .lock()
+                        .unwrap()
+                        .ack_features(u64::from(value) << (self.driver_feature_select * 32));
+                } else {
+                    warn!(
+                        "invalid ack_features (page {}, value 0x{:x})",
+                        self.driver_feature_select, value
+                    );
+                }
+            }
+            DRIVER_FEATURES_SEL => self.driver_feature_select = value,
+            QUEUE_SEL => self.queue_select = value,
+            QUEUE_NUM => self.with_queue_mut(|q| q.set_size(value as u16)),
+            QUEUE_READY => self.with_queue_mut(|q| q.set_ready(value == 1)),
+            QUEUE_NOTIFY => {
+                // Handled with ioeventfds, unless they aren't registered.
+                if let Some(queue_evt) = self.queue_evts.get(value as usize) {
+                    queue_evt.write(1).ok();
+                }
+            }
+            INTERRUPT_ACK => {
+                self.interrupt_status
+                    .fetch_and(!(value as usize), Ordering::AcqRel);
+            }
+            STATUS => self.driver_status = value,
+            QUEUE_DESC_LOW => self.with_queue_mut(|q| q.set_desc_table_address(Some(value), None)),
+            QUEUE_DESC_HIGH => self.with_queue_mut(|q| q.set_desc_table_address(None, Some(value))),
+            QUEUE_DRIVER_LOW => {
+                self.with_queue_mut(|q| q.set_avail_ring_address(Some(value), None))
+            }
+            QUEUE_DRIVER_HIGH => {
+                self.with_queue_mut(|q| q.set_avail_ring_address(None, Some(value)))
+            }
+            QUEUE_DEVICE_LOW => self.with_queue_mut(|q| q.set_used_ring_address(Some(value), None)),
+            QUEUE_DEVICE_HIGH => {
+                self.with_queue_mut(|q| q.set_used_ring_address(None, Some(value)))
+            }
+            _ => {
+                warn!(
+                    "{}: invalid virtio-mmio register write: 0x{:x}",
+                    self.id, offset
+                );
+            }
+        }
+    }
+}
+
+impl BusDevice for VirtioMmioDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if offset >= CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .read_config(offset - CONFIG, data);
+            return;
+        }
+
+        // The registers are only accessed as aligned 32 bits values.
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!(
+                "{}: invalid virtio-mmio register read: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+            return;
+        }
+
+        LittleEndian::write_u32(data, self.read_register(offset));
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if offset >= CONFIG {
+            self.device
+                .lock()
+                .unwrap()
+                .write_config(offset - CONFIG, data);
+            self.config_generation = self.config_generation.wrapping_add(1);
+            return None;
+        }
+
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!(
+                "{}: invalid virtio-mmio register write: 0x{:x}, len {}",
+                self.id,
+                offset,
+                data.len()
+            );
+            return None;
+        }
+
+        self.write_register(offset, LittleEndian::read_u32(data));
+
+        // Try and activate the device if the driver status has changed
+        if self.needs_activation() {
+            let barrier = Arc::new(Barrier::new(2));
+            let activator = self.prepare_activator(Some(barrier.clone()));
+            self.pending_activations.lock().unwrap().push(activator);
+            info!(
+                "{}: Needs activation; writing to activate event fd",
+                self.id
+            );
+            self.activate_evt.write(1).ok();
+            info!("{}: Needs activation; returning barrier", self.id);
+            return Some(barrier);
+        }
+
+        // Device has been reset by the driver
+        if self.device_activated.load(Ordering::SeqCst) && self.is_driver_init() {
+            let mut device = self.device.lock().unwrap();
+            if let Some(virtio_interrupt) = device.reset() {
+                // Upon reset the device returns its interrupt EventFD
+                self.virtio_interrupt = Some(virtio_interrupt);
+                self.device_activated.store(false, Ordering::SeqCst);
+
+                // Reset queue readiness, queue sizes, selected_queue and
+                // interrupt status as per spec for reset
+                self.queues.iter_mut().for_each(Queue::reset);
+                self.queue_select = 0;
+                self.interrupt_status.store(0, Ordering::SeqCst);
+            } else {
+                error!("Attempt to reset device when not implemented in underlying device");
+                self.driver_status = DEVICE_FAILED;
+            }
+        }
+
+        None
+    }
+}
+
+impl Pausable for VirtioMmioDevice {}
+
+impl Snapshottable for VirtioMmioDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_versioned_state(&self.state())
+    }
+}
+impl Transportable for VirtioMmioDevice {}
+impl Migratable for VirtioMmioDevice {}
+
+/// Interrupt of a virtio-mmio device, a single legacy interrupt shared by the
+/// queues and the configuration changes, the driver finding out which from
+/// the interrupt status register.
+pub struct VirtioInterruptIntx {
+    interrupt_status: Arc<AtomicUsize>,
+    interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+}
+
+impl VirtioInterruptIntx {
+    pub fn new(
+        interrupt_status: Arc<AtomicUsize>,
+        interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    ) -> Self {
+        VirtioInterruptIntx {
+            interrupt_status,
+            interrupt_source_group,
+        }
+    }
+}
+
+impl VirtioInterrupt for VirtioInterruptIntx {
+    fn trigger(&self, int_type: VirtioInterruptType) -> std::result::Result<(), std::io::Error> {
+        let status = match int_type {
+            VirtioInterruptType::Config => INTERRUPT_CONFIG_CHANGED,
+            VirtioInterruptType::Queue(_) => INTERRUPT_USED_RING,
+        };
+        self.interrupt_status.fetch_or(status, Ordering::AcqRel);
+
+        self.interrupt_source_group.trigger(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActivateError;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    struct DummyDevice {
+        features: u64,
+        acked_features: u64,
+    }
+
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            crate::VirtioDeviceType::Rng as u32
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16]
+        }
+
+        fn features(&self) -> u64 {
+            self.features
+        }
+
+        fn ack_features(&mut self, value: u64) {
+            self.acked_features |= value;
+        }
+
+        fn read_config(&self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<(usize, Queue, EventFd)>,
+        ) -> ActivateResult {
+            Err(ActivateError::BadActivate)
+        }
+    }
+
+    struct DummyInterrupt;
+
+    impl InterruptSourceGroup for DummyInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    fn read(device: &mut VirtioMmioDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(0, offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write(device: &mut VirtioMmioDevice, offset: u64, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        device.write(0, offset, &data);
+    }
+
+    #[test]
+    fn test_virtio_mmio_device() {
+        let memory = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        );
+        let virtio_device = Arc::new(Mutex::new(DummyDevice {
+            features: (1 << crate::VIRTIO_F_VERSION_1) | (1 << 3),
+            acked_features: 0,
+        }));
+        let mut device = VirtioMmioDevice::new(
+            "_virtio-mmio-rng".to_owned(),
+            memory,
+            virtio_device.clone(),
+            Arc::new(DummyInterrupt),
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            Arc::new(Mutex::new(Vec::new())),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(read(&mut device, MAGIC_VALUE), MMIO_MAGIC_VALUE);
+        assert_eq!(read(&mut device, VERSION), MMIO_VERSION);
+        assert_eq!(
+            read(&mut device, DEVICE_ID),
+            crate::VirtioDeviceType::Rng as u32
+        );
+
+        // Features are read and acknowledged 32 bits at a time
+        assert_eq!(read(&mut device, DEVICE_FEATURES), 1 << 3);
+        write(&mut device, DEVICE_FEATURES_SEL, 1);
+        assert_eq!(read(&mut device, DEVICE_FEATURES), 1);
+        write(&mut device, DRIVER_FEATURES_SEL, 1);
+        write(&mut device, DRIVER_FEATURES, 1);
+        assert_eq!(
+            virtio_device.lock().unwrap().acked_features,
+            1 << crate::VIRTIO_F_VERSION_1
+        );
+
+        write(&mut device, QUEUE_SEL, 0);
+        assert_eq!(read(&mut device, QUEUE_NUM_MAX), 16);
+        write(&mut device, QUEUE_NUM, 8);
+        write(&mut device, QUEUE_DESC_LOW, 0x1000);
+        write(&mut device, QUEUE_DRIVER_LOW, 0x2000);
+        write(&mut device, QUEUE_DEVICE_LOW, 0x3000);
+        write(&mut device, QUEUE_READY, 1);
+        assert_eq!(read(&mut device, QUEUE_READY), 1);
+        assert_eq!(device.queues[0].size(), 8);
+        assert_eq!(device.queues[0].used_ring(), 0x3000);
+        // Out of range queues are ignored
+        write(&mut device, QUEUE_SEL, 1);
+        assert_eq!(read(&mut device, QUEUE_NUM_MAX), 0);
+
+        // Notifications all go through the same register
+        let ioeventfds = device.ioeventfds(0x1000_0000);
+        assert_eq!(ioeventfds.len(), 1);
+        assert_eq!(ioeventfds[0].1, 0x1000_0000 + QUEUE_NOTIFY);
+        assert_eq!(ioeventfds[0].2, 0);
+
+        // The interrupt status is set on trigger, until acknowledged
+        device
+            .virtio_interrupt
+            .as_ref()
+            .unwrap()
+            .trigger(VirtioInterruptType::Queue(0))
+            .unwrap();
+        assert_eq!(
+            read(&mut device, INTERRUPT_STATUS),
+            INTERRUPT_USED_RING as u32
+        );
+        write(&mut device, INTERRUPT_ACK, INTERRUPT_USED_RING as u32);
+        assert_eq!(read(&mut device, INTERRUPT_STATUS), 0);
+
+        // The device configuration follows the registers
+        let mut data = [0u8; 2];
+        device.read(0, CONFIG + 4, &mut data);
+        assert_eq!(data, [4, 4]);
+
+        // Activation is requested once the driver is ready
+        for status in [
+            DEVICE_ACKNOWLEDGE,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK,
+        ] {
+            write(&mut device, STATUS, status);
+        }
+        assert!(device.pending_activations.lock().unwrap().is_empty());
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(
+            &mut data,
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK,
+        );
+        assert!(device.write(0, STATUS, &data).is_some());
+        assert_eq!(device.pending_activations.lock().unwrap().len(), 1);
+    }
+}
@@ -2,12 +2,200 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::{GuestMemoryMmap, QueueCursors};
+use serde::{Deserialize, Serialize};
+use virtio_queue::{Queue, QueueT};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError};
 use vmm_sys_util::eventfd::EventFd;
+mod mmio;
 mod pci_common_config;
 mod pci_device;
+pub use mmio::{
+    VirtioInterruptIntx, VirtioMmioDevice, VirtioMmioDeviceError, VIRTIO_MMIO_DEVICE_SIZE,
+};
 pub use pci_common_config::{VirtioPciCommonConfig, VIRTIO_PCI_COMMON_CONFIG_ID};
 pub use pci_device::{VirtioPciDevice, VirtioPciDeviceActivator, VirtioPciDeviceError};
 
 pub trait VirtioTransport {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
 }
+
+/// State of a virtqueue, as shared by the driver and the device through the
+/// rings in guest memory, and as published by the thread processing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VirtqueueState {
+    pub index: usize,
+    pub size: u16,
+    pub ready: bool,
+    pub avail_idx: u16,
+    pub avail_flags: u16,
+    pub used_idx: u16,
+    pub used_flags: u16,
+    /// Used index the driver asks to be notified at, with VIRTIO_F_EVENT_IDX
+    pub used_event: u16,
+    /// Available index the device asks to be notified at, with
+    /// VIRTIO_F_EVENT_IDX
+    pub avail_event: u16,
+    /// Next available index the device will pop
+    pub next_avail: Option<u16>,
+    /// Next used index the device will push
+    pub next_used: Option<u16>,
+    /// Used index the driver was last notified at
+    pub signalled_used: Option<u16>,
+    /// Number of descriptor chains popped by the device and not used yet
+    pub in_flight: Option<u16>,
+}
+
+fn read_u16(memory: &GuestMemoryMmap, addr: u64) -> Result<u16, GuestMemoryError> {
+    memory.read_obj::<u16>(GuestAddress(addr)).map(u16::from_le)
+}
+
+/// Read the state of the given queue from the rings in guest memory, and from
+/// the cursors of the thread processing it when it runs in the VMM.
+pub fn virtqueue_state(
+    index: usize,
+    queue: &Queue,
+    cursors: Option<&QueueCursors>,
+    memory: &GuestMemoryMmap,
+) -> Result<VirtqueueState, GuestMemoryError> {
+    let mut state = VirtqueueState {
+        index,
+        size: queue.size(),
+        ready: queue.ready(),
+        ..Default::default()
+    };
+    if !state.ready || state.size == 0 {
+        return Ok(state);
+    }
+
+    let size = u64::from(state.size);
+    let avail_ring = queue.avail_ring();
+    let used_ring = queue.used_ring();
+    state.avail_flags = read_u16(memory, avail_ring)?;
+    state.avail_idx = read_u16(memory, avail_ring + 2)?;
+    state.used_event = read_u16(memory, avail_ring + 4 + 2 * size)?;
+    state.used_flags = read_u16(memory, used_ring)?;
+    state.used_idx = read_u16(memory, used_ring + 2)?;
+    state.avail_event = read_u16(memory, used_ring + 4 + 8 * size)?;
+
+    if let Some(cursors) = cursors {
+        let next_avail = cursors.next_avail();
+        let next_used = cursors.next_used();
+        state.next_avail = Some(next_avail);
+        state.next_used = Some(next_used);
+        state.signalled_used = cursors.signalled_used();
+        state.in_flight = Some(next_avail.wrapping_sub(next_used));
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUEUE_SIZE: u16 = 16;
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+
+    fn write_u16(memory: &GuestMemoryMmap, addr: u64, value: u16) {
+        memory
+            .write_obj::<u16>(value.to_le(), GuestAddress(addr))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_virtqueue_state() {
+        let memory = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut queue = Queue::new(QUEUE_SIZE).unwrap();
+
+        // Nothing is read from the rings until the driver sets the queue up
+        assert_eq!(
+            virtqueue_state(1, &queue, None, &memory).unwrap(),
+            VirtqueueState {
+                index: 1,
+                size: QUEUE_SIZE,
+                ..Default::default()
+            }
+        );
+
+        queue
+            .try_set_desc_table_address(GuestAddress(DESC_TABLE))
+            .unwrap();
+        queue
+            .try_set_avail_ring_address(GuestAddress(AVAIL_RING))
+            .unwrap();
+        queue
+            .try_set_used_ring_address(GuestAddress(USED_RING))
+            .unwrap();
+        queue.set_ready(true);
+
+        let size = u64::from(QUEUE_SIZE);
+        write_u16(&memory, AVAIL_RING, 1);
+        write_u16(&memory, AVAIL_RING + 2, 5);
+        write_u16(&memory, AVAIL_RING + 4 + 2 * size, 3);
+        write_u16(&memory, USED_RING, 1);
+        write_u16(&memory, USED_RING + 2, 2);
+        write_u16(&memory, USED_RING + 4 + 8 * size, 4);
+
+        let rings = VirtqueueState {
+            index: 1,
+            size: QUEUE_SIZE,
+            ready: true,
+            avail_idx: 5,
+            avail_flags: 1,
+            used_idx: 2,
+            used_flags: 1,
+            used_event: 3,
+            avail_event: 4,
+            ..Default::default()
+        };
+        assert_eq!(virtqueue_state(1, &queue, None, &memory).unwrap(), rings);
+
+        // The handler popped all the chains and used two of them
+        let cursors = QueueCursors::default();
+        queue.set_next_avail(5);
+        queue.set_next_used(2);
+        cursors.update(&queue);
+        assert_eq!(
+            virtqueue_state(1, &queue, Some(&cursors), &memory).unwrap(),
+            VirtqueueState {
+                next_avail: Some(5),
+                next_used: Some(2),
+                signalled_used: None,
+                in_flight: Some(3),
+                ..rings.clone()
+            }
+        );
+
+        // Then used two more and notified the driver
+        queue.set_next_used(4);
+        cursors.signalled(&queue);
+        assert_eq!(
+            virtqueue_state(1, &queue, Some(&cursors), &memory).unwrap(),
+            VirtqueueState {
+                next_avail: Some(5),
+                next_used: Some(4),
+                signalled_used: Some(4),
+                in_flight: Some(1),
+                ..rings.clone()
+            }
+        );
+
+        // The chains in flight are counted across the wrap of the indexes
+        queue.set_next_avail(1);
+        queue.set_next_used(u16::MAX);
+        cursors.update(&queue);
+        assert_eq!(
+            virtqueue_state(1, &queue, Some(&cursors), &memory).unwrap(),
+            VirtqueueState {
+                next_avail: Some(1),
+                next_used: Some(u16::MAX),
+                signalled_used: Some(4),
+                in_flight: Some(2),
+                ..rings
+            }
+        );
+    }
+}
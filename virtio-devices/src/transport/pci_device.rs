@@ -6,12 +6,15 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use crate::transport::{VirtioPciCommonConfig, VirtioTransport, VIRTIO_PCI_COMMON_CONFIG_ID};
+use crate::transport::{
+    virtqueue_state, VirtioPciCommonConfig, VirtioTransport, VirtqueueState,
+    VIRTIO_PCI_COMMON_CONFIG_ID,
+};
 use crate::GuestMemoryMmap;
 use crate::{
-    ActivateResult, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
-    DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK,
-    DEVICE_INIT,
+    ActivateResult, PciIdsConfig, VirtioDevice, VirtioDeviceType, VirtioInterrupt,
+    VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
+    DEVICE_FEATURES_OK, DEVICE_INIT,
 };
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
@@ -36,7 +39,10 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
 use vm_device::{BusDevice, PciBarType, Resource};
-use vm_memory::{Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, Le32};
+use vm_memory::{
+    Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
+    Le16, Le32,
+};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
 };
@@ -221,6 +227,50 @@ struct VirtioPciCfgCapInfo {
     cap: VirtioPciCfgCap,
 }
 
+// PCI power management capability, only supporting the D0 and D3hot states.
+const PCI_PM_CAP_VERSION: u16 = 3;
+const PCI_PM_CAP_PME_D0: u16 = 1 << 11;
+const PCI_PM_CAP_PME_D3HOT: u16 = 1 << 14;
+const PCI_PM_CTRL_STATE_MASK: u16 = 0x3;
+const PCI_PM_CTRL_NO_SOFT_RESET: u16 = 1 << 3;
+const PCI_PM_CTRL_PME_ENABLE: u16 = 1 << 8;
+const PCI_PM_CTRL_PME_STATUS: u16 = 1 << 15;
+const PCI_PM_STATE_D0: u16 = 0;
+const PCI_PM_STATE_D3HOT: u16 = 3;
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+struct PciPmCap {
+    pmc: Le16,
+    pmcsr: Le16,
+    bridge_ext: u8,
+    data: u8,
+}
+// SAFETY: All members are simple numbers and any value is valid.
+unsafe impl ByteValued for PciPmCap {}
+
+impl PciCapability for PciPmCap {
+    fn bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+
+    fn id(&self) -> PciCapabilityId {
+        PciCapabilityId::PowerManagement
+    }
+}
+
+impl PciPmCap {
+    fn new() -> Self {
+        PciPmCap {
+            pmc: Le16::from(PCI_PM_CAP_VERSION | PCI_PM_CAP_PME_D0 | PCI_PM_CAP_PME_D3HOT),
+            // The device state is preserved when going back to D0.
+            pmcsr: Le16::from(PCI_PM_CTRL_NO_SOFT_RESET),
+            ..Default::default()
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 pub enum PciVirtioSubclass {
@@ -264,13 +314,13 @@ const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
 #[derive(Versionize)]
-struct QueueState {
-    max_size: u16,
-    size: u16,
-    ready: bool,
-    desc_table: u64,
-    avail_ring: u64,
-    used_ring: u64,
+pub(super) struct QueueState {
+    pub(super) max_size: u16,
+    pub(super) size: u16,
+    pub(super) ready: bool,
+    pub(super) desc_table: u64,
+    pub(super) avail_ring: u64,
+    pub(super) used_ring: u64,
 }
 
 #[derive(Versionize)]
@@ -278,18 +328,21 @@ pub struct VirtioPciDeviceState {
     device_activated: bool,
     queues: Vec<QueueState>,
     interrupt_status: usize,
+    pm_cap_reg_idx: Option<usize>,
+    pm_control: u16,
 }
 
 impl VersionMapped for VirtioPciDeviceState {}
 
+// Also used by the virtio-mmio transport, activating the devices the same way.
 pub struct VirtioPciDeviceActivator {
-    interrupt: Option<Arc<dyn VirtioInterrupt>>,
-    memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
-    device: Arc<Mutex<dyn VirtioDevice>>,
-    device_activated: Arc<AtomicBool>,
-    queues: Option<Vec<(usize, Queue, EventFd)>>,
-    barrier: Option<Arc<Barrier>>,
-    id: String,
+    pub(super) interrupt: Option<Arc<dyn VirtioInterrupt>>,
+    pub(super) memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    pub(super) device: Arc<Mutex<dyn VirtioDevice>>,
+    pub(super) device_activated: Arc<AtomicBool>,
+    pub(super) queues: Option<Vec<(usize, Queue, EventFd)>>,
+    pub(super) barrier: Option<Arc<Barrier>>,
+    pub(super) id: String,
 }
 
 impl VirtioPciDeviceActivator {
@@ -363,6 +416,11 @@ pub struct VirtioPciDevice {
     // a device.
     cap_pci_cfg_info: VirtioPciCfgCapInfo,
 
+    // Register of the power management capability holding the PMCSR, which
+    // is emulated through pm_control as it's shared with the interrupts.
+    pm_cap_reg_idx: Option<usize>,
+    pm_control: Arc<AtomicU16>,
+
     // Details of bar regions to free
     bar_regions: Vec<PciBarConfiguration>,
 
@@ -391,6 +449,7 @@ impl VirtioPciDevice {
         use_64bit_bar: bool,
         dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
         pending_activations: Arc<Mutex<Vec<VirtioPciDeviceActivator>>>,
+        pci_ids: PciIdsConfig,
         snapshot: Option<Snapshot>,
     ) -> Result<Self> {
         let mut locked_device = device.lock().unwrap();
@@ -482,13 +541,14 @@ impl VirtioPciDevice {
         let configuration = PciConfiguration::new(
             VIRTIO_PCI_VENDOR_ID,
             pci_device_id,
-            0x1, // For modern virtio-PCI devices
+            // Revision 0x1 by default, for modern virtio-PCI devices
+            pci_ids.revision_id.unwrap_or(0x1),
             class,
             subclass,
             None,
             PciHeaderType::Device,
-            VIRTIO_PCI_VENDOR_ID,
-            pci_device_id,
+            pci_ids.subsystem_vendor_id.unwrap_or(VIRTIO_PCI_VENDOR_ID),
+            pci_ids.subsystem_id.unwrap_or(pci_device_id),
             msix_config_clone,
             pci_configuration_state,
         );
@@ -530,38 +590,44 @@ impl VirtioPciDevice {
                 ))
             })?;
 
-        let (device_activated, interrupt_status) = if let Some(state) = state {
-            // Update virtqueues indexes for both available and used rings.
-            for (i, queue) in queues.iter_mut().enumerate() {
-                queue.set_size(state.queues[i].size);
-                queue.set_ready(state.queues[i].ready);
-                queue
-                    .try_set_desc_table_address(GuestAddress(state.queues[i].desc_table))
-                    .unwrap();
-                queue
-                    .try_set_avail_ring_address(GuestAddress(state.queues[i].avail_ring))
-                    .unwrap();
-                queue
-                    .try_set_used_ring_address(GuestAddress(state.queues[i].used_ring))
-                    .unwrap();
-                queue.set_next_avail(
+        let (device_activated, interrupt_status, pm_cap_reg_idx, pm_control) =
+            if let Some(state) = state {
+                // Update virtqueues indexes for both available and used rings.
+                for (i, queue) in queues.iter_mut().enumerate() {
+                    queue.set_size(state.queues[i].size);
+                    queue.set_ready(state.queues[i].ready);
                     queue
-                        .used_idx(memory.memory().deref(), Ordering::Acquire)
-                        .unwrap()
-                        .0,
-                );
-                queue.set_next_used(
+                        .try_set_desc_table_address(GuestAddress(state.queues[i].desc_table))
+                        .unwrap();
                     queue
-                        .used_idx(memory.memory().deref(), Ordering::Acquire)
-                        .unwrap()
-                        .0,
-                );
-            }
+                        .try_set_avail_ring_address(GuestAddress(state.queues[i].avail_ring))
+                        .unwrap();
+                    queue
+                        .try_set_used_ring_address(GuestAddress(state.queues[i].used_ring))
+                        .unwrap();
+                    queue.set_next_avail(
+                        queue
+                            .used_idx(memory.memory().deref(), Ordering::Acquire)
+                            .unwrap()
+                            .0,
+                    );
+                    queue.set_next_used(
+                        queue
+                            .used_idx(memory.memory().deref(), Ordering::Acquire)
+                            .unwrap()
+                            .0,
+                    );
+                }
 
-            (state.device_activated, state.interrupt_status)
-        } else {
-            (false, 0)
-        };
+                (
+                    state.device_activated,
+                    state.interrupt_status,
+                    state.pm_cap_reg_idx,
+                    state.pm_control,
+                )
+            } else {
+                (false, 0, None, PCI_PM_CTRL_NO_SOFT_RESET)
+            };
 
         // Dropping the MutexGuard to unlock the VirtioDevice. This is required
         // in the context of a restore given the device might require some
@@ -586,6 +652,8 @@ impl VirtioPciDevice {
             use_64bit_bar,
             interrupt_source_group,
             cap_pci_cfg_info: VirtioPciCfgCapInfo::default(),
+            pm_cap_reg_idx,
+            pm_control: Arc::new(AtomicU16::new(pm_control)),
             bar_regions: vec![],
             activate_evt,
             dma_handler,
@@ -598,6 +666,7 @@ impl VirtioPciDevice {
                 virtio_pci_device.common_config.msix_config.clone(),
                 virtio_pci_device.common_config.msix_queues.clone(),
                 virtio_pci_device.interrupt_source_group.clone(),
+                virtio_pci_device.pm_control.clone(),
             )));
         }
 
@@ -622,6 +691,8 @@ impl VirtioPciDevice {
         VirtioPciDeviceState {
             device_activated: self.device_activated.load(Ordering::Acquire),
             interrupt_status: self.interrupt_status.load(Ordering::Acquire),
+            pm_cap_reg_idx: self.pm_cap_reg_idx,
+            pm_control: self.pm_control.load(Ordering::Acquire),
             queues: self
                 .queues
                 .iter()
@@ -728,10 +799,74 @@ impl VirtioPciDevice {
                 .map_err(PciDeviceError::CapabilitiesSetup)?;
         }
 
+        let pm_cap = PciPmCap::new();
+        let pm_cap_offset = self
+            .configuration
+            .add_capability(&pm_cap)
+            .map_err(PciDeviceError::CapabilitiesSetup)?;
+        // The PMCSR follows the PMC, and capabilities are dword aligned.
+        self.pm_cap_reg_idx = Some(pm_cap_offset / 4 + 1);
+
         self.settings_bar = settings_bar;
         Ok(())
     }
 
+    fn write_pm_control(&mut self, offset: u64, data: &[u8]) {
+        let mut value = 0u16;
+        let mut written = 0u16;
+        for (i, byte) in data.iter().enumerate() {
+            let shift = (offset as usize + i) * 8;
+            // The upper half of the register is read-only.
+            if shift >= 16 {
+                break;
+            }
+            value |= (*byte as u16) << shift;
+            written |= 0xff << shift;
+        }
+
+        let mut old_state = PCI_PM_STATE_D0;
+        let mut new_state = PCI_PM_STATE_D0;
+        // The PME status can be set concurrently by the interrupts.
+        let _ = self
+            .pm_control
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old| {
+                old_state = old & PCI_PM_CTRL_STATE_MASK;
+                new_state = if written & PCI_PM_CTRL_STATE_MASK != 0 {
+                    match value & PCI_PM_CTRL_STATE_MASK {
+                        // D1 and D2 aren't supported, the state doesn't change.
+                        s @ (PCI_PM_STATE_D0 | PCI_PM_STATE_D3HOT) => s,
+                        _ => old_state,
+                    }
+                } else {
+                    old_state
+                };
+                let pme_enable = if written & PCI_PM_CTRL_PME_ENABLE != 0 {
+                    value & PCI_PM_CTRL_PME_ENABLE
+                } else {
+                    old & PCI_PM_CTRL_PME_ENABLE
+                };
+                // The PME status is cleared by writing 1 to it.
+                let pme_status = old & PCI_PM_CTRL_PME_STATUS & !(value & written);
+
+                Some(PCI_PM_CTRL_NO_SOFT_RESET | new_state | pme_enable | pme_status)
+            });
+
+        if old_state != new_state {
+            info!("{}: Entering D{}", self.id, new_state);
+        }
+
+        // Deliver the interrupts which got pending while in D3hot.
+        if old_state == PCI_PM_STATE_D3HOT && new_state == PCI_PM_STATE_D0 {
+            if let Some(msix_config) = &self.msix_config {
+                msix_config.lock().unwrap().inject_pending();
+            }
+        }
+    }
+
+    fn in_d3hot(&self) -> bool {
+        self.pm_control.load(Ordering::Acquire) & PCI_PM_CTRL_STATE_MASK == PCI_PM_STATE_D3HOT
+    }
+
     fn read_cap_pci_cfg(&mut self, offset: usize, mut data: &mut [u8]) {
         let cap_slice = self.cap_pci_cfg_info.cap.as_slice();
         let data_len = data.len();
@@ -780,6 +915,73 @@ impl VirtioPciDevice {
         self.device.clone()
     }
 
+    /// Refresh the shared memory capabilities so that they describe the
+    /// regions currently exposed by the device. The guest driver picks up
+    /// the new values the next time it probes the device.
+    pub fn update_shm_capabilities(&mut self) -> std::result::Result<(), PciDeviceError> {
+        let shm_list = match self.device.lock().unwrap().get_shm_regions() {
+            Some(shm_list) => shm_list,
+            None => return Ok(()),
+        };
+
+        for cap_offset in self
+            .configuration
+            .capability_offsets(PciCapabilityId::VendorSpecific)
+        {
+            // Skip the generic header to reach the cfg_type and id fields.
+            let cap_reg = self.configuration.read_reg(cap_offset / 4);
+            if (cap_reg >> 24) as u8 != PciCapabilityType::SharedMemory as u8 {
+                continue;
+            }
+            let idx = (self.configuration.read_reg(cap_offset / 4 + 1) >> 8) & 0xff;
+            if let Some(shm) = shm_list.region_list.get(idx as usize) {
+                let shm_cap = VirtioPciCap64::new(
+                    PciCapabilityType::SharedMemory,
+                    VIRTIO_SHM_BAR_INDEX as u8,
+                    idx as u8,
+                    shm.offset,
+                    shm.len,
+                );
+                self.configuration
+                    .update_capability(cap_offset, &shm_cap)
+                    .map_err(PciDeviceError::CapabilitiesSetup)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// State of the queues of the device, read from the rings the driver
+    /// shares with the threads processing them.
+    pub fn virtqueue_states(&self) -> Result<Vec<VirtqueueState>, GuestMemoryError> {
+        let memory = self.memory.memory();
+        let cursors = self.device.lock().unwrap().queue_cursors();
+        self.queues
+            .iter()
+            .enumerate()
+            .map(|(index, queue)| {
+                let cursors = cursors.iter().find(|(i, _)| *i == index);
+                virtqueue_state(index, queue, cursors.map(|(_, c)| c.as_ref()), &memory)
+            })
+            .collect()
+    }
+
+    /// Reset the device left activated by the driver, stopping the threads
+    /// processing its queues, as when it gets unplugged without the guest
+    /// releasing it first.
+    pub fn reset_device(&mut self) {
+        if !self.device_activated.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(virtio_interrupt) = self.device.lock().unwrap().reset() {
+            self.virtio_interrupt = Some(virtio_interrupt);
+        }
+        self.device_activated.store(false, Ordering::SeqCst);
+        self.queues.iter_mut().for_each(Queue::reset);
+        self.common_config.queue_select = 0;
+    }
+
     fn prepare_activator(&mut self, barrier: Option<Arc<Barrier>>) -> VirtioPciDeviceActivator {
         let mut queues = Vec::new();
 
@@ -844,6 +1046,7 @@ pub struct VirtioInterruptMsix {
     config_vector: Arc<AtomicU16>,
     queues_vectors: Arc<Mutex<Vec<u16>>>,
     interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+    pm_control: Arc<AtomicU16>,
 }
 
 impl VirtioInterruptMsix {
@@ -852,12 +1055,14 @@ impl VirtioInterruptMsix {
         config_vector: Arc<AtomicU16>,
         queues_vectors: Arc<Mutex<Vec<u16>>>,
         interrupt_source_group: Arc<dyn InterruptSourceGroup>,
+        pm_control: Arc<AtomicU16>,
     ) -> Self {
         VirtioInterruptMsix {
             msix_config,
             config_vector,
             queues_vectors,
             interrupt_source_group,
+            pm_control,
         }
     }
 }
@@ -887,6 +1092,18 @@ impl VirtioInterrupt for VirtioInterruptMsix {
             return Ok(());
         }
 
+        // A device in D3hot can't send interrupts, it signals a wake event
+        // instead, the interrupt being delivered once back in D0.
+        let pm_control = self.pm_control.load(Ordering::Acquire);
+        if pm_control & PCI_PM_CTRL_STATE_MASK == PCI_PM_STATE_D3HOT {
+            config.set_pba_bit(vector, false);
+            if pm_control & PCI_PM_CTRL_PME_ENABLE != 0 {
+                self.pm_control
+                    .fetch_or(PCI_PM_CTRL_PME_STATUS, Ordering::AcqRel);
+            }
+            return Ok(());
+        }
+
         self.interrupt_source_group
             .trigger(vector as InterruptIndex)
     }
@@ -921,6 +1138,9 @@ impl PciDevice for VirtioPciDevice {
         {
             let offset = base + offset as usize - self.cap_pci_cfg_info.offset;
             self.write_cap_pci_cfg(offset, data)
+        } else if Some(reg_idx) == self.pm_cap_reg_idx {
+            self.write_pm_control(offset, data);
+            None
         } else {
             self.configuration
                 .write_config_register(reg_idx, offset, data);
@@ -940,6 +1160,8 @@ impl PciDevice for VirtioPciDevice {
             let mut data = [0u8; 4];
             self.read_cap_pci_cfg(offset, &mut data);
             u32::from_le_bytes(data)
+        } else if Some(reg_idx) == self.pm_cap_reg_idx {
+            self.pm_control.load(Ordering::Acquire) as u32
         } else {
             self.configuration.read_reg(reg_idx)
         }
@@ -1112,6 +1334,13 @@ impl PciDevice for VirtioPciDevice {
     }
 
     fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        // The device doesn't decode the accesses to its BARs in D3hot, the
+        // reads completing with all ones.
+        if self.in_d3hot() {
+            data.fill(0xff);
+            return;
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -1157,6 +1386,10 @@ impl PciDevice for VirtioPciDevice {
     }
 
     fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if self.in_d3hot() {
+            return None;
+        }
+
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.write(
                 o - COMMON_CONFIG_BAR_OFFSET,
@@ -1292,3 +1525,156 @@ impl Snapshottable for VirtioPciDevice {
 }
 impl Transportable for VirtioPciDevice {}
 impl Migratable for VirtioPciDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActivateError;
+    use vm_device::interrupt::InterruptSourceConfig;
+
+    struct DummyDevice;
+
+    impl VirtioDevice for DummyDevice {
+        fn device_type(&self) -> u32 {
+            VirtioDeviceType::Rng as u32
+        }
+
+        fn queue_max_sizes(&self) -> &[u16] {
+            &[16]
+        }
+
+        fn activate(
+            &mut self,
+            _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+            _interrupt_evt: Arc<dyn VirtioInterrupt>,
+            _queues: Vec<(usize, Queue, EventFd)>,
+        ) -> ActivateResult {
+            Err(ActivateError::BadActivate)
+        }
+    }
+
+    struct DummyInterrupt {
+        triggers: Arc<AtomicUsize>,
+    }
+
+    impl InterruptSourceGroup for DummyInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::result::Result<(), std::io::Error> {
+            self.triggers.fetch_add(1, Ordering::AcqRel);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+            _masked: bool,
+            _set_gsi: bool,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    struct DummyInterruptManager {
+        triggers: Arc<AtomicUsize>,
+    }
+
+    impl InterruptManager for DummyInterruptManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> std::result::Result<Arc<dyn InterruptSourceGroup>, std::io::Error> {
+            Ok(Arc::new(DummyInterrupt {
+                triggers: self.triggers.clone(),
+            }))
+        }
+
+        fn destroy_group(
+            &self,
+            _group: Arc<dyn InterruptSourceGroup>,
+        ) -> std::result::Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_virtio_pci_power_management() {
+        let triggers = Arc::new(AtomicUsize::new(0));
+        let interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            Arc::new(DummyInterruptManager {
+                triggers: triggers.clone(),
+            });
+        let memory = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        );
+        let mut device = VirtioPciDevice::new(
+            "_virtio-pci-rng".to_owned(),
+            memory,
+            Arc::new(Mutex::new(DummyDevice)),
+            2,
+            None,
+            &interrupt_manager,
+            0,
+            EventFd::new(EFD_NONBLOCK).unwrap(),
+            false,
+            None,
+            Arc::new(Mutex::new(Vec::new())),
+            PciIdsConfig::default(),
+            None,
+        )
+        .unwrap();
+        device.add_pci_capabilities(0).unwrap();
+        let pm_reg = device.pm_cap_reg_idx.unwrap();
+        assert_eq!(
+            device.read_config_register(pm_reg),
+            u32::from(PCI_PM_CTRL_NO_SOFT_RESET)
+        );
+
+        // Unmask the vector of the configuration changes
+        device.write_bar(0, MSIX_TABLE_BAR_OFFSET + 12, &[0; 4]);
+        device.common_config.msix_config.store(0, Ordering::Release);
+        let interrupt = device.virtio_interrupt.clone().unwrap();
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(triggers.load(Ordering::Acquire), 1);
+
+        // In D3hot, the BARs aren't decoded and the interrupts are replaced
+        // with a wake event.
+        let value = PCI_PM_CTRL_PME_ENABLE | PCI_PM_STATE_D3HOT;
+        device.write_config_register(pm_reg, 0, &value.to_le_bytes());
+        let mut data = [0u8; 4];
+        device.read_bar(0, COMMON_CONFIG_BAR_OFFSET, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(triggers.load(Ordering::Acquire), 1);
+        let pm_control = device.read_config_register(pm_reg) as u16;
+        assert_eq!(
+            pm_control,
+            PCI_PM_CTRL_NO_SOFT_RESET | value | PCI_PM_CTRL_PME_STATUS
+        );
+
+        // The power state is part of the snapshot
+        let state = device.state();
+        assert_eq!(state.pm_control, pm_control);
+        assert_eq!(state.pm_cap_reg_idx, Some(pm_reg));
+
+        // Back in D0, clearing the PME status, the pending interrupt is
+        // delivered.
+        let value = PCI_PM_CTRL_PME_ENABLE | PCI_PM_CTRL_PME_STATUS;
+        device.write_config_register(pm_reg, 0, &value.to_le_bytes());
+        assert_eq!(triggers.load(Ordering::Acquire), 2);
+        assert_eq!(
+            device.read_config_register(pm_reg) as u16,
+            PCI_PM_CTRL_NO_SOFT_RESET | PCI_PM_CTRL_PME_ENABLE
+        );
+    }
+}
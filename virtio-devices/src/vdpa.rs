@@ -26,13 +26,14 @@ use vhost::{
     vhost_kern::{vdpa::VhostKernVdpa, vhost_binding::VHOST_BACKEND_F_SUSPEND},
     VhostBackend, VringConfigData,
 };
+use virtio_bindings::virtio_blk::{VIRTIO_BLK_F_CONFIG_WCE, VIRTIO_BLK_F_MQ};
 use virtio_queue::{Descriptor, Queue, QueueT};
 use vm_device::dma_mapping::ExternalDmaMapping;
 use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic};
 use vm_migration::{
     Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
 };
-use vm_virtio::{AccessPlatform, Translatable};
+use vm_virtio::{AccessPlatform, Translatable, VirtioDeviceType};
 use vmm_sys_util::eventfd::EventFd;
 
 #[derive(Error, Debug)]
@@ -47,6 +48,8 @@ pub enum Error {
     GetAddressRange,
     #[error("Failed to get the available index from the virtio queue: {0}")]
     GetAvailableIndex(virtio_queue::Error),
+    #[error("Get virtio configuration: {0}")]
+    GetConfig(vhost::Error),
     #[error("Get virtio configuration size: {0}")]
     GetConfigSize(vhost::Error),
     #[error("Get virtio device identifier: {0}")]
@@ -61,6 +64,8 @@ pub enum Error {
     GetVringNum(vhost::Error),
     #[error("Invalid IOVA range: {0}-{1}")]
     InvalidIovaRange(u64, u64),
+    #[error("Invalid number of queues {0}, the device supports up to {1}")]
+    InvalidNumQueues(u16, u16),
     #[error("Missing VIRTIO_F_ACCESS_PLATFORM feature")]
     MissingAccessPlatformVirtioFeature,
     #[error("Failed to reset owner: {0}")]
@@ -95,6 +100,10 @@ pub enum Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// Offsets of the fields of the virtio-blk configuration space
+const VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET: u64 = 32;
+const VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET: u64 = 34;
+
 #[derive(Versionize)]
 pub struct VdpaState {
     pub avail_features: u64,
@@ -109,6 +118,23 @@ pub struct VdpaState {
 
 impl VersionMapped for VdpaState {}
 
+// The guest must only see the queues it was given, not all the ones of the
+// virtio-blk device, in the configuration space read at `offset`.
+fn patch_block_num_queues(offset: u64, data: &mut [u8], num_queues: u16) {
+    for (i, byte) in num_queues.to_le_bytes().iter().enumerate() {
+        let field_offset = VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET + i as u64;
+        if field_offset >= offset && field_offset < offset + data.len() as u64 {
+            data[(field_offset - offset) as usize] = *byte;
+        }
+    }
+}
+
+// Writeback is the only field of the virtio-blk configuration space the
+// driver may write, once VIRTIO_BLK_F_CONFIG_WCE is negotiated.
+fn block_config_writable(offset: u64, len: usize, wce_acked: bool) -> bool {
+    offset == VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET && len == 1 && wce_acked
+}
+
 pub struct Vdpa {
     common: VirtioCommon,
     id: String,
@@ -173,6 +199,13 @@ impl Vdpa {
                 return Err(Error::MissingAccessPlatformVirtioFeature);
             }
 
+            if device_type == VirtioDeviceType::Block as u32 {
+                let max_queues = Self::block_max_queues(&vhost, avail_features)?;
+                if num_queues > max_queues {
+                    return Err(Error::InvalidNumQueues(num_queues, max_queues));
+                }
+            }
+
             (
                 device_type,
                 avail_features,
@@ -203,6 +236,27 @@ impl Vdpa {
         })
     }
 
+    // Number of request queues of a virtio-blk device, only reported through
+    // the configuration space when it offers VIRTIO_BLK_F_MQ.
+    fn block_max_queues(
+        vhost: &VhostKernVdpa<GuestMemoryAtomic<GuestMemoryMmap>>,
+        avail_features: u64,
+    ) -> Result<u16> {
+        if avail_features & (1u64 << VIRTIO_BLK_F_MQ) == 0 {
+            return Ok(1);
+        }
+
+        let mut num_queues = [0u8; 2];
+        vhost
+            .get_config(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET as u32, &mut num_queues)
+            .map_err(Error::GetConfig)?;
+        Ok(u16::from_le_bytes(num_queues))
+    }
+
+    fn is_block(&self) -> bool {
+        self.common.device_type == VirtioDeviceType::Block as u32
+    }
+
     fn enable_vrings(&mut self, enable: bool) -> Result<()> {
         assert!(self.vhost.is_some());
 
@@ -375,8 +429,14 @@ impl Vdpa {
             .unwrap()
             .get_config_size()
             .map_err(Error::GetConfigSize)?;
+        // Saved as the device reports it, the number of queues of a virtio-blk
+        // device being restored from the queue sizes.
         let mut config = vec![0; config_size as usize];
-        self.read_config(0, config.as_mut_slice());
+        self.vhost
+            .as_ref()
+            .unwrap()
+            .get_config(0, config.as_mut_slice())
+            .map_err(Error::GetConfig)?;
 
         Ok(VdpaState {
             avail_features: self.common.avail_features,
@@ -412,11 +472,31 @@ impl VirtioDevice for Vdpa {
         assert!(self.vhost.is_some());
         if let Err(e) = self.vhost.as_ref().unwrap().get_config(offset as u32, data) {
             error!("Failed reading virtio config: {}", e);
+            return;
+        }
+
+        if self.is_block() && self.common.avail_features & (1u64 << VIRTIO_BLK_F_MQ) != 0 {
+            patch_block_num_queues(offset, data, self.common.queue_sizes.len() as u16);
         }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         assert!(self.vhost.is_some());
+        if self.is_block()
+            && !block_config_writable(
+                offset,
+                data.len(),
+                self.common.feature_acked(VIRTIO_BLK_F_CONFIG_WCE.into()),
+            )
+        {
+            warn!(
+                "Ignoring write to virtio-blk config: offset = 0x{:x}, length = {}",
+                offset,
+                data.len()
+            );
+            return;
+        }
+
         if let Err(e) = self.vhost.as_ref().unwrap().set_config(offset as u32, data) {
             error!("Failed writing virtio config: {}", e);
         }
@@ -590,3 +670,69 @@ impl<M: GuestAddressSpace + Sync + Send> ExternalDmaMapping for VdpaDmaMapping<M
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_block_num_queues() {
+        // The whole field
+        let mut data = [0xffu8; 2];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET, &mut data, 0x0102);
+        assert_eq!(data, [0x02, 0x01]);
+
+        // A read covering the field and its neighbours
+        let mut data = [0xffu8; 6];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET - 2, &mut data, 4);
+        assert_eq!(data, [0xff, 0xff, 0x04, 0x00, 0xff, 0xff]);
+
+        // Reads overlapping one byte of the field
+        let mut data = [0xffu8; 4];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET - 3, &mut data, 4);
+        assert_eq!(data, [0xff, 0xff, 0xff, 0x04]);
+        let mut data = [0xffu8; 4];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET + 1, &mut data, 0x0102);
+        assert_eq!(data, [0x01, 0xff, 0xff, 0xff]);
+
+        // Reads around the field are left alone
+        let mut data = [0xffu8; 4];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET - 4, &mut data, 4);
+        assert_eq!(data, [0xff; 4]);
+        let mut data = [0xffu8; 4];
+        patch_block_num_queues(VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET + 2, &mut data, 4);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn test_block_config_writable() {
+        assert!(block_config_writable(
+            VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET,
+            1,
+            true
+        ));
+        // Not until VIRTIO_BLK_F_CONFIG_WCE is negotiated
+        assert!(!block_config_writable(
+            VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET,
+            1,
+            false
+        ));
+        // Nothing but the writeback byte
+        assert!(!block_config_writable(
+            VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET,
+            2,
+            true
+        ));
+        assert!(!block_config_writable(
+            VIRTIO_BLK_CONFIG_WRITEBACK_OFFSET - 1,
+            2,
+            true
+        ));
+        assert!(!block_config_writable(
+            VIRTIO_BLK_CONFIG_NUM_QUEUES_OFFSET,
+            1,
+            true
+        ));
+        assert!(!block_config_writable(0, 8, true));
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Transfer of the internal state of a vhost-user backend, such as the inodes
+//! and file handles of virtiofsd, for the VM to be migrated along with it.
+//!
+//! The state goes through a pipe handed to the backend with the
+//! VHOST_USER_SET_DEVICE_STATE_FD message, while the rings are stopped, the
+//! VHOST_USER_CHECK_DEVICE_STATE message then telling whether the backend
+//! managed to save or load it. The vhost crate in use predates these messages
+//! and the DEVICE_STATE protocol feature, which is why they are exchanged
+//! directly on the socket of the master, nothing else using it meanwhile.
+
+use super::{Error, Result};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use vhost::vhost_user::Master;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+
+const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+const VHOST_USER_SET_DEVICE_STATE_FD: u32 = 42;
+const VHOST_USER_CHECK_DEVICE_STATE: u32 = 43;
+
+pub const VHOST_USER_PROTOCOL_F_DEVICE_STATE: u64 = 1 << 19;
+
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_REPLY: u32 = 0x4;
+const VHOST_USER_HEADER_SIZE: usize = 12;
+
+const TRANSFER_DIRECTION_SAVE: u32 = 0;
+const TRANSFER_DIRECTION_LOAD: u32 = 1;
+const MIGRATION_PHASE_STOPPED: u32 = 0;
+
+// Set in the reply to VHOST_USER_SET_DEVICE_STATE_FD when the backend uses the
+// pipe it was given, rather than returning another file descriptor.
+const DEVICE_STATE_FD_INVALID: u64 = 1 << 8;
+const DEVICE_STATE_ERROR_MASK: u64 = 0xff;
+
+fn device_state_error(msg: &str) -> Error {
+    Error::VhostUserDeviceState(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    // SAFETY: FFI call with a valid array of two file descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(Error::VhostUserDeviceState(io::Error::last_os_error()));
+    }
+    // SAFETY: the file descriptors were just created, nothing else owns them
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// Sends a message, and waits for its reply when it has one.
+fn request(
+    sock: &UnixStream,
+    request: u32,
+    payload: &[u8],
+    fds: &[RawFd],
+    reply: bool,
+) -> Result<Option<(u64, Option<File>)>> {
+    let mut msg = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    msg.extend_from_slice(&request.to_le_bytes());
+    msg.extend_from_slice(&VHOST_USER_VERSION.to_le_bytes());
+    msg.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    msg.extend_from_slice(payload);
+    let sent = sock
+        .send_with_fds(&[&msg[..]], fds)
+        .map_err(|e| Error::VhostUserDeviceState(e.into()))?;
+    if sent != msg.len() {
+        return Err(device_state_error("short write of the vhost-user message"));
+    }
+
+    if !reply {
+        return Ok(None);
+    }
+
+    let mut hdr = [0u8; VHOST_USER_HEADER_SIZE];
+    let mut iovecs = [libc::iovec {
+        iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+        iov_len: hdr.len(),
+    }];
+    let mut reply_fds = [-1; 1];
+    // SAFETY: the iovec points to the header, valid for its whole length
+    let (count, fd_count) = unsafe { sock.recv_with_fds(&mut iovecs, &mut reply_fds) }
+        .map_err(|e| Error::VhostUserDeviceState(e.into()))?;
+    let reply_file = (fd_count > 0).then(|| {
+        // SAFETY: the file descriptor was just received, nothing else owns it
+        unsafe { File::from_raw_fd(reply_fds[0]) }
+    });
+    if count != hdr.len() {
+        return Err(device_state_error("short read of the vhost-user reply"));
+    }
+
+    let reply_request = u32::from_le_bytes(hdr[0..4].try_into().unwrap());
+    let flags = u32::from_le_bytes(hdr[4..8].try_into().unwrap());
+    let size = u32::from_le_bytes(hdr[8..12].try_into().unwrap());
+    if reply_request != request || flags & VHOST_USER_REPLY == 0 || size != 8 {
+        return Err(device_state_error("invalid vhost-user reply"));
+    }
+    let mut value = [0u8; 8];
+    (&*sock)
+        .read_exact(&mut value)
+        .map_err(Error::VhostUserDeviceState)?;
+
+    Ok(Some((u64::from_le_bytes(value), reply_file)))
+}
+
+// The master socket, borrowed for the exchange.
+fn master_socket(vu: &Master) -> ManuallyDrop<UnixStream> {
+    // SAFETY: the socket is owned by the master, which outlives the returned
+    // stream, the latter never closing it.
+    ManuallyDrop::new(unsafe { UnixStream::from_raw_fd(vu.as_raw_fd()) })
+}
+
+fn protocol_features(sock: &UnixStream) -> Result<u64> {
+    request(sock, VHOST_USER_GET_PROTOCOL_FEATURES, &[], &[], true).map(|reply| reply.unwrap().0)
+}
+
+/// Returns whether the backend can transfer its internal state.
+pub fn supports_device_state(vu: &Master) -> Result<bool> {
+    Ok(protocol_features(&master_socket(vu))? & VHOST_USER_PROTOCOL_F_DEVICE_STATE != 0)
+}
+
+// Acks the DEVICE_STATE protocol feature on top of the other ones, and hands
+// one end of a pipe to the backend, returning the end the state goes through.
+fn set_device_state_fd(
+    sock: &UnixStream,
+    acked_protocol_features: u64,
+    direction: u32,
+) -> Result<File> {
+    // The protocol features are acked again as the vhost crate doesn't know
+    // about this one, and would drop it when reconnecting to the backend.
+    let features = acked_protocol_features | VHOST_USER_PROTOCOL_F_DEVICE_STATE;
+    request(
+        sock,
+        VHOST_USER_SET_PROTOCOL_FEATURES,
+        &features.to_le_bytes(),
+        &[],
+        false,
+    )?;
+
+    let (read_end, write_end) = pipe()?;
+    let (backend_end, front_end) = if direction == TRANSFER_DIRECTION_SAVE {
+        (write_end, read_end)
+    } else {
+        (read_end, write_end)
+    };
+
+    let mut payload = Vec::with_capacity(8);
+    payload.extend_from_slice(&direction.to_le_bytes());
+    payload.extend_from_slice(&MIGRATION_PHASE_STOPPED.to_le_bytes());
+    let (value, backend_file) = request(
+        sock,
+        VHOST_USER_SET_DEVICE_STATE_FD,
+        &payload,
+        &[backend_end.as_raw_fd()],
+        true,
+    )?
+    .unwrap();
+    // Our copy of the end given to the backend must be closed for the end of
+    // the transfer to be seen.
+    drop(backend_end);
+
+    if value & DEVICE_STATE_ERROR_MASK != 0 {
+        return Err(device_state_error(
+            "the backend refused to transfer its state",
+        ));
+    }
+    if value & DEVICE_STATE_FD_INVALID == 0 {
+        return backend_file
+            .ok_or_else(|| device_state_error("the backend didn't return its file descriptor"));
+    }
+
+    Ok(front_end)
+}
+
+fn check_device_state(sock: &UnixStream) -> Result<()> {
+    let (value, _) = request(sock, VHOST_USER_CHECK_DEVICE_STATE, &[], &[], true)?.unwrap();
+    if value != 0 {
+        return Err(device_state_error(
+            "the backend failed to transfer its state",
+        ));
+    }
+
+    Ok(())
+}
+
+fn save(sock: &UnixStream, acked_protocol_features: u64) -> Result<Vec<u8>> {
+    let mut file = set_device_state_fd(sock, acked_protocol_features, TRANSFER_DIRECTION_SAVE)?;
+    let mut state = Vec::new();
+    file.read_to_end(&mut state)
+        .map_err(Error::VhostUserDeviceState)?;
+    check_device_state(sock)?;
+
+    Ok(state)
+}
+
+fn load(sock: &UnixStream, acked_protocol_features: u64, state: &[u8]) -> Result<()> {
+    let mut file = set_device_state_fd(sock, acked_protocol_features, TRANSFER_DIRECTION_LOAD)?;
+    file.write_all(state).map_err(Error::VhostUserDeviceState)?;
+    drop(file);
+
+    check_device_state(sock)
+}
+
+/// Retrieves the internal state of the backend, whose rings must be stopped.
+pub fn save_device_state(vu: &Master, acked_protocol_features: u64) -> Result<Vec<u8>> {
+    save(&master_socket(vu), acked_protocol_features)
+}
+
+/// Hands its internal state to the backend, before its rings are started.
+pub fn load_device_state(vu: &Master, acked_protocol_features: u64, state: &[u8]) -> Result<()> {
+    load(&master_socket(vu), acked_protocol_features, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Receives a message from the master, returning its request, payload and
+    // file descriptor.
+    fn receive(sock: &UnixStream) -> (u32, Vec<u8>, Option<File>) {
+        let mut hdr = [0u8; VHOST_USER_HEADER_SIZE];
+        let mut iovecs = [libc::iovec {
+            iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+            iov_len: hdr.len(),
+        }];
+        let mut fds = [-1; 1];
+        // SAFETY: the iovec points to the header, valid for its whole length
+        let (_, fd_count) = unsafe { sock.recv_with_fds(&mut iovecs, &mut fds) }.unwrap();
+        let size = u32::from_le_bytes(hdr[8..12].try_into().unwrap()) as usize;
+        let mut payload = vec![0u8; size];
+        (&*sock).read_exact(&mut payload).unwrap();
+        (
+            u32::from_le_bytes(hdr[0..4].try_into().unwrap()),
+            payload,
+            // SAFETY: the file descriptor was just received
+            (fd_count > 0).then(|| unsafe { File::from_raw_fd(fds[0]) }),
+        )
+    }
+
+    fn reply(sock: &UnixStream, request: u32, value: u64) {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&request.to_le_bytes());
+        msg.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY).to_le_bytes());
+        msg.extend_from_slice(&8u32.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        (&*sock).write_all(&msg).unwrap();
+    }
+
+    // Backend transferring its state through the pipe it is given.
+    fn backend(sock: UnixStream, state: Vec<u8>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let (request, payload, _) = receive(&sock);
+            assert_eq!(request, VHOST_USER_SET_PROTOCOL_FEATURES);
+            assert_eq!(
+                u64::from_le_bytes(payload.try_into().unwrap()),
+                0x3 | VHOST_USER_PROTOCOL_F_DEVICE_STATE
+            );
+
+            let (request, payload, file) = receive(&sock);
+            assert_eq!(request, VHOST_USER_SET_DEVICE_STATE_FD);
+            let direction = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+            reply(&sock, request, DEVICE_STATE_FD_INVALID);
+
+            let mut file = file.unwrap();
+            let mut loaded = Vec::new();
+            if direction == TRANSFER_DIRECTION_SAVE {
+                file.write_all(&state).unwrap();
+            } else {
+                file.read_to_end(&mut loaded).unwrap();
+            }
+            drop(file);
+
+            let (request, _, _) = receive(&sock);
+            assert_eq!(request, VHOST_USER_CHECK_DEVICE_STATE);
+            reply(&sock, request, 0);
+
+            loaded
+        })
+    }
+
+    #[test]
+    fn test_save_device_state() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let backend = backend(slave, b"inodes".to_vec());
+
+        assert_eq!(save(&master, 0x3).unwrap(), b"inodes");
+        assert!(backend.join().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_load_device_state() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let backend = backend(slave, Vec::new());
+
+        load(&master, 0x3, b"handles").unwrap();
+        assert_eq!(backend.join().unwrap(), b"handles");
+    }
+
+    #[test]
+    fn test_device_state_check_failed() {
+        let (master, slave) = UnixStream::pair().unwrap();
+        let backend = thread::spawn(move || {
+            receive(&slave);
+            let (request, _, _) = receive(&slave);
+            reply(&slave, request, DEVICE_STATE_FD_INVALID);
+            let (request, _, _) = receive(&slave);
+            reply(&slave, request, 1);
+        });
+
+        assert!(save(&master, 0x3).is_err());
+        backend.join().unwrap();
+    }
+}
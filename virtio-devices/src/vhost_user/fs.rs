@@ -1,6 +1,7 @@
 // Copyright 2019 Intel Corporation. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use super::device_state::{load_device_state, save_device_state, supports_device_state};
 use super::vu_common_ctrl::VhostUserHandle;
 use super::{Error, Result, DEFAULT_VIRTIO_FEATURES};
 use crate::seccomp_filters::Thread;
@@ -11,12 +12,13 @@ use crate::{
     VirtioInterrupt, VirtioSharedMemoryList, VIRTIO_F_IOMMU_PLATFORM,
 };
 use crate::{GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use anyhow::anyhow;
 use libc::{self, c_void, off64_t, pread64, pwrite64};
 use seccompiler::SeccompAction;
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::result;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use versionize::{VersionMap, Versionize, VersionizeResult};
@@ -41,6 +43,9 @@ use vmm_sys_util::eventfd::EventFd;
 const NUM_QUEUE_OFFSET: usize = 1;
 const DEFAULT_QUEUE_NUMBER: usize = 2;
 
+// DAX mappings are set up by the guest driver with a 2MiB granularity.
+const DAX_WINDOW_ALIGNMENT: u64 = 0x20_0000;
+
 #[derive(Versionize)]
 pub struct State {
     pub avail_features: u64,
@@ -49,18 +54,39 @@ pub struct State {
     pub acked_protocol_features: u64,
     pub vu_num_queues: usize,
     pub slave_req_support: bool,
+    pub cache_window: u64,
+    pub pending_cache_window: Option<u64>,
+    pub device_state_support: bool,
+    // Internal state of the backend, transferred along with the VM.
+    pub device_state: Option<Vec<u8>>,
 }
 
 impl VersionMapped for State {}
 
+// Check that a DAX window of `size` bytes can be carved out of a shared
+// memory region of `region_len` bytes.
+fn validate_cache_window(size: u64, region_len: u64) -> Result<()> {
+    if size > region_len || size % DAX_WINDOW_ALIGNMENT != 0 {
+        return Err(Error::InvalidDaxCacheSize(size));
+    }
+
+    Ok(())
+}
+
 struct SlaveReqHandler {
     cache_offset: GuestAddress,
-    cache_size: u64,
+    // Size of the usable part of the cache, which can be shrunk or grown at
+    // runtime within the boundaries of the shared memory region.
+    cache_window: Arc<AtomicU64>,
     mmap_cache_addr: u64,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
 }
 
 impl SlaveReqHandler {
+    fn cache_size(&self) -> u64 {
+        self.cache_window.load(Ordering::Acquire)
+    }
+
     // Make sure request is within cache range
     fn is_req_valid(&self, offset: u64, len: u64) -> bool {
         let end = match offset.checked_add(len) {
@@ -68,7 +94,8 @@ impl SlaveReqHandler {
             None => return false,
         };
 
-        !(offset >= self.cache_size || end > self.cache_size)
+        let cache_size = self.cache_size();
+        !(offset >= cache_size || end > cache_size)
     }
 }
 
@@ -129,7 +156,7 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             // Need to handle a special case where the slave ask for the unmapping
             // of the entire mapping.
             let offset = if len == 0xffff_ffff_ffff_ffff {
-                len = self.cache_size;
+                len = self.cache_size();
                 0
             } else {
                 fs.cache_offset[i]
@@ -200,7 +227,7 @@ impl VhostUserMasterReqHandler for SlaveReqHandler {
             let mut foffset = fs.fd_offset[i];
             let mut len = fs.len[i] as usize;
             let gpa = fs.cache_offset[i];
-            let cache_end = self.cache_offset.raw_value() + self.cache_size;
+            let cache_end = self.cache_offset.raw_value() + self.cache_size();
             let efault = libc::EFAULT;
 
             let mut ptr = if gpa >= self.cache_offset.raw_value() && gpa < cache_end {
@@ -298,7 +325,16 @@ pub struct Fs {
     // Hold ownership of the memory that is allocated for the device
     // which will be automatically dropped when the device is dropped
     cache: Option<(VirtioSharedMemoryList, MmapRegion)>,
+    cache_window: Arc<AtomicU64>,
+    // Window size requested while the device was activated. It is applied
+    // on the next activation, once the guest driver and the backend have
+    // been reset and don't rely on the current mappings anymore.
+    pending_cache_window: Option<u64>,
     slave_req_support: bool,
+    // Whether the backend supports the DEVICE_STATE protocol feature, which
+    // the vhost crate doesn't know about, hence not recorded along with the
+    // acked protocol features.
+    device_state_support: bool,
     seccomp_action: SeccompAction,
     guest_memory: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
     epoll_thread: Option<thread::JoinHandle<()>>,
@@ -329,6 +365,18 @@ impl Fs {
         // Connect to the vhost-user socket.
         let mut vu = VhostUserHandle::connect_vhost_user(false, path, num_queues as u64, false)?;
 
+        let region_len = cache.as_ref().map(|cache| cache.0.len).unwrap_or(0);
+        let (cache_window, pending_cache_window) = match state.as_ref() {
+            Some(state) => {
+                validate_cache_window(state.cache_window, region_len)?;
+                if let Some(size) = state.pending_cache_window {
+                    validate_cache_window(size, region_len)?;
+                }
+                (state.cache_window, state.pending_cache_window)
+            }
+            None => (region_len, None),
+        };
+
         let (
             avail_features,
             acked_features,
@@ -336,6 +384,7 @@ impl Fs {
             vu_num_queues,
             config,
             slave_req_support,
+            device_state_support,
             paused,
         ) = if let Some(state) = state {
             info!("Restoring vhost-user-fs {}", id);
@@ -345,6 +394,14 @@ impl Fs {
                 state.acked_protocol_features,
             )?;
 
+            if let Some(device_state) = &state.device_state {
+                load_device_state(
+                    vu.socket_handle(),
+                    state.acked_protocol_features,
+                    device_state,
+                )?;
+            }
+
             (
                 state.avail_features,
                 state.acked_features,
@@ -352,6 +409,7 @@ impl Fs {
                 state.vu_num_queues,
                 state.config,
                 state.slave_req_support,
+                state.device_state_support,
                 true,
             )
         } else {
@@ -395,6 +453,11 @@ impl Fs {
                 slave_req_support = true;
             }
 
+            // The protocol features can only be queried once negotiated.
+            let device_state_support =
+                acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0
+                    && supports_device_state(vu.socket_handle())?;
+
             // Create virtio-fs device configuration.
             let mut config = VirtioFsConfig::default();
             let tag_bytes_vec = tag.to_string().into_bytes();
@@ -412,6 +475,7 @@ impl Fs {
                 num_queues,
                 config,
                 slave_req_support,
+                device_state_support,
                 false,
             )
         };
@@ -437,7 +501,10 @@ impl Fs {
             id,
             config,
             cache,
+            cache_window: Arc::new(AtomicU64::new(cache_window)),
+            pending_cache_window,
             slave_req_support,
+            device_state_support,
             seccomp_action,
             guest_memory: None,
             epoll_thread: None,
@@ -446,15 +513,115 @@ impl Fs {
         })
     }
 
-    fn state(&self) -> State {
-        State {
+    /// Resize the DAX window exposed through the shared memory region.
+    ///
+    /// The window can't exceed the size of the shared memory region which
+    /// was reserved when the device was created. The guest driver learns the
+    /// size of the window when probing the device, and the backend sets up
+    /// its mappings accordingly, so the new size only takes effect while the
+    /// device isn't activated. For an activated device, it is recorded and
+    /// applied through `apply_pending_cache_resize()` once the guest reset
+    /// the device, which also resets the backend session.
+    ///
+    /// Returns whether the window was resized right away.
+    pub fn resize_cache(&mut self, desired_size: u64) -> Result<bool> {
+        let cache = self.cache.as_ref().ok_or(Error::MissingDaxCache)?;
+        validate_cache_window(desired_size, cache.0.len)?;
+
+        if self.common.kill_evt.is_some() {
+            self.pending_cache_window = Some(desired_size);
+            return Ok(false);
+        }
+
+        self.pending_cache_window = None;
+        self.set_cache_window(desired_size)?;
+
+        Ok(true)
+    }
+
+    /// Apply the window size requested while the device was activated.
+    ///
+    /// Returns whether the window was resized.
+    pub fn apply_pending_cache_resize(&mut self) -> Result<bool> {
+        if self.common.kill_evt.is_some() {
+            return Ok(false);
+        }
+
+        match self.pending_cache_window.take() {
+            Some(size) => self.set_cache_window(size).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    // When shrinking, any mapping the backend set up beyond the new size is
+    // torn down, and subsequent mapping requests outside of the window are
+    // rejected.
+    fn set_cache_window(&mut self, desired_size: u64) -> Result<()> {
+        let cache = self.cache.as_ref().ok_or(Error::MissingDaxCache)?;
+        let current_size = self.cache_window.load(Ordering::Acquire);
+
+        self.cache_window.store(desired_size, Ordering::Release);
+        if desired_size < current_size {
+            // SAFETY: FFI call with valid arguments, the range is contained
+            // in the anonymous mapping owned by this device.
+            let ret = unsafe {
+                libc::mmap(
+                    (cache.0.host_addr + desired_size) as *mut libc::c_void,
+                    (current_size - desired_size) as usize,
+                    libc::PROT_NONE,
+                    libc::MAP_ANONYMOUS | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                    -1,
+                    0,
+                )
+            };
+            if ret == libc::MAP_FAILED {
+                return Err(Error::DaxCacheUnmap(io::Error::last_os_error()));
+            }
+        }
+
+        event!(
+            "virtio-device",
+            "dax-window-resized",
+            "id",
+            &self.id,
+            "size",
+            desired_size.to_string()
+        );
+
+        Ok(())
+    }
+
+    fn state(&self) -> result::Result<State, MigratableError> {
+        // The state of the backend is retrieved while the device is paused,
+        // its rings being stopped.
+        let device_state = match &self.vu_common.vu {
+            Some(vu) if self.device_state_support => Some(
+                save_device_state(
+                    vu.lock().unwrap().socket_handle(),
+                    self.vu_common.acked_protocol_features,
+                )
+                .map_err(|e| {
+                    MigratableError::Snapshot(anyhow!(
+                        "Error saving the state of the vhost-user-fs backend: {:?}",
+                        e
+                    ))
+                })?,
+            ),
+            _ => None,
+        };
+
+        Ok(State {
             avail_features: self.common.avail_features,
             acked_features: self.common.acked_features,
             config: self.config,
             acked_protocol_features: self.vu_common.acked_protocol_features,
             vu_num_queues: self.vu_common.vu_num_queues,
             slave_req_support: self.slave_req_support,
-        }
+            cache_window: self.cache_window.load(Ordering::Acquire),
+            pending_cache_window: self.pending_cache_window,
+            device_state_support: self.device_state_support,
+            device_state,
+        })
     }
 }
 
@@ -512,7 +679,7 @@ impl VirtioDevice for Fs {
             if let Some(cache) = self.cache.as_ref() {
                 let vu_master_req_handler = Arc::new(SlaveReqHandler {
                     cache_offset: cache.0.addr,
-                    cache_size: cache.0.len,
+                    cache_window: self.cache_window.clone(),
                     mmap_cache_addr: cache.0.host_addr,
                     mem: mem.clone(),
                 });
@@ -598,7 +765,18 @@ impl VirtioDevice for Fs {
     }
 
     fn get_shm_regions(&self) -> Option<VirtioSharedMemoryList> {
-        self.cache.as_ref().map(|cache| cache.0.clone())
+        // The whole region backs the BAR, but the capability advertises the
+        // window the guest driver is allowed to use from its next probe.
+        let window = self
+            .pending_cache_window
+            .unwrap_or_else(|| self.cache_window.load(Ordering::Acquire));
+        self.cache.as_ref().map(|cache| {
+            let mut shm_list = cache.0.clone();
+            for shm in shm_list.region_list.iter_mut() {
+                shm.len = window;
+            }
+            shm_list
+        })
     }
 
     fn set_shm_regions(
@@ -622,12 +800,15 @@ impl VirtioDevice for Fs {
 
     fn userspace_mappings(&self) -> Vec<UserspaceMapping> {
         let mut mappings = Vec::new();
-        if let Some(cache) = self.cache.as_ref() {
+        // Only the DAX window is mapped into the guest, nothing is left when
+        // it has been shrunk down to zero.
+        let window = self.cache_window.load(Ordering::Acquire);
+        if let Some(cache) = self.cache.as_ref().filter(|_| window != 0) {
             mappings.push(UserspaceMapping {
                 host_addr: cache.0.host_addr,
                 mem_slot: cache.0.mem_slot,
                 addr: cache.0.addr,
-                len: cache.0.len,
+                len: window,
                 mergeable: false,
             })
         }
@@ -659,7 +840,8 @@ impl Snapshottable for Fs {
     }
 
     fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
-        self.vu_common.snapshot(&self.state())
+        let state = self.state()?;
+        self.vu_common.snapshot(&state)
     }
 }
 impl Transportable for Fs {}
@@ -686,3 +868,48 @@ impl Migratable for Fs {
             .complete_migration(self.common.kill_evt.take())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_cache_window() {
+        let region_len = 4 * DAX_WINDOW_ALIGNMENT;
+
+        validate_cache_window(0, region_len).unwrap();
+        validate_cache_window(DAX_WINDOW_ALIGNMENT, region_len).unwrap();
+        validate_cache_window(region_len, region_len).unwrap();
+
+        // Not aligned on the DAX mapping granularity.
+        assert!(validate_cache_window(DAX_WINDOW_ALIGNMENT + 0x1000, region_len).is_err());
+        // Beyond the shared memory region.
+        assert!(validate_cache_window(region_len + DAX_WINDOW_ALIGNMENT, region_len).is_err());
+    }
+
+    #[test]
+    fn test_slave_req_follows_cache_window() {
+        let cache_window = Arc::new(AtomicU64::new(4 * DAX_WINDOW_ALIGNMENT));
+        let handler = SlaveReqHandler {
+            cache_offset: GuestAddress(0x1_0000_0000),
+            cache_window: cache_window.clone(),
+            mmap_cache_addr: 0,
+            mem: GuestMemoryAtomic::new(
+                GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap(),
+            ),
+        };
+
+        assert!(handler.is_req_valid(0, 4 * DAX_WINDOW_ALIGNMENT));
+        assert!(handler.is_req_valid(3 * DAX_WINDOW_ALIGNMENT, DAX_WINDOW_ALIGNMENT));
+        assert!(!handler.is_req_valid(u64::MAX, 2));
+
+        // Once the window is shrunk, requests beyond it are rejected.
+        cache_window.store(2 * DAX_WINDOW_ALIGNMENT, Ordering::Release);
+        assert!(handler.is_req_valid(DAX_WINDOW_ALIGNMENT, DAX_WINDOW_ALIGNMENT));
+        assert!(!handler.is_req_valid(DAX_WINDOW_ALIGNMENT, 2 * DAX_WINDOW_ALIGNMENT));
+        assert!(!handler.is_req_valid(3 * DAX_WINDOW_ALIGNMENT, DAX_WINDOW_ALIGNMENT));
+
+        cache_window.store(0, Ordering::Release);
+        assert!(!handler.is_req_valid(0, DAX_WINDOW_ALIGNMENT));
+    }
+}
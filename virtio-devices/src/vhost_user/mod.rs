@@ -11,7 +11,9 @@ use anyhow::anyhow;
 use std::io;
 use std::ops::Deref;
 use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
 use std::sync::{atomic::AtomicBool, Arc, Barrier, Mutex};
+use std::time::Duration;
 use thiserror::Error;
 use versionize::Versionize;
 use vhost::vhost_user::message::{
@@ -26,10 +28,11 @@ use vm_memory::{
     GuestMemoryAtomic,
 };
 use vm_migration::{protocol::MemoryRangeTable, MigratableError, Snapshot, VersionMapped};
-use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::{eventfd::EventFd, timerfd::TimerFd};
 use vu_common_ctrl::VhostUserHandle;
 
 pub mod blk;
+pub mod device_state;
 pub mod fs;
 pub mod net;
 pub mod vu_common_ctrl;
@@ -143,6 +146,14 @@ pub enum Error {
     NewMmapRegion(MmapRegionError),
     #[error("Could not find the shm log region")]
     MissingShmLogRegion,
+    #[error("No DAX cache window associated with the device")]
+    MissingDaxCache,
+    #[error("Invalid DAX cache window size: {0:#x}")]
+    InvalidDaxCacheSize(u64),
+    #[error("Failed unmapping the DAX cache window: {0}")]
+    DaxCacheUnmap(io::Error),
+    #[error("Failed transferring the state of the backend: {0}")]
+    VhostUserDeviceState(io::Error),
 }
 type Result<T> = std::result::Result<T, Error>;
 
@@ -156,6 +167,11 @@ pub const DEFAULT_VIRTIO_FEATURES: u64 = 1 << VIRTIO_F_RING_INDIRECT_DESC
 
 const HUP_CONNECTION_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 const SLAVE_REQ_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
+const RECONNECT_TIMER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+const RECONNECT_LISTENER_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
+
+// Delay before trying to reconnect again after a failed attempt.
+const RECONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Default)]
 pub struct Inflight {
@@ -176,6 +192,8 @@ pub struct VhostUserEpollHandler<S: VhostUserMasterReqHandler> {
     pub server: bool,
     pub slave_req_handler: Option<MasterReqHandler<S>>,
     pub inflight: Option<Inflight>,
+    pub reconnect_timer: Option<TimerFd>,
+    pub reconnect_listener: Option<UnixListener>,
 }
 
 impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
@@ -200,56 +218,151 @@ impl<S: VhostUserMasterReqHandler> VhostUserEpollHandler<S> {
         Ok(())
     }
 
-    fn reconnect(&mut self, helper: &mut EpollHelper) -> std::result::Result<(), EpollHelperError> {
+    fn reconnect(&mut self, mut vhost_user: VhostUserHandle) -> Result<()> {
+        // Initialize the backend, restoring the features which were
+        // negotiated and the inflight I/O tracking region, so that the
+        // backend can resume from where the previous instance stopped.
+        vhost_user.reinitialize_vhost_user(
+            self.mem.memory().deref(),
+            self.queues
+                .iter()
+                .map(|(i, q, e)| (*i, vm_virtio::clone_queue(q), e.try_clone().unwrap()))
+                .collect(),
+            &self.virtio_interrupt,
+            self.acked_features,
+            self.acked_protocol_features,
+            &self.slave_req_handler,
+            self.inflight.as_mut(),
+        )?;
+
+        // Update vhost-user reference
+        let mut vu = self.vu.lock().unwrap();
+        *vu = vhost_user;
+
+        Ok(())
+    }
+
+    fn handle_disconnection(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> std::result::Result<(), EpollHelperError> {
         helper.del_event_custom(
             self.vu.lock().unwrap().socket_handle().as_raw_fd(),
             HUP_CONNECTION_EVENT,
             epoll::Events::EPOLLHUP,
         )?;
 
-        let mut vhost_user = VhostUserHandle::connect_vhost_user(
-            self.server,
-            &self.socket_path,
-            self.queues.len() as u64,
-            true,
-        )
-        .map_err(|e| {
-            EpollHelperError::IoError(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("failed connecting vhost-user backend {e:?}"),
+        warn!(
+            "Lost connection with vhost-user backend {}",
+            self.socket_path
+        );
+        event!("vhost-user", "disconnected", "socket", &self.socket_path);
+
+        // Nothing here may wait for the backend, as this thread must keep
+        // handling the other events, starting with the kill and pause ones.
+        if self.server {
+            self.listen_for_reconnection(helper)
+        } else {
+            self.try_reconnect(helper)
+        }
+    }
+
+    // A failure to reconnect is not fatal, the backend could be restarting
+    // or be temporarily unavailable. The device is left without backend and
+    // another attempt is scheduled through the reconnection timer.
+    fn try_reconnect(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> std::result::Result<(), EpollHelperError> {
+        let result =
+            VhostUserHandle::try_connect_vhost_user(&self.socket_path, self.queues.len() as u64)
+                .and_then(|vhost_user| self.reconnect(vhost_user));
+        if let Err(e) = result {
+            error!(
+                "Failed reconnecting vhost-user backend {}: {:?}",
+                self.socket_path, e
+            );
+
+            if self.reconnect_timer.is_none() {
+                let timer = TimerFd::new().map_err(|e| EpollHelperError::IoError(e.into()))?;
+                helper.add_event(timer.as_raw_fd(), RECONNECT_TIMER_EVENT)?;
+                self.reconnect_timer = Some(timer);
+            }
+
+            return self
+                .reconnect_timer
+                .as_mut()
+                .unwrap()
+                .reset(RECONNECT_RETRY_INTERVAL, None)
+                .map_err(|e| EpollHelperError::IoError(e.into()));
+        }
+
+        self.reconnected(helper)
+    }
+
+    // When the VMM is the server, the backend reconnects on its own. The
+    // listener is polled along with the other events rather than waiting
+    // for the connection to be accepted.
+    fn listen_for_reconnection(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> std::result::Result<(), EpollHelperError> {
+        let listener = VhostUserHandle::listen_vhost_user(&self.socket_path).map_err(|e| {
+            EpollHelperError::HandleEvent(anyhow!(
+                "failed listening for the vhost-user backend: {:?}",
+                e
             ))
         })?;
+        helper.add_event(listener.as_raw_fd(), RECONNECT_LISTENER_EVENT)?;
+        self.reconnect_listener = Some(listener);
 
-        // Initialize the backend
-        vhost_user
-            .reinitialize_vhost_user(
-                self.mem.memory().deref(),
-                self.queues
-                    .iter()
-                    .map(|(i, q, e)| (*i, vm_virtio::clone_queue(q), e.try_clone().unwrap()))
-                    .collect(),
-                &self.virtio_interrupt,
-                self.acked_features,
-                self.acked_protocol_features,
-                &self.slave_req_handler,
-                self.inflight.as_mut(),
-            )
-            .map_err(|e| {
-                EpollHelperError::IoError(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("failed reconnecting vhost-user backend: {e:?}"),
-                ))
-            })?;
+        Ok(())
+    }
+
+    fn accept_reconnection(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> std::result::Result<(), EpollHelperError> {
+        let stream = match self.reconnect_listener.as_ref().map(|l| l.accept()) {
+            Some(Ok((stream, _))) => stream,
+            Some(Err(e)) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Some(Err(e)) => return Err(EpollHelperError::IoError(e)),
+            None => return Ok(()),
+        };
+
+        let vhost_user = VhostUserHandle::from_stream(stream, self.queues.len() as u64);
+        if let Err(e) = self.reconnect(vhost_user) {
+            // Keep listening, the backend may connect again.
+            error!(
+                "Failed reconnecting vhost-user backend {}: {:?}",
+                self.socket_path, e
+            );
+            return Ok(());
+        }
 
+        if let Some(listener) = self.reconnect_listener.take() {
+            helper.del_event_custom(
+                listener.as_raw_fd(),
+                RECONNECT_LISTENER_EVENT,
+                epoll::Events::EPOLLIN,
+            )?;
+        }
+
+        self.reconnected(helper)
+    }
+
+    fn reconnected(
+        &mut self,
+        helper: &mut EpollHelper,
+    ) -> std::result::Result<(), EpollHelperError> {
         helper.add_event_custom(
-            vhost_user.socket_handle().as_raw_fd(),
+            self.vu.lock().unwrap().socket_handle().as_raw_fd(),
             HUP_CONNECTION_EVENT,
             epoll::Events::EPOLLHUP,
         )?;
 
-        // Update vhost-user reference
-        let mut vu = self.vu.lock().unwrap();
-        *vu = vhost_user;
+        info!("Reconnected vhost-user backend {}", self.socket_path);
+        event!("vhost-user", "reconnected", "socket", &self.socket_path);
 
         Ok(())
     }
@@ -264,12 +377,18 @@ impl<S: VhostUserMasterReqHandler> EpollHelperHandler for VhostUserEpollHandler<
         let ev_type = event.data as u16;
         match ev_type {
             HUP_CONNECTION_EVENT => {
-                self.reconnect(helper).map_err(|e| {
-                    EpollHelperError::HandleEvent(anyhow!(
-                        "failed to reconnect vhost-user backend: {:?}",
-                        e
-                    ))
-                })?;
+                self.handle_disconnection(helper)?;
+            }
+            RECONNECT_TIMER_EVENT => {
+                if let Some(timer) = self.reconnect_timer.as_mut() {
+                    timer
+                        .wait()
+                        .map_err(|e| EpollHelperError::IoError(e.into()))?;
+                }
+                self.try_reconnect(helper)?;
+            }
+            RECONNECT_LISTENER_EVENT => {
+                self.accept_reconnection(helper)?;
             }
             SLAVE_REQ_EVENT => {
                 if let Some(slave_req_handler) = self.slave_req_handler.as_mut() {
@@ -355,6 +474,8 @@ impl VhostUserCommon {
             server: self.server,
             slave_req_handler,
             inflight,
+            reconnect_timer: None,
+            reconnect_listener: None,
         })
     }
 
@@ -6,8 +6,9 @@ use crate::thread_helper::spawn_virtio_thread;
 use crate::vhost_user::vu_common_ctrl::{VhostUserConfig, VhostUserHandle};
 use crate::vhost_user::{Error, Result, VhostUserCommon};
 use crate::{
-    ActivateResult, NetCtrlEpollHandler, VirtioCommon, VirtioDevice, VirtioDeviceType,
-    VirtioInterrupt, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+    ActivateResult, NetCtrlEpollHandler, QueueCursors, VirtioCommon, VirtioDevice,
+    VirtioDeviceType, VirtioInterrupt, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_VERSION_1,
 };
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use net_util::{build_net_config_space, CtrlQueue, MacAddr, VirtioNetConfig};
@@ -305,7 +306,7 @@ impl VirtioDevice for Net {
         let event_idx = self.common.feature_acked(VIRTIO_RING_F_EVENT_IDX.into());
         if self.common.feature_acked(VIRTIO_NET_F_CTRL_VQ.into()) && num_queues % 2 != 0 {
             let ctrl_queue_index = num_queues - 1;
-            let (_, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
+            let (index, mut ctrl_queue, ctrl_queue_evt) = queues.remove(ctrl_queue_index);
 
             ctrl_queue.set_event_idx(event_idx);
 
@@ -317,6 +318,7 @@ impl VirtioDevice for Net {
                 pause_evt,
                 ctrl_q: CtrlQueue::new(Vec::new()),
                 queue: ctrl_queue,
+                queue_cursors: self.common.queue_cursors(index),
                 queue_evt: ctrl_queue_evt,
                 access_platform: None,
                 interrupt_cb: interrupt_cb.clone(),
@@ -407,6 +409,12 @@ impl VirtioDevice for Net {
         self.vu_common.shutdown();
     }
 
+    // Only the control queue is processed by the VMM, the backend processing
+    // the other ones.
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
     fn add_memory_region(
         &mut self,
         region: &Arc<GuestRegionMmap>,
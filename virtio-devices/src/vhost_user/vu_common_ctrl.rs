@@ -10,8 +10,9 @@ use crate::{
 use std::convert::TryInto;
 use std::ffi;
 use std::fs::File;
+use std::io;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::os::unix::net::UnixListener;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -333,6 +334,55 @@ impl VhostUserHandle {
         Ok(())
     }
 
+    // The backend might have been restarted with a different set of features,
+    // which is why they must be validated before the connection can be
+    // restored. The protocol features are mandatory as the VMM relies on them
+    // (inflight tracking, slave requests, ...) and so are the virtio features
+    // since the guest driver can't be told it lost some of them.
+    fn renegotiate_features_vhost_user(
+        &mut self,
+        acked_features: u64,
+        acked_protocol_features: u64,
+    ) -> Result<()> {
+        self.vu.set_owner().map_err(Error::VhostUserSetOwner)?;
+        let backend_features = self
+            .vu
+            .get_features()
+            .map_err(Error::VhostUserGetFeatures)?;
+
+        check_backend_features(acked_features, backend_features)?;
+
+        if acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            let acked_protocol_features =
+                VhostUserProtocolFeatures::from_bits(acked_protocol_features)
+                    .ok_or(Error::InvalidFeatures)?;
+            let backend_protocol_features = self
+                .vu
+                .get_protocol_features()
+                .map_err(Error::VhostUserGetProtocolFeatures)?;
+
+            if !backend_protocol_features.contains(acked_protocol_features) {
+                error!(
+                    "vhost-user backend no longer supports protocol features {:?}",
+                    acked_protocol_features - backend_protocol_features
+                );
+                return Err(Error::InvalidFeatures);
+            }
+
+            self.vu
+                .set_protocol_features(acked_protocol_features)
+                .map_err(Error::VhostUserSetProtocolFeatures)?;
+
+            if acked_protocol_features.contains(VhostUserProtocolFeatures::REPLY_ACK) {
+                self.vu.set_hdr_flags(VhostUserHeaderFlag::NEED_REPLY);
+            }
+        }
+
+        self.update_supports_migration(acked_features, acked_protocol_features);
+
+        Ok(())
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn reinitialize_vhost_user<S: VhostUserMasterReqHandler>(
         &mut self,
@@ -344,7 +394,7 @@ impl VhostUserHandle {
         slave_req_handler: &Option<MasterReqHandler<S>>,
         inflight: Option<&mut Inflight>,
     ) -> Result<()> {
-        self.set_protocol_features_vhost_user(acked_features, acked_protocol_features)?;
+        self.renegotiate_features_vhost_user(acked_features, acked_protocol_features)?;
 
         self.setup_vhost_user(
             mem,
@@ -356,6 +406,52 @@ impl VhostUserHandle {
         )
     }
 
+    fn from_master(vu: Master) -> Self {
+        VhostUserHandle {
+            vu,
+            ready: false,
+            supports_migration: false,
+            shm_log: None,
+            acked_features: 0,
+            vrings_info: None,
+            queue_indexes: Vec::new(),
+        }
+    }
+
+    /// Create a handle from a connection accepted on a listener returned by
+    /// `listen_vhost_user()`.
+    pub fn from_stream(stream: UnixStream, num_queues: u64) -> Self {
+        Self::from_master(Master::from_stream(stream, num_queues))
+    }
+
+    /// Bind a nonblocking listener on `socket_path`, for the backend to
+    /// connect to when the VMM acts as the server. Any stale socket left
+    /// behind by a previous listener is removed first.
+    pub fn listen_vhost_user(socket_path: &str) -> Result<UnixListener> {
+        match std::fs::remove_file(socket_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(Error::RemoveSocketPath(e));
+            }
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(socket_path).map_err(Error::BindSocket)?;
+        listener.set_nonblocking(true).map_err(Error::BindSocket)?;
+
+        Ok(listener)
+    }
+
+    /// Make a single attempt at connecting to the backend listening on
+    /// `socket_path`, without waiting for it to show up.
+    pub fn try_connect_vhost_user(socket_path: &str, num_queues: u64) -> Result<Self> {
+        Master::connect(socket_path, num_queues)
+            .map(Self::from_master)
+            .map_err(|e| {
+                debug!("Failed connecting the backend: {:?}", e);
+                Error::VhostUserConnect
+            })
+    }
+
     pub fn connect_vhost_user(
         server: bool,
         socket_path: &str,
@@ -372,32 +468,14 @@ impl VhostUserHandle {
             info!("Waiting for incoming vhost-user connection...");
             let (stream, _) = listener.accept().map_err(Error::AcceptConnection)?;
 
-            Ok(VhostUserHandle {
-                vu: Master::from_stream(stream, num_queues),
-                ready: false,
-                supports_migration: false,
-                shm_log: None,
-                acked_features: 0,
-                vrings_info: None,
-                queue_indexes: Vec::new(),
-            })
+            Ok(Self::from_stream(stream, num_queues))
         } else {
             let now = Instant::now();
 
             // Retry connecting for a full minute
             let err = loop {
                 let err = match Master::connect(socket_path, num_queues) {
-                    Ok(m) => {
-                        return Ok(VhostUserHandle {
-                            vu: m,
-                            ready: false,
-                            supports_migration: false,
-                            shm_log: None,
-                            acked_features: 0,
-                            vrings_info: None,
-                            queue_indexes: Vec::new(),
-                        })
-                    }
+                    Ok(m) => return Ok(Self::from_master(m)),
                     Err(e) => e,
                 };
                 sleep(Duration::from_millis(100));
@@ -593,3 +671,86 @@ fn memfd_create(name: &ffi::CStr, flags: u32) -> std::result::Result<RawFd, std:
         Ok(res as RawFd)
     }
 }
+
+// Check that the backend still supports all the virtio features which were
+// acked by the guest driver.
+fn check_backend_features(acked_features: u64, backend_features: u64) -> Result<()> {
+    let missing_features = acked_features & !backend_features;
+    if missing_features != 0 {
+        error!(
+            "vhost-user backend no longer supports features 0x{:x}",
+            missing_features
+        );
+        return Err(Error::InvalidFeatures);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_socket_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "ch-vhost-user-{}-{}.sock",
+                name,
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn test_check_backend_features() {
+        check_backend_features(0b1010, 0b1010).unwrap();
+        check_backend_features(0b1010, 0b1111).unwrap();
+        assert!(matches!(
+            check_backend_features(0b1010, 0b0010),
+            Err(Error::InvalidFeatures)
+        ));
+    }
+
+    #[test]
+    fn test_try_connect_does_not_wait() {
+        let socket_path = test_socket_path("connect");
+        let _ = std::fs::remove_file(&socket_path);
+
+        // Nobody is listening, the attempt fails right away instead of
+        // retrying until the backend shows up.
+        let now = Instant::now();
+        assert!(matches!(
+            VhostUserHandle::try_connect_vhost_user(&socket_path, 1),
+            Err(Error::VhostUserConnect)
+        ));
+        assert!(now.elapsed() < Duration::from_secs(1));
+
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+        VhostUserHandle::try_connect_vhost_user(&socket_path, 1).unwrap();
+
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    fn test_listen_vhost_user() {
+        let socket_path = test_socket_path("listen");
+
+        // A stale socket left behind doesn't prevent listening again.
+        drop(UnixListener::bind(&socket_path).unwrap());
+        let listener = VhostUserHandle::listen_vhost_user(&socket_path).unwrap();
+
+        // Accepting never blocks the epoll thread waiting for the backend.
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let _backend = UnixStream::connect(&socket_path).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let _vu = VhostUserHandle::from_stream(stream, 1);
+
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -28,6 +28,7 @@
 /// - a backend FD.
 ///
 use super::{VsockBackend, VsockPacket};
+use crate::histogram::QueueLatencies;
 use crate::seccomp_filters::Thread;
 use crate::Error as DeviceError;
 use crate::GuestMemoryMmap;
@@ -40,12 +41,15 @@ use crate::{
 use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use seccompiler::SeccompAction;
+use std::collections::HashMap;
 use std::io;
+use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Barrier, RwLock};
+use std::time::Instant;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use virtio_queue::Queue;
@@ -99,6 +103,7 @@ pub struct VsockEpollHandler<B: VsockBackend> {
     pub interrupt_cb: Arc<dyn VirtioInterrupt>,
     pub backend: Arc<RwLock<B>>,
     pub access_platform: Option<Arc<dyn AccessPlatform>>,
+    pub latencies: QueueLatencies,
 }
 
 impl<B> VsockEpollHandler<B>
@@ -125,6 +130,7 @@ where
     fn process_rx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_rx()");
 
+        let start = Instant::now();
         let mut used_descs = false;
 
         while let Some(mut desc_chain) = self.queues[0].pop_descriptor_chain(self.mem.memory()) {
@@ -155,6 +161,7 @@ where
         }
 
         if used_descs {
+            self.latencies.rx.record(start.elapsed().as_micros() as u64);
             self.signal_used_queue(0)
         } else {
             Ok(())
@@ -167,6 +174,7 @@ where
     fn process_tx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_tx()");
 
+        let start = Instant::now();
         let mut used_descs = false;
 
         while let Some(mut desc_chain) = self.queues[1].pop_descriptor_chain(self.mem.memory()) {
@@ -197,6 +205,7 @@ where
         }
 
         if used_descs {
+            self.latencies.tx.record(start.elapsed().as_micros() as u64);
             self.signal_used_queue(1)
         } else {
             Ok(())
@@ -321,6 +330,7 @@ pub struct Vsock<B: VsockBackend> {
     path: PathBuf,
     seccomp_action: SeccompAction,
     exit_evt: EventFd,
+    latencies: QueueLatencies,
 }
 
 #[derive(Versionize)]
@@ -377,6 +387,7 @@ where
             path,
             seccomp_action,
             exit_evt,
+            latencies: QueueLatencies::default(),
         })
     }
 
@@ -461,6 +472,7 @@ where
             interrupt_cb,
             backend: self.backend.clone(),
             access_platform: self.common.access_platform.clone(),
+            latencies: self.latencies.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -492,6 +504,13 @@ where
         std::fs::remove_file(&self.path).ok();
     }
 
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+        self.latencies.add_counters(&mut counters);
+
+        Some(counters)
+    }
+
     fn set_access_platform(&mut self, access_platform: Arc<dyn AccessPlatform>) {
         self.common.set_access_platform(access_platform)
     }
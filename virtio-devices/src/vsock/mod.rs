@@ -164,6 +164,7 @@ mod tests {
     use super::*;
     use crate::device::{VirtioInterrupt, VirtioInterruptType};
     use crate::epoll_helper::EpollHelperHandler;
+    use crate::histogram::QueueLatencies;
     use crate::EpollHelper;
     use crate::GuestMemoryMmap;
     use libc::EFD_NONBLOCK;
@@ -335,6 +336,7 @@ mod tests {
                     interrupt_cb,
                     backend: Arc::new(RwLock::new(TestBackend::new())),
                     access_platform: None,
+                    latencies: QueueLatencies::default(),
                 },
             }
         }
@@ -41,6 +41,8 @@ pub enum Error {
     ParseInteger(std::num::ParseIntError),
     /// Error reading stream port.
     ReadStreamPort(Box<Error>),
+    /// Error creating the timer tracking the sibling connection requests.
+    TimerFd(std::io::Error),
     /// Error accepting a new connection from the host-side Unix socket.
     UnixAccept(std::io::Error),
     /// Error binding to the host-side Unix socket.
@@ -51,6 +53,8 @@ pub enum Error {
     UnixRead(std::io::Error),
     /// Muxer connection limit reached.
     TooManyConnections,
+    /// A connection was requested on behalf of a CID which isn't a registered sibling.
+    UnknownSibling(u64),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -37,13 +37,32 @@
 //!
 //! To route all these events to their handlers, the muxer uses another `HashMap` object,
 //! mapping `RawFd`s to `EpollListener`s.
+//!
+//! ## Sibling VMs and port sockets
+//!
+//! Guest connection requests addressed to the CID of a sibling VM (i.e. a VM running on the
+//! same host, and registered through `VsockMuxer::add_sibling()`) are forwarded to that VM's
+//! host-side Unix socket, by issuing a "connect \<port> \<cid>" command, carrying the CID of
+//! the local guest along with the destination port. The sibling VM only accepts such a command
+//! for a CID it has itself registered as a sibling, and presents the connection to its guest as
+//! coming from that CID. Both guests thus see the connection established with the real CID of
+//! their peer. Requests which don't get acknowledged in time are dropped, as tracked by a timer
+//! registered under the nested epoll FD.
+//!
+//! Additional Unix sockets can also be bound to specific guest ports, through
+//! `VsockMuxer::bind_port()`. Connections accepted on such a socket are directly forwarded to
+//! the associated guest port, without expecting any "connect" command.
 
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::{Duration, Instant};
+use vmm_sys_util::timerfd::TimerFd;
 
+use super::super::csm::defs as csm_defs;
 use super::super::csm::ConnState;
 use super::super::defs::uapi;
 use super::super::packet::VsockPacket;
@@ -72,7 +91,11 @@ pub enum MuxerRx {
     /// The packet must be fetched from the connection identified by `ConnMapKey`.
     ConnRx(ConnMapKey),
     /// The muxer must produce an RST packet.
-    RstPkt { local_port: u32, peer_port: u32 },
+    RstPkt {
+        local_cid: u64,
+        local_port: u32,
+        peer_port: u32,
+    },
 }
 
 /// An epoll listener, registered under the muxer's nested epoll FD.
@@ -90,6 +113,32 @@ enum EpollListener {
     /// A listener interested in reading host "connect \<port>" commands from a freshly
     /// connected host socket.
     LocalStream(UnixStream),
+    /// A listener interested in new host-initiated connections, targeting the guest port
+    /// `port`.
+    PortSock { sock: UnixListener, port: u32 },
+    /// A listener interested in the acknowledgement of a guest connection request forwarded
+    /// to a sibling VM.
+    SiblingStream(SiblingRequest),
+    /// A listener interested in the expiry of the connection requests forwarded to sibling
+    /// VMs.
+    SiblingTimer,
+}
+
+/// A guest connection request forwarded to a sibling VM, waiting for the sibling to
+/// acknowledge it.
+///
+struct SiblingRequest {
+    stream: UnixStream,
+    /// The key of the connection, once acknowledged.
+    key: ConnMapKey,
+    /// The CID of the sibling VM.
+    local_cid: u64,
+    /// The buffer space the guest advertised in its connection request.
+    peer_buf_alloc: u32,
+    /// The acknowledgement read so far.
+    ack: Vec<u8>,
+    /// The time the sibling has to acknowledge the request by.
+    expiry: Instant,
 }
 
 /// The vsock connection multiplexer.
@@ -122,6 +171,17 @@ pub struct VsockMuxer {
     local_port_set: HashSet<u32>,
     /// The last used host-side port.
     local_port_last: u32,
+    /// A hash map used to look up the host-side Unix socket of sibling VMs, keyed by CID.
+    siblings: HashMap<u64, String>,
+    /// The timer used to give up on the connection requests forwarded to sibling VMs, which
+    /// weren't acknowledged in time.
+    sibling_timer: TimerFd,
+    /// The file system paths of the Unix sockets bound to specific guest ports.
+    port_sock_paths: Vec<String>,
+    /// A hash set used to keep track of the host-initiated connections which must not be
+    /// acknowledged once established, since they weren't initiated through a "connect"
+    /// command.
+    no_ack_conns: HashSet<ConnMapKey>,
 }
 
 impl VsockChannel for VsockMuxer {
@@ -145,11 +205,12 @@ impl VsockChannel for VsockMuxer {
             let res = match rx {
                 // We need to build an RST packet, going from `local_port` to `peer_port`.
                 MuxerRx::RstPkt {
+                    local_cid,
                     local_port,
                     peer_port,
                 } => {
                     pkt.set_op(uapi::VSOCK_OP_RST)
-                        .set_src_cid(uapi::VSOCK_HOST_CID)
+                        .set_src_cid(local_cid)
                         .set_dst_cid(self.cid)
                         .set_src_port(local_port)
                         .set_dst_port(peer_port)
@@ -221,13 +282,14 @@ impl VsockChannel for VsockMuxer {
         // If this packet has an unsupported type (!=stream), we must send back an RST.
         //
         if pkt.type_() != uapi::VSOCK_TYPE_STREAM {
-            self.enq_rst(pkt.dst_port(), pkt.src_port());
+            self.enq_rst(pkt.dst_cid(), pkt.dst_port(), pkt.src_port());
             return Ok(());
         }
 
         // We don't know how to handle packets addressed to other CIDs. We only handle the host
-        // part of the guest - host communication here.
-        if pkt.dst_cid() != uapi::VSOCK_HOST_CID {
+        // part of the guest - host communication here, along with the communication with
+        // sibling VMs.
+        if pkt.dst_cid() != uapi::VSOCK_HOST_CID && !self.siblings.contains_key(&pkt.dst_cid()) {
             info!(
                 "vsock: dropping guest packet for unknown CID: {:?}",
                 pkt.hdr()
@@ -244,7 +306,7 @@ impl VsockChannel for VsockMuxer {
                 self.handle_peer_request_pkt(pkt);
             } else {
                 // Send back an RST, to let the drive know we weren't expecting this packet.
-                self.enq_rst(pkt.dst_port(), pkt.src_port());
+                self.enq_rst(pkt.dst_cid(), pkt.dst_port(), pkt.src_port());
             }
             return Ok(());
         }
@@ -298,6 +360,8 @@ impl VsockEpollListener for VsockMuxer {
     fn notify(&mut self, _: epoll::Events) {
         debug!("vsock: muxer received kick");
 
+        self.sweep_sibling_requests();
+
         let mut epoll_events = vec![epoll::Event::new(epoll::Events::empty(), 0); 32];
         'epoll: loop {
             match epoll::wait(self.epoll_file.as_raw_fd(), 0, epoll_events.as_mut_slice()) {
@@ -350,6 +414,8 @@ impl VsockMuxer {
             .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
             .map_err(Error::UnixBind)?;
 
+        let sibling_timer = TimerFd::new().map_err(Error::TimerFd)?;
+
         let mut muxer = Self {
             cid,
             host_sock,
@@ -361,12 +427,36 @@ impl VsockMuxer {
             killq: MuxerKillQ::new(),
             local_port_last: (1u32 << 30) - 1,
             local_port_set: HashSet::with_capacity(defs::MAX_CONNECTIONS),
+            siblings: HashMap::new(),
+            sibling_timer,
+            port_sock_paths: Vec::new(),
+            no_ack_conns: HashSet::new(),
         };
 
         muxer.add_listener(muxer.host_sock.as_raw_fd(), EpollListener::HostSock)?;
+        muxer.add_listener(muxer.sibling_timer.as_raw_fd(), EpollListener::SiblingTimer)?;
         Ok(muxer)
     }
 
+    /// Register a sibling VM, identified by its guest CID, and reachable through the
+    /// host-side Unix socket of its own vsock device.
+    ///
+    pub fn add_sibling(&mut self, cid: u64, host_sock_path: String) {
+        self.siblings.insert(cid, host_sock_path);
+    }
+
+    /// Bind a host-side Unix socket to the guest port `port`, so that host applications can
+    /// reach a guest service without issuing a "connect" command.
+    ///
+    pub fn bind_port(&mut self, port: u32, sock_path: String) -> Result<()> {
+        let sock = UnixListener::bind(&sock_path)
+            .and_then(|sock| sock.set_nonblocking(true).map(|_| sock))
+            .map_err(Error::UnixBind)?;
+        self.port_sock_paths.push(sock_path);
+
+        self.add_listener(sock.as_raw_fd(), EpollListener::PortSock { sock, port })
+    }
+
     /// Handle/dispatch an epoll event to its listener.
     ///
     fn handle_event(&mut self, fd: RawFd, event_set: epoll::Events) {
@@ -426,19 +516,26 @@ impl VsockMuxer {
             Some(EpollListener::LocalStream(_)) => {
                 if let Some(EpollListener::LocalStream(mut stream)) = self.remove_listener(fd) {
                     Self::read_local_stream_port(&mut stream)
-                        .map(|peer_port| (self.allocate_local_port(), peer_port))
-                        .and_then(|(local_port, peer_port)| {
+                        .and_then(|(peer_port, sibling_cid)| match sibling_cid {
+                            // Only the VMs registered as siblings may connect on behalf of
+                            // their guest.
+                            Some(cid) if !self.siblings.contains_key(&cid) => {
+                                Err(Error::UnknownSibling(cid))
+                            }
+                            Some(cid) => Ok((peer_port, cid)),
+                            None => Ok((peer_port, uapi::VSOCK_HOST_CID)),
+                        })
+                        .map(|(peer_port, local_cid)| {
+                            (self.allocate_local_port(), peer_port, local_cid)
+                        })
+                        .and_then(|(local_port, peer_port, local_cid)| {
                             self.add_connection(
                                 ConnMapKey {
                                     local_port,
                                     peer_port,
                                 },
                                 MuxerConnection::new_local_init(
-                                    stream,
-                                    uapi::VSOCK_HOST_CID,
-                                    self.cid,
-                                    local_port,
-                                    peer_port,
+                                    stream, local_cid, self.cid, local_port, peer_port,
                                 ),
                             )
                         })
@@ -448,6 +545,89 @@ impl VsockMuxer {
                 }
             }
 
+            // The sibling VM answered a forwarded connection request, or closed the socket.
+            Some(EpollListener::SiblingStream(_)) => {
+                if let Some(EpollListener::SiblingStream(mut request)) = self.remove_listener(fd) {
+                    let (key, local_cid) = (request.key, request.local_cid);
+                    let res = match Self::read_sibling_ack(&mut request) {
+                        // The acknowledgement is still on its way.
+                        Ok(false) => self.add_listener(fd, EpollListener::SiblingStream(request)),
+                        Ok(true) => self.add_connection(
+                            key,
+                            MuxerConnection::new_peer_init(
+                                request.stream,
+                                local_cid,
+                                self.cid,
+                                key.local_port,
+                                key.peer_port,
+                                request.peer_buf_alloc,
+                            ),
+                        ),
+                        Err(e) => Err(Error::UnixConnect(e)),
+                    };
+                    if let Err(err) = res {
+                        info!("vsock: error connecting to sibling VM: {:?}", err);
+                        self.enq_rst(local_cid, key.local_port, key.peer_port);
+                    }
+                }
+            }
+
+            // Some connection requests forwarded to sibling VMs may have expired.
+            Some(EpollListener::SiblingTimer) => {
+                // The expiration count doesn't matter, each request is checked against its
+                // own deadline.
+                if let Err(err) = self.sibling_timer.wait() {
+                    warn!("vsock: error reading the sibling request timer: {:?}", err);
+                }
+                self.sweep_sibling_requests();
+                self.arm_sibling_timer();
+            }
+
+            // A new host-initiated connection, targeting a known guest port, is ready to be
+            // accepted.
+            Some(EpollListener::PortSock { sock, port }) => {
+                let peer_port = *port;
+                let accepted = sock.accept();
+                if self.conn_map.len() == defs::MAX_CONNECTIONS {
+                    // If we're already maxed-out on connections, the freshly accepted one
+                    // is simply discarded.
+                    warn!("vsock: connection limit reached; refusing new host connection");
+                    return;
+                }
+                accepted
+                    .map_err(Error::UnixAccept)
+                    .and_then(|(stream, _)| {
+                        stream
+                            .set_nonblocking(true)
+                            .map(|_| stream)
+                            .map_err(Error::UnixAccept)
+                    })
+                    .and_then(|stream| {
+                        let local_port = self.allocate_local_port();
+                        let key = ConnMapKey {
+                            local_port,
+                            peer_port,
+                        };
+                        self.no_ack_conns.insert(key);
+                        self.add_connection(
+                            key,
+                            MuxerConnection::new_local_init(
+                                stream,
+                                uapi::VSOCK_HOST_CID,
+                                self.cid,
+                                local_port,
+                                peer_port,
+                            ),
+                        )
+                    })
+                    .unwrap_or_else(|err| {
+                        warn!(
+                            "vsock: unable to accept connection for port {}: {:?}",
+                            peer_port, err
+                        );
+                    });
+            }
+
             _ => {
                 info!(
                     "vsock: unexpected event: fd={:?}, event_set={:?}",
@@ -457,10 +637,11 @@ impl VsockMuxer {
         }
     }
 
-    /// Parse a host "connect" command, and extract the destination vsock port.
+    /// Parse a host "connect" command, and extract the destination vsock port, along with the
+    /// CID of the guest the connection comes from, when issued by a sibling VM.
     ///
-    fn read_local_stream_port(stream: &mut UnixStream) -> Result<u32> {
-        let mut buf = [0u8; 32];
+    fn read_local_stream_port(stream: &mut UnixStream) -> Result<(u32, Option<u64>)> {
+        let mut buf = [0u8; 48];
 
         // This is the minimum number of bytes that we should be able to read, when parsing a
         // valid connection request. I.e. `b"connect 0\n".len()`.
@@ -498,6 +679,13 @@ impl VsockMuxer {
             })
             .and_then(|_| word_iter.next().ok_or(Error::InvalidPortRequest))
             .and_then(|word| word.parse::<u32>().map_err(Error::ParseInteger))
+            .and_then(|port| {
+                word_iter
+                    .next()
+                    .map(|word| word.parse::<u64>().map_err(Error::ParseInteger))
+                    .transpose()
+                    .map(|cid| (port, cid))
+            })
             .map_err(|e| Error::ReadStreamPort(Box::new(e)))
     }
 
@@ -543,6 +731,7 @@ impl VsockMuxer {
         if let Some(conn) = self.conn_map.remove(&key) {
             self.remove_listener(conn.get_polled_fd());
         }
+        self.no_ack_conns.remove(&key);
         self.free_local_port(key.local_port);
     }
 
@@ -573,6 +762,9 @@ impl VsockMuxer {
             EpollListener::Connection { evset, .. } => evset,
             EpollListener::LocalStream(_) => epoll::Events::EPOLLIN,
             EpollListener::HostSock => epoll::Events::EPOLLIN,
+            EpollListener::PortSock { .. } => epoll::Events::EPOLLIN,
+            EpollListener::SiblingStream(_) => epoll::Events::EPOLLIN,
+            EpollListener::SiblingTimer => epoll::Events::EPOLLIN,
         };
 
         epoll::ctl(
@@ -642,10 +834,40 @@ impl VsockMuxer {
     /// connection object will be created and added to the connection pool. On failure, a new
     /// RST packet will be scheduled for delivery to the guest.
     ///
+    /// Requests addressed to a sibling VM are forwarded to the host-side Unix socket of this
+    /// sibling, by issuing a "connect" command for the destination port. The connection is
+    /// only added to the pool once the sibling acknowledged it, which the muxer epoll gets
+    /// notified about.
+    ///
     fn handle_peer_request_pkt(&mut self, pkt: &VsockPacket) {
-        let port_path = format!("{}_{}", self.host_sock_path, pkt.dst_port());
+        if let Some(sibling_path) = self.siblings.get(&pkt.dst_cid()).cloned() {
+            self.sweep_sibling_requests();
+            Self::connect_sibling(&sibling_path, pkt.dst_port(), self.cid)
+                .map_err(Error::UnixConnect)
+                .and_then(|stream| {
+                    let fd = stream.as_raw_fd();
+                    self.add_listener(
+                        fd,
+                        EpollListener::SiblingStream(SiblingRequest {
+                            stream,
+                            key: ConnMapKey {
+                                local_port: pkt.dst_port(),
+                                peer_port: pkt.src_port(),
+                            },
+                            local_cid: pkt.dst_cid(),
+                            peer_buf_alloc: pkt.buf_alloc(),
+                            ack: Vec::new(),
+                            expiry: Instant::now()
+                                + Duration::from_millis(csm_defs::CONN_REQUEST_TIMEOUT_MS),
+                        }),
+                    )
+                })
+                .unwrap_or_else(|_| self.enq_rst(pkt.dst_cid(), pkt.dst_port(), pkt.src_port()));
+            self.arm_sibling_timer();
+            return;
+        }
 
-        UnixStream::connect(port_path)
+        UnixStream::connect(format!("{}_{}", self.host_sock_path, pkt.dst_port()))
             .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
             .map_err(Error::UnixConnect)
             .and_then(|stream| {
@@ -656,7 +878,7 @@ impl VsockMuxer {
                     },
                     MuxerConnection::new_peer_init(
                         stream,
-                        uapi::VSOCK_HOST_CID,
+                        pkt.dst_cid(),
                         self.cid,
                         pkt.dst_port(),
                         pkt.src_port(),
@@ -664,7 +886,153 @@ impl VsockMuxer {
                     ),
                 )
             })
-            .unwrap_or_else(|_| self.enq_rst(pkt.dst_port(), pkt.src_port()));
+            .unwrap_or_else(|_| self.enq_rst(pkt.dst_cid(), pkt.dst_port(), pkt.src_port()));
+    }
+
+    /// Connect to the host-side Unix socket of a sibling VM, and issue the "connect" command
+    /// for `port` on behalf of the guest `cid`, without blocking. A sibling that has too many
+    /// connections waiting to be accepted refuses the new one.
+    ///
+    fn connect_sibling(sock_path: &str, port: u32, cid: u64) -> io::Result<UnixStream> {
+        // SAFETY: FFI call with valid arguments
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a valid socket, owned by the stream from now on
+        let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
+
+        // SAFETY: an all-zero sockaddr_un is valid
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        if sock_path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sibling socket path too long",
+            ));
+        }
+        for (dst, src) in addr.sun_path.iter_mut().zip(sock_path.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        // SAFETY: FFI call with a valid socket and address
+        let ret = unsafe {
+            libc::connect(
+                fd,
+                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The socket buffer of a fresh connection always has room for the command.
+        stream.write_all(format!("connect {port} {cid}\n").as_bytes())?;
+        Ok(stream)
+    }
+
+    /// Read the acknowledgement of a connection request forwarded to a sibling VM, as far as
+    /// available. Returns whether the whole acknowledgement was read.
+    ///
+    /// The acknowledgement is read one byte at a time, to make sure none of the data the
+    /// sibling might send right after it gets consumed.
+    ///
+    fn read_sibling_ack(request: &mut SiblingRequest) -> io::Result<bool> {
+        // Maximum length of a "OK \<port>" acknowledgement.
+        const MAX_ACK_LEN: usize = 32;
+
+        let mut byte = [0u8; 1];
+        loop {
+            match request.stream.read(&mut byte) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionRefused,
+                        "connection refused by sibling",
+                    ))
+                }
+                Ok(_) if byte[0] == b'\n' => break,
+                Ok(_) if request.ack.len() == MAX_ACK_LEN => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid sibling acknowledgement",
+                    ))
+                }
+                Ok(_) => request.ack.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !request.ack.starts_with(b"OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "connection refused by sibling",
+            ));
+        }
+
+        Ok(true)
+    }
+
+    /// Give up on the connection requests forwarded to sibling VMs which weren't acknowledged
+    /// in time, and let the guest know by sending it an RST packet.
+    ///
+    fn sweep_sibling_requests(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<RawFd> = self
+            .listener_map
+            .iter()
+            .filter_map(|(fd, listener)| match listener {
+                EpollListener::SiblingStream(request) if request.expiry <= now => Some(*fd),
+                _ => None,
+            })
+            .collect();
+
+        for fd in expired {
+            if let Some(EpollListener::SiblingStream(request)) = self.remove_listener(fd) {
+                info!("vsock: sibling VM connection request timed out");
+                self.enq_rst(
+                    request.local_cid,
+                    request.key.local_port,
+                    request.key.peer_port,
+                );
+            }
+        }
+    }
+
+    /// Arm the sibling request timer for the earliest deadline of the connection requests
+    /// forwarded to sibling VMs, or disarm it if there's none left.
+    ///
+    fn arm_sibling_timer(&mut self) {
+        let expiry = self
+            .listener_map
+            .values()
+            .filter_map(|listener| match listener {
+                EpollListener::SiblingStream(request) => Some(request.expiry),
+                _ => None,
+            })
+            .min();
+
+        let res = match expiry {
+            // A zero duration would disarm the timer, while the deadline may have just passed.
+            Some(expiry) => self.sibling_timer.reset(
+                cmp::max(
+                    expiry.saturating_duration_since(Instant::now()),
+                    Duration::from_millis(1),
+                ),
+                None,
+            ),
+            None => self.sibling_timer.clear(),
+        };
+        if let Err(err) = res {
+            warn!("vsock: error arming the sibling request timer: {:?}", err);
+        }
     }
 
     /// Perform an action that might mutate a connection's state.
@@ -688,7 +1056,10 @@ impl VsockMuxer {
 
             // If this is a host-initiated connection that has just become established, we'll have
             // to send an ack message to the host end.
-            if prev_state == ConnState::LocalInit && conn.state() == ConnState::Established {
+            if prev_state == ConnState::LocalInit
+                && conn.state() == ConnState::Established
+                && !self.no_ack_conns.remove(&key)
+            {
                 let msg = format!("OK {}\n", key.local_port);
                 match conn.send_bytes_raw(msg.as_bytes()) {
                     Ok(written) if written == msg.len() => (),
@@ -804,8 +1175,9 @@ impl VsockMuxer {
     /// handle them. We do, however, log a warning, since not being able to enqueue an RST
     /// packet means we have to drop it, which is not normal operation.
     ///
-    fn enq_rst(&mut self, local_port: u32, peer_port: u32) {
+    fn enq_rst(&mut self, local_cid: u64, local_port: u32, peer_port: u32) {
         let pushed = self.rxq.push(MuxerRx::RstPkt {
+            local_cid,
             local_port,
             peer_port,
         });
@@ -818,6 +1190,14 @@ impl VsockMuxer {
     }
 }
 
+impl Drop for VsockMuxer {
+    fn drop(&mut self) {
+        for path in self.port_sock_paths.iter() {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::{Read, Write};
@@ -1426,4 +1806,182 @@ mod tests {
         // not be any pending RX in the muxer.
         assert!(!ctx.muxer.has_pending_rx());
     }
+
+    #[test]
+    fn test_sibling_connection() {
+        const SIBLING_CID: u64 = PEER_CID + 1;
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("sibling_connection");
+
+        // Test sibling connection refused, as nothing listens on the sibling socket.
+        let sibling_path = format!("{}_sibling", ctx.muxer.host_sock_path);
+        ctx.muxer.add_sibling(SIBLING_CID, sibling_path.clone());
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_dst_cid(SIBLING_CID);
+        ctx.send();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_cid(), SIBLING_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+
+        // Test sibling connection accepted, the sibling muxer being emulated by a thread
+        // acknowledging the "connect" command.
+        let listener = LocalListener::new(sibling_path);
+        let sock = listener.sock.try_clone().unwrap();
+        sock.set_nonblocking(false).unwrap();
+        let sibling = std::thread::spawn(move || {
+            let (mut stream, _) = sock.accept().unwrap();
+            let mut buf = [0u8; 32];
+            let len = stream.read(&mut buf).unwrap();
+            assert_eq!(
+                &buf[..len],
+                format!("connect {LOCAL_PORT} {PEER_CID}\n").as_bytes()
+            );
+            stream.write_all(b"OK 1073741824\n").unwrap();
+            stream
+        });
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_dst_cid(SIBLING_CID);
+        ctx.send();
+        // The muxer doesn't wait for the acknowledgement, the connection only being
+        // established once it gets notified about it.
+        assert!(!ctx.muxer.has_pending_rx());
+        let mut stream = sibling.join().unwrap();
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RESPONSE);
+        assert_eq!(ctx.pkt.src_cid(), SIBLING_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+
+        // Test guest -> sibling data flow.
+        let data = [1, 2, 3, 4];
+        ctx.init_data_pkt(LOCAL_PORT, PEER_PORT, &data)
+            .set_dst_cid(SIBLING_CID);
+        ctx.send();
+        let mut buf = vec![0; data.len()];
+        stream.read_exact(buf.as_mut_slice()).unwrap();
+        assert_eq!(buf.as_slice(), data);
+    }
+
+    #[test]
+    fn test_sibling_request_timeout() {
+        const SIBLING_CID: u64 = PEER_CID + 1;
+        const LOCAL_PORT: u32 = 1026;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("sibling_request_timeout");
+        let sibling_path = format!("{}_sibling", ctx.muxer.host_sock_path);
+        ctx.muxer.add_sibling(SIBLING_CID, sibling_path.clone());
+
+        // The sibling accepts the connection, but never acknowledges it.
+        let _listener = LocalListener::new(sibling_path);
+        ctx.init_pkt(LOCAL_PORT, PEER_PORT, uapi::VSOCK_OP_REQUEST)
+            .set_dst_cid(SIBLING_CID);
+        ctx.send();
+        assert!(!ctx.muxer.has_pending_rx());
+
+        // Without any other activity, the muxer FD becomes ready once the request expired.
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 1];
+        let ev_cnt = epoll::wait(
+            ctx.muxer.get_polled_fd(),
+            (2 * csm_defs::CONN_REQUEST_TIMEOUT_MS) as i32,
+            events.as_mut_slice(),
+        )
+        .unwrap();
+        assert_eq!(ev_cnt, 1);
+
+        ctx.notify_muxer();
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RST);
+        assert_eq!(ctx.pkt.src_cid(), SIBLING_CID);
+        assert_eq!(ctx.pkt.src_port(), LOCAL_PORT);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        assert!(!ctx
+            .muxer
+            .listener_map
+            .values()
+            .any(|listener| matches!(listener, EpollListener::SiblingStream(_))));
+    }
+
+    #[test]
+    fn test_sibling_incoming_connection() {
+        const SIBLING_CID: u64 = PEER_CID + 1;
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("sibling_incoming_connection");
+        ctx.muxer
+            .add_sibling(SIBLING_CID, format!("{}_sibling", ctx.muxer.host_sock_path));
+
+        // A CID which isn't registered as a sibling is refused.
+        let mut stream = UnixStream::connect(&ctx.muxer.host_sock_path).unwrap();
+        ctx.notify_muxer();
+        stream
+            .write_all(format!("connect {PEER_PORT} {}\n", SIBLING_CID + 1).as_bytes())
+            .unwrap();
+        ctx.notify_muxer();
+        assert!(!ctx.muxer.has_pending_rx());
+        let mut buf = [0u8; 32];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        // The guest sees the connection coming from the sibling CID, not from the host.
+        let mut stream = UnixStream::connect(&ctx.muxer.host_sock_path).unwrap();
+        ctx.notify_muxer();
+        stream
+            .write_all(format!("connect {PEER_PORT} {SIBLING_CID}\n").as_bytes())
+            .unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.src_cid(), SIBLING_CID);
+        assert_eq!(ctx.pkt.dst_cid(), PEER_CID);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+    }
+
+    #[test]
+    fn test_port_sock_connection() {
+        const PEER_PORT: u32 = 1025;
+
+        let mut ctx = MuxerTestContext::new("port_sock_connection");
+        let port_path = format!("{}_bound", ctx.muxer.host_sock_path);
+        ctx.muxer.bind_port(PEER_PORT, port_path.clone()).unwrap();
+
+        let mut stream = UnixStream::connect(&port_path).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        ctx.notify_muxer();
+
+        // No "connect" command is expected, the connection request for the bound guest port
+        // should be available right away.
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_REQUEST);
+        assert_eq!(ctx.pkt.dst_port(), PEER_PORT);
+        let local_port = ctx.pkt.src_port();
+
+        ctx.init_pkt(local_port, PEER_PORT, uapi::VSOCK_OP_RESPONSE);
+        ctx.send();
+
+        // The connection must not have been acknowledged.
+        let mut buf = [0u8; 32];
+        assert_eq!(
+            stream.read(&mut buf[..]).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Test host -> guest data flow.
+        let data = [5u8, 6, 7, 8];
+        stream.write_all(&data).unwrap();
+        ctx.notify_muxer();
+        assert!(ctx.muxer.has_pending_rx());
+        ctx.recv();
+        assert_eq!(ctx.pkt.op(), uapi::VSOCK_OP_RW);
+        assert_eq!(ctx.pkt.buf().unwrap()[..data.len()], data);
+
+        drop(ctx);
+        assert!(!Path::new(&port_path).exists());
+    }
 }
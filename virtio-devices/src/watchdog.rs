@@ -7,8 +7,8 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, VirtioCommon,
-    VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
+    ActivateError, ActivateResult, EpollHelper, EpollHelperError, EpollHelperHandler, QueueCursors,
+    VirtioCommon, VirtioDevice, VirtioDeviceType, EPOLL_HELPER_EVENT_LAST, VIRTIO_F_VERSION_1,
 };
 use crate::seccomp_filters::Thread;
 use crate::thread_helper::spawn_virtio_thread;
@@ -16,13 +16,17 @@ use crate::GuestMemoryMmap;
 use crate::{VirtioInterrupt, VirtioInterruptType};
 use anyhow::anyhow;
 use seccompiler::SeccompAction;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::{self, Read};
+use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::result;
-use std::sync::atomic::AtomicBool;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -47,6 +51,83 @@ const WATCHDOG_TIMER_INTERVAL: i64 = 15;
 // Number of seconds since last ping to trigger reboot
 const WATCHDOG_TIMEOUT: u64 = WATCHDOG_TIMER_INTERVAL as u64 + 5;
 
+/// Action taken when the guest stops pinging the watchdog.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WatchdogAction {
+    /// Reset the VM.
+    #[default]
+    Reset,
+    /// Power the VM off.
+    Poweroff,
+    /// Pause the VM, leaving it available for inspection.
+    Pause,
+    /// Only report the expiry through the event monitor.
+    Event,
+    /// Handle the expiry as a guest panic, applying the pvpanic policy.
+    Panic,
+}
+
+#[derive(Debug)]
+pub enum ParseWatchdogActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for WatchdogAction {
+    type Err = ParseWatchdogActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reset" => Ok(WatchdogAction::Reset),
+            "poweroff" => Ok(WatchdogAction::Poweroff),
+            "pause" => Ok(WatchdogAction::Pause),
+            "event" => Ok(WatchdogAction::Event),
+            "panic" => Ok(WatchdogAction::Panic),
+            _ => Err(ParseWatchdogActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// Escalation of the expiries handled by resetting the VM: past `resets`
+/// resets within `window`, the VM is powered off instead.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchdogEscalation {
+    pub resets: u32,
+    pub window: Duration,
+}
+
+/// Events of the watchdog, meant to be shared across VM reboots.
+#[derive(Default)]
+pub struct WatchdogCounters {
+    expirations: AtomicU64,
+    resets: AtomicU64,
+    poweroffs: AtomicU64,
+    pauses: AtomicU64,
+    panics: AtomicU64,
+    escalations: AtomicU64,
+    // Times of the resets within the escalation window
+    recent_resets: Mutex<VecDeque<Instant>>,
+}
+
+impl WatchdogCounters {
+    // Whether the VM was already reset as many times as allowed within the
+    // escalation window, the reset being recorded otherwise.
+    fn escalate(&self, escalation: &WatchdogEscalation, now: Instant) -> bool {
+        let mut recent_resets = self.recent_resets.lock().unwrap();
+        while recent_resets.front().map_or(false, |reset| {
+            now.duration_since(*reset) > escalation.window
+        }) {
+            recent_resets.pop_front();
+        }
+
+        if recent_resets.len() >= escalation.resets as usize {
+            return true;
+        }
+        recent_resets.push_back(now);
+
+        false
+    }
+}
+
 #[derive(Error, Debug)]
 enum Error {
     #[error("Error programming timer fd: {0}")]
@@ -64,6 +145,7 @@ enum Error {
 struct WatchdogEpollHandler {
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     queue: Queue,
+    queue_cursors: Arc<QueueCursors>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
@@ -71,6 +153,12 @@ struct WatchdogEpollHandler {
     timer: File,
     last_ping_time: Arc<Mutex<Option<Instant>>>,
     reset_evt: EventFd,
+    vm_pause_evt: EventFd,
+    exit_evt: EventFd,
+    guest_panic_evt: EventFd,
+    action: WatchdogAction,
+    escalation: Option<WatchdogEscalation>,
+    counters: Arc<WatchdogCounters>,
 }
 
 impl WatchdogEpollHandler {
@@ -111,6 +199,7 @@ impl WatchdogEpollHandler {
     }
 
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
+        self.queue_cursors.signalled(&self.queue);
         self.interrupt_cb
             .trigger(VirtioInterruptType::Queue(0))
             .map_err(|e| {
@@ -119,6 +208,68 @@ impl WatchdogEpollHandler {
             })
     }
 
+    fn expire(&mut self, gap: u64) {
+        error!(
+            "Watchdog triggered: {} seconds since last ping, action {:?}",
+            gap, self.action
+        );
+        self.counters.expirations.fetch_add(1, Ordering::AcqRel);
+        event!(
+            "virtio-watchdog",
+            "expired",
+            "action",
+            format!("{:?}", self.action)
+        );
+
+        match self.action {
+            WatchdogAction::Reset => {
+                if let Some(escalation) = self.escalation.as_ref() {
+                    if self.counters.escalate(escalation, Instant::now()) {
+                        error!(
+                            "Watchdog already reset the VM {} times within {:?}, powering it off",
+                            escalation.resets, escalation.window
+                        );
+                        self.counters.escalations.fetch_add(1, Ordering::AcqRel);
+                        self.counters.poweroffs.fetch_add(1, Ordering::AcqRel);
+                        event!(
+                            "virtio-watchdog",
+                            "alert",
+                            "resets",
+                            escalation.resets.to_string()
+                        );
+                        self.exit_evt.write(1).ok();
+                        return;
+                    }
+                }
+                self.counters.resets.fetch_add(1, Ordering::AcqRel);
+                self.reset_evt.write(1).ok();
+            }
+            WatchdogAction::Poweroff => {
+                self.counters.poweroffs.fetch_add(1, Ordering::AcqRel);
+                self.exit_evt.write(1).ok();
+            }
+            WatchdogAction::Pause => {
+                // The timer keeps firing until the VMM gets to pause the
+                // device, which must not be taken as another expiry.
+                self.last_ping_time.lock().unwrap().replace(Instant::now());
+                self.counters.pauses.fetch_add(1, Ordering::AcqRel);
+                self.vm_pause_evt.write(1).ok();
+            }
+            WatchdogAction::Event => {
+                // The VM keeps running, so give the guest another full
+                // timeout before reporting the next expiry.
+                self.last_ping_time.lock().unwrap().replace(Instant::now());
+            }
+            WatchdogAction::Panic => {
+                // Don't report the same hang again if the policy lets the
+                // VM run.
+                self.last_ping_time.lock().unwrap().replace(Instant::now());
+                self.counters.panics.fetch_add(1, Ordering::AcqRel);
+                self.guest_panic_evt.write(1).ok();
+            }
+        }
+    }
+
     fn run(
         &mut self,
         paused: Arc<AtomicBool>,
@@ -166,12 +317,13 @@ impl EpollHelperHandler for WatchdogEpollHandler {
                     EpollHelperError::HandleEvent(anyhow!("Error reading from timer fd: {:}", e))
                 })?;
 
-                if let Some(last_ping_time) = self.last_ping_time.lock().unwrap().as_ref() {
-                    let now = Instant::now();
-                    let gap = now.duration_since(*last_ping_time).as_secs();
+                let gap =
+                    self.last_ping_time.lock().unwrap().map(|last_ping_time| {
+                        Instant::now().duration_since(last_ping_time).as_secs()
+                    });
+                if let Some(gap) = gap {
                     if gap > WATCHDOG_TIMEOUT {
-                        error!("Watchdog triggered: {} seconds since last ping", gap);
-                        self.reset_evt.write(1).ok();
+                        self.expire(gap);
                     }
                 }
             }
@@ -182,6 +334,7 @@ impl EpollHelperHandler for WatchdogEpollHandler {
                 )));
             }
         }
+        self.queue_cursors.update(&self.queue);
         Ok(())
     }
 }
@@ -192,9 +345,14 @@ pub struct Watchdog {
     id: String,
     seccomp_action: SeccompAction,
     reset_evt: EventFd,
+    vm_pause_evt: EventFd,
+    action: WatchdogAction,
+    escalation: Option<WatchdogEscalation>,
+    counters: Arc<WatchdogCounters>,
     last_ping_time: Arc<Mutex<Option<Instant>>>,
     timer: File,
     exit_evt: EventFd,
+    guest_panic_evt: EventFd,
 }
 
 #[derive(Versionize)]
@@ -207,12 +365,21 @@ pub struct WatchdogState {
 impl VersionMapped for WatchdogState {}
 
 impl Watchdog {
-    /// Create a new virtio watchdog device that will reboot VM if the guest hangs
+    /// Create a new virtio watchdog device that will apply `action` to the VM
+    /// if the guest hangs, the resets escalating to powering the VM off as
+    /// set by `escalation`. `counters` records the events of the watchdog and
+    /// is meant to be shared across VM reboots.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         reset_evt: EventFd,
+        vm_pause_evt: EventFd,
+        action: WatchdogAction,
+        escalation: Option<WatchdogEscalation>,
+        counters: Arc<WatchdogCounters>,
         seccomp_action: SeccompAction,
         exit_evt: EventFd,
+        guest_panic_evt: EventFd,
         state: Option<WatchdogState>,
     ) -> io::Result<Watchdog> {
         let mut last_ping_time = None;
@@ -252,9 +419,14 @@ impl Watchdog {
             id,
             seccomp_action,
             reset_evt,
+            vm_pause_evt,
+            action,
+            escalation,
+            counters,
             last_ping_time: Arc::new(Mutex::new(last_ping_time)),
             timer,
             exit_evt,
+            guest_panic_evt,
         })
     }
 
@@ -346,16 +518,32 @@ impl VirtioDevice for Watchdog {
             ActivateError::BadActivate
         })?;
 
+        let vm_pause_evt = self.vm_pause_evt.try_clone().map_err(|e| {
+            error!("Failed to clone vm_pause_evt eventfd: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        let exit_evt = self.exit_evt.try_clone().map_err(|e| {
+            error!("Failed to clone exit_evt eventfd: {}", e);
+            ActivateError::BadActivate
+        })?;
+
+        let guest_panic_evt = self.guest_panic_evt.try_clone().map_err(|e| {
+            error!("Failed to clone guest_panic_evt eventfd: {}", e);
+            ActivateError::BadActivate
+        })?;
+
         let timer = self.timer.try_clone().map_err(|e| {
             error!("Failed to clone timer fd: {}", e);
             ActivateError::BadActivate
         })?;
 
-        let (_, queue, queue_evt) = queues.remove(0);
+        let (index, queue, queue_evt) = queues.remove(0);
 
         let mut handler = WatchdogEpollHandler {
             mem,
             queue,
+            queue_cursors: self.common.queue_cursors(index),
             interrupt_cb,
             queue_evt,
             kill_evt,
@@ -363,6 +551,12 @@ impl VirtioDevice for Watchdog {
             timer,
             last_ping_time: self.last_ping_time.clone(),
             reset_evt,
+            vm_pause_evt,
+            exit_evt,
+            guest_panic_evt,
+            action: self.action,
+            escalation: self.escalation,
+            counters: self.counters.clone(),
         };
 
         let paused = self.common.paused.clone();
@@ -389,6 +583,41 @@ impl VirtioDevice for Watchdog {
         event!("virtio-device", "reset", "id", &self.id);
         result
     }
+
+    fn queue_cursors(&self) -> Vec<(usize, Arc<QueueCursors>)> {
+        self.common.queue_cursors.clone()
+    }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        counters.insert(
+            "expirations",
+            Wrapping(self.counters.expirations.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "resets",
+            Wrapping(self.counters.resets.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "poweroffs",
+            Wrapping(self.counters.poweroffs.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "pauses",
+            Wrapping(self.counters.pauses.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "panics",
+            Wrapping(self.counters.panics.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "escalations",
+            Wrapping(self.counters.escalations.load(Ordering::Acquire)),
+        );
+
+        Some(counters)
+    }
 }
 
 impl Pausable for Watchdog {
@@ -426,3 +655,26 @@ impl Snapshottable for Watchdog {
 
 impl Transportable for Watchdog {}
 impl Migratable for Watchdog {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_escalation() {
+        let escalation = WatchdogEscalation {
+            resets: 2,
+            window: Duration::from_secs(60),
+        };
+        let counters = WatchdogCounters::default();
+        let start = Instant::now();
+
+        assert!(!counters.escalate(&escalation, start));
+        assert!(!counters.escalate(&escalation, start + Duration::from_secs(30)));
+        assert!(counters.escalate(&escalation, start + Duration::from_secs(50)));
+
+        // The first reset left the window
+        assert!(!counters.escalate(&escalation, start + Duration::from_secs(70)));
+        assert!(counters.escalate(&escalation, start + Duration::from_secs(80)));
+    }
+}
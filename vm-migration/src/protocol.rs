@@ -48,6 +48,15 @@ use vm_memory::ByteValued;
 //
 // The destination can at any time send an "error response" to cancel
 // The source can at any time send an "abandon request" to cancel
+//
+// "Pre-check": (Checking the destination before migrating)
+// 1: Source establishes communication with destination
+// 2: Source -> Dest : send "precheck command" followed by config data, length
+//                     in command is length of config data
+// 3: Dest -> Source : sends "ok response" followed by the report of the
+//                     checks, length in response is length of the report
+// 4: Source closes the communication, the destination waiting for a new one
+//    for the migration itself
 
 #[repr(u16)]
 #[derive(Copy, Clone)]
@@ -60,6 +69,7 @@ pub enum Command {
     Complete,
     Abandon,
     MemoryFd,
+    Precheck,
 }
 
 impl Default for Command {
@@ -116,6 +126,10 @@ impl Request {
         Self::new(Command::Abandon, 0)
     }
 
+    pub fn precheck(length: u64) -> Self {
+        Self::new(Command::Precheck, length)
+    }
+
     pub fn command(&self) -> Command {
         self.command
     }
@@ -184,6 +198,10 @@ impl Response {
         self.status
     }
 
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
     pub fn read_from(fd: &mut dyn Read) -> Result<Response, MigratableError> {
         let mut response = Response::default();
         fd.read_exact(Self::as_mut_slice(&mut response))
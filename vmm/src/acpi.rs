@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+use crate::config::AcpiTableConfig;
 use crate::cpu::CpuManager;
 use crate::device_manager::DeviceManager;
 use crate::memory_manager::MemoryManager;
@@ -17,8 +18,12 @@ use arch::DeviceType;
 use arch::NumaNodes;
 use bitflags::bitflags;
 use pci::PciBdf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use thiserror::Error;
 use tracer::trace_scoped;
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryRegion};
 use zerocopy::AsBytes;
@@ -169,6 +174,38 @@ struct ViotPciRangeNode {
     _reserved2: [u8; 6],
 }
 
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct HardwareErrorNotification {
+    pub type_: u8,
+    pub length: u8,
+    pub config_write_enable: u16,
+    pub poll_interval: u32,
+    pub vector: u32,
+    pub polling_threshold_value: u32,
+    pub polling_threshold_window: u32,
+    pub error_threshold_value: u32,
+    pub error_threshold_window: u32,
+}
+
+#[allow(dead_code)]
+#[repr(packed)]
+#[derive(Default, AsBytes)]
+struct GenericHardwareErrorSource {
+    pub type_: u16,
+    pub source_id: u16,
+    pub related_source_id: u16,
+    pub flags: u8,
+    pub enabled: u8,
+    pub records_to_preallocate: u32,
+    pub max_sections_per_record: u32,
+    pub max_raw_data_length: u32,
+    pub error_status_address: GenericAddress,
+    pub notification: HardwareErrorNotification,
+    pub error_status_block_length: u32,
+}
+
 pub fn create_dsdt_table(
     device_manager: &Arc<Mutex<DeviceManager>>,
     cpu_manager: &Arc<Mutex<CpuManager>>,
@@ -188,7 +225,38 @@ pub fn create_dsdt_table(
     dsdt
 }
 
-fn create_facp_table(dsdt_offset: GuestAddress, device_manager: &Arc<Mutex<DeviceManager>>) -> Sdt {
+// Revision 2 of the ACPI FACS table is 64 bytes long
+#[cfg(target_arch = "x86_64")]
+const FACS_LENGTH: usize = 64;
+#[cfg(target_arch = "x86_64")]
+const FACS_FIRMWARE_WAKING_VECTOR_OFFSET: u64 = 12;
+
+#[cfg(target_arch = "x86_64")]
+fn create_facs_table() -> Vec<u8> {
+    // The FACS has no SDT header nor checksum
+    let mut facs = vec![0u8; FACS_LENGTH];
+    facs[0..4].copy_from_slice(b"FACS");
+    facs[4..8].copy_from_slice(&(FACS_LENGTH as u32).to_le_bytes());
+    // Version
+    facs[32] = 2;
+
+    facs
+}
+
+/// Returns the address the guest left in the FACS for the firmware to jump
+/// to when waking up from the S3 sleep state, 0 if none was set.
+#[cfg(target_arch = "x86_64")]
+pub fn firmware_waking_vector(guest_mem: &GuestMemoryMmap) -> u32 {
+    guest_mem
+        .read_obj(arch::layout::FACS_START.unchecked_add(FACS_FIRMWARE_WAKING_VECTOR_OFFSET))
+        .unwrap_or(0)
+}
+
+fn create_facp_table(
+    dsdt_offset: GuestAddress,
+    facs_offset: Option<GuestAddress>,
+    device_manager: &Arc<Mutex<DeviceManager>>,
+) -> Sdt {
     trace_scoped!("create_facp_table");
 
     // Revision 6 of the ACPI FADT table is 276 bytes long
@@ -238,6 +306,10 @@ fn create_facp_table(dsdt_offset: GuestAddress, device_manager: &Arc<Mutex<Devic
     facp.write(131, 3u8);
     // X_DSDT
     facp.write(140, dsdt_offset.0);
+    if let Some(facs_offset) = facs_offset {
+        // X_FIRMWARE_CTRL
+        facp.write(132, facs_offset.0);
+    }
     // Hypervisor Vendor Identity
     facp.write_bytes(268, b"CLOUDHYP");
 
@@ -255,28 +327,95 @@ fn create_mcfg_table(pci_segments: &[PciSegment]) -> Sdt {
     for segment in pci_segments {
         // 32-bit PCI enhanced configuration mechanism
         mcfg.append(PciRangeEntry {
-            base_address: segment.mmio_config_address,
+            base_address: segment.ecam_base_address(),
             segment: segment.id,
-            start: 0,
-            end: 0,
+            start: segment.start_bus,
+            end: segment.end_bus,
             ..Default::default()
         });
     }
     mcfg
 }
 
+// Offset of the CRB control area within the TPM MMIO region
+const TPM_CRB_CTRL_AREA_OFFSET: u64 = 0x40;
+
 fn create_tpm2_table() -> Sdt {
-    let mut tpm = Sdt::new(*b"TPM2", 52, 3, *b"CLOUDH", *b"CHTPM2  ", 1);
+    // Revision 4 of the table includes the start method specific
+    // parameters and the event log area (LAML/LASA).
+    let mut tpm = Sdt::new(*b"TPM2", 76, 4, *b"CLOUDH", *b"CHTPM2  ", 1);
 
     tpm.write(36, 0_u16); //Platform Class
     tpm.write(38, 0_u16); // Reserved Space
-    tpm.write(40, 0xfed4_0040_u64); // Address of Control Area
+    tpm.write(40, arch::layout::TPM_START.0 + TPM_CRB_CTRL_AREA_OFFSET); // Address of Control Area
     tpm.write(48, 7_u32); //Start Method
+    tpm.write(64, arch::layout::TPM_LOG_SIZE as u32); // Log Area Minimum Length
+    tpm.write(68, arch::layout::TPM_LOG_START.0); // Log Area Start Address
 
     tpm.update_checksum();
     tpm
 }
 
+// Content of the TPM event log area: the crypto agile log only holds the
+// "Spec ID Event03" header, advertising the SHA-256 bank, until the firmware
+// or the guest extends the PCRs and logs the events after it.
+fn create_tpm_event_log() -> Vec<u8> {
+    const EV_NO_ACTION: u32 = 3;
+    const TPM_ALG_SHA256: u16 = 0xb;
+    const SHA256_DIGEST_SIZE: u16 = 32;
+
+    let mut spec_id = Vec::new();
+    spec_id.extend_from_slice(b"Spec ID Event03\0");
+    spec_id.extend_from_slice(&0_u32.to_le_bytes()); // Platform Class
+    spec_id.extend_from_slice(&[0, 2, 0, 2]); // Version 2.0, errata 0, UINTN size 2 (64 bits)
+    spec_id.extend_from_slice(&1_u32.to_le_bytes()); // Number of algorithms
+    spec_id.extend_from_slice(&TPM_ALG_SHA256.to_le_bytes());
+    spec_id.extend_from_slice(&SHA256_DIGEST_SIZE.to_le_bytes());
+    spec_id.push(0); // Vendor information size
+
+    let mut log = vec![0u8; arch::layout::TPM_LOG_SIZE as usize];
+    let mut header = Vec::new();
+    header.extend_from_slice(&0_u32.to_le_bytes()); // PCR Index
+    header.extend_from_slice(&EV_NO_ACTION.to_le_bytes());
+    header.extend_from_slice(&[0u8; 20]); // SHA-1 digest
+    header.extend_from_slice(&(spec_id.len() as u32).to_le_bytes());
+    header.extend_from_slice(&spec_id);
+    log[..header.len()].copy_from_slice(&header);
+
+    log
+}
+
+// Single error source notified through the Hardware Error Device, which is
+// signaled by the GED.
+fn create_hest_table(error_status_address: GuestAddress) -> Sdt {
+    const HEST_TYPE_GHES: u16 = 9;
+    const HEST_NOTIFY_SCI: u8 = 3;
+
+    let mut hest = Sdt::new(*b"HEST", 36, 1, *b"CLOUDH", *b"CHHEST  ", 1);
+    // Error source count
+    hest.append(1u32);
+
+    assert_eq!(std::mem::size_of::<GenericHardwareErrorSource>(), 64);
+    hest.append(GenericHardwareErrorSource {
+        type_: HEST_TYPE_GHES,
+        related_source_id: 0xffff,
+        enabled: 1,
+        records_to_preallocate: 1,
+        max_sections_per_record: 1,
+        error_status_address: GenericAddress::mmio_address::<u64>(error_status_address.0),
+        notification: HardwareErrorNotification {
+            type_: HEST_NOTIFY_SCI,
+            length: std::mem::size_of::<HardwareErrorNotification>() as u8,
+            ..Default::default()
+        },
+        error_status_block_length: devices::ghes::ERROR_STATUS_BLOCK_LENGTH as u32,
+        ..Default::default()
+    });
+
+    hest.update_checksum();
+    hest
+}
+
 fn create_srat_table(numa_nodes: &NumaNodes) -> Sdt {
     let mut srat = Sdt::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
     // SRAT reserved 12 bytes
@@ -559,12 +698,13 @@ fn create_iort_table(pci_segments: &[PciSegment]) -> Sdt {
         // From offset 32 onward is the space for ID mappings Array.
         // Now we have only one mapping.
         let mapping_offset: usize = node_offset + 36;
-        // The lowest value in the input range
-        iort.write(mapping_offset, (0u32).to_le());
+        // The lowest value in the input range, the ID of the first device
+        // of the first bus of the segment
+        iort.write(mapping_offset, ((segment.start_bus as u32) << 8).to_le());
         // The number of IDs in the range minus one:
         // This should cover all the devices of a segment:
         // 1 (bus) x 32 (devices) x 8 (functions) = 256
-        // Note: Currently only 1 bus is supported in a segment.
+        // Note: Devices are only attached to the first bus of a segment.
         iort.write(mapping_offset + 4, (255_u32).to_le());
         // The lowest value in the output range
         iort.write(mapping_offset + 8, ((256 * segment.id) as u32).to_le());
@@ -616,6 +756,136 @@ fn create_viot_table(iommu_bdf: &PciBdf, devices_bdf: &[PciBdf]) -> Sdt {
     viot
 }
 
+// Single interrupt remapping unit covering the devices of the default PCI
+// segment, along with the IOAPIC.
+#[cfg(target_arch = "x86_64")]
+fn create_dmar_table(register_base: GuestAddress) -> Sdt {
+    const DMAR_INTR_REMAP: u8 = 1 << 0;
+    const DRHD_TYPE: u16 = 0;
+    const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+    const DEVICE_SCOPE_IOAPIC: u8 = 3;
+    const DRHD_LENGTH: u16 = 24;
+
+    let source_id = crate::interrupt_remapping::IOAPIC_SOURCE_ID;
+
+    let mut dmar = Sdt::new(*b"DMAR", 36, 1, *b"CLOUDH", *b"CHDMAR  ", 1);
+    // Host address width, minus one
+    dmar.append(47u8);
+    dmar.append(DMAR_INTR_REMAP);
+    dmar.append_slice(&[0u8; 10]);
+
+    // DMA remapping hardware unit definition
+    dmar.append(DRHD_TYPE);
+    dmar.append(DRHD_LENGTH);
+    dmar.append(DRHD_INCLUDE_PCI_ALL);
+    dmar.append(0u8);
+    // PCI segment
+    dmar.append(0u16);
+    dmar.append(register_base.0);
+
+    // IOAPIC device scope, whose enumeration id is the IOAPIC id from the
+    // MADT, and whose path gives its source identifier.
+    dmar.append(DEVICE_SCOPE_IOAPIC);
+    dmar.append(8u8);
+    dmar.append(0u16);
+    dmar.append(0u8);
+    dmar.append((source_id >> 8) as u8);
+    dmar.append(((source_id >> 3) & 0x1f) as u8);
+    dmar.append((source_id & 0x7) as u8);
+
+    dmar.update_checksum();
+    dmar
+}
+
+// Size of the header shared by the system description tables
+const SDT_HEADER_SIZE: usize = 36;
+const SDT_CHECKSUM_OFFSET: usize = 9;
+
+// Room left to the tables from the configuration, the whole set of tables
+// having to fit below the SMBIOS tables on x86_64.
+const USER_TABLES_MAX_SIZE: usize = 0x10000;
+
+// Signatures of the tables generated by the VMM, which can't be given twice
+const GENERATED_SIGNATURES: [&[u8; 4]; 18] = [
+    b"APIC", b"DBG2", b"DMAR", b"DSDT", b"FACP", b"FACS", b"GTDT", b"HEST", b"IORT", b"MCFG",
+    b"PPTT", b"RSDT", b"SLIT", b"SPCR", b"SRAT", b"TPM2", b"VIOT", b"XSDT",
+];
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read the ACPI table {0}: {1}")]
+    ReadTable(PathBuf, #[source] io::Error),
+
+    #[error("Invalid ACPI table {0}: {1}")]
+    InvalidTable(PathBuf, &'static str),
+
+    #[error("ACPI table {0} clashes with the generated {1} table")]
+    GeneratedTable(PathBuf, String),
+
+    #[error("ACPI tables too large: {0} bytes, the limit being {USER_TABLES_MAX_SIZE}")]
+    TablesTooLarge(usize),
+}
+
+/// ACPI table from the configuration, added as is to the generated ones.
+pub struct AcpiTable {
+    data: Vec<u8>,
+}
+
+impl AcpiTable {
+    fn new(path: &Path, mut data: Vec<u8>) -> Result<Self, Error> {
+        let invalid = |reason| Error::InvalidTable(path.to_path_buf(), reason);
+
+        if data.len() < SDT_HEADER_SIZE {
+            return Err(invalid("shorter than the table header"));
+        }
+        let signature = &data[0..4];
+        if !signature
+            .iter()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == b'_')
+        {
+            return Err(invalid("malformed signature"));
+        }
+        if GENERATED_SIGNATURES.iter().any(|s| &s[..] == signature) {
+            return Err(Error::GeneratedTable(
+                path.to_path_buf(),
+                String::from_utf8_lossy(signature).into_owned(),
+            ));
+        }
+        let length = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        if length as usize != data.len() {
+            return Err(invalid("length not matching the size of the file"));
+        }
+
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if sum != 0 {
+            warn!("Fixing the checksum of the ACPI table {}", path.display());
+            data[SDT_CHECKSUM_OFFSET] = data[SDT_CHECKSUM_OFFSET].wrapping_sub(sum);
+        }
+
+        Ok(AcpiTable { data })
+    }
+}
+
+/// Read and validate the ACPI tables from the configuration, their checksum
+/// being fixed if needed.
+pub fn load_acpi_tables(configs: &[AcpiTableConfig]) -> Result<Vec<AcpiTable>, Error> {
+    let tables = configs
+        .iter()
+        .map(|config| {
+            let data =
+                fs::read(&config.path).map_err(|e| Error::ReadTable(config.path.clone(), e))?;
+            AcpiTable::new(&config.path, data)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let size = tables.iter().map(|t| t.data.len()).sum();
+    if size > USER_TABLES_MAX_SIZE {
+        return Err(Error::TablesTooLarge(size));
+    }
+
+    Ok(tables)
+}
+
 pub fn create_acpi_tables(
     guest_mem: &GuestMemoryMmap,
     device_manager: &Arc<Mutex<DeviceManager>>,
@@ -623,6 +893,7 @@ pub fn create_acpi_tables(
     memory_manager: &Arc<Mutex<MemoryManager>>,
     numa_nodes: &NumaNodes,
     tpm_enabled: bool,
+    user_tables: &[AcpiTable],
 ) -> GuestAddress {
     trace_scoped!("create_acpi_tables");
 
@@ -630,15 +901,30 @@ pub fn create_acpi_tables(
     let rsdp_offset = arch::layout::RSDP_POINTER;
     let mut tables: Vec<u64> = Vec::new();
 
+    // FACS
+    #[cfg(target_arch = "x86_64")]
+    let (facs_offset, dsdt_offset) = {
+        let facs = create_facs_table();
+        let facs_offset = arch::layout::FACS_START;
+        guest_mem
+            .write_slice(&facs, facs_offset)
+            .expect("Error writing FACS table");
+        (
+            Some(facs_offset),
+            facs_offset.checked_add(facs.len() as u64).unwrap(),
+        )
+    };
+    #[cfg(target_arch = "aarch64")]
+    let (facs_offset, dsdt_offset) = (None, rsdp_offset.checked_add(Rsdp::len() as u64).unwrap());
+
     // DSDT
     let dsdt = create_dsdt_table(device_manager, cpu_manager, memory_manager);
-    let dsdt_offset = rsdp_offset.checked_add(Rsdp::len() as u64).unwrap();
     guest_mem
         .write_slice(dsdt.as_slice(), dsdt_offset)
         .expect("Error writing DSDT table");
 
     // FACP aka FADT
-    let facp = create_facp_table(dsdt_offset, device_manager);
+    let facp = create_facp_table(dsdt_offset, facs_offset, device_manager);
     let facp_offset = dsdt_offset.checked_add(dsdt.len() as u64).unwrap();
     guest_mem
         .write_slice(facp.as_slice(), facp_offset)
@@ -748,6 +1034,11 @@ pub fn create_acpi_tables(
 
         prev_tbl_len = tpm2.len() as u64;
         prev_tbl_off = tpm2_offset;
+
+        // TPM event log
+        guest_mem
+            .write_slice(&create_tpm_event_log(), arch::layout::TPM_LOG_START)
+            .expect("Error writing TPM event log");
     }
     // SRAT and SLIT
     // Only created if the NUMA nodes list is not empty.
@@ -798,6 +1089,47 @@ pub fn create_acpi_tables(
         prev_tbl_off = viot_offset;
     }
 
+    // DMAR
+    #[cfg(target_arch = "x86_64")]
+    if let Some(address) = device_manager.lock().unwrap().interrupt_remapping_address() {
+        let dmar = create_dmar_table(address);
+        let dmar_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(dmar.as_slice(), dmar_offset)
+            .expect("Error writing DMAR table");
+        tables.push(dmar_offset.0);
+        prev_tbl_len = dmar.len() as u64;
+        prev_tbl_off = dmar_offset;
+    }
+
+    // HEST
+    let ghes_address = device_manager
+        .lock()
+        .unwrap()
+        .ghes_device()
+        .map(|ghes| ghes.lock().unwrap().error_status_address());
+    if let Some(ghes_address) = ghes_address {
+        let hest = create_hest_table(ghes_address);
+        let hest_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(hest.as_slice(), hest_offset)
+            .expect("Error writing HEST table");
+        tables.push(hest_offset.0);
+        prev_tbl_len = hest.len() as u64;
+        prev_tbl_off = hest_offset;
+    }
+
+    // Tables from the configuration
+    for table in user_tables {
+        let table_offset = prev_tbl_off.checked_add(prev_tbl_len).unwrap();
+        guest_mem
+            .write_slice(&table.data, table_offset)
+            .expect("Error writing ACPI table");
+        tables.push(table_offset.0);
+        prev_tbl_len = table.data.len() as u64;
+        prev_tbl_off = table_offset;
+    }
+
     // XSDT
     let mut xsdt = Sdt::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
@@ -809,6 +1141,12 @@ pub fn create_acpi_tables(
         .write_slice(xsdt.as_slice(), xsdt_offset)
         .expect("Error writing XSDT table");
 
+    #[cfg(target_arch = "x86_64")]
+    assert!(
+        !tpm_enabled || xsdt_offset.unchecked_add(xsdt.len() as u64) <= arch::layout::TPM_LOG_START,
+        "ACPI tables overlap the TPM event log"
+    );
+
     // RSDP
     let rsdp = Rsdp::new(*b"CLOUDH", xsdt_offset.0);
     guest_mem
@@ -838,7 +1176,7 @@ pub fn create_acpi_tables_tdx(
     )];
 
     // FACP aka FADT
-    tables.push(create_facp_table(GuestAddress(0), device_manager));
+    tables.push(create_facp_table(GuestAddress(0), None, device_manager));
 
     // MADT
     tables.push(cpu_manager.lock().unwrap().create_madt());
@@ -864,5 +1202,112 @@ pub fn create_acpi_tables_tdx(
         tables.push(create_viot_table(iommu_bdf, devices_bdf));
     }
 
+    // DMAR
+    if let Some(address) = device_manager.lock().unwrap().interrupt_remapping_address() {
+        tables.push(create_dmar_table(address));
+    }
+
+    // HEST
+    if let Some(ghes) = device_manager.lock().unwrap().ghes_device() {
+        tables.push(create_hest_table(
+            ghes.lock().unwrap().error_status_address(),
+        ));
+    }
+
     tables
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acpi_table() {
+        let path = Path::new("/path/to/ssdt.aml");
+        let mut ssdt = Sdt::new(*b"SSDT", 36, 2, *b"CLOUDH", *b"CHSSDT  ", 1);
+        ssdt.append_slice(&[0x10, 0x05, 0x5c, 0x00]);
+        ssdt.update_checksum();
+        let data = ssdt.as_slice().to_vec();
+
+        // A valid checksum is kept as is
+        let table = AcpiTable::new(path, data.clone()).unwrap();
+        assert_eq!(table.data, data);
+
+        // An invalid one gets fixed
+        let mut corrupted = data.clone();
+        corrupted[SDT_CHECKSUM_OFFSET] ^= 0xff;
+        let table = AcpiTable::new(path, corrupted).unwrap();
+        assert_eq!(table.data, data);
+
+        assert!(matches!(
+            AcpiTable::new(path, data[..SDT_HEADER_SIZE - 1].to_vec()),
+            Err(Error::InvalidTable(..))
+        ));
+        assert!(matches!(
+            AcpiTable::new(path, data[..SDT_HEADER_SIZE].to_vec()),
+            Err(Error::InvalidTable(..))
+        ));
+
+        let mut dsdt = data;
+        dsdt[0..4].copy_from_slice(b"DSDT");
+        assert!(matches!(
+            AcpiTable::new(path, dsdt),
+            Err(Error::GeneratedTable(..))
+        ));
+    }
+
+    #[test]
+    fn test_tpm2_table() {
+        let tpm2 = create_tpm2_table();
+        let data = tpm2.as_slice();
+
+        assert_eq!(data.len(), 76);
+        assert_eq!(data[8], 4);
+        assert_eq!(data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)), 0);
+        assert_eq!(
+            u32::from_le_bytes(data[64..68].try_into().unwrap()) as u64,
+            arch::layout::TPM_LOG_SIZE
+        );
+        assert_eq!(
+            u64::from_le_bytes(data[68..76].try_into().unwrap()),
+            arch::layout::TPM_LOG_START.0
+        );
+
+        let log = create_tpm_event_log();
+        assert_eq!(log.len() as u64, arch::layout::TPM_LOG_SIZE);
+        // EV_NO_ACTION event in PCR 0, holding the Spec ID header
+        assert_eq!(u32::from_le_bytes(log[0..4].try_into().unwrap()), 0);
+        assert_eq!(u32::from_le_bytes(log[4..8].try_into().unwrap()), 3);
+        let event_size = u32::from_le_bytes(log[28..32].try_into().unwrap()) as usize;
+        assert_eq!(event_size, 33);
+        assert_eq!(&log[32..48], b"Spec ID Event03\0");
+        // Single SHA-256 bank
+        assert_eq!(u32::from_le_bytes(log[56..60].try_into().unwrap()), 1);
+        assert_eq!(u16::from_le_bytes(log[60..62].try_into().unwrap()), 0xb);
+        assert_eq!(u16::from_le_bytes(log[62..64].try_into().unwrap()), 32);
+        assert!(log[32 + event_size..].iter().all(|b| *b == 0));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_dmar_table() {
+        let dmar = create_dmar_table(arch::layout::INTERRUPT_REMAPPING_START);
+        let data = dmar.as_slice();
+
+        assert_eq!(data.len(), 72);
+        assert_eq!(&data[0..4], b"DMAR");
+        assert_eq!(data.iter().fold(0u8, |acc, x| acc.wrapping_add(*x)), 0);
+        // Interrupt remapping
+        assert_eq!(data[37], 1);
+        // Unit covering the whole default segment
+        assert_eq!(u16::from_le_bytes(data[48..50].try_into().unwrap()), 0);
+        assert_eq!(u16::from_le_bytes(data[50..52].try_into().unwrap()), 24);
+        assert_eq!(data[52], 1);
+        assert_eq!(
+            u64::from_le_bytes(data[56..64].try_into().unwrap()),
+            arch::layout::INTERRUPT_REMAPPING_START.0
+        );
+        // IOAPIC 0 at 0xf0:1f.0
+        assert_eq!(&data[64..72], &[3, 8, 0, 0, 0, 0xf0, 0x1f, 0]);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Audit log of the requests received through the APIs.
+//!
+//! Each request is recorded as a JSON line with the endpoint, a summary of the
+//! payload, the credentials of the peer when the transport provides them, and
+//! the result. The payloads themselves are not recorded, as they may hold
+//! secrets, only their size and their top level fields. Once the log reaches
+//! its maximum size it is rotated, the previous logs being kept as
+//! `<path>.1`, `<path>.2`, and so on.
+
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static AUDIT_LOG: OnceCell<Mutex<AuditLog>> = OnceCell::new();
+
+pub const DEFAULT_AUDIT_LOG_SIZE: u64 = 10 << 20;
+pub const DEFAULT_AUDIT_LOG_FILES: u32 = 5;
+
+/// Credentials of the process a request comes from.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PeerCredentials {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    /// Subject of the certificate the client authenticated with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct PayloadSummary {
+    size: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp_ms: u128,
+    transport: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer: Option<PeerCredentials>,
+    endpoint: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<PayloadSummary>,
+    result: &'a str,
+}
+
+struct AuditLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: u32,
+}
+
+impl AuditLog {
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                match fs::rename(rotated_path(&self.path, i), rotated_path(&self.path, i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            self.file = open(&self.path)?;
+        }
+
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{index}"));
+    PathBuf::from(path)
+}
+
+// Only the top level fields of the JSON payloads are kept
+fn summarize(payload: &[u8]) -> PayloadSummary {
+    let fields = match serde_json::from_slice::<serde_json::Value>(payload) {
+        Ok(serde_json::Value::Object(map)) => map.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+
+    PayloadSummary {
+        size: payload.len(),
+        fields,
+    }
+}
+
+/// Start recording the API requests to the given file, rotating it once it
+/// reaches `max_size` bytes and keeping `max_files` previous logs.
+pub fn set_audit_log(path: &Path, max_size: u64, max_files: u32) -> io::Result<()> {
+    let file = open(path)?;
+    let size = file.metadata()?.len();
+
+    AUDIT_LOG
+        .set(Mutex::new(AuditLog {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            max_files,
+        }))
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "Audit log already set"))
+}
+
+pub fn enabled() -> bool {
+    AUDIT_LOG.get().is_some()
+}
+
+/// Record a request, if the requests are audited.
+pub fn record(
+    transport: &str,
+    peer: Option<PeerCredentials>,
+    endpoint: &str,
+    payload: Option<&[u8]>,
+    result: &str,
+) {
+    let audit_log = match AUDIT_LOG.get() {
+        Some(audit_log) => audit_log,
+        None => return,
+    };
+
+    let entry = AuditEntry {
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+        transport,
+        peer,
+        endpoint,
+        payload: payload.filter(|p| !p.is_empty()).map(summarize),
+        result,
+    };
+    let mut line = match serde_json::to_vec(&entry) {
+        Ok(line) => line,
+        Err(e) => {
+            warn!("Failed to serialize the audit entry: {}", e);
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    if let Err(e) = audit_log.lock().unwrap().write(&line) {
+        warn!("Failed to write the audit log: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        assert_eq!(
+            summarize(br#"{"path":"/tmp/disk.img","readonly":true}"#),
+            PayloadSummary {
+                size: 40,
+                fields: vec!["path".to_string(), "readonly".to_string()],
+            }
+        );
+        assert_eq!(
+            summarize(b"not json"),
+            PayloadSummary {
+                size: 8,
+                fields: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch-audit").unwrap();
+        let path = dir.as_path().join("audit.log");
+        let mut audit_log = AuditLog {
+            path: path.clone(),
+            file: open(&path).unwrap(),
+            size: 0,
+            max_size: 16,
+            max_files: 2,
+        };
+
+        for line in ["first line\n", "second line\n", "third line\n", "last\n"] {
+            audit_log.write(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third line\nlast\n");
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            "second line\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            "first line\n"
+        );
+        assert!(!rotated_path(&path, 3).exists());
+    }
+}
@@ -2,7 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
-use super::{ApiRequest, VmAction};
+use super::audit::{self, PeerCredentials};
+use super::jobs::{self, JobKind};
+use super::{ApiRequest, VmAction, VmCountersData, VmJobsData, VmSnapshotConfig};
+use crate::config::RestoreConfig;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error as VmmError, Result as VmmResult};
 use crate::{NetConfig, VmConfig};
@@ -10,14 +13,16 @@ use futures::channel::oneshot;
 use futures::{executor, FutureExt};
 use hypervisor::HypervisorType;
 use seccompiler::{apply_filter, SeccompAction};
+use std::future::Future;
 use std::panic::AssertUnwindSafe;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
-use zbus::fdo::{self, Result};
+use zbus::fdo::{self, DBusProxy, Result};
+use zbus::names::BusName;
 use zbus::zvariant::Optional;
-use zbus::{dbus_interface, ConnectionBuilder};
+use zbus::{dbus_interface, Connection, ConnectionBuilder, MessageHeader};
 
 pub type DBusApiShutdownChannels = (oneshot::Sender<()>, oneshot::Receiver<()>);
 
@@ -37,6 +42,68 @@ fn api_error(error: impl std::fmt::Debug) -> fdo::Error {
     fdo::Error::Failed(format!("{error:?}"))
 }
 
+// Snapshots can't be streamed through a file descriptor, which can't be sent
+// along with the method call.
+fn check_snapshot_url(url: &str) -> Result<()> {
+    if url.starts_with("fd://") {
+        return Err(fdo::Error::NotSupported(format!(
+            "Cannot stream a snapshot through {url} over D-Bus"
+        )));
+    }
+    Ok(())
+}
+
+// Handle a method call, recording it to the audit log along with the
+// credentials of the caller, as known by the bus.
+async fn audited<T>(
+    connection: &Connection,
+    header: &MessageHeader<'_>,
+    payload: Option<&str>,
+    request: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let result = request.await;
+
+    if audit::enabled() {
+        let endpoint = header
+            .member()
+            .ok()
+            .flatten()
+            .map_or("", |member| member.as_str());
+        let outcome = match &result {
+            Ok(_) => "Ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        audit::record(
+            "dbus",
+            peer_credentials(connection, header).await,
+            endpoint,
+            payload.map(str::as_bytes),
+            &outcome,
+        );
+    }
+
+    result
+}
+
+async fn peer_credentials(
+    connection: &Connection,
+    header: &MessageHeader<'_>,
+) -> Option<PeerCredentials> {
+    let sender = header.sender().ok()??;
+    let credentials = DBusProxy::new(connection)
+        .await
+        .ok()?
+        .get_connection_credentials(BusName::from(sender.clone()))
+        .await
+        .ok()?;
+
+    Some(PeerCredentials {
+        uid: credentials.unix_user_id(),
+        pid: credentials.process_id(),
+        subject: None,
+    })
+}
+
 // This method is intended to ensure that the DBusApi thread has enough time to
 // send a response to the VmmShutdown method call before it is terminated. If
 // this step is omitted, the thread may be terminated before it can send a
@@ -95,208 +162,827 @@ impl DBusApi {
 
 #[dbus_interface(name = "org.cloudhypervisor.DBusApi1")]
 impl DBusApi {
-    async fn vmm_ping(&self) -> Result<String> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vmm_ping(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String> {
+        audited(connection, &header, None, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
 
-        let result = blocking::unblock(move || super::vmm_ping(api_notifier, api_sender))
-            .await
-            .map_err(api_error)?;
-        serde_json::to_string(&result).map_err(api_error)
+            let result = blocking::unblock(move || super::vmm_ping(api_notifier, api_sender))
+                .await
+                .map_err(api_error)?;
+            serde_json::to_string(&result).map_err(api_error)
+        })
+        .await
     }
 
-    async fn vmm_shutdown(&self) -> Result<()> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vmm_resources(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String> {
+        audited(connection, &header, None, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
 
-        blocking::unblock(move || super::vmm_shutdown(api_notifier, api_sender))
-            .await
-            .map_err(api_error)
+            let result = blocking::unblock(move || super::vmm_resources(api_notifier, api_sender))
+                .await
+                .map_err(api_error)?;
+            serde_json::to_string(&result).map_err(api_error)
+        })
+        .await
     }
 
-    async fn vm_add_device(&self, device_config: String) -> Result<Optional<String>> {
-        let device_config = serde_json::from_str(&device_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddDevice(Arc::new(device_config)))
-            .await
+    async fn vmm_shutdown(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            blocking::unblock(move || super::vmm_shutdown(api_notifier, api_sender))
+                .await
+                .map_err(api_error)
+        })
+        .await
     }
 
-    async fn vm_add_disk(&self, disk_config: String) -> Result<Optional<String>> {
-        let disk_config = serde_json::from_str(&disk_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddDisk(Arc::new(disk_config)))
-            .await
+    async fn vm_add_device(
+        &self,
+        device_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&device_config), async {
+            let device_config = serde_json::from_str(&device_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddDevice(Arc::new(device_config)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_add_fs(&self, fs_config: String) -> Result<Optional<String>> {
-        let fs_config = serde_json::from_str(&fs_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddFs(Arc::new(fs_config))).await
+    async fn vm_add_vf(
+        &self,
+        vf_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vf_config), async {
+            let vf_config = serde_json::from_str(&vf_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddVf(Arc::new(vf_config))).await
+        })
+        .await
     }
 
-    async fn vm_add_net(&self, net_config: String) -> Result<Optional<String>> {
-        let mut net_config: NetConfig = serde_json::from_str(&net_config).map_err(api_error)?;
-        if net_config.fds.is_some() {
-            warn!("Ignoring FDs sent via the D-Bus request body");
-            net_config.fds = None;
-        }
-        self.vm_action(VmAction::AddNet(Arc::new(net_config))).await
+    async fn vm_add_mdev(
+        &self,
+        mdev_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&mdev_config), async {
+            let mdev_config = serde_json::from_str(&mdev_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddMdev(Arc::new(mdev_config)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_add_pmem(&self, pmem_config: String) -> Result<Optional<String>> {
-        let pmem_config = serde_json::from_str(&pmem_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddPmem(Arc::new(pmem_config)))
-            .await
+    async fn vm_add_disk(
+        &self,
+        disk_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&disk_config), async {
+            let disk_config = serde_json::from_str(&disk_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddDisk(Arc::new(disk_config)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_add_user_device(&self, vm_add_user_device: String) -> Result<Optional<String>> {
-        let vm_add_user_device = serde_json::from_str(&vm_add_user_device).map_err(api_error)?;
-        self.vm_action(VmAction::AddUserDevice(Arc::new(vm_add_user_device)))
-            .await
+    async fn vm_add_fs(
+        &self,
+        fs_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&fs_config), async {
+            let fs_config = serde_json::from_str(&fs_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddFs(Arc::new(fs_config))).await
+        })
+        .await
     }
 
-    async fn vm_add_vdpa(&self, vdpa_config: String) -> Result<Optional<String>> {
-        let vdpa_config = serde_json::from_str(&vdpa_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddVdpa(Arc::new(vdpa_config)))
-            .await
+    async fn vm_add_net(
+        &self,
+        net_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&net_config), async {
+            let mut net_config: NetConfig = serde_json::from_str(&net_config).map_err(api_error)?;
+            if net_config.fds.is_some() {
+                warn!("Ignoring FDs sent via the D-Bus request body");
+                net_config.fds = None;
+            }
+            self.vm_action(VmAction::AddNet(Arc::new(net_config))).await
+        })
+        .await
     }
 
-    async fn vm_add_vsock(&self, vsock_config: String) -> Result<Optional<String>> {
-        let vsock_config = serde_json::from_str(&vsock_config).map_err(api_error)?;
-        self.vm_action(VmAction::AddVsock(Arc::new(vsock_config)))
-            .await
+    async fn vm_add_pmem(
+        &self,
+        pmem_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&pmem_config), async {
+            let pmem_config = serde_json::from_str(&pmem_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddPmem(Arc::new(pmem_config)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_add_user_device(
+        &self,
+        vm_add_user_device: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vm_add_user_device), async {
+            let vm_add_user_device =
+                serde_json::from_str(&vm_add_user_device).map_err(api_error)?;
+            self.vm_action(VmAction::AddUserDevice(Arc::new(vm_add_user_device)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_add_vdpa(
+        &self,
+        vdpa_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vdpa_config), async {
+            let vdpa_config = serde_json::from_str(&vdpa_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddVdpa(Arc::new(vdpa_config)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_boot(&self) -> Result<()> {
-        self.vm_action(VmAction::Boot).await.map(|_| ())
+    async fn vm_add_vsock(
+        &self,
+        vsock_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vsock_config), async {
+            let vsock_config = serde_json::from_str(&vsock_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddVsock(Arc::new(vsock_config)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_add_sgx_epc(
+        &self,
+        sgx_epc_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&sgx_epc_config), async {
+            #[cfg(target_arch = "x86_64")]
+            {
+                let sgx_epc_config = serde_json::from_str(&sgx_epc_config).map_err(api_error)?;
+                self.vm_action(VmAction::AddSgxEpc(Arc::new(sgx_epc_config)))
+                    .await
+                    .map(|_| ())
+            }
+
+            #[cfg(not(target_arch = "x86_64"))]
+            Err(api_error("VmAddSgxEpc only works on x86_64"))
+        })
+        .await
+    }
+
+    async fn vm_add_console_port(
+        &self,
+        console_port_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&console_port_config), async {
+            let console_port_config =
+                serde_json::from_str(&console_port_config).map_err(api_error)?;
+            self.vm_action(VmAction::AddConsolePort(Arc::new(console_port_config)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_boot(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Boot).await.map(|_| ())
+        })
+        .await
     }
 
     #[allow(unused_variables)]
     // zbus doesn't support cfg attributes on interface methods
     // as a workaround, we make the *call to the internal API* conditionally
     // compile and return an error on unsupported platforms.
-    async fn vm_coredump(&self, vm_coredump_data: String) -> Result<()> {
-        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
-        {
-            let vm_coredump_data = serde_json::from_str(&vm_coredump_data).map_err(api_error)?;
-            self.vm_action(VmAction::Coredump(Arc::new(vm_coredump_data)))
+    async fn vm_coredump(
+        &self,
+        vm_coredump_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_coredump_data), async {
+            #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+            {
+                let vm_coredump_data =
+                    serde_json::from_str(&vm_coredump_data).map_err(api_error)?;
+                self.vm_action(VmAction::Coredump(Arc::new(vm_coredump_data)))
+                    .await
+                    .map(|_| ())
+            }
+
+            #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+            Err(api_error(
+                "VmCoredump only works on x86_64 with the `guest_debug` feature enabled",
+            ))
+        })
+        .await
+    }
+
+    async fn vm_counters(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Counters(Arc::default())).await
+        })
+        .await
+    }
+
+    async fn vm_counter_rates(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Counters(Arc::new(VmCountersData { rates: true })))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_create(
+        &self,
+        vm_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_config), async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            let mut vm_config: VmConfig = serde_json::from_str(&vm_config).map_err(api_error)?;
+
+            if let Some(ref mut nets) = vm_config.net {
+                if nets.iter().any(|net| net.fds.is_some()) {
+                    warn!("Ignoring FDs sent via the D-Bus request body");
+                }
+                for net in nets {
+                    net.fds = None;
+                }
+            }
+
+            blocking::unblock(move || {
+                super::vm_create(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
+            })
+            .await
+            .map_err(api_error)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn vm_delete(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Delete).await.map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_guest_exec(
+        &self,
+        vm_guest_exec: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vm_guest_exec), async {
+            let vm_guest_exec = serde_json::from_str(&vm_guest_exec).map_err(api_error)?;
+            self.vm_action(VmAction::GuestExec(Arc::new(vm_guest_exec)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_memory_read(
+        &self,
+        vm_memory_read: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vm_memory_read), async {
+            let vm_memory_read = serde_json::from_str(&vm_memory_read).map_err(api_error)?;
+            self.vm_action(VmAction::MemoryRead(Arc::new(vm_memory_read)))
+                .await
+        })
+        .await
+    }
+
+    async fn vm_memory_write(
+        &self,
+        vm_memory_write: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_memory_write), async {
+            let vm_memory_write = serde_json::from_str(&vm_memory_write).map_err(api_error)?;
+            self.vm_action(VmAction::MemoryWrite(Arc::new(vm_memory_write)))
                 .await
                 .map(|_| ())
-        }
+        })
+        .await
+    }
 
-        #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
-        Err(api_error(
-            "VmCoredump only works on x86_64 with the `guest_debug` feature enabled",
-        ))
+    async fn vm_virtqueue_state(
+        &self,
+        vm_virtqueue_state: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vm_virtqueue_state), async {
+            let vm_virtqueue_state =
+                serde_json::from_str(&vm_virtqueue_state).map_err(api_error)?;
+            self.vm_action(VmAction::VirtqueueState(Arc::new(vm_virtqueue_state)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_counters(&self) -> Result<Optional<String>> {
-        self.vm_action(VmAction::Counters).await
+    async fn vm_guest_fsfreeze(
+        &self,
+        vm_guest_fsfreeze: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&vm_guest_fsfreeze), async {
+            let vm_guest_fsfreeze = serde_json::from_str(&vm_guest_fsfreeze).map_err(api_error)?;
+            self.vm_action(VmAction::GuestFsFreeze(Arc::new(vm_guest_fsfreeze)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_create(&self, vm_config: String) -> Result<()> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vm_boot_timings(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::BootTimings).await
+        })
+        .await
+    }
 
-        let mut vm_config: VmConfig = serde_json::from_str(&vm_config).map_err(api_error)?;
+    async fn vm_hetero_balloon(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::HeteroBalloon).await
+        })
+        .await
+    }
 
-        if let Some(ref mut nets) = vm_config.net {
-            if nets.iter().any(|net| net.fds.is_some()) {
-                warn!("Ignoring FDs sent via the D-Bus request body");
-            }
-            for net in nets {
-                net.fds = None;
+    async fn vm_guest_info(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::GuestInfo).await
+        })
+        .await
+    }
+
+    async fn vm_info(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String> {
+        audited(connection, &header, None, async {
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+
+            let result = blocking::unblock(move || super::vm_info(api_notifier, api_sender))
+                .await
+                .map_err(api_error)?;
+            serde_json::to_string(&result).map_err(api_error)
+        })
+        .await
+    }
+
+    async fn vm_jobs(
+        &self,
+        vm_jobs_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String> {
+        audited(connection, &header, Some(&vm_jobs_data), async {
+            let data: VmJobsData = if vm_jobs_data.is_empty() {
+                VmJobsData::default()
+            } else {
+                serde_json::from_str(&vm_jobs_data).map_err(api_error)?
+            };
+
+            match data.id {
+                Some(id) => serde_json::to_string(
+                    &jobs::get(id)
+                        .ok_or_else(|| fdo::Error::InvalidArgs(format!("No job {id}")))?,
+                ),
+                None => serde_json::to_string(&jobs::list()),
             }
-        }
+            .map_err(api_error)
+        })
+        .await
+    }
 
-        blocking::unblock(move || {
-            super::vm_create(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
+    async fn vm_pause(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Pause).await.map(|_| ())
         })
         .await
-        .map_err(api_error)?;
+    }
 
-        Ok(())
+    async fn vm_power_button(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::PowerButton).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_delete(&self) -> Result<()> {
-        self.vm_action(VmAction::Delete).await.map(|_| ())
+    async fn vm_report_free_pages(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::ReportFreePages).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_info(&self) -> Result<String> {
-        let api_sender = self.clone_api_sender().await;
-        let api_notifier = self.clone_api_notifier()?;
+    async fn vm_reboot(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Reboot).await.map(|_| ())
+        })
+        .await
+    }
 
-        let result = blocking::unblock(move || super::vm_info(api_notifier, api_sender))
+    async fn vm_remove_console_port(
+        &self,
+        vm_remove_console_port: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_remove_console_port), async {
+            let vm_remove_console_port =
+                serde_json::from_str(&vm_remove_console_port).map_err(api_error)?;
+            self.vm_action(VmAction::RemoveConsolePort(Arc::new(
+                vm_remove_console_port,
+            )))
             .await
-            .map_err(api_error)?;
-        serde_json::to_string(&result).map_err(api_error)
+            .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_pause(&self) -> Result<()> {
-        self.vm_action(VmAction::Pause).await.map(|_| ())
+    async fn vm_remove_device(
+        &self,
+        vm_remove_device: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_remove_device), async {
+            let vm_remove_device = serde_json::from_str(&vm_remove_device).map_err(api_error)?;
+            self.vm_action(VmAction::RemoveDevice(Arc::new(vm_remove_device)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_power_button(&self) -> Result<()> {
-        self.vm_action(VmAction::PowerButton).await.map(|_| ())
+    async fn vm_resize(
+        &self,
+        vm_resize: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_resize), async {
+            let vm_resize = serde_json::from_str(&vm_resize).map_err(api_error)?;
+            self.vm_action(VmAction::Resize(Arc::new(vm_resize)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_reboot(&self) -> Result<()> {
-        self.vm_action(VmAction::Reboot).await.map(|_| ())
+    async fn vm_resize_zone(
+        &self,
+        vm_resize_zone: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_resize_zone), async {
+            let vm_resize_zone = serde_json::from_str(&vm_resize_zone).map_err(api_error)?;
+            self.vm_action(VmAction::ResizeZone(Arc::new(vm_resize_zone)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_remove_device(&self, vm_remove_device: String) -> Result<()> {
-        let vm_remove_device = serde_json::from_str(&vm_remove_device).map_err(api_error)?;
-        self.vm_action(VmAction::RemoveDevice(Arc::new(vm_remove_device)))
-            .await
-            .map(|_| ())
+    async fn vm_bind_zone(
+        &self,
+        vm_bind_zone: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_bind_zone), async {
+            let vm_bind_zone = serde_json::from_str(&vm_bind_zone).map_err(api_error)?;
+            self.vm_action(VmAction::BindZone(Arc::new(vm_bind_zone)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resize(&self, vm_resize: String) -> Result<()> {
-        let vm_resize = serde_json::from_str(&vm_resize).map_err(api_error)?;
-        self.vm_action(VmAction::Resize(Arc::new(vm_resize)))
-            .await
-            .map(|_| ())
+    async fn vm_remove_vcpu(
+        &self,
+        vm_remove_vcpu: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_remove_vcpu), async {
+            let vm_remove_vcpu = serde_json::from_str(&vm_remove_vcpu).map_err(api_error)?;
+            self.vm_action(VmAction::RemoveVcpu(Arc::new(vm_remove_vcpu)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resize_zone(&self, vm_resize_zone: String) -> Result<()> {
-        let vm_resize_zone = serde_json::from_str(&vm_resize_zone).map_err(api_error)?;
-        self.vm_action(VmAction::ResizeZone(Arc::new(vm_resize_zone)))
-            .await
-            .map(|_| ())
+    async fn vm_set_cpu_affinity(
+        &self,
+        vm_set_cpu_affinity: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_set_cpu_affinity), async {
+            let vm_set_cpu_affinity =
+                serde_json::from_str(&vm_set_cpu_affinity).map_err(api_error)?;
+            self.vm_action(VmAction::SetCpuAffinity(Arc::new(vm_set_cpu_affinity)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_restore(&self, restore_config: String) -> Result<()> {
-        let restore_config = serde_json::from_str(&restore_config).map_err(api_error)?;
-        self.vm_action(VmAction::Restore(Arc::new(restore_config)))
-            .await
-            .map(|_| ())
+    async fn vm_set_cpu_bandwidth(
+        &self,
+        vm_set_cpu_bandwidth: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_set_cpu_bandwidth), async {
+            let vm_set_cpu_bandwidth =
+                serde_json::from_str(&vm_set_cpu_bandwidth).map_err(api_error)?;
+            self.vm_action(VmAction::SetCpuBandwidth(Arc::new(vm_set_cpu_bandwidth)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_receive_migration(&self, receive_migration_data: String) -> Result<()> {
-        let receive_migration_data =
-            serde_json::from_str(&receive_migration_data).map_err(api_error)?;
-        self.vm_action(VmAction::ReceiveMigration(Arc::new(receive_migration_data)))
-            .await
-            .map(|_| ())
+    async fn vm_resize_fs(
+        &self,
+        vm_resize_fs: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_resize_fs), async {
+            let vm_resize_fs = serde_json::from_str(&vm_resize_fs).map_err(api_error)?;
+            self.vm_action(VmAction::ResizeFs(Arc::new(vm_resize_fs)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_send_migration(&self, send_migration_data: String) -> Result<()> {
-        let send_migration_data = serde_json::from_str(&send_migration_data).map_err(api_error)?;
-        self.vm_action(VmAction::SendMigration(Arc::new(send_migration_data)))
-            .await
-            .map(|_| ())
+    async fn vm_restore(
+        &self,
+        restore_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&restore_config), async {
+            let restore_config: RestoreConfig =
+                serde_json::from_str(&restore_config).map_err(api_error)?;
+            check_snapshot_url(&restore_config.source_url.to_string_lossy())?;
+            self.vm_action(VmAction::Restore(Arc::new(restore_config)))
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_receive_migration(
+        &self,
+        receive_migration_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&receive_migration_data), async {
+            let receive_migration_data =
+                serde_json::from_str(&receive_migration_data).map_err(api_error)?;
+            self.vm_action(VmAction::ReceiveMigration(Arc::new(receive_migration_data)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_resume(&self) -> Result<()> {
-        self.vm_action(VmAction::Resume).await.map(|_| ())
+    async fn vm_migration_precheck(
+        &self,
+        send_migration_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<Optional<String>> {
+        audited(connection, &header, Some(&send_migration_data), async {
+            let send_migration_data =
+                serde_json::from_str(&send_migration_data).map_err(api_error)?;
+            self.vm_action(VmAction::MigrationPrecheck(Arc::new(send_migration_data)))
+                .await
+        })
+        .await
     }
 
-    async fn vm_shutdown(&self) -> Result<()> {
-        self.vm_action(VmAction::Shutdown).await.map(|_| ())
+    async fn vm_send_migration(
+        &self,
+        send_migration_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&send_migration_data), async {
+            let send_migration_data =
+                serde_json::from_str(&send_migration_data).map_err(api_error)?;
+            self.vm_action(VmAction::SendMigration(Arc::new(send_migration_data)))
+                .await
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn vm_resume(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Resume).await.map(|_| ())
+        })
+        .await
     }
 
-    async fn vm_snapshot(&self, vm_snapshot_config: String) -> Result<()> {
-        let vm_snapshot_config = serde_json::from_str(&vm_snapshot_config).map_err(api_error)?;
-        self.vm_action(VmAction::Snapshot(Arc::new(vm_snapshot_config)))
+    async fn vm_shutdown(
+        &self,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, None, async {
+            self.vm_action(VmAction::Shutdown).await.map(|_| ())
+        })
+        .await
+    }
+
+    // Run the long running request of the given kind, such as "snapshot"
+    // or "send-migration", as a job, returning the job rather than waiting
+    // for the request to complete.
+    async fn vm_start_job(
+        &self,
+        kind: String,
+        request_data: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<String> {
+        audited(connection, &header, Some(&request_data), async {
+            let kind: JobKind =
+                serde_json::from_value(serde_json::Value::String(kind)).map_err(api_error)?;
+            let action =
+                match kind {
+                    JobKind::Snapshot => {
+                        let vm_snapshot_config: VmSnapshotConfig =
+                            serde_json::from_str(&request_data).map_err(api_error)?;
+                        check_snapshot_url(&vm_snapshot_config.destination_url)?;
+                        VmAction::Snapshot(Arc::new(vm_snapshot_config))
+                    }
+                    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                    JobKind::Coredump => VmAction::Coredump(Arc::new(
+                        serde_json::from_str(&request_data).map_err(api_error)?,
+                    )),
+                    #[cfg(not(all(target_arch = "x86_64", feature = "guest_debug")))]
+                    JobKind::Coredump => return Err(api_error(
+                        "VmCoredump only works on x86_64 with the `guest_debug` feature enabled",
+                    )),
+                    JobKind::SendMigration => VmAction::SendMigration(Arc::new(
+                        serde_json::from_str(&request_data).map_err(api_error)?,
+                    )),
+                    JobKind::ReceiveMigration => VmAction::ReceiveMigration(Arc::new(
+                        serde_json::from_str(&request_data).map_err(api_error)?,
+                    )),
+                };
+
+            let api_sender = self.clone_api_sender().await;
+            let api_notifier = self.clone_api_notifier()?;
+            let job_sender = api_sender.clone();
+            let job_notifier = self.clone_api_notifier()?;
+            let id = blocking::unblock(move || {
+                jobs::start(kind, &api_notifier, &api_sender, move || {
+                    super::vm_action(job_notifier, job_sender, action)
+                        .map(|_| ())
+                        .map_err(|e| format!("{e:?}"))
+                })
+            })
             .await
-            .map(|_| ())
+            .map_err(api_error)?;
+
+            serde_json::to_string(&jobs::get(id)).map_err(api_error)
+        })
+        .await
+    }
+
+    async fn vm_snapshot(
+        &self,
+        vm_snapshot_config: String,
+        #[zbus(header)] header: MessageHeader<'_>,
+        #[zbus(connection)] connection: &Connection,
+    ) -> Result<()> {
+        audited(connection, &header, Some(&vm_snapshot_config), async {
+            let vm_snapshot_config: VmSnapshotConfig =
+                serde_json::from_str(&vm_snapshot_config).map_err(api_error)?;
+            check_snapshot_url(&vm_snapshot_config.destination_url)?;
+            self.vm_action(VmAction::Snapshot(Arc::new(vm_snapshot_config)))
+                .await
+                .map(|_| ())
+        })
+        .await
     }
 
     // implementation of this function is provided by the `dbus_interface` macro
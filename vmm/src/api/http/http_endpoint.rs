@@ -4,23 +4,152 @@
 //
 
 use crate::api::http::{error_response, EndpointHandler, HttpError};
+use crate::api::jobs::{self, JobKind};
+#[cfg(target_arch = "x86_64")]
+use crate::api::vm_add_sgx_epc;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::api::vm_coredump;
+use crate::api::VmSnapshotConfig;
 use crate::api::{
-    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_user_device,
-    vm_add_vdpa, vm_add_vsock, vm_boot, vm_counters, vm_create, vm_delete, vm_info, vm_pause,
-    vm_power_button, vm_reboot, vm_receive_migration, vm_remove_device, vm_resize, vm_resize_zone,
-    vm_restore, vm_resume, vm_send_migration, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown,
-    ApiRequest, VmAction, VmConfig,
+    vm_add_console_port, vm_add_device, vm_add_disk, vm_add_fs, vm_add_mdev, vm_add_net,
+    vm_add_pmem, vm_add_user_device, vm_add_vdpa, vm_add_vf, vm_add_vsock, vm_bind_zone, vm_boot,
+    vm_boot_timings, vm_counters, vm_create, vm_delete, vm_guest_exec, vm_guest_fsfreeze,
+    vm_guest_info, vm_hetero_balloon, vm_info, vm_memory_read, vm_memory_write,
+    vm_migration_precheck, vm_pause, vm_power_button, vm_reboot, vm_receive_migration,
+    vm_remove_console_port, vm_remove_device, vm_remove_vcpu, vm_report_free_pages, vm_resize,
+    vm_resize_fs, vm_resize_zone, vm_restore, vm_resume, vm_send_migration, vm_set_cpu_affinity,
+    vm_set_cpu_bandwidth, vm_shutdown, vm_snapshot, vm_virtqueue_state, vmm_events, vmm_ping,
+    vmm_resources, vmm_shutdown, ApiRequest, ApiResult, VmAction, VmConfig, VmCountersData,
+    VmJobsData, VmmEventsData,
 };
-use crate::config::NetConfig;
-use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use crate::config::{NetConfig, RestoreConfig};
+use micro_http::{Body, MediaType, Method, Request, Response, StatusCode, Version};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
-use std::os::unix::io::IntoRawFd;
+use std::os::unix::io::{IntoRawFd, OwnedFd};
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
 
+// Indexes, among the files sent through control message, of the payload files
+// given as file descriptors.
+#[derive(Default, Deserialize)]
+struct PayloadFdIndexes {
+    #[serde(default)]
+    firmware_fd: Option<usize>,
+    #[serde(default)]
+    kernel_fd: Option<usize>,
+    #[serde(default)]
+    initramfs_fd: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct VmCreatePayloadFds {
+    #[serde(default)]
+    payload: Option<PayloadFdIndexes>,
+}
+
+// The payload files referred to by the request body are handed over to the
+// VM configuration, which keeps them open for the payload to be loaded again
+// when the VM reboots.
+fn attach_payload_files(
+    vm_config: &mut VmConfig,
+    body: &[u8],
+    files: &[File],
+) -> Result<(), HttpError> {
+    let indexes = serde_json::from_slice::<VmCreatePayloadFds>(body)
+        .map_err(HttpError::SerdeJsonDeserialize)?
+        .payload
+        .unwrap_or_default();
+    let Some(payload) = vm_config.payload.as_mut() else {
+        return Ok(());
+    };
+
+    let mut fds = Vec::new();
+    for (fd, index) in [
+        (&mut payload.firmware_fd, indexes.firmware_fd),
+        (&mut payload.kernel_fd, indexes.kernel_fd),
+        (&mut payload.initramfs_fd, indexes.initramfs_fd),
+    ] {
+        let Some(index) = index else {
+            continue;
+        };
+        let file = files.get(index).ok_or(HttpError::BadRequest)?;
+        // Cloning the file dup() its descriptor, the same file possibly
+        // backing several parts of the payload.
+        let owned_fd = OwnedFd::from(
+            file.try_clone()
+                .map_err(|_| HttpError::InternalServerError)?,
+        );
+        let raw_fd = owned_fd.into_raw_fd();
+        *fd = Some(raw_fd);
+        fds.push(raw_fd);
+    }
+
+    // SAFETY: the FDs were just duplicated, and are owned by the
+    // configuration from now on.
+    unsafe { vm_config.add_preserved_fds(fds) };
+
+    Ok(())
+}
+
+// A snapshot streamed through a file descriptor refers to the file sent
+// through control message by its index, replaced by the file descriptor.
+fn attach_snapshot_file(url: &str, files: &[File]) -> Result<String, HttpError> {
+    let Some(index) = url.strip_prefix("fd://") else {
+        return Ok(url.to_string());
+    };
+
+    let file = index
+        .parse::<usize>()
+        .ok()
+        .and_then(|index| files.get(index))
+        .ok_or(HttpError::BadRequest)?;
+    let fd = file
+        .try_clone()
+        .map_err(|_| HttpError::InternalServerError)?
+        .into_raw_fd();
+
+    Ok(format!("fd://{fd}"))
+}
+
+// The long running requests are run as a job when asked for, the response
+// describing the job rather than the outcome of the request.
+#[derive(Deserialize)]
+struct JobRequest {
+    #[serde(default, rename = "async")]
+    run_async: bool,
+}
+
+fn run_async(body: &Body) -> Result<bool, HttpError> {
+    Ok(serde_json::from_slice::<JobRequest>(body.raw())?.run_async)
+}
+
+fn start_job<F>(
+    kind: JobKind,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    request: F,
+) -> Result<Option<Body>, HttpError>
+where
+    F: FnOnce(EventFd, Sender<ApiRequest>) -> ApiResult<Option<Body>> + Send + 'static,
+{
+    let job_notifier = api_notifier
+        .try_clone()
+        .map_err(|_| HttpError::InternalServerError)?;
+    let job_sender = api_sender.clone();
+    let id = jobs::start(kind, &api_notifier, &api_sender, move || {
+        request(job_notifier, job_sender)
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    })
+    .map_err(HttpError::Job)?;
+    let info = jobs::get(id).ok_or(HttpError::InternalServerError)?;
+
+    Ok(Some(Body::new(serde_json::to_string(&info).unwrap())))
+}
+
 // /api/v1/vm.create handler
 pub struct VmCreate {}
 
@@ -52,6 +181,11 @@ impl EndpointHandler for VmCreate {
                             }
                         }
 
+                        if let Err(e) = attach_payload_files(&mut vm_config, body.raw(), &req.files)
+                        {
+                            return error_response(e, StatusCode::BadRequest);
+                        }
+
                         // Call vm_create()
                         match vm_create(api_notifier, api_sender, Arc::new(Mutex::new(vm_config)))
                             .map_err(HttpError::ApiError)
@@ -97,6 +231,16 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                AddVf(_) => vm_add_vf(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                AddMdev(_) => vm_add_mdev(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
                 AddDisk(_) => vm_add_disk(
                     api_notifier,
                     api_sender,
@@ -136,6 +280,17 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                #[cfg(target_arch = "x86_64")]
+                AddSgxEpc(_) => vm_add_sgx_epc(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                AddConsolePort(_) => vm_add_console_port(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
                 AddUserDevice(_) => vm_add_user_device(
                     api_notifier,
                     api_sender,
@@ -146,6 +301,26 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                RemoveVcpu(_) => vm_remove_vcpu(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                SetCpuAffinity(_) => vm_set_cpu_affinity(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                SetCpuBandwidth(_) => vm_set_cpu_bandwidth(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                RemoveConsolePort(_) => vm_remove_console_port(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
                 Resize(_) => vm_resize(
                     api_notifier,
                     api_sender,
@@ -156,28 +331,115 @@ impl EndpointHandler for VmActionHandler {
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
-                Restore(_) => vm_restore(
+                BindZone(_) => vm_bind_zone(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
-                Snapshot(_) => vm_snapshot(
+                ResizeFs(_) => vm_resize_fs(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
+                Restore(_) => {
+                    let mut restore_cfg: RestoreConfig = serde_json::from_slice(body.raw())?;
+                    let source_url = restore_cfg
+                        .source_url
+                        .to_str()
+                        .ok_or(HttpError::BadRequest)?;
+                    restore_cfg.source_url = attach_snapshot_file(source_url, &files)?.into();
+                    vm_restore(api_notifier, api_sender, Arc::new(restore_cfg))
+                }
+                Snapshot(_) => {
+                    let mut snapshot_cfg: VmSnapshotConfig = serde_json::from_slice(body.raw())?;
+                    snapshot_cfg.destination_url =
+                        attach_snapshot_file(&snapshot_cfg.destination_url, &files)?;
+                    let snapshot_cfg = Arc::new(snapshot_cfg);
+                    if run_async(body)? {
+                        return start_job(
+                            JobKind::Snapshot,
+                            api_notifier,
+                            api_sender,
+                            move |api_notifier, api_sender| {
+                                vm_snapshot(api_notifier, api_sender, snapshot_cfg)
+                            },
+                        );
+                    }
+                    vm_snapshot(api_notifier, api_sender, snapshot_cfg)
+                }
                 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
-                Coredump(_) => vm_coredump(
+                Coredump(_) => {
+                    let coredump_data = Arc::new(serde_json::from_slice(body.raw())?);
+                    if run_async(body)? {
+                        return start_job(
+                            JobKind::Coredump,
+                            api_notifier,
+                            api_sender,
+                            move |api_notifier, api_sender| {
+                                vm_coredump(api_notifier, api_sender, coredump_data)
+                            },
+                        );
+                    }
+                    vm_coredump(api_notifier, api_sender, coredump_data)
+                }
+                ReceiveMigration(_) => {
+                    let receive_migration_data = Arc::new(serde_json::from_slice(body.raw())?);
+                    if run_async(body)? {
+                        return start_job(
+                            JobKind::ReceiveMigration,
+                            api_notifier,
+                            api_sender,
+                            move |api_notifier, api_sender| {
+                                vm_receive_migration(
+                                    api_notifier,
+                                    api_sender,
+                                    receive_migration_data,
+                                )
+                            },
+                        );
+                    }
+                    vm_receive_migration(api_notifier, api_sender, receive_migration_data)
+                }
+                SendMigration(_) => {
+                    let send_migration_data = Arc::new(serde_json::from_slice(body.raw())?);
+                    if run_async(body)? {
+                        return start_job(
+                            JobKind::SendMigration,
+                            api_notifier,
+                            api_sender,
+                            move |api_notifier, api_sender| {
+                                vm_send_migration(api_notifier, api_sender, send_migration_data)
+                            },
+                        );
+                    }
+                    vm_send_migration(api_notifier, api_sender, send_migration_data)
+                }
+                MigrationPrecheck(_) => vm_migration_precheck(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                GuestExec(_) => vm_guest_exec(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                GuestFsFreeze(_) => vm_guest_fsfreeze(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
-                ReceiveMigration(_) => vm_receive_migration(
+                MemoryRead(_) => vm_memory_read(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
                 ),
-                SendMigration(_) => vm_send_migration(
+                MemoryWrite(_) => vm_memory_write(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                ),
+                VirtqueueState(_) => vm_virtqueue_state(
                     api_notifier,
                     api_sender,
                     Arc::new(serde_json::from_slice(body.raw())?),
@@ -190,6 +452,7 @@ impl EndpointHandler for VmActionHandler {
                 Pause => vm_pause(api_notifier, api_sender),
                 Resume => vm_resume(api_notifier, api_sender),
                 PowerButton => vm_power_button(api_notifier, api_sender),
+                ReportFreePages => vm_report_free_pages(api_notifier, api_sender),
 
                 _ => return Err(HttpError::BadRequest),
             }
@@ -202,6 +465,7 @@ impl EndpointHandler for VmActionHandler {
                 Pause => vm_pause(api_notifier, api_sender),
                 Resume => vm_resume(api_notifier, api_sender),
                 PowerButton => vm_power_button(api_notifier, api_sender),
+                ReportFreePages => vm_report_free_pages(api_notifier, api_sender),
                 _ => return Err(HttpError::BadRequest),
             }
         }
@@ -212,11 +476,22 @@ impl EndpointHandler for VmActionHandler {
         &self,
         api_notifier: EventFd,
         api_sender: Sender<ApiRequest>,
-        _body: &Option<Body>,
+        body: &Option<Body>,
     ) -> std::result::Result<Option<Body>, HttpError> {
         use VmAction::*;
         match self.action {
-            Counters => vm_counters(api_notifier, api_sender).map_err(HttpError::ApiError),
+            BootTimings => vm_boot_timings(api_notifier, api_sender).map_err(HttpError::ApiError),
+            Counters(_) => {
+                let data = match body {
+                    Some(body) => serde_json::from_slice(body.raw())?,
+                    None => VmCountersData::default(),
+                };
+                vm_counters(api_notifier, api_sender, Arc::new(data)).map_err(HttpError::ApiError)
+            }
+            GuestInfo => vm_guest_info(api_notifier, api_sender).map_err(HttpError::ApiError),
+            HeteroBalloon => {
+                vm_hetero_balloon(api_notifier, api_sender).map_err(HttpError::ApiError)
+            }
             _ => Err(HttpError::BadRequest),
         }
     }
@@ -248,6 +523,97 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// /api/v1/vm.metrics handler
+pub struct VmMetrics {}
+
+impl EndpointHandler for VmMetrics {
+    fn get_handler(
+        &self,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+        _body: &Option<Body>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let counters = match vm_counters(api_notifier, api_sender, Arc::default())
+            .map_err(HttpError::ApiError)?
+        {
+            Some(body) => serde_json::from_slice(body.raw())?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(Some(Body::new(prometheus_metrics(&counters))))
+    }
+
+    fn content_type(&self) -> MediaType {
+        MediaType::PlainText
+    }
+}
+
+// Format the counters of the devices with the Prometheus text format, the
+// device being set as a label. The counters of the latency histograms, whose
+// buckets are named "<histogram>_bucket_le_<bound>", are reported as
+// histograms, the minimum, maximum and average latencies as gauges, and all
+// the other ones as counters.
+fn prometheus_metrics(counters: &BTreeMap<String, BTreeMap<String, u64>>) -> String {
+    let histograms: BTreeSet<&str> = counters
+        .values()
+        .flat_map(|device_counters| device_counters.keys())
+        .filter_map(|name| {
+            name.split_once("_bucket_le_")
+                .map(|(histogram, _)| histogram)
+        })
+        .collect();
+
+    // All the samples of a metric must be grouped together
+    let mut metrics: BTreeMap<&str, Vec<(String, String, u64)>> = BTreeMap::new();
+    for (device, device_counters) in counters {
+        let device = device.replace('\\', "\\\\").replace('"', "\\\"");
+        for (name, value) in device_counters {
+            let (metric, sample, labels) = match name.split_once("_bucket_le_") {
+                Some((histogram, bound)) => (
+                    histogram,
+                    format!("{histogram}_bucket"),
+                    format!(
+                        "device=\"{device}\",le=\"{}\"",
+                        if bound == "inf" { "+Inf" } else { bound }
+                    ),
+                ),
+                None => (
+                    name.strip_suffix("_sum")
+                        .or_else(|| name.strip_suffix("_count"))
+                        .filter(|histogram| histograms.contains(histogram))
+                        .unwrap_or(name.as_str()),
+                    name.clone(),
+                    format!("device=\"{device}\""),
+                ),
+            };
+            metrics
+                .entry(metric)
+                .or_default()
+                .push((sample, labels, *value));
+        }
+    }
+
+    let mut output = String::new();
+    for (metric, samples) in metrics {
+        let (metric_type, help) = if histograms.contains(&metric) {
+            (
+                "histogram",
+                "Latency histogram of the devices, in microseconds",
+            )
+        } else if ["_min", "_max", "_avg"].iter().any(|s| metric.ends_with(s)) {
+            ("gauge", "Latency of the devices, in microseconds")
+        } else {
+            ("counter", "Counter of the devices")
+        };
+        output.push_str(&format!("# HELP cloud_hypervisor_{metric} {help}\n"));
+        output.push_str(&format!("# TYPE cloud_hypervisor_{metric} {metric_type}\n"));
+        for (sample, labels, value) in samples {
+            output.push_str(&format!("cloud_hypervisor_{sample}{{{labels}}} {value}\n"));
+        }
+    }
+    output
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -275,6 +641,104 @@ impl EndpointHandler for VmmPing {
     }
 }
 
+// /api/v1/vmm.events handler
+pub struct VmmEvents {}
+
+impl EndpointHandler for VmmEvents {
+    fn handle_request(
+        &self,
+        req: &Request,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                let data = match &req.body {
+                    Some(body) => match serde_json::from_slice::<VmmEventsData>(body.raw()) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            return error_response(
+                                HttpError::SerdeJsonDeserialize(e),
+                                StatusCode::BadRequest,
+                            )
+                        }
+                    },
+                    None => VmmEventsData::default(),
+                };
+
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                let events_serialized = serde_json::to_string(&vmm_events(&data)).unwrap();
+
+                response.set_body(Body::new(events_serialized));
+                response
+            }
+
+            _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
+        }
+    }
+
+    fn needs_vmm(&self) -> bool {
+        false
+    }
+}
+
+// /api/v1/vm.jobs handler
+pub struct VmJobs {}
+
+impl EndpointHandler for VmJobs {
+    fn get_handler(
+        &self,
+        _api_notifier: EventFd,
+        _api_sender: Sender<ApiRequest>,
+        body: &Option<Body>,
+    ) -> std::result::Result<Option<Body>, HttpError> {
+        let data: VmJobsData = match body {
+            Some(body) => serde_json::from_slice(body.raw())?,
+            None => VmJobsData::default(),
+        };
+
+        let jobs = match data.id {
+            Some(id) => serde_json::to_string(&jobs::get(id).ok_or(HttpError::NotFound)?),
+            None => serde_json::to_string(&jobs::list()),
+        };
+
+        Ok(Some(Body::new(jobs.unwrap())))
+    }
+
+    fn needs_vmm(&self) -> bool {
+        false
+    }
+}
+
+// /api/v1/vmm.resources handler
+pub struct VmmResources {}
+
+impl EndpointHandler for VmmResources {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                match vmm_resources(api_notifier, api_sender).map_err(HttpError::ApiError) {
+                    Ok(resources) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let resources_serialized = serde_json::to_string(&resources).unwrap();
+
+                        response.set_body(Body::new(resources_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+
+            _ => error_response(HttpError::BadRequest, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.shutdown handler
 pub struct VmmShutdown {}
 
@@ -296,3 +760,106 @@ impl EndpointHandler for VmmShutdown {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_metrics() {
+        let counters = BTreeMap::from([
+            (
+                "_disk0".to_string(),
+                BTreeMap::from([
+                    ("read_bytes".to_string(), 4096),
+                    ("read_latency_min".to_string(), 12),
+                    ("read_latency_us_bucket_le_10".to_string(), 0),
+                    ("read_latency_us_bucket_le_inf".to_string(), 2),
+                    ("read_latency_us_count".to_string(), 2),
+                    ("read_latency_us_sum".to_string(), 40),
+                ]),
+            ),
+            (
+                "_net\"1".to_string(),
+                BTreeMap::from([("rx_bytes".to_string(), 1500)]),
+            ),
+        ]);
+
+        assert_eq!(
+            prometheus_metrics(&counters),
+            "# HELP cloud_hypervisor_read_bytes Counter of the devices\n\
+             # TYPE cloud_hypervisor_read_bytes counter\n\
+             cloud_hypervisor_read_bytes{device=\"_disk0\"} 4096\n\
+             # HELP cloud_hypervisor_read_latency_min Latency of the devices, in microseconds\n\
+             # TYPE cloud_hypervisor_read_latency_min gauge\n\
+             cloud_hypervisor_read_latency_min{device=\"_disk0\"} 12\n\
+             # HELP cloud_hypervisor_read_latency_us Latency histogram of the devices, in microseconds\n\
+             # TYPE cloud_hypervisor_read_latency_us histogram\n\
+             cloud_hypervisor_read_latency_us_bucket{device=\"_disk0\",le=\"10\"} 0\n\
+             cloud_hypervisor_read_latency_us_bucket{device=\"_disk0\",le=\"+Inf\"} 2\n\
+             cloud_hypervisor_read_latency_us_count{device=\"_disk0\"} 2\n\
+             cloud_hypervisor_read_latency_us_sum{device=\"_disk0\"} 40\n\
+             # HELP cloud_hypervisor_rx_bytes Counter of the devices\n\
+             # TYPE cloud_hypervisor_rx_bytes counter\n\
+             cloud_hypervisor_rx_bytes{device=\"_net\\\"1\"} 1500\n"
+        );
+    }
+
+    #[test]
+    fn test_attach_payload_files() {
+        use std::io::{Read, Write};
+        use vmm_sys_util::tempfile::TempFile;
+
+        let body =
+            br#"{"payload": {"kernel_fd": 1, "initramfs_fd": 0, "cmdline": "console=ttyS0"}}"#;
+        let mut vm_config: VmConfig = serde_json::from_slice(body).unwrap();
+        // The indexes from the request body are never taken as FDs
+        assert_eq!(vm_config.payload.as_ref().unwrap().kernel_fd, None);
+
+        let files: Vec<File> = [&b"initramfs"[..], &b"kernel"[..]]
+            .iter()
+            .map(|content| {
+                let file = TempFile::new().unwrap().as_file().try_clone().unwrap();
+                (&file).write_all(content).unwrap();
+                file
+            })
+            .collect();
+        attach_payload_files(&mut vm_config, body, &files).unwrap();
+        drop(files);
+        assert_eq!(vm_config.preserved_fds.as_ref().unwrap().len(), 2);
+
+        // The FDs are owned by the configuration, its clones getting their
+        // own copy, and are kept out of its serialization
+        let cloned_config = vm_config.clone();
+        drop(vm_config);
+        let payload = cloned_config.payload.as_ref().unwrap();
+        let mut kernel = String::new();
+        payload
+            .open_kernel()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut kernel)
+            .unwrap();
+        assert_eq!(kernel, "kernel");
+        let mut initramfs = String::new();
+        payload
+            .open_initramfs()
+            .unwrap()
+            .unwrap()
+            .read_to_string(&mut initramfs)
+            .unwrap();
+        assert_eq!(initramfs, "initramfs");
+        assert!(!serde_json::to_string(&cloned_config)
+            .unwrap()
+            .contains("kernel_fd"));
+
+        // Indexes beyond the files sent are refused
+        let body = br#"{"payload": {"kernel_fd": 2}}"#;
+        let mut vm_config: VmConfig = serde_json::from_slice(body).unwrap();
+        assert!(matches!(
+            attach_payload_files(&mut vm_config, body, &[]),
+            Err(HttpError::BadRequest)
+        ));
+        assert_eq!(vm_config.preserved_fds, None);
+    }
+}
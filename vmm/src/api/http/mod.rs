@@ -3,27 +3,35 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use self::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
-use crate::api::{ApiError, ApiRequest, VmAction};
+use self::http_endpoint::{
+    VmActionHandler, VmCreate, VmInfo, VmJobs, VmMetrics, VmmEvents, VmmPing, VmmResources,
+    VmmShutdown,
+};
+use crate::api::jobs::{self, JobError};
+use crate::api::{audit, ApiError, ApiRequest, VmAction};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::{Error as VmmError, Result};
 use hypervisor::HypervisorType;
-use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusCode, Version};
+use micro_http::{Body, MediaType, Method, Request, Response, StatusCode, Version};
 use once_cell::sync::Lazy;
 use seccompiler::{apply_filter, SeccompAction};
 use serde_json::Error as SerdeError;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::os::unix::io::{IntoRawFd, RawFd};
-use std::os::unix::net::UnixListener;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
 pub mod http_endpoint;
+#[cfg(feature = "tls_api")]
+pub mod tls;
 
 /// Errors associated with VMM management
 #[derive(Debug)]
@@ -42,6 +50,9 @@ pub enum HttpError {
 
     /// Error from internal API
     ApiError(ApiError),
+
+    /// Error running the request as a job
+    Job(JobError),
 }
 
 impl From<serde_json::Error> for HttpError {
@@ -52,7 +63,20 @@ impl From<serde_json::Error> for HttpError {
 
 const HTTP_ROOT: &str = "/api/v1";
 
+// Maximum number of clients connected to the UNIX socket at the same time.
+const MAX_CONNECTIONS: usize = 64;
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+// Maximum number of file descriptors attached to a request.
+const MAX_REQUEST_FDS: usize = 32;
+
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
+    // Whatever the request, a job keeping the VMM busy is only transient
+    let status = match error {
+        HttpError::Job(JobError::Busy(_)) | HttpError::ApiError(ApiError::Job(_)) => {
+            StatusCode::ServiceUnavailable
+        }
+        _ => status,
+    };
     let mut response = Response::new(Version::Http11, status);
     response.set_body(Body::new(format!("{error:?}")));
 
@@ -94,6 +118,10 @@ pub trait EndpointHandler {
                 }
             }
             Err(e @ HttpError::BadRequest) => error_response(e, StatusCode::BadRequest),
+            Err(e @ HttpError::Job(JobError::Busy(_))) => {
+                error_response(e, StatusCode::ServiceUnavailable)
+            }
+            Err(e @ HttpError::NotFound) => error_response(e, StatusCode::NotFound),
             Err(e @ HttpError::SerdeJsonDeserialize(_)) => {
                 error_response(e, StatusCode::BadRequest)
             }
@@ -119,6 +147,17 @@ pub trait EndpointHandler {
     ) -> std::result::Result<Option<Body>, HttpError> {
         Err(HttpError::BadRequest)
     }
+
+    /// Media type of the responses of the endpoint.
+    fn content_type(&self) -> MediaType {
+        MediaType::ApplicationJson
+    }
+
+    /// Whether the endpoint sends requests to the VMM, which only answers
+    /// them once the running job completed.
+    fn needs_vmm(&self) -> bool {
+        true
+    }
 }
 
 /// An HTTP routes structure.
@@ -139,10 +178,24 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         routes: BTreeMap::new(),
     };
 
+    r.routes.insert(
+        endpoint!("/vm.add-console-port"),
+        Box::new(VmActionHandler::new(VmAction::AddConsolePort(
+            Arc::default(),
+        ))),
+    );
     r.routes.insert(
         endpoint!("/vm.add-device"),
         Box::new(VmActionHandler::new(VmAction::AddDevice(Arc::default()))),
     );
+    r.routes.insert(
+        endpoint!("/vm.add-vf"),
+        Box::new(VmActionHandler::new(VmAction::AddVf(Arc::default()))),
+    );
+    r.routes.insert(
+        endpoint!("/vm.add-mdev"),
+        Box::new(VmActionHandler::new(VmAction::AddMdev(Arc::default()))),
+    );
     r.routes.insert(
         endpoint!("/vm.add-user-device"),
         Box::new(VmActionHandler::new(
@@ -165,6 +218,11 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.add-pmem"),
         Box::new(VmActionHandler::new(VmAction::AddPmem(Arc::default()))),
     );
+    #[cfg(target_arch = "x86_64")]
+    r.routes.insert(
+        endpoint!("/vm.add-sgx-epc"),
+        Box::new(VmActionHandler::new(VmAction::AddSgxEpc(Arc::default()))),
+    );
     r.routes.insert(
         endpoint!("/vm.add-vdpa"),
         Box::new(VmActionHandler::new(VmAction::AddVdpa(Arc::default()))),
@@ -173,13 +231,21 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.add-vsock"),
         Box::new(VmActionHandler::new(VmAction::AddVsock(Arc::default()))),
     );
+    r.routes.insert(
+        endpoint!("/vm.bind-zone"),
+        Box::new(VmActionHandler::new(VmAction::BindZone(Arc::default()))),
+    );
     r.routes.insert(
         endpoint!("/vm.boot"),
         Box::new(VmActionHandler::new(VmAction::Boot)),
     );
+    r.routes.insert(
+        endpoint!("/vm.boot-timings"),
+        Box::new(VmActionHandler::new(VmAction::BootTimings)),
+    );
     r.routes.insert(
         endpoint!("/vm.counters"),
-        Box::new(VmActionHandler::new(VmAction::Counters)),
+        Box::new(VmActionHandler::new(VmAction::Counters(Arc::default()))),
     );
     r.routes
         .insert(endpoint!("/vm.create"), Box::new(VmCreate {}));
@@ -187,7 +253,42 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.delete"),
         Box::new(VmActionHandler::new(VmAction::Delete)),
     );
+    r.routes.insert(
+        endpoint!("/vm.guest-exec"),
+        Box::new(VmActionHandler::new(VmAction::GuestExec(Arc::default()))),
+    );
+    r.routes.insert(
+        endpoint!("/vm.guest-fsfreeze"),
+        Box::new(VmActionHandler::new(
+            VmAction::GuestFsFreeze(Arc::default()),
+        )),
+    );
+    r.routes.insert(
+        endpoint!("/vm.guest-info"),
+        Box::new(VmActionHandler::new(VmAction::GuestInfo)),
+    );
+    r.routes.insert(
+        endpoint!("/vm.hetero-balloon"),
+        Box::new(VmActionHandler::new(VmAction::HeteroBalloon)),
+    );
     r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+    r.routes.insert(endpoint!("/vm.jobs"), Box::new(VmJobs {}));
+    r.routes.insert(
+        endpoint!("/vm.memory-read"),
+        Box::new(VmActionHandler::new(VmAction::MemoryRead(Arc::default()))),
+    );
+    r.routes.insert(
+        endpoint!("/vm.memory-write"),
+        Box::new(VmActionHandler::new(VmAction::MemoryWrite(Arc::default()))),
+    );
+    r.routes
+        .insert(endpoint!("/vm.metrics"), Box::new(VmMetrics {}));
+    r.routes.insert(
+        endpoint!("/vm.migration-precheck"),
+        Box::new(VmActionHandler::new(VmAction::MigrationPrecheck(
+            Arc::default(),
+        ))),
+    );
     r.routes.insert(
         endpoint!("/vm.pause"),
         Box::new(VmActionHandler::new(VmAction::Pause)),
@@ -206,14 +307,32 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
             Arc::default(),
         ))),
     );
+    r.routes.insert(
+        endpoint!("/vm.remove-console-port"),
+        Box::new(VmActionHandler::new(VmAction::RemoveConsolePort(
+            Arc::default(),
+        ))),
+    );
     r.routes.insert(
         endpoint!("/vm.remove-device"),
         Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))),
     );
+    r.routes.insert(
+        endpoint!("/vm.remove-vcpu"),
+        Box::new(VmActionHandler::new(VmAction::RemoveVcpu(Arc::default()))),
+    );
+    r.routes.insert(
+        endpoint!("/vm.report-free-pages"),
+        Box::new(VmActionHandler::new(VmAction::ReportFreePages)),
+    );
     r.routes.insert(
         endpoint!("/vm.resize"),
         Box::new(VmActionHandler::new(VmAction::Resize(Arc::default()))),
     );
+    r.routes.insert(
+        endpoint!("/vm.resize-fs"),
+        Box::new(VmActionHandler::new(VmAction::ResizeFs(Arc::default()))),
+    );
     r.routes.insert(
         endpoint!("/vm.resize-zone"),
         Box::new(VmActionHandler::new(VmAction::ResizeZone(Arc::default()))),
@@ -232,6 +351,18 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
             VmAction::SendMigration(Arc::default()),
         )),
     );
+    r.routes.insert(
+        endpoint!("/vm.set-cpu-affinity"),
+        Box::new(VmActionHandler::new(VmAction::SetCpuAffinity(
+            Arc::default(),
+        ))),
+    );
+    r.routes.insert(
+        endpoint!("/vm.set-cpu-bandwidth"),
+        Box::new(VmActionHandler::new(VmAction::SetCpuBandwidth(
+            Arc::default(),
+        ))),
+    );
     r.routes.insert(
         endpoint!("/vm.shutdown"),
         Box::new(VmActionHandler::new(VmAction::Shutdown)),
@@ -240,13 +371,23 @@ pub static HTTP_ROUTES: Lazy<HttpRoutes> = Lazy::new(|| {
         endpoint!("/vm.snapshot"),
         Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))),
     );
+    r.routes.insert(
+        endpoint!("/vm.virtqueue-state"),
+        Box::new(VmActionHandler::new(VmAction::VirtqueueState(
+            Arc::default(),
+        ))),
+    );
     #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
     r.routes.insert(
         endpoint!("/vm.coredump"),
         Box::new(VmActionHandler::new(VmAction::Coredump(Arc::default()))),
     );
+    r.routes
+        .insert(endpoint!("/vmm.events"), Box::new(VmmEvents {}));
     r.routes
         .insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
+    r.routes
+        .insert(endpoint!("/vmm.resources"), Box::new(VmmResources {}));
     r.routes
         .insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
@@ -257,26 +398,379 @@ fn handle_http_request(
     request: &Request,
     api_notifier: &EventFd,
     api_sender: &Sender<ApiRequest>,
+    transport: &str,
+    peer: Option<audit::PeerCredentials>,
 ) -> Response {
     let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
-        Some(route) => match api_notifier.try_clone() {
+    let route = HTTP_ROUTES.routes.get(&path);
+    // The VMM only answers once the running job completed. The requests
+    // reading the state of the VM are answered with their last response,
+    // while the ones changing the VM conflict with the job and are refused.
+    let busy = route
+        .filter(|route| route.needs_vmm() && request.method() != Method::Get)
+        .and_then(|_| jobs::running());
+    let mut response = match (route, busy) {
+        (Some(_), Some(id)) => error_response(
+            HttpError::Job(JobError::Busy(id)),
+            StatusCode::ServiceUnavailable,
+        ),
+        (Some(route), None) => match api_notifier.try_clone() {
             Ok(notifier) => route.handle_request(request, notifier, api_sender.clone()),
             Err(_) => error_response(
                 HttpError::InternalServerError,
                 StatusCode::InternalServerError,
             ),
         },
-        None => error_response(HttpError::NotFound, StatusCode::NotFound),
+        (None, _) => error_response(HttpError::NotFound, StatusCode::NotFound),
     };
 
     response.set_server("Cloud Hypervisor API");
-    response.set_content_type(MediaType::ApplicationJson);
     response
+        .set_content_type(route.map_or(MediaType::ApplicationJson, |route| route.content_type()));
+
+    audit::record(
+        transport,
+        peer,
+        &format!(
+            "{} {}",
+            format!("{:?}", request.method()).to_uppercase(),
+            path
+        ),
+        request.body.as_ref().map(|body| body.raw()),
+        &format!("{:?}", response.status()),
+    );
+
+    response
+}
+
+// Content-Length of the request, and whether the client waits for the server
+// to accept the request before sending its body.
+fn parse_headers(headers: &[u8]) -> io::Result<(usize, bool)> {
+    let headers =
+        std::str::from_utf8(headers).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for line in headers.split("\r\n").skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            } else if name.eq_ignore_ascii_case("expect")
+                && value.eq_ignore_ascii_case("100-continue")
+            {
+                expect_continue = true;
+            }
+        }
+    }
+
+    Ok((content_length, expect_continue))
+}
+
+// Length of the request at the start of the buffer, once its headers were
+// received, and whether the client waits for the server to accept the request
+// before sending its body.
+fn pending_request(buf: &[u8]) -> io::Result<Option<(usize, bool)>> {
+    let headers_len = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None if buf.len() > MAX_REQUEST_SIZE => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request too large",
+            ))
+        }
+        None => return Ok(None),
+    };
+
+    let (content_length, expect_continue) = parse_headers(&buf[..headers_len])?;
+    let request_len = headers_len + content_length;
+    if request_len > MAX_REQUEST_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request too large",
+        ));
+    }
+
+    Ok(Some((request_len, expect_continue)))
+}
+
+// Client of the UNIX socket, which can attach file descriptors to its
+// requests, such as the TAP devices of a network device. Its socket is non
+// blocking, what it sent and what it is sent being buffered until the epoll
+// loop of the server tells it can be read or written.
+struct UnixClient {
+    stream: UnixStream,
+    peer: Option<audit::PeerCredentials>,
+    input: Vec<u8>,
+    files: Vec<File>,
+    output: Vec<u8>,
+    // The client was told to send the body of its pending request.
+    continued: bool,
+}
+
+impl UnixClient {
+    fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let peer = peer_credentials(&stream);
+
+        Ok(UnixClient {
+            stream,
+            peer,
+            input: Vec::new(),
+            files: Vec::new(),
+            output: Vec::new(),
+            continued: false,
+        })
+    }
+
+    // Receives what the client sent, returns false once it closed the
+    // connection.
+    fn receive(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let mut iovecs = [libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        }];
+        let mut fds = [0; MAX_REQUEST_FDS];
+        // SAFETY: the iovec points to the buffer, valid for its whole length
+        let (count, fd_count) = match unsafe { self.stream.recv_with_fds(&mut iovecs, &mut fds) }
+            .map_err(io::Error::from)
+        {
+            Ok(received) => received,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                return Ok(true)
+            }
+            Err(e) => return Err(e),
+        };
+        self.files.extend(fds[..fd_count].iter().map(|fd| {
+            // SAFETY: the file descriptor was just received, nothing else owns it
+            unsafe { File::from_raw_fd(*fd) }
+        }));
+        self.input.extend_from_slice(&buf[..count]);
+
+        Ok(count > 0)
+    }
+
+    // Takes the next complete request out of the input.
+    fn next_request(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let (request_len, expect_continue) = match pending_request(&self.input)? {
+            Some(pending) => pending,
+            None => return Ok(None),
+        };
+        if self.input.len() < request_len {
+            if expect_continue && !self.continued {
+                self.output
+                    .extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                self.continued = true;
+            }
+            return Ok(None);
+        }
+        self.continued = false;
+
+        Ok(Some(self.input.drain(..request_len).collect()))
+    }
+
+    // Sends as much of the output as the socket accepts.
+    fn send(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => {
+                    self.output.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Handles the requests received so far, the connection being kept open
+    // for the next ones. The client isn't read from while its responses are
+    // pending, which bounds what is buffered for it.
+    fn serve(
+        &mut self,
+        readable: bool,
+        api_notifier: &EventFd,
+        api_sender: &Sender<ApiRequest>,
+    ) -> io::Result<bool> {
+        if readable && self.output.is_empty() && !self.receive()? {
+            return Ok(false);
+        }
+
+        loop {
+            self.send()?;
+            if !self.output.is_empty() {
+                break;
+            }
+            match self.next_request()? {
+                Some(request) => {
+                    let response = match Request::try_from(&request, None) {
+                        Ok(mut request) => {
+                            request.files = std::mem::take(&mut self.files);
+                            handle_http_request(
+                                &request,
+                                api_notifier,
+                                api_sender,
+                                "http",
+                                self.peer.clone(),
+                            )
+                        }
+                        Err(e) => {
+                            warn!("Invalid HTTP API request: {:?}", e);
+                            error_response(HttpError::BadRequest, StatusCode::BadRequest)
+                        }
+                    };
+                    response.write_all(&mut self.output)?;
+                }
+                None if self.output.is_empty() => break,
+                None => {}
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn events(&self) -> epoll::Events {
+        if self.output.is_empty() {
+            epoll::Events::EPOLLIN
+        } else {
+            epoll::Events::EPOLLOUT
+        }
+    }
+}
+
+// Credentials of the process connected to the UNIX socket, as the kernel
+// recorded them when it connected.
+fn peer_credentials(stream: &UnixStream) -> Option<audit::PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: FFI call with a valid socket, and a buffer of the given length
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        warn!(
+            "Cannot get the credentials of the HTTP API client: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+
+    Some(audit::PeerCredentials {
+        uid: Some(cred.uid),
+        pid: Some(cred.pid as u32),
+        ..Default::default()
+    })
+}
+
+// The clients are served from an epoll loop, a slow client not delaying the
+// others, and their credentials are read when their connection is accepted.
+fn run_http_server(
+    listener: UnixListener,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+
+    let epoll_fd = epoll::create(true)?;
+    // SAFETY: the epoll_fd returned by epoll::create is valid and owned by us.
+    let _epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    let listener_fd = listener.as_raw_fd();
+    epoll::ctl(
+        epoll_fd,
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        listener_fd,
+        epoll::Event::new(epoll::Events::EPOLLIN, listener_fd as u64),
+    )?;
+
+    let mut clients: HashMap<RawFd, UnixClient> = HashMap::new();
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); MAX_CONNECTIONS + 1];
+    loop {
+        let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+            Ok(num_events) => num_events,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for event in events.iter().take(num_events) {
+            let fd = event.data as RawFd;
+            if fd == listener_fd {
+                loop {
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            warn!("Cannot accept HTTP API connection: {}", e);
+                            break;
+                        }
+                    };
+                    if clients.len() >= MAX_CONNECTIONS {
+                        warn!("Too many HTTP API clients, closing the new connection");
+                        continue;
+                    }
+                    let client = UnixClient::new(stream)?;
+                    let client_fd = client.stream.as_raw_fd();
+                    epoll::ctl(
+                        epoll_fd,
+                        epoll::ControlOptions::EPOLL_CTL_ADD,
+                        client_fd,
+                        epoll::Event::new(client.events(), client_fd as u64),
+                    )?;
+                    clients.insert(client_fd, client);
+                }
+                continue;
+            }
+
+            let client = match clients.get_mut(&fd) {
+                Some(client) => client,
+                None => continue,
+            };
+            let events = client.events();
+            let readable = epoll::Events::from_bits_truncate(event.events)
+                .intersects(epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP);
+            match client.serve(readable, api_notifier, api_sender) {
+                Ok(true) => {
+                    if client.events() != events {
+                        epoll::ctl(
+                            epoll_fd,
+                            epoll::ControlOptions::EPOLL_CTL_MOD,
+                            fd,
+                            epoll::Event::new(client.events(), fd as u64),
+                        )?;
+                    }
+                }
+                result => {
+                    if let Err(e) = result {
+                        warn!("HTTP API connection error: {}", e);
+                    }
+                    // Closing the socket removes it from the epoll set.
+                    clients.remove(&fd);
+                }
+            }
+        }
+    }
 }
 
 fn start_http_thread(
-    mut server: HttpServer,
+    listener: UnixListener,
     api_notifier: EventFd,
     api_sender: Sender<ApiRequest>,
     seccomp_action: &SeccompAction,
@@ -301,33 +795,19 @@ fn start_http_thread(
                     })?;
             }
 
-            std::panic::catch_unwind(AssertUnwindSafe(move || {
-                server.start_server().unwrap();
-                loop {
-                    match server.requests() {
-                        Ok(request_vec) => {
-                            for server_request in request_vec {
-                                if let Err(e) = server.respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
-                                })) {
-                                    error!("HTTP server error on response: {}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!(
-                                "HTTP server error on retrieving incoming request. Error: {}",
-                                e
-                            );
-                        }
-                    }
+            match std::panic::catch_unwind(AssertUnwindSafe(move || {
+                run_http_server(listener, &api_notifier, &api_sender)
+            })) {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("HTTP server error: {}", e);
+                    exit_evt.write(1).ok();
+                }
+                Err(_) => {
+                    error!("http-server thread panicked");
+                    exit_evt.write(1).ok();
                 }
-            }))
-            .map_err(|_| {
-                error!("http-server thread panicked");
-                exit_evt.write(1).ok()
-            })
-            .ok();
+            }
 
             Ok(())
         })
@@ -343,12 +823,9 @@ pub fn start_http_path_thread(
     hypervisor_type: HypervisorType,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let socket_path = PathBuf::from(path);
-    let socket_fd = UnixListener::bind(socket_path).map_err(VmmError::CreateApiServerSocket)?;
-    // SAFETY: Valid FD just opened
-    let server = unsafe { HttpServer::new_from_fd(socket_fd.into_raw_fd()) }
-        .map_err(VmmError::CreateApiServer)?;
+    let listener = UnixListener::bind(socket_path).map_err(VmmError::CreateApiServerSocket)?;
     start_http_thread(
-        server,
+        listener,
         api_notifier,
         api_sender,
         seccomp_action,
@@ -365,10 +842,10 @@ pub fn start_http_fd_thread(
     exit_evt: EventFd,
     hypervisor_type: HypervisorType,
 ) -> Result<thread::JoinHandle<Result<()>>> {
-    // SAFETY: Valid FD
-    let server = unsafe { HttpServer::new_from_fd(fd) }.map_err(VmmError::CreateApiServer)?;
+    // SAFETY: Valid FD of a listening socket, owned by the server from now on
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
     start_http_thread(
-        server,
+        listener,
         api_notifier,
         api_sender,
         seccomp_action,
@@ -376,3 +853,61 @@ pub fn start_http_fd_thread(
         hypervisor_type,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_pending_request() {
+        let request = b"PUT /api/v1/vm.resize HTTP/1.1\r\n\
+            Content-Type: application/json\r\n\
+            content-length: 17\r\n\
+            Expect: 100-continue\r\n\r\n\
+            {\"desired_ram\":1}";
+        assert_eq!(
+            pending_request(request).unwrap(),
+            Some((request.len(), true))
+        );
+        assert_eq!(
+            pending_request(b"GET /api/v1/vm.info HTTP/1.1\r\n").unwrap(),
+            None
+        );
+        assert!(pending_request(b"GET / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_unix_client() {
+        let (mut stream, server) = UnixStream::pair().unwrap();
+        let mut client = UnixClient::new(server).unwrap();
+        let api_notifier = EventFd::new(0).unwrap();
+        let (api_sender, _api_receiver) = std::sync::mpsc::channel();
+        let mut buf = [0u8; 4096];
+
+        // The client is asked for the body of its request
+        stream
+            .write_all(
+                b"PUT /api/v1/vm.unknown HTTP/1.1\r\n\
+                Content-Length: 2\r\n\
+                Expect: 100-continue\r\n\r\n",
+            )
+            .unwrap();
+        assert!(client.serve(true, &api_notifier, &api_sender).unwrap());
+        let count = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..count], b"HTTP/1.1 100 Continue\r\n\r\n");
+        assert_eq!(client.events(), epoll::Events::EPOLLIN);
+
+        // Both requests are answered, the connection being kept open
+        stream
+            .write_all(b"{}GET /api/v1/vm.unknown HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(client.serve(true, &api_notifier, &api_sender).unwrap());
+        let count = stream.read(&mut buf).unwrap();
+        let responses = String::from_utf8_lossy(&buf[..count]);
+        assert_eq!(responses.matches("HTTP/1.1 404").count(), 2);
+
+        drop(stream);
+        assert!(!client.serve(true, &api_notifier, &api_sender).unwrap());
+    }
+}
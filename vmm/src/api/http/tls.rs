@@ -0,0 +1,624 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! HTTP API served over TCP, protected by TLS.
+//!
+//! The clients must authenticate with a certificate signed by the given CA,
+//! the subject of their certificate being recorded in the audit log. The
+//! requests are handled by the same endpoints as the UNIX socket, one request
+//! per connection, and without any file descriptor attached to them. Each
+//! connection is served by a thread of its own, within a deadline, so that a
+//! client sending its bytes slowly can't hold the API.
+
+use super::{error_response, handle_http_request, HttpError};
+use crate::api::audit::PeerCredentials;
+use crate::api::ApiRequest;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{Error as VmmError, Result};
+use hypervisor::HypervisorType;
+use micro_http::{Request, StatusCode};
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Ref;
+use seccompiler::{apply_filter, SeccompAction};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+// A client can't hold a connection for longer than this, whatever the pace
+// it sends its bytes at.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// Connections served at once, the next ones being closed right away.
+const MAX_CONNECTIONS: usize = 16;
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+pub struct TlsApiOptions {
+    pub listen: SocketAddr,
+    /// Certificate of the server, in PEM format
+    pub cert: PathBuf,
+    /// Private key of the server, in PEM format
+    pub key: PathBuf,
+    /// CA the client certificates must be signed by, in PEM format
+    pub client_ca: PathBuf,
+}
+
+/// HTTPS API server thread, stopped through `shutdown`.
+pub struct HttpsApiHandle {
+    thread: thread::JoinHandle<Result<()>>,
+    shutdown_evt: EventFd,
+}
+
+impl HttpsApiHandle {
+    /// Stop accepting connections and wait for the server thread, the
+    /// connections being served completing on their own.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown_evt.write(1).map_err(VmmError::EventFdWrite)?;
+        self.thread.join().map_err(VmmError::ThreadCleanup)?
+    }
+}
+
+// TCP stream failing the reads and writes past the deadline of the
+// connection, the socket timeouts only bounding each of them.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl DeadlineStream {
+    fn remaining(&self) -> io::Result<Duration> {
+        self.deadline
+            .checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero())
+            .ok_or_else(|| io::ErrorKind::TimedOut.into())
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// Connection being served, counted until dropped.
+struct ConnectionSlot {
+    count: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(ConnectionSlot {
+            count: count.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn tls_acceptor(options: &TlsApiOptions) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())
+        .map_err(VmmError::CreateApiTlsContext)?;
+    builder
+        .set_certificate_chain_file(&options.cert)
+        .map_err(VmmError::CreateApiTlsContext)?;
+    builder
+        .set_private_key_file(&options.key, SslFiletype::PEM)
+        .map_err(VmmError::CreateApiTlsContext)?;
+    builder
+        .check_private_key()
+        .map_err(VmmError::CreateApiTlsContext)?;
+    builder
+        .set_ca_file(&options.client_ca)
+        .map_err(VmmError::CreateApiTlsContext)?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+
+    Ok(builder.build())
+}
+
+fn subject(cert: &X509Ref) -> String {
+    cert.subject_name()
+        .entries()
+        .map(|entry| {
+            let field = entry.object().nid().short_name().unwrap_or("?");
+            match entry.data().as_utf8() {
+                Ok(value) => format!("{field}={value}"),
+                Err(_) => format!("{field}=?"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Content-Length of the request, and whether the client waits for the server
+// to accept the request before sending its body.
+fn parse_headers(headers: &[u8]) -> io::Result<(usize, bool)> {
+    let headers =
+        std::str::from_utf8(headers).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for line in headers.split("\r\n").skip(1) {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            } else if name.eq_ignore_ascii_case("expect")
+                && value.eq_ignore_ascii_case("100-continue")
+            {
+                expect_continue = true;
+            }
+        }
+    }
+
+    Ok((content_length, expect_continue))
+}
+
+fn read_request<S: Read + Write>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    let headers_len = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request too large",
+            ));
+        }
+        let count = stream.read(&mut buf)?;
+        if count == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..count]);
+    };
+
+    let (content_length, expect_continue) = parse_headers(&request[..headers_len])?;
+    let request_len = headers_len + content_length;
+    if request_len > MAX_REQUEST_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request too large",
+        ));
+    }
+    if expect_continue && request.len() < request_len {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+
+    while request.len() < request_len {
+        let count = stream.read(&mut buf)?;
+        if count == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..count]);
+    }
+    request.truncate(request_len);
+
+    Ok(request)
+}
+
+fn handle_connection(
+    acceptor: &SslAcceptor,
+    stream: TcpStream,
+    deadline: Instant,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<()> {
+    let mut stream = acceptor
+        .accept(DeadlineStream { stream, deadline })
+        .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+    let subject = stream.ssl().peer_certificate().map(|cert| subject(&cert));
+
+    let request = read_request(&mut stream)?;
+    let response = match Request::try_from(&request, None) {
+        Ok(request) => handle_http_request(
+            &request,
+            api_notifier,
+            api_sender,
+            "https",
+            Some(PeerCredentials {
+                subject,
+                ..Default::default()
+            }),
+        ),
+        Err(e) => {
+            warn!("Invalid HTTPS API request: {:?}", e);
+            error_response(HttpError::BadRequest, StatusCode::BadRequest)
+        }
+    };
+    response.write_all(&mut stream)?;
+
+    stream
+        .shutdown()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    Ok(())
+}
+
+// Accept the connections until `shutdown_evt` is written, serving each of
+// them from a thread of its own.
+fn serve(
+    listener: &TcpListener,
+    shutdown_evt: &EventFd,
+    acceptor: &SslAcceptor,
+    timeout: Duration,
+    api_notifier: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+) -> io::Result<()> {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mut fds = [
+        libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: shutdown_evt.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        // SAFETY: FFI call with a valid array of pollfd, of the given length
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if fds[1].revents != 0 {
+            return Ok(());
+        }
+        if fds[0].revents == 0 {
+            continue;
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Error accepting HTTPS API connection: {}", e);
+                continue;
+            }
+        };
+        let Some(slot) = ConnectionSlot::acquire(&connections, MAX_CONNECTIONS) else {
+            warn!("Too many HTTPS API connections, closing the new one");
+            continue;
+        };
+
+        let deadline = Instant::now() + timeout;
+        let acceptor = acceptor.clone();
+        let api_notifier = api_notifier.try_clone()?;
+        let api_sender = api_sender.clone();
+        thread::Builder::new()
+            .name("https-connection".to_string())
+            .spawn(move || {
+                let _slot = slot;
+                if let Err(e) =
+                    handle_connection(&acceptor, stream, deadline, &api_notifier, &api_sender)
+                {
+                    warn!("HTTPS API connection error: {}", e);
+                }
+            })?;
+    }
+}
+
+pub fn start_https_thread(
+    options: &TlsApiOptions,
+    api_notifier: EventFd,
+    api_sender: Sender<ApiRequest>,
+    seccomp_action: &SeccompAction,
+    exit_evt: EventFd,
+    hypervisor_type: HypervisorType,
+) -> Result<HttpsApiHandle> {
+    let acceptor = tls_acceptor(options)?;
+    let listener = TcpListener::bind(options.listen).map_err(VmmError::CreateApiServerSocket)?;
+    let shutdown_evt = EventFd::new(0).map_err(VmmError::EventFdCreate)?;
+    let thread_shutdown_evt = shutdown_evt.try_clone().map_err(VmmError::EventFdClone)?;
+
+    // Retrieve seccomp filter for the HTTPS API thread
+    let api_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::HttpsApi, hypervisor_type)
+        .map_err(VmmError::CreateSeccompFilter)?;
+
+    let thread = thread::Builder::new()
+        .name("https-server".to_string())
+        .spawn(move || {
+            // Apply seccomp filter for the HTTPS API thread.
+            if !api_seccomp_filter.is_empty() {
+                apply_filter(&api_seccomp_filter)
+                    .map_err(VmmError::ApplySeccompFilter)
+                    .map_err(|e| {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        exit_evt.write(1).ok();
+                        e
+                    })?;
+            }
+
+            std::panic::catch_unwind(AssertUnwindSafe(move || {
+                if let Err(e) = serve(
+                    &listener,
+                    &thread_shutdown_evt,
+                    &acceptor,
+                    CONNECTION_TIMEOUT,
+                    &api_notifier,
+                    &api_sender,
+                ) {
+                    error!("Error serving the HTTPS API: {}", e);
+                }
+            }))
+            .map_err(|_| {
+                error!("https-server thread panicked");
+                exit_evt.write(1).ok()
+            })
+            .ok();
+
+            Ok(())
+        })
+        .map_err(VmmError::HttpThreadSpawn)?;
+
+    Ok(HttpsApiHandle {
+        thread,
+        shutdown_evt,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::SslConnector;
+    use openssl::x509::extension::BasicConstraints;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::io::Cursor;
+    use std::path::Path;
+    use std::sync::mpsc::channel;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Reads from the request, records what the server writes
+    struct Client {
+        request: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = b"PUT /api/v1/vm.resize HTTP/1.1\r\n\
+            Content-Type: application/json\r\n\
+            content-length: 17\r\n\
+            Expect: 100-continue\r\n\r\n\
+            {\"desired_ram\":1}";
+        let mut client = Client {
+            request: Cursor::new(request.to_vec()),
+            written: Vec::new(),
+        };
+        assert_eq!(read_request(&mut client).unwrap(), request);
+        assert_eq!(client.written, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        let mut client = Client {
+            request: Cursor::new(b"GET /api/v1/vm.info HTTP/1.1\r\n".to_vec()),
+            written: Vec::new(),
+        };
+        assert!(read_request(&mut client).is_err());
+    }
+
+    #[test]
+    fn test_parse_headers() {
+        assert_eq!(
+            parse_headers(b"GET /api/v1/vm.info HTTP/1.1\r\nAccept: */*\r\n\r\n").unwrap(),
+            (0, false)
+        );
+        assert_eq!(
+            parse_headers(b"PUT /api/v1/vm.boot HTTP/1.1\r\nCONTENT-LENGTH: 2\r\n\r\n").unwrap(),
+            (2, false)
+        );
+        assert!(
+            parse_headers(b"PUT /api/v1/vm.boot HTTP/1.1\r\nContent-Length: -1\r\n\r\n").is_err()
+        );
+        assert!(parse_headers(b"PUT /api/v1/vm.boot HTTP/1.1\r\n\xff: 1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_connection_slot() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let first = ConnectionSlot::acquire(&count, 2).unwrap();
+        let second = ConnectionSlot::acquire(&count, 2).unwrap();
+        assert!(ConnectionSlot::acquire(&count, 2).is_none());
+
+        drop(first);
+        let third = ConnectionSlot::acquire(&count, 2).unwrap();
+        assert_eq!(count.load(Ordering::Acquire), 2);
+
+        drop(second);
+        drop(third);
+        assert_eq!(count.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_deadline_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        // A byte every 50ms keeps each read below any per-read timeout
+        let sender = thread::spawn(move || {
+            for _ in 0..40 {
+                if client.write_all(b"x").is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let start = Instant::now();
+        let mut stream = DeadlineStream {
+            stream,
+            deadline: start + Duration::from_millis(300),
+        };
+        let mut buf = [0u8; 1];
+        let e = loop {
+            if let Err(e) = stream.read(&mut buf) {
+                break e;
+            }
+        };
+        assert!(matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(
+            stream.write(b"x").unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+
+        drop(stream);
+        sender.join().unwrap();
+    }
+
+    // Self-signed certificate, written along with its key, used both by the
+    // server and by the client.
+    fn write_certificate(dir: &Path) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        std::fs::write(dir.join("cert.pem"), cert.to_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        (cert, key)
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let (cert, key) = write_certificate(dir.as_path());
+        let acceptor = tls_acceptor(&TlsApiOptions {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            cert: dir.as_path().join("cert.pem"),
+            key: dir.as_path().join("key.pem"),
+            client_ca: dir.as_path().join("cert.pem"),
+        })
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown_evt = EventFd::new(0).unwrap();
+        let server_shutdown_evt = shutdown_evt.try_clone().unwrap();
+        let (api_sender, _api_receiver) = channel();
+        let timeout = Duration::from_secs(2);
+        let server = thread::spawn(move || {
+            serve(
+                &listener,
+                &server_shutdown_evt,
+                &acceptor,
+                timeout,
+                &EventFd::new(0).unwrap(),
+                &api_sender,
+            )
+        });
+
+        // A client never completing its handshake doesn't hold the others
+        let start = Instant::now();
+        let mut idle = TcpStream::connect(addr).unwrap();
+
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_certificate(&cert).unwrap();
+        connector.set_private_key(&key).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut client = connector
+            .build()
+            .connect("localhost", TcpStream::connect(addr).unwrap())
+            .unwrap();
+        client.write_all(b"INVALID\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).ok();
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+        assert!(start.elapsed() < timeout);
+
+        // The idle client is disconnected once past the deadline
+        idle.set_read_timeout(Some(timeout * 2)).unwrap();
+        assert_eq!(idle.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+
+        shutdown_evt.write(1).unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
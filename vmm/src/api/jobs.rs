@@ -0,0 +1,309 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Long running API requests, run in the background.
+//!
+//! Snapshotting, coredumping or migrating a VM can take minutes, during which
+//! the API server would otherwise be stuck waiting for the VMM to answer. When
+//! asked for, these requests are run as a job instead, by a thread of their
+//! own, the server answering right away with the identifier of the job for
+//! its state and progress to be polled.
+//!
+//! The VMM still handles one request at a time, so only one job runs at a
+//! time, and the requests changing the VM are refused while it runs. The
+//! requests only reading its state are answered with their last response
+//! instead, the VMM only answering them once the job completed.
+
+use super::{ApiError, ApiRequest, ApiResult};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use thiserror::Error;
+use vmm_sys_util::eventfd::EventFd;
+
+static JOBS: Lazy<Mutex<Jobs>> = Lazy::new(Mutex::default);
+
+// Last response of each request only reading the state of the VMM.
+static RESPONSES: Lazy<Mutex<HashMap<String, Box<dyn Any + Send>>>> = Lazy::new(Mutex::default);
+
+/// Number of completed jobs kept for their result to be polled.
+pub const JOB_HISTORY_SIZE: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Job {0} is running")]
+    Busy(u64),
+
+    #[error("Cannot spawn the thread of the job: {0}")]
+    Spawn(#[source] io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobKind {
+    Snapshot,
+    Coredump,
+    SendMigration,
+    ReceiveMigration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// Step the operation is at, as reported by the VMM
+    pub stage: Option<String>,
+    /// Guest memory transferred so far, in bytes
+    pub memory_bytes: u64,
+    /// Time the job has been running for, or took to complete
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+struct Job {
+    info: JobInfo,
+    start: Instant,
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: VecDeque<Job>,
+}
+
+impl Jobs {
+    fn running(&mut self) -> Option<&mut Job> {
+        self.jobs
+            .iter_mut()
+            .find(|job| job.info.state == JobState::Running)
+    }
+
+    fn finish(&mut self, id: u64, result: Result<(), String>) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.info.id == id) {
+            job.info.duration_ms = job.start.elapsed().as_millis() as u64;
+            match result {
+                Ok(()) => job.info.state = JobState::Succeeded,
+                Err(e) => {
+                    job.info.state = JobState::Failed;
+                    job.info.error = Some(e);
+                }
+            }
+        }
+
+        while self.jobs.len() > JOB_HISTORY_SIZE {
+            self.jobs.pop_front();
+        }
+    }
+}
+
+fn job_info(job: &Job) -> JobInfo {
+    let mut info = job.info.clone();
+    if info.state == JobState::Running {
+        info.duration_ms = job.start.elapsed().as_millis() as u64;
+    }
+    info
+}
+
+/// Identifier of the running job, if any.
+pub fn running() -> Option<u64> {
+    JOBS.lock().unwrap().running().map(|job| job.info.id)
+}
+
+/// Send a request only reading the state of the VMM, named after its
+/// endpoint and parameters. While a job runs, the last response to the same
+/// request is returned rather than waiting for the VMM.
+pub fn read_only<T, F>(request: String, send: F) -> ApiResult<T>
+where
+    T: Clone + Send + 'static,
+    F: FnOnce() -> ApiResult<T>,
+{
+    if let Some(id) = running() {
+        return RESPONSES
+            .lock()
+            .unwrap()
+            .get(&request)
+            .and_then(|response| response.downcast_ref::<T>())
+            .cloned()
+            .ok_or(ApiError::Job(JobError::Busy(id)));
+    }
+
+    let response = send()?;
+    RESPONSES
+        .lock()
+        .unwrap()
+        .insert(request, Box::new(response.clone()));
+    Ok(response)
+}
+
+/// Run the request as a job, returning its identifier.
+///
+/// The state of the VM and of the VMM is read beforehand, while the VMM can
+/// still answer, for it to be known until the job completed.
+pub fn start<F>(
+    kind: JobKind,
+    api_evt: &EventFd,
+    api_sender: &Sender<ApiRequest>,
+    request: F,
+) -> Result<u64, JobError>
+where
+    F: FnOnce() -> Result<(), String> + Send + 'static,
+{
+    if running().is_none() {
+        if let Ok(api_evt) = api_evt.try_clone() {
+            super::vm_info(api_evt, api_sender.clone()).ok();
+        }
+        if let Ok(api_evt) = api_evt.try_clone() {
+            super::vmm_ping(api_evt, api_sender.clone()).ok();
+        }
+    }
+
+    let mut jobs = JOBS.lock().unwrap();
+    if let Some(job) = jobs.running() {
+        return Err(JobError::Busy(job.info.id));
+    }
+
+    let id = jobs.next_id;
+    jobs.next_id += 1;
+    jobs.jobs.push_back(Job {
+        info: JobInfo {
+            id,
+            kind,
+            state: JobState::Running,
+            stage: None,
+            memory_bytes: 0,
+            duration_ms: 0,
+            error: None,
+        },
+        start: Instant::now(),
+    });
+
+    thread::Builder::new()
+        .name(format!("api-job{id}"))
+        .spawn(move || {
+            let result = request();
+            if let Err(e) = &result {
+                warn!("Job {} ({:?}) failed: {}", id, kind, e);
+            }
+            JOBS.lock().unwrap().finish(id, result);
+        })
+        .map_err(|e| {
+            jobs.jobs.pop_back();
+            JobError::Spawn(e)
+        })?;
+
+    Ok(id)
+}
+
+/// Report the step the running job is at. This is a no-op when the request
+/// isn't run as a job.
+pub fn set_stage(stage: &str) {
+    if let Some(job) = JOBS.lock().unwrap().running() {
+        job.info.stage = Some(stage.to_owned());
+    }
+}
+
+/// Account for guest memory transferred by the running job.
+pub fn add_memory_bytes(bytes: u64) {
+    if let Some(job) = JOBS.lock().unwrap().running() {
+        job.info.memory_bytes += bytes;
+    }
+}
+
+pub fn get(id: u64) -> Option<JobInfo> {
+    JOBS.lock()
+        .unwrap()
+        .jobs
+        .iter()
+        .find(|job| job.info.id == id)
+        .map(job_info)
+}
+
+pub fn list() -> Vec<JobInfo> {
+    JOBS.lock().unwrap().jobs.iter().map(job_info).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_jobs() {
+        // No VMM answers the requests
+        let api_evt = EventFd::new(0).unwrap();
+        let (api_sender, _) = channel();
+
+        read_only("vmm.ping".to_string(), || Ok(1u64)).unwrap();
+        assert!(matches!(
+            read_only::<u64, _>("vm.info".to_string(), || Err(ApiError::VmNotCreated)),
+            Err(ApiError::VmNotCreated)
+        ));
+
+        let (release, wait) = channel::<()>();
+        let id = start(JobKind::Snapshot, &api_evt, &api_sender, move || {
+            wait.recv().unwrap();
+            Err("Snapshot failed".to_string())
+        })
+        .unwrap();
+
+        assert_eq!(running(), Some(id));
+        assert!(matches!(
+            start(JobKind::Coredump, &api_evt, &api_sender, || Ok(())),
+            Err(JobError::Busy(busy)) if busy == id
+        ));
+
+        // The VMM isn't asked while the job runs
+        assert_eq!(
+            read_only("vmm.ping".to_string(), || -> ApiResult<u64> {
+                unreachable!()
+            })
+            .unwrap(),
+            1
+        );
+        assert!(matches!(
+            read_only("vm.info".to_string(), || -> ApiResult<u64> { unreachable!() }),
+            Err(ApiError::Job(JobError::Busy(busy))) if busy == id
+        ));
+
+        set_stage("memory");
+        add_memory_bytes(4096);
+        add_memory_bytes(4096);
+        let info = get(id).unwrap();
+        assert_eq!(info.state, JobState::Running);
+        assert_eq!(info.stage.as_deref(), Some("memory"));
+        assert_eq!(info.memory_bytes, 8192);
+
+        release.send(()).unwrap();
+        while running().is_some() {
+            thread::yield_now();
+        }
+        let info = get(id).unwrap();
+        assert_eq!(info.state, JobState::Failed);
+        assert_eq!(info.error.as_deref(), Some("Snapshot failed"));
+
+        let id = start(JobKind::SendMigration, &api_evt, &api_sender, || Ok(())).unwrap();
+        while running().is_some() {
+            thread::yield_now();
+        }
+        assert_eq!(get(id).unwrap().state, JobState::Succeeded);
+        assert_eq!(list().len(), 2);
+        assert_eq!(read_only("vmm.ping".to_string(), || Ok(2u64)).unwrap(), 2);
+    }
+}
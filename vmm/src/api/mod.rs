@@ -28,27 +28,41 @@
 //!    response channel Receiver.
 //! 5. The thread handles the response and forwards potential errors.
 
+pub mod audit;
 #[cfg(feature = "dbus_api")]
 pub mod dbus;
 pub mod http;
+pub mod jobs;
 
 #[cfg(feature = "dbus_api")]
 pub use self::dbus::start_dbus_thread;
 pub use self::http::start_http_fd_thread;
 pub use self::http::start_http_path_thread;
+#[cfg(feature = "tls_api")]
+pub use self::http::tls::{start_https_thread, HttpsApiHandle, TlsApiOptions};
 
+#[cfg(target_arch = "x86_64")]
+use crate::config::SgxEpcConfig;
 use crate::config::{
-    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, UserDeviceConfig,
-    VdpaConfig, VmConfig, VsockConfig,
+    ConsoleOutputMode, ConsolePortConfig, CpuAffinity, CpuBandwidth, DeviceConfig, DiskConfig,
+    FsConfig, MdevConfig, NetConfig, PmemConfig, RestoreConfig, UserDeviceConfig, VdpaConfig,
+    VfConfig, VmConfig, VsockConfig,
 };
+use crate::device_manager::{CONSOLE_DEVICE_NAME, SERIAL_DEVICE_NAME};
 use crate::device_tree::DeviceTree;
+use crate::resource_monitor::VmmResources;
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use virtio_devices::VirtioMemResizeStatus;
 use vm_migration::MigratableError;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -115,9 +129,30 @@ pub enum ApiError {
     /// The memory zone could not be resized.
     VmResizeZone(VmError),
 
+    /// The memory zone could not be bound to the host NUMA node.
+    VmBindZone(VmError),
+
+    /// The virtio-fs DAX window could not be resized.
+    VmResizeFs(VmError),
+
+    /// The vCPU could not be removed from the VM.
+    VmRemoveVcpu(VmError),
+
+    /// The vCPU affinity could not be changed.
+    VmSetCpuAffinity(VmError),
+
+    /// The CPU bandwidth limits could not be changed.
+    VmSetCpuBandwidth(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
+    /// The SR-IOV virtual function could not be added to the VM.
+    VmAddVf(VmError),
+
+    /// The mediated device could not be added to the VM.
+    VmAddMdev(VmError),
+
     /// The user device could not be added to the VM.
     VmAddUserDevice(VmError),
 
@@ -148,17 +183,63 @@ pub enum ApiError {
     /// The vsock device could not be added to the VM.
     VmAddVsock(VmError),
 
+    /// The SGX EPC section could not be added to the VM.
+    #[cfg(target_arch = "x86_64")]
+    VmAddSgxEpc(VmError),
+
+    /// The console port could not be added to the VM.
+    VmAddConsolePort(VmError),
+
+    /// The console port could not be removed from the VM.
+    VmRemoveConsolePort(VmError),
+
     /// Error starting migration receiever
     VmReceiveMigration(MigratableError),
 
     /// Error starting migration sender
     VmSendMigration(MigratableError),
 
+    /// Error checking the migration against its destination
+    VmMigrationPrecheck(MigratableError),
+
     /// Error triggering power button
     VmPowerButton(VmError),
 
     /// Error enabling heterogeneous memory
     VmEnableHmem(VmError),
+
+    /// The command could not be run by the guest agent.
+    VmGuestExec(VmError),
+
+    /// The guest filesystems could not be frozen or thawed.
+    VmGuestFsFreeze(VmError),
+
+    /// The guest memory could not be read.
+    VmMemoryRead(VmError),
+
+    /// The guest memory could not be written.
+    VmMemoryWrite(VmError),
+
+    /// The state of the virtqueues could not be read.
+    VmVirtqueueState(VmError),
+
+    /// The guest agent information is not available.
+    VmGuestInfo(VmError),
+
+    /// The boot timings could not be retrieved.
+    VmBootTimings(VmError),
+
+    /// The heterogeneous balloon controller state could not be retrieved.
+    VmHeteroBalloon(VmError),
+
+    /// The guest could not be asked to report its free pages.
+    VmReportFreePages(VmError),
+
+    /// The resources used by the VMM could not be sampled.
+    VmmResources(io::Error),
+
+    /// A job keeps the VMM busy.
+    Job(jobs::JobError),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -168,6 +249,88 @@ pub struct VmInfo {
     pub state: VmState,
     pub memory_actual_size: u64,
     pub device_tree: Option<Arc<Mutex<DeviceTree>>>,
+    #[serde(default)]
+    pub consoles: Vec<ConsoleInfo>,
+    #[serde(default)]
+    pub virtio_mem: Vec<VirtioMemInfo>,
+}
+
+/// Resize state of the virtio-mem device of a memory zone. The memory is only
+/// usable by the guest once it plugged it, after the resize request returned.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VirtioMemInfo {
+    /// Identifier of the memory zone
+    pub id: String,
+    pub requested_size: u64,
+    pub plugged_size: u64,
+    pub status: VirtioMemResizeStatus,
+}
+
+/// Host side of the serial port, of the virtio-console, or of one of its
+/// additional ports, for management stacks to attach to a given channel.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConsoleInfo {
+    pub id: String,
+    /// Name of the port, as exposed to the guest
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Number of the virtio-console port, once plugged
+    #[serde(default)]
+    pub port: Option<u32>,
+    pub mode: ConsoleOutputMode,
+    /// UNIX socket, PTY or file the console is attached to
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub tcp: Option<SocketAddr>,
+}
+
+impl ConsoleInfo {
+    /// List the consoles of the VM, given the ports plugged into the
+    /// virtio-console by port number.
+    pub fn list(config: &VmConfig, port_ids: &BTreeMap<u32, String>) -> Vec<Self> {
+        let mut consoles = Vec::new();
+
+        for (id, console, port) in [
+            (SERIAL_DEVICE_NAME, &config.serial, None),
+            (CONSOLE_DEVICE_NAME, &config.console, Some(0)),
+        ] {
+            if console.mode == ConsoleOutputMode::Off {
+                continue;
+            }
+            consoles.push(ConsoleInfo {
+                id: id.to_owned(),
+                name: None,
+                port,
+                mode: console.mode.clone(),
+                path: console.socket.clone().or_else(|| console.file.clone()),
+                tcp: console.tcp,
+            });
+        }
+
+        for port_cfg in config.console_ports.iter().flatten() {
+            let id = port_cfg.id.clone().unwrap_or_default();
+            let port = port_ids
+                .iter()
+                .find(|(_, port_id)| **port_id == id)
+                .map(|(number, _)| *number);
+            let mode = if port_cfg.socket.is_some() {
+                ConsoleOutputMode::Socket
+            } else {
+                ConsoleOutputMode::Pty
+            };
+            consoles.push(ConsoleInfo {
+                id,
+                name: port_cfg.name.clone(),
+                port,
+                mode,
+                path: port_cfg.socket.clone().or_else(|| port_cfg.file.clone()),
+                tcp: None,
+            });
+        }
+
+        consoles
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -178,6 +341,35 @@ pub struct VmmPingResponse {
     pub features: Vec<String>,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmEventsData {
+    /// Number of the first event to return
+    #[serde(default)]
+    pub since: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmJobsData {
+    /// Job to return, all the jobs kept being returned otherwise
+    #[serde(default)]
+    pub id: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmmEventsResponse {
+    /// Number of the next event to be reported, to poll the events from
+    pub next: u64,
+    pub events: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmCountersData {
+    /// Return the increase of the counters per second, over the last
+    /// sampling interval, rather than their totals
+    #[serde(default)]
+    pub rates: bool,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmResizeData {
     pub desired_vcpus: Option<u8>,
@@ -191,11 +383,110 @@ pub struct VmResizeZoneData {
     pub desired_ram: u64,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmBindZoneData {
+    pub id: String,
+    pub host_numa_node: u32,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmResizeFsData {
+    pub id: String,
+    pub desired_cache_size: u64,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmRemoveVcpuData {
+    pub id: u8,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSetCpuAffinityData {
+    pub affinity: Vec<CpuAffinity>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmSetCpuBandwidthData {
+    #[serde(default)]
+    pub cpu_max: Option<CpuBandwidth>,
+    #[serde(default)]
+    pub vcpu_max: Option<CpuBandwidth>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmGuestExecData {
+    /// Path of the binary to run in the guest
+    pub path: String,
+    /// Arguments passed to the binary
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Environment of the process, as `NAME=value` entries
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Capture stdout and stderr of the process
+    #[serde(default)]
+    pub capture_output: bool,
+    /// Wait for the process to exit and return its status
+    #[serde(default)]
+    pub wait: bool,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VmGuestFsFreezeAction {
+    Freeze,
+    Thaw,
+    #[default]
+    Status,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmGuestFsFreezeData {
+    pub action: VmGuestFsFreezeAction,
+}
+
+/// Largest guest memory access allowed through the API, in bytes.
+pub const MAX_MEMORY_ACCESS_SIZE: u64 = 64 << 10;
+
+static MEMORY_ACCESS: AtomicBool = AtomicBool::new(false);
+
+/// Allow the guest memory to be read and written through the API. As this
+/// gives full access to the guest, it is only enabled on request.
+pub fn enable_memory_access() {
+    MEMORY_ACCESS.store(true, Ordering::SeqCst);
+}
+
+pub(crate) fn memory_access_enabled() -> bool {
+    MEMORY_ACCESS.load(Ordering::SeqCst)
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmMemoryReadData {
+    /// Guest physical address to read from
+    pub address: u64,
+    /// Number of bytes to read
+    pub length: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug, PartialEq, Eq)]
+pub struct VmMemoryData {
+    /// Guest physical address of the data
+    pub address: u64,
+    /// Bytes of guest memory, hex encoded
+    pub data: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default, Debug)]
+pub struct VmVirtqueueStateData {
+    /// Identifier of the virtio device, or of its PCI device
+    pub id: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default, Debug)]
 pub struct VmSnapshotConfig {
     /// The snapshot destination URL
@@ -241,6 +532,9 @@ pub enum ApiResponsePayload {
     /// Vmm ping response
     VmmPing(VmmPingResponse),
 
+    /// Resources used by the VMM
+    VmmResources(VmmResources),
+
     /// Vm action response
     VmAction(Option<Vec<u8>>),
 }
@@ -273,6 +567,9 @@ pub enum ApiRequest {
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
+    /// Sample the resources used by the VMM threads.
+    VmmResources(Sender<ApiResponse>),
+
     /// Pause a VM.
     VmPause(Sender<ApiResponse>),
 
@@ -280,7 +577,7 @@ pub enum ApiRequest {
     VmResume(Sender<ApiResponse>),
 
     /// Get counters for a VM.
-    VmCounters(Sender<ApiResponse>),
+    VmCounters(Arc<VmCountersData>, Sender<ApiResponse>),
 
     /// Shut the previously booted virtual machine down.
     /// If the VM was not previously booted or created, the VMM API server
@@ -303,9 +600,30 @@ pub enum ApiRequest {
     /// Resize the memory zone.
     VmResizeZone(Arc<VmResizeZoneData>, Sender<ApiResponse>),
 
+    /// Bind the memory zone to a host NUMA node.
+    VmBindZone(Arc<VmBindZoneData>, Sender<ApiResponse>),
+
+    /// Resize the DAX window of a virtio-fs device.
+    VmResizeFs(Arc<VmResizeFsData>, Sender<ApiResponse>),
+
+    /// Remove a specific vCPU from the VM.
+    VmRemoveVcpu(Arc<VmRemoveVcpuData>, Sender<ApiResponse>),
+
+    /// Change the host CPUs some vCPUs run onto.
+    VmSetCpuAffinity(Arc<VmSetCpuAffinityData>, Sender<ApiResponse>),
+
+    /// Change the CPU bandwidth limits of the VM and of its vCPUs.
+    VmSetCpuBandwidth(Arc<VmSetCpuBandwidthData>, Sender<ApiResponse>),
+
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
+    /// Create an SR-IOV virtual function and add it to the VM.
+    VmAddVf(Arc<VfConfig>, Sender<ApiResponse>),
+
+    /// Create a mediated device and add it to the VM.
+    VmAddMdev(Arc<MdevConfig>, Sender<ApiResponse>),
+
     /// Add a user device to the VM.
     VmAddUserDevice(Arc<UserDeviceConfig>, Sender<ApiResponse>),
 
@@ -330,6 +648,16 @@ pub enum ApiRequest {
     /// Add a vsock device to the VM.
     VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
 
+    /// Add an SGX EPC section to the VM.
+    #[cfg(target_arch = "x86_64")]
+    VmAddSgxEpc(Arc<SgxEpcConfig>, Sender<ApiResponse>),
+
+    /// Add a port to the virtio-console device.
+    VmAddConsolePort(Arc<ConsolePortConfig>, Sender<ApiResponse>),
+
+    /// Remove a port from the virtio-console device.
+    VmRemoveConsolePort(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
+
     /// Take a VM snapshot
     VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
 
@@ -346,11 +674,42 @@ pub enum ApiRequest {
     /// Outgoing migration
     VmSendMigration(Arc<VmSendMigrationData>, Sender<ApiResponse>),
 
+    /// Check an outgoing migration against its destination
+    VmMigrationPrecheck(Arc<VmSendMigrationData>, Sender<ApiResponse>),
+
     // Trigger power button
     VmPowerButton(Sender<ApiResponse>),
 
     /// Enable heterogeneous memory management
     VmmEnableHmem(Arc<VmmEnableHmemData>, Sender<ApiResponse>),
+
+    /// Run a command in the guest through the guest agent.
+    VmGuestExec(Arc<VmGuestExecData>, Sender<ApiResponse>),
+
+    /// Freeze, thaw or query the guest filesystems through the guest agent.
+    VmGuestFsFreeze(Arc<VmGuestFsFreezeData>, Sender<ApiResponse>),
+
+    /// Read guest memory.
+    VmMemoryRead(Arc<VmMemoryReadData>, Sender<ApiResponse>),
+
+    /// Write guest memory, while the VM is paused.
+    VmMemoryWrite(Arc<VmMemoryData>, Sender<ApiResponse>),
+
+    /// Dump the state of the virtqueues of a device.
+    VmVirtqueueState(Arc<VmVirtqueueStateData>, Sender<ApiResponse>),
+
+    /// Request the guest agent information.
+    VmGuestInfo(Sender<ApiResponse>),
+
+    /// Request the breakdown of the time spent booting the VM.
+    VmBootTimings(Sender<ApiResponse>),
+
+    /// Request the state of the controller moving guest memory to the
+    /// heterogeneous memory on memory pressure.
+    VmHeteroBalloon(Sender<ApiResponse>),
+
+    /// Ask the guest to report its free pages through the balloon.
+    VmReportFreePages(Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -394,11 +753,17 @@ pub enum VmAction {
     Resume,
 
     /// Return VM counters
-    Counters,
+    Counters(Arc<VmCountersData>),
 
     /// Add VFIO device
     AddDevice(Arc<DeviceConfig>),
 
+    /// Add SR-IOV virtual function
+    AddVf(Arc<VfConfig>),
+
+    /// Add mediated device
+    AddMdev(Arc<MdevConfig>),
+
     /// Add disk
     AddDisk(Arc<DiskConfig>),
 
@@ -417,6 +782,16 @@ pub enum VmAction {
     /// Add vsock
     AddVsock(Arc<VsockConfig>),
 
+    /// Add SGX EPC section
+    #[cfg(target_arch = "x86_64")]
+    AddSgxEpc(Arc<SgxEpcConfig>),
+
+    /// Add console port
+    AddConsolePort(Arc<ConsolePortConfig>),
+
+    /// Remove console port
+    RemoveConsolePort(Arc<VmRemoveDeviceData>),
+
     /// Add user  device
     AddUserDevice(Arc<UserDeviceConfig>),
 
@@ -429,6 +804,21 @@ pub enum VmAction {
     /// Resize memory zone
     ResizeZone(Arc<VmResizeZoneData>),
 
+    /// Bind memory zone to a host NUMA node
+    BindZone(Arc<VmBindZoneData>),
+
+    /// Resize virtio-fs DAX window
+    ResizeFs(Arc<VmResizeFsData>),
+
+    /// Remove vCPU
+    RemoveVcpu(Arc<VmRemoveVcpuData>),
+
+    /// Set vCPU affinity
+    SetCpuAffinity(Arc<VmSetCpuAffinityData>),
+
+    /// Set CPU bandwidth limits
+    SetCpuBandwidth(Arc<VmSetCpuBandwidthData>),
+
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
@@ -445,11 +835,41 @@ pub enum VmAction {
     /// Outgoing migration
     SendMigration(Arc<VmSendMigrationData>),
 
+    /// Check outgoing migration
+    MigrationPrecheck(Arc<VmSendMigrationData>),
+
     /// Power Button for clean shutdown
     PowerButton,
 
     /// Enable heterogeneous memory
     VmmEnableHmemData(Arc<VmmEnableHmemData>),
+
+    /// Run a command through the guest agent
+    GuestExec(Arc<VmGuestExecData>),
+
+    /// Freeze or thaw guest filesystems
+    GuestFsFreeze(Arc<VmGuestFsFreezeData>),
+
+    /// Read guest memory
+    MemoryRead(Arc<VmMemoryReadData>),
+
+    /// Write guest memory
+    MemoryWrite(Arc<VmMemoryData>),
+
+    /// Return the state of the virtqueues of a device
+    VirtqueueState(Arc<VmVirtqueueStateData>),
+
+    /// Return guest agent information
+    GuestInfo,
+
+    /// Return the boot timings
+    BootTimings,
+
+    /// Return the heterogeneous balloon controller state
+    HeteroBalloon,
+
+    /// Ask the guest to report its free pages
+    ReportFreePages,
 }
 
 fn vm_action(
@@ -467,26 +887,47 @@ fn vm_action(
         Reboot => ApiRequest::VmReboot(response_sender),
         Pause => ApiRequest::VmPause(response_sender),
         Resume => ApiRequest::VmResume(response_sender),
-        Counters => ApiRequest::VmCounters(response_sender),
+        Counters(v) => ApiRequest::VmCounters(v, response_sender),
         AddDevice(v) => ApiRequest::VmAddDevice(v, response_sender),
+        AddVf(v) => ApiRequest::VmAddVf(v, response_sender),
+        AddMdev(v) => ApiRequest::VmAddMdev(v, response_sender),
         AddDisk(v) => ApiRequest::VmAddDisk(v, response_sender),
         AddFs(v) => ApiRequest::VmAddFs(v, response_sender),
         AddPmem(v) => ApiRequest::VmAddPmem(v, response_sender),
         AddNet(v) => ApiRequest::VmAddNet(v, response_sender),
         AddVdpa(v) => ApiRequest::VmAddVdpa(v, response_sender),
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
+        #[cfg(target_arch = "x86_64")]
+        AddSgxEpc(v) => ApiRequest::VmAddSgxEpc(v, response_sender),
+        AddConsolePort(v) => ApiRequest::VmAddConsolePort(v, response_sender),
+        RemoveConsolePort(v) => ApiRequest::VmRemoveConsolePort(v, response_sender),
         AddUserDevice(v) => ApiRequest::VmAddUserDevice(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
         ResizeZone(v) => ApiRequest::VmResizeZone(v, response_sender),
+        BindZone(v) => ApiRequest::VmBindZone(v, response_sender),
+        ResizeFs(v) => ApiRequest::VmResizeFs(v, response_sender),
+        RemoveVcpu(v) => ApiRequest::VmRemoveVcpu(v, response_sender),
+        SetCpuAffinity(v) => ApiRequest::VmSetCpuAffinity(v, response_sender),
+        SetCpuBandwidth(v) => ApiRequest::VmSetCpuBandwidth(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
         #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
         Coredump(v) => ApiRequest::VmCoredump(v, response_sender),
         ReceiveMigration(v) => ApiRequest::VmReceiveMigration(v, response_sender),
         SendMigration(v) => ApiRequest::VmSendMigration(v, response_sender),
+        MigrationPrecheck(v) => ApiRequest::VmMigrationPrecheck(v, response_sender),
         PowerButton => ApiRequest::VmPowerButton(response_sender),
         VmmEnableHmemData(v) => ApiRequest::VmmEnableHmem(v, response_sender),
+        GuestExec(v) => ApiRequest::VmGuestExec(v, response_sender),
+        GuestFsFreeze(v) => ApiRequest::VmGuestFsFreeze(v, response_sender),
+        MemoryRead(v) => ApiRequest::VmMemoryRead(v, response_sender),
+        MemoryWrite(v) => ApiRequest::VmMemoryWrite(v, response_sender),
+        VirtqueueState(v) => ApiRequest::VmVirtqueueState(v, response_sender),
+        GuestInfo => ApiRequest::VmGuestInfo(response_sender),
+        BootTimings => ApiRequest::VmBootTimings(response_sender),
+        HeteroBalloon => ApiRequest::VmHeteroBalloon(response_sender),
+        ReportFreePages => ApiRequest::VmReportFreePages(response_sender),
     };
 
     // Send the VM request.
@@ -526,8 +967,85 @@ pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
-pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
-    vm_action(api_evt, api_sender, VmAction::Counters)
+pub fn vm_counters(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmCountersData>,
+) -> ApiResult<Option<Body>> {
+    jobs::read_only(format!("vm.counters rates={}", data.rates), || {
+        vm_action(api_evt, api_sender, VmAction::Counters(data))
+    })
+}
+
+pub fn vm_boot_timings(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    jobs::read_only("vm.boot-timings".to_string(), || {
+        vm_action(api_evt, api_sender, VmAction::BootTimings)
+    })
+}
+
+pub fn vm_hetero_balloon(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    jobs::read_only("vm.hetero-balloon".to_string(), || {
+        vm_action(api_evt, api_sender, VmAction::HeteroBalloon)
+    })
+}
+
+pub fn vm_report_free_pages(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ReportFreePages)
+}
+
+pub fn vm_guest_exec(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmGuestExecData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::GuestExec(data))
+}
+
+pub fn vm_guest_fsfreeze(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmGuestFsFreezeData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::GuestFsFreeze(data))
+}
+
+pub fn vm_memory_read(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmMemoryReadData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MemoryRead(data))
+}
+
+pub fn vm_memory_write(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmMemoryData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MemoryWrite(data))
+}
+
+pub fn vm_virtqueue_state(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmVirtqueueStateData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::VirtqueueState(data))
+}
+
+pub fn vm_guest_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Option<Body>> {
+    jobs::read_only("vm.guest-info".to_string(), || {
+        vm_action(api_evt, api_sender, VmAction::GuestInfo)
+    })
 }
 
 pub fn vm_power_button(
@@ -553,6 +1071,14 @@ pub fn vm_send_migration(
     vm_action(api_evt, api_sender, VmAction::SendMigration(data))
 }
 
+pub fn vm_migration_precheck(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSendMigrationData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::MigrationPrecheck(data))
+}
+
 pub fn vm_snapshot(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -579,6 +1105,10 @@ pub fn vm_coredump(
 }
 
 pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
+    jobs::read_only("vm.info".to_string(), || send_vm_info(api_evt, api_sender))
+}
+
+fn send_vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
     let (response_sender, response_receiver) = channel();
 
     // Send the VM request.
@@ -596,6 +1126,12 @@ pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vm
 }
 
 pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmPingResponse> {
+    jobs::read_only("vmm.ping".to_string(), || {
+        send_vmm_ping(api_evt, api_sender)
+    })
+}
+
+fn send_vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmPingResponse> {
     let (response_sender, response_receiver) = channel();
 
     api_sender
@@ -611,6 +1147,42 @@ pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<V
     }
 }
 
+pub fn vmm_resources(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmResources> {
+    jobs::read_only("vmm.resources".to_string(), || {
+        send_vmm_resources(api_evt, api_sender)
+    })
+}
+
+fn send_vmm_resources(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmResources> {
+    let (response_sender, response_receiver) = channel();
+
+    api_sender
+        .send(ApiRequest::VmmResources(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let vmm_resources = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match vmm_resources {
+        ApiResponsePayload::VmmResources(resources) => Ok(resources),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+// The events are reported from every thread, and kept by the event monitor
+// rather than the VMM thread.
+pub fn vmm_events(data: &VmmEventsData) -> VmmEventsResponse {
+    let (events, next) = event_monitor::history_since(data.since);
+
+    VmmEventsResponse {
+        next,
+        events: events
+            .iter()
+            .filter_map(|event| serde_json::from_str(event).ok())
+            .collect(),
+    }
+}
+
 pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
@@ -641,6 +1213,46 @@ pub fn vm_resize_zone(
     vm_action(api_evt, api_sender, VmAction::ResizeZone(data))
 }
 
+pub fn vm_bind_zone(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmBindZoneData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::BindZone(data))
+}
+
+pub fn vm_resize_fs(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeFsData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResizeFs(data))
+}
+
+pub fn vm_remove_vcpu(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRemoveVcpuData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::RemoveVcpu(data))
+}
+
+pub fn vm_set_cpu_affinity(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetCpuAffinityData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetCpuAffinity(data))
+}
+
+pub fn vm_set_cpu_bandwidth(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetCpuBandwidthData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetCpuBandwidth(data))
+}
+
 pub fn vm_add_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -649,6 +1261,22 @@ pub fn vm_add_device(
     vm_action(api_evt, api_sender, VmAction::AddDevice(data))
 }
 
+pub fn vm_add_vf(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VfConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddVf(data))
+}
+
+pub fn vm_add_mdev(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<MdevConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddMdev(data))
+}
+
 pub fn vm_add_user_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -713,6 +1341,31 @@ pub fn vm_add_vsock(
     vm_action(api_evt, api_sender, VmAction::AddVsock(data))
 }
 
+#[cfg(target_arch = "x86_64")]
+pub fn vm_add_sgx_epc(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<SgxEpcConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddSgxEpc(data))
+}
+
+pub fn vm_add_console_port(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<ConsolePortConfig>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::AddConsolePort(data))
+}
+
+pub fn vm_remove_console_port(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRemoveDeviceData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::RemoveConsolePort(data))
+}
+
 pub fn vmm_enable_hmem(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -720,3 +1373,91 @@ pub fn vmm_enable_hmem(
 ) -> ApiResult<Option<Body>> {
     vm_action(api_evt, api_sender, VmAction::VmmEnableHmemData(data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_info_list() {
+        let mut config: VmConfig =
+            serde_json::from_str(r#"{"payload": {"kernel": "/path/to/kernel"}}"#).unwrap();
+        config.serial.mode = ConsoleOutputMode::Socket;
+        config.serial.socket = Some(PathBuf::from("/run/vm/serial.sock"));
+        config.console_ports = Some(vec![
+            ConsolePortConfig {
+                id: Some("_console_port0".to_owned()),
+                name: Some("org.qemu.guest_agent.0".to_owned()),
+                socket: Some(PathBuf::from("/run/vm/org.qemu.guest_agent.0")),
+                file: None,
+            },
+            ConsolePortConfig {
+                id: Some("_console_port1".to_owned()),
+                name: None,
+                socket: None,
+                file: Some(PathBuf::from("/dev/pts/3")),
+            },
+            ConsolePortConfig {
+                id: Some("_console_port2".to_owned()),
+                ..Default::default()
+            },
+        ]);
+        let port_ids = BTreeMap::from([
+            (1, "_console_port1".to_owned()),
+            (2, "_console_port0".to_owned()),
+        ]);
+
+        assert_eq!(
+            ConsoleInfo::list(&config, &port_ids),
+            vec![
+                ConsoleInfo {
+                    id: SERIAL_DEVICE_NAME.to_owned(),
+                    name: None,
+                    port: None,
+                    mode: ConsoleOutputMode::Socket,
+                    path: Some(PathBuf::from("/run/vm/serial.sock")),
+                    tcp: None,
+                },
+                ConsoleInfo {
+                    id: CONSOLE_DEVICE_NAME.to_owned(),
+                    name: None,
+                    port: Some(0),
+                    mode: ConsoleOutputMode::Tty,
+                    path: None,
+                    tcp: None,
+                },
+                ConsoleInfo {
+                    id: "_console_port0".to_owned(),
+                    name: Some("org.qemu.guest_agent.0".to_owned()),
+                    port: Some(2),
+                    mode: ConsoleOutputMode::Socket,
+                    path: Some(PathBuf::from("/run/vm/org.qemu.guest_agent.0")),
+                    tcp: None,
+                },
+                ConsoleInfo {
+                    id: "_console_port1".to_owned(),
+                    name: None,
+                    port: Some(1),
+                    mode: ConsoleOutputMode::Pty,
+                    path: Some(PathBuf::from("/dev/pts/3")),
+                    tcp: None,
+                },
+                // Not plugged, so without a port number
+                ConsoleInfo {
+                    id: "_console_port2".to_owned(),
+                    name: None,
+                    port: None,
+                    mode: ConsoleOutputMode::Pty,
+                    path: None,
+                    tcp: None,
+                },
+            ]
+        );
+
+        // The consoles turned off aren't listed
+        config.serial.mode = ConsoleOutputMode::Off;
+        config.console.mode = ConsoleOutputMode::Off;
+        config.console_ports = None;
+        assert!(ConsoleInfo::list(&config, &BTreeMap::new()).is_empty());
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Resource limits enforced through the cgroup v2 `cpu`, `memory` and `io`
+//! controllers.
+//!
+//! The VMM process moves itself into a `cloud-hypervisor` child of the cgroup
+//! it was started in, or into the cgroup given in its configuration, so that
+//! the `cpu.max`, `memory.max` and `io.max` files of this cgroup cap the
+//! resources consumed by the whole VM. Each vCPU thread, and the threads of
+//! each device, can additionally be capped on their own, by moving them into
+//! a threaded `vcpu<id>` or `device-<id>` child of that cgroup. Only the `cpu`
+//! controller applies to these threaded cgroups.
+//!
+//! This requires the VMM to be allowed to manage the cgroup it runs in, as it
+//! is the case when running in a delegated cgroup (e.g. a systemd unit with
+//! `Delegate=yes`), and this cgroup must not contain any other process.
+
+use crate::config::{CgroupConfig, CpuBandwidth, IoMaxConfig};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const CGROUP_MOUNT_POINT: &str = "/sys/fs/cgroup";
+const VM_CGROUP: &str = "cloud-hypervisor";
+const PROC_SELF_TASK: &str = "/proc/self/task";
+// Length of the thread names, without the terminating null byte
+const THREAD_NAME_MAX_LEN: usize = 15;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read the cgroup of the process: {0}")]
+    ReadProcCgroup(#[source] io::Error),
+
+    #[error("The process is not part of a cgroup v2 hierarchy")]
+    NoCgroupV2,
+
+    #[error("Cannot create cgroup {0}: {1}")]
+    Create(PathBuf, #[source] io::Error),
+
+    #[error("Cannot write to {0}: {1}")]
+    Write(PathBuf, #[source] io::Error),
+
+    #[error("Cannot remove cgroup {0}: {1}")]
+    Remove(PathBuf, #[source] io::Error),
+
+    #[error("Cannot list the threads of the process: {0}")]
+    ReadThreads(#[source] io::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub struct VmmCgroup {
+    path: PathBuf,
+    memory_max: Option<u64>,
+    io_max: Vec<IoMaxConfig>,
+}
+
+impl VmmCgroup {
+    /// Move the VMM process into its own cgroup, or into the one from the
+    /// configuration, with the `cpu` controller enabled along with the ones
+    /// the configured limits rely on, and apply these limits.
+    pub fn new(config: Option<&CgroupConfig>) -> Result<Self> {
+        let configured_path = config.and_then(|c| c.path.as_ref());
+        let path = match configured_path {
+            Some(path) => {
+                let path =
+                    Path::new(CGROUP_MOUNT_POINT).join(path.strip_prefix("/").unwrap_or(path));
+                if current_cgroup()? != path {
+                    create(&path)?;
+                    write(&path.join("cgroup.procs"), &std::process::id().to_string())?;
+                }
+                path
+            }
+            None => {
+                let current = current_cgroup()?;
+
+                // The process is already in its own cgroup when a VM is created
+                // again after the previous one got deleted.
+                if current.file_name() == Some(OsStr::new(VM_CGROUP)) {
+                    current
+                } else {
+                    let path = current.join(VM_CGROUP);
+                    create(&path)?;
+                    write(&path.join("cgroup.procs"), &std::process::id().to_string())?;
+                    path
+                }
+            }
+        };
+
+        let memory_max = config.and_then(|c| c.memory_max);
+        let io_max = config.and_then(|c| c.io_max.clone()).unwrap_or_default();
+
+        // The controllers of a cgroup can only be enabled for its children
+        // once it doesn't contain any process.
+        let mut controllers = vec!["+cpu"];
+        if memory_max.is_some() {
+            controllers.push("+memory");
+        }
+        if !io_max.is_empty() {
+            controllers.push("+io");
+        }
+        write(
+            &path.parent().unwrap().join("cgroup.subtree_control"),
+            &controllers.join(" "),
+        )?;
+
+        let cgroup = VmmCgroup {
+            path,
+            memory_max,
+            io_max,
+        };
+        if let Some(memory_max) = cgroup.memory_max {
+            write(&cgroup.path.join("memory.max"), &memory_max.to_string())?;
+        }
+        for io_max in cgroup.io_max.iter() {
+            write(&cgroup.path.join("io.max"), &io_max_line(io_max))?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// Limit the CPU time of the whole VM, or lift the limit.
+    pub fn set_cpu_max(&self, bandwidth: Option<CpuBandwidth>) -> Result<()> {
+        write(&self.path.join("cpu.max"), &cpu_max(bandwidth))
+    }
+
+    /// Move a vCPU thread into its own cgroup and limit its CPU time, or lift
+    /// the limit.
+    pub fn set_vcpu_max(
+        &self,
+        vcpu_id: u8,
+        tid: i32,
+        bandwidth: Option<CpuBandwidth>,
+    ) -> Result<()> {
+        self.set_threads_cpu_max(&format!("vcpu{vcpu_id}"), &[tid], bandwidth)
+    }
+
+    /// Remove the cgroup of a vCPU, once its thread exited.
+    pub fn remove_vcpu(&self, vcpu_id: u8) -> Result<()> {
+        remove(&self.path.join(format!("vcpu{vcpu_id}")))
+    }
+
+    /// Move the threads of a device into their own cgroup and limit their
+    /// CPU time.
+    pub fn set_device_cpu_max(
+        &self,
+        device_id: &str,
+        tids: &[i32],
+        bandwidth: CpuBandwidth,
+    ) -> Result<()> {
+        self.set_threads_cpu_max(&format!("device-{device_id}"), tids, Some(bandwidth))
+    }
+
+    fn set_threads_cpu_max(
+        &self,
+        name: &str,
+        tids: &[i32],
+        bandwidth: Option<CpuBandwidth>,
+    ) -> Result<()> {
+        let path = self.path.join(name);
+        create(&path)?;
+        // Threaded cgroups can hold individual threads of a process, and they
+        // don't prevent the VM cgroup from containing the other threads.
+        write(&path.join("cgroup.type"), "threaded")?;
+        write(&self.path.join("cgroup.subtree_control"), "+cpu")?;
+        for tid in tids {
+            write(&path.join("cgroup.threads"), &tid.to_string())?;
+        }
+
+        write(&path.join("cpu.max"), &cpu_max(bandwidth))
+    }
+}
+
+impl Drop for VmmCgroup {
+    fn drop(&mut self) {
+        // The threaded cgroups of the vCPUs and devices are empty once the VM
+        // is gone.
+        if let Ok(entries) = fs::read_dir(&self.path) {
+            for entry in entries.flatten() {
+                if is_thread_cgroup(&entry.file_name().to_string_lossy()) {
+                    if let Err(e) = remove(&entry.path()) {
+                        warn!("{}", e);
+                    }
+                }
+            }
+        }
+
+        // The process stays in the cgroup, which must not limit a VM created
+        // later on.
+        if let Err(e) = self.set_cpu_max(None) {
+            warn!("Cannot reset the CPU bandwidth limit: {}", e);
+        }
+        if self.memory_max.is_some() {
+            if let Err(e) = write(&self.path.join("memory.max"), "max") {
+                warn!("Cannot reset the memory limit: {}", e);
+            }
+        }
+        for io_max in self.io_max.iter() {
+            let unlimited = IoMaxConfig {
+                major: io_max.major,
+                minor: io_max.minor,
+                ..Default::default()
+            };
+            if let Err(e) = write(&self.path.join("io.max"), &io_max_line(&unlimited)) {
+                warn!("Cannot reset the I/O limits: {}", e);
+            }
+        }
+    }
+}
+
+fn cpu_max(bandwidth: Option<CpuBandwidth>) -> String {
+    match bandwidth {
+        Some(b) => format!("{} {}", b.quota, b.period),
+        None => "max".to_string(),
+    }
+}
+
+/// Thread IDs of the device, its threads being named after its identifier,
+/// possibly followed by an underscore and the queue they handle.
+pub fn device_threads(device_id: &str) -> Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(PROC_SELF_TASK).map_err(Error::ReadThreads)? {
+        let entry = entry.map_err(Error::ReadThreads)?;
+        let tid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // The thread may have exited since the directory was read
+        let comm = match fs::read_to_string(entry.path().join("comm")) {
+            Ok(comm) => comm,
+            Err(_) => continue,
+        };
+        if is_device_thread(comm.trim_end(), device_id) {
+            tids.push(tid);
+        }
+    }
+
+    Ok(tids)
+}
+
+fn is_device_thread(name: &str, device_id: &str) -> bool {
+    if device_id.len() >= THREAD_NAME_MAX_LEN {
+        return device_id.as_bytes().get(..THREAD_NAME_MAX_LEN) == Some(name.as_bytes());
+    }
+
+    name == device_id
+        || name
+            .strip_prefix(device_id)
+            .map_or(false, |suffix| suffix.starts_with('_'))
+}
+
+fn is_thread_cgroup(name: &str) -> bool {
+    name.strip_prefix("vcpu")
+        .map_or(false, |id| id.parse::<u8>().is_ok())
+        || name.starts_with("device-")
+}
+
+fn io_max_line(io_max: &IoMaxConfig) -> String {
+    let limit = |value: Option<u64>| value.map_or("max".to_string(), |v| v.to_string());
+    format!(
+        "{}:{} rbps={} wbps={} riops={} wiops={}",
+        io_max.major,
+        io_max.minor,
+        limit(io_max.rbps),
+        limit(io_max.wbps),
+        limit(io_max.riops),
+        limit(io_max.wiops)
+    )
+}
+
+/// Path of the cgroup v2 the process runs in.
+pub fn current_cgroup() -> Result<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").map_err(Error::ReadProcCgroup)?;
+    parse_proc_cgroup(&cgroups)
+        .map(|p| Path::new(CGROUP_MOUNT_POINT).join(p.trim_start_matches('/')))
+        .ok_or(Error::NoCgroupV2)
+}
+
+// The cgroup v2 hierarchy is identified by the "0::<path>" entry.
+fn parse_proc_cgroup(cgroups: &str) -> Option<&str> {
+    cgroups.lines().find_map(|l| l.strip_prefix("0::"))
+}
+
+fn create(path: &Path) -> Result<()> {
+    match fs::create_dir(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            Err(Error::Create(path.to_path_buf(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_dir(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::Remove(path.to_path_buf(), e)),
+        _ => Ok(()),
+    }
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| Error::Write(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_cgroup() {
+        assert_eq!(
+            parse_proc_cgroup("0::/system.slice/ch.service\n"),
+            Some("/system.slice/ch.service")
+        );
+        assert_eq!(
+            parse_proc_cgroup("12:cpu,cpuacct:/user.slice\n1:name=systemd:/user.slice\n"),
+            None
+        );
+        assert_eq!(
+            cpu_max(Some(CpuBandwidth {
+                quota: 50000,
+                period: 100000
+            })),
+            "50000 100000"
+        );
+        assert_eq!(cpu_max(None), "max");
+        assert_eq!(
+            io_max_line(&IoMaxConfig {
+                major: 8,
+                minor: 16,
+                rbps: Some(1048576),
+                wiops: Some(100),
+                ..Default::default()
+            }),
+            "8:16 rbps=1048576 wbps=max riops=max wiops=100"
+        );
+    }
+
+    #[test]
+    fn test_is_device_thread() {
+        assert!(is_device_thread("_disk0", "_disk0"));
+        assert!(is_device_thread("_disk0_q1", "_disk0"));
+        assert!(!is_device_thread("_disk01", "_disk0"));
+        assert!(!is_device_thread("vcpu0", "_disk0"));
+        // Names longer than 15 bytes are truncated
+        assert!(is_device_thread("a_very_long_dev", "a_very_long_device"));
+    }
+
+    #[test]
+    fn test_is_thread_cgroup() {
+        assert!(is_thread_cgroup("vcpu0"));
+        assert!(is_thread_cgroup("vcpu254"));
+        assert!(is_thread_cgroup("device-_disk0"));
+        assert!(!is_thread_cgroup("vcpus"));
+        assert!(!is_thread_cgroup("cgroup.procs"));
+        assert!(!is_thread_cgroup("cpu.max"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let path = dir.as_path().join("vcpu0");
+        create(&path).unwrap();
+        remove(&path).unwrap();
+        assert!(!path.exists());
+        // Removing a cgroup that is already gone isn't an error.
+        remove(&path).unwrap();
+    }
+}
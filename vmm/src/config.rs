@@ -3,23 +3,37 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::device_manager::{CONSOLE_DEVICE_NAME, RNG_DEVICE_NAME};
 pub use crate::vm_config::*;
 use clap::ArgMatches;
+use net_util::MacAddr;
 use option_parser::{
-    ByteSized, ByteSizedList, IntegerList, NanosecTimed, OptionParser, OptionParserError,
-    StringList, Toggle, Tuple,
+    ByteSized, ByteSizedList, Hex, IntegerList, NanosecTimed, OptionParser, OptionParserError,
+    StringList, Toggle, Tuple, TupleError, TupleValue,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::From;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, Seek};
+use std::net::SocketAddr;
+use std::os::unix::io::BorrowedFd;
+use std::path::{Component, PathBuf};
 use std::result;
 use std::str::FromStr;
 use thiserror::Error;
-use virtio_devices::{RateLimiterConfig, TokenBucketConfig};
+use virtio_devices::{
+    seccomp_filters, EventLoop, NotificationConfig, ParseWatchdogActionError, PciIdsConfig,
+    RateLimiterConfig, TokenBucketConfig, VIRTIO_CONSOLE_MAX_PORTS,
+};
 
 const MAX_NUM_PCI_SEGMENTS: u16 = 96;
+// Socket of the serial port in its socket directory
+const SERIAL_SOCKET_NAME: &str = "serial.sock";
+// Each bus takes 1MiB of the PCI configuration space (ECAM)
+const MAX_NUM_PCI_BUSES: u64 =
+    arch::layout::PCI_MMCONFIG_SIZE / arch::layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug, Error)]
@@ -30,6 +44,8 @@ pub enum Error {
     ParseFsSockMissing,
     /// Missing persistent memory file parameter.
     ParsePmemFileMissing,
+    /// Missing ivshmem path or socket parameter, or both given.
+    ParseIvshmemBackend,
     /// Missing vsock socket path parameter.
     ParseVsockSockMissing,
     /// Missing vsock cid parameter.
@@ -40,6 +56,8 @@ pub enum Error {
     ParseCpus(OptionParserError),
     /// Invalid CPU features
     InvalidCpuFeatures(String),
+    /// Unsupported Hyper-V feature
+    InvalidHypervFeature(String),
     /// Error parsing memory options
     ParseMemory(OptionParserError),
     /// Error parsing memory zone options
@@ -58,14 +76,32 @@ pub enum Error {
     ParseFileSystem(OptionParserError),
     /// Error parsing persistent memory parameters
     ParsePersistentMemory(OptionParserError),
+    /// Error parsing ivshmem parameters
+    ParseIvshmem(OptionParserError),
     /// Failed parsing console
     ParseConsole(OptionParserError),
     /// No mode given for console
     ParseConsoleInvalidModeGiven,
+    /// Failed parsing debug console
+    ParseDebugConsole(OptionParserError),
+    /// No mode given for debug console
+    ParseDebugConsoleInvalidModeGiven,
     /// Failed parsing device parameters
     ParseDevice(OptionParserError),
     /// Missing path from device,
     ParseDevicePathMissing,
+    /// Failed parsing virtual function parameters
+    ParseVf(OptionParserError),
+    /// Missing physical function from virtual function
+    ParseVfPfMissing,
+    /// Missing index from virtual function
+    ParseVfIndexMissing,
+    /// Failed parsing mediated device parameters
+    ParseMdev(OptionParserError),
+    /// Missing parent from mediated device
+    ParseMdevParentMissing,
+    /// Missing type from mediated device
+    ParseMdevTypeMissing,
     /// Failed parsing vsock parameters
     ParseVsock(OptionParserError),
     /// Failed parsing restore parameters
@@ -76,6 +112,21 @@ pub enum Error {
     /// Missing 'id' from SGX EPC section
     #[cfg(target_arch = "x86_64")]
     ParseSgxEpcIdMissing,
+    /// Failed parsing MSR filter parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseMsrFilter(OptionParserError),
+    /// Invalid MSR filter default action
+    #[cfg(target_arch = "x86_64")]
+    InvalidMsrFilterDefaultAction(String),
+    /// Invalid MSR index
+    #[cfg(target_arch = "x86_64")]
+    InvalidMsrIndex(String),
+    /// Failed parsing UEFI variable store parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseUefiVars(OptionParserError),
+    /// Missing 'path' from UEFI variable store parameters
+    #[cfg(target_arch = "x86_64")]
+    ParseUefiVarsPathMissing,
     /// Failed parsing NUMA parameters
     ParseNuma(OptionParserError),
     /// Failed validating configuration
@@ -95,6 +146,14 @@ pub enum Error {
     ParseUserDeviceSocketMissing,
     /// Failed parsing platform parameters
     ParsePlatform(OptionParserError),
+    /// Failed parsing PCI segment parameters
+    ParsePciSegment(OptionParserError),
+    /// Missing id from PCI segment
+    ParsePciSegmentIdMissing,
+    /// Failed parsing PCI root port parameters
+    ParsePciRootPort(OptionParserError),
+    /// Missing id from PCI root port
+    ParsePciRootPortIdMissing,
     /// Failed parsing vDPA device
     ParseVdpa(OptionParserError),
     /// Missing path for vDPA device
@@ -103,6 +162,36 @@ pub enum Error {
     ParseTpm(OptionParserError),
     /// Missing path for TPM device
     ParseTpmPathMissing,
+    /// Failed parsing ACPI table
+    ParseAcpiTable(OptionParserError),
+    /// Missing path for ACPI table
+    ParseAcpiTablePathMissing,
+    /// Failed parsing watchdog expiry action
+    ParseWatchdogAction(ParseWatchdogActionError),
+    /// Failed parsing pvpanic policy
+    ParsePvPanicPolicy(OptionParserError),
+    /// Failed parsing crash policy
+    ParseOnCrash(OptionParserError),
+    /// Failed parsing watchdog escalation
+    ParseWatchdogEscalation(OptionParserError),
+    /// Failed parsing console port parameters
+    ParseConsolePort(OptionParserError),
+    /// Failed parsing guest agent parameters
+    ParseGuestAgent(OptionParserError),
+    /// Failed parsing cgroup parameters
+    ParseCgroup(OptionParserError),
+    /// Failed parsing I/O limits of the cgroup
+    ParseCgroupIoMax(ParseIoMaxError),
+    /// Failed parsing process limits
+    ParseProcessLimits(OptionParserError),
+    /// Failed parsing seccomp override
+    ParseSeccompOverride(OptionParserError),
+    /// Missing device identifier for seccomp override
+    ParseSeccompOverrideIdMissing,
+    /// Failed parsing Landlock rules
+    ParseLandlockRules(OptionParserError),
+    /// Missing path or access from Landlock rule
+    ParseLandlockMissingFields,
 }
 
 #[derive(Debug, PartialEq, Eq, Error)]
@@ -115,6 +204,14 @@ pub enum ValidationError {
     ConsoleFileMissing,
     /// Missing socket path for console
     ConsoleSocketPathMissing,
+    /// Debug console mode other than tty, file or socket
+    DebugConsoleModeUnsupported,
+    /// Missing address for the tcp console mode
+    ConsoleTcpAddressMissing,
+    /// Console mode tcp used by the virtio console
+    ConsoleTcpUnsupported,
+    /// Telnet negotiation without the tcp console mode
+    ConsoleTelnetWithoutTcp,
     /// Max is less than boot
     CpusMaxLowerThanBoot,
     /// Both socket and path specified
@@ -123,6 +220,14 @@ pub enum ValidationError {
     VhostUserRequiresSharedMemory,
     /// No socket provided for vhost_use
     VhostUserMissingSocket,
+    /// Disk backend selected for a vhost-user disk
+    VhostUserDiskBackend,
+    /// No disk backend registered with this name
+    UnknownDiskBackend(String),
+    /// I/O priority level out of range
+    InvalidIoPriorityLevel(u8),
+    /// I/O priority set on a vhost-user disk
+    VhostUserIoPriority,
     /// Trying to use IOMMU without PCI
     IommuUnsupported,
     /// Trying to use VFIO without PCI
@@ -131,9 +236,6 @@ pub enum ValidationError {
     CpuTopologyCount,
     /// One part of the CPU topology was zero
     CpuTopologyZeroPart,
-    #[cfg(target_arch = "aarch64")]
-    /// Dies per package must be 1
-    CpuTopologyDiesPerPackage,
     /// Virtio needs a min of 2 queues
     VnetQueueLowerThan2,
     /// The input queue number for virtio_net must match the number of input fds
@@ -142,6 +244,18 @@ pub enum ValidationError {
     VnetReservedFd,
     /// Hardware checksum offload is disabled.
     NoHardwareChecksumOffload,
+    /// Notifications coalesced without any buffer or timeout
+    InvalidNotificationCoalescing,
+    /// Notifications tuned for a vhost-user device
+    VhostUserNotification,
+    /// Event loop picked for a vhost-user device
+    VhostUserEventLoop,
+    /// virtio-mmio device behind the IOMMU or with PCI options
+    MmioPciOption,
+    /// virtio-mmio transport requested for a vhost-user device
+    MmioVhostUser,
+    /// AF_XDP network device also given a TAP interface or vhost-user
+    XdpWithOtherBackend,
     /// Hugepages not turned on
     HugePageSizeWithoutHugePages,
     /// Huge page size is not power of 2
@@ -152,6 +266,18 @@ pub enum ValidationError {
     /// Missing firmware for TDX
     #[cfg(feature = "tdx")]
     TdxFirmwareMissing,
+    /// Quote generation requires TDX
+    #[cfg(feature = "tdx")]
+    TdxQuoteGenerationWithoutTdx,
+    /// The S3 sleep state is not supported with TDX
+    #[cfg(feature = "tdx")]
+    TdxS3,
+    /// The hibernation policy requires the S4 sleep state
+    #[cfg(target_arch = "x86_64")]
+    HibernateActionWithoutS4,
+    /// Interrupt remapping only covers the default PCI segment
+    #[cfg(target_arch = "x86_64")]
+    InterruptRemappingMultipleSegments,
     /// Insufficient vCPUs for queues
     TooManyQueues,
     /// Need shared memory for vfio-user
@@ -164,6 +290,13 @@ pub enum ValidationError {
     InvalidPciSegment(u16),
     /// Balloon too big
     BalloonLargerThanRam([u64; 2], u64),
+    /// Balloon reclaim thresholds or step invalid
+    InvalidBalloonReclaim,
+    /// Balloon heterogeneous memory pressure thresholds or step invalid
+    InvalidBalloonHeteroPressure,
+    /// Overcommittable memory zone backed by huge pages, or without the
+    /// balloon reclaiming memory on host pressure
+    InvalidMemoryZoneOvercommit(String),
     /// On a IOMMU segment but not behind IOMMU
     OnIommuSegment(u16),
     // On a IOMMU segment but IOMMU not supported
@@ -182,6 +315,132 @@ pub enum ValidationError {
     PciSegmentReused(u16, u32, u32),
     /// Default PCI segment is assigned to NUMA node other than 0.
     DefaultPciSegmentInvalidNode(u32),
+    /// PCI segment configured more than once
+    DuplicatePciSegmentConfig(u16),
+    /// Invalid bus range for a PCI segment
+    InvalidPciSegmentBusRange(u16, u8, u8),
+    /// PCI segments bus ranges don't fit the PCI configuration space
+    TooManyPciBuses(u64),
+    /// Not enough buses on a PCI segment for its root ports
+    TooManyPciRootPorts(u16),
+    /// Device attached to an unknown PCI root port
+    UnknownPciRootPort(String),
+    /// PCI root port used by multiple devices
+    PciRootPortReused(String),
+    /// DAX cache size for virtio-fs is not a power of 2
+    InvalidFsCacheSize(u64),
+    /// Same backing file used by multiple persistent memory devices, one of
+    /// them writing to it
+    DuplicatePmemFile(String),
+    /// Discard requested on a persistent memory device discarding writes
+    PmemDiscardWithDiscardWrites,
+    /// ivshmem device given both or none of a file and a server
+    IvshmemPathOrSocket,
+    /// ivshmem memory size is not a power of 2
+    InvalidIvshmemSize(u64),
+    /// ivshmem memory size set along with a server
+    IvshmemSocketWithSize,
+    /// Invalid number of ivshmem interrupt vectors
+    InvalidIvshmemVectors(u16),
+    /// Invalid or duplicated vsock sibling CID
+    InvalidVsockSiblingCid(u64),
+    /// Same vsock port bound to multiple host sockets
+    DuplicateVsockListenerPort(u32),
+    /// A pvpanic policy is set without the pvpanic device or the watchdog
+    /// panic action
+    PvPanicPolicyWithoutDevice,
+    /// The guest agent channel requires a vsock device
+    GuestAgentWithoutVsock,
+    /// The coredump pvpanic action is missing its destination
+    PvPanicCoredumpPathMissing,
+    /// The coredump or kdump pvpanic action is not supported by this build
+    PvPanicCoredumpUnsupported,
+    /// The kdump pvpanic action is missing its destination
+    PvPanicKdumpDirMissing,
+    /// The restart option is set on a pvpanic action not capturing the guest
+    PvPanicRestartUnsupported,
+    /// A crash policy is set along with a pvpanic policy
+    OnCrashWithPvPanicPolicy,
+    /// The coredump crash action is missing its destination
+    OnCrashCoredumpDirMissing,
+    /// The coredump crash action is not supported by this build
+    OnCrashCoredumpUnsupported,
+    /// The maximum backoff of the crash policy is lower than the backoff
+    OnCrashInvalidBackoff,
+    /// The watchdog escalation requires the watchdog reset action
+    WatchdogEscalationWithoutReset,
+    /// Number of console ports out of range
+    InvalidConsoleMaxPorts(u32),
+    /// Multiple ports are only supported by the virtio-console device
+    SerialMaxPortsUnsupported,
+    /// Console ports require a multiport virtio-console device
+    ConsolePortsWithoutMultiport,
+    /// More console ports than the virtio-console device can hold
+    TooManyConsolePorts(usize, u32),
+    /// Console port name unusable as the name of its socket
+    InvalidConsolePortName(String),
+    /// CPU affinity set for a vCPU beyond the maximum number of vCPUs
+    InvalidCpuAffinityVcpu(u8),
+    /// Removed vCPUs not leaving room for the boot vCPUs
+    InvalidRemovedVcpus,
+    /// Efficiency core beyond the maximum number of vCPUs
+    InvalidEfficiencyCore(u8),
+    /// Hybrid core types are only supported on x86_64
+    EfficiencyCoresUnsupported,
+    /// PMU events listed while the PMU is disabled
+    PmuEventsWithoutPmu,
+    /// Empty list of PMU events
+    EmptyPmuEvents,
+    /// PMU control is only supported on aarch64
+    PmuControlUnsupported,
+    /// Hyper-V features listed while the KVM Hyper-V emulation is disabled
+    KvmHypervFeaturesWithoutKvmHyperv,
+    /// Synthetic timers require the SynIC
+    StimerWithoutSynic,
+    /// Unknown CPU model
+    UnknownCpuModel(String),
+    /// Unknown CPUID feature
+    UnknownCpuidFeature(String),
+    /// CPU models and CPUID masking are only supported on x86_64
+    CpuModelUnsupported,
+    /// CPU bandwidth limit out of the range accepted by the kernel
+    InvalidCpuBandwidth(CpuBandwidth),
+    /// Cgroup path escaping the cgroup hierarchy, or naming its root
+    InvalidCgroupPath(PathBuf),
+    /// MSR listed more than once in the MSR filter
+    #[cfg(target_arch = "x86_64")]
+    DuplicateMsrFilterEntry(u32),
+    /// UEFI variable store given without a firmware
+    #[cfg(target_arch = "x86_64")]
+    UefiVarsWithoutFirmware,
+    /// Peer-to-peer DMA requested for a device behind the virtual IOMMU
+    P2pDmaWithIommu(PathBuf),
+    /// Peer-to-peer DMA allowed to a device not taking part in it
+    P2pDmaInvalidPeer(String),
+    /// Landlock rules given while Landlock is disabled
+    LandlockRulesWithoutLandlock,
+    /// Both a path and a file descriptor given for the same payload
+    PayloadPathAndFd,
+    /// OOM score adjustment out of the range accepted by the kernel
+    InvalidOomScoreAdj(i32),
+    /// Syscall unknown to the seccomp overrides
+    UnknownSeccompSyscall(String),
+    /// Syscall both allowed and denied, or device overridden twice
+    InvalidSeccompOverride(String),
+    /// IGVM file given along with a firmware, kernel or initramfs
+    #[cfg(feature = "sev_snp")]
+    IgvmWithOtherPayload,
+    /// IGVM file given while SEV-SNP is disabled
+    #[cfg(feature = "sev_snp")]
+    IgvmWithoutSevSnp,
+    /// Host data given without any IGVM file
+    #[cfg(feature = "sev_snp")]
+    HostDataWithoutIgvm,
+    /// Host data not made of 32 bytes in hexadecimal
+    #[cfg(feature = "sev_snp")]
+    InvalidHostData(String),
+    /// Device or memory zone changed on restore missing from the snapshot
+    UnknownRestoredId(String),
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -194,6 +453,19 @@ impl fmt::Display for ValidationError {
             KernelMissing => write!(f, "No kernel specified"),
             ConsoleFileMissing => write!(f, "Path missing when using file console mode"),
             ConsoleSocketPathMissing => write!(f, "Path missing when using socket console mode"),
+            DebugConsoleModeUnsupported => {
+                write!(
+                    f,
+                    "The debug console only supports the tty, file and socket modes"
+                )
+            }
+            ConsoleTcpAddressMissing => write!(f, "Address missing when using tcp console mode"),
+            ConsoleTcpUnsupported => {
+                write!(f, "Console mode tcp is only supported by the serial port")
+            }
+            ConsoleTelnetWithoutTcp => {
+                write!(f, "Telnet negotiation requires the tcp console mode")
+            }
             CpusMaxLowerThanBoot => write!(f, "Max CPUs lower than boot CPUs"),
             DiskSocketAndPath => write!(f, "Disk path and vhost socket both provided"),
             VhostUserRequiresSharedMemory => {
@@ -203,6 +475,17 @@ impl fmt::Display for ValidationError {
                 )
             }
             VhostUserMissingSocket => write!(f, "No socket provided when using vhost-user"),
+            VhostUserDiskBackend => {
+                write!(f, "A disk backend cannot be selected for a vhost-user disk")
+            }
+            UnknownDiskBackend(backend) => write!(f, "Unknown disk backend: {backend}"),
+            InvalidIoPriorityLevel(level) => write!(
+                f,
+                "I/O priority level {level} is greater than {MAX_IO_PRIORITY_LEVEL}"
+            ),
+            VhostUserIoPriority => {
+                write!(f, "An I/O priority cannot be set on a vhost-user disk")
+            }
             IommuUnsupported => write!(f, "Using an IOMMU without PCI support is unsupported"),
             VfioUnsupported => write!(f, "Using VFIO without PCI support is unsupported"),
             CpuTopologyZeroPart => write!(f, "No part of the CPU topology can be zero"),
@@ -210,8 +493,6 @@ impl fmt::Display for ValidationError {
                 f,
                 "Product of CPU topology parts does not match maximum vCPUs"
             ),
-            #[cfg(target_arch = "aarch64")]
-            CpuTopologyDiesPerPackage => write!(f, "Dies per package must be 1"),
             VnetQueueLowerThan2 => write!(f, "Number of queues to virtio_net less than 2"),
             VnetQueueFdMismatch => write!(
                 f,
@@ -222,6 +503,32 @@ impl fmt::Display for ValidationError {
                 f,
                 "\"offload_tso\" and \"offload_ufo\" depend on \"offload_tso\""
             ),
+            InvalidNotificationCoalescing => write!(
+                f,
+                "\"coalesce_buffers\" and \"coalesce_timeout\" cannot be zero"
+            ),
+            VhostUserNotification => write!(
+                f,
+                "The notifications of a vhost-user device are handled by its backend"
+            ),
+            VhostUserEventLoop => write!(
+                f,
+                "The events of a vhost-user device are waited for by its backend"
+            ),
+            MmioPciOption => write!(
+                f,
+                "A virtio-mmio device can't be behind the IOMMU nor use PCI options \
+                (pci_segment, pci_root_port, PCI IDs)"
+            ),
+            MmioVhostUser => write!(
+                f,
+                "The virtio-mmio transport is not supported by vhost-user devices"
+            ),
+            XdpWithOtherBackend => write!(
+                f,
+                "A network device using AF_XDP can't also use a TAP interface \
+                (tap, fd) or vhost-user"
+            ),
             HugePageSizeWithoutHugePages => {
                 write!(f, "Huge page size specified but huge pages not enabled")
             }
@@ -236,6 +543,25 @@ impl fmt::Display for ValidationError {
             TdxFirmwareMissing => {
                 write!(f, "No TDX firmware specified")
             }
+            #[cfg(feature = "tdx")]
+            TdxQuoteGenerationWithoutTdx => {
+                write!(f, "Quote generation socket specified but TDX not enabled")
+            }
+            #[cfg(feature = "tdx")]
+            TdxS3 => {
+                write!(f, "The S3 sleep state is not supported with TDX")
+            }
+            #[cfg(target_arch = "x86_64")]
+            HibernateActionWithoutS4 => {
+                write!(f, "Hibernation action specified but S4 not enabled")
+            }
+            #[cfg(target_arch = "x86_64")]
+            InterruptRemappingMultipleSegments => {
+                write!(
+                    f,
+                    "Interrupt remapping isn't supported with multiple PCI segments"
+                )
+            }
             TooManyQueues => {
                 write!(f, "Number of vCPUs is insufficient for number of queues")
             }
@@ -266,6 +592,26 @@ impl fmt::Display for ValidationError {
                     "Ballon size ({balloon_size:?}) greater than RAM ({ram_size})"
                 )
             }
+            InvalidBalloonReclaim => {
+                write!(
+                    f,
+                    "Balloon release_above requires a lower reclaim_below, and reclaim_step cannot be 0"
+                )
+            }
+            InvalidBalloonHeteroPressure => {
+                write!(
+                    f,
+                    "Balloon hetero_pressure_high requires heterogeneous_memory, a percentage \
+                    higher than hetero_pressure_low, and hetero_step cannot be 0"
+                )
+            }
+            InvalidMemoryZoneOvercommit(id) => {
+                write!(
+                    f,
+                    "Memory zone {id} with overcommit requires the balloon reclaim_below \
+                    option, and cannot use huge pages"
+                )
+            }
             OnIommuSegment(pci_segment) => {
                 write!(
                     f,
@@ -303,6 +649,284 @@ impl fmt::Display for ValidationError {
             DefaultPciSegmentInvalidNode(u1) => {
                 write!(f, "Default PCI segment assigned to non-zero NUMA node {u1}")
             }
+            DuplicatePciSegmentConfig(pci_segment) => {
+                write!(f, "PCI segment {pci_segment} configured more than once")
+            }
+            InvalidPciSegmentBusRange(pci_segment, start, end) => {
+                write!(
+                    f,
+                    "Invalid bus range {start}-{end} for PCI segment {pci_segment}"
+                )
+            }
+            TooManyPciBuses(n) => {
+                write!(
+                    f,
+                    "Number of PCI buses ({n}) over the maximum of {MAX_NUM_PCI_BUSES}"
+                )
+            }
+            TooManyPciRootPorts(pci_segment) => {
+                write!(
+                    f,
+                    "Not enough buses on PCI segment {pci_segment} for its root ports"
+                )
+            }
+            UnknownPciRootPort(id) => {
+                write!(f, "Device attached to unknown PCI root port {id}")
+            }
+            PciRootPortReused(id) => {
+                write!(f, "PCI root port {id} used by multiple devices")
+            }
+            InvalidFsCacheSize(s) => {
+                write!(f, "virtio-fs DAX cache size is not a power of 2: {s}")
+            }
+            DuplicatePmemFile(p) => {
+                write!(f, "Persistent memory file used by multiple devices: {p}")
+            }
+            PmemDiscardWithDiscardWrites => {
+                write!(
+                    f,
+                    "\"discard\" is incompatible with \"discard_writes\" for persistent memory"
+                )
+            }
+            IvshmemPathOrSocket => {
+                write!(f, "An ivshmem device requires either a path or a socket")
+            }
+            InvalidIvshmemSize(s) => {
+                write!(f, "ivshmem memory size is not a power of 2: {s}")
+            }
+            IvshmemSocketWithSize => {
+                write!(
+                    f,
+                    "The size of the ivshmem memory is set by the server, not by \"size\""
+                )
+            }
+            InvalidIvshmemVectors(v) => {
+                write!(
+                    f,
+                    "Number of ivshmem vectors must be between 1 and {}: {v}",
+                    devices::ivshmem::IVSHMEM_MAX_VECTORS
+                )
+            }
+            InvalidVsockSiblingCid(c) => {
+                write!(f, "Invalid or duplicated vsock sibling CID: {c}")
+            }
+            DuplicateVsockListenerPort(p) => {
+                write!(f, "vsock port bound to multiple sockets: {p}")
+            }
+            PvPanicPolicyWithoutDevice => {
+                write!(
+                    f,
+                    "A pvpanic policy requires the pvpanic device or the watchdog panic action"
+                )
+            }
+            GuestAgentWithoutVsock => {
+                write!(f, "The guest agent channel requires a vsock device")
+            }
+            PvPanicCoredumpPathMissing => {
+                write!(f, "The pvpanic coredump action requires a coredump_path")
+            }
+            PvPanicCoredumpUnsupported => {
+                write!(
+                    f,
+                    "The pvpanic coredump and kdump actions require the guest_debug feature on x86_64"
+                )
+            }
+            PvPanicKdumpDirMissing => {
+                write!(f, "The pvpanic kdump action requires a kdump_dir")
+            }
+            PvPanicRestartUnsupported => {
+                write!(
+                    f,
+                    "The pvpanic restart option only applies to the coredump and kdump actions"
+                )
+            }
+            OnCrashWithPvPanicPolicy => {
+                write!(f, "A crash policy cannot be combined with a pvpanic policy")
+            }
+            OnCrashCoredumpDirMissing => {
+                write!(
+                    f,
+                    "The coredump-and-restart crash action requires a coredump_dir"
+                )
+            }
+            OnCrashCoredumpUnsupported => {
+                write!(
+                    f,
+                    "The coredump-and-restart crash action requires the guest_debug feature on x86_64"
+                )
+            }
+            OnCrashInvalidBackoff => {
+                write!(
+                    f,
+                    "The crash policy max_backoff cannot be lower than backoff"
+                )
+            }
+            WatchdogEscalationWithoutReset => {
+                write!(
+                    f,
+                    "The watchdog escalation requires the watchdog with the reset action"
+                )
+            }
+            InvalidConsoleMaxPorts(n) => {
+                write!(
+                    f,
+                    "Console max_ports must be between 1 and {VIRTIO_CONSOLE_MAX_PORTS}: {n}"
+                )
+            }
+            SerialMaxPortsUnsupported => {
+                write!(f, "max_ports is only supported by the console device")
+            }
+            ConsolePortsWithoutMultiport => {
+                write!(
+                    f,
+                    "Console ports require the console device with max_ports greater than 1"
+                )
+            }
+            TooManyConsolePorts(ports, max_ports) => {
+                write!(
+                    f,
+                    "Too many console ports ({ports}) for a console with max_ports={max_ports}"
+                )
+            }
+            InvalidConsolePortName(name) => {
+                write!(f, "Console port name {name:?} can't name its socket")
+            }
+            InvalidCpuAffinityVcpu(v) => {
+                write!(f, "CPU affinity set for vCPU {v} beyond the maximum vCPUs")
+            }
+            InvalidRemovedVcpus => {
+                write!(f, "Removed vCPUs leave fewer vCPUs than the boot ones")
+            }
+            InvalidEfficiencyCore(v) => {
+                write!(f, "Efficiency core {v} beyond the maximum vCPUs")
+            }
+            EfficiencyCoresUnsupported => {
+                write!(f, "Efficiency cores are only supported on x86_64")
+            }
+            PmuEventsWithoutPmu => {
+                write!(f, "PMU events specified but the PMU is disabled")
+            }
+            EmptyPmuEvents => {
+                write!(f, "The list of PMU events can't be empty")
+            }
+            PmuControlUnsupported => {
+                write!(
+                    f,
+                    "Disabling or filtering the PMU is only supported on aarch64"
+                )
+            }
+            KvmHypervFeaturesWithoutKvmHyperv => {
+                write!(
+                    f,
+                    "Hyper-V features specified but the KVM Hyper-V emulation is disabled"
+                )
+            }
+            StimerWithoutSynic => {
+                write!(f, "The stimer Hyper-V feature requires the synic one")
+            }
+            UnknownCpuModel(s) => {
+                write!(f, "Unknown CPU model: {s}")
+            }
+            UnknownCpuidFeature(s) => {
+                write!(f, "Unknown CPUID feature: {s}")
+            }
+            CpuModelUnsupported => {
+                write!(
+                    f,
+                    "CPU models and CPUID features masking are only supported on x86_64"
+                )
+            }
+            InvalidCpuBandwidth(b) => {
+                write!(
+                    f,
+                    "Invalid CPU bandwidth {}:{}, the quota must be at least 1000us and the period between 1000us and 1s",
+                    b.quota, b.period
+                )
+            }
+            InvalidCgroupPath(p) => {
+                write!(
+                    f,
+                    "Invalid cgroup path {}, it must name a cgroup below /sys/fs/cgroup, without any `.` or `..` component",
+                    p.display()
+                )
+            }
+            #[cfg(target_arch = "x86_64")]
+            DuplicateMsrFilterEntry(msr) => {
+                write!(f, "MSR {msr:#x} listed more than once in the MSR filter")
+            }
+            #[cfg(target_arch = "x86_64")]
+            UefiVarsWithoutFirmware => {
+                write!(f, "The UEFI variable store requires booting a firmware")
+            }
+            P2pDmaWithIommu(p) => {
+                write!(
+                    f,
+                    "Peer-to-peer DMA is not supported for device {} placed behind the virtual IOMMU",
+                    p.display()
+                )
+            }
+            P2pDmaInvalidPeer(id) => {
+                write!(
+                    f,
+                    "Peer-to-peer DMA allowed to device {id} which doesn't have the p2p option"
+                )
+            }
+            LandlockRulesWithoutLandlock => {
+                write!(f, "Landlock rules require Landlock to be enabled")
+            }
+            PayloadPathAndFd => {
+                write!(
+                    f,
+                    "The firmware, kernel and initramfs can't be given as both a path and a file descriptor"
+                )
+            }
+            InvalidOomScoreAdj(adj) => {
+                write!(
+                    f,
+                    "Invalid OOM score adjustment {adj}, it must be between -1000 and 1000"
+                )
+            }
+            UnknownSeccompSyscall(s) => {
+                write!(f, "Syscall {s} cannot be used to override a seccomp filter")
+            }
+            InvalidSeccompOverride(id) => {
+                write!(
+                    f,
+                    "Seccomp filter of device {id} overridden more than once, or with a \
+                    syscall both allowed and denied"
+                )
+            }
+            #[cfg(feature = "sev_snp")]
+            IgvmWithOtherPayload => {
+                write!(
+                    f,
+                    "An IGVM file can't be given along with a firmware, kernel or initramfs"
+                )
+            }
+            #[cfg(feature = "sev_snp")]
+            IgvmWithoutSevSnp => {
+                write!(
+                    f,
+                    "Booting from an IGVM file requires SEV-SNP to be enabled"
+                )
+            }
+            #[cfg(feature = "sev_snp")]
+            HostDataWithoutIgvm => {
+                write!(f, "Host data can only be given along with an IGVM file")
+            }
+            #[cfg(feature = "sev_snp")]
+            InvalidHostData(s) => {
+                write!(
+                    f,
+                    "Invalid host data {s}, it must be made of 64 hexadecimal digits"
+                )
+            }
+            UnknownRestoredId(id) => {
+                write!(
+                    f,
+                    "Identifier {id} to change on restore not found in the snapshot"
+                )
+            }
         }
     }
 }
@@ -315,15 +939,40 @@ impl fmt::Display for Error {
             ParseConsoleInvalidModeGiven => {
                 write!(f, "Error parsing --console: invalid console mode given")
             }
+            ParseDebugConsole(o) => write!(f, "Error parsing --debug-console: {o}"),
+            ParseDebugConsoleInvalidModeGiven => {
+                write!(
+                    f,
+                    "Error parsing --debug-console: invalid debug console mode given"
+                )
+            }
             ParseCpus(o) => write!(f, "Error parsing --cpus: {o}"),
             InvalidCpuFeatures(o) => write!(f, "Invalid feature in --cpus features list: {o}"),
+            InvalidHypervFeature(o) => {
+                write!(f, "Invalid feature in --cpus kvm_hyperv_features list: {o}")
+            }
             ParseDevice(o) => write!(f, "Error parsing --device: {o}"),
             ParseDevicePathMissing => write!(f, "Error parsing --device: path missing"),
+            ParseVf(o) => write!(f, "Error parsing virtual function: {o}"),
+            ParseVfPfMissing => {
+                write!(
+                    f,
+                    "Error parsing virtual function: physical function missing"
+                )
+            }
+            ParseVfIndexMissing => write!(f, "Error parsing virtual function: index missing"),
+            ParseMdev(o) => write!(f, "Error parsing mediated device: {o}"),
+            ParseMdevParentMissing => write!(f, "Error parsing mediated device: parent missing"),
+            ParseMdevTypeMissing => write!(f, "Error parsing mediated device: type missing"),
             ParseFileSystem(o) => write!(f, "Error parsing --fs: {o}"),
             ParseFsSockMissing => write!(f, "Error parsing --fs: socket missing"),
             ParseFsTagMissing => write!(f, "Error parsing --fs: tag missing"),
             ParsePersistentMemory(o) => write!(f, "Error parsing --pmem: {o}"),
             ParsePmemFileMissing => write!(f, "Error parsing --pmem: file missing"),
+            ParseIvshmem(o) => write!(f, "Error parsing --ivshmem: {o}"),
+            ParseIvshmemBackend => {
+                write!(f, "Error parsing --ivshmem: either path or socket required")
+            }
             ParseVsock(o) => write!(f, "Error parsing --vsock: {o}"),
             ParseVsockCidMissing => write!(f, "Error parsing --vsock: cid missing"),
             ParseVsockSockMissing => write!(f, "Error parsing --vsock: socket missing"),
@@ -339,6 +988,18 @@ impl fmt::Display for Error {
             ParseSgxEpc(o) => write!(f, "Error parsing --sgx-epc: {o}"),
             #[cfg(target_arch = "x86_64")]
             ParseSgxEpcIdMissing => write!(f, "Error parsing --sgx-epc: id missing"),
+            #[cfg(target_arch = "x86_64")]
+            ParseMsrFilter(o) => write!(f, "Error parsing --msr-filter: {o}"),
+            #[cfg(target_arch = "x86_64")]
+            InvalidMsrFilterDefaultAction(o) => {
+                write!(f, "Error parsing --msr-filter: invalid default action {o}")
+            }
+            #[cfg(target_arch = "x86_64")]
+            InvalidMsrIndex(o) => write!(f, "Error parsing --msr-filter: invalid MSR {o}"),
+            #[cfg(target_arch = "x86_64")]
+            ParseUefiVars(o) => write!(f, "Error parsing --uefi-vars: {o}"),
+            #[cfg(target_arch = "x86_64")]
+            ParseUefiVarsPathMissing => write!(f, "Error parsing --uefi-vars: path missing"),
             ParseNuma(o) => write!(f, "Error parsing --numa: {o}"),
             ParseRestoreSourceUrlMissing => {
                 write!(f, "Error parsing --restore: source_url missing")
@@ -355,10 +1016,37 @@ impl fmt::Display for Error {
             #[cfg(feature = "tdx")]
             FirmwarePathMissing => write!(f, "TDX firmware missing"),
             ParsePlatform(o) => write!(f, "Error parsing --platform: {o}"),
+            ParsePciSegment(o) => write!(f, "Error parsing --pci-segment: {o}"),
+            ParsePciSegmentIdMissing => {
+                write!(f, "Error parsing --pci-segment: pci_segment missing")
+            }
+            ParsePciRootPort(o) => write!(f, "Error parsing --pci-root-port: {o}"),
+            ParsePciRootPortIdMissing => {
+                write!(f, "Error parsing --pci-root-port: id missing")
+            }
             ParseVdpa(o) => write!(f, "Error parsing --vdpa: {o}"),
             ParseVdpaPathMissing => write!(f, "Error parsing --vdpa: path missing"),
             ParseTpm(o) => write!(f, "Error parsing --tpm: {o}"),
             ParseTpmPathMissing => write!(f, "Error parsing --tpm: path missing"),
+            ParseAcpiTable(o) => write!(f, "Error parsing --acpi-table: {o}"),
+            ParseAcpiTablePathMissing => write!(f, "Error parsing --acpi-table: path missing"),
+            ParseWatchdogAction(e) => write!(f, "Error parsing --watchdog-action: {e:?}"),
+            ParsePvPanicPolicy(o) => write!(f, "Error parsing --pvpanic-policy: {o}"),
+            ParseOnCrash(o) => write!(f, "Error parsing --on-crash: {o}"),
+            ParseWatchdogEscalation(o) => write!(f, "Error parsing --watchdog-escalation: {o}"),
+            ParseConsolePort(o) => write!(f, "Error parsing --console-port: {o}"),
+            ParseGuestAgent(o) => write!(f, "Error parsing --guest-agent: {o}"),
+            ParseCgroup(o) => write!(f, "Error parsing --cgroup: {o}"),
+            ParseCgroupIoMax(e) => write!(f, "Error parsing --cgroup io_max: {e:?}"),
+            ParseProcessLimits(o) => write!(f, "Error parsing --process-limits: {o}"),
+            ParseSeccompOverride(o) => write!(f, "Error parsing --seccomp-override: {o}"),
+            ParseSeccompOverrideIdMissing => {
+                write!(f, "Error parsing --seccomp-override: id missing")
+            }
+            ParseLandlockRules(o) => write!(f, "Error parsing --landlock-rules: {o}"),
+            ParseLandlockMissingFields => {
+                write!(f, "Error parsing --landlock-rules: path or access missing")
+            }
         }
     }
 }
@@ -387,21 +1075,45 @@ pub struct VmParams<'a> {
     pub balloon: Option<&'a str>,
     pub fs: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
+    pub ivshmem: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
+    pub console_ports: Option<Vec<&'a str>>,
+    pub debug_console: Option<&'a str>,
     pub devices: Option<Vec<&'a str>>,
     pub user_devices: Option<Vec<&'a str>>,
     pub vdpa: Option<Vec<&'a str>>,
     pub vsock: Option<&'a str>,
+    pub guest_agent: Option<&'a str>,
     pub pvpanic: bool,
+    pub pvpanic_policy: Option<&'a str>,
+    pub on_crash: Option<&'a str>,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<&'a str>>,
+    #[cfg(target_arch = "x86_64")]
+    pub msr_filter: Option<&'a str>,
+    #[cfg(target_arch = "x86_64")]
+    pub uefi_vars: Option<&'a str>,
     pub numa: Option<Vec<&'a str>>,
     pub watchdog: bool,
+    pub watchdog_action: Option<&'a str>,
+    pub watchdog_escalation: Option<&'a str>,
     #[cfg(feature = "guest_debug")]
     pub gdb: bool,
     pub platform: Option<&'a str>,
+    pub pci_segments: Option<Vec<&'a str>>,
+    pub pci_root_ports: Option<Vec<&'a str>>,
     pub tpm: Option<&'a str>,
+    pub acpi_tables: Option<Vec<&'a str>>,
+    pub cgroup: Option<&'a str>,
+    pub process_limits: Option<&'a str>,
+    pub seccomp_overrides: Option<Vec<&'a str>>,
+    pub landlock_enable: bool,
+    pub landlock_rules: Option<Vec<&'a str>>,
+    #[cfg(feature = "sev_snp")]
+    pub igvm: Option<&'a str>,
+    #[cfg(feature = "sev_snp")]
+    pub host_data: Option<&'a str>,
 }
 
 impl<'a> VmParams<'a> {
@@ -425,6 +1137,7 @@ impl<'a> VmParams<'a> {
             .get_many::<String>("net")
             .map(|x| x.map(|y| y as &str).collect());
         let console = args.get_one::<String>("console").unwrap();
+        let debug_console = args.get_one::<String>("debug-console").map(|x| x as &str);
         let balloon = args.get_one::<String>("balloon").map(|x| x as &str);
         let fs: Option<Vec<&str>> = args
             .get_many::<String>("fs")
@@ -432,6 +1145,12 @@ impl<'a> VmParams<'a> {
         let pmem: Option<Vec<&str>> = args
             .get_many::<String>("pmem")
             .map(|x| x.map(|y| y as &str).collect());
+        let ivshmem: Option<Vec<&str>> = args
+            .get_many::<String>("ivshmem")
+            .map(|x| x.map(|y| y as &str).collect());
+        let console_ports: Option<Vec<&str>> = args
+            .get_many::<String>("console-port")
+            .map(|x| x.map(|y| y as &str).collect());
         let devices: Option<Vec<&str>> = args
             .get_many::<String>("device")
             .map(|x| x.map(|y| y as &str).collect());
@@ -442,19 +1161,53 @@ impl<'a> VmParams<'a> {
             .get_many::<String>("vdpa")
             .map(|x| x.map(|y| y as &str).collect());
         let vsock: Option<&str> = args.get_one::<String>("vsock").map(|x| x as &str);
+        let guest_agent: Option<&str> = args.get_one::<String>("guest-agent").map(|x| x as &str);
         let pvpanic = args.get_flag("pvpanic");
+        let pvpanic_policy = args.get_one::<String>("pvpanic-policy").map(|x| x as &str);
+        let on_crash = args.get_one::<String>("on-crash").map(|x| x as &str);
         #[cfg(target_arch = "x86_64")]
         let sgx_epc: Option<Vec<&str>> = args
             .get_many::<String>("sgx-epc")
             .map(|x| x.map(|y| y as &str).collect());
+        #[cfg(target_arch = "x86_64")]
+        let msr_filter = args.get_one::<String>("msr-filter").map(|x| x as &str);
+        #[cfg(target_arch = "x86_64")]
+        let uefi_vars = args.get_one::<String>("uefi-vars").map(|x| x as &str);
         let numa: Option<Vec<&str>> = args
             .get_many::<String>("numa")
             .map(|x| x.map(|y| y as &str).collect());
         let watchdog = args.get_flag("watchdog");
+        let watchdog_action = args.get_one::<String>("watchdog-action").map(|x| x as &str);
+        let watchdog_escalation = args
+            .get_one::<String>("watchdog-escalation")
+            .map(|x| x as &str);
         let platform = args.get_one::<String>("platform").map(|x| x as &str);
+        let pci_segments: Option<Vec<&str>> = args
+            .get_many::<String>("pci-segment")
+            .map(|x| x.map(|y| y as &str).collect());
+        let pci_root_ports: Option<Vec<&str>> = args
+            .get_many::<String>("pci-root-port")
+            .map(|x| x.map(|y| y as &str).collect());
         #[cfg(feature = "guest_debug")]
         let gdb = args.contains_id("gdb");
         let tpm: Option<&str> = args.get_one::<String>("tpm").map(|x| x as &str);
+        let acpi_tables: Option<Vec<&str>> = args
+            .get_many::<String>("acpi-table")
+            .map(|x| x.map(|y| y as &str).collect());
+        let cgroup: Option<&str> = args.get_one::<String>("cgroup").map(|x| x as &str);
+        let process_limits: Option<&str> =
+            args.get_one::<String>("process-limits").map(|x| x as &str);
+        let seccomp_overrides: Option<Vec<&str>> = args
+            .get_many::<String>("seccomp-override")
+            .map(|x| x.map(|y| y as &str).collect());
+        let landlock_enable = args.get_flag("landlock");
+        let landlock_rules: Option<Vec<&str>> = args
+            .get_many::<String>("landlock-rules")
+            .map(|x| x.map(|y| y as &str).collect());
+        #[cfg(feature = "sev_snp")]
+        let igvm = args.get_one::<String>("igvm").map(|x| x as &str);
+        #[cfg(feature = "sev_snp")]
+        let host_data = args.get_one::<String>("host-data").map(|x| x as &str);
         VmParams {
             cpus,
             memory,
@@ -469,21 +1222,45 @@ impl<'a> VmParams<'a> {
             balloon,
             fs,
             pmem,
+            ivshmem,
             serial,
             console,
+            console_ports,
+            debug_console,
             devices,
             user_devices,
             vdpa,
             vsock,
+            guest_agent,
             pvpanic,
+            pvpanic_policy,
+            on_crash,
             #[cfg(target_arch = "x86_64")]
             sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars,
             numa,
             watchdog,
+            watchdog_action,
+            watchdog_escalation,
             #[cfg(feature = "guest_debug")]
             gdb,
             platform,
+            pci_segments,
+            pci_root_ports,
             tpm,
+            acpi_tables,
+            cgroup,
+            process_limits,
+            seccomp_overrides,
+            landlock_enable,
+            landlock_rules,
+            #[cfg(feature = "sev_snp")]
+            igvm,
+            #[cfg(feature = "sev_snp")]
+            host_data,
         }
     }
 }
@@ -505,7 +1282,80 @@ impl FromStr for HotplugMethod {
     }
 }
 
-pub enum CpuTopologyParseError {
+#[derive(Debug)]
+pub enum ParseLandlockAccessError {
+    InvalidValue(String),
+}
+
+impl FromStr for LandlockAccess {
+    type Err = ParseLandlockAccessError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "r" => Ok(LandlockAccess::Read),
+            "w" => Ok(LandlockAccess::Write),
+            "rw" => Ok(LandlockAccess::ReadWrite),
+            _ => Err(ParseLandlockAccessError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseHibernateActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for HibernateAction {
+    type Err = ParseHibernateActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "exit" => Ok(HibernateAction::Exit),
+            "shutdown" => Ok(HibernateAction::Shutdown),
+            "reboot" => Ok(HibernateAction::Reboot),
+            _ => Err(ParseHibernateActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParsePvPanicActionError {
+    InvalidValue(String),
+}
+
+#[derive(Debug)]
+pub enum ParseOnCrashActionError {
+    InvalidValue(String),
+}
+
+impl FromStr for OnCrashAction {
+    type Err = ParseOnCrashActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "restart" => Ok(OnCrashAction::Restart),
+            "preserve" => Ok(OnCrashAction::Preserve),
+            "coredump-and-restart" => Ok(OnCrashAction::CoredumpAndRestart),
+            _ => Err(ParseOnCrashActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for PvPanicAction {
+    type Err = ParsePvPanicActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pause" => Ok(PvPanicAction::Pause),
+            "coredump" => Ok(PvPanicAction::Coredump),
+            "restart" => Ok(PvPanicAction::Restart),
+            "kdump" => Ok(PvPanicAction::Kdump),
+            _ => Err(ParsePvPanicActionError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+pub enum CpuTopologyParseError {
     InvalidValue(String),
 }
 
@@ -538,6 +1388,99 @@ impl FromStr for CpuTopology {
     }
 }
 
+pub enum CpuBandwidthParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for CpuBandwidth {
+    type Err = CpuBandwidthParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+
+        if parts.len() != 2 {
+            return Err(Self::Err::InvalidValue(s.to_owned()));
+        }
+
+        Ok(CpuBandwidth {
+            quota: parts[0]
+                .parse()
+                .map_err(|_| Self::Err::InvalidValue(s.to_owned()))?,
+            period: parts[1]
+                .parse()
+                .map_err(|_| Self::Err::InvalidValue(s.to_owned()))?,
+        })
+    }
+}
+
+impl TupleValue for CpuBandwidth {
+    fn parse_value(input: &str) -> std::result::Result<Self, TupleError> {
+        CpuBandwidth::from_str(input).map_err(|_| TupleError::InvalidValue(input.to_owned()))
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseIoMaxError {
+    InvalidValue(String),
+}
+
+impl FromStr for IoMaxConfig {
+    type Err = ParseIoMaxError;
+
+    // Same format as the io.max file of the cgroup, such as
+    // "8:16 rbps=1048576 wiops=100", the limits left out being unlimited.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || ParseIoMaxError::InvalidValue(s.to_owned());
+        let mut fields = s.split_whitespace();
+
+        let (major, minor) = fields
+            .next()
+            .and_then(|d| d.split_once(':'))
+            .ok_or_else(invalid)?;
+        let mut io_max = IoMaxConfig {
+            major: major.parse().map_err(|_| invalid())?,
+            minor: minor.parse().map_err(|_| invalid())?,
+            ..Default::default()
+        };
+
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or_else(invalid)?;
+            let value = if value == "max" {
+                None
+            } else {
+                Some(value.parse().map_err(|_| invalid())?)
+            };
+            match key {
+                "rbps" => io_max.rbps = value,
+                "wbps" => io_max.wbps = value,
+                "riops" => io_max.riops = value,
+                "wiops" => io_max.wiops = value,
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(io_max)
+    }
+}
+
+// Limits enforced by the kernel on the values written to cpu.max
+const CPU_BANDWIDTH_MIN_QUOTA: u64 = 1000;
+const CPU_BANDWIDTH_MIN_PERIOD: u64 = 1000;
+const CPU_BANDWIDTH_MAX_PERIOD: u64 = 1_000_000;
+
+impl CpuBandwidth {
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.quota < CPU_BANDWIDTH_MIN_QUOTA
+            || self.period < CPU_BANDWIDTH_MIN_PERIOD
+            || self.period > CPU_BANDWIDTH_MAX_PERIOD
+        {
+            return Err(ValidationError::InvalidCpuBandwidth(*self));
+        }
+
+        Ok(())
+    }
+}
+
 impl CpusConfig {
     pub fn parse(cpus: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -546,8 +1489,18 @@ impl CpusConfig {
             .add("max")
             .add("topology")
             .add("kvm_hyperv")
+            .add("kvm_hyperv_features")
             .add("max_phys_bits")
             .add("affinity")
+            .add("efficiency_cores")
+            .add("pmu")
+            .add("pmu_events")
+            .add("nested")
+            .add("model")
+            .add("cpuid_add")
+            .add("cpuid_remove")
+            .add("cpu_max")
+            .add("vcpu_max")
             .add("features");
         parser.parse(cpus).map_err(Error::ParseCpus)?;
 
@@ -565,6 +1518,21 @@ impl CpusConfig {
             .map_err(Error::ParseCpus)?
             .unwrap_or(Toggle(false))
             .0;
+        let kvm_hyperv_features = parser
+            .convert::<StringList>("kvm_hyperv_features")
+            .map_err(Error::ParseCpus)?
+            .map(|v| {
+                v.0.into_iter()
+                    .map(|s| match s.as_str() {
+                        "synic" => Ok(HypervFeature::Synic),
+                        "stimer" => Ok(HypervFeature::Stimer),
+                        "reenlightenment" => Ok(HypervFeature::Reenlightenment),
+                        "tlbflush" => Ok(HypervFeature::Tlbflush),
+                        _ => Err(Error::InvalidHypervFeature(s)),
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
         let max_phys_bits = parser
             .convert::<u8>("max_phys_bits")
             .map_err(Error::ParseCpus)?
@@ -580,6 +1548,35 @@ impl CpusConfig {
                     })
                     .collect()
             });
+        let efficiency_cores = parser
+            .convert::<IntegerList>("efficiency_cores")
+            .map_err(Error::ParseCpus)?
+            .map(|v| v.0.iter().map(|e| *e as u8).collect());
+        let pmu = parser
+            .convert::<Toggle>("pmu")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(true))
+            .0;
+        let pmu_events = parser
+            .convert::<IntegerList>("pmu_events")
+            .map_err(Error::ParseCpus)?
+            .map(|v| v.0.iter().map(|e| *e as u16).collect());
+        let nested = parser
+            .convert::<Toggle>("nested")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let model = parser.get("model");
+        let cpuid_add = parser
+            .convert::<StringList>("cpuid_add")
+            .map_err(Error::ParseCpus)?
+            .map(|v| v.0);
+        let cpuid_remove = parser
+            .convert::<StringList>("cpuid_remove")
+            .map_err(Error::ParseCpus)?
+            .map(|v| v.0);
+        let cpu_max = parser.convert("cpu_max").map_err(Error::ParseCpus)?;
+        let vcpu_max = parser.convert("vcpu_max").map_err(Error::ParseCpus)?;
         let features_list = parser
             .convert::<StringList>("features")
             .map_err(Error::ParseCpus)?
@@ -607,11 +1604,83 @@ impl CpusConfig {
             max_vcpus,
             topology,
             kvm_hyperv,
+            kvm_hyperv_features,
             max_phys_bits,
             affinity,
+            efficiency_cores,
+            pmu,
+            pmu_events,
+            nested,
+            model,
+            cpuid_add,
+            cpuid_remove,
+            cpu_max,
+            vcpu_max,
             features,
+            removed_vcpus: None,
         })
     }
+
+    /// Record a vCPU removed on its own, or added back, so that the vCPUs
+    /// present keep their ids across a reboot or a restore.
+    pub fn set_vcpu_removed(&mut self, vcpu: u8, removed: bool) {
+        let mut current = self.removed_vcpus.take().unwrap_or_default();
+        current.retain(|v| *v != vcpu);
+        if removed {
+            current.push(vcpu);
+            current.sort_unstable();
+        }
+
+        if !current.is_empty() {
+            self.removed_vcpus = Some(current);
+        }
+    }
+
+    /// Ids of the vCPUs present at boot, skipping the removed ones.
+    pub fn boot_vcpu_ids(&self) -> Vec<u8> {
+        let removed = self.removed_vcpus.as_deref().unwrap_or_default();
+        (0..self.max_vcpus)
+            .filter(|vcpu| !removed.contains(vcpu))
+            .take(self.boot_vcpus as usize)
+            .collect()
+    }
+
+    /// Merge new vCPU affinities into the existing ones. An empty list of
+    /// host CPUs removes the affinity of the vCPU.
+    pub fn update_affinity(&mut self, affinity: &[CpuAffinity]) {
+        let mut current = self.affinity.take().unwrap_or_default();
+        for a in affinity {
+            current.retain(|c| c.vcpu != a.vcpu);
+            if !a.host_cpus.is_empty() {
+                current.push(a.clone());
+            }
+        }
+        current.sort_by_key(|c| c.vcpu);
+
+        if !current.is_empty() {
+            self.affinity = Some(current);
+        }
+    }
+
+    /// Hyper-V enlightenments exposed to the guest, the SynIC and the
+    /// synthetic timers being the default ones.
+    #[cfg(target_arch = "x86_64")]
+    pub fn hyperv_features(&self) -> arch::x86_64::HypervFeatures {
+        match &self.kvm_hyperv_features {
+            Some(features) => arch::x86_64::HypervFeatures {
+                synic: features.contains(&HypervFeature::Synic),
+                stimer: features.contains(&HypervFeature::Stimer),
+                reenlightenment: features.contains(&HypervFeature::Reenlightenment),
+                tlbflush: features.contains(&HypervFeature::Tlbflush),
+            },
+            None => arch::x86_64::HypervFeatures {
+                synic: true,
+                stimer: true,
+                reenlightenment: false,
+                tlbflush: false,
+            },
+        }
+    }
 }
 
 impl PlatformConfig {
@@ -620,11 +1689,19 @@ impl PlatformConfig {
         parser
             .add("num_pci_segments")
             .add("iommu_segments")
+            .add("manufacturer")
+            .add("product")
             .add("serial_number")
             .add("uuid")
             .add("oem_strings");
+        #[cfg(target_arch = "x86_64")]
+        parser
+            .add("s3")
+            .add("s4")
+            .add("hibernate_action")
+            .add("interrupt_remapping");
         #[cfg(feature = "tdx")]
-        parser.add("tdx");
+        parser.add("tdx").add("quote_generation_socket");
         #[cfg(feature = "sev_snp")]
         parser.add("sev_snp");
         parser.parse(platform).map_err(Error::ParsePlatform)?;
@@ -637,6 +1714,10 @@ impl PlatformConfig {
             .convert::<IntegerList>("iommu_segments")
             .map_err(Error::ParsePlatform)?
             .map(|v| v.0.iter().map(|e| *e as u16).collect());
+        let manufacturer = parser
+            .convert("manufacturer")
+            .map_err(Error::ParsePlatform)?;
+        let product = parser.convert("product").map_err(Error::ParsePlatform)?;
         let serial_number = parser
             .convert("serial_number")
             .map_err(Error::ParsePlatform)?;
@@ -645,12 +1726,37 @@ impl PlatformConfig {
             .convert::<StringList>("oem_strings")
             .map_err(Error::ParsePlatform)?
             .map(|v| v.0);
+        #[cfg(target_arch = "x86_64")]
+        let s3 = parser
+            .convert::<Toggle>("s3")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
+        #[cfg(target_arch = "x86_64")]
+        let s4 = parser
+            .convert::<Toggle>("s4")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
+        #[cfg(target_arch = "x86_64")]
+        let hibernate_action = parser
+            .convert("hibernate_action")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or_default();
+        #[cfg(target_arch = "x86_64")]
+        let interrupt_remapping = parser
+            .convert::<Toggle>("interrupt_remapping")
+            .map_err(Error::ParsePlatform)?
+            .unwrap_or(Toggle(false))
+            .0;
         #[cfg(feature = "tdx")]
         let tdx = parser
             .convert::<Toggle>("tdx")
             .map_err(Error::ParsePlatform)?
             .unwrap_or(Toggle(false))
             .0;
+        #[cfg(feature = "tdx")]
+        let quote_generation_socket = parser.get("quote_generation_socket").map(PathBuf::from);
         #[cfg(feature = "sev_snp")]
         let sev_snp = parser
             .convert::<Toggle>("sev_snp")
@@ -660,11 +1766,23 @@ impl PlatformConfig {
         Ok(PlatformConfig {
             num_pci_segments,
             iommu_segments,
+            manufacturer,
+            product,
             serial_number,
             uuid,
             oem_strings,
+            #[cfg(target_arch = "x86_64")]
+            s3,
+            #[cfg(target_arch = "x86_64")]
+            s4,
+            #[cfg(target_arch = "x86_64")]
+            hibernate_action,
+            #[cfg(target_arch = "x86_64")]
+            interrupt_remapping,
             #[cfg(feature = "tdx")]
             tdx,
+            #[cfg(feature = "tdx")]
+            quote_generation_socket,
             #[cfg(feature = "sev_snp")]
             sev_snp,
         })
@@ -685,8 +1803,103 @@ impl PlatformConfig {
             }
         }
 
+        #[cfg(feature = "tdx")]
+        if self.quote_generation_socket.is_some() && !self.tdx {
+            return Err(ValidationError::TdxQuoteGenerationWithoutTdx);
+        }
+
+        #[cfg(feature = "tdx")]
+        if self.s3 && self.tdx {
+            return Err(ValidationError::TdxS3);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if !self.s4 && self.hibernate_action != HibernateAction::default() {
+            return Err(ValidationError::HibernateActionWithoutS4);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if self.interrupt_remapping && self.num_pci_segments > 1 {
+            return Err(ValidationError::InterruptRemappingMultipleSegments);
+        }
+
+        Ok(())
+    }
+}
+
+impl PciSegmentConfig {
+    pub const SYNTAX: &'static str = "PCI segment parameters \
+        \"pci_segment=<segment_id>,start_bus=<first_bus_number>,end_bus=<last_bus_number>\"";
+
+    pub fn parse(pci_segment: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("pci_segment").add("start_bus").add("end_bus");
+        parser.parse(pci_segment).map_err(Error::ParsePciSegment)?;
+
+        let pci_segment = parser
+            .convert::<u16>("pci_segment")
+            .map_err(Error::ParsePciSegment)?
+            .ok_or(Error::ParsePciSegmentIdMissing)?;
+        let start_bus = parser
+            .convert::<u8>("start_bus")
+            .map_err(Error::ParsePciSegment)?
+            .unwrap_or_default();
+        // A single bus by default
+        let end_bus = parser
+            .convert::<u8>("end_bus")
+            .map_err(Error::ParsePciSegment)?
+            .unwrap_or(start_bus);
+
+        Ok(PciSegmentConfig {
+            pci_segment,
+            start_bus,
+            end_bus,
+        })
+    }
+
+    pub fn validate(&self, num_pci_segments: u16) -> ValidationResult<()> {
+        if self.pci_segment >= num_pci_segments {
+            return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+        }
+
+        // The default segment is also reachable through the legacy PCI
+        // configuration mechanism, which only knows about bus 0.
+        if self.end_bus < self.start_bus || (self.pci_segment == 0 && self.start_bus != 0) {
+            return Err(ValidationError::InvalidPciSegmentBusRange(
+                self.pci_segment,
+                self.start_bus,
+                self.end_bus,
+            ));
+        }
+
         Ok(())
     }
+
+    /// Number of buses of the segment.
+    pub fn num_buses(&self) -> u64 {
+        (self.end_bus - self.start_bus) as u64 + 1
+    }
+}
+
+impl PciRootPortConfig {
+    pub const SYNTAX: &'static str = "PCI Express root port parameters \
+        \"id=<root_port_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(pci_root_port: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("pci_segment");
+        parser
+            .parse(pci_root_port)
+            .map_err(Error::ParsePciRootPort)?;
+
+        let id = parser.get("id").ok_or(Error::ParsePciRootPortIdMissing)?;
+        let pci_segment = parser
+            .convert::<u16>("pci_segment")
+            .map_err(Error::ParsePciRootPort)?
+            .unwrap_or_default();
+
+        Ok(PciRootPortConfig { id, pci_segment })
+    }
 }
 
 impl MemoryConfig {
@@ -767,7 +1980,8 @@ impl MemoryConfig {
                     .add("host_numa_node")
                     .add("hotplug_size")
                     .add("hotplugged_size")
-                    .add("prefault");
+                    .add("prefault")
+                    .add("overcommit");
                 parser.parse(memory_zone).map_err(Error::ParseMemoryZone)?;
 
                 let id = parser.get("id").ok_or(Error::ParseMemoryZoneIdMissing)?;
@@ -808,6 +2022,11 @@ impl MemoryConfig {
                     .map_err(Error::ParseMemoryZone)?
                     .unwrap_or(Toggle(false))
                     .0;
+                let overcommit = parser
+                    .convert::<Toggle>("overcommit")
+                    .map_err(Error::ParseMemoryZone)?
+                    .unwrap_or(Toggle(false))
+                    .0;
 
                 zones.push(MemoryZoneConfig {
                     id,
@@ -820,6 +2039,7 @@ impl MemoryConfig {
                     hotplug_size,
                     hotplugged_size,
                     prefault,
+                    overcommit,
                 });
             }
             Some(zones)
@@ -868,7 +2088,11 @@ impl DiskConfig {
          vhost_user=on|off,socket=<vhost_user_socket_path>,\
          bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
          ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
-         id=<device_id>,pci_segment=<segment_id>\"";
+         id=<device_id>,pci_segment=<segment_id>,pci_root_port=<root_port_id>,\
+         subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>,\
+         backend=<disk_backend>,mmio=on|off,io_priority=rt|be|idle[:<level>],\
+         event_loop=epoll|io_uring,event_idx=on|off,coalesce_buffers=<buffers>,\
+         coalesce_timeout=<us>\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -891,7 +2115,18 @@ impl DiskConfig {
             .add("_disable_io_uring")
             .add("_disable_aio")
             .add("pci_segment")
-            .add("serial");
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id")
+            .add("serial")
+            .add("pci_root_port")
+            .add("backend")
+            .add("mmio")
+            .add("io_priority")
+            .add("event_loop")
+            .add("event_idx")
+            .add("coalesce_buffers")
+            .add("coalesce_timeout");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -964,6 +2199,18 @@ impl DiskConfig {
             .map_err(Error::ParseDisk)?
             .unwrap_or_default();
         let serial = parser.get("serial");
+        let pci_root_port = parser.get("pci_root_port");
+        let backend = parser.get("backend");
+        let mmio = parser
+            .convert::<Toggle>("mmio")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let io_priority = parser.convert("io_priority").map_err(Error::ParseDisk)?;
+        let event_loop = parser
+            .convert("event_loop")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_default();
         let bw_tb_config = if bw_size != 0 && bw_refill_time != 0 {
             Some(TokenBucketConfig {
                 size: bw_size,
@@ -991,6 +2238,9 @@ impl DiskConfig {
             None
         };
 
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParseDisk)?;
+        let notification = parse_notification(&parser).map_err(Error::ParseDisk)?;
+
         Ok(DiskConfig {
             path,
             readonly,
@@ -1005,7 +2255,14 @@ impl DiskConfig {
             disable_io_uring,
             disable_aio,
             pci_segment,
+            pci_ids,
             serial,
+            pci_root_port,
+            backend,
+            mmio,
+            io_priority,
+            event_loop,
+            notification,
         })
     }
 
@@ -1014,10 +2271,47 @@ impl DiskConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        if self.vhost_user && self.event_loop != EventLoop::Epoll {
+            return Err(ValidationError::VhostUserEventLoop);
+        }
+
+        if let Some(io_priority) = &self.io_priority {
+            if self.vhost_user {
+                return Err(ValidationError::VhostUserIoPriority);
+            }
+            if io_priority.level > MAX_IO_PRIORITY_LEVEL {
+                return Err(ValidationError::InvalidIoPriorityLevel(io_priority.level));
+            }
+        }
+
         if self.vhost_user && self.iommu {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if self.mmio {
+            if self.vhost_user {
+                return Err(ValidationError::MmioVhostUser);
+            }
+            if self.iommu
+                || self.pci_segment != 0
+                || self.pci_root_port.is_some()
+                || self.pci_ids.is_some()
+            {
+                return Err(ValidationError::MmioPciOption);
+            }
+        }
+
+        validate_notification(self.notification.as_ref(), self.vhost_user)?;
+
+        if let Some(backend) = &self.backend {
+            if self.vhost_user {
+                return Err(ValidationError::VhostUserDiskBackend);
+            }
+            if block::backend::disk_backend(backend).is_none() {
+                return Err(ValidationError::UnknownDiskBackend(backend.clone()));
+            }
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1034,6 +2328,37 @@ impl DiskConfig {
     }
 }
 
+#[derive(Debug)]
+pub enum ParseIoPriorityError {
+    InvalidClass(String),
+    InvalidLevel(String),
+}
+
+impl FromStr for IoPriorityConfig {
+    type Err = ParseIoPriorityError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let class = match class.to_lowercase().as_str() {
+            "rt" => IoPriorityClass::Realtime,
+            "be" => IoPriorityClass::BestEffort,
+            "idle" => IoPriorityClass::Idle,
+            _ => return Err(ParseIoPriorityError::InvalidClass(class.to_owned())),
+        };
+        let level = match level {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ParseIoPriorityError::InvalidLevel(level.to_owned()))?,
+            None => 0,
+        };
+
+        Ok(IoPriorityConfig { class, level })
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseVhostModeError {
     InvalidValue(String),
@@ -1058,7 +2383,10 @@ impl NetConfig {
     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,vhost_mode=client|server,\
     bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,\
     ops_size=<io_ops>,ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,pci_segment=<segment_id>\
-    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off\"";
+    offload_tso=on|off,offload_ufo=on|off,offload_csum=on|off,pci_root_port=<root_port_id>,\
+    subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>,\
+    event_idx=on|off,coalesce_buffers=<buffers>,coalesce_timeout=<us>,mmio=on|off,\
+    xdp=<if_name>,xdp_zero_copy=on|off,event_loop=epoll|io_uring\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1087,7 +2415,18 @@ impl NetConfig {
             .add("ops_size")
             .add("ops_one_time_burst")
             .add("ops_refill_time")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id")
+            .add("pci_root_port")
+            .add("event_idx")
+            .add("coalesce_buffers")
+            .add("coalesce_timeout")
+            .add("mmio")
+            .add("xdp")
+            .add("xdp_zero_copy")
+            .add("event_loop");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -1119,6 +2458,22 @@ impl NetConfig {
             .map_err(Error::ParseNetwork)?
             .unwrap_or(Toggle(true))
             .0;
+        let pci_root_port = parser.get("pci_root_port");
+        let mmio = parser
+            .convert::<Toggle>("mmio")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let xdp = parser.get("xdp");
+        let xdp_zero_copy = parser
+            .convert::<Toggle>("xdp_zero_copy")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(true))
+            .0;
+        let event_loop = parser
+            .convert("event_loop")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
         let mtu = parser.convert("mtu").map_err(Error::ParseNetwork)?;
         let iommu = parser
             .convert::<Toggle>("iommu")
@@ -1203,6 +2558,9 @@ impl NetConfig {
             None
         };
 
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParseNetwork)?;
+        let notification = parse_notification(&parser).map_err(Error::ParseNetwork)?;
+
         let config = NetConfig {
             tap,
             ip,
@@ -1220,9 +2578,16 @@ impl NetConfig {
             fds,
             rate_limiter_config,
             pci_segment,
+            pci_ids,
             offload_tso,
             offload_ufo,
             offload_csum,
+            pci_root_port,
+            notification,
+            mmio,
+            xdp,
+            xdp_zero_copy,
+            event_loop,
         };
         Ok(config)
     }
@@ -1248,10 +2613,27 @@ impl NetConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        if self.xdp.is_some() && (self.tap.is_some() || self.fds.is_some() || self.vhost_user) {
+            return Err(ValidationError::XdpWithOtherBackend);
+        }
+
         if self.vhost_user && self.iommu {
             return Err(ValidationError::IommuNotSupported);
         }
 
+        if self.mmio {
+            if self.vhost_user {
+                return Err(ValidationError::MmioVhostUser);
+            }
+            if self.iommu
+                || self.pci_segment != 0
+                || self.pci_root_port.is_some()
+                || self.pci_ids.is_some()
+            {
+                return Err(ValidationError::MmioPciOption);
+            }
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1274,14 +2656,30 @@ impl NetConfig {
             return Err(ValidationError::NoHardwareChecksumOffload);
         }
 
+        if self.vhost_user && self.event_loop != EventLoop::Epoll {
+            return Err(ValidationError::VhostUserEventLoop);
+        }
+
+        validate_notification(self.notification.as_ref(), self.vhost_user)?;
+
         Ok(())
     }
 }
 
 impl RngConfig {
+    pub const SYNTAX: &'static str = "Random number generator parameters \
+        \"src=<entropy_source_path>,iommu=on|off,\
+        bw_size=<bytes>,bw_one_time_burst=<bytes>,bw_refill_time=<ms>,mmio=on|off\"";
+
     pub fn parse(rng: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("src").add("iommu");
+        parser
+            .add("src")
+            .add("iommu")
+            .add("bw_size")
+            .add("bw_one_time_burst")
+            .add("bw_refill_time")
+            .add("mmio");
         parser.parse(rng).map_err(Error::ParseRng)?;
 
         let src = PathBuf::from(
@@ -1294,15 +2692,55 @@ impl RngConfig {
             .map_err(Error::ParseRng)?
             .unwrap_or(Toggle(false))
             .0;
+        let mmio = parser
+            .convert::<Toggle>("mmio")
+            .map_err(Error::ParseRng)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let bw_size = parser
+            .convert("bw_size")
+            .map_err(Error::ParseRng)?
+            .unwrap_or_default();
+        let bw_one_time_burst = parser
+            .convert("bw_one_time_burst")
+            .map_err(Error::ParseRng)?;
+        let bw_refill_time = parser
+            .convert("bw_refill_time")
+            .map_err(Error::ParseRng)?
+            .unwrap_or_default();
+        let rate_limiter_config = if bw_size != 0 && bw_refill_time != 0 {
+            Some(RateLimiterConfig {
+                bandwidth: Some(TokenBucketConfig {
+                    size: bw_size,
+                    one_time_burst: bw_one_time_burst,
+                    refill_time: bw_refill_time,
+                }),
+                ops: None,
+            })
+        } else {
+            None
+        };
 
-        Ok(RngConfig { src, iommu })
+        Ok(RngConfig {
+            src,
+            iommu,
+            rate_limiter_config,
+            mmio,
+            removed: false,
+        })
     }
 }
 
 impl BalloonConfig {
     pub const SYNTAX: &'static str =
         "Balloon parameters \"size=<balloon_size>,deflate_on_oom=on|off,\
-        free_page_reporting=on|off\"";
+        free_page_reporting=on|off,free_page_hinting=on|off,reclaim_below=<host_available_memory>,\
+        release_above=<host_available_memory>,reclaim_step=<reclaim_step_size>,\
+        reclaim_max=<max_reclaimed_size>,reclaim_priority=<priority>,\
+        hetero_pressure_high=<memory_pressure_percentage>,\
+        hetero_pressure_low=<memory_pressure_percentage>,hetero_step=<hetero_step_size>,\
+        hetero_max=<max_hetero_size>,hetero_pressure_file=<pressure_stall_information_file>,\
+        cgroup_memory_high=on|off\"";
 
     pub fn parse(balloon: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1310,7 +2748,19 @@ impl BalloonConfig {
         parser.add("statistics");
         parser.add("deflate_on_oom");
         parser.add("free_page_reporting");
+        parser.add("free_page_hinting");
         parser.add("heterogeneous_memory");
+        parser.add("reclaim_below");
+        parser.add("release_above");
+        parser.add("reclaim_step");
+        parser.add("reclaim_max");
+        parser.add("reclaim_priority");
+        parser.add("hetero_pressure_high");
+        parser.add("hetero_pressure_low");
+        parser.add("hetero_step");
+        parser.add("hetero_max");
+        parser.add("hetero_pressure_file");
+        parser.add("cgroup_memory_high");
         parser.parse(balloon).map_err(Error::ParseBalloon)?;
 
         let size = if let Ok(size) = parser.convert::<ByteSized>("size") {
@@ -1342,18 +2792,79 @@ impl BalloonConfig {
             .unwrap_or(Toggle(false))
             .0;
 
+        let free_page_hinting = parser
+            .convert::<Toggle>("free_page_hinting")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(Toggle(false))
+            .0;
+
         let heterogeneous_memory = parser
             .convert::<Toggle>("heterogeneous_memory")
             .map_err(Error::ParseBalloon)?
             .unwrap_or(Toggle(false))
             .0;
 
+        let reclaim_below = parser
+            .convert::<ByteSized>("reclaim_below")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+        let release_above = parser
+            .convert::<ByteSized>("release_above")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+        let reclaim_step = parser
+            .convert::<ByteSized>("reclaim_step")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0)
+            .unwrap_or_else(default_balloonconfig_reclaim_step);
+        let reclaim_max = parser
+            .convert::<ByteSized>("reclaim_max")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+        let reclaim_priority = parser
+            .convert("reclaim_priority")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or_default();
+        let hetero_pressure_high = parser
+            .convert("hetero_pressure_high")
+            .map_err(Error::ParseBalloon)?;
+        let hetero_pressure_low = parser
+            .convert("hetero_pressure_low")
+            .map_err(Error::ParseBalloon)?;
+        let hetero_step = parser
+            .convert::<ByteSized>("hetero_step")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0)
+            .unwrap_or_else(default_balloonconfig_reclaim_step);
+        let hetero_max = parser
+            .convert::<ByteSized>("hetero_max")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0);
+        let hetero_pressure_file = parser.get("hetero_pressure_file").map(PathBuf::from);
+        let cgroup_memory_high = parser
+            .convert::<Toggle>("cgroup_memory_high")
+            .map_err(Error::ParseBalloon)?
+            .unwrap_or(Toggle(false))
+            .0;
+
         Ok(BalloonConfig {
             size,
             statistics,
             deflate_on_oom,
             free_page_reporting,
+            free_page_hinting,
             heterogeneous_memory,
+            reclaim_below,
+            release_above,
+            reclaim_step,
+            reclaim_max,
+            reclaim_priority,
+            hetero_pressure_high,
+            hetero_pressure_low,
+            hetero_step,
+            hetero_max,
+            hetero_pressure_file,
+            cgroup_memory_high,
         })
     }
 }
@@ -1361,7 +2872,10 @@ impl BalloonConfig {
 impl FsConfig {
     pub const SYNTAX: &'static str = "virtio-fs parameters \
     \"tag=<tag_name>,socket=<socket_path>,num_queues=<number_of_queues>,\
-    queue_size=<size_of_each_queue>,id=<device_id>,pci_segment=<segment_id>\"";
+    queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
+    default 8Gib>,id=<device_id>,pci_segment=<segment_id>,\
+    subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>,\
+    shared_dir=<directory shared through a virtiofsd spawned by the VMM>\"";
 
     pub fn parse(fs: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1370,8 +2884,14 @@ impl FsConfig {
             .add("queue_size")
             .add("num_queues")
             .add("socket")
+            .add("dax")
+            .add("cache_size")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id")
+            .add("shared_dir");
         parser.parse(fs).map_err(Error::ParseFileSystem)?;
 
         let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
@@ -1385,6 +2905,16 @@ impl FsConfig {
             .convert("num_queues")
             .map_err(Error::ParseFileSystem)?
             .unwrap_or_else(default_fsconfig_num_queues);
+        let dax = parser
+            .convert::<Toggle>("dax")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let cache_size = parser
+            .convert::<ByteSized>("cache_size")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or(ByteSized(default_fsconfig_cache_size()))
+            .0;
 
         let id = parser.get("id");
 
@@ -1393,13 +2923,21 @@ impl FsConfig {
             .map_err(Error::ParseFileSystem)?
             .unwrap_or_default();
 
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParseFileSystem)?;
+
+        let shared_dir = parser.get("shared_dir").map(PathBuf::from);
+
         Ok(FsConfig {
             tag,
             socket,
             num_queues,
             queue_size,
+            dax,
+            cache_size,
             id,
             pci_segment,
+            pci_ids,
+            shared_dir,
         })
     }
 
@@ -1408,6 +2946,12 @@ impl FsConfig {
             return Err(ValidationError::TooManyQueues);
         }
 
+        // The DAX window is exposed through a dedicated PCI BAR, hence its
+        // size must be a power of 2.
+        if self.dax && !self.cache_size.is_power_of_two() {
+            return Err(ValidationError::InvalidFsCacheSize(self.cache_size));
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1429,7 +2973,8 @@ impl FsConfig {
 impl PmemConfig {
     pub const SYNTAX: &'static str = "Persistent memory parameters \
     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-    discard_writes=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+    discard_writes=on|off,discard=on|off,id=<device_id>,pci_segment=<segment_id>,\
+    subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>\"";
 
     pub fn parse(pmem: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1438,8 +2983,12 @@ impl PmemConfig {
             .add("file")
             .add("iommu")
             .add("discard_writes")
+            .add("discard")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id");
         parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
 
         let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
@@ -1457,23 +3006,36 @@ impl PmemConfig {
             .map_err(Error::ParsePersistentMemory)?
             .unwrap_or(Toggle(false))
             .0;
+        let discard = parser
+            .convert::<Toggle>("discard")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
         let id = parser.get("id");
         let pci_segment = parser
             .convert("pci_segment")
             .map_err(Error::ParsePersistentMemory)?
             .unwrap_or_default();
 
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParsePersistentMemory)?;
+
         Ok(PmemConfig {
             file,
             size,
             iommu,
             discard_writes,
+            discard,
             id,
             pci_segment,
+            pci_ids,
         })
     }
 
     pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.discard && self.discard_writes {
+            return Err(ValidationError::PmemDiscardWithDiscardWrites);
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1490,6 +3052,80 @@ impl PmemConfig {
     }
 }
 
+impl IvshmemConfig {
+    pub const SYNTAX: &'static str = "ivshmem shared memory parameters \
+    \"path=<shared_memory_file>,size=<shared_memory_size>,\
+    socket=<ivshmem_server_socket_path>,vectors=<number_of_interrupt_vectors>,\
+    id=<device_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(ivshmem: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("size")
+            .add("socket")
+            .add("vectors")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(ivshmem).map_err(Error::ParseIvshmem)?;
+
+        let path = parser.get("path").map(PathBuf::from);
+        let socket = parser.get("socket").map(PathBuf::from);
+        if path.is_some() == socket.is_some() {
+            return Err(Error::ParseIvshmemBackend);
+        }
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseIvshmem)?
+            .map(|v| v.0);
+        let vectors = parser
+            .convert("vectors")
+            .map_err(Error::ParseIvshmem)?
+            .unwrap_or_else(default_ivshmemconfig_vectors);
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert("pci_segment")
+            .map_err(Error::ParseIvshmem)?
+            .unwrap_or_default();
+
+        Ok(IvshmemConfig {
+            path,
+            size,
+            socket,
+            vectors,
+            id,
+            pci_segment,
+        })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.path.is_some() == self.socket.is_some() {
+            return Err(ValidationError::IvshmemPathOrSocket);
+        }
+
+        if let Some(size) = self.size {
+            if self.socket.is_some() {
+                return Err(ValidationError::IvshmemSocketWithSize);
+            }
+            if !size.is_power_of_two() {
+                return Err(ValidationError::InvalidIvshmemSize(size));
+            }
+        }
+
+        if self.vectors == 0 || self.vectors > devices::ivshmem::IVSHMEM_MAX_VECTORS {
+            return Err(ValidationError::InvalidIvshmemVectors(self.vectors));
+        }
+
+        if let Some(platform_config) = vm_config.platform.as_ref() {
+            if self.pci_segment >= platform_config.num_pci_segments {
+                return Err(ValidationError::InvalidPciSegment(self.pci_segment));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl ConsoleConfig {
     pub fn parse(console: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1500,11 +3136,16 @@ impl ConsoleConfig {
             .add_valueless("null")
             .add("file")
             .add("iommu")
-            .add("socket");
+            .add("socket")
+            .add("max_ports")
+            .add("tcp")
+            .add("telnet")
+            .add("socket_dir");
         parser.parse(console).map_err(Error::ParseConsole)?;
 
         let mut file: Option<PathBuf> = default_consoleconfig_file();
         let mut socket: Option<PathBuf> = None;
+        let mut tcp: Option<SocketAddr> = None;
         let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
 
         if parser.is_set("off") {
@@ -1525,6 +3166,14 @@ impl ConsoleConfig {
             socket = Some(PathBuf::from(parser.get("socket").ok_or(
                 Error::Validation(ValidationError::ConsoleSocketPathMissing),
             )?));
+        } else if parser.is_set("tcp") {
+            mode = ConsoleOutputMode::Tcp;
+            tcp = parser
+                .convert::<SocketAddr>("tcp")
+                .map_err(Error::ParseConsole)?;
+        } else if parser.is_set("socket_dir") {
+            // The socket is named after the device, once known.
+            mode = ConsoleOutputMode::Socket;
         } else {
             return Err(Error::ParseConsoleInvalidModeGiven);
         }
@@ -1533,23 +3182,138 @@ impl ConsoleConfig {
             .map_err(Error::ParseConsole)?
             .unwrap_or(Toggle(false))
             .0;
+        let max_ports = parser
+            .convert("max_ports")
+            .map_err(Error::ParseConsole)?
+            .unwrap_or_else(default_consoleconfig_max_ports);
+        let telnet = parser
+            .convert::<Toggle>("telnet")
+            .map_err(Error::ParseConsole)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let socket_dir = parser.get("socket_dir").map(PathBuf::from);
 
         Ok(Self {
             file,
             mode,
             iommu,
             socket,
+            max_ports,
+            tcp,
+            telnet,
+            socket_dir,
+        })
+    }
+}
+
+impl DebugConsoleConfig {
+    pub const SYNTAX: &'static str = "Debug console (0xe9 I/O port on x86_64, MMIO \
+        register on aarch64) parameters \"tty|file=</path/to/a/file>|socket=</path/to/a/socket>\"";
+
+    pub fn parse(debug_console: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add_valueless("tty").add("file").add("socket");
+        parser
+            .parse(debug_console)
+            .map_err(Error::ParseDebugConsole)?;
+
+        let mut file = None;
+        let mut socket = None;
+        let mode = if parser.is_set("tty") {
+            ConsoleOutputMode::Tty
+        } else if parser.is_set("file") {
+            file =
+                Some(PathBuf::from(parser.get("file").ok_or(
+                    Error::Validation(ValidationError::ConsoleFileMissing),
+                )?));
+            ConsoleOutputMode::File
+        } else if parser.is_set("socket") {
+            socket = Some(PathBuf::from(parser.get("socket").ok_or(
+                Error::Validation(ValidationError::ConsoleSocketPathMissing),
+            )?));
+            ConsoleOutputMode::Socket
+        } else {
+            return Err(Error::ParseDebugConsoleInvalidModeGiven);
+        };
+
+        Ok(Self { file, mode, socket })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        match self.mode {
+            ConsoleOutputMode::Tty => Ok(()),
+            ConsoleOutputMode::File if self.file.is_none() => {
+                Err(ValidationError::ConsoleFileMissing)
+            }
+            ConsoleOutputMode::File => Ok(()),
+            ConsoleOutputMode::Socket if self.socket.is_none() => {
+                Err(ValidationError::ConsoleSocketPathMissing)
+            }
+            ConsoleOutputMode::Socket => Ok(()),
+            ConsoleOutputMode::Off
+            | ConsoleOutputMode::Pty
+            | ConsoleOutputMode::Tcp
+            | ConsoleOutputMode::Null => Err(ValidationError::DebugConsoleModeUnsupported),
+        }
+    }
+}
+
+impl ConsolePortConfig {
+    pub const SYNTAX: &'static str = "Console port parameters \
+    \"name=<port_name>,socket=<socket_path>,id=<port_id>\". \
+    The port is backed by a PTY when no socket is given.";
+
+    pub fn parse(console_port: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("name").add("socket").add("id");
+        parser
+            .parse(console_port)
+            .map_err(Error::ParseConsolePort)?;
+
+        let name = parser.get("name");
+        let socket = parser.get("socket").map(PathBuf::from);
+        let id = parser.get("id");
+
+        Ok(ConsolePortConfig {
+            id,
+            name,
+            socket,
+            file: None,
         })
     }
+
+    /// Name of the socket the port gets in the socket directory of the
+    /// console, after the name of the port, or its identifier.
+    pub fn socket_name(&self) -> Option<String> {
+        self.name
+            .as_ref()
+            .or(self.id.as_ref())
+            .map(|name| format!("{name}.sock"))
+    }
+
+    fn validate_socket_name(&self) -> ValidationResult<()> {
+        match self.name.as_ref().or(self.id.as_ref()) {
+            Some(name) if name.is_empty() || name.contains('/') => {
+                Err(ValidationError::InvalidConsolePortName(name.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl DeviceConfig {
     pub const SYNTAX: &'static str =
-        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+        "Direct device assignment parameters \"path=<device_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,p2p=[<device_id>,...],pci_root_port=<root_port_id>\"";
 
     pub fn parse(device: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("path").add("id").add("iommu").add("pci_segment");
+        parser
+            .add("path")
+            .add("id")
+            .add("iommu")
+            .add("pci_segment")
+            .add("p2p")
+            .add("pci_root_port");
         parser.parse(device).map_err(Error::ParseDevice)?;
 
         let path = parser
@@ -1566,12 +3330,21 @@ impl DeviceConfig {
             .convert::<u16>("pci_segment")
             .map_err(Error::ParseDevice)?
             .unwrap_or_default();
+        // The devices allowed to reach the BARs, an empty list only making
+        // the device a candidate peer of the others.
+        let p2p = parser
+            .convert::<StringList>("p2p")
+            .map_err(Error::ParseDevice)?
+            .map(|l| l.0.into_iter().filter(|id| !id.is_empty()).collect());
+        let pci_root_port = parser.get("pci_root_port");
 
         Ok(DeviceConfig {
             path,
             iommu,
             id,
             pci_segment,
+            p2p,
+            pci_root_port,
         })
     }
 
@@ -1588,10 +3361,133 @@ impl DeviceConfig {
             }
         }
 
+        // The guest addresses of the BARs can only be mapped in the
+        // containers of the devices which are not behind the vIOMMU.
+        if let Some(peers) = &self.p2p {
+            if self.iommu {
+                return Err(ValidationError::P2pDmaWithIommu(self.path.clone()));
+            }
+
+            // The peers must have their own container for the BARs to be
+            // mapped there, they may also be added later on.
+            for peer in peers {
+                let peer_cfg = vm_config
+                    .devices
+                    .iter()
+                    .flatten()
+                    .find(|d| d.id.as_ref() == Some(peer));
+                if matches!(peer_cfg, Some(d) if d.p2p.is_none()) {
+                    return Err(ValidationError::P2pDmaInvalidPeer(peer.clone()));
+                }
+            }
+        }
+
         Ok(())
     }
 }
 
+impl VfConfig {
+    pub const SYNTAX: &'static str = "SR-IOV virtual function parameters \
+        \"pf=<pf_pci_address>,vf=<vf_index>,num_vfs=<number_of_vfs>,iommu=on|off,\
+        id=<device_id>,pci_segment=<segment_id>\"";
+
+    pub fn parse(vf: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("pf")
+            .add("vf")
+            .add("num_vfs")
+            .add("iommu")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(vf).map_err(Error::ParseVf)?;
+
+        let pf = parser.get("pf").ok_or(Error::ParseVfPfMissing)?;
+        let vf = parser
+            .convert::<u16>("vf")
+            .map_err(Error::ParseVf)?
+            .ok_or(Error::ParseVfIndexMissing)?;
+        let num_vfs = parser.convert::<u16>("num_vfs").map_err(Error::ParseVf)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseVf)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert::<u16>("pci_segment")
+            .map_err(Error::ParseVf)?
+            .unwrap_or_default();
+
+        Ok(VfConfig {
+            pf,
+            vf,
+            num_vfs,
+            iommu,
+            id,
+            pci_segment,
+        })
+    }
+
+    /// Device assignment configuration of the VF, once bound to vfio-pci.
+    pub fn device_config(&self, path: PathBuf) -> DeviceConfig {
+        DeviceConfig {
+            path,
+            iommu: self.iommu,
+            id: self.id.clone(),
+            pci_segment: self.pci_segment,
+            p2p: None,
+            pci_root_port: None,
+        }
+    }
+}
+
+impl MdevConfig {
+    pub const SYNTAX: &'static str = "Mediated device parameters \
+        \"parent=<parent_device>,type=<mdev_type>,uuid=<mdev_uuid>,id=<device_id>,\
+        pci_segment=<segment_id>\"";
+
+    pub fn parse(mdev: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("parent")
+            .add("type")
+            .add("uuid")
+            .add("id")
+            .add("pci_segment");
+        parser.parse(mdev).map_err(Error::ParseMdev)?;
+
+        let parent = parser.get("parent").ok_or(Error::ParseMdevParentMissing)?;
+        let mdev_type = parser.get("type").ok_or(Error::ParseMdevTypeMissing)?;
+        let uuid = parser.get("uuid");
+        let id = parser.get("id");
+        let pci_segment = parser
+            .convert::<u16>("pci_segment")
+            .map_err(Error::ParseMdev)?
+            .unwrap_or_default();
+
+        Ok(MdevConfig {
+            parent,
+            mdev_type,
+            uuid,
+            id,
+            pci_segment,
+        })
+    }
+
+    /// Device assignment configuration of the mediated device, once created.
+    pub fn device_config(&self, path: PathBuf) -> DeviceConfig {
+        DeviceConfig {
+            path,
+            iommu: false,
+            id: self.id.clone(),
+            pci_segment: self.pci_segment,
+            p2p: None,
+            pci_root_port: None,
+        }
+    }
+}
+
 impl UserDeviceConfig {
     pub const SYNTAX: &'static str =
         "Userspace device socket=<socket_path>,id=<device_id>,pci_segment=<segment_id>\"";
@@ -1640,7 +3536,8 @@ impl UserDeviceConfig {
 impl VdpaConfig {
     pub const SYNTAX: &'static str = "vDPA device \
         \"path=<device_path>,num_queues=<number_of_queues>,iommu=on|off,\
-        id=<device_id>,pci_segment=<segment_id>\"";
+        id=<device_id>,pci_segment=<segment_id>,\
+        subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>\"";
 
     pub fn parse(vdpa: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1649,7 +3546,10 @@ impl VdpaConfig {
             .add("num_queues")
             .add("iommu")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id");
         parser.parse(vdpa).map_err(Error::ParseVdpa)?;
 
         let path = parser
@@ -1671,12 +3571,15 @@ impl VdpaConfig {
             .map_err(Error::ParseVdpa)?
             .unwrap_or_default();
 
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParseVdpa)?;
+
         Ok(VdpaConfig {
             path,
             num_queues,
             iommu,
             id,
             pci_segment,
+            pci_ids,
         })
     }
 
@@ -1699,7 +3602,9 @@ impl VdpaConfig {
 
 impl VsockConfig {
     pub const SYNTAX: &'static str = "Virtio VSOCK parameters \
-        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>\"";
+        \"cid=<context_id>,socket=<socket_path>,iommu=on|off,id=<device_id>,pci_segment=<segment_id>,\
+        siblings=[<sibling_cid>@<sibling_socket_path>],listeners=[<port>@<socket_path>],\
+        subsystem_vendor_id=<hex_id>,subsystem_id=<hex_id>,revision_id=<hex_id>,mmio=on|off\"";
 
     pub fn parse(vsock: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -1708,7 +3613,13 @@ impl VsockConfig {
             .add("cid")
             .add("iommu")
             .add("id")
-            .add("pci_segment");
+            .add("pci_segment")
+            .add("subsystem_vendor_id")
+            .add("subsystem_id")
+            .add("revision_id")
+            .add("siblings")
+            .add("listeners")
+            .add("mmio");
         parser.parse(vsock).map_err(Error::ParseVsock)?;
 
         let socket = parser
@@ -1729,6 +3640,36 @@ impl VsockConfig {
             .convert("pci_segment")
             .map_err(Error::ParseVsock)?
             .unwrap_or_default();
+        let siblings = parser
+            .convert::<Tuple<u64, String>>("siblings")
+            .map_err(Error::ParseVsock)?
+            .map(|v| {
+                v.0.into_iter()
+                    .map(|(cid, socket)| VsockSiblingConfig {
+                        cid,
+                        socket: PathBuf::from(socket),
+                    })
+                    .collect()
+            });
+        let listeners = parser
+            .convert::<Tuple<u32, String>>("listeners")
+            .map_err(Error::ParseVsock)?
+            .map(|v| {
+                v.0.into_iter()
+                    .map(|(port, socket)| VsockListenerConfig {
+                        port,
+                        socket: PathBuf::from(socket),
+                    })
+                    .collect()
+            });
+
+        let mmio = parser
+            .convert::<Toggle>("mmio")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        let pci_ids = parse_pci_ids(&parser).map_err(Error::ParseVsock)?;
 
         Ok(VsockConfig {
             cid,
@@ -1736,10 +3677,18 @@ impl VsockConfig {
             iommu,
             id,
             pci_segment,
+            pci_ids,
+            siblings,
+            listeners,
+            mmio,
         })
     }
 
     pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if self.mmio && (self.iommu || self.pci_segment != 0 || self.pci_ids.is_some()) {
+            return Err(ValidationError::MmioPciOption);
+        }
+
         if let Some(platform_config) = vm_config.platform.as_ref() {
             if self.pci_segment >= platform_config.num_pci_segments {
                 return Err(ValidationError::InvalidPciSegment(self.pci_segment));
@@ -1752,6 +3701,25 @@ impl VsockConfig {
             }
         }
 
+        if let Some(siblings) = &self.siblings {
+            let mut cids = BTreeSet::new();
+            for sibling in siblings.iter() {
+                // CIDs 0 to 2 are reserved, and a sibling can't share our CID.
+                if sibling.cid < 3 || sibling.cid == self.cid || !cids.insert(sibling.cid) {
+                    return Err(ValidationError::InvalidVsockSiblingCid(sibling.cid));
+                }
+            }
+        }
+
+        if let Some(listeners) = &self.listeners {
+            let mut ports = BTreeSet::new();
+            for listener in listeners.iter() {
+                if !ports.insert(listener.port) {
+                    return Err(ValidationError::DuplicateVsockListenerPort(listener.port));
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -1782,10 +3750,154 @@ impl SgxEpcConfig {
     }
 }
 
-impl NumaConfig {
-    pub const SYNTAX: &'static str = "Settings related to a given NUMA node \
-        \"guest_numa_id=<node_id>,cpus=<cpus_id>,distances=<list_of_distances_to_destination_nodes>,\
-        memory_zones=<list_of_memory_zones>,sgx_epc_sections=<list_of_sgx_epc_sections>,\
+#[cfg(target_arch = "x86_64")]
+impl MsrFilterConfig {
+    pub const SYNTAX: &'static str = "MSR filtering parameters \
+        \"default_action=allow|deny,allow=<list_of_msrs>,deny=<list_of_msrs>,\
+        zero=<list_of_msrs>\"";
+
+    pub fn parse(msr_filter: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("default_action")
+            .add("allow")
+            .add("deny")
+            .add("zero");
+        parser.parse(msr_filter).map_err(Error::ParseMsrFilter)?;
+
+        let default_action = match parser.get("default_action").as_deref() {
+            None | Some("allow") => MsrFilterDefaultAction::Allow,
+            Some("deny") => MsrFilterDefaultAction::Deny,
+            Some(s) => return Err(Error::InvalidMsrFilterDefaultAction(s.to_owned())),
+        };
+        let msrs = |option| -> Result<Vec<u32>> {
+            parser
+                .convert::<StringList>(option)
+                .map_err(Error::ParseMsrFilter)?
+                .unwrap_or_default()
+                .0
+                .iter()
+                .map(String::as_str)
+                .map(parse_msr_index)
+                .collect()
+        };
+
+        Ok(MsrFilterConfig {
+            default_action,
+            allow: msrs("allow")?,
+            deny: msrs("deny")?,
+            zero: msrs("zero")?,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        let mut msrs = BTreeSet::new();
+        for msr in self.allow.iter().chain(&self.deny).chain(&self.zero) {
+            if !msrs.insert(*msr) {
+                return Err(ValidationError::DuplicateMsrFilterEntry(*msr));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl UefiVarsConfig {
+    pub const SYNTAX: &'static str = "UEFI variable store persisting the firmware \
+        variables, created if missing with the given DER encoded Secure Boot \
+        certificates enrolled \"path=</path/to/vars.fd>,pk=</path/to/pk.der>,\
+        kek=<list_of_certificates>,db=<list_of_certificates>\"";
+
+    pub fn parse(uefi_vars: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("pk").add("kek").add("db");
+        parser.parse(uefi_vars).map_err(Error::ParseUefiVars)?;
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseUefiVarsPathMissing)?;
+        let pk = parser.get("pk").map(PathBuf::from);
+        let certs = |option| -> Result<Option<Vec<PathBuf>>> {
+            Ok(parser
+                .convert::<StringList>(option)
+                .map_err(Error::ParseUefiVars)?
+                .map(|l| l.0.iter().map(PathBuf::from).collect()))
+        };
+        let kek = certs("kek")?;
+        let db = certs("db")?;
+
+        Ok(UefiVarsConfig { path, pk, kek, db })
+    }
+}
+
+// Identifiers replacing the default ones of a virtio-pci device, if any is
+// given.
+fn parse_pci_ids(
+    parser: &OptionParser,
+) -> std::result::Result<Option<PciIdsConfig>, OptionParserError> {
+    let pci_ids = PciIdsConfig {
+        subsystem_vendor_id: parser
+            .convert::<Hex<u16>>("subsystem_vendor_id")?
+            .map(|v| v.0),
+        subsystem_id: parser.convert::<Hex<u16>>("subsystem_id")?.map(|v| v.0),
+        revision_id: parser.convert::<Hex<u8>>("revision_id")?.map(|v| v.0),
+    };
+
+    Ok((pci_ids != PciIdsConfig::default()).then_some(pci_ids))
+}
+
+// Tuning of the used buffer notifications of a virtio device, if any
+// departs from the defaults.
+fn parse_notification(
+    parser: &OptionParser,
+) -> std::result::Result<Option<NotificationConfig>, OptionParserError> {
+    let default = NotificationConfig::default();
+    let notification = NotificationConfig {
+        event_idx: parser
+            .convert::<Toggle>("event_idx")?
+            .map_or(default.event_idx, |t| t.0),
+        coalesce_buffers: parser
+            .convert("coalesce_buffers")?
+            .unwrap_or(default.coalesce_buffers),
+        coalesce_timeout: parser
+            .convert("coalesce_timeout")?
+            .unwrap_or(default.coalesce_timeout),
+    };
+
+    Ok((notification != default).then_some(notification))
+}
+
+fn validate_notification(
+    notification: Option<&NotificationConfig>,
+    vhost_user: bool,
+) -> ValidationResult<()> {
+    if let Some(notification) = notification {
+        if vhost_user {
+            return Err(ValidationError::VhostUserNotification);
+        }
+        if notification.coalesce_buffers == 0 || notification.coalesce_timeout == 0 {
+            return Err(ValidationError::InvalidNotificationCoalescing);
+        }
+    }
+
+    Ok(())
+}
+
+// MSRs are usually referred to by their hexadecimal index.
+#[cfg(target_arch = "x86_64")]
+fn parse_msr_index(s: &str) -> Result<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| Error::InvalidMsrIndex(s.to_owned()))
+}
+
+impl NumaConfig {
+    pub const SYNTAX: &'static str = "Settings related to a given NUMA node \
+        \"guest_numa_id=<node_id>,cpus=<cpus_id>,distances=<list_of_distances_to_destination_nodes>,\
+        memory_zones=<list_of_memory_zones>,sgx_epc_sections=<list_of_sgx_epc_sections>,\
         pci_segments=<list_of_pci_segments>\"";
 
     pub fn parse(numa: &str) -> Result<Self> {
@@ -1844,22 +3956,72 @@ impl NumaConfig {
     }
 }
 
+/// Changes to a network device of a VM restored from a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct RestoredNetConfig {
+    pub id: String,
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+/// Changes to a disk of a VM restored from a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct RestoredDiskConfig {
+    pub id: String,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+}
+
+/// Changes to a memory zone of a VM restored from a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct RestoredMemoryZoneConfig {
+    pub id: String,
+    #[serde(default)]
+    pub host_numa_node: Option<u32>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct RestoreConfig {
     pub source_url: PathBuf,
     #[serde(default)]
     pub prefault: bool,
+    #[serde(default)]
+    pub require_manifest: bool,
+    #[serde(default)]
+    pub net: Option<Vec<RestoredNetConfig>>,
+    #[serde(default)]
+    pub disks: Option<Vec<RestoredDiskConfig>>,
+    #[serde(default)]
+    pub memory_zones: Option<Vec<RestoredMemoryZoneConfig>>,
 }
 
 impl RestoreConfig {
     pub const SYNTAX: &'static str = "Restore from a VM snapshot. \
-        \nRestore parameters \"source_url=<source_url>,prefault=on|off\" \
-        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo) \
-        \n`prefault` brings memory pages in when enabled (disabled by default)";
+        \nRestore parameters \"source_url=<source_url>,prefault=on|off,\
+        require_manifest=on|off,net_mac=[<net_id>@<mac>,...],disk_path=[<disk_id>@</path/to/image>,...],\
+        memory_zone_host_numa_node=[<zone_id>@<node>,...]\" \
+        \n`source_url` should be a valid URL (e.g file:///foo/bar or tcp://192.168.1.10/foo), \
+        or a stream the snapshot is read from (unix:/foo/socket or fd://<fd>) \
+        \n`prefault` brings memory pages in when enabled (disabled by default) \
+        \n`require_manifest` refuses the snapshots without a manifest to check \
+        them against, such as the streamed ones (disabled by default) \
+        \n`net_mac`, `disk_path` and `memory_zone_host_numa_node` change the \
+        configuration of the restored VM, e.g. to run it as a clone of the VM \
+        the snapshot was taken from";
 
     pub fn parse(restore: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
-        parser.add("source_url").add("prefault");
+        parser
+            .add("source_url")
+            .add("prefault")
+            .add("require_manifest")
+            .add("net_mac")
+            .add("disk_path")
+            .add("memory_zone_host_numa_node");
         parser.parse(restore).map_err(Error::ParseRestore)?;
 
         let source_url = parser
@@ -1871,12 +4033,124 @@ impl RestoreConfig {
             .map_err(Error::ParseRestore)?
             .unwrap_or(Toggle(false))
             .0;
+        let require_manifest = parser
+            .convert::<Toggle>("require_manifest")
+            .map_err(Error::ParseRestore)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let net = parser
+            .convert::<Tuple<String, String>>("net_mac")
+            .map_err(Error::ParseRestore)?
+            .map(|macs| {
+                macs.0
+                    .into_iter()
+                    .map(|(id, mac)| {
+                        let mac = MacAddr::from_str(&mac).map_err(|_| {
+                            Error::ParseRestore(OptionParserError::Conversion(
+                                "net_mac".to_owned(),
+                                mac,
+                            ))
+                        })?;
+                        Ok(RestoredNetConfig {
+                            id,
+                            mac: Some(mac),
+                            ..Default::default()
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let disks = parser
+            .convert::<Tuple<String, String>>("disk_path")
+            .map_err(Error::ParseRestore)?
+            .map(|paths| {
+                paths
+                    .0
+                    .into_iter()
+                    .map(|(id, path)| RestoredDiskConfig {
+                        id,
+                        path: Some(PathBuf::from(path)),
+                        ..Default::default()
+                    })
+                    .collect()
+            });
+        let memory_zones = parser
+            .convert::<Tuple<String, u64>>("memory_zone_host_numa_node")
+            .map_err(Error::ParseRestore)?
+            .map(|nodes| {
+                nodes
+                    .0
+                    .into_iter()
+                    .map(|(id, node)| RestoredMemoryZoneConfig {
+                        id,
+                        host_numa_node: Some(node as u32),
+                    })
+                    .collect()
+            });
 
         Ok(RestoreConfig {
             source_url,
             prefault,
+            require_manifest,
+            net,
+            disks,
+            memory_zones,
         })
     }
+
+    /// Whether the configuration of the restored VM gets changed.
+    pub fn has_overrides(&self) -> bool {
+        self.net.is_some() || self.disks.is_some() || self.memory_zones.is_some()
+    }
+
+    /// Apply the changes to the configuration of the VM restored from the
+    /// snapshot, the devices and memory zones being found by their id.
+    pub fn apply_overrides(&self, vm_config: &mut VmConfig) -> ValidationResult<()> {
+        for restored in self.net.iter().flatten() {
+            let net = vm_config
+                .net
+                .iter_mut()
+                .flatten()
+                .find(|net| net.id.as_ref() == Some(&restored.id))
+                .ok_or_else(|| ValidationError::UnknownRestoredId(restored.id.clone()))?;
+            if let Some(mac) = restored.mac {
+                net.mac = mac;
+            }
+            if let Some(rate_limiter_config) = restored.rate_limiter_config {
+                net.rate_limiter_config = Some(rate_limiter_config);
+            }
+        }
+
+        for restored in self.disks.iter().flatten() {
+            let disk = vm_config
+                .disks
+                .iter_mut()
+                .flatten()
+                .find(|disk| disk.id.as_ref() == Some(&restored.id))
+                .ok_or_else(|| ValidationError::UnknownRestoredId(restored.id.clone()))?;
+            if let Some(path) = &restored.path {
+                disk.path = Some(path.clone());
+            }
+            if let Some(rate_limiter_config) = restored.rate_limiter_config {
+                disk.rate_limiter_config = Some(rate_limiter_config);
+            }
+        }
+
+        for restored in self.memory_zones.iter().flatten() {
+            let zone = vm_config
+                .memory
+                .zones
+                .iter_mut()
+                .flatten()
+                .find(|zone| zone.id == restored.id)
+                .ok_or_else(|| ValidationError::UnknownRestoredId(restored.id.clone()))?;
+            if let Some(host_numa_node) = restored.host_numa_node {
+                zone.host_numa_node = Some(host_numa_node);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl TpmConfig {
@@ -1895,1585 +4169,4597 @@ impl TpmConfig {
     }
 }
 
-impl VmConfig {
-    fn validate_identifier(
-        id_list: &mut BTreeSet<String>,
-        id: &Option<String>,
-    ) -> ValidationResult<()> {
-        if let Some(id) = id.as_ref() {
-            if id.starts_with("__") {
-                return Err(ValidationError::InvalidIdentifier(id.clone()));
-            }
+impl AcpiTableConfig {
+    pub const SYNTAX: &'static str = "ACPI table added to the generated ones, \
+        such as a SSDT compiled with iasl \"path=</path/to/table.aml>\"";
 
-            if !id_list.insert(id.clone()) {
-                return Err(ValidationError::IdentifierNotUnique(id.clone()));
+    pub fn parse(acpi_table: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path");
+        parser.parse(acpi_table).map_err(Error::ParseAcpiTable)?;
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseAcpiTablePathMissing)?;
+        Ok(AcpiTableConfig { path })
+    }
+}
+
+impl CgroupConfig {
+    pub const SYNTAX: &'static str = "cgroup v2 placement and limits of the VMM \
+        \"path=<cgroup_path_below_/sys/fs/cgroup>,memory_max=<memory_limit>,\
+        io_max=[<major>:<minor> rbps=<bytes> wbps=<bytes> riops=<iops> wiops=<iops>,...],\
+        device_cpu_max=[<device_id>@<quota_us>:<period_us>,...]\"";
+
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("memory_max")
+            .add("io_max")
+            .add("device_cpu_max");
+        parser.parse(cgroup).map_err(Error::ParseCgroup)?;
+
+        let path = parser.get("path").map(PathBuf::from);
+        let memory_max = parser
+            .convert::<ByteSized>("memory_max")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| v.0);
+        let io_max = parser
+            .convert::<StringList>("io_max")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| {
+                v.0.iter()
+                    .map(|io_max| IoMaxConfig::from_str(io_max))
+                    .collect::<std::result::Result<Vec<_>, _>>()
+            })
+            .transpose()
+            .map_err(Error::ParseCgroupIoMax)?;
+        let device_cpu_max = parser
+            .convert::<Tuple<String, CpuBandwidth>>("device_cpu_max")
+            .map_err(Error::ParseCgroup)?
+            .map(|v| {
+                v.0.into_iter()
+                    .map(|(id, cpu_max)| DeviceCpuMaxConfig { id, cpu_max })
+                    .collect()
+            });
+
+        Ok(CgroupConfig {
+            path,
+            memory_max,
+            io_max,
+            device_cpu_max,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        // The path is relative to the cgroup mount point, even when it starts
+        // with a `/`, and must stay below it.
+        if let Some(path) = &self.path {
+            let mut components = path
+                .components()
+                .skip_while(|c| matches!(c, Component::RootDir))
+                .peekable();
+            if components.peek().is_none() || !components.all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(ValidationError::InvalidCgroupPath(path.clone()));
             }
         }
 
+        for device_cpu_max in self.device_cpu_max.iter().flatten() {
+            device_cpu_max.cpu_max.validate()?;
+        }
+
         Ok(())
     }
+}
 
-    pub fn backed_by_shared_memory(&self) -> bool {
-        if self.memory.shared || self.memory.hugepages {
-            return true;
-        }
+impl ProcessLimitsConfig {
+    pub const SYNTAX: &'static str = "Resource limits of the VMM process \
+        \"nofile=<max_open_files>,memlock=<max_locked_memory>,\
+        oom_score_adj=<oom_score_adjustment>\"";
 
-        if self.memory.size == 0 {
-            for zone in self.memory.zones.as_ref().unwrap() {
-                if !zone.shared && !zone.hugepages {
-                    return false;
-                }
+    pub fn parse(process_limits: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("nofile").add("memlock").add("oom_score_adj");
+        parser
+            .parse(process_limits)
+            .map_err(Error::ParseProcessLimits)?;
+
+        let nofile = parser
+            .convert("nofile")
+            .map_err(Error::ParseProcessLimits)?;
+        let memlock = parser
+            .convert::<ByteSized>("memlock")
+            .map_err(Error::ParseProcessLimits)?
+            .map(|v| v.0);
+        let oom_score_adj = parser
+            .convert("oom_score_adj")
+            .map_err(Error::ParseProcessLimits)?;
+
+        Ok(ProcessLimitsConfig {
+            nofile,
+            memlock,
+            oom_score_adj,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        if let Some(adj) = self.oom_score_adj {
+            if !(-1000..=1000).contains(&adj) {
+                return Err(ValidationError::InvalidOomScoreAdj(adj));
             }
-            true
-        } else {
-            false
         }
+
+        Ok(())
     }
+}
 
-    // Also enables virtio-iommu if the config needs it
-    // Returns the list of unique identifiers provided through the
-    // configuration.
-    pub fn validate(&mut self) -> ValidationResult<BTreeSet<String>> {
-        let mut id_list = BTreeSet::new();
+impl SeccompOverrideConfig {
+    pub const SYNTAX: &'static str = "Syscalls allowed or denied on top of the \
+        seccomp filter of the threads of a device \
+        \"id=<device_id>,allow=<list_of_syscalls>,deny=<list_of_syscalls>\"";
 
-        self.payload
-            .as_ref()
-            .ok_or(ValidationError::KernelMissing)?;
+    pub fn parse(seccomp_override: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("id").add("allow").add("deny");
+        parser
+            .parse(seccomp_override)
+            .map_err(Error::ParseSeccompOverride)?;
+
+        let id = parser
+            .get("id")
+            .ok_or(Error::ParseSeccompOverrideIdMissing)?;
+        let syscalls = |option| -> Result<Vec<String>> {
+            Ok(parser
+                .convert::<StringList>(option)
+                .map_err(Error::ParseSeccompOverride)?
+                .unwrap_or_default()
+                .0)
+        };
 
-        #[cfg(feature = "tdx")]
-        {
-            let tdx_enabled = self.platform.as_ref().map(|p| p.tdx).unwrap_or(false);
-            // At this point we know payload isn't None.
-            if tdx_enabled && self.payload.as_ref().unwrap().firmware.is_none() {
-                return Err(ValidationError::TdxFirmwareMissing);
-            }
-            if tdx_enabled && (self.cpus.max_vcpus != self.cpus.boot_vcpus) {
-                return Err(ValidationError::TdxNoCpuHotplug);
+        Ok(SeccompOverrideConfig {
+            id,
+            allow: syscalls("allow")?,
+            deny: syscalls("deny")?,
+        })
+    }
+
+    pub fn validate(&self) -> ValidationResult<()> {
+        for syscall in self.allow.iter().chain(&self.deny) {
+            if seccomp_filters::syscall_number(syscall).is_none() {
+                return Err(ValidationError::UnknownSeccompSyscall(syscall.clone()));
             }
         }
-
-        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
-        {
-            return Err(ValidationError::DoubleTtyMode);
+        if self.allow.iter().any(|syscall| self.deny.contains(syscall)) {
+            return Err(ValidationError::InvalidSeccompOverride(self.id.clone()));
         }
 
-        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+        Ok(())
+    }
+}
 
-        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
-            return Err(ValidationError::ConsoleFileMissing);
-        }
+// The file descriptor is duplicated, so that the payload can be loaded again
+// from it when the VM reboots, and rewound as both share the same offset.
+fn open_payload_file(path: &Option<PathBuf>, fd: Option<i32>) -> io::Result<Option<File>> {
+    if let Some(fd) = fd {
+        // SAFETY: the file descriptor is among the preserved ones of the
+        // configuration holding the payload, and remains open as long as it
+        // does.
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+        let mut file = File::from(fd);
+        file.rewind()?;
+        return Ok(Some(file));
+    }
 
-        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
-            return Err(ValidationError::CpusMaxLowerThanBoot);
-        }
+    path.as_ref().map(File::open).transpose()
+}
 
-        if let Some(disks) = &self.disks {
-            for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
-                if disk.vhost_user && !self.backed_by_shared_memory() {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-                if disk.vhost_user && disk.vhost_socket.is_none() {
-                    return Err(ValidationError::VhostUserMissingSocket);
-                }
-                disk.validate(self)?;
-                self.iommu |= disk.iommu;
+impl PayloadConfig {
+    pub fn has_firmware(&self) -> bool {
+        self.firmware.is_some() || self.firmware_fd.is_some()
+    }
 
-                Self::validate_identifier(&mut id_list, &disk.id)?;
-            }
-        }
+    pub fn has_kernel(&self) -> bool {
+        self.kernel.is_some() || self.kernel_fd.is_some()
+    }
 
-        if let Some(nets) = &self.net {
-            for net in nets {
-                if net.vhost_user && !self.backed_by_shared_memory() {
-                    return Err(ValidationError::VhostUserRequiresSharedMemory);
-                }
-                net.validate(self)?;
-                self.iommu |= net.iommu;
+    pub fn has_initramfs(&self) -> bool {
+        self.initramfs.is_some() || self.initramfs_fd.is_some()
+    }
 
-                Self::validate_identifier(&mut id_list, &net.id)?;
-            }
+    /// Open the firmware, from its file descriptor or its path.
+    pub fn open_firmware(&self) -> io::Result<Option<File>> {
+        open_payload_file(&self.firmware, self.firmware_fd)
+    }
+
+    /// Open the kernel, from its file descriptor or its path.
+    pub fn open_kernel(&self) -> io::Result<Option<File>> {
+        open_payload_file(&self.kernel, self.kernel_fd)
+    }
+
+    /// Open the initramfs, from its file descriptor or its path.
+    pub fn open_initramfs(&self) -> io::Result<Option<File>> {
+        open_payload_file(&self.initramfs, self.initramfs_fd)
+    }
+
+    pub fn validate(
+        &self,
+        #[cfg(feature = "sev_snp")] sev_snp_enabled: bool,
+    ) -> ValidationResult<()> {
+        if (self.firmware.is_some() && self.firmware_fd.is_some())
+            || (self.kernel.is_some() && self.kernel_fd.is_some())
+            || (self.initramfs.is_some() && self.initramfs_fd.is_some())
+        {
+            return Err(ValidationError::PayloadPathAndFd);
         }
 
-        if let Some(fses) = &self.fs {
-            if !fses.is_empty() && !self.backed_by_shared_memory() {
-                return Err(ValidationError::VhostUserRequiresSharedMemory);
+        #[cfg(feature = "sev_snp")]
+        if self.igvm.is_some() {
+            if self.has_firmware() || self.has_kernel() || self.has_initramfs() {
+                return Err(ValidationError::IgvmWithOtherPayload);
             }
-            for fs in fses {
-                fs.validate(self)?;
+            if !sev_snp_enabled {
+                return Err(ValidationError::IgvmWithoutSevSnp);
+            }
+        }
 
-                Self::validate_identifier(&mut id_list, &fs.id)?;
+        #[cfg(feature = "sev_snp")]
+        if let Some(host_data) = &self.host_data {
+            if self.igvm.is_none() {
+                return Err(ValidationError::HostDataWithoutIgvm);
+            }
+            if host_data.len() != 64 || !host_data.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ValidationError::InvalidHostData(host_data.clone()));
             }
         }
 
-        if let Some(pmems) = &self.pmem {
-            for pmem in pmems {
-                pmem.validate(self)?;
-                self.iommu |= pmem.iommu;
+        Ok(())
+    }
+}
 
-                Self::validate_identifier(&mut id_list, &pmem.id)?;
-            }
-        }
+impl LandlockConfig {
+    pub const SYNTAX: &'static str = "Landlock rules allowing the VMM to access \
+        a path and its content \"path=<path/to/dir/or/file>,access=r|w|rw\"";
 
-        self.iommu |= self.rng.iommu;
-        self.iommu |= self.console.iommu;
+    pub fn parse(landlock_rule: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("access");
+        parser
+            .parse(landlock_rule)
+            .map_err(Error::ParseLandlockRules)?;
 
-        if let Some(t) = &self.cpus.topology {
-            if t.threads_per_core == 0
-                || t.cores_per_die == 0
-                || t.dies_per_package == 0
-                || t.packages == 0
-            {
-                return Err(ValidationError::CpuTopologyZeroPart);
-            }
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseLandlockMissingFields)?;
+        let access = parser
+            .convert("access")
+            .map_err(Error::ParseLandlockRules)?
+            .ok_or(Error::ParseLandlockMissingFields)?;
 
-            // The setting of dies doesen't apply on AArch64.
-            // Only '1' value is accepted, so its impact on the vcpu topology
-            // setting can be ignored.
-            #[cfg(target_arch = "aarch64")]
-            if t.dies_per_package != 1 {
-                return Err(ValidationError::CpuTopologyDiesPerPackage);
-            }
+        Ok(LandlockConfig { path, access })
+    }
+}
 
-            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
-            if total != self.cpus.max_vcpus {
-                return Err(ValidationError::CpuTopologyCount);
-            }
-        }
+impl GuestAgentConfig {
+    pub const SYNTAX: &'static str = "Guest agent channel parameters \
+        \"port=<vsock_port>,timeout=<command_timeout_in_seconds>\"";
 
-        if let Some(hugepage_size) = &self.memory.hugepage_size {
-            if !self.memory.hugepages {
-                return Err(ValidationError::HugePageSizeWithoutHugePages);
-            }
-            if !hugepage_size.is_power_of_two() {
-                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
-            }
+    pub fn parse(guest_agent: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("port").add("timeout");
+        parser.parse(guest_agent).map_err(Error::ParseGuestAgent)?;
+
+        let port = parser
+            .convert("port")
+            .map_err(Error::ParseGuestAgent)?
+            .unwrap_or(DEFAULT_GUEST_AGENT_PORT);
+        let timeout = parser
+            .convert("timeout")
+            .map_err(Error::ParseGuestAgent)?
+            .unwrap_or(DEFAULT_GUEST_AGENT_TIMEOUT);
+
+        Ok(GuestAgentConfig { port, timeout })
+    }
+
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if vm_config.vsock.is_none() {
+            return Err(ValidationError::GuestAgentWithoutVsock);
         }
 
-        if let Some(user_devices) = &self.user_devices {
-            if !user_devices.is_empty() && !self.backed_by_shared_memory() {
-                return Err(ValidationError::UserDevicesRequireSharedMemory);
-            }
+        Ok(())
+    }
+}
 
-            for user_device in user_devices {
-                user_device.validate(self)?;
+impl PvPanicPolicyConfig {
+    pub const SYNTAX: &'static str = "pvpanic policy applied on guest panic \
+        \"action=pause|coredump|restart|kdump,coredump_path=<coredump_file_path>,\
+        kdump_dir=<kdump_directory_path>,restart=on|off\"";
 
-                Self::validate_identifier(&mut id_list, &user_device.id)?;
-            }
-        }
+    pub fn parse(pvpanic_policy: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("action")
+            .add("coredump_path")
+            .add("kdump_dir")
+            .add("restart");
+        parser
+            .parse(pvpanic_policy)
+            .map_err(Error::ParsePvPanicPolicy)?;
 
-        if let Some(vdpa_devices) = &self.vdpa {
-            for vdpa_device in vdpa_devices {
-                vdpa_device.validate(self)?;
-                self.iommu |= vdpa_device.iommu;
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParsePvPanicPolicy)?
+            .unwrap_or_default();
+        let coredump_path = parser.get("coredump_path").map(PathBuf::from);
+        let kdump_dir = parser.get("kdump_dir").map(PathBuf::from);
+        let restart = parser
+            .convert::<Toggle>("restart")
+            .map_err(Error::ParsePvPanicPolicy)?
+            .unwrap_or(Toggle(false))
+            .0;
 
-                Self::validate_identifier(&mut id_list, &vdpa_device.id)?;
-            }
-        }
+        Ok(PvPanicPolicyConfig {
+            action,
+            coredump_path,
+            kdump_dir,
+            restart,
+        })
+    }
 
-        if let Some(balloon) = &self.balloon {
-            let mut ram_size = self.memory.size;
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        let watchdog_panic =
+            vm_config.watchdog && vm_config.watchdog_action == WatchdogAction::Panic;
+        if !vm_config.pvpanic && !watchdog_panic {
+            return Err(ValidationError::PvPanicPolicyWithoutDevice);
+        }
 
-            if let Some(zones) = &self.memory.zones {
-                for zone in zones {
-                    ram_size += zone.size;
+        match self.action {
+            PvPanicAction::Coredump => {
+                if !cfg!(all(target_arch = "x86_64", feature = "guest_debug")) {
+                    return Err(ValidationError::PvPanicCoredumpUnsupported);
+                }
+                if self.coredump_path.is_none() {
+                    return Err(ValidationError::PvPanicCoredumpPathMissing);
                 }
             }
-
-            if balloon.size.iter().sum::<u64>() >= ram_size {
-                return Err(ValidationError::BalloonLargerThanRam(
-                    balloon.size,
-                    ram_size,
-                ));
+            PvPanicAction::Kdump => {
+                if !cfg!(all(target_arch = "x86_64", feature = "guest_debug")) {
+                    return Err(ValidationError::PvPanicCoredumpUnsupported);
+                }
+                if self.kdump_dir.is_none() {
+                    return Err(ValidationError::PvPanicKdumpDirMissing);
+                }
             }
-        }
-
-        if let Some(devices) = &self.devices {
-            let mut device_paths = BTreeSet::new();
-            for device in devices {
-                if !device_paths.insert(device.path.to_string_lossy()) {
-                    return Err(ValidationError::DuplicateDevicePath(
-                        device.path.to_string_lossy().to_string(),
-                    ));
+            // The guest is either left paused or already restarted.
+            PvPanicAction::Pause | PvPanicAction::Restart => {
+                if self.restart {
+                    return Err(ValidationError::PvPanicRestartUnsupported);
                 }
-
-                device.validate(self)?;
-                self.iommu |= device.iommu;
-
-                Self::validate_identifier(&mut id_list, &device.id)?;
             }
         }
 
-        if let Some(vsock) = &self.vsock {
-            vsock.validate(self)?;
-            self.iommu |= vsock.iommu;
+        Ok(())
+    }
+}
 
-            Self::validate_identifier(&mut id_list, &vsock.id)?;
-        }
+impl OnCrashConfig {
+    pub const SYNTAX: &'static str = "Policy applied when the guest crashes \
+        \"action=restart|preserve|coredump-and-restart,coredump_dir=<coredump_directory_path>,\
+        backoff=<initial_restart_delay_in_seconds>,max_backoff=<maximum_restart_delay_in_seconds>,\
+        max_restarts=<maximum_number_of_crashes_in_a_row>\"";
 
-        let num_pci_segments = match &self.platform {
-            Some(platform_config) => platform_config.num_pci_segments,
-            None => 1,
-        };
-        if let Some(numa) = &self.numa {
-            let mut used_numa_node_memory_zones = HashMap::new();
-            let mut used_pci_segments = HashMap::new();
-            for numa_node in numa.iter() {
-                if let Some(memory_zones) = numa_node.memory_zones.clone() {
-                    for memory_zone in memory_zones.iter() {
-                        if !used_numa_node_memory_zones.contains_key(memory_zone) {
-                            used_numa_node_memory_zones
-                                .insert(memory_zone.to_string(), numa_node.guest_numa_id);
-                        } else {
-                            return Err(ValidationError::MemoryZoneReused(
-                                memory_zone.to_string(),
-                                *used_numa_node_memory_zones.get(memory_zone).unwrap(),
-                                numa_node.guest_numa_id,
-                            ));
-                        }
-                    }
-                }
+    pub fn parse(on_crash: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("action")
+            .add("coredump_dir")
+            .add("backoff")
+            .add("max_backoff")
+            .add("max_restarts");
+        parser.parse(on_crash).map_err(Error::ParseOnCrash)?;
+
+        let action = parser
+            .convert("action")
+            .map_err(Error::ParseOnCrash)?
+            .unwrap_or_default();
+        let coredump_dir = parser.get("coredump_dir").map(PathBuf::from);
+        let backoff = parser
+            .convert("backoff")
+            .map_err(Error::ParseOnCrash)?
+            .unwrap_or_else(default_oncrashconfig_backoff);
+        let max_backoff = parser
+            .convert("max_backoff")
+            .map_err(Error::ParseOnCrash)?
+            .unwrap_or_else(default_oncrashconfig_max_backoff);
+        let max_restarts = parser
+            .convert("max_restarts")
+            .map_err(Error::ParseOnCrash)?;
+
+        Ok(OnCrashConfig {
+            action,
+            coredump_dir,
+            backoff,
+            max_backoff,
+            max_restarts,
+        })
+    }
 
-                if let Some(pci_segments) = numa_node.pci_segments.clone() {
-                    for pci_segment in pci_segments.iter() {
-                        if *pci_segment >= num_pci_segments {
-                            return Err(ValidationError::InvalidPciSegment(*pci_segment));
-                        }
-                        if *pci_segment == 0 && numa_node.guest_numa_id != 0 {
-                            return Err(ValidationError::DefaultPciSegmentInvalidNode(
-                                numa_node.guest_numa_id,
-                            ));
-                        }
-                        if !used_pci_segments.contains_key(pci_segment) {
-                            used_pci_segments.insert(*pci_segment, numa_node.guest_numa_id);
-                        } else {
-                            return Err(ValidationError::PciSegmentReused(
-                                *pci_segment,
-                                *used_pci_segments.get(pci_segment).unwrap(),
-                                numa_node.guest_numa_id,
-                            ));
-                        }
-                    }
-                }
-            }
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if vm_config.pvpanic_policy.is_some() {
+            return Err(ValidationError::OnCrashWithPvPanicPolicy);
         }
 
-        if let Some(zones) = &self.memory.zones {
-            for zone in zones.iter() {
-                let id = zone.id.clone();
-                Self::validate_identifier(&mut id_list, &Some(id))?;
+        if self.action == OnCrashAction::CoredumpAndRestart {
+            if !cfg!(all(target_arch = "x86_64", feature = "guest_debug")) {
+                return Err(ValidationError::OnCrashCoredumpUnsupported);
+            }
+            if self.coredump_dir.is_none() {
+                return Err(ValidationError::OnCrashCoredumpDirMissing);
             }
         }
 
-        #[cfg(target_arch = "x86_64")]
-        if let Some(sgx_epcs) = &self.sgx_epc {
-            for sgx_epc in sgx_epcs.iter() {
-                let id = sgx_epc.id.clone();
-                Self::validate_identifier(&mut id_list, &Some(id))?;
-            }
+        if self.max_backoff < self.backoff {
+            return Err(ValidationError::OnCrashInvalidBackoff);
         }
 
-        self.platform.as_ref().map(|p| p.validate()).transpose()?;
-        self.iommu |= self
-            .platform
-            .as_ref()
-            .map(|p| p.iommu_segments.is_some())
-            .unwrap_or_default();
+        Ok(())
+    }
+}
 
-        Ok(id_list)
+impl WatchdogEscalationConfig {
+    pub const SYNTAX: &'static str = "Escalation of the virtio-watchdog resets to a power off \
+        \"resets=<maximum_number_of_resets>,window=<window_of_the_resets_in_seconds>\"";
+
+    pub fn parse(watchdog_escalation: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("resets").add("window");
+        parser
+            .parse(watchdog_escalation)
+            .map_err(Error::ParseWatchdogEscalation)?;
+
+        let resets = parser
+            .convert("resets")
+            .map_err(Error::ParseWatchdogEscalation)?
+            .unwrap_or_else(default_watchdogescalationconfig_resets);
+        let window = parser
+            .convert("window")
+            .map_err(Error::ParseWatchdogEscalation)?
+            .unwrap_or_else(default_watchdogescalationconfig_window);
+
+        Ok(WatchdogEscalationConfig { resets, window })
     }
 
-    pub fn parse(vm_params: VmParams) -> Result<Self> {
-        let mut disks: Option<Vec<DiskConfig>> = None;
-        if let Some(disk_list) = &vm_params.disks {
-            let mut disk_config_list = Vec::new();
-            for item in disk_list.iter() {
-                let disk_config = DiskConfig::parse(item)?;
-                disk_config_list.push(disk_config);
-            }
-            disks = Some(disk_config_list);
+    pub fn validate(&self, vm_config: &VmConfig) -> ValidationResult<()> {
+        if !vm_config.watchdog || vm_config.watchdog_action != WatchdogAction::Reset {
+            return Err(ValidationError::WatchdogEscalationWithoutReset);
         }
 
-        let mut net: Option<Vec<NetConfig>> = None;
-        if let Some(net_list) = &vm_params.net {
-            let mut net_config_list = Vec::new();
-            for item in net_list.iter() {
-                let net_config = NetConfig::parse(item)?;
-                net_config_list.push(net_config);
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    fn validate_identifier(
+        id_list: &mut BTreeSet<String>,
+        id: &Option<String>,
+    ) -> ValidationResult<()> {
+        if let Some(id) = id.as_ref() {
+            if id.starts_with("__") {
+                return Err(ValidationError::InvalidIdentifier(id.clone()));
+            }
+
+            if !id_list.insert(id.clone()) {
+                return Err(ValidationError::IdentifierNotUnique(id.clone()));
             }
-            net = Some(net_config_list);
         }
 
-        let rng = RngConfig::parse(vm_params.rng)?;
+        Ok(())
+    }
 
-        let mut balloon: Option<BalloonConfig> = None;
-        if let Some(balloon_params) = &vm_params.balloon {
-            balloon = Some(BalloonConfig::parse(balloon_params)?);
+    pub fn backed_by_shared_memory(&self) -> bool {
+        if self.memory.shared || self.memory.hugepages {
+            return true;
         }
 
-        let mut fs: Option<Vec<FsConfig>> = None;
-        if let Some(fs_list) = &vm_params.fs {
-            let mut fs_config_list = Vec::new();
-            for item in fs_list.iter() {
-                fs_config_list.push(FsConfig::parse(item)?);
+        if self.memory.size == 0 {
+            for zone in self.memory.zones.as_ref().unwrap() {
+                if !zone.shared && !zone.hugepages {
+                    return false;
+                }
             }
-            fs = Some(fs_config_list);
+            true
+        } else {
+            false
         }
+    }
 
-        let mut pmem: Option<Vec<PmemConfig>> = None;
-        if let Some(pmem_list) = &vm_params.pmem {
-            let mut pmem_config_list = Vec::new();
-            for item in pmem_list.iter() {
-                let pmem_config = PmemConfig::parse(item)?;
-                pmem_config_list.push(pmem_config);
-            }
-            pmem = Some(pmem_config_list);
-        }
+    // Also enables virtio-iommu if the config needs it
+    // Returns the list of unique identifiers provided through the
+    // configuration.
+    pub fn validate(&mut self) -> ValidationResult<BTreeSet<String>> {
+        let mut id_list = BTreeSet::new();
 
-        let console = ConsoleConfig::parse(vm_params.console)?;
-        let serial = ConsoleConfig::parse(vm_params.serial)?;
+        self.payload
+            .as_ref()
+            .ok_or(ValidationError::KernelMissing)?
+            .validate(
+                #[cfg(feature = "sev_snp")]
+                self.is_sev_snp_enabled(),
+            )?;
 
-        let mut devices: Option<Vec<DeviceConfig>> = None;
-        if let Some(device_list) = &vm_params.devices {
-            let mut device_config_list = Vec::new();
-            for item in device_list.iter() {
-                let device_config = DeviceConfig::parse(item)?;
-                device_config_list.push(device_config);
+        #[cfg(feature = "tdx")]
+        {
+            let tdx_enabled = self.platform.as_ref().map(|p| p.tdx).unwrap_or(false);
+            // At this point we know payload isn't None.
+            if tdx_enabled && !self.payload.as_ref().unwrap().has_firmware() {
+                return Err(ValidationError::TdxFirmwareMissing);
+            }
+            if tdx_enabled && (self.cpus.max_vcpus != self.cpus.boot_vcpus) {
+                return Err(ValidationError::TdxNoCpuHotplug);
             }
-            devices = Some(device_config_list);
         }
 
-        let mut user_devices: Option<Vec<UserDeviceConfig>> = None;
-        if let Some(user_device_list) = &vm_params.user_devices {
-            let mut user_device_config_list = Vec::new();
-            for item in user_device_list.iter() {
-                let user_device_config = UserDeviceConfig::parse(item)?;
-                user_device_config_list.push(user_device_config);
-            }
-            user_devices = Some(user_device_config_list);
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(ValidationError::DoubleTtyMode);
         }
 
-        let mut vdpa: Option<Vec<VdpaConfig>> = None;
-        if let Some(vdpa_list) = &vm_params.vdpa {
-            let mut vdpa_config_list = Vec::new();
-            for item in vdpa_list.iter() {
-                let vdpa_config = VdpaConfig::parse(item)?;
-                vdpa_config_list.push(vdpa_config);
-            }
-            vdpa = Some(vdpa_config_list);
+        if self.console.mode == ConsoleOutputMode::File && self.console.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
         }
 
-        let mut vsock: Option<VsockConfig> = None;
-        if let Some(vs) = &vm_params.vsock {
-            let vsock_config = VsockConfig::parse(vs)?;
-            vsock = Some(vsock_config);
+        if self.serial.mode == ConsoleOutputMode::File && self.serial.file.is_none() {
+            return Err(ValidationError::ConsoleFileMissing);
         }
 
-        let platform = vm_params.platform.map(PlatformConfig::parse).transpose()?;
+        if self.console.mode == ConsoleOutputMode::Tcp {
+            return Err(ValidationError::ConsoleTcpUnsupported);
+        }
 
-        #[cfg(target_arch = "x86_64")]
-        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
-        #[cfg(target_arch = "x86_64")]
-        {
-            if let Some(sgx_epc_list) = &vm_params.sgx_epc {
-                let mut sgx_epc_config_list = Vec::new();
-                for item in sgx_epc_list.iter() {
-                    let sgx_epc_config = SgxEpcConfig::parse(item)?;
-                    sgx_epc_config_list.push(sgx_epc_config);
-                }
-                sgx_epc = Some(sgx_epc_config_list);
-            }
+        if self.serial.mode == ConsoleOutputMode::Tcp && self.serial.tcp.is_none() {
+            return Err(ValidationError::ConsoleTcpAddressMissing);
         }
 
-        let mut numa: Option<Vec<NumaConfig>> = None;
-        if let Some(numa_list) = &vm_params.numa {
-            let mut numa_config_list = Vec::new();
-            for item in numa_list.iter() {
-                let numa_config = NumaConfig::parse(item)?;
-                numa_config_list.push(numa_config);
-            }
-            numa = Some(numa_config_list);
+        if self.serial.mode == ConsoleOutputMode::Socket && self.serial.socket.is_none() {
+            let socket_dir = self
+                .serial
+                .socket_dir
+                .as_ref()
+                .ok_or(ValidationError::ConsoleSocketPathMissing)?;
+            self.serial.socket = Some(socket_dir.join(SERIAL_SOCKET_NAME));
         }
 
-        let payload = if vm_params.kernel.is_some() || vm_params.firmware.is_some() {
-            Some(PayloadConfig {
-                kernel: vm_params.kernel.map(PathBuf::from),
-                initramfs: vm_params.initramfs.map(PathBuf::from),
-                cmdline: vm_params.cmdline.map(|s| s.to_string()),
-                firmware: vm_params.firmware.map(PathBuf::from),
-            })
-        } else {
-            None
-        };
+        if (self.serial.telnet && self.serial.mode != ConsoleOutputMode::Tcp) || self.console.telnet
+        {
+            return Err(ValidationError::ConsoleTelnetWithoutTcp);
+        }
 
-        let mut tpm: Option<TpmConfig> = None;
-        if let Some(tc) = vm_params.tpm {
-            let tpm_conf = TpmConfig::parse(tc)?;
-            tpm = Some(TpmConfig {
-                socket: tpm_conf.socket,
-            });
+        if let Some(debug_console) = &self.debug_console {
+            debug_console.validate()?;
         }
 
-        #[cfg(feature = "guest_debug")]
-        let gdb = vm_params.gdb;
+        if self.console.max_ports == 0 || self.console.max_ports > VIRTIO_CONSOLE_MAX_PORTS {
+            return Err(ValidationError::InvalidConsoleMaxPorts(
+                self.console.max_ports,
+            ));
+        }
 
-        let mut config = VmConfig {
-            cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
-            payload,
-            disks,
-            net,
-            rng,
-            balloon,
-            fs,
-            pmem,
-            serial,
-            console,
-            devices,
-            user_devices,
-            vdpa,
-            vsock,
-            pvpanic: vm_params.pvpanic,
-            iommu: false, // updated in VmConfig::validate()
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc,
-            numa,
-            watchdog: vm_params.watchdog,
-            #[cfg(feature = "guest_debug")]
-            gdb,
-            platform,
-            tpm,
-            preserved_fds: None,
-        };
-        config.validate().map_err(Error::Validation)?;
-        Ok(config)
-    }
+        if self.serial.max_ports != 1 {
+            return Err(ValidationError::SerialMaxPortsUnsupported);
+        }
 
-    pub fn remove_device(&mut self, id: &str) -> bool {
-        let mut removed = false;
+        if let Some(console_ports) = &self.console_ports {
+            if self.console.mode == ConsoleOutputMode::Off || self.console.max_ports == 1 {
+                return Err(ValidationError::ConsolePortsWithoutMultiport);
+            }
+            // The first port is always the console itself.
+            if console_ports.len() >= self.console.max_ports as usize {
+                return Err(ValidationError::TooManyConsolePorts(
+                    console_ports.len(),
+                    self.console.max_ports,
+                ));
+            }
+            for console_port in console_ports.iter() {
+                Self::validate_identifier(&mut id_list, &console_port.id)?;
+                if self.console.socket_dir.is_some() {
+                    console_port.validate_socket_name()?;
+                }
+            }
+        }
 
-        // Remove if VFIO device
-        if let Some(devices) = self.devices.as_mut() {
-            let len = devices.len();
-            devices.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= devices.len() != len;
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            return Err(ValidationError::CpusMaxLowerThanBoot);
         }
 
-        // Remove if VFIO user device
-        if let Some(user_devices) = self.user_devices.as_mut() {
-            let len = user_devices.len();
-            user_devices.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= user_devices.len() != len;
+        if self.cpus.boot_vcpu_ids().len() < self.cpus.boot_vcpus as usize {
+            return Err(ValidationError::InvalidRemovedVcpus);
         }
 
-        // Remove if disk device
-        if let Some(disks) = self.disks.as_mut() {
-            let len = disks.len();
-            disks.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= disks.len() != len;
+        if let Some(affinity) = &self.cpus.affinity {
+            for a in affinity {
+                if a.vcpu >= self.cpus.max_vcpus {
+                    return Err(ValidationError::InvalidCpuAffinityVcpu(a.vcpu));
+                }
+            }
         }
 
-        // Remove if fs device
-        if let Some(fs) = self.fs.as_mut() {
-            let len = fs.len();
-            fs.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= fs.len() != len;
+        if let Some(efficiency_cores) = &self.cpus.efficiency_cores {
+            #[cfg(not(target_arch = "x86_64"))]
+            if !efficiency_cores.is_empty() {
+                return Err(ValidationError::EfficiencyCoresUnsupported);
+            }
+            for core in efficiency_cores {
+                if *core >= self.cpus.max_vcpus {
+                    return Err(ValidationError::InvalidEfficiencyCore(*core));
+                }
+            }
         }
 
-        // Remove if net device
-        if let Some(net) = self.net.as_mut() {
-            let len = net.len();
-            net.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= net.len() != len;
+        if let Some(features) = &self.cpus.kvm_hyperv_features {
+            if !self.cpus.kvm_hyperv {
+                return Err(ValidationError::KvmHypervFeaturesWithoutKvmHyperv);
+            }
+            if features.contains(&HypervFeature::Stimer)
+                && !features.contains(&HypervFeature::Synic)
+            {
+                return Err(ValidationError::StimerWithoutSynic);
+            }
         }
 
-        // Remove if pmem device
-        if let Some(pmem) = self.pmem.as_mut() {
-            let len = pmem.len();
-            pmem.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= pmem.len() != len;
+        if let Some(pmu_events) = &self.cpus.pmu_events {
+            if !self.cpus.pmu {
+                return Err(ValidationError::PmuEventsWithoutPmu);
+            }
+            if pmu_events.is_empty() {
+                return Err(ValidationError::EmptyPmuEvents);
+            }
         }
 
-        // Remove if vDPA device
-        if let Some(vdpa) = self.vdpa.as_mut() {
-            let len = vdpa.len();
-            vdpa.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
-            removed |= vdpa.len() != len;
+        #[cfg(not(target_arch = "aarch64"))]
+        if !self.cpus.pmu || self.cpus.pmu_events.is_some() {
+            return Err(ValidationError::PmuControlUnsupported);
         }
 
-        // Remove if vsock device
-        if let Some(vsock) = self.vsock.as_ref() {
-            if vsock.id.as_ref().map(|id| id.as_ref()) == Some(id) {
-                self.vsock = None;
-                removed = true;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(model) = &self.cpus.model {
+                if arch::x86_64::cpu_model::find_cpu_model(model).is_none() {
+                    return Err(ValidationError::UnknownCpuModel(model.clone()));
+                }
+            }
+            for feature in self
+                .cpus
+                .cpuid_add
+                .iter()
+                .chain(self.cpus.cpuid_remove.iter())
+                .flatten()
+            {
+                if arch::x86_64::cpu_model::find_cpuid_feature(feature).is_none() {
+                    return Err(ValidationError::UnknownCpuidFeature(feature.clone()));
+                }
             }
         }
+        #[cfg(not(target_arch = "x86_64"))]
+        if self.cpus.model.is_some()
+            || self.cpus.cpuid_add.is_some()
+            || self.cpus.cpuid_remove.is_some()
+        {
+            return Err(ValidationError::CpuModelUnsupported);
+        }
 
-        removed
-    }
+        for bandwidth in [&self.cpus.cpu_max, &self.cpus.vcpu_max]
+            .into_iter()
+            .flatten()
+        {
+            bandwidth.validate()?;
+        }
 
-    /// # Safety
-    /// To use this safely, the caller must guarantee that the input
-    /// fds are all valid.
-    pub unsafe fn add_preserved_fds(&mut self, mut fds: Vec<i32>) {
-        if fds.is_empty() {
-            return;
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.validate()?;
         }
 
-        if let Some(preserved_fds) = &self.preserved_fds {
-            fds.append(&mut preserved_fds.clone());
+        if let Some(process_limits) = &self.process_limits {
+            process_limits.validate()?;
         }
 
-        self.preserved_fds = Some(fds);
-    }
+        let mut seccomp_override_ids = BTreeSet::new();
+        for seccomp_override in self.seccomp_overrides.iter().flatten() {
+            seccomp_override.validate()?;
+            if !seccomp_override_ids.insert(&seccomp_override.id) {
+                return Err(ValidationError::InvalidSeccompOverride(
+                    seccomp_override.id.clone(),
+                ));
+            }
+        }
 
-    #[cfg(feature = "tdx")]
-    pub fn is_tdx_enabled(&self) -> bool {
-        self.platform.as_ref().map(|p| p.tdx).unwrap_or(false)
-    }
+        if let Some(pvpanic_policy) = &self.pvpanic_policy {
+            pvpanic_policy.validate(self)?;
+        }
 
-    #[cfg(feature = "sev_snp")]
-    pub fn is_sev_snp_enabled(&self) -> bool {
-        self.platform.as_ref().map(|p| p.sev_snp).unwrap_or(false)
-    }
-}
+        if let Some(on_crash) = &self.on_crash {
+            on_crash.validate(self)?;
+        }
 
-impl Clone for VmConfig {
-    fn clone(&self) -> Self {
-        VmConfig {
-            cpus: self.cpus.clone(),
-            memory: self.memory.clone(),
-            payload: self.payload.clone(),
-            disks: self.disks.clone(),
-            net: self.net.clone(),
-            rng: self.rng.clone(),
-            balloon: self.balloon.clone(),
-            fs: self.fs.clone(),
-            pmem: self.pmem.clone(),
-            serial: self.serial.clone(),
-            console: self.console.clone(),
-            devices: self.devices.clone(),
-            user_devices: self.user_devices.clone(),
-            vdpa: self.vdpa.clone(),
-            vsock: self.vsock.clone(),
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc: self.sgx_epc.clone(),
-            numa: self.numa.clone(),
-            platform: self.platform.clone(),
-            tpm: self.tpm.clone(),
-            preserved_fds: self
-                .preserved_fds
-                .as_ref()
-                // SAFETY: FFI call with valid FDs
-                .map(|fds| fds.iter().map(|fd| unsafe { libc::dup(*fd) }).collect()),
-            ..*self
+        if let Some(watchdog_escalation) = &self.watchdog_escalation {
+            watchdog_escalation.validate(self)?;
         }
-    }
-}
 
-impl Drop for VmConfig {
-    fn drop(&mut self) {
-        if let Some(mut fds) = self.preserved_fds.take() {
-            for fd in fds.drain(..) {
-                // SAFETY: FFI call with valid FDs
-                unsafe { libc::close(fd) };
-            }
+        if self.landlock_rules.is_some() && !self.landlock_enable {
+            return Err(ValidationError::LandlockRulesWithoutLandlock);
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use net_util::MacAddr;
-    use std::fs::File;
-    use std::os::unix::io::AsRawFd;
+        if let Some(disks) = &self.disks {
+            for disk in disks {
+                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
+                    return Err(ValidationError::DiskSocketAndPath);
+                }
+                if disk.vhost_user && !self.backed_by_shared_memory() {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+                if disk.vhost_user && disk.vhost_socket.is_none() {
+                    return Err(ValidationError::VhostUserMissingSocket);
+                }
+                disk.validate(self)?;
+                self.iommu |= disk.iommu;
+
+                Self::validate_identifier(&mut id_list, &disk.id)?;
+            }
+        }
+
+        if let Some(nets) = &self.net {
+            for net in nets {
+                if net.vhost_user && !self.backed_by_shared_memory() {
+                    return Err(ValidationError::VhostUserRequiresSharedMemory);
+                }
+                net.validate(self)?;
+                self.iommu |= net.iommu;
+
+                Self::validate_identifier(&mut id_list, &net.id)?;
+            }
+        }
+
+        if let Some(fses) = &self.fs {
+            if !fses.is_empty() && !self.backed_by_shared_memory() {
+                return Err(ValidationError::VhostUserRequiresSharedMemory);
+            }
+            for fs in fses {
+                fs.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &fs.id)?;
+            }
+        }
+
+        if let Some(pmems) = &self.pmem {
+            // Backing files, along with whether a device writes to them.
+            let mut pmem_files = HashMap::new();
+            for pmem in pmems {
+                // A directory leads to an anonymous temporary file being
+                // created for each device, hence it can be shared. A file
+                // can also be shared by the devices discarding their writes,
+                // as they map it privately.
+                if !pmem.file.is_dir() {
+                    let file =
+                        std::fs::canonicalize(&pmem.file).unwrap_or_else(|_| pmem.file.clone());
+                    let writable = !pmem.discard_writes;
+                    if let Some(shared_writable) = pmem_files.insert(file, writable) {
+                        if shared_writable || writable {
+                            return Err(ValidationError::DuplicatePmemFile(
+                                pmem.file.to_string_lossy().to_string(),
+                            ));
+                        }
+                    }
+                }
+                pmem.validate(self)?;
+                self.iommu |= pmem.iommu;
+
+                Self::validate_identifier(&mut id_list, &pmem.id)?;
+            }
+        }
+
+        if let Some(ivshmems) = &self.ivshmem {
+            for ivshmem in ivshmems {
+                ivshmem.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &ivshmem.id)?;
+            }
+        }
+
+        if self.rng.mmio && self.rng.iommu {
+            return Err(ValidationError::MmioPciOption);
+        }
+        self.iommu |= self.rng.iommu;
+        self.iommu |= self.console.iommu;
+
+        if let Some(t) = &self.cpus.topology {
+            if t.threads_per_core == 0
+                || t.cores_per_die == 0
+                || t.dies_per_package == 0
+                || t.packages == 0
+            {
+                return Err(ValidationError::CpuTopologyZeroPart);
+            }
+
+            let total = t.threads_per_core * t.cores_per_die * t.dies_per_package * t.packages;
+            if total != self.cpus.max_vcpus {
+                return Err(ValidationError::CpuTopologyCount);
+            }
+        }
+
+        if let Some(hugepage_size) = &self.memory.hugepage_size {
+            if !self.memory.hugepages {
+                return Err(ValidationError::HugePageSizeWithoutHugePages);
+            }
+            if !hugepage_size.is_power_of_two() {
+                return Err(ValidationError::InvalidHugePageSize(*hugepage_size));
+            }
+        }
+
+        if let Some(user_devices) = &self.user_devices {
+            if !user_devices.is_empty() && !self.backed_by_shared_memory() {
+                return Err(ValidationError::UserDevicesRequireSharedMemory);
+            }
+
+            for user_device in user_devices {
+                user_device.validate(self)?;
+
+                Self::validate_identifier(&mut id_list, &user_device.id)?;
+            }
+        }
+
+        if let Some(vdpa_devices) = &self.vdpa {
+            for vdpa_device in vdpa_devices {
+                vdpa_device.validate(self)?;
+                self.iommu |= vdpa_device.iommu;
+
+                Self::validate_identifier(&mut id_list, &vdpa_device.id)?;
+            }
+        }
+
+        if let Some(balloon) = &self.balloon {
+            let mut ram_size = self.memory.size;
+
+            if let Some(zones) = &self.memory.zones {
+                for zone in zones {
+                    ram_size += zone.size;
+                }
+            }
+
+            if balloon.size.iter().sum::<u64>() >= ram_size {
+                return Err(ValidationError::BalloonLargerThanRam(
+                    balloon.size,
+                    ram_size,
+                ));
+            }
+
+            let valid_thresholds = match (balloon.reclaim_below, balloon.release_above) {
+                (None, Some(_)) => false,
+                (Some(below), Some(above)) => above > below,
+                _ => true,
+            };
+            if !valid_thresholds || balloon.reclaim_step == 0 {
+                return Err(ValidationError::InvalidBalloonReclaim);
+            }
+
+            let valid_hetero_pressure =
+                match (balloon.hetero_pressure_high, balloon.hetero_pressure_low) {
+                    (None, None) => true,
+                    (None, Some(_)) => false,
+                    (Some(high), low) => {
+                        balloon.heterogeneous_memory
+                            && high <= 100
+                            && low.map_or(true, |low| low < high)
+                    }
+                };
+            if !valid_hetero_pressure || balloon.hetero_step == 0 {
+                return Err(ValidationError::InvalidBalloonHeteroPressure);
+            }
+        }
+
+        // The cold memory of the overcommittable zones is paged out while the
+        // balloon reclaims memory on host pressure.
+        let balloon_reclaim = self
+            .balloon
+            .as_ref()
+            .map_or(false, |b| b.reclaim_below.is_some());
+        for zone in self.memory.zones.iter().flatten() {
+            if zone.overcommit && (zone.hugepages || !balloon_reclaim) {
+                return Err(ValidationError::InvalidMemoryZoneOvercommit(
+                    zone.id.clone(),
+                ));
+            }
+        }
+
+        if let Some(devices) = &self.devices {
+            let mut device_paths = BTreeSet::new();
+            for device in devices {
+                if !device_paths.insert(device.path.to_string_lossy()) {
+                    return Err(ValidationError::DuplicateDevicePath(
+                        device.path.to_string_lossy().to_string(),
+                    ));
+                }
+
+                device.validate(self)?;
+                self.iommu |= device.iommu;
+
+                Self::validate_identifier(&mut id_list, &device.id)?;
+            }
+        }
+
+        if let Some(vsock) = &self.vsock {
+            vsock.validate(self)?;
+            self.iommu |= vsock.iommu;
+
+            Self::validate_identifier(&mut id_list, &vsock.id)?;
+        }
+
+        if let Some(guest_agent) = &self.guest_agent {
+            guest_agent.validate(self)?;
+        }
+
+        let num_pci_segments = match &self.platform {
+            Some(platform_config) => platform_config.num_pci_segments,
+            None => 1,
+        };
+        if let Some(numa) = &self.numa {
+            let mut used_numa_node_memory_zones = HashMap::new();
+            let mut used_pci_segments = HashMap::new();
+            for numa_node in numa.iter() {
+                if let Some(memory_zones) = numa_node.memory_zones.clone() {
+                    for memory_zone in memory_zones.iter() {
+                        if !used_numa_node_memory_zones.contains_key(memory_zone) {
+                            used_numa_node_memory_zones
+                                .insert(memory_zone.to_string(), numa_node.guest_numa_id);
+                        } else {
+                            return Err(ValidationError::MemoryZoneReused(
+                                memory_zone.to_string(),
+                                *used_numa_node_memory_zones.get(memory_zone).unwrap(),
+                                numa_node.guest_numa_id,
+                            ));
+                        }
+                    }
+                }
+
+                if let Some(pci_segments) = numa_node.pci_segments.clone() {
+                    for pci_segment in pci_segments.iter() {
+                        if *pci_segment >= num_pci_segments {
+                            return Err(ValidationError::InvalidPciSegment(*pci_segment));
+                        }
+                        if *pci_segment == 0 && numa_node.guest_numa_id != 0 {
+                            return Err(ValidationError::DefaultPciSegmentInvalidNode(
+                                numa_node.guest_numa_id,
+                            ));
+                        }
+                        if !used_pci_segments.contains_key(pci_segment) {
+                            used_pci_segments.insert(*pci_segment, numa_node.guest_numa_id);
+                        } else {
+                            return Err(ValidationError::PciSegmentReused(
+                                *pci_segment,
+                                *used_pci_segments.get(pci_segment).unwrap(),
+                                numa_node.guest_numa_id,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Segments without any configuration span a single bus
+        let mut num_pci_buses = num_pci_segments as u64;
+        if let Some(pci_segments) = &self.pci_segments {
+            let mut configured_pci_segments = HashSet::new();
+            for pci_segment in pci_segments.iter() {
+                pci_segment.validate(num_pci_segments)?;
+                if !configured_pci_segments.insert(pci_segment.pci_segment) {
+                    return Err(ValidationError::DuplicatePciSegmentConfig(
+                        pci_segment.pci_segment,
+                    ));
+                }
+                num_pci_buses += pci_segment.num_buses() - 1;
+            }
+        }
+        if num_pci_buses > MAX_NUM_PCI_BUSES {
+            return Err(ValidationError::TooManyPciBuses(num_pci_buses));
+        }
+
+        // Each root port leads to its own bus, taken from the buses of its
+        // segment following the first one.
+        let mut root_ports = HashMap::new();
+        if let Some(pci_root_ports) = &self.pci_root_ports {
+            for root_port in pci_root_ports.iter() {
+                Self::validate_identifier(&mut id_list, &Some(root_port.id.clone()))?;
+                if root_port.pci_segment >= num_pci_segments {
+                    return Err(ValidationError::InvalidPciSegment(root_port.pci_segment));
+                }
+                root_ports.insert(root_port.id.as_str(), root_port.pci_segment);
+
+                let num_root_ports = root_ports
+                    .values()
+                    .filter(|s| **s == root_port.pci_segment)
+                    .count() as u64;
+                let num_buses = self
+                    .pci_segments
+                    .iter()
+                    .flatten()
+                    .find(|s| s.pci_segment == root_port.pci_segment)
+                    .map(|s| s.num_buses())
+                    .unwrap_or(1);
+                if num_root_ports >= num_buses {
+                    return Err(ValidationError::TooManyPciRootPorts(root_port.pci_segment));
+                }
+            }
+        }
+
+        let mut used_root_ports = HashSet::new();
+        let devices_root_ports = self
+            .disks
+            .iter()
+            .flatten()
+            .map(|d| (&d.pci_root_port, d.pci_segment))
+            .chain(
+                self.net
+                    .iter()
+                    .flatten()
+                    .map(|n| (&n.pci_root_port, n.pci_segment)),
+            )
+            .chain(
+                self.devices
+                    .iter()
+                    .flatten()
+                    .map(|d| (&d.pci_root_port, d.pci_segment)),
+            );
+        for (root_port, pci_segment) in devices_root_ports {
+            if let Some(root_port) = root_port {
+                if root_ports.get(root_port.as_str()) != Some(&pci_segment) {
+                    return Err(ValidationError::UnknownPciRootPort(root_port.clone()));
+                }
+                if !used_root_ports.insert(root_port) {
+                    return Err(ValidationError::PciRootPortReused(root_port.clone()));
+                }
+            }
+        }
+
+        if let Some(zones) = &self.memory.zones {
+            for zone in zones.iter() {
+                let id = zone.id.clone();
+                Self::validate_identifier(&mut id_list, &Some(id))?;
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(sgx_epcs) = &self.sgx_epc {
+            for sgx_epc in sgx_epcs.iter() {
+                let id = sgx_epc.id.clone();
+                Self::validate_identifier(&mut id_list, &Some(id))?;
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        self.msr_filter.as_ref().map(|m| m.validate()).transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        if self.uefi_vars.is_some() && !self.payload.as_ref().map_or(false, |p| p.has_firmware()) {
+            return Err(ValidationError::UefiVarsWithoutFirmware);
+        }
+
+        self.platform.as_ref().map(|p| p.validate()).transpose()?;
+        self.iommu |= self
+            .platform
+            .as_ref()
+            .map(|p| p.iommu_segments.is_some())
+            .unwrap_or_default();
+
+        Ok(id_list)
+    }
+
+    pub fn parse(vm_params: VmParams) -> Result<Self> {
+        let mut disks: Option<Vec<DiskConfig>> = None;
+        if let Some(disk_list) = &vm_params.disks {
+            let mut disk_config_list = Vec::new();
+            for item in disk_list.iter() {
+                let disk_config = DiskConfig::parse(item)?;
+                disk_config_list.push(disk_config);
+            }
+            disks = Some(disk_config_list);
+        }
+
+        let mut net: Option<Vec<NetConfig>> = None;
+        if let Some(net_list) = &vm_params.net {
+            let mut net_config_list = Vec::new();
+            for item in net_list.iter() {
+                let net_config = NetConfig::parse(item)?;
+                net_config_list.push(net_config);
+            }
+            net = Some(net_config_list);
+        }
+
+        let rng = RngConfig::parse(vm_params.rng)?;
+
+        let mut balloon: Option<BalloonConfig> = None;
+        if let Some(balloon_params) = &vm_params.balloon {
+            balloon = Some(BalloonConfig::parse(balloon_params)?);
+        }
+
+        let mut fs: Option<Vec<FsConfig>> = None;
+        if let Some(fs_list) = &vm_params.fs {
+            let mut fs_config_list = Vec::new();
+            for item in fs_list.iter() {
+                fs_config_list.push(FsConfig::parse(item)?);
+            }
+            fs = Some(fs_config_list);
+        }
+
+        let mut pmem: Option<Vec<PmemConfig>> = None;
+        if let Some(pmem_list) = &vm_params.pmem {
+            let mut pmem_config_list = Vec::new();
+            for item in pmem_list.iter() {
+                let pmem_config = PmemConfig::parse(item)?;
+                pmem_config_list.push(pmem_config);
+            }
+            pmem = Some(pmem_config_list);
+        }
+
+        let ivshmem = vm_params
+            .ivshmem
+            .as_ref()
+            .map(|ivshmem_list| {
+                ivshmem_list
+                    .iter()
+                    .map(|item| IvshmemConfig::parse(item))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        let console = ConsoleConfig::parse(vm_params.console)?;
+        let debug_console = vm_params
+            .debug_console
+            .map(DebugConsoleConfig::parse)
+            .transpose()?;
+        let serial = ConsoleConfig::parse(vm_params.serial)?;
+
+        let mut devices: Option<Vec<DeviceConfig>> = None;
+        if let Some(device_list) = &vm_params.devices {
+            let mut device_config_list = Vec::new();
+            for item in device_list.iter() {
+                let device_config = DeviceConfig::parse(item)?;
+                device_config_list.push(device_config);
+            }
+            devices = Some(device_config_list);
+        }
+
+        let mut user_devices: Option<Vec<UserDeviceConfig>> = None;
+        if let Some(user_device_list) = &vm_params.user_devices {
+            let mut user_device_config_list = Vec::new();
+            for item in user_device_list.iter() {
+                let user_device_config = UserDeviceConfig::parse(item)?;
+                user_device_config_list.push(user_device_config);
+            }
+            user_devices = Some(user_device_config_list);
+        }
+
+        let mut console_ports: Option<Vec<ConsolePortConfig>> = None;
+        if let Some(console_port_list) = &vm_params.console_ports {
+            let mut console_port_config_list = Vec::new();
+            for item in console_port_list.iter() {
+                let console_port_config = ConsolePortConfig::parse(item)?;
+                console_port_config_list.push(console_port_config);
+            }
+            console_ports = Some(console_port_config_list);
+        }
+
+        let mut vdpa: Option<Vec<VdpaConfig>> = None;
+        if let Some(vdpa_list) = &vm_params.vdpa {
+            let mut vdpa_config_list = Vec::new();
+            for item in vdpa_list.iter() {
+                let vdpa_config = VdpaConfig::parse(item)?;
+                vdpa_config_list.push(vdpa_config);
+            }
+            vdpa = Some(vdpa_config_list);
+        }
+
+        let mut vsock: Option<VsockConfig> = None;
+        if let Some(vs) = &vm_params.vsock {
+            let vsock_config = VsockConfig::parse(vs)?;
+            vsock = Some(vsock_config);
+        }
+
+        let guest_agent = vm_params
+            .guest_agent
+            .map(GuestAgentConfig::parse)
+            .transpose()?;
+
+        let platform = vm_params.platform.map(PlatformConfig::parse).transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        let msr_filter = vm_params
+            .msr_filter
+            .map(MsrFilterConfig::parse)
+            .transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        let uefi_vars = vm_params.uefi_vars.map(UefiVarsConfig::parse).transpose()?;
+
+        #[cfg(target_arch = "x86_64")]
+        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
+        #[cfg(target_arch = "x86_64")]
+        {
+            if let Some(sgx_epc_list) = &vm_params.sgx_epc {
+                let mut sgx_epc_config_list = Vec::new();
+                for item in sgx_epc_list.iter() {
+                    let sgx_epc_config = SgxEpcConfig::parse(item)?;
+                    sgx_epc_config_list.push(sgx_epc_config);
+                }
+                sgx_epc = Some(sgx_epc_config_list);
+            }
+        }
+
+        let mut pci_segments: Option<Vec<PciSegmentConfig>> = None;
+        if let Some(pci_segment_list) = &vm_params.pci_segments {
+            let mut pci_segment_config_list = Vec::new();
+            for item in pci_segment_list.iter() {
+                let pci_segment_config = PciSegmentConfig::parse(item)?;
+                pci_segment_config_list.push(pci_segment_config);
+            }
+            pci_segments = Some(pci_segment_config_list);
+        }
+
+        let mut pci_root_ports: Option<Vec<PciRootPortConfig>> = None;
+        if let Some(pci_root_port_list) = &vm_params.pci_root_ports {
+            let mut pci_root_port_config_list = Vec::new();
+            for item in pci_root_port_list.iter() {
+                let pci_root_port_config = PciRootPortConfig::parse(item)?;
+                pci_root_port_config_list.push(pci_root_port_config);
+            }
+            pci_root_ports = Some(pci_root_port_config_list);
+        }
+
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                let numa_config = NumaConfig::parse(item)?;
+                numa_config_list.push(numa_config);
+            }
+            numa = Some(numa_config_list);
+        }
+
+        let payload_present = vm_params.kernel.is_some() || vm_params.firmware.is_some();
+        #[cfg(feature = "sev_snp")]
+        let payload_present = payload_present || vm_params.igvm.is_some();
+        let payload = if payload_present {
+            Some(PayloadConfig {
+                kernel: vm_params.kernel.map(PathBuf::from),
+                initramfs: vm_params.initramfs.map(PathBuf::from),
+                cmdline: vm_params.cmdline.map(|s| s.to_string()),
+                firmware: vm_params.firmware.map(PathBuf::from),
+                firmware_fd: None,
+                kernel_fd: None,
+                initramfs_fd: None,
+                #[cfg(feature = "sev_snp")]
+                igvm: vm_params.igvm.map(PathBuf::from),
+                #[cfg(feature = "sev_snp")]
+                host_data: vm_params.host_data.map(|s| s.to_string()),
+            })
+        } else {
+            None
+        };
+
+        let mut tpm: Option<TpmConfig> = None;
+        if let Some(tc) = vm_params.tpm {
+            let tpm_conf = TpmConfig::parse(tc)?;
+            tpm = Some(TpmConfig {
+                socket: tpm_conf.socket,
+            });
+        }
+
+        let mut acpi_tables: Option<Vec<AcpiTableConfig>> = None;
+        if let Some(acpi_table_list) = &vm_params.acpi_tables {
+            let mut acpi_table_config_list = Vec::new();
+            for item in acpi_table_list.iter() {
+                acpi_table_config_list.push(AcpiTableConfig::parse(item)?);
+            }
+            acpi_tables = Some(acpi_table_config_list);
+        }
+
+        let pvpanic_policy = vm_params
+            .pvpanic_policy
+            .map(PvPanicPolicyConfig::parse)
+            .transpose()?;
+
+        let on_crash = vm_params.on_crash.map(OnCrashConfig::parse).transpose()?;
+
+        let cgroup = vm_params.cgroup.map(CgroupConfig::parse).transpose()?;
+
+        let process_limits = vm_params
+            .process_limits
+            .map(ProcessLimitsConfig::parse)
+            .transpose()?;
+
+        let seccomp_overrides = vm_params
+            .seccomp_overrides
+            .as_ref()
+            .map(|list| {
+                list.iter()
+                    .map(|item| SeccompOverrideConfig::parse(item))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        let mut landlock_rules: Option<Vec<LandlockConfig>> = None;
+        if let Some(landlock_rule_list) = &vm_params.landlock_rules {
+            let mut landlock_rule_config_list = Vec::new();
+            for item in landlock_rule_list.iter() {
+                landlock_rule_config_list.push(LandlockConfig::parse(item)?);
+            }
+            landlock_rules = Some(landlock_rule_config_list);
+        }
+
+        let watchdog_action = vm_params
+            .watchdog_action
+            .map(WatchdogAction::from_str)
+            .transpose()
+            .map_err(Error::ParseWatchdogAction)?
+            .unwrap_or_default();
+        let watchdog_escalation = vm_params
+            .watchdog_escalation
+            .map(WatchdogEscalationConfig::parse)
+            .transpose()?;
+
+        #[cfg(feature = "guest_debug")]
+        let gdb = vm_params.gdb;
+
+        let mut config = VmConfig {
+            cpus: CpusConfig::parse(vm_params.cpus)?,
+            memory: MemoryConfig::parse(vm_params.memory, vm_params.memory_zones)?,
+            payload,
+            disks,
+            net,
+            rng,
+            balloon,
+            fs,
+            pmem,
+            ivshmem,
+            serial,
+            console,
+            console_ports,
+            debug_console,
+            devices,
+            user_devices,
+            vdpa,
+            vsock,
+            guest_agent,
+            pvpanic: vm_params.pvpanic,
+            pvpanic_policy,
+            on_crash,
+            iommu: false, // updated in VmConfig::validate()
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars,
+            numa,
+            watchdog: vm_params.watchdog,
+            watchdog_action,
+            watchdog_escalation,
+            #[cfg(feature = "guest_debug")]
+            gdb,
+            platform,
+            pci_segments,
+            pci_root_ports,
+            tpm,
+            acpi_tables,
+            cgroup,
+            process_limits,
+            seccomp_overrides,
+            landlock_enable: vm_params.landlock_enable,
+            landlock_rules,
+            preserved_fds: None,
+        };
+        config.validate().map_err(Error::Validation)?;
+        Ok(config)
+    }
+
+    pub fn remove_device(&mut self, id: &str) -> bool {
+        let mut removed = false;
+
+        // Remove if VFIO device
+        if let Some(devices) = self.devices.as_mut() {
+            let len = devices.len();
+            devices.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= devices.len() != len;
+        }
+
+        // Remove if VFIO user device
+        if let Some(user_devices) = self.user_devices.as_mut() {
+            let len = user_devices.len();
+            user_devices.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= user_devices.len() != len;
+        }
+
+        // Remove if disk device
+        if let Some(disks) = self.disks.as_mut() {
+            let len = disks.len();
+            disks.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= disks.len() != len;
+        }
+
+        // Remove if fs device
+        if let Some(fs) = self.fs.as_mut() {
+            let len = fs.len();
+            fs.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= fs.len() != len;
+        }
+
+        // Remove if net device
+        if let Some(net) = self.net.as_mut() {
+            let len = net.len();
+            net.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= net.len() != len;
+        }
+
+        // Remove if pmem device
+        if let Some(pmem) = self.pmem.as_mut() {
+            let len = pmem.len();
+            pmem.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= pmem.len() != len;
+        }
+
+        // Remove if vDPA device
+        if let Some(vdpa) = self.vdpa.as_mut() {
+            let len = vdpa.len();
+            vdpa.retain(|dev| dev.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            removed |= vdpa.len() != len;
+        }
+
+        // Remove if vsock device
+        if let Some(vsock) = self.vsock.as_ref() {
+            if vsock.id.as_ref().map(|id| id.as_ref()) == Some(id) {
+                self.vsock = None;
+                removed = true;
+            }
+        }
+
+        // Remove if virtio-rng device
+        if id == RNG_DEVICE_NAME && !self.rng.removed {
+            self.rng.removed = true;
+            removed = true;
+        }
+
+        // Remove if virtio-console device, along with its ports
+        if id == CONSOLE_DEVICE_NAME && self.console.mode != ConsoleOutputMode::Off {
+            self.console.mode = ConsoleOutputMode::Off;
+            self.console.file = None;
+            self.console_ports = None;
+            removed = true;
+        }
+
+        removed
+    }
+
+    pub fn remove_console_port(&mut self, id: &str) -> bool {
+        if let Some(console_ports) = self.console_ports.as_mut() {
+            let len = console_ports.len();
+            console_ports.retain(|port| port.id.as_ref().map(|id| id.as_ref()) != Some(id));
+            return console_ports.len() != len;
+        }
+
+        false
+    }
+
+    /// # Safety
+    /// To use this safely, the caller must guarantee that the input
+    /// fds are all valid.
+    pub unsafe fn add_preserved_fds(&mut self, mut fds: Vec<i32>) {
+        if fds.is_empty() {
+            return;
+        }
+
+        if let Some(preserved_fds) = &self.preserved_fds {
+            fds.append(&mut preserved_fds.clone());
+        }
+
+        self.preserved_fds = Some(fds);
+    }
+
+    #[cfg(feature = "tdx")]
+    pub fn is_tdx_enabled(&self) -> bool {
+        self.platform.as_ref().map(|p| p.tdx).unwrap_or(false)
+    }
+
+    #[cfg(feature = "sev_snp")]
+    pub fn is_sev_snp_enabled(&self) -> bool {
+        self.platform.as_ref().map(|p| p.sev_snp).unwrap_or(false)
+    }
+}
+
+impl Clone for VmConfig {
+    fn clone(&self) -> Self {
+        let preserved_fds: Option<Vec<i32>> = self
+            .preserved_fds
+            .as_ref()
+            // SAFETY: FFI call with valid FDs
+            .map(|fds| fds.iter().map(|fd| unsafe { libc::dup(*fd) }).collect());
+
+        // The payload FDs are among the preserved ones, and get replaced by
+        // their duplicates.
+        let mut payload = self.payload.clone();
+        if let (Some(payload), Some(fds), Some(dup_fds)) =
+            (payload.as_mut(), &self.preserved_fds, &preserved_fds)
+        {
+            for fd in [
+                &mut payload.firmware_fd,
+                &mut payload.kernel_fd,
+                &mut payload.initramfs_fd,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if let Some(index) = fds.iter().position(|f| f == fd) {
+                    *fd = dup_fds[index];
+                }
+            }
+        }
+
+        VmConfig {
+            cpus: self.cpus.clone(),
+            memory: self.memory.clone(),
+            payload,
+            disks: self.disks.clone(),
+            net: self.net.clone(),
+            rng: self.rng.clone(),
+            balloon: self.balloon.clone(),
+            fs: self.fs.clone(),
+            pmem: self.pmem.clone(),
+            ivshmem: self.ivshmem.clone(),
+            serial: self.serial.clone(),
+            console: self.console.clone(),
+            console_ports: self.console_ports.clone(),
+            debug_console: self.debug_console.clone(),
+            devices: self.devices.clone(),
+            user_devices: self.user_devices.clone(),
+            vdpa: self.vdpa.clone(),
+            vsock: self.vsock.clone(),
+            guest_agent: self.guest_agent.clone(),
+            pvpanic_policy: self.pvpanic_policy.clone(),
+            on_crash: self.on_crash.clone(),
+            watchdog_escalation: self.watchdog_escalation.clone(),
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: self.sgx_epc.clone(),
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: self.msr_filter.clone(),
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars: self.uefi_vars.clone(),
+            numa: self.numa.clone(),
+            platform: self.platform.clone(),
+            pci_segments: self.pci_segments.clone(),
+            pci_root_ports: self.pci_root_ports.clone(),
+            tpm: self.tpm.clone(),
+            acpi_tables: self.acpi_tables.clone(),
+            cgroup: self.cgroup.clone(),
+            process_limits: self.process_limits.clone(),
+            seccomp_overrides: self.seccomp_overrides.clone(),
+            landlock_rules: self.landlock_rules.clone(),
+            preserved_fds,
+            ..*self
+        }
+    }
+}
+
+impl Drop for VmConfig {
+    fn drop(&mut self) {
+        if let Some(mut fds) = self.preserved_fds.take() {
+            for fd in fds.drain(..) {
+                // SAFETY: FFI call with valid FDs
+                unsafe { libc::close(fd) };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net_util::MacAddr;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_cpu_parsing() -> Result<()> {
+        assert_eq!(CpusConfig::parse("")?, CpusConfig::default());
+
+        assert_eq!(
+            CpusConfig::parse("boot=1")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,max=2")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=8,topology=2:2:1:2")?,
+            CpusConfig {
+                boot_vcpus: 8,
+                max_vcpus: 8,
+                topology: Some(CpuTopology {
+                    threads_per_core: 2,
+                    cores_per_die: 2,
+                    dies_per_package: 1,
+                    packages: 2
+                }),
+                ..Default::default()
+            }
+        );
+
+        assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
+        assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=1,kvm_hyperv=on")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                kvm_hyperv: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            CpusConfig::parse("boot=1,kvm_hyperv=on,kvm_hyperv_features=[synic,tlbflush]")?,
+            CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                kvm_hyperv: true,
+                kvm_hyperv_features: Some(vec![HypervFeature::Synic, HypervFeature::Tlbflush]),
+                ..Default::default()
+            }
+        );
+        assert!(CpusConfig::parse("boot=1,kvm_hyperv=on,kvm_hyperv_features=[vapic]").is_err());
+        assert_eq!(
+            CpusConfig::parse("boot=2,affinity=[0@[0,2],1@[1,3]]")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                affinity: Some(vec![
+                    CpuAffinity {
+                        vcpu: 0,
+                        host_cpus: vec![0, 2],
+                    },
+                    CpuAffinity {
+                        vcpu: 1,
+                        host_cpus: vec![1, 3],
+                    }
+                ]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=4,efficiency_cores=[2-3]")?,
+            CpusConfig {
+                boot_vcpus: 4,
+                max_vcpus: 4,
+                efficiency_cores: Some(vec![2, 3]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=1,pmu=off")?,
+            CpusConfig {
+                pmu: false,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=1,pmu_events=[8,17-19]")?,
+            CpusConfig {
+                pmu_events: Some(vec![8, 17, 18, 19]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=1,nested=on")?,
+            CpusConfig {
+                nested: true,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=1,model=x86-64-v3,cpuid_add=[aes,sha_ni],cpuid_remove=[avx2]")?,
+            CpusConfig {
+                model: Some("x86-64-v3".to_string()),
+                cpuid_add: Some(vec!["aes".to_string(), "sha_ni".to_string()]),
+                cpuid_remove: Some(vec!["avx2".to_string()]),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            CpusConfig::parse("boot=2,cpu_max=150000:100000,vcpu_max=50000:100000")?,
+            CpusConfig {
+                boot_vcpus: 2,
+                max_vcpus: 2,
+                cpu_max: Some(CpuBandwidth {
+                    quota: 150000,
+                    period: 100000,
+                }),
+                vcpu_max: Some(CpuBandwidth {
+                    quota: 50000,
+                    period: 100000,
+                }),
+                ..Default::default()
+            },
+        );
+        assert!(CpusConfig::parse("boot=1,cpu_max=50000").is_err());
+
+        let mut cpus = CpusConfig::parse("boot=2,affinity=[0@[0,2],1@[1,3]]")?;
+        cpus.update_affinity(&[
+            CpuAffinity {
+                vcpu: 0,
+                host_cpus: vec![],
+            },
+            CpuAffinity {
+                vcpu: 1,
+                host_cpus: vec![4],
+            },
+        ]);
+        assert_eq!(
+            cpus.affinity,
+            Some(vec![CpuAffinity {
+                vcpu: 1,
+                host_cpus: vec![4],
+            }])
+        );
+        cpus.update_affinity(&[CpuAffinity {
+            vcpu: 1,
+            host_cpus: vec![],
+        }]);
+        assert_eq!(cpus.affinity, None);
+
+        // Removing vCPU 2 out of 4 leaves a hole, the last vCPU keeping its
+        // id after a reboot, until the hole is filled again.
+        let mut cpus = CpusConfig::parse("boot=3,max=4")?;
+        assert_eq!(cpus.boot_vcpu_ids(), vec![0, 1, 2]);
+        cpus.set_vcpu_removed(2, true);
+        assert_eq!(cpus.removed_vcpus, Some(vec![2]));
+        assert_eq!(cpus.boot_vcpu_ids(), vec![0, 1, 3]);
+        cpus.set_vcpu_removed(1, true);
+        cpus.boot_vcpus = 2;
+        assert_eq!(cpus.removed_vcpus, Some(vec![1, 2]));
+        assert_eq!(cpus.boot_vcpu_ids(), vec![0, 3]);
+        cpus.set_vcpu_removed(2, false);
+        cpus.set_vcpu_removed(1, false);
+        cpus.boot_vcpus = 4;
+        assert_eq!(cpus.removed_vcpus, None);
+        assert_eq!(cpus.boot_vcpu_ids(), vec![0, 1, 2, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_parsing() -> Result<()> {
+        assert_eq!(MemoryConfig::parse("", None)?, MemoryConfig::default());
+        // Default string
+        assert_eq!(
+            MemoryConfig::parse("size=512M", None)?,
+            MemoryConfig::default()
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=512M,mergeable=on", None)?,
+            MemoryConfig {
+                size: 512 << 20,
+                mergeable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("mergeable=on", None)?,
+            MemoryConfig {
+                mergeable: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("size=1G,mergeable=off", None)?,
+            MemoryConfig {
+                size: 1 << 30,
+                mergeable: false,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=acpi", None)?,
+            MemoryConfig {
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=acpi,hotplug_size=512M", None)?,
+            MemoryConfig {
+                hotplug_size: Some(512 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
+            MemoryConfig {
+                hotplug_size: Some(512 << 20),
+                hotplug_method: HotplugMethod::VirtioMem,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MemoryConfig::parse("hugepages=on,size=1G,hugepage_size=2M", None)?,
+            MemoryConfig {
+                hugepage_size: Some(2 << 20),
+                size: 1 << 30,
+                hugepages: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_parsing() -> Result<()> {
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,id=mydisk0")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                id: Some("mydisk0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
+            DiskConfig {
+                vhost_socket: Some(String::from("/tmp/sock")),
+                vhost_user: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: true,
+                queue_size: 256,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256,num_queues=4")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                iommu: true,
+                queue_size: 256,
+                num_queues: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,direct=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                direct: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,serial=test")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                serial: Some(String::from("test")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,subsystem_vendor_id=0x1234,subsystem_id=5678,revision_id=0x2"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                pci_ids: Some(PciIdsConfig {
+                    subsystem_vendor_id: Some(0x1234),
+                    subsystem_id: Some(0x5678),
+                    revision_id: Some(0x2),
+                }),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,revision_id=0x100").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,backend=qcow2")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                backend: Some("qcow2".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,mmio=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                mmio: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_priority=be:2")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                io_priority: Some(IoPriorityConfig {
+                    class: IoPriorityClass::BestEffort,
+                    level: 2,
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,io_priority=idle")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                io_priority: Some(IoPriorityConfig {
+                    class: IoPriorityClass::Idle,
+                    level: 0,
+                }),
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,io_priority=high").is_err());
+        assert!(DiskConfig::parse("path=/path/to_file,io_priority=be:x").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,event_loop=io_uring")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                event_loop: EventLoop::IoUring,
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,event_loop=poll").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,event_idx=off,coalesce_buffers=16")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                notification: Some(NotificationConfig {
+                    event_idx: false,
+                    coalesce_buffers: 16,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_net_parsing() -> Result<()> {
+        // mac address is random
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                id: Some("mynet0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                tap: Some("tap0".to_owned()),
+                ip: "192.168.100.1".parse().unwrap(),
+                mask: "255.255.255.128".parse().unwrap(),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                vhost_user: true,
+                vhost_socket: Some("/tmp/sock".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                num_queues: 4,
+                queue_size: 1024,
+                iommu: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                fds: Some(vec![3, 7]),
+                num_queues: 4,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,event_idx=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,event_idx=off,coalesce_buffers=32,coalesce_timeout=50"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                notification: Some(NotificationConfig {
+                    event_idx: false,
+                    coalesce_buffers: 32,
+                    coalesce_timeout: 50,
+                }),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,xdp=eth0,xdp_zero_copy=off")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                xdp: Some("eth0".to_owned()),
+                xdp_zero_copy: false,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,event_loop=io_uring")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                event_loop: EventLoop::IoUring,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rng() -> Result<()> {
+        assert_eq!(RngConfig::parse("")?, RngConfig::default());
+        assert_eq!(
+            RngConfig::parse("src=/dev/random")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("src=/dev/random,iommu=on")?,
+            RngConfig {
+                src: PathBuf::from("/dev/random"),
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("src=/dev/hwrng,bw_size=1024,bw_refill_time=100")?,
+            RngConfig {
+                src: PathBuf::from("/dev/hwrng"),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1024,
+                        one_time_burst: None,
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse(
+                "src=/dev/hwrng,bw_size=1024,bw_one_time_burst=4096,bw_refill_time=100"
+            )?,
+            RngConfig {
+                src: PathBuf::from("/dev/hwrng"),
+                rate_limiter_config: Some(RateLimiterConfig {
+                    bandwidth: Some(TokenBucketConfig {
+                        size: 1024,
+                        one_time_burst: Some(4096),
+                        refill_time: 100,
+                    }),
+                    ops: None,
+                }),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RngConfig::parse("iommu=on")?,
+            RngConfig {
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_fs() -> Result<()> {
+        // "tag" and "socket" must be supplied
+        assert!(FsConfig::parse("").is_err());
+        assert!(FsConfig::parse("tag=mytag").is_err());
+        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                num_queues: 4,
+                queue_size: 1024,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,dax=on,cache_size=1G")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                dax: true,
+                cache_size: 1 << 30,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            FsConfig::parse("tag=mytag,socket=/tmp/sock,shared_dir=/srv/share")?,
+            FsConfig {
+                socket: PathBuf::from("/tmp/sock"),
+                tag: "mytag".to_owned(),
+                shared_dir: Some(PathBuf::from("/srv/share")),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_balloon_parsing() -> Result<()> {
+        let balloon = BalloonConfig::parse("size=1G")?;
+        assert_eq!(balloon.size, [1 << 30, 0]);
+        assert!(!balloon.free_page_reporting);
+        assert!(!balloon.free_page_hinting);
+
+        let balloon = BalloonConfig::parse("size=0,free_page_reporting=on")?;
+        assert!(balloon.free_page_reporting);
+        assert!(!balloon.free_page_hinting);
+
+        let balloon = BalloonConfig::parse("size=0,free_page_hinting=on")?;
+        assert!(!balloon.free_page_reporting);
+        assert!(balloon.free_page_hinting);
+
+        assert!(BalloonConfig::parse("size=0,free_page_hinting=maybe").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pmem_parsing() -> Result<()> {
+        // Must always give a file and size
+        assert!(PmemConfig::parse("").is_err());
+        assert!(PmemConfig::parse("size=128M").is_err());
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                id: Some("mypmem0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,discard_writes=on")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                discard_writes: true,
+                iommu: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            PmemConfig::parse("file=/tmp/pmem,size=128M,discard=on")?,
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                size: Some(128 << 20),
+                discard: true,
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ivshmem_parsing() -> Result<()> {
+        assert!(IvshmemConfig::parse("").is_err());
+        assert!(IvshmemConfig::parse("size=1M").is_err());
+        assert!(IvshmemConfig::parse("path=/dev/shm/ivshmem,socket=/tmp/ivshmem.sock").is_err());
+        assert_eq!(
+            IvshmemConfig::parse("path=/dev/shm/ivshmem,size=1M")?,
+            IvshmemConfig {
+                path: Some(PathBuf::from("/dev/shm/ivshmem")),
+                size: Some(1 << 20),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            IvshmemConfig::parse("socket=/tmp/ivshmem.sock,vectors=4,id=myivshmem0")?,
+            IvshmemConfig {
+                socket: Some(PathBuf::from("/tmp/ivshmem.sock")),
+                vectors: 4,
+                id: Some("myivshmem0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_parsing() -> Result<()> {
+        assert!(ConsoleConfig::parse("").is_err());
+        assert!(ConsoleConfig::parse("badmode").is_err());
+        assert_eq!(
+            ConsoleConfig::parse("off")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Off,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tty,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: false,
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("null,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Null,
+                iommu: true,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::File,
+                iommu: true,
+                file: Some(PathBuf::from("/tmp/console")),
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("socket=/tmp/serial.sock,iommu=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Socket,
+                iommu: true,
+                file: None,
+                socket: Some(PathBuf::from("/tmp/serial.sock")),
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("pty,max_ports=4")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 4,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("tcp=127.0.0.1:4444,telnet=on")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Tcp,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: Some(SocketAddr::from(([127, 0, 0, 1], 4444))),
+                telnet: true,
+                socket_dir: None,
+            }
+        );
+        assert!(ConsoleConfig::parse("tcp=127.0.0.1").is_err());
+        assert_eq!(
+            ConsoleConfig::parse("pty,max_ports=4,socket_dir=/run/vm")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 4,
+                tcp: None,
+                telnet: false,
+                socket_dir: Some(PathBuf::from("/run/vm")),
+            }
+        );
+        assert_eq!(
+            ConsoleConfig::parse("socket_dir=/run/vm")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Socket,
+                iommu: false,
+                file: None,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: Some(PathBuf::from("/run/vm")),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_port_parsing() -> Result<()> {
+        assert_eq!(ConsolePortConfig::parse("")?, ConsolePortConfig::default());
+        assert_eq!(
+            ConsolePortConfig::parse("name=org.qemu.guest_agent.0,socket=/tmp/qga.sock")?,
+            ConsolePortConfig {
+                name: Some("org.qemu.guest_agent.0".to_owned()),
+                socket: Some(PathBuf::from("/tmp/qga.sock")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            ConsolePortConfig::parse("id=myport0")?,
+            ConsolePortConfig {
+                id: Some("myport0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(ConsolePortConfig::parse("mode=pty").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_parsing() -> Result<()> {
+        // Device must have a path provided
+        assert!(DeviceConfig::parse("").is_err());
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: false,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: None,
+                iommu: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                id: Some("mydevice0".to_owned()),
+                iommu: true,
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,p2p=[gpu0,nic0]")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                p2p: Some(vec!["gpu0".to_owned(), "nic0".to_owned()]),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,p2p=[]")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                p2p: Some(Vec::new()),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("path=/path/to/device,pci_root_port=rp0")?,
+            DeviceConfig {
+                path: PathBuf::from("/path/to/device"),
+                pci_root_port: Some("rp0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_segment_parsing() -> Result<()> {
+        // The segment id is required
+        assert!(PciSegmentConfig::parse("start_bus=1").is_err());
+        assert_eq!(
+            PciSegmentConfig::parse("pci_segment=1")?,
+            PciSegmentConfig {
+                pci_segment: 1,
+                start_bus: 0,
+                end_bus: 0,
+            }
+        );
+        assert_eq!(
+            PciSegmentConfig::parse("pci_segment=1,start_bus=128")?,
+            PciSegmentConfig {
+                pci_segment: 1,
+                start_bus: 128,
+                end_bus: 128,
+            }
+        );
+        assert_eq!(
+            PciSegmentConfig::parse("pci_segment=1,start_bus=128,end_bus=191")?,
+            PciSegmentConfig {
+                pci_segment: 1,
+                start_bus: 128,
+                end_bus: 191,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_root_port_parsing() -> Result<()> {
+        // The root port id is required
+        assert!(PciRootPortConfig::parse("pci_segment=1").is_err());
+        assert_eq!(
+            PciRootPortConfig::parse("id=rp0")?,
+            PciRootPortConfig {
+                id: "rp0".to_owned(),
+                pci_segment: 0,
+            }
+        );
+        assert_eq!(
+            PciRootPortConfig::parse("id=rp1,pci_segment=1")?,
+            PciRootPortConfig {
+                id: "rp1".to_owned(),
+                pci_segment: 1,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vf_parsing() -> Result<()> {
+        // Both the PF and the VF index are required
+        assert!(VfConfig::parse("").is_err());
+        assert!(VfConfig::parse("pf=0000:3b:00.0").is_err());
+        assert!(VfConfig::parse("vf=2").is_err());
+        assert_eq!(
+            VfConfig::parse("pf=0000:3b:00.0,vf=2")?,
+            VfConfig {
+                pf: "0000:3b:00.0".to_owned(),
+                vf: 2,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            VfConfig::parse("pf=0000:3b:00.0,vf=2,num_vfs=8,iommu=on,id=vf2")?,
+            VfConfig {
+                pf: "0000:3b:00.0".to_owned(),
+                vf: 2,
+                num_vfs: Some(8),
+                iommu: true,
+                id: Some("vf2".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mdev_parsing() -> Result<()> {
+        // Both the parent and the type are required
+        assert!(MdevConfig::parse("").is_err());
+        assert!(MdevConfig::parse("parent=0000:00:02.0").is_err());
+        assert!(MdevConfig::parse("type=i915-GVTg_V5_4").is_err());
+        assert_eq!(
+            MdevConfig::parse("parent=0000:00:02.0,type=i915-GVTg_V5_4")?,
+            MdevConfig {
+                parent: "0000:00:02.0".to_owned(),
+                mdev_type: "i915-GVTg_V5_4".to_owned(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MdevConfig::parse(
+                "parent=0000:3b:00.0,type=nvidia-63,uuid=c5fb8bd0-3b1e-4d0a-9a4a-6c4d2ae1f4e5,id=vgpu0"
+            )?,
+            MdevConfig {
+                parent: "0000:3b:00.0".to_owned(),
+                mdev_type: "nvidia-63".to_owned(),
+                uuid: Some("c5fb8bd0-3b1e-4d0a-9a4a-6c4d2ae1f4e5".to_owned()),
+                id: Some("vgpu0".to_owned()),
+                ..Default::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vdpa_parsing() -> Result<()> {
+        // path is required
+        assert!(VdpaConfig::parse("").is_err());
+        assert_eq!(
+            VdpaConfig::parse("path=/dev/vhost-vdpa")?,
+            VdpaConfig {
+                path: PathBuf::from("/dev/vhost-vdpa"),
+                num_queues: 1,
+                id: None,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            VdpaConfig::parse("path=/dev/vhost-vdpa,num_queues=2,id=my_vdpa")?,
+            VdpaConfig {
+                path: PathBuf::from("/dev/vhost-vdpa"),
+                num_queues: 2,
+                id: Some("my_vdpa".to_owned()),
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tpm_parsing() -> Result<()> {
+        // path is required
+        assert!(TpmConfig::parse("").is_err());
+        assert_eq!(
+            TpmConfig::parse("socket=/var/run/tpm.sock")?,
+            TpmConfig {
+                socket: PathBuf::from("/var/run/tpm.sock"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_platform_parsing() -> Result<()> {
+        assert_eq!(PlatformConfig::parse("")?, PlatformConfig::default());
+        assert_eq!(
+            PlatformConfig::parse(
+                "manufacturer=ACME,product=Rocket,serial_number=1234,\
+                 uuid=1e8aa28a-435d-4027-87f4-40dceff1fa0a,oem_strings=[foo=bar,baz]"
+            )?,
+            PlatformConfig {
+                manufacturer: Some("ACME".to_owned()),
+                product: Some("Rocket".to_owned()),
+                serial_number: Some("1234".to_owned()),
+                uuid: Some("1e8aa28a-435d-4027-87f4-40dceff1fa0a".to_owned()),
+                oem_strings: Some(vec!["foo=bar".to_owned(), "baz".to_owned()]),
+                ..Default::default()
+            }
+        );
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(
+                PlatformConfig::parse("s3=on")?,
+                PlatformConfig {
+                    s3: true,
+                    ..Default::default()
+                }
+            );
+            assert_eq!(
+                PlatformConfig::parse("s4=on,hibernate_action=reboot")?,
+                PlatformConfig {
+                    s4: true,
+                    hibernate_action: HibernateAction::Reboot,
+                    ..Default::default()
+                }
+            );
+            assert!(PlatformConfig::parse("s4=on,hibernate_action=sleep").is_err());
+            assert_eq!(
+                PlatformConfig::parse("interrupt_remapping=on")?,
+                PlatformConfig {
+                    interrupt_remapping: true,
+                    ..Default::default()
+                }
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_parsing() -> Result<()> {
+        // source_url is required
+        assert!(RestoreConfig::parse("prefault=on").is_err());
+        assert_eq!(
+            RestoreConfig::parse("source_url=file:///tmp/snapshot")?,
+            RestoreConfig {
+                source_url: PathBuf::from("file:///tmp/snapshot"),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse(
+                "source_url=file:///tmp/snapshot,net_mac=[net0@de:ad:be:ef:12:34],\
+                disk_path=[disk0@/path/to/clone.img],memory_zone_host_numa_node=[mem0@1]"
+            )?,
+            RestoreConfig {
+                source_url: PathBuf::from("file:///tmp/snapshot"),
+                net: Some(vec![RestoredNetConfig {
+                    id: "net0".to_owned(),
+                    mac: Some(MacAddr::parse_str("de:ad:be:ef:12:34").unwrap()),
+                    ..Default::default()
+                }]),
+                disks: Some(vec![RestoredDiskConfig {
+                    id: "disk0".to_owned(),
+                    path: Some(PathBuf::from("/path/to/clone.img")),
+                    ..Default::default()
+                }]),
+                memory_zones: Some(vec![RestoredMemoryZoneConfig {
+                    id: "mem0".to_owned(),
+                    host_numa_node: Some(1),
+                }]),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            RestoreConfig::parse("source_url=file:///tmp/snapshot,require_manifest=on")?,
+            RestoreConfig {
+                source_url: PathBuf::from("file:///tmp/snapshot"),
+                require_manifest: true,
+                ..Default::default()
+            }
+        );
+        assert!(
+            RestoreConfig::parse("source_url=file:///tmp/snapshot,net_mac=[net0@foo]").is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_acpi_table_parsing() -> Result<()> {
+        // path is required
+        assert!(AcpiTableConfig::parse("").is_err());
+        assert_eq!(
+            AcpiTableConfig::parse("path=/path/to/ssdt.aml")?,
+            AcpiTableConfig {
+                path: PathBuf::from("/path/to/ssdt.aml"),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_vsock_parsing() -> Result<()> {
+        // socket and cid is required
+        assert!(VsockConfig::parse("").is_err());
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=1")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: false,
+                id: None,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse("socket=/tmp/sock,cid=1,iommu=on")?,
+            VsockConfig {
+                cid: 1,
+                socket: PathBuf::from("/tmp/sock"),
+                iommu: true,
+                id: None,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            VsockConfig::parse(
+                "socket=/tmp/sock,cid=3,siblings=[4@/tmp/sock4,5@/tmp/sock5],listeners=[1234@/tmp/sock_1234]"
+            )?,
+            VsockConfig {
+                cid: 3,
+                socket: PathBuf::from("/tmp/sock"),
+                siblings: Some(vec![
+                    VsockSiblingConfig {
+                        cid: 4,
+                        socket: PathBuf::from("/tmp/sock4"),
+                    },
+                    VsockSiblingConfig {
+                        cid: 5,
+                        socket: PathBuf::from("/tmp/sock5"),
+                    },
+                ]),
+                listeners: Some(vec![VsockListenerConfig {
+                    port: 1234,
+                    socket: PathBuf::from("/tmp/sock_1234"),
+                }]),
+                ..Default::default()
+            }
+        );
+        assert!(VsockConfig::parse("socket=/tmp/sock,cid=3,siblings=[4]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_guest_agent_parsing() -> Result<()> {
+        assert_eq!(GuestAgentConfig::parse("")?, GuestAgentConfig::default());
+        assert_eq!(
+            GuestAgentConfig::parse("port=4321,timeout=30")?,
+            GuestAgentConfig {
+                port: 4321,
+                timeout: 30,
+            }
+        );
+        assert!(GuestAgentConfig::parse("port=foo").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_console_parsing() -> Result<()> {
+        assert!(DebugConsoleConfig::parse("").is_err());
+        assert!(DebugConsoleConfig::parse("pty").is_err());
+        assert_eq!(
+            DebugConsoleConfig::parse("tty")?,
+            DebugConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Tty,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            DebugConsoleConfig::parse("file=/tmp/debug.log")?,
+            DebugConsoleConfig {
+                file: Some(PathBuf::from("/tmp/debug.log")),
+                mode: ConsoleOutputMode::File,
+                socket: None,
+            }
+        );
+        assert_eq!(
+            DebugConsoleConfig::parse("socket=/tmp/debug.sock")?,
+            DebugConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Socket,
+                socket: Some(PathBuf::from("/tmp/debug.sock")),
+            }
+        );
+        Ok(())
+    }
 
     #[test]
-    fn test_cpu_parsing() -> Result<()> {
-        assert_eq!(CpusConfig::parse("")?, CpusConfig::default());
+    fn test_landlock_parsing() -> Result<()> {
+        assert!(LandlockConfig::parse("").is_err());
+        assert!(LandlockConfig::parse("path=/tmp").is_err());
+        assert!(LandlockConfig::parse("path=/tmp,access=x").is_err());
+        assert_eq!(
+            LandlockConfig::parse("path=/path/to/snapshots,access=rw")?,
+            LandlockConfig {
+                path: PathBuf::from("/path/to/snapshots"),
+                access: LandlockAccess::ReadWrite,
+            }
+        );
+        assert_eq!(
+            LandlockConfig::parse("path=/path/to/image,access=r")?,
+            LandlockConfig {
+                path: PathBuf::from("/path/to/image"),
+                access: LandlockAccess::Read,
+            }
+        );
+        Ok(())
+    }
 
+    #[test]
+    fn test_cgroup_parsing() -> Result<()> {
+        assert_eq!(CgroupConfig::parse("")?, CgroupConfig::default());
         assert_eq!(
-            CpusConfig::parse("boot=1")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
-                ..Default::default()
+            CgroupConfig::parse(
+                "path=/machine/vm0,memory_max=2G,io_max=[8:0 rbps=1048576 wiops=100,8:16 riops=max],device_cpu_max=[_disk0@50000:100000]"
+            )?,
+            CgroupConfig {
+                path: Some(PathBuf::from("/machine/vm0")),
+                memory_max: Some(2 << 30),
+                io_max: Some(vec![
+                    IoMaxConfig {
+                        major: 8,
+                        minor: 0,
+                        rbps: Some(1048576),
+                        wiops: Some(100),
+                        ..Default::default()
+                    },
+                    IoMaxConfig {
+                        major: 8,
+                        minor: 16,
+                        ..Default::default()
+                    },
+                ]),
+                device_cpu_max: Some(vec![DeviceCpuMaxConfig {
+                    id: "_disk0".to_string(),
+                    cpu_max: CpuBandwidth {
+                        quota: 50000,
+                        period: 100000,
+                    },
+                }]),
             }
         );
+        assert!(CgroupConfig::parse("io_max=[8 rbps=1]").is_err());
+        assert!(CgroupConfig::parse("io_max=[8:0 rbps]").is_err());
+        assert!(CgroupConfig::parse("io_max=[8:0 bps=1]").is_err());
+        assert!(CgroupConfig::parse("device_cpu_max=[_disk0@50000]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_limits_parsing() -> Result<()> {
         assert_eq!(
-            CpusConfig::parse("boot=1,max=2")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 2,
-                ..Default::default()
+            ProcessLimitsConfig::parse("")?,
+            ProcessLimitsConfig::default()
+        );
+        assert_eq!(
+            ProcessLimitsConfig::parse("nofile=65536,memlock=16G,oom_score_adj=-500")?,
+            ProcessLimitsConfig {
+                nofile: Some(65536),
+                memlock: Some(16 << 30),
+                oom_score_adj: Some(-500),
+            }
+        );
+        assert!(ProcessLimitsConfig::parse("nofile=many").is_err());
+        assert!(ProcessLimitsConfig::parse("stack=8M").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seccomp_override_parsing() -> Result<()> {
+        assert!(SeccompOverrideConfig::parse("").is_err());
+        assert_eq!(
+            SeccompOverrideConfig::parse("id=__balloon,deny=[fallocate]")?,
+            SeccompOverrideConfig {
+                id: "__balloon".to_owned(),
+                allow: Vec::new(),
+                deny: vec!["fallocate".to_owned()],
             }
         );
         assert_eq!(
-            CpusConfig::parse("boot=8,topology=2:2:1:2")?,
-            CpusConfig {
-                boot_vcpus: 8,
-                max_vcpus: 8,
-                topology: Some(CpuTopology {
-                    threads_per_core: 2,
-                    cores_per_die: 2,
-                    dies_per_package: 1,
-                    packages: 2
-                }),
+            SeccompOverrideConfig::parse("id=disk0,allow=[statx,copy_file_range]")?,
+            SeccompOverrideConfig {
+                id: "disk0".to_owned(),
+                allow: vec!["statx".to_owned(), "copy_file_range".to_owned()],
+                deny: Vec::new(),
+            }
+        );
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_msr_filter_parsing() -> Result<()> {
+        assert_eq!(MsrFilterConfig::parse("")?, MsrFilterConfig::default());
+        assert_eq!(
+            MsrFilterConfig::parse("default_action=deny,allow=[0x10,0xc0000080],zero=[0x1a0]")?,
+            MsrFilterConfig {
+                default_action: MsrFilterDefaultAction::Deny,
+                allow: vec![0x10, 0xc000_0080],
+                deny: vec![],
+                zero: vec![0x1a0],
+            }
+        );
+        assert_eq!(
+            MsrFilterConfig::parse("deny=[16,0xc0010131]")?,
+            MsrFilterConfig {
+                deny: vec![0x10, 0xc001_0131],
                 ..Default::default()
             }
         );
+        assert!(MsrFilterConfig::parse("default_action=zero").is_err());
+        assert!(MsrFilterConfig::parse("deny=[0xfoo]").is_err());
+        Ok(())
+    }
 
-        assert!(CpusConfig::parse("boot=8,topology=2:2:1").is_err());
-        assert!(CpusConfig::parse("boot=8,topology=2:2:1:x").is_err());
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_uefi_vars_parsing() -> Result<()> {
+        // path is required
+        assert!(UefiVarsConfig::parse("").is_err());
         assert_eq!(
-            CpusConfig::parse("boot=1,kvm_hyperv=on")?,
-            CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
-                kvm_hyperv: true,
+            UefiVarsConfig::parse("path=/path/to/vars.fd")?,
+            UefiVarsConfig {
+                path: PathBuf::from("/path/to/vars.fd"),
                 ..Default::default()
             }
         );
         assert_eq!(
-            CpusConfig::parse("boot=2,affinity=[0@[0,2],1@[1,3]]")?,
-            CpusConfig {
-                boot_vcpus: 2,
-                max_vcpus: 2,
-                affinity: Some(vec![
-                    CpuAffinity {
-                        vcpu: 0,
-                        host_cpus: vec![0, 2],
-                    },
-                    CpuAffinity {
-                        vcpu: 1,
-                        host_cpus: vec![1, 3],
-                    }
+            UefiVarsConfig::parse(
+                "path=/path/to/vars.fd,pk=/path/to/pk.der,kek=[/path/to/kek.der],\
+                 db=[/path/to/db1.der,/path/to/db2.der]"
+            )?,
+            UefiVarsConfig {
+                path: PathBuf::from("/path/to/vars.fd"),
+                pk: Some(PathBuf::from("/path/to/pk.der")),
+                kek: Some(vec![PathBuf::from("/path/to/kek.der")]),
+                db: Some(vec![
+                    PathBuf::from("/path/to/db1.der"),
+                    PathBuf::from("/path/to/db2.der"),
                 ]),
-                ..Default::default()
-            },
+            }
         );
-
         Ok(())
     }
 
     #[test]
-    fn test_mem_parsing() -> Result<()> {
-        assert_eq!(MemoryConfig::parse("", None)?, MemoryConfig::default());
-        // Default string
+    fn test_pvpanic_policy_parsing() -> Result<()> {
         assert_eq!(
-            MemoryConfig::parse("size=512M", None)?,
-            MemoryConfig::default()
+            PvPanicPolicyConfig::parse("")?,
+            PvPanicPolicyConfig {
+                action: PvPanicAction::Pause,
+                ..Default::default()
+            }
         );
         assert_eq!(
-            MemoryConfig::parse("size=512M,mergeable=on", None)?,
-            MemoryConfig {
-                size: 512 << 20,
-                mergeable: true,
+            PvPanicPolicyConfig::parse("action=restart")?,
+            PvPanicPolicyConfig {
+                action: PvPanicAction::Restart,
                 ..Default::default()
             }
         );
         assert_eq!(
-            MemoryConfig::parse("mergeable=on", None)?,
-            MemoryConfig {
-                mergeable: true,
+            PvPanicPolicyConfig::parse("action=coredump,coredump_path=/tmp/core")?,
+            PvPanicPolicyConfig {
+                action: PvPanicAction::Coredump,
+                coredump_path: Some(PathBuf::from("/tmp/core")),
                 ..Default::default()
             }
         );
         assert_eq!(
-            MemoryConfig::parse("size=1G,mergeable=off", None)?,
-            MemoryConfig {
-                size: 1 << 30,
-                mergeable: false,
+            PvPanicPolicyConfig::parse("action=kdump,kdump_dir=/var/crash,restart=on")?,
+            PvPanicPolicyConfig {
+                action: PvPanicAction::Kdump,
+                kdump_dir: Some(PathBuf::from("/var/crash")),
+                restart: true,
                 ..Default::default()
             }
         );
+        assert!(PvPanicPolicyConfig::parse("action=shutdown").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watchdog_escalation_parsing() -> Result<()> {
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=acpi", None)?,
-            MemoryConfig {
-                ..Default::default()
+            WatchdogEscalationConfig::parse("")?,
+            WatchdogEscalationConfig::default()
+        );
+        assert_eq!(
+            WatchdogEscalationConfig::parse("resets=5,window=3600")?,
+            WatchdogEscalationConfig {
+                resets: 5,
+                window: 3600,
             }
         );
+        assert!(WatchdogEscalationConfig::parse("resets=five").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_crash_parsing() -> Result<()> {
+        assert_eq!(OnCrashConfig::parse("")?, OnCrashConfig::default());
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=acpi,hotplug_size=512M", None)?,
-            MemoryConfig {
-                hotplug_size: Some(512 << 20),
+            OnCrashConfig::parse("action=preserve")?,
+            OnCrashConfig {
+                action: OnCrashAction::Preserve,
                 ..Default::default()
             }
         );
         assert_eq!(
-            MemoryConfig::parse("hotplug_method=virtio-mem,hotplug_size=512M", None)?,
-            MemoryConfig {
-                hotplug_size: Some(512 << 20),
-                hotplug_method: HotplugMethod::VirtioMem,
-                ..Default::default()
+            OnCrashConfig::parse(
+                "action=coredump-and-restart,coredump_dir=/var/crash,\
+                 backoff=5,max_backoff=300,max_restarts=10"
+            )?,
+            OnCrashConfig {
+                action: OnCrashAction::CoredumpAndRestart,
+                coredump_dir: Some(PathBuf::from("/var/crash")),
+                backoff: 5,
+                max_backoff: 300,
+                max_restarts: Some(10),
             }
         );
+        assert!(OnCrashConfig::parse("action=pause").is_err());
+        assert!(OnCrashConfig::parse("backoff=-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_validation() {
+        let mut valid_config = VmConfig {
+            cpus: CpusConfig {
+                boot_vcpus: 1,
+                max_vcpus: 1,
+                ..Default::default()
+            },
+            memory: MemoryConfig {
+                size: 536_870_912,
+                mergeable: false,
+                hotplug_method: HotplugMethod::Acpi,
+                hotplug_size: None,
+                hotplugged_size: None,
+                shared: false,
+                hugepages: false,
+                hugepage_size: None,
+                prefault: false,
+                zones: None,
+                thp: true,
+            },
+            payload: Some(PayloadConfig {
+                kernel: Some(PathBuf::from("/path/to/kernel")),
+                ..Default::default()
+            }),
+            disks: None,
+            net: None,
+            rng: RngConfig {
+                src: PathBuf::from("/dev/urandom"),
+                iommu: false,
+                rate_limiter_config: None,
+                mmio: false,
+                removed: false,
+            },
+            balloon: None,
+            fs: None,
+            pmem: None,
+            ivshmem: None,
+            serial: ConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Null,
+                iommu: false,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            },
+            console: ConsoleConfig {
+                file: None,
+                mode: ConsoleOutputMode::Tty,
+                iommu: false,
+                socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
+            },
+            console_ports: None,
+            debug_console: None,
+            devices: None,
+            user_devices: None,
+            vdpa: None,
+            vsock: None,
+            guest_agent: None,
+            pvpanic: false,
+            pvpanic_policy: None,
+            on_crash: None,
+            iommu: false,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: None,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars: None,
+            numa: None,
+            watchdog: false,
+            watchdog_action: WatchdogAction::Reset,
+            watchdog_escalation: None,
+            #[cfg(feature = "guest_debug")]
+            gdb: false,
+            platform: None,
+            pci_segments: None,
+            pci_root_ports: None,
+            tpm: None,
+            acpi_tables: None,
+            cgroup: None,
+            process_limits: None,
+            seccomp_overrides: None,
+            landlock_enable: false,
+            landlock_rules: None,
+            preserved_fds: None,
+        };
+
+        assert!(valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tty;
+        invalid_config.console.mode = ConsoleOutputMode::Tty;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DoubleTtyMode)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.payload = None;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::KernelMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::File;
+        invalid_config.serial.file = None;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleFileMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.mode = ConsoleOutputMode::Tcp;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleTcpAddressMissing)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.mode = ConsoleOutputMode::Tcp;
+        invalid_config.console.tcp = Some("127.0.0.1:4444".parse().unwrap());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleTcpUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.serial.telnet = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsoleTelnetWithoutTcp)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial.mode = ConsoleOutputMode::Tcp;
+        still_valid_config.serial.tcp = Some("127.0.0.1:4444".parse().unwrap());
+        still_valid_config.serial.telnet = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 32;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::CpusMaxLowerThanBoot)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.affinity = Some(vec![CpuAffinity {
+            vcpu: 1,
+            host_cpus: vec![0],
+        }]);
         assert_eq!(
-            MemoryConfig::parse("hugepages=on,size=1G,hugepage_size=2M", None)?,
-            MemoryConfig {
-                hugepage_size: Some(2 << 20),
-                size: 1 << 30,
-                hugepages: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidCpuAffinityVcpu(1))
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_disk_parsing() -> Result<()> {
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 4;
+        invalid_config.cpus.boot_vcpus = 3;
+        invalid_config.cpus.removed_vcpus = Some(vec![1, 2]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidRemovedVcpus)
         );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.kvm_hyperv = true;
+        still_valid_config.cpus.kvm_hyperv_features = Some(Vec::new());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.kvm_hyperv_features = Some(vec![HypervFeature::Stimer]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,id=mydisk0")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                id: Some("mydisk0".to_owned()),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::StimerWithoutSynic)
         );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.cpus.kvm_hyperv = false;
         assert_eq!(
-            DiskConfig::parse("vhost_user=true,socket=/tmp/sock")?,
-            DiskConfig {
-                vhost_socket: Some(String::from("/tmp/sock")),
-                vhost_user: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::KvmHypervFeaturesWithoutKvmHyperv)
         );
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.efficiency_cores = Some(vec![0, 1]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidEfficiencyCore(1))
+            );
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.pmu_events = Some(vec![8, 17]);
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.cpus.pmu = false;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::PmuEventsWithoutPmu)
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.pmu_events = Some(Vec::new());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::EmptyPmuEvents)
+            );
+        }
+
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.pmu = false;
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::PmuControlUnsupported)
+            );
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.cpus.model = Some("SkylakeServer".to_string());
+            still_valid_config.cpus.cpuid_remove = Some(vec!["avx512f".to_string()]);
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.model = Some("Pentium".to_string());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::UnknownCpuModel("Pentium".to_string()))
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.cpuid_add = Some(vec!["avx9".to_string()]);
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::UnknownCpuidFeature("avx9".to_string()))
+            );
+        }
+
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cpus.model = Some("x86-64-v2".to_string());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::CpuModelUnsupported)
+            );
+        }
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cpus.vcpu_max = Some(CpuBandwidth {
+            quota: 200000,
+            period: 100000,
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        let bandwidth = CpuBandwidth {
+            quota: 500,
+            period: 100000,
+        };
+        invalid_config.cpus.cpu_max = Some(bandwidth);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidCpuBandwidth(bandwidth))
         );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.cgroup = Some(CgroupConfig::parse("path=/machine/vm0").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+        still_valid_config.cgroup = Some(CgroupConfig::parse("path=machine/vm0").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+
+        for path in ["/machine/../../etc", "..", "/", "./vm0"] {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.cgroup = Some(CgroupConfig::parse(&format!("path={path}")).unwrap());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidCgroupPath(PathBuf::from(path)))
+            );
+        }
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.cpus.max_vcpus = 16;
+        invalid_config.cpus.boot_vcpus = 16;
+        invalid_config.cpus.topology = Some(CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 8,
+            dies_per_package: 1,
+            packages: 2,
+        });
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
-                queue_size: 256,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::CpuTopologyCount)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            path: Some(PathBuf::from("/path/to/image")),
+            ..Default::default()
+        }]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,iommu=on,queue_size=256,num_queues=4")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                iommu: true,
-                queue_size: 256,
-                num_queues: 4,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::DiskSocketAndPath)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,direct=on")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                direct: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserMissingSocket)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.shared = true;
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            backend: Some("raw".to_owned()),
+            ..Default::default()
+        }]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserDiskBackend)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            io_priority: Some(IoPriorityConfig {
+                class: IoPriorityClass::BestEffort,
+                level: 8,
+            }),
+            ..Default::default()
+        }]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIoPriorityLevel(8))
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            backend: Some("unknown".to_owned()),
+            ..Default::default()
+        }]);
         assert_eq!(
-            DiskConfig::parse("path=/path/to_file,serial=test")?,
-            DiskConfig {
-                path: Some(PathBuf::from("/path/to_file")),
-                serial: Some(String::from("test")),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::UnknownDiskBackend("unknown".to_owned()))
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_net_parsing() -> Result<()> {
-        // mac address is random
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            backend: Some("qcow2".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
         assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,id=mynet0")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                id: Some("mynet0".to_owned()),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
         );
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            ..Default::default()
+        }]);
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            fds: Some(vec![0]),
+            ..Default::default()
+        }]);
         assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,tap=tap0,ip=192.168.100.1,mask=255.255.255.128"
-            )?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                tap: Some("tap0".to_owned()),
-                ip: "192.168.100.1".parse().unwrap(),
-                mask: "255.255.255.128".parse().unwrap(),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VnetReservedFd)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            offload_csum: false,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::NoHardwareChecksumOffload)
         );
 
-        assert_eq!(
-            NetConfig::parse(
-                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,vhost_user=true,socket=/tmp/sock"
-            )?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                vhost_user: true,
-                vhost_socket: Some("/tmp/sock".to_owned()),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            notification: Some(NotificationConfig {
+                coalesce_buffers: 0,
                 ..Default::default()
-            }
+            }),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNotificationCoalescing)
         );
 
-        assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,num_queues=4,queue_size=1024,iommu=on")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
-                num_queues: 4,
-                queue_size: 1024,
-                iommu: true,
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            notification: Some(NotificationConfig {
+                event_idx: false,
                 ..Default::default()
-            }
+            }),
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserNotification)
         );
 
-        assert_eq!(
-            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=[3,7],num_queues=4")?,
-            NetConfig {
-                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
-                fds: Some(vec![3, 7]),
-                num_queues: 4,
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            notification: Some(NotificationConfig {
+                coalesce_buffers: 8,
+                coalesce_timeout: 0,
                 ..Default::default()
-            }
+            }),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNotificationCoalescing)
         );
 
-        Ok(())
-    }
-
-    #[test]
-    fn test_parse_rng() -> Result<()> {
-        assert_eq!(RngConfig::parse("")?, RngConfig::default());
-        assert_eq!(
-            RngConfig::parse("src=/dev/random")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            notification: Some(NotificationConfig {
+                coalesce_buffers: 8,
                 ..Default::default()
-            }
-        );
+            }),
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
         assert_eq!(
-            RngConfig::parse("src=/dev/random,iommu=on")?,
-            RngConfig {
-                src: PathBuf::from("/dev/random"),
-                iommu: true,
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserNotification)
         );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            mmio: true,
+            ..Default::default()
+        }]);
+        still_valid_config.rng.mmio = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            mmio: true,
+            pci_root_port: Some("rp0".to_owned()),
+            ..Default::default()
+        }]);
         assert_eq!(
-            RngConfig::parse("iommu=on")?,
-            RngConfig {
-                iommu: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::MmioPciOption)
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_parse_fs() -> Result<()> {
-        // "tag" and "socket" must be supplied
-        assert!(FsConfig::parse("").is_err());
-        assert!(FsConfig::parse("tag=mytag").is_err());
-        assert!(FsConfig::parse("socket=/tmp/sock").is_err());
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            xdp: Some("eth0".to_owned()),
+            tap: Some("tap0".to_owned()),
+            ..Default::default()
+        }]);
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::XdpWithOtherBackend)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/path/to/sock".to_owned()),
+            mmio: true,
+            ..Default::default()
+        }]);
+        invalid_config.memory.shared = true;
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::MmioVhostUser)
         );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.fs = Some(vec![FsConfig {
+            ..Default::default()
+        }]);
         assert_eq!(
-            FsConfig::parse("tag=mytag,socket=/tmp/sock,num_queues=4,queue_size=1024")?,
-            FsConfig {
-                socket: PathBuf::from("/tmp/sock"),
-                tag: "mytag".to_owned(),
-                num_queues: 4,
-                queue_size: 1024,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::VhostUserRequiresSharedMemory)
         );
 
-        Ok(())
-    }
-
-    #[test]
-    fn test_pmem_parsing() -> Result<()> {
-        // Must always give a file and size
-        assert!(PmemConfig::parse("").is_err());
-        assert!(PmemConfig::parse("size=128M").is_err());
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![PmemConfig {
+            file: PathBuf::from("/tmp/pmem"),
+            discard: true,
+            discard_writes: true,
+            ..Default::default()
+        }]);
         assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M")?,
-            PmemConfig {
-                file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::PmemDiscardWithDiscardWrites)
         );
-        assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,id=mypmem0")?,
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![
             PmemConfig {
                 file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                id: Some("mypmem0".to_owned()),
                 ..Default::default()
-            }
-        );
-        assert_eq!(
-            PmemConfig::parse("file=/tmp/pmem,size=128M,iommu=on,discard_writes=on")?,
+            },
             PmemConfig {
                 file: PathBuf::from("/tmp/pmem"),
-                size: Some(128 << 20),
-                discard_writes: true,
-                iommu: true,
                 ..Default::default()
-            }
-        );
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_console_parsing() -> Result<()> {
-        assert!(ConsoleConfig::parse("").is_err());
-        assert!(ConsoleConfig::parse("badmode").is_err());
-        assert_eq!(
-            ConsoleConfig::parse("off")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Off,
-                iommu: false,
-                file: None,
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("pty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Pty,
-                iommu: false,
-                file: None,
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("tty")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Tty,
-                iommu: false,
-                file: None,
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("null")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: false,
-                file: None,
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: false,
-                file: Some(PathBuf::from("/tmp/console")),
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("null,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Null,
-                iommu: true,
-                file: None,
-                socket: None,
-            }
-        );
-        assert_eq!(
-            ConsoleConfig::parse("file=/tmp/console,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::File,
-                iommu: true,
-                file: Some(PathBuf::from("/tmp/console")),
-                socket: None,
-            }
-        );
+            },
+        ]);
         assert_eq!(
-            ConsoleConfig::parse("socket=/tmp/serial.sock,iommu=on")?,
-            ConsoleConfig {
-                mode: ConsoleOutputMode::Socket,
-                iommu: true,
-                file: None,
-                socket: Some(PathBuf::from("/tmp/serial.sock")),
-            }
+            invalid_config.validate(),
+            Err(ValidationError::DuplicatePmemFile("/tmp/pmem".to_string()))
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_device_parsing() -> Result<()> {
-        // Device must have a path provided
-        assert!(DeviceConfig::parse("").is_err());
-        assert_eq!(
-            DeviceConfig::parse("path=/path/to/device")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: None,
-                iommu: false,
+        // The same file reached through another path is detected
+        let mut invalid_config = valid_config.clone();
+        invalid_config.pmem = Some(vec![
+            PmemConfig {
+                file: PathBuf::from("/dev/null"),
                 ..Default::default()
-            }
+            },
+            PmemConfig {
+                file: PathBuf::from("/dev/../dev/null"),
+                discard_writes: true,
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::DuplicatePmemFile(
+                "/dev/../dev/null".to_string()
+            ))
         );
 
-        assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: None,
-                iommu: true,
+        // The devices discarding their writes can share a file
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.pmem = Some(vec![
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                discard_writes: true,
                 ..Default::default()
-            }
-        );
+            },
+            PmemConfig {
+                file: PathBuf::from("/tmp/pmem"),
+                discard_writes: true,
+                ..Default::default()
+            },
+        ]);
+        still_valid_config.validate().unwrap();
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.ivshmem = Some(vec![IvshmemConfig {
+            path: Some(PathBuf::from("/dev/shm/ivshmem")),
+            size: Some(3 << 20),
+            ..Default::default()
+        }]);
         assert_eq!(
-            DeviceConfig::parse("path=/path/to/device,iommu=on,id=mydevice0")?,
-            DeviceConfig {
-                path: PathBuf::from("/path/to/device"),
-                id: Some("mydevice0".to_owned()),
-                iommu: true,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIvshmemSize(3 << 20))
         );
 
-        Ok(())
-    }
-
-    #[test]
-    fn test_vdpa_parsing() -> Result<()> {
-        // path is required
-        assert!(VdpaConfig::parse("").is_err());
+        let mut invalid_config = valid_config.clone();
+        invalid_config.ivshmem = Some(vec![IvshmemConfig {
+            socket: Some(PathBuf::from("/tmp/ivshmem.sock")),
+            vectors: 0,
+            ..Default::default()
+        }]);
         assert_eq!(
-            VdpaConfig::parse("path=/dev/vhost-vdpa")?,
-            VdpaConfig {
-                path: PathBuf::from("/dev/vhost-vdpa"),
-                num_queues: 1,
-                id: None,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidIvshmemVectors(0))
         );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.shared = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory.hugepages = true;
+        still_valid_config.memory.hugepage_size = Some(2 << 20);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hugepages = false;
+        invalid_config.memory.hugepage_size = Some(2 << 20);
         assert_eq!(
-            VdpaConfig::parse("path=/dev/vhost-vdpa,num_queues=2,id=my_vdpa")?,
-            VdpaConfig {
-                path: PathBuf::from("/dev/vhost-vdpa"),
-                num_queues: 2,
-                id: Some("my_vdpa".to_owned()),
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::HugePageSizeWithoutHugePages)
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_tpm_parsing() -> Result<()> {
-        // path is required
-        assert!(TpmConfig::parse("").is_err());
+        let mut invalid_config = valid_config.clone();
+        invalid_config.memory.hugepages = true;
+        invalid_config.memory.hugepage_size = Some(3 << 20);
         assert_eq!(
-            TpmConfig::parse("socket=/var/run/tpm.sock")?,
-            TpmConfig {
-                socket: PathBuf::from("/var/run/tpm.sock"),
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidHugePageSize(3 << 20))
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_vsock_parsing() -> Result<()> {
-        // socket and cid is required
-        assert!(VsockConfig::parse("").is_err());
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS + 1,
+            ..Default::default()
+        });
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=1")?,
-            VsockConfig {
-                cid: 1,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: false,
-                id: None,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidNumPciSegments(
+                MAX_NUM_PCI_SEGMENTS + 1
+            ))
         );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![MAX_NUM_PCI_SEGMENTS + 1, MAX_NUM_PCI_SEGMENTS + 2]),
+            ..Default::default()
+        });
         assert_eq!(
-            VsockConfig::parse("socket=/tmp/sock,cid=1,iommu=on")?,
-            VsockConfig {
-                cid: 1,
-                socket: PathBuf::from("/tmp/sock"),
-                iommu: true,
-                id: None,
-                ..Default::default()
-            }
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSegment(MAX_NUM_PCI_SEGMENTS + 1))
         );
-        Ok(())
-    }
 
-    #[test]
-    fn test_config_validation() {
-        let mut valid_config = VmConfig {
-            cpus: CpusConfig {
-                boot_vcpus: 1,
-                max_vcpus: 1,
-                ..Default::default()
-            },
-            memory: MemoryConfig {
-                size: 536_870_912,
-                mergeable: false,
-                hotplug_method: HotplugMethod::Acpi,
-                hotplug_size: None,
-                hotplugged_size: None,
-                shared: false,
-                hugepages: false,
-                hugepage_size: None,
-                prefault: false,
-                zones: None,
-                thp: true,
-            },
-            payload: Some(PayloadConfig {
-                kernel: Some(PathBuf::from("/path/to/kernel")),
-                ..Default::default()
-            }),
-            disks: None,
-            net: None,
-            rng: RngConfig {
-                src: PathBuf::from("/dev/urandom"),
-                iommu: false,
-            },
-            balloon: None,
-            fs: None,
-            pmem: None,
-            serial: ConsoleConfig {
-                file: None,
-                mode: ConsoleOutputMode::Null,
-                iommu: false,
-                socket: None,
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: 2,
+            ..Default::default()
+        });
+        still_valid_config.pci_segments = Some(vec![
+            PciSegmentConfig {
+                pci_segment: 0,
+                start_bus: 0,
+                end_bus: 127,
             },
-            console: ConsoleConfig {
-                file: None,
-                mode: ConsoleOutputMode::Tty,
-                iommu: false,
-                socket: None,
+            PciSegmentConfig {
+                pci_segment: 1,
+                start_bus: 128,
+                end_bus: 255,
             },
-            devices: None,
-            user_devices: None,
-            vdpa: None,
-            vsock: None,
-            pvpanic: false,
-            iommu: false,
-            #[cfg(target_arch = "x86_64")]
-            sgx_epc: None,
-            numa: None,
-            watchdog: false,
-            #[cfg(feature = "guest_debug")]
-            gdb: false,
-            platform: None,
-            tpm: None,
-            preserved_fds: None,
-        };
+        ]);
+        assert!(still_valid_config.validate().is_ok());
 
-        assert!(valid_config.validate().is_ok());
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pci_segments.as_mut().unwrap()[0].end_bus = 128;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TooManyPciBuses(257))
+        );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::Tty;
-        invalid_config.console.mode = ConsoleOutputMode::Tty;
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pci_segments.as_mut().unwrap()[1].pci_segment = 0;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidPciSegmentBusRange(0, 128, 255))
+        );
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pci_segments.as_mut().unwrap()[1].end_bus = 127;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::DoubleTtyMode)
+            Err(ValidationError::InvalidPciSegmentBusRange(1, 128, 127))
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.payload = None;
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pci_segments.as_mut().unwrap()[1].pci_segment = 2;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::KernelMissing)
+            Err(ValidationError::InvalidPciSegment(2))
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.serial.mode = ConsoleOutputMode::File;
-        invalid_config.serial.file = None;
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config
+            .pci_segments
+            .as_mut()
+            .unwrap()
+            .push(PciSegmentConfig {
+                pci_segment: 1,
+                ..Default::default()
+            });
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::ConsoleFileMissing)
+            Err(ValidationError::DuplicatePciSegmentConfig(1))
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 32;
+        let mut still_valid_config = still_valid_config;
+        still_valid_config.pci_root_ports = Some(vec![
+            PciRootPortConfig {
+                id: "rp0".to_owned(),
+                pci_segment: 0,
+            },
+            PciRootPortConfig {
+                id: "rp1".to_owned(),
+                pci_segment: 1,
+            },
+        ]);
+        still_valid_config.devices = Some(vec![DeviceConfig {
+            path: "/device1".into(),
+            pci_segment: 1,
+            pci_root_port: Some("rp1".to_owned()),
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pci_segments.as_mut().unwrap()[1].end_bus = 128;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::CpusMaxLowerThanBoot)
+            Err(ValidationError::TooManyPciRootPorts(1))
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.cpus.max_vcpus = 16;
-        invalid_config.cpus.boot_vcpus = 16;
-        invalid_config.cpus.topology = Some(CpuTopology {
-            threads_per_core: 2,
-            cores_per_die: 8,
-            dies_per_package: 1,
-            packages: 2,
-        });
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.devices.as_mut().unwrap()[0].pci_segment = 0;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::CpuTopologyCount)
+            Err(ValidationError::UnknownPciRootPort("rp1".to_owned()))
         );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.disks = Some(vec![DiskConfig {
-            vhost_socket: Some("/path/to/sock".to_owned()),
-            path: Some(PathBuf::from("/path/to/image")),
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            pci_segment: 1,
+            pci_root_port: Some("rp1".to_owned()),
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::DiskSocketAndPath)
+            Err(ValidationError::PciRootPortReused("rp1".to_owned()))
         );
 
+        #[cfg(feature = "tdx")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                quote_generation_socket: Some(PathBuf::from("/tmp/qgs.socket")),
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::TdxQuoteGenerationWithoutTdx)
+            );
+
+            let platform = PlatformConfig {
+                s3: true,
+                tdx: true,
+                ..Default::default()
+            };
+            assert_eq!(platform.validate(), Err(ValidationError::TdxS3));
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                hibernate_action: HibernateAction::Shutdown,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::HibernateActionWithoutS4)
+            );
+
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                s4: true,
+                hibernate_action: HibernateAction::Shutdown,
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.platform = Some(PlatformConfig {
+                num_pci_segments: 2,
+                interrupt_remapping: true,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InterruptRemappingMultipleSegments)
+            );
+        }
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        still_valid_config.disks = Some(vec![DiskConfig {
+            iommu: true,
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        still_valid_config.net = Some(vec![NetConfig {
+            iommu: true,
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        still_valid_config.pmem = Some(vec![PmemConfig {
+            iommu: true,
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        still_valid_config.devices = Some(vec![DeviceConfig {
+            iommu: true,
+            pci_segment: 1,
+            ..Default::default()
+        }]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        still_valid_config.vsock = Some(VsockConfig {
+            iommu: true,
+            pci_segment: 1,
+            ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
-        invalid_config.memory.shared = true;
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
         invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
+            iommu: false,
+            pci_segment: 1,
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserMissingSocket)
+            Err(ValidationError::OnIommuSegment(1))
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        invalid_config.net = Some(vec![NetConfig {
+            iommu: false,
+            pci_segment: 1,
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::OnIommuSegment(1))
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.disks = Some(vec![DiskConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        invalid_config.pmem = Some(vec![PmemConfig {
+            iommu: false,
+            pci_segment: 1,
             ..Default::default()
         }]);
-        still_valid_config.memory.shared = true;
-        assert!(still_valid_config.validate().is_ok());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OnIommuSegment(1))
+        );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            vhost_user: true,
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
+            ..Default::default()
+        });
+        invalid_config.devices = Some(vec![DeviceConfig {
+            iommu: false,
+            pci_segment: 1,
             ..Default::default()
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::OnIommuSegment(1))
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.net = Some(vec![NetConfig {
-            vhost_user: true,
-            vhost_socket: Some("/path/to/sock".to_owned()),
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![DeviceConfig {
+            path: "/device1".into(),
+            iommu: true,
+            p2p: Some(Vec::new()),
             ..Default::default()
         }]);
-        still_valid_config.memory.shared = true;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::P2pDmaWithIommu("/device1".into()))
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.devices = Some(vec![
+            DeviceConfig {
+                path: "/device1".into(),
+                id: Some("gpu0".to_owned()),
+                p2p: Some(vec!["nic0".to_owned()]),
+                ..Default::default()
+            },
+            DeviceConfig {
+                path: "/device2".into(),
+                id: Some("nic0".to_owned()),
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::P2pDmaInvalidPeer("nic0".to_owned()))
+        );
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.devices.as_mut().unwrap()[1].p2p = Some(Vec::new());
         assert!(still_valid_config.validate().is_ok());
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            fds: Some(vec![0]),
+        invalid_config.platform = Some(PlatformConfig {
+            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+            iommu_segments: Some(vec![1, 2, 3]),
             ..Default::default()
-        }]);
+        });
+        invalid_config.vsock = Some(VsockConfig {
+            iommu: false,
+            pci_segment: 1,
+            ..Default::default()
+        });
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VnetReservedFd)
+            Err(ValidationError::OnIommuSegment(1))
         );
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.msr_filter = Some(MsrFilterConfig {
+                deny: vec![0x10],
+                zero: vec![0x10],
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::DuplicateMsrFilterEntry(0x10))
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.uefi_vars = Some(UefiVarsConfig {
+                path: PathBuf::from("/path/to/vars.fd"),
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::UefiVarsWithoutFirmware)
+            );
+
+            let mut still_valid_config = invalid_config.clone();
+            still_valid_config.payload = Some(PayloadConfig {
+                firmware: Some(PathBuf::from("/path/to/firmware")),
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+        }
+
         let mut invalid_config = valid_config.clone();
-        invalid_config.net = Some(vec![NetConfig {
-            offload_csum: false,
+        invalid_config.guest_agent = Some(GuestAgentConfig::default());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::GuestAgentWithoutVsock)
+        );
+
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.vsock = Some(VsockConfig {
+            cid: 3,
+            socket: PathBuf::from("/tmp/sock"),
             ..Default::default()
+        });
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.landlock_rules = Some(vec![LandlockConfig {
+            path: PathBuf::from("/tmp"),
+            access: LandlockAccess::ReadWrite,
         }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::NoHardwareChecksumOffload)
+            Err(ValidationError::LandlockRulesWithoutLandlock)
         );
 
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.landlock_enable = true;
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
-        invalid_config.fs = Some(vec![FsConfig {
-            ..Default::default()
-        }]);
+        invalid_config.payload.as_mut().unwrap().kernel_fd = Some(3);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::VhostUserRequiresSharedMemory)
+            Err(ValidationError::PayloadPathAndFd)
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.shared = true;
+        let mut still_valid_config = invalid_config.clone();
+        still_valid_config.payload.as_mut().unwrap().kernel = None;
         assert!(still_valid_config.validate().is_ok());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.process_limits = Some(ProcessLimitsConfig {
+            oom_score_adj: Some(-1001),
+            ..Default::default()
+        });
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidOomScoreAdj(-1001))
+        );
+
         let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.hugepages = true;
+        still_valid_config.seccomp_overrides = Some(vec![SeccompOverrideConfig::parse(
+            "id=__balloon,deny=[fallocate]",
+        )
+        .unwrap()]);
         assert!(still_valid_config.validate().is_ok());
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.memory.hugepages = true;
-        still_valid_config.memory.hugepage_size = Some(2 << 20);
-        assert!(still_valid_config.validate().is_ok());
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.seccomp_overrides = Some(vec![SeccompOverrideConfig::parse(
+            "id=__balloon,allow=[reboot]",
+        )
+        .unwrap()]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::UnknownSeccompSyscall("reboot".to_owned()))
+        );
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.memory.hugepages = false;
-        invalid_config.memory.hugepage_size = Some(2 << 20);
+        let mut invalid_config = still_valid_config;
+        invalid_config
+            .seccomp_overrides
+            .as_mut()
+            .unwrap()
+            .push(SeccompOverrideConfig::parse("id=__balloon,allow=[statx]").unwrap());
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::HugePageSizeWithoutHugePages)
+            Err(ValidationError::InvalidSeccompOverride(
+                "__balloon".to_owned()
+            ))
         );
 
+        #[cfg(feature = "sev_snp")]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.payload = Some(PayloadConfig {
+                igvm: Some(PathBuf::from("/path/to/igvm")),
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::IgvmWithoutSevSnp)
+            );
+
+            let mut still_valid_config = invalid_config.clone();
+            still_valid_config.platform = Some(PlatformConfig {
+                sev_snp: true,
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.payload.as_mut().unwrap().kernel =
+                Some(PathBuf::from("/path/to/kernel"));
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::IgvmWithOtherPayload)
+            );
+
+            let mut invalid_config = still_valid_config.clone();
+            invalid_config.payload.as_mut().unwrap().host_data = Some("00ff".to_string());
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::InvalidHostData("00ff".to_string()))
+            );
+
+            let mut still_valid_config = still_valid_config.clone();
+            still_valid_config.payload.as_mut().unwrap().host_data = Some("ab".repeat(32));
+            assert!(still_valid_config.validate().is_ok());
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.payload.as_mut().unwrap().host_data = Some("ab".repeat(32));
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::HostDataWithoutIgvm)
+            );
+        }
+
         let mut invalid_config = valid_config.clone();
-        invalid_config.memory.hugepages = true;
-        invalid_config.memory.hugepage_size = Some(3 << 20);
+        invalid_config.pvpanic_policy = Some(PvPanicPolicyConfig::default());
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::InvalidHugePageSize(3 << 20))
+            Err(ValidationError::PvPanicPolicyWithoutDevice)
         );
 
         let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
+        still_valid_config.pvpanic = true;
+        still_valid_config.pvpanic_policy = Some(PvPanicPolicyConfig {
+            action: PvPanicAction::Restart,
             ..Default::default()
         });
         assert!(still_valid_config.validate().is_ok());
 
-        let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS + 1,
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pvpanic_policy = Some(PvPanicPolicyConfig {
+            action: PvPanicAction::Pause,
+            restart: true,
             ..Default::default()
         });
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::InvalidNumPciSegments(
-                MAX_NUM_PCI_SEGMENTS + 1
-            ))
+            Err(ValidationError::PvPanicRestartUnsupported)
         );
 
         let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
+        still_valid_config.watchdog = true;
+        still_valid_config.watchdog_action = WatchdogAction::Panic;
+        still_valid_config.pvpanic_policy = Some(PvPanicPolicyConfig::default());
         assert!(still_valid_config.validate().is_ok());
 
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.pvpanic = true;
+            invalid_config.pvpanic_policy = Some(PvPanicPolicyConfig {
+                action: PvPanicAction::Coredump,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::PvPanicCoredumpPathMissing)
+            );
+
+            let mut invalid_config = valid_config.clone();
+            invalid_config.pvpanic = true;
+            invalid_config.pvpanic_policy = Some(PvPanicPolicyConfig {
+                action: PvPanicAction::Kdump,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::PvPanicKdumpDirMissing)
+            );
+
+            let mut still_valid_config = valid_config.clone();
+            still_valid_config.pvpanic = true;
+            still_valid_config.pvpanic_policy = Some(PvPanicPolicyConfig {
+                action: PvPanicAction::Coredump,
+                coredump_path: Some(PathBuf::from("/tmp/core")),
+                restart: true,
+                ..Default::default()
+            });
+            assert!(still_valid_config.validate().is_ok());
+        }
+
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![MAX_NUM_PCI_SEGMENTS + 1, MAX_NUM_PCI_SEGMENTS + 2]),
-            ..Default::default()
-        });
+        invalid_config.watchdog_escalation = Some(WatchdogEscalationConfig::default());
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::InvalidPciSegment(MAX_NUM_PCI_SEGMENTS + 1))
+            Err(ValidationError::WatchdogEscalationWithoutReset)
         );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        still_valid_config.disks = Some(vec![DiskConfig {
-            iommu: true,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
+        let mut still_valid_config = invalid_config;
+        still_valid_config.watchdog = true;
         assert!(still_valid_config.validate().is_ok());
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        still_valid_config.net = Some(vec![NetConfig {
-            iommu: true,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
-        assert!(still_valid_config.validate().is_ok());
+        let mut invalid_config = still_valid_config;
+        invalid_config.watchdog_action = WatchdogAction::Poweroff;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::WatchdogEscalationWithoutReset)
+        );
 
         let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        still_valid_config.pmem = Some(vec![PmemConfig {
-            iommu: true,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
+        still_valid_config.on_crash = Some(OnCrashConfig::default());
         assert!(still_valid_config.validate().is_ok());
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        still_valid_config.devices = Some(vec![DeviceConfig {
-            iommu: true,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
-        assert!(still_valid_config.validate().is_ok());
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.pvpanic = true;
+        invalid_config.pvpanic_policy = Some(PvPanicPolicyConfig::default());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OnCrashWithPvPanicPolicy)
+        );
 
-        let mut still_valid_config = valid_config.clone();
-        still_valid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        still_valid_config.vsock = Some(VsockConfig {
-            iommu: true,
-            pci_segment: 1,
+        let mut invalid_config = still_valid_config;
+        invalid_config.on_crash = Some(OnCrashConfig {
+            backoff: 10,
+            max_backoff: 5,
             ..Default::default()
         });
-        assert!(still_valid_config.validate().is_ok());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::OnCrashInvalidBackoff)
+        );
+
+        #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+        {
+            let mut invalid_config = valid_config.clone();
+            invalid_config.on_crash = Some(OnCrashConfig {
+                action: OnCrashAction::CoredumpAndRestart,
+                ..Default::default()
+            });
+            assert_eq!(
+                invalid_config.validate(),
+                Err(ValidationError::OnCrashCoredumpDirMissing)
+            );
+        }
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
+        invalid_config.vsock = Some(VsockConfig {
+            cid: 3,
+            siblings: Some(vec![VsockSiblingConfig {
+                cid: 3,
+                socket: PathBuf::from("/tmp/sock3"),
+            }]),
             ..Default::default()
         });
-        invalid_config.disks = Some(vec![DiskConfig {
-            iommu: false,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::OnIommuSegment(1))
+            Err(ValidationError::InvalidVsockSiblingCid(3))
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
+        invalid_config.vsock = Some(VsockConfig {
+            cid: 3,
+            listeners: Some(vec![
+                VsockListenerConfig {
+                    port: 1234,
+                    socket: PathBuf::from("/tmp/sock_a"),
+                },
+                VsockListenerConfig {
+                    port: 1234,
+                    socket: PathBuf::from("/tmp/sock_b"),
+                },
+            ]),
             ..Default::default()
         });
-        invalid_config.net = Some(vec![NetConfig {
-            iommu: false,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::OnIommuSegment(1))
+            Err(ValidationError::DuplicateVsockListenerPort(1234))
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        invalid_config.pmem = Some(vec![PmemConfig {
-            iommu: false,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
+        invalid_config.console.max_ports = VIRTIO_CONSOLE_MAX_PORTS + 1;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::OnIommuSegment(1))
+            Err(ValidationError::InvalidConsoleMaxPorts(
+                VIRTIO_CONSOLE_MAX_PORTS + 1
+            ))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial = ConsoleConfig::parse("socket_dir=/run/vm").unwrap();
+        assert!(still_valid_config.validate().is_ok());
+        assert_eq!(
+            still_valid_config.serial.socket,
+            Some(PathBuf::from("/run/vm/serial.sock"))
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        invalid_config.devices = Some(vec![DeviceConfig {
-            iommu: false,
-            pci_segment: 1,
-            ..Default::default()
-        }]);
+        invalid_config.serial.mode = ConsoleOutputMode::Socket;
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::OnIommuSegment(1))
+            Err(ValidationError::ConsoleSocketPathMissing)
         );
 
         let mut invalid_config = valid_config.clone();
-        invalid_config.platform = Some(PlatformConfig {
-            num_pci_segments: MAX_NUM_PCI_SEGMENTS,
-            iommu_segments: Some(vec![1, 2, 3]),
-            ..Default::default()
-        });
-        invalid_config.vsock = Some(VsockConfig {
-            iommu: false,
-            pci_segment: 1,
+        invalid_config.serial.max_ports = 2;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::SerialMaxPortsUnsupported)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console_ports = Some(vec![ConsolePortConfig::default()]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::ConsolePortsWithoutMultiport)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.console.max_ports = 2;
+        invalid_config.console_ports = Some(vec![
+            ConsolePortConfig::default(),
+            ConsolePortConfig::default(),
+        ]);
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::TooManyConsolePorts(2, 2))
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.console.max_ports = 2;
+        still_valid_config.console_ports = Some(vec![ConsolePortConfig::default()]);
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.console.socket_dir = Some(PathBuf::from("/run/vm"));
+        invalid_config.console_ports = Some(vec![ConsolePortConfig {
+            name: Some("agent/0".to_owned()),
             ..Default::default()
-        });
+        }]);
         assert_eq!(
             invalid_config.validate(),
-            Err(ValidationError::OnIommuSegment(1))
+            Err(ValidationError::InvalidConsolePortName(
+                "agent/0".to_owned()
+            ))
         );
 
         let mut invalid_config = valid_config.clone();
@@ -3606,6 +8892,131 @@ mod tests {
         ]);
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.balloon =
+            Some(BalloonConfig::parse("size=0,reclaim_below=1G,release_above=2G").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon =
+            Some(BalloonConfig::parse("size=0,reclaim_below=2G,release_above=1G").unwrap());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBalloonReclaim)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon = Some(BalloonConfig::parse("size=0,release_above=1G").unwrap());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBalloonReclaim)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.balloon = Some(
+            BalloonConfig::parse(
+                "size=0,heterogeneous_memory=on,hetero_pressure_high=40,hetero_pressure_low=10",
+            )
+            .unwrap(),
+        );
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.balloon = Some(
+            BalloonConfig::parse("size=0,heterogeneous_memory=on,cgroup_memory_high=on").unwrap(),
+        );
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon =
+            Some(BalloonConfig::parse("size=0,hetero_pressure_high=40").unwrap());
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBalloonHeteroPressure)
+        );
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.balloon = Some(
+            BalloonConfig::parse(
+                "size=0,heterogeneous_memory=on,hetero_pressure_high=10,hetero_pressure_low=40",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidBalloonHeteroPressure)
+        );
+
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.memory =
+            MemoryConfig::parse("size=0", Some(vec!["id=mem0,size=1G,overcommit=on"])).unwrap();
+        still_valid_config.balloon = Some(BalloonConfig::parse("size=0,reclaim_below=1G").unwrap());
+        assert!(still_valid_config.validate().is_ok());
+
+        let mut invalid_config = still_valid_config.clone();
+        invalid_config.balloon = None;
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMemoryZoneOvercommit(
+                "mem0".to_owned()
+            ))
+        );
+
+        let mut invalid_config = still_valid_config;
+        invalid_config.memory = MemoryConfig::parse(
+            "size=0",
+            Some(vec!["id=mem0,size=1G,hugepages=on,overcommit=on"]),
+        )
+        .unwrap();
+        assert_eq!(
+            invalid_config.validate(),
+            Err(ValidationError::InvalidMemoryZoneOvercommit(
+                "mem0".to_owned()
+            ))
+        );
+
+        let mut restored_config = valid_config.clone();
+        restored_config.net = Some(vec![NetConfig {
+            id: Some("net0".to_owned()),
+            ..Default::default()
+        }]);
+        restored_config.disks = Some(vec![DiskConfig {
+            id: Some("disk0".to_owned()),
+            path: Some(PathBuf::from("/path/to/image.img")),
+            ..Default::default()
+        }]);
+        let restore_config = RestoreConfig::parse(
+            "source_url=file:///tmp/snapshot,net_mac=[net0@de:ad:be:ef:12:34],\
+            disk_path=[disk0@/path/to/clone.img]",
+        )
+        .unwrap();
+        restore_config
+            .apply_overrides(&mut restored_config)
+            .unwrap();
+        assert_eq!(
+            restored_config.net.as_ref().unwrap()[0].mac,
+            MacAddr::parse_str("de:ad:be:ef:12:34").unwrap()
+        );
+        assert_eq!(
+            restored_config.disks.as_ref().unwrap()[0].path,
+            Some(PathBuf::from("/path/to/clone.img"))
+        );
+        // The changes apply to devices and memory zones of the snapshot
+        let restore_config = RestoreConfig::parse(
+            "source_url=file:///tmp/snapshot,memory_zone_host_numa_node=[mem0@1]",
+        )
+        .unwrap();
+        assert_eq!(
+            restore_config.apply_overrides(&mut restored_config),
+            Err(ValidationError::UnknownRestoredId("mem0".to_owned()))
+        );
+
+        // The virtio-rng device is kept out once removed
+        let mut removed_config = valid_config.clone();
+        assert!(removed_config.remove_device(RNG_DEVICE_NAME));
+        assert!(removed_config.rng.removed);
+        assert!(!removed_config.remove_device(RNG_DEVICE_NAME));
+
         let mut still_valid_config = valid_config;
         // SAFETY: Safe as the file was just opened
         let fd1 = unsafe { libc::dup(File::open("/dev/null").unwrap().as_raw_fd()) };
@@ -0,0 +1,143 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Rates of the counters of the VM.
+//!
+//! The counters only ever increase, and the clients interested in how fast
+//! they do would otherwise have to poll them and compute the differences
+//! themselves. Once a client asks for the rates, the VMM samples the counters
+//! periodically instead, each rate being the increase of a counter over the
+//! last sampling interval, per second. A counter found lower than on the last
+//! sample was reset along with its device, recreated on reboot or plugged
+//! again under the same identifier, and increased from zero since. The
+//! sampling stops once the rates haven't been asked for a while.
+
+use std::collections::{BTreeMap, HashMap};
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+pub const COUNTER_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Time without any request for the rates after which the sampling stops.
+const COUNTER_RATES_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+type Counters = HashMap<String, HashMap<&'static str, Wrapping<u64>>>;
+pub type Rates = BTreeMap<String, BTreeMap<&'static str, f64>>;
+
+pub struct CounterRates {
+    last_sample: Option<(Instant, Counters)>,
+    last_request: Instant,
+    rates: Rates,
+}
+
+impl CounterRates {
+    pub fn new(now: Instant) -> Self {
+        CounterRates {
+            last_sample: None,
+            last_request: now,
+            rates: Rates::new(),
+        }
+    }
+
+    pub fn sample(&mut self, counters: Counters, now: Instant) {
+        if let Some((last_time, last_counters)) = &self.last_sample {
+            let elapsed = now.duration_since(*last_time).as_secs_f64();
+            if elapsed > 0.0 {
+                // The counters of a device added since the last sample have
+                // no rate until the next one.
+                self.rates = counters
+                    .iter()
+                    .map(|(device, values)| {
+                        let rates = values
+                            .iter()
+                            .map(|(name, value)| {
+                                let last = last_counters
+                                    .get(device)
+                                    .and_then(|values| values.get(name))
+                                    .copied()
+                                    .unwrap_or(*value);
+                                let increase = if *value < last {
+                                    value.0
+                                } else {
+                                    (*value - last).0
+                                };
+                                (*name, increase as f64 / elapsed)
+                            })
+                            .collect();
+                        (device.clone(), rates)
+                    })
+                    .collect();
+            }
+        }
+
+        self.last_sample = Some((now, counters));
+    }
+
+    /// Rates as of the last sample, the request keeping the sampling going.
+    pub fn rates(&mut self, now: Instant) -> &Rates {
+        self.last_request = now;
+        &self.rates
+    }
+
+    /// Whether the rates haven't been asked for long enough for the sampling
+    /// to stop.
+    pub fn idle(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_request) >= COUNTER_RATES_IDLE_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counters(values: &[(&str, &'static str, u64)]) -> Counters {
+        let mut counters = Counters::new();
+        for (device, name, value) in values {
+            counters
+                .entry(device.to_string())
+                .or_default()
+                .insert(*name, Wrapping(*value));
+        }
+        counters
+    }
+
+    #[test]
+    fn test_counter_rates() {
+        let start = Instant::now();
+        let mut rates = CounterRates::new(start);
+
+        rates.sample(counters(&[("_disk0", "read_bytes", 1000)]), start);
+        assert!(rates.rates(start).is_empty());
+
+        rates.sample(
+            counters(&[("_disk0", "read_bytes", 5000), ("_net1", "rx_bytes", 300)]),
+            start + Duration::from_secs(2),
+        );
+        let now = start + Duration::from_secs(2);
+        assert_eq!(rates.rates(now)["_disk0"]["read_bytes"], 2000.0);
+        assert_eq!(rates.rates(now)["_net1"]["rx_bytes"], 0.0);
+
+        // Counters reset by a device recreated since the last sample
+        rates.sample(
+            counters(&[("_disk0", "read_bytes", 5000), ("_net1", "rx_bytes", 100)]),
+            start + Duration::from_secs(3),
+        );
+        let now = start + Duration::from_secs(3);
+        assert_eq!(rates.rates(now)["_disk0"]["read_bytes"], 0.0);
+        assert_eq!(rates.rates(now)["_net1"]["rx_bytes"], 100.0);
+    }
+
+    #[test]
+    fn test_counter_rates_idle() {
+        let start = Instant::now();
+        let mut rates = CounterRates::new(start);
+        assert!(!rates.idle(start + COUNTER_RATES_IDLE_TIMEOUT / 2));
+        assert!(rates.idle(start + COUNTER_RATES_IDLE_TIMEOUT));
+
+        // Each request delays the end of the sampling
+        rates.rates(start + COUNTER_RATES_IDLE_TIMEOUT / 2);
+        assert!(!rates.idle(start + COUNTER_RATES_IDLE_TIMEOUT));
+        assert!(rates.idle(start + COUNTER_RATES_IDLE_TIMEOUT * 3 / 2));
+    }
+}
@@ -11,7 +11,8 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::CpusConfig;
+use crate::cgroup::VmmCgroup;
+use crate::config::{CpuAffinity, CpuBandwidth, CpusConfig, MemoryZoneConfig, VmConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
     CpuElf64Writable, CpuSegment, CpuState as DumpCpusState, DumpState, Elf64Writable,
@@ -23,6 +24,8 @@ use crate::gdb::{get_raw_tid, Debuggable, DebuggableError};
 #[cfg(target_arch = "x86_64")]
 use crate::memory_manager::MemoryManager;
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+#[cfg(feature = "tdx")]
+use crate::tdx_quote::QuoteGenerationService;
 #[cfg(target_arch = "x86_64")]
 use crate::vm::physical_bits;
 use crate::GuestMemoryMmap;
@@ -31,6 +34,8 @@ use acpi_tables::{aml, sdt::Sdt, Aml};
 use anyhow::anyhow;
 #[cfg(all(target_arch = "aarch64", feature = "guest_debug"))]
 use arch::aarch64::regs;
+#[cfg(target_arch = "x86_64")]
+use arch::CoreType;
 use arch::EntryPoint;
 use arch::NumaNodes;
 #[cfg(target_arch = "aarch64")]
@@ -58,20 +63,26 @@ use hypervisor::kvm::kvm_ioctls::Cap;
 use hypervisor::kvm::{TdxExitDetails, TdxExitStatus};
 #[cfg(target_arch = "x86_64")]
 use hypervisor::CpuVendor;
-use hypervisor::{CpuState, HypervisorCpuError, HypervisorType, VmExit, VmOps};
+use hypervisor::{CpuState, HypervisorCpuError, HypervisorType, VcpuExitCounters, VmExit, VmOps};
+#[cfg(feature = "guest_debug")]
+use hypervisor::{DebugExit, Watchpoint};
 use libc::{c_void, siginfo_t};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use linux_loader::elf::Elf64_Nhdr;
 use seccompiler::{apply_filter, SeccompAction};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::io::Write;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use std::mem::size_of;
+use std::num::Wrapping;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "tdx")]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::channel;
 use std::sync::{Arc, Barrier, Mutex};
-use std::{cmp, io, result, thread};
+use std::{cmp, fs, io, result, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
 use vm_device::BusDevice;
@@ -108,6 +119,10 @@ macro_rules! extract_bits_64_without_offset {
 
 pub const CPU_MANAGER_ACPI_SIZE: usize = 0xc;
 
+const MPOL_PREFERRED: libc::c_int = 1;
+// Nodes of the memory policies saved and restored around the vCPU creation
+const MAX_NUMA_NODES: usize = 1024;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Error creating vCPU: {0}")]
@@ -119,6 +134,10 @@ pub enum Error {
     #[error("Error spawning vCPU thread: {0}")]
     VcpuSpawn(#[source] io::Error),
 
+    #[cfg(feature = "tdx")]
+    #[error("Error spawning the TDX quote generation thread: {0}")]
+    QuoteGenerationSpawn(#[source] io::Error),
+
     #[error("Error generating common CPUID: {0}")]
     CommonCpuId(#[source] arch::Error),
 
@@ -128,6 +147,24 @@ pub enum Error {
     #[error("Still pending removed vcpu")]
     VcpuPendingRemovedVcpu,
 
+    #[error("vCPU hotplug is not supported")]
+    VcpuHotplugNotSupported,
+
+    #[error("vCPU {0} is not present")]
+    VcpuNotPresent(u8),
+
+    #[error("vCPU {0} cannot be removed")]
+    VcpuNotRemovable(u8),
+
+    #[error("Error getting the VMM CPU affinity: {0}")]
+    GetVmmAffinity(#[source] io::Error),
+
+    #[error("Error setting the affinity of vCPU {0}: {1}")]
+    SetVcpuAffinity(u8, #[source] io::Error),
+
+    #[error("Error setting the CPU bandwidth limit: {0}")]
+    SetCpuBandwidth(#[source] crate::cgroup::Error),
+
     #[cfg(target_arch = "aarch64")]
     #[error("Error fetching preferred target: {0}")]
     VcpuArmPreferredTarget(#[source] hypervisor::HypervisorVmError),
@@ -164,10 +201,18 @@ pub enum Error {
     #[error("Error initializing TDX: {0}")]
     InitializeTdx(#[source] hypervisor::HypervisorCpuError),
 
+    #[cfg(feature = "sev_snp")]
+    #[error("Error setting the SEV control register: {0}")]
+    SetSevControlRegister(#[source] hypervisor::HypervisorCpuError),
+
     #[cfg(target_arch = "aarch64")]
     #[error("Error initializing PMU: {0}")]
     InitPmu(#[source] hypervisor::HypervisorCpuError),
 
+    #[cfg(target_arch = "aarch64")]
+    #[error("Error setting the PMU event filter: {0}")]
+    SetPmuEventFilter(#[source] hypervisor::HypervisorCpuError),
+
     #[cfg(feature = "guest_debug")]
     #[error("Error during CPU debug: {0}")]
     CpuDebug(#[source] hypervisor::HypervisorCpuError),
@@ -360,14 +405,16 @@ impl Vcpu {
     pub fn configure(
         &mut self,
         #[cfg(target_arch = "aarch64")] vm: &Arc<dyn hypervisor::Vm>,
+        #[cfg(target_arch = "aarch64")] pmu: bool,
         boot_setup: Option<(EntryPoint, &GuestMemoryAtomic<GuestMemoryMmap>)>,
         #[cfg(target_arch = "x86_64")] cpuid: Vec<CpuIdEntry>,
         #[cfg(target_arch = "x86_64")] kvm_hyperv: bool,
         #[cfg(target_arch = "x86_64")] topology: Option<(u8, u8, u8)>,
+        #[cfg(target_arch = "x86_64")] core_type: Option<CoreType>,
     ) -> Result<()> {
         #[cfg(target_arch = "aarch64")]
         {
-            self.init(vm)?;
+            self.init(vm, pmu)?;
             self.mpidr = arch::configure_vcpu(&self.vcpu, self.id, boot_setup)
                 .map_err(Error::VcpuConfiguration)?;
         }
@@ -381,6 +428,7 @@ impl Vcpu {
             kvm_hyperv,
             self.vendor,
             topology,
+            core_type,
         )
         .map_err(Error::VcpuConfiguration)?;
 
@@ -399,9 +447,10 @@ impl Vcpu {
         self.saved_state.clone()
     }
 
-    /// Initializes an aarch64 specific vcpu for booting Linux.
+    /// Initializes an aarch64 specific vcpu for booting Linux. The PMU is
+    /// only exposed when `pmu` is set.
     #[cfg(target_arch = "aarch64")]
-    pub fn init(&self, vm: &Arc<dyn hypervisor::Vm>) -> Result<()> {
+    pub fn init(&self, vm: &Arc<dyn hypervisor::Vm>, pmu: bool) -> Result<()> {
         let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
 
         // This reads back the kernel's preferred target type.
@@ -409,11 +458,12 @@ impl Vcpu {
             .map_err(Error::VcpuArmPreferredTarget)?;
         // We already checked that the capability is supported.
         kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PSCI_0_2;
-        if vm
-            .as_any()
-            .downcast_ref::<hypervisor::kvm::KvmVm>()
-            .unwrap()
-            .check_extension(Cap::ArmPmuV3)
+        if pmu
+            && vm
+                .as_any()
+                .downcast_ref::<hypervisor::kvm::KvmVm>()
+                .unwrap()
+                .check_extension(Cap::ArmPmuV3)
         {
             kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PMU_V3;
         }
@@ -455,6 +505,8 @@ impl Snapshottable for Vcpu {
 
 pub struct CpuManager {
     config: CpusConfig,
+    // Configuration of the VM, updated once the guest ejected a vCPU
+    vm_config: Arc<Mutex<VmConfig>>,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     interrupt_controller: Option<Arc<Mutex<dyn InterruptController>>>,
     #[cfg(target_arch = "x86_64")]
@@ -466,6 +518,9 @@ pub struct CpuManager {
     exit_evt: EventFd,
     #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
     reset_evt: EventFd,
+    // Signalled on a triple fault when a crash policy is set
+    #[cfg_attr(target_arch = "aarch64", allow(dead_code))]
+    crash_evt: Option<EventFd>,
     #[cfg(feature = "guest_debug")]
     vm_debug_evt: EventFd,
     vcpu_states: Vec<VcpuState>,
@@ -477,8 +532,14 @@ pub struct CpuManager {
     acpi_address: Option<GuestAddress>,
     proximity_domain_per_cpu: BTreeMap<u8, u32>,
     affinity: BTreeMap<u8, Vec<u8>>,
+    host_numa_nodes: BTreeMap<u8, Vec<u32>>,
+    // Set when the VM is configured with a cgroup, otherwise created once a
+    // CPU bandwidth limit gets set.
+    cgroup: Option<Arc<VmmCgroup>>,
     dynamic: bool,
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    #[cfg(feature = "tdx")]
+    quote_generation: Option<Arc<QuoteGenerationService>>,
 }
 
 const CPU_ENABLE_FLAG: usize = 0;
@@ -568,11 +629,22 @@ impl BusDevice for CpuManager {
 struct VcpuState {
     inserting: bool,
     removing: bool,
+    // The removal of this vCPU was requested on its own rather than by a
+    // resize, the configuration being left to update on ejection.
+    removal_requested: bool,
     pending_removal: Arc<AtomicBool>,
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
     vcpu_run_interrupted: Arc<AtomicBool>,
     paused: Arc<AtomicBool>,
+    // Host thread ID of the vCPU thread, 0 until the thread starts.
+    tid: Arc<AtomicI32>,
+    // Exits of the vCPU by reason, read without locking the vCPU as it is
+    // held while running.
+    exit_counters: Option<Arc<VcpuExitCounters>>,
+    // Cause of the last debug exit, until the debugger looks at it.
+    #[cfg(feature = "guest_debug")]
+    debug_exit: Arc<Mutex<Option<DebugExit>>>,
 }
 
 impl VcpuState {
@@ -611,6 +683,159 @@ impl VcpuState {
             handle.thread().unpark()
         }
     }
+
+    fn set_affinity(&self, cpuset: &libc::cpu_set_t) -> io::Result<()> {
+        if let Some(handle) = self.handle.as_ref() {
+            // SAFETY: FFI call with correct arguments
+            let ret = unsafe {
+                libc::pthread_setaffinity_np(
+                    handle.as_pthread_t() as _,
+                    std::mem::size_of::<libc::cpu_set_t>(),
+                    cpuset as *const libc::cpu_set_t,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Build the CPU set made of the given host CPUs.
+fn host_cpuset(host_cpus: &[u8]) -> libc::cpu_set_t {
+    // SAFETY: all zeros is a valid pattern
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: FFI call, trivially safe
+    unsafe { libc::CPU_ZERO(&mut cpuset) };
+    for host_cpu in host_cpus {
+        // SAFETY: FFI call, trivially safe
+        unsafe { libc::CPU_SET(*host_cpu as usize, &mut cpuset) };
+    }
+    cpuset
+}
+
+// Host NUMA nodes the memory zones of the guest NUMA node of each vCPU are
+// bound to, the vCPUs of the guest NUMA nodes without such binding being left
+// out.
+fn host_numa_nodes_per_cpu(
+    numa_nodes: &NumaNodes,
+    memory_zones: &[MemoryZoneConfig],
+) -> BTreeMap<u8, Vec<u32>> {
+    let mut host_numa_nodes = BTreeMap::new();
+    for numa_node in numa_nodes.values() {
+        let mut host_nodes: Vec<u32> = numa_node
+            .memory_zones
+            .iter()
+            .filter_map(|id| memory_zones.iter().find(|zone| &zone.id == id))
+            .filter_map(|zone| zone.host_numa_node)
+            .collect();
+        host_nodes.sort_unstable();
+        host_nodes.dedup();
+
+        if !host_nodes.is_empty() {
+            for cpu in numa_node.cpus.iter() {
+                host_numa_nodes.insert(*cpu, host_nodes.clone());
+            }
+        }
+    }
+    host_numa_nodes
+}
+
+fn set_thread_memory_policy(mode: libc::c_int, nodemask: &[u64]) -> io::Result<()> {
+    // Linux cuts off the last node of the mask, hence the extra one.
+    let maxnode = (nodemask.len() * 64) as u64 + 1;
+    // SAFETY: FFI call with a node mask holding maxnode - 1 bits
+    let ret = unsafe { libc::syscall(libc::SYS_set_mempolicy, mode, nodemask.as_ptr(), maxnode) };
+
+    if ret != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Allocate the memory on behalf of the calling thread, such as its stack and
+// the per-vCPU structures of the hypervisor, from the first of the given host
+// NUMA nodes. Unlike a binding, this is only a preference, the guest memory
+// without a policy of its own that the thread faults in still coming from the
+// other nodes when that one is full.
+fn prefer_host_numa_nodes(host_nodes: &[u32]) -> io::Result<()> {
+    let max_node = host_nodes.iter().copied().max().unwrap_or_default();
+    let mut nodemask = vec![0u64; max_node as usize / 64 + 1];
+    for node in host_nodes {
+        nodemask[*node as usize / 64] |= 1u64 << (node % 64);
+    }
+
+    set_thread_memory_policy(MPOL_PREFERRED, &nodemask)
+}
+
+// Memory policy of the calling thread, restored when dropped.
+struct ThreadMemoryPolicy {
+    mode: libc::c_int,
+    nodemask: [u64; MAX_NUMA_NODES / 64],
+}
+
+impl ThreadMemoryPolicy {
+    // Prefer the given host NUMA nodes until dropped.
+    fn prefer(host_nodes: &[u32]) -> io::Result<Self> {
+        let mut policy = ThreadMemoryPolicy {
+            mode: 0,
+            nodemask: [0; MAX_NUMA_NODES / 64],
+        };
+        // SAFETY: FFI call with a node mask holding MAX_NUMA_NODES bits, and
+        // no address as the policy of the thread is asked for.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut policy.mode as *mut libc::c_int,
+                policy.nodemask.as_mut_ptr(),
+                MAX_NUMA_NODES as u64,
+                std::ptr::null_mut::<libc::c_void>(),
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        prefer_host_numa_nodes(host_nodes)?;
+
+        Ok(policy)
+    }
+}
+
+impl Drop for ThreadMemoryPolicy {
+    fn drop(&mut self) {
+        if let Err(e) = set_thread_memory_policy(self.mode, &self.nodemask) {
+            warn!("Failed restoring the memory policy of the thread: {}", e);
+        }
+    }
+}
+
+// The schedstat file of a task holds the time spent running on a CPU, the
+// time spent waiting on a runqueue, and the number of timeslices run. The
+// second one, the run delay, is what KVM accounts as steal time for a vCPU
+// thread, in nanoseconds.
+fn schedstat_run_delay(schedstat: &str) -> Option<u64> {
+    let fields: Vec<&str> = schedstat.split_whitespace().collect();
+    if fields.len() != 3 {
+        return None;
+    }
+    fields[1].parse().ok()
+}
+
+fn thread_steal_time(tid: i32) -> io::Result<u64> {
+    let schedstat = fs::read_to_string(format!("/proc/self/task/{tid}/schedstat"))?;
+    schedstat_run_delay(&schedstat)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid schedstat"))
+}
+
+// Name of the counters entry of a vCPU. Like the other entries the VMM
+// creates, it is prefixed with "__" so that it can't clash with a device id.
+fn vcpu_counters_name(vcpu_id: usize) -> String {
+    format!("__vcpu{vcpu_id}")
 }
 
 impl CpuManager {
@@ -618,15 +843,18 @@ impl CpuManager {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: &CpusConfig,
+        vm_config: Arc<Mutex<VmConfig>>,
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        crash_evt: Option<EventFd>,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         hypervisor: &Arc<dyn hypervisor::Hypervisor>,
         seccomp_action: SeccompAction,
         vm_ops: Arc<dyn VmOps>,
         #[cfg(feature = "tdx")] tdx_enabled: bool,
         numa_nodes: &NumaNodes,
+        memory_zones: &[MemoryZoneConfig],
     ) -> Result<Arc<Mutex<CpuManager>>> {
         if u32::from(config.max_vcpus) > hypervisor.get_max_vcpus() {
             return Err(Error::MaximumVcpusExceeded);
@@ -698,6 +926,7 @@ impl CpuManager {
 
         Ok(Arc::new(Mutex::new(CpuManager {
             config: config.clone(),
+            vm_config,
             interrupt_controller: None,
             #[cfg(target_arch = "x86_64")]
             cpuid: Vec::new(),
@@ -707,6 +936,7 @@ impl CpuManager {
             vcpu_states,
             exit_evt,
             reset_evt,
+            crash_evt,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             selected_cpu: 0,
@@ -716,8 +946,12 @@ impl CpuManager {
             acpi_address: None,
             proximity_domain_per_cpu,
             affinity,
+            host_numa_nodes: host_numa_nodes_per_cpu(numa_nodes, memory_zones),
+            cgroup: None,
             dynamic,
             hypervisor: hypervisor.clone(),
+            #[cfg(feature = "tdx")]
+            quote_generation: None,
         })))
     }
 
@@ -743,9 +977,14 @@ impl CpuManager {
                     sgx_epc_sections,
                     phys_bits,
                     kvm_hyperv: self.config.kvm_hyperv,
+                    kvm_hyperv_features: self.config.hyperv_features(),
                     #[cfg(feature = "tdx")]
                     tdx,
                     amx: self.config.features.amx,
+                    nested: self.config.nested,
+                    model: self.config.model.clone(),
+                    cpuid_add: self.config.cpuid_add.clone(),
+                    cpuid_remove: self.config.cpuid_remove.clone(),
                 },
             )
             .map_err(Error::CommonCpuId)?
@@ -757,18 +996,32 @@ impl CpuManager {
     fn create_vcpu(&mut self, cpu_id: u8, snapshot: Option<Snapshot>) -> Result<Arc<Mutex<Vcpu>>> {
         info!("Creating vCPU: cpu_id = {}", cpu_id);
 
-        let mut vcpu = Vcpu::new(
+        // The structures the hypervisor allocates when creating the vCPU
+        // come from the host NUMA nodes of its guest NUMA node.
+        let memory_policy = self.host_numa_nodes.get(&cpu_id).and_then(|host_nodes| {
+            ThreadMemoryPolicy::prefer(host_nodes)
+                .map_err(|e| {
+                    warn!(
+                        "Failed preferring the host NUMA nodes {:?} for the vCPU {}: {}",
+                        host_nodes, cpu_id, e
+                    )
+                })
+                .ok()
+        });
+        let vcpu = Vcpu::new(
             cpu_id,
             &self.vm,
             Some(self.vm_ops.clone()),
             #[cfg(target_arch = "x86_64")]
             self.hypervisor.get_cpu_vendor(),
-        )?;
+        );
+        drop(memory_policy);
+        let mut vcpu = vcpu?;
 
         if let Some(snapshot) = snapshot {
             // AArch64 vCPUs should be initialized after created.
             #[cfg(target_arch = "aarch64")]
-            vcpu.init(&self.vm)?;
+            vcpu.init(&self.vm, self.config.pmu)?;
 
             let state: CpuState = snapshot.to_state().map_err(|e| {
                 Error::VcpuCreate(anyhow!("Could not get vCPU state from snapshot {:?}", e))
@@ -780,6 +1033,8 @@ impl CpuManager {
             vcpu.saved_state = Some(state);
         }
 
+        self.vcpu_states[usize::from(cpu_id)].exit_counters = Some(vcpu.vcpu.exit_counters());
+
         let vcpu = Arc::new(Mutex::new(vcpu));
 
         // Adding vCPU to the CpuManager's vCPU list.
@@ -815,10 +1070,11 @@ impl CpuManager {
             self.cpuid.clone(),
             self.config.kvm_hyperv,
             topology,
+            self.core_type(vcpu.id),
         )?;
 
         #[cfg(target_arch = "aarch64")]
-        vcpu.configure(&self.vm, boot_setup)?;
+        vcpu.configure(&self.vm, self.config.pmu, boot_setup)?;
 
         Ok(())
     }
@@ -857,10 +1113,23 @@ impl CpuManager {
 
     #[cfg(target_arch = "aarch64")]
     pub fn init_pmu(&self, irq: u32) -> Result<bool> {
-        for cpu in self.vcpus.iter() {
+        if !self.config.pmu {
+            return Ok(false);
+        }
+
+        for (index, cpu) in self.vcpus.iter().enumerate() {
             let cpu = cpu.lock().unwrap();
             // Check if PMU attr is available, if not, log the information.
             if cpu.vcpu.has_pmu_support() {
+                // The event filter is shared by all vCPUs, and must be set
+                // before the PMU is initialized.
+                if index == 0 {
+                    if let Some(events) = &self.config.pmu_events {
+                        cpu.vcpu
+                            .set_pmu_event_filter(events)
+                            .map_err(Error::SetPmuEventFilter)?;
+                    }
+                }
                 cpu.vcpu.init_pmu(irq).map_err(Error::InitPmu)?;
             } else {
                 debug!(
@@ -874,6 +1143,14 @@ impl CpuManager {
         Ok(true)
     }
 
+    /// Set the boot vCPU to resume from the S3 sleep state at
+    /// `waking_vector`, the guest bringing the other ones up again.
+    #[cfg(target_arch = "x86_64")]
+    pub fn wakeup(&self, waking_vector: u32) -> Result<()> {
+        let vcpu = self.vcpus[0].lock().unwrap();
+        arch::configure_vcpu_wakeup(&vcpu.vcpu, waking_vector).map_err(Error::VcpuConfiguration)
+    }
+
     pub fn vcpus(&self) -> Vec<Arc<Mutex<Vcpu>>> {
         self.vcpus.clone()
     }
@@ -886,6 +1163,11 @@ impl CpuManager {
         inserting: bool,
     ) -> Result<()> {
         let reset_evt = self.reset_evt.try_clone().unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let crash_evt = self
+            .crash_evt
+            .as_ref()
+            .map(|crash_evt| crash_evt.try_clone().unwrap());
         let exit_evt = self.exit_evt.try_clone().unwrap();
         #[cfg(feature = "kvm")]
         let hypervisor_type = self.hypervisor.hypervisor_type();
@@ -901,19 +1183,16 @@ impl CpuManager {
             .clone();
         let panic_vcpu_run_interrupted = vcpu_run_interrupted.clone();
         let vcpu_paused = self.vcpu_states[usize::from(vcpu_id)].paused.clone();
+        let vcpu_tid = self.vcpu_states[usize::from(vcpu_id)].tid.clone();
+        #[cfg(feature = "guest_debug")]
+        let vcpu_debug_exit = self.vcpu_states[usize::from(vcpu_id)].debug_exit.clone();
 
         // Prepare the CPU set the current vCPU is expected to run onto.
-        let cpuset = self.affinity.get(&vcpu_id).map(|host_cpus| {
-            // SAFETY: all zeros is a valid pattern
-            let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
-            // SAFETY: FFI call, trivially safe
-            unsafe { libc::CPU_ZERO(&mut cpuset) };
-            for host_cpu in host_cpus {
-                // SAFETY: FFI call, trivially safe
-                unsafe { libc::CPU_SET(*host_cpu as usize, &mut cpuset) };
-            }
-            cpuset
-        });
+        let cpuset = self
+            .affinity
+            .get(&vcpu_id)
+            .map(|host_cpus| host_cpuset(host_cpus));
+        let host_numa_nodes = self.host_numa_nodes.get(&vcpu_id).cloned();
 
         // Retrieve seccomp filter for vcpu thread
         let vcpu_seccomp_filter = get_seccomp_filter(
@@ -926,12 +1205,23 @@ impl CpuManager {
         #[cfg(target_arch = "x86_64")]
         let interrupt_controller_clone = self.interrupt_controller.as_ref().cloned();
 
+        #[cfg(feature = "tdx")]
+        let quote_generation = self.quote_generation.clone();
+
         info!("Starting vCPU: cpu_id = {}", vcpu_id);
 
+        let (started_sender, started_receiver) = channel();
         let handle = Some(
             thread::Builder::new()
                 .name(format!("vcpu{vcpu_id}"))
                 .spawn(move || {
+                    // SAFETY: FFI call without any argument
+                    vcpu_tid.store(
+                        unsafe { libc::syscall(libc::SYS_gettid) } as i32,
+                        Ordering::Release,
+                    );
+                    started_sender.send(()).ok();
+
                     // Schedule the thread to run on the expected CPU set
                     if let Some(cpuset) = cpuset.as_ref() {
                         // SAFETY: FFI call with correct arguments
@@ -953,6 +1243,17 @@ impl CpuManager {
                         }
                     }
 
+                    // Allocate from the host NUMA nodes the guest NUMA node
+                    // of the vCPU is bound to
+                    if let Some(host_numa_nodes) = host_numa_nodes.as_ref() {
+                        if let Err(e) = prefer_host_numa_nodes(host_numa_nodes) {
+                            warn!(
+                                "Failed preferring the host NUMA nodes {:?} for the vCPU {}: {}",
+                                host_numa_nodes, vcpu_id, e
+                            );
+                        }
+                    }
+
                     // Apply seccomp filter for vcpu thread.
                     if !vcpu_seccomp_filter.is_empty() {
                         if let Err(e) =
@@ -1030,14 +1331,17 @@ impl CpuManager {
                             let mut vcpu = vcpu.lock().unwrap();
                             #[cfg(not(feature = "tdx"))]
                             let vcpu = vcpu.lock().unwrap();
+                            #[cfg(target_arch = "x86_64")]
+                            let mut crashed = false;
                             // vcpu.run() returns false on a triple-fault so trigger a reset
                             match vcpu.run() {
                                 Ok(run) => match run {
                                     #[cfg(feature = "kvm")]
-                                    VmExit::Debug => {
-                                        info!("VmExit::Debug");
+                                    VmExit::Debug(debug_exit) => {
+                                        info!("VmExit::Debug: {:?}", debug_exit);
                                         #[cfg(feature = "guest_debug")]
                                         {
+                                            *vcpu_debug_exit.lock().unwrap() = Some(debug_exit);
                                             vcpu_pause_signalled.store(true, Ordering::SeqCst);
                                             let raw_tid = get_raw_tid(vcpu_id as usize);
                                             vm_debug_evt.write(raw_tid as u64).unwrap();
@@ -1062,6 +1366,18 @@ impl CpuManager {
                                         reset_evt.write(1).unwrap();
                                         break;
                                     }
+                                    #[cfg(target_arch = "x86_64")]
+                                    VmExit::TripleFault => {
+                                        info!("VmExit::TripleFault");
+                                        vcpu_run_interrupted.store(true, Ordering::SeqCst);
+                                        if let Some(crash_evt) = crash_evt.as_ref() {
+                                            crash_evt.write(1).unwrap();
+                                            crashed = true;
+                                        } else {
+                                            reset_evt.write(1).unwrap();
+                                            break;
+                                        }
+                                    }
                                     VmExit::Shutdown => {
                                         info!("VmExit::Shutdown");
                                         vcpu_run_interrupted.store(true, Ordering::SeqCst);
@@ -1071,16 +1387,27 @@ impl CpuManager {
                                     #[cfg(feature = "tdx")]
                                     VmExit::Tdx => {
                                         if let Some(vcpu) = Arc::get_mut(&mut vcpu.vcpu) {
-                                            match vcpu.get_tdx_exit_details() {
+                                            let status = match vcpu.get_tdx_exit_details() {
                                                 Ok(details) => match details {
-                                                    TdxExitDetails::GetQuote => warn!("TDG_VP_VMCALL_GET_QUOTE not supported"),
+                                                    TdxExitDetails::GetQuote { gpa, size } => {
+                                                        if let Some(quote_generation) = &quote_generation {
+                                                            quote_generation.get_quote(gpa, size)
+                                                        } else {
+                                                            warn!("TDG_VP_VMCALL_GET_QUOTE not supported without a quote generation socket");
+                                                            TdxExitStatus::InvalidOperand
+                                                        }
+                                                    }
                                                     TdxExitDetails::SetupEventNotifyInterrupt => {
-                                                        warn!("TDG_VP_VMCALL_SETUP_EVENT_NOTIFY_INTERRUPT not supported")
+                                                        warn!("TDG_VP_VMCALL_SETUP_EVENT_NOTIFY_INTERRUPT not supported");
+                                                        TdxExitStatus::InvalidOperand
                                                     }
                                                 },
-                                                Err(e) => error!("Unexpected TDX VMCALL: {}", e),
-                                            }
-                                            vcpu.set_tdx_status(TdxExitStatus::InvalidOperand);
+                                                Err(e) => {
+                                                    error!("Unexpected TDX VMCALL: {}", e);
+                                                    TdxExitStatus::InvalidOperand
+                                                }
+                                            };
+                                            vcpu.set_tdx_status(status);
                                         } else {
                                             // We should never reach this code as
                                             // this means the design from the code
@@ -1107,6 +1434,22 @@ impl CpuManager {
                                 }
                             }
 
+                            // Running the vCPU again would only fault again, so
+                            // it stays parked as if paused until the VMM applied
+                            // the crash policy, resuming, resetting or killing it.
+                            #[cfg(target_arch = "x86_64")]
+                            if crashed {
+                                drop(vcpu);
+                                vcpu_paused.store(true, Ordering::SeqCst);
+                                while vcpu_paused.load(Ordering::SeqCst)
+                                    && !vcpu_kill_signalled.load(Ordering::SeqCst)
+                                    && !vcpu_kill.load(Ordering::SeqCst)
+                                {
+                                    thread::park();
+                                }
+                                vcpu_run_interrupted.store(false, Ordering::SeqCst);
+                            }
+
                             // We've been told to terminate
                             if vcpu_kill_signalled.load(Ordering::SeqCst)
                                 || vcpu_kill.load(Ordering::SeqCst)
@@ -1131,6 +1474,10 @@ impl CpuManager {
         self.vcpu_states[usize::from(vcpu_id)].handle = handle;
         self.vcpu_states[usize::from(vcpu_id)].inserting = inserting;
 
+        // Wait for the thread ID to be known, as it is needed to limit the
+        // CPU time of the thread before it gets to run the guest.
+        started_receiver.recv().ok();
+
         Ok(())
     }
 
@@ -1161,27 +1508,90 @@ impl CpuManager {
             self.vcpus_pause_signalled.load(Ordering::SeqCst)
         );
 
-        // This reuses any inactive vCPUs as well as any that were newly created
-        for vcpu_id in self.present_vcpus()..desired_vcpus {
-            let vcpu = Arc::clone(&self.vcpus[vcpu_id as usize]);
-            self.start_vcpu(vcpu, vcpu_id, vcpu_thread_barrier.clone(), inserting)?;
+        // This reuses any inactive vCPUs, including the ones removed by id
+        // which leave holes behind, as well as any that were newly created.
+        // When booting, the holes left by the removed vCPUs are kept.
+        let boot_vcpu_ids = self.config.boot_vcpu_ids();
+        let vcpu_ids: Vec<u8> = (0..self.vcpus.len() as u8)
+            .filter(|vcpu_id| !self.vcpu_states[usize::from(*vcpu_id)].active())
+            .filter(|vcpu_id| inserting || boot_vcpu_ids.contains(vcpu_id))
+            .take((desired_vcpus - self.present_vcpus()) as usize)
+            .collect();
+        for vcpu_id in vcpu_ids.iter() {
+            let vcpu = Arc::clone(&self.vcpus[*vcpu_id as usize]);
+            self.start_vcpu(vcpu, *vcpu_id, vcpu_thread_barrier.clone(), inserting)?;
+            if inserting {
+                self.vm_config
+                    .lock()
+                    .unwrap()
+                    .cpus
+                    .set_vcpu_removed(*vcpu_id, false);
+            }
+        }
+
+        // The vCPU threads are held by the barrier, so that they don't run
+        // the guest before their CPU time is limited. They exit instead if the
+        // limit can't be applied.
+        if let Err(e) = self.set_vcpus_max(&vcpu_ids) {
+            for vcpu_id in vcpu_ids {
+                self.vcpu_states[usize::from(vcpu_id)]
+                    .kill
+                    .store(true, Ordering::SeqCst);
+            }
+            vcpu_thread_barrier.wait();
+            return Err(e);
         }
 
         // Unblock all CPU threads.
         vcpu_thread_barrier.wait();
+
+        Ok(())
+    }
+
+    fn set_vcpus_max(&self, vcpu_ids: &[u8]) -> Result<()> {
+        if let Some(cgroup) = self.cgroup.as_ref() {
+            for vcpu_id in vcpu_ids {
+                let tid = self.vcpu_states[usize::from(*vcpu_id)]
+                    .tid
+                    .load(Ordering::Acquire);
+                cgroup
+                    .set_vcpu_max(*vcpu_id, tid, self.config.vcpu_max)
+                    .map_err(Error::SetCpuBandwidth)?;
+            }
+        }
+
         Ok(())
     }
 
+    // Remove the cgroup of a vCPU whose thread exited.
+    fn remove_vcpu_cgroup(&self, vcpu_id: u8) {
+        if let Some(cgroup) = self.cgroup.as_ref() {
+            if let Err(e) = cgroup.remove_vcpu(vcpu_id) {
+                warn!("Cannot remove the cgroup of vCPU {}: {}", vcpu_id, e);
+            }
+        }
+    }
+
     fn mark_vcpus_for_removal(&mut self, desired_vcpus: u8) {
-        // Mark vCPUs for removal, actual removal happens on ejection
-        for cpu_id in desired_vcpus..self.present_vcpus() {
-            self.vcpu_states[usize::from(cpu_id)].removing = true;
-            self.vcpu_states[usize::from(cpu_id)]
-                .pending_removal
-                .store(true, Ordering::SeqCst);
+        // Mark the vCPUs with the highest ids for removal, actual removal
+        // happens on ejection
+        let cpu_ids: Vec<u8> = (0..self.max_vcpus())
+            .rev()
+            .filter(|cpu_id| self.vcpu_states[usize::from(*cpu_id)].active())
+            .take((self.present_vcpus() - desired_vcpus) as usize)
+            .collect();
+        for cpu_id in cpu_ids {
+            self.mark_vcpu_for_removal(cpu_id);
         }
     }
 
+    fn mark_vcpu_for_removal(&mut self, cpu_id: u8) {
+        self.vcpu_states[usize::from(cpu_id)].removing = true;
+        self.vcpu_states[usize::from(cpu_id)]
+            .pending_removal
+            .store(true, Ordering::SeqCst);
+    }
+
     pub fn check_pending_removed_vcpu(&mut self) -> bool {
         for state in self.vcpu_states.iter() {
             if state.active() && state.pending_removal.load(Ordering::SeqCst) {
@@ -1195,6 +1605,8 @@ impl CpuManager {
         info!("Removing vCPU: cpu_id = {}", cpu_id);
         let state = &mut self.vcpu_states[usize::from(cpu_id)];
         state.kill.store(true, Ordering::SeqCst);
+        // A vCPU parked after a triple fault only wakes up when unparked
+        state.unpark_thread();
         state.signal_thread();
         state.join_thread()?;
         state.handle = None;
@@ -1202,6 +1614,18 @@ impl CpuManager {
         // Once the thread has exited, clear the "kill" so that it can reused
         state.kill.store(false, Ordering::SeqCst);
         state.pending_removal.store(false, Ordering::SeqCst);
+        state.tid.store(0, Ordering::Release);
+
+        // Keep the number of vCPUs in sync, so that a reboot or a resize
+        // operates on the reduced count, and the vCPUs after the hole keep
+        // their ids. A resize already updated the count.
+        if std::mem::take(&mut state.removal_requested) {
+            let cpus = &mut self.vm_config.lock().unwrap().cpus;
+            cpus.boot_vcpus -= 1;
+            cpus.set_vcpu_removed(cpu_id, true);
+        }
+
+        self.remove_vcpu_cgroup(cpu_id);
 
         Ok(())
     }
@@ -1212,16 +1636,25 @@ impl CpuManager {
     ) -> Result<Vec<Arc<Mutex<Vcpu>>>> {
         trace_scoped!("create_boot_vcpus");
 
-        self.create_vcpus(self.boot_vcpus(), snapshot)
+        // The vCPUs removed before a reboot or a snapshot are created as
+        // well, for the ones after them to keep their ids and their state.
+        let vcpus = self
+            .config
+            .boot_vcpu_ids()
+            .last()
+            .map_or(0, |vcpu_id| vcpu_id + 1);
+        self.create_vcpus(vcpus, snapshot)
     }
 
     // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running.
     pub fn start_boot_vcpus(&mut self, paused: bool) -> Result<()> {
+        self.init_cpu_bandwidth()?;
         self.activate_vcpus(self.boot_vcpus(), false, Some(paused))
     }
 
     pub fn start_restored_vcpus(&mut self) -> Result<()> {
-        self.activate_vcpus(self.vcpus.len() as u8, false, Some(true))
+        self.init_cpu_bandwidth()?;
+        self.activate_vcpus(self.boot_vcpus(), false, Some(true))
             .map_err(|e| {
                 Error::StartRestoreVcpu(anyhow!("Failed to start restored vCPUs: {:#?}", e))
             })?;
@@ -1259,6 +1692,180 @@ impl CpuManager {
         }
     }
 
+    /// Request the guest to offline and eject a specific vCPU. The vCPU
+    /// thread is stopped once the guest acknowledges the ejection, while
+    /// the hypervisor vCPU is kept around to be reused by a later hotplug.
+    pub fn remove_vcpu_by_id(&mut self, cpu_id: u8) -> Result<()> {
+        if !self.dynamic {
+            return Err(Error::VcpuHotplugNotSupported);
+        }
+
+        if cpu_id >= self.max_vcpus() || !self.vcpu_states[usize::from(cpu_id)].active() {
+            return Err(Error::VcpuNotPresent(cpu_id));
+        }
+
+        // The boot vCPU cannot be taken offline by the guest
+        if cpu_id == 0 {
+            return Err(Error::VcpuNotRemovable(cpu_id));
+        }
+
+        if self.check_pending_removed_vcpu() {
+            return Err(Error::VcpuPendingRemovedVcpu);
+        }
+
+        self.mark_vcpu_for_removal(cpu_id);
+        self.vcpu_states[usize::from(cpu_id)].removal_requested = true;
+
+        Ok(())
+    }
+
+    /// Update the host NUMA nodes the vCPUs started from now on allocate
+    /// their memory from, after a memory zone was bound to another node.
+    pub fn update_host_numa_nodes(
+        &mut self,
+        numa_nodes: &NumaNodes,
+        memory_zones: &[MemoryZoneConfig],
+    ) {
+        self.host_numa_nodes = host_numa_nodes_per_cpu(numa_nodes, memory_zones);
+    }
+
+    /// Change the host CPUs the given vCPUs run onto. Running vCPU threads
+    /// are moved right away, while the affinity of inactive vCPUs is applied
+    /// when they get hotplugged. An empty list of host CPUs lets the vCPU
+    /// run on any of the host CPUs the VMM is allowed to use.
+    pub fn set_affinity(&mut self, affinity: &[CpuAffinity]) -> Result<()> {
+        // SAFETY: all zeros is a valid pattern
+        let mut vmm_cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        // SAFETY: FFI call with correct arguments
+        let ret = unsafe {
+            libc::sched_getaffinity(
+                0,
+                std::mem::size_of::<libc::cpu_set_t>(),
+                &mut vmm_cpuset as *mut libc::cpu_set_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::GetVmmAffinity(io::Error::last_os_error()));
+        }
+
+        for a in affinity {
+            if a.vcpu >= self.max_vcpus() {
+                return Err(Error::VcpuNotPresent(a.vcpu));
+            }
+
+            let cpuset = if a.host_cpus.is_empty() {
+                vmm_cpuset
+            } else {
+                host_cpuset(&a.host_cpus)
+            };
+            self.vcpu_states[usize::from(a.vcpu)]
+                .set_affinity(&cpuset)
+                .map_err(|e| Error::SetVcpuAffinity(a.vcpu, e))?;
+
+            if a.host_cpus.is_empty() {
+                self.affinity.remove(&a.vcpu);
+            } else {
+                self.affinity.insert(a.vcpu, a.host_cpus.clone());
+            }
+        }
+        self.config.update_affinity(affinity);
+
+        Ok(())
+    }
+
+    // Apply the CPU bandwidth limits from the configuration, before the vCPU
+    // threads get started.
+    fn init_cpu_bandwidth(&mut self) -> Result<()> {
+        if self.config.cpu_max.is_none() && self.config.vcpu_max.is_none() {
+            return Ok(());
+        }
+
+        if self.cgroup.is_none() {
+            self.cgroup = Some(Arc::new(
+                VmmCgroup::new(None).map_err(Error::SetCpuBandwidth)?,
+            ));
+        }
+        self.cgroup
+            .as_ref()
+            .unwrap()
+            .set_cpu_max(self.config.cpu_max)
+            .map_err(Error::SetCpuBandwidth)?;
+
+        Ok(())
+    }
+
+    /// Use the cgroup the VMM got placed in, rather than creating one when
+    /// a CPU bandwidth limit is set.
+    pub fn set_cgroup(&mut self, cgroup: Arc<VmmCgroup>) {
+        self.cgroup = Some(cgroup);
+    }
+
+    /// Change the CPU bandwidth limits of the whole VM and of each vCPU
+    /// thread, `None` meaning unlimited. The limit of the vCPUs applies to
+    /// the ones hotplugged later on as well.
+    pub fn set_cpu_bandwidth(
+        &mut self,
+        cpu_max: Option<CpuBandwidth>,
+        vcpu_max: Option<CpuBandwidth>,
+    ) -> Result<()> {
+        if self.cgroup.is_none() {
+            if cpu_max.is_none() && vcpu_max.is_none() {
+                return Ok(());
+            }
+            self.cgroup = Some(Arc::new(
+                VmmCgroup::new(None).map_err(Error::SetCpuBandwidth)?,
+            ));
+        }
+        let cgroup = self.cgroup.as_ref().unwrap();
+
+        cgroup
+            .set_cpu_max(cpu_max)
+            .map_err(Error::SetCpuBandwidth)?;
+        for (vcpu_id, state) in self.vcpu_states.iter().enumerate() {
+            let tid = state.tid.load(Ordering::Acquire);
+            if !state.active() || tid == 0 {
+                continue;
+            }
+            cgroup
+                .set_vcpu_max(vcpu_id as u8, tid, vcpu_max)
+                .map_err(Error::SetCpuBandwidth)?;
+        }
+        self.config.cpu_max = cpu_max;
+        self.config.vcpu_max = vcpu_max;
+
+        Ok(())
+    }
+
+    /// Per vCPU counters, matching the steal time reported to the guest
+    /// through the paravirtualized steal time structure, along with the
+    /// number of exits by reason.
+    pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
+        let mut counters = HashMap::new();
+
+        for (id, state) in self.vcpu_states.iter().enumerate() {
+            let tid = state.tid.load(Ordering::Acquire);
+            if !state.active() || tid == 0 {
+                continue;
+            }
+
+            let mut vcpu_counters = HashMap::new();
+            match thread_steal_time(tid) {
+                Ok(steal_time) => {
+                    vcpu_counters.insert("steal_time_ns", Wrapping(steal_time));
+                }
+                Err(e) => warn!("Cannot read the steal time of vCPU {}: {}", id, e),
+            }
+            if let Some(exit_counters) = &state.exit_counters {
+                for (name, value) in exit_counters.counters() {
+                    vcpu_counters.insert(name, Wrapping(value));
+                }
+            }
+            counters.insert(vcpu_counters_name(id), vcpu_counters);
+        }
+
+        counters
+    }
+
     pub fn shutdown(&mut self) -> Result<()> {
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
@@ -1279,8 +1886,13 @@ impl CpuManager {
         }
 
         // Wait for all the threads to finish. This removes the state from the vector.
-        for mut state in self.vcpu_states.drain(..) {
+        let vcpu_states: Vec<VcpuState> = self.vcpu_states.drain(..).collect();
+        for (vcpu_id, mut state) in vcpu_states.into_iter().enumerate() {
+            let active = state.active();
             state.join_thread()?;
+            if active {
+                self.remove_vcpu_cgroup(vcpu_id as u8);
+            }
         }
 
         Ok(())
@@ -1298,6 +1910,18 @@ impl CpuManager {
         Ok(())
     }
 
+    /// Start the boot vCPU from the VMSA found at the given page, the other
+    /// vCPUs being brought up by the guest itself.
+    #[cfg(feature = "sev_snp")]
+    pub fn set_sev_control_register(&self, vmsa_pfn: u64) -> Result<()> {
+        self.vcpus[0]
+            .lock()
+            .unwrap()
+            .vcpu
+            .set_sev_control_register(vmsa_pfn)
+            .map_err(Error::SetSevControlRegister)
+    }
+
     pub fn boot_vcpus(&self) -> u8 {
         self.config.boot_vcpus
     }
@@ -1306,12 +1930,42 @@ impl CpuManager {
         self.config.max_vcpus
     }
 
+    // Once efficiency cores are defined, every other vCPU is reported as a
+    // performance core.
+    #[cfg(target_arch = "x86_64")]
+    fn core_type(&self, cpu_id: u8) -> Option<CoreType> {
+        self.config.efficiency_cores.as_ref().map(|cores| {
+            if cores.contains(&cpu_id) {
+                CoreType::Efficiency
+            } else {
+                CoreType::Performance
+            }
+        })
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn common_cpuid(&self) -> Vec<CpuIdEntry> {
         assert!(!self.cpuid.is_empty());
         self.cpuid.clone()
     }
 
+    #[cfg(feature = "tdx")]
+    pub fn set_quote_generation_socket(&mut self, socket: &Path) -> Result<()> {
+        let phys_bits = physical_bits(&self.hypervisor, self.config.max_phys_bits);
+        let seccomp_filter = get_seccomp_filter(
+            &self.seccomp_action,
+            Thread::TdxQuote,
+            self.hypervisor.hypervisor_type(),
+        )
+        .map_err(Error::CreateSeccompFilter)?;
+        self.quote_generation = Some(Arc::new(
+            QuoteGenerationService::new(socket, phys_bits, self.vm_ops.clone(), seccomp_filter)
+                .map_err(Error::QuoteGenerationSpawn)?,
+        ));
+
+        Ok(())
+    }
+
     fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
@@ -1334,12 +1988,18 @@ impl CpuManager {
             .collect()
     }
 
+    /// On AArch64, the dies of the topology are exposed to the guest as
+    /// clusters, which is the level grouping cores below the package.
     #[cfg(target_arch = "aarch64")]
-    pub fn get_vcpu_topology(&self) -> Option<(u8, u8, u8)> {
-        self.config
-            .topology
-            .clone()
-            .map(|t| (t.threads_per_core, t.cores_per_die, t.packages))
+    pub fn get_vcpu_topology(&self) -> Option<(u8, u8, u8, u8)> {
+        self.config.topology.clone().map(|t| {
+            (
+                t.threads_per_core,
+                t.cores_per_die,
+                t.dies_per_package,
+                t.packages,
+            )
+        })
     }
 
     pub fn create_madt(&self) -> Sdt {
@@ -1352,13 +2012,14 @@ impl CpuManager {
         {
             madt.write(36, arch::layout::APIC_START.0);
 
+            let boot_vcpu_ids = self.config.boot_vcpu_ids();
             for cpu in 0..self.config.max_vcpus {
                 let lapic = LocalX2Apic {
                     r#type: acpi::ACPI_X2APIC_PROCESSOR,
                     length: 16,
                     processor_id: cpu.into(),
                     apic_id: cpu.into(),
-                    flags: if cpu < self.config.boot_vcpus {
+                    flags: if boot_vcpu_ids.contains(&cpu) {
                         1 << MADT_CPU_ENABLE_FLAG
                     } else {
                         0
@@ -1479,68 +2140,91 @@ impl CpuManager {
         // If topology is not specified, the default setting is:
         // 1 package, multiple cores, 1 thread per core
         // This is also the behavior when PPTT is missing.
-        let (threads_per_core, cores_per_package, packages) =
-            self.get_vcpu_topology().unwrap_or((1, self.max_vcpus(), 1));
+        let (threads_per_core, cores_per_cluster, clusters_per_package, packages) = self
+            .get_vcpu_topology()
+            .unwrap_or((1, self.max_vcpus(), 1, 1));
 
         let mut pptt = Sdt::new(*b"PPTT", 36, 2, *b"CLOUDH", *b"CHPPTT  ", 1);
 
-        for cluster_idx in 0..packages {
+        for package_idx in 0..packages {
             if cpus < self.config.boot_vcpus as usize {
-                let cluster_offset = pptt.len() - pptt_start;
-                let cluster_hierarchy_node = ProcessorHierarchyNode {
+                let package_offset = pptt.len() - pptt_start;
+                let package_hierarchy_node = ProcessorHierarchyNode {
                     r#type: 0,
                     length: 20,
                     reserved: 0,
                     flags: 0x2,
                     parent: 0,
-                    acpi_processor_id: cluster_idx as u32,
+                    acpi_processor_id: package_idx as u32,
                     num_private_resources: 0,
                 };
-                pptt.append(cluster_hierarchy_node);
-
-                for core_idx in 0..cores_per_package {
-                    let core_offset = pptt.len() - pptt_start;
-
-                    if threads_per_core > 1 {
-                        let core_hierarchy_node = ProcessorHierarchyNode {
+                pptt.append(package_hierarchy_node);
+
+                for cluster_idx in 0..clusters_per_package {
+                    // Only describe the cluster level when there is more
+                    // than one cluster, otherwise cores belong directly to
+                    // the package.
+                    let cluster_offset = if clusters_per_package > 1 {
+                        let cluster_offset = pptt.len() - pptt_start;
+                        let cluster_hierarchy_node = ProcessorHierarchyNode {
                             r#type: 0,
                             length: 20,
                             reserved: 0,
                             flags: 0x2,
-                            parent: cluster_offset as u32,
-                            acpi_processor_id: core_idx as u32,
+                            parent: package_offset as u32,
+                            acpi_processor_id: cluster_idx as u32,
                             num_private_resources: 0,
                         };
-                        pptt.append(core_hierarchy_node);
+                        pptt.append(cluster_hierarchy_node);
+                        cluster_offset
+                    } else {
+                        package_offset
+                    };
+
+                    for core_idx in 0..cores_per_cluster {
+                        let core_offset = pptt.len() - pptt_start;
 
-                        for _thread_idx in 0..threads_per_core {
+                        if threads_per_core > 1 {
+                            let core_hierarchy_node = ProcessorHierarchyNode {
+                                r#type: 0,
+                                length: 20,
+                                reserved: 0,
+                                flags: 0x2,
+                                parent: cluster_offset as u32,
+                                acpi_processor_id: core_idx as u32,
+                                num_private_resources: 0,
+                            };
+                            pptt.append(core_hierarchy_node);
+
+                            for _thread_idx in 0..threads_per_core {
+                                let thread_hierarchy_node = ProcessorHierarchyNode {
+                                    r#type: 0,
+                                    length: 20,
+                                    reserved: 0,
+                                    flags: 0xE,
+                                    parent: core_offset as u32,
+                                    acpi_processor_id: uid as u32,
+                                    num_private_resources: 0,
+                                };
+                                pptt.append(thread_hierarchy_node);
+                                uid += 1;
+                            }
+                        } else {
                             let thread_hierarchy_node = ProcessorHierarchyNode {
                                 r#type: 0,
                                 length: 20,
                                 reserved: 0,
-                                flags: 0xE,
-                                parent: core_offset as u32,
+                                flags: 0xA,
+                                parent: cluster_offset as u32,
                                 acpi_processor_id: uid as u32,
                                 num_private_resources: 0,
                             };
                             pptt.append(thread_hierarchy_node);
                             uid += 1;
                         }
-                    } else {
-                        let thread_hierarchy_node = ProcessorHierarchyNode {
-                            r#type: 0,
-                            length: 20,
-                            reserved: 0,
-                            flags: 0xA,
-                            parent: cluster_offset as u32,
-                            acpi_processor_id: uid as u32,
-                            num_private_resources: 0,
-                        };
-                        pptt.append(thread_hierarchy_node);
-                        uid += 1;
                     }
                 }
-                cpus += (cores_per_package * threads_per_core) as usize;
+                cpus += (clusters_per_package * cores_per_cluster * threads_per_core) as usize;
             }
         }
 
@@ -1790,8 +2474,17 @@ struct Cpu {
     cpu_id: u8,
     proximity_domain: u32,
     dynamic: bool,
+    #[cfg(target_arch = "x86_64")]
+    core_type: Option<CoreType>,
 }
 
+// Highest performance reported through _CPC for the performance and the
+// efficiency cores, only meaningful relative to each other.
+#[cfg(target_arch = "x86_64")]
+const CPPC_PERFORMANCE_CORE_PERF: u8 = 100;
+#[cfg(target_arch = "x86_64")]
+const CPPC_EFFICIENCY_CORE_PERF: u8 = 60;
+
 #[cfg(target_arch = "x86_64")]
 const MADT_CPU_ENABLE_FLAG: usize = 0;
 
@@ -1818,10 +2511,70 @@ impl Cpu {
     }
 }
 
+// Revision 3 _CPC package, only describing the performance of the core and
+// leaving all the registers unimplemented, for the guest to know about the
+// relative capacity of the performance and efficiency cores. Nothing is
+// described when no core type is set.
+#[cfg(target_arch = "x86_64")]
+struct Cpc {
+    core_type: Option<CoreType>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Aml for Cpc {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        // Generic Register Descriptor with a null address, followed by the
+        // end tag of the resource template.
+        const NULL_REGISTER: [u8; 17] = [
+            0x82, 0x0c, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x79, 0x00,
+        ];
+
+        let perf = match self.core_type {
+            Some(CoreType::Performance) => CPPC_PERFORMANCE_CORE_PERF,
+            Some(CoreType::Efficiency) => CPPC_EFFICIENCY_CORE_PERF,
+            None => return,
+        };
+        let null = aml::BufferData::new(NULL_REGISTER.to_vec());
+        aml::Name::new(
+            "_CPC".into(),
+            &aml::Package::new(vec![
+                &23u8, // Number of entries
+                &3u8,  // Revision
+                &perf, // Highest Performance
+                &perf, // Nominal Performance
+                &1u8,  // Lowest Nonlinear Performance
+                &1u8,  // Lowest Performance
+                &null, // Guaranteed Performance Register
+                &null, // Desired Performance Register
+                &null, // Minimum Performance Register
+                &null, // Maximum Performance Register
+                &null, // Performance Reduction Tolerance Register
+                &null, // Time Window Register
+                &0u8,  // Counter Wraparound Time
+                &null, // Reference Performance Counter Register
+                &null, // Delivered Performance Counter Register
+                &null, // Performance Limited Register
+                &null, // CPPC Enable Register
+                &0u8,  // Autonomous Selection Enable
+                &null, // Autonomous Activity Window Register
+                &null, // Energy Performance Preference Register
+                &perf, // Reference Performance
+                &0u8,  // Lowest Frequency
+                &0u8,  // Nominal Frequency
+            ]),
+        )
+        .to_aml_bytes(sink);
+    }
+}
+
 impl Aml for Cpu {
     fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
         #[cfg(target_arch = "x86_64")]
         let mat_data: Vec<u8> = self.generate_mat();
+        #[cfg(target_arch = "x86_64")]
+        let cpc = Cpc {
+            core_type: self.core_type,
+        };
         #[allow(clippy::if_same_then_else)]
         if self.dynamic {
             aml::Device::new(
@@ -1870,6 +2623,8 @@ impl Aml for Cpu {
                         // Call into CEJ0 method which will actually eject device
                         vec![&aml::MethodCall::new("CEJ0".into(), vec![&self.cpu_id])],
                     ),
+                    #[cfg(target_arch = "x86_64")]
+                    &cpc,
                 ],
             )
             .to_aml_bytes(sink);
@@ -1898,6 +2653,8 @@ impl Aml for Cpu {
                     // even it if is disabled in the MADT (non-boot CPU)
                     #[cfg(target_arch = "x86_64")]
                     &aml::Name::new("_MAT".into(), &aml::BufferData::new(mat_data)),
+                    #[cfg(target_arch = "x86_64")]
+                    &cpc,
                 ],
             )
             .to_aml_bytes(sink);
@@ -2115,6 +2872,8 @@ impl Aml for CpuManager {
                 cpu_id,
                 proximity_domain,
                 dynamic: self.dynamic,
+                #[cfg(target_arch = "x86_64")]
+                core_type: self.core_type(cpu_id),
             };
 
             cpu_devices.push(cpu_device);
@@ -2216,16 +2975,21 @@ impl Debuggable for CpuManager {
         &self,
         cpu_id: usize,
         addrs: &[GuestAddress],
+        watchpoints: &[Watchpoint],
         singlestep: bool,
     ) -> std::result::Result<(), DebuggableError> {
         self.vcpus[cpu_id]
             .lock()
             .unwrap()
             .vcpu
-            .set_guest_debug(addrs, singlestep)
+            .set_guest_debug(addrs, watchpoints, singlestep)
             .map_err(DebuggableError::SetDebug)
     }
 
+    fn take_debug_exit(&self, cpu_id: usize) -> Option<DebugExit> {
+        self.vcpu_states[cpu_id].debug_exit.lock().unwrap().take()
+    }
+
     fn debug_pause(&mut self) -> std::result::Result<(), DebuggableError> {
         Ok(())
     }
@@ -2741,6 +3505,124 @@ mod tests {
         let actual_regs: StandardRegisters = vcpu.get_regs().unwrap();
         assert_eq!(actual_regs, expected_regs);
     }
+
+    #[test]
+    fn test_cpc() {
+        use super::{Cpc, CPPC_EFFICIENCY_CORE_PERF, CPPC_PERFORMANCE_CORE_PERF};
+        use acpi_tables::Aml;
+        use arch::CoreType;
+
+        let cpc_bytes = |core_type| {
+            let mut bytes = Vec::new();
+            Cpc { core_type }.to_aml_bytes(&mut bytes);
+            bytes
+        };
+
+        assert!(cpc_bytes(None).is_empty());
+
+        // NameOp, name, PackageOp and package length, followed by the
+        // number of elements, then the number of entries, the revision and
+        // the highest performance as byte constants
+        for (core_type, perf) in [
+            (CoreType::Performance, CPPC_PERFORMANCE_CORE_PERF),
+            (CoreType::Efficiency, CPPC_EFFICIENCY_CORE_PERF),
+        ] {
+            let bytes = cpc_bytes(Some(core_type));
+            assert_eq!(&bytes[..5], b"\x08_CPC");
+            assert_eq!(bytes[5], 0x12);
+            assert_eq!(&bytes[8..15], &[23, 0x0a, 23, 0x0a, 3, 0x0a, perf]);
+        }
+    }
+
+    #[test]
+    fn test_schedstat_run_delay() {
+        use super::{schedstat_run_delay, vcpu_counters_name};
+
+        assert_eq!(schedstat_run_delay("4125735 1820394 42\n"), Some(1820394));
+        assert_eq!(schedstat_run_delay("4125735 1820394\n"), None);
+        assert_eq!(schedstat_run_delay("4125735 -1 42\n"), None);
+        assert_eq!(schedstat_run_delay(""), None);
+
+        assert_eq!(vcpu_counters_name(3), "__vcpu3");
+    }
+
+    #[test]
+    fn test_host_numa_nodes_per_cpu() {
+        use super::host_numa_nodes_per_cpu;
+        use crate::config::MemoryZoneConfig;
+        use arch::{NumaNode, NumaNodes};
+
+        let zone = |id: &str, host_numa_node: Option<u32>| MemoryZoneConfig {
+            id: id.to_owned(),
+            size: 1 << 30,
+            file: None,
+            shared: false,
+            hugepages: false,
+            hugepage_size: None,
+            host_numa_node,
+            hotplug_size: None,
+            hotplugged_size: None,
+            prefault: false,
+            overcommit: false,
+        };
+        let memory_zones = [
+            zone("mem0", Some(1)),
+            zone("mem1", Some(0)),
+            zone("mem2", Some(1)),
+            zone("mem3", None),
+        ];
+        let node = |cpus: Vec<u8>, memory_zones: &[&str]| NumaNode {
+            cpus,
+            memory_zones: memory_zones.iter().map(|id| id.to_string()).collect(),
+            ..Default::default()
+        };
+        let numa_nodes = NumaNodes::from([
+            (0, node(vec![0, 1], &["mem0", "mem1", "mem2"])),
+            (1, node(vec![2], &["mem3"])),
+            (2, node(vec![3], &[])),
+        ]);
+
+        // The vCPUs of the guest NUMA nodes not bound to any host NUMA node
+        // are left out, the others getting the sorted nodes of their zones.
+        let host_numa_nodes = host_numa_nodes_per_cpu(&numa_nodes, &memory_zones);
+        assert_eq!(host_numa_nodes.len(), 2);
+        assert_eq!(host_numa_nodes[&0], vec![0, 1]);
+        assert_eq!(host_numa_nodes[&1], vec![0, 1]);
+    }
+
+    #[test]
+    fn test_thread_memory_policy() {
+        use super::{ThreadMemoryPolicy, MAX_NUMA_NODES, MPOL_PREFERRED};
+
+        fn thread_memory_policy() -> (libc::c_int, u64) {
+            let mut mode = 0;
+            let mut nodemask = [0u64; MAX_NUMA_NODES / 64];
+            // SAFETY: FFI call with a node mask holding MAX_NUMA_NODES bits
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_get_mempolicy,
+                    &mut mode as *mut libc::c_int,
+                    nodemask.as_mut_ptr(),
+                    MAX_NUMA_NODES as u64,
+                    std::ptr::null_mut::<libc::c_void>(),
+                    0,
+                )
+            };
+            assert_eq!(ret, 0);
+            (mode, nodemask[0])
+        }
+
+        // Run from a thread of its own, the policy being the one of the thread
+        std::thread::spawn(move || {
+            let original = thread_memory_policy();
+            let policy = ThreadMemoryPolicy::prefer(&[0]).unwrap();
+            assert_eq!(thread_memory_policy(), (MPOL_PREFERRED, 1));
+            drop(policy);
+            assert_eq!(thread_memory_policy(), original);
+        })
+        .join()
+        .unwrap();
+    }
 }
 
 #[cfg(target_arch = "aarch64")]
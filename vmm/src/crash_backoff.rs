@@ -0,0 +1,94 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Backoff of the restarts of a crashing VM.
+//!
+//! A guest crashing right after booting would otherwise be restarted in a
+//! tight loop. The delay before restarting the VM doubles with each crash in a
+//! row, from the backoff of the crash policy up to its maximum backoff, a VM
+//! running for longer than the maximum backoff being considered healthy again.
+//! Once the VM crashed too many times in a row, it is not restarted anymore.
+
+use crate::vm_config::OnCrashConfig;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct CrashBackoff {
+    // Crashes without the VM running long enough in between
+    consecutive: u32,
+    // Time after which the next crash doesn't follow the last one anymore
+    healthy_at: Option<Instant>,
+}
+
+impl CrashBackoff {
+    /// Delay before restarting the VM after a crash at `now`, `None` if the
+    /// VM must not be restarted anymore.
+    pub fn restart_delay(&mut self, config: &OnCrashConfig, now: Instant) -> Option<Duration> {
+        if self.healthy_at.map_or(true, |healthy_at| now >= healthy_at) {
+            self.consecutive = 0;
+        }
+
+        if config
+            .max_restarts
+            .map_or(false, |max_restarts| self.consecutive >= max_restarts)
+        {
+            return None;
+        }
+
+        let max_backoff = Duration::from_secs(config.max_backoff);
+        let delay = Duration::from_secs(config.backoff)
+            .saturating_mul(2u32.saturating_pow(self.consecutive))
+            .min(max_backoff);
+
+        self.consecutive += 1;
+        self.healthy_at = Some(now + delay + max_backoff);
+
+        Some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crash_backoff() {
+        let config = OnCrashConfig {
+            backoff: 1,
+            max_backoff: 4,
+            max_restarts: Some(4),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut backoff = CrashBackoff::default();
+
+        assert_eq!(
+            backoff.restart_delay(&config, start),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            backoff.restart_delay(&config, start + Duration::from_secs(2)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            backoff.restart_delay(&config, start + Duration::from_secs(5)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            backoff.restart_delay(&config, start + Duration::from_secs(10)),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(
+            backoff.restart_delay(&config, start + Duration::from_secs(15)),
+            None
+        );
+
+        // Healthy again after running for longer than the maximum backoff
+        assert_eq!(
+            backoff.restart_delay(&config, start + Duration::from_secs(30)),
+            Some(Duration::from_secs(1))
+        );
+    }
+}
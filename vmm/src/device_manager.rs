@@ -9,19 +9,30 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+#[cfg(target_arch = "x86_64")]
+use crate::config::UefiVarsConfig;
 use crate::config::{
-    ConsoleOutputMode, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, UserDeviceConfig,
-    VdpaConfig, VhostMode, VmConfig, VsockConfig,
+    ConsoleOutputMode, ConsolePortConfig, DeviceConfig, DiskConfig, FsConfig, IoPriorityConfig,
+    IvshmemConfig, NetConfig, PciSegmentConfig, PmemConfig, UserDeviceConfig, VdpaConfig,
+    VhostMode, VmConfig, VsockConfig,
 };
 use crate::cpu::{CpuManager, CPU_MANAGER_ACPI_SIZE};
 use crate::device_tree::{DeviceNode, DeviceTree};
 use crate::interrupt::LegacyUserspaceInterruptManager;
 use crate::interrupt::MsiInterruptManager;
+use crate::interrupt::RemappedMsiInterruptManager;
+#[cfg(target_arch = "x86_64")]
+use crate::interrupt_remapping::{
+    InterruptRemappingDevice, InterruptRemappingUnit, INTERRUPT_REMAPPING_SIZE, IOAPIC_SOURCE_ID,
+};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager, MEMORY_MANAGER_ACPI_SIZE};
-use crate::pci_segment::PciSegment;
+use crate::pci_segment::{PciSegment, RootPort};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::serial_manager::{Error as SerialManagerError, SerialManager};
 use crate::sigwinch_listener::start_sigwinch_listener;
+#[cfg(target_arch = "x86_64")]
+use crate::uefi_vars;
+use crate::virtiofsd::{self, VirtiofsDaemon};
 use crate::GuestRegionMmap;
 use crate::PciDeviceInfo;
 use crate::{device_node, DEVICE_MANAGER_SNAPSHOT_ID};
@@ -35,12 +46,9 @@ use arch::NumaNodes;
 #[cfg(target_arch = "aarch64")]
 use arch::{DeviceType, MmioDeviceInfo};
 use block::{
-    async_io::DiskFile, block_aio_is_supported, block_io_uring_is_supported, detect_image_type,
-    fixed_vhd_sync::FixedVhdDiskSync, qcow, qcow_sync::QcowDiskSync, raw_async_aio::RawFileDiskAio,
-    raw_sync::RawFileDiskSync, vhdx, vhdx_sync::VhdxDiskSync, ImageType,
+    backend::{open_disk, DiskBackendError, DiskBackendOptions},
+    block_aio_is_supported, block_io_uring_is_supported, qcow,
 };
-#[cfg(feature = "io_uring")]
-use block::{fixed_vhd_async::FixedVhdDiskAsync, raw_async::RawFileDisk};
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
@@ -52,37 +60,42 @@ use devices::legacy::Serial;
 use devices::{
     interrupt_controller, interrupt_controller::InterruptController, AcpiNotificationFlags,
 };
-use hypervisor::{HypervisorType, IoEventAddress};
+use hypervisor::{DataMatch, HypervisorType, IoEventAddress};
 use libc::{
-    cfmakeraw, isatty, tcgetattr, tcsetattr, termios, MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED,
-    O_TMPFILE, PROT_READ, PROT_WRITE, TCSANOW,
+    cfmakeraw, isatty, tcgetattr, tcsetattr, termios, MAP_ANONYMOUS, MAP_NORESERVE, MAP_PRIVATE,
+    MAP_SHARED, O_TMPFILE, PROT_NONE, PROT_READ, PROT_WRITE, TCSANOW,
 };
 use pci::{
-    DeviceRelocation, PciBarRegionType, PciBdf, PciDevice, VfioPciDevice, VfioUserDmaMapping,
-    VfioUserPciDevice, VfioUserPciDeviceError,
+    DeviceRelocation, PciBarRegionType, PciBdf, PciDevice, PciErrorSeverity, PciRootPort,
+    VfioPciDevice, VfioUserDmaMapping, VfioUserPciDevice, VfioUserPciDeviceError,
 };
-use seccompiler::SeccompAction;
+use seccompiler::{apply_filter, SeccompAction};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{read_link, File, OpenOptions};
 use std::io::{self, stdout, Seek, SeekFrom};
 use std::mem::zeroed;
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
 use std::result;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 use tracer::trace_scoped;
 use vfio_ioctls::{VfioContainer, VfioDevice, VfioDeviceFd};
+use virtio_devices::seccomp_filters::{self, set_seccomp_overrides, SeccompOverride};
 use virtio_devices::transport::VirtioTransport;
+use virtio_devices::transport::{VirtioMmioDevice, VirtqueueState, VIRTIO_MMIO_DEVICE_SIZE};
 use virtio_devices::transport::{VirtioPciDevice, VirtioPciDeviceActivator};
 use virtio_devices::vhost_user::VhostUserConfig;
 use virtio_devices::{
-    AccessPlatformMapping, ActivateError, VdpaDmaMapping, VirtioMemMappingSource,
+    AccessPlatformMapping, ActivateError, PciIdsConfig, VdpaDmaMapping, VirtioMemMappingSource,
+    VirtioSharedMemory, VirtioSharedMemoryList, WatchdogCounters, WatchdogEscalation,
 };
-use virtio_devices::{Endpoint, IommuMapping};
+use virtio_devices::{ConsolePort, ConsolePortEndpoint, Endpoint, IommuMapping};
 use vm_allocator::{AddressAllocator, SystemAllocator};
 use vm_device::dma_mapping::vfio::VfioDmaMapping;
 use vm_device::dma_mapping::ExternalDmaMapping;
@@ -106,16 +119,21 @@ use vmm_sys_util::eventfd::EventFd;
 #[cfg(target_arch = "aarch64")]
 const MMIO_LEN: u64 = 0x1000;
 
+#[cfg(target_arch = "x86_64")]
+const DEBUG_CONSOLE_PORT: u64 = 0xe9;
+
 // Singleton devices / devices the user cannot name
 #[cfg(target_arch = "x86_64")]
 const IOAPIC_DEVICE_NAME: &str = "__ioapic";
-const SERIAL_DEVICE_NAME: &str = "__serial";
+#[cfg(target_arch = "x86_64")]
+const INTERRUPT_REMAPPING_DEVICE_NAME: &str = "__dmar";
+pub(crate) const SERIAL_DEVICE_NAME: &str = "__serial";
 #[cfg(target_arch = "aarch64")]
 const GPIO_DEVICE_NAME: &str = "__gpio";
-const RNG_DEVICE_NAME: &str = "__rng";
+pub(crate) const RNG_DEVICE_NAME: &str = "__rng";
 const IOMMU_DEVICE_NAME: &str = "__iommu";
 const BALLOON_DEVICE_NAME: &str = "__balloon";
-const CONSOLE_DEVICE_NAME: &str = "__console";
+pub(crate) const CONSOLE_DEVICE_NAME: &str = "__console";
 const PVPANIC_DEVICE_NAME: &str = "__pvpanic";
 
 // Devices that the user may name and for which we generate
@@ -124,12 +142,15 @@ const DISK_DEVICE_NAME_PREFIX: &str = "_disk";
 const FS_DEVICE_NAME_PREFIX: &str = "_fs";
 const NET_DEVICE_NAME_PREFIX: &str = "_net";
 const PMEM_DEVICE_NAME_PREFIX: &str = "_pmem";
+const IVSHMEM_DEVICE_NAME_PREFIX: &str = "_ivshmem";
 const VDPA_DEVICE_NAME_PREFIX: &str = "_vdpa";
 const VSOCK_DEVICE_NAME_PREFIX: &str = "_vsock";
+const CONSOLE_PORT_NAME_PREFIX: &str = "_console_port";
 const WATCHDOG_DEVICE_NAME: &str = "__watchdog";
 const VFIO_DEVICE_NAME_PREFIX: &str = "_vfio";
 const VFIO_USER_DEVICE_NAME_PREFIX: &str = "_vfio_user";
 const VIRTIO_PCI_DEVICE_NAME_PREFIX: &str = "_virtio-pci";
+const VIRTIO_MMIO_DEVICE_NAME_PREFIX: &str = "_virtio-mmio";
 
 /// Errors associated with device manager
 #[derive(Debug)]
@@ -137,8 +158,8 @@ pub enum DeviceManagerError {
     /// Cannot create EventFd.
     EventFd(io::Error),
 
-    /// Cannot open disk path
-    Disk(io::Error),
+    /// Cannot create the disk
+    Disk(DiskBackendError),
 
     /// Cannot create vhost-user-net device
     CreateVhostUserNet(virtio_devices::vhost_user::Error),
@@ -152,15 +173,48 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
+    /// Missing virtio-console device, can't proceed as expected.
+    MissingVirtioConsole,
+
+    /// Cannot bind the console port socket
+    ConsolePortSocketBind(io::Error),
+
+    /// Failed to plug a port into the virtio-console device
+    AddConsolePort(virtio_devices::ConsolePortError),
+
+    /// Failed to unplug a port from the virtio-console device
+    RemoveConsolePort(virtio_devices::ConsolePortError),
+
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
     /// Cannot create virtio-fs device
     CreateVirtioFs(virtio_devices::vhost_user::Error),
 
+    /// Cannot spawn the daemon of a virtio-fs device
+    SpawnVirtiofsd(virtiofsd::Error),
+
     /// Virtio-fs device was created without a socket.
     NoVirtioFsSock,
 
+    /// Cannot find a memory range for the virtio-fs DAX cache
+    FsRangeAllocation,
+
+    /// Expected resources for virtio-fs DAX cache could not be found.
+    MissingVirtioFsResources,
+
+    /// Missing virtio-fs device, can't proceed as expected.
+    MissingVirtioFs(String),
+
+    /// Failed to resize the virtio-fs DAX cache window
+    VirtioFsResize(virtio_devices::vhost_user::Error),
+
+    /// Failed to update the memory slot backing the virtio-fs DAX cache window
+    UpdateVirtioFsCacheMapping(hypervisor::HypervisorVmError),
+
+    /// Failed to update the shared memory capabilities of a virtio-pci device
+    UpdateShmCapabilities(pci::PciDeviceError),
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(virtio_devices::vhost_user::Error),
 
@@ -176,6 +230,18 @@ pub enum DeviceManagerError {
     /// Cannot create tpm device
     CreateTpmDevice(anyhow::Error),
 
+    /// Cannot create the UEFI variable store
+    #[cfg(target_arch = "x86_64")]
+    CreateUefiVars(io::Error),
+
+    /// UEFI variable store larger than its address range
+    #[cfg(target_arch = "x86_64")]
+    UefiVarsTooLarge(u64),
+
+    /// Cannot enroll the Secure Boot keys in the UEFI variable store
+    #[cfg(target_arch = "x86_64")]
+    ProvisionUefiVars(crate::uefi_vars::Error),
+
     /// Failed to convert Path to &str for the vDPA device.
     CreateVdpaConvertPath,
 
@@ -194,9 +260,6 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-watchdog device
     CreateVirtioWatchdog(io::Error),
 
-    /// Failed to parse disk image format
-    DetectImageType(io::Error),
-
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
@@ -230,6 +293,9 @@ pub enum DeviceManagerError {
     /// Cannot create virtio device
     VirtioDevice(virtio_devices::transport::VirtioPciDeviceError),
 
+    /// Cannot create virtio-mmio device
+    VirtioMmioDevice(virtio_devices::transport::VirtioMmioDeviceError),
+
     /// Cannot add PCI device
     AddPciDevice(pci::PciRootError),
 
@@ -251,6 +317,15 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Error creating debug console output file
+    DebugConsoleOutputFileOpen(io::Error),
+
+    /// Error connecting to the debug console socket
+    DebugConsoleSocketConnect(io::Error),
+
+    /// Error creating the debug console
+    CreateDebugConsole(io::Error),
+
     /// Error creating serial pty
     SerialPtyOpen(io::Error),
 
@@ -269,6 +344,9 @@ pub enum DeviceManagerError {
     /// Cannot create a VFIO PCI device
     VfioPciCreate(pci::VfioPciError),
 
+    /// Cannot map the BARs of a VFIO PCI device for peer-to-peer DMA
+    VfioP2pDmaMap(pci::VfioPciError),
+
     /// Failed to map VFIO MMIO region.
     VfioMapRegion(pci::VfioPciError),
 
@@ -308,6 +386,10 @@ pub enum DeviceManagerError {
     /// Failed to create interrupt controller.
     CreateInterruptController(interrupt_controller::Error),
 
+    /// Failed to restore interrupt controller.
+    #[cfg(target_arch = "aarch64")]
+    RestoreInterruptController(interrupt_controller::Error),
+
     /// Failed to create a new MmapRegion instance.
     NewMmapRegion(vm_memory::mmap::MmapRegionError),
 
@@ -404,6 +486,12 @@ pub enum DeviceManagerError {
     /// Missing virtio-balloon, can't proceed as expected.
     MissingVirtioBalloon,
 
+    /// Seccomp filter overridden for a device not part of the VM
+    UnknownSeccompOverrideDevice(String),
+
+    /// Failed to ask the guest to report its free pages
+    VirtioBalloonReportFreePages(virtio_devices::balloon::Error),
+
     /// Missing virtual IOMMU device
     MissingVirtualIommu,
 
@@ -417,18 +505,6 @@ pub enum DeviceManagerError {
     /// Failed to set O_DIRECT flag to file descriptor
     SetDirectIo,
 
-    /// Failed to create FixedVhdDiskAsync
-    CreateFixedVhdDiskAsync(io::Error),
-
-    /// Failed to create FixedVhdDiskSync
-    CreateFixedVhdDiskSync(io::Error),
-
-    /// Failed to create QcowDiskSync
-    CreateQcowDiskSync(qcow::Error),
-
-    /// Failed to create FixedVhdxDiskSync
-    CreateFixedVhdxDiskSync(vhdx::VhdxError),
-
     /// Failed to add DMA mapping handler to virtio-mem device.
     AddDmaMappingHandlerVirtioMem(virtio_devices::mem::Error),
 
@@ -479,11 +555,65 @@ pub enum DeviceManagerError {
 
     /// Cannot create a PvPanic device
     PvPanicCreate(devices::pvpanic::PvPanicError),
+
+    /// Cannot open the shared memory file of an ivshmem device
+    IvshmemFileOpen(io::Error),
+
+    /// Cannot set the size of the shared memory file of an ivshmem device
+    IvshmemFileSetLen(io::Error),
+
+    /// The shared memory size of an ivshmem device is not a power of 2
+    IvshmemSizeInvalid(u64),
+
+    /// Cannot create an ivshmem device
+    IvshmemCreate(devices::ivshmem::IvshmemError),
+
+    /// The shared memory BAR of an ivshmem device is missing
+    MissingIvshmemBar,
+
+    /// Cannot create the seccomp filter of the doorbell thread of an ivshmem device
+    CreateSeccompFilter(seccompiler::Error),
+
+    /// Cannot spawn the doorbell thread of an ivshmem device
+    SpawnIvshmemThread(io::Error),
+
+    /// Cannot spawn the thread forwarding the errors of a VFIO device
+    SpawnVfioErrorThread(io::Error),
+
+    /// Cannot create a PCI Express root port
+    CreatePciRootPort(pci::PciRootPortError),
+
+    /// Cannot attach a device behind a PCI Express root port
+    AttachPciRootPort(pci::PciRootPortError),
+
+    /// Cannot find the PCI Express root port
+    UnknownPciRootPort(String),
+
+    /// A device is already attached to the PCI Express root port
+    PciRootPortInUse(String),
+
+    /// Devices can't be hot plugged or unplugged behind a PCI Express root port
+    PciRootPortHotplug,
+
+    /// Devices can't be hot unplugged from a PCI expansion bus
+    PciExpansionBusHotplug,
+
+    /// virtio-mmio devices can't be hot plugged or unplugged
+    MmioDeviceHotplug,
+
+    /// The device isn't a virtio device.
+    NotVirtioDevice(String),
+
+    /// Failed to read the state of the virtqueues
+    VirtqueueState(vm_memory::GuestMemoryError),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
 const DEVICE_MANAGER_ACPI_SIZE: usize = 0x10;
 
+// Interval at which the VFIO devices are polled for correctable errors
+const VFIO_CORRECTABLE_ERRORS_POLL_MS: libc::c_int = 1000;
+
 const TIOCSPTLCK: libc::c_int = 0x4004_5431;
 const TIOCGTPEER: libc::c_int = 0x5441;
 
@@ -564,6 +694,41 @@ pub(crate) struct AddressManager {
     pci_mmio_allocators: Vec<Arc<Mutex<AddressAllocator>>>,
 }
 
+impl AddressManager {
+    // Move memory mapped through a BAR, by replacing its user memory region
+    fn move_user_memory_region(
+        &self,
+        mem_slot: u32,
+        old_base: u64,
+        new_base: u64,
+        len: u64,
+        host_addr: u64,
+    ) -> std::result::Result<(), std::io::Error> {
+        let mem_region = self
+            .vm
+            .make_user_memory_region(mem_slot, old_base, len, host_addr, false, false);
+
+        self.vm.remove_user_memory_region(mem_region).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to remove user memory region: {e:?}"),
+            )
+        })?;
+
+        // Create new mapping by inserting new region to KVM.
+        let mem_region = self
+            .vm
+            .make_user_memory_region(mem_slot, new_base, len, host_addr, false, false);
+
+        self.vm.create_user_memory_region(mem_region).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed to create user memory regions: {e:?}"),
+            )
+        })
+    }
+}
+
 impl DeviceRelocation for AddressManager {
     fn move_bar(
         &self,
@@ -723,38 +888,17 @@ impl DeviceRelocation for AddressManager {
                 let mut virtio_dev = virtio_dev.lock().unwrap();
                 if let Some(mut shm_regions) = virtio_dev.get_shm_regions() {
                     if shm_regions.addr.raw_value() == old_base {
-                        let mem_region = self.vm.make_user_memory_region(
-                            shm_regions.mem_slot,
-                            old_base,
-                            shm_regions.len,
-                            shm_regions.host_addr,
-                            false,
-                            false,
-                        );
-
-                        self.vm.remove_user_memory_region(mem_region).map_err(|e| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("failed to remove user memory region: {e:?}"),
-                            )
-                        })?;
-
-                        // Create new mapping by inserting new region to KVM.
-                        let mem_region = self.vm.make_user_memory_region(
-                            shm_regions.mem_slot,
-                            new_base,
-                            shm_regions.len,
-                            shm_regions.host_addr,
-                            false,
-                            false,
-                        );
-
-                        self.vm.create_user_memory_region(mem_region).map_err(|e| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!("failed to create user memory regions: {e:?}"),
-                            )
-                        })?;
+                        // Only the part of the region mapped into the guest
+                        // is backed by a memory slot.
+                        for mapping in virtio_dev.userspace_mappings() {
+                            self.move_user_memory_region(
+                                mapping.mem_slot,
+                                old_base,
+                                new_base,
+                                mapping.len,
+                                mapping.host_addr,
+                            )?;
+                        }
 
                         // Update shared memory regions to reflect the new mapping.
                         shm_regions.addr = GuestAddress(new_base);
@@ -767,10 +911,118 @@ impl DeviceRelocation for AddressManager {
                     }
                 }
             }
+        } else if let Some(ivshmem_dev) = any_dev.downcast_ref::<devices::IvshmemDevice>() {
+            if ivshmem_dev.shm_bar_addr() == Some(old_base) {
+                if let Some(mem_slot) = ivshmem_dev.shm_mem_slot() {
+                    self.move_user_memory_region(
+                        mem_slot,
+                        old_base,
+                        new_base,
+                        ivshmem_dev.shm_size(),
+                        ivshmem_dev.shm_host_addr(),
+                    )?;
+                }
+            }
         }
 
         pci_dev.move_bar(old_base, new_base)
     }
+
+    fn resize_bar(
+        &self,
+        base: u64,
+        old_len: u64,
+        new_len: u64,
+        pci_dev: &mut dyn PciDevice,
+        region_type: PciBarRegionType,
+    ) -> std::result::Result<(), std::io::Error> {
+        // Keep the BAR where it is if there is enough room for its new size,
+        // otherwise find a new location from the same window.
+        let new_base = match region_type {
+            PciBarRegionType::IoRegion => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "I/O BARs can't be resized",
+                ));
+            }
+            PciBarRegionType::Memory32BitRegion => {
+                let mut allocator = self.allocator.lock().unwrap();
+                allocator.free_mmio_hole_addresses(GuestAddress(base), old_len);
+                let new_base = allocator
+                    .allocate_mmio_hole_addresses(Some(GuestAddress(base)), new_len, Some(new_len))
+                    .or_else(|| {
+                        allocator.allocate_mmio_hole_addresses(None, new_len, Some(new_len))
+                    });
+                if new_base.is_none() {
+                    allocator.allocate_mmio_hole_addresses(
+                        Some(GuestAddress(base)),
+                        old_len,
+                        Some(old_len),
+                    );
+                }
+                new_base
+            }
+            PciBarRegionType::Memory64BitRegion => {
+                let allocator = self
+                    .pci_mmio_allocators
+                    .iter()
+                    .find(|a| {
+                        let a = a.lock().unwrap();
+                        base >= a.base().0 && base <= a.end().0
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("no 64 bits MMIO window holds the BAR at 0x{base:x}"),
+                        )
+                    })?;
+                let mut allocator = allocator.lock().unwrap();
+                allocator.free(GuestAddress(base), old_len);
+                let new_base = allocator
+                    .allocate(Some(GuestAddress(base)), new_len, Some(new_len))
+                    .or_else(|| allocator.allocate(None, new_len, Some(new_len)));
+                if new_base.is_none() {
+                    allocator.allocate(Some(GuestAddress(base)), old_len, None);
+                }
+                new_base
+            }
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("failed allocating a MMIO range of 0x{new_len:x} bytes"),
+            )
+        })?
+        .raw_value();
+
+        // Update MMIO bus
+        self.mmio_bus
+            .update_range(base, old_len, new_base, new_len)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // Update the device_tree resources associated with the device
+        if let Some(id) = pci_dev.id() {
+            if let Some(node) = self.device_tree.lock().unwrap().get_mut(&id) {
+                for resource in node.resources.iter_mut() {
+                    if let Resource::PciBar {
+                        base: bar_base,
+                        size,
+                        type_,
+                        ..
+                    } = resource
+                    {
+                        if PciBarRegionType::from(*type_) == region_type && *bar_base == base {
+                            *bar_base = new_base;
+                            *size = new_len;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        pci_dev.resize_bar(base, new_base, new_len)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -801,6 +1053,15 @@ pub enum PciDeviceHandle {
     VfioUser(Arc<Mutex<VfioUserPciDevice>>),
 }
 
+// Path through which the errors of a VFIO device reach the guest
+enum VfioErrorReporting {
+    RootPort(Arc<Mutex<PciRootPort>>),
+    Ghes(
+        Arc<Mutex<devices::GhesDevice>>,
+        Arc<Mutex<devices::AcpiGedDevice>>,
+    ),
+}
+
 #[derive(Clone)]
 struct MetaVirtioDevice {
     virtio_device: Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -808,6 +1069,10 @@ struct MetaVirtioDevice {
     id: String,
     pci_segment: u16,
     dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
+    pci_root_port: Option<String>,
+    pci_ids: Option<PciIdsConfig>,
+    // Exposed through the virtio-mmio transport rather than virtio-pci
+    mmio: bool,
 }
 
 #[derive(Default)]
@@ -849,13 +1114,16 @@ pub struct DeviceManager {
     #[cfg(target_arch = "aarch64")]
     interrupt_controller: Option<Arc<Mutex<gic::Gic>>>,
 
-    // Things to be added to the commandline (e.g. aarch64 early console)
-    #[cfg(target_arch = "aarch64")]
+    // Things to be added to the commandline (e.g. aarch64 early console,
+    // x86_64 virtio-mmio devices)
     cmdline_additions: Vec<String>,
 
     // ACPI GED notification device
     ged_notification_device: Option<Arc<Mutex<devices::AcpiGedDevice>>>,
 
+    // ACPI hardware error source, reporting the errors of the VFIO devices
+    ghes_device: Option<Arc<Mutex<devices::GhesDevice>>>,
+
     // VM configuration
     config: Arc<Mutex<VmConfig>>,
 
@@ -895,6 +1163,10 @@ pub struct DeviceManager {
     // DeviceManager to be reused.
     vfio_container: Option<Arc<VfioContainer>>,
 
+    // Dedicated VFIO containers of the devices taking part in peer-to-peer
+    // DMA, indexed by device id.
+    p2p_vfio_containers: HashMap<String, Arc<VfioContainer>>,
+
     // Paravirtualized IOMMU
     iommu_device: Option<Arc<Mutex<virtio_devices::Iommu>>>,
     iommu_mapping: Option<Arc<IommuMapping>>,
@@ -905,6 +1177,11 @@ pub struct DeviceManager {
     // information for filling the ACPI VIOT table.
     iommu_attached_devices: Option<(PciBdf, Vec<PciBdf>)>,
 
+    // Interrupt remapping unit, remapping the MSIs of the IOAPIC and of the
+    // PCI devices.
+    #[cfg(target_arch = "x86_64")]
+    interrupt_remapping_unit: Option<Arc<InterruptRemappingUnit>>,
+
     // Tree of devices, representing the dependencies between devices.
     // Useful for introspection, snapshot and restore.
     device_tree: Arc<Mutex<DeviceTree>>,
@@ -913,6 +1190,21 @@ pub struct DeviceManager {
     exit_evt: EventFd,
     reset_evt: EventFd,
 
+    // Pause event, used by devices requesting the VM to be paused
+    pause_evt: EventFd,
+
+    // Events of the virtio-watchdog since the VMM started
+    watchdog_counters: Arc<WatchdogCounters>,
+
+    // Guest panic event, signaled by the pvpanic device
+    guest_panic_evt: EventFd,
+
+    // Suspend event, signaled when the guest enters the S3 sleep state
+    suspend_evt: EventFd,
+
+    // Hibernate event, signaled when the guest enters the S4 sleep state
+    hibernate_evt: EventFd,
+
     #[cfg(target_arch = "aarch64")]
     id_to_dev_info: HashMap<(DeviceType, String), MmioDeviceInfo>,
 
@@ -936,6 +1228,19 @@ pub struct DeviceManager {
     // Possible handle to the virtio-mem device
     virtio_mem_devices: Vec<Arc<Mutex<virtio_devices::Mem>>>,
 
+    // Handles to the virtio-fs devices, indexed by their identifier
+    fs_devices: HashMap<String, Arc<Mutex<virtio_devices::vhost_user::Fs>>>,
+
+    // Daemons spawned for the virtio-fs devices sharing a directory, indexed
+    // by the identifier of their device
+    virtiofs_daemons: HashMap<String, VirtiofsDaemon>,
+
+    // Handles to the virtio-mmio devices, indexed by their identifier
+    virtio_mmio_devices: HashMap<String, Arc<Mutex<VirtioMmioDevice>>>,
+
+    // Handle to the virtio-console device, used to plug additional ports
+    console_device: Option<Arc<Mutex<virtio_devices::Console>>>,
+
     #[cfg(target_arch = "aarch64")]
     // GPIO device for AArch64
     gpio_device: Option<Arc<Mutex<devices::legacy::Gpio>>>,
@@ -965,6 +1270,11 @@ pub struct DeviceManager {
     acpi_platform_addresses: AcpiPlatformAddresses,
 
     snapshot: Option<Snapshot>,
+
+    // Whether the PCI devices being added can be attached to the expansion
+    // buses, which is only the case at boot since these buses don't support
+    // hot-plug.
+    pci_expansion: bool,
 }
 
 impl DeviceManager {
@@ -979,6 +1289,11 @@ impl DeviceManager {
         cpu_manager: Arc<Mutex<CpuManager>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        pause_evt: EventFd,
+        watchdog_counters: Arc<WatchdogCounters>,
+        guest_panic_evt: EventFd,
+        suspend_evt: EventFd,
+        hibernate_evt: EventFd,
         seccomp_action: SeccompAction,
         numa_nodes: NumaNodes,
         activate_evt: &EventFd,
@@ -1010,19 +1325,67 @@ impl DeviceManager {
         let start_of_device_area = memory_manager.lock().unwrap().start_of_device_area().0;
         let end_of_device_area = memory_manager.lock().unwrap().end_of_device_area().0;
 
+        // Segments without any configuration span a single bus
+        let pci_segments_config = config.lock().unwrap().pci_segments.clone();
+        let pci_segment_config = |id: u16| {
+            pci_segments_config
+                .iter()
+                .flatten()
+                .find(|c| c.pci_segment == id)
+                .cloned()
+                .unwrap_or(PciSegmentConfig {
+                    pci_segment: id,
+                    ..Default::default()
+                })
+        };
+
+        // The buses of a segment which aren't used by its root ports are
+        // expansion buses.
+        let pci_root_ports = config.lock().unwrap().pci_root_ports.clone();
+        let num_expansion_buses = |id: u16| {
+            let num_root_ports = pci_root_ports
+                .iter()
+                .flatten()
+                .filter(|p| p.pci_segment == id)
+                .count() as u64;
+            (pci_segment_config(id).num_buses() - 1).saturating_sub(num_root_ports)
+        };
+
         // Start each PCI segment range on a 4GiB boundary
         let pci_segment_size = (end_of_device_area - start_of_device_area + 1)
             / ((4 << 30) * num_pci_segments as u64)
             * (4 << 30);
 
+        // Each root bus of a segment gets an equal share of its range, the
+        // first bus getting the first one.
         let mut pci_mmio_allocators = vec![];
+        let mut expansion_allocators = vec![];
         for i in 0..num_pci_segments as u64 {
             let mmio_start = start_of_device_area + i * pci_segment_size;
+            let num_root_buses = num_expansion_buses(i as u16) + 1;
+            let bus_size = pci_segment_size / ((1 << 20) * num_root_buses) * (1 << 20);
             let allocator = Arc::new(Mutex::new(
-                AddressAllocator::new(GuestAddress(mmio_start), pci_segment_size).unwrap(),
+                AddressAllocator::new(GuestAddress(mmio_start), bus_size).unwrap(),
             ));
-            pci_mmio_allocators.push(allocator)
+            pci_mmio_allocators.push(allocator);
+
+            expansion_allocators.push(
+                (1..num_root_buses)
+                    .map(|bus| {
+                        Arc::new(Mutex::new(
+                            AddressAllocator::new(
+                                GuestAddress(mmio_start + bus * bus_size),
+                                bus_size,
+                            )
+                            .unwrap(),
+                        ))
+                    })
+                    .collect::<Vec<_>>(),
+            );
         }
+        // The expansion buses follow the segments, as these are looked up
+        // by index.
+        pci_mmio_allocators.extend(expansion_allocators.iter().flatten().cloned());
 
         let address_manager = Arc::new(AddressManager {
             allocator: memory_manager.lock().unwrap().allocator(),
@@ -1060,19 +1423,28 @@ impl DeviceManager {
         )?;
 
         let mut pci_segments = vec![PciSegment::new_default_segment(
+            &pci_segment_config(0),
             &address_manager,
             Arc::clone(&address_manager.pci_mmio_allocators[0]),
+            &expansion_allocators[0],
             &pci_irq_slots,
         )?];
 
+        // The configuration spaces of the segments follow each other
+        let mut mmio_config_address =
+            layout::PCI_MMCONFIG_START.0 + pci_segments[0].mmio_config_size();
         for i in 1..num_pci_segments as usize {
-            pci_segments.push(PciSegment::new(
-                i as u16,
+            let segment = PciSegment::new(
+                &pci_segment_config(i as u16),
                 numa_node_id_from_pci_segment_id(&numa_nodes, i as u16),
+                mmio_config_address,
                 &address_manager,
                 Arc::clone(&address_manager.pci_mmio_allocators[i]),
+                &expansion_allocators[i],
                 &pci_irq_slots,
-            )?);
+            )?;
+            mmio_config_address += segment.mmio_config_size();
+            pci_segments.push(segment);
         }
 
         if dynamic {
@@ -1100,9 +1472,9 @@ impl DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
             interrupt_controller: None,
-            #[cfg(target_arch = "aarch64")]
             cmdline_additions: Vec::new(),
             ged_notification_device: None,
+            ghes_device: None,
             config,
             memory_manager,
             cpu_manager,
@@ -1113,13 +1485,21 @@ impl DeviceManager {
             legacy_interrupt_manager: None,
             passthrough_device: None,
             vfio_container: None,
+            p2p_vfio_containers: HashMap::new(),
             iommu_device: None,
             iommu_mapping: None,
             iommu_attached_devices: None,
+            #[cfg(target_arch = "x86_64")]
+            interrupt_remapping_unit: None,
             pci_segments,
             device_tree,
             exit_evt,
             reset_evt,
+            pause_evt,
+            watchdog_counters,
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
             seccomp_action,
@@ -1136,6 +1516,10 @@ impl DeviceManager {
             console_resize_pipe: None,
             original_termios_opt: Arc::new(Mutex::new(None)),
             virtio_mem_devices: Vec::new(),
+            fs_devices: HashMap::new(),
+            virtiofs_daemons: HashMap::new(),
+            virtio_mmio_devices: HashMap::new(),
+            console_device: None,
             #[cfg(target_arch = "aarch64")]
             gpio_device: None,
             pvpanic_device: None,
@@ -1147,6 +1531,7 @@ impl DeviceManager {
             pending_activations: Arc::new(Mutex::new(Vec::default())),
             acpi_platform_addresses: AcpiPlatformAddresses::default(),
             snapshot,
+            pci_expansion: false,
         };
 
         let device_manager = Arc::new(Mutex::new(device_manager));
@@ -1190,6 +1575,20 @@ impl DeviceManager {
 
         let mut virtio_devices: Vec<MetaVirtioDevice> = Vec::new();
 
+        // The interrupt remapping unit comes first, remapping the MSIs of the
+        // IOAPIC.
+        #[cfg(target_arch = "x86_64")]
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map_or(false, |p| p.interrupt_remapping)
+        {
+            self.add_interrupt_remapping_unit()?;
+        }
+
         let interrupt_controller = self.add_interrupt_controller()?;
 
         self.cpu_manager
@@ -1250,11 +1649,18 @@ impl DeviceManager {
             console_resize_pipe,
         )?;
 
+        self.add_debug_console_device()?;
+
         if let Some(tpm) = self.config.clone().lock().unwrap().tpm.as_ref() {
             let tpm_dev = self.add_tpm_device(tpm.socket.clone())?;
             self.bus_devices
                 .push(Arc::clone(&tpm_dev) as Arc<Mutex<dyn BusDevice>>)
         }
+
+        #[cfg(target_arch = "x86_64")]
+        if let Some(uefi_vars) = self.config.clone().lock().unwrap().uefi_vars.as_ref() {
+            self.add_uefi_vars_device(uefi_vars)?;
+        }
         self.legacy_interrupt_manager = Some(legacy_interrupt_manager);
 
         virtio_devices.append(&mut self.make_virtio_devices()?);
@@ -1267,6 +1673,45 @@ impl DeviceManager {
             self.pvpanic_device = self.add_pvpanic_device()?;
         }
 
+        self.set_seccomp_overrides()?;
+
+        Ok(())
+    }
+
+    // Override the seccomp filters of the threads of the virtio devices, once
+    // all the devices the overrides refer to have been created.
+    fn set_seccomp_overrides(&self) -> DeviceManagerResult<()> {
+        let mut overrides = BTreeMap::new();
+        let syscalls = |names: &[String]| -> Vec<i64> {
+            names
+                .iter()
+                .filter_map(|name| seccomp_filters::syscall_number(name))
+                .collect()
+        };
+
+        for config in self
+            .config
+            .lock()
+            .unwrap()
+            .seccomp_overrides
+            .iter()
+            .flatten()
+        {
+            if !self.device_tree.lock().unwrap().contains_key(&config.id) {
+                return Err(DeviceManagerError::UnknownSeccompOverrideDevice(
+                    config.id.clone(),
+                ));
+            }
+            overrides.insert(
+                config.id.clone(),
+                SeccompOverride {
+                    allow: syscalls(&config.allow),
+                    deny: syscalls(&config.deny),
+                },
+            );
+        }
+
+        set_seccomp_overrides(overrides);
         Ok(())
     }
 
@@ -1302,6 +1747,9 @@ impl DeviceManager {
         &mut self,
         virtio_devices: Vec<MetaVirtioDevice>,
     ) -> DeviceManagerResult<()> {
+        self.add_pci_root_ports()?;
+        self.pci_expansion = true;
+
         let iommu_id = String::from(IOMMU_DEVICE_NAME);
 
         let iommu_device = if self.config.lock().unwrap().iommu {
@@ -1336,6 +1784,11 @@ impl DeviceManager {
         let mut iommu_attached_devices = Vec::new();
         {
             for handle in virtio_devices {
+                if handle.mmio {
+                    self.add_virtio_mmio_device(handle.virtio_device, handle.id)?;
+                    continue;
+                }
+
                 let mapping: Option<Arc<IommuMapping>> = if handle.iommu {
                     self.iommu_mapping.clone()
                 } else {
@@ -1348,6 +1801,8 @@ impl DeviceManager {
                     handle.id,
                     handle.pci_segment,
                     handle.dma_handler,
+                    handle.pci_root_port.as_deref(),
+                    handle.pci_ids,
                 )?;
 
                 if handle.iommu {
@@ -1361,14 +1816,25 @@ impl DeviceManager {
             let mut vfio_user_iommu_device_ids = self.add_user_devices()?;
             iommu_attached_devices.append(&mut vfio_user_iommu_device_ids);
 
+            self.add_ivshmem_devices()?;
+
             // Add all devices from forced iommu segments
             if let Some(platform_config) = self.config.lock().unwrap().platform.as_ref() {
                 if let Some(iommu_segments) = platform_config.iommu_segments.as_ref() {
                     for segment in iommu_segments {
-                        for device in 0..32 {
-                            let bdf = PciBdf::new(*segment, 0, device, 0);
-                            if !iommu_attached_devices.contains(&bdf) {
-                                iommu_attached_devices.push(bdf);
+                        let pci_segment = &self.pci_segments[*segment as usize];
+                        let buses = std::iter::once(pci_segment.start_bus).chain(
+                            pci_segment
+                                .expansion_buses
+                                .iter()
+                                .map(|expansion_bus| expansion_bus.bus),
+                        );
+                        for bus in buses {
+                            for device in 0..32 {
+                                let bdf = PciBdf::new(*segment, bus, device, 0);
+                                if !iommu_attached_devices.contains(&bdf) {
+                                    iommu_attached_devices.push(bdf);
+                                }
                             }
                         }
                     }
@@ -1376,10 +1842,12 @@ impl DeviceManager {
             }
 
             if let Some(iommu_device) = iommu_device {
-                let dev_id = self.add_virtio_pci_device(iommu_device, &None, iommu_id, 0, None)?;
+                let dev_id =
+                    self.add_virtio_pci_device(iommu_device, &None, iommu_id, 0, None, None, None)?;
                 self.iommu_attached_devices = Some((dev_id, iommu_attached_devices));
             }
         }
+        self.pci_expansion = false;
 
         for segment in &self.pci_segments {
             #[cfg(target_arch = "x86_64")]
@@ -1432,7 +1900,7 @@ impl DeviceManager {
                 .lock()
                 .unwrap()
                 .restore_vgic(vgic_state, &saved_vcpu_states)
-                .unwrap();
+                .map_err(DeviceManagerError::RestoreInterruptController)?;
         }
 
         self.device_tree
@@ -1454,12 +1922,24 @@ impl DeviceManager {
     ) -> DeviceManagerResult<Arc<Mutex<dyn InterruptController>>> {
         let id = String::from(IOAPIC_DEVICE_NAME);
 
+        // The messages of the IOAPIC are remapped as the ones of the PCI
+        // devices, from their own source.
+        let msi_interrupt_manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> =
+            match &self.interrupt_remapping_unit {
+                Some(unit) => Arc::new(RemappedMsiInterruptManager::new(
+                    self.msi_interrupt_manager.clone(),
+                    IOAPIC_SOURCE_ID.into(),
+                    unit.clone(),
+                )),
+                None => self.msi_interrupt_manager.clone(),
+            };
+
         // Create IOAPIC
         let interrupt_controller = Arc::new(Mutex::new(
             ioapic::Ioapic::new(
                 id.clone(),
                 APIC_START,
-                Arc::clone(&self.msi_interrupt_manager),
+                msi_interrupt_manager,
                 versioned_state_from_id(self.snapshot.as_ref(), id.as_str())
                     .map_err(DeviceManagerError::RestoreGetState)?,
             )
@@ -1487,6 +1967,77 @@ impl DeviceManager {
         Ok(interrupt_controller)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn add_interrupt_remapping_unit(&mut self) -> DeviceManagerResult<()> {
+        let id = String::from(INTERRUPT_REMAPPING_DEVICE_NAME);
+
+        let unit = Arc::new(InterruptRemappingUnit::new(
+            self.memory_manager.lock().unwrap().guest_memory(),
+            versioned_state_from_id(self.snapshot.as_ref(), id.as_str())
+                .map_err(DeviceManagerError::RestoreGetState)?,
+        ));
+        let device = Arc::new(Mutex::new(InterruptRemappingDevice::new(
+            id.clone(),
+            unit.clone(),
+        )));
+
+        self.address_manager
+            .mmio_bus
+            .insert(
+                device.clone(),
+                layout::INTERRUPT_REMAPPING_START.0,
+                INTERRUPT_REMAPPING_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+
+        self.bus_devices
+            .push(Arc::clone(&device) as Arc<Mutex<dyn BusDevice>>);
+
+        self.device_tree
+            .lock()
+            .unwrap()
+            .insert(id.clone(), device_node!(id, device));
+
+        self.interrupt_remapping_unit = Some(unit);
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn interrupt_remapping_address(&self) -> Option<GuestAddress> {
+        self.interrupt_remapping_unit
+            .as_ref()
+            .map(|_| layout::INTERRUPT_REMAPPING_START)
+    }
+
+    // MSI interrupt manager of a PCI device, whose messages are remapped by
+    // the virtual IOMMU if the device is attached to it, then by the
+    // interrupt remapping unit if there's one.
+    fn pci_msi_interrupt_manager(
+        &self,
+        pci_device_bdf: PciBdf,
+        iommu_mapping: Option<&Arc<IommuMapping>>,
+    ) -> Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>> {
+        let mut manager = self.msi_interrupt_manager.clone();
+        #[cfg(target_arch = "x86_64")]
+        if let Some(unit) = &self.interrupt_remapping_unit {
+            manager = Arc::new(RemappedMsiInterruptManager::new(
+                manager,
+                pci_device_bdf.into(),
+                unit.clone(),
+            ));
+        }
+        if let Some(mapping) = iommu_mapping {
+            manager = Arc::new(RemappedMsiInterruptManager::new(
+                manager,
+                pci_device_bdf.into(),
+                mapping.clone(),
+            ));
+        }
+
+        manager
+    }
+
     fn add_acpi_devices(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
@@ -1502,6 +2053,12 @@ impl DeviceManager {
         let shutdown_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
             exit_evt,
             reset_evt,
+            self.suspend_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            self.hibernate_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
             vcpus_kill_signalled,
         )));
 
@@ -1571,6 +2128,31 @@ impl DeviceManager {
         self.bus_devices
             .push(Arc::clone(&ged_device) as Arc<Mutex<dyn BusDevice>>);
 
+        // The address is allocated even when the device isn't created, for
+        // the platform MMIO layout to not depend on the configuration.
+        let ghes_address = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_platform_mmio_addresses(None, devices::GHES_DEVICE_SIZE, None)
+            .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+        // The errors are only reported in firmware first mode for the
+        // passthrough devices which aren't behind a root port, the error
+        // source is exposed when some are present at boot.
+        let passthrough_devices = self
+            .config
+            .lock()
+            .unwrap()
+            .devices
+            .as_ref()
+            .map_or(false, |devices| {
+                devices.iter().any(|d| d.pci_root_port.is_none())
+            });
+        if passthrough_devices {
+            self.add_ghes_device(ghes_address, &ged_device)?;
+        }
+
         let pm_timer_device = Arc::new(Mutex::new(devices::AcpiPmTimerDevice::new()));
 
         self.bus_devices
@@ -1599,6 +2181,34 @@ impl DeviceManager {
         Ok(Some(ged_device))
     }
 
+    fn add_ghes_device(
+        &mut self,
+        ghes_address: GuestAddress,
+        ged_device: &Arc<Mutex<devices::AcpiGedDevice>>,
+    ) -> DeviceManagerResult<()> {
+        let ghes_device = Arc::new(Mutex::new(devices::GhesDevice::new(ghes_address)));
+        self.address_manager
+            .mmio_bus
+            .insert(
+                ghes_device.clone(),
+                ghes_address.0,
+                devices::GHES_DEVICE_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+        self.bus_devices
+            .push(Arc::clone(&ghes_device) as Arc<Mutex<dyn BusDevice>>);
+        self.ghes_device = Some(ghes_device);
+
+        // The guest is notified about the errors through the Hardware Error
+        // Device.
+        ged_device
+            .lock()
+            .unwrap()
+            .enable_hardware_error_notification();
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn add_legacy_devices(&mut self, reset_evt: EventFd) -> DeviceManagerResult<()> {
         let vcpus_kill_signalled = self
@@ -2003,7 +2613,7 @@ impl DeviceManager {
                     Endpoint::File(stdout)
                 }
             }
-            ConsoleOutputMode::Socket => {
+            ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp => {
                 return Err(DeviceManagerError::NoSocketOptionSupportForConsoleDevice);
             }
             ConsoleOutputMode::Null => Endpoint::Null,
@@ -2018,6 +2628,7 @@ impl DeviceManager {
                 .as_ref()
                 .map(|p| p.try_clone().unwrap()),
             self.force_iommu | console_config.iommu,
+            console_config.max_ports,
             self.seccomp_action.clone(),
             self.exit_evt
                 .try_clone()
@@ -2034,6 +2645,9 @@ impl DeviceManager {
             id: id.clone(),
             pci_segment: 0,
             dma_handler: None,
+            pci_root_port: None,
+            pci_ids: None,
+            mmio: false,
         });
 
         // Fill the device tree with a new node. In case of restore, we
@@ -2043,8 +2657,17 @@ impl DeviceManager {
             .lock()
             .unwrap()
             .insert(id.clone(), device_node!(id, virtio_console_device));
+        self.console_device = Some(virtio_console_device);
 
-        // Only provide a resizer (for SIGWINCH handling) if the console is attached to the TTY
+        let console_ports = self.config.lock().unwrap().console_ports.clone();
+        if let Some(mut console_ports) = console_ports {
+            for port_cfg in console_ports.iter_mut() {
+                self.plug_console_port(port_cfg)?;
+            }
+            self.config.lock().unwrap().console_ports = Some(console_ports);
+        }
+
+        // Only provide a resizer (for SIGWINCH handling) if the console is attached to the TTY
         Ok(if matches!(console_config.mode, ConsoleOutputMode::Tty) {
             Some(console_resizer)
         } else {
@@ -2052,6 +2675,57 @@ impl DeviceManager {
         })
     }
 
+    fn plug_console_port(&mut self, port_cfg: &mut ConsolePortConfig) -> DeviceManagerResult<u32> {
+        let console_device = self
+            .console_device
+            .clone()
+            .ok_or(DeviceManagerError::MissingVirtioConsole)?;
+
+        if port_cfg.id.is_none() {
+            port_cfg.id = Some(self.next_device_name(CONSOLE_PORT_NAME_PREFIX)?);
+        }
+
+        if port_cfg.socket.is_none() {
+            let socket_dir = self.config.lock().unwrap().console.socket_dir.clone();
+            if let Some(socket_dir) = socket_dir {
+                port_cfg.socket = port_cfg.socket_name().map(|name| socket_dir.join(name));
+            }
+        }
+
+        let endpoint = if let Some(socket) = port_cfg.socket.as_ref() {
+            ConsolePortEndpoint::Socket(
+                UnixListener::bind(socket).map_err(DeviceManagerError::ConsolePortSocketBind)?,
+            )
+        } else {
+            let (main, sub, path) = create_pty().map_err(DeviceManagerError::ConsolePtyOpen)?;
+            self.set_raw_mode(&sub)
+                .map_err(DeviceManagerError::SetPtyRaw)?;
+            port_cfg.file = Some(path);
+            ConsolePortEndpoint::Pty(main, sub)
+        };
+
+        let port = console_device
+            .lock()
+            .unwrap()
+            .add_port(ConsolePort {
+                id: port_cfg.id.clone().unwrap(),
+                name: port_cfg.name.clone(),
+                endpoint,
+            })
+            .map_err(DeviceManagerError::AddConsolePort)?;
+
+        event!(
+            "vm",
+            "console-port-added",
+            "id",
+            port_cfg.id.as_ref().unwrap(),
+            "port",
+            port.to_string()
+        );
+
+        Ok(port)
+    }
+
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
@@ -2085,17 +2759,25 @@ impl DeviceManager {
                 let _ = self.set_raw_mode(&out);
                 Some(Box::new(out))
             }
-            ConsoleOutputMode::Off | ConsoleOutputMode::Null | ConsoleOutputMode::Socket => None,
+            ConsoleOutputMode::Off
+            | ConsoleOutputMode::Null
+            | ConsoleOutputMode::Socket
+            | ConsoleOutputMode::Tcp => None,
         };
         if serial_config.mode != ConsoleOutputMode::Off {
             let serial = self.add_serial_device(interrupt_manager, serial_writer)?;
             self.serial_manager = match serial_config.mode {
-                ConsoleOutputMode::Pty | ConsoleOutputMode::Tty | ConsoleOutputMode::Socket => {
+                ConsoleOutputMode::Pty
+                | ConsoleOutputMode::Tty
+                | ConsoleOutputMode::Socket
+                | ConsoleOutputMode::Tcp => {
                     let serial_manager = SerialManager::new(
                         serial,
                         self.serial_pty.clone(),
                         serial_config.mode,
                         serial_config.socket,
+                        serial_config.tcp,
+                        serial_config.telnet,
                     )
                     .map_err(DeviceManagerError::CreateSerialManager)?;
                     if let Some(mut serial_manager) = serial_manager {
@@ -2121,6 +2803,63 @@ impl DeviceManager {
         Ok(Arc::new(Console { console_resizer }))
     }
 
+    fn add_debug_console_device(&mut self) -> DeviceManagerResult<()> {
+        let debug_console_config = match self.config.lock().unwrap().debug_console.clone() {
+            Some(debug_console_config) => debug_console_config,
+            None => return Ok(()),
+        };
+        let out: Box<dyn io::Write + Send> = match debug_console_config.mode {
+            ConsoleOutputMode::File => Box::new(
+                File::create(debug_console_config.file.as_ref().unwrap())
+                    .map_err(DeviceManagerError::DebugConsoleOutputFileOpen)?,
+            ),
+            ConsoleOutputMode::Socket => Box::new(
+                UnixStream::connect(debug_console_config.socket.as_ref().unwrap())
+                    .map_err(DeviceManagerError::DebugConsoleSocketConnect)?,
+            ),
+            ConsoleOutputMode::Tty => Box::new(stdout()),
+            ConsoleOutputMode::Off
+            | ConsoleOutputMode::Pty
+            | ConsoleOutputMode::Tcp
+            | ConsoleOutputMode::Null => return Ok(()),
+        };
+
+        let debug_console = Arc::new(Mutex::new(
+            devices::legacy::DebugConsole::new(out)
+                .map_err(DeviceManagerError::CreateDebugConsole)?,
+        ));
+        self.bus_devices
+            .push(Arc::clone(&debug_console) as Arc<Mutex<dyn BusDevice>>);
+
+        #[cfg(target_arch = "x86_64")]
+        self.address_manager
+            .io_bus
+            .insert(debug_console, DEBUG_CONSOLE_PORT, 0x1)
+            .map_err(DeviceManagerError::BusError)?;
+
+        #[cfg(target_arch = "aarch64")]
+        self.address_manager
+            .mmio_bus
+            .insert(
+                debug_console,
+                arch::layout::LEGACY_DEBUG_CONSOLE_MAPPED_IO_START.0,
+                MMIO_LEN,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+
+        #[cfg(target_arch = "aarch64")]
+        self.id_to_dev_info.insert(
+            (DeviceType::DebugConsole, "debug_console".to_string()),
+            MmioDeviceInfo {
+                addr: arch::layout::LEGACY_DEBUG_CONSOLE_MAPPED_IO_START.0,
+                len: MMIO_LEN,
+                irq: 0,
+            },
+        );
+
+        Ok(())
+    }
+
     fn add_tpm_device(
         &mut self,
         tpm_path: PathBuf,
@@ -2144,6 +2883,55 @@ impl DeviceManager {
         Ok(tpm)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn add_uefi_vars_device(&mut self, config: &UefiVarsConfig) -> DeviceManagerResult<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&config.path)
+            .map_err(DeviceManagerError::CreateUefiVars)?;
+
+        // The Secure Boot keys are only enrolled in a new store, the firmware
+        // managing them afterwards.
+        let size = file
+            .metadata()
+            .map_err(DeviceManagerError::CreateUefiVars)?
+            .len();
+        if uefi_vars::has_keys(config) {
+            if size == 0 {
+                info!(
+                    "Enrolling the Secure Boot keys in {}",
+                    config.path.display()
+                );
+                uefi_vars::create_var_store(&file, config)
+                    .map_err(DeviceManagerError::ProvisionUefiVars)?;
+            } else {
+                warn!(
+                    "Not enrolling the Secure Boot keys in the existing {}",
+                    config.path.display()
+                );
+            }
+        }
+
+        let pflash = devices::legacy::Pflash::new(file, uefi_vars::VAR_STORE_SIZE)
+            .map_err(DeviceManagerError::CreateUefiVars)?;
+        let size = pflash.size();
+        if size > arch::layout::UEFI_VARS_SIZE {
+            return Err(DeviceManagerError::UefiVarsTooLarge(size));
+        }
+
+        let pflash = Arc::new(Mutex::new(pflash));
+        self.bus_devices
+            .push(Arc::clone(&pflash) as Arc<Mutex<dyn BusDevice>>);
+        self.address_manager
+            .mmio_bus
+            .insert(pflash, arch::layout::UEFI_VARS_START.0, size)
+            .map_err(DeviceManagerError::BusError)?;
+
+        Ok(())
+    }
+
     fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices: Vec<MetaVirtioDevice> = Vec::new();
 
@@ -2246,90 +3034,25 @@ impl DeviceManager {
                 vhost_user_block as Arc<Mutex<dyn Migratable>>,
             )
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let mut file: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-            let image_type =
-                detect_image_type(&mut file).map_err(DeviceManagerError::DetectImageType)?;
-
-            let image = match image_type {
-                ImageType::FixedVhd => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous fixed VHD disk file (io_uring)");
-
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(
-                                FixedVhdDiskAsync::new(file)
-                                    .map_err(DeviceManagerError::CreateFixedVhdDiskAsync)?,
-                            ) as Box<dyn DiskFile>
-                        }
-                    } else {
-                        info!("Using synchronous fixed VHD disk file");
-                        Box::new(
-                            FixedVhdDiskSync::new(file)
-                                .map_err(DeviceManagerError::CreateFixedVhdDiskSync)?,
-                        ) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Raw => {
-                    // Use asynchronous backend relying on io_uring if the
-                    // syscalls are supported.
-                    if cfg!(feature = "io_uring")
-                        && !disk_cfg.disable_io_uring
-                        && self.io_uring_is_supported()
-                    {
-                        info!("Using asynchronous RAW disk file (io_uring)");
-
-                        #[cfg(not(feature = "io_uring"))]
-                        unreachable!("Checked in if statement above");
-                        #[cfg(feature = "io_uring")]
-                        {
-                            Box::new(RawFileDisk::new(file)) as Box<dyn DiskFile>
-                        }
-                    } else if !disk_cfg.disable_aio && self.aio_is_supported() {
-                        info!("Using asynchronous RAW disk file (aio)");
-                        Box::new(RawFileDiskAio::new(file)) as Box<dyn DiskFile>
-                    } else {
-                        info!("Using synchronous RAW disk file");
-                        Box::new(RawFileDiskSync::new(file)) as Box<dyn DiskFile>
-                    }
-                }
-                ImageType::Qcow2 => {
-                    info!("Using synchronous QCOW disk file");
-                    Box::new(
-                        QcowDiskSync::new(file, disk_cfg.direct)
-                            .map_err(DeviceManagerError::CreateQcowDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
-                ImageType::Vhdx => {
-                    info!("Using synchronous VHDX disk file");
-                    Box::new(
-                        VhdxDiskSync::new(file)
-                            .map_err(DeviceManagerError::CreateFixedVhdxDiskSync)?,
-                    ) as Box<dyn DiskFile>
-                }
+            let options = DiskBackendOptions {
+                readonly: disk_cfg.readonly,
+                direct: disk_cfg.direct,
+                // Use asynchronous backend relying on io_uring if the
+                // syscalls are supported.
+                io_uring: cfg!(feature = "io_uring")
+                    && !disk_cfg.disable_io_uring
+                    && self.io_uring_is_supported(),
+                aio: !disk_cfg.disable_aio && self.aio_is_supported(),
             };
+            let image = open_disk(
+                disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?,
+                disk_cfg.backend.as_deref(),
+                &options,
+            )
+            .map_err(DeviceManagerError::Disk)?;
 
             let virtio_block = Arc::new(Mutex::new(
                 virtio_devices::Block::new(
@@ -2347,6 +3070,8 @@ impl DeviceManager {
                     disk_cfg.serial.clone(),
                     self.seccomp_action.clone(),
                     disk_cfg.rate_limiter_config,
+                    disk_cfg.io_priority.as_ref().map(IoPriorityConfig::ioprio),
+                    disk_cfg.notification.unwrap_or_default(),
                     self.exit_evt
                         .try_clone()
                         .map_err(DeviceManagerError::EventFd)?,
@@ -2357,6 +3082,10 @@ impl DeviceManager {
                 )
                 .map_err(DeviceManagerError::CreateVirtioBlock)?,
             ));
+            virtio_block
+                .lock()
+                .unwrap()
+                .set_event_loop(disk_cfg.event_loop);
 
             (
                 Arc::clone(&virtio_block) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2378,6 +3107,9 @@ impl DeviceManager {
             id,
             pci_segment: disk_cfg.pci_segment,
             dma_handler: None,
+            pci_root_port: disk_cfg.pci_root_port.clone(),
+            pci_ids: disk_cfg.pci_ids,
+            mmio: disk_cfg.mmio,
         })
     }
 
@@ -2458,7 +3190,27 @@ impl DeviceManager {
                 .transpose()
                 .map_err(DeviceManagerError::RestoreGetState)?;
 
-            let virtio_net = if let Some(ref tap_if_name) = net_cfg.tap {
+            let virtio_net = if let Some(ref xdp_if_name) = net_cfg.xdp {
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_xdp(
+                        id.clone(),
+                        xdp_if_name,
+                        net_cfg.xdp_zero_copy,
+                        Some(net_cfg.mac),
+                        self.force_iommu | net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        self.seccomp_action.clone(),
+                        net_cfg.rate_limiter_config,
+                        self.exit_evt
+                            .try_clone()
+                            .map_err(DeviceManagerError::EventFd)?,
+                        state,
+                        net_cfg.notification.unwrap_or_default(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
@@ -2480,6 +3232,7 @@ impl DeviceManager {
                         net_cfg.offload_tso,
                         net_cfg.offload_ufo,
                         net_cfg.offload_csum,
+                        net_cfg.notification.unwrap_or_default(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
@@ -2500,6 +3253,7 @@ impl DeviceManager {
                     net_cfg.offload_tso,
                     net_cfg.offload_ufo,
                     net_cfg.offload_csum,
+                    net_cfg.notification.unwrap_or_default(),
                 )
                 .map_err(DeviceManagerError::CreateVirtioNet)?;
 
@@ -2531,10 +3285,15 @@ impl DeviceManager {
                         net_cfg.offload_tso,
                         net_cfg.offload_ufo,
                         net_cfg.offload_csum,
+                        net_cfg.notification.unwrap_or_default(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             };
+            virtio_net
+                .lock()
+                .unwrap()
+                .set_event_loop(net_cfg.event_loop);
 
             (
                 Arc::clone(&virtio_net) as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2556,6 +3315,9 @@ impl DeviceManager {
             id,
             pci_segment: net_cfg.pci_segment,
             dma_handler: None,
+            pci_root_port: net_cfg.pci_root_port.clone(),
+            pci_ids: net_cfg.pci_ids,
+            mmio: net_cfg.mmio,
         })
     }
 
@@ -2578,6 +3340,9 @@ impl DeviceManager {
 
         // Add virtio-rng if required
         let rng_config = self.config.lock().unwrap().rng.clone();
+        if rng_config.removed {
+            return Ok(devices);
+        }
         if let Some(rng_path) = rng_config.src.to_str() {
             info!("Creating virtio-rng device: {:?}", rng_config);
             let id = String::from(RNG_DEVICE_NAME);
@@ -2593,6 +3358,7 @@ impl DeviceManager {
                         .map_err(DeviceManagerError::EventFd)?,
                     versioned_state_from_id(self.snapshot.as_ref(), id.as_str())
                         .map_err(DeviceManagerError::RestoreGetState)?,
+                    rng_config.rate_limiter_config,
                 )
                 .map_err(DeviceManagerError::CreateVirtioRng)?,
             ));
@@ -2603,6 +3369,9 @@ impl DeviceManager {
                 id: id.clone(),
                 pci_segment: 0,
                 dma_handler: None,
+                pci_root_port: None,
+                pci_ids: None,
+                mmio: rng_config.mmio,
             });
 
             // Fill the device tree with a new node. In case of restore, we
@@ -2634,6 +3403,118 @@ impl DeviceManager {
         let mut node = device_node!(id);
 
         if let Some(fs_socket) = fs_cfg.socket.to_str() {
+            // The daemon must be listening before the device connects to it.
+            let daemon =
+                VirtiofsDaemon::spawn(fs_cfg).map_err(DeviceManagerError::SpawnVirtiofsd)?;
+
+            let cache = if fs_cfg.dax {
+                // Look for the id in the device tree. If it can be found, that
+                // means the device is being restored, otherwise it's created
+                // from scratch.
+                let cache_range = if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
+                    info!("Restoring virtio-fs {} resources", id);
+
+                    let mut cache_range: Option<(u64, u64)> = None;
+                    for resource in node.resources.iter() {
+                        match resource {
+                            Resource::MmioAddressRange { base, size } => {
+                                if cache_range.is_some() {
+                                    return Err(DeviceManagerError::ResourceAlreadyExists);
+                                }
+
+                                cache_range = Some((*base, *size));
+                            }
+                            _ => {
+                                error!("Unexpected resource {:?} for {}", resource, id);
+                            }
+                        }
+                    }
+
+                    Some(cache_range.ok_or(DeviceManagerError::MissingVirtioFsResources)?)
+                } else {
+                    None
+                };
+
+                // The memory needs to be 2MiB aligned in order to support
+                // hugepages.
+                let (cache_base, cache_size) = if let Some((base, size)) = cache_range {
+                    self.pci_segments[fs_cfg.pci_segment as usize]
+                        .allocator
+                        .lock()
+                        .unwrap()
+                        .allocate(
+                            Some(GuestAddress(base)),
+                            size as GuestUsize,
+                            Some(0x0020_0000),
+                        )
+                        .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                    (base, size)
+                } else {
+                    let size = fs_cfg.cache_size;
+                    let base = self.pci_segments[fs_cfg.pci_segment as usize]
+                        .allocator
+                        .lock()
+                        .unwrap()
+                        .allocate(None, size as GuestUsize, Some(0x0020_0000))
+                        .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+                    (base.raw_value(), size)
+                };
+
+                node.resources.push(Resource::MmioAddressRange {
+                    base: cache_base,
+                    size: cache_size,
+                });
+
+                // The whole window is reserved but left inaccessible until
+                // the backend maps some file content into it.
+                let mmap_region = MmapRegion::build(
+                    None,
+                    cache_size as usize,
+                    PROT_NONE,
+                    MAP_ANONYMOUS | MAP_PRIVATE | MAP_NORESERVE,
+                )
+                .map_err(DeviceManagerError::NewMmapRegion)?;
+                let host_addr: u64 = mmap_region.as_ptr() as u64;
+
+                let mem_slot = self
+                    .memory_manager
+                    .lock()
+                    .unwrap()
+                    .create_userspace_mapping(
+                        cache_base, cache_size, host_addr, false, false, false,
+                    )
+                    .map_err(DeviceManagerError::MemoryManager)?;
+
+                let region_list = vec![VirtioSharedMemory {
+                    offset: 0,
+                    len: cache_size,
+                }];
+
+                Some((
+                    VirtioSharedMemoryList {
+                        host_addr,
+                        mem_slot,
+                        addr: GuestAddress(cache_base),
+                        len: cache_size as GuestUsize,
+                        region_list,
+                    },
+                    mmap_region,
+                ))
+            } else {
+                None
+            };
+            let cache_mapping = cache
+                .as_ref()
+                .map(|cache| virtio_devices::UserspaceMapping {
+                    host_addr: cache.0.host_addr,
+                    mem_slot: cache.0.mem_slot,
+                    addr: cache.0.addr,
+                    len: cache.0.len,
+                    mergeable: false,
+                });
+
             let virtio_fs_device = Arc::new(Mutex::new(
                 virtio_devices::vhost_user::Fs::new(
                     id.clone(),
@@ -2641,7 +3522,7 @@ impl DeviceManager {
                     &fs_cfg.tag,
                     fs_cfg.num_queues,
                     fs_cfg.queue_size,
-                    None,
+                    cache,
                     self.seccomp_action.clone(),
                     self.exit_evt
                         .try_clone()
@@ -2653,10 +3534,27 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::CreateVirtioFs)?,
             ));
 
+            // The whole region was mapped above, but a restored device may
+            // only expose part of it through its DAX window.
+            if let Some(cache_mapping) = cache_mapping {
+                let fs = virtio_fs_device.lock().unwrap();
+                if fs.userspace_mappings().first().map(|mapping| mapping.len)
+                    != Some(cache_mapping.len)
+                {
+                    self.update_fs_cache_mapping(&[cache_mapping], &fs)?;
+                }
+            }
+
             // Update the device tree with the migratable device.
             node.migratable = Some(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
             self.device_tree.lock().unwrap().insert(id.clone(), node);
 
+            self.fs_devices
+                .insert(id.clone(), Arc::clone(&virtio_fs_device));
+            if let Some(daemon) = daemon {
+                self.virtiofs_daemons.insert(id.clone(), daemon);
+            }
+
             Ok(MetaVirtioDevice {
                 virtio_device: Arc::clone(&virtio_fs_device)
                     as Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -2664,6 +3562,9 @@ impl DeviceManager {
                 id,
                 pci_segment: fs_cfg.pci_segment,
                 dma_handler: None,
+                pci_root_port: None,
+                pci_ids: fs_cfg.pci_ids,
+                mmio: false,
             })
         } else {
             Err(DeviceManagerError::NoVirtioFsSock)
@@ -2827,6 +3728,7 @@ impl DeviceManager {
                 mapping,
                 mmap_region,
                 self.force_iommu | pmem_cfg.iommu,
+                pmem_cfg.discard,
                 self.seccomp_action.clone(),
                 self.exit_evt
                     .try_clone()
@@ -2853,6 +3755,9 @@ impl DeviceManager {
             id,
             pci_segment: pmem_cfg.pci_segment,
             dma_handler: None,
+            pci_root_port: None,
+            pci_ids: pmem_cfg.pci_ids,
+            mmio: false,
         })
     }
 
@@ -2888,10 +3793,28 @@ impl DeviceManager {
             .socket
             .to_str()
             .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-        let backend =
+        let mut backend =
             virtio_devices::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
                 .map_err(DeviceManagerError::CreateVsockBackend)?;
 
+        for sibling in vsock_cfg.siblings.iter().flatten() {
+            let sibling_path = sibling
+                .socket
+                .to_str()
+                .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
+            backend.add_sibling(sibling.cid, sibling_path.to_string());
+        }
+
+        for listener in vsock_cfg.listeners.iter().flatten() {
+            let listener_path = listener
+                .socket
+                .to_str()
+                .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
+            backend
+                .bind_port(listener.port, listener_path.to_string())
+                .map_err(DeviceManagerError::CreateVsockBackend)?;
+        }
+
         let vsock_device = Arc::new(Mutex::new(
             virtio_devices::Vsock::new(
                 id.clone(),
@@ -2924,6 +3847,9 @@ impl DeviceManager {
             id,
             pci_segment: vsock_cfg.pci_segment,
             dma_handler: None,
+            pci_root_port: None,
+            pci_ids: vsock_cfg.pci_ids,
+            mmio: vsock_cfg.mmio,
         })
     }
 
@@ -2959,6 +3885,7 @@ impl DeviceManager {
                         node_id,
                         virtio_mem_zone.hotplugged_size(),
                         virtio_mem_zone.hugepages(),
+                        virtio_mem_zone.block_size(),
                         self.exit_evt
                             .try_clone()
                             .map_err(DeviceManagerError::EventFd)?,
@@ -2983,6 +3910,9 @@ impl DeviceManager {
                     id: memory_zone_id.clone(),
                     pci_segment: 0,
                     dma_handler: None,
+                    pci_root_port: None,
+                    pci_ids: None,
+                    mmio: false,
                 });
 
                 // Fill the device tree with a new node. In case of restore, we
@@ -3012,6 +3942,7 @@ impl DeviceManager {
                     balloon_config.statistics,
                     balloon_config.deflate_on_oom,
                     balloon_config.free_page_reporting,
+                    balloon_config.free_page_hinting,
                     balloon_config.heterogeneous_memory,
                     self.seccomp_action.clone(),
                     self.exit_evt
@@ -3032,6 +3963,9 @@ impl DeviceManager {
                 id: id.clone(),
                 pci_segment: 0,
                 dma_handler: None,
+                pci_root_port: None,
+                pci_ids: None,
+                mmio: false,
             });
 
             self.device_tree
@@ -3046,7 +3980,15 @@ impl DeviceManager {
     fn make_virtio_watchdog_devices(&mut self) -> DeviceManagerResult<Vec<MetaVirtioDevice>> {
         let mut devices = Vec::new();
 
-        if !self.config.lock().unwrap().watchdog {
+        let (watchdog, watchdog_action, watchdog_escalation) = {
+            let config = self.config.lock().unwrap();
+            (
+                config.watchdog,
+                config.watchdog_action,
+                config.watchdog_escalation.clone(),
+            )
+        };
+        if !watchdog {
             return Ok(devices);
         }
 
@@ -3057,10 +3999,22 @@ impl DeviceManager {
             virtio_devices::Watchdog::new(
                 id.clone(),
                 self.reset_evt.try_clone().unwrap(),
+                self.pause_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                watchdog_action,
+                watchdog_escalation.map(|escalation| WatchdogEscalation {
+                    resets: escalation.resets,
+                    window: Duration::from_secs(escalation.window),
+                }),
+                self.watchdog_counters.clone(),
                 self.seccomp_action.clone(),
                 self.exit_evt
                     .try_clone()
                     .map_err(DeviceManagerError::EventFd)?,
+                self.guest_panic_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
                 versioned_state_from_id(self.snapshot.as_ref(), id.as_str())
                     .map_err(DeviceManagerError::RestoreGetState)?,
             )
@@ -3073,6 +4027,9 @@ impl DeviceManager {
             id: id.clone(),
             pci_segment: 0,
             dma_handler: None,
+            pci_root_port: None,
+            pci_ids: None,
+            mmio: false,
         });
 
         self.device_tree
@@ -3131,6 +4088,9 @@ impl DeviceManager {
             id,
             pci_segment: vdpa_cfg.pci_segment,
             dma_handler: Some(vdpa_mapping),
+            pci_root_port: None,
+            pci_ids: vdpa_cfg.pci_ids,
+            mmio: false,
         })
     }
 
@@ -3216,8 +4176,11 @@ impl DeviceManager {
             id
         };
 
-        let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&vfio_name, device_cfg.pci_segment)?;
+        let (pci_segment_id, pci_device_bdf, resources) = self.pci_resources(
+            &vfio_name,
+            device_cfg.pci_segment,
+            device_cfg.pci_root_port.as_deref(),
+        )?;
 
         let mut needs_dma_mapping = false;
 
@@ -3246,6 +4209,16 @@ impl DeviceManager {
                 return Err(DeviceManagerError::MissingVirtualIommu);
             }
 
+            vfio_container
+        } else if device_cfg.p2p.is_some() {
+            // The BARs of the peers are mapped into the container of the
+            // device, which can't be shared for them to be reachable from the
+            // allowed devices only.
+            let vfio_container = self.create_vfio_container()?;
+            needs_dma_mapping = true;
+            self.p2p_vfio_containers
+                .insert(vfio_name.clone(), Arc::clone(&vfio_container));
+
             vfio_container
         } else if let Some(vfio_container) = &self.vfio_container {
             Arc::clone(vfio_container)
@@ -3281,41 +4254,52 @@ impl DeviceManager {
                 Arc::new(self.memory_manager.lock().unwrap().guest_memory()),
             ));
 
+            let source = || {
+                if device_cfg.p2p.is_some() {
+                    VirtioMemMappingSource::Device(pci_device_bdf.into())
+                } else {
+                    VirtioMemMappingSource::Container
+                }
+            };
             for virtio_mem_device in self.virtio_mem_devices.iter() {
                 virtio_mem_device
                     .lock()
                     .unwrap()
-                    .add_dma_mapping_handler(
-                        VirtioMemMappingSource::Container,
-                        vfio_mapping.clone(),
-                    )
+                    .add_dma_mapping_handler(source(), vfio_mapping.clone())
                     .map_err(DeviceManagerError::AddDmaMappingHandlerVirtioMem)?;
             }
         }
 
-        let legacy_interrupt_group =
-            if let Some(legacy_interrupt_manager) = &self.legacy_interrupt_manager {
-                Some(
-                    legacy_interrupt_manager
-                        .create_group(LegacyIrqGroupConfig {
-                            irq: self.pci_segments[pci_segment_id as usize].pci_irq_slots
-                                [pci_device_bdf.device() as usize]
-                                as InterruptIndex,
-                        })
-                        .map_err(DeviceManagerError::CreateInterruptGroup)?,
-                )
-            } else {
-                None
-            };
+        let legacy_interrupt_group = if let Some(legacy_interrupt_manager) =
+            &self.legacy_interrupt_manager
+        {
+            Some(
+                legacy_interrupt_manager
+                    .create_group(LegacyIrqGroupConfig {
+                        irq: self.pci_segments[pci_segment_id as usize].legacy_irq(pci_device_bdf)
+                            as InterruptIndex,
+                    })
+                    .map_err(DeviceManagerError::CreateInterruptGroup)?,
+            )
+        } else {
+            None
+        };
+
+        // The MSIs of a device attached to the virtual IOMMU are remapped by
+        // the IOMMU, so that the device can't signal interrupts on its own.
+        let msi_interrupt_manager = self.pci_msi_interrupt_manager(
+            pci_device_bdf,
+            self.iommu_mapping.as_ref().filter(|_| device_cfg.iommu),
+        );
 
         let memory_manager = self.memory_manager.clone();
 
-        let vfio_pci_device = VfioPciDevice::new(
+        let mut vfio_pci_device = VfioPciDevice::new(
             vfio_name.clone(),
             &self.address_manager.vm,
             vfio_device,
             vfio_container,
-            self.msi_interrupt_manager.clone(),
+            msi_interrupt_manager,
             legacy_interrupt_group,
             device_cfg.iommu,
             pci_device_bdf,
@@ -3323,6 +4307,11 @@ impl DeviceManager {
             vm_migration::snapshot_from_id(self.snapshot.as_ref(), vfio_name.as_str()),
         )
         .map_err(DeviceManagerError::VfioPciCreate)?;
+        // The memory window of a root port only forwards the non-prefetchable
+        // BARs below 4 GiB.
+        if device_cfg.pci_root_port.is_some() {
+            vfio_pci_device.place_non_prefetchable_bars_below_4g();
+        }
 
         let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
 
@@ -3340,6 +4329,19 @@ impl DeviceManager {
             .map_mmio_regions()
             .map_err(DeviceManagerError::VfioMapRegion)?;
 
+        self.add_p2p_dma(&vfio_name, device_cfg, &vfio_pci_device)?;
+
+        let error_evt = vfio_pci_device.lock().unwrap().error_notifier();
+        if let Some(error_evt) = error_evt {
+            self.forward_vfio_errors(
+                &vfio_name,
+                error_evt,
+                &vfio_pci_device,
+                pci_segment_id,
+                pci_device_bdf,
+            )?;
+        }
+
         let mut node = device_node!(vfio_name, vfio_pci_device);
 
         // Update the device tree with correct resource information.
@@ -3355,60 +4357,255 @@ impl DeviceManager {
         Ok((pci_device_bdf, vfio_name))
     }
 
-    fn add_pci_device(
-        &mut self,
-        bus_device: Arc<Mutex<dyn BusDevice>>,
-        pci_device: Arc<Mutex<dyn PciDevice>>,
-        segment_id: u16,
-        bdf: PciBdf,
-        resources: Option<Vec<Resource>>,
-    ) -> DeviceManagerResult<Vec<Resource>> {
-        let bars = pci_device
+    // Map the BARs of the devices allowing the new device to reach them into
+    // its container, and its own BARs into the containers of the devices it
+    // allows, whichever was added first.
+    fn add_p2p_dma(
+        &self,
+        id: &str,
+        device_cfg: &DeviceConfig,
+        device: &Arc<Mutex<VfioPciDevice>>,
+    ) -> DeviceManagerResult<()> {
+        let Some(container) = self.p2p_vfio_containers.get(id) else {
+            return Ok(());
+        };
+        let allowed = device_cfg.p2p.as_deref().unwrap_or_default();
+        let devices = self
+            .config
             .lock()
             .unwrap()
-            .allocate_bars(
-                &self.address_manager.allocator,
-                &mut self.pci_segments[segment_id as usize]
-                    .allocator
-                    .lock()
-                    .unwrap(),
-                resources,
-            )
-            .map_err(DeviceManagerError::AllocateBars)?;
-
-        let mut pci_bus = self.pci_segments[segment_id as usize]
-            .pci_bus
-            .lock()
-            .unwrap();
-
-        pci_bus
-            .add_device(bdf.device() as u32, pci_device)
-            .map_err(DeviceManagerError::AddPciDevice)?;
+            .devices
+            .clone()
+            .unwrap_or_default();
 
-        self.bus_devices.push(Arc::clone(&bus_device));
+        let device_tree = self.device_tree.lock().unwrap();
+        for node in device_tree.pci_devices() {
+            let (Some(PciDeviceHandle::Vfio(peer)), Some(peer_container)) = (
+                &node.pci_device_handle,
+                self.p2p_vfio_containers.get(&node.id),
+            ) else {
+                continue;
+            };
+            if node.id == id {
+                continue;
+            }
 
-        pci_bus
-            .register_mapping(
-                bus_device,
-                #[cfg(target_arch = "x86_64")]
-                self.address_manager.io_bus.as_ref(),
-                self.address_manager.mmio_bus.as_ref(),
-                bars.clone(),
-            )
-            .map_err(DeviceManagerError::AddPciDevice)?;
+            if allowed.contains(&node.id) {
+                device
+                    .lock()
+                    .unwrap()
+                    .add_p2p_container(Arc::clone(peer_container))
+                    .map_err(DeviceManagerError::VfioP2pDmaMap)?;
+            }
 
-        let mut new_resources = Vec::new();
-        for bar in bars {
-            new_resources.push(Resource::PciBar {
-                index: bar.idx(),
-                base: bar.addr(),
-                size: bar.size(),
-                type_: bar.region_type().into(),
-                prefetchable: bar.prefetchable().into(),
+            let peer_allows = devices.iter().any(|d| {
+                d.id.as_deref() == Some(node.id.as_str())
+                    && d.p2p.as_ref().map_or(false, |p| p.iter().any(|p| p == id))
             });
+            if peer_allows {
+                peer.lock()
+                    .unwrap()
+                    .add_p2p_container(Arc::clone(container))
+                    .map_err(DeviceManagerError::VfioP2pDmaMap)?;
+            }
         }
 
-        Ok(new_resources)
+        Ok(())
+    }
+
+    // Report the errors the host detects on a VFIO device to the guest, as
+    // long as the device exists. The correctable errors aren't signaled by
+    // the host, the device being polled for them between the uncorrectable
+    // ones. The errors of a device behind a root port are received by the
+    // port, the other ones being reported in firmware first mode.
+    fn forward_vfio_errors(
+        &self,
+        id: &str,
+        error_evt: EventFd,
+        device: &Arc<Mutex<VfioPciDevice>>,
+        segment_id: u16,
+        bdf: PciBdf,
+    ) -> DeviceManagerResult<()> {
+        let reporting = match (
+            self.pci_segments[segment_id as usize].root_port(bdf.bus()),
+            &self.ghes_device,
+            &self.ged_notification_device,
+        ) {
+            (Some(root_port), _, _) => VfioErrorReporting::RootPort(root_port.port.clone()),
+            (None, Some(ghes), Some(ged)) => VfioErrorReporting::Ghes(ghes.clone(), ged.clone()),
+            _ => return Ok(()),
+        };
+        let device = Arc::downgrade(device);
+        let id = id.to_string();
+
+        thread::Builder::new()
+            .name(format!("{id}_errors"))
+            .spawn(move || loop {
+                let mut pollfd = libc::pollfd {
+                    fd: error_evt.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: FFI call with a valid pollfd, the eventfd being
+                // owned by the thread.
+                let ret = unsafe { libc::poll(&mut pollfd, 1, VFIO_CORRECTABLE_ERRORS_POLL_MS) };
+                if ret < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    error!("Failed polling the error eventfd of {}: {}", id, e);
+                    break;
+                }
+                let uncorrectable = ret > 0;
+                if uncorrectable {
+                    if let Err(e) = error_evt.read() {
+                        error!("Failed reading the error eventfd of {}: {}", id, e);
+                        break;
+                    }
+                }
+                let device = match device.upgrade() {
+                    Some(device) => device,
+                    None => break,
+                };
+
+                // The guest clears the errors latched in the device when
+                // they are received by a root port, and can't when they are
+                // reported in firmware first mode.
+                let mut device = device.lock().unwrap();
+                let clear = matches!(reporting, VfioErrorReporting::Ghes(..));
+                let aer = if uncorrectable {
+                    device.aer_registers()
+                } else {
+                    match device.correctable_errors(clear) {
+                        Some(aer) => Some(aer),
+                        None => continue,
+                    }
+                };
+                if uncorrectable {
+                    warn!("Error detected on device {} ({}), reporting it", id, bdf);
+                } else {
+                    debug!(
+                        "Correctable error detected on device {} ({}), reporting it",
+                        id, bdf
+                    );
+                }
+
+                let (ghes_device, ged_device) = match &reporting {
+                    VfioErrorReporting::RootPort(root_port) => {
+                        drop(device);
+                        let severity = if uncorrectable {
+                            PciErrorSeverity::uncorrectable(aer.as_ref().map(|aer| &aer[..]))
+                        } else {
+                            PciErrorSeverity::Correctable
+                        };
+                        if !root_port.lock().unwrap().report_error(severity) {
+                            debug!("Reporting of the errors of {} disabled by the guest", id);
+                        }
+                        continue;
+                    }
+                    VfioErrorReporting::Ghes(ghes_device, ged_device) => (ghes_device, ged_device),
+                };
+
+                let id_reg = device.read_config_register(0);
+                let command_reg = device.read_config_register(1);
+                let class_reg = device.read_config_register(2);
+                let error = devices::PcieError {
+                    segment: segment_id,
+                    bus: bdf.bus(),
+                    devfn: (bdf.device() << 3) | bdf.function(),
+                    vendor_id: id_reg as u16,
+                    device_id: (id_reg >> 16) as u16,
+                    class_code: [
+                        (class_reg >> 8) as u8,
+                        (class_reg >> 16) as u8,
+                        (class_reg >> 24) as u8,
+                    ],
+                    command: command_reg as u16,
+                    status: (command_reg >> 16) as u16,
+                    aer,
+                };
+                drop(device);
+
+                if !ghes_device.lock().unwrap().report_pcie_error(&error) {
+                    warn!(
+                        "Previous error not acknowledged by the guest, dropping the error of {}",
+                        id
+                    );
+                    continue;
+                }
+                if let Err(e) = ged_device
+                    .lock()
+                    .unwrap()
+                    .notify(AcpiNotificationFlags::HARDWARE_ERROR)
+                {
+                    error!("Failed notifying the guest about an error: {}", e);
+                }
+            })
+            .map_err(DeviceManagerError::SpawnVfioErrorThread)?;
+
+        Ok(())
+    }
+
+    fn add_pci_device(
+        &mut self,
+        bus_device: Arc<Mutex<dyn BusDevice>>,
+        pci_device: Arc<Mutex<dyn PciDevice>>,
+        segment_id: u16,
+        bdf: PciBdf,
+        resources: Option<Vec<Resource>>,
+    ) -> DeviceManagerResult<Vec<Resource>> {
+        let pci_segment = &self.pci_segments[segment_id as usize];
+        let bars = pci_device
+            .lock()
+            .unwrap()
+            .allocate_bars(
+                &self.address_manager.allocator,
+                &mut pci_segment.bus_allocator(bdf.bus()).lock().unwrap(),
+                resources,
+            )
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        let device_id = pci_segment.device_id(bdf);
+        let mut pci_bus = pci_segment.pci_bus.lock().unwrap();
+
+        if let Some(root_port) = pci_segment.root_port(bdf.bus()) {
+            root_port
+                .port
+                .lock()
+                .unwrap()
+                .attach_device(pci_device, &bars)
+                .map_err(DeviceManagerError::AttachPciRootPort)?;
+        } else if let Some(device_id) = device_id {
+            pci_bus
+                .add_device(device_id, pci_device)
+                .map_err(DeviceManagerError::AddPciDevice)?;
+        }
+
+        self.bus_devices.push(Arc::clone(&bus_device));
+
+        pci_bus
+            .register_mapping(
+                bus_device,
+                #[cfg(target_arch = "x86_64")]
+                self.address_manager.io_bus.as_ref(),
+                self.address_manager.mmio_bus.as_ref(),
+                bars.clone(),
+            )
+            .map_err(DeviceManagerError::AddPciDevice)?;
+
+        let mut new_resources = Vec::new();
+        for bar in bars {
+            new_resources.push(Resource::PciBar {
+                index: bar.idx(),
+                base: bar.addr(),
+                size: bar.size(),
+                type_: bar.region_type().into(),
+                prefetchable: bar.prefetchable().into(),
+            });
+        }
+
+        Ok(new_resources)
     }
 
     fn add_vfio_devices(&mut self) -> DeviceManagerResult<Vec<PciBdf>> {
@@ -3443,22 +4640,22 @@ impl DeviceManager {
         };
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&vfio_user_name, device_cfg.pci_segment)?;
-
-        let legacy_interrupt_group =
-            if let Some(legacy_interrupt_manager) = &self.legacy_interrupt_manager {
-                Some(
-                    legacy_interrupt_manager
-                        .create_group(LegacyIrqGroupConfig {
-                            irq: self.pci_segments[pci_segment_id as usize].pci_irq_slots
-                                [pci_device_bdf.device() as usize]
-                                as InterruptIndex,
-                        })
-                        .map_err(DeviceManagerError::CreateInterruptGroup)?,
-                )
-            } else {
-                None
-            };
+            self.pci_resources(&vfio_user_name, device_cfg.pci_segment, None)?;
+
+        let legacy_interrupt_group = if let Some(legacy_interrupt_manager) =
+            &self.legacy_interrupt_manager
+        {
+            Some(
+                legacy_interrupt_manager
+                    .create_group(LegacyIrqGroupConfig {
+                        irq: self.pci_segments[pci_segment_id as usize].legacy_irq(pci_device_bdf)
+                            as InterruptIndex,
+                    })
+                    .map_err(DeviceManagerError::CreateInterruptGroup)?,
+            )
+        } else {
+            None
+        };
 
         let client = Arc::new(Mutex::new(
             vfio_user::Client::new(&device_cfg.socket)
@@ -3471,7 +4668,7 @@ impl DeviceManager {
             vfio_user_name.clone(),
             &self.address_manager.vm,
             client.clone(),
-            self.msi_interrupt_manager.clone(),
+            self.pci_msi_interrupt_manager(pci_device_bdf, None),
             legacy_interrupt_group,
             pci_device_bdf,
             Arc::new(move || memory_manager.lock().unwrap().allocate_memory_slot()),
@@ -3548,6 +4745,287 @@ impl DeviceManager {
         Ok(vec![])
     }
 
+    fn add_ivshmem_device(&mut self, ivshmem_cfg: &mut IvshmemConfig) -> DeviceManagerResult<()> {
+        let id = if let Some(id) = &ivshmem_cfg.id {
+            id.clone()
+        } else {
+            let id = self.next_device_name(IVSHMEM_DEVICE_NAME_PREFIX)?;
+            ivshmem_cfg.id = Some(id.clone());
+            id
+        };
+
+        info!("Creating ivshmem device: {:?}", ivshmem_cfg);
+
+        let (pci_segment_id, pci_device_bdf, resources) =
+            self.pci_resources(&id, ivshmem_cfg.pci_segment, None)?;
+
+        // The shared memory is either handed out by the server or backed by
+        // the file, created when given a size.
+        let (server, file) = match (&ivshmem_cfg.socket, &ivshmem_cfg.path) {
+            (Some(socket), _) => {
+                let (server, file) = devices::IvshmemServer::connect(socket)
+                    .map_err(DeviceManagerError::IvshmemCreate)?;
+                (Some(server), file)
+            }
+            (None, Some(path)) => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(ivshmem_cfg.size.is_some())
+                    .open(path)
+                    .map_err(DeviceManagerError::IvshmemFileOpen)?;
+                (None, file)
+            }
+            (None, None) => unreachable!("ivshmem configuration validated"),
+        };
+
+        let file_size = file
+            .metadata()
+            .map_err(DeviceManagerError::IvshmemFileOpen)?
+            .len();
+        let size = match ivshmem_cfg.size {
+            Some(size) if size > file_size => {
+                file.set_len(size)
+                    .map_err(DeviceManagerError::IvshmemFileSetLen)?;
+                size
+            }
+            Some(size) => size,
+            None => file_size,
+        };
+        if !size.is_power_of_two() {
+            return Err(DeviceManagerError::IvshmemSizeInvalid(size));
+        }
+
+        let shm_region = MmapRegion::build(
+            Some(FileOffset::new(file, 0)),
+            size as usize,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED | MAP_NORESERVE,
+        )
+        .map_err(DeviceManagerError::NewMmapRegion)?;
+
+        let ivshmem_device = Arc::new(Mutex::new(
+            devices::IvshmemDevice::new(
+                id.clone(),
+                shm_region,
+                server,
+                ivshmem_cfg.vectors,
+                self.pci_msi_interrupt_manager(pci_device_bdf, None)
+                    .as_ref(),
+                pci_device_bdf.into(),
+                snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+            )
+            .map_err(DeviceManagerError::IvshmemCreate)?,
+        ));
+
+        let new_resources = self.add_pci_device(
+            ivshmem_device.clone(),
+            ivshmem_device.clone(),
+            pci_segment_id,
+            pci_device_bdf,
+            resources,
+        )?;
+
+        let mut ivshmem = ivshmem_device.lock().unwrap();
+        let shm_bar_addr = ivshmem
+            .shm_bar_addr()
+            .ok_or(DeviceManagerError::MissingIvshmemBar)?;
+        let mem_slot = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .create_userspace_mapping(
+                shm_bar_addr,
+                ivshmem.shm_size(),
+                ivshmem.shm_host_addr(),
+                false,
+                false,
+                false,
+            )
+            .map_err(DeviceManagerError::MemoryManager)?;
+        ivshmem.set_shm_mem_slot(mem_slot);
+
+        if let Some(mut handler) = ivshmem.doorbell_handler() {
+            let seccomp_filter =
+                get_seccomp_filter(&self.seccomp_action, Thread::Ivshmem, self.hypervisor_type)
+                    .map_err(DeviceManagerError::CreateSeccompFilter)?;
+            let exit_evt = self
+                .exit_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?;
+            let thread_id = id.clone();
+
+            thread::Builder::new()
+                .name(format!("{id}_doorbell"))
+                .spawn(move || {
+                    if !seccomp_filter.is_empty() {
+                        if let Err(e) = apply_filter(&seccomp_filter) {
+                            error!("Error applying seccomp filter: {:?}", e);
+                            exit_evt.write(1).ok();
+                            return;
+                        }
+                    }
+                    if let Err(e) = handler.run() {
+                        error!("Error running the doorbell of {}: {}", thread_id, e);
+                        exit_evt.write(1).ok();
+                    }
+                })
+                .map_err(DeviceManagerError::SpawnIvshmemThread)?;
+        }
+        drop(ivshmem);
+
+        let mut node = device_node!(id, ivshmem_device);
+
+        node.resources = new_resources;
+        node.pci_bdf = Some(pci_device_bdf);
+        node.pci_device_handle = None;
+
+        self.device_tree.lock().unwrap().insert(id, node);
+
+        Ok(())
+    }
+
+    fn add_ivshmem_devices(&mut self) -> DeviceManagerResult<()> {
+        let mut ivshmem_devices = self.config.lock().unwrap().ivshmem.clone();
+
+        if let Some(device_list_cfg) = &mut ivshmem_devices {
+            for device_cfg in device_list_cfg.iter_mut() {
+                self.add_ivshmem_device(device_cfg)?;
+            }
+        }
+
+        // Update the list of devices
+        self.config.lock().unwrap().ivshmem = ivshmem_devices;
+
+        Ok(())
+    }
+
+    fn add_virtio_mmio_device(
+        &mut self,
+        virtio_device: Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+        virtio_device_id: String,
+    ) -> DeviceManagerResult<()> {
+        let id = format!("{VIRTIO_MMIO_DEVICE_NAME_PREFIX}-{virtio_device_id}");
+
+        // Add the new virtio-mmio node to the device tree.
+        let mut node = device_node!(id);
+        node.children = vec![virtio_device_id.clone()];
+
+        // Look for the id in the device tree. If it can be found, that means
+        // the device is being restored, otherwise it's created from scratch.
+        let mut restored_base = None;
+        if let Some(node) = self.device_tree.lock().unwrap().get(&id) {
+            info!("Restoring virtio-mmio {} resources", id);
+            for resource in node.resources.iter() {
+                match resource {
+                    Resource::MmioAddressRange { base, .. } => {
+                        restored_base = Some(GuestAddress(*base));
+                    }
+                    Resource::LegacyIrq(_) => {}
+                    _ => {
+                        error!("Unexpected resource {:?} for {}", resource, id);
+                    }
+                }
+            }
+        }
+
+        // Update the existing virtio node by setting the parent.
+        if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
+            node.parent = Some(id.clone());
+        } else {
+            return Err(DeviceManagerError::MissingNode);
+        }
+
+        let (base, irq) = {
+            let mut allocator = self.address_manager.allocator.lock().unwrap();
+            let base = allocator
+                .allocate_platform_mmio_addresses(restored_base, VIRTIO_MMIO_DEVICE_SIZE, None)
+                .ok_or(DeviceManagerError::AllocateMmioAddress)?;
+            // The IRQs being allocated in the order the devices are created,
+            // a restored device gets the same one back.
+            let irq = allocator
+                .allocate_irq()
+                .ok_or(DeviceManagerError::AllocateIrq)?;
+            (base.raw_value(), irq)
+        };
+
+        let interrupt_group = self
+            .legacy_interrupt_manager
+            .as_ref()
+            .unwrap()
+            .create_group(LegacyIrqGroupConfig {
+                irq: irq as InterruptIndex,
+            })
+            .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+        #[cfg(target_arch = "aarch64")]
+        let device_type = virtio_device.lock().unwrap().device_type();
+        let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let virtio_mmio_device = Arc::new(Mutex::new(
+            VirtioMmioDevice::new(
+                id.clone(),
+                memory,
+                virtio_device,
+                interrupt_group,
+                self.activate_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
+                self.pending_activations.clone(),
+                vm_migration::snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+            )
+            .map_err(DeviceManagerError::VirtioMmioDevice)?,
+        ));
+
+        self.bus_devices
+            .push(Arc::clone(&virtio_mmio_device) as Arc<Mutex<dyn BusDevice>>);
+        self.address_manager
+            .mmio_bus
+            .insert(virtio_mmio_device.clone(), base, VIRTIO_MMIO_DEVICE_SIZE)
+            .map_err(DeviceManagerError::BusError)?;
+
+        for (event, addr, queue_index) in virtio_mmio_device.lock().unwrap().ioeventfds(base) {
+            let io_addr = IoEventAddress::Mmio(addr);
+            self.address_manager
+                .vm
+                .register_ioevent(event, &io_addr, Some(DataMatch::DataMatch32(queue_index)))
+                .map_err(|e| DeviceManagerError::RegisterIoevent(e.into()))?;
+        }
+
+        // Describe the device to the guest, through the device tree on
+        // aarch64, and the kernel command line on x86_64.
+        #[cfg(target_arch = "aarch64")]
+        self.id_to_dev_info.insert(
+            (DeviceType::Virtio(device_type), id.clone()),
+            MmioDeviceInfo {
+                addr: base,
+                len: VIRTIO_MMIO_DEVICE_SIZE,
+                irq,
+            },
+        );
+        #[cfg(target_arch = "x86_64")]
+        self.cmdline_additions.push(format!(
+            "virtio_mmio.device={}K@0x{:x}:{}",
+            VIRTIO_MMIO_DEVICE_SIZE >> 10,
+            base,
+            irq
+        ));
+
+        // Update the device tree with correct resource information.
+        node.resources = vec![
+            Resource::MmioAddressRange {
+                base,
+                size: VIRTIO_MMIO_DEVICE_SIZE,
+            },
+            Resource::LegacyIrq(irq),
+        ];
+        node.migratable = Some(Arc::clone(&virtio_mmio_device) as Arc<Mutex<dyn Migratable>>);
+        self.device_tree.lock().unwrap().insert(id.clone(), node);
+        self.virtio_mmio_devices.insert(id, virtio_mmio_device);
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn add_virtio_pci_device(
         &mut self,
         virtio_device: Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
@@ -3555,6 +5033,8 @@ impl DeviceManager {
         virtio_device_id: String,
         pci_segment_id: u16,
         dma_handler: Option<Arc<dyn ExternalDmaMapping>>,
+        pci_root_port: Option<&str>,
+        pci_ids: Option<PciIdsConfig>,
     ) -> DeviceManagerResult<PciBdf> {
         let id = format!("{VIRTIO_PCI_DEVICE_NAME_PREFIX}-{virtio_device_id}");
 
@@ -3563,7 +5043,7 @@ impl DeviceManager {
         node.children = vec![virtio_device_id.clone()];
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&id, pci_segment_id)?;
+            self.pci_resources(&id, pci_segment_id, pci_root_port)?;
 
         // Update the existing virtio node by setting the parent.
         if let Some(node) = self.device_tree.lock().unwrap().get_mut(&virtio_device_id) {
@@ -3640,7 +5120,7 @@ impl DeviceManager {
                 virtio_device,
                 msix_num,
                 access_platform,
-                &self.msi_interrupt_manager,
+                &self.pci_msi_interrupt_manager(pci_device_bdf, iommu_mapping.as_ref()),
                 pci_device_bdf.into(),
                 self.activate_evt
                     .try_clone()
@@ -3649,9 +5129,14 @@ impl DeviceManager {
                 // The block devices should be given a 32-bit BAR so that they are easily accessible
                 // to firmware without requiring excessive identity mapping.
                 // The exception being if not on the default PCI segment.
-                pci_segment_id > 0 || device_type != VirtioDeviceType::Block as u32,
+                // The devices behind a root port are given a 32-bit BAR too,
+                // as the port only forwards the non-prefetchable BARs below
+                // 4 GiB.
+                pci_root_port.is_none()
+                    && (pci_segment_id > 0 || device_type != VirtioDeviceType::Block as u32),
                 dma_handler,
                 self.pending_activations.clone(),
+                pci_ids.unwrap_or_default(),
                 vm_migration::snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
             )
             .map_err(DeviceManagerError::VirtioDevice)?,
@@ -3684,6 +5169,62 @@ impl DeviceManager {
         Ok(pci_device_bdf)
     }
 
+    fn add_pci_root_ports(&mut self) -> DeviceManagerResult<()> {
+        let root_ports = self.config.lock().unwrap().pci_root_ports.clone();
+
+        for root_port_cfg in root_ports.iter().flatten() {
+            let id = root_port_cfg.id.clone();
+            let pci_segment = &self.pci_segments[root_port_cfg.pci_segment as usize];
+            // Each port leads to the bus following the ones of the ports
+            // created before it.
+            let port_number = pci_segment.root_ports.len() as u8;
+            let secondary_bus = pci_segment.start_bus + port_number + 1;
+
+            info!("Creating PCI Express root port {}", id);
+
+            let (pci_segment_id, pci_device_bdf, _) =
+                self.pci_resources(&id, root_port_cfg.pci_segment, None)?;
+
+            // A single vector notifies the hot-plug events of the slot.
+            let interrupt_group = self
+                .pci_msi_interrupt_manager(pci_device_bdf, None)
+                .create_group(MsiIrqGroupConfig { base: 0, count: 1 })
+                .map_err(DeviceManagerError::CreateInterruptGroup)?;
+
+            let root_port = Arc::new(Mutex::new(
+                PciRootPort::new(
+                    id.clone(),
+                    port_number,
+                    pci_device_bdf.bus(),
+                    secondary_bus,
+                    interrupt_group,
+                    snapshot_from_id(self.snapshot.as_ref(), id.as_str()),
+                )
+                .map_err(DeviceManagerError::CreatePciRootPort)?,
+            ));
+
+            let pci_segment = &mut self.pci_segments[pci_segment_id as usize];
+            pci_segment
+                .pci_bus
+                .lock()
+                .unwrap()
+                .add_root_port(pci_device_bdf.device() as u32, root_port.clone())
+                .map_err(DeviceManagerError::AddPciDevice)?;
+            pci_segment.root_ports.push(RootPort {
+                id: id.clone(),
+                device_id: pci_device_bdf.device(),
+                bus: secondary_bus,
+                port: root_port.clone(),
+            });
+
+            let mut node = device_node!(id, root_port);
+            node.pci_bdf = Some(pci_device_bdf);
+            self.device_tree.lock().unwrap().insert(id, node);
+        }
+
+        Ok(())
+    }
+
     fn add_pvpanic_device(
         &mut self,
     ) -> DeviceManagerResult<Option<Arc<Mutex<devices::PvPanicDevice>>>> {
@@ -3693,12 +5234,18 @@ impl DeviceManager {
         info!("Creating pvpanic device {}", id);
 
         let (pci_segment_id, pci_device_bdf, resources) =
-            self.pci_resources(&id, pci_segment_id)?;
+            self.pci_resources(&id, pci_segment_id, None)?;
 
         let snapshot = snapshot_from_id(self.snapshot.as_ref(), id.as_str());
 
-        let pvpanic_device = devices::PvPanicDevice::new(id.clone(), snapshot)
-            .map_err(DeviceManagerError::PvPanicCreate)?;
+        let pvpanic_device = devices::PvPanicDevice::new(
+            id.clone(),
+            self.guest_panic_evt
+                .try_clone()
+                .map_err(DeviceManagerError::EventFd)?,
+            snapshot,
+        )
+        .map_err(DeviceManagerError::PvPanicCreate)?;
 
         let pvpanic_device = Arc::new(Mutex::new(pvpanic_device));
 
@@ -3725,6 +5272,7 @@ impl DeviceManager {
         &self,
         id: &str,
         pci_segment_id: u16,
+        pci_root_port: Option<&str>,
     ) -> DeviceManagerResult<(u16, PciBdf, Option<Vec<Resource>>)> {
         // Look for the id in the device tree. If it can be found, that means
         // the device is being restored, otherwise it's created from scratch.
@@ -3735,18 +5283,26 @@ impl DeviceManager {
                     .pci_bdf
                     .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
                 let pci_segment_id = pci_device_bdf.segment();
+                let pci_segment = &self.pci_segments[pci_segment_id as usize];
 
-                self.pci_segments[pci_segment_id as usize]
-                    .pci_bus
-                    .lock()
-                    .unwrap()
-                    .get_device_id(pci_device_bdf.device() as usize)
-                    .map_err(DeviceManagerError::GetPciDeviceId)?;
+                // The devices behind the root ports don't take any slot of
+                // the root buses of the segment.
+                if let Some(device_id) = pci_segment.device_id(pci_device_bdf) {
+                    pci_segment
+                        .pci_bus
+                        .lock()
+                        .unwrap()
+                        .get_device_id(device_id as usize)
+                        .map_err(DeviceManagerError::GetPciDeviceId)?;
+                }
 
                 (pci_segment_id, pci_device_bdf, Some(node.resources.clone()))
             } else {
-                let pci_device_bdf =
-                    self.pci_segments[pci_segment_id as usize].next_device_bdf()?;
+                let pci_segment = &self.pci_segments[pci_segment_id as usize];
+                let pci_device_bdf = match pci_root_port {
+                    Some(pci_root_port) => pci_segment.root_port_bdf(pci_root_port)?,
+                    None => pci_segment.next_device_bdf(self.pci_expansion)?,
+                };
 
                 (pci_segment_id, pci_device_bdf, None)
             },
@@ -3776,11 +5332,14 @@ impl DeviceManager {
         &self.pci_segments
     }
 
+    pub(crate) fn ghes_device(&self) -> Option<&Arc<Mutex<devices::GhesDevice>>> {
+        self.ghes_device.as_ref()
+    }
+
     pub fn console(&self) -> &Arc<Console> {
         &self.console
     }
 
-    #[cfg(target_arch = "aarch64")]
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
@@ -3806,7 +5365,11 @@ impl DeviceManager {
         }
 
         // Take care of updating the memory for VFIO PCI devices.
-        if let Some(vfio_container) = &self.vfio_container {
+        for vfio_container in self
+            .vfio_container
+            .iter()
+            .chain(self.p2p_vfio_containers.values())
+        {
             vfio_container
                 .vfio_dma_map(
                     new_region.start_addr().raw_value(),
@@ -3838,6 +5401,21 @@ impl DeviceManager {
     }
 
     pub fn activate_virtio_devices(&self) -> DeviceManagerResult<()> {
+        trace_scoped!("activate_virtio_devices");
+
+        // DAX window resizes requested while a virtio-fs device was activated
+        // take effect once the guest has reset it, before it's activated again.
+        for fs in self.fs_devices.values() {
+            let mut fs = fs.lock().unwrap();
+            let old_mappings = fs.userspace_mappings();
+            if fs
+                .apply_pending_cache_resize()
+                .map_err(DeviceManagerError::VirtioFsResize)?
+            {
+                self.update_fs_cache_mapping(&old_mappings, &fs)?;
+            }
+        }
+
         for mut activator in self.pending_activations.lock().unwrap().drain(..) {
             activator
                 .activate()
@@ -3870,6 +5448,10 @@ impl DeviceManager {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
+        if device_cfg.pci_root_port.is_some() {
+            return Err(DeviceManagerError::PciRootPortHotplug);
+        }
+
         let (bdf, device_name) = self.add_passthrough_device(device_cfg)?;
 
         // Update the PCIU bitmap
@@ -3915,6 +5497,9 @@ impl DeviceManager {
                 .parent
                 .as_ref()
                 .ok_or(DeviceManagerError::MissingNode)?;
+            if parent.starts_with(VIRTIO_MMIO_DEVICE_NAME_PREFIX) {
+                return Err(DeviceManagerError::MmioDeviceHotplug);
+            }
             device_tree
                 .get(parent)
                 .ok_or(DeviceManagerError::MissingNode)?
@@ -3924,6 +5509,17 @@ impl DeviceManager {
             .pci_bdf
             .ok_or(DeviceManagerError::MissingDeviceNodePciBdf)?;
         let pci_segment_id = pci_device_bdf.segment();
+        let pci_segment = &self.pci_segments[pci_segment_id as usize];
+        if pci_segment
+            .expansion_buses
+            .iter()
+            .any(|expansion_bus| expansion_bus.bus == pci_device_bdf.bus())
+        {
+            return Err(DeviceManagerError::PciExpansionBusHotplug);
+        }
+        if pci_device_bdf.bus() != pci_segment.start_bus {
+            return Err(DeviceManagerError::PciRootPortHotplug);
+        }
 
         let pci_device_handle = pci_device_node
             .pci_device_handle
@@ -3945,7 +5541,9 @@ impl DeviceManager {
                 | VirtioDeviceType::Block
                 | VirtioDeviceType::Pmem
                 | VirtioDeviceType::Fs
-                | VirtioDeviceType::Vsock => {}
+                | VirtioDeviceType::Vsock
+                | VirtioDeviceType::Rng
+                | VirtioDeviceType::Console => {}
                 _ => return Err(DeviceManagerError::RemovalNotAllowed(device_type)),
             }
         }
@@ -3963,7 +5561,12 @@ impl DeviceManager {
         );
 
         // Convert the device ID into the corresponding b/d/f.
-        let pci_device_bdf = PciBdf::new(pci_segment_id, 0, device_id, 0);
+        let pci_device_bdf = PciBdf::new(
+            pci_segment_id,
+            self.pci_segments[pci_segment_id as usize].start_bus,
+            device_id,
+            0,
+        );
 
         // Give the PCI device ID back to the PCI bus.
         self.pci_segments[pci_segment_id as usize]
@@ -4006,15 +5609,28 @@ impl DeviceManager {
         }
 
         let (pci_device, bus_device, virtio_device, remove_dma_handler) = match pci_device_handle {
-            // No need to remove any virtio-mem mapping here as the container outlives all devices
-            PciDeviceHandle::Vfio(vfio_pci_device) => (
-                Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
-                Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
-                None as Option<Arc<Mutex<dyn virtio_devices::VirtioDevice>>>,
-                false,
-            ),
+            // No need to remove any virtio-mem mapping here as the shared
+            // container outlives all devices, unlike the dedicated containers
+            // of the devices taking part in peer-to-peer DMA.
+            PciDeviceHandle::Vfio(vfio_pci_device) => {
+                let p2p_container = self.p2p_vfio_containers.remove(&id);
+                if let Some(container) = &p2p_container {
+                    for node in device_tree.pci_devices() {
+                        if let Some(PciDeviceHandle::Vfio(peer)) = &node.pci_device_handle {
+                            peer.lock().unwrap().remove_p2p_container(container);
+                        }
+                    }
+                }
+
+                (
+                    Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
+                    Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
+                    None as Option<Arc<Mutex<dyn virtio_devices::VirtioDevice>>>,
+                    p2p_container.is_some(),
+                )
+            }
             PciDeviceHandle::Virtio(virtio_pci_device) => {
-                let dev = virtio_pci_device.lock().unwrap();
+                let mut dev = virtio_pci_device.lock().unwrap();
                 let bar_addr = dev.config_bar_addr();
                 for (event, addr) in dev.ioeventfds(bar_addr) {
                     let io_addr = IoEventAddress::Mmio(addr);
@@ -4023,6 +5639,7 @@ impl DeviceManager {
                         .unregister_ioevent(event, &io_addr)
                         .map_err(|e| DeviceManagerError::UnRegisterIoevent(e.into()))?;
                 }
+                dev.reset_device();
 
                 if let Some(dma_handler) = dev.dma_handler() {
                     if !iommu_attached {
@@ -4112,6 +5729,14 @@ impl DeviceManager {
         self.bus_devices
             .retain(|dev| !Arc::ptr_eq(dev, &bus_device));
 
+        self.fs_devices.remove(&id);
+        self.virtiofs_daemons.remove(&id);
+        if id == CONSOLE_DEVICE_NAME {
+            self.console_device = None;
+            self.console_pty = None;
+            self.console_resize_pipe = None;
+        }
+
         // Shutdown and remove the underlying virtio-device if present
         if let Some(virtio_device) = virtio_device {
             for mapping in virtio_device.lock().unwrap().userspace_mappings() {
@@ -4171,6 +5796,8 @@ impl DeviceManager {
             handle.id.clone(),
             handle.pci_segment,
             handle.dma_handler,
+            handle.pci_root_port.as_deref(),
+            handle.pci_ids,
         )?;
 
         // Update the PCIU bitmap
@@ -4202,6 +5829,14 @@ impl DeviceManager {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
+        if disk_cfg.pci_root_port.is_some() {
+            return Err(DeviceManagerError::PciRootPortHotplug);
+        }
+
+        if disk_cfg.mmio {
+            return Err(DeviceManagerError::MmioDeviceHotplug);
+        }
+
         let device = self.make_virtio_block_device(disk_cfg)?;
         self.hotplug_virtio_pci_device(device)
     }
@@ -4210,7 +5845,56 @@ impl DeviceManager {
         self.validate_identifier(&fs_cfg.id)?;
 
         let device = self.make_virtio_fs_device(fs_cfg)?;
-        self.hotplug_virtio_pci_device(device)
+        let virtio_device = Arc::clone(&device.virtio_device);
+        let id = device.id.clone();
+
+        match self.hotplug_virtio_pci_device(device) {
+            Ok(info) => Ok(info),
+            Err(e) => {
+                // The DAX window has been allocated and mapped along with the
+                // device, and would be leaked otherwise.
+                self.release_virtio_fs_device(&id, fs_cfg.pci_segment, &virtio_device);
+                Err(e)
+            }
+        }
+    }
+
+    fn release_virtio_fs_device(
+        &mut self,
+        id: &str,
+        pci_segment: u16,
+        virtio_device: &Arc<Mutex<dyn virtio_devices::VirtioDevice>>,
+    ) {
+        let device = virtio_device.lock().unwrap();
+        for mapping in device.userspace_mappings() {
+            if let Err(e) = self
+                .memory_manager
+                .lock()
+                .unwrap()
+                .remove_userspace_mapping(
+                    mapping.addr.raw_value(),
+                    mapping.len,
+                    mapping.host_addr,
+                    mapping.mergeable,
+                    mapping.mem_slot,
+                )
+            {
+                error!("Cannot remove the DAX window of {}: {:?}", id, e);
+            }
+        }
+        if let Some(shm_list) = device.get_shm_regions() {
+            self.pci_segments[pci_segment as usize]
+                .allocator
+                .lock()
+                .unwrap()
+                .free(shm_list.addr, shm_list.len);
+        }
+        drop(device);
+
+        self.virtio_devices.retain(|handle| handle.id != id);
+        self.fs_devices.remove(id);
+        self.virtiofs_daemons.remove(id);
+        self.device_tree.lock().unwrap().remove(id);
     }
 
     pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<PciDeviceInfo> {
@@ -4231,6 +5915,14 @@ impl DeviceManager {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
+        if net_cfg.pci_root_port.is_some() {
+            return Err(DeviceManagerError::PciRootPortHotplug);
+        }
+
+        if net_cfg.mmio {
+            return Err(DeviceManagerError::MmioDeviceHotplug);
+        }
+
         let device = self.make_virtio_net_device(net_cfg)?;
         self.hotplug_virtio_pci_device(device)
     }
@@ -4253,10 +5945,53 @@ impl DeviceManager {
             return Err(DeviceManagerError::InvalidIommuHotplug);
         }
 
+        if vsock_cfg.mmio {
+            return Err(DeviceManagerError::MmioDeviceHotplug);
+        }
+
         let device = self.make_virtio_vsock_device(vsock_cfg)?;
         self.hotplug_virtio_pci_device(device)
     }
 
+    pub fn virtqueue_states(&self, id: &str) -> DeviceManagerResult<Vec<VirtqueueState>> {
+        // As when removing a device, the 'id' refers either to the transport
+        // node or to the virtio device behind it.
+        let device_tree = self.device_tree.lock().unwrap();
+        let node = device_tree
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownDeviceId(id.to_owned()))?;
+
+        let transport_id =
+            if node.pci_device_handle.is_some() || self.virtio_mmio_devices.contains_key(id) {
+                id
+            } else {
+                node.parent
+                    .as_deref()
+                    .ok_or(DeviceManagerError::MissingNode)?
+            };
+
+        if let Some(virtio_mmio_device) = self.virtio_mmio_devices.get(transport_id) {
+            return virtio_mmio_device
+                .lock()
+                .unwrap()
+                .virtqueue_states()
+                .map_err(DeviceManagerError::VirtqueueState);
+        }
+
+        let pci_device_node = device_tree
+            .get(transport_id)
+            .ok_or(DeviceManagerError::MissingNode)?;
+
+        match pci_device_node.pci_device_handle.as_ref() {
+            Some(PciDeviceHandle::Virtio(virtio_pci_device)) => virtio_pci_device
+                .lock()
+                .unwrap()
+                .virtqueue_states()
+                .map_err(DeviceManagerError::VirtqueueState),
+            _ => Err(DeviceManagerError::NotVirtioDevice(id.to_owned())),
+        }
+    }
+
     pub fn counters(&self) -> HashMap<String, HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -4283,6 +6018,127 @@ impl DeviceManager {
         Err(DeviceManagerError::MissingVirtioBalloon)
     }
 
+    pub fn report_free_pages(&mut self) -> DeviceManagerResult<()> {
+        if let Some(balloon) = &self.balloon {
+            return balloon
+                .lock()
+                .unwrap()
+                .report_free_pages()
+                .map_err(DeviceManagerError::VirtioBalloonReportFreePages);
+        }
+
+        warn!("No balloon setup: Can't report the free pages");
+        Err(DeviceManagerError::MissingVirtioBalloon)
+    }
+
+    pub fn resize_fs(&mut self, id: &str, desired_size: u64) -> DeviceManagerResult<()> {
+        let fs = self
+            .fs_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::MissingVirtioFs(id.to_owned()))?;
+
+        {
+            let mut fs = fs.lock().unwrap();
+            let old_mappings = fs.userspace_mappings();
+            if fs
+                .resize_cache(desired_size)
+                .map_err(DeviceManagerError::VirtioFsResize)?
+            {
+                self.update_fs_cache_mapping(&old_mappings, &fs)?;
+            }
+        }
+
+        // Advertise the new window through the shared memory capability, even
+        // if it's only applied after the guest resets the device, since this
+        // is what the guest driver will rely on when probing it again.
+        let device_tree = self.device_tree.lock().unwrap();
+        let transport_id = device_tree
+            .get(id)
+            .and_then(|node| node.parent.as_ref())
+            .ok_or(DeviceManagerError::MissingNode)?;
+        if let Some(PciDeviceHandle::Virtio(virtio_pci_device)) = device_tree
+            .get(transport_id)
+            .and_then(|node| node.pci_device_handle.as_ref())
+        {
+            virtio_pci_device
+                .lock()
+                .unwrap()
+                .update_shm_capabilities()
+                .map_err(DeviceManagerError::UpdateShmCapabilities)?;
+        }
+
+        Ok(())
+    }
+
+    // Resize the memory slot backing the DAX window of a virtio-fs device
+    // after the window changed from `old_mappings`.
+    fn update_fs_cache_mapping(
+        &self,
+        old_mappings: &[virtio_devices::UserspaceMapping],
+        fs: &virtio_devices::vhost_user::Fs,
+    ) -> DeviceManagerResult<()> {
+        // A memory slot can't be resized in place, it's removed and inserted
+        // again with the same identifier so that the device keeps track of it.
+        for mapping in old_mappings {
+            let mem_region = self.vm.make_user_memory_region(
+                mapping.mem_slot,
+                mapping.addr.raw_value(),
+                mapping.len,
+                mapping.host_addr,
+                false,
+                false,
+            );
+            self.vm
+                .remove_user_memory_region(mem_region)
+                .map_err(DeviceManagerError::UpdateVirtioFsCacheMapping)?;
+        }
+
+        for mapping in fs.userspace_mappings() {
+            let mem_region = self.vm.make_user_memory_region(
+                mapping.mem_slot,
+                mapping.addr.raw_value(),
+                mapping.len,
+                mapping.host_addr,
+                false,
+                false,
+            );
+            self.vm
+                .create_user_memory_region(mem_region)
+                .map_err(DeviceManagerError::UpdateVirtioFsCacheMapping)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_console_port(
+        &mut self,
+        port_cfg: &mut ConsolePortConfig,
+    ) -> DeviceManagerResult<()> {
+        self.validate_identifier(&port_cfg.id)?;
+        self.plug_console_port(port_cfg).map(|_| ())
+    }
+
+    pub fn console_port_ids(&self) -> BTreeMap<u32, String> {
+        self.console_device
+            .as_ref()
+            .map(|console| console.lock().unwrap().port_ids())
+            .unwrap_or_default()
+    }
+
+    pub fn remove_console_port(&mut self, id: &str) -> DeviceManagerResult<()> {
+        self.console_device
+            .as_ref()
+            .ok_or(DeviceManagerError::MissingVirtioConsole)?
+            .lock()
+            .unwrap()
+            .remove_port(id)
+            .map_err(DeviceManagerError::RemoveConsolePort)?;
+
+        event!("vm", "console-port-removed", "id", id);
+
+        Ok(())
+    }
+
     pub fn balloon_size(&self) -> u64 {
         if let Some(balloon) = &self.balloon {
             return balloon.lock().unwrap().get_actual();
@@ -4483,7 +6339,7 @@ impl Aml for DeviceManager {
             mbrd_memory.push(aml::Memory32Fixed::new(
                 true,
                 segment.mmio_config_address as u32,
-                layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT as u32,
+                segment.mmio_config_size() as u32,
             ))
         }
 
@@ -4549,6 +6405,54 @@ impl Aml for DeviceManager {
             .to_aml_bytes(sink);
         }
 
+        // Debug console, described for the guest to find it, and to keep
+        // its MMIO page for itself on aarch64.
+        #[cfg(target_arch = "aarch64")]
+        if self
+            .get_device_info()
+            .contains_key(&(DeviceType::DebugConsole, "debug_console".to_string()))
+        {
+            aml::Device::new(
+                "_SB_.DBGC".into(),
+                vec![
+                    &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C02")),
+                    &aml::Name::new("_UID".into(), &aml::ONE),
+                    &aml::Name::new("_DDN".into(), &"Debug console"),
+                    &aml::Name::new(
+                        "_CRS".into(),
+                        &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
+                            true,
+                            arch::layout::LEGACY_DEBUG_CONSOLE_MAPPED_IO_START.raw_value() as u32,
+                            MMIO_LEN as u32,
+                        )]),
+                    ),
+                ],
+            )
+            .to_aml_bytes(sink);
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map_or(false, |platform| platform.s3)
+        {
+            aml::Name::new("_S3_".into(), &aml::Package::new(vec![&3u8])).to_aml_bytes(sink);
+        }
+        #[cfg(target_arch = "x86_64")]
+        if self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .map_or(false, |platform| platform.s4)
+        {
+            aml::Name::new("_S4_".into(), &aml::Package::new(vec![&4u8])).to_aml_bytes(sink);
+        }
         aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes(sink);
 
         aml::Device::new(
@@ -4565,6 +6469,10 @@ impl Aml for DeviceManager {
             TpmDevice {}.to_aml_bytes(sink);
         }
 
+        if let Some(ghes_device) = &self.ghes_device {
+            ghes_device.lock().unwrap().to_aml_bytes(sink);
+        }
+
         self.ged_notification_device
             .as_ref()
             .unwrap()
@@ -0,0 +1,757 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Embedding the VMM into another Rust program.
+//!
+//! The items of this module are the stable API of the crate, following
+//! semantic versioning, for other projects to run VMs without spawning the
+//! `cloud-hypervisor` binary. The rest of the crate is shared with the binary
+//! and may change from one release to the next, so none of its types are
+//! part of this API: the devices are described with the options of the
+//! command line of the binary, and the results of the requests are returned
+//! as the types of this module.
+//!
+//! A [`VmmBuilder`] starts the VMM threads in the current process, returning
+//! an [`EmbeddedVmm`] to create, boot and control the VM. The VM configuration
+//! is built by a [`VmConfigBuilder`], and the events reported by the VMM are
+//! received from [`Events`].
+//!
+//! ```no_run
+//! use vmm::embed::{VmConfigBuilder, VmmBuilder};
+//!
+//! let mut vmm = VmmBuilder::new().build().unwrap();
+//! let mut events = vmm.events().unwrap();
+//!
+//! let config = VmConfigBuilder::new()
+//!     .kernel("/path/to/vmlinux")
+//!     .cmdline("console=hvc0 root=/dev/vda1")
+//!     .disk_path("/path/to/rootfs.raw")
+//!     .net("tap=tap0,mac=12:34:56:78:90:ab")
+//!     .build()
+//!     .unwrap();
+//! vmm.create(config).unwrap();
+//! vmm.boot().unwrap();
+//!
+//! for event in events.by_ref() {
+//!     if event.source == "vm" && event.event == "shutdown" {
+//!         break;
+//!     }
+//! }
+//! vmm.shutdown().unwrap();
+//! ```
+//!
+//! Only one VMM can be embedded per process, as the VMM reports its events
+//! and handles `SIGTERM` and `SIGINT` process wide. Like the binary, the
+//! embedding program should block the [`Vmm::HANDLED_SIGNALS`] and the
+//! [`Vm::HANDLED_SIGNALS`] before building the VMM, for them to only be
+//! handled by the VMM threads.
+//!
+//! [`Vmm::HANDLED_SIGNALS`]: crate::Vmm::HANDLED_SIGNALS
+//! [`Vm::HANDLED_SIGNALS`]: crate::vm::Vm::HANDLED_SIGNALS
+
+use crate::api::{self, ApiError, ApiRequest};
+use crate::config::{
+    self, ConsoleConfig, ConsoleOutputMode, CpusConfig, DiskConfig, FsConfig, MemoryConfig,
+    NetConfig, PayloadConfig, PlatformConfig, RestoreConfig, VsockConfig,
+};
+use crate::{VmmThreadHandle, VmmVersionInfo};
+use micro_http::Body;
+use seccompiler::SeccompAction;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+static EMBEDDED: AtomicBool = AtomicBool::new(false);
+
+/// Errors of the embedded VMM.
+///
+/// The errors of the rest of the crate are only carried as their message,
+/// for them not to be part of the stable API.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("A VMM is already embedded in this process")]
+    AlreadyEmbedded,
+
+    #[error("Cannot create the hypervisor: {0}")]
+    Hypervisor(String),
+
+    #[error("Cannot create an event file descriptor: {0}")]
+    EventFd(#[source] std::io::Error),
+
+    #[error("Cannot set up the event monitor: {0}")]
+    EventMonitor(#[source] std::io::Error),
+
+    #[error("Cannot start the VMM: {0}")]
+    Start(String),
+
+    #[error("The VMM failed: {0}")]
+    Vmm(String),
+
+    #[error("The VMM thread panicked")]
+    VmmThreadPanicked,
+
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
+    #[error("The VM configuration is invalid: {0}")]
+    InvalidConfig(String),
+
+    #[error("The request failed: {0}")]
+    Request(String),
+
+    #[error("The response is invalid: {0}")]
+    InvalidResponse(#[source] serde_json::Error),
+}
+
+impl From<ApiError> for Error {
+    fn from(e: ApiError) -> Self {
+        Error::Request(format!("{e:?}"))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Configuration of a VM, as built by a [`VmConfigBuilder`].
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    config: config::VmConfig,
+}
+
+/// Builder of the configuration of a VM.
+///
+/// The configuration starts from the defaults of the `cloud-hypervisor`
+/// binary, without any console attached to the terminal of the embedding
+/// program, and is validated once built. Beyond the dedicated methods, the
+/// devices are described with the options of the matching command line
+/// parameter of the binary, such as `"path=/path/to/disk.raw,readonly=on"`
+/// for a `--disk`, the first invalid one being reported by
+/// [`VmConfigBuilder::build`].
+pub struct VmConfigBuilder {
+    config: config::VmConfig,
+    error: Option<Error>,
+}
+
+impl Default for VmConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmConfigBuilder {
+    pub fn new() -> Self {
+        let mut config = config::VmConfig::default();
+        config.console.mode = ConsoleOutputMode::Off;
+
+        VmConfigBuilder {
+            config,
+            error: None,
+        }
+    }
+
+    fn payload(&mut self) -> &mut PayloadConfig {
+        self.config.payload.get_or_insert_with(|| PayloadConfig {
+            firmware: None,
+            kernel: None,
+            cmdline: None,
+            initramfs: None,
+            firmware_fd: None,
+            kernel_fd: None,
+            initramfs_fd: None,
+            #[cfg(feature = "sev_snp")]
+            igvm: None,
+            #[cfg(feature = "sev_snp")]
+            host_data: None,
+        })
+    }
+
+    fn option<T, F: FnOnce(&mut config::VmConfig, T)>(
+        mut self,
+        option: std::result::Result<T, config::Error>,
+        f: F,
+    ) -> Self {
+        match option {
+            Ok(option) => f(&mut self.config, option),
+            Err(e) => {
+                self.error
+                    .get_or_insert_with(|| Error::InvalidOption(e.to_string()));
+            }
+        }
+        self
+    }
+
+    /// vCPUs of the VM, with the options of `--cpus`.
+    pub fn cpus(self, options: &str) -> Self {
+        self.option(CpusConfig::parse(options), |config, cpus| {
+            config.cpus = cpus
+        })
+    }
+
+    /// Number of vCPUs the VM boots with, and can't grow beyond.
+    pub fn vcpus(mut self, vcpus: u8) -> Self {
+        self.config.cpus.boot_vcpus = vcpus;
+        self.config.cpus.max_vcpus = vcpus;
+        self
+    }
+
+    /// Memory of the VM, with the options of `--memory`.
+    pub fn memory(self, options: &str) -> Self {
+        self.option(MemoryConfig::parse(options, None), |config, memory| {
+            config.memory = memory
+        })
+    }
+
+    /// Size of the memory of the VM, in bytes.
+    pub fn memory_size(mut self, size: u64) -> Self {
+        self.config.memory.size = size;
+        self
+    }
+
+    pub fn kernel<P: Into<PathBuf>>(mut self, kernel: P) -> Self {
+        self.payload().kernel = Some(kernel.into());
+        self
+    }
+
+    pub fn firmware<P: Into<PathBuf>>(mut self, firmware: P) -> Self {
+        self.payload().firmware = Some(firmware.into());
+        self
+    }
+
+    pub fn initramfs<P: Into<PathBuf>>(mut self, initramfs: P) -> Self {
+        self.payload().initramfs = Some(initramfs.into());
+        self
+    }
+
+    pub fn cmdline<S: Into<String>>(mut self, cmdline: S) -> Self {
+        self.payload().cmdline = Some(cmdline.into());
+        self
+    }
+
+    /// Disk with the options of `--disk`.
+    pub fn disk(self, options: &str) -> Self {
+        self.option(DiskConfig::parse(options), |config, disk| {
+            config.disks.get_or_insert_with(Vec::new).push(disk)
+        })
+    }
+
+    /// Disk image at the given path, with the default options.
+    pub fn disk_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.config
+            .disks
+            .get_or_insert_with(Vec::new)
+            .push(DiskConfig {
+                path: Some(path.into()),
+                ..Default::default()
+            });
+        self
+    }
+
+    /// Network interface with the options of `--net`.
+    pub fn net(self, options: &str) -> Self {
+        self.option(NetConfig::parse(options), |config, net| {
+            config.net.get_or_insert_with(Vec::new).push(net)
+        })
+    }
+
+    /// Shared directory with the options of `--fs`.
+    pub fn fs(self, options: &str) -> Self {
+        self.option(FsConfig::parse(options), |config, fs| {
+            config.fs.get_or_insert_with(Vec::new).push(fs)
+        })
+    }
+
+    /// vsock device with the options of `--vsock`.
+    pub fn vsock(self, options: &str) -> Self {
+        self.option(VsockConfig::parse(options), |config, vsock| {
+            config.vsock = Some(vsock)
+        })
+    }
+
+    /// Serial port with the options of `--serial`.
+    pub fn serial(self, options: &str) -> Self {
+        self.option(ConsoleConfig::parse(options), |config, serial| {
+            config.serial = serial
+        })
+    }
+
+    /// virtio-console device with the options of `--console`.
+    pub fn console(self, options: &str) -> Self {
+        self.option(ConsoleConfig::parse(options), |config, console| {
+            config.console = console
+        })
+    }
+
+    /// Platform of the VM with the options of `--platform`.
+    pub fn platform(self, options: &str) -> Self {
+        self.option(PlatformConfig::parse(options), |config, platform| {
+            config.platform = Some(platform)
+        })
+    }
+
+    pub fn build(mut self) -> Result<VmConfig> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.config
+            .validate()
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+
+        Ok(VmConfig {
+            config: self.config,
+        })
+    }
+}
+
+/// State of the VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VmState {
+    Created,
+    Running,
+    Shutdown,
+    Paused,
+    BreakPoint,
+}
+
+impl From<crate::vm::VmState> for VmState {
+    fn from(state: crate::vm::VmState) -> Self {
+        match state {
+            crate::vm::VmState::Created => VmState::Created,
+            crate::vm::VmState::Running => VmState::Running,
+            crate::vm::VmState::Shutdown => VmState::Shutdown,
+            crate::vm::VmState::Paused => VmState::Paused,
+            crate::vm::VmState::BreakPoint => VmState::BreakPoint,
+        }
+    }
+}
+
+/// Information about the VM.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VmInfo {
+    pub state: VmState,
+    /// Size of the memory of the VM, in bytes, minus the memory taken back
+    /// by the balloon
+    pub memory_actual_size: u64,
+}
+
+/// Device hotplugged into the VM.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DeviceInfo {
+    pub id: String,
+    /// PCI address of the device, as `segment:bus:device.function`
+    pub bdf: String,
+}
+
+/// Version and features of the VMM.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct VersionInfo {
+    pub build_version: String,
+    pub version: String,
+    pub pid: i64,
+    pub features: Vec<String>,
+}
+
+/// Event reported by the VMM, as written to the `--event-monitor` file.
+#[derive(Clone, Debug, Deserialize)]
+#[non_exhaustive]
+pub struct Event {
+    /// Time since the VMM started
+    pub timestamp: Duration,
+    /// Part of the VMM reporting the event, such as `vm` or `vmm`
+    pub source: String,
+    pub event: String,
+    #[serde(default)]
+    pub properties: Option<HashMap<String, String>>,
+}
+
+/// Receiver of the events reported by the VMM, in order.
+pub struct Events {
+    rx: flume::Receiver<Arc<String>>,
+}
+
+impl Events {
+    fn parse(event: Arc<String>) -> Option<Event> {
+        serde_json::from_str(&event).ok()
+    }
+
+    /// Wait for the next event, `None` once the VMM is gone.
+    pub fn recv(&self) -> Option<Event> {
+        loop {
+            let event = self.rx.recv().ok()?;
+            if let Some(event) = Self::parse(event) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Wait for the next event up to the given timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let event = self.rx.recv_deadline(deadline).ok()?;
+            if let Some(event) = Self::parse(event) {
+                return Some(event);
+            }
+        }
+    }
+
+    /// Next event if one was already reported.
+    pub fn try_recv(&self) -> Option<Event> {
+        while let Ok(event) = self.rx.try_recv() {
+            if let Some(event) = Self::parse(event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv()
+    }
+}
+
+/// Builder of the VMM embedded in the current process.
+pub struct VmmBuilder {
+    seccomp_action: SeccompAction,
+    api_socket: Option<String>,
+    version: VmmVersionInfo,
+}
+
+impl Default for VmmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmmBuilder {
+    pub fn new() -> Self {
+        VmmBuilder {
+            seccomp_action: SeccompAction::Trap,
+            api_socket: None,
+            version: VmmVersionInfo::new(env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    /// Action taken on the system calls the seccomp filters of the VMM
+    /// threads don't allow, `SeccompAction::Allow` disabling the filters.
+    pub fn seccomp(mut self, action: SeccompAction) -> Self {
+        self.seccomp_action = action;
+        self
+    }
+
+    /// Also serve the HTTP API on the given UNIX socket, for the usual tools
+    /// to inspect the VM.
+    pub fn api_socket<S: Into<String>>(mut self, path: S) -> Self {
+        self.api_socket = Some(path.into());
+        self
+    }
+
+    /// Version reported by the VMM, defaults to the version of the crate.
+    pub fn version(mut self, build_version: &str, version: &str) -> Self {
+        self.version = VmmVersionInfo::new(build_version, version);
+        self
+    }
+
+    pub fn build(self) -> Result<EmbeddedVmm> {
+        if EMBEDDED.swap(true, Ordering::SeqCst) {
+            return Err(Error::AlreadyEmbedded);
+        }
+
+        let hypervisor = hypervisor::new().map_err(|e| Error::Hypervisor(e.to_string()))?;
+        let api_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        #[cfg(feature = "guest_debug")]
+        let debug_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        #[cfg(feature = "guest_debug")]
+        let vm_debug_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let (api_sender, api_receiver) = channel();
+
+        let mut monitor = event_monitor::set_monitor(None).map_err(Error::EventMonitor)?;
+        let events = monitor.subscribe();
+        crate::start_event_monitor_thread(
+            monitor,
+            &self.seccomp_action,
+            hypervisor.hypervisor_type(),
+            exit_evt.try_clone().map_err(Error::EventFd)?,
+        )
+        .map_err(|e| Error::Start(e.to_string()))?;
+
+        let thread = crate::start_vmm_thread(
+            self.version,
+            &self.api_socket,
+            None,
+            #[cfg(feature = "dbus_api")]
+            None,
+            #[cfg(feature = "tls_api")]
+            None,
+            api_evt.try_clone().map_err(Error::EventFd)?,
+            api_sender.clone(),
+            api_receiver,
+            #[cfg(feature = "guest_debug")]
+            None,
+            #[cfg(feature = "guest_debug")]
+            debug_evt,
+            #[cfg(feature = "guest_debug")]
+            vm_debug_evt,
+            exit_evt,
+            &self.seccomp_action,
+            hypervisor,
+        )
+        .map_err(|e| Error::Start(e.to_string()))?;
+
+        Ok(EmbeddedVmm {
+            api_evt,
+            api_sender,
+            thread: Some(thread),
+            events: Some(Events { rx: events }),
+        })
+    }
+}
+
+/// VMM running in the current process.
+///
+/// Each method is a request to the VMM, returning once it completed. The VMM
+/// is shut down when dropped, along with its VM.
+pub struct EmbeddedVmm {
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    thread: Option<VmmThreadHandle>,
+    events: Option<Events>,
+}
+
+impl EmbeddedVmm {
+    fn api_evt(&self) -> Result<EventFd> {
+        self.api_evt.try_clone().map_err(Error::EventFd)
+    }
+
+    fn action(
+        &self,
+        f: fn(EventFd, Sender<ApiRequest>) -> api::ApiResult<Option<Body>>,
+    ) -> Result<()> {
+        f(self.api_evt()?, self.api_sender.clone())?;
+        Ok(())
+    }
+
+    fn device_info(body: Option<Body>) -> Result<Option<DeviceInfo>> {
+        body.map(|body| {
+            let info: crate::PciDeviceInfo =
+                serde_json::from_slice(body.raw()).map_err(Error::InvalidResponse)?;
+            Ok(DeviceInfo {
+                id: info.id,
+                bdf: info.bdf.to_string(),
+            })
+        })
+        .transpose()
+    }
+
+    /// Events reported by the VMM from the time it was built. They can only
+    /// be taken once.
+    pub fn events(&mut self) -> Option<Events> {
+        self.events.take()
+    }
+
+    pub fn ping(&self) -> Result<VersionInfo> {
+        let ping = api::vmm_ping(self.api_evt()?, self.api_sender.clone())?;
+        Ok(VersionInfo {
+            build_version: ping.build_version,
+            version: ping.version,
+            pid: ping.pid,
+            features: ping.features,
+        })
+    }
+
+    pub fn create(&self, config: VmConfig) -> Result<()> {
+        api::vm_create(
+            self.api_evt()?,
+            self.api_sender.clone(),
+            Arc::new(Mutex::new(config.config)),
+        )?;
+        Ok(())
+    }
+
+    pub fn boot(&self) -> Result<()> {
+        self.action(api::vm_boot)
+    }
+
+    pub fn pause(&self) -> Result<()> {
+        self.action(api::vm_pause)
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.action(api::vm_resume)
+    }
+
+    pub fn reboot(&self) -> Result<()> {
+        self.action(api::vm_reboot)
+    }
+
+    pub fn power_button(&self) -> Result<()> {
+        self.action(api::vm_power_button)
+    }
+
+    /// Shut the VM down, keeping its configuration for it to be booted again.
+    pub fn shutdown_vm(&self) -> Result<()> {
+        self.action(api::vm_shutdown)
+    }
+
+    /// Shut the VM down and drop its configuration.
+    pub fn delete(&self) -> Result<()> {
+        self.action(api::vm_delete)
+    }
+
+    pub fn info(&self) -> Result<VmInfo> {
+        let info = api::vm_info(self.api_evt()?, self.api_sender.clone())?;
+        Ok(VmInfo {
+            state: info.state.into(),
+            memory_actual_size: info.memory_actual_size,
+        })
+    }
+
+    /// Hotplug a disk with the options of `--disk`.
+    pub fn add_disk(&self, options: &str) -> Result<Option<DeviceInfo>> {
+        let disk = DiskConfig::parse(options).map_err(|e| Error::InvalidOption(e.to_string()))?;
+        let body = api::vm_add_disk(self.api_evt()?, self.api_sender.clone(), Arc::new(disk))?;
+        Self::device_info(body)
+    }
+
+    /// Hotplug a network interface with the options of `--net`.
+    pub fn add_net(&self, options: &str) -> Result<Option<DeviceInfo>> {
+        let net = NetConfig::parse(options).map_err(|e| Error::InvalidOption(e.to_string()))?;
+        let body = api::vm_add_net(self.api_evt()?, self.api_sender.clone(), Arc::new(net))?;
+        Self::device_info(body)
+    }
+
+    pub fn remove_device(&self, id: &str) -> Result<()> {
+        api::vm_remove_device(
+            self.api_evt()?,
+            self.api_sender.clone(),
+            Arc::new(api::VmRemoveDeviceData { id: id.to_owned() }),
+        )?;
+        Ok(())
+    }
+
+    /// Snapshot the paused VM into the given directory.
+    pub fn snapshot(&self, destination_url: &str) -> Result<()> {
+        api::vm_snapshot(
+            self.api_evt()?,
+            self.api_sender.clone(),
+            Arc::new(api::VmSnapshotConfig {
+                destination_url: destination_url.to_owned(),
+            }),
+        )?;
+        Ok(())
+    }
+
+    /// Restore a VM from a snapshot, with the options of `--restore`.
+    pub fn restore(&self, options: &str) -> Result<()> {
+        let config =
+            RestoreConfig::parse(options).map_err(|e| Error::InvalidOption(e.to_string()))?;
+        api::vm_restore(self.api_evt()?, self.api_sender.clone(), Arc::new(config))?;
+        Ok(())
+    }
+
+    /// Shut the VMM down, along with its VM, and wait for its threads.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let Some(thread) = self.thread.take() else {
+            return Ok(());
+        };
+
+        // The VMM may already be gone after a failure
+        api::vmm_shutdown(self.api_evt()?, self.api_sender.clone()).ok();
+
+        thread
+            .thread_handle
+            .join()
+            .map_err(|_| Error::VmmThreadPanicked)?
+            .map_err(|e| Error::Vmm(e.to_string()))
+    }
+}
+
+impl Drop for EmbeddedVmm {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Error shutting down the embedded VMM: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vm_config_builder() {
+        assert!(matches!(
+            VmConfigBuilder::new().build(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            VmConfigBuilder::new()
+                .kernel("/path/to/vmlinux")
+                .disk("readonly=maybe")
+                .net("mac=00:00")
+                .build(),
+            Err(Error::InvalidOption(e)) if e.contains("--disk")
+        ));
+
+        let config = VmConfigBuilder::new()
+            .vcpus(2)
+            .memory_size(1 << 30)
+            .kernel("/path/to/vmlinux")
+            .cmdline("console=hvc0")
+            .disk_path("/path/to/rootfs.raw")
+            .disk("path=/path/to/data.raw,readonly=on")
+            .net("tap=tap0,mac=12:34:56:78:90:ab")
+            .serial("socket=/path/to/serial.sock")
+            .build()
+            .unwrap()
+            .config;
+
+        assert_eq!(config.cpus.boot_vcpus, 2);
+        assert_eq!(config.cpus.max_vcpus, 2);
+        assert_eq!(config.memory.size, 1 << 30);
+        assert_eq!(
+            config.payload.as_ref().unwrap().kernel,
+            Some(PathBuf::from("/path/to/vmlinux"))
+        );
+        let disks = config.disks.as_ref().unwrap();
+        assert_eq!(disks[0].path, Some(PathBuf::from("/path/to/rootfs.raw")));
+        assert!(!disks[0].readonly);
+        assert_eq!(disks[1].path, Some(PathBuf::from("/path/to/data.raw")));
+        assert!(disks[1].readonly);
+        assert_eq!(
+            config.net.as_ref().unwrap()[0].tap,
+            Some("tap0".to_string())
+        );
+        assert_eq!(config.serial.mode, ConsoleOutputMode::Socket);
+        assert_eq!(config.console.mode, ConsoleOutputMode::Off);
+    }
+
+    #[test]
+    fn test_vm_state() {
+        assert_eq!(VmState::from(crate::vm::VmState::Running), VmState::Running);
+        assert_eq!(
+            VmState::from(crate::vm::VmState::BreakPoint),
+            VmState::BreakPoint
+        );
+    }
+}
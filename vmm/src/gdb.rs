@@ -20,7 +20,11 @@ use gdbstub::{
                 },
                 BaseOps,
             },
-            breakpoints::{Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps},
+            breakpoints::{
+                Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps, HwWatchpoint,
+                HwWatchpointOps, WatchKind,
+            },
+            thread_extra_info::{ThreadExtraInfo, ThreadExtraInfoOps},
         },
         Target, TargetError, TargetResult,
     },
@@ -33,6 +37,7 @@ use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use gdbstub_arch::x86::reg::X86_64CoreRegs as CoreRegs;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::{DebugExit, Watchpoint, WatchpointKind};
 use std::{os::unix::net::UnixListener, sync::mpsc};
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryError};
 
@@ -56,8 +61,10 @@ pub trait Debuggable: vm_migration::Pausable {
         &self,
         cpu_id: usize,
         addrs: &[GuestAddress],
+        watchpoints: &[Watchpoint],
         singlestep: bool,
     ) -> Result<(), DebuggableError>;
+    fn take_debug_exit(&self, cpu_id: usize) -> Option<DebugExit>;
     fn debug_pause(&mut self) -> std::result::Result<(), DebuggableError>;
     fn debug_resume(&mut self) -> std::result::Result<(), DebuggableError>;
     fn read_regs(&self, cpu_id: usize) -> std::result::Result<CoreRegs, DebuggableError>;
@@ -90,6 +97,7 @@ pub enum Error {
     GdbResponseNotify(std::io::Error),
     GdbResponse(mpsc::RecvError),
     GdbResponseTimeout(mpsc::RecvTimeoutError),
+    UnexpectedResponse(GdbResponsePayload),
 }
 type GdbResult<T> = std::result::Result<T, Error>;
 
@@ -108,8 +116,12 @@ pub enum GdbRequestPayload {
     WriteMem(GuestAddress, Vec<u8>),
     Pause,
     Resume,
-    SetSingleStep(bool),
-    SetHwBreakPoint(Vec<GuestAddress>),
+    SetGuestDebug {
+        hw_breakpoints: Vec<GuestAddress>,
+        hw_watchpoints: Vec<Watchpoint>,
+        single_step: bool,
+    },
+    TakeDebugExit,
     ActiveVcpus,
 }
 
@@ -121,6 +133,7 @@ pub enum GdbResponsePayload {
     RegValues(Box<CoreRegs>),
     MemoryRegion(Vec<u8>),
     ActiveVcpus(usize),
+    DebugExit(Option<DebugExit>),
 }
 
 pub struct GdbStub {
@@ -128,7 +141,11 @@ pub struct GdbStub {
     gdb_event: vmm_sys_util::eventfd::EventFd,
     vm_event: vmm_sys_util::eventfd::EventFd,
     hw_breakpoints: Vec<GuestAddress>,
-    single_step: bool,
+    hw_watchpoints: Vec<Watchpoint>,
+    max_hw_breakpoints: usize,
+    max_hw_watchpoints: usize,
+    // vCPU to single step when resuming
+    single_step: Option<usize>,
 }
 
 impl GdbStub {
@@ -137,13 +154,17 @@ impl GdbStub {
         gdb_event: vmm_sys_util::eventfd::EventFd,
         vm_event: vmm_sys_util::eventfd::EventFd,
         hw_breakpoints: usize,
+        hw_watchpoints: usize,
     ) -> Self {
         Self {
             gdb_sender,
             gdb_event,
             vm_event,
             hw_breakpoints: Vec::with_capacity(hw_breakpoints),
-            single_step: false,
+            hw_watchpoints: Vec::with_capacity(hw_watchpoints),
+            max_hw_breakpoints: hw_breakpoints,
+            max_hw_watchpoints: hw_watchpoints,
+            single_step: None,
         }
     }
 
@@ -165,6 +186,117 @@ impl GdbStub {
         let res = response_receiver.recv().map_err(Error::GdbResponse)??;
         Ok(res)
     }
+
+    fn active_vcpus(&self) -> GdbResult<usize> {
+        match self.vm_request(GdbRequestPayload::ActiveVcpus, 0)? {
+            GdbResponsePayload::ActiveVcpus(active_vcpus) => Ok(active_vcpus),
+            r => Err(Error::UnexpectedResponse(r)),
+        }
+    }
+
+    // Program the breakpoints and watchpoints on all the vCPUs, only enabling
+    // the single step on the vCPU being stepped.
+    fn set_guest_debug(&self) -> GdbResult<()> {
+        for cpu_id in 0..self.active_vcpus()? {
+            let payload = GdbRequestPayload::SetGuestDebug {
+                hw_breakpoints: self.hw_breakpoints.clone(),
+                hw_watchpoints: self.hw_watchpoints.clone(),
+                single_step: self.single_step == Some(cpu_id),
+            };
+            self.vm_request(payload, cpu_id)?;
+        }
+        Ok(())
+    }
+
+    fn hw_breakpoint_available(&self) -> bool {
+        let used = self.hw_breakpoints.len();
+        // The debug registers are shared with the watchpoints
+        #[cfg(target_arch = "x86_64")]
+        let used = used + self.hw_watchpoints.len();
+        used < self.max_hw_breakpoints
+    }
+
+    fn hw_watchpoint_available(&self) -> bool {
+        let used = self.hw_watchpoints.len();
+        // The debug registers are shared with the breakpoints
+        #[cfg(target_arch = "x86_64")]
+        let used = used + self.hw_breakpoints.len();
+        used < self.max_hw_watchpoints
+    }
+
+    // Find the watchpoint which was triggered, from what the vCPU reported
+    fn find_watchpoint(&self, index: Option<usize>, addr: Option<u64>) -> Option<&Watchpoint> {
+        if let Some(watchpoint) = index.and_then(|i| self.hw_watchpoints.get(i)) {
+            return Some(watchpoint);
+        }
+        let addr = addr?;
+        self.hw_watchpoints
+            .iter()
+            .find(|w| (w.addr.0..w.addr.0 + w.len).contains(&addr))
+            // The reported address may be anywhere in the accessed range
+            .or_else(|| {
+                self.hw_watchpoints
+                    .iter()
+                    .find(|w| w.addr.0 & !0x7 == addr & !0x7)
+            })
+    }
+
+    // Reason of the stop of the VM, as found from the first vCPU which had a
+    // debug exit.
+    fn stop_reason(&self) -> GdbResult<MultiThreadStopReason<ArchUsize>> {
+        for cpu_id in 0..self.active_vcpus()? {
+            let debug_exit = match self.vm_request(GdbRequestPayload::TakeDebugExit, cpu_id)? {
+                GdbResponsePayload::DebugExit(Some(debug_exit)) => debug_exit,
+                GdbResponsePayload::DebugExit(None) => continue,
+                r => return Err(Error::UnexpectedResponse(r)),
+            };
+
+            let tid = cpuid_to_tid(cpu_id);
+            let trap = MultiThreadStopReason::SignalWithThread {
+                tid,
+                signal: Signal::SIGTRAP,
+            };
+            return Ok(match debug_exit {
+                DebugExit::HwBreakpoint => MultiThreadStopReason::HwBreak(tid),
+                DebugExit::HwWatchpoint { index, addr } => {
+                    match self.find_watchpoint(index, addr) {
+                        Some(watchpoint) => MultiThreadStopReason::Watch {
+                            tid,
+                            kind: to_watch_kind(watchpoint.kind),
+                            addr: watchpoint.addr.0,
+                        },
+                        None => trap,
+                    }
+                }
+                DebugExit::SingleStep | DebugExit::Unknown => trap,
+            });
+        }
+
+        Ok(MultiThreadStopReason::Signal(Signal::SIGTRAP))
+    }
+}
+
+fn to_watch_kind(kind: WatchpointKind) -> WatchKind {
+    match kind {
+        WatchpointKind::Write => WatchKind::Write,
+        WatchpointKind::Read => WatchKind::Read,
+        WatchpointKind::ReadWrite => WatchKind::ReadWrite,
+    }
+}
+
+// Whether a watchpoint on the range can be set with a single debug register
+fn is_supported_watchpoint(addr: u64, len: u64, kind: WatchKind) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        // Reads can only be watched along with the writes
+        matches!(len, 1 | 2 | 4 | 8) && addr % len == 0 && kind != WatchKind::Read
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        // The range must fit in a double word
+        let _ = kind;
+        len > 0 && (addr & 0x7) + len <= 8
+    }
 }
 
 impl Target for GdbStub {
@@ -287,26 +419,41 @@ impl MultiThreadBase for GdbStub {
         &mut self,
         thread_is_active: &mut dyn FnMut(Tid),
     ) -> Result<(), Self::Error> {
-        match self.vm_request(GdbRequestPayload::ActiveVcpus, 0) {
-            Ok(GdbResponsePayload::ActiveVcpus(active_vcpus)) => {
-                (0..active_vcpus).for_each(|cpu_id| {
-                    thread_is_active(cpuid_to_tid(cpu_id));
-                });
-                Ok(())
-            }
-            Ok(s) => Err(format!("Unexpected response for ActiveVcpus: {s:?}")),
-            Err(e) => Err(format!("Failed to request ActiveVcpus: {e:?}")),
-        }
+        let active_vcpus = self
+            .active_vcpus()
+            .map_err(|e| format!("Failed to request ActiveVcpus: {e:?}"))?;
+        (0..active_vcpus).for_each(|cpu_id| {
+            thread_is_active(cpuid_to_tid(cpu_id));
+        });
+        Ok(())
     }
 
     #[inline(always)]
     fn support_resume(&mut self) -> Option<MultiThreadResumeOps<'_, Self>> {
         Some(self)
     }
+
+    #[inline(always)]
+    fn support_thread_extra_info(&mut self) -> Option<ThreadExtraInfoOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+impl ThreadExtraInfo for GdbStub {
+    fn thread_extra_info(&self, tid: Tid, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let info = format!("vCPU {}", tid_to_cpuid(tid));
+        let len = info.len().min(buf.len());
+        buf[..len].copy_from_slice(&info.as_bytes()[..len]);
+        Ok(len)
+    }
 }
 
 impl MultiThreadResume for GdbStub {
+    // The vCPUs are paused and resumed together, the resume actions only
+    // selecting the vCPU to single step.
     fn resume(&mut self) -> Result<(), Self::Error> {
+        self.set_guest_debug()
+            .map_err(|e| format!("Failed to request SetGuestDebug: {e:?}"))?;
         match self.vm_request(GdbRequestPayload::Resume, 0) {
             Ok(_) => Ok(()),
             Err(e) => Err(format!("Failed to resume the target: {e:?}")),
@@ -314,31 +461,19 @@ impl MultiThreadResume for GdbStub {
     }
 
     fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
-        if self.single_step {
-            match self.vm_request(GdbRequestPayload::SetSingleStep(false), 0) {
-                Ok(_) => {
-                    self.single_step = false;
-                }
-                Err(e) => {
-                    return Err(format!("Failed to request SetSingleStep: {e:?}"));
-                }
-            }
-        }
+        self.single_step = None;
         Ok(())
     }
 
     fn set_resume_action_continue(
         &mut self,
-        tid: Tid,
+        _tid: Tid,
         signal: Option<Signal>,
     ) -> Result<(), Self::Error> {
         if signal.is_some() {
             return Err("no support for continuing with signal".to_owned());
         }
-        match self.vm_request(GdbRequestPayload::Resume, tid_to_cpuid(tid)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Failed to resume the target: {e:?}")),
-        }
+        Ok(())
     }
 
     #[inline(always)]
@@ -356,21 +491,8 @@ impl MultiThreadSingleStep for GdbStub {
         if signal.is_some() {
             return Err("no support for stepping with signal".to_owned());
         }
-
-        if !self.single_step {
-            match self.vm_request(GdbRequestPayload::SetSingleStep(true), tid_to_cpuid(tid)) {
-                Ok(_) => {
-                    self.single_step = true;
-                }
-                Err(e) => {
-                    return Err(format!("Failed to request SetSingleStep: {e:?}"));
-                }
-            }
-        }
-        match self.vm_request(GdbRequestPayload::Resume, tid_to_cpuid(tid)) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(format!("Failed to resume the target: {e:?}")),
-        }
+        self.single_step = Some(tid_to_cpuid(tid));
+        Ok(())
     }
 }
 
@@ -379,6 +501,11 @@ impl Breakpoints for GdbStub {
     fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
         Some(self)
     }
+
+    #[inline(always)]
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+        Some(self)
+    }
 }
 
 impl HwBreakpoint for GdbStub {
@@ -388,21 +515,20 @@ impl HwBreakpoint for GdbStub {
         _kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
         // If the HW breakpoints reach the limit, no more can be added.
-        if self.hw_breakpoints.len() >= self.hw_breakpoints.capacity() {
+        if !self.hw_breakpoint_available() {
             error!(
                 "Not allowed to set more than {} HW breakpoints",
-                self.hw_breakpoints.capacity()
+                self.max_hw_breakpoints
             );
             return Ok(false);
         }
 
         self.hw_breakpoints.push(GuestAddress(addr));
 
-        let payload = GdbRequestPayload::SetHwBreakPoint(self.hw_breakpoints.clone());
-        match self.vm_request(payload, 0) {
+        match self.set_guest_debug() {
             Ok(_) => Ok(true),
             Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {:?}", e);
+                error!("Failed to request SetGuestDebug: {:?}", e);
                 Err(TargetError::NonFatal)
             }
         }
@@ -417,11 +543,73 @@ impl HwBreakpoint for GdbStub {
             Some(pos) => self.hw_breakpoints.remove(pos),
         };
 
-        let payload = GdbRequestPayload::SetHwBreakPoint(self.hw_breakpoints.clone());
-        match self.vm_request(payload, 0) {
+        match self.set_guest_debug() {
             Ok(_) => Ok(true),
             Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {:?}", e);
+                error!("Failed to request SetGuestDebug: {:?}", e);
+                Err(TargetError::NonFatal)
+            }
+        }
+    }
+}
+
+impl HwWatchpoint for GdbStub {
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        // GDB falls back to software watchpoints for the unsupported ranges.
+        if !is_supported_watchpoint(addr, len, kind) {
+            return Ok(false);
+        }
+        if !self.hw_watchpoint_available() {
+            error!(
+                "Not allowed to set more than {} HW watchpoints",
+                self.max_hw_watchpoints
+            );
+            return Ok(false);
+        }
+
+        self.hw_watchpoints.push(Watchpoint {
+            addr: GuestAddress(addr),
+            len,
+            kind: match kind {
+                WatchKind::Write => WatchpointKind::Write,
+                WatchKind::Read => WatchpointKind::Read,
+                WatchKind::ReadWrite => WatchpointKind::ReadWrite,
+            },
+        });
+
+        match self.set_guest_debug() {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                error!("Failed to request SetGuestDebug: {:?}", e);
+                Err(TargetError::NonFatal)
+            }
+        }
+    }
+
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        match self
+            .hw_watchpoints
+            .iter()
+            .position(|w| w.addr.0 == addr && w.len == len && to_watch_kind(w.kind) == kind)
+        {
+            None => return Ok(false),
+            Some(pos) => self.hw_watchpoints.remove(pos),
+        };
+
+        match self.set_guest_debug() {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                error!("Failed to request SetGuestDebug: {:?}", e);
                 Err(TargetError::NonFatal)
             }
         }
@@ -449,7 +637,7 @@ impl run_blocking::BlockingEventLoop for GdbEventLoop {
         loop {
             // This read is non-blocking.
             match target.vm_event.read() {
-                Ok(tid) => {
+                Ok(_) => {
                     target
                         .vm_request(GdbRequestPayload::Pause, 0)
                         .map_err(|_| {
@@ -457,11 +645,11 @@ impl run_blocking::BlockingEventLoop for GdbEventLoop {
                                 "Failed to pause VM".to_owned(),
                             )
                         })?;
-                    let stop_reason = if target.single_step {
-                        MultiThreadStopReason::DoneStep
-                    } else {
-                        MultiThreadStopReason::HwBreak(Tid::new(tid as usize).unwrap())
-                    };
+                    let stop_reason = target.stop_reason().map_err(|e| {
+                        run_blocking::WaitForStopReasonError::Target(format!(
+                            "Failed to find the stop reason: {e:?}"
+                        ))
+                    })?;
                     return Ok(run_blocking::Event::TargetStopped(stop_reason));
                 }
                 Err(e) => {
@@ -520,13 +708,10 @@ pub fn gdb_thread(mut gdbstub: GdbStub, path: &std::path::Path) {
             DisconnectReason::Disconnect => {
                 info!("GDB client has disconnected. Running...");
 
-                if let Err(e) = gdbstub.vm_request(GdbRequestPayload::SetSingleStep(false), 0) {
-                    error!("Failed to disable single step: {:?}", e);
-                }
-
-                if let Err(e) =
-                    gdbstub.vm_request(GdbRequestPayload::SetHwBreakPoint(Vec::new()), 0)
-                {
+                gdbstub.hw_breakpoints.clear();
+                gdbstub.hw_watchpoints.clear();
+                gdbstub.single_step = None;
+                if let Err(e) = gdbstub.set_guest_debug() {
                     error!("Failed to remove breakpoints: {:?}", e);
                 }
 
@@ -0,0 +1,268 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Client side of the guest agent channel.
+//!
+//! The guest agent listens on a dedicated vsock port inside the guest. The
+//! VMM reaches it through the host end of the virtio-vsock device, using the
+//! `CONNECT <port>` handshake, and then exchanges newline delimited JSON
+//! messages following the QEMU guest agent protocol:
+//!
+//! - request: `{"execute": "<command>", "arguments": {...}}`
+//! - response: `{"return": <value>}` or `{"error": {"class": ..., "desc": ...}}`
+//!
+//! A new connection is opened for each command, which prevents any stale
+//! data left by a previous (possibly timed out) command from being
+//! interpreted as the answer to the next one.
+
+use crate::api::{VmGuestExecData, VmGuestFsFreezeAction};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+// Delay between two guest-exec-status requests while waiting for a command.
+const EXEC_STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot connect to the vsock socket: {0}")]
+    Connect(#[source] io::Error),
+
+    #[error("Cannot configure the guest agent connection: {0}")]
+    SetTimeout(#[source] io::Error),
+
+    #[error("Guest agent is not listening on vsock port {0}")]
+    NotListening(u32),
+
+    #[error("Cannot write to the guest agent: {0}")]
+    Write(#[source] io::Error),
+
+    #[error("Cannot read from the guest agent: {0}")]
+    Read(#[source] io::Error),
+
+    #[error("Guest agent closed the connection")]
+    ConnectionClosed,
+
+    #[error("Invalid guest agent message: {0}")]
+    InvalidMessage(#[source] serde_json::Error),
+
+    #[error("Guest agent command failed: {class}: {desc}")]
+    Command { class: String, desc: String },
+
+    #[error("Guest agent command timed out")]
+    Timeout,
+
+    #[error("Cannot spawn the guest agent thread: {0}")]
+    SpawnThread(#[source] io::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Serialize)]
+struct Request<'a> {
+    execute: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arguments: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct CommandError {
+    class: String,
+    desc: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    Return(Value),
+    Error(CommandError),
+}
+
+pub struct GuestAgent {
+    socket: PathBuf,
+    port: u32,
+    timeout: Duration,
+}
+
+impl GuestAgent {
+    pub fn new(socket: &Path, port: u32, timeout: Duration) -> Self {
+        GuestAgent {
+            socket: socket.to_path_buf(),
+            port,
+            timeout,
+        }
+    }
+
+    fn connect(&self) -> Result<BufReader<UnixStream>> {
+        let mut stream = UnixStream::connect(&self.socket).map_err(Error::Connect)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(Error::SetTimeout)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(Error::SetTimeout)?;
+
+        stream
+            .write_all(format!("CONNECT {}\n", self.port).as_bytes())
+            .map_err(Error::Write)?;
+
+        // The vsock device acknowledges the connection with "OK <port>" once
+        // the guest accepted it, and closes the socket if it was refused.
+        let mut reader = BufReader::new(stream);
+        let mut ack = String::new();
+        match read_line(&mut reader, &mut ack) {
+            Err(Error::ConnectionClosed) => return Err(Error::NotListening(self.port)),
+            r => r?,
+        }
+        if !ack.starts_with("OK ") {
+            return Err(Error::NotListening(self.port));
+        }
+
+        Ok(reader)
+    }
+
+    /// Run a single command and return the value produced by the agent.
+    pub fn execute(&self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut reader = self.connect()?;
+
+        let mut request = serde_json::to_vec(&Request {
+            execute: command,
+            arguments,
+        })
+        .map_err(Error::InvalidMessage)?;
+        request.push(b'\n');
+        reader.get_mut().write_all(&request).map_err(Error::Write)?;
+
+        let mut line = String::new();
+        read_line(&mut reader, &mut line)?;
+
+        match serde_json::from_str(&line).map_err(Error::InvalidMessage)? {
+            Response::Return(value) => Ok(value),
+            Response::Error(e) => Err(Error::Command {
+                class: e.class,
+                desc: e.desc,
+            }),
+        }
+    }
+
+    pub fn info(&self) -> Result<Value> {
+        self.execute("guest-info", None)
+    }
+
+    pub fn exec(&self, data: &VmGuestExecData) -> Result<Value> {
+        let arguments = serde_json::json!({
+            "path": data.path,
+            "arg": data.args,
+            "env": data.env,
+            "capture-output": data.capture_output,
+        });
+        let pid = self.execute("guest-exec", Some(arguments))?;
+        if !data.wait {
+            return Ok(pid);
+        }
+
+        let arguments = Some(pid);
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let status = self.execute("guest-exec-status", arguments.clone())?;
+            if status.get("exited").and_then(Value::as_bool) == Some(true) {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            thread::sleep(EXEC_STATUS_POLL_INTERVAL);
+        }
+    }
+
+    pub fn fsfreeze(&self, action: VmGuestFsFreezeAction) -> Result<Value> {
+        let command = match action {
+            VmGuestFsFreezeAction::Freeze => "guest-fsfreeze-freeze",
+            VmGuestFsFreezeAction::Thaw => "guest-fsfreeze-thaw",
+            VmGuestFsFreezeAction::Status => "guest-fsfreeze-status",
+        };
+        self.execute(command, None)
+    }
+}
+
+fn read_line(reader: &mut BufReader<UnixStream>, line: &mut String) -> Result<()> {
+    match reader.read_line(line) {
+        Ok(0) => Err(Error::ConnectionClosed),
+        Ok(_) => Ok(()),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(Error::Timeout)
+        }
+        Err(e) => Err(Error::Read(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Emulate the host end of the vsock device plus a guest agent answering
+    // a single command with the given response.
+    fn spawn_agent(
+        socket: PathBuf,
+        port: u32,
+        response: &'static str,
+    ) -> thread::JoinHandle<String> {
+        let listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, format!("CONNECT {port}\n"));
+            reader.get_mut().write_all(b"OK 1073741824\n").unwrap();
+
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn test_guest_agent_execute() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let socket = dir.as_path().join("vsock");
+
+        let agent_thread = spawn_agent(
+            socket.clone(),
+            1097,
+            "{\"return\": {\"version\": \"8.0\"}}\n",
+        );
+        let agent = GuestAgent::new(&socket, 1097, Duration::from_secs(5));
+        let value = agent.info().unwrap();
+        assert_eq!(value["version"], "8.0");
+        let request: Value = serde_json::from_str(&agent_thread.join().unwrap()).unwrap();
+        assert_eq!(request, serde_json::json!({"execute": "guest-info"}));
+        std::fs::remove_file(&socket).unwrap();
+
+        let agent_thread = spawn_agent(
+            socket.clone(),
+            1097,
+            "{\"error\": {\"class\": \"GenericError\", \"desc\": \"frozen\"}}\n",
+        );
+        assert!(matches!(
+            agent.fsfreeze(VmGuestFsFreezeAction::Freeze),
+            Err(Error::Command { class, desc }) if class == "GenericError" && desc == "frozen"
+        ));
+        let request: Value = serde_json::from_str(&agent_thread.join().unwrap()).unwrap();
+        assert_eq!(request["execute"], "guest-fsfreeze-freeze");
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Lifecycle hooks of the VM.
+//!
+//! Executables run on the host when the VM boots, shuts down, pauses, resumes
+//! or crashes, for the host side setup to follow the VM without polling the
+//! API. They get the event, the state of the VM and its identifier through
+//! their environment.
+//!
+//! The hooks are run by a process forked when the VMM starts, before it jails
+//! and restricts itself, as the hooks would otherwise inherit the jail and the
+//! seccomp filters. The process releases the file descriptors of the VMM, such
+//! as its API socket and the TAP interfaces of the VM, for the hooks not to
+//! inherit them. The VMM sends the events over a pipe, and the process runs
+//! the hooks of each event one after the other, killing the ones running past
+//! their timeout. It exits once the VMM is gone and the hooks of the last
+//! events have run, the hooks of the shutdown of the VM included.
+
+use once_cell::sync::OnceCell;
+use option_parser::{OptionParser, OptionParserError};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+static HOOKS: OnceCell<Mutex<File>> = OnceCell::new();
+
+/// Time a hook is given to complete, in seconds.
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 10;
+
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Error parsing --hook: {0}")]
+    Parse(#[source] OptionParserError),
+
+    #[error("Error parsing --hook: event and path required")]
+    MissingEventOrPath,
+
+    #[error("Cannot create the pipe of the hooks: {0}")]
+    CreatePipe(#[source] io::Error),
+
+    #[error("Cannot fork the process running the hooks: {0}")]
+    Fork(#[source] io::Error),
+
+    #[error("Hooks already set")]
+    AlreadySet,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum HookEvent {
+    Boot,
+    Shutdown,
+    Pause,
+    Resume,
+    Crash,
+}
+
+impl HookEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::Boot => "boot",
+            HookEvent::Shutdown => "shutdown",
+            HookEvent::Pause => "pause",
+            HookEvent::Resume => "resume",
+            HookEvent::Crash => "crash",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseHookEventError {
+    InvalidValue(String),
+}
+
+impl FromStr for HookEvent {
+    type Err = ParseHookEventError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "boot" => Ok(HookEvent::Boot),
+            "shutdown" => Ok(HookEvent::Shutdown),
+            "pause" => Ok(HookEvent::Pause),
+            "resume" => Ok(HookEvent::Resume),
+            "crash" => Ok(HookEvent::Crash),
+            _ => Err(ParseHookEventError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookConfig {
+    pub event: HookEvent,
+    pub path: PathBuf,
+    pub timeout: Duration,
+}
+
+impl HookConfig {
+    pub const SYNTAX: &'static str = "Executable run on an event of the VM \
+        \"event=boot|shutdown|pause|resume|crash,path=<executable_path>,\
+        timeout=<timeout_in_seconds>\"";
+
+    pub fn parse(hook: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("event").add("path").add("timeout");
+        parser.parse(hook).map_err(Error::Parse)?;
+
+        let event = parser
+            .convert("event")
+            .map_err(Error::Parse)?
+            .ok_or(Error::MissingEventOrPath)?;
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::MissingEventOrPath)?;
+        let timeout = parser
+            .convert("timeout")
+            .map_err(Error::Parse)?
+            .unwrap_or(DEFAULT_HOOK_TIMEOUT);
+
+        Ok(HookConfig {
+            event,
+            path,
+            timeout: Duration::from_secs(timeout),
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct HookRequest {
+    event: HookEvent,
+    state: String,
+    vm_id: Option<String>,
+}
+
+fn run_hook(hook: &HookConfig, request: &HookRequest) {
+    let mut command = Command::new(&hook.path);
+    command
+        .env("CH_VM_EVENT", request.event.as_str())
+        .env("CH_VM_STATE", &request.state)
+        .stdin(Stdio::null());
+    if let Some(vm_id) = &request.vm_id {
+        command.env("CH_VM_ID", vm_id);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!(
+                "Cannot run the {} hook {:?}: {}",
+                request.event.as_str(),
+                hook.path,
+                e
+            );
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    warn!(
+                        "The {} hook {:?} failed: {}",
+                        request.event.as_str(),
+                        hook.path,
+                        status
+                    );
+                }
+                return;
+            }
+            Ok(None) if start.elapsed() >= hook.timeout => {
+                warn!(
+                    "The {} hook {:?} timed out after {:?}, killing it",
+                    request.event.as_str(),
+                    hook.path,
+                    hook.timeout
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+            Ok(None) => thread::sleep(HOOK_POLL_INTERVAL),
+            Err(e) => {
+                error!(
+                    "Cannot wait for the {} hook {:?}: {}",
+                    request.event.as_str(),
+                    hook.path,
+                    e
+                );
+                return;
+            }
+        }
+    }
+}
+
+fn run_hooks(hooks: &[HookConfig], pipe: File) {
+    for line in BufReader::new(pipe).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let request: HookRequest = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Invalid hook request: {}", e);
+                continue;
+            }
+        };
+
+        for hook in hooks.iter().filter(|hook| hook.event == request.event) {
+            run_hook(hook, &request);
+        }
+    }
+}
+
+/// Fork the process running the given hooks. This must be called while the
+/// VMM is single threaded.
+pub fn start_hooks(hooks: Vec<HookConfig>) -> Result<()> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+
+    let mut fds = [-1; 2];
+    // SAFETY: FFI call with a valid array of two file descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(Error::CreatePipe(io::Error::last_os_error()));
+    }
+    // SAFETY: the file descriptors were just created, and are owned by the
+    // files from now on.
+    let (read_end, write_end) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: FFI call, the VMM being single threaded
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::Fork(io::Error::last_os_error())),
+        0 => {
+            drop(write_end);
+            crate::virtiofsd::release_inherited_fds(read_end.as_raw_fd());
+            // The hooks must not inherit the signals blocked by the VMM
+            // SAFETY: FFI calls with a valid signal set
+            unsafe {
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::pthread_sigmask(libc::SIG_SETMASK, &set, std::ptr::null_mut());
+            }
+            run_hooks(&hooks, read_end);
+            std::process::exit(0);
+        }
+        pid => {
+            drop(read_end);
+            // The VMM never waits for the hooks
+            // SAFETY: FFI call on a valid file descriptor
+            unsafe {
+                let flags = libc::fcntl(fds[1], libc::F_GETFL);
+                libc::fcntl(fds[1], libc::F_SETFL, flags | libc::O_NONBLOCK);
+            }
+            info!("Running the VM lifecycle hooks from process {}", pid);
+
+            HOOKS
+                .set(Mutex::new(write_end))
+                .map_err(|_| Error::AlreadySet)
+        }
+    }
+}
+
+/// Run the hooks of the given event of the VM, without waiting for them.
+pub fn notify(event: HookEvent, state: &str, vm_id: Option<&str>) {
+    let Some(pipe) = HOOKS.get() else {
+        return;
+    };
+
+    let request = HookRequest {
+        event,
+        state: state.to_owned(),
+        vm_id: vm_id.map(str::to_owned),
+    };
+    let mut line = match serde_json::to_vec(&request) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Cannot serialize the hook request: {}", e);
+            return;
+        }
+    };
+    line.push(b'\n');
+
+    if let Err(e) = pipe.lock().unwrap().write_all(&line) {
+        warn!("Cannot run the {} hooks: {}", event.as_str(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_hook_parsing() -> Result<()> {
+        assert_eq!(
+            HookConfig::parse("event=boot,path=/usr/libexec/vm-up")?,
+            HookConfig {
+                event: HookEvent::Boot,
+                path: PathBuf::from("/usr/libexec/vm-up"),
+                timeout: Duration::from_secs(DEFAULT_HOOK_TIMEOUT),
+            }
+        );
+        assert_eq!(
+            HookConfig::parse("event=crash,path=/usr/libexec/vm-alert,timeout=30")?,
+            HookConfig {
+                event: HookEvent::Crash,
+                path: PathBuf::from("/usr/libexec/vm-alert"),
+                timeout: Duration::from_secs(30),
+            }
+        );
+        assert!(HookConfig::parse("path=/usr/libexec/vm-up").is_err());
+        assert!(HookConfig::parse("event=boot").is_err());
+        assert!(HookConfig::parse("event=reboot,path=/usr/libexec/vm-up").is_err());
+        Ok(())
+    }
+
+    fn write_hook(path: &Path, script: &str) {
+        std::fs::write(path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_run_hooks() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let out = dir.as_path().join("out");
+        let hook = dir.as_path().join("hook");
+        write_hook(
+            &hook,
+            &format!(
+                "echo \"$CH_VM_EVENT $CH_VM_STATE ${{CH_VM_ID:-none}}\" >> {}",
+                out.display()
+            ),
+        );
+        let slow_hook = dir.as_path().join("slow_hook");
+        write_hook(&slow_hook, "exec sleep 60");
+
+        let hooks = [
+            HookConfig {
+                event: HookEvent::Boot,
+                path: hook.clone(),
+                timeout: Duration::from_secs(DEFAULT_HOOK_TIMEOUT),
+            },
+            HookConfig {
+                event: HookEvent::Shutdown,
+                path: slow_hook,
+                timeout: Duration::from_millis(100),
+            },
+            HookConfig {
+                event: HookEvent::Shutdown,
+                path: hook,
+                timeout: Duration::from_secs(DEFAULT_HOOK_TIMEOUT),
+            },
+        ];
+
+        let requests = [
+            HookRequest {
+                event: HookEvent::Boot,
+                state: "Running".to_owned(),
+                vm_id: Some("4f8e6a1c".to_owned()),
+            },
+            HookRequest {
+                event: HookEvent::Pause,
+                state: "Paused".to_owned(),
+                vm_id: None,
+            },
+            HookRequest {
+                event: HookEvent::Shutdown,
+                state: "Shutdown".to_owned(),
+                vm_id: None,
+            },
+        ];
+        let mut fds = [-1; 2];
+        // SAFETY: FFI call with a valid array of two file descriptors
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // SAFETY: the file descriptors were just created, and are owned by
+        // the files from now on.
+        let (read_end, mut write_end) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        for request in requests.iter() {
+            let mut line = serde_json::to_vec(request).unwrap();
+            line.push(b'\n');
+            write_end.write_all(&line).unwrap();
+        }
+        write_end.write_all(b"invalid\n").unwrap();
+        drop(write_end);
+
+        // The hooks of the events are run in order, the ones timing out being
+        // killed, until the VMM end of the pipe is closed.
+        let start = Instant::now();
+        run_hooks(&hooks, read_end);
+        assert!(start.elapsed() < Duration::from_secs(DEFAULT_HOOK_TIMEOUT));
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "boot Running 4f8e6a1c\nshutdown Shutdown none\n"
+        );
+    }
+}
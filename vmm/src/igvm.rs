@@ -0,0 +1,372 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Loader for the IGVM (Independent Guest Virtual Machine) files.
+//!
+//! An IGVM file packages the initial state of a confidential guest, usually
+//! its firmware, as a list of directives: the pages to load into the guest
+//! memory, the parameter areas the VMM fills with the VM configuration, such
+//! as the vCPU count, the memory map or the command line, and the initial
+//! state of the boot vCPU, its VMSA with SEV-SNP.
+//!
+//! Each page is written to the guest memory before being imported into the
+//! isolated partition, its content being measured unless flagged otherwise,
+//! and the import is completed with the optional ID block and host data, so
+//! that the guest can prove the launch digest it was started from.
+
+use crate::memory_manager::MemoryManager;
+use hypervisor::mshv::{
+    hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
+    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO,
+};
+use igvm_defs::{
+    IgvmPageDataType, MemoryMapEntryType, IGVM_VHS_MEMORY_MAP_ENTRY, IGVM_VHS_PARAMETER,
+    IGVM_VHS_SNP_ID_BLOCK, PAGE_SIZE_4K,
+};
+use igvm_parser::{IgvmDirectiveHeader, IgvmFile, IsolationType};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryRegion};
+use zerocopy::AsBytes;
+
+const HOST_DATA_SIZE: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read the IGVM file: {0}")]
+    ReadFile(#[source] io::Error),
+
+    #[error("Cannot parse the IGVM file: {0}")]
+    Parse(#[source] igvm_parser::Error),
+
+    #[error("Unknown parameter area {0}")]
+    UnknownParameterArea(u32),
+
+    #[error("Parameter area {0} too small")]
+    ParameterAreaTooSmall(u32),
+
+    #[error("Unsupported VMSA for vCPU {0}, only the boot vCPU can have one")]
+    UnsupportedVmsa(u16),
+
+    #[error("No VMSA found for the boot vCPU")]
+    MissingVmsa,
+
+    #[error("Required memory at 0x{0:x} isn't part of the guest memory")]
+    MissingMemory(u64),
+
+    #[error("Cannot write to the guest memory: {0}")]
+    GuestMemory(#[source] vm_memory::GuestMemoryError),
+
+    #[error("Cannot import the pages at 0x{0:x}: {1}")]
+    ImportPages(u64, #[source] hypervisor::HypervisorVmError),
+
+    #[error("Cannot complete the import: {0}")]
+    CompleteImport(#[source] hypervisor::HypervisorVmError),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// State of the guest once the IGVM file is loaded.
+#[derive(Debug)]
+pub struct IgvmLoadedInfo {
+    /// Guest physical address of the VMSA of the boot vCPU.
+    pub vmsa_gpa: u64,
+}
+
+struct ImportedPage {
+    gpa: u64,
+    page_type: u32,
+}
+
+fn page_type(data_type: IgvmPageDataType, unmeasured: bool, empty: bool) -> u32 {
+    match data_type {
+        IgvmPageDataType::SECRETS => hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_SECRETS,
+        IgvmPageDataType::CPUID_DATA | IgvmPageDataType::CPUID_XF => {
+            hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_CPUID
+        }
+        _ if unmeasured => hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+        _ if empty => hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_ZERO,
+        _ => hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+    }
+}
+
+/// Decode the host data given as 64 hexadecimal digits, as checked when the
+/// configuration is validated.
+fn host_data(host_data: Option<&str>) -> [u8; HOST_DATA_SIZE] {
+    let mut data = [0u8; HOST_DATA_SIZE];
+    if let Some(host_data) = host_data {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&host_data[2 * i..2 * i + 2], 16).unwrap();
+        }
+    }
+    data
+}
+
+// Writes the value into the parameter area, at the offset given by the
+// directive.
+fn write_parameter(
+    areas: &mut HashMap<u32, Vec<u8>>,
+    param: &IGVM_VHS_PARAMETER,
+    value: &[u8],
+) -> Result<()> {
+    let area = areas
+        .get_mut(&param.parameter_area_index)
+        .ok_or(Error::UnknownParameterArea(param.parameter_area_index))?;
+    let start = param.byte_offset as usize;
+    area.get_mut(start..start + value.len())
+        .ok_or(Error::ParameterAreaTooSmall(param.parameter_area_index))?
+        .copy_from_slice(value);
+    Ok(())
+}
+
+// Groups the pages by type, following the guest physical addresses, so that
+// they get imported with as few calls as possible.
+fn import_groups(mut pages: Vec<ImportedPage>) -> Vec<(u32, Vec<u64>)> {
+    pages.sort_by_key(|p| p.gpa);
+
+    let mut groups: Vec<(u32, Vec<u64>)> = Vec::new();
+    for page in pages {
+        let pfn = page.gpa / PAGE_SIZE_4K;
+        match groups.last_mut() {
+            Some((page_type, pfns)) if *page_type == page.page_type => pfns.push(pfn),
+            _ => groups.push((page.page_type, vec![pfn])),
+        }
+    }
+    groups
+}
+
+/// Load the IGVM file into the guest memory and import it into the isolated
+/// partition. This must be called once the guest memory is mapped.
+pub fn load_igvm(
+    mut file: File,
+    vm: &Arc<dyn hypervisor::Vm>,
+    memory_manager: &Arc<Mutex<MemoryManager>>,
+    boot_vcpus: u32,
+    cmdline: &str,
+    host_data_hex: Option<&str>,
+) -> Result<IgvmLoadedInfo> {
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).map_err(Error::ReadFile)?;
+    let igvm = IgvmFile::new_from_binary(&data, Some(IsolationType::Snp)).map_err(Error::Parse)?;
+
+    let guest_memory = memory_manager.lock().unwrap().guest_memory();
+    let mem = guest_memory.memory();
+
+    let mut pages = Vec::new();
+    let mut areas: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut vmsa_gpa = None;
+    let mut id_block = None;
+
+    for directive in igvm.directives() {
+        match directive {
+            IgvmDirectiveHeader::PageData {
+                gpa,
+                flags,
+                data_type,
+                data,
+                ..
+            } => {
+                mem.write_slice(data, GuestAddress(*gpa))
+                    .map_err(Error::GuestMemory)?;
+                pages.push(ImportedPage {
+                    gpa: *gpa,
+                    page_type: page_type(*data_type, flags.unmeasured(), data.is_empty()),
+                });
+            }
+            IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes,
+                parameter_area_index,
+                initial_data,
+            } => {
+                let mut area = initial_data.clone();
+                area.resize(*number_of_bytes as usize, 0);
+                areas.insert(*parameter_area_index, area);
+            }
+            IgvmDirectiveHeader::VpCount(param) => {
+                write_parameter(&mut areas, param, &boot_vcpus.to_le_bytes())?;
+            }
+            IgvmDirectiveHeader::CommandLine(param) => {
+                let mut cmdline = cmdline.as_bytes().to_vec();
+                cmdline.push(0);
+                write_parameter(&mut areas, param, &cmdline)?;
+            }
+            IgvmDirectiveHeader::MemoryMap(param) => {
+                let entries: Vec<u8> = mem
+                    .iter()
+                    .flat_map(|region| {
+                        IGVM_VHS_MEMORY_MAP_ENTRY {
+                            starting_gpa_page_number: region.start_addr().raw_value()
+                                / PAGE_SIZE_4K,
+                            number_of_pages: region.len() / PAGE_SIZE_4K,
+                            entry_type: MemoryMapEntryType::MEMORY,
+                            flags: 0,
+                            reserved: 0,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    })
+                    .collect();
+                write_parameter(&mut areas, param, &entries)?;
+            }
+            IgvmDirectiveHeader::ParameterInsert(insert) => {
+                let area = areas
+                    .remove(&insert.parameter_area_index)
+                    .ok_or(Error::UnknownParameterArea(insert.parameter_area_index))?;
+                mem.write_slice(&area, GuestAddress(insert.gpa))
+                    .map_err(Error::GuestMemory)?;
+                // The parameters depend on the VM configuration, they can't
+                // be part of the launch digest.
+                for offset in (0..area.len() as u64).step_by(PAGE_SIZE_4K as usize) {
+                    pages.push(ImportedPage {
+                        gpa: insert.gpa + offset,
+                        page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_UNMEASURED,
+                    });
+                }
+            }
+            IgvmDirectiveHeader::SnpVpContext {
+                gpa,
+                vp_index,
+                vmsa,
+                ..
+            } => {
+                if *vp_index != 0 {
+                    return Err(Error::UnsupportedVmsa(*vp_index));
+                }
+                mem.write_slice(vmsa.as_bytes(), GuestAddress(*gpa))
+                    .map_err(Error::GuestMemory)?;
+                pages.push(ImportedPage {
+                    gpa: *gpa,
+                    page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
+                });
+                vmsa_gpa = Some(*gpa);
+            }
+            IgvmDirectiveHeader::SnpIdBlock {
+                compatibility_mask,
+                author_key_enabled,
+                reserved,
+                ld,
+                family_id,
+                image_id,
+                version,
+                guest_svn,
+                id_key_algorithm,
+                author_key_algorithm,
+                id_key_signature,
+                id_public_key,
+                author_key_signature,
+                author_public_key,
+            } => {
+                id_block = Some(IGVM_VHS_SNP_ID_BLOCK {
+                    compatibility_mask: *compatibility_mask,
+                    author_key_enabled: *author_key_enabled,
+                    reserved: *reserved,
+                    ld: *ld,
+                    family_id: *family_id,
+                    image_id: *image_id,
+                    version: *version,
+                    guest_svn: *guest_svn,
+                    id_key_algorithm: *id_key_algorithm,
+                    author_key_algorithm: *author_key_algorithm,
+                    id_key_signature: **id_key_signature,
+                    id_public_key: **id_public_key,
+                    author_key_signature: **author_key_signature,
+                    author_public_key: **author_public_key,
+                });
+            }
+            IgvmDirectiveHeader::RequiredMemory {
+                gpa,
+                number_of_bytes,
+                ..
+            } => {
+                let end = gpa + u64::from(*number_of_bytes) - 1;
+                if !mem.address_in_range(GuestAddress(*gpa))
+                    || !mem.address_in_range(GuestAddress(end))
+                {
+                    return Err(Error::MissingMemory(*gpa));
+                }
+            }
+            directive => {
+                warn!("Ignoring unsupported IGVM directive {:?}", directive);
+            }
+        }
+    }
+
+    let vmsa_gpa = vmsa_gpa.ok_or(Error::MissingVmsa)?;
+
+    for (page_type, pfns) in import_groups(pages) {
+        vm.import_isolated_pages(
+            page_type,
+            hv_isolated_page_size_HV_ISOLATED_PAGE_SIZE_4KB,
+            &pfns,
+        )
+        .map_err(|e| Error::ImportPages(pfns[0] * PAGE_SIZE_4K, e))?;
+    }
+
+    let id_block_enabled = u8::from(id_block.is_some());
+    vm.complete_isolated_import(
+        id_block.unwrap_or_default(),
+        &host_data(host_data_hex),
+        id_block_enabled,
+    )
+    .map_err(Error::CompleteImport)?;
+
+    info!("IGVM file loaded: vmsa_gpa = 0x{:x}", vmsa_gpa);
+
+    Ok(IgvmLoadedInfo { vmsa_gpa })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_groups() {
+        let pages = vec![
+            ImportedPage {
+                gpa: 0x3000,
+                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+            },
+            ImportedPage {
+                gpa: 0x1000,
+                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+            },
+            ImportedPage {
+                gpa: 0x2000,
+                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA,
+            },
+            ImportedPage {
+                gpa: 0x4000,
+                page_type: hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+            },
+        ];
+
+        assert_eq!(
+            import_groups(pages),
+            vec![
+                (hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL, vec![1]),
+                (hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_VMSA, vec![2]),
+                (
+                    hv_isolated_page_type_HV_ISOLATED_PAGE_TYPE_NORMAL,
+                    vec![3, 4]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_data() {
+        assert_eq!(host_data(None), [0u8; HOST_DATA_SIZE]);
+
+        let data = host_data(Some("0a".repeat(HOST_DATA_SIZE).as_str()));
+        assert_eq!(data, [0x0au8; HOST_DATA_SIZE]);
+    }
+}
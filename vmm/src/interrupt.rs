@@ -8,11 +8,12 @@ use hypervisor::IrqRoutingEntry;
 use std::collections::HashMap;
 use std::io;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
+use virtio_devices::{InterruptRemapping, InterruptRemappingListener};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
-    LegacyIrqGroupConfig, MsiIrqGroupConfig,
+    LegacyIrqGroupConfig, MsiIrqGroupConfig, MsiIrqSourceConfig,
 };
 use vmm_sys_util::eventfd::EventFd;
 
@@ -316,3 +317,300 @@ impl InterruptManager for MsiInterruptManager {
         Ok(())
     }
 }
+
+/// MSI interrupt group of a device whose messages are remapped, by the virtual
+/// IOMMU or by the interrupt remapping unit, before being routed.
+pub struct RemappedMsiInterruptGroup {
+    group: Arc<dyn InterruptSourceGroup>,
+    id: u32,
+    remapping: Arc<dyn InterruptRemapping>,
+    // Messages programmed by the device and whether the vector is masked, to
+    // remap them again whenever the remapping changes.
+    vectors: Mutex<HashMap<InterruptIndex, (MsiIrqSourceConfig, bool)>>,
+}
+
+impl RemappedMsiInterruptGroup {
+    fn remap(
+        &self,
+        index: InterruptIndex,
+        mut msi: MsiIrqSourceConfig,
+        masked: bool,
+        set_gsi: bool,
+    ) -> Result<()> {
+        let addr = (u64::from(msi.high_addr) << 32) | u64::from(msi.low_addr);
+        let masked = match self.remapping.remap_msi(self.id, addr, msi.data) {
+            Ok((addr, data)) => {
+                msi.high_addr = (addr >> 32) as u32;
+                msi.low_addr = addr as u32;
+                msi.data = data;
+                masked
+            }
+            // The vector stays masked rather than letting the device signal
+            // an interrupt the guest didn't allow.
+            Err(e) => {
+                if !masked {
+                    warn!("Blocking vector {} of device 0x{:x}: {}", index, self.id, e);
+                }
+                true
+            }
+        };
+
+        self.group
+            .update(index, InterruptSourceConfig::MsiIrq(msi), masked, set_gsi)
+    }
+}
+
+impl InterruptSourceGroup for RemappedMsiInterruptGroup {
+    fn enable(&self) -> Result<()> {
+        self.group.enable()
+    }
+
+    fn disable(&self) -> Result<()> {
+        self.group.disable()
+    }
+
+    fn trigger(&self, index: InterruptIndex) -> Result<()> {
+        self.group.trigger(index)
+    }
+
+    fn notifier(&self, index: InterruptIndex) -> Option<EventFd> {
+        self.group.notifier(index)
+    }
+
+    fn update(
+        &self,
+        index: InterruptIndex,
+        config: InterruptSourceConfig,
+        masked: bool,
+        set_gsi: bool,
+    ) -> Result<()> {
+        match config {
+            InterruptSourceConfig::MsiIrq(msi) => {
+                let mut vectors = self.vectors.lock().unwrap();
+                vectors.insert(index, (msi, masked));
+                self.remap(index, msi, masked, set_gsi)
+            }
+            config => self.group.update(index, config, masked, set_gsi),
+        }
+    }
+
+    fn set_gsi(&self) -> Result<()> {
+        self.group.set_gsi()
+    }
+}
+
+impl InterruptRemappingListener for RemappedMsiInterruptGroup {
+    fn remapping_changed(&self) {
+        let vectors = self.vectors.lock().unwrap();
+        if vectors.is_empty() {
+            return;
+        }
+
+        for (index, (msi, masked)) in vectors.iter() {
+            if let Err(e) = self.remap(*index, *msi, *masked, false) {
+                error!(
+                    "Failed remapping vector {} of device 0x{:x}: {}",
+                    index, self.id, e
+                );
+            }
+        }
+        if let Err(e) = self.group.set_gsi() {
+            error!(
+                "Failed updating the routes of device 0x{:x}: {}",
+                self.id, e
+            );
+        }
+    }
+}
+
+/// MSI interrupt manager of a device whose messages are remapped.
+pub struct RemappedMsiInterruptManager {
+    manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+    id: u32,
+    remapping: Arc<dyn InterruptRemapping>,
+}
+
+impl RemappedMsiInterruptManager {
+    pub fn new(
+        manager: Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
+        id: u32,
+        remapping: Arc<dyn InterruptRemapping>,
+    ) -> Self {
+        RemappedMsiInterruptManager {
+            manager,
+            id,
+            remapping,
+        }
+    }
+}
+
+impl InterruptManager for RemappedMsiInterruptManager {
+    type GroupConfig = MsiIrqGroupConfig;
+
+    fn create_group(&self, config: Self::GroupConfig) -> Result<Arc<dyn InterruptSourceGroup>> {
+        let group = Arc::new(RemappedMsiInterruptGroup {
+            group: self.manager.create_group(config)?,
+            id: self.id,
+            remapping: self.remapping.clone(),
+            vectors: Mutex::new(HashMap::new()),
+        });
+        let listener: Weak<dyn InterruptRemappingListener> = Arc::downgrade(&group);
+        self.remapping.add_listener(listener);
+
+        Ok(group)
+    }
+
+    fn destroy_group(&self, group: Arc<dyn InterruptSourceGroup>) -> Result<()> {
+        self.manager.destroy_group(group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use virtio_devices::InterruptRemappingListeners;
+
+    #[derive(Default)]
+    struct TestGroup {
+        updates: Mutex<Vec<(InterruptIndex, MsiIrqSourceConfig, bool, bool)>>,
+        set_gsi_count: Mutex<usize>,
+    }
+
+    impl InterruptSourceGroup for TestGroup {
+        fn trigger(&self, _index: InterruptIndex) -> Result<()> {
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            index: InterruptIndex,
+            config: InterruptSourceConfig,
+            masked: bool,
+            set_gsi: bool,
+        ) -> Result<()> {
+            if let InterruptSourceConfig::MsiIrq(msi) = config {
+                self.updates
+                    .lock()
+                    .unwrap()
+                    .push((index, msi, masked, set_gsi));
+            }
+            Ok(())
+        }
+
+        fn set_gsi(&self) -> Result<()> {
+            *self.set_gsi_count.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    struct TestManager {
+        group: Arc<TestGroup>,
+    }
+
+    impl InterruptManager for TestManager {
+        type GroupConfig = MsiIrqGroupConfig;
+
+        fn create_group(
+            &self,
+            _config: Self::GroupConfig,
+        ) -> Result<Arc<dyn InterruptSourceGroup>> {
+            Ok(self.group.clone())
+        }
+
+        fn destroy_group(&self, _group: Arc<dyn InterruptSourceGroup>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Remapping only letting the messages of an allowed device through,
+    // their vector being offset by 0x10.
+    #[derive(Default)]
+    struct TestRemapping {
+        allowed: Mutex<Option<u32>>,
+        listeners: InterruptRemappingListeners,
+    }
+
+    impl InterruptRemapping for TestRemapping {
+        fn remap_msi(&self, id: u32, addr: u64, data: u32) -> Result<(u64, u32)> {
+            if *self.allowed.lock().unwrap() == Some(id) {
+                Ok((addr, data + 0x10))
+            } else {
+                Err(io::Error::new(io::ErrorKind::Other, "blocked"))
+            }
+        }
+
+        fn add_listener(&self, listener: Weak<dyn InterruptRemappingListener>) {
+            self.listeners.add(listener);
+        }
+    }
+
+    fn msi(data: u32) -> InterruptSourceConfig {
+        InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig {
+            high_addr: 0,
+            low_addr: 0xfee0_0000,
+            data,
+            devid: 0,
+        })
+    }
+
+    #[test]
+    fn test_remapped_msi_interrupt_group() {
+        let inner = Arc::new(TestGroup::default());
+        let remapping = Arc::new(TestRemapping::default());
+        let manager = RemappedMsiInterruptManager::new(
+            Arc::new(TestManager {
+                group: inner.clone(),
+            }),
+            0x8,
+            remapping.clone(),
+        );
+        let group = manager
+            .create_group(MsiIrqGroupConfig { base: 0, count: 2 })
+            .unwrap();
+
+        // Blocked messages are forwarded masked
+        group.update(0, msi(0x30), false, true).unwrap();
+        group.update(1, msi(0x31), true, true).unwrap();
+        {
+            let updates = inner.updates.lock().unwrap();
+            assert_eq!(updates.len(), 2);
+            assert_eq!((updates[0].0, updates[0].2, updates[0].3), (0, true, true));
+            assert_eq!(updates[0].1.data, 0x30);
+            assert_eq!((updates[1].0, updates[1].2), (1, true));
+        }
+
+        // Once allowed, the vectors are remapped again, keeping the mask the
+        // device asked for, before the routes being set at once
+        *remapping.allowed.lock().unwrap() = Some(0x8);
+        remapping.listeners.notify();
+        {
+            let mut updates = inner.updates.lock().unwrap()[2..].to_vec();
+            updates.sort_by_key(|u| u.0);
+            assert_eq!(updates.len(), 2);
+            assert_eq!(
+                (updates[0].1.data, updates[0].2, updates[0].3),
+                (0x40, false, false)
+            );
+            assert_eq!(
+                (updates[1].1.data, updates[1].2, updates[1].3),
+                (0x41, true, false)
+            );
+        }
+        assert_eq!(*inner.set_gsi_count.lock().unwrap(), 1);
+
+        // Blocking the device again masks its vectors
+        *remapping.allowed.lock().unwrap() = None;
+        remapping.listeners.notify();
+        assert!(inner.updates.lock().unwrap()[4..].iter().all(|u| u.2));
+
+        // The group stops listening once dropped
+        drop(group);
+        remapping.listeners.notify();
+        assert_eq!(inner.updates.lock().unwrap().len(), 6);
+        assert_eq!(*inner.set_gsi_count.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,611 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Interrupt remapping unit of an emulated Intel VT-d, described to the guest
+//! through the DMAR table.
+//!
+//! The unit only remaps interrupts, the DMA remapping being left to the
+//! virtual IOMMU: it reports no supported address width, which makes the
+//! guest ignore it for DMA while still programming its interrupt remapping
+//! table through it. The interrupt entry cache is invalidated through the
+//! invalidation queue, the messages of the devices being remapped again each
+//! time it is.
+
+use crate::GuestMemoryMmap;
+use std::io;
+use std::sync::{Arc, Barrier, Mutex, Weak};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+use virtio_devices::{InterruptRemapping, InterruptRemappingListener, InterruptRemappingListeners};
+use vm_device::BusDevice;
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic};
+use vm_migration::{
+    Migratable, MigratableError, Pausable, Snapshot, Snapshottable, Transportable, VersionMapped,
+};
+
+/// Size of the register set of the unit.
+pub const INTERRUPT_REMAPPING_SIZE: u64 = 0x1000;
+
+/// Source identifier of the IOAPIC, whose messages are remapped as the ones
+/// of a PCI device would be. It's the one QEMU uses, on a bus no PCI segment
+/// reaches.
+pub const IOAPIC_SOURCE_ID: u16 = 0xf0f8;
+
+// Register offsets
+const VER_REG: u64 = 0x0;
+const CAP_REG: u64 = 0x8;
+const ECAP_REG: u64 = 0x10;
+const GCMD_REG: u64 = 0x18;
+const FSTS_REG: u64 = 0x30;
+const FECTL_REG: u64 = 0x38;
+const FEADDR_REG: u64 = 0x40;
+const IQH_REG: u64 = 0x80;
+const IQT_REG: u64 = 0x88;
+const IQA_REG: u64 = 0x90;
+const ICS_REG: u64 = 0x98;
+const IECTL_REG: u64 = 0xa0;
+const IEADDR_REG: u64 = 0xa8;
+const IRTA_REG: u64 = 0xb8;
+
+// Version 1.0
+const VERSION: u64 = 0x10;
+// A single fault recording register, right after the registers above, and no
+// supported address width.
+const FAULT_RECORDING_OFFSET: u64 = 0x200;
+const CAPABILITIES: u64 = (FAULT_RECORDING_OFFSET / 16) << 24;
+// Queued invalidation and interrupt remapping, the destinations being xAPIC
+// ones only.
+const EXTENDED_CAPABILITIES: u64 = 1 << 1 | 1 << 3;
+
+// Global command bits, reported by the global status at the same positions
+const GCMD_QIE: u32 = 1 << 26;
+const GCMD_IRE: u32 = 1 << 25;
+const GCMD_SIRTP: u32 = 1 << 24;
+const GCMD_CFI: u32 = 1 << 23;
+
+// Fault status bits cleared by writing 1
+const FSTS_RW1C: u32 = 0x71;
+// Invalidation completion status, cleared by writing 1
+const ICS_IWC: u32 = 1 << 0;
+// Interrupt mask of the fault and invalidation event controls
+const EVENT_MASK: u32 = 1 << 31;
+
+// Invalidation queue address
+const IQA_DW: u64 = 1 << 11;
+const IQA_QS: u64 = 0x7;
+
+// Invalidation descriptors
+const INV_DESC_TYPE: u64 = 0xf;
+const INV_DESC_IEC: u64 = 0x4;
+const INV_DESC_WAIT: u64 = 0x5;
+const INV_WAIT_IF: u64 = 1 << 4;
+const INV_WAIT_SW: u64 = 1 << 5;
+
+// Interrupt remapping table address
+const IRTA_S: u64 = 0xf;
+const IRTE_SIZE: u64 = 16;
+
+// Interrupt remapping table entry
+const IRTE_PRESENT: u64 = 1 << 0;
+const IRTE_POSTED: u64 = 1 << 15;
+
+// Remappable format MSI address
+const MSI_REMAPPABLE: u64 = 1 << 4;
+const MSI_SHV: u64 = 1 << 3;
+
+const PAGE_MASK: u64 = !0xfff;
+
+#[derive(Clone, Versionize)]
+pub struct InterruptRemappingState {
+    gsts: u32,
+    fsts: u32,
+    fectl: u32,
+    fedata: u32,
+    feaddr: u64,
+    iqh: u64,
+    iqt: u64,
+    iqa: u64,
+    ics: u32,
+    iectl: u32,
+    iedata: u32,
+    ieaddr: u64,
+    irta: u64,
+    // Interrupt remapping table address, latched from the register by the
+    // set interrupt remapping table pointer command.
+    irt: u64,
+}
+
+impl VersionMapped for InterruptRemappingState {}
+
+impl Default for InterruptRemappingState {
+    fn default() -> Self {
+        InterruptRemappingState {
+            gsts: 0,
+            fsts: 0,
+            fectl: EVENT_MASK,
+            fedata: 0,
+            feaddr: 0,
+            iqh: 0,
+            iqt: 0,
+            iqa: 0,
+            ics: 0,
+            iectl: EVENT_MASK,
+            iedata: 0,
+            ieaddr: 0,
+            irta: 0,
+            irt: 0,
+        }
+    }
+}
+
+/// Remap the message of the source `source_id` through the interrupt
+/// remapping table entry `irte`, into a compatibility format message.
+fn remap_irte(irte: [u64; 2], source_id: u16) -> io::Result<(u64, u32)> {
+    let (low, high) = (irte[0], irte[1]);
+    if low & IRTE_PRESENT == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "entry not present"));
+    }
+    if low & IRTE_POSTED != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "posted interrupts not supported",
+        ));
+    }
+
+    // Source validation
+    let sid = high as u16;
+    let sq = (high >> 16) & 0x3;
+    let svt = (high >> 18) & 0x3;
+    let allowed = match svt {
+        // No validation
+        0 => true,
+        // Requester identifier, ignoring the function bits given by SQ
+        1 => {
+            let mask: u16 = match sq {
+                0 => 0xffff,
+                1 => 0xfffb,
+                2 => 0xfff9,
+                _ => 0xfff8,
+            };
+            source_id & mask == sid & mask
+        }
+        // Bus of the requester, in the range of the bridge
+        2 => ((sid >> 8)..=(sid & 0xff)).contains(&(source_id >> 8)),
+        _ => false,
+    };
+    if !allowed {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("source 0x{source_id:x} not allowed"),
+        ));
+    }
+
+    let vector = (low >> 16) & 0xff;
+    let delivery_mode = (low >> 5) & 0x7;
+    let trigger_mode = (low >> 4) & 0x1;
+    let redirection_hint = (low >> 3) & 0x1;
+    let destination_mode = (low >> 2) & 0x1;
+    // xAPIC destination
+    let destination = (low >> 40) & 0xff;
+
+    let addr = arch::layout::APIC_START.0
+        | destination << 12
+        | redirection_hint << 3
+        | destination_mode << 2;
+    let data = trigger_mode << 15 | delivery_mode << 8 | vector;
+
+    Ok((addr, data as u32))
+}
+
+/// Interrupt remapping unit, shared by the interrupt managers of the devices
+/// whose messages it remaps.
+pub struct InterruptRemappingUnit {
+    state: Mutex<InterruptRemappingState>,
+    memory: GuestMemoryAtomic<GuestMemoryMmap>,
+    listeners: InterruptRemappingListeners,
+}
+
+impl InterruptRemappingUnit {
+    pub fn new(
+        memory: GuestMemoryAtomic<GuestMemoryMmap>,
+        state: Option<InterruptRemappingState>,
+    ) -> Self {
+        InterruptRemappingUnit {
+            state: Mutex::new(state.unwrap_or_default()),
+            memory,
+            listeners: InterruptRemappingListeners::default(),
+        }
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        let state = self.state.lock().unwrap();
+        match offset {
+            VER_REG => VERSION,
+            CAP_REG => CAPABILITIES,
+            ECAP_REG => EXTENDED_CAPABILITIES,
+            // The global command register reads as 0
+            GCMD_REG => u64::from(state.gsts) << 32,
+            FSTS_REG => u64::from(state.fsts) << 32,
+            FECTL_REG => u64::from(state.fectl) | u64::from(state.fedata) << 32,
+            FEADDR_REG => state.feaddr,
+            IQH_REG => state.iqh,
+            IQT_REG => state.iqt,
+            IQA_REG => state.iqa,
+            ICS_REG => u64::from(state.ics) << 32,
+            IECTL_REG => u64::from(state.iectl) | u64::from(state.iedata) << 32,
+            IEADDR_REG => state.ieaddr,
+            IRTA_REG => state.irta,
+            _ => 0,
+        }
+    }
+
+    // Write the bytes of `value` selected by `mask` in the 64 bits register
+    // at `offset`, returning whether the remapping changed.
+    fn write(&self, offset: u64, value: u64, mask: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let merge = |old: u64| (old & !mask) | (value & mask);
+        match offset {
+            GCMD_REG if mask as u32 != 0 => return self.command(&mut state, value as u32),
+            FSTS_REG => state.fsts &= !(((value & mask) >> 32) as u32 & FSTS_RW1C),
+            FECTL_REG => {
+                let v = merge(u64::from(state.fectl) | u64::from(state.fedata) << 32);
+                state.fectl = v as u32 & EVENT_MASK;
+                state.fedata = (v >> 32) as u32;
+            }
+            FEADDR_REG => state.feaddr = merge(state.feaddr),
+            IQT_REG => {
+                state.iqt = merge(state.iqt) & 0x7fff0;
+                return self.process_invalidation_queue(&mut state);
+            }
+            IQA_REG => state.iqa = merge(state.iqa) & (PAGE_MASK | IQA_DW | IQA_QS),
+            ICS_REG => state.ics &= !(((value & mask) >> 32) as u32 & ICS_IWC),
+            IECTL_REG => {
+                let v = merge(u64::from(state.iectl) | u64::from(state.iedata) << 32);
+                state.iectl = v as u32 & EVENT_MASK;
+                state.iedata = (v >> 32) as u32;
+            }
+            IEADDR_REG => state.ieaddr = merge(state.ieaddr),
+            IRTA_REG => state.irta = merge(state.irta) & (PAGE_MASK | IRTA_S),
+            _ => debug!("Ignoring write to register 0x{:x}", offset),
+        }
+
+        false
+    }
+
+    fn command(&self, state: &mut InterruptRemappingState, command: u32) -> bool {
+        let mut changed = false;
+
+        if command & GCMD_QIE != state.gsts & GCMD_QIE {
+            state.gsts ^= GCMD_QIE;
+            state.iqh = 0;
+        }
+
+        // Setting the table pointer invalidates the interrupt entry cache
+        if command & GCMD_SIRTP != 0 {
+            state.irt = state.irta;
+            state.gsts |= GCMD_SIRTP;
+            changed = true;
+        }
+
+        for bit in [GCMD_IRE, GCMD_CFI] {
+            if command & bit != state.gsts & bit {
+                state.gsts ^= bit;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    // Process the descriptors between the head and the tail of the queue,
+    // returning whether the interrupt entry cache was invalidated.
+    fn process_invalidation_queue(&self, state: &mut InterruptRemappingState) -> bool {
+        if state.gsts & GCMD_QIE == 0 {
+            return false;
+        }
+
+        let memory = self.memory.memory();
+        let base = state.iqa & PAGE_MASK;
+        let size = 0x1000 << (state.iqa & IQA_QS);
+        let descriptor_size = if state.iqa & IQA_DW != 0 { 32 } else { 16 };
+        let tail = state.iqt & (size - 1) & !(descriptor_size - 1);
+        state.iqh &= (size - 1) & !(descriptor_size - 1);
+        let mut changed = false;
+
+        while state.iqh != tail {
+            let addr = GuestAddress(base + state.iqh);
+            let descriptor: [u64; 2] = match memory.read_obj(addr) {
+                Ok(descriptor) => descriptor,
+                Err(e) => {
+                    error!("Failed reading invalidation descriptor: {}", e);
+                    // Invalidation queue error
+                    state.fsts |= 1 << 4;
+                    return changed;
+                }
+            };
+
+            match descriptor[0] & INV_DESC_TYPE {
+                INV_DESC_IEC => changed = true,
+                INV_DESC_WAIT => {
+                    if descriptor[0] & INV_WAIT_SW != 0 {
+                        let status = (descriptor[0] >> 32) as u32;
+                        if let Err(e) = memory.write_obj(status, GuestAddress(descriptor[1] & !0x3))
+                        {
+                            error!("Failed writing invalidation wait status: {}", e);
+                        }
+                    }
+                    if descriptor[0] & INV_WAIT_IF != 0 {
+                        state.ics |= ICS_IWC;
+                    }
+                }
+                // Nothing is cached besides the interrupt entries
+                t => debug!("Ignoring invalidation descriptor type {}", t),
+            }
+
+            state.iqh = (state.iqh + descriptor_size) % size;
+        }
+
+        changed
+    }
+}
+
+impl InterruptRemapping for InterruptRemappingUnit {
+    fn remap_msi(&self, id: u32, addr: u64, data: u32) -> io::Result<(u64, u32)> {
+        let state = self.state.lock().unwrap();
+        if state.gsts & GCMD_IRE == 0 {
+            return Ok((addr, data));
+        }
+
+        // Compatibility format messages go through unless the guest blocked
+        // them, as it does once it relies on interrupt remapping.
+        if addr & MSI_REMAPPABLE == 0 {
+            return if state.gsts & GCMD_CFI != 0 {
+                Ok((addr, data))
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "compatibility format interrupts blocked",
+                ))
+            };
+        }
+
+        let handle = (addr >> 5) & 0x7fff | ((addr >> 2) & 0x1) << 15;
+        let index = if addr & MSI_SHV != 0 {
+            handle + u64::from(data & 0xffff)
+        } else {
+            handle
+        };
+        if index >= 2 << (state.irt & IRTA_S) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("interrupt index {index} beyond the table"),
+            ));
+        }
+
+        let irte: [u64; 2] = self
+            .memory
+            .memory()
+            .read_obj(GuestAddress((state.irt & PAGE_MASK) + index * IRTE_SIZE))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        remap_irte(irte, id as u16).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("interrupt index {index}: {e}"),
+            )
+        })
+    }
+
+    fn add_listener(&self, listener: Weak<dyn InterruptRemappingListener>) {
+        self.listeners.add(listener);
+    }
+}
+
+/// Register set of the interrupt remapping unit.
+pub struct InterruptRemappingDevice {
+    id: String,
+    unit: Arc<InterruptRemappingUnit>,
+}
+
+impl InterruptRemappingDevice {
+    pub fn new(id: String, unit: Arc<InterruptRemappingUnit>) -> Self {
+        InterruptRemappingDevice { id, unit }
+    }
+}
+
+impl BusDevice for InterruptRemappingDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if !matches!(data.len(), 4 | 8) || offset % data.len() as u64 != 0 {
+            warn!("Invalid read at 0x{:x} of {} bytes", offset, data.len());
+            data.fill(0);
+            return;
+        }
+
+        let value = self.unit.read(offset & !0x7) >> ((offset & 0x7) * 8);
+        data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) -> Option<Arc<Barrier>> {
+        if !matches!(data.len(), 4 | 8) || offset % data.len() as u64 != 0 {
+            warn!("Invalid write at 0x{:x} of {} bytes", offset, data.len());
+            return None;
+        }
+
+        let shift = (offset & 0x7) * 8;
+        let mut bytes = [0u8; 8];
+        bytes[..data.len()].copy_from_slice(data);
+        let value = u64::from_le_bytes(bytes) << shift;
+        let mask = if data.len() == 8 {
+            u64::MAX
+        } else {
+            0xffff_ffff << shift
+        };
+
+        // The messages are remapped again once the lock of the unit is
+        // released, as remapping them takes it.
+        if self.unit.write(offset & !0x7, value, mask) {
+            self.unit.listeners.notify();
+        }
+
+        None
+    }
+}
+
+impl Snapshottable for InterruptRemappingDevice {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_versioned_state(&self.unit.state.lock().unwrap().clone())
+    }
+}
+
+impl Pausable for InterruptRemappingDevice {}
+impl Transportable for InterruptRemappingDevice {}
+impl Migratable for InterruptRemappingDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const IRT_ADDR: u64 = 0x1000;
+    const IQ_ADDR: u64 = 0x2000;
+    const STATUS_ADDR: u64 = 0x3000;
+
+    // IRTE of a fixed edge interrupt, vector 0x31 for the APIC 2, only
+    // allowed from the source 00:03.0.
+    const IRTE: [u64; 2] = [0x0000_0200_0031_0001, 0x0004_0018];
+
+    #[derive(Default)]
+    struct TestListener {
+        count: AtomicUsize,
+    }
+
+    impl InterruptRemappingListener for TestListener {
+        fn remapping_changed(&self) {
+            self.count.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn write_reg(device: &mut InterruptRemappingDevice, offset: u64, value: u64) {
+        device.write(0, offset, &value.to_le_bytes());
+    }
+
+    fn read_reg(device: &mut InterruptRemappingDevice, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_remap_irte() {
+        let (addr, data) = remap_irte(IRTE, 0x18).unwrap();
+        assert_eq!(addr, 0xfee0_2000);
+        assert_eq!(data, 0x31);
+
+        // Level triggered lowest priority in logical mode
+        let (addr, data) = remap_irte([IRTE[0] | 0x3c, 0], 0x20).unwrap();
+        assert_eq!(addr, 0xfee0_200c);
+        assert_eq!(data, 0x8131);
+
+        // Source validation
+        assert!(remap_irte(IRTE, 0x19).is_err());
+        assert!(remap_irte([IRTE[0], IRTE[1] | 0x3 << 16], 0x1f).is_ok());
+        assert!(remap_irte([IRTE[0], 0x0008_0204], 0x0318).is_ok());
+        assert!(remap_irte([IRTE[0], 0x0008_0204], 0x0518).is_err());
+
+        // Not present and posted entries
+        assert!(remap_irte([IRTE[0] & !IRTE_PRESENT, IRTE[1]], 0x18).is_err());
+        assert!(remap_irte([IRTE[0] | IRTE_POSTED, IRTE[1]], 0x18).is_err());
+    }
+
+    #[test]
+    fn test_interrupt_remapping_unit() {
+        let memory = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap(),
+        );
+        let unit = Arc::new(InterruptRemappingUnit::new(memory.clone(), None));
+        let mut device = InterruptRemappingDevice::new("__dmar".to_string(), unit.clone());
+        let listener = Arc::new(TestListener::default());
+        let weak: Weak<dyn InterruptRemappingListener> = Arc::downgrade(&listener);
+        unit.add_listener(weak);
+
+        let remappable = 0xfee0_0000 | 3 << 5 | MSI_REMAPPABLE;
+
+        // Messages go through until interrupt remapping is enabled
+        assert_eq!(
+            unit.remap_msi(0x18, 0xfee0_1000, 0x30).unwrap(),
+            (0xfee0_1000, 0x30)
+        );
+
+        memory
+            .memory()
+            .write_obj(IRTE, GuestAddress(IRT_ADDR + 3 * IRTE_SIZE))
+            .unwrap();
+        write_reg(&mut device, IRTA_REG, IRT_ADDR | 0x3);
+        write_reg(&mut device, GCMD_REG, u64::from(GCMD_SIRTP));
+        assert_eq!(read_reg(&mut device, 0x1c), GCMD_SIRTP);
+        write_reg(&mut device, GCMD_REG, u64::from(GCMD_IRE));
+        assert_eq!(read_reg(&mut device, 0x1c), GCMD_SIRTP | GCMD_IRE);
+        assert_eq!(listener.count.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            unit.remap_msi(0x18, remappable, 0).unwrap(),
+            (0xfee0_2000, 0x31)
+        );
+        // Wrong source, index beyond the table, entry not present, and
+        // blocked compatibility format
+        assert!(unit.remap_msi(0x20, remappable, 0).is_err());
+        assert!(unit.remap_msi(0x18, remappable | MSI_SHV, 13).is_err());
+        assert!(unit.remap_msi(0x18, remappable | MSI_SHV, 1).is_err());
+        assert!(unit.remap_msi(0x18, 0xfee0_1000, 0x30).is_err());
+
+        // The entry is updated and its cache invalidated through the queue,
+        // along with a wait descriptor writing its status
+        memory
+            .memory()
+            .write_obj(
+                [IRTE[0] | 0x4 << 40, IRTE[1]],
+                GuestAddress(IRT_ADDR + 3 * IRTE_SIZE),
+            )
+            .unwrap();
+        let descriptors: [u64; 4] = [
+            INV_DESC_IEC,
+            0,
+            INV_DESC_WAIT | INV_WAIT_SW | 1 << 32,
+            STATUS_ADDR,
+        ];
+        memory
+            .memory()
+            .write_obj(descriptors, GuestAddress(IQ_ADDR))
+            .unwrap();
+        write_reg(&mut device, IQA_REG, IQ_ADDR);
+        write_reg(&mut device, GCMD_REG, u64::from(GCMD_IRE | GCMD_QIE));
+        write_reg(&mut device, IQT_REG, 0x20);
+        assert_eq!(read_reg(&mut device, IQH_REG), 0x20);
+        assert_eq!(
+            memory
+                .memory()
+                .read_obj::<u32>(GuestAddress(STATUS_ADDR))
+                .unwrap(),
+            1
+        );
+        assert_eq!(listener.count.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            unit.remap_msi(0x18, remappable, 0).unwrap(),
+            (0xfee0_6000, 0x31)
+        );
+
+        // Disabling interrupt remapping lets the messages through again
+        write_reg(&mut device, GCMD_REG, u64::from(GCMD_QIE));
+        assert_eq!(listener.count.load(Ordering::SeqCst), 4);
+        assert_eq!(
+            unit.remap_msi(0x18, 0xfee0_1000, 0x30).unwrap(),
+            (0xfee0_1000, 0x30)
+        );
+    }
+}
@@ -0,0 +1,472 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Jailer confining the VMM into its own root directory.
+//!
+//! The VMM moves into a private mount namespace, bind-mounts the few device
+//! nodes it may need, along with the resources of the VM, below the jail
+//! directory, and pivots into it, so that nothing else of the host filesystem
+//! remains reachable. It then drops its privileges by switching to the given
+//! user and group.
+//!
+//! Both the mount namespace and the credentials can only be changed for the
+//! whole process while it is single threaded, hence the jail being entered
+//! when the VMM starts, before the guest boots. The files opened beforehand,
+//! such as the hypervisor device or the file descriptors handed over to the
+//! VMM, remain usable, while the resources opened later on, such as the disk
+//! images, must be accessible to the unprivileged user.
+
+use crate::landlock::config_paths;
+use crate::vm_config::{VhostMode, VmConfig};
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Device nodes any VM may rely on once jailed
+const DEVICE_NODES: [&str; 4] = ["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot get the current directory: {0}")]
+    CurrentDir(#[source] io::Error),
+
+    #[error("Cannot create a mount namespace: {0}")]
+    Unshare(#[source] io::Error),
+
+    #[error("Cannot create the mount point {0}: {1}")]
+    CreateMountPoint(PathBuf, #[source] io::Error),
+
+    #[error("Cannot mount {0}: {1}")]
+    Mount(PathBuf, #[source] io::Error),
+
+    #[error("Cannot pivot into the jail {0}: {1}")]
+    PivotRoot(PathBuf, #[source] io::Error),
+
+    #[error("Cannot drop the privileges: {0}")]
+    DropPrivileges(#[source] io::Error),
+
+    #[error("Cannot resolve the path {0}: {1}")]
+    ResolvePath(PathBuf, #[source] io::Error),
+
+    #[error("Cannot bind-mount {1} for {0} into the jail, it holds the host root or the jail")]
+    UnsafeBindSource(PathBuf, PathBuf),
+
+    #[error("Cannot bind-mount {1} for {0} into the jail, the directory is shared with others")]
+    SharedBindDirectory(PathBuf, PathBuf),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct JailConfig {
+    /// Directory becoming the root of the VMM.
+    pub path: PathBuf,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Paths bind-mounted into the jail on top of the ones from the VM
+    /// configuration.
+    pub bind: Vec<PathBuf>,
+}
+
+fn cstring(path: &Path) -> CString {
+    // Paths coming from the configuration can't hold null bytes
+    CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn bind_mount(source: &Path, target: &Path) -> Result<()> {
+    let source_c = cstring(source);
+    let target_c = cstring(target);
+    // SAFETY: FFI call with valid null terminated strings
+    check(unsafe {
+        libc::mount(
+            source_c.as_ptr(),
+            target_c.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND | libc::MS_REC,
+            std::ptr::null(),
+        )
+    })
+    .map_err(|e| Error::Mount(source.to_path_buf(), e))
+}
+
+// Bind mounts need an existing target of the same kind as their source.
+fn create_mount_point(source: &Path, target: &Path) -> Result<()> {
+    let res = if source.is_dir() {
+        fs::create_dir_all(target)
+    } else {
+        fs::create_dir_all(target.parent().unwrap()).and_then(|_| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(target)
+                .map(|_| ())
+        })
+    };
+    res.map_err(|e| Error::CreateMountPoint(target.to_path_buf(), e))
+}
+
+// The directory holding a file the VMM creates gets bind-mounted in its
+// entirety, which must not expose anything but the files of the VM. The
+// world writable directories with the sticky bit set, such as /tmp, are
+// shared by design.
+fn is_shared_dir(dir: &Path) -> io::Result<bool> {
+    let mode = fs::metadata(dir)?.permissions().mode();
+    Ok(mode & 0o1002 == 0o1002)
+}
+
+// Sockets of the backends the VMM connects to. Landlock doesn't restrict
+// connecting to them, hence their absence from the Landlock rules.
+fn client_sockets(vm_config: &VmConfig) -> Vec<PathBuf> {
+    let mut sockets = Vec::new();
+
+    for disk in vm_config.disks.iter().flatten() {
+        if let Some(socket) = disk.vhost_socket.as_ref().filter(|_| disk.vhost_user) {
+            sockets.push(PathBuf::from(socket));
+        }
+    }
+    for net in vm_config.net.iter().flatten() {
+        if let Some(socket) = &net.vhost_socket {
+            if net.vhost_mode == VhostMode::Client {
+                sockets.push(PathBuf::from(socket));
+            }
+        }
+    }
+    sockets.extend(vm_config.fs.iter().flatten().map(|fs| fs.socket.clone()));
+    sockets.extend(
+        vm_config
+            .user_devices
+            .iter()
+            .flatten()
+            .map(|device| device.socket.clone()),
+    );
+    sockets.extend(vm_config.tpm.iter().map(|tpm| tpm.socket.clone()));
+
+    sockets
+}
+
+// Bind source of the socket of a backend. Its directory is preferred, for the
+// VMM to reach the socket the backend creates again when restarted, unless
+// the directory is shared with others, in which case only the socket itself
+// is bind-mounted.
+fn client_socket_source(path: PathBuf, current_dir: &Path, jail: &Path) -> Result<PathBuf> {
+    let absolute = current_dir.join(&path);
+    if let Some(parent) = absolute.parent().filter(|p| p.exists()) {
+        let parent =
+            fs::canonicalize(parent).map_err(|e| Error::ResolvePath(parent.to_path_buf(), e))?;
+        let shared = is_shared_dir(&parent).map_err(|e| Error::ResolvePath(parent.clone(), e))?;
+        if parent.parent().is_some() && !jail.starts_with(&parent) && !shared {
+            return Ok(parent);
+        }
+        if !absolute.exists() {
+            return Err(Error::SharedBindDirectory(path, parent));
+        }
+    }
+
+    fs::canonicalize(&absolute).map_err(|e| Error::ResolvePath(absolute, e))
+}
+
+// Absolute paths to bind-mount into the jail. The missing paths, such as
+// files the VMM creates, and the sockets it listens on, which it creates
+// again, are replaced by their directory, and the paths found below another
+// one are left out as the latter covers them. The sources holding the host
+// root or the jail, which would give the whole host filesystem or the other
+// jails away, are refused, as are the shared directories of the files the VMM
+// creates. The sockets of the backends are bind-mounted along with them.
+fn bind_sources(
+    paths: Vec<PathBuf>,
+    sockets: Vec<PathBuf>,
+    current_dir: &Path,
+    jail: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut sources = Vec::new();
+    for socket in sockets {
+        sources.push(client_socket_source(socket, current_dir, jail)?);
+    }
+    for path in paths {
+        let absolute = current_dir.join(&path);
+        let recreated = match fs::symlink_metadata(&absolute) {
+            Ok(metadata) => metadata.file_type().is_socket(),
+            Err(_) => true,
+        };
+        let source = if recreated {
+            match absolute.parent().filter(|p| p.exists()) {
+                Some(parent) => parent.to_path_buf(),
+                None => continue,
+            }
+        } else {
+            absolute
+        };
+        let source =
+            fs::canonicalize(&source).map_err(|e| Error::ResolvePath(source.clone(), e))?;
+
+        if source.parent().is_none() || jail.starts_with(&source) {
+            return Err(Error::UnsafeBindSource(path, source));
+        }
+        if recreated && is_shared_dir(&source).map_err(|e| Error::ResolvePath(source.clone(), e))? {
+            return Err(Error::SharedBindDirectory(path, source));
+        }
+        sources.push(source);
+    }
+    sources.sort();
+
+    let mut kept: Vec<PathBuf> = Vec::new();
+    for source in sources {
+        if !kept.iter().any(|k| source.starts_with(k)) {
+            kept.push(source);
+        }
+    }
+
+    Ok(kept)
+}
+
+// Pivoting onto the current directory stacks the old root on top of the new
+// one, which is then detached, without needing a directory to hold it.
+fn pivot_root(jail: &Path) -> io::Result<()> {
+    std::env::set_current_dir(jail)?;
+    let dot = b".\0".as_ptr() as *const libc::c_char;
+    // SAFETY: FFI call with valid null terminated strings
+    check(unsafe { libc::syscall(libc::SYS_pivot_root, dot, dot) } as libc::c_int)?;
+    // SAFETY: FFI call with a valid null terminated string
+    check(unsafe { libc::umount2(dot, libc::MNT_DETACH) })?;
+    std::env::set_current_dir("/")
+}
+
+fn drop_privileges(uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+
+    // SAFETY: FFI call without any pointer, the supplementary groups being
+    // cleared
+    check(unsafe { libc::setgroups(0, std::ptr::null()) }).map_err(Error::DropPrivileges)?;
+    if let Some(gid) = gid {
+        // SAFETY: FFI call with integer arguments
+        check(unsafe { libc::setresgid(gid, gid, gid) }).map_err(Error::DropPrivileges)?;
+    }
+    if let Some(uid) = uid {
+        // SAFETY: FFI call with integer arguments
+        check(unsafe { libc::setresuid(uid, uid, uid) }).map_err(Error::DropPrivileges)?;
+    }
+
+    Ok(())
+}
+
+/// Confine the VMM into the jail, with the resources of the VM configuration,
+/// if any, and the extra paths bind-mounted into it, then drop its
+/// privileges. This must be called before any thread gets started.
+pub fn enter_jail(
+    config: &JailConfig,
+    vm_config: Option<&VmConfig>,
+    extra_paths: &[PathBuf],
+) -> Result<()> {
+    let current_dir = std::env::current_dir().map_err(Error::CurrentDir)?;
+    let jail = current_dir.join(&config.path);
+    let jail = fs::canonicalize(&jail).map_err(|e| Error::ResolvePath(jail, e))?;
+
+    let mut paths: Vec<PathBuf> = std::iter::once("/proc")
+        .chain(DEVICE_NODES)
+        .map(PathBuf::from)
+        .collect();
+    let mut sockets = Vec::new();
+    if let Some(vm_config) = vm_config {
+        paths.extend(config_paths(vm_config));
+        sockets = client_sockets(vm_config);
+    }
+    paths.extend(config.bind.iter().cloned());
+    paths.extend(extra_paths.iter().cloned());
+    let sources = bind_sources(paths, sockets, &current_dir, &jail)?;
+
+    // SAFETY: FFI call with a valid flag
+    check(unsafe { libc::unshare(libc::CLONE_NEWNS) }).map_err(Error::Unshare)?;
+    // Keep the mounts of the jail from propagating back to the host
+    // SAFETY: FFI call with valid null terminated strings
+    check(unsafe {
+        libc::mount(
+            std::ptr::null(),
+            b"/\0".as_ptr() as *const libc::c_char,
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    })
+    .map_err(|e| Error::Mount(PathBuf::from("/"), e))?;
+
+    // The new root must be a mount point
+    bind_mount(&jail, &jail)?;
+    for source in sources {
+        let target = jail.join(source.strip_prefix("/").unwrap());
+        create_mount_point(&source, &target)?;
+        bind_mount(&source, &target)?;
+    }
+    // Relative paths from the configuration are resolved against the same
+    // directory once jailed.
+    let jailed_current_dir = jail.join(current_dir.strip_prefix("/").unwrap());
+    fs::create_dir_all(&jailed_current_dir)
+        .map_err(|e| Error::CreateMountPoint(jailed_current_dir, e))?;
+
+    pivot_root(&jail).map_err(|e| Error::PivotRoot(jail.clone(), e))?;
+    std::env::set_current_dir(&current_dir).map_err(|e| Error::PivotRoot(jail.clone(), e))?;
+
+    drop_privileges(config.uid, config.gid)?;
+    info!("Entered the jail {}", jail.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_config::{DiskConfig, FsConfig, NetConfig, TpmConfig};
+
+    #[test]
+    fn test_bind_sources() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch-jail").unwrap();
+        let dir = dir.as_path();
+        fs::create_dir(dir.join("images")).unwrap();
+        fs::write(dir.join("images/disk.raw"), b"").unwrap();
+
+        assert_eq!(
+            bind_sources(
+                vec![
+                    PathBuf::from("images/disk.raw"),
+                    dir.join("images"),
+                    PathBuf::from("serial.log"),
+                    PathBuf::from("/proc/self"),
+                    PathBuf::from("/proc"),
+                    PathBuf::from("/nonexistent/file"),
+                ],
+                Vec::new(),
+                dir,
+                Path::new("/srv/jail")
+            )
+            .unwrap(),
+            vec![PathBuf::from("/proc"), fs::canonicalize(dir).unwrap()]
+        );
+
+        // The host root and the ancestors of the jail are refused
+        assert!(matches!(
+            bind_sources(
+                vec![PathBuf::from("/")],
+                Vec::new(),
+                dir,
+                Path::new("/srv/jail")
+            ),
+            Err(Error::UnsafeBindSource(..))
+        ));
+        assert!(matches!(
+            bind_sources(
+                vec![PathBuf::from("images")],
+                Vec::new(),
+                dir,
+                &fs::canonicalize(dir).unwrap().join("images/jail")
+            ),
+            Err(Error::UnsafeBindSource(..))
+        ));
+        assert!(matches!(
+            bind_sources(
+                vec![PathBuf::from("api.sock")],
+                Vec::new(),
+                Path::new("/"),
+                Path::new("/srv/jail")
+            ),
+            Err(Error::UnsafeBindSource(..))
+        ));
+        // The directory of a missing file is only bind-mounted when dedicated
+        assert!(matches!(
+            bind_sources(
+                vec![PathBuf::from("/tmp/ch-missing.sock")],
+                Vec::new(),
+                dir,
+                Path::new("/srv/jail")
+            ),
+            Err(Error::SharedBindDirectory(..))
+        ));
+    }
+
+    #[test]
+    fn test_client_sockets() {
+        let dir = vmm_sys_util::tempdir::TempDir::new_with_prefix("/tmp/ch-jail").unwrap();
+        let dir = fs::canonicalize(dir.as_path()).unwrap();
+        fs::create_dir(dir.join("virtiofsd")).unwrap();
+        fs::write(dir.join("tpm.sock"), b"").unwrap();
+
+        let mut vm_config: VmConfig =
+            serde_json::from_str(r#"{"payload": {"kernel": "/path/to/kernel"}}"#).unwrap();
+        vm_config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            vhost_socket: Some("/run/vhost-blk.sock".to_string()),
+            ..Default::default()
+        }]);
+        vm_config.net = Some(vec![
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/run/vhost-net.sock".to_string()),
+                ..Default::default()
+            },
+            NetConfig {
+                vhost_user: true,
+                vhost_socket: Some("/run/net.sock".to_string()),
+                vhost_mode: VhostMode::Server,
+                ..Default::default()
+            },
+        ]);
+        vm_config.fs = Some(vec![FsConfig {
+            tag: "fs0".to_string(),
+            socket: PathBuf::from("virtiofsd/fs.sock"),
+            ..Default::default()
+        }]);
+        vm_config.tpm = Some(TpmConfig {
+            socket: PathBuf::from("tpm.sock"),
+        });
+        let sockets = client_sockets(&vm_config);
+        assert_eq!(
+            sockets,
+            vec![
+                PathBuf::from("/run/vhost-blk.sock"),
+                PathBuf::from("/run/vhost-net.sock"),
+                PathBuf::from("virtiofsd/fs.sock"),
+                PathBuf::from("tpm.sock"),
+            ]
+        );
+
+        // The dedicated directory of a socket is bind-mounted, for the
+        // backend to create it again
+        assert_eq!(
+            client_socket_source(
+                PathBuf::from("virtiofsd/fs.sock"),
+                &dir,
+                Path::new("/srv/jail")
+            )
+            .unwrap(),
+            dir.join("virtiofsd")
+        );
+        // Only the socket is when its directory is shared, or holds the jail
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o1777)).unwrap();
+        assert_eq!(
+            client_socket_source(PathBuf::from("tpm.sock"), &dir, Path::new("/srv/jail")).unwrap(),
+            dir.join("tpm.sock")
+        );
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(
+            client_socket_source(PathBuf::from("tpm.sock"), &dir, &dir.join("jail")).unwrap(),
+            dir.join("tpm.sock")
+        );
+        assert!(matches!(
+            client_socket_source(PathBuf::from("missing.sock"), &dir, &dir.join("jail")),
+            Err(Error::SharedBindDirectory(..))
+        ));
+    }
+}
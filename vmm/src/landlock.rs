@@ -0,0 +1,576 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Filesystem sandboxing of the VMM through Landlock.
+//!
+//! Once the VM is created, the VMM thread restricts itself, and every thread
+//! it spawns afterwards such as the vCPU and device threads, to the paths
+//! found in the configuration of the VM: the payload, the disk images, the
+//! memory backing files, the sockets the VMM listens on, and so on, along
+//! with the few device nodes the configured devices rely on. Any other path
+//! can be allowed explicitly through `--landlock-rules`. Connecting to the
+//! sockets of the vhost-user backends or of the TPM isn't restricted by
+//! Landlock, these don't need any rule.
+//!
+//! The restrictions can't be lifted, they remain in place after the VM is
+//! deleted. The threads started before the VM is created, such as the API
+//! threads, aren't restricted.
+
+use crate::vm_config::{ConsoleOutputMode, LandlockAccess, LandlockConfig, VmConfig};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+// Access rights of the first version of the Landlock ABI
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+const LANDLOCK_ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+const LANDLOCK_ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+const LANDLOCK_ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+
+const HANDLED_ACCESS_FS: u64 = LANDLOCK_ACCESS_FS_EXECUTE
+    | LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_READ_FILE
+    | LANDLOCK_ACCESS_FS_READ_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_CHAR
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_FIFO
+    | LANDLOCK_ACCESS_FS_MAKE_BLOCK
+    | LANDLOCK_ACCESS_FS_MAKE_SYM;
+
+// Rights which only apply to the content of a directory
+const DIR_READ_ACCESS: u64 = LANDLOCK_ACCESS_FS_READ_DIR;
+const DIR_WRITE_ACCESS: u64 = LANDLOCK_ACCESS_FS_REMOVE_DIR
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG
+    | LANDLOCK_ACCESS_FS_MAKE_SOCK
+    | LANDLOCK_ACCESS_FS_MAKE_FIFO
+    | LANDLOCK_ACCESS_FS_MAKE_SYM;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Landlock is not supported by the host kernel: {0}")]
+    Unsupported(#[source] io::Error),
+
+    #[error("Cannot create the Landlock ruleset: {0}")]
+    CreateRuleset(#[source] io::Error),
+
+    #[error("Cannot open {0} for a Landlock rule: {1}")]
+    OpenPath(PathBuf, #[source] io::Error),
+
+    #[error("Cannot add the Landlock rule for {0}: {1}")]
+    AddRule(PathBuf, #[source] io::Error),
+
+    #[error("Cannot restrict the VMM with Landlock: {0}")]
+    RestrictSelf(#[source] io::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Access {
+    Path(LandlockAccess),
+    // Sockets the VMM listens on, created and removed in their directory.
+    // Connecting to a socket isn't restricted by Landlock.
+    Listener,
+    // Directory where the VMM creates and removes the sockets it listens on.
+    ListenerDirectory,
+}
+
+pub struct Landlock {
+    ruleset: File,
+}
+
+impl Landlock {
+    pub fn new() -> Result<Self> {
+        // SAFETY: FFI call querying the ABI version, without any attribute
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<LandlockRulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 0 {
+            return Err(Error::Unsupported(io::Error::last_os_error()));
+        }
+
+        let attr = LandlockRulesetAttr {
+            handled_access_fs: HANDLED_ACCESS_FS,
+        };
+        // SAFETY: FFI call with a valid attribute and its size
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const LandlockRulesetAttr,
+                size_of::<LandlockRulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::CreateRuleset(io::Error::last_os_error()));
+        }
+
+        Ok(Landlock {
+            // SAFETY: fd is a valid file descriptor we own
+            ruleset: unsafe { File::from_raw_fd(fd as i32) },
+        })
+    }
+
+    /// Allow the given access to the path, and to everything beneath it if
+    /// it is a directory.
+    pub fn add_rule(&mut self, path: &Path, access: LandlockAccess) -> Result<()> {
+        let is_dir = path.is_dir();
+        let mut allowed_access = 0;
+        if access.readable() {
+            allowed_access |= LANDLOCK_ACCESS_FS_READ_FILE;
+            if is_dir {
+                allowed_access |= DIR_READ_ACCESS;
+            }
+        }
+        if access.writable() {
+            allowed_access |= LANDLOCK_ACCESS_FS_WRITE_FILE;
+            if is_dir {
+                allowed_access |= DIR_WRITE_ACCESS;
+            }
+        }
+
+        // A file which doesn't exist yet is created in its directory
+        if access.writable() && !path.exists() {
+            if let Some(parent) = path.parent().filter(|p| p.is_dir()) {
+                allowed_access |= LANDLOCK_ACCESS_FS_MAKE_REG;
+                return self.add_path_beneath(parent, allowed_access);
+            }
+        }
+
+        self.add_path_beneath(path, allowed_access)
+    }
+
+    fn add_listener_rule(&mut self, socket: &Path) -> Result<()> {
+        let parent = match socket.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        self.add_listener_directory_rule(parent)
+    }
+
+    fn add_listener_directory_rule(&mut self, dir: &Path) -> Result<()> {
+        self.add_path_beneath(
+            dir,
+            LANDLOCK_ACCESS_FS_MAKE_SOCK | LANDLOCK_ACCESS_FS_REMOVE_FILE,
+        )
+    }
+
+    fn add_path_beneath(&mut self, path: &Path, allowed_access: u64) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+            .map_err(|e| Error::OpenPath(path.to_path_buf(), e))?;
+
+        let attr = LandlockPathBeneathAttr {
+            allowed_access,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: FFI call with valid file descriptors and attribute
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                self.ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const LandlockPathBeneathAttr,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::AddRule(
+                path.to_path_buf(),
+                io::Error::last_os_error(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Enforce the ruleset on the calling thread and the threads it spawns.
+    pub fn restrict_self(self) -> Result<()> {
+        // Required for a process without CAP_SYS_ADMIN to restrict itself
+        // SAFETY: FFI call with valid arguments
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(Error::RestrictSelf(io::Error::last_os_error()));
+        }
+
+        // SAFETY: FFI call with a valid ruleset file descriptor
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_restrict_self,
+                self.ruleset.as_raw_fd(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::RestrictSelf(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+}
+
+// Paths the VMM needs to access to run the VM
+fn config_rules(vm_config: &VmConfig) -> Vec<(PathBuf, Access)> {
+    let mut rules = Vec::new();
+    let mut add = |path: &Path, access: Access| rules.push((path.to_path_buf(), access));
+    let read = Access::Path(LandlockAccess::Read);
+    let read_write = Access::Path(LandlockAccess::ReadWrite);
+
+    // Counters and resources of the VMM threads
+    add(Path::new("/proc/self"), read);
+
+    if let Some(payload) = &vm_config.payload {
+        for path in [&payload.firmware, &payload.kernel, &payload.initramfs]
+            .into_iter()
+            .flatten()
+        {
+            add(path, read);
+        }
+        #[cfg(feature = "sev_snp")]
+        if let Some(igvm) = &payload.igvm {
+            add(igvm, read);
+        }
+    }
+
+    for zone in vm_config.memory.zones.iter().flatten() {
+        if let Some(file) = &zone.file {
+            add(file, read_write);
+        } else if zone.hugepages {
+            add(Path::new("/dev/hugepages"), read);
+        }
+    }
+    if vm_config.memory.hugepages {
+        add(Path::new("/dev/hugepages"), read);
+    }
+
+    for disk in vm_config.disks.iter().flatten() {
+        if let Some(path) = &disk.path {
+            let access = if disk.readonly {
+                LandlockAccess::Read
+            } else {
+                LandlockAccess::ReadWrite
+            };
+            add(path, Access::Path(access));
+        }
+    }
+
+    for net in vm_config.net.iter().flatten() {
+        if let Some(socket) = &net.vhost_socket {
+            if net.vhost_mode == crate::vm_config::VhostMode::Server {
+                add(Path::new(socket), Access::Listener);
+            }
+        } else if net.fds.is_none() {
+            add(Path::new("/dev/net/tun"), read_write);
+            add(Path::new("/sys/class/net"), read);
+        }
+    }
+
+    add(&vm_config.rng.src, read);
+
+    for pmem in vm_config.pmem.iter().flatten() {
+        let access = if pmem.discard_writes {
+            LandlockAccess::Read
+        } else {
+            LandlockAccess::ReadWrite
+        };
+        add(&pmem.file, Access::Path(access));
+    }
+
+    // The files shared as is. The memory shared through a server is received
+    // over its socket instead, the connection not being restricted.
+    for path in vm_config.ivshmem.iter().flatten().flat_map(|i| &i.path) {
+        add(path, read_write);
+    }
+
+    let consoles = [&vm_config.serial, &vm_config.console];
+    for console in consoles {
+        match console.mode {
+            ConsoleOutputMode::File => {
+                if let Some(file) = &console.file {
+                    add(file, read_write);
+                }
+            }
+            ConsoleOutputMode::Socket => {
+                if let Some(socket) = &console.socket {
+                    add(socket, Access::Listener);
+                }
+            }
+            ConsoleOutputMode::Pty => {
+                add(Path::new("/dev/ptmx"), read_write);
+                add(Path::new("/dev/pts"), read_write);
+            }
+            _ => {}
+        }
+    }
+    // Ports not given a socket get one in the socket directory.
+    for socket_dir in consoles.iter().flat_map(|c| &c.socket_dir) {
+        add(socket_dir, Access::ListenerDirectory);
+    }
+    for port in vm_config.console_ports.iter().flatten() {
+        match (&port.socket, &port.file) {
+            (Some(socket), _) => add(socket, Access::Listener),
+            (None, Some(file)) => add(file, read_write),
+            (None, None) => {
+                add(Path::new("/dev/ptmx"), read_write);
+                add(Path::new("/dev/pts"), read_write);
+            }
+        }
+    }
+    if let Some(debug_console) = &vm_config.debug_console {
+        if let Some(file) = &debug_console.file {
+            add(file, read_write);
+        }
+    }
+
+    for device in vm_config.devices.iter().flatten() {
+        add(&device.path, read_write);
+        add(Path::new("/dev/vfio"), read_write);
+    }
+
+    for vdpa in vm_config.vdpa.iter().flatten() {
+        add(&vdpa.path, read_write);
+    }
+
+    if let Some(vsock) = &vm_config.vsock {
+        add(&vsock.socket, Access::Listener);
+        for listener in vsock.listeners.iter().flatten() {
+            add(&listener.socket, Access::Listener);
+        }
+    }
+
+    if let Some(pvpanic_policy) = &vm_config.pvpanic_policy {
+        if let Some(coredump_path) = &pvpanic_policy.coredump_path {
+            add(coredump_path, read_write);
+        }
+        if let Some(kdump_dir) = &pvpanic_policy.kdump_dir {
+            add(kdump_dir, read_write);
+        }
+    }
+
+    if let Some(coredump_dir) = vm_config
+        .on_crash
+        .as_ref()
+        .and_then(|on_crash| on_crash.coredump_dir.as_ref())
+    {
+        add(coredump_dir, read_write);
+    }
+
+    for table in vm_config.acpi_tables.iter().flatten() {
+        add(&table.path, read);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if let Some(uefi_vars) = &vm_config.uefi_vars {
+        add(&uefi_vars.path, read_write);
+        let certs = uefi_vars.pk.iter().chain(
+            uefi_vars
+                .kek
+                .iter()
+                .flatten()
+                .chain(uefi_vars.db.iter().flatten()),
+        );
+        for cert in certs {
+            add(cert, read);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if vm_config.sgx_epc.is_some() {
+        add(Path::new("/dev/sgx_provision"), read_write);
+        add(Path::new("/dev/sgx_vepc"), read_write);
+    }
+
+    if vm_config.cpus.cpu_max.is_some()
+        || vm_config.cpus.vcpu_max.is_some()
+        || vm_config.cgroup.is_some()
+    {
+        add(Path::new("/sys/fs/cgroup"), read_write);
+    }
+
+    if vm_config
+        .process_limits
+        .as_ref()
+        .map_or(false, |l| l.oom_score_adj.is_some())
+    {
+        add(Path::new("/proc/self/oom_score_adj"), read_write);
+    }
+
+    // Host memory pressure, for the balloon to reclaim memory from the guest
+    if vm_config
+        .balloon
+        .as_ref()
+        .map_or(false, |b| b.reclaim_below.is_some())
+    {
+        add(Path::new("/proc/meminfo"), read);
+
+        // Idle page tracking, for the cold memory of the overcommittable
+        // zones to be paged out
+        if vm_config
+            .memory
+            .zones
+            .iter()
+            .flatten()
+            .any(|z| z.overcommit)
+        {
+            add(Path::new("/proc/self/pagemap"), read);
+            add(Path::new(crate::overcommit::PAGE_IDLE_BITMAP), read_write);
+        }
+    }
+
+    // Memory usage of the cgroup of the VMM, for the balloon to keep it under
+    // its memory.high limit
+    if vm_config
+        .balloon
+        .as_ref()
+        .map_or(false, |b| b.cgroup_memory_high)
+    {
+        add(Path::new("/proc/self/cgroup"), read);
+        add(Path::new("/sys/fs/cgroup"), read);
+    }
+
+    // Memory pressure, for the balloon to move guest memory to the
+    // heterogeneous memory
+    if let Some(balloon) = vm_config
+        .balloon
+        .as_ref()
+        .filter(|b| b.hetero_pressure_high.is_some())
+    {
+        add(
+            balloon
+                .hetero_pressure_file
+                .as_deref()
+                .unwrap_or_else(|| Path::new(crate::overcommit::PROC_PRESSURE_MEMORY)),
+            read,
+        );
+    }
+
+    for rule in vm_config.landlock_rules.iter().flatten() {
+        add(&rule.path, Access::Path(rule.access));
+    }
+
+    rules
+}
+
+/// Paths the VMM needs to access to run the VM, the sockets it listens on
+/// included.
+pub(crate) fn config_paths(vm_config: &VmConfig) -> Vec<PathBuf> {
+    config_rules(vm_config)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Restrict the VMM to the paths of the VM configuration, along with the
+/// given extra rules.
+pub fn apply_landlock(vm_config: &VmConfig, extra_rules: &[LandlockConfig]) -> Result<()> {
+    let mut landlock = Landlock::new()?;
+
+    let rules = config_rules(vm_config).into_iter().chain(
+        extra_rules
+            .iter()
+            .map(|rule| (rule.path.clone(), Access::Path(rule.access))),
+    );
+    for (path, access) in rules {
+        match access {
+            Access::Path(access) => landlock.add_rule(&path, access)?,
+            Access::Listener => landlock.add_listener_rule(&path)?,
+            Access::ListenerDirectory => landlock.add_listener_directory_rule(&path)?,
+        }
+    }
+
+    landlock.restrict_self()?;
+    info!("Restricted the VMM filesystem accesses with Landlock");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm_config::{DiskConfig, VsockConfig};
+
+    #[test]
+    fn test_config_rules() {
+        let mut vm_config: VmConfig =
+            serde_json::from_str(r#"{"payload": {"kernel": "/path/to/kernel"}}"#).unwrap();
+        vm_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/disk.img")),
+            readonly: true,
+            ..Default::default()
+        }]);
+        vm_config.vsock = Some(VsockConfig {
+            cid: 3,
+            socket: PathBuf::from("/tmp/vsock.sock"),
+            ..Default::default()
+        });
+        vm_config.console.socket_dir = Some(PathBuf::from("/run/vm"));
+        vm_config.landlock_rules = Some(vec![LandlockConfig {
+            path: PathBuf::from("/var/snapshots"),
+            access: LandlockAccess::Write,
+        }]);
+
+        let rules = config_rules(&vm_config);
+        for rule in [
+            (
+                PathBuf::from("/path/to/kernel"),
+                Access::Path(LandlockAccess::Read),
+            ),
+            (
+                PathBuf::from("/path/to/disk.img"),
+                Access::Path(LandlockAccess::Read),
+            ),
+            (PathBuf::from("/tmp/vsock.sock"), Access::Listener),
+            (PathBuf::from("/run/vm"), Access::ListenerDirectory),
+            (
+                PathBuf::from("/dev/urandom"),
+                Access::Path(LandlockAccess::Read),
+            ),
+            (
+                PathBuf::from("/var/snapshots"),
+                Access::Path(LandlockAccess::Write),
+            ),
+        ] {
+            assert!(rules.contains(&rule), "Missing rule {rule:?}");
+        }
+    }
+}
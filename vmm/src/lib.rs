@@ -9,19 +9,36 @@ extern crate event_monitor;
 extern crate log;
 
 use crate::api::{
-    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmReceiveMigrationData,
-    VmSendMigrationData, VmmPingResponse,
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, ConsoleInfo, VmCountersData, VmInfo,
+    VmMemoryData, VmMemoryReadData, VmReceiveMigrationData, VmSendMigrationData,
+    VmVirtqueueStateData, VmmPingResponse,
 };
 use crate::config::{
-    add_to_config, DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig,
-    UserDeviceConfig, VdpaConfig, VmConfig, VsockConfig,
+    add_to_config, ConsolePortConfig, CpuAffinity, CpuBandwidth, DeviceConfig, DiskConfig,
+    FsConfig, LandlockAccess, LandlockConfig, MdevConfig, NetConfig, OnCrashAction, OnCrashConfig,
+    PmemConfig, PvPanicAction, RestoreConfig, UserDeviceConfig, VdpaConfig, VfConfig, VmConfig,
+    VsockConfig,
 };
+#[cfg(target_arch = "x86_64")]
+use crate::config::{HibernateAction, SgxEpcConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggable;
+use crate::counter_rates::{CounterRates, COUNTER_SAMPLE_INTERVAL};
+use crate::crash_backoff::CrashBackoff;
+use crate::guest_agent::GuestAgent;
+use crate::hooks::HookEvent;
 use crate::memory_manager::MemoryManager;
 #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
 use crate::migration::get_vm_snapshot;
-use crate::migration::{recv_vm_config, recv_vm_state};
+use crate::migration::{
+    check_destination, open_snapshot_stream, MigrationPrecheckReport, SnapshotSource,
+};
+use crate::overcommit::{
+    cgroup_memory_usage, host_available_memory, inotify_drain, inotify_new, memory_pressure,
+    CgroupMemoryMonitor, ColdMemoryScanner, HeteroBalloonController, OvercommitMonitor,
+    OVERCOMMIT_INTERVAL,
+};
+use crate::resource_monitor::{ResourceMonitor, VmmResources};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::vm::{Error as VmError, Vm, VmState};
 use anyhow::anyhow;
@@ -32,6 +49,7 @@ use libc::{tcsetattr, termios, EFD_NONBLOCK, SIGINT, SIGTERM, TCSANOW};
 use memory_manager::MemoryManagerSnapshotData;
 use pci::PciBdf;
 use seccompiler::{apply_filter, SeccompAction};
+use serde::de::{self, Deserializer};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::{Deserialize, Serialize};
 use signal_hook::iterator::{Handle, Signals};
@@ -43,14 +61,18 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::net::UnixListener;
 use std::os::unix::net::UnixStream;
 use std::panic::AssertUnwindSafe;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{result, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
+use virtio_devices::WatchdogCounters;
 use vm_memory::bitmap::AtomicBitmap;
 use vm_migration::{protocol::*, Migratable};
 use vm_migration::{MigratableError, Pausable, Snapshot, Snapshottable, Transportable};
@@ -62,22 +84,45 @@ use vmm_sys_util::timerfd::TimerFd;
 
 mod acpi;
 pub mod api;
+mod cgroup;
 mod clone3;
 pub mod config;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 mod coredump;
+mod counter_rates;
 pub mod cpu;
+mod crash_backoff;
 pub mod device_manager;
 pub mod device_tree;
+pub mod embed;
 #[cfg(feature = "guest_debug")]
 mod gdb;
+mod guest_agent;
+pub mod hooks;
+#[cfg(feature = "sev_snp")]
+mod igvm;
 pub mod interrupt;
+#[cfg(target_arch = "x86_64")]
+mod interrupt_remapping;
+pub mod jail;
+mod landlock;
+mod mdev;
 pub mod memory_manager;
 pub mod migration;
+mod overcommit;
 mod pci_segment;
+mod process_limits;
+pub mod resource_monitor;
 pub mod seccomp_filters;
+pub mod seccomp_monitor;
 mod serial_manager;
 mod sigwinch_listener;
+mod sriov;
+#[cfg(feature = "tdx")]
+mod tdx_quote;
+#[cfg(target_arch = "x86_64")]
+mod uefi_vars;
+pub mod virtiofsd;
 pub mod vm;
 pub mod vm_config;
 
@@ -111,6 +156,10 @@ pub enum Error {
     #[error("Error reading from EventFd: {0}")]
     EventFdRead(#[source] io::Error),
 
+    /// Cannot write to EventFd.
+    #[error("Error writing to EventFd: {0}")]
+    EventFdWrite(#[source] io::Error),
+
     /// Cannot create TimerFd.
     #[error("Error creating TimerFd: {0}")]
     TimerFdCreate(#[source] errno::Error),
@@ -119,6 +168,10 @@ pub enum Error {
     #[error("Error waiting TimerFd: {0}")]
     TimerFdWait(#[source] errno::Error),
 
+    /// Cannot create inotify instance.
+    #[error("Error creating inotify instance: {0}")]
+    InotifyCreate(#[source] io::Error),
+
     /// Cannot create epoll context.
     #[error("Error creating epoll context: {0}")]
     Epoll(#[source] io::Error),
@@ -137,10 +190,19 @@ pub enum Error {
     #[error("Error starting D-Bus session: {0}")]
     CreateDBusSession(#[source] zbus::Error),
 
+    /// Cannot set up the TLS of the HTTP API
+    #[cfg(feature = "tls_api")]
+    #[error("Error setting up the TLS of the HTTP API: {0}")]
+    CreateApiTlsContext(#[source] openssl::error::ErrorStack),
+
     /// Cannot create `event-monitor` thread
     #[error("Error spawning `event-monitor` thread: {0}")]
     EventMonitorThreadSpawn(#[source] io::Error),
 
+    /// Cannot create `seccomp-monitor` thread
+    #[error("Error spawning `seccomp-monitor` thread: {0}")]
+    SeccompMonitorThreadSpawn(#[source] io::Error),
+
     /// Cannot handle the VM STDIN stream
     #[error("Error handling VM stdin: {0:?}")]
     Stdin(VmError),
@@ -173,10 +235,6 @@ pub enum Error {
     #[error("Error activating virtio devices: {0:?}")]
     ActivateVirtioDevices(VmError),
 
-    /// Error creating API server
-    #[error("Error creating API server {0:?}")]
-    CreateApiServer(micro_http::ServerError),
-
     /// Error binding API server socket
     #[error("Error creation API server's socket {0:?}")]
     CreateApiServerSocket(#[source] io::Error),
@@ -215,6 +273,14 @@ pub enum EpollDispatch {
     ActivateVirtioDevices = 3,
     Debug = 4,
     Hmem = 5,
+    Pause = 6,
+    GuestPanic = 7,
+    Suspend = 8,
+    Hibernate = 9,
+    Overcommit = 10,
+    CgroupMemory = 11,
+    CounterRates = 12,
+    CrashRestart = 13,
     Unknown,
 }
 
@@ -228,6 +294,14 @@ impl From<u64> for EpollDispatch {
             3 => ActivateVirtioDevices,
             4 => Debug,
             5 => Hmem,
+            6 => Pause,
+            7 => GuestPanic,
+            8 => Suspend,
+            9 => Hibernate,
+            10 => Overcommit,
+            11 => CgroupMemory,
+            12 => CounterRates,
+            13 => CrashRestart,
             _ => Unknown,
         }
     }
@@ -309,6 +383,31 @@ impl Serialize for PciDeviceInfo {
     }
 }
 
+impl<'de> Deserialize<'de> for PciDeviceInfo {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct PciDeviceInfoData {
+            id: String,
+            bdf: String,
+        }
+
+        let data = PciDeviceInfoData::deserialize(deserializer)?;
+        // PciBdf::from_str() expects the "segment:bus:device.function" form
+        let valid = data.bdf.split_once('.').map_or(false, |(sbd, function)| {
+            sbd.split(':').count() == 3 && !function.contains('.')
+        });
+        if !valid {
+            return Err(de::Error::custom(format!("invalid PCI BDF {}", data.bdf)));
+        }
+        let bdf = PciBdf::from_str(&data.bdf).map_err(de::Error::custom)?;
+
+        Ok(PciDeviceInfo { id: data.id, bdf })
+    }
+}
+
 pub fn feature_list() -> Vec<String> {
     vec![
         #[cfg(feature = "dbus_api")]
@@ -327,6 +426,8 @@ pub fn feature_list() -> Vec<String> {
         "sev_snp".to_string(),
         #[cfg(feature = "tdx")]
         "tdx".to_string(),
+        #[cfg(feature = "tls_api")]
+        "tls_api".to_string(),
         #[cfg(feature = "tracing")]
         "tracing".to_string(),
     ]
@@ -368,6 +469,8 @@ pub fn start_event_monitor_thread(
                     for tx in monitor.broadcast.iter() {
                         tx.send(event.clone()).ok();
                     }
+
+                    event_monitor::record_history(event);
                 }
             }))
             .map_err(|_| {
@@ -388,6 +491,7 @@ pub fn start_vmm_thread(
     http_path: &Option<String>,
     http_fd: Option<RawFd>,
     #[cfg(feature = "dbus_api")] dbus_options: Option<DBusApiOptions>,
+    #[cfg(feature = "tls_api")] tls_options: Option<api::TlsApiOptions>,
     api_event: EventFd,
     api_sender: Sender<ApiRequest>,
     api_receiver: Receiver<ApiRequest>,
@@ -401,6 +505,8 @@ pub fn start_vmm_thread(
     #[cfg(feature = "guest_debug")]
     let gdb_hw_breakpoints = hypervisor.get_guest_debug_hw_bps();
     #[cfg(feature = "guest_debug")]
+    let gdb_hw_watchpoints = hypervisor.get_guest_debug_hw_wps();
+    #[cfg(feature = "guest_debug")]
     let (gdb_sender, gdb_receiver) = std::sync::mpsc::channel();
     #[cfg(feature = "guest_debug")]
     let gdb_debug_event = debug_event.try_clone().map_err(Error::EventFdClone)?;
@@ -410,6 +516,11 @@ pub fn start_vmm_thread(
     let api_event_clone = api_event.try_clone().map_err(Error::EventFdClone)?;
     let hypervisor_type = hypervisor.hypervisor_type();
 
+    // The violations are only logged by the kernel, report them from there
+    if *seccomp_action == SeccompAction::Log {
+        seccomp_monitor::start_seccomp_monitor_thread(seccomp_action, hypervisor_type)?;
+    }
+
     // Retrieve seccomp filter
     let vmm_seccomp_filter = get_seccomp_filter(seccomp_action, Thread::Vmm, hypervisor_type)
         .map_err(Error::CreateSeccompFilter)?;
@@ -466,6 +577,19 @@ pub fn start_vmm_thread(
         None => None,
     };
 
+    #[cfg(feature = "tls_api")]
+    let https_api_handle = match tls_options {
+        Some(tls_options) => Some(api::start_https_thread(
+            &tls_options,
+            api_event_clone.try_clone().map_err(Error::EventFdClone)?,
+            api_sender.clone(),
+            seccomp_action,
+            exit_event.try_clone().map_err(Error::EventFdClone)?,
+            hypervisor_type,
+        )?),
+        None => None,
+    };
+
     if let Some(http_path) = http_path {
         api::start_http_path_thread(
             http_path,
@@ -493,6 +617,7 @@ pub fn start_vmm_thread(
             gdb_debug_event,
             gdb_vm_debug_event,
             gdb_hw_breakpoints,
+            gdb_hw_watchpoints,
         );
         thread::Builder::new()
             .name("gdb".to_owned())
@@ -504,6 +629,8 @@ pub fn start_vmm_thread(
         thread_handle: thread,
         #[cfg(feature = "dbus_api")]
         dbus_shutdown_chs,
+        #[cfg(feature = "tls_api")]
+        https_api_handle,
     })
 }
 
@@ -512,9 +639,17 @@ struct VmMigrationConfig {
     vm_config: Arc<Mutex<VmConfig>>,
     #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
     common_cpuid: Vec<hypervisor::arch::x86::CpuIdEntry>,
+    #[cfg(target_arch = "aarch64")]
+    gic_capabilities: hypervisor::arch::aarch64::gic::VgicCapabilities,
     memory_manager_data: MemoryManagerSnapshotData,
 }
 
+#[derive(Deserialize, Serialize)]
+struct MigrationPrecheckData {
+    config: VmMigrationConfig,
+    local: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct VmmVersionInfo {
     pub build_version: String,
@@ -534,12 +669,21 @@ pub struct VmmThreadHandle {
     pub thread_handle: thread::JoinHandle<Result<()>>,
     #[cfg(feature = "dbus_api")]
     pub dbus_shutdown_chs: Option<DBusApiShutdownChannels>,
+    #[cfg(feature = "tls_api")]
+    pub https_api_handle: Option<api::HttpsApiHandle>,
 }
 
 pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    pause_evt: EventFd,
+    // Shared with the virtio-watchdog device so that the counters and the
+    // escalation survive VM reboots.
+    watchdog_counters: Arc<WatchdogCounters>,
+    guest_panic_evt: EventFd,
+    suspend_evt: EventFd,
+    hibernate_evt: EventFd,
     api_evt: EventFd,
     #[cfg(feature = "guest_debug")]
     debug_evt: EventFd,
@@ -552,9 +696,24 @@ pub struct Vmm {
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     activate_evt: EventFd,
     hmem_evt: TimerFd,
+    overcommit_evt: TimerFd,
+    overcommit: Option<OvercommitMonitor>,
+    hetero_balloon: Option<HeteroBalloonController>,
+    cold_memory: Option<ColdMemoryScanner>,
+    cgroup_memory_evt: File,
+    cgroup_memory: Option<CgroupMemoryMonitor>,
     signals: Option<Handle>,
     threads: Vec<thread::JoinHandle<()>>,
     original_termios_opt: Arc<Mutex<Option<termios>>>,
+    resource_monitor: ResourceMonitor,
+    counters_evt: TimerFd,
+    // Only sampled once the rates were asked for
+    counter_rates: Option<CounterRates>,
+    crash_restart_evt: TimerFd,
+    crash_backoff: CrashBackoff,
+    // SR-IOV VFs to give back to the host once removed from the VM, indexed
+    // by their sysfs path
+    sriov_vfs: HashMap<PathBuf, sriov::VirtualFunction>,
 }
 
 impl Vmm {
@@ -648,8 +807,16 @@ impl Vmm {
     ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let pause_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let guest_panic_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let suspend_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let hibernate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let activate_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let hmem_evt = TimerFd::new().map_err(Error::TimerFdCreate)?;
+        let overcommit_evt = TimerFd::new().map_err(Error::TimerFdCreate)?;
+        let cgroup_memory_evt = inotify_new().map_err(Error::InotifyCreate)?;
+        let counters_evt = TimerFd::new().map_err(Error::TimerFdCreate)?;
+        let crash_restart_evt = TimerFd::new().map_err(Error::TimerFdCreate)?;
 
         epoll
             .add_event(&exit_evt, EpollDispatch::Exit)
@@ -659,6 +826,22 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&pause_evt, EpollDispatch::Pause)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&guest_panic_evt, EpollDispatch::GuestPanic)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&suspend_evt, EpollDispatch::Suspend)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&hibernate_evt, EpollDispatch::Hibernate)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&activate_evt, EpollDispatch::ActivateVirtioDevices)
             .map_err(Error::Epoll)?;
@@ -676,10 +859,31 @@ impl Vmm {
             .add_event(&hmem_evt, EpollDispatch::Hmem)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&overcommit_evt, EpollDispatch::Overcommit)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&cgroup_memory_evt, EpollDispatch::CgroupMemory)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&counters_evt, EpollDispatch::CounterRates)
+            .map_err(Error::Epoll)?;
+
+        epoll
+            .add_event(&crash_restart_evt, EpollDispatch::CrashRestart)
+            .map_err(Error::Epoll)?;
+
         Ok(Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            pause_evt,
+            watchdog_counters: Arc::new(WatchdogCounters::default()),
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             api_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
@@ -695,6 +899,18 @@ impl Vmm {
             threads: vec![],
             original_termios_opt: Arc::new(Mutex::new(None)),
             hmem_evt,
+            overcommit_evt,
+            overcommit: None,
+            hetero_balloon: None,
+            cold_memory: None,
+            cgroup_memory_evt,
+            cgroup_memory: None,
+            resource_monitor: ResourceMonitor::new(),
+            counters_evt,
+            counter_rates: None,
+            crash_restart_evt,
+            crash_backoff: CrashBackoff::default(),
+            sriov_vfs: HashMap::new(),
         })
     }
 
@@ -702,6 +918,10 @@ impl Vmm {
         // We only store the passed VM config.
         // The VM will be created when being asked to boot it.
         if self.vm_config.is_none() {
+            if config.lock().unwrap().landlock_enable {
+                landlock::apply_landlock(&config.lock().unwrap(), &[])
+                    .map_err(VmError::ApplyLandlock)?;
+            }
             self.vm_config = Some(config);
             Ok(())
         } else {
@@ -718,10 +938,26 @@ impl Vmm {
                 return Err(VmError::VmMissingConfig);
             };
 
+            self.cancel_crash_restart();
+
             // Create a new VM if we don't have one yet.
             if self.vm.is_none() {
+                self.crash_backoff = CrashBackoff::default();
                 let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
                 let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+                let pause_evt = self.pause_evt.try_clone().map_err(VmError::EventFdClone)?;
+                let guest_panic_evt = self
+                    .guest_panic_evt
+                    .try_clone()
+                    .map_err(VmError::EventFdClone)?;
+                let suspend_evt = self
+                    .suspend_evt
+                    .try_clone()
+                    .map_err(VmError::EventFdClone)?;
+                let hibernate_evt = self
+                    .hibernate_evt
+                    .try_clone()
+                    .map_err(VmError::EventFdClone)?;
                 #[cfg(feature = "guest_debug")]
                 let vm_debug_evt = self
                     .vm_debug_evt
@@ -737,6 +973,11 @@ impl Vmm {
                         Arc::clone(vm_config),
                         exit_evt,
                         reset_evt,
+                        pause_evt,
+                        self.watchdog_counters.clone(),
+                        guest_panic_evt,
+                        suspend_evt,
+                        hibernate_evt,
                         #[cfg(feature = "guest_debug")]
                         vm_debug_evt,
                         &self.seccomp_action,
@@ -763,12 +1004,34 @@ impl Vmm {
             }
         };
         tracer::end();
-        r
+        r.and_then(|_| self.start_overcommit())?;
+        self.run_hooks(HookEvent::Boot);
+        Ok(())
+    }
+
+    // Run the hooks of the event, with the current state of the VM
+    fn run_hooks(&self, event: HookEvent) {
+        let state = self
+            .vm
+            .as_ref()
+            .and_then(|vm| vm.get_state().ok())
+            .unwrap_or(VmState::Shutdown);
+        let vm_id = self.vm_config.as_ref().and_then(|config| {
+            config
+                .lock()
+                .unwrap()
+                .platform
+                .as_ref()
+                .and_then(|platform| platform.uuid.clone())
+        });
+        hooks::notify(event, &format!("{state:?}"), vm_id.as_deref());
     }
 
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.pause().map_err(VmError::Pause)
+            vm.pause().map_err(VmError::Pause)?;
+            self.run_hooks(HookEvent::Pause);
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -776,19 +1039,59 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            #[cfg(target_arch = "x86_64")]
+            if vm.is_suspended() {
+                return self.vm_wakeup();
+            }
+            vm.resume().map_err(VmError::Resume)?;
+            self.cancel_crash_restart();
+            self.run_hooks(HookEvent::Resume);
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn vm_suspend(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.suspend()
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn vm_wakeup(&mut self) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        if let Some(waking_vector) = vm.firmware_waking_vector() {
+            vm.wakeup(waking_vector)
+        } else {
+            // Like the firmware would do, boot from scratch when the guest
+            // did not leave anything to resume.
+            warn!("No waking vector set by the guest, rebooting the VM");
+            self.vm_reboot()
+        }
+    }
+
     fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+        if let Some(blocker) = self.mdev_migration_blocker() {
+            return Err(VmError::Snapshot(MigratableError::Snapshot(blocker)));
+        }
+
         if let Some(ref mut vm) = self.vm {
+            api::jobs::set_stage("state");
             vm.snapshot()
                 .map_err(VmError::Snapshot)
                 .and_then(|snapshot| {
-                    vm.send(&snapshot, destination_url)
-                        .map_err(VmError::SnapshotSend)
+                    api::jobs::set_stage("memory");
+                    let sent = match open_snapshot_stream(destination_url)
+                        .map_err(VmError::SnapshotSend)?
+                    {
+                        Some(mut stream) => vm.send_stream(&snapshot, &mut stream),
+                        None => vm.send(&snapshot, destination_url),
+                    };
+                    sent.map_err(VmError::SnapshotSend)
                 })
         } else {
             Err(VmError::VmNotRunning)
@@ -807,10 +1110,18 @@ impl Vmm {
         // Safe to unwrap as we checked it was Some(&str).
         let source_url = source_url.unwrap();
 
-        let vm_config = Arc::new(Mutex::new(
-            recv_vm_config(source_url).map_err(VmError::Restore)?,
-        ));
-        let snapshot = recv_vm_state(source_url).map_err(VmError::Restore)?;
+        let mut snapshot_source = SnapshotSource::open(source_url, restore_cfg.require_manifest)
+            .map_err(VmError::Restore)?;
+        let mut vm_config = snapshot_source.recv_vm_config().map_err(VmError::Restore)?;
+        // Changes for the restored VM to run as a clone of the snapshotted one
+        if restore_cfg.has_overrides() {
+            restore_cfg
+                .apply_overrides(&mut vm_config)
+                .map_err(VmError::ConfigValidation)?;
+            vm_config.validate().map_err(VmError::ConfigValidation)?;
+        }
+        let vm_config = Arc::new(Mutex::new(vm_config));
+        let snapshot = snapshot_source.recv_vm_state().map_err(VmError::Restore)?;
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let vm_snapshot = get_vm_snapshot(&snapshot).map_err(VmError::Restore)?;
 
@@ -818,10 +1129,37 @@ impl Vmm {
         self.vm_check_cpuid_compatibility(&vm_config, &vm_snapshot.common_cpuid)
             .map_err(VmError::Restore)?;
 
+        // The memory of the VM is restored from the snapshot once the VMM is
+        // restricted, a streamed snapshot being already open.
+        if vm_config.lock().unwrap().landlock_enable {
+            let snapshot_rules = match &snapshot_source {
+                SnapshotSource::Dir(path) => vec![LandlockConfig {
+                    path: path.clone(),
+                    access: LandlockAccess::Read,
+                }],
+                SnapshotSource::Stream(_) => Vec::new(),
+            };
+            landlock::apply_landlock(&vm_config.lock().unwrap(), &snapshot_rules)
+                .map_err(VmError::ApplyLandlock)?;
+        }
+
         self.vm_config = Some(Arc::clone(&vm_config));
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let pause_evt = self.pause_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let guest_panic_evt = self
+            .guest_panic_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let hibernate_evt = self
+            .hibernate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self
             .vm_debug_evt
@@ -836,6 +1174,11 @@ impl Vmm {
             vm_config,
             exit_evt,
             reset_evt,
+            pause_evt,
+            self.watchdog_counters.clone(),
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -846,17 +1189,23 @@ impl Vmm {
             None,
             Arc::clone(&self.original_termios_opt),
             Some(snapshot),
-            Some(source_url),
+            Some(&mut snapshot_source),
             Some(restore_cfg.prefault),
         )?;
+        self.cancel_crash_restart();
+        self.crash_backoff = CrashBackoff::default();
         self.vm = Some(vm);
 
         // Now we can restore the rest of the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.restore()
+            vm.restore()?;
         } else {
-            Err(VmError::VmNotCreated)
+            return Err(VmError::VmNotCreated);
         }
+
+        self.start_overcommit()?;
+        self.run_hooks(HookEvent::Boot);
+        Ok(())
     }
 
     #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
@@ -868,15 +1217,175 @@ impl Vmm {
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn hibernate_action(&self) -> HibernateAction {
+        self.vm_config
+            .as_ref()
+            .and_then(|config| {
+                config
+                    .lock()
+                    .unwrap()
+                    .platform
+                    .as_ref()
+                    .map(|p| p.hibernate_action)
+            })
+            .unwrap_or_default()
+    }
+
+    fn vm_guest_panic(&mut self) -> result::Result<(), VmError> {
+        self.run_hooks(HookEvent::Crash);
+
+        let on_crash = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().on_crash.clone());
+        if let Some(on_crash) = on_crash {
+            return self.vm_crash(&on_crash);
+        }
+
+        let policy = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.lock().unwrap().pvpanic_policy.clone());
+
+        if let Some(policy) = policy {
+            info!("Applying pvpanic policy: {:?}", policy.action);
+            match policy.action {
+                PvPanicAction::Pause => self.vm_pause()?,
+                PvPanicAction::Coredump => {
+                    // Validation guarantees coredump_path is set and the
+                    // coredump support is built in.
+                    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                    if let Some(path) = policy.coredump_path {
+                        self.vm_coredump(&format!("file://{}", path.display()))?;
+                        if policy.restart {
+                            self.vm_reboot()?;
+                        }
+                    }
+                }
+                PvPanicAction::Restart => self.vm_reboot()?,
+                PvPanicAction::Kdump => {
+                    // Validation guarantees kdump_dir is set and the
+                    // coredump support is built in.
+                    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                    if let Some(kdump_dir) = policy.kdump_dir {
+                        self.vm_kdump(&kdump_dir, policy.restart)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vm_crash(&mut self, on_crash: &OnCrashConfig) -> result::Result<(), VmError> {
+        let vm = self.vm.as_ref().ok_or(VmError::VmNotRunning)?;
+        event!("vm", "crashed");
+
+        // The VM is already waiting to be restarted
+        if self
+            .crash_restart_evt
+            .is_armed()
+            .map_err(VmError::TimerfdError)?
+        {
+            return Ok(());
+        }
+
+        // A crashed guest only burns CPU, so the VM stays paused until it
+        // gets restarted.
+        if vm.get_state()? == VmState::Running {
+            self.vm_pause()?;
+        }
+
+        match on_crash.action {
+            OnCrashAction::Restart => {}
+            OnCrashAction::Preserve => {
+                info!("Preserving the crashed VM");
+                return Ok(());
+            }
+            OnCrashAction::CoredumpAndRestart => {
+                // Validation guarantees coredump_dir is set and the coredump
+                // support is built in.
+                #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+                if let Some(coredump_dir) = on_crash.coredump_dir.as_ref() {
+                    // Each crash is dumped to its own file
+                    let timestamp = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+                    let path = coredump_dir.join(format!("core-{timestamp}"));
+                    // The VM still gets restarted without its coredump
+                    if let Err(e) = self.vm_coredump(&format!("file://{}", path.display())) {
+                        error!("Failed writing the coredump of the crashed VM: {:?}", e);
+                    }
+                }
+            }
+        }
+
+        match self.crash_backoff.restart_delay(on_crash, Instant::now()) {
+            Some(delay) if delay.is_zero() => self.vm_reboot(),
+            Some(delay) => {
+                info!("Restarting the crashed VM in {:?}", delay);
+                self.crash_restart_evt
+                    .reset(delay, None)
+                    .map_err(VmError::TimerfdError)
+            }
+            None => {
+                warn!("The VM crashed too many times in a row, preserving it");
+                event!("vm", "crash-loop");
+                Ok(())
+            }
+        }
+    }
+
+    // The VM waiting to be restarted after a crash doesn't anymore once
+    // resumed, shut down, or replaced, and a stale timer would otherwise
+    // restart it while running, or restart the next VM.
+    fn cancel_crash_restart(&self) {
+        if let Err(e) = self.crash_restart_evt.clear() {
+            warn!("Failed disarming the crash restart timer: {}", e);
+        }
+    }
+
+    fn vm_crash_restart(&mut self) -> result::Result<(), VmError> {
+        // The VM was resumed, shut down or deleted in the meantime
+        match self.vm.as_ref().map(|vm| vm.get_state()).transpose()? {
+            Some(VmState::Paused) => self.vm_reboot(),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    fn vm_kdump(&mut self, kdump_dir: &Path, restart: bool) -> result::Result<(), VmError> {
+        let vm = self.vm.as_mut().ok_or(VmError::VmNotRunning)?;
+        // Each panic is captured in its own directory
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        vm.kdump(&kdump_dir.join(format!("kdump-{timestamp}")))?;
+
+        if restart {
+            self.vm_reboot()?;
+        }
+
+        Ok(())
+    }
+
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
+        self.cancel_crash_restart();
         if let Some(ref mut vm) = self.vm.take() {
-            vm.shutdown()
+            vm.shutdown()?;
+            self.run_hooks(HookEvent::Shutdown);
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
     fn vm_reboot(&mut self) -> result::Result<(), VmError> {
+        self.cancel_crash_restart();
+
         // First we stop the current VM
         let (config, serial_pty, console_pty, console_resize_pipe) =
             if let Some(mut vm) = self.vm.take() {
@@ -888,6 +1397,7 @@ impl Vmm {
                     .as_ref()
                     .map(|pipe| pipe.try_clone().unwrap());
                 vm.shutdown()?;
+                self.run_hooks(HookEvent::Shutdown);
                 (config, serial_pty, console_pty, console_resize_pipe)
             } else {
                 return Err(VmError::VmNotCreated);
@@ -895,6 +1405,19 @@ impl Vmm {
 
         let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
         let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let pause_evt = self.pause_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let guest_panic_evt = self
+            .guest_panic_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let suspend_evt = self
+            .suspend_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
+        let hibernate_evt = self
+            .hibernate_evt
+            .try_clone()
+            .map_err(VmError::EventFdClone)?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self
             .vm_debug_evt
@@ -917,6 +1440,11 @@ impl Vmm {
             config,
             exit_evt,
             reset_evt,
+            pause_evt,
+            self.watchdog_counters.clone(),
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -936,6 +1464,8 @@ impl Vmm {
 
         self.vm = Some(vm);
 
+        self.start_overcommit()?;
+        self.run_hooks(HookEvent::Boot);
         Ok(())
     }
 
@@ -956,11 +1486,26 @@ impl Vmm {
 
                 let device_tree = self.vm.as_ref().map(|vm| vm.device_tree());
 
+                let port_ids = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.console_port_ids())
+                    .unwrap_or_default();
+                let consoles = ConsoleInfo::list(&config.lock().unwrap(), &port_ids);
+
+                let virtio_mem = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.virtio_mem_info())
+                    .unwrap_or_default();
+
                 Ok(VmInfo {
                     config,
                     state,
                     memory_actual_size,
                     device_tree,
+                    consoles,
+                    virtio_mem,
                 })
             }
             None => Err(VmError::VmNotCreated),
@@ -981,6 +1526,10 @@ impl Vmm {
         }
     }
 
+    fn vmm_resources(&mut self) -> result::Result<VmmResources, io::Error> {
+        self.resource_monitor.sample()
+    }
+
     fn vm_delete(&mut self) -> result::Result<(), VmError> {
         if self.vm_config.is_none() {
             return Ok(());
@@ -992,6 +1541,16 @@ impl Vmm {
         }
 
         self.vm_config = None;
+        // The crashes of the next VM don't follow the ones of this one
+        self.cancel_crash_restart();
+        self.crash_backoff = CrashBackoff::default();
+
+        // The VFIO devices were closed along with the VM
+        for (_, vf) in self.sriov_vfs.drain() {
+            if let Err(e) = vf.release() {
+                warn!("Cannot release the SR-IOV VF: {}", e);
+            }
+        }
 
         event!("vm", "deleted");
 
@@ -1064,91 +1623,280 @@ impl Vmm {
         }
     }
 
-    fn vm_add_device(
-        &mut self,
-        device_cfg: DeviceConfig,
-    ) -> result::Result<Option<Vec<u8>>, VmError> {
+    fn vm_bind_zone(&mut self, id: String, host_numa_node: u32) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.bind_zone(id, host_numa_node) {
+                error!("Error when binding memory zone: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            // Update VmConfig by setting the new host NUMA node.
+            let memory_config = &mut self.vm_config.as_ref().unwrap().lock().unwrap().memory;
+
+            if let Some(zones) = &mut memory_config.zones {
+                for zone in zones.iter_mut() {
+                    if zone.id == id {
+                        zone.host_numa_node = Some(host_numa_node);
+                        return Ok(());
+                    }
+                }
+            }
+
+            error!("Could not find the memory zone {} for the binding", id);
+            Err(VmError::BindZone)
+        }
+    }
+
+    fn vm_resize_fs(&mut self, id: String, desired_cache_size: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.resize_fs(id, desired_cache_size) {
+                error!("Error when resizing virtio-fs DAX window: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_cpu_affinity(&mut self, affinity: &[CpuAffinity]) -> result::Result<(), VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
-            add_to_config(&mut config.devices, device_cfg.clone());
+            config.cpus.update_affinity(affinity);
             config.validate().map_err(VmError::ConfigValidation)?;
         }
 
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_device(device_cfg).map_err(|e| {
-                error!("Error when adding new device to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info)
-                .map(Some)
-                .map_err(VmError::SerializeJson)
+            if let Err(e) = vm.set_cpu_affinity(affinity) {
+                error!("Error when setting the vCPU affinity: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
         } else {
-            // Update VmConfig by adding the new device.
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
-            add_to_config(&mut config.devices, device_cfg);
-            Ok(None)
+            config.cpus.update_affinity(affinity);
+            Ok(())
         }
     }
 
-    fn vm_add_user_device(
+    fn vm_set_cpu_bandwidth(
         &mut self,
-        device_cfg: UserDeviceConfig,
-    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        cpu_max: Option<CpuBandwidth>,
+        vcpu_max: Option<CpuBandwidth>,
+    ) -> result::Result<(), VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
-            add_to_config(&mut config.user_devices, device_cfg.clone());
+            config.cpus.cpu_max = cpu_max;
+            config.cpus.vcpu_max = vcpu_max;
             config.validate().map_err(VmError::ConfigValidation)?;
         }
 
         if let Some(ref mut vm) = self.vm {
-            let info = vm.add_user_device(device_cfg).map_err(|e| {
-                error!("Error when adding new user device to the VM: {:?}", e);
-                e
-            })?;
-            serde_json::to_vec(&info)
-                .map(Some)
-                .map_err(VmError::SerializeJson)
+            if let Err(e) = vm.set_cpu_bandwidth(cpu_max, vcpu_max) {
+                error!("Error when setting the CPU bandwidth: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
         } else {
-            // Update VmConfig by adding the new device.
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
-            add_to_config(&mut config.user_devices, device_cfg);
-            Ok(None)
+            config.cpus.cpu_max = cpu_max;
+            config.cpus.vcpu_max = vcpu_max;
+            Ok(())
         }
     }
 
-    fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
+    fn vm_remove_vcpu(&mut self, id: u8) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            if let Err(e) = vm.remove_device(id) {
-                error!("Error when removing device from the VM: {:?}", e);
+            if let Err(e) = vm.remove_vcpu(id) {
+                error!("Error when removing vCPU: {:?}", e);
                 Err(e)
             } else {
                 Ok(())
             }
-        } else if let Some(ref config) = self.vm_config {
-            let mut config = config.lock().unwrap();
-            if config.remove_device(&id) {
-                Ok(())
-            } else {
-                Err(VmError::NoDeviceToRemove(id))
-            }
         } else {
-            Err(VmError::VmNotCreated)
+            Err(VmError::VmNotRunning)
         }
     }
 
-    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+    fn vm_add_device(
+        &mut self,
+        device_cfg: DeviceConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
         self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
 
         {
             // Validate the configuration change in a cloned configuration
             let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
-            add_to_config(&mut config.disks, disk_cfg.clone());
+            add_to_config(&mut config.devices, device_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.add_device(device_cfg).map_err(|e| {
+                error!("Error when adding new device to the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.devices, device_cfg);
+            Ok(None)
+        }
+    }
+
+    fn vm_add_vf(&mut self, vf_cfg: VfConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        let vf =
+            sriov::prepare_vf(&vf_cfg.pf, vf_cfg.vf, vf_cfg.num_vfs).map_err(VmError::PrepareVf)?;
+        let path = vf.path().to_path_buf();
+        match self.vm_add_device(vf_cfg.device_config(path.clone())) {
+            Ok(info) => {
+                self.sriov_vfs.insert(path, vf);
+                Ok(info)
+            }
+            Err(e) => {
+                if let Err(e) = vf.release() {
+                    warn!("Cannot release the SR-IOV VF: {}", e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Give back the VF backing a device removed from the VM. As unbinding it
+    // from vfio-pci waits for the VFIO device to be closed, which only happens
+    // once the guest ejected the device, this is done from its own thread when
+    // the VM is running.
+    fn release_vf(&mut self, path: &Path) -> result::Result<(), VmError> {
+        let vf = match self.sriov_vfs.remove(path) {
+            Some(vf) => vf,
+            None => return Ok(()),
+        };
+
+        if self.vm.is_none() {
+            return vf.release().map_err(VmError::PrepareVf);
+        }
+
+        self.threads.push(
+            thread::Builder::new()
+                .name("sriov_release".to_string())
+                .spawn(move || {
+                    if let Err(e) = vf.release() {
+                        error!("Cannot release the SR-IOV VF: {}", e);
+                    }
+                })
+                .map_err(VmError::ReleaseVfSpawn)?,
+        );
+
+        Ok(())
+    }
+
+    fn vm_add_mdev(&mut self, mdev_cfg: MdevConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        let (path, created) = mdev::prepare_mdev(
+            &mdev_cfg.parent,
+            &mdev_cfg.mdev_type,
+            mdev_cfg.uuid.as_deref(),
+        )
+        .map_err(VmError::PrepareMdev)?;
+        let result = self.vm_add_device(mdev_cfg.device_config(path.clone()));
+        // The device isn't left behind when it was only created for the VM.
+        if result.is_err() && created {
+            if let Err(e) = mdev::remove_mdev(&path) {
+                error!("Cannot remove the mediated device: {}", e);
+            }
+        }
+
+        result
+    }
+
+    fn vm_add_user_device(
+        &mut self,
+        device_cfg: UserDeviceConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.user_devices, device_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let info = vm.add_user_device(device_cfg).map_err(|e| {
+                error!("Error when adding new user device to the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&info)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new device.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.user_devices, device_cfg);
+            Ok(None)
+        }
+    }
+
+    fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
+        // Path of the device, in case it is an SR-IOV VF to give back
+        let device_path = self.vm_config.as_ref().and_then(|config| {
+            config
+                .lock()
+                .unwrap()
+                .devices
+                .as_ref()?
+                .iter()
+                .find(|d| d.id.as_deref() == Some(id.as_str()))
+                .map(|d| d.path.clone())
+        });
+
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.remove_device(id) {
+                error!("Error when removing device from the VM: {:?}", e);
+                return Err(e);
+            }
+        } else if let Some(ref config) = self.vm_config {
+            let mut config = config.lock().unwrap();
+            if !config.remove_device(&id) {
+                return Err(VmError::NoDeviceToRemove(id));
+            }
+        } else {
+            return Err(VmError::VmNotCreated);
+        }
+
+        match device_path {
+            Some(path) => self.release_vf(&path),
+            None => Ok(()),
+        }
+    }
+
+    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.disks, disk_cfg.clone());
             config.validate().map_err(VmError::ConfigValidation)?;
         }
 
@@ -1303,13 +2051,233 @@ impl Vmm {
         }
     }
 
-    fn vm_counters(&mut self) -> result::Result<Option<Vec<u8>>, VmError> {
+    #[cfg(target_arch = "x86_64")]
+    fn vm_add_sgx_epc(&mut self, sgx_epc_cfg: SgxEpcConfig) -> result::Result<(), VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.sgx_epc, sgx_epc_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        // The EPC sections are enumerated by the guest through CPUID, which
+        // can't be modified once the vCPUs have run. A section added to a
+        // booted VM is set up along with the others when the VM reboots.
+        if self.vm.is_some() {
+            info!(
+                "SGX EPC section {} exposed to the guest on its next reboot",
+                sgx_epc_cfg.id
+            );
+        }
+
+        let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+        add_to_config(&mut config.sgx_epc, sgx_epc_cfg);
+        Ok(())
+    }
+
+    fn vm_add_console_port(
+        &mut self,
+        port_cfg: ConsolePortConfig,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        self.vm_config.as_ref().ok_or(VmError::VmNotCreated)?;
+
+        {
+            // Validate the configuration change in a cloned configuration
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap().clone();
+            add_to_config(&mut config.console_ports, port_cfg.clone());
+            config.validate().map_err(VmError::ConfigValidation)?;
+        }
+
+        if let Some(ref mut vm) = self.vm {
+            let port_cfg = vm.add_console_port(port_cfg).map_err(|e| {
+                error!("Error when adding new console port to the VM: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&port_cfg)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            // Update VmConfig by adding the new port.
+            let mut config = self.vm_config.as_ref().unwrap().lock().unwrap();
+            add_to_config(&mut config.console_ports, port_cfg);
+            Ok(None)
+        }
+    }
+
+    fn vm_remove_console_port(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.remove_console_port(id) {
+                error!("Error when removing console port from the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else if let Some(ref config) = self.vm_config {
+            let mut config = config.lock().unwrap();
+            if config.remove_console_port(&id) {
+                Ok(())
+            } else {
+                Err(VmError::NoDeviceToRemove(id))
+            }
+        } else {
+            Err(VmError::VmNotCreated)
+        }
+    }
+
+    fn vm_counters(&mut self, data: &VmCountersData) -> result::Result<Option<Vec<u8>>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.counters().map_err(|e| {
                 error!("Error when getting counters from the VM: {:?}", e);
                 e
             })?;
-            serde_json::to_vec(&info)
+
+            if !data.rates {
+                return serde_json::to_vec(&info)
+                    .map(Some)
+                    .map_err(VmError::SerializeJson);
+            }
+
+            // The first request starts the sampling, the rates being
+            // available once the first interval elapsed.
+            let now = Instant::now();
+            if self.counter_rates.is_none() {
+                let mut counter_rates = CounterRates::new(now);
+                counter_rates.sample(info, now);
+                self.counters_evt
+                    .reset(COUNTER_SAMPLE_INTERVAL, Some(COUNTER_SAMPLE_INTERVAL))
+                    .map_err(VmError::TimerfdError)?;
+                self.counter_rates = Some(counter_rates);
+            }
+
+            serde_json::to_vec(self.counter_rates.as_mut().unwrap().rates(now))
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_sample_counters(&mut self) {
+        let Some(counter_rates) = self.counter_rates.as_mut() else {
+            return;
+        };
+
+        // The rates are computed again from scratch for the next VM, or for
+        // the next request once nobody asked for them for a while.
+        let now = Instant::now();
+        let Some(vm) = self.vm.as_ref().filter(|_| !counter_rates.idle(now)) else {
+            self.counter_rates = None;
+            if let Err(e) = self.counters_evt.clear() {
+                warn!("Failed stopping the counters sampling timer: {}", e);
+            }
+            return;
+        };
+
+        match vm.counters() {
+            Ok(counters) => counter_rates.sample(counters, now),
+            Err(e) => warn!("Failed sampling the counters of the VM: {:?}", e),
+        }
+    }
+
+    // The guest agent commands wait on the guest, for as long as the timeout
+    // configured for the agent, so they run on a thread of their own and
+    // answer the API request once done, leaving the control loop responsive.
+    fn vm_guest_agent_request<F>(
+        &self,
+        sender: Sender<ApiResponse>,
+        api_error: fn(VmError) -> ApiError,
+        context: &'static str,
+        command: F,
+    ) -> result::Result<(), Error>
+    where
+        F: FnOnce(&GuestAgent) -> guest_agent::Result<serde_json::Value> + Send + 'static,
+    {
+        let agent = match &self.vm {
+            Some(vm) => vm.guest_agent(),
+            None => Err(VmError::VmNotRunning),
+        };
+        let agent = match agent {
+            Ok(agent) => agent,
+            Err(e) => {
+                return sender
+                    .send(Err(api_error(e)))
+                    .map_err(Error::ApiResponseSend)
+            }
+        };
+
+        let thread_sender = sender.clone();
+        let spawned = thread::Builder::new()
+            .name("guest_agent".to_string())
+            .spawn(move || {
+                let response = command(&agent)
+                    .map_err(|e| {
+                        error!("Error {} through the guest agent: {:?}", context, e);
+                        VmError::GuestAgent(e)
+                    })
+                    .and_then(|value| serde_json::to_vec(&value).map_err(VmError::SerializeJson))
+                    .map(|value| ApiResponsePayload::VmAction(Some(value)))
+                    .map_err(api_error);
+                if let Err(e) = thread_sender.send(response) {
+                    error!("Error sending the guest agent response: {:?}", e);
+                }
+            });
+        if let Err(e) = spawned {
+            let e = VmError::GuestAgent(guest_agent::Error::SpawnThread(e));
+            sender
+                .send(Err(api_error(e)))
+                .map_err(Error::ApiResponseSend)?;
+        }
+
+        Ok(())
+    }
+
+    fn vm_memory_read(&self, data: &VmMemoryReadData) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let memory = vm.memory_read(data).map_err(|e| {
+                error!("Error reading guest memory: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&memory)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_memory_write(&self, data: &VmMemoryData) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.memory_write(data).map_err(|e| {
+                error!("Error writing guest memory: {:?}", e);
+                e
+            })
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_virtqueue_state(
+        &self,
+        data: &VmVirtqueueStateData,
+    ) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            let states = vm.virtqueue_states(&data.id).map_err(|e| {
+                error!("Error reading the state of the virtqueues: {:?}", e);
+                e
+            })?;
+            serde_json::to_vec(&states)
+                .map(Some)
+                .map_err(VmError::SerializeJson)
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_boot_timings(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if let Some(ref vm) = self.vm {
+            serde_json::to_vec(&vm.boot_timings())
                 .map(Some)
                 .map_err(VmError::SerializeJson)
         } else {
@@ -1319,12 +2287,254 @@ impl Vmm {
 
     fn vm_power_button(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            // Pressing the power button wakes a sleeping machine up
+            #[cfg(target_arch = "x86_64")]
+            if vm.is_suspended() {
+                return self.vm_wakeup();
+            }
             vm.power_button()
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
+    fn vm_report_free_pages(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.report_free_pages()
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    // Monitor the pressure on the host memory, if the balloon of the VM
+    // reclaims memory from the guest when the host runs low on memory, or
+    // moves guest memory to the heterogeneous memory on memory pressure, and
+    // the memory cgroup of the VMM if the balloon keeps it under its limit.
+    fn start_overcommit(&mut self) -> result::Result<(), VmError> {
+        (self.overcommit, self.hetero_balloon) = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| {
+                let config = config.lock().unwrap();
+                let balloon = config.balloon.as_ref()?;
+                let ram_size = config
+                    .memory
+                    .total_size()
+                    .saturating_sub(balloon.size.iter().sum());
+                Some((
+                    OvercommitMonitor::new(balloon, ram_size),
+                    HeteroBalloonController::new(balloon, ram_size),
+                ))
+            })
+            .unwrap_or_default();
+
+        // Drop the previous monitor first, for its watch to be removed
+        self.cgroup_memory = None;
+        self.cgroup_memory = self.vm_config.as_ref().and_then(|config| {
+            let config = config.lock().unwrap();
+            let balloon = config.balloon.as_ref().filter(|b| b.cgroup_memory_high)?;
+            let ram_size = config
+                .memory
+                .total_size()
+                .saturating_sub(balloon.size.iter().sum());
+            let path = match cgroup::current_cgroup() {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("Failed finding the memory cgroup of the VMM: {}", e);
+                    return None;
+                }
+            };
+            match CgroupMemoryMonitor::new(balloon, ram_size, path, &self.cgroup_memory_evt) {
+                Ok(monitor) => monitor,
+                Err(e) => {
+                    warn!("Failed watching the memory cgroup of the VMM: {}", e);
+                    None
+                }
+            }
+        });
+
+        let overcommit_zones = self.vm_config.as_ref().map_or(false, |config| {
+            let config = config.lock().unwrap();
+            config.memory.zones.iter().flatten().any(|z| z.overcommit)
+        });
+        self.cold_memory = if self.overcommit.is_some() && overcommit_zones {
+            match ColdMemoryScanner::new() {
+                Ok(scanner) => Some(scanner),
+                Err(e) => {
+                    warn!("Failed tracking the idle guest memory: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        if self.overcommit.is_some()
+            || self.hetero_balloon.is_some()
+            || self.cgroup_memory.is_some()
+        {
+            self.overcommit_evt
+                .reset(OVERCOMMIT_INTERVAL, Some(OVERCOMMIT_INTERVAL))
+                .map_err(VmError::TimerfdError)
+        } else {
+            self.overcommit_evt.clear().map_err(VmError::TimerfdError)
+        }
+    }
+
+    fn vm_reclaim_memory(&mut self) {
+        if self.vm.is_none() {
+            // The VM is gone
+            self.overcommit = None;
+            self.hetero_balloon = None;
+            self.cold_memory = None;
+            self.cgroup_memory = None;
+            if let Err(e) = self.overcommit_evt.clear() {
+                warn!("Failed stopping the host memory pressure timer: {}", e);
+            }
+            return;
+        }
+
+        let Some(vm) = self.vm.as_mut() else {
+            return;
+        };
+
+        if !matches!(vm.get_state(), Ok(VmState::Running)) {
+            return;
+        }
+
+        let mut changed = false;
+
+        if let Some(monitor) = self.overcommit.as_mut() {
+            match host_available_memory() {
+                Ok(available) => {
+                    if let Some(reclaimed) = monitor.sample(available) {
+                        info!(
+                            "Host available memory {} bytes, reclaiming {} bytes from the guest",
+                            available, reclaimed
+                        );
+                        changed = true;
+                    }
+                }
+                Err(e) => warn!("Failed reading the host available memory: {}", e),
+            }
+
+            if let Some(scanner) = self
+                .cold_memory
+                .as_mut()
+                .filter(|_| monitor.cold_memory_due())
+            {
+                if let Err(e) = scanner.scan(vm.overcommit_regions()) {
+                    warn!("Failed scanning the cold guest memory: {}", e);
+                }
+            }
+        }
+
+        if let Some(controller) = self.hetero_balloon.as_mut() {
+            match memory_pressure(controller.pressure_file()) {
+                Ok(pressure) => {
+                    if let Some(moved) = controller.sample(pressure) {
+                        info!(
+                            "Memory pressure {:.2}%, moving {} bytes to the heterogeneous memory",
+                            pressure, moved
+                        );
+                        changed = true;
+                    }
+                }
+                Err(e) => warn!("Failed reading the memory pressure: {}", e),
+            }
+        }
+
+        if changed {
+            self.reclaim_balloon();
+        }
+
+        self.vm_cgroup_memory_high();
+    }
+
+    // Inflate or deflate the balloon to keep the memory cgroup of the VMM
+    // under its memory.high limit.
+    fn vm_cgroup_memory_high(&mut self) {
+        let Some(monitor) = self.cgroup_memory.as_mut() else {
+            return;
+        };
+
+        if !matches!(
+            self.vm.as_ref().map(|vm| vm.get_state()),
+            Some(Ok(VmState::Running))
+        ) {
+            return;
+        }
+
+        let usage = match cgroup_memory_usage(monitor.path()) {
+            Ok(usage) => usage,
+            Err(e) => {
+                warn!("Failed reading the memory usage of the cgroup: {}", e);
+                return;
+            }
+        };
+
+        let Some(action) = monitor.sample(&usage, Instant::now()) else {
+            return;
+        };
+
+        let reclaimed = monitor.reclaimed();
+        info!(
+            "Cgroup memory {} bytes over a limit of {:?} bytes, {} the balloon to reclaim {:?} bytes",
+            usage.current,
+            usage.high,
+            action.as_str(),
+            reclaimed
+        );
+        event!(
+            "vm",
+            "balloon-cgroup-memory-high",
+            "action",
+            action.as_str(),
+            "memory_current",
+            usage.current.to_string(),
+            "memory_high",
+            usage.high.map_or("max".to_owned(), |high| high.to_string()),
+            "reclaimed",
+            reclaimed[0].to_string(),
+            "hetero_reclaimed",
+            reclaimed[1].to_string()
+        );
+
+        self.reclaim_balloon();
+    }
+
+    // Apply the memory reclaimed from the guest by all the monitors.
+    fn reclaim_balloon(&mut self) {
+        let Some(vm) = self.vm.as_mut() else {
+            return;
+        };
+
+        let cgroup_reclaimed = self
+            .cgroup_memory
+            .as_ref()
+            .map_or([0; 2], |m| m.reclaimed());
+        let reclaimed = [
+            self.overcommit.as_ref().map_or(0, |m| m.reclaimed()) + cgroup_reclaimed[0],
+            self.hetero_balloon.as_ref().map_or(0, |c| c.state().moved) + cgroup_reclaimed[1],
+        ];
+        if let Err(e) = vm.reclaim_balloon(reclaimed) {
+            warn!("Failed reclaiming memory from the guest: {:?}", e);
+        }
+    }
+
+    fn vm_hetero_balloon(&self) -> result::Result<Option<Vec<u8>>, VmError> {
+        if self.vm.is_none() {
+            return Err(VmError::VmNotRunning);
+        }
+
+        match &self.hetero_balloon {
+            Some(controller) => serde_json::to_vec(controller.state())
+                .map(Some)
+                .map_err(VmError::SerializeJson),
+            None => Err(VmError::HeteroBalloonNotEnabled),
+        }
+    }
+
     fn vmm_enable_hmem(
         &mut self,
         enable_hmem_data: VmmEnableHmemData,
@@ -1366,6 +2576,12 @@ impl Vmm {
             &vm_migration_config.common_cpuid,
         )?;
 
+        #[cfg(target_arch = "aarch64")]
+        self.vm_check_gic_compatibility(
+            &vm_migration_config.vm_config,
+            &vm_migration_config.gic_capabilities,
+        )?;
+
         let config = vm_migration_config.vm_config.clone();
         self.vm_config = Some(vm_migration_config.vm_config);
 
@@ -1435,6 +2651,18 @@ impl Vmm {
         let reset_evt = self.reset_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning reset EventFd: {}", e))
         })?;
+        let pause_evt = self.pause_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning pause EventFd: {}", e))
+        })?;
+        let guest_panic_evt = self.guest_panic_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning guest panic EventFd: {}", e))
+        })?;
+        let suspend_evt = self.suspend_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning suspend EventFd: {}", e))
+        })?;
+        let hibernate_evt = self.hibernate_evt.try_clone().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error cloning hibernate EventFd: {}", e))
+        })?;
         #[cfg(feature = "guest_debug")]
         let debug_evt = self.vm_debug_evt.try_clone().map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error cloning debug EventFd: {}", e))
@@ -1443,6 +2671,11 @@ impl Vmm {
             MigratableError::MigrateReceive(anyhow!("Error cloning activate EventFd: {}", e))
         })?;
 
+        let cgroup =
+            Vm::create_cgroup(&self.vm_config.as_ref().unwrap().lock().unwrap()).map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error creating the cgroup: {:?}", e))
+            })?;
+
         let timestamp = Instant::now();
         let hypervisor_vm = mm.lock().unwrap().vm.clone();
         let mut vm = Vm::new_from_memory_manager(
@@ -1451,6 +2684,11 @@ impl Vmm {
             hypervisor_vm,
             exit_evt,
             reset_evt,
+            pause_evt,
+            self.watchdog_counters.clone(),
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             #[cfg(feature = "guest_debug")]
             debug_evt,
             &self.seccomp_action,
@@ -1462,6 +2700,7 @@ impl Vmm {
             None,
             Arc::clone(&self.original_termios_opt),
             Some(snapshot),
+            cgroup,
         )
         .map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error creating VM from snapshot: {:?}", e))
@@ -1472,8 +2711,15 @@ impl Vmm {
             Response::error().write_to(socket).ok();
             MigratableError::MigrateReceive(anyhow!("Failed restoring the Vm: {}", e))
         })?;
+        self.cancel_crash_restart();
+        self.crash_backoff = CrashBackoff::default();
         self.vm = Some(vm);
 
+        if let Err(e) = self.start_overcommit() {
+            warn!("Failed monitoring the host memory pressure: {:?}", e);
+        }
+        self.run_hooks(HookEvent::Boot);
+
         Response::ok().write_to(socket)?;
 
         Ok(())
@@ -1492,12 +2738,14 @@ impl Vmm {
         let table = MemoryRangeTable::read_from(socket, req.length())?;
 
         // And then read the memory itself
+        api::jobs::set_stage("memory");
         memory_manager
             .receive_memory_regions(&table, socket)
             .map_err(|e| {
                 Response::error().write_to(socket).ok();
                 e
             })?;
+        api::jobs::add_memory_bytes(Self::memory_bytes(&table));
         Response::ok().write_to(socket)?;
         Ok(())
     }
@@ -1523,9 +2771,22 @@ impl Vmm {
         let listener = UnixListener::bind(&path).map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error binding to UNIX socket: {}", e))
         })?;
-        let (mut socket, _addr) = listener.accept().map_err(|e| {
-            MigratableError::MigrateReceive(anyhow!("Error accepting on UNIX socket: {}", e))
-        })?;
+        // The connections checking the destination beforehand only last for
+        // their pre-check, the next one being waited for.
+        let (mut socket, first_req) = loop {
+            let (mut socket, _addr) = listener.accept().map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error accepting on UNIX socket: {}", e))
+            })?;
+            let req = Request::read_from(&mut socket)?;
+            if !matches!(req.command(), Command::Precheck) {
+                break (socket, req);
+            }
+
+            info!("Precheck Command Received");
+            if let Err(e) = self.vm_receive_precheck(&req, &mut socket) {
+                warn!("Failed checking the migration: {:?}", e);
+            }
+        };
         std::fs::remove_file(&path).map_err(|e| {
             MigratableError::MigrateReceive(anyhow!("Error unlinking UNIX socket: {}", e))
         })?;
@@ -1533,8 +2794,12 @@ impl Vmm {
         let mut started = false;
         let mut memory_manager: Option<Arc<Mutex<MemoryManager>>> = None;
         let mut existing_memory_files = None;
+        let mut next_req = Some(first_req);
         loop {
-            let req = Request::read_from(&mut socket)?;
+            let req = match next_req.take() {
+                Some(req) => req,
+                None => Request::read_from(&mut socket)?,
+            };
             match req.command() {
                 Command::Invalid => info!("Invalid Command Received"),
                 Command::Start => {
@@ -1559,6 +2824,7 @@ impl Vmm {
                 }
                 Command::State => {
                     info!("State Command Received");
+                    api::jobs::set_stage("state");
 
                     if !started {
                         warn!("Migration not started yet");
@@ -1633,12 +2899,77 @@ impl Vmm {
                     Response::ok().write_to(&mut socket).ok();
                     break;
                 }
+                Command::Precheck => {
+                    warn!("Precheck Command Received during the migration");
+                    Response::error().write_to(&mut socket)?;
+                }
             }
         }
 
         Ok(())
     }
 
+    // Check the configuration of the VM to migrate against the destination,
+    // without creating anything.
+    fn vm_receive_precheck<T>(
+        &self,
+        req: &Request,
+        socket: &mut T,
+    ) -> std::result::Result<(), MigratableError>
+    where
+        T: Read + Write,
+    {
+        let mut data: Vec<u8> = Vec::new();
+        data.resize_with(req.length() as usize, Default::default);
+        socket
+            .read_exact(&mut data)
+            .map_err(MigratableError::MigrateSocket)?;
+
+        let precheck_data: MigrationPrecheckData = serde_json::from_slice(&data).map_err(|e| {
+            Response::error().write_to(socket).ok();
+            MigratableError::MigrateReceive(anyhow!("Error deserialising config: {}", e))
+        })?;
+        let vm_migration_config = precheck_data.config;
+
+        let mut errors = Vec::new();
+
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        if let Err(e) = self.vm_check_cpuid_compatibility(
+            &vm_migration_config.vm_config,
+            &vm_migration_config.common_cpuid,
+        ) {
+            errors.push(e.to_string());
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if let Err(e) = self.vm_check_gic_compatibility(
+            &vm_migration_config.vm_config,
+            &vm_migration_config.gic_capabilities,
+        ) {
+            errors.push(e.to_string());
+        }
+
+        errors.extend(check_destination(
+            &vm_migration_config.vm_config.lock().unwrap(),
+            precheck_data.local,
+        ));
+
+        let report = serde_json::to_vec(&MigrationPrecheckReport {
+            errors,
+            ..Default::default()
+        })
+        .unwrap();
+        Response::new(Status::Ok, report.len() as u64).write_to(socket)?;
+        socket
+            .write_all(&report)
+            .map_err(MigratableError::MigrateSocket)
+    }
+
+    // Guest memory covered by the table, in bytes
+    fn memory_bytes(table: &MemoryRangeTable) -> u64 {
+        table.regions().iter().map(|range| range.length).sum()
+    }
+
     // Returns true if there were dirty pages to send
     fn vm_maybe_send_dirty_pages<T>(
         vm: &mut Vm,
@@ -1659,6 +2990,7 @@ impl Vmm {
         table.write_to(socket)?;
         // And then the memory itself
         vm.send_memory_regions(&table, socket)?;
+        api::jobs::add_memory_bytes(Self::memory_bytes(&table));
         let res = Response::read_from(socket)?;
         if res.status() != Status::Ok {
             warn!("Error during dirty memory migration");
@@ -1672,6 +3004,57 @@ impl Vmm {
         Ok(true)
     }
 
+    // Configuration of the VM sent to the destination
+    fn migration_config(
+        vm: &Vm,
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))] hypervisor: &Arc<
+            dyn hypervisor::Hypervisor,
+        >,
+    ) -> result::Result<VmMigrationConfig, MigratableError> {
+        let vm_config = vm.get_config();
+        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+        let common_cpuid = {
+            #[cfg(feature = "tdx")]
+            let tdx = vm_config.lock().unwrap().is_tdx_enabled();
+            let amx = vm_config.lock().unwrap().cpus.features.amx;
+            let kvm_hyperv_features = vm_config.lock().unwrap().cpus.hyperv_features();
+            let nested = vm_config.lock().unwrap().cpus.nested;
+            let model = vm_config.lock().unwrap().cpus.model.clone();
+            let cpuid_add = vm_config.lock().unwrap().cpus.cpuid_add.clone();
+            let cpuid_remove = vm_config.lock().unwrap().cpus.cpuid_remove.clone();
+            let phys_bits =
+                vm::physical_bits(hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
+            arch::generate_common_cpuid(
+                hypervisor,
+                &arch::CpuidConfig {
+                    sgx_epc_sections: None,
+                    phys_bits,
+                    kvm_hyperv: vm_config.lock().unwrap().cpus.kvm_hyperv,
+                    kvm_hyperv_features,
+                    #[cfg(feature = "tdx")]
+                    tdx,
+                    amx,
+                    nested,
+                    model,
+                    cpuid_add,
+                    cpuid_remove,
+                },
+            )
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error generating common cpuid': {:?}", e))
+            })?
+        };
+
+        Ok(VmMigrationConfig {
+            vm_config,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            common_cpuid,
+            #[cfg(target_arch = "aarch64")]
+            gic_capabilities: vm.gic_capabilities()?,
+            memory_manager_data: vm.memory_manager_data(),
+        })
+    }
+
     fn send_migration(
         vm: &mut Vm,
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))] hypervisor: Arc<
@@ -1679,6 +3062,7 @@ impl Vmm {
         >,
         send_data_migration: VmSendMigrationData,
     ) -> result::Result<(), MigratableError> {
+        trace_scoped!("send_migration");
         let path = Self::socket_url_to_path(&send_data_migration.destination_url)?;
         let mut socket = UnixStream::connect(path).map_err(|e| {
             MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
@@ -1697,40 +3081,16 @@ impl Vmm {
         }
 
         // Send config
-        let vm_config = vm.get_config();
-        #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-        let common_cpuid = {
-            #[cfg(feature = "tdx")]
-            let tdx = vm_config.lock().unwrap().is_tdx_enabled();
-            let amx = vm_config.lock().unwrap().cpus.features.amx;
-            let phys_bits =
-                vm::physical_bits(&hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
-            arch::generate_common_cpuid(
-                &hypervisor,
-                &arch::CpuidConfig {
-                    sgx_epc_sections: None,
-                    phys_bits,
-                    kvm_hyperv: vm_config.lock().unwrap().cpus.kvm_hyperv,
-                    #[cfg(feature = "tdx")]
-                    tdx,
-                    amx,
-                },
-            )
-            .map_err(|e| {
-                MigratableError::MigrateReceive(anyhow!("Error generating common cpuid': {:?}", e))
-            })?
-        };
+        let vm_migration_config = Self::migration_config(
+            vm,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            &hypervisor,
+        )?;
 
         if send_data_migration.local {
             vm.send_memory_fds(&mut socket)?;
         }
 
-        let vm_migration_config = VmMigrationConfig {
-            vm_config,
-            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
-            common_cpuid,
-            memory_manager_data: vm.memory_manager_data(),
-        };
         let config_data = serde_json::to_vec(&vm_migration_config).unwrap();
         Request::config(config_data.len() as u64).write_to(&mut socket)?;
         socket
@@ -1757,6 +3117,7 @@ impl Vmm {
             vm.start_dirty_log()?;
 
             // Send memory table
+            api::jobs::set_stage("memory");
             let table = vm.memory_range_table()?;
             Request::memory(table.length())
                 .write_to(&mut socket)
@@ -1764,6 +3125,7 @@ impl Vmm {
             table.write_to(&mut socket)?;
             // And then the memory itself
             vm.send_memory_regions(&table, &mut socket)?;
+            api::jobs::add_memory_bytes(Self::memory_bytes(&table));
             let res = Response::read_from(&mut socket)?;
             if res.status() != Status::Ok {
                 warn!("Error during memory migration");
@@ -1775,8 +3137,10 @@ impl Vmm {
             }
 
             // Try at most 5 passes of dirty memory sending
+            api::jobs::set_stage("dirty-memory");
             const MAX_DIRTY_MIGRATIONS: usize = 5;
             for i in 0..MAX_DIRTY_MIGRATIONS {
+                trace_scoped!("migration_iteration");
                 info!("Dirty memory migration {} of {}", i, MAX_DIRTY_MIGRATIONS);
                 if !Self::vm_maybe_send_dirty_pages(vm, &mut socket)? {
                     break;
@@ -1792,38 +3156,155 @@ impl Vmm {
             // Stop logging dirty pages
             vm.stop_dirty_log()?;
         }
-        // Capture snapshot and send it
-        let vm_snapshot = vm.snapshot()?;
-        let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
-        Request::state(snapshot_data.len() as u64).write_to(&mut socket)?;
+        // Capture snapshot and send it
+        api::jobs::set_stage("state");
+        let vm_snapshot = vm.snapshot()?;
+        let snapshot_data = serde_json::to_vec(&vm_snapshot).unwrap();
+        Request::state(snapshot_data.len() as u64).write_to(&mut socket)?;
+        socket
+            .write_all(&snapshot_data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let res = Response::read_from(&mut socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error during state migration");
+            Request::abandon().write_to(&mut socket)?;
+            Response::read_from(&mut socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error during state migration"
+            )));
+        }
+
+        // Complete the migration
+        Request::complete().write_to(&mut socket)?;
+        let res = Response::read_from(&mut socket)?;
+        if res.status() != Status::Ok {
+            warn!("Error completing migration");
+            Request::abandon().write_to(&mut socket)?;
+            Response::read_from(&mut socket).ok();
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Error completing migration"
+            )));
+        }
+        info!("Migration complete");
+
+        // Let every Migratable object know about the migration being complete
+        vm.complete_migration()
+    }
+
+    // Check that the VM can be migrated, whatever the destination.
+    fn vm_check_migratable(
+        &self,
+        send_data_migration: &VmSendMigrationData,
+    ) -> result::Result<(), MigratableError> {
+        if !self
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .backed_by_shared_memory()
+            && send_data_migration.local
+        {
+            return Err(MigratableError::MigrateSend(anyhow!(
+                "Local migration requires shared memory or hugepages enabled"
+            )));
+        }
+
+        if let Some(blocker) = self.mdev_migration_blocker() {
+            return Err(MigratableError::MigrateSend(blocker));
+        }
+
+        Ok(())
+    }
+
+    // The state of the mediated devices can't be saved, which prevents the
+    // VM from being snapshotted or migrated.
+    fn mdev_migration_blocker(&self) -> Option<anyhow::Error> {
+        let config = self.vm_config.as_ref()?.lock().unwrap();
+        let device = config
+            .devices
+            .as_ref()?
+            .iter()
+            .find(|d| mdev::is_mdev(&d.path))?;
+
+        Some(anyhow!(
+            "Migration is blocked by the mediated device {} ({})",
+            device.id.as_deref().unwrap_or_default(),
+            device.path.display()
+        ))
+    }
+
+    // Check the migration of the VM against its destination, and estimate
+    // the amount of data to transfer, without pausing the VM nor sending any
+    // of its state.
+    fn vm_migration_precheck(
+        &self,
+        send_data_migration: VmSendMigrationData,
+    ) -> result::Result<Option<Vec<u8>>, MigratableError> {
+        info!(
+            "Checking migration: destination_url = {}, local = {}",
+            send_data_migration.destination_url, send_data_migration.local
+        );
+
+        let Some(vm) = self.vm.as_ref() else {
+            return Err(MigratableError::MigrateSend(anyhow!("VM is not running")));
+        };
+
+        let mut report = MigrationPrecheckReport::default();
+        if let Err(e) = self.vm_check_migratable(&send_data_migration) {
+            report.errors.push(e.to_string());
+        }
+
+        let config = Self::migration_config(
+            vm,
+            #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
+            &self.hypervisor,
+        )?;
+        report.config_size = serde_json::to_vec(&config).unwrap().len() as u64;
+        if !send_data_migration.local {
+            report.memory_size = vm
+                .memory_range_table()?
+                .regions()
+                .iter()
+                .map(|r| r.length)
+                .sum();
+        }
+
+        let path = Self::socket_url_to_path(&send_data_migration.destination_url)?;
+        let mut socket = UnixStream::connect(path).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error connecting to UNIX socket: {}", e))
+        })?;
+
+        let precheck_data = serde_json::to_vec(&MigrationPrecheckData {
+            config,
+            local: send_data_migration.local,
+        })
+        .unwrap();
+        Request::precheck(precheck_data.len() as u64).write_to(&mut socket)?;
         socket
-            .write_all(&snapshot_data)
+            .write_all(&precheck_data)
             .map_err(MigratableError::MigrateSocket)?;
         let res = Response::read_from(&mut socket)?;
         if res.status() != Status::Ok {
-            warn!("Error during state migration");
-            Request::abandon().write_to(&mut socket)?;
-            Response::read_from(&mut socket).ok();
             return Err(MigratableError::MigrateSend(anyhow!(
-                "Error during state migration"
+                "Error checking the migration on the destination"
             )));
         }
 
-        // Complete the migration
-        Request::complete().write_to(&mut socket)?;
-        let res = Response::read_from(&mut socket)?;
-        if res.status() != Status::Ok {
-            warn!("Error completing migration");
-            Request::abandon().write_to(&mut socket)?;
-            Response::read_from(&mut socket).ok();
-            return Err(MigratableError::MigrateSend(anyhow!(
-                "Error completing migration"
-            )));
-        }
-        info!("Migration complete");
+        let mut data: Vec<u8> = Vec::new();
+        data.resize_with(res.length() as usize, Default::default);
+        socket
+            .read_exact(&mut data)
+            .map_err(MigratableError::MigrateSocket)?;
+        let destination_report: MigrationPrecheckReport =
+            serde_json::from_slice(&data).map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error deserialising the report: {}", e))
+            })?;
+        report.errors.extend(destination_report.errors);
 
-        // Let every Migratable object know about the migration being complete
-        vm.complete_migration()
+        serde_json::to_vec(&report).map(Some).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Error serialising the report: {}", e))
+        })
     }
 
     fn vm_send_migration(
@@ -1835,19 +3316,7 @@ impl Vmm {
             send_data_migration.destination_url, send_data_migration.local
         );
 
-        if !self
-            .vm_config
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .backed_by_shared_memory()
-            && send_data_migration.local
-        {
-            return Err(MigratableError::MigrateSend(anyhow!(
-                "Local migration requires shared memory or hugepages enabled"
-            )));
-        }
+        self.vm_check_migratable(&send_data_migration)?;
 
         if let Some(vm) = self.vm.as_mut() {
             Self::send_migration(
@@ -1903,9 +3372,14 @@ impl Vmm {
                     sgx_epc_sections: None,
                     phys_bits,
                     kvm_hyperv: vm_config.cpus.kvm_hyperv,
+                    kvm_hyperv_features: vm_config.cpus.hyperv_features(),
                     #[cfg(feature = "tdx")]
                     tdx: vm_config.is_tdx_enabled(),
                     amx: vm_config.cpus.features.amx,
+                    nested: vm_config.cpus.nested,
+                    model: vm_config.cpus.model.clone(),
+                    cpuid_add: vm_config.cpus.cpuid_add.clone(),
+                    cpuid_remove: vm_config.cpus.cpuid_remove.clone(),
                 },
             )
             .map_err(|e| {
@@ -1920,6 +3394,38 @@ impl Vmm {
         })
     }
 
+    #[cfg(target_arch = "aarch64")]
+    fn vm_check_gic_compatibility(
+        &self,
+        src_vm_config: &Arc<Mutex<VmConfig>>,
+        src_gic_capabilities: &hypervisor::arch::aarch64::gic::VgicCapabilities,
+    ) -> result::Result<(), MigratableError> {
+        // A VM only gets a single GIC, so the capabilities of the GIC of the
+        // destination are probed from a GIC created in a throwaway VM.
+        let vm = self.hypervisor.create_vm().map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!("Error creating hypervisor VM: {:?}", e))
+        })?;
+        let boot_vcpus = src_vm_config.lock().unwrap().cpus.boot_vcpus;
+        let dest_gic_capabilities = vm
+            .create_vgic(devices::gic::Gic::create_default_config(boot_vcpus.into()))
+            .map_err(|e| MigratableError::MigrateReceive(anyhow!("Error creating GIC: {:?}", e)))?
+            .lock()
+            .unwrap()
+            .capabilities()
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Error getting GIC capabilities: {:?}", e))
+            })?;
+
+        dest_gic_capabilities
+            .check_compatibility(src_gic_capabilities)
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!(
+                    "Error checking GIC compatibility: {:?}",
+                    e
+                ))
+            })
+    }
+
     fn control_loop(
         &mut self,
         api_receiver: Rc<Receiver<ApiRequest>>,
@@ -1948,6 +3454,7 @@ impl Vmm {
                 }
             };
 
+            let dispatch_start = Instant::now();
             for event in events.iter().take(num_events) {
                 let dispatch_event: EpollDispatch = event.data.into();
                 match dispatch_event {
@@ -1969,6 +3476,57 @@ impl Vmm {
                         self.reset_evt.read().map_err(Error::EventFdRead)?;
                         self.vm_reboot().map_err(Error::VmReboot)?;
                     }
+                    EpollDispatch::Pause => {
+                        info!("VM pause event");
+                        // Consume the event.
+                        self.pause_evt.read().map_err(Error::EventFdRead)?;
+                        // The VM may already be paused or not running anymore,
+                        // which must not bring the VMM down.
+                        if let Err(e) = self.vm_pause() {
+                            warn!("Failed to pause the VM: {:?}", e);
+                        }
+                    }
+                    EpollDispatch::Suspend => {
+                        info!("VM suspend event");
+                        // Consume the event.
+                        self.suspend_evt.read().map_err(Error::EventFdRead)?;
+                        #[cfg(target_arch = "x86_64")]
+                        if let Err(e) = self.vm_suspend() {
+                            error!("Failed to suspend the VM: {:?}", e);
+                        }
+                    }
+                    EpollDispatch::Hibernate => {
+                        info!("VM hibernate event");
+                        // Consume the event.
+                        self.hibernate_evt.read().map_err(Error::EventFdRead)?;
+                        event!("vm", "hibernated");
+                        #[cfg(target_arch = "x86_64")]
+                        match self.hibernate_action() {
+                            HibernateAction::Exit => {
+                                self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+
+                                break 'outer;
+                            }
+                            HibernateAction::Shutdown => {
+                                // The VM gets booted again through the API,
+                                // the guest resuming from its hibernation image.
+                                if let Err(e) = self.vm_shutdown() {
+                                    error!("Failed to shut the hibernated VM down: {:?}", e);
+                                }
+                            }
+                            HibernateAction::Reboot => {
+                                self.vm_reboot().map_err(Error::VmReboot)?;
+                            }
+                        }
+                    }
+                    EpollDispatch::GuestPanic => {
+                        info!("VM guest panic event");
+                        // Consume the event.
+                        self.guest_panic_evt.read().map_err(Error::EventFdRead)?;
+                        if let Err(e) = self.vm_guest_panic() {
+                            error!("Failed to apply the crash or pvpanic policy: {:?}", e);
+                        }
+                    }
                     EpollDispatch::ActivateVirtioDevices => {
                         if let Some(ref vm) = self.vm {
                             let count = self.activate_evt.read().map_err(Error::EventFdRead)?;
@@ -1985,6 +3543,7 @@ impl Vmm {
                         for _ in 0..self.api_evt.read().map_err(Error::EventFdRead)? {
                             // Read from the API receiver channel
                             let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
+                            trace_scoped!("api_request");
 
                             info!("API request event: {:?}", api_request);
                             match api_request {
@@ -2041,6 +3600,14 @@ impl Vmm {
 
                                     sender.send(Ok(response)).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmmResources(sender) => {
+                                    let response = self
+                                        .vmm_resources()
+                                        .map_err(ApiError::VmmResources)
+                                        .map(ApiResponsePayload::VmmResources);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmPause(sender) => {
                                     let response = self
                                         .vm_pause()
@@ -2113,6 +3680,50 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmBindZone(bind_zone_data, sender) => {
+                                    let response = self
+                                        .vm_bind_zone(
+                                            bind_zone_data.id.clone(),
+                                            bind_zone_data.host_numa_node,
+                                        )
+                                        .map_err(ApiError::VmBindZone)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmResizeFs(resize_fs_data, sender) => {
+                                    let response = self
+                                        .vm_resize_fs(
+                                            resize_fs_data.id.clone(),
+                                            resize_fs_data.desired_cache_size,
+                                        )
+                                        .map_err(ApiError::VmResizeFs)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetCpuAffinity(cpu_affinity_data, sender) => {
+                                    let response = self
+                                        .vm_set_cpu_affinity(&cpu_affinity_data.affinity)
+                                        .map_err(ApiError::VmSetCpuAffinity)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetCpuBandwidth(cpu_bandwidth_data, sender) => {
+                                    let response = self
+                                        .vm_set_cpu_bandwidth(
+                                            cpu_bandwidth_data.cpu_max,
+                                            cpu_bandwidth_data.vcpu_max,
+                                        )
+                                        .map_err(ApiError::VmSetCpuBandwidth)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRemoveVcpu(remove_vcpu_data, sender) => {
+                                    let response = self
+                                        .vm_remove_vcpu(remove_vcpu_data.id)
+                                        .map_err(ApiError::VmRemoveVcpu)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddDevice(add_device_data, sender) => {
                                     let response = self
                                         .vm_add_device(add_device_data.as_ref().clone())
@@ -2120,6 +3731,20 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAddVf(add_vf_data, sender) => {
+                                    let response = self
+                                        .vm_add_vf(add_vf_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddVf)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddMdev(add_mdev_data, sender) => {
+                                    let response = self
+                                        .vm_add_mdev(add_mdev_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddMdev)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddUserDevice(add_device_data, sender) => {
                                     let response = self
                                         .vm_add_user_device(add_device_data.as_ref().clone())
@@ -2176,13 +3801,98 @@ impl Vmm {
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
-                                ApiRequest::VmCounters(sender) => {
+                                #[cfg(target_arch = "x86_64")]
+                                ApiRequest::VmAddSgxEpc(add_sgx_epc_data, sender) => {
+                                    let response = self
+                                        .vm_add_sgx_epc(add_sgx_epc_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddSgxEpc)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddConsolePort(add_console_port_data, sender) => {
+                                    let response = self
+                                        .vm_add_console_port(add_console_port_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddConsolePort)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRemoveConsolePort(
+                                    remove_console_port_data,
+                                    sender,
+                                ) => {
+                                    let response = self
+                                        .vm_remove_console_port(remove_console_port_data.id.clone())
+                                        .map_err(ApiError::VmRemoveConsolePort)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCounters(counters_data, sender) => {
                                     let response = self
-                                        .vm_counters()
+                                        .vm_counters(counters_data.as_ref())
                                         .map_err(ApiError::VmInfo)
                                         .map(ApiResponsePayload::VmAction);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmGuestExec(guest_exec_data, sender) => {
+                                    self.vm_guest_agent_request(
+                                        sender,
+                                        ApiError::VmGuestExec,
+                                        "running a command",
+                                        move |agent| agent.exec(guest_exec_data.as_ref()),
+                                    )?;
+                                }
+                                ApiRequest::VmGuestFsFreeze(guest_fsfreeze_data, sender) => {
+                                    let action = guest_fsfreeze_data.action;
+                                    self.vm_guest_agent_request(
+                                        sender,
+                                        ApiError::VmGuestFsFreeze,
+                                        "freezing the filesystems",
+                                        move |agent| agent.fsfreeze(action),
+                                    )?;
+                                }
+                                ApiRequest::VmMemoryRead(memory_read_data, sender) => {
+                                    let response = self
+                                        .vm_memory_read(memory_read_data.as_ref())
+                                        .map_err(ApiError::VmMemoryRead)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmMemoryWrite(memory_write_data, sender) => {
+                                    let response = self
+                                        .vm_memory_write(memory_write_data.as_ref())
+                                        .map_err(ApiError::VmMemoryWrite)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmVirtqueueState(virtqueue_state_data, sender) => {
+                                    let response = self
+                                        .vm_virtqueue_state(virtqueue_state_data.as_ref())
+                                        .map_err(ApiError::VmVirtqueueState)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmGuestInfo(sender) => {
+                                    self.vm_guest_agent_request(
+                                        sender,
+                                        ApiError::VmGuestInfo,
+                                        "getting the information",
+                                        |agent| agent.info(),
+                                    )?;
+                                }
+                                ApiRequest::VmBootTimings(sender) => {
+                                    let response = self
+                                        .vm_boot_timings()
+                                        .map_err(ApiError::VmBootTimings)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmHeteroBalloon(sender) => {
+                                    let response = self
+                                        .vm_hetero_balloon()
+                                        .map_err(ApiError::VmHeteroBalloon)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmReceiveMigration(receive_migration_data, sender) => {
                                     let response = self
                                         .vm_receive_migration(
@@ -2199,6 +3909,13 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmMigrationPrecheck(send_migration_data, sender) => {
+                                    let response = self
+                                        .vm_migration_precheck(send_migration_data.as_ref().clone())
+                                        .map_err(ApiError::VmMigrationPrecheck)
+                                        .map(ApiResponsePayload::VmAction);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmPowerButton(sender) => {
                                     let response = self
                                         .vm_power_button()
@@ -2207,6 +3924,14 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmReportFreePages(sender) => {
+                                    let response = self
+                                        .vm_report_free_pages()
+                                        .map_err(ApiError::VmReportFreePages)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmmEnableHmem(enable_hmem_data, sender) => {
                                     let response = self
                                         .vmm_enable_hmem(enable_hmem_data.as_ref().clone())
@@ -2239,6 +3964,31 @@ impl Vmm {
                     }
                     #[cfg(not(feature = "guest_debug"))]
                     EpollDispatch::Debug => {}
+                    EpollDispatch::Overcommit => {
+                        // Consume the event.
+                        self.overcommit_evt.wait().map_err(Error::TimerFdWait)?;
+                        self.vm_reclaim_memory();
+                    }
+                    EpollDispatch::CounterRates => {
+                        // Consume the event.
+                        self.counters_evt.wait().map_err(Error::TimerFdWait)?;
+                        self.vm_sample_counters();
+                    }
+                    EpollDispatch::CrashRestart => {
+                        info!("VM crash restart event");
+                        // Consume the event.
+                        self.crash_restart_evt.wait().map_err(Error::TimerFdWait)?;
+                        if let Err(e) = self.vm_crash_restart() {
+                            error!("Failed to restart the crashed VM: {:?}", e);
+                        }
+                    }
+                    EpollDispatch::CgroupMemory => {
+                        // Consume the event.
+                        if let Err(e) = inotify_drain(&self.cgroup_memory_evt) {
+                            warn!("Failed reading the memory cgroup events: {}", e);
+                        }
+                        self.vm_cgroup_memory_high();
+                    }
                     EpollDispatch::Hmem => {
                         // Consume the event.
                         let count = self.hmem_evt.wait().map_err(Error::TimerFdWait)?;
@@ -2252,6 +4002,8 @@ impl Vmm {
                     }
                 }
             }
+            self.resource_monitor
+                .record_epoll_latency(dispatch_start.elapsed());
         }
 
         // Trigger the termination of the signal_handler thread
@@ -2277,8 +4029,9 @@ mod unit_tests {
     use super::*;
     use config::{
         ConsoleConfig, ConsoleOutputMode, CpusConfig, HotplugMethod, MemoryConfig, PayloadConfig,
-        RngConfig, VmConfig,
+        RngConfig, ValidationError, VmConfig, WatchdogAction,
     };
+    use std::time::Duration;
 
     fn create_dummy_vmm() -> Vmm {
         Vmm::new(
@@ -2302,9 +4055,20 @@ mod unit_tests {
                 max_vcpus: 1,
                 topology: None,
                 kvm_hyperv: false,
+                kvm_hyperv_features: None,
                 max_phys_bits: 46,
                 affinity: None,
+                efficiency_cores: None,
+                pmu: true,
+                pmu_events: None,
+                nested: false,
+                model: None,
+                cpuid_add: None,
+                cpuid_remove: None,
+                cpu_max: None,
+                vcpu_max: None,
                 features: config::CpuFeatures::default(),
+                removed_vcpus: None,
             },
             memory: MemoryConfig {
                 size: 536_870_912,
@@ -2328,36 +4092,67 @@ mod unit_tests {
             rng: RngConfig {
                 src: PathBuf::from("/dev/urandom"),
                 iommu: false,
+                rate_limiter_config: None,
+                mmio: false,
+                removed: false,
             },
             balloon: None,
             fs: None,
             pmem: None,
+            ivshmem: None,
             serial: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Null,
                 iommu: false,
                 socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
             },
             console: ConsoleConfig {
                 file: None,
                 mode: ConsoleOutputMode::Tty,
                 iommu: false,
                 socket: None,
+                max_ports: 1,
+                tcp: None,
+                telnet: false,
+                socket_dir: None,
             },
+            console_ports: None,
+            debug_console: None,
             devices: None,
             user_devices: None,
             vdpa: None,
             vsock: None,
+            guest_agent: None,
             pvpanic: false,
+            pvpanic_policy: None,
+            on_crash: None,
             iommu: false,
             #[cfg(target_arch = "x86_64")]
             sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: None,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars: None,
             numa: None,
             watchdog: false,
+            watchdog_action: WatchdogAction::Reset,
+            watchdog_escalation: None,
             #[cfg(feature = "guest_debug")]
             gdb: false,
             platform: None,
+            pci_segments: None,
+            pci_root_ports: None,
             tpm: None,
+            acpi_tables: None,
+            cgroup: None,
+            process_limits: None,
+            seccomp_overrides: None,
+            landlock_enable: false,
+            landlock_rules: None,
             preserved_fds: None,
         }))
     }
@@ -2374,6 +4169,58 @@ mod unit_tests {
         ));
     }
 
+    #[test]
+    fn test_vmm_vm_delete_cancels_crash_restart() {
+        let mut vmm = create_dummy_vmm();
+        let on_crash = OnCrashConfig {
+            backoff: 1,
+            max_backoff: 60,
+            ..Default::default()
+        };
+
+        assert!(matches!(vmm.vm_create(create_dummy_vm_config()), Ok(())));
+        let now = Instant::now();
+        assert_eq!(
+            vmm.crash_backoff.restart_delay(&on_crash, now),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            vmm.crash_backoff.restart_delay(&on_crash, now),
+            Some(Duration::from_secs(2))
+        );
+        vmm.crash_restart_evt
+            .reset(Duration::from_secs(60), None)
+            .unwrap();
+
+        // The next VM neither gets restarted by the timer of the deleted one
+        // nor inherits its backoff.
+        assert!(matches!(vmm.vm_delete(), Ok(())));
+        assert!(!vmm.crash_restart_evt.is_armed().unwrap());
+        assert_eq!(
+            vmm.crash_backoff.restart_delay(&on_crash, now),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_pci_device_info_serde() {
+        let info = PciDeviceInfo {
+            id: "_disk0".to_owned(),
+            bdf: PciBdf::new(1, 0, 3, 0),
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(json, r#"{"id":"_disk0","bdf":"0001:00:03.0"}"#);
+
+        let info: PciDeviceInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(info.id, "_disk0");
+        assert!(info.bdf == PciBdf::new(1, 0, 3, 0));
+
+        assert!(serde_json::from_str::<PciDeviceInfo>(r#"{"id":"a","bdf":"0001:03.0"}"#).is_err());
+        assert!(
+            serde_json::from_str::<PciDeviceInfo>(r#"{"id":"a","bdf":"00:03:0.0.0"}"#).is_err()
+        );
+    }
+
     #[test]
     fn test_vmm_vm_cold_add_device() {
         let mut vmm = create_dummy_vmm();
@@ -2742,4 +4589,95 @@ mod unit_tests {
             vsock_config
         );
     }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vmm_vm_cold_add_sgx_epc() {
+        let mut vmm = create_dummy_vmm();
+        let sgx_epc_config = SgxEpcConfig::parse("id=epc0,size=64M,prefault=on").unwrap();
+
+        assert!(matches!(
+            vmm.vm_add_sgx_epc(sgx_epc_config.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(vmm
+            .vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .sgx_epc
+            .is_none());
+
+        assert!(vmm.vm_add_sgx_epc(sgx_epc_config.clone()).is_ok());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .sgx_epc
+                .clone()
+                .unwrap(),
+            vec![sgx_epc_config.clone()]
+        );
+
+        // Identifiers must be unique across the configuration.
+        assert!(matches!(
+            vmm.vm_add_sgx_epc(sgx_epc_config),
+            Err(VmError::ConfigValidation(
+                ValidationError::IdentifierNotUnique(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_vmm_vm_cold_add_console_port() {
+        let mut vmm = create_dummy_vmm();
+        let port_config =
+            ConsolePortConfig::parse("name=agent,socket=/tmp/agent.sock,id=port0").unwrap();
+
+        assert!(matches!(
+            vmm.vm_add_console_port(port_config.clone()),
+            Err(VmError::VmNotCreated)
+        ));
+
+        let _ = vmm.vm_create(create_dummy_vm_config());
+        assert!(matches!(
+            vmm.vm_add_console_port(port_config.clone()),
+            Err(VmError::ConfigValidation(
+                ValidationError::ConsolePortsWithoutMultiport
+            ))
+        ));
+
+        vmm.vm_config
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .console
+            .max_ports = 2;
+        let result = vmm.vm_add_console_port(port_config.clone());
+        assert!(result.is_ok());
+        assert!(result.unwrap().is_none());
+        assert_eq!(
+            vmm.vm_config
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .console_ports
+                .clone()
+                .unwrap(),
+            vec![port_config]
+        );
+
+        assert!(vmm.vm_remove_console_port("port0".to_owned()).is_ok());
+        assert!(matches!(
+            vmm.vm_remove_console_port("port0".to_owned()),
+            Err(VmError::NoDeviceToRemove(_))
+        ));
+    }
 }
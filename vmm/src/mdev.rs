@@ -0,0 +1,155 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host side setup of the mediated devices (mdev), such as vGPUs.
+//!
+//! A parent device registered with the mdev framework lists the types of the
+//! devices it can create under `mdev_supported_types`, and an instance of a
+//! type is created by writing a UUID to its `create` file. The instance is then
+//! found under `/sys/bus/mdev/devices/<uuid>`, from where it is assigned to the
+//! VM like any other VFIO device.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use uuid::Uuid;
+
+const MDEV_BUS_CLASS: &str = "/sys/class/mdev_bus";
+const MDEV_DEVICES: &str = "/sys/bus/mdev/devices";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Device {0} is not a mediated device parent")]
+    NotParent(String),
+
+    #[error("Device {0} doesn't support the mediated device type {1}")]
+    UnsupportedType(String, String),
+
+    #[error("No instance of the mediated device type {1} is available on {0}")]
+    NoAvailableInstance(String, String),
+
+    #[error("Mediated device {0} exists with type {1}")]
+    TypeMismatch(String, String),
+
+    #[error("Invalid mediated device UUID {0}")]
+    InvalidUuid(String),
+
+    #[error("Cannot read {0}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+
+    #[error("Cannot write to {0}: {1}")]
+    Write(PathBuf, #[source] io::Error),
+
+    #[error("Invalid content in {0}")]
+    InvalidContent(PathBuf),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Create a mediated device of the given type on its parent, returning the
+/// sysfs path of the device and whether it was created. A device already
+/// existing with the given UUID is reused, as long as it has the expected
+/// type.
+pub fn prepare_mdev(parent: &str, mdev_type: &str, uuid: Option<&str>) -> Result<(PathBuf, bool)> {
+    let uuid = match uuid {
+        Some(uuid) => Uuid::parse_str(uuid).map_err(|_| Error::InvalidUuid(uuid.to_string()))?,
+        None => Uuid::new_v4(),
+    };
+    let mdev_path = Path::new(MDEV_DEVICES).join(uuid.to_string());
+    if mdev_path.exists() {
+        let current_type = device_type(&mdev_path)?;
+        if current_type != mdev_type {
+            return Err(Error::TypeMismatch(uuid.to_string(), current_type));
+        }
+        return Ok((mdev_path, false));
+    }
+
+    let parent_path = Path::new(MDEV_BUS_CLASS).join(parent);
+    if !parent_path.exists() {
+        return Err(Error::NotParent(parent.to_string()));
+    }
+    let type_path = parent_path.join("mdev_supported_types").join(mdev_type);
+    if !type_path.exists() {
+        return Err(Error::UnsupportedType(
+            parent.to_string(),
+            mdev_type.to_string(),
+        ));
+    }
+    if read_u32(&type_path.join("available_instances"))? == 0 {
+        return Err(Error::NoAvailableInstance(
+            parent.to_string(),
+            mdev_type.to_string(),
+        ));
+    }
+
+    let create_path = type_path.join("create");
+    fs::write(&create_path, uuid.to_string()).map_err(|e| Error::Write(create_path, e))?;
+    info!(
+        "Created mediated device {} of type {} on {}",
+        uuid, mdev_type, parent
+    );
+
+    Ok((mdev_path, true))
+}
+
+/// Destroy the mediated device found at the given sysfs path.
+pub fn remove_mdev(mdev_path: &Path) -> Result<()> {
+    let remove_path = mdev_path.join("remove");
+    fs::write(&remove_path, "1").map_err(|e| Error::Write(remove_path, e))?;
+    info!("Removed mediated device {}", mdev_path.display());
+
+    Ok(())
+}
+
+/// Whether the device found at the given sysfs path is a mediated device.
+pub fn is_mdev(device_path: &Path) -> bool {
+    // The link is checked for itself, as it points outside of the device
+    device_path.join("mdev_type").symlink_metadata().is_ok()
+}
+
+// Type of a mediated device, as found from the link to its type
+fn device_type(mdev_path: &Path) -> Result<String> {
+    let link = mdev_path.join("mdev_type");
+    let target = fs::read_link(&link).map_err(|e| Error::Read(link.clone(), e))?;
+    target
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map(|f| f.to_string())
+        .ok_or(Error::InvalidContent(link))
+}
+
+fn read_u32(path: &Path) -> Result<u32> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::Read(path.to_path_buf(), e))?
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidContent(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_device_type() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let mdev_path = dir.as_path().join("c5fb8bd0-3b1e-4d0a-9a4a-6c4d2ae1f4e5");
+        let pci_path = dir.as_path().join("0000:00:02.0");
+        fs::create_dir(&mdev_path).unwrap();
+        fs::create_dir(&pci_path).unwrap();
+        symlink(
+            "../../../devices/pci0000:00/0000:00:02.0/mdev_supported_types/i915-GVTg_V5_4",
+            mdev_path.join("mdev_type"),
+        )
+        .unwrap();
+
+        assert!(is_mdev(&mdev_path));
+        assert!(!is_mdev(&pci_path));
+        assert_eq!(device_type(&mdev_path).unwrap(), "i915-GVTg_V5_4");
+        assert!(device_type(&pci_path).is_err());
+    }
+}
@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 //
+use crate::api::VirtioMemInfo;
 #[cfg(target_arch = "x86_64")]
 use crate::config::SgxEpcConfig;
 use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
@@ -9,7 +10,7 @@ use crate::config::{HotplugMethod, MemoryConfig, MemoryZoneConfig};
 use crate::coredump::{
     CoredumpMemoryRegion, CoredumpMemoryRegions, DumpState, GuestDebuggableError,
 };
-use crate::migration::url_to_path;
+use crate::migration::{recv_snapshot_section, send_snapshot_section, url_to_path, SnapshotSource};
 use crate::MEMORY_MANAGER_SNAPSHOT_ID;
 use crate::{GuestMemoryMmap, GuestRegionMmap};
 use acpi_tables::{aml, Aml};
@@ -30,12 +31,14 @@ use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::ops::{BitAnd, Deref, Not, Sub};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
 use tracer::trace_scoped;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
@@ -61,6 +64,21 @@ const DEFAULT_MEMORY_ZONE: &str = "mem0";
 
 const SNAPSHOT_FILENAME: &str = "memory-ranges";
 
+// Prefix of the copies of the memory backing files in the snapshot, followed
+// by the guest address of the region.
+const SNAPSHOT_BACKING_FILE_PREFIX: &str = "memory-backing-";
+
+// See include/uapi/linux/fs.h in the kernel code.
+const FICLONERANGE: u64 = 0x4020_940d;
+
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
 #[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
@@ -74,6 +92,10 @@ const MPOL_BIND: u32 = 2;
 const MPOL_MF_STRICT: u32 = 1;
 const MPOL_MF_MOVE: u32 = 1 << 1;
 
+// Pages moved by each move_pages() call when binding a zone to another host
+// NUMA node, the migration being cancelled in between once superseded.
+const ZONE_MIGRATION_BATCH_PAGES: usize = 512;
+
 // Reserve 1 MiB for platform MMIO devices (e.g. ACPI control devices)
 const PLATFORM_DEVICE_AREA_SIZE: u64 = 1 << 20;
 
@@ -91,6 +113,7 @@ pub struct VirtioMemZone {
     virtio_device: Option<Arc<Mutex<virtio_devices::Mem>>>,
     hotplugged_size: u64,
     hugepages: bool,
+    block_size: u64,
     blocks_state: Arc<Mutex<BlocksState>>,
 }
 
@@ -107,6 +130,9 @@ impl VirtioMemZone {
     pub fn hugepages(&self) -> bool {
         self.hugepages
     }
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
     pub fn blocks_state(&self) -> &Arc<Mutex<BlocksState>> {
         &self.blocks_state
     }
@@ -122,9 +148,17 @@ impl VirtioMemZone {
 pub struct MemoryZone {
     regions: Vec<Arc<GuestRegionMmap>>,
     virtio_mem_zone: Option<VirtioMemZone>,
+    // Cold memory paged out on host memory pressure
+    overcommit: bool,
 }
 
 impl MemoryZone {
+    fn new(config: &MemoryZoneConfig) -> Self {
+        MemoryZone {
+            overcommit: config.overcommit,
+            ..Default::default()
+        }
+    }
     pub fn regions(&self) -> &Vec<Arc<GuestRegionMmap>> {
         &self.regions
     }
@@ -148,6 +182,15 @@ struct GuestRamMapping {
     file_offset: u64,
 }
 
+// Copy of the file backing a shared memory region, saved in the snapshot
+// instead of the content of the region.
+#[derive(Clone, Serialize, Deserialize, Versionize)]
+struct BackingFileSnapshot {
+    gpa: u64,
+    length: u64,
+    file: String,
+}
+
 #[derive(Clone, Serialize, Deserialize, Versionize)]
 struct ArchMemRegion {
     base: u64,
@@ -180,6 +223,7 @@ pub struct MemoryManager {
     sgx_epc_region: Option<SgxEpcRegion>,
     user_provided_zones: bool,
     snapshot_memory_ranges: MemoryRangeTable,
+    snapshot_backing_files: Vec<BackingFileSnapshot>,
     memory_zones: MemoryZones,
     log_dirty: bool, // Enable dirty logging for created RAM regions
     arch_mem_regions: Vec<ArchMemRegion>,
@@ -194,6 +238,10 @@ pub struct MemoryManager {
     pub acpi_address: Option<GuestAddress>,
     #[cfg(target_arch = "aarch64")]
     uefi_flash: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+
+    // Cancellation flags of the threads moving the pages of the zones bound
+    // to another host NUMA node.
+    zone_migrations: HashMap<String, Arc<AtomicBool>>,
 }
 
 #[derive(Debug)]
@@ -312,6 +360,12 @@ pub enum Error {
     /// Resizing the memory zone failed.
     ResizeZone,
 
+    /// Binding the memory zone to a host NUMA node failed.
+    BindZone,
+
+    /// Failed to spawn the thread moving the memory of a zone.
+    ZoneMigrationSpawn(io::Error),
+
     /// Guest address overflow
     GuestAddressOverFlow,
 
@@ -321,6 +375,18 @@ pub enum Error {
     // Error copying snapshot into region
     SnapshotCopy(GuestMemoryError),
 
+    /// Region restored from a copy of its backing file not backed by a file
+    SnapshotBackingFile(u64),
+
+    /// Error reading the copy of a backing file
+    SnapshotBackingFileRead(io::Error),
+
+    /// Backing file changed since the snapshot
+    SnapshotBackingFileChanged(u64),
+
+    /// Snapshot ending before the whole memory got restored
+    SnapshotTruncated,
+
     /// Failed to allocate MMIO address
     AllocateMmioAddress,
 
@@ -430,6 +496,84 @@ where
     (val & (align - 1u8.into())) == 0u8.into()
 }
 
+// Copy a range of `src` to `dest` by sharing the extents between both files
+// (reflink), the data only being copied once either file gets modified. This
+// fails if the filesystem does not support it, or the files are on different
+// filesystems.
+fn clone_file_range(
+    src: &File,
+    src_offset: u64,
+    dest: &File,
+    dest_offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    let range = FileCloneRange {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: length,
+        dest_offset,
+    };
+    // SAFETY: FFI call with a valid file descriptor, and a pointer to a
+    // properly initialized structure the kernel only reads from.
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), FICLONERANGE as _, &range) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// The blocks of a virtio-mem region backed by hugepages are made of whole
+// hugepages, for each block to be plugged and unplugged independently.
+fn virtio_mem_block_size(zone: &MemoryZoneConfig) -> Result<u64, Error> {
+    if !zone.hugepages {
+        return Ok(virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE);
+    }
+
+    let hugepage_size = match zone.hugepage_size {
+        Some(hugepage_size) => hugepage_size,
+        None => statfs_get_bsize("/dev/hugepages")?,
+    };
+
+    Ok(hugepage_size.max(virtio_devices::VIRTIO_MEM_DEFAULT_BLOCK_SIZE))
+}
+
+// Size of the chunks compared between the copy of a backing file and the
+// memory region it backs.
+const BACKING_FILE_CHUNK_SIZE: u64 = 1 << 20;
+
+// Whether the memory region at `gpa` holds the content of its copy read from
+// `reader`.
+fn region_matches_copy<R: Read>(
+    guest_memory: &GuestMemoryMmap,
+    reader: &mut R,
+    gpa: u64,
+    length: u64,
+) -> Result<bool, Error> {
+    let mut saved = vec![0u8; BACKING_FILE_CHUNK_SIZE as usize];
+    let mut current = vec![0u8; BACKING_FILE_CHUNK_SIZE as usize];
+    let mut offset = 0;
+    while offset < length {
+        let len = std::cmp::min(length - offset, BACKING_FILE_CHUNK_SIZE) as usize;
+        reader.read_exact(&mut saved[..len]).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                Error::SnapshotTruncated
+            } else {
+                Error::SnapshotBackingFileRead(e)
+            }
+        })?;
+        guest_memory
+            .read_slice(&mut current[..len], GuestAddress(gpa + offset))
+            .map_err(Error::SnapshotCopy)?;
+        if saved[..len] != current[..len] {
+            return Ok(false);
+        }
+        offset += len as u64;
+    }
+
+    Ok(true)
+}
+
 impl BusDevice for MemoryManager {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         if self.selected_slot < self.hotplug_slots.len() {
@@ -540,7 +684,7 @@ impl MemoryManager {
         }
 
         // Add zone id to the list of memory zones.
-        memory_zones.insert(zone.id.clone(), MemoryZone::default());
+        memory_zones.insert(zone.id.clone(), MemoryZone::new(zone));
 
         for ram_region in ram_regions.iter() {
             let mut ram_region_offset = 0;
@@ -631,7 +775,7 @@ impl MemoryManager {
                         );
                         return Err(Error::DuplicateZoneId);
                     }
-                    memory_zones.insert(zone.id.clone(), MemoryZone::default());
+                    memory_zones.insert(zone.id.clone(), MemoryZone::new(zone));
                 }
 
                 if ram_region_consumed {
@@ -659,7 +803,7 @@ impl MemoryManager {
         let mut memory_zones = HashMap::new();
 
         for zone_config in zones_config {
-            memory_zones.insert(zone_config.id.clone(), MemoryZone::default());
+            memory_zones.insert(zone_config.id.clone(), MemoryZone::new(zone_config));
         }
 
         for guest_ram_mapping in guest_ram_mappings {
@@ -683,12 +827,17 @@ impl MemoryManager {
                         if guest_ram_mapping.virtio_mem {
                             let hotplugged_size = zone_config.hotplugged_size.unwrap_or(0);
                             let region_size = region.len();
+                            let block_size = virtio_mem_block_size(zone_config)?;
                             memory_zone.virtio_mem_zone = Some(VirtioMemZone {
                                 region,
                                 virtio_device: None,
                                 hotplugged_size,
                                 hugepages: zone_config.hugepages,
-                                blocks_state: Arc::new(Mutex::new(BlocksState::new(region_size))),
+                                block_size,
+                                blocks_state: Arc::new(Mutex::new(BlocksState::new(
+                                    region_size,
+                                    block_size,
+                                ))),
                             });
                         } else {
                             memory_zone.regions.push(region);
@@ -718,6 +867,16 @@ impl MemoryManager {
             .open(file_path)
             .map_err(Error::SnapshotOpen)?;
 
+        self.read_saved_regions(&mut memory_file, &saved_regions)
+    }
+
+    // Copy the saved regions into the guest memory, their content following
+    // one after the other in the snapshot.
+    fn read_saved_regions<F: Read>(
+        &mut self,
+        reader: &mut F,
+        saved_regions: &MemoryRangeTable,
+    ) -> Result<(), Error> {
         let guest_memory = self.guest_memory.memory();
         for range in saved_regions.regions() {
             let mut offset: u64 = 0;
@@ -726,20 +885,91 @@ impl MemoryManager {
             // from vm-memory::GuestMemory of read_exact_from() as it is not
             // following the correct behavior. For more info about this issue
             // see: https://github.com/rust-vmm/vm-memory/issues/174
-            loop {
+            while offset < range.length {
                 let bytes_read = guest_memory
                     .read_from(
                         GuestAddress(range.gpa + offset),
-                        &mut memory_file,
+                        reader,
                         (range.length - offset) as usize,
                     )
                     .map_err(Error::SnapshotCopy)?;
+                if bytes_read == 0 {
+                    return Err(Error::SnapshotTruncated);
+                }
                 offset += bytes_read as u64;
+            }
+        }
 
-                if offset == range.length {
-                    break;
-                }
+        Ok(())
+    }
+
+    // Restore the memory from a snapshot stream, the copies of the files
+    // backing the shared memory regions preceding the memory ranges.
+    fn restore_from_stream(
+        &mut self,
+        stream: &mut File,
+        mem_snapshot: &MemoryManagerSnapshotData,
+    ) -> Result<(), Error> {
+        let recv_section = |stream: &mut File, name: &str, length: u64| {
+            if recv_snapshot_section(stream, name).map_err(Error::Restore)? != length {
+                return Err(Error::Restore(MigratableError::MigrateReceive(anyhow!(
+                    "Unexpected length of snapshot section {}",
+                    name
+                ))));
             }
+            Ok(())
+        };
+
+        for backing_file in mem_snapshot.backing_files.iter() {
+            recv_section(stream, &backing_file.file, backing_file.length)?;
+            self.check_backing_file(stream, backing_file)?;
+        }
+
+        let memory_ranges = &mem_snapshot.memory_ranges;
+        recv_section(
+            stream,
+            SNAPSHOT_FILENAME,
+            memory_ranges.regions().iter().map(|r| r.length).sum(),
+        )?;
+        self.read_saved_regions(stream, memory_ranges)
+    }
+
+    // The files backing the shared memory regions are owned by the user, and
+    // possibly shared with others, so they are never overwritten with the
+    // copies saved in the snapshot. The restore is refused instead when their
+    // content changed since the snapshot.
+    fn check_backing_files(
+        &self,
+        snapshot_dir: &Path,
+        backing_files: &[BackingFileSnapshot],
+    ) -> Result<(), Error> {
+        for backing_file in backing_files {
+            let saved_path = snapshot_dir.join(&backing_file.file);
+            let mut saved = File::open(&saved_path).map_err(Error::SnapshotOpen)?;
+            self.check_backing_file(&mut saved, backing_file)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_backing_file<R: Read>(
+        &self,
+        reader: &mut R,
+        backing_file: &BackingFileSnapshot,
+    ) -> Result<(), Error> {
+        let guest_memory = self.guest_memory.memory();
+        guest_memory
+            .find_region(GuestAddress(backing_file.gpa))
+            .and_then(|region| region.file_offset())
+            .ok_or(Error::SnapshotBackingFile(backing_file.gpa))?;
+
+        if !region_matches_copy(&guest_memory, reader, backing_file.gpa, backing_file.length)? {
+            error!(
+                "The file backing the memory region at {:#x} changed since the snapshot, \
+                it must be brought back from {} for the VM to be restored",
+                backing_file.gpa, backing_file.file
+            );
+            return Err(Error::SnapshotBackingFileChanged(backing_file.gpa));
         }
 
         Ok(())
@@ -803,6 +1033,7 @@ impl MemoryManager {
                 hotplug_size: config.hotplug_size,
                 hotplugged_size: config.hotplugged_size,
                 prefault: config.prefault,
+                overcommit: false,
             }];
 
             Ok((config.size, zones, allow_mem_hotplug))
@@ -1075,12 +1306,19 @@ impl MemoryManager {
                                 .checked_add(hotplug_size)
                                 .ok_or(Error::GuestAddressOverFlow)?;
                         } else {
+                            let block_size = virtio_mem_block_size(zone)?;
+                            if !is_aligned(hotplug_size, block_size) {
+                                error!(
+                                    "'hotplug_size' must be a multiple of the virtio-mem block size ({:#x})",
+                                    block_size
+                                );
+                                return Err(Error::InvalidHotplugSize);
+                            }
+
                             // Alignment must be "natural" i.e. same as size of block
+                            let align_size = block_size.max(virtio_devices::VIRTIO_MEM_ALIGN_SIZE);
                             let start_addr = GuestAddress(
-                                (start_of_device_area.0 + virtio_devices::VIRTIO_MEM_ALIGN_SIZE
-                                    - 1)
-                                    / virtio_devices::VIRTIO_MEM_ALIGN_SIZE
-                                    * virtio_devices::VIRTIO_MEM_ALIGN_SIZE,
+                                (start_of_device_area.0 + align_size - 1) / align_size * align_size,
                             );
 
                             // When `prefault` is set by vm_restore, memory manager
@@ -1111,7 +1349,11 @@ impl MemoryManager {
                                 virtio_device: None,
                                 hotplugged_size,
                                 hugepages: zone.hugepages,
-                                blocks_state: Arc::new(Mutex::new(BlocksState::new(region_size))),
+                                block_size,
+                                blocks_state: Arc::new(Mutex::new(BlocksState::new(
+                                    region_size,
+                                    block_size,
+                                ))),
                             });
 
                             start_of_device_area = start_addr
@@ -1217,6 +1459,7 @@ impl MemoryManager {
             sgx_epc_region: None,
             user_provided_zones,
             snapshot_memory_ranges: MemoryRangeTable::default(),
+            snapshot_backing_files: Vec::new(),
             memory_zones,
             guest_ram_mappings: Vec::new(),
             acpi_address,
@@ -1227,6 +1470,7 @@ impl MemoryManager {
             #[cfg(target_arch = "aarch64")]
             uefi_flash: None,
             thp: config.thp,
+            zone_migrations: HashMap::new(),
         };
 
         #[cfg(target_arch = "aarch64")]
@@ -1253,14 +1497,11 @@ impl MemoryManager {
         snapshot: &Snapshot,
         vm: Arc<dyn hypervisor::Vm>,
         config: &MemoryConfig,
-        source_url: Option<&str>,
+        source: Option<&mut SnapshotSource>,
         prefault: bool,
         phys_bits: u8,
     ) -> Result<Arc<Mutex<MemoryManager>>, Error> {
-        if let Some(source_url) = source_url {
-            let mut memory_file_path = url_to_path(source_url).map_err(Error::Restore)?;
-            memory_file_path.push(String::from(SNAPSHOT_FILENAME));
-
+        if let Some(source) = source {
             let mem_snapshot: MemoryManagerSnapshotData =
                 snapshot.to_versioned_state().map_err(Error::Restore)?;
 
@@ -1277,9 +1518,20 @@ impl MemoryManager {
                 None,
             )?;
 
-            mm.lock()
-                .unwrap()
-                .fill_saved_regions(memory_file_path, mem_snapshot.memory_ranges)?;
+            let mut memory_manager = mm.lock().unwrap();
+            match source {
+                SnapshotSource::Dir(path) => {
+                    memory_manager.check_backing_files(path, &mem_snapshot.backing_files)?;
+                    memory_manager.fill_saved_regions(
+                        path.join(SNAPSHOT_FILENAME),
+                        mem_snapshot.memory_ranges,
+                    )?;
+                }
+                SnapshotSource::Stream(stream) => {
+                    memory_manager.restore_from_stream(stream, &mem_snapshot)?
+                }
+            }
+            drop(memory_manager);
 
             Ok(mm)
         } else {
@@ -1446,39 +1698,105 @@ impl MemoryManager {
 
         // Apply NUMA policy if needed.
         if let Some(node) = host_numa_node {
-            let addr = region.deref().as_ptr();
-            let len = region.deref().size() as u64;
-            let mode = MPOL_BIND;
-            let mut nodemask: Vec<u64> = Vec::new();
-            let flags = MPOL_MF_STRICT | MPOL_MF_MOVE;
-
-            // Linux is kind of buggy in the way it interprets maxnode as it
-            // will cut off the last node. That's why we have to add 1 to what
-            // we would consider as the proper maxnode value.
-            let maxnode = node as u64 + 1 + 1;
-
-            // Allocate the right size for the vector.
-            nodemask.resize((node as usize / 64) + 1, 0);
-
-            // Fill the global bitmask through the nodemask vector.
-            let idx = (node / 64) as usize;
-            let shift = node % 64;
-            nodemask[idx] |= 1u64 << shift;
-
             // Policies are enforced by using MPOL_MF_MOVE flag as it will
             // force the kernel to move all pages that might have been already
             // allocated to the proper set of NUMA nodes. MPOL_MF_STRICT is
             // used to throw an error if MPOL_MF_MOVE didn't succeed.
-            // MPOL_BIND is the selected mode as it specifies a strict policy
-            // that restricts memory allocation to the nodes specified in the
-            // nodemask.
-            Self::mbind(addr, len, mode, nodemask, maxnode, flags)
+            Self::bind_to_host_numa_node(&region, node, MPOL_MF_STRICT | MPOL_MF_MOVE)
                 .map_err(Error::ApplyNumaPolicy)?;
         }
 
         Ok(Arc::new(region))
     }
 
+    fn bind_to_host_numa_node(
+        region: &GuestRegionMmap,
+        node: u32,
+        flags: u32,
+    ) -> Result<(), io::Error> {
+        let addr = region.deref().as_ptr();
+        let len = region.deref().size() as u64;
+        let mode = MPOL_BIND;
+        let mut nodemask: Vec<u64> = Vec::new();
+
+        // Linux is kind of buggy in the way it interprets maxnode as it
+        // will cut off the last node. That's why we have to add 1 to what
+        // we would consider as the proper maxnode value.
+        let maxnode = node as u64 + 1 + 1;
+
+        // Allocate the right size for the vector.
+        nodemask.resize((node as usize / 64) + 1, 0);
+
+        // Fill the global bitmask through the nodemask vector.
+        let idx = (node / 64) as usize;
+        let shift = node % 64;
+        nodemask[idx] |= 1u64 << shift;
+
+        // MPOL_BIND is the selected mode as it specifies a strict policy
+        // that restricts memory allocation to the nodes specified in the
+        // nodemask.
+        Self::mbind(addr, len, mode, nodemask, maxnode, flags)
+    }
+
+    fn move_pages(pages: &[*mut libc::c_void], node: u32) -> Result<(), io::Error> {
+        let nodes = vec![node as libc::c_int; pages.len()];
+        let mut status = vec![0 as libc::c_int; pages.len()];
+        // SAFETY: FFI call with arrays of the same length, the pages of the
+        // calling process, not mapped or already on the node, being reported
+        // through the status rather than failing the call.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_move_pages,
+                0,
+                pages.len() as libc::c_ulong,
+                pages.as_ptr(),
+                nodes.as_ptr(),
+                status.as_mut_ptr(),
+                MPOL_MF_MOVE as libc::c_int,
+            )
+        };
+
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Move the pages of the given ranges by batches, until done or cancelled.
+    // Returns whether all of them were moved.
+    fn move_zone_pages<F>(
+        ranges: &[(usize, usize)],
+        page_size: usize,
+        cancel: &AtomicBool,
+        mut move_batch: F,
+    ) -> Result<bool, io::Error>
+    where
+        F: FnMut(&[*mut libc::c_void]) -> Result<(), io::Error>,
+    {
+        let mut batch = Vec::with_capacity(ZONE_MIGRATION_BATCH_PAGES);
+        for (start, len) in ranges {
+            for addr in (*start..start + len).step_by(page_size) {
+                batch.push(addr as *mut libc::c_void);
+                if batch.len() == ZONE_MIGRATION_BATCH_PAGES {
+                    if cancel.load(Ordering::Acquire) {
+                        return Ok(false);
+                    }
+                    move_batch(&batch)?;
+                    batch.clear();
+                }
+            }
+        }
+        if !batch.is_empty() {
+            if cancel.load(Ordering::Acquire) {
+                return Ok(false);
+            }
+            move_batch(&batch)?;
+        }
+
+        Ok(true)
+    }
+
     // Update the GuestMemoryMmap with the new range
     fn add_region(&mut self, region: Arc<GuestRegionMmap>) -> Result<(), Error> {
         let guest_memory = self
@@ -1836,6 +2154,72 @@ impl MemoryManager {
         self.virtio_mem_resize(id, virtio_mem_size)
     }
 
+    /// Bind the memory of a zone to a new host NUMA node. The pages allocated
+    /// from now on, including the virtio-mem ones, are taken from that node,
+    /// while the ones already allocated are moved to it by a thread of its
+    /// own, so that the VMM isn't blocked for as long as it takes. Binding the
+    /// zone again cancels the pending move.
+    pub fn bind_zone(&mut self, id: &str, host_numa_node: u32) -> Result<(), Error> {
+        if !self.user_provided_zones {
+            error!(
+                "Not allowed to bind guest memory zone when no zone is \
+                defined."
+            );
+            return Err(Error::BindZone);
+        }
+
+        let memory_zone = self.memory_zones.get(id).ok_or_else(|| {
+            error!("Failed binding memory zone: Unknown memory zone");
+            Error::UnknownMemoryZone
+        })?;
+
+        let regions: Vec<Arc<GuestRegionMmap>> = memory_zone
+            .regions
+            .iter()
+            .chain(memory_zone.virtio_mem_zone.as_ref().map(|z| &z.region))
+            .cloned()
+            .collect();
+        for region in regions.iter() {
+            Self::bind_to_host_numa_node(region, host_numa_node, 0)
+                .map_err(Error::ApplyNumaPolicy)?;
+        }
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        if let Some(previous) = self.zone_migrations.insert(id.to_string(), cancel.clone()) {
+            previous.store(true, Ordering::Release);
+        }
+
+        let id = id.to_string();
+        thread::Builder::new()
+            .name(format!("{id}_migration"))
+            .spawn(move || {
+                // SAFETY: FFI call. Trivially safe.
+                let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
+                // The regions are held until done, so that their pages remain
+                // mapped.
+                let ranges: Vec<(usize, usize)> = regions
+                    .iter()
+                    .map(|region| (region.as_ptr() as usize, region.len() as usize))
+                    .collect();
+                match Self::move_zone_pages(&ranges, page_size, &cancel, |pages| {
+                    Self::move_pages(pages, host_numa_node)
+                }) {
+                    Ok(true) => info!(
+                        "Moved memory zone {} to host NUMA node {}",
+                        id, host_numa_node
+                    ),
+                    Ok(false) => info!("Cancelled moving memory zone {}", id),
+                    Err(e) => warn!(
+                        "Failed moving memory zone {} to host NUMA node {}: {}",
+                        id, host_numa_node, e
+                    ),
+                }
+            })
+            .map_err(Error::ZoneMigrationSpawn)?;
+
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     pub fn setup_sgx(&mut self, sgx_epc_config: Vec<SgxEpcConfig>) -> Result<(), Error> {
         let file = OpenOptions::new()
@@ -1961,10 +2345,54 @@ impl MemoryManager {
         &self.memory_zones
     }
 
+    /// Resize state of the virtio-mem devices, sorted by memory zone.
+    pub fn virtio_mem_info(&self) -> Vec<VirtioMemInfo> {
+        let mut info: Vec<VirtioMemInfo> = self
+            .memory_zones
+            .iter()
+            .filter_map(|(id, memory_zone)| {
+                let device = memory_zone
+                    .virtio_mem_zone
+                    .as_ref()?
+                    .virtio_device
+                    .as_ref()?;
+                let resize = device.lock().unwrap().resize_info();
+                Some(VirtioMemInfo {
+                    id: id.clone(),
+                    requested_size: resize.requested_size,
+                    plugged_size: resize.plugged_size,
+                    status: resize.status,
+                })
+            })
+            .collect();
+        info.sort_by(|a, b| a.id.cmp(&b.id));
+        info
+    }
+
+    /// Regions of the overcommittable memory zones, whose cold memory gets
+    /// paged out on host pressure.
+    pub fn overcommit_regions(&self) -> Vec<Arc<GuestRegionMmap>> {
+        self.memory_zones
+            .values()
+            .filter(|zone| zone.overcommit)
+            .flat_map(|zone| zone.regions().iter().cloned())
+            .collect()
+    }
+
     pub fn memory_zones_mut(&mut self) -> &mut MemoryZones {
         &mut self.memory_zones
     }
 
+    // Whether the content of the region is written to a file on the host
+    // filesystem that can be accessed by the user, as the region is backed by
+    // this file and the mapping is shared.
+    fn is_backed_by_host_file(region: &GuestRegionMmap) -> bool {
+        region.file_offset().map_or(false, |file_offset| {
+            (region.flags() & libc::MAP_SHARED == libc::MAP_SHARED)
+                && Self::is_hardlink(file_offset.file())
+        })
+    }
+
     pub fn memory_range_table(
         &self,
         snapshot: bool,
@@ -1977,23 +2405,12 @@ impl MemoryManager {
             }
 
             for region in memory_zone.regions() {
-                if snapshot {
-                    if let Some(file_offset) = region.file_offset() {
-                        if (region.flags() & libc::MAP_SHARED == libc::MAP_SHARED)
-                            && Self::is_hardlink(file_offset.file())
-                        {
-                            // In this very specific case, we know the memory
-                            // region is backed by a file on the host filesystem
-                            // that can be accessed by the user, and additionally
-                            // the mapping is shared, which means that modifications
-                            // to the content are written to the actual file.
-                            // When meeting these conditions, we can skip the
-                            // copy of the memory content for this specific region,
-                            // as we can assume the user will have it saved through
-                            // the backing file already.
-                            continue;
-                        }
-                    }
+                if snapshot && Self::is_backed_by_host_file(region) {
+                    // The memory content of this region is not copied, the
+                    // snapshot saving a copy of its backing file instead,
+                    // which is cheap when the filesystem can share the
+                    // extents of both files.
+                    continue;
                 }
 
                 table.push(MemoryRange {
@@ -2018,7 +2435,100 @@ impl MemoryManager {
             next_memory_slot: self.next_memory_slot,
             selected_slot: self.selected_slot,
             next_hotplug_slot: self.next_hotplug_slot,
+            backing_files: self.snapshot_backing_files.clone(),
+        }
+    }
+
+    // Save a copy of the files backing the shared memory regions in the
+    // snapshot, sharing their extents when possible.
+    fn send_backing_files(&self, destination_url: &str) -> result::Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+
+        for backing_file in self.snapshot_backing_files.iter() {
+            let mut path = url_to_path(destination_url)?;
+            path.push(&backing_file.file);
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+
+            let file_offset = guest_memory
+                .find_region(GuestAddress(backing_file.gpa))
+                .and_then(|region| region.file_offset())
+                .ok_or_else(|| {
+                    MigratableError::MigrateSend(anyhow!(
+                        "No file backing the memory region at {:#x}",
+                        backing_file.gpa
+                    ))
+                })?;
+
+            if let Err(e) = clone_file_range(
+                file_offset.file(),
+                file_offset.start(),
+                &file,
+                0,
+                backing_file.length,
+            ) {
+                info!(
+                    "Cannot clone the file backing the memory region at {:#x}, copying its content: {}",
+                    backing_file.gpa, e
+                );
+                self.send_memory_range(backing_file.gpa, backing_file.length, &mut file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write a range of the guest memory.
+    fn send_memory_range<F: Write>(
+        &self,
+        gpa: u64,
+        length: u64,
+        writer: &mut F,
+    ) -> result::Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+        let mut offset: u64 = 0;
+        // Here we are manually handling the retry in case we can't read
+        // the whole region at once because we can't use the implementation
+        // from vm-memory::GuestMemory of write_all_to() as it is not
+        // following the correct behavior. For more info about this issue
+        // see: https://github.com/rust-vmm/vm-memory/issues/174
+        while offset < length {
+            offset += guest_memory
+                .write_to(
+                    GuestAddress(gpa + offset),
+                    writer,
+                    (length - offset) as usize,
+                )
+                .map_err(|e| MigratableError::MigrateSend(e.into()))? as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Write the memory to a snapshot stream rather than a directory, the
+    /// copies of the files backing the shared memory regions preceding the
+    /// memory ranges.
+    pub fn send_stream(&self, stream: &mut File) -> result::Result<(), MigratableError> {
+        for backing_file in self.snapshot_backing_files.iter() {
+            send_snapshot_section(stream, &backing_file.file, backing_file.length)?;
+            self.send_memory_range(backing_file.gpa, backing_file.length, stream)?;
+        }
+
+        let memory_ranges = self.snapshot_memory_ranges.regions();
+        send_snapshot_section(
+            stream,
+            SNAPSHOT_FILENAME,
+            memory_ranges.iter().map(|r| r.length).sum(),
+        )?;
+        for range in memory_ranges {
+            self.send_memory_range(range.gpa, range.length, stream)?;
         }
+
+        Ok(())
     }
 
     pub fn memory_slot_fds(&self) -> HashMap<u32, RawFd> {
@@ -2540,6 +3050,8 @@ pub struct MemoryManagerSnapshotData {
     next_memory_slot: u32,
     selected_slot: usize,
     next_hotplug_slot: usize,
+    #[serde(default)]
+    backing_files: Vec<BackingFileSnapshot>,
 }
 
 impl VersionMapped for MemoryManagerSnapshotData {}
@@ -2561,6 +3073,20 @@ impl Snapshottable for MemoryManager {
         // process, and instead it can directly proceed with storing the
         // memory range content for the ranges requiring it.
         self.snapshot_memory_ranges = memory_ranges;
+        self.snapshot_backing_files = self
+            .memory_zones
+            .values()
+            .flat_map(|memory_zone| memory_zone.regions())
+            .filter(|region| Self::is_backed_by_host_file(region))
+            .map(|region| {
+                let gpa = region.start_addr().raw_value();
+                BackingFileSnapshot {
+                    gpa,
+                    length: region.len(),
+                    file: format!("{SNAPSHOT_BACKING_FILE_PREFIX}{gpa:x}"),
+                }
+            })
+            .collect();
 
         Ok(Snapshot::from_data(SnapshotData::new_from_versioned_state(
             &self.snapshot_data(),
@@ -2574,6 +3100,8 @@ impl Transportable for MemoryManager {
         _snapshot: &Snapshot,
         destination_url: &str,
     ) -> result::Result<(), MigratableError> {
+        self.send_backing_files(destination_url)?;
+
         if self.snapshot_memory_ranges.is_empty() {
             return Ok(());
         }
@@ -2589,29 +3117,8 @@ impl Transportable for MemoryManager {
             .open(memory_file_path)
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
-        let guest_memory = self.guest_memory.memory();
-
         for range in self.snapshot_memory_ranges.regions() {
-            let mut offset: u64 = 0;
-            // Here we are manually handling the retry in case we can't read
-            // the whole region at once because we can't use the implementation
-            // from vm-memory::GuestMemory of write_all_to() as it is not
-            // following the correct behavior. For more info about this issue
-            // see: https://github.com/rust-vmm/vm-memory/issues/174
-            loop {
-                let bytes_written = guest_memory
-                    .write_to(
-                        GuestAddress(range.gpa + offset),
-                        &mut memory_file,
-                        (range.length - offset) as usize,
-                    )
-                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
-                offset += bytes_written as u64;
-
-                if offset == range.length {
-                    break;
-                }
-            }
+            self.send_memory_range(range.gpa, range.length, &mut memory_file)?;
         }
         Ok(())
     }
@@ -2687,3 +3194,113 @@ impl Migratable for MemoryManager {
         Ok(table)
     }
 }
+
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        // The zones being moved keep the guest memory mapped until done.
+        for cancel in self.zone_migrations.values() {
+            cancel.store(true, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_zone_pages() {
+        let page_size = 0x1000;
+        let ranges = [
+            (
+                0x10_0000,
+                ZONE_MIGRATION_BATCH_PAGES * page_size + 2 * page_size,
+            ),
+            (0x100_0000, 2 * page_size),
+        ];
+
+        // All the pages are moved, by batches
+        let cancel = AtomicBool::new(false);
+        let mut batches: Vec<Vec<usize>> = Vec::new();
+        assert!(
+            MemoryManager::move_zone_pages(&ranges, page_size, &cancel, |pages| {
+                batches.push(pages.iter().map(|p| *p as usize).collect());
+                Ok(())
+            })
+            .unwrap()
+        );
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), ZONE_MIGRATION_BATCH_PAGES);
+        assert_eq!(batches[0][1], 0x10_0000 + page_size);
+        assert_eq!(
+            batches[1],
+            vec![
+                0x10_0000 + ZONE_MIGRATION_BATCH_PAGES * page_size,
+                0x10_0000 + (ZONE_MIGRATION_BATCH_PAGES + 1) * page_size,
+                0x100_0000,
+                0x100_0000 + page_size,
+            ]
+        );
+
+        // The migration stops once superseded
+        let mut count = 0;
+        assert!(
+            !MemoryManager::move_zone_pages(&ranges, page_size, &cancel, |_| {
+                count += 1;
+                cancel.store(true, Ordering::Release);
+                Ok(())
+            })
+            .unwrap()
+        );
+        assert_eq!(count, 1);
+
+        // Errors are reported
+        let cancel = AtomicBool::new(false);
+        assert!(
+            MemoryManager::move_zone_pages(&ranges, page_size, &cancel, |_| {
+                Err(io::Error::from_raw_os_error(libc::EPERM))
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_region_matches_copy() {
+        let guest_memory =
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0x10_0000), 0x4000)]).unwrap();
+        let content: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+        guest_memory
+            .write_slice(&content, GuestAddress(0x10_1000))
+            .unwrap();
+
+        assert!(region_matches_copy(
+            &guest_memory,
+            &mut io::Cursor::new(&content),
+            0x10_1000,
+            0x2000
+        )
+        .unwrap());
+
+        // The backing file changed since the snapshot
+        let mut changed = content.clone();
+        changed[0x1fff] ^= 0xff;
+        assert!(!region_matches_copy(
+            &guest_memory,
+            &mut io::Cursor::new(&changed),
+            0x10_1000,
+            0x2000
+        )
+        .unwrap());
+
+        // The copy is shorter than the region
+        assert!(matches!(
+            region_matches_copy(
+                &guest_memory,
+                &mut io::Cursor::new(&content[..0x1000]),
+                0x10_1000,
+                0x2000
+            ),
+            Err(Error::SnapshotTruncated)
+        ));
+    }
+}
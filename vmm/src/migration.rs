@@ -2,17 +2,45 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::config::{MemoryConfig, VhostMode};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::GuestDebuggableError;
+use crate::overcommit::host_available_memory;
 use crate::{config::VmConfig, vm::VmSnapshot};
 use anyhow::anyhow;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use vm_migration::{MigratableError, Snapshot};
 
 pub const SNAPSHOT_STATE_FILE: &str = "state.json";
 pub const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+pub const SNAPSHOT_MANIFEST_FILE: &str = "manifest.json";
+
+// Opening of a snapshot streamed rather than written to a directory
+const SNAPSHOT_STREAM_MAGIC: &[u8; 8] = b"CHSNAPST";
+
+const PROC_MEMINFO: &str = "/proc/meminfo";
+const SYS_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
+const SYS_NODES: &str = "/sys/devices/system/node";
+
+/// Outcome of checking a migration against its destination, before the VM
+/// gets paused or any of its state is transferred.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MigrationPrecheckReport {
+    /// Reasons preventing the migration, none when it can proceed.
+    pub errors: Vec<String>,
+    /// Guest memory sent by the first pass of the migration, the memory
+    /// dirtied in the meantime being sent again on top of it.
+    pub memory_size: u64,
+    /// Size of the VM configuration sent to the destination.
+    pub config_size: u64,
+}
 
 pub fn url_to_path(url: &str) -> std::result::Result<PathBuf, MigratableError> {
     let path: PathBuf = url
@@ -43,28 +71,400 @@ pub fn url_to_file(url: &str) -> std::result::Result<PathBuf, GuestDebuggableErr
     Ok(file)
 }
 
-pub fn recv_vm_config(source_url: &str) -> std::result::Result<VmConfig, MigratableError> {
-    let mut vm_config_path = url_to_path(source_url)?;
+/// Open the stream a snapshot is written to or read from, for the URLs
+/// designating a stream rather than a directory: `unix:<path>` connects to a
+/// listening UNIX socket, and `fd://<fd>` takes the file descriptor over, such
+/// as a pipe.
+pub fn open_snapshot_stream(url: &str) -> std::result::Result<Option<File>, MigratableError> {
+    if let Some(path) = url.strip_prefix("unix:") {
+        let stream = UnixStream::connect(path).map_err(MigratableError::MigrateSocket)?;
+        return Ok(Some(File::from(OwnedFd::from(stream))));
+    }
+
+    if let Some(fd) = url.strip_prefix("fd://") {
+        let fd: RawFd = fd.parse().map_err(|_| {
+            MigratableError::MigrateSend(anyhow!("Invalid file descriptor in URL: {}", url))
+        })?;
+        // SAFETY: the file descriptor is handed over to the VMM by the URL,
+        // and owned by the stream from now on.
+        return Ok(Some(unsafe { File::from_raw_fd(fd) }));
+    }
+
+    Ok(None)
+}
+
+/// Write the opening of a snapshot stream, before its first section.
+pub fn send_snapshot_stream_header(stream: &mut File) -> std::result::Result<(), MigratableError> {
+    stream
+        .write_all(SNAPSHOT_STREAM_MAGIC)
+        .map_err(MigratableError::MigrateSocket)
+}
+
+/// Write the header of a section of a snapshot stream, to be followed by the
+/// `length` bytes of its content. The sections are named after the files
+/// holding them in a snapshot directory.
+pub fn send_snapshot_section(
+    stream: &mut File,
+    name: &str,
+    length: u64,
+) -> std::result::Result<(), MigratableError> {
+    let mut header = Vec::with_capacity(4 + name.len() + 8);
+    header.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    header.extend_from_slice(name.as_bytes());
+    header.extend_from_slice(&length.to_le_bytes());
+    stream
+        .write_all(&header)
+        .map_err(MigratableError::MigrateSocket)
+}
+
+/// Read the header of the next section of a snapshot stream, expected to be
+/// `name`, returning the length of its content.
+pub fn recv_snapshot_section(
+    stream: &mut File,
+    name: &str,
+) -> std::result::Result<u64, MigratableError> {
+    let mut name_length = [0u8; 4];
+    stream
+        .read_exact(&mut name_length)
+        .map_err(MigratableError::MigrateSocket)?;
+    let mut section = vec![0u8; u32::from_le_bytes(name_length) as usize];
+    stream
+        .read_exact(&mut section)
+        .map_err(MigratableError::MigrateSocket)?;
+    if section != name.as_bytes() {
+        return Err(MigratableError::MigrateReceive(anyhow!(
+            "Expected snapshot section {}, found {}",
+            name,
+            String::from_utf8_lossy(&section)
+        )));
+    }
+
+    let mut length = [0u8; 8];
+    stream
+        .read_exact(&mut length)
+        .map_err(MigratableError::MigrateSocket)?;
+    Ok(u64::from_le_bytes(length))
+}
+
+/// Size and checksum of a file of a snapshot directory.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotFileDigest {
+    pub size: u64,
+    /// SHA-256 of the content of the file, hex encoded
+    pub sha256: String,
+}
+
+/// Files of a snapshot directory, written along with them for the snapshot
+/// to be checked before restoring from it, once it was copied around.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotManifest {
+    pub files: BTreeMap<String, SnapshotFileDigest>,
+}
+
+fn file_digest(path: &Path) -> io::Result<SnapshotFileDigest> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+
+    Ok(SnapshotFileDigest { size, sha256 })
+}
+
+/// Write the manifest of the snapshot directory, covering all the files it
+/// holds but the configuration, which can be edited before restoring.
+pub fn write_snapshot_manifest(path: &Path) -> std::result::Result<(), MigratableError> {
+    let mut manifest = SnapshotManifest::default();
+    for entry in fs::read_dir(path).map_err(|e| MigratableError::MigrateSend(e.into()))? {
+        let entry = entry.map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_file = entry
+            .file_type()
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?
+            .is_file();
+        if !is_file || name == SNAPSHOT_MANIFEST_FILE || name == SNAPSHOT_CONFIG_FILE {
+            continue;
+        }
 
-    vm_config_path.push(SNAPSHOT_CONFIG_FILE);
+        let digest = file_digest(&entry.path()).map_err(|e| {
+            MigratableError::MigrateSend(anyhow!("Cannot checksum snapshot file {}: {}", name, e))
+        })?;
+        manifest.files.insert(name, digest);
+    }
 
-    // Try opening the snapshot file
-    let vm_config_file =
-        File::open(vm_config_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
-    let vm_config_reader = BufReader::new(vm_config_file);
-    serde_json::from_reader(vm_config_reader).map_err(|e| MigratableError::MigrateReceive(e.into()))
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path.join(SNAPSHOT_MANIFEST_FILE))
+        .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+    serde_json::to_writer(file, &manifest).map_err(|e| MigratableError::MigrateSend(e.into()))
 }
 
-pub fn recv_vm_state(source_url: &str) -> std::result::Result<Snapshot, MigratableError> {
-    let mut vm_state_path = url_to_path(source_url)?;
+/// Check the files of the snapshot directory against its manifest, the
+/// sizes first, for a truncated snapshot to be reported without reading the
+/// whole of it. The snapshots written before the manifests were introduced
+/// are accepted as they are, unless the manifest is `required`.
+pub fn verify_snapshot_manifest(
+    path: &Path,
+    required: bool,
+) -> std::result::Result<(), MigratableError> {
+    let manifest: SnapshotManifest = match File::open(path.join(SNAPSHOT_MANIFEST_FILE)) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| MigratableError::MigrateReceive(e.into()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && required => {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "No manifest in the snapshot, its integrity can't be checked"
+            )));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("No manifest in the snapshot, its integrity can't be checked");
+            return Ok(());
+        }
+        Err(e) => return Err(MigratableError::MigrateReceive(e.into())),
+    };
 
-    vm_state_path.push(SNAPSHOT_STATE_FILE);
+    for (name, digest) in manifest.files.iter() {
+        let size = fs::metadata(path.join(name))
+            .map_err(|e| {
+                MigratableError::MigrateReceive(anyhow!("Missing snapshot file {}: {}", name, e))
+            })?
+            .len();
+        if size != digest.size {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Snapshot file {} was truncated or corrupted: {} bytes instead of {}",
+                name,
+                size,
+                digest.size
+            )));
+        }
+    }
+
+    for (name, digest) in manifest.files.iter() {
+        let actual = file_digest(&path.join(name)).map_err(|e| {
+            MigratableError::MigrateReceive(anyhow!(
+                "Cannot checksum snapshot file {}: {}",
+                name,
+                e
+            ))
+        })?;
+        if actual.sha256 != digest.sha256 {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Snapshot file {} was corrupted: SHA-256 {} instead of {}",
+                name,
+                actual.sha256,
+                digest.sha256
+            )));
+        }
+    }
+
+    Ok(())
+}
 
-    // Try opening the snapshot file
-    let vm_state_file =
-        File::open(vm_state_path).map_err(|e| MigratableError::MigrateSend(e.into()))?;
-    let vm_state_reader = BufReader::new(vm_state_file);
-    serde_json::from_reader(vm_state_reader).map_err(|e| MigratableError::MigrateReceive(e.into()))
+/// Snapshot a VM is restored from.
+pub enum SnapshotSource {
+    /// Directory holding the parts of the snapshot as separate files
+    Dir(PathBuf),
+    /// Stream carrying the parts of the snapshot one after the other
+    Stream(File),
+}
+
+impl SnapshotSource {
+    pub fn open(
+        source_url: &str,
+        require_manifest: bool,
+    ) -> std::result::Result<Self, MigratableError> {
+        let Some(mut stream) = open_snapshot_stream(source_url)? else {
+            let path = url_to_path(source_url)?;
+            verify_snapshot_manifest(&path, require_manifest)?;
+            return Ok(SnapshotSource::Dir(path));
+        };
+        if require_manifest {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "A streamed snapshot has no manifest, its integrity can't be checked"
+            )));
+        }
+
+        let mut magic = [0u8; SNAPSHOT_STREAM_MAGIC.len()];
+        stream
+            .read_exact(&mut magic)
+            .map_err(MigratableError::MigrateSocket)?;
+        if &magic != SNAPSHOT_STREAM_MAGIC {
+            return Err(MigratableError::MigrateReceive(anyhow!(
+                "Not a snapshot stream: {}",
+                source_url
+            )));
+        }
+
+        Ok(SnapshotSource::Stream(stream))
+    }
+
+    // Deserialize a part of the snapshot, from its file or its section.
+    fn recv_json<T>(&mut self, name: &str) -> std::result::Result<T, MigratableError>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let result = match self {
+            SnapshotSource::Dir(path) => {
+                // Try opening the snapshot file
+                let file = File::open(path.join(name))
+                    .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+                serde_json::from_reader(BufReader::new(file))
+            }
+            SnapshotSource::Stream(stream) => {
+                let length = recv_snapshot_section(stream, name)?;
+                serde_json::from_reader(BufReader::new(stream.by_ref().take(length)))
+            }
+        };
+        result.map_err(|e| MigratableError::MigrateReceive(e.into()))
+    }
+
+    pub fn recv_vm_config(&mut self) -> std::result::Result<VmConfig, MigratableError> {
+        self.recv_json(SNAPSHOT_CONFIG_FILE)
+    }
+
+    pub fn recv_vm_state(&mut self) -> std::result::Result<Snapshot, MigratableError> {
+        self.recv_json(SNAPSHOT_STATE_FILE)
+    }
+}
+
+// Guest memory backed by hugepages, by hugepage size, None being the default
+// size.
+fn hugepage_memory(memory: &MemoryConfig) -> BTreeMap<Option<u64>, u64> {
+    let mut hugepage_memory = BTreeMap::new();
+    if memory.hugepages {
+        *hugepage_memory.entry(memory.hugepage_size).or_default() +=
+            memory.size + memory.hotplugged_size.unwrap_or(0);
+    }
+    for zone in memory.zones.iter().flatten().filter(|z| z.hugepages) {
+        *hugepage_memory.entry(zone.hugepage_size).or_default() +=
+            zone.size + zone.hotplugged_size.unwrap_or(0);
+    }
+
+    hugepage_memory
+}
+
+fn default_hugepage_size() -> io::Result<u64> {
+    let meminfo = fs::read_to_string(PROC_MEMINFO)?;
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("Hugepagesize:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kib| kib << 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Hugepagesize"))
+}
+
+fn free_hugepages(size: u64) -> io::Result<u64> {
+    let path = Path::new(SYS_HUGEPAGES)
+        .join(format!("hugepages-{}kB", size >> 10))
+        .join("free_hugepages");
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Check that the host can run the VM migrated with `vm_config`, returning
+/// the reasons it can't. With a `local` migration, the guest memory is
+/// shared with the source rather than allocated.
+pub fn check_destination(vm_config: &VmConfig, local: bool) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    // The device models, such as the disk backends, must be available
+    if let Err(e) = vm_config.clone().validate() {
+        errors.push(format!("Invalid configuration: {e}"));
+    }
+
+    for zone in vm_config.memory.zones.iter().flatten() {
+        if let Some(node) = zone.host_numa_node {
+            if !Path::new(SYS_NODES).join(format!("node{node}")).exists() {
+                errors.push(format!(
+                    "Memory zone {} is bound to the host NUMA node {} which does not exist",
+                    zone.id, node
+                ));
+            }
+        }
+    }
+
+    if !local {
+        let hugepage_memory = hugepage_memory(&vm_config.memory);
+        for (size, needed) in hugepage_memory.iter() {
+            let size = match size.map_or_else(default_hugepage_size, Ok) {
+                Ok(size) => size,
+                Err(e) => {
+                    errors.push(format!("Cannot find the default hugepage size: {e}"));
+                    continue;
+                }
+            };
+            let pages = (needed + size - 1) / size;
+            match free_hugepages(size) {
+                Ok(free) if free >= pages => {}
+                Ok(free) => errors.push(format!(
+                    "{pages} hugepages of {size} bytes are needed, only {free} are free"
+                )),
+                Err(e) => errors.push(format!("Cannot find hugepages of {size} bytes: {e}")),
+            }
+        }
+
+        let needed = vm_config
+            .memory
+            .total_size()
+            .saturating_sub(hugepage_memory.values().sum());
+        match host_available_memory() {
+            Ok(available) if available >= needed => {}
+            Ok(available) => errors.push(format!(
+                "{needed} bytes of memory are needed, only {available} are available"
+            )),
+            Err(e) => errors.push(format!("Cannot read the available memory: {e}")),
+        }
+    }
+
+    let mut check_path = |what: &str, path: &Path| {
+        if !path.exists() {
+            errors.push(format!("{what} {path:?} does not exist"));
+        }
+    };
+    for disk in vm_config.disks.iter().flatten() {
+        if let Some(socket) = disk.vhost_socket.as_ref().filter(|_| disk.vhost_user) {
+            check_path("Disk vhost-user socket", Path::new(socket));
+        } else if let Some(path) = disk
+            .path
+            .as_ref()
+            .filter(|p| disk.backend.is_none() && !block::nbd::is_nbd_uri(p))
+        {
+            check_path("Disk image", path);
+        }
+    }
+    for net in vm_config.net.iter().flatten() {
+        if let Some(socket) = net
+            .vhost_socket
+            .as_ref()
+            .filter(|_| net.vhost_user && net.vhost_mode == VhostMode::Client)
+        {
+            check_path("Network vhost-user socket", Path::new(socket));
+        }
+    }
+    for fs in vm_config.fs.iter().flatten() {
+        if fs.shared_dir.is_none() {
+            check_path("Filesystem vhost-user socket", &fs.socket);
+        }
+    }
+    for pmem in vm_config.pmem.iter().flatten() {
+        check_path("Persistent memory file", &pmem.file);
+    }
+    for device in vm_config.devices.iter().flatten() {
+        check_path("Device", &device.path);
+    }
+    for device in vm_config.user_devices.iter().flatten() {
+        check_path("User device socket", &device.socket);
+    }
+    for vdpa in vm_config.vdpa.iter().flatten() {
+        check_path("vDPA device", &vdpa.path);
+    }
+
+    errors
 }
 
 pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, MigratableError> {
@@ -76,3 +476,89 @@ pub fn get_vm_snapshot(snapshot: &Snapshot) -> std::result::Result<VmSnapshot, M
         "Could not find VM config snapshot section"
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Seek, SeekFrom};
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_hugepage_memory() {
+        let memory = MemoryConfig::parse("size=1G,hugepages=on", None).unwrap();
+        assert_eq!(hugepage_memory(&memory), BTreeMap::from([(None, 1 << 30)]));
+
+        let memory = MemoryConfig::parse(
+            "size=0",
+            Some(vec![
+                "id=mem0,size=1G,hugepages=on,hugepage_size=2M",
+                "id=mem1,size=2G",
+                "id=mem2,size=1G,hugepages=on,hugepage_size=2M",
+                "id=mem3,size=1G,hugepages=on,hugepage_size=1G",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            hugepage_memory(&memory),
+            BTreeMap::from([(Some(2 << 20), 2 << 30), (Some(1 << 30), 1 << 30)])
+        );
+    }
+
+    #[test]
+    fn test_snapshot_sections() {
+        let mut stream = TempFile::new().unwrap().into_file();
+        send_snapshot_section(&mut stream, SNAPSHOT_CONFIG_FILE, 4).unwrap();
+        stream.write_all(b"data").unwrap();
+        send_snapshot_section(&mut stream, SNAPSHOT_STATE_FILE, 0).unwrap();
+        stream.seek(SeekFrom::Start(0)).unwrap();
+
+        assert_eq!(
+            recv_snapshot_section(&mut stream, SNAPSHOT_CONFIG_FILE).unwrap(),
+            4
+        );
+        let mut data = [0u8; 4];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"data");
+        // The sections are expected in the order they were written
+        assert!(recv_snapshot_section(&mut stream, SNAPSHOT_CONFIG_FILE).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_manifest() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path();
+        // Snapshots without a manifest are accepted, unless it is required
+        verify_snapshot_manifest(path, false).unwrap();
+        assert!(verify_snapshot_manifest(path, true).is_err());
+
+        fs::write(path.join(SNAPSHOT_CONFIG_FILE), b"{}").unwrap();
+        fs::write(path.join(SNAPSHOT_STATE_FILE), b"{}").unwrap();
+        fs::write(path.join("memory-ranges"), vec![0xaa; 8192]).unwrap();
+        write_snapshot_manifest(path).unwrap();
+        fs::write(path.join(SNAPSHOT_CONFIG_FILE), b"{ }").unwrap();
+        verify_snapshot_manifest(path, true).unwrap();
+
+        let manifest: SnapshotManifest =
+            serde_json::from_slice(&fs::read(path.join(SNAPSHOT_MANIFEST_FILE)).unwrap()).unwrap();
+        // The configuration can be edited
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(
+            manifest.files[SNAPSHOT_STATE_FILE],
+            SnapshotFileDigest {
+                size: 2,
+                sha256: "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                    .to_string(),
+            }
+        );
+
+        // Truncated
+        fs::write(path.join("memory-ranges"), vec![0xaa; 4096]).unwrap();
+        assert!(verify_snapshot_manifest(path, false).is_err());
+        // Corrupted
+        let mut memory = vec![0xaa; 8192];
+        memory[4096] = 0;
+        fs::write(path.join("memory-ranges"), memory).unwrap();
+        assert!(verify_snapshot_manifest(path, false).is_err());
+    }
+}
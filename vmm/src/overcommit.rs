@@ -0,0 +1,908 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Memory overcommit driven by the pressure on the host memory.
+//!
+//! Every second, the memory available on the host is read from procfs. Below
+//! the reclaim threshold of the balloon, the balloon is inflated by one step,
+//! taking memory back from the guest, up to the maximum amount reclaimed.
+//! Above the release threshold, the balloon is deflated by one step, until
+//! the guest gets back all the memory reclaimed. The configured size of the
+//! balloon is left untouched, the reclaimed memory coming on top of it.
+//!
+//! The VMs sharing a host don't know about each other. The priority of the
+//! balloon is the number of additional seconds the pressure must last before
+//! memory is reclaimed from the guest, so that the VMs with the lowest
+//! priority give memory back first, the other ones only being reclaimed from
+//! if it was not enough.
+//!
+//! With heterogeneous memory, the memory pressure reported by the kernel
+//! through its pressure stall information (PSI) can also move guest memory to
+//! the heterogeneous memory, inflating the heterogeneous balloon by one step
+//! every second the pressure is over its high threshold, and deflating it once
+//! the pressure dropped under the low threshold.
+//!
+//! When the VMM runs in a memory cgroup whose `memory.high` limit is set, the
+//! balloon can also bring the cgroup back under this limit. The `memory.events`
+//! file of the cgroup is watched through inotify, its `high` counter going up
+//! each time the cgroup gets throttled for going over the limit. The balloon
+//! is then inflated by one step at most every second, reclaiming normal memory
+//! first and heterogeneous memory once the normal memory reclaimed reached its
+//! maximum. The memory is given back, heterogeneous memory first, once the
+//! usage of the cgroup is two steps under the limit.
+//!
+//! While the balloon reclaims memory from the guest, the memory zones marked
+//! overcommittable are also scanned every few seconds through the idle page
+//! tracking of the host kernel. Their pages left untouched since the previous
+//! scan are cold, and get paged out to the host swap right away, freeing host
+//! memory while keeping the guest contents recoverable. The scan walks all the
+//! pages of these zones, and runs from a thread of its own not to hold the VMM
+//! loop for that long.
+
+use crate::config::BalloonConfig;
+use crate::GuestRegionMmap;
+use serde::Serialize;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PROC_MEMINFO: &str = "/proc/meminfo";
+/// Host memory pressure stall information.
+pub const PROC_PRESSURE_MEMORY: &str = "/proc/pressure/memory";
+
+/// Interval between two samples of the host memory.
+pub const OVERCOMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const PROC_SELF_PAGEMAP: &str = "/proc/self/pagemap";
+/// Idle flags of the host pages, see the idle page tracking documentation of
+/// the kernel.
+pub const PAGE_IDLE_BITMAP: &str = "/sys/kernel/mm/page_idle/bitmap";
+
+/// Number of samples under host pressure between two scans of the cold
+/// memory, the pages not accessed in between being paged out.
+pub const COLD_MEMORY_PERIOD: u32 = 10;
+
+const PAGEMAP_ENTRY_SIZE: u64 = 8;
+const PAGEMAP_PRESENT: u64 = 1 << 63;
+const PAGEMAP_PFN_MASK: u64 = (1 << 55) - 1;
+// Number of pagemap entries read at once
+const PAGEMAP_BATCH: u64 = 512;
+
+/// Memory available on the host for new allocations, in bytes.
+pub fn host_available_memory() -> io::Result<u64> {
+    let meminfo = fs::read_to_string(PROC_MEMINFO)?;
+    meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))
+        .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kib| kib << 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MemAvailable"))
+}
+
+pub struct OvercommitMonitor {
+    reclaim_below: u64,
+    release_above: u64,
+    step: u64,
+    max: u64,
+    priority: u8,
+    // Number of consecutive samples the host has been under pressure
+    pressure: u32,
+    reclaimed: u64,
+}
+
+impl OvercommitMonitor {
+    /// Create the monitor for the balloon, if it reclaims memory on host
+    /// pressure. `ram_size` is the memory of the guest not already taken by
+    /// the balloon.
+    pub fn new(balloon: &BalloonConfig, ram_size: u64) -> Option<Self> {
+        let reclaim_below = balloon.reclaim_below?;
+
+        Some(OvercommitMonitor {
+            reclaim_below,
+            release_above: balloon
+                .release_above
+                .unwrap_or(reclaim_below.saturating_mul(2)),
+            step: balloon.reclaim_step,
+            max: balloon.reclaim_max.unwrap_or(ram_size / 2),
+            priority: balloon.reclaim_priority,
+            pressure: 0,
+            reclaimed: 0,
+        })
+    }
+
+    /// Account for a new sample of the memory available on the host. The
+    /// new amount of memory to reclaim from the guest is returned when it
+    /// changed.
+    pub fn sample(&mut self, available: u64) -> Option<u64> {
+        if available < self.reclaim_below {
+            self.pressure = self.pressure.saturating_add(1);
+            if self.pressure > self.priority as u32 && self.reclaimed < self.max {
+                self.reclaimed = (self.reclaimed + self.step).min(self.max);
+                return Some(self.reclaimed);
+            }
+        } else {
+            self.pressure = 0;
+            if available > self.release_above && self.reclaimed > 0 {
+                self.reclaimed = self.reclaimed.saturating_sub(self.step);
+                return Some(self.reclaimed);
+            }
+        }
+
+        None
+    }
+
+    /// Memory currently reclaimed from the guest.
+    pub fn reclaimed(&self) -> u64 {
+        self.reclaimed
+    }
+
+    /// Whether the cold memory should be scanned on this sample, every
+    /// `COLD_MEMORY_PERIOD` samples once the pressure outlasted the priority.
+    pub fn cold_memory_due(&self) -> bool {
+        let priority = self.priority as u32;
+        self.pressure > priority && (self.pressure - priority - 1) % COLD_MEMORY_PERIOD == 0
+    }
+}
+
+/// Tracks the pages of host mappings not accessed, by the guest or the VMM,
+/// between two scans.
+pub struct IdlePageTracker {
+    pagemap: File,
+    bitmap: File,
+    page_size: u64,
+}
+
+impl IdlePageTracker {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self::from_files(
+            File::open(PROC_SELF_PAGEMAP)?,
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(PAGE_IDLE_BITMAP)?,
+            // SAFETY: FFI call. Trivially safe.
+            unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 },
+        ))
+    }
+
+    fn from_files(pagemap: File, bitmap: File, page_size: u64) -> Self {
+        IdlePageTracker {
+            pagemap,
+            bitmap,
+            page_size,
+        }
+    }
+
+    // The idle flags are read and written by 64 pages at once.
+    fn read_idle(&self, index: u64) -> io::Result<u64> {
+        let mut word = [0u8; 8];
+        self.bitmap.read_exact_at(&mut word, index * 8)?;
+        Ok(u64::from_ne_bytes(word))
+    }
+
+    fn mark_idle(&self, index: u64, pages: u64) -> io::Result<()> {
+        self.bitmap.write_all_at(&pages.to_ne_bytes(), index * 8)
+    }
+
+    /// Find the ranges of the mapping of `len` bytes at `addr` whose pages
+    /// were not accessed since the previous scan, and mark all the pages of
+    /// the mapping idle for the next one. The pages not present are skipped.
+    pub fn cold_ranges(&mut self, addr: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let first_page = addr / self.page_size;
+        let pages = len / self.page_size;
+        let mut entries = vec![0u8; (PAGEMAP_BATCH * PAGEMAP_ENTRY_SIZE) as usize];
+        // Word of the idle bitmap being scanned, with the pages to mark idle
+        let mut word_index = None;
+        let (mut word_idle, mut word_mark) = (0, 0);
+
+        let mut page = 0;
+        while page < pages {
+            let count = (pages - page).min(PAGEMAP_BATCH);
+            let entries = &mut entries[..(count * PAGEMAP_ENTRY_SIZE) as usize];
+            self.pagemap
+                .read_exact_at(entries, (first_page + page) * PAGEMAP_ENTRY_SIZE)?;
+
+            for (i, entry) in entries
+                .chunks_exact(PAGEMAP_ENTRY_SIZE as usize)
+                .enumerate()
+            {
+                let entry = u64::from_ne_bytes(entry.try_into().unwrap());
+                if entry & PAGEMAP_PRESENT == 0 {
+                    continue;
+                }
+                let pfn = entry & PAGEMAP_PFN_MASK;
+                if pfn == 0 {
+                    // The page frame numbers are hidden without CAP_SYS_ADMIN
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "no page frame numbers in pagemap",
+                    ));
+                }
+
+                let (index, bit) = (pfn / 64, 1u64 << (pfn % 64));
+                if word_index != Some(index) {
+                    if let Some(word_index) = word_index {
+                        self.mark_idle(word_index, word_mark)?;
+                    }
+                    word_index = Some(index);
+                    word_idle = self.read_idle(index)?;
+                    word_mark = 0;
+                }
+                word_mark |= bit;
+
+                if word_idle & bit != 0 {
+                    let start = (first_page + page + i as u64) * self.page_size;
+                    match ranges.last_mut() {
+                        Some((s, l)) if *s + *l == start => *l += self.page_size,
+                        _ => ranges.push((start, self.page_size)),
+                    }
+                }
+            }
+
+            page += count;
+        }
+
+        if let Some(word_index) = word_index {
+            self.mark_idle(word_index, word_mark)?;
+        }
+
+        Ok(ranges)
+    }
+}
+
+/// Page the `len` bytes of the host mapping at `addr` out, to the host swap
+/// or to the file backing the mapping.
+pub fn page_out(addr: u64, len: u64) -> io::Result<()> {
+    // SAFETY: FFI call with a range of the guest memory mappings, whose
+    // content is only moved out of the host memory.
+    let ret = unsafe { libc::madvise(addr as *mut libc::c_void, len as usize, libc::MADV_PAGEOUT) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Pages the cold memory of guest memory regions out from a thread of its
+/// own, a single scan being in flight at once.
+pub struct ColdMemoryScanner {
+    // Taken by the scan in flight, and given back when it completes
+    tracker: Option<IdlePageTracker>,
+    scan: Option<thread::JoinHandle<IdlePageTracker>>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl ColdMemoryScanner {
+    pub fn new() -> io::Result<Self> {
+        Ok(ColdMemoryScanner {
+            tracker: Some(IdlePageTracker::new()?),
+            scan: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Start paging the cold memory of the regions out, unless the previous
+    /// scan is still running. The regions are kept mapped until the scan
+    /// completes.
+    pub fn scan(&mut self, regions: Vec<Arc<GuestRegionMmap>>) -> io::Result<()> {
+        if let Some(scan) = self.scan.take() {
+            if !scan.is_finished() {
+                debug!("Previous scan of the cold guest memory still running");
+                self.scan = Some(scan);
+                return Ok(());
+            }
+            self.tracker =
+                Some(scan.join().map_err(|_| {
+                    io::Error::new(io::ErrorKind::Other, "cold memory scan panicked")
+                })?);
+        }
+        let Some(mut tracker) = self.tracker.take() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cold memory scan panicked",
+            ));
+        };
+
+        let cancel = self.cancel.clone();
+        self.scan = Some(
+            thread::Builder::new()
+                .name("cold_memory".to_string())
+                .spawn(move || {
+                    let ranges = regions
+                        .iter()
+                        .map(|region| (region.as_ptr() as u64, region.len()));
+                    match page_out_cold_memory(&mut tracker, ranges, &cancel) {
+                        Ok(paged_out) => {
+                            info!("Paged {} bytes of cold guest memory out", paged_out)
+                        }
+                        Err(e) => warn!("Failed paging the cold guest memory out: {}", e),
+                    }
+                    tracker
+                })?,
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for ColdMemoryScanner {
+    fn drop(&mut self) {
+        // The scan in flight stops at the next region, not to keep the guest
+        // memory mapped once the VM is gone.
+        self.cancel.store(true, Ordering::Release);
+    }
+}
+
+// Page the pages of the host mappings not accessed since the previous scan
+// out, returning the amount of memory paged out.
+fn page_out_cold_memory(
+    tracker: &mut IdlePageTracker,
+    mappings: impl Iterator<Item = (u64, u64)>,
+    cancel: &AtomicBool,
+) -> io::Result<u64> {
+    let mut paged_out = 0;
+    for (addr, len) in mappings {
+        if cancel.load(Ordering::Acquire) {
+            break;
+        }
+        for (addr, len) in tracker.cold_ranges(addr, len)? {
+            page_out(addr, len)?;
+            paged_out += len;
+        }
+    }
+
+    Ok(paged_out)
+}
+
+/// Percentage of the last 10 seconds during which some tasks stalled on
+/// memory, read from a pressure stall information file.
+pub fn memory_pressure(path: &Path) -> io::Result<f64> {
+    let pressure = fs::read_to_string(path)?;
+    pressure
+        .lines()
+        .find_map(|l| l.strip_prefix("some "))
+        .and_then(|l| l.split_whitespace().find_map(|f| f.strip_prefix("avg10=")))
+        .and_then(|v| v.parse::<f64>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no some avg10"))
+}
+
+const MEMORY_EVENTS: &str = "memory.events";
+const MEMORY_CURRENT: &str = "memory.current";
+const MEMORY_HIGH: &str = "memory.high";
+
+/// Create an inotify instance, readable once one of its watches fired.
+pub fn inotify_new() -> io::Result<File> {
+    // SAFETY: FFI call. Trivially safe.
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a valid file descriptor we own.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Consume the pending events of an inotify instance.
+pub fn inotify_drain(mut inotify: &File) -> io::Result<()> {
+    let mut events = [0u8; 4096];
+    loop {
+        match inotify.read(&mut events) {
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Memory usage of a cgroup, read from its memory controller files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CgroupMemoryUsage {
+    /// Number of times the cgroup went over its `memory.high` limit.
+    pub high_events: u64,
+    pub current: u64,
+    /// `memory.high` limit, None when the cgroup is not limited.
+    pub high: Option<u64>,
+}
+
+fn parse_memory_events(events: &str) -> Option<u64> {
+    events
+        .lines()
+        .find_map(|l| l.strip_prefix("high "))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+fn parse_memory_high(high: &str) -> Option<Option<u64>> {
+    match high.trim() {
+        "max" => Some(None),
+        high => high.parse().ok().map(Some),
+    }
+}
+
+/// Read the memory usage of the cgroup at `path`.
+pub fn cgroup_memory_usage(path: &Path) -> io::Result<CgroupMemoryUsage> {
+    let invalid = |file| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid {file} in {path:?}"),
+        )
+    };
+
+    let high_events = parse_memory_events(&fs::read_to_string(path.join(MEMORY_EVENTS))?)
+        .ok_or_else(|| invalid(MEMORY_EVENTS))?;
+    let current = fs::read_to_string(path.join(MEMORY_CURRENT))?
+        .trim()
+        .parse()
+        .map_err(|_| invalid(MEMORY_CURRENT))?;
+    let high = parse_memory_high(&fs::read_to_string(path.join(MEMORY_HIGH))?)
+        .ok_or_else(|| invalid(MEMORY_HIGH))?;
+
+    Ok(CgroupMemoryUsage {
+        high_events,
+        current,
+        high,
+    })
+}
+
+/// Action taken on the balloon to keep a cgroup under its `memory.high`
+/// limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupMemoryAction {
+    Inflate,
+    Deflate,
+}
+
+impl CgroupMemoryAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CgroupMemoryAction::Inflate => "inflate",
+            CgroupMemoryAction::Deflate => "deflate",
+        }
+    }
+}
+
+pub struct CgroupMemoryMonitor {
+    path: PathBuf,
+    inotify: File,
+    watch: libc::c_int,
+    step: u64,
+    // Maximum amount of normal and heterogeneous memory reclaimed
+    max: [u64; 2],
+    reclaimed: [u64; 2],
+    high_events: u64,
+    last_change: Option<Instant>,
+}
+
+impl CgroupMemoryMonitor {
+    /// Create the monitor for the balloon, if it keeps the memory cgroup of
+    /// the VMM at `path` under its `memory.high` limit, watching the events
+    /// of the cgroup through the `inotify` instance. `ram_size` is the memory
+    /// of the guest not already taken by the balloon.
+    pub fn new(
+        balloon: &BalloonConfig,
+        ram_size: u64,
+        path: PathBuf,
+        inotify: &File,
+    ) -> io::Result<Option<Self>> {
+        if !balloon.cgroup_memory_high {
+            return Ok(None);
+        }
+
+        let usage = cgroup_memory_usage(&path)?;
+        let events = CString::new(path.join(MEMORY_EVENTS).as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: FFI call with a valid inotify instance and a valid C string.
+        let watch = unsafe {
+            libc::inotify_add_watch(inotify.as_raw_fd(), events.as_ptr(), libc::IN_MODIFY)
+        };
+        if watch < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let hetero_max = if balloon.heterogeneous_memory {
+            balloon.hetero_max.unwrap_or(ram_size / 2)
+        } else {
+            0
+        };
+
+        Ok(Some(CgroupMemoryMonitor {
+            path,
+            inotify: inotify.try_clone()?,
+            watch,
+            step: balloon.reclaim_step,
+            max: [balloon.reclaim_max.unwrap_or(ram_size / 2), hetero_max],
+            reclaimed: [0; 2],
+            high_events: usage.high_events,
+            last_change: None,
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Account for a new sample of the memory usage of the cgroup. The action
+    /// taken on the balloon is returned when the memory to reclaim from the
+    /// guest changed, at most once every `OVERCOMMIT_INTERVAL`.
+    pub fn sample(
+        &mut self,
+        usage: &CgroupMemoryUsage,
+        now: Instant,
+    ) -> Option<CgroupMemoryAction> {
+        let throttled = usage.high_events > self.high_events;
+        self.high_events = usage.high_events;
+
+        if self
+            .last_change
+            .map_or(false, |t| now.duration_since(t) < OVERCOMMIT_INTERVAL)
+        {
+            return None;
+        }
+
+        let action = match usage.high {
+            Some(high) if throttled || usage.current > high => {
+                // Normal memory first
+                let tier = (0..2).find(|&i| self.reclaimed[i] < self.max[i])?;
+                self.reclaimed[tier] = (self.reclaimed[tier] + self.step).min(self.max[tier]);
+                CgroupMemoryAction::Inflate
+            }
+            Some(high) if usage.current.saturating_add(2 * self.step) > high => return None,
+            _ => {
+                // Heterogeneous memory first
+                let tier = (0..2).rev().find(|&i| self.reclaimed[i] > 0)?;
+                self.reclaimed[tier] = self.reclaimed[tier].saturating_sub(self.step);
+                CgroupMemoryAction::Deflate
+            }
+        };
+        self.last_change = Some(now);
+
+        Some(action)
+    }
+
+    /// Normal and heterogeneous memory currently reclaimed from the guest.
+    pub fn reclaimed(&self) -> [u64; 2] {
+        self.reclaimed
+    }
+}
+
+impl Drop for CgroupMemoryMonitor {
+    fn drop(&mut self) {
+        // SAFETY: FFI call with a valid inotify instance and watch.
+        unsafe { libc::inotify_rm_watch(self.inotify.as_raw_fd(), self.watch) };
+    }
+}
+
+/// State of the controller moving guest memory to the heterogeneous memory.
+#[derive(Clone, Debug, Serialize)]
+pub struct HeteroBalloonState {
+    pub pressure_file: PathBuf,
+    /// Last memory pressure sampled.
+    pub pressure: f64,
+    pub pressure_high: u8,
+    pub pressure_low: u8,
+    /// Memory moved to the heterogeneous memory, on top of the size of the
+    /// heterogeneous balloon.
+    pub moved: u64,
+    pub max: u64,
+}
+
+pub struct HeteroBalloonController {
+    state: HeteroBalloonState,
+    step: u64,
+}
+
+impl HeteroBalloonController {
+    /// Create the controller for the balloon, if it moves memory to the
+    /// heterogeneous memory on memory pressure. `ram_size` is the memory of
+    /// the guest not already taken by the balloon.
+    pub fn new(balloon: &BalloonConfig, ram_size: u64) -> Option<Self> {
+        let pressure_high = balloon.hetero_pressure_high?;
+
+        Some(HeteroBalloonController {
+            state: HeteroBalloonState {
+                pressure_file: balloon
+                    .hetero_pressure_file
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from(PROC_PRESSURE_MEMORY)),
+                pressure: 0.0,
+                pressure_high,
+                pressure_low: balloon.hetero_pressure_low.unwrap_or(pressure_high / 2),
+                moved: 0,
+                max: balloon.hetero_max.unwrap_or(ram_size / 2),
+            },
+            step: balloon.hetero_step,
+        })
+    }
+
+    pub fn pressure_file(&self) -> &Path {
+        &self.state.pressure_file
+    }
+
+    pub fn state(&self) -> &HeteroBalloonState {
+        &self.state
+    }
+
+    /// Account for a new sample of the memory pressure. The new amount of
+    /// memory to move to the heterogeneous memory is returned when it
+    /// changed.
+    pub fn sample(&mut self, pressure: f64) -> Option<u64> {
+        let state = &mut self.state;
+        state.pressure = pressure;
+
+        if pressure >= state.pressure_high as f64 && state.moved < state.max {
+            state.moved = (state.moved + self.step).min(state.max);
+            Some(state.moved)
+        } else if pressure <= state.pressure_low as f64 && state.moved > 0 {
+            state.moved = state.moved.saturating_sub(self.step);
+            Some(state.moved)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const MIB: u64 = 1 << 20;
+
+    #[test]
+    fn test_overcommit_monitor() {
+        let balloon = BalloonConfig::parse(
+            "size=0,reclaim_below=1G,release_above=2G,reclaim_step=256M,reclaim_max=512M,\
+            reclaim_priority=1",
+        )
+        .unwrap();
+        assert!(OvercommitMonitor::new(&BalloonConfig::parse("size=0").unwrap(), 0).is_none());
+        let mut monitor = OvercommitMonitor::new(&balloon, 4096 * MIB).unwrap();
+
+        // The pressure must last for a second sample with a priority of 1
+        assert_eq!(monitor.sample(512 * MIB), None);
+        assert_eq!(monitor.sample(512 * MIB), Some(256 * MIB));
+        assert_eq!(monitor.sample(512 * MIB), Some(512 * MIB));
+        // Capped to the maximum
+        assert_eq!(monitor.sample(512 * MIB), None);
+
+        // Nothing released between the two thresholds
+        assert_eq!(monitor.sample(1536 * MIB), None);
+        assert_eq!(monitor.sample(3072 * MIB), Some(256 * MIB));
+        assert_eq!(monitor.sample(3072 * MIB), Some(0));
+        assert_eq!(monitor.sample(3072 * MIB), None);
+
+        // The pressure starts over after being relieved
+        assert_eq!(monitor.sample(512 * MIB), None);
+        assert!(!monitor.cold_memory_due());
+        monitor.sample(512 * MIB);
+        assert!(monitor.cold_memory_due());
+        for _ in 0..COLD_MEMORY_PERIOD - 1 {
+            monitor.sample(512 * MIB);
+            assert!(!monitor.cold_memory_due());
+        }
+        monitor.sample(512 * MIB);
+        assert!(monitor.cold_memory_due());
+    }
+
+    #[test]
+    fn test_hetero_balloon_controller() {
+        let balloon = BalloonConfig::parse(
+            "size=0,heterogeneous_memory=on,hetero_pressure_high=40,hetero_step=256M,\
+            hetero_max=512M",
+        )
+        .unwrap();
+        let mut controller = HeteroBalloonController::new(&balloon, 4096 * MIB).unwrap();
+        assert_eq!(controller.state().pressure_low, 20);
+        assert_eq!(controller.pressure_file(), Path::new(PROC_PRESSURE_MEMORY));
+
+        assert_eq!(controller.sample(10.0), None);
+        assert_eq!(controller.sample(45.5), Some(256 * MIB));
+        assert_eq!(controller.sample(60.0), Some(512 * MIB));
+        // Capped to the maximum
+        assert_eq!(controller.sample(60.0), None);
+        // Nothing given back between the two thresholds
+        assert_eq!(controller.sample(30.0), None);
+        assert_eq!(controller.sample(5.0), Some(256 * MIB));
+        assert_eq!(controller.sample(5.0), Some(0));
+        assert_eq!(controller.sample(5.0), None);
+        assert_eq!(controller.state().pressure, 5.0);
+    }
+
+    #[test]
+    fn test_cgroup_memory_monitor() {
+        assert_eq!(
+            parse_memory_events("low 0\nhigh 12\nmax 0\noom 0\noom_kill 0\n"),
+            Some(12)
+        );
+        assert_eq!(parse_memory_high("max\n"), Some(None));
+        assert_eq!(parse_memory_high("1073741824\n"), Some(Some(1 << 30)));
+        assert_eq!(parse_memory_high("1G\n"), None);
+
+        let dir = TempDir::new().unwrap();
+        let write_usage = |usage: &CgroupMemoryUsage| {
+            fs::write(
+                dir.as_path().join(MEMORY_EVENTS),
+                format!("low 0\nhigh {}\nmax 0\n", usage.high_events),
+            )
+            .unwrap();
+            fs::write(
+                dir.as_path().join(MEMORY_CURRENT),
+                format!("{}\n", usage.current),
+            )
+            .unwrap();
+            fs::write(
+                dir.as_path().join(MEMORY_HIGH),
+                usage
+                    .high
+                    .map_or("max\n".to_owned(), |high| format!("{high}\n")),
+            )
+            .unwrap();
+        };
+        let mut usage = CgroupMemoryUsage {
+            high_events: 3,
+            current: 1536 * MIB,
+            high: Some(2048 * MIB),
+        };
+        write_usage(&usage);
+        assert_eq!(cgroup_memory_usage(dir.as_path()).unwrap(), usage);
+
+        let inotify = inotify_new().unwrap();
+        let balloon = BalloonConfig::parse(
+            "size=0,heterogeneous_memory=on,cgroup_memory_high=on,reclaim_step=256M,\
+            reclaim_max=256M,hetero_max=512M",
+        )
+        .unwrap();
+        assert!(CgroupMemoryMonitor::new(
+            &BalloonConfig::parse("size=0").unwrap(),
+            4096 * MIB,
+            dir.as_path().to_path_buf(),
+            &inotify
+        )
+        .unwrap()
+        .is_none());
+        let mut monitor =
+            CgroupMemoryMonitor::new(&balloon, 4096 * MIB, dir.as_path().to_path_buf(), &inotify)
+                .unwrap()
+                .unwrap();
+
+        // Watching the events of the cgroup
+        inotify_drain(&inotify).unwrap();
+        usage.high_events = 4;
+        write_usage(&usage);
+        let mut events = [0u8; 4096];
+        assert!((&inotify).read(&mut events).unwrap() > 0);
+
+        // The normal memory is reclaimed first, once per interval
+        let mut now = Instant::now();
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Inflate)
+        );
+        assert_eq!(monitor.reclaimed(), [256 * MIB, 0]);
+        usage.high_events = 5;
+        assert_eq!(monitor.sample(&usage, now), None);
+        now += OVERCOMMIT_INTERVAL;
+        usage.current = 2560 * MIB;
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Inflate)
+        );
+        assert_eq!(monitor.reclaimed(), [256 * MIB, 256 * MIB]);
+        now += OVERCOMMIT_INTERVAL;
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Inflate)
+        );
+        now += OVERCOMMIT_INTERVAL;
+        // Capped to the maximum
+        assert_eq!(monitor.sample(&usage, now), None);
+        assert_eq!(monitor.reclaimed(), [256 * MIB, 512 * MIB]);
+
+        // Nothing given back without two steps under the limit
+        usage.current = 1792 * MIB;
+        assert_eq!(monitor.sample(&usage, now), None);
+        // The heterogeneous memory is given back first
+        usage.current = 1536 * MIB;
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Deflate)
+        );
+        assert_eq!(monitor.reclaimed(), [256 * MIB, 256 * MIB]);
+        now += OVERCOMMIT_INTERVAL;
+        usage.high = None;
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Deflate)
+        );
+        now += OVERCOMMIT_INTERVAL;
+        assert_eq!(
+            monitor.sample(&usage, now),
+            Some(CgroupMemoryAction::Deflate)
+        );
+        assert_eq!(monitor.reclaimed(), [0, 0]);
+        now += OVERCOMMIT_INTERVAL;
+        assert_eq!(monitor.sample(&usage, now), None);
+    }
+
+    #[test]
+    fn test_memory_pressure() {
+        let file = TempFile::new().unwrap();
+        fs::write(
+            file.as_path(),
+            "some avg10=12.34 avg60=5.00 avg300=1.00 total=123456\n\
+            full avg10=1.00 avg60=0.50 avg300=0.10 total=1234\n",
+        )
+        .unwrap();
+        assert_eq!(memory_pressure(file.as_path()).unwrap(), 12.34);
+    }
+
+    #[test]
+    fn test_cold_ranges() {
+        const PAGE_SIZE: u64 = 0x1000;
+        // Mapping of 8 pages from the 16th page
+        const FIRST_PAGE: u64 = 16;
+
+        let pagemap = TempFile::new().unwrap().into_file();
+        let bitmap = TempFile::new().unwrap().into_file();
+        let read_word = |index: u64| {
+            let mut word = [0u8; 8];
+            bitmap.read_exact_at(&mut word, index * 8).unwrap();
+            u64::from_ne_bytes(word)
+        };
+
+        // Page frame of each page, the third one not being present
+        let pfns = [64, 65, 0, 66, 200, 201, 130, 131];
+        for (page, pfn) in pfns.iter().enumerate() {
+            let entry = if *pfn == 0 { 0 } else { PAGEMAP_PRESENT | pfn };
+            pagemap
+                .write_all_at(
+                    &entry.to_ne_bytes(),
+                    (FIRST_PAGE + page as u64) * PAGEMAP_ENTRY_SIZE,
+                )
+                .unwrap();
+        }
+        // The frames 64, 65, 200 and 201 were left idle since the last scan
+        for (index, word) in [(1u64, 0b11u64), (2, 0), (3, 0b11 << 8)] {
+            bitmap.write_all_at(&word.to_ne_bytes(), index * 8).unwrap();
+        }
+
+        let mut tracker = IdlePageTracker::from_files(
+            pagemap.try_clone().unwrap(),
+            bitmap.try_clone().unwrap(),
+            PAGE_SIZE,
+        );
+        let addr = FIRST_PAGE * PAGE_SIZE;
+        assert_eq!(
+            tracker.cold_ranges(addr, 8 * PAGE_SIZE).unwrap(),
+            vec![(addr, 2 * PAGE_SIZE), (addr + 4 * PAGE_SIZE, 2 * PAGE_SIZE)]
+        );
+
+        // All the present pages are marked idle for the next scan
+        assert_eq!(read_word(1), 0b111);
+        assert_eq!(read_word(2), 0b1100);
+        assert_eq!(read_word(3), 0b11 << 8);
+
+        // The page frames are hidden from unprivileged processes
+        pagemap
+            .write_all_at(
+                &PAGEMAP_PRESENT.to_ne_bytes(),
+                (FIRST_PAGE + 2) * PAGEMAP_ENTRY_SIZE,
+            )
+            .unwrap();
+        assert_eq!(
+            tracker.cold_ranges(addr, 8 * PAGE_SIZE).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+}
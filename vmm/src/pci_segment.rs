@@ -9,10 +9,11 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::config::PciSegmentConfig;
 use crate::device_manager::{AddressManager, DeviceManagerError, DeviceManagerResult};
 use acpi_tables::{self, aml, Aml};
 use arch::layout;
-use pci::{DeviceRelocation, PciBdf, PciBus, PciConfigMmio, PciRoot};
+use pci::{DeviceRelocation, PciBdf, PciBus, PciConfigMmio, PciRoot, PciRootPort};
 #[cfg(target_arch = "x86_64")]
 use pci::{PciConfigIo, PCI_CONFIG_IO_PORT, PCI_CONFIG_IO_PORT_SIZE};
 use std::sync::{Arc, Mutex};
@@ -20,6 +21,26 @@ use uuid::Uuid;
 use vm_allocator::AddressAllocator;
 use vm_device::BusDevice;
 
+/// Root port attached to the first bus of a segment, leading to its own bus.
+pub(crate) struct RootPort {
+    pub(crate) id: String,
+    // Slot of the port on the first bus of the segment
+    pub(crate) device_id: u8,
+    // Bus behind the port
+    pub(crate) bus: u8,
+    pub(crate) port: Arc<Mutex<PciRootPort>>,
+}
+
+/// Root bus of a segment other than its first one, taking the devices once
+/// the first bus is full. It is exposed to the guest as a host bridge of its
+/// own, with its own share of the device memory of the segment.
+pub(crate) struct ExpansionBus {
+    pub(crate) bus: u8,
+    pub(crate) allocator: Arc<Mutex<AddressAllocator>>,
+    pub(crate) start_of_device_area: u64,
+    pub(crate) end_of_device_area: u64,
+}
+
 pub(crate) struct PciSegment {
     pub(crate) id: u16,
     pub(crate) pci_bus: Arc<Mutex<PciBus>>,
@@ -27,6 +48,13 @@ pub(crate) struct PciSegment {
     pub(crate) mmio_config_address: u64,
     pub(crate) proximity_domain: u32,
 
+    // Range of buses covered by the segment, the devices being attached to
+    // the first one, and to the expansion buses ending the range once it is
+    // full.
+    pub(crate) start_bus: u8,
+    pub(crate) end_bus: u8,
+    pub(crate) expansion_buses: Vec<ExpansionBus>,
+
     #[cfg(target_arch = "x86_64")]
     pub(crate) pci_config_io: Option<Arc<Mutex<PciConfigIo>>>,
 
@@ -42,32 +70,52 @@ pub(crate) struct PciSegment {
     pub(crate) end_of_device_area: u64,
 
     pub(crate) allocator: Arc<Mutex<AddressAllocator>>,
+
+    pub(crate) root_ports: Vec<RootPort>,
 }
 
 impl PciSegment {
     pub(crate) fn new(
-        id: u16,
+        config: &PciSegmentConfig,
         numa_node: u32,
+        mmio_config_address: u64,
         address_manager: &Arc<AddressManager>,
         allocator: Arc<Mutex<AddressAllocator>>,
+        expansion_allocators: &[Arc<Mutex<AddressAllocator>>],
         pci_irq_slots: &[u8; 32],
     ) -> DeviceManagerResult<PciSegment> {
         let pci_root = PciRoot::new(None);
-        let pci_bus = Arc::new(Mutex::new(PciBus::new(
+        let mut pci_bus = PciBus::new(
             pci_root,
             Arc::clone(address_manager) as Arc<dyn DeviceRelocation>,
-        )));
+            config.start_bus,
+        );
 
+        let first_expansion_bus = config.end_bus + 1 - expansion_allocators.len() as u8;
+        let mut expansion_buses = Vec::new();
+        for (bus, allocator) in (first_expansion_bus..=config.end_bus).zip(expansion_allocators) {
+            pci_bus.add_expansion_bus(bus);
+            let start_of_device_area = allocator.lock().unwrap().base().0;
+            let end_of_device_area = allocator.lock().unwrap().end().0;
+            expansion_buses.push(ExpansionBus {
+                bus,
+                allocator: Arc::clone(allocator),
+                start_of_device_area,
+                end_of_device_area,
+            });
+        }
+        let pci_bus = Arc::new(Mutex::new(pci_bus));
+
+        // The configuration space of the segment starts with its first bus,
+        // which the PCI bus sees as bus 0.
         let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::clone(&pci_bus))));
-        let mmio_config_address =
-            layout::PCI_MMCONFIG_START.0 + layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT * id as u64;
 
         address_manager
             .mmio_bus
             .insert(
                 Arc::clone(&pci_config_mmio) as Arc<Mutex<dyn BusDevice>>,
                 mmio_config_address,
-                layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
+                config.num_buses() * layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT,
             )
             .map_err(DeviceManagerError::BusError)?;
 
@@ -75,11 +123,14 @@ impl PciSegment {
         let end_of_device_area = allocator.lock().unwrap().end().0;
 
         let segment = PciSegment {
-            id,
+            id: config.pci_segment,
             pci_bus,
             pci_config_mmio,
             mmio_config_address,
             proximity_domain: numa_node,
+            start_bus: config.start_bus,
+            end_bus: config.end_bus,
+            expansion_buses,
             pci_devices_up: 0,
             pci_devices_down: 0,
             #[cfg(target_arch = "x86_64")]
@@ -88,22 +139,33 @@ impl PciSegment {
             start_of_device_area,
             end_of_device_area,
             pci_irq_slots: *pci_irq_slots,
+            root_ports: Vec::new(),
         };
 
         info!(
-            "Adding PCI segment: id={}, PCI MMIO config address: 0x{:x}, device area [0x{:x}-0x{:x}",
-            segment.id, segment.mmio_config_address, segment.start_of_device_area, segment.end_of_device_area
+            "Adding PCI segment: id={}, buses [0x{:x}-0x{:x}], PCI MMIO config address: 0x{:x}, device area [0x{:x}-0x{:x}",
+            segment.id, segment.start_bus, segment.end_bus, segment.mmio_config_address, segment.start_of_device_area, segment.end_of_device_area
         );
         Ok(segment)
     }
 
     #[cfg(target_arch = "x86_64")]
     pub(crate) fn new_default_segment(
+        config: &PciSegmentConfig,
         address_manager: &Arc<AddressManager>,
         allocator: Arc<Mutex<AddressAllocator>>,
+        expansion_allocators: &[Arc<Mutex<AddressAllocator>>],
         pci_irq_slots: &[u8; 32],
     ) -> DeviceManagerResult<PciSegment> {
-        let mut segment = Self::new(0, 0, address_manager, allocator, pci_irq_slots)?;
+        let mut segment = Self::new(
+            config,
+            0,
+            layout::PCI_MMCONFIG_START.0,
+            address_manager,
+            allocator,
+            expansion_allocators,
+            pci_irq_slots,
+        )?;
         let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(Arc::clone(&segment.pci_bus))));
 
         address_manager
@@ -122,24 +184,99 @@ impl PciSegment {
 
     #[cfg(target_arch = "aarch64")]
     pub(crate) fn new_default_segment(
+        config: &PciSegmentConfig,
         address_manager: &Arc<AddressManager>,
         allocator: Arc<Mutex<AddressAllocator>>,
+        expansion_allocators: &[Arc<Mutex<AddressAllocator>>],
         pci_irq_slots: &[u8; 32],
     ) -> DeviceManagerResult<PciSegment> {
-        Self::new(0, 0, address_manager, allocator, pci_irq_slots)
+        Self::new(
+            config,
+            0,
+            layout::PCI_MMCONFIG_START.0,
+            address_manager,
+            allocator,
+            expansion_allocators,
+            pci_irq_slots,
+        )
     }
 
-    pub(crate) fn next_device_bdf(&self) -> DeviceManagerResult<PciBdf> {
-        Ok(PciBdf::new(
-            self.id,
-            0,
-            self.pci_bus
-                .lock()
-                .unwrap()
-                .next_device_id()
-                .map_err(DeviceManagerError::NextPciDeviceId)? as u8,
-            0,
-        ))
+    /// Size of the configuration space of the segment.
+    pub(crate) fn mmio_config_size(&self) -> u64 {
+        ((self.end_bus - self.start_bus) as u64 + 1) * layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT
+    }
+
+    /// Address of the configuration space of bus 0 of the segment, as
+    /// expected by the MCFG table, even when the segment doesn't start with
+    /// this bus.
+    pub(crate) fn ecam_base_address(&self) -> u64 {
+        self.mmio_config_address - self.start_bus as u64 * layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT
+    }
+
+    /// Last bus reached through the first bus of the segment, the buses
+    /// following it being the expansion buses.
+    pub(crate) fn first_bus_end(&self) -> u8 {
+        self.expansion_buses
+            .first()
+            .map_or(self.end_bus, |expansion_bus| expansion_bus.bus - 1)
+    }
+
+    /// Address of the next device of the segment, which can only be found on
+    /// an expansion bus when asked to, as the hot-plug of the devices only
+    /// involves the first bus.
+    pub(crate) fn next_device_bdf(&self, expand: bool) -> DeviceManagerResult<PciBdf> {
+        let mut pci_bus = self.pci_bus.lock().unwrap();
+        let id = pci_bus
+            .next_device_id(expand)
+            .map_err(DeviceManagerError::NextPciDeviceId)?;
+        let (bus, device) = pci_bus.device_address(id);
+        Ok(PciBdf::new(self.id, bus, device, 0))
+    }
+
+    /// Id of a device attached to the first bus or to an expansion bus of
+    /// the segment.
+    pub(crate) fn device_id(&self, bdf: PciBdf) -> Option<u32> {
+        self.pci_bus
+            .lock()
+            .unwrap()
+            .device_id(bdf.bus(), bdf.device())
+    }
+
+    /// Allocator of the 64-bit BARs of the devices of the given bus.
+    pub(crate) fn bus_allocator(&self, bus: u8) -> &Arc<Mutex<AddressAllocator>> {
+        self.expansion_buses
+            .iter()
+            .find(|expansion_bus| expansion_bus.bus == bus)
+            .map_or(&self.allocator, |expansion_bus| &expansion_bus.allocator)
+    }
+
+    /// Root port leading to the given bus.
+    pub(crate) fn root_port(&self, bus: u8) -> Option<&RootPort> {
+        self.root_ports.iter().find(|p| p.bus == bus)
+    }
+
+    /// Address of the device to attach behind the given root port.
+    pub(crate) fn root_port_bdf(&self, id: &str) -> DeviceManagerResult<PciBdf> {
+        let root_port = self
+            .root_ports
+            .iter()
+            .find(|p| p.id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownPciRootPort(id.to_string()))?;
+        if root_port.port.lock().unwrap().device().is_some() {
+            return Err(DeviceManagerError::PciRootPortInUse(id.to_string()));
+        }
+
+        Ok(PciBdf::new(self.id, root_port.bus, 0, 0))
+    }
+
+    /// Legacy interrupt of a device, the interrupt of a device behind a root
+    /// port being the one of the slot of the port.
+    pub(crate) fn legacy_irq(&self, bdf: PciBdf) -> u8 {
+        let slot = match self.root_port(bdf.bus()) {
+            Some(root_port) => root_port.device_id,
+            None => bdf.device(),
+        };
+        self.pci_irq_slots[slot as usize]
     }
 
     pub fn reserve_legacy_interrupts_for_pci_devices(
@@ -314,6 +451,59 @@ impl Aml for PciDsmMethod {
     }
 }
 
+struct PciOscMethod {}
+
+impl Aml for PciOscMethod {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        // Refer to PCI Firmware spec v3.3 Ch 4.5
+        // _OSC (Operating System Capabilities), granting the control of the
+        // native PCI Express hot-plug and AER of the root ports, and of the
+        // PCI Express capability structure they rely on.
+        /*
+        Method (_OSC, 4, NotSerialized)
+        {
+            CreateDWordField (Arg3, Zero, CDW1)
+            If ((Arg0 == ToUUID ("33db4d5b-1ff7-401c-9657-7441c03dd766")))
+            {
+                CreateDWordField (Arg3, 0x08, CDW3)
+                CDW3 &= 0x19
+                Return (Arg3)
+            }
+
+            CDW1 += 0x04 // Unrecognized UUID
+            Return (Arg3)
+        }
+         */
+        let uuid = Uuid::parse_str("33DB4D5B-1FF7-401C-9657-7441C03DD766").unwrap();
+        let (uuid_d1, uuid_d2, uuid_d3, uuid_d4) = uuid.as_fields();
+        let mut uuid_buf = vec![];
+        uuid_buf.extend(uuid_d1.to_le_bytes());
+        uuid_buf.extend(uuid_d2.to_le_bytes());
+        uuid_buf.extend(uuid_d3.to_le_bytes());
+        uuid_buf.extend(uuid_d4);
+        aml::Method::new(
+            "_OSC".into(),
+            4,
+            false,
+            vec![
+                &aml::CreateDWordField::new(&aml::Path::new("CDW1"), &aml::Arg(3), &0usize),
+                &aml::If::new(
+                    &aml::Equal::new(&aml::Arg(0), &aml::BufferData::new(uuid_buf)),
+                    vec![
+                        &aml::CreateDWordField::new(&aml::Path::new("CDW3"), &aml::Arg(3), &8usize),
+                        &aml::And::new(&aml::Path::new("CDW3"), &aml::Path::new("CDW3"), &0x19u8),
+                        &aml::Return::new(&aml::Arg(3)),
+                    ],
+                ),
+                // The unrecognized UUID bit is never set by the OS.
+                &aml::Add::new(&aml::Path::new("CDW1"), &aml::Path::new("CDW1"), &0x04u8),
+                &aml::Return::new(&aml::Arg(3)),
+            ],
+        )
+        .to_aml_bytes(sink)
+    }
+}
+
 impl Aml for PciSegment {
     fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
         let mut pci_dsdt_inner_data: Vec<&dyn Aml> = Vec::new();
@@ -325,6 +515,8 @@ impl Aml for PciSegment {
         pci_dsdt_inner_data.push(&adr);
         let seg = aml::Name::new("_SEG".into(), &self.id);
         pci_dsdt_inner_data.push(&seg);
+        let bbn = aml::Name::new("_BBN".into(), &self.start_bus);
+        pci_dsdt_inner_data.push(&bbn);
         let uid = aml::Name::new("_UID".into(), &aml::ZERO);
         pci_dsdt_inner_data.push(&uid);
         let cca = aml::Name::new("_CCA".into(), &aml::ONE);
@@ -340,11 +532,20 @@ impl Aml for PciSegment {
         let pci_dsm = PciDsmMethod {};
         pci_dsdt_inner_data.push(&pci_dsm);
 
+        // The native PCI Express services are only used by the root ports.
+        let pci_osc = PciOscMethod {};
+        if !self.root_ports.is_empty() {
+            pci_dsdt_inner_data.push(&pci_osc);
+        }
+
         let crs = if self.id == 0 {
             aml::Name::new(
                 "_CRS".into(),
                 &aml::ResourceTemplate::new(vec![
-                    &aml::AddressSpace::new_bus_number(0x0u16, 0x0u16),
+                    &aml::AddressSpace::new_bus_number(
+                        self.start_bus as u16,
+                        self.first_bus_end() as u16,
+                    ),
                     #[cfg(target_arch = "x86_64")]
                     &aml::IO::new(0xcf8, 0xcf8, 1, 0x8),
                     &aml::AddressSpace::new_memory(
@@ -372,11 +573,14 @@ impl Aml for PciSegment {
             aml::Name::new(
                 "_CRS".into(),
                 &aml::ResourceTemplate::new(vec![
-                    &aml::AddressSpace::new_bus_number(0x0u16, 0x0u16),
+                    &aml::AddressSpace::new_bus_number(
+                        self.start_bus as u16,
+                        self.first_bus_end() as u16,
+                    ),
                     &aml::Memory32Fixed::new(
                         true,
                         self.mmio_config_address as u32,
-                        layout::PCI_MMIO_CONFIG_SIZE_PER_SEGMENT as u32,
+                        self.mmio_config_size() as u32,
                     ),
                     &aml::AddressSpace::new_memory(
                         aml::AddressSpaceCacheable::NotCacheable,
@@ -402,7 +606,34 @@ impl Aml for PciSegment {
         let pci_device_methods = PciDevSlotMethods {};
         pci_dsdt_inner_data.push(&pci_device_methods);
 
-        // Build PCI routing table, listing IRQs assigned to PCI devices.
+        let prt = PciRoutingTable {
+            pci_irq_slots: &self.pci_irq_slots,
+        };
+        pci_dsdt_inner_data.push(&prt);
+
+        aml::Device::new(
+            format!("_SB_.PC{:02X}", self.id).as_str().into(),
+            pci_dsdt_inner_data,
+        )
+        .to_aml_bytes(sink);
+
+        for expansion_bus in self.expansion_buses.iter() {
+            ExpansionBusHostBridge {
+                segment: self,
+                expansion_bus,
+            }
+            .to_aml_bytes(sink);
+        }
+    }
+}
+
+// PCI routing table, listing the IRQs assigned to the PCI devices.
+struct PciRoutingTable<'a> {
+    pci_irq_slots: &'a [u8; 32],
+}
+
+impl<'a> Aml for PciRoutingTable<'a> {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
         let prt_package_list: Vec<(u32, u32)> = self
             .pci_irq_slots
             .iter()
@@ -417,13 +648,79 @@ impl Aml for PciSegment {
             .iter()
             .map(|item| item as &dyn Aml)
             .collect();
-        let prt = aml::Name::new("_PRT".into(), &aml::Package::new(prt_package_list));
-        pci_dsdt_inner_data.push(&prt);
+        aml::Name::new("_PRT".into(), &aml::Package::new(prt_package_list)).to_aml_bytes(sink)
+    }
+}
+
+// Name of the host bridge of an expansion bus, made unique across the
+// segments by encoding the segment and bus numbers in base 36.
+fn expansion_bus_name(segment: u16, bus: u8) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut n = segment as usize * 256 + bus as usize;
+    let mut name = [b'0'; 3];
+    for c in name.iter_mut().rev() {
+        *c = DIGITS[n % DIGITS.len()];
+        n /= DIGITS.len();
+    }
+    format!("_SB_.B{}", std::str::from_utf8(&name).unwrap())
+}
+
+// Host bridge of an expansion bus, which doesn't support hot-plug.
+struct ExpansionBusHostBridge<'a> {
+    segment: &'a PciSegment,
+    expansion_bus: &'a ExpansionBus,
+}
 
+impl<'a> Aml for ExpansionBusHostBridge<'a> {
+    fn to_aml_bytes(&self, sink: &mut dyn acpi_tables::AmlSink) {
+        let bus = self.expansion_bus.bus;
+        let proximity_domain = self.segment.proximity_domain;
         aml::Device::new(
-            format!("_SB_.PC{:02X}", self.id).as_str().into(),
-            pci_dsdt_inner_data,
+            expansion_bus_name(self.segment.id, bus).as_str().into(),
+            vec![
+                &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08")),
+                &aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03")),
+                &aml::Name::new("_SEG".into(), &self.segment.id),
+                &aml::Name::new("_BBN".into(), &bus),
+                &aml::Name::new("_UID".into(), &bus),
+                &aml::Name::new("_CCA".into(), &aml::ONE),
+                &aml::Method::new(
+                    "_PXM".into(),
+                    0,
+                    false,
+                    vec![&aml::Return::new(&proximity_domain)],
+                ),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![
+                        &aml::AddressSpace::new_bus_number(bus as u16, bus as u16),
+                        &aml::AddressSpace::new_memory(
+                            aml::AddressSpaceCacheable::NotCacheable,
+                            true,
+                            self.expansion_bus.start_of_device_area,
+                            self.expansion_bus.end_of_device_area,
+                            None,
+                        ),
+                    ]),
+                ),
+                &PciRoutingTable {
+                    pci_irq_slots: &self.segment.pci_irq_slots,
+                },
+            ],
         )
         .to_aml_bytes(sink)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expansion_bus_name() {
+        assert_eq!(expansion_bus_name(0, 1), "_SB_.B001");
+        assert_eq!(expansion_bus_name(0, 0xff), "_SB_.B073");
+        assert_eq!(expansion_bus_name(1, 0), "_SB_.B074");
+        assert_eq!(expansion_bus_name(95, 0xff), "_SB_.BIYN");
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Resource limits and OOM killer adjustment of the VMM process.
+//!
+//! Large VMs may need more file descriptors than the default `RLIMIT_NOFILE`
+//! allows, with many vhost queues or VFIO devices, and more locked memory than
+//! `RLIMIT_MEMLOCK` allows, as the guest memory gets pinned when devices are
+//! assigned. Both the soft and hard limits are set to the configured value,
+//! raising the hard limit requiring `CAP_SYS_RESOURCE`, as does lowering the
+//! OOM score adjustment.
+//!
+//! The kernel shares these values between all the threads of a process, hence
+//! they can't be adjusted for the device threads alone.
+
+use crate::config::ProcessLimitsConfig;
+use std::fs;
+use std::io;
+use thiserror::Error;
+
+const PROC_SELF_OOM_SCORE_ADJ: &str = "/proc/self/oom_score_adj";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot set the {0} limit to {1}: {2}")]
+    SetRlimit(&'static str, u64, #[source] io::Error),
+
+    #[error("Cannot set the OOM score adjustment to {0}: {1}")]
+    SetOomScoreAdj(i32, #[source] io::Error),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The type of the resource argument of setrlimit() differs between the C
+// libraries, hence the call being made from a macro.
+macro_rules! set_rlimit {
+    ($resource:ident, $value:expr) => {{
+        let rlim = libc::rlimit {
+            rlim_cur: $value,
+            rlim_max: $value,
+        };
+        // SAFETY: FFI call with a valid rlimit structure
+        if unsafe { libc::setrlimit(libc::$resource, &rlim) } < 0 {
+            Err(Error::SetRlimit(
+                stringify!($resource),
+                $value,
+                io::Error::last_os_error(),
+            ))
+        } else {
+            Ok(())
+        }
+    }};
+}
+
+/// Apply the limits from the configuration to the VMM process, the ones left
+/// out keeping their current value.
+pub fn apply_process_limits(config: &ProcessLimitsConfig) -> Result<()> {
+    if let Some(nofile) = config.nofile {
+        set_rlimit!(RLIMIT_NOFILE, nofile)?;
+    }
+    if let Some(memlock) = config.memlock {
+        set_rlimit!(RLIMIT_MEMLOCK, memlock)?;
+    }
+    if let Some(adj) = config.oom_score_adj {
+        fs::write(PROC_SELF_OOM_SCORE_ADJ, adj.to_string())
+            .map_err(|e| Error::SetOomScoreAdj(adj, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_process_limits() {
+        apply_process_limits(&ProcessLimitsConfig::default()).unwrap();
+
+        // Writing back the current adjustment never requires any privilege
+        let adj: i32 = fs::read_to_string(PROC_SELF_OOM_SCORE_ADJ)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        apply_process_limits(&ProcessLimitsConfig {
+            oom_score_adj: Some(adj),
+            ..Default::default()
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Monitoring of the resources used by the VMM itself.
+//!
+//! Each sample reads the CPU time of every thread of the VMM process, along
+//! with the resident memory and the number of open file descriptors of the
+//! process, from procfs. The CPU usage of the threads is computed over the
+//! interval since the previous sample, so that a thread spinning, such as a
+//! device thread stuck in a loop, stands out. The latency of the control loop
+//! is the time taken to handle the events returned by each wait on its epoll
+//! file descriptor.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+const PROC_SELF_STATUS: &str = "/proc/self/status";
+const PROC_SELF_FD: &str = "/proc/self/fd";
+const PROC_SELF_TASK: &str = "/proc/self/task";
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ThreadResources {
+    pub tid: u32,
+    pub name: String,
+    /// CPU time spent by the thread since it started.
+    pub cpu_time_ms: u64,
+    /// Share of a host CPU used by the thread since the previous sample.
+    pub cpu_usage_percent: f64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EpollLatency {
+    pub count: u64,
+    pub average_us: u64,
+    pub max_us: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VmmResources {
+    /// Time since the previous sample, which the usage and the latencies
+    /// are computed over.
+    pub interval_ms: u64,
+    pub rss_kib: u64,
+    pub fds: u64,
+    pub threads: Vec<ThreadResources>,
+    pub epoll_latency: EpollLatency,
+}
+
+pub struct ResourceMonitor {
+    last_sample: Instant,
+    // CPU time of each thread at the previous sample, in clock ticks
+    last_cpu_ticks: HashMap<u32, u64>,
+    epoll_count: u64,
+    epoll_total: Duration,
+    epoll_max: Duration,
+}
+
+impl Default for ResourceMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResourceMonitor {
+    pub fn new() -> Self {
+        ResourceMonitor {
+            last_sample: Instant::now(),
+            last_cpu_ticks: HashMap::new(),
+            epoll_count: 0,
+            epoll_total: Duration::ZERO,
+            epoll_max: Duration::ZERO,
+        }
+    }
+
+    pub fn record_epoll_latency(&mut self, latency: Duration) {
+        self.epoll_count += 1;
+        self.epoll_total += latency;
+        self.epoll_max = self.epoll_max.max(latency);
+    }
+
+    pub fn sample(&mut self) -> io::Result<VmmResources> {
+        let now = Instant::now();
+        let interval = now.duration_since(self.last_sample);
+        // SAFETY: FFI call with no argument
+        let ticks_per_sec = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+            ticks if ticks > 0 => ticks as u64,
+            _ => 100,
+        };
+
+        let mut cpu_ticks = HashMap::new();
+        let mut threads = Vec::new();
+        for entry in fs::read_dir(PROC_SELF_TASK)? {
+            let entry = entry?;
+            let tid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(tid) => tid,
+                None => continue,
+            };
+            // The thread may have exited since the directory was read
+            let stat = match fs::read_to_string(entry.path().join("stat")) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            let (name, ticks) = match parse_thread_stat(&stat) {
+                Some(thread) => thread,
+                None => continue,
+            };
+
+            let ticks_used =
+                ticks.saturating_sub(self.last_cpu_ticks.get(&tid).copied().unwrap_or(0));
+            let cpu_usage_percent = if interval.is_zero() {
+                0.0
+            } else {
+                ticks_used as f64 * 100.0 / ticks_per_sec as f64 / interval.as_secs_f64()
+            };
+            threads.push(ThreadResources {
+                tid,
+                name,
+                cpu_time_ms: ticks * 1000 / ticks_per_sec,
+                cpu_usage_percent,
+            });
+            cpu_ticks.insert(tid, ticks);
+        }
+        threads.sort_by_key(|thread| thread.tid);
+
+        let epoll_latency = EpollLatency {
+            count: self.epoll_count,
+            average_us: self
+                .epoll_total
+                .as_micros()
+                .checked_div(self.epoll_count as u128)
+                .unwrap_or(0) as u64,
+            max_us: self.epoll_max.as_micros() as u64,
+        };
+
+        let resources = VmmResources {
+            interval_ms: interval.as_millis() as u64,
+            rss_kib: parse_rss(&fs::read_to_string(PROC_SELF_STATUS)?).unwrap_or(0),
+            fds: fs::read_dir(PROC_SELF_FD)?.count() as u64,
+            threads,
+            epoll_latency,
+        };
+
+        self.last_sample = now;
+        self.last_cpu_ticks = cpu_ticks;
+        self.epoll_count = 0;
+        self.epoll_total = Duration::ZERO;
+        self.epoll_max = Duration::ZERO;
+
+        Ok(resources)
+    }
+}
+
+// Name of the thread and CPU time it spent, in user and system mode, from
+// its /proc/<pid>/task/<tid>/stat file. The name is between parentheses and
+// may hold spaces, the other fields following the closing one.
+fn parse_thread_stat(stat: &str) -> Option<(String, u64)> {
+    let start = stat.find('(')?;
+    let end = stat.rfind(')')?;
+    let name = stat.get(start + 1..end)?.to_string();
+
+    // Fields from the state (3rd) one, utime and stime being the 14th and 15th
+    let mut fields = stat.get(end + 1..)?.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+
+    Some((name, utime + stime))
+}
+
+fn parse_rss(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_thread_stat() {
+        let stat = "1234 (virtio-net rx) S 1 1234 1234 0 -1 4194560 100 0 0 0 \
+            250 75 0 0 20 0 4 0 123456 1000000 500 18446744073709551615";
+        assert_eq!(
+            parse_thread_stat(stat),
+            Some(("virtio-net rx".to_string(), 325))
+        );
+        assert_eq!(parse_thread_stat("1234 (vcpu0) S 1"), None);
+    }
+
+    #[test]
+    fn test_parse_rss() {
+        let status = "Name:\tcloud-hyperviso\nVmPeak:\t  123456 kB\nVmRSS:\t   65432 kB\n";
+        assert_eq!(parse_rss(status), Some(65432));
+        assert_eq!(parse_rss("Name:\tkthreadd\n"), None);
+    }
+}
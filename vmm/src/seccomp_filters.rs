@@ -13,6 +13,8 @@ use std::convert::TryInto;
 
 pub enum Thread {
     HttpApi,
+    #[cfg(feature = "tls_api")]
+    HttpsApi,
     #[cfg(feature = "dbus_api")]
     DBusApi,
     EventMonitor,
@@ -20,6 +22,10 @@ pub enum Thread {
     Vcpu,
     Vmm,
     PtyForeground,
+    SeccompMonitor,
+    Ivshmem,
+    #[cfg(feature = "tdx")]
+    TdxQuote,
 }
 
 /// Shorthand for chaining `SeccompCondition`s with the `and` operator  in a `SeccompRule`.
@@ -57,6 +63,7 @@ const BLKSSZGET: u64 = 0x1268;
 const BLKPBSZGET: u64 = 0x127b;
 const BLKIOMIN: u64 = 0x1278;
 const BLKIOOPT: u64 = 0x1279;
+const FICLONERANGE: u64 = 0x4020_940d;
 
 // See include/uapi/linux/if_tun.h in the kernel code.
 const TUNGETIFF: u64 = 0x8004_54d2;
@@ -125,6 +132,8 @@ mod kvm {
     pub const KVM_CREATE_VCPU: u64 = 0xae41;
     pub const KVM_CREATE_IRQCHIP: u64 = 0xae60;
     pub const KVM_RUN: u64 = 0xae80;
+    pub const KVM_RESET_DIRTY_RINGS: u64 = 0xaec7;
+    pub const KVM_GET_STATS_FD: u64 = 0xaece;
     pub const KVM_SET_MP_STATE: u64 = 0x4004_ae99;
     pub const KVM_SET_GSI_ROUTING: u64 = 0x4008_ae6a;
     pub const KVM_SET_DEVICE_ATTR: u64 = 0x4018_aee1;
@@ -230,11 +239,13 @@ fn create_vmm_ioctl_seccomp_rule_common_kvm() -> Result<Vec<SeccompRule>, Backen
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_ONE_REG)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_REGS)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_REG_LIST)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_STATS_FD)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_SUPPORTED_CPUID,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_VCPU_EVENTS,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_GET_VCPU_MMAP_SIZE,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_IOEVENTFD)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_IRQFD)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_RESET_DIRTY_RINGS)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_RUN)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_MEMORY_ENCRYPT_OP)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_DEVICE_ATTR,)?],
@@ -267,6 +278,7 @@ fn create_vmm_ioctl_seccomp_rule_common(
         and![Cond::new(1, ArgLen::Dword, Eq, BLKPBSZGET)?],
         and![Cond::new(1, ArgLen::Dword, Eq, BLKIOMIN)?],
         and![Cond::new(1, ArgLen::Dword, Eq, BLKIOOPT)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, FICLONERANGE)?],
         and![Cond::new(1, ArgLen::Dword, Eq, FIOCLEX)?],
         and![Cond::new(1, ArgLen::Dword, Eq, FIONBIO)?],
         and![Cond::new(1, ArgLen::Dword, Eq, SIOCGIFFLAGS)?],
@@ -372,6 +384,7 @@ fn create_vmm_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError>
     const KVM_SET_XSAVE: u64 = 0x5000_aea5;
     const KVM_SET_GUEST_DEBUG: u64 = 0x4048_ae9b;
     const KVM_TRANSLATE: u64 = 0xc018_ae85;
+    const KVM_X86_SET_MSR_FILTER: u64 = 0x4188_aec6;
 
     let common_rules = create_vmm_ioctl_seccomp_rule_common(HypervisorType::Kvm)?;
     let mut arch_rules = or![
@@ -400,6 +413,7 @@ fn create_vmm_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError>
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_XSAVE,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_GUEST_DEBUG,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_TRANSLATE,)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_X86_SET_MSR_FILTER)?],
     ];
     arch_rules.extend(common_rules);
 
@@ -512,6 +526,7 @@ fn vmm_thread_rules(
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_access, vec![]),
         (libc::SYS_bind, vec![]),
+        (libc::SYS_bpf, vec![]),
         (libc::SYS_brk, vec![]),
         (libc::SYS_clock_gettime, vec![]),
         (libc::SYS_clock_nanosleep, vec![]),
@@ -541,14 +556,19 @@ fn vmm_thread_rules(
         (libc::SYS_newfstatat, vec![]),
         (libc::SYS_futex, vec![]),
         (libc::SYS_getdents64, vec![]),
+        (libc::SYS_get_mempolicy, vec![]),
         (libc::SYS_getpgid, vec![]),
         #[cfg(target_arch = "x86_64")]
         (libc::SYS_getpgrp, vec![]),
         (libc::SYS_getpid, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getsockopt, vec![]),
         (libc::SYS_gettid, vec![]),
         (libc::SYS_gettimeofday, vec![]),
         (libc::SYS_getuid, vec![]),
+        (libc::SYS_inotify_add_watch, vec![]),
+        (libc::SYS_inotify_init1, vec![]),
+        (libc::SYS_inotify_rm_watch, vec![]),
         (
             libc::SYS_ioctl,
             create_vmm_ioctl_seccomp_rule(hypervisor_type)?,
@@ -562,12 +582,19 @@ fn vmm_thread_rules(
         (libc::SYS_io_uring_setup, vec![]),
         (libc::SYS_io_uring_register, vec![]),
         (libc::SYS_kill, vec![]),
+        (libc::SYS_landlock_add_rule, vec![]),
+        (libc::SYS_landlock_create_ruleset, vec![]),
+        (libc::SYS_landlock_restrict_self, vec![]),
         (libc::SYS_listen, vec![]),
         (libc::SYS_lseek, vec![]),
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mbind, vec![]),
         (libc::SYS_memfd_create, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_mkdir, vec![]),
+        (libc::SYS_mkdirat, vec![]),
         (libc::SYS_mmap, vec![]),
+        (libc::SYS_move_pages, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_mremap, vec![]),
         (libc::SYS_munmap, vec![]),
@@ -610,6 +637,7 @@ fn vmm_thread_rules(
         (libc::SYS_seccomp, vec![]),
         (libc::SYS_sendmsg, vec![]),
         (libc::SYS_sendto, vec![]),
+        (libc::SYS_set_mempolicy, vec![]),
         (libc::SYS_set_robust_list, vec![]),
         (libc::SYS_setsid, vec![]),
         (libc::SYS_setsockopt, vec![]),
@@ -620,6 +648,8 @@ fn vmm_thread_rules(
             or![
                 and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_INET6 as u64)?],
+                and![Cond::new(0, ArgLen::Dword, Eq, libc::AF_XDP as u64)?],
             ],
         ),
         (libc::SYS_socketpair, vec![]),
@@ -629,6 +659,7 @@ fn vmm_thread_rules(
         (libc::SYS_statx, vec![]),
         (libc::SYS_tgkill, vec![]),
         (libc::SYS_timerfd_create, vec![]),
+        (libc::SYS_timerfd_gettime, vec![]),
         (libc::SYS_timerfd_settime, vec![]),
         (libc::SYS_tkill, vec![]),
         (
@@ -654,6 +685,7 @@ fn create_vcpu_ioctl_seccomp_rule_kvm() -> Result<Vec<SeccompRule>, BackendError
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_DEVICE_ATTR,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_GSI_ROUTING,)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_SET_USER_MEMORY_REGION,)?],
+        and![Cond::new(1, ArgLen::Dword, Eq, KVM_RESET_DIRTY_RINGS)?],
         and![Cond::new(1, ArgLen::Dword, Eq, KVM_RUN,)?],
     ])
 }
@@ -767,6 +799,10 @@ fn http_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
     Ok(vec![
         (libc::SYS_accept4, vec![]),
         (libc::SYS_brk, vec![]),
+        (libc::SYS_clock_gettime, vec![]),
+        // The jobs are run by threads of their own
+        (libc::SYS_clone, vec![]),
+        (libc::SYS_clone3, vec![]),
         (libc::SYS_close, vec![]),
         (libc::SYS_dup, vec![]),
         (libc::SYS_epoll_create1, vec![]),
@@ -776,20 +812,55 @@ fn http_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_epoll_wait, vec![]),
         (libc::SYS_exit, vec![]),
         (libc::SYS_fcntl, vec![]),
+        (libc::SYS_ftruncate, vec![]),
         (libc::SYS_futex, vec![]),
         (libc::SYS_getrandom, vec![]),
+        (libc::SYS_getsockopt, vec![]),
         (libc::SYS_ioctl, create_api_ioctl_seccomp_rule()?),
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
+        (libc::SYS_openat, vec![]),
+        (libc::SYS_prctl, vec![]),
         (libc::SYS_recvfrom, vec![]),
         (libc::SYS_recvmsg, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_rename, vec![]),
+        (libc::SYS_renameat, vec![]),
+        (libc::SYS_renameat2, vec![]),
+        // musl is missing this constant
+        // (libc::SYS_rseq, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (334, vec![]),
+        #[cfg(target_arch = "aarch64")]
+        (293, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sched_getaffinity, vec![]),
+        (libc::SYS_set_robust_list, vec![]),
         (libc::SYS_sigaltstack, vec![]),
         (libc::SYS_write, vec![]),
     ])
 }
 
+// The filter containing the white listed syscall rules required by the HTTP API
+// served over TLS to function, on top of the ones of the HTTP API.
+#[cfg(feature = "tls_api")]
+fn https_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    let mut rules = http_api_thread_rules()?;
+    rules.extend(vec![
+        (libc::SYS_getpid, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_poll, vec![]),
+        (libc::SYS_ppoll, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_shutdown, vec![]),
+    ]);
+    Ok(rules)
+}
+
 // The filter containing the white listed syscall rules required by the D-Bus API
 // to function.
 #[cfg(feature = "dbus_api")]
@@ -803,14 +874,20 @@ fn dbus_api_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError>
         (libc::SYS_dup, vec![]),
         (libc::SYS_epoll_ctl, vec![]),
         (libc::SYS_exit, vec![]),
+        (libc::SYS_ftruncate, vec![]),
         (libc::SYS_futex, vec![]),
         (libc::SYS_getrandom, vec![]),
         (libc::SYS_madvise, vec![]),
         (libc::SYS_mmap, vec![]),
         (libc::SYS_mprotect, vec![]),
         (libc::SYS_munmap, vec![]),
+        (libc::SYS_openat, vec![]),
         (libc::SYS_prctl, vec![]),
         (libc::SYS_recvmsg, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_rename, vec![]),
+        (libc::SYS_renameat, vec![]),
+        (libc::SYS_renameat2, vec![]),
         // musl is missing this constant
         // (libc::SYS_rseq, vec![]),
         #[cfg(target_arch = "x86_64")]
@@ -830,7 +907,68 @@ fn event_monitor_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendE
     Ok(vec![
         (libc::SYS_brk, vec![]),
         (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+fn seccomp_monitor_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_getpid, vec![]),
+        (libc::SYS_madvise, vec![]),
         (libc::SYS_mmap, vec![]),
+        (libc::SYS_mremap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+fn ivshmem_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_epoll_create1, vec![]),
+        (libc::SYS_epoll_ctl, vec![]),
+        (libc::SYS_epoll_pwait, vec![]),
+        #[cfg(target_arch = "x86_64")]
+        (libc::SYS_epoll_wait, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_fcntl, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_recvmsg, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_write, vec![]),
+    ])
+}
+
+#[cfg(feature = "tdx")]
+fn tdx_quote_thread_rules() -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
+    Ok(vec![
+        (libc::SYS_brk, vec![]),
+        (libc::SYS_close, vec![]),
+        (libc::SYS_connect, vec![]),
+        (libc::SYS_exit, vec![]),
+        (libc::SYS_futex, vec![]),
+        (libc::SYS_madvise, vec![]),
+        (libc::SYS_mmap, vec![]),
+        (libc::SYS_munmap, vec![]),
+        (libc::SYS_read, vec![]),
+        (libc::SYS_recvfrom, vec![]),
+        (libc::SYS_rt_sigprocmask, vec![]),
+        (libc::SYS_sendto, vec![]),
+        (libc::SYS_setsockopt, vec![]),
+        (libc::SYS_sigaltstack, vec![]),
+        (libc::SYS_socket, vec![]),
         (libc::SYS_write, vec![]),
     ])
 }
@@ -841,6 +979,8 @@ fn get_seccomp_rules(
 ) -> Result<Vec<(i64, Vec<SeccompRule>)>, BackendError> {
     match thread_type {
         Thread::HttpApi => Ok(http_api_thread_rules()?),
+        #[cfg(feature = "tls_api")]
+        Thread::HttpsApi => Ok(https_api_thread_rules()?),
         #[cfg(feature = "dbus_api")]
         Thread::DBusApi => Ok(dbus_api_thread_rules()?),
         Thread::EventMonitor => Ok(event_monitor_thread_rules()?),
@@ -848,6 +988,10 @@ fn get_seccomp_rules(
         Thread::Vcpu => Ok(vcpu_thread_rules(hypervisor_type)?),
         Thread::Vmm => Ok(vmm_thread_rules(hypervisor_type)?),
         Thread::PtyForeground => Ok(pty_foreground_thread_rules()?),
+        Thread::SeccompMonitor => Ok(seccomp_monitor_thread_rules()?),
+        Thread::Ivshmem => Ok(ivshmem_thread_rules()?),
+        #[cfg(feature = "tdx")]
+        Thread::TdxQuote => Ok(tdx_quote_thread_rules()?),
     }
 }
 
@@ -0,0 +1,173 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reporting of the seccomp violations when the filters only log them.
+//!
+//! With `--seccomp log`, the system calls denied by the filters are still
+//! allowed, and the kernel records each of them in its log as an audit message.
+//! The monitor follows the kernel log to find the messages about the VMM
+//! process, counting the violations and reporting the first one of each system
+//! call from a given thread, both in the logs and as an event.
+
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{Error, Result};
+use hypervisor::HypervisorType;
+use seccompiler::{apply_filter, SeccompAction};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+const KMSG_PATH: &str = "/dev/kmsg";
+// Audit message type of the seccomp events
+const AUDIT_SECCOMP: &str = "type=1326";
+// Records longer than this are truncated by the kernel
+const KMSG_RECORD_SIZE: usize = 8192;
+
+static MONITORING: AtomicBool = AtomicBool::new(false);
+static VIOLATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Violation {
+    thread: String,
+    syscall: i64,
+}
+
+/// Number of seccomp violations seen so far, if they are monitored.
+pub fn violations() -> Option<u64> {
+    MONITORING
+        .load(Ordering::Acquire)
+        .then(|| VIOLATIONS.load(Ordering::Acquire))
+}
+
+/// Start following the kernel log for the seccomp violations. Without access
+/// to the kernel log, the violations are only found in there.
+pub fn start_seccomp_monitor_thread(
+    seccomp_action: &SeccompAction,
+    hypervisor_type: HypervisorType,
+) -> Result<()> {
+    let mut kmsg = match File::open(KMSG_PATH) {
+        Ok(kmsg) => kmsg,
+        Err(e) => {
+            warn!("Cannot monitor the seccomp violations, failed to open {KMSG_PATH}: {e}");
+            return Ok(());
+        }
+    };
+    // Skip the messages logged before the VMM started
+    if let Err(e) = kmsg.seek(SeekFrom::End(0)) {
+        warn!("Cannot monitor the seccomp violations, failed to seek {KMSG_PATH}: {e}");
+        return Ok(());
+    }
+
+    let seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::SeccompMonitor, hypervisor_type)
+            .map_err(Error::CreateSeccompFilter)?;
+    MONITORING.store(true, Ordering::Release);
+
+    thread::Builder::new()
+        .name("seccomp-monitor".to_owned())
+        .spawn(move || {
+            if !seccomp_filter.is_empty() {
+                if let Err(e) = apply_filter(&seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                    return;
+                }
+            }
+
+            if let Err(e) = monitor(kmsg) {
+                error!("Stopped monitoring the seccomp violations: {}", e);
+            }
+        })
+        .map_err(Error::SeccompMonitorThreadSpawn)?;
+
+    Ok(())
+}
+
+fn monitor(mut kmsg: File) -> io::Result<()> {
+    let pid = std::process::id();
+    let mut reported = HashSet::new();
+    let mut record = vec![0u8; KMSG_RECORD_SIZE];
+
+    loop {
+        // Each read returns a single record
+        let len = match kmsg.read(&mut record) {
+            Ok(len) => len,
+            // Records overwritten before being read
+            Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let record = String::from_utf8_lossy(&record[..len]);
+        if let Some(violation) = parse_violation(&record, pid) {
+            let count = VIOLATIONS.fetch_add(1, Ordering::AcqRel) + 1;
+            if !reported.contains(&violation) {
+                warn!(
+                    "Seccomp violation: system call {} from thread {} ({} violations so far)",
+                    violation.syscall, violation.thread, count
+                );
+                event!(
+                    "seccomp",
+                    "violation",
+                    "thread",
+                    &violation.thread,
+                    "syscall",
+                    violation.syscall.to_string()
+                );
+                reported.insert(violation);
+            }
+        }
+    }
+}
+
+// Parse an audit message such as:
+// 6,1234,5678,-;audit: type=1326 audit(1700000000.123:45): auid=4294967295
+// uid=0 gid=0 ses=4294967295 pid=1000 comm="vcpu0" exe="/usr/bin/cloud-hypervisor"
+// sig=0 arch=c000003e syscall=57 compat=0 ip=0x7f5e3a2b1c2d code=0x7ffc0000
+fn parse_violation(record: &str, pid: u32) -> Option<Violation> {
+    let (_, message) = record.split_once(';')?;
+    if !message.contains(AUDIT_SECCOMP) {
+        return None;
+    }
+
+    let field = |name: &str| {
+        message
+            .split_whitespace()
+            .find_map(|f| f.strip_prefix(name)?.strip_prefix('='))
+    };
+    if field("pid")?.parse::<u32>().ok()? != pid {
+        return None;
+    }
+
+    Some(Violation {
+        thread: field("comm")?.trim_matches('"').to_string(),
+        syscall: field("syscall")?.parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_violation() {
+        let record = "5,1234,5678,-;audit: type=1326 audit(1700000000.123:45): \
+            auid=4294967295 uid=0 gid=0 ses=4294967295 pid=1000 comm=\"vcpu0\" \
+            exe=\"/usr/bin/cloud-hypervisor\" sig=0 arch=c000003e syscall=57 compat=0 \
+            ip=0x7f5e3a2b1c2d code=0x7ffc0000\n";
+        assert_eq!(
+            parse_violation(record, 1000),
+            Some(Violation {
+                thread: "vcpu0".to_string(),
+                syscall: 57
+            })
+        );
+        assert_eq!(parse_violation(record, 1001), None);
+        assert_eq!(
+            parse_violation("6,1235,5679,-;virtio_net virtio1: link up\n", 1000),
+            None
+        );
+    }
+}
@@ -12,9 +12,9 @@ use devices::legacy::Serial;
 use libc::EFD_NONBLOCK;
 use serial_buffer::SerialBuffer;
 use std::fs::File;
-use std::io::Read;
-use std::net::Shutdown;
-use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
@@ -62,13 +62,17 @@ pub enum Error {
     #[error("Error binding to socket: {0}")]
     BindUnixSocket(#[source] io::Error),
 
+    /// Cannot bind to TCP address
+    #[error("Error binding to TCP address: {0}")]
+    BindTcpSocket(#[source] io::Error),
+
     /// Cannot accept connection from Unix socket
     #[error("Error accepting connection: {0}")]
     AcceptConnection(#[source] io::Error),
 
-    /// Cannot clone the UnixStream
-    #[error("Error cloning UnixStream: {0}")]
-    CloneUnixStream(#[source] io::Error),
+    /// Cannot clone the connection
+    #[error("Error cloning the connection: {0}")]
+    CloneStream(#[source] io::Error),
 
     /// Cannot shutdown the connection
     #[error("Error shutting down a connection: {0}")]
@@ -101,6 +105,178 @@ impl From<u64> for EpollDispatch {
     }
 }
 
+// Telnet commands (RFC 854) and options
+const TELNET_IAC: u8 = 255;
+const TELNET_DONT: u8 = 254;
+const TELNET_DO: u8 = 253;
+const TELNET_WONT: u8 = 252;
+const TELNET_WILL: u8 = 251;
+const TELNET_SB: u8 = 250;
+const TELNET_SE: u8 = 240;
+const TELNET_OPT_BINARY: u8 = 0;
+const TELNET_OPT_ECHO: u8 = 1;
+const TELNET_OPT_SGA: u8 = 3;
+
+// Sent to the telnet clients once connected, for them to switch to character
+// mode, leaving the echo to the guest, and to pass the data through as is.
+const TELNET_NEGOTIATION: [u8; 12] = [
+    TELNET_IAC,
+    TELNET_WILL,
+    TELNET_OPT_ECHO,
+    TELNET_IAC,
+    TELNET_WILL,
+    TELNET_OPT_SGA,
+    TELNET_IAC,
+    TELNET_WILL,
+    TELNET_OPT_BINARY,
+    TELNET_IAC,
+    TELNET_DO,
+    TELNET_OPT_BINARY,
+];
+
+#[derive(Clone, Copy, Default)]
+enum TelnetState {
+    #[default]
+    Data,
+    // Carriage return received, which telnet clients may follow with NUL
+    Cr,
+    Iac,
+    Negotiation,
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+// Strips the telnet commands from the input of a client, the commands being
+// possibly split across reads.
+#[derive(Default)]
+struct TelnetInput {
+    state: TelnetState,
+}
+
+impl TelnetInput {
+    fn push(&mut self, byte: u8) -> Option<u8> {
+        use TelnetState::*;
+        let (state, data) = match (self.state, byte) {
+            (Iac, TELNET_IAC) => (Data, Some(TELNET_IAC)),
+            (Iac, TELNET_WILL | TELNET_WONT | TELNET_DO | TELNET_DONT) => (Negotiation, None),
+            (Iac, TELNET_SB) => (Subnegotiation, None),
+            (Iac, _) | (Negotiation, _) => (Data, None),
+            (Subnegotiation, TELNET_IAC) => (SubnegotiationIac, None),
+            (SubnegotiationIac, TELNET_SE) => (Data, None),
+            (Subnegotiation, _) | (SubnegotiationIac, _) => (Subnegotiation, None),
+            (Cr, 0) => (Data, None),
+            (Data | Cr, TELNET_IAC) => (Iac, None),
+            (Data | Cr, b'\r') => (Cr, Some(byte)),
+            (Data | Cr, _) => (Data, Some(byte)),
+        };
+        self.state = state;
+
+        data
+    }
+
+    fn filter(&mut self, input: &[u8]) -> Vec<u8> {
+        input.iter().filter_map(|byte| self.push(*byte)).collect()
+    }
+}
+
+// Escapes the IAC bytes of the output sent to a telnet client.
+struct TelnetWriter<W: Write>(W);
+
+impl<W: Write> Write for TelnetWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut escaped = Vec::with_capacity(buf.len());
+        for byte in buf {
+            escaped.push(*byte);
+            if *byte == TELNET_IAC {
+                escaped.push(TELNET_IAC);
+            }
+        }
+        self.0.write_all(&escaped)?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Listening socket of the socket and tcp modes.
+enum SerialListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl SerialListener {
+    fn accept(&self) -> io::Result<SerialStream> {
+        match self {
+            SerialListener::Unix(listener) => Ok(SerialStream::Unix(listener.accept()?.0)),
+            SerialListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                info!("Serial connection from {}", addr);
+                stream.set_nodelay(true)?;
+                Ok(SerialStream::Tcp(stream))
+            }
+        }
+    }
+}
+
+// Client connected to the listening socket.
+enum SerialStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl SerialStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            SerialStream::Unix(stream) => SerialStream::Unix(stream.try_clone()?),
+            SerialStream::Tcp(stream) => SerialStream::Tcp(stream.try_clone()?),
+        })
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            SerialStream::Unix(stream) => stream.shutdown(Shutdown::Both),
+            SerialStream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for SerialStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SerialStream::Unix(stream) => stream.read(buf),
+            SerialStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for SerialStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SerialStream::Unix(stream) => stream.write(buf),
+            SerialStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SerialStream::Unix(stream) => stream.flush(),
+            SerialStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for SerialStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            SerialStream::Unix(stream) => stream.as_raw_fd(),
+            SerialStream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
 pub struct SerialManager {
     #[cfg(target_arch = "x86_64")]
     serial: Arc<Mutex<Serial>>,
@@ -113,6 +289,7 @@ pub struct SerialManager {
     pty_write_out: Option<Arc<AtomicBool>>,
     mode: ConsoleOutputMode,
     socket_path: Option<PathBuf>,
+    telnet: bool,
 }
 
 impl SerialManager {
@@ -122,6 +299,8 @@ impl SerialManager {
         pty_pair: Option<Arc<Mutex<PtyPair>>>,
         mode: ConsoleOutputMode,
         socket: Option<PathBuf>,
+        tcp: Option<SocketAddr>,
+        telnet: bool,
     ) -> Result<Option<Self>> {
         let mut socket_path: Option<PathBuf> = None;
 
@@ -171,6 +350,16 @@ impl SerialManager {
                     return Ok(None);
                 }
             }
+            ConsoleOutputMode::Tcp => {
+                if let Some(addr) = tcp {
+                    let listener = TcpListener::bind(addr).map_err(Error::BindTcpSocket)?;
+                    info!("Serial port listening on {}", addr);
+                    // SAFETY: listener is valid and will return valid fd
+                    unsafe { File::from_raw_fd(listener.into_raw_fd()) }
+                } else {
+                    return Ok(None);
+                }
+            }
             _ => return Ok(None),
         };
 
@@ -185,7 +374,7 @@ impl SerialManager {
         )
         .map_err(Error::Epoll)?;
 
-        let epoll_fd_data = if mode == ConsoleOutputMode::Socket {
+        let epoll_fd_data = if matches!(mode, ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp) {
             EpollDispatch::Socket
         } else {
             EpollDispatch::File
@@ -225,9 +414,27 @@ impl SerialManager {
             pty_write_out,
             mode,
             socket_path,
+            telnet,
         }))
     }
 
+    // Stop watching a connection, either replaced by a new one or closed by
+    // the client.
+    fn close_connection(epoll_fd: RawFd, stream: SerialStream) -> Result<()> {
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_DEL,
+            stream.as_raw_fd(),
+            epoll::Event::new(epoll::Events::empty(), 0),
+        )
+        .map_err(Error::Epoll)?;
+
+        match stream.shutdown() {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(Error::ShutdownConnection(e)),
+            _ => Ok(()),
+        }
+    }
+
     // This function should be called when the other end of the PTY is
     // connected. It verifies if this is the first time it's been invoked
     // after the connection happened, and if that's the case it flushes
@@ -265,9 +472,26 @@ impl SerialManager {
         let mut in_file = self.in_file.try_clone().map_err(Error::FileClone)?;
         let serial = self.serial.clone();
         let pty_write_out = self.pty_write_out.clone();
-        //SAFETY: in_file is has a valid fd
-        let listener = unsafe { UnixListener::from_raw_fd(self.in_file.as_raw_fd()) };
-        let mut reader: Option<UnixStream> = None;
+        let listener = match self.mode {
+            ConsoleOutputMode::Socket => {
+                let fd = self.in_file.try_clone().map_err(Error::FileClone)?;
+                // SAFETY: fd is a valid listening socket, owned by the listener
+                Some(SerialListener::Unix(unsafe {
+                    UnixListener::from_raw_fd(fd.into_raw_fd())
+                }))
+            }
+            ConsoleOutputMode::Tcp => {
+                let fd = self.in_file.try_clone().map_err(Error::FileClone)?;
+                // SAFETY: fd is a valid listening socket, owned by the listener
+                Some(SerialListener::Tcp(unsafe {
+                    TcpListener::from_raw_fd(fd.into_raw_fd())
+                }))
+            }
+            _ => None,
+        };
+        let mut connection: Option<SerialStream> = None;
+        let mut telnet_input = TelnetInput::default();
+        let telnet = self.telnet;
         let mode = self.mode.clone();
 
         // In case of PTY, we want to be able to detect a connection on the
@@ -307,7 +531,9 @@ impl SerialManager {
                             }
                         };
 
-                        if mode != ConsoleOutputMode::Socket && num_events == 0 {
+                        if !matches!(mode, ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp)
+                            && num_events == 0
+                        {
                             // This very specific case happens when the serial is connected
                             // to a PTY. We know EPOLLHUP is always present when there's nothing
                             // connected at the other end of the PTY. That's why getting no event
@@ -326,55 +552,85 @@ impl SerialManager {
                                 EpollDispatch::Socket => {
                                     // New connection request arrived.
                                     // Shutdown the previous connection, if any
-                                    if let Some(previous_reader) = reader {
-                                        previous_reader
-                                            .shutdown(Shutdown::Both)
-                                            .map_err(Error::AcceptConnection)?;
+                                    if let Some(previous) = connection.take() {
+                                        Self::close_connection(epoll_fd, previous)?;
                                     }
                                     // Events on the listening socket will be connection requests.
                                     // Accept them, create a reader and a writer.
-                                    let (unix_stream, _) =
-                                        listener.accept().map_err(Error::AcceptConnection)?;
-                                    let writer =
-                                        unix_stream.try_clone().map_err(Error::CloneUnixStream)?;
-                                    reader = Some(
-                                        unix_stream.try_clone().map_err(Error::CloneUnixStream)?,
-                                    );
+                                    let stream = match listener.as_ref() {
+                                        Some(listener) => {
+                                            listener.accept().map_err(Error::AcceptConnection)?
+                                        }
+                                        None => continue,
+                                    };
+                                    let mut writer =
+                                        stream.try_clone().map_err(Error::CloneStream)?;
+
+                                    if telnet {
+                                        if let Err(e) = writer.write_all(&TELNET_NEGOTIATION) {
+                                            warn!("Cannot negotiate with telnet client: {}", e);
+                                            continue;
+                                        }
+                                        telnet_input = TelnetInput::default();
+                                    }
 
                                     epoll::ctl(
                                         epoll_fd,
                                         epoll::ControlOptions::EPOLL_CTL_ADD,
-                                        unix_stream.into_raw_fd(),
+                                        stream.as_raw_fd(),
                                         epoll::Event::new(
                                             epoll::Events::EPOLLIN,
                                             EpollDispatch::File as u64,
                                         ),
                                     )
                                     .map_err(Error::Epoll)?;
-                                    serial.lock().unwrap().set_out(Some(Box::new(writer)));
+                                    connection = Some(stream);
+
+                                    let writer: Box<dyn Write + Send> = if telnet {
+                                        Box::new(TelnetWriter(writer))
+                                    } else {
+                                        Box::new(writer)
+                                    };
+                                    serial.lock().unwrap().set_out(Some(writer));
                                 }
                                 EpollDispatch::File => {
                                     if event.events & libc::EPOLLIN as u32 != 0 {
                                         let mut input = [0u8; 64];
                                         let count = match mode {
-                                            ConsoleOutputMode::Socket => {
-                                                if let Some(mut serial_reader) = reader.as_ref() {
-                                                    let count = serial_reader
+                                            ConsoleOutputMode::Socket | ConsoleOutputMode::Tcp => {
+                                                if let Some(mut stream) = connection.take() {
+                                                    // A connection reset by the client is
+                                                    // handled as if it was closed.
+                                                    let count = stream
                                                         .read(&mut input)
-                                                        .map_err(Error::ReadInput)?;
+                                                        .unwrap_or_else(|e| {
+                                                            warn!(
+                                                                "Error reading serial socket: {}",
+                                                                e
+                                                            );
+                                                            0
+                                                        });
                                                     if count == 0 {
                                                         info!("Remote end closed serial socket");
-                                                        serial_reader
-                                                            .shutdown(Shutdown::Both)
-                                                            .map_err(Error::ShutdownConnection)?;
-                                                        reader = None;
+                                                        Self::close_connection(epoll_fd, stream)?;
                                                         serial
                                                             .as_ref()
                                                             .lock()
                                                             .unwrap()
                                                             .set_out(None);
+                                                        0
+                                                    } else {
+                                                        connection = Some(stream);
+                                                        if telnet {
+                                                            let data = telnet_input
+                                                                .filter(&input[..count]);
+                                                            input[..data.len()]
+                                                                .copy_from_slice(&data);
+                                                            data.len()
+                                                        } else {
+                                                            count
+                                                        }
                                                     }
-                                                    count
                                                 } else {
                                                     0
                                                 }
@@ -446,3 +702,39 @@ impl Drop for SerialManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_telnet_input() {
+        let mut input = TelnetInput::default();
+        assert_eq!(
+            input.filter(&[
+                b'a',
+                TELNET_IAC,
+                TELNET_DO,
+                TELNET_OPT_ECHO,
+                b'b',
+                TELNET_IAC,
+                TELNET_IAC
+            ]),
+            vec![b'a', b'b', TELNET_IAC]
+        );
+
+        // Commands split across reads
+        assert!(input.filter(&[TELNET_IAC]).is_empty());
+        assert_eq!(
+            input.filter(&[TELNET_SB, 31, 0, 80, TELNET_IAC, TELNET_SE, b'\r', 0, b'c']),
+            vec![b'\r', b'c']
+        );
+    }
+
+    #[test]
+    fn test_telnet_writer() {
+        let mut writer = TelnetWriter(Vec::new());
+        assert_eq!(writer.write(&[b'a', TELNET_IAC]).unwrap(), 2);
+        assert_eq!(writer.0, vec![b'a', TELNET_IAC, TELNET_IAC]);
+    }
+}
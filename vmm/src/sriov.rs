@@ -0,0 +1,273 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host side setup of the SR-IOV virtual functions (VFs).
+//!
+//! The VFs of a physical function (PF) are created by writing their number to
+//! the `sriov_numvfs` file of the PF, each of them then being reachable from
+//! the `virtfn<index>` link of the PF. A VF is made available for passthrough
+//! by overriding its driver with vfio-pci, and asking the kernel to probe it
+//! again once unbound from its current driver. Releasing the VF undoes these
+//! steps, destroying the VFs as well if they were created for the VM.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const PCI_DEVICES: &str = "/sys/bus/pci/devices";
+const PCI_DRIVERS_PROBE: &str = "/sys/bus/pci/drivers_probe";
+const VFIO_PCI_DRIVER: &str = "vfio-pci";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Device {0} is not an SR-IOV physical function")]
+    NotPhysicalFunction(String),
+
+    #[error("Cannot read {0}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+
+    #[error("Cannot write to {0}: {1}")]
+    Write(PathBuf, #[source] io::Error),
+
+    #[error("Invalid content in {0}")]
+    InvalidContent(PathBuf),
+
+    #[error("Physical function {0} supports at most {1} VFs")]
+    TooManyVfs(String, u16),
+
+    #[error("Physical function {0} has no VF {1}")]
+    VfNotFound(String, u16),
+
+    #[error("Physical function {0} already has {1} VFs")]
+    NumVfsMismatch(String, u16),
+
+    #[error("Cannot bind VF {0} to vfio-pci")]
+    BindVfio(String),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A VF prepared for passthrough, remembering what was changed on the host so
+/// that it can be restored once the VF isn't assigned anymore.
+pub struct VirtualFunction {
+    pf_path: PathBuf,
+    path: PathBuf,
+    address: String,
+    // Whether the VF was bound to vfio-pci by the VMM
+    rebound: bool,
+    // Whether the VFs of the PF were created by the VMM
+    created_vfs: bool,
+}
+
+impl VirtualFunction {
+    /// Sysfs path of the VF.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Give the VF back to its host driver, destroying the VFs of the PF if
+    /// they were created for this VF and none of them is bound to vfio-pci
+    /// anymore. The unbinding from vfio-pci waits for the VFIO device to be
+    /// closed.
+    pub fn release(self) -> Result<()> {
+        if self.rebound {
+            write(&self.path.join("driver_override"), "\n")?;
+            if driver(&self.path).is_some() {
+                write(&self.path.join("driver/unbind"), &self.address)?;
+            }
+        }
+
+        if self.created_vfs && !vfs_in_use(&self.pf_path)? {
+            info!("Removing the VFs of {}", self.pf_path.display());
+            write(&self.pf_path.join("sriov_numvfs"), "0")
+        } else if self.rebound {
+            write(Path::new(PCI_DRIVERS_PROBE), &self.address)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Bind the VF to vfio-pci, unless it is already
+    fn bind(&mut self, vf: u16) -> Result<()> {
+        self.address = vf_address(&self.pf_path, vf)?;
+        self.path = Path::new(PCI_DEVICES).join(&self.address);
+        if driver(&self.path).as_deref() == Some(VFIO_PCI_DRIVER) {
+            return Ok(());
+        }
+
+        self.rebound = true;
+        write(&self.path.join("driver_override"), VFIO_PCI_DRIVER)?;
+        if driver(&self.path).is_some() {
+            write(&self.path.join("driver/unbind"), &self.address)?;
+        }
+        write(Path::new(PCI_DRIVERS_PROBE), &self.address)?;
+
+        if driver(&self.path).as_deref() != Some(VFIO_PCI_DRIVER) {
+            return Err(Error::BindVfio(self.address.clone()));
+        }
+        info!(
+            "Bound VF {} of {} ({}) to vfio-pci",
+            vf,
+            self.pf_path.display(),
+            self.address
+        );
+
+        Ok(())
+    }
+}
+
+/// Create the VFs of a PF if it doesn't have any yet, and bind one of them to
+/// vfio-pci.
+pub fn prepare_vf(pf: &str, vf: u16, num_vfs: Option<u16>) -> Result<VirtualFunction> {
+    let pf_path = Path::new(PCI_DEVICES).join(pf);
+    let total_vfs_path = pf_path.join("sriov_totalvfs");
+    if !total_vfs_path.exists() {
+        return Err(Error::NotPhysicalFunction(pf.to_string()));
+    }
+    let total_vfs = read_u16(&total_vfs_path)?;
+
+    // The number of VFs can't be changed without destroying the existing
+    // ones, which may be assigned to some VMs already.
+    let num_vfs_path = pf_path.join("sriov_numvfs");
+    let mut current_vfs = read_u16(&num_vfs_path)?;
+    let mut created_vfs = false;
+    if current_vfs == 0 {
+        let num_vfs = num_vfs.unwrap_or(vf + 1);
+        if num_vfs > total_vfs {
+            return Err(Error::TooManyVfs(pf.to_string(), total_vfs));
+        }
+        if vf < num_vfs {
+            info!("Creating {} VFs on {}", num_vfs, pf);
+            write(&num_vfs_path, &num_vfs.to_string())?;
+            current_vfs = num_vfs;
+            created_vfs = true;
+        }
+    } else if num_vfs.map_or(false, |n| n != current_vfs) {
+        return Err(Error::NumVfsMismatch(pf.to_string(), current_vfs));
+    }
+
+    if vf >= current_vfs {
+        return Err(Error::VfNotFound(pf.to_string(), vf));
+    }
+
+    let mut virtual_function = VirtualFunction {
+        pf_path,
+        path: PathBuf::new(),
+        address: String::new(),
+        rebound: false,
+        created_vfs,
+    };
+    if let Err(e) = virtual_function.bind(vf) {
+        // Don't leave the VFs created above behind
+        if let Err(e) = virtual_function.release() {
+            warn!("Cannot release VF {} of {}: {}", vf, pf, e);
+        }
+        return Err(e);
+    }
+
+    Ok(virtual_function)
+}
+
+// Whether one of the VFs of a PF is bound to vfio-pci
+fn vfs_in_use(pf_path: &Path) -> Result<bool> {
+    let num_vfs = read_u16(&pf_path.join("sriov_numvfs"))?;
+    for vf in 0..num_vfs {
+        let vf_path = pf_path.join(format!("virtfn{vf}"));
+        if driver(&vf_path).as_deref() == Some(VFIO_PCI_DRIVER) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// PCI address of a VF, as found from the link of its PF
+fn vf_address(pf_path: &Path, vf: u16) -> Result<String> {
+    let link = pf_path.join(format!("virtfn{vf}"));
+    let target = fs::read_link(&link).map_err(|e| Error::Read(link.clone(), e))?;
+    target
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map(|f| f.to_string())
+        .ok_or(Error::InvalidContent(link))
+}
+
+// Name of the driver a device is bound to
+fn driver(device_path: &Path) -> Option<String> {
+    fs::read_link(device_path.join("driver"))
+        .ok()
+        .and_then(|d| d.file_name().and_then(|f| f.to_str()).map(String::from))
+}
+
+fn read_u16(path: &Path) -> Result<u16> {
+    fs::read_to_string(path)
+        .map_err(|e| Error::Read(path.to_path_buf(), e))?
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidContent(path.to_path_buf()))
+}
+
+fn write(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| Error::Write(path.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_vf_address() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let pf_path = dir.as_path().join("0000:3b:00.0");
+        let vf_path = dir.as_path().join("0000:3b:02.1");
+        fs::create_dir(&pf_path).unwrap();
+        fs::create_dir(&vf_path).unwrap();
+        symlink("../0000:3b:02.1", pf_path.join("virtfn1")).unwrap();
+        symlink("../../bus/pci/drivers/ixgbevf", vf_path.join("driver")).unwrap();
+
+        assert_eq!(vf_address(&pf_path, 1).unwrap(), "0000:3b:02.1");
+        assert!(vf_address(&pf_path, 2).is_err());
+        assert_eq!(driver(&vf_path).as_deref(), Some("ixgbevf"));
+        assert_eq!(driver(&pf_path), None);
+    }
+
+    #[test]
+    fn test_release() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let pf_path = dir.as_path().join("0000:3b:00.0");
+        fs::create_dir(&pf_path).unwrap();
+        for (vf, address) in ["0000:3b:02.0", "0000:3b:02.1"].iter().enumerate() {
+            fs::create_dir(dir.as_path().join(address)).unwrap();
+            symlink(format!("../{address}"), pf_path.join(format!("virtfn{vf}"))).unwrap();
+        }
+        fs::write(pf_path.join("sriov_numvfs"), "2").unwrap();
+        let virtual_function = |created_vfs| VirtualFunction {
+            pf_path: pf_path.clone(),
+            path: dir.as_path().join("0000:3b:02.0"),
+            address: "0000:3b:02.0".to_string(),
+            rebound: false,
+            created_vfs,
+        };
+
+        // The VFs are kept as long as one of them is bound to vfio-pci
+        symlink(
+            "../../bus/pci/drivers/vfio-pci",
+            dir.as_path().join("0000:3b:02.1/driver"),
+        )
+        .unwrap();
+        assert!(vfs_in_use(&pf_path).unwrap());
+        virtual_function(true).release().unwrap();
+        assert_eq!(read_u16(&pf_path.join("sriov_numvfs")).unwrap(), 2);
+
+        // Only the VFs created for the VM are destroyed
+        fs::remove_file(dir.as_path().join("0000:3b:02.1/driver")).unwrap();
+        assert!(!vfs_in_use(&pf_path).unwrap());
+        virtual_function(false).release().unwrap();
+        assert_eq!(read_u16(&pf_path.join("sriov_numvfs")).unwrap(), 2);
+        virtual_function(true).release().unwrap();
+        assert_eq!(read_u16(&pf_path.join("sriov_numvfs")).unwrap(), 0);
+    }
+}
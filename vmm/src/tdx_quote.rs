@@ -0,0 +1,412 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Forwarding of the TDX GetQuote requests to the host.
+//!
+//! A TD obtains a quote by issuing a TDG.VP.VMCALL<GetQuote>, passing the
+//! address and the size of a buffer shared with the VMM. The buffer starts
+//! with the following header, and is followed by the message intended to the
+//! Quote Generation Service (QGS) running on the host:
+//!
+//! - version (u64): always 1
+//! - status (u64): outcome of the request, filled by the VMM
+//! - in_len (u32): length of the message sent by the TD
+//! - out_len (u32): length of the response, filled by the VMM
+//!
+//! The message is forwarded as is to the QGS through its Unix socket, prefixed
+//! with its length as a 32 bits big endian integer. The response uses the same
+//! framing, and its payload is copied back into the buffer, right after the
+//! header.
+//!
+//! The request is completed asynchronously: the vCPU only validates the
+//! buffer and marks it as in flight, leaving the exchange with the QGS to a
+//! dedicated thread, which updates the status of the buffer once done. The TD
+//! polls on that status meanwhile.
+
+use hypervisor::kvm::TdxExitStatus;
+use hypervisor::{HypervisorVmError, VmOps};
+use seccompiler::{apply_filter, BpfProgram};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+const GET_QUOTE_HEADER_VERSION: u64 = 1;
+const GET_QUOTE_HEADER_SIZE: usize = 24;
+const GET_QUOTE_STATUS_OFFSET: u64 = 8;
+const GET_QUOTE_OUT_LEN_OFFSET: u64 = 20;
+
+const GET_QUOTE_SUCCESS: u64 = 0;
+const GET_QUOTE_IN_FLIGHT: u64 = 0xffff_ffff_ffff_ffff;
+const GET_QUOTE_ERROR: u64 = 0x8000_0000_0000_0000;
+const GET_QUOTE_SERVICE_UNAVAILABLE: u64 = 0x8000_0000_0000_0001;
+
+// Time given to the QGS to produce a quote.
+const QGS_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot access the GetQuote buffer: {0}")]
+    GuestMemory(#[source] HypervisorVmError),
+
+    #[error("Invalid GetQuote buffer")]
+    InvalidBuffer,
+
+    #[error("Cannot connect to the Quote Generation Service: {0}")]
+    Connect(#[source] io::Error),
+
+    #[error("Cannot communicate with the Quote Generation Service: {0}")]
+    Transfer(#[source] io::Error),
+
+    #[error("Quote Generation Service response doesn't fit the GetQuote buffer")]
+    ResponseTooLarge,
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+// GetQuote request validated by the vCPU, left to the QGS thread.
+struct QuoteRequest {
+    gpa: u64,
+    max_len: usize,
+    message: Vec<u8>,
+}
+
+pub struct QuoteGenerationService {
+    // Bit identifying a shared GPA, which must be cleared to find the
+    // corresponding guest memory.
+    shared_mask: u64,
+    vm_ops: Arc<dyn VmOps>,
+    // Closing the channel stops the QGS thread.
+    requests: Mutex<Sender<QuoteRequest>>,
+}
+
+impl QuoteGenerationService {
+    pub fn new(
+        socket: &Path,
+        phys_bits: u8,
+        vm_ops: Arc<dyn VmOps>,
+        seccomp_filter: BpfProgram,
+    ) -> io::Result<Self> {
+        // The shared bit is the top bit of the guest physical address width,
+        // which is either 48 or 52 bits.
+        let gpaw = if phys_bits > 48 { 52 } else { 48 };
+
+        let (sender, receiver) = channel();
+        let worker = QuoteWorker {
+            socket: socket.to_path_buf(),
+            vm_ops: vm_ops.clone(),
+        };
+        thread::Builder::new()
+            .name("tdx_quote".to_string())
+            .spawn(move || {
+                if !seccomp_filter.is_empty() {
+                    if let Err(e) = apply_filter(&seccomp_filter) {
+                        error!("Error applying seccomp filter: {:?}", e);
+                        return;
+                    }
+                }
+                worker.run(receiver);
+            })?;
+
+        Ok(QuoteGenerationService {
+            shared_mask: 1 << (gpaw - 1),
+            vm_ops,
+            requests: Mutex::new(sender),
+        })
+    }
+
+    /// Handle a GetQuote request. Failures to reach the QGS are reported to
+    /// the TD through the status field of the buffer, while an invalid buffer
+    /// makes the TDVMCALL itself fail.
+    pub fn get_quote(&self, gpa: u64, size: u64) -> TdxExitStatus {
+        match self.queue_request(gpa & !self.shared_mask, size) {
+            Ok(()) => TdxExitStatus::Success,
+            Err(e) => {
+                error!("Error handling TDX GetQuote request: {}", e);
+                TdxExitStatus::InvalidOperand
+            }
+        }
+    }
+
+    fn queue_request(&self, gpa: u64, size: u64) -> Result<()> {
+        let mut header = [0u8; GET_QUOTE_HEADER_SIZE];
+        read_guest(&self.vm_ops, gpa, &mut header)?;
+
+        let version = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let in_len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+        let max_len = (size as usize)
+            .checked_sub(GET_QUOTE_HEADER_SIZE)
+            .ok_or(Error::InvalidBuffer)?;
+        if version != GET_QUOTE_HEADER_VERSION || in_len > max_len {
+            return Err(Error::InvalidBuffer);
+        }
+
+        let mut message = vec![0u8; in_len];
+        read_guest(
+            &self.vm_ops,
+            gpa + GET_QUOTE_HEADER_SIZE as u64,
+            &mut message,
+        )?;
+
+        write_guest(
+            &self.vm_ops,
+            gpa + GET_QUOTE_STATUS_OFFSET,
+            &GET_QUOTE_IN_FLIGHT.to_le_bytes(),
+        )?;
+        let request = QuoteRequest {
+            gpa,
+            max_len,
+            message,
+        };
+        if self.requests.lock().unwrap().send(request).is_err() {
+            warn!("TDX quote generation thread is gone");
+            write_guest(
+                &self.vm_ops,
+                gpa + GET_QUOTE_STATUS_OFFSET,
+                &GET_QUOTE_SERVICE_UNAVAILABLE.to_le_bytes(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+struct QuoteWorker {
+    socket: PathBuf,
+    vm_ops: Arc<dyn VmOps>,
+}
+
+impl QuoteWorker {
+    fn run(&self, receiver: Receiver<QuoteRequest>) {
+        while let Ok(request) = receiver.recv() {
+            if let Err(e) = self.complete_request(&request) {
+                error!("Error completing TDX GetQuote request: {}", e);
+            }
+        }
+    }
+
+    fn complete_request(&self, request: &QuoteRequest) -> Result<()> {
+        let (status, response) = match self.request(&request.message, request.max_len) {
+            Ok(response) => (GET_QUOTE_SUCCESS, response),
+            Err(e @ Error::Connect(_)) => {
+                warn!("{}", e);
+                (GET_QUOTE_SERVICE_UNAVAILABLE, Vec::new())
+            }
+            Err(e) => {
+                warn!("{}", e);
+                (GET_QUOTE_ERROR, Vec::new())
+            }
+        };
+
+        let gpa = request.gpa;
+        write_guest(&self.vm_ops, gpa + GET_QUOTE_HEADER_SIZE as u64, &response)?;
+        write_guest(
+            &self.vm_ops,
+            gpa + GET_QUOTE_OUT_LEN_OFFSET,
+            &(response.len() as u32).to_le_bytes(),
+        )?;
+        // The status is updated last as the TD is polling on it.
+        write_guest(
+            &self.vm_ops,
+            gpa + GET_QUOTE_STATUS_OFFSET,
+            &status.to_le_bytes(),
+        )
+    }
+
+    fn request(&self, message: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.socket).map_err(Error::Connect)?;
+        stream
+            .set_read_timeout(Some(QGS_TIMEOUT))
+            .map_err(Error::Transfer)?;
+        stream
+            .set_write_timeout(Some(QGS_TIMEOUT))
+            .map_err(Error::Transfer)?;
+
+        stream
+            .write_all(&(message.len() as u32).to_be_bytes())
+            .map_err(Error::Transfer)?;
+        stream.write_all(message).map_err(Error::Transfer)?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).map_err(Error::Transfer)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > max_len {
+            return Err(Error::ResponseTooLarge);
+        }
+
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).map_err(Error::Transfer)?;
+
+        Ok(response)
+    }
+}
+
+fn read_guest(vm_ops: &Arc<dyn VmOps>, gpa: u64, buf: &mut [u8]) -> Result<()> {
+    let len = vm_ops
+        .guest_mem_read(gpa, buf)
+        .map_err(Error::GuestMemory)?;
+    if len != buf.len() {
+        return Err(Error::InvalidBuffer);
+    }
+
+    Ok(())
+}
+
+fn write_guest(vm_ops: &Arc<dyn VmOps>, gpa: u64, buf: &[u8]) -> Result<()> {
+    let len = vm_ops
+        .guest_mem_write(gpa, buf)
+        .map_err(Error::GuestMemory)?;
+    if len != buf.len() {
+        return Err(Error::InvalidBuffer);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use vmm_sys_util::tempdir::TempDir;
+
+    const BUFFER_GPA: u64 = 0x1000;
+    const BUFFER_SIZE: usize = 0x100;
+
+    struct TestVmOps {
+        buffer: Mutex<Vec<u8>>,
+    }
+
+    impl VmOps for TestVmOps {
+        fn guest_mem_write(
+            &self,
+            gpa: u64,
+            buf: &[u8],
+        ) -> std::result::Result<usize, HypervisorVmError> {
+            let offset = (gpa - BUFFER_GPA) as usize;
+            self.buffer.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn guest_mem_read(
+            &self,
+            gpa: u64,
+            buf: &mut [u8],
+        ) -> std::result::Result<usize, HypervisorVmError> {
+            let offset = (gpa - BUFFER_GPA) as usize;
+            buf.copy_from_slice(&self.buffer.lock().unwrap()[offset..offset + buf.len()]);
+            Ok(buf.len())
+        }
+
+        fn mmio_read(
+            &self,
+            _gpa: u64,
+            _data: &mut [u8],
+        ) -> std::result::Result<(), HypervisorVmError> {
+            unimplemented!()
+        }
+
+        fn mmio_write(
+            &self,
+            _gpa: u64,
+            _data: &[u8],
+        ) -> std::result::Result<(), HypervisorVmError> {
+            unimplemented!()
+        }
+
+        fn pio_read(
+            &self,
+            _port: u64,
+            _data: &mut [u8],
+        ) -> std::result::Result<(), HypervisorVmError> {
+            unimplemented!()
+        }
+
+        fn pio_write(
+            &self,
+            _port: u64,
+            _data: &[u8],
+        ) -> std::result::Result<(), HypervisorVmError> {
+            unimplemented!()
+        }
+    }
+
+    fn get_quote_buffer(message: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; BUFFER_SIZE];
+        buffer[0..8].copy_from_slice(&GET_QUOTE_HEADER_VERSION.to_le_bytes());
+        buffer[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        buffer[16..20].copy_from_slice(&(message.len() as u32).to_le_bytes());
+        buffer[GET_QUOTE_HEADER_SIZE..GET_QUOTE_HEADER_SIZE + message.len()]
+            .copy_from_slice(message);
+        buffer
+    }
+
+    // Wait for the QGS thread to complete the request.
+    fn wait_status(vm_ops: &TestVmOps) -> Vec<u8> {
+        for _ in 0..1000 {
+            let buffer = vm_ops.buffer.lock().unwrap().clone();
+            if buffer[8..16] != GET_QUOTE_IN_FLIGHT.to_le_bytes() {
+                return buffer;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("GetQuote request never completed");
+    }
+
+    #[test]
+    fn test_tdx_get_quote() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let socket = dir.as_path().join("qgs");
+        let vm_ops = Arc::new(TestVmOps {
+            buffer: Mutex::new(get_quote_buffer(b"report")),
+        });
+        let qgs =
+            QuoteGenerationService::new(&socket, 48, vm_ops.clone(), BpfProgram::new()).unwrap();
+
+        // Without any QGS listening, the TD is told the service is unavailable.
+        assert!(matches!(
+            qgs.get_quote(BUFFER_GPA | (1 << 47), BUFFER_SIZE as u64),
+            TdxExitStatus::Success
+        ));
+        assert_eq!(
+            wait_status(&vm_ops)[8..16],
+            GET_QUOTE_SERVICE_UNAVAILABLE.to_le_bytes()
+        );
+
+        let listener = UnixListener::bind(&socket).unwrap();
+        let qgs_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).unwrap();
+            let mut message = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut message).unwrap();
+            stream.write_all(&5u32.to_be_bytes()).unwrap();
+            stream.write_all(b"quote").unwrap();
+            message
+        });
+
+        *vm_ops.buffer.lock().unwrap() = get_quote_buffer(b"report");
+        assert!(matches!(
+            qgs.get_quote(BUFFER_GPA | (1 << 47), BUFFER_SIZE as u64),
+            TdxExitStatus::Success
+        ));
+        let buffer = wait_status(&vm_ops);
+        assert_eq!(qgs_thread.join().unwrap(), b"report");
+        assert_eq!(buffer[8..16], GET_QUOTE_SUCCESS.to_le_bytes());
+        assert_eq!(buffer[20..24], 5u32.to_le_bytes());
+        assert_eq!(&buffer[24..29], b"quote");
+
+        // A message larger than the buffer is rejected.
+        let mut buffer = get_quote_buffer(b"report");
+        buffer[16..20].copy_from_slice(&(BUFFER_SIZE as u32).to_le_bytes());
+        *vm_ops.buffer.lock().unwrap() = buffer;
+        assert!(matches!(
+            qgs.get_quote(BUFFER_GPA, BUFFER_SIZE as u64),
+            TdxExitStatus::InvalidOperand
+        ));
+    }
+}
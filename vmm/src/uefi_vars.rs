@@ -0,0 +1,263 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Creation of a UEFI variable store with the Secure Boot keys enrolled.
+//!
+//! The store is laid out as the one of the EDK II 4 MiB builds: a firmware
+//! volume holding the authenticated variables, followed by the event log and
+//! the fault tolerant write areas. The PK, KEK and db variables are added as
+//! the firmware stores them once enrolled, EDK II leaving the setup mode and
+//! enabling Secure Boot as soon as it finds a PK.
+
+use crate::vm_config::UefiVarsConfig;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read the certificate {0}: {1}")]
+    ReadCertificate(PathBuf, #[source] io::Error),
+
+    #[error("Certificate {0} isn't DER encoded")]
+    InvalidCertificate(PathBuf),
+
+    #[error("Secure Boot keys too large for the UEFI variable store")]
+    StoreFull,
+
+    #[error("Cannot write the UEFI variable store: {0}")]
+    WriteStore(#[source] io::Error),
+}
+
+/// Size of a new variable store.
+pub const VAR_STORE_SIZE: u64 = 0x84000;
+
+const BLOCK_SIZE: u32 = 0x1000;
+const ERASED: u8 = 0xff;
+
+// Layout of the store
+const VARIABLES_SIZE: usize = 0x40000;
+const FTW_WORKING_OFFSET: usize = 0x41000;
+
+// EFI_FIRMWARE_VOLUME_HEADER, with its two entries block map
+const FV_HEADER_SIZE: usize = 0x48;
+const FV_ATTRIBUTES: u32 = 0x0004_feff;
+const FV_REVISION: u8 = 2;
+
+// VARIABLE_STORE_HEADER
+const VAR_STORE_HEADER_SIZE: usize = 0x1c;
+const VAR_STORE_FORMATTED: u8 = 0x5a;
+const VAR_STORE_HEALTHY: u8 = 0xfe;
+
+// AUTHENTICATED_VARIABLE_HEADER
+const VAR_HEADER_SIZE: usize = 60;
+const VAR_START_ID: u16 = 0x55aa;
+const VAR_ADDED: u8 = 0x3f;
+const VAR_ALIGNMENT: usize = 4;
+
+// EFI_VARIABLE_NON_VOLATILE | EFI_VARIABLE_BOOTSERVICE_ACCESS |
+// EFI_VARIABLE_RUNTIME_ACCESS |
+// EFI_VARIABLE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS
+const AUTHENTICATED_ATTRIBUTES: u32 = 0x27;
+
+// EFI_SIGNATURE_LIST and EFI_SIGNATURE_DATA headers
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+const SIGNATURE_DATA_HEADER_SIZE: usize = 16;
+
+// EFI_FAULT_TOLERANT_WORKING_BLOCK_HEADER of a valid and empty working block,
+// as found in the EDK II variable store template.
+const FTW_WORKING_HEADER: [u8; 32] = [
+    0x2b, 0x29, 0x58, 0x9e, 0x68, 0x7c, 0x7d, 0x49, 0xa0, 0xce, 0x65, 0x00, 0xfd, 0x9f, 0x1b, 0x95,
+    0x2c, 0xaf, 0x2c, 0x64, 0xfe, 0xff, 0xff, 0xff, 0xe0, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+const EFI_SYSTEM_NV_DATA_FV_GUID: Uuid = Uuid::from_u128(0xfff12b8d_7696_4c8b_a985_2747075b4f50);
+const EFI_AUTHENTICATED_VARIABLE_GUID: Uuid =
+    Uuid::from_u128(0xaaf32c78_947b_439a_a180_2e144ec37792);
+const EFI_GLOBAL_VARIABLE_GUID: Uuid = Uuid::from_u128(0x8be4df61_93ca_11d2_aa0d_00e098032b8c);
+const EFI_IMAGE_SECURITY_DATABASE_GUID: Uuid =
+    Uuid::from_u128(0xd719b2cb_3d3a_4596_a3bc_dad00e67656f);
+const EFI_CERT_X509_GUID: Uuid = Uuid::from_u128(0xa5c059a1_94e4_4aa7_87b5_ab155c2bf072);
+
+// Owner recorded along with the enrolled certificates
+const SIGNATURE_OWNER_GUID: Uuid = Uuid::from_u128(0x5c5e3f1a_5d9b_4c57_9a3e_3c6d6f1b7e21);
+
+/// Whether the configuration has Secure Boot keys to enroll.
+pub fn has_keys(config: &UefiVarsConfig) -> bool {
+    config.pk.is_some() || config.kek.is_some() || config.db.is_some()
+}
+
+/// Write a new variable store to `file`, with the Secure Boot keys of the
+/// configuration enrolled.
+pub fn create_var_store(file: &File, config: &UefiVarsConfig) -> Result<(), Error> {
+    let mut store = vec![ERASED; VAR_STORE_SIZE as usize];
+    write_headers(&mut store);
+
+    let pk = config.pk.as_ref().map_or(&[][..], std::slice::from_ref);
+    let variables = [
+        ("PK", EFI_GLOBAL_VARIABLE_GUID, pk),
+        (
+            "KEK",
+            EFI_GLOBAL_VARIABLE_GUID,
+            config.kek.as_deref().unwrap_or_default(),
+        ),
+        (
+            "db",
+            EFI_IMAGE_SECURITY_DATABASE_GUID,
+            config.db.as_deref().unwrap_or_default(),
+        ),
+    ];
+    let mut offset = FV_HEADER_SIZE + VAR_STORE_HEADER_SIZE;
+    for (name, vendor, certs) in variables {
+        if !certs.is_empty() {
+            let data = signature_lists(certs)?;
+            offset = add_variable(&mut store, offset, name, vendor, &data)?;
+        }
+    }
+
+    file.write_all_at(&store, 0).map_err(Error::WriteStore)
+}
+
+fn write_headers(store: &mut [u8]) {
+    let fv_header = &mut store[..FV_HEADER_SIZE];
+    fv_header.fill(0);
+    fv_header[16..32].copy_from_slice(&EFI_SYSTEM_NV_DATA_FV_GUID.to_bytes_le());
+    fv_header[32..40].copy_from_slice(&VAR_STORE_SIZE.to_le_bytes());
+    fv_header[40..44].copy_from_slice(b"_FVH");
+    fv_header[44..48].copy_from_slice(&FV_ATTRIBUTES.to_le_bytes());
+    fv_header[48..50].copy_from_slice(&(FV_HEADER_SIZE as u16).to_le_bytes());
+    fv_header[55] = FV_REVISION;
+    fv_header[56..60].copy_from_slice(&(VAR_STORE_SIZE as u32 / BLOCK_SIZE).to_le_bytes());
+    fv_header[60..64].copy_from_slice(&BLOCK_SIZE.to_le_bytes());
+    // The 16 bits words of the header sum to zero
+    let sum = fv_header.chunks(2).fold(0u16, |sum, w| {
+        sum.wrapping_add(u16::from_le_bytes([w[0], w[1]]))
+    });
+    fv_header[50..52].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+
+    let var_store_header = &mut store[FV_HEADER_SIZE..FV_HEADER_SIZE + VAR_STORE_HEADER_SIZE];
+    var_store_header.fill(0);
+    var_store_header[..16].copy_from_slice(&EFI_AUTHENTICATED_VARIABLE_GUID.to_bytes_le());
+    var_store_header[16..20]
+        .copy_from_slice(&((VARIABLES_SIZE - FV_HEADER_SIZE) as u32).to_le_bytes());
+    var_store_header[20] = VAR_STORE_FORMATTED;
+    var_store_header[21] = VAR_STORE_HEALTHY;
+
+    store[FTW_WORKING_OFFSET..FTW_WORKING_OFFSET + FTW_WORKING_HEADER.len()]
+        .copy_from_slice(&FTW_WORKING_HEADER);
+}
+
+// One signature list per certificate, the signatures of a list having the
+// same size.
+fn signature_lists(certs: &[PathBuf]) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    for path in certs {
+        let cert = fs::read(path).map_err(|e| Error::ReadCertificate(path.clone(), e))?;
+        // A DER encoded certificate is an ASN.1 sequence
+        if cert.first() != Some(&0x30) {
+            return Err(Error::InvalidCertificate(path.clone()));
+        }
+
+        let signature_size = SIGNATURE_DATA_HEADER_SIZE + cert.len();
+        data.extend_from_slice(&EFI_CERT_X509_GUID.to_bytes_le());
+        data.extend_from_slice(
+            &((SIGNATURE_LIST_HEADER_SIZE + signature_size) as u32).to_le_bytes(),
+        );
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        data.extend_from_slice(&SIGNATURE_OWNER_GUID.to_bytes_le());
+        data.extend_from_slice(&cert);
+    }
+
+    Ok(data)
+}
+
+// Add the variable at `offset`, returning the offset of the next one.
+fn add_variable(
+    store: &mut [u8],
+    offset: usize,
+    name: &str,
+    vendor: Uuid,
+    data: &[u8],
+) -> Result<usize, Error> {
+    let name: Vec<u8> = name
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    let name_offset = offset + VAR_HEADER_SIZE;
+    let data_offset = name_offset + name.len();
+    let end = data_offset + data.len();
+    if end > VARIABLES_SIZE {
+        return Err(Error::StoreFull);
+    }
+
+    // The monotonic count, timestamp and public key index are left zero
+    let header = &mut store[offset..name_offset];
+    header.fill(0);
+    header[..2].copy_from_slice(&VAR_START_ID.to_le_bytes());
+    header[2] = VAR_ADDED;
+    header[4..8].copy_from_slice(&AUTHENTICATED_ATTRIBUTES.to_le_bytes());
+    header[36..40].copy_from_slice(&(name.len() as u32).to_le_bytes());
+    header[40..44].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    header[44..60].copy_from_slice(&vendor.to_bytes_le());
+    store[name_offset..data_offset].copy_from_slice(&name);
+    store[data_offset..end].copy_from_slice(data);
+
+    Ok((end + VAR_ALIGNMENT - 1) & !(VAR_ALIGNMENT - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_create_var_store() {
+        let pk = TempFile::new().unwrap();
+        pk.as_file().write_all_at(&[0x30, 0x82, 0x01], 0).unwrap();
+        let config = UefiVarsConfig {
+            pk: Some(pk.as_path().to_path_buf()),
+            ..Default::default()
+        };
+        assert!(has_keys(&config));
+
+        let vars = TempFile::new().unwrap();
+        create_var_store(vars.as_file(), &config).unwrap();
+        let mut store = vec![0; VAR_STORE_SIZE as usize + 1];
+        assert_eq!(
+            vars.as_file().read_at(&mut store, 0).unwrap(),
+            VAR_STORE_SIZE as usize
+        );
+
+        let fv_sum = store[..FV_HEADER_SIZE].chunks(2).fold(0u16, |sum, w| {
+            sum.wrapping_add(u16::from_le_bytes([w[0], w[1]]))
+        });
+        assert_eq!(fv_sum, 0);
+        assert_eq!(&store[40..44], b"_FVH");
+
+        // PK, with a single signature list holding the certificate
+        let var = &store[FV_HEADER_SIZE + VAR_STORE_HEADER_SIZE..];
+        assert_eq!(&var[..4], &[0xaa, 0x55, VAR_ADDED, 0]);
+        assert_eq!(u32::from_le_bytes(var[36..40].try_into().unwrap()), 6);
+        assert_eq!(u32::from_le_bytes(var[40..44].try_into().unwrap()), 47);
+        assert_eq!(&var[60..66], &[b'P', 0, b'K', 0, 0, 0]);
+        assert_eq!(&var[66 + 44..66 + 47], &[0x30, 0x82, 0x01]);
+        // Nothing following it
+        assert_eq!(&var[116..118], &[ERASED, ERASED]);
+
+        // Certificates must be DER encoded
+        pk.as_file()
+            .write_all_at(b"-----BEGIN CERTIFICATE-----", 0)
+            .unwrap();
+        assert!(matches!(
+            create_var_store(vars.as_file(), &config),
+            Err(Error::InvalidCertificate(_))
+        ));
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright © 2023 Cloud Hypervisor Authors
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! virtio-fs daemons spawned by the VMM.
+//!
+//! A virtio-fs device sharing a directory, rather than connecting to a daemon
+//! started beforehand, gets its own virtiofsd spawned when the device is
+//! created, be it when the VM boots, is restored or received from a
+//! migration, or when the device is added at runtime. The daemon listens on
+//! the socket of the device, and confines itself to the shared directory
+//! through its own sandbox, by default moving into new namespaces where the
+//! shared directory is the root.
+//!
+//! The daemons would inherit the jail and the seccomp filters of the VMM,
+//! hence them being spawned by a launcher process forked when the VMM starts,
+//! before it restricts itself, as the hooks are. The VMM sends its requests
+//! over a socket pair, and waits for the daemon to listen on its socket.
+//!
+//! virtiofsd exits once its frontend disconnects, as it does when the VM
+//! reboots or the device reconnects, so the launcher spawns the daemon again
+//! until the last device using it is gone. The daemons are killed along with
+//! the launcher, which exits once the VMM is gone.
+
+use crate::vm_config::FsConfig;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+static LAUNCHER: OnceCell<Mutex<BufReader<UnixStream>>> = OnceCell::new();
+
+/// Daemon spawned for the virtio-fs devices sharing a directory, looked up
+/// from the `PATH`.
+pub const VIRTIOFSD_BINARY: &str = "virtiofsd";
+
+// Time given to a daemon to create its socket
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(5);
+const SOCKET_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Interval at which the launcher checks on its daemons, and minimal delay
+// between two spawns of the same daemon.
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(100);
+const RESPAWN_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot create the socket of the virtio-fs launcher: {0}")]
+    CreateSocket(#[source] io::Error),
+
+    #[error("Cannot fork the virtio-fs launcher: {0}")]
+    Fork(#[source] io::Error),
+
+    #[error("virtio-fs launcher already set")]
+    AlreadySet,
+
+    #[error("No virtio-fs launcher to spawn {VIRTIOFSD_BINARY} for {0:?}")]
+    NoLauncher(PathBuf),
+
+    #[error("Cannot reach the virtio-fs launcher: {0}")]
+    Launcher(#[source] io::Error),
+
+    #[error("Cannot spawn {VIRTIOFSD_BINARY} for {0:?}: {1}")]
+    Daemon(PathBuf, String),
+
+    #[error("Cannot spawn {VIRTIOFSD_BINARY}: {0}")]
+    Spawn(#[source] io::Error),
+
+    #[error("{VIRTIOFSD_BINARY} exited before creating its socket")]
+    Exited,
+
+    #[error("{VIRTIOFSD_BINARY} did not create its socket in time")]
+    Timeout,
+
+    #[error("Socket {0:?} already exists")]
+    SocketExists(PathBuf),
+
+    #[error("Socket {0:?} is used by the daemon sharing {1:?}")]
+    SocketInUse(PathBuf, PathBuf),
+}
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum LauncherRequest {
+    Start {
+        socket: PathBuf,
+        shared_dir: PathBuf,
+    },
+    Stop {
+        socket: PathBuf,
+    },
+}
+
+struct Daemon {
+    shared_dir: PathBuf,
+    child: Option<Child>,
+    spawned: Instant,
+    // Devices using the daemon, the one of a rebooted VM being created
+    // before the one of the previous VM is gone.
+    users: usize,
+}
+
+// Daemons run by the launcher, indexed by their socket
+struct Daemons {
+    binary: PathBuf,
+    respawn_delay: Duration,
+    daemons: HashMap<PathBuf, Daemon>,
+}
+
+impl Daemons {
+    fn new(binary: PathBuf, respawn_delay: Duration) -> Self {
+        Daemons {
+            binary,
+            respawn_delay,
+            daemons: HashMap::new(),
+        }
+    }
+
+    fn spawn(&self, socket: &Path, shared_dir: &Path) -> Result<Child> {
+        let mut command = Command::new(&self.binary);
+        command
+            .arg("--socket-path")
+            .arg(socket)
+            .arg("--shared-dir")
+            .arg(shared_dir)
+            .arg("--sandbox")
+            .arg("namespace")
+            .stdin(Stdio::null());
+        // SAFETY: prctl() is async-signal-safe, and only changes the signal
+        // the child receives once the launcher dies.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        command.spawn().map_err(Error::Spawn)
+    }
+
+    // Spawn the daemon listening on the given socket, or share the one
+    // already running.
+    fn start(&mut self, socket: &Path, shared_dir: &Path) -> Result<()> {
+        if let Some(daemon) = self.daemons.get_mut(socket) {
+            if daemon.shared_dir != shared_dir {
+                return Err(Error::SocketInUse(
+                    socket.to_path_buf(),
+                    daemon.shared_dir.clone(),
+                ));
+            }
+            daemon.users += 1;
+            return Ok(());
+        }
+
+        // Whatever is found at the path belongs to someone else, and is not
+        // for the launcher to remove.
+        if socket.symlink_metadata().is_ok() {
+            return Err(Error::SocketExists(socket.to_path_buf()));
+        }
+
+        let mut child = self.spawn(socket, shared_dir)?;
+        let start = Instant::now();
+        while !socket.exists() {
+            let failure = if !matches!(child.try_wait(), Ok(None)) {
+                Error::Exited
+            } else if start.elapsed() > SOCKET_TIMEOUT {
+                Error::Timeout
+            } else {
+                thread::sleep(SOCKET_POLL_INTERVAL);
+                continue;
+            };
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = std::fs::remove_file(socket);
+            return Err(failure);
+        }
+
+        info!(
+            "Spawned {:?} (pid {}) sharing {:?} on {:?}",
+            self.binary,
+            child.id(),
+            shared_dir,
+            socket
+        );
+
+        self.daemons.insert(
+            socket.to_path_buf(),
+            Daemon {
+                shared_dir: shared_dir.to_path_buf(),
+                child: Some(child),
+                spawned: start,
+                users: 1,
+            },
+        );
+
+        Ok(())
+    }
+
+    fn stop(&mut self, socket: &Path) {
+        let Some(daemon) = self.daemons.get_mut(socket) else {
+            return;
+        };
+        daemon.users -= 1;
+        if daemon.users > 0 {
+            return;
+        }
+
+        if let Some(mut child) = self.daemons.remove(socket).unwrap().child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = std::fs::remove_file(socket);
+    }
+
+    // Spawn again the daemons which exited, their frontend having
+    // disconnected.
+    fn supervise(&mut self) {
+        let mut respawns = Vec::new();
+        for (socket, daemon) in self.daemons.iter_mut() {
+            if let Some(child) = &mut daemon.child {
+                if matches!(child.try_wait(), Ok(None)) {
+                    continue;
+                }
+                daemon.child = None;
+                // The socket was created by the daemon, which may have left
+                // it behind.
+                let _ = std::fs::remove_file(socket);
+            }
+            if daemon.spawned.elapsed() >= self.respawn_delay {
+                respawns.push((socket.clone(), daemon.shared_dir.clone()));
+            }
+        }
+
+        for (socket, shared_dir) in respawns {
+            let child = match self.spawn(&socket, &shared_dir) {
+                Ok(child) => {
+                    info!(
+                        "Spawned {:?} (pid {}) again on {:?}",
+                        self.binary,
+                        child.id(),
+                        socket
+                    );
+                    Some(child)
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            };
+            let daemon = self.daemons.get_mut(&socket).unwrap();
+            daemon.child = child;
+            daemon.spawned = Instant::now();
+        }
+    }
+}
+
+impl Drop for Daemons {
+    fn drop(&mut self) {
+        for (socket, daemon) in self.daemons.drain() {
+            if let Some(mut child) = daemon.child {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
+
+fn wait_readable(fd: RawFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: FFI call with a valid pollfd
+    unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) > 0 }
+}
+
+fn run_launcher(socket: UnixStream) {
+    let fd = socket.as_raw_fd();
+    let mut writer = match socket.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("Cannot clone the socket of the virtio-fs launcher: {}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(socket);
+    let mut daemons = Daemons::new(PathBuf::from(VIRTIOFSD_BINARY), RESPAWN_DELAY);
+
+    loop {
+        if reader.buffer().is_empty() && !wait_readable(fd, SUPERVISE_INTERVAL) {
+            daemons.supervise();
+            continue;
+        }
+
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+
+        let reply = match serde_json::from_str(&line) {
+            Ok(LauncherRequest::Start { socket, shared_dir }) => daemons
+                .start(&socket, &shared_dir)
+                .map_err(|e| e.to_string()),
+            Ok(LauncherRequest::Stop { socket }) => {
+                daemons.stop(&socket);
+                Ok(())
+            }
+            Err(e) => Err(format!("Invalid request: {e}")),
+        };
+        let mut reply = serde_json::to_vec(&reply).unwrap();
+        reply.push(b'\n');
+        if writer.write_all(&reply).is_err() {
+            break;
+        }
+    }
+}
+
+// Release the file descriptors inherited from the VMM, but the given one.
+// They are replaced by /dev/null rather than closed, as the logger may still
+// write into its file, which must not be mistaken for one opened later on.
+pub(crate) fn release_inherited_fds(keep: RawFd) {
+    let Ok(null) = std::fs::File::open("/dev/null") else {
+        return;
+    };
+    let fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds
+        .into_iter()
+        .filter(|fd| *fd > 2 && *fd != keep && *fd != null.as_raw_fd())
+    {
+        // SAFETY: FFI call with valid file descriptors, the ones replaced
+        // not being used by the launcher.
+        unsafe { libc::dup3(null.as_raw_fd(), fd, libc::O_CLOEXEC) };
+    }
+}
+
+/// Fork the process spawning the virtio-fs daemons. This must be called
+/// while the VMM is single threaded.
+pub fn start_virtiofs_launcher() -> Result<()> {
+    let (vmm_end, launcher_end) = UnixStream::pair().map_err(Error::CreateSocket)?;
+
+    // SAFETY: FFI call, the VMM being single threaded
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::Fork(io::Error::last_os_error())),
+        0 => {
+            drop(vmm_end);
+            release_inherited_fds(launcher_end.as_raw_fd());
+            // The daemons must not inherit the signals blocked by the VMM
+            // SAFETY: FFI calls with a valid signal set
+            unsafe {
+                let mut set: libc::sigset_t = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::pthread_sigmask(libc::SIG_SETMASK, &set, std::ptr::null_mut());
+            }
+            run_launcher(launcher_end);
+            std::process::exit(0);
+        }
+        pid => {
+            drop(launcher_end);
+            info!("Spawning the virtio-fs daemons from process {}", pid);
+
+            LAUNCHER
+                .set(Mutex::new(BufReader::new(vmm_end)))
+                .map_err(|_| Error::AlreadySet)
+        }
+    }
+}
+
+fn request(request: &LauncherRequest) -> Result<std::result::Result<(), String>> {
+    let launcher = LAUNCHER.get().ok_or_else(|| match request {
+        LauncherRequest::Start { shared_dir, .. } => Error::NoLauncher(shared_dir.clone()),
+        LauncherRequest::Stop { socket } => Error::NoLauncher(socket.clone()),
+    })?;
+    let mut launcher = launcher.lock().unwrap();
+
+    let mut line = serde_json::to_vec(request).map_err(|e| Error::Launcher(e.into()))?;
+    line.push(b'\n');
+    launcher
+        .get_mut()
+        .write_all(&line)
+        .map_err(Error::Launcher)?;
+
+    let mut reply = String::new();
+    if launcher.read_line(&mut reply).map_err(Error::Launcher)? == 0 {
+        return Err(Error::Launcher(io::Error::from(
+            io::ErrorKind::UnexpectedEof,
+        )));
+    }
+    serde_json::from_str(&reply).map_err(|e| Error::Launcher(e.into()))
+}
+
+/// Handle on the daemon of a virtio-fs device sharing a directory, which
+/// is stopped once the last device using it is gone.
+pub struct VirtiofsDaemon {
+    socket: PathBuf,
+}
+
+impl VirtiofsDaemon {
+    /// Spawn the daemon of a virtio-fs device sharing a directory, returning
+    /// once it is listening on the socket of the device.
+    pub fn spawn(fs: &FsConfig) -> Result<Option<Self>> {
+        let Some(shared_dir) = &fs.shared_dir else {
+            return Ok(None);
+        };
+
+        request(&LauncherRequest::Start {
+            socket: fs.socket.clone(),
+            shared_dir: shared_dir.clone(),
+        })?
+        .map_err(|e| Error::Daemon(shared_dir.clone(), e))?;
+
+        Ok(Some(VirtiofsDaemon {
+            socket: fs.socket.clone(),
+        }))
+    }
+}
+
+impl Drop for VirtiofsDaemon {
+    fn drop(&mut self) {
+        let stop = LauncherRequest::Stop {
+            socket: self.socket.clone(),
+        };
+        if let Err(e) = request(&stop) {
+            warn!(
+                "Cannot stop {} on {:?}: {}",
+                VIRTIOFSD_BINARY, self.socket, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use vmm_sys_util::tempdir::TempDir;
+
+    // Stand-in for virtiofsd, creating its socket and waiting to be killed
+    const FAKE_VIRTIOFSD: &str = "#!/bin/sh\ntouch \"$2\"\nexec sleep 60\n";
+
+    #[test]
+    fn test_spawn_virtiofs_daemon() {
+        let dir = TempDir::new_with_prefix("/tmp/ch").unwrap();
+        let binary = dir.as_path().join("virtiofsd");
+        std::fs::write(&binary, FAKE_VIRTIOFSD).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let socket = dir.as_path().join("fs.sock");
+        let shared_dir = dir.as_path().join("shared");
+        let other_dir = dir.as_path().join("other");
+
+        let mut daemons = Daemons::new(binary, Duration::ZERO);
+        daemons.start(&socket, &shared_dir).unwrap();
+        assert!(socket.exists());
+
+        // The daemon is shared by the devices using the same socket and
+        // directory.
+        daemons.start(&socket, &shared_dir).unwrap();
+        assert!(matches!(
+            daemons.start(&socket, &other_dir),
+            Err(Error::SocketInUse(_, _))
+        ));
+        assert_eq!(daemons.daemons[&socket].users, 2);
+
+        // A daemon exiting on the disconnection of its frontend is spawned
+        // again.
+        let pid = daemons.daemons[&socket].child.as_ref().unwrap().id();
+        let child = daemons.daemons.get_mut(&socket).unwrap();
+        child.child.as_mut().unwrap().kill().unwrap();
+        child.child.as_mut().unwrap().wait().unwrap();
+        daemons.supervise();
+        let respawned = daemons.daemons[&socket].child.as_ref().unwrap().id();
+        assert_ne!(pid, respawned);
+
+        // The daemon runs until the last device using it is gone.
+        daemons.stop(&socket);
+        assert!(daemons.daemons.contains_key(&socket));
+        daemons.stop(&socket);
+        assert!(daemons.daemons.is_empty());
+        assert!(!socket.exists());
+
+        // A file found at the socket path is left alone.
+        std::fs::write(&socket, b"").unwrap();
+        assert!(matches!(
+            daemons.start(&socket, &shared_dir),
+            Err(Error::SocketExists(_))
+        ));
+        assert!(socket.exists());
+    }
+}
@@ -11,10 +11,16 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use crate::acpi::{load_acpi_tables, AcpiTable};
+use crate::api::{VirtioMemInfo, VmMemoryData, VmMemoryReadData, MAX_MEMORY_ACCESS_SIZE};
+use crate::cgroup::{device_threads, VmmCgroup};
 use crate::config::{
-    add_to_config, DeviceConfig, DiskConfig, FsConfig, HotplugMethod, NetConfig, PmemConfig,
-    UserDeviceConfig, ValidationError, VdpaConfig, VmConfig, VsockConfig,
+    add_to_config, ConsolePortConfig, CpuAffinity, CpuBandwidth, DeviceConfig, DiskConfig,
+    FsConfig, HotplugMethod, NetConfig, PmemConfig, UserDeviceConfig, ValidationError, VdpaConfig,
+    VmConfig, VsockConfig,
 };
+#[cfg(target_arch = "x86_64")]
+use crate::config::{MsrFilterConfig, MsrFilterDefaultAction};
 use crate::config::{NumaConfig, PayloadConfig};
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::coredump::{
@@ -25,6 +31,7 @@ use crate::device_manager::{DeviceManager, DeviceManagerError, PtyPair};
 use crate::device_tree::DeviceTree;
 #[cfg(feature = "guest_debug")]
 use crate::gdb::{Debuggable, DebuggableError, GdbRequestPayload, GdbResponsePayload};
+use crate::guest_agent::{Error as GuestAgentError, GuestAgent};
 use crate::memory_manager::{
     Error as MemoryManagerError, MemoryManager, MemoryManagerSnapshotData,
 };
@@ -32,8 +39,12 @@ use crate::memory_manager::{
 use crate::migration::get_vm_snapshot;
 #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
 use crate::migration::url_to_file;
-use crate::migration::{url_to_path, SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE};
-use crate::GuestMemoryMmap;
+use crate::migration::{
+    send_snapshot_section, send_snapshot_stream_header, url_to_path, write_snapshot_manifest,
+    SnapshotSource, SNAPSHOT_CONFIG_FILE, SNAPSHOT_STATE_FILE,
+};
+use crate::process_limits::apply_process_limits;
+use crate::{GuestMemoryMmap, GuestRegionMmap};
 use crate::{
     PciDeviceInfo, CPU_MANAGER_SNAPSHOT_ID, DEVICE_MANAGER_SNAPSHOT_ID, MEMORY_MANAGER_SNAPSHOT_ID,
 };
@@ -79,11 +90,15 @@ use std::mem::size_of;
 use std::num::Wrapping;
 use std::ops::Deref;
 use std::os::unix::net::UnixStream;
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+use std::path::Path;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{result, str, thread};
 use thiserror::Error;
 use tracer::trace_scoped;
+use virtio_devices::transport::VirtqueueState;
+use virtio_devices::WatchdogCounters;
 use vm_device::Bus;
 #[cfg(feature = "tdx")]
 use vm_memory::{Address, ByteValued, GuestMemory, GuestMemoryRegion};
@@ -156,6 +171,15 @@ pub enum Error {
     #[error("VM is already created")]
     VmAlreadyCreated,
 
+    #[error("Cannot apply the Landlock rules: {0}")]
+    ApplyLandlock(#[source] crate::landlock::Error),
+
+    #[error("Cannot place the VMM in its cgroup: {0}")]
+    Cgroup(#[source] crate::cgroup::Error),
+
+    #[error("Cannot apply the process limits: {0}")]
+    ApplyProcessLimits(#[source] crate::process_limits::Error),
+
     #[error("VM is not running")]
     VmNotRunning,
 
@@ -216,6 +240,19 @@ pub enum Error {
     #[error("Too many virtio-vsock devices")]
     TooManyVsockDevices,
 
+    #[cfg(target_arch = "x86_64")]
+    #[error("Error setting the MSR filter: {0}")]
+    SetMsrFilter(#[source] hypervisor::HypervisorVmError),
+
+    #[error("Cannot prepare the SR-IOV virtual function: {0}")]
+    PrepareVf(#[source] crate::sriov::Error),
+
+    #[error("Cannot spawn the SR-IOV VF release thread: {0}")]
+    ReleaseVfSpawn(#[source] io::Error),
+
+    #[error("Cannot prepare the mediated device: {0}")]
+    PrepareMdev(#[source] crate::mdev::Error),
+
     #[error("Failed serializing into JSON: {0}")]
     SerializeJson(#[source] serde_json::Error),
 
@@ -231,9 +268,18 @@ pub enum Error {
     #[error("Failed resizing a memory zone")]
     ResizeZone,
 
+    #[error("Failed binding a memory zone to a host NUMA node")]
+    BindZone,
+
+    #[error("The balloon does not move memory to the heterogeneous memory on memory pressure")]
+    HeteroBalloonNotEnabled,
+
     #[error("Cannot activate virtio devices: {0:?}")]
     ActivateVirtioDevices(DeviceManagerError),
 
+    #[error("Error asking the guest to report its free pages: {0:?}")]
+    ReportFreePages(DeviceManagerError),
+
     #[error("Error triggering power button: {0:?}")]
     PowerButton(DeviceManagerError),
 
@@ -256,6 +302,17 @@ pub enum Error {
     #[error("Error enabling SEV-SNP VM: {0}")]
     InitializeSevSnpVm(#[source] hypervisor::HypervisorVmError),
 
+    #[error("Error loading the ACPI tables: {0}")]
+    AcpiTables(#[source] crate::acpi::Error),
+
+    #[cfg(feature = "sev_snp")]
+    #[error("Error opening the IGVM file: {0}")]
+    IgvmFile(#[source] std::io::Error),
+
+    #[cfg(feature = "sev_snp")]
+    #[error("Error loading the IGVM file: {0}")]
+    IgvmLoad(#[source] crate::igvm::Error),
+
     #[cfg(feature = "tdx")]
     #[error("Error performing I/O on TDX firmware file: {0}")]
     LoadTdvf(#[source] std::io::Error),
@@ -309,12 +366,73 @@ pub enum Error {
     #[error("Payload configuration is not bootable")]
     InvalidPayload,
 
+    #[error("Guest agent channel is not configured")]
+    GuestAgentNotConfigured,
+
+    #[error("Error communicating with the guest agent: {0}")]
+    GuestAgent(#[source] GuestAgentError),
+
+    #[error("Guest memory access through the API is not enabled")]
+    MemoryAccessNotEnabled,
+
+    #[error("Guest memory can only be written while the VM is paused")]
+    VmNotPaused,
+
+    #[error("Guest memory access of {0} bytes, up to {MAX_MEMORY_ACCESS_SIZE} allowed")]
+    MemoryAccessTooLarge(u64),
+
+    #[error("Guest memory data is not a valid hex string")]
+    InvalidMemoryData,
+
+    #[error("Error accessing guest memory: {0}")]
+    GuestMemoryAccess(#[source] vm_memory::GuestMemoryError),
+
     #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
     #[error("Error coredumping VM: {0:?}")]
     Coredump(GuestDebuggableError),
+
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    #[error("Cannot create the kdump directory: {0}")]
+    KdumpDirectory(#[source] io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+// Entry of the counters reporting the seccomp violations
+const SECCOMP_COUNTERS_NAME: &str = "__seccomp";
+
+/// Stage of the boot of the VM, in microseconds since the VM started being
+/// created.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BootPhase {
+    pub start_us: u64,
+    pub end_us: u64,
+}
+
+impl BootPhase {
+    fn new(timestamp: Instant, start: Instant, end: Instant) -> Self {
+        BootPhase {
+            start_us: start.saturating_duration_since(timestamp).as_micros() as u64,
+            end_us: end.saturating_duration_since(timestamp).as_micros() as u64,
+        }
+    }
+}
+
+/// Breakdown of the time spent booting the VM. The stages that didn't run,
+/// such as all of them for a restored VM, are left empty.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct BootTimings {
+    pub memory_setup: Option<BootPhase>,
+    pub device_creation: Option<BootPhase>,
+    /// Loading of the firmware or kernel, run in parallel to the creation of
+    /// the devices.
+    pub payload_load: Option<BootPhase>,
+    /// Configuration of the vCPUs, ending with the handoff to the payload.
+    pub vcpu_setup: Option<BootPhase>,
+    /// From the first to the latest activation of a virtio device by the
+    /// guest, including the devices hotplugged afterwards.
+    pub device_activation: Option<BootPhase>,
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum VmState {
     Created,
@@ -437,10 +555,25 @@ pub fn physical_bits(hypervisor: &Arc<dyn hypervisor::Hypervisor>, max_phys_bits
     cmp::min(host_phys_bits, max_phys_bits)
 }
 
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
 pub struct Vm {
     #[cfg(feature = "tdx")]
     kernel: Option<File>,
     initramfs: Option<File>,
+    acpi_tables: Vec<AcpiTable>,
     threads: Vec<thread::JoinHandle<()>>,
     device_manager: Arc<Mutex<DeviceManager>>,
     config: Arc<Mutex<VmConfig>>,
@@ -457,6 +590,12 @@ pub struct Vm {
     hypervisor: Arc<dyn hypervisor::Hypervisor>,
     stop_on_boot: bool,
     load_payload_handle: Option<thread::JoinHandle<Result<EntryPoint>>>,
+    timestamp: Instant,
+    boot_timings: Arc<Mutex<BootTimings>>,
+    cgroup: Option<Arc<VmmCgroup>>,
+    // Paused in the S3 sleep state, waiting to be woken up
+    #[cfg(target_arch = "x86_64")]
+    suspended: bool,
 }
 
 impl Vm {
@@ -469,6 +608,11 @@ impl Vm {
         vm: Arc<dyn hypervisor::Vm>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        pause_evt: EventFd,
+        watchdog_counters: Arc<WatchdogCounters>,
+        guest_panic_evt: EventFd,
+        suspend_evt: EventFd,
+        hibernate_evt: EventFd,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
@@ -479,17 +623,25 @@ impl Vm {
         console_resize_pipe: Option<File>,
         original_termios: Arc<Mutex<Option<termios>>>,
         snapshot: Option<Snapshot>,
+        cgroup: Option<Arc<VmmCgroup>>,
     ) -> Result<Self> {
         trace_scoped!("Vm::new_from_memory_manager");
 
+        // The limits must be raised before the devices, such as the VFIO
+        // ones pinning the guest memory, get created.
+        if let Some(process_limits) = config.lock().unwrap().process_limits.as_ref() {
+            apply_process_limits(process_limits).map_err(Error::ApplyProcessLimits)?;
+        }
+
         let boot_id_list = config
             .lock()
             .unwrap()
             .validate()
             .map_err(Error::ConfigValidation)?;
 
+        let boot_timings = Arc::new(Mutex::new(BootTimings::default()));
         let load_payload_handle = if snapshot.is_none() {
-            Self::load_payload_async(&memory_manager, &config)?
+            Self::load_payload_async(&memory_manager, &config, timestamp, &boot_timings)?
         } else {
             None
         };
@@ -500,6 +652,11 @@ impl Vm {
         let numa_nodes =
             Self::create_numa_nodes(config.lock().unwrap().numa.clone(), &memory_manager)?;
 
+        #[cfg(target_arch = "x86_64")]
+        if let Some(msr_filter) = config.lock().unwrap().msr_filter.as_ref() {
+            Self::set_msr_filter(&vm, msr_filter)?;
+        }
+
         #[cfg(feature = "tdx")]
         let tdx_enabled = config.lock().unwrap().is_tdx_enabled();
         #[cfg(feature = "sev_snp")]
@@ -527,11 +684,27 @@ impl Vm {
         });
 
         let cpus_config = { &config.lock().unwrap().cpus.clone() };
+        let memory_zones = config
+            .lock()
+            .unwrap()
+            .memory
+            .zones
+            .clone()
+            .unwrap_or_default();
+        // With a crash policy, a triple fault is handled as a guest panic
+        // rather than as a reset.
+        let crash_evt = if config.lock().unwrap().on_crash.is_some() {
+            Some(guest_panic_evt.try_clone().map_err(Error::EventFdClone)?)
+        } else {
+            None
+        };
         let cpu_manager = cpu::CpuManager::new(
             cpus_config,
+            config.clone(),
             vm.clone(),
             exit_evt.try_clone().map_err(Error::EventFdClone)?,
             reset_evt.try_clone().map_err(Error::EventFdClone)?,
+            crash_evt,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             &hypervisor,
@@ -540,9 +713,14 @@ impl Vm {
             #[cfg(feature = "tdx")]
             tdx_enabled,
             &numa_nodes,
+            &memory_zones,
         )
         .map_err(Error::CpuManager)?;
 
+        if let Some(cgroup) = cgroup.as_ref() {
+            cpu_manager.lock().unwrap().set_cgroup(cgroup.clone());
+        }
+
         #[cfg(target_arch = "x86_64")]
         cpu_manager
             .lock()
@@ -563,6 +741,20 @@ impl Vm {
             let max_vcpus = cpu_manager.lock().unwrap().max_vcpus() as u32;
             vm.tdx_init(&cpuid, max_vcpus)
                 .map_err(Error::InitializeTdxVm)?;
+
+            if let Some(socket) = config
+                .lock()
+                .unwrap()
+                .platform
+                .as_ref()
+                .and_then(|p| p.quote_generation_socket.as_ref())
+            {
+                cpu_manager
+                    .lock()
+                    .unwrap()
+                    .set_quote_generation_socket(socket)
+                    .map_err(Error::CpuManager)?;
+            }
         }
 
         cpu_manager
@@ -584,6 +776,7 @@ impl Vm {
         #[cfg(not(feature = "tdx"))]
         let dynamic = true;
 
+        let device_creation_start = Instant::now();
         let device_manager = DeviceManager::new(
             #[cfg(target_arch = "x86_64")]
             io_bus,
@@ -595,6 +788,11 @@ impl Vm {
             cpu_manager.clone(),
             exit_evt.try_clone().map_err(Error::EventFdClone)?,
             reset_evt,
+            pause_evt,
+            watchdog_counters,
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             seccomp_action.clone(),
             numa_nodes.clone(),
             &activate_evt,
@@ -616,6 +814,11 @@ impl Vm {
                 original_termios,
             )
             .map_err(Error::DeviceManager)?;
+        boot_timings.lock().unwrap().device_creation = Some(BootPhase::new(
+            timestamp,
+            device_creation_start,
+            Instant::now(),
+        ));
 
         #[cfg(feature = "tdx")]
         let kernel = config
@@ -623,20 +826,27 @@ impl Vm {
             .unwrap()
             .payload
             .as_ref()
-            .map(|p| p.kernel.as_ref().map(File::open))
-            .unwrap_or_default()
+            .map(PayloadConfig::open_kernel)
             .transpose()
-            .map_err(Error::KernelFile)?;
+            .map_err(Error::KernelFile)?
+            .flatten();
 
         let initramfs = config
             .lock()
             .unwrap()
             .payload
             .as_ref()
-            .map(|p| p.initramfs.as_ref().map(File::open))
-            .unwrap_or_default()
+            .map(PayloadConfig::open_initramfs)
             .transpose()
-            .map_err(Error::InitramfsFile)?;
+            .map_err(Error::InitramfsFile)?
+            .flatten();
+
+        let acpi_tables = match config.lock().unwrap().acpi_tables.as_deref() {
+            Some(configs) if snapshot.is_none() => {
+                load_acpi_tables(configs).map_err(Error::AcpiTables)?
+            }
+            _ => Vec::new(),
+        };
 
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let saved_clock = if let Some(snapshot) = snapshot.as_ref() {
@@ -656,6 +866,7 @@ impl Vm {
             #[cfg(feature = "tdx")]
             kernel,
             initramfs,
+            acpi_tables,
             device_manager,
             config,
             threads: Vec::with_capacity(1),
@@ -669,9 +880,74 @@ impl Vm {
             hypervisor,
             stop_on_boot,
             load_payload_handle,
+            timestamp,
+            boot_timings,
+            cgroup,
+            #[cfg(target_arch = "x86_64")]
+            suspended: false,
         })
     }
 
+    /// Place the VMM in the cgroup from the configuration, if any, before the
+    /// guest memory gets allocated so that it is accounted to this cgroup.
+    pub fn create_cgroup(config: &VmConfig) -> Result<Option<Arc<VmmCgroup>>> {
+        config
+            .cgroup
+            .as_ref()
+            .map(|c| VmmCgroup::new(Some(c)).map(Arc::new))
+            .transpose()
+            .map_err(Error::Cgroup)
+    }
+
+    // Move the threads of the devices with a CPU bandwidth limit into their
+    // own cgroup, once they got started.
+    fn place_device_threads(&self) -> Result<()> {
+        let cgroup = match self.cgroup.as_ref() {
+            Some(cgroup) => cgroup,
+            None => return Ok(()),
+        };
+        let device_cpu_max = self
+            .config
+            .lock()
+            .unwrap()
+            .cgroup
+            .as_ref()
+            .and_then(|c| c.device_cpu_max.clone())
+            .unwrap_or_default();
+
+        for device in device_cpu_max {
+            let tids = device_threads(&device.id).map_err(Error::Cgroup)?;
+            if tids.is_empty() {
+                continue;
+            }
+            cgroup
+                .set_device_cpu_max(&device.id, &tids, device.cpu_max)
+                .map_err(Error::Cgroup)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_msr_filter(vm: &Arc<dyn hypervisor::Vm>, msr_filter: &MsrFilterConfig) -> Result<()> {
+        use hypervisor::arch::x86::MsrFilterAction;
+
+        let default_action = match msr_filter.default_action {
+            MsrFilterDefaultAction::Allow => MsrFilterAction::Allow,
+            MsrFilterDefaultAction::Deny => MsrFilterAction::Deny,
+        };
+        let msrs: Vec<(u32, MsrFilterAction)> = msr_filter
+            .allow
+            .iter()
+            .map(|i| (*i, MsrFilterAction::Allow))
+            .chain(msr_filter.deny.iter().map(|i| (*i, MsrFilterAction::Deny)))
+            .chain(msr_filter.zero.iter().map(|i| (*i, MsrFilterAction::Zero)))
+            .collect();
+
+        vm.set_msr_filter(default_action, &msrs)
+            .map_err(Error::SetMsrFilter)
+    }
+
     fn create_numa_nodes(
         configs: Option<Vec<NumaConfig>>,
         memory_manager: &Arc<Mutex<MemoryManager>>,
@@ -761,6 +1037,11 @@ impl Vm {
         vm_config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
+        pause_evt: EventFd,
+        watchdog_counters: Arc<WatchdogCounters>,
+        guest_panic_evt: EventFd,
+        suspend_evt: EventFd,
+        hibernate_evt: EventFd,
         #[cfg(feature = "guest_debug")] vm_debug_evt: EventFd,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
@@ -770,7 +1051,7 @@ impl Vm {
         console_resize_pipe: Option<File>,
         original_termios: Arc<Mutex<Option<termios>>>,
         snapshot: Option<Snapshot>,
-        source_url: Option<&str>,
+        snapshot_source: Option<&mut SnapshotSource>,
         prefault: Option<bool>,
     ) -> Result<Self> {
         trace_scoped!("Vm::new");
@@ -801,6 +1082,9 @@ impl Vm {
 
         let phys_bits = physical_bits(&hypervisor, vm_config.lock().unwrap().cpus.max_phys_bits);
 
+        let cgroup = Self::create_cgroup(&vm_config.lock().unwrap())?;
+
+        let memory_setup_start = Instant::now();
         let memory_manager = if let Some(snapshot) =
             snapshot_from_id(snapshot.as_ref(), MEMORY_MANAGER_SNAPSHOT_ID)
         {
@@ -808,7 +1092,7 @@ impl Vm {
                 &snapshot,
                 vm.clone(),
                 &vm_config.lock().unwrap().memory.clone(),
-                source_url,
+                snapshot_source,
                 prefault.unwrap(),
                 phys_bits,
             )
@@ -831,13 +1115,19 @@ impl Vm {
             )
             .map_err(Error::MemoryManager)?
         };
+        let memory_setup = BootPhase::new(timestamp, memory_setup_start, Instant::now());
 
-        Vm::new_from_memory_manager(
+        let vm = Vm::new_from_memory_manager(
             vm_config,
             memory_manager,
             vm,
             exit_evt,
             reset_evt,
+            pause_evt,
+            watchdog_counters,
+            guest_panic_evt,
+            suspend_evt,
+            hibernate_evt,
             #[cfg(feature = "guest_debug")]
             vm_debug_evt,
             seccomp_action,
@@ -849,7 +1139,13 @@ impl Vm {
             console_resize_pipe,
             original_termios,
             snapshot,
-        )
+            cgroup,
+        )?;
+        if vm.get_state()? == VmState::Created {
+            vm.boot_timings.lock().unwrap().memory_setup = Some(memory_setup);
+        }
+
+        Ok(vm)
     }
 
     pub fn create_hypervisor_vm(
@@ -1016,17 +1312,15 @@ impl Vm {
     ) -> Result<EntryPoint> {
         trace_scoped!("load_payload");
         match (
-            &payload.firmware,
-            &payload.kernel,
-            &payload.initramfs,
+            payload.open_firmware().map_err(Error::FirmwareFile)?,
+            payload.open_kernel().map_err(Error::KernelFile)?,
+            payload.has_initramfs(),
             &payload.cmdline,
         ) {
-            (Some(firmware), None, None, None) => {
-                let firmware = File::open(firmware).map_err(Error::FirmwareFile)?;
+            (Some(firmware), None, false, None) => {
                 Self::load_kernel(firmware, None, memory_manager)
             }
             (None, Some(kernel), _, _) => {
-                let kernel = File::open(kernel).map_err(Error::KernelFile)?;
                 let cmdline = Self::generate_cmdline(payload)?;
                 Self::load_kernel(kernel, Some(cmdline), memory_manager)
             }
@@ -1039,15 +1333,12 @@ impl Vm {
         payload: &PayloadConfig,
         memory_manager: Arc<Mutex<MemoryManager>>,
     ) -> Result<EntryPoint> {
-        match (&payload.firmware, &payload.kernel) {
-            (Some(firmware), None) => {
-                let firmware = File::open(firmware).map_err(Error::FirmwareFile)?;
-                Self::load_kernel(Some(firmware), None, memory_manager)
-            }
-            (None, Some(kernel)) => {
-                let kernel = File::open(kernel).map_err(Error::KernelFile)?;
-                Self::load_kernel(None, Some(kernel), memory_manager)
-            }
+        match (
+            payload.open_firmware().map_err(Error::FirmwareFile)?,
+            payload.open_kernel().map_err(Error::KernelFile)?,
+        ) {
+            (Some(firmware), None) => Self::load_kernel(Some(firmware), None, memory_manager),
+            (None, Some(kernel)) => Self::load_kernel(None, Some(kernel), memory_manager),
             _ => Err(Error::InvalidPayload),
         }
     }
@@ -1055,6 +1346,8 @@ impl Vm {
     fn load_payload_async(
         memory_manager: &Arc<Mutex<MemoryManager>>,
         config: &Arc<Mutex<VmConfig>>,
+        timestamp: Instant,
+        boot_timings: &Arc<Mutex<BootTimings>>,
     ) -> Result<Option<thread::JoinHandle<Result<EntryPoint>>>> {
         // Kernel with TDX is loaded in a different manner
         #[cfg(feature = "tdx")]
@@ -1062,6 +1355,18 @@ impl Vm {
             return Ok(None);
         }
 
+        // IGVM files can only be loaded once the guest memory is mapped
+        #[cfg(feature = "sev_snp")]
+        if config
+            .lock()
+            .unwrap()
+            .payload
+            .as_ref()
+            .map_or(false, |p| p.igvm.is_some())
+        {
+            return Ok(None);
+        }
+
         config
             .lock()
             .unwrap()
@@ -1070,10 +1375,17 @@ impl Vm {
             .map(|payload| {
                 let memory_manager = memory_manager.clone();
                 let payload = payload.clone();
+                let boot_timings = boot_timings.clone();
 
                 std::thread::Builder::new()
                     .name("payload_loader".into())
-                    .spawn(move || Self::load_payload(&payload, memory_manager))
+                    .spawn(move || {
+                        let start = Instant::now();
+                        let entry_point = Self::load_payload(&payload, memory_manager);
+                        boot_timings.lock().unwrap().payload_load =
+                            Some(BootPhase::new(timestamp, start, Instant::now()));
+                        entry_point
+                    })
                     .map_err(Error::KernelLoadThreadSpawn)
             })
             .transpose()
@@ -1090,6 +1402,31 @@ impl Vm {
             None => None,
         };
 
+        // The kernel got loaded along with its command line before the devices
+        // were created, the command line is written again to describe the
+        // virtio-mmio devices.
+        let cmdline_additions = self
+            .device_manager
+            .lock()
+            .unwrap()
+            .cmdline_additions()
+            .to_vec();
+        if !cmdline_additions.is_empty() {
+            let payload = self.config.lock().unwrap().payload.clone().unwrap();
+            if payload.has_kernel() {
+                let mut cmdline = Self::generate_cmdline(&payload)?;
+                for entry in cmdline_additions.iter() {
+                    cmdline.insert_str(entry).map_err(Error::CmdLineInsertStr)?;
+                }
+                linux_loader::loader::load_cmdline(
+                    mem.deref(),
+                    arch::layout::CMDLINE_START,
+                    &cmdline,
+                )
+                .map_err(Error::LoadCmdLine)?;
+            }
+        }
+
         let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
         let rsdp_addr = Some(rsdp_addr);
         let sgx_epc_region = self
@@ -1100,6 +1437,22 @@ impl Vm {
             .as_ref()
             .cloned();
 
+        let manufacturer = self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|p| p.manufacturer.clone());
+
+        let product = self
+            .config
+            .lock()
+            .unwrap()
+            .platform
+            .as_ref()
+            .and_then(|p| p.product.clone());
+
         let serial_number = self
             .config
             .lock()
@@ -1135,6 +1488,8 @@ impl Vm {
             boot_vcpus,
             rsdp_addr,
             sgx_epc_region,
+            manufacturer.as_deref(),
+            product.as_deref(),
             serial_number.as_deref(),
             uuid.as_deref(),
             oem_strings.as_deref(),
@@ -1169,6 +1524,12 @@ impl Vm {
             let pci_space = PciSpaceInfo {
                 pci_segment_id: pci_segment.id,
                 mmio_config_address: pci_segment.mmio_config_address,
+                mmio_config_size: pci_segment.mmio_config_size(),
+                // The expansion buses are only described through ACPI, as
+                // the device tree can't hold several host bridges for a
+                // segment.
+                start_bus: pci_segment.start_bus,
+                end_bus: pci_segment.first_bus_end(),
                 pci_device_space_start: pci_segment.start_of_device_area,
                 pci_device_space_size: pci_segment.end_of_device_area
                     - pci_segment.start_of_device_area
@@ -1360,6 +1721,52 @@ impl Vm {
         Ok(())
     }
 
+    pub fn remove_vcpu(&mut self, id: u8) -> Result<()> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .remove_vcpu_by_id(id)
+            .map_err(Error::CpuManager)?;
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(AcpiNotificationFlags::CPU_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)?;
+
+        Ok(())
+    }
+
+    pub fn set_cpu_affinity(&mut self, affinity: &[CpuAffinity]) -> Result<()> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .set_affinity(affinity)
+            .map_err(Error::CpuManager)?;
+
+        self.config.lock().unwrap().cpus.update_affinity(affinity);
+
+        Ok(())
+    }
+
+    pub fn set_cpu_bandwidth(
+        &mut self,
+        cpu_max: Option<CpuBandwidth>,
+        vcpu_max: Option<CpuBandwidth>,
+    ) -> Result<()> {
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .set_cpu_bandwidth(cpu_max, vcpu_max)
+            .map_err(Error::CpuManager)?;
+
+        let mut config = self.config.lock().unwrap();
+        config.cpus.cpu_max = cpu_max;
+        config.cpus.vcpu_max = vcpu_max;
+
+        Ok(())
+    }
+
     pub fn resize_zone(&mut self, id: String, desired_memory: u64) -> Result<()> {
         let memory_config = &mut self.config.lock().unwrap().memory;
 
@@ -1396,6 +1803,43 @@ impl Vm {
         Err(Error::ResizeZone)
     }
 
+    pub fn bind_zone(&mut self, id: String, host_numa_node: u32) -> Result<()> {
+        let memory_config = &mut self.config.lock().unwrap().memory;
+
+        if let Some(zones) = &mut memory_config.zones {
+            if let Some(zone) = zones.iter_mut().find(|zone| zone.id == id) {
+                if zone.shared && zone.file.is_some() {
+                    error!(
+                        "Invalid to set host NUMA policy for a memory zone \
+                        backed by a regular file and mapped as 'shared'"
+                    );
+                    return Err(Error::BindZone);
+                }
+
+                self.memory_manager
+                    .lock()
+                    .unwrap()
+                    .bind_zone(&id, host_numa_node)
+                    .map_err(Error::MemoryManager)?;
+                // Keep the memory zone config in sync, so that the zone
+                // stays on the same host NUMA node across a reboot.
+                zone.host_numa_node = Some(host_numa_node);
+
+                // The vCPU threads can only set their own memory policy, the
+                // running ones keeping the nodes they were started with.
+                self.cpu_manager
+                    .lock()
+                    .unwrap()
+                    .update_host_numa_nodes(&self.numa_nodes, zones);
+
+                return Ok(());
+            }
+        }
+
+        error!("Could not find the memory zone {} for the binding", id);
+        Err(Error::BindZone)
+    }
+
     pub fn add_device(&mut self, mut device_cfg: DeviceConfig) -> Result<PciDeviceInfo> {
         let pci_device_info = self
             .device_manager
@@ -1444,6 +1888,14 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn resize_fs(&mut self, id: String, desired_cache_size: u64) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_fs(&id, desired_cache_size)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn remove_device(&mut self, id: String) -> Result<()> {
         self.device_manager
             .lock()
@@ -1607,26 +2059,75 @@ impl Vm {
         Ok(pci_device_info)
     }
 
+    pub fn add_console_port(
+        &mut self,
+        mut port_cfg: ConsolePortConfig,
+    ) -> Result<ConsolePortConfig> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .add_console_port(&mut port_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by adding the new port. This is important to
+        // ensure the port would be created in case of a reboot.
+        {
+            let mut config = self.config.lock().unwrap();
+            add_to_config(&mut config.console_ports, port_cfg.clone());
+        }
+
+        Ok(port_cfg)
+    }
+
+    pub fn remove_console_port(&mut self, id: String) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .remove_console_port(&id)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig by removing the port. This is important to
+        // ensure the port would not be created in case of a reboot.
+        self.config.lock().unwrap().remove_console_port(&id);
+
+        Ok(())
+    }
+
+    pub fn virtqueue_states(&self, id: &str) -> Result<Vec<VirtqueueState>> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .virtqueue_states(id)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn counters(&self) -> Result<HashMap<String, HashMap<&'static str, Wrapping<u64>>>> {
-        Ok(self.device_manager.lock().unwrap().counters())
+        let mut counters = self.device_manager.lock().unwrap().counters();
+        counters.extend(self.cpu_manager.lock().unwrap().counters());
+        if let Some(violations) = crate::seccomp_monitor::violations() {
+            counters.insert(
+                SECCOMP_COUNTERS_NAME.to_string(),
+                HashMap::from([("violations", Wrapping(violations))]),
+            );
+        }
+        Ok(counters)
     }
 
     #[cfg(feature = "tdx")]
     fn extract_tdvf_sections(&mut self) -> Result<(Vec<TdvfSection>, bool)> {
         use arch::x86_64::tdx::*;
 
-        let firmware_path = self
+        // The TDVF file contains a table of section as well as code
+        let mut firmware_file = self
             .config
             .lock()
             .unwrap()
             .payload
             .as_ref()
             .unwrap()
-            .firmware
-            .clone()
+            .open_firmware()
+            .map_err(Error::LoadTdvf)?
             .ok_or(Error::TdxFirmwareMissing)?;
-        // The TDVF file contains a table of section as well as code
-        let mut firmware_file = File::open(firmware_path).map_err(Error::LoadTdvf)?;
 
         // For all the sections allocate some RAM backing them
         parse_tdvf_sections(&mut firmware_file).map_err(Error::ParseTdvf)
@@ -1725,17 +2226,16 @@ impl Vm {
         }
 
         // The TDVF file contains a table of section as well as code
-        let firmware_path = self
+        let mut firmware_file = self
             .config
             .lock()
             .unwrap()
             .payload
             .as_ref()
             .unwrap()
-            .firmware
-            .clone()
+            .open_firmware()
+            .map_err(Error::LoadTdvf)?
             .ok_or(Error::TdxFirmwareMissing)?;
-        let mut firmware_file = File::open(firmware_path).map_err(Error::LoadTdvf)?;
 
         // The guest memory at this point now has all the required regions so it
         // is safe to copy from the TDVF file into it.
@@ -1925,6 +2425,7 @@ impl Vm {
             &self.memory_manager,
             &self.numa_nodes,
             tpm_enabled,
+            &self.acpi_tables,
         );
         info!("Created ACPI tables: rsdp_addr = 0x{:x}", rsdp_addr.0);
 
@@ -1940,6 +2441,34 @@ impl Vm {
             .transpose()
     }
 
+    #[cfg(feature = "sev_snp")]
+    fn load_igvm(&self) -> Result<()> {
+        let payload = self.config.lock().unwrap().payload.clone();
+        if let Some(payload) = payload {
+            if let Some(igvm) = &payload.igvm {
+                let file = File::open(igvm).map_err(Error::IgvmFile)?;
+                let boot_vcpus = self.cpu_manager.lock().unwrap().boot_vcpus();
+                let info = crate::igvm::load_igvm(
+                    file,
+                    &self.vm,
+                    &self.memory_manager,
+                    u32::from(boot_vcpus),
+                    payload.cmdline.as_deref().unwrap_or_default(),
+                    payload.host_data.as_deref(),
+                )
+                .map_err(Error::IgvmLoad)?;
+
+                self.cpu_manager
+                    .lock()
+                    .unwrap()
+                    .set_sev_control_register(info.vmsa_gpa / igvm_defs::PAGE_SIZE_4K)
+                    .map_err(Error::CpuManager)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn boot(&mut self) -> Result<()> {
         trace_scoped!("Vm::boot");
         info!("Booting VM");
@@ -1955,6 +2484,7 @@ impl Vm {
             VmState::Running
         };
         current_state.valid_transition(new_state)?;
+        let boot_start = Instant::now();
 
         // Do earlier to parallelise with loading kernel
         #[cfg(target_arch = "x86_64")]
@@ -2021,6 +2551,11 @@ impl Vm {
             .allocate_address_space()
             .map_err(Error::MemoryManager)?;
 
+        // The pages of the IGVM file are imported into the guest memory as
+        // mapped by the hypervisor.
+        #[cfg(feature = "sev_snp")]
+        self.load_igvm()?;
+
         #[cfg(feature = "tdx")]
         if let Some(hob_address) = hob_address {
             // With the HOB address extracted the vCPUs can have
@@ -2043,6 +2578,8 @@ impl Vm {
             .unwrap()
             .start_boot_vcpus(new_state == VmState::BreakPoint)
             .map_err(Error::CpuManager)?;
+        self.boot_timings.lock().unwrap().vcpu_setup =
+            Some(BootPhase::new(self.timestamp, boot_start, Instant::now()));
 
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         *state = new_state;
@@ -2094,6 +2631,40 @@ impl Vm {
         self.device_manager.lock().unwrap().balloon_size()
     }
 
+    /// Regions of the overcommittable memory zones.
+    pub fn overcommit_regions(&self) -> Vec<Arc<GuestRegionMmap>> {
+        self.memory_manager.lock().unwrap().overcommit_regions()
+    }
+
+    /// Ask the guest to report its free pages through the balloon right
+    /// away, for the host to reclaim them.
+    pub fn report_free_pages(&mut self) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .report_free_pages()
+            .map_err(Error::ReportFreePages)
+    }
+
+    /// Inflate the balloon to reclaim `reclaimed` bytes of normal and
+    /// heterogeneous memory from the guest on top of its configured size.
+    /// The configuration is left untouched, for a reboot not to keep that
+    /// memory away from the guest.
+    pub fn reclaim_balloon(&mut self, reclaimed: [u64; 2]) -> Result<()> {
+        let mut size = match &self.config.lock().unwrap().balloon {
+            Some(balloon_config) => balloon_config.size,
+            None => return Ok(()),
+        };
+        size[0] = size[0].saturating_add(reclaimed[0]);
+        size[1] = size[1].saturating_add(reclaimed[1]);
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_balloon(size)
+            .map_err(Error::DeviceManager)
+    }
+
     pub fn send_memory_fds(
         &mut self,
         socket: &mut UnixStream,
@@ -2183,12 +2754,161 @@ impl Vm {
         self.device_manager.lock().unwrap().device_tree()
     }
 
+    pub fn virtio_mem_info(&self) -> Vec<VirtioMemInfo> {
+        self.memory_manager.lock().unwrap().virtio_mem_info()
+    }
+
+    pub fn console_port_ids(&self) -> BTreeMap<u32, String> {
+        self.device_manager.lock().unwrap().console_port_ids()
+    }
+
     pub fn activate_virtio_devices(&self) -> Result<()> {
+        let start = Instant::now();
         self.device_manager
             .lock()
             .unwrap()
             .activate_virtio_devices()
-            .map_err(Error::ActivateVirtioDevices)
+            .map_err(Error::ActivateVirtioDevices)?;
+        self.place_device_threads()?;
+
+        let mut boot_timings = self.boot_timings.lock().unwrap();
+        let mut phase = BootPhase::new(self.timestamp, start, Instant::now());
+        if let Some(first) = boot_timings.device_activation {
+            phase.start_us = first.start_us;
+        }
+        boot_timings.device_activation = Some(phase);
+
+        Ok(())
+    }
+
+    pub fn boot_timings(&self) -> BootTimings {
+        self.boot_timings.lock().unwrap().clone()
+    }
+
+    /// Client of the guest agent, which the caller is expected to drive off
+    /// the VMM control thread as each command may wait on the guest for up to
+    /// the configured timeout.
+    pub fn guest_agent(&self) -> Result<GuestAgent> {
+        // The agent can't answer while the vCPUs are not running.
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        let config = self.config.lock().unwrap();
+        let guest_agent = config
+            .guest_agent
+            .as_ref()
+            .ok_or(Error::GuestAgentNotConfigured)?;
+        let vsock = config
+            .vsock
+            .as_ref()
+            .ok_or(Error::GuestAgentNotConfigured)?;
+
+        Ok(GuestAgent::new(
+            &vsock.socket,
+            guest_agent.port,
+            Duration::from_secs(guest_agent.timeout),
+        ))
+    }
+
+    fn check_memory_access(&self, length: u64) -> Result<()> {
+        if !crate::api::memory_access_enabled() {
+            return Err(Error::MemoryAccessNotEnabled);
+        }
+        if length > MAX_MEMORY_ACCESS_SIZE {
+            return Err(Error::MemoryAccessTooLarge(length));
+        }
+        Ok(())
+    }
+
+    /// Read guest memory, for debugging.
+    pub fn memory_read(&self, data: &VmMemoryReadData) -> Result<VmMemoryData> {
+        self.check_memory_access(data.length)?;
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let mut bytes = vec![0; data.length as usize];
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .memory()
+            .read_slice(&mut bytes, GuestAddress(data.address))
+            .map_err(Error::GuestMemoryAccess)?;
+
+        Ok(VmMemoryData {
+            address: data.address,
+            data: hex_encode(&bytes),
+        })
+    }
+
+    /// Patch guest memory while the vCPUs are stopped, for debugging.
+    pub fn memory_write(&self, data: &VmMemoryData) -> Result<()> {
+        let bytes = hex_decode(&data.data).ok_or(Error::InvalidMemoryData)?;
+        self.check_memory_access(bytes.len() as u64)?;
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .guest_memory()
+            .memory()
+            .write_slice(&bytes, GuestAddress(data.address))
+            .map_err(Error::GuestMemoryAccess)?;
+
+        info!(
+            "Wrote {} bytes of guest memory at 0x{:x} through the API",
+            bytes.len(),
+            data.address
+        );
+        Ok(())
+    }
+
+    /// Pause the VM as the guest entered the S3 sleep state, its memory and
+    /// devices being kept as they are until it gets woken up.
+    #[cfg(target_arch = "x86_64")]
+    pub fn suspend(&mut self) -> Result<()> {
+        event!("vm", "suspending");
+        self.pause().map_err(Error::Pause)?;
+        self.suspended = true;
+        event!("vm", "suspended");
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// The real mode address the guest expects to be woken up at, if it
+    /// set one.
+    #[cfg(target_arch = "x86_64")]
+    pub fn firmware_waking_vector(&self) -> Option<u32> {
+        let waking_vector = crate::acpi::firmware_waking_vector(
+            &self.memory_manager.lock().unwrap().guest_memory().memory(),
+        );
+        (waking_vector != 0 && u64::from(waking_vector) < arch::layout::HIGH_RAM_START.0)
+            .then_some(waking_vector)
+    }
+
+    /// Wake the VM up from the S3 sleep state, the boot vCPU resuming at
+    /// `waking_vector`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn wakeup(&mut self, waking_vector: u32) -> Result<()> {
+        event!("vm", "waking");
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .wakeup(waking_vector)
+            .map_err(Error::CpuManager)?;
+        self.suspended = false;
+        self.resume().map_err(Error::Resume)?;
+        event!("vm", "woken");
+        Ok(())
     }
 
     #[cfg(target_arch = "x86_64")]
@@ -2214,6 +2934,28 @@ impl Vm {
         self.memory_manager.lock().unwrap().snapshot_data()
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn gic_capabilities(
+        &self,
+    ) -> std::result::Result<hypervisor::arch::aarch64::gic::VgicCapabilities, MigratableError>
+    {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .get_interrupt_controller()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .get_vgic()
+            .map_err(|e| MigratableError::MigrateSend(anyhow!("Error getting the GIC: {:?}", e)))?
+            .lock()
+            .unwrap()
+            .capabilities()
+            .map_err(|e| {
+                MigratableError::MigrateSend(anyhow!("Error getting GIC capabilities: {:?}", e))
+            })
+    }
+
     pub fn start_dirty_log(&mut self) -> std::result::Result<(), MigratableError> {
         self.memory_manager.lock().unwrap().start_dirty_log()
     }
@@ -2245,13 +2987,17 @@ impl Vm {
     ) -> Result<GdbResponsePayload> {
         use GdbRequestPayload::*;
         match gdb_request {
-            SetSingleStep(single_step) => {
-                self.set_guest_debug(cpu_id, &[], *single_step)
+            SetGuestDebug {
+                hw_breakpoints,
+                hw_watchpoints,
+                single_step,
+            } => {
+                self.set_guest_debug(cpu_id, hw_breakpoints, hw_watchpoints, *single_step)
                     .map_err(Error::Debug)?;
             }
-            SetHwBreakPoint(addrs) => {
-                self.set_guest_debug(cpu_id, addrs, false)
-                    .map_err(Error::Debug)?;
+            TakeDebugExit => {
+                let debug_exit = self.take_debug_exit(cpu_id);
+                return Ok(GdbResponsePayload::DebugExit(debug_exit));
             }
             Pause => {
                 self.debug_pause().map_err(Error::Debug)?;
@@ -2412,6 +3158,8 @@ pub struct VmSnapshot {
 }
 
 pub const VM_SNAPSHOT_ID: &str = "vm";
+#[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+const KDUMP_VMCORE_FILE: &str = "vmcore";
 impl Snapshottable for Vm {
     fn id(&self) -> String {
         VM_SNAPSHOT_ID.to_string()
@@ -2442,6 +3190,11 @@ impl Snapshottable for Vm {
         #[cfg(all(feature = "kvm", target_arch = "x86_64"))]
         let common_cpuid = {
             let amx = self.config.lock().unwrap().cpus.features.amx;
+            let kvm_hyperv_features = self.config.lock().unwrap().cpus.hyperv_features();
+            let nested = self.config.lock().unwrap().cpus.nested;
+            let model = self.config.lock().unwrap().cpus.model.clone();
+            let cpuid_add = self.config.lock().unwrap().cpus.cpuid_add.clone();
+            let cpuid_remove = self.config.lock().unwrap().cpus.cpuid_remove.clone();
             let phys_bits = physical_bits(
                 &self.hypervisor,
                 self.config.lock().unwrap().cpus.max_phys_bits,
@@ -2452,9 +3205,14 @@ impl Snapshottable for Vm {
                     sgx_epc_sections: None,
                     phys_bits,
                     kvm_hyperv: self.config.lock().unwrap().cpus.kvm_hyperv,
+                    kvm_hyperv_features,
                     #[cfg(feature = "tdx")]
                     tdx: tdx_enabled,
                     amx,
+                    nested,
+                    model,
+                    cpuid_add,
+                    cpuid_remove,
                 },
             )
             .map_err(|e| {
@@ -2493,8 +3251,10 @@ impl Snapshottable for Vm {
     }
 }
 
-impl Transportable for Vm {
-    fn send(
+impl Vm {
+    // Write the configuration and the state of the VM, leaving the guest
+    // memory out.
+    fn send_state(
         &self,
         snapshot: &Snapshot,
         destination_url: &str,
@@ -2537,6 +3297,65 @@ impl Transportable for Vm {
             .write(&vm_state)
             .map_err(|e| MigratableError::MigrateSend(e.into()))?;
 
+        Ok(())
+    }
+
+    /// Write the snapshot of the VM to a stream rather than a directory, its
+    /// configuration, its state and its memory following one another.
+    pub fn send_stream(
+        &self,
+        snapshot: &Snapshot,
+        stream: &mut File,
+    ) -> std::result::Result<(), MigratableError> {
+        send_snapshot_stream_header(stream)?;
+
+        let vm_config = serde_json::to_vec(self.config.lock().unwrap().deref())
+            .map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        send_snapshot_section(stream, SNAPSHOT_CONFIG_FILE, vm_config.len() as u64)?;
+        stream
+            .write_all(&vm_config)
+            .map_err(MigratableError::MigrateSocket)?;
+
+        let vm_state =
+            serde_json::to_vec(snapshot).map_err(|e| MigratableError::MigrateSend(e.into()))?;
+        send_snapshot_section(stream, SNAPSHOT_STATE_FILE, vm_state.len() as u64)?;
+        stream
+            .write_all(&vm_state)
+            .map_err(MigratableError::MigrateSocket)?;
+
+        self.memory_manager.lock().unwrap().send_stream(stream)
+    }
+
+    /// Capture a panicked guest for a post-mortem analysis, writing its
+    /// memory as an ELF coredump along with the configuration and the state
+    /// of the devices to the destination directory. The VM is left paused.
+    #[cfg(all(target_arch = "x86_64", feature = "guest_debug"))]
+    pub fn kdump(&mut self, destination: &Path) -> Result<()> {
+        if self.get_state()? == VmState::Running {
+            self.pause().map_err(Error::Pause)?;
+        }
+
+        std::fs::create_dir_all(destination).map_err(Error::KdumpDirectory)?;
+        let destination_url = format!("file://{}", destination.display());
+        self.coredump(&format!("{destination_url}/{KDUMP_VMCORE_FILE}"))
+            .map_err(Error::Coredump)?;
+        let snapshot = self.snapshot().map_err(Error::Snapshot)?;
+        self.send_state(&snapshot, &destination_url)
+            .map_err(Error::SnapshotSend)?;
+
+        event!("vm", "kdump", "path", &destination.to_string_lossy());
+        Ok(())
+    }
+}
+
+impl Transportable for Vm {
+    fn send(
+        &self,
+        snapshot: &Snapshot,
+        destination_url: &str,
+    ) -> std::result::Result<(), MigratableError> {
+        self.send_state(snapshot, destination_url)?;
+
         // Tell the memory manager to also send/write its own snapshot.
         if let Some(memory_manager_snapshot) = snapshot.snapshots.get(MEMORY_MANAGER_SNAPSHOT_ID) {
             self.memory_manager
@@ -2549,7 +3368,7 @@ impl Transportable for Vm {
             )));
         }
 
-        Ok(())
+        write_snapshot_manifest(&url_to_path(destination_url)?)
     }
 }
 
@@ -2588,12 +3407,17 @@ impl Debuggable for Vm {
         &self,
         cpu_id: usize,
         addrs: &[GuestAddress],
+        watchpoints: &[hypervisor::Watchpoint],
         singlestep: bool,
     ) -> std::result::Result<(), DebuggableError> {
         self.cpu_manager
             .lock()
             .unwrap()
-            .set_guest_debug(cpu_id, addrs, singlestep)
+            .set_guest_debug(cpu_id, addrs, watchpoints, singlestep)
+    }
+
+    fn take_debug_exit(&self, cpu_id: usize) -> Option<hypervisor::DebugExit> {
+        self.cpu_manager.lock().unwrap().take_debug_exit(cpu_id)
     }
 
     fn debug_pause(&mut self) -> std::result::Result<(), DebuggableError> {
@@ -2801,6 +3625,16 @@ mod tests {
         test_vm_state_transitions(VmState::Paused);
     }
 
+    #[test]
+    fn test_memory_data_hex() {
+        assert_eq!(hex_encode(&[0x00, 0x0f, 0xc3, 0xff]), "000fc3ff");
+        assert_eq!(hex_decode("000fC3ff"), Some(vec![0x00, 0x0f, 0xc3, 0xff]));
+        assert_eq!(hex_decode(""), Some(vec![]));
+        assert_eq!(hex_decode("0f0"), None);
+        assert_eq!(hex_decode("+f"), None);
+        assert_eq!(hex_decode("zz"), None);
+    }
+
     #[cfg(feature = "tdx")]
     #[test]
     fn test_hob_memory_resources() {
@@ -3063,7 +3897,7 @@ mod tests {
             &mem,
             "console=tty0",
             vec![0],
-            Some((0, 0, 0)),
+            Some((0, 0, 0, 0)),
             &dev_info,
             &gic,
             &None,
@@ -4,8 +4,13 @@
 //
 use net_util::MacAddr;
 use serde::{Deserialize, Serialize};
-use std::{net::Ipv4Addr, path::PathBuf, time::Duration};
-use virtio_devices::RateLimiterConfig;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+pub use virtio_devices::WatchdogAction;
+use virtio_devices::{EventLoop, NotificationConfig, PciIdsConfig, RateLimiterConfig};
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpuAffinity {
@@ -20,6 +25,15 @@ pub struct CpuFeatures {
     pub amx: bool,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HypervFeature {
+    Synic,
+    Stimer,
+    Reenlightenment,
+    Tlbflush,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpuTopology {
     pub threads_per_core: u8,
@@ -28,6 +42,14 @@ pub struct CpuTopology {
     pub packages: u8,
 }
 
+/// CPU bandwidth limit, expressed as in the cgroup v2 `cpu.max` file: the
+/// threads may run for `quota` microseconds every `period` microseconds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CpuBandwidth {
+    pub quota: u64,
+    pub period: u64,
+}
+
 // When booting with PVH boot the maximum physical addressable size
 // is a 46 bit address space even when the host supports with 5-level
 // paging.
@@ -37,6 +59,10 @@ pub fn default_cpuconfig_max_phys_bits() -> u8 {
     DEFAULT_MAX_PHYS_BITS
 }
 
+pub fn default_cpuconfig_pmu() -> bool {
+    true
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpusConfig {
     pub boot_vcpus: u8,
@@ -45,12 +71,36 @@ pub struct CpusConfig {
     pub topology: Option<CpuTopology>,
     #[serde(default)]
     pub kvm_hyperv: bool,
+    #[serde(default)]
+    pub kvm_hyperv_features: Option<Vec<HypervFeature>>,
     #[serde(default = "default_cpuconfig_max_phys_bits")]
     pub max_phys_bits: u8,
     #[serde(default)]
     pub affinity: Option<Vec<CpuAffinity>>,
     #[serde(default)]
+    pub efficiency_cores: Option<Vec<u8>>,
+    #[serde(default = "default_cpuconfig_pmu")]
+    pub pmu: bool,
+    #[serde(default)]
+    pub pmu_events: Option<Vec<u16>>,
+    #[serde(default)]
+    pub nested: bool,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub cpuid_add: Option<Vec<String>>,
+    #[serde(default)]
+    pub cpuid_remove: Option<Vec<String>>,
+    #[serde(default)]
+    pub cpu_max: Option<CpuBandwidth>,
+    #[serde(default)]
+    pub vcpu_max: Option<CpuBandwidth>,
+    #[serde(default)]
     pub features: CpuFeatures,
+    // vCPUs removed on their own, leaving holes in the ids of the vCPUs
+    // present at boot
+    #[serde(default)]
+    pub removed_vcpus: Option<Vec<u8>>,
 }
 
 pub const DEFAULT_VCPUS: u8 = 1;
@@ -62,9 +112,20 @@ impl Default for CpusConfig {
             max_vcpus: DEFAULT_VCPUS,
             topology: None,
             kvm_hyperv: false,
+            kvm_hyperv_features: None,
             max_phys_bits: DEFAULT_MAX_PHYS_BITS,
             affinity: None,
+            efficiency_cores: None,
+            pmu: true,
+            pmu_events: None,
+            nested: false,
+            model: None,
+            cpuid_add: None,
+            cpuid_remove: None,
+            cpu_max: None,
+            vcpu_max: None,
             features: CpuFeatures::default(),
+            removed_vcpus: None,
         }
     }
 }
@@ -81,14 +142,33 @@ pub struct PlatformConfig {
     #[serde(default)]
     pub iommu_segments: Option<Vec<u16>>,
     #[serde(default)]
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    pub product: Option<String>,
+    #[serde(default)]
     pub serial_number: Option<String>,
     #[serde(default)]
     pub uuid: Option<String>,
     #[serde(default)]
     pub oem_strings: Option<Vec<String>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub s3: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub s4: bool,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub hibernate_action: HibernateAction,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub interrupt_remapping: bool,
     #[cfg(feature = "tdx")]
     #[serde(default)]
     pub tdx: bool,
+    #[cfg(feature = "tdx")]
+    #[serde(default)]
+    pub quote_generation_socket: Option<PathBuf>,
     #[cfg(feature = "sev_snp")]
     #[serde(default)]
     pub sev_snp: bool,
@@ -99,17 +179,45 @@ impl Default for PlatformConfig {
         PlatformConfig {
             num_pci_segments: DEFAULT_NUM_PCI_SEGMENTS,
             iommu_segments: None,
+            manufacturer: None,
+            product: None,
             serial_number: None,
             uuid: None,
             oem_strings: None,
+            #[cfg(target_arch = "x86_64")]
+            s3: false,
+            #[cfg(target_arch = "x86_64")]
+            s4: false,
+            #[cfg(target_arch = "x86_64")]
+            hibernate_action: HibernateAction::default(),
+            #[cfg(target_arch = "x86_64")]
+            interrupt_remapping: false,
             #[cfg(feature = "tdx")]
             tdx: false,
+            #[cfg(feature = "tdx")]
+            quote_generation_socket: None,
             #[cfg(feature = "sev_snp")]
             sev_snp: false,
         }
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct PciSegmentConfig {
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub start_bus: u8,
+    #[serde(default)]
+    pub end_bus: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct PciRootPortConfig {
+    pub id: String,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct MemoryZoneConfig {
     pub id: String,
@@ -130,6 +238,8 @@ pub struct MemoryZoneConfig {
     pub hotplugged_size: Option<u64>,
     #[serde(default)]
     pub prefault: bool,
+    #[serde(default)]
+    pub overcommit: bool,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -195,6 +305,36 @@ pub enum VhostMode {
     Server,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum IoPriorityClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+/// I/O scheduling priority given to the requests of a disk, following the
+/// classes and levels of ioprio_set(2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IoPriorityConfig {
+    pub class: IoPriorityClass,
+    #[serde(default)]
+    pub level: u8,
+}
+
+pub const MAX_IO_PRIORITY_LEVEL: u8 = 7;
+
+impl IoPriorityConfig {
+    /// Value of the priority as expected by ioprio_set(2).
+    pub fn ioprio(&self) -> u16 {
+        let class = match self.class {
+            IoPriorityClass::Realtime => 1,
+            IoPriorityClass::BestEffort => 2,
+            IoPriorityClass::Idle => 3,
+        };
+        (class << 13) | u16::from(self.level)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -224,7 +364,21 @@ pub struct DiskConfig {
     #[serde(default)]
     pub pci_segment: u16,
     #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
+    #[serde(default)]
     pub serial: Option<String>,
+    #[serde(default)]
+    pub pci_root_port: Option<String>,
+    #[serde(default)]
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub mmio: bool,
+    #[serde(default)]
+    pub io_priority: Option<IoPriorityConfig>,
+    #[serde(default)]
+    pub event_loop: EventLoop,
+    #[serde(default)]
+    pub notification: Option<NotificationConfig>,
 }
 
 pub const DEFAULT_DISK_NUM_QUEUES: usize = 1;
@@ -255,7 +409,14 @@ impl Default for DiskConfig {
             disable_aio: false,
             rate_limiter_config: None,
             pci_segment: 0,
+            pci_ids: None,
             serial: None,
+            pci_root_port: None,
+            backend: None,
+            mmio: false,
+            io_priority: None,
+            event_loop: EventLoop::Epoll,
+            notification: None,
         }
     }
 }
@@ -293,12 +454,26 @@ pub struct NetConfig {
     pub rate_limiter_config: Option<RateLimiterConfig>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
     #[serde(default = "default_netconfig_true")]
     pub offload_tso: bool,
     #[serde(default = "default_netconfig_true")]
     pub offload_ufo: bool,
     #[serde(default = "default_netconfig_true")]
     pub offload_csum: bool,
+    #[serde(default)]
+    pub pci_root_port: Option<String>,
+    #[serde(default)]
+    pub notification: Option<NotificationConfig>,
+    #[serde(default)]
+    pub mmio: bool,
+    #[serde(default)]
+    pub xdp: Option<String>,
+    #[serde(default = "default_netconfig_true")]
+    pub xdp_zero_copy: bool,
+    #[serde(default)]
+    pub event_loop: EventLoop,
 }
 
 pub fn default_netconfig_true() -> bool {
@@ -352,9 +527,16 @@ impl Default for NetConfig {
             fds: None,
             rate_limiter_config: None,
             pci_segment: 0,
+            pci_ids: None,
             offload_tso: true,
             offload_ufo: true,
             offload_csum: true,
+            pci_root_port: None,
+            notification: None,
+            mmio: false,
+            xdp: None,
+            xdp_zero_copy: true,
+            event_loop: EventLoop::Epoll,
         }
     }
 }
@@ -364,6 +546,13 @@ pub struct RngConfig {
     pub src: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub mmio: bool,
+    /// The device got hot-unplugged, and isn't created anymore.
+    #[serde(default)]
+    pub removed: bool,
 }
 
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
@@ -373,6 +562,9 @@ impl Default for RngConfig {
         RngConfig {
             src: PathBuf::from(DEFAULT_RNG_SOURCE),
             iommu: false,
+            rate_limiter_config: None,
+            mmio: false,
+            removed: false,
         }
     }
 }
@@ -389,9 +581,60 @@ pub struct BalloonConfig {
     /// Option to enable free page reporting from the guest.
     #[serde(default)]
     pub free_page_reporting: bool,
+    /// Option to let the host ask the guest to hint its free pages.
+    #[serde(default)]
+    pub free_page_hinting: bool,
     /// Option to enable ballooning heterogeneous memory.
     #[serde(default)]
     pub heterogeneous_memory: bool,
+    /// Host available memory under which memory is reclaimed from the
+    /// guest by inflating the balloon.
+    #[serde(default)]
+    pub reclaim_below: Option<u64>,
+    /// Host available memory over which the reclaimed memory is given back
+    /// to the guest.
+    #[serde(default)]
+    pub release_above: Option<u64>,
+    /// Amount of memory reclaimed or given back at once.
+    #[serde(default = "default_balloonconfig_reclaim_step")]
+    pub reclaim_step: u64,
+    /// Maximum amount of memory reclaimed on top of the balloon size.
+    #[serde(default)]
+    pub reclaim_max: Option<u64>,
+    /// Number of additional seconds the host pressure must last for before
+    /// memory is reclaimed from the guest.
+    #[serde(default)]
+    pub reclaim_priority: u8,
+    /// Memory pressure, as the percentage of time tasks stalled on memory
+    /// over the last 10 seconds, above which guest memory is moved to the
+    /// heterogeneous memory by inflating the heterogeneous balloon.
+    #[serde(default)]
+    pub hetero_pressure_high: Option<u8>,
+    /// Memory pressure under which the memory moved to the heterogeneous
+    /// memory is given back.
+    #[serde(default)]
+    pub hetero_pressure_low: Option<u8>,
+    /// Amount of memory moved to or from the heterogeneous memory at once.
+    #[serde(default = "default_balloonconfig_reclaim_step")]
+    pub hetero_step: u64,
+    /// Maximum amount of memory moved to the heterogeneous memory on top of
+    /// the heterogeneous balloon size.
+    #[serde(default)]
+    pub hetero_max: Option<u64>,
+    /// Pressure stall information file the memory pressure is read from,
+    /// instead of the host one, e.g. the `memory.pressure` file of a cgroup.
+    #[serde(default)]
+    pub hetero_pressure_file: Option<PathBuf>,
+    /// Option to inflate the balloon when the memory cgroup of the VMM goes
+    /// over its `memory.high` limit.
+    #[serde(default)]
+    pub cgroup_memory_high: bool,
+}
+
+pub const DEFAULT_BALLOON_RECLAIM_STEP: u64 = 128 << 20;
+
+pub fn default_balloonconfig_reclaim_step() -> u64 {
+    DEFAULT_BALLOON_RECLAIM_STEP
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -403,9 +646,17 @@ pub struct FsConfig {
     #[serde(default = "default_fsconfig_queue_size")]
     pub queue_size: u16,
     #[serde(default)]
+    pub dax: bool,
+    #[serde(default = "default_fsconfig_cache_size")]
+    pub cache_size: u64,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
+    #[serde(default)]
+    pub shared_dir: Option<PathBuf>,
 }
 
 pub fn default_fsconfig_num_queues() -> usize {
@@ -416,6 +667,10 @@ pub fn default_fsconfig_queue_size() -> u16 {
     1024
 }
 
+pub fn default_fsconfig_cache_size() -> u64 {
+    0x0002_0000_0000
+}
+
 impl Default for FsConfig {
     fn default() -> Self {
         Self {
@@ -423,8 +678,12 @@ impl Default for FsConfig {
             socket: PathBuf::new(),
             num_queues: default_fsconfig_num_queues(),
             queue_size: default_fsconfig_queue_size(),
+            dax: false,
+            cache_size: default_fsconfig_cache_size(),
             id: None,
             pci_segment: 0,
+            pci_ids: None,
+            shared_dir: None,
         }
     }
 }
@@ -439,9 +698,46 @@ pub struct PmemConfig {
     #[serde(default)]
     pub discard_writes: bool,
     #[serde(default)]
+    pub discard: bool,
+    #[serde(default)]
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IvshmemConfig {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default = "default_ivshmemconfig_vectors")]
+    pub vectors: u16,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+pub fn default_ivshmemconfig_vectors() -> u16 {
+    1
+}
+
+impl Default for IvshmemConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            size: None,
+            socket: None,
+            vectors: default_ivshmemconfig_vectors(),
+            id: None,
+            pci_segment: 0,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -451,6 +747,7 @@ pub enum ConsoleOutputMode {
     Tty,
     File,
     Socket,
+    Tcp,
     Null,
 }
 
@@ -462,12 +759,51 @@ pub struct ConsoleConfig {
     #[serde(default)]
     pub iommu: bool,
     pub socket: Option<PathBuf>,
+    #[serde(default = "default_consoleconfig_max_ports")]
+    pub max_ports: u32,
+    /// Address listened on by the serial port in `Tcp` mode.
+    #[serde(default)]
+    pub tcp: Option<SocketAddr>,
+    /// Negotiate a character mode session with telnet clients connecting
+    /// in `Tcp` mode.
+    #[serde(default)]
+    pub telnet: bool,
+    /// Directory where the additional ports not given a socket get one,
+    /// named after the port, as does the serial port, as `serial.sock`.
+    #[serde(default)]
+    pub socket_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DebugConsoleConfig {
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    pub mode: ConsoleOutputMode,
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
 }
 
 pub fn default_consoleconfig_file() -> Option<PathBuf> {
     None
 }
 
+pub fn default_consoleconfig_max_ports() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct ConsolePortConfig {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    // Backed by a PTY when no socket is given.
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct DeviceConfig {
     pub path: PathBuf,
@@ -477,6 +813,36 @@ pub struct DeviceConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub p2p: Option<Vec<String>>,
+    #[serde(default)]
+    pub pci_root_port: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VfConfig {
+    pub pf: String,
+    pub vf: u16,
+    #[serde(default)]
+    pub num_vfs: Option<u16>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct MdevConfig {
+    pub parent: String,
+    pub mdev_type: String,
+    #[serde(default)]
+    pub uuid: Option<String>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub pci_segment: u16,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
@@ -499,6 +865,8 @@ pub struct VdpaConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
 }
 
 pub fn default_vdpaconfig_num_queues() -> usize {
@@ -515,6 +883,55 @@ pub struct VsockConfig {
     pub id: Option<String>,
     #[serde(default)]
     pub pci_segment: u16,
+    #[serde(default)]
+    pub pci_ids: Option<PciIdsConfig>,
+    #[serde(default)]
+    pub siblings: Option<Vec<VsockSiblingConfig>>,
+    #[serde(default)]
+    pub listeners: Option<Vec<VsockListenerConfig>>,
+    #[serde(default)]
+    pub mmio: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VsockSiblingConfig {
+    pub cid: u64,
+    pub socket: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct VsockListenerConfig {
+    pub port: u32,
+    pub socket: PathBuf,
+}
+
+pub const DEFAULT_GUEST_AGENT_PORT: u32 = 1097;
+
+pub fn default_guestagentconfig_port() -> u32 {
+    DEFAULT_GUEST_AGENT_PORT
+}
+
+pub const DEFAULT_GUEST_AGENT_TIMEOUT: u64 = 10;
+
+pub fn default_guestagentconfig_timeout() -> u64 {
+    DEFAULT_GUEST_AGENT_TIMEOUT
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GuestAgentConfig {
+    #[serde(default = "default_guestagentconfig_port")]
+    pub port: u32,
+    #[serde(default = "default_guestagentconfig_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for GuestAgentConfig {
+    fn default() -> Self {
+        GuestAgentConfig {
+            port: default_guestagentconfig_port(),
+            timeout: default_guestagentconfig_timeout(),
+        }
+    }
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -527,6 +944,44 @@ pub struct SgxEpcConfig {
     pub prefault: bool,
 }
 
+#[cfg(target_arch = "x86_64")]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MsrFilterDefaultAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct MsrFilterConfig {
+    #[serde(default)]
+    pub default_action: MsrFilterDefaultAction,
+    // MSRs the guest can access
+    #[serde(default)]
+    pub allow: Vec<u32>,
+    // MSRs the guest gets a #GP for
+    #[serde(default)]
+    pub deny: Vec<u32>,
+    // MSRs read as zero by the guest, while its writes are ignored
+    #[serde(default)]
+    pub zero: Vec<u32>,
+}
+
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UefiVarsConfig {
+    pub path: PathBuf,
+    // Secure Boot keys enrolled when creating the variable store
+    #[serde(default)]
+    pub pk: Option<PathBuf>,
+    #[serde(default)]
+    pub kek: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    pub db: Option<Vec<PathBuf>>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
 pub struct NumaDistance {
     #[serde(default)]
@@ -562,6 +1017,20 @@ pub struct PayloadConfig {
     pub cmdline: Option<String>,
     #[serde(default)]
     pub initramfs: Option<PathBuf>,
+    // File descriptors of the payload handed over through the API, owned by
+    // the preserved FDs of the holding VmConfig instance.
+    #[serde(skip)]
+    pub firmware_fd: Option<i32>,
+    #[serde(skip)]
+    pub kernel_fd: Option<i32>,
+    #[serde(skip)]
+    pub initramfs_fd: Option<i32>,
+    #[cfg(feature = "sev_snp")]
+    #[serde(default)]
+    pub igvm: Option<PathBuf>,
+    #[cfg(feature = "sev_snp")]
+    #[serde(default)]
+    pub host_data: Option<String>,
 }
 
 pub fn default_serial() -> ConsoleConfig {
@@ -570,6 +1039,10 @@ pub fn default_serial() -> ConsoleConfig {
         mode: ConsoleOutputMode::Null,
         iommu: false,
         socket: None,
+        max_ports: default_consoleconfig_max_ports(),
+        tcp: None,
+        telnet: false,
+        socket_dir: None,
     }
 }
 
@@ -579,6 +1052,121 @@ pub fn default_console() -> ConsoleConfig {
         mode: ConsoleOutputMode::Tty,
         iommu: false,
         socket: None,
+        max_ports: default_consoleconfig_max_ports(),
+        tcp: None,
+        telnet: false,
+        socket_dir: None,
+    }
+}
+
+/// What happens to the VM once the guest has hibernated (S4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub enum HibernateAction {
+    /// The VMM exits, as for a shutdown
+    #[default]
+    Exit,
+    /// The VM is shut down, to be booted again through the API
+    Shutdown,
+    /// The VM is booted again right away
+    Reboot,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub enum PvPanicAction {
+    #[default]
+    Pause,
+    Coredump,
+    Restart,
+    Kdump,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub struct PvPanicPolicyConfig {
+    #[serde(default)]
+    pub action: PvPanicAction,
+    #[serde(default)]
+    pub coredump_path: Option<PathBuf>,
+    #[serde(default)]
+    pub kdump_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub restart: bool,
+}
+
+/// What the VMM does once the guest crashed, through a triple fault, a
+/// pvpanic report or an expiry of the watchdog.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+pub enum OnCrashAction {
+    /// The VM is restarted
+    #[default]
+    Restart,
+    /// The VM is left paused, for its state to be inspected
+    Preserve,
+    /// A coredump of the VM is written before restarting it
+    CoredumpAndRestart,
+}
+
+pub fn default_oncrashconfig_backoff() -> u64 {
+    1
+}
+
+pub fn default_oncrashconfig_max_backoff() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OnCrashConfig {
+    #[serde(default)]
+    pub action: OnCrashAction,
+    /// Directory the coredumps are written to
+    #[serde(default)]
+    pub coredump_dir: Option<PathBuf>,
+    /// Delay before the first restart, in seconds
+    #[serde(default = "default_oncrashconfig_backoff")]
+    pub backoff: u64,
+    /// Maximum delay before a restart, in seconds
+    #[serde(default = "default_oncrashconfig_max_backoff")]
+    pub max_backoff: u64,
+    /// Number of crashes in a row after which the VM is preserved
+    #[serde(default)]
+    pub max_restarts: Option<u32>,
+}
+
+impl Default for OnCrashConfig {
+    fn default() -> Self {
+        OnCrashConfig {
+            action: OnCrashAction::default(),
+            coredump_dir: None,
+            backoff: default_oncrashconfig_backoff(),
+            max_backoff: default_oncrashconfig_max_backoff(),
+            max_restarts: None,
+        }
+    }
+}
+
+pub fn default_watchdogescalationconfig_resets() -> u32 {
+    3
+}
+
+pub fn default_watchdogescalationconfig_window() -> u64 {
+    600
+}
+
+/// Escalation of the watchdog expiries: past `resets` resets of the VM within
+/// `window` seconds, the watchdog powers the VM off instead.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WatchdogEscalationConfig {
+    #[serde(default = "default_watchdogescalationconfig_resets")]
+    pub resets: u32,
+    #[serde(default = "default_watchdogescalationconfig_window")]
+    pub window: u64,
+}
+
+impl Default for WatchdogEscalationConfig {
+    fn default() -> Self {
+        WatchdogEscalationConfig {
+            resets: default_watchdogescalationconfig_resets(),
+            window: default_watchdogescalationconfig_window(),
+        }
     }
 }
 
@@ -587,6 +1175,94 @@ pub struct TpmConfig {
     pub socket: PathBuf,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AcpiTableConfig {
+    pub path: PathBuf,
+}
+
+/// I/O limits of a host block device, expressed as in the cgroup v2 `io.max`
+/// file, `None` meaning unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IoMaxConfig {
+    pub major: u32,
+    pub minor: u32,
+    #[serde(default)]
+    pub rbps: Option<u64>,
+    #[serde(default)]
+    pub wbps: Option<u64>,
+    #[serde(default)]
+    pub riops: Option<u64>,
+    #[serde(default)]
+    pub wiops: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceCpuMaxConfig {
+    pub id: String,
+    pub cpu_max: CpuBandwidth,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CgroupConfig {
+    /// Cgroup to create or join, relative to the root of the cgroup v2
+    /// hierarchy, instead of a child of the cgroup the VMM started in.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub memory_max: Option<u64>,
+    #[serde(default)]
+    pub io_max: Option<Vec<IoMaxConfig>>,
+    #[serde(default)]
+    pub device_cpu_max: Option<Vec<DeviceCpuMaxConfig>>,
+}
+
+/// Resource limits and OOM killer adjustment of the VMM process, `None`
+/// keeping the values it was started with.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ProcessLimitsConfig {
+    #[serde(default)]
+    pub nofile: Option<u64>,
+    #[serde(default)]
+    pub memlock: Option<u64>,
+    #[serde(default)]
+    pub oom_score_adj: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum LandlockAccess {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+    #[serde(rename = "rw")]
+    ReadWrite,
+}
+
+impl LandlockAccess {
+    pub fn readable(&self) -> bool {
+        matches!(self, LandlockAccess::Read | LandlockAccess::ReadWrite)
+    }
+
+    pub fn writable(&self) -> bool {
+        matches!(self, LandlockAccess::Write | LandlockAccess::ReadWrite)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LandlockConfig {
+    pub path: PathBuf,
+    pub access: LandlockAccess,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SeccompOverrideConfig {
+    pub id: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct VmConfig {
     #[serde(default)]
@@ -601,28 +1277,63 @@ pub struct VmConfig {
     pub balloon: Option<BalloonConfig>,
     pub fs: Option<Vec<FsConfig>>,
     pub pmem: Option<Vec<PmemConfig>>,
+    #[serde(default)]
+    pub ivshmem: Option<Vec<IvshmemConfig>>,
     #[serde(default = "default_serial")]
     pub serial: ConsoleConfig,
     #[serde(default = "default_console")]
     pub console: ConsoleConfig,
+    pub console_ports: Option<Vec<ConsolePortConfig>>,
+    #[serde(default)]
+    pub debug_console: Option<DebugConsoleConfig>,
     pub devices: Option<Vec<DeviceConfig>>,
     pub user_devices: Option<Vec<UserDeviceConfig>>,
     pub vdpa: Option<Vec<VdpaConfig>>,
     pub vsock: Option<VsockConfig>,
+    pub guest_agent: Option<GuestAgentConfig>,
     #[serde(default)]
     pub pvpanic: bool,
     #[serde(default)]
+    pub pvpanic_policy: Option<PvPanicPolicyConfig>,
+    #[serde(default)]
+    pub on_crash: Option<OnCrashConfig>,
+    #[serde(default)]
     pub iommu: bool,
     #[cfg(target_arch = "x86_64")]
     pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub msr_filter: Option<MsrFilterConfig>,
+    #[cfg(target_arch = "x86_64")]
+    #[serde(default)]
+    pub uefi_vars: Option<UefiVarsConfig>,
     pub numa: Option<Vec<NumaConfig>>,
     #[serde(default)]
     pub watchdog: bool,
+    #[serde(default)]
+    pub watchdog_action: WatchdogAction,
+    #[serde(default)]
+    pub watchdog_escalation: Option<WatchdogEscalationConfig>,
     #[cfg(feature = "guest_debug")]
     #[serde(default)]
     pub gdb: bool,
     pub platform: Option<PlatformConfig>,
+    #[serde(default)]
+    pub pci_segments: Option<Vec<PciSegmentConfig>>,
+    #[serde(default)]
+    pub pci_root_ports: Option<Vec<PciRootPortConfig>>,
     pub tpm: Option<TpmConfig>,
+    #[serde(default)]
+    pub acpi_tables: Option<Vec<AcpiTableConfig>>,
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    #[serde(default)]
+    pub process_limits: Option<ProcessLimitsConfig>,
+    #[serde(default)]
+    pub seccomp_overrides: Option<Vec<SeccompOverrideConfig>>,
+    #[serde(default)]
+    pub landlock_enable: bool,
+    pub landlock_rules: Option<Vec<LandlockConfig>>,
     // Preserved FDs are the ones that share the same life-time as its holding
     // VmConfig instance, such as FDs for creating TAP devices.
     // Preserved FDs will stay open as long as the holding VmConfig instance is
@@ -630,3 +1341,58 @@ pub struct VmConfig {
     #[serde(skip)]
     pub preserved_fds: Option<Vec<i32>>,
 }
+
+// Matches the defaults of the deserialized configuration, without any payload
+// nor device beyond the default ones.
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig {
+            cpus: CpusConfig::default(),
+            memory: MemoryConfig::default(),
+            payload: None,
+            disks: None,
+            net: None,
+            rng: RngConfig::default(),
+            balloon: None,
+            fs: None,
+            pmem: None,
+            ivshmem: None,
+            serial: default_serial(),
+            console: default_console(),
+            console_ports: None,
+            debug_console: None,
+            devices: None,
+            user_devices: None,
+            vdpa: None,
+            vsock: None,
+            guest_agent: None,
+            pvpanic: false,
+            pvpanic_policy: None,
+            on_crash: None,
+            iommu: false,
+            #[cfg(target_arch = "x86_64")]
+            sgx_epc: None,
+            #[cfg(target_arch = "x86_64")]
+            msr_filter: None,
+            #[cfg(target_arch = "x86_64")]
+            uefi_vars: None,
+            numa: None,
+            watchdog: false,
+            watchdog_action: WatchdogAction::default(),
+            watchdog_escalation: None,
+            #[cfg(feature = "guest_debug")]
+            gdb: false,
+            platform: None,
+            pci_segments: None,
+            pci_root_ports: None,
+            tpm: None,
+            acpi_tables: None,
+            cgroup: None,
+            process_limits: None,
+            seccomp_overrides: None,
+            landlock_enable: false,
+            landlock_rules: None,
+            preserved_fds: None,
+        }
+    }
+}